@@ -4,15 +4,87 @@
 //!
 //! The ATProto subset is described [here](<https://atproto.com/specs/did>)
 
-use std::ops::Bound;
+use crate::{span, SelfIndex, ValidationError};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::ops::Bound;
 use thiserror::Error;
 
-type SelfIndex = (Bound<usize>, Bound<usize>);
+/// DIDs up to this many bytes are stored inline, which covers every did:plc and most did:web
+const INLINE_CAPACITY: usize = 46;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone)]
+/// Storage for a DID string, avoiding a heap allocation for all but unusually long DIDs
+enum Repr {
+    /// Literals are borrowed rather than copied so that they can be constants, see
+    /// [`crate::did!`]
+    Static(&'static str),
+    Inline{len: u8, bytes: [u8; INLINE_CAPACITY]},
+    Heap(Box<str>)
+}
+
+impl Repr {
+    fn new(id: String) -> Repr {
+        if id.len() > INLINE_CAPACITY {
+            return Repr::Heap(id.into_boxed_str())
+        }
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes[..id.len()].copy_from_slice(id.as_bytes());
+        Repr::Inline{len: id.len() as u8, bytes}
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Repr::Static(s) => s,
+            // SAFETY: the bytes were copied from a str, and `len` is that str's length so the
+            // slice ends on a char boundary
+            Repr::Inline{len, bytes} => unsafe { core::str::from_utf8_unchecked(&bytes[..*len as usize]) },
+            Repr::Heap(s) => s
+        }
+    }
+}
+
+#[derive(Clone)]
 /// Wrapper struct around a DID identifier string
 pub struct Did {
-    inner: String,
+    inner: Repr,
+}
+
+impl PartialEq for Did {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Did {}
+
+impl Hash for Did {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl PartialOrd for Did {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Did {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Debug for Did {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Did").field("inner", &self.as_str()).finish()
+    }
 }
 
 #[derive(Debug, Error, PartialEq)]
@@ -21,11 +93,32 @@ pub enum DidValidationError {
     #[error("Expected an identifier of at least 9 chars")]
     TooShort,
     #[error("Expected a prefix of did: - found {found}")]
-    InvalidPrefix{found: String},
+    InvalidPrefix{found: String, span: SelfIndex},
     #[error("Expected a method of either web or plc - found {found}")]
-    InvalidMethod{found: String},
+    InvalidMethod{found: String, span: SelfIndex},
+    /// `span` covers the first offending char
     #[error("Identifier didn't conform to DID identifier format - found {found}")]
-    InvalidIdentifier{found: String}
+    InvalidIdentifier{found: String, span: SelfIndex}
+}
+
+impl ValidationError for DidValidationError {
+    fn span(&self) -> SelfIndex {
+        use DidValidationError::*;
+        match self {
+            TooShort => (Bound::Unbounded, Bound::Unbounded),
+            InvalidPrefix{span, ..} | InvalidMethod{span, ..} | InvalidIdentifier{span, ..} => *span
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        use DidValidationError::*;
+        match self {
+            TooShort => "did.too_short",
+            InvalidPrefix{..} => "did.invalid_prefix",
+            InvalidMethod{..} => "did.invalid_method",
+            InvalidIdentifier{..} => "did.invalid_identifier",
+        }
+    }
 }
 
 /// The two ATProto DID methods
@@ -43,23 +136,45 @@ impl Did {
             return Err(TooShort)
         }
 
-        // Given the previous error it's not guaranteed that no panics will occur for these slices
-        if &id[0..4] != "did:"{
-            return Err(InvalidPrefix{found: id[0..4].to_string()})
+        // Slicing by byte offset could split a multi-byte char, so compare bytes instead
+        if !id.starts_with("did:") {
+            let found: String = id.chars().take(4).collect();
+            return Err(InvalidPrefix{span: span(0, found.len()), found})
         }
         
         // These are the only allowed methods for ATProto, simplifying parsing
-        if &id[4..8] != "web:" && &id[4..8] != "plc:"{
-            return Err(InvalidMethod{found: id[4..8].to_string()})
+        if !id[4..].starts_with("web:") && !id[4..].starts_with("plc:") {
+            let found: String = id[4..].chars().take(4).collect();
+            return Err(InvalidMethod{span: span(4, 4 + found.len()), found})
         }
 
-        if !id[8..].chars().all(|c| {
-            c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == ':' || c == '-'
-        }) || id.chars().last() == Some(':') {
-            return Err(InvalidIdentifier{found: id[8..].to_string()})
+        // Anything else has to be percent-encoded, e.g. the port in did:web:localhost%3A8080
+        let bytes = id.as_bytes();
+        let valid_at = |i: usize| match bytes[i] {
+            b'%' => bytes.get(i + 1..i + 3).is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)),
+            c => c.is_ascii_alphanumeric() || c == b'.' || c == b'_' || c == b':' || c == b'-'
+        };
+        let invalid = match (8..id.len()).find(|&i| !valid_at(i)) {
+            // Everything before `i` is ASCII so it's a char boundary
+            Some(i) => Some(span(i, i + id[i..].chars().next().map_or(1, char::len_utf8))),
+            None if id.ends_with(':') => Some(span(id.len() - 1, id.len())),
+            None => None
+        };
+        if let Some(span) = invalid {
+            return Err(InvalidIdentifier{found: id[8..].to_string(), span})
         }
 
-        Ok(Did{inner: id})
+        Ok(Did{inner: Repr::new(id)})
+    }
+
+    /// Create a DID from a static string in a const context
+    ///
+    /// # Panics
+    /// If `id` isn't valid - at compile time when used in a `const`, which is what the
+    /// [`crate::did!`] macro arranges
+    pub const fn from_static(id: &'static str) -> Did {
+        assert!(crate::macros::valid_did(id), "Invalid DID literal");
+        Did{inner: Repr::Static(id)}
     }
 
     /// Get the method of this DID
@@ -67,7 +182,7 @@ impl Did {
     /// Assumes that the method is one of the two ATProto supported ones
     /// and that the ID has been validated
     pub fn method(&self) -> DidMethod {
-         match &self.inner[4..7] {
+         match &self.as_str()[4..7] {
             "web" => DidMethod::Web,
             "plc" => DidMethod::Plc,
             _ => panic!("An incorrect DID method snuck its way in {self:?}")
@@ -78,8 +193,95 @@ impl Did {
     /// 
     /// Assumes that `self` is validated correctly and in particular that identifier is not "" and
     /// that the method is three characters long
-    pub fn identifier<'a>(&'a self) -> &'a str {
-        &self.inner[8..]
+    pub fn identifier(&self) -> &str {
+        &self.as_str()[8..]
+    }
+
+    /// The URL of this DID's document, if it's a did:web
+    ///
+    /// The first part of the identifier is the host, with a port percent-encoded as `%3A`, and
+    /// any later colon-separated parts are path segments:
+    /// - `did:web:example.com` is `https://example.com/.well-known/did.json`
+    /// - `did:web:localhost%3A8080:u:alice` is `https://localhost:8080/u/alice/did.json`
+    pub fn to_did_web_url(&self) -> Option<String> {
+        let host = self.web_host()?;
+        let path = self.web_path();
+        Some(match path.is_empty() {
+            true => format!("https://{host}/.well-known/did.json"),
+            false => format!("https://{host}/{}/did.json", path.join("/"))
+        })
+    }
+
+    /// The decoded host (and port) of a did:web
+    ///
+    /// Only the `%3A` before a port is decoded: any other escape could smuggle a `/` or `@`
+    /// into the host, so a DID with one has no host
+    pub fn web_host(&self) -> Option<String> {
+        if !matches!(self.method(), DidMethod::Web) {
+            return None
+        }
+        let host = self.identifier().split(':').next().unwrap_or_default();
+        let host = host.to_ascii_lowercase().replace("%3a", ":");
+        (!host.contains('%')).then_some(host)
+    }
+
+    /// The decoded path segments of a did:web, empty for a host-level DID
+    pub fn web_path(&self) -> Vec<String> {
+        if !matches!(self.method(), DidMethod::Web) {
+            return Vec::new()
+        }
+        self.identifier().split(':').skip(1).map(percent_decode).collect()
+    }
+
+    /// The did:web for a host (which may include a port) and path, as served from
+    /// [`Did::to_did_web_url`]
+    pub fn did_web(host: &str, path: &[&str]) -> Result<Did, DidValidationError> {
+        let mut id = format!("did:web:{}", percent_encode(&host.to_ascii_lowercase()));
+        for segment in path {
+            id.push(':');
+            id.push_str(&percent_encode(segment));
+        }
+        Did::try_create(id)
+    }
+
+    /// Get the full DID string
+    pub fn as_str(&self) -> &str {
+        self.inner.as_str()
+    }
+}
+
+/// Decode `%XX` escapes, leaving anything malformed as it is
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| core::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Encode everything which can't appear literally in a DID identifier segment
+fn percent_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b if b.is_ascii_alphanumeric() || b == b'.' || b == b'_' || b == b'-' => (b as char).to_string(),
+        b => format!("%{b:02X}")
+    }).collect()
+}
+
+impl fmt::Display for Did {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
@@ -90,19 +292,19 @@ mod tests {
     #[test]
     fn valid_web() {
         let did = "did:web:localhost";
-        assert_eq!(Did::try_create(did.to_string()), Ok(Did{inner: did.to_string()}))
+        assert_eq!(Did::try_create(did.to_string()), Ok(Did::from_static(did)))
     }
 
     #[test]
     fn valid_plc() {
         let did = "did:plc:z72i7hdynmk6r22z27h6tvur";
-        assert_eq!(Did::try_create(did.to_string()), Ok(Did{inner: did.to_string()}))
+        assert_eq!(Did::try_create(did.to_string()), Ok(Did::from_static(did)))
     }
 
     #[test]
     fn invalid_method() {
         let did = "did:key:zQ3shZc2QzApp2oymGvQbzP8eKheVshBHbU4ZYjeXqwSKEn6N";
-        assert_eq!(Did::try_create(did.to_string()), Err(DidValidationError::InvalidMethod{found:"key:".to_string()}))
+        assert_eq!(Did::try_create(did.to_string()), Err(DidValidationError::InvalidMethod{found:"key:".to_string(), span: span(4, 8)}))
     }
 
     #[test]
@@ -116,4 +318,67 @@ mod tests {
         let did = "did:web:";
         assert_eq!(Did::try_create(did.to_string()), Err(DidValidationError::TooShort))
     }
+
+    #[test]
+    fn did_web_urls() {
+        let did = Did::from_static("did:web:example.com");
+        assert_eq!(did.to_did_web_url().as_deref(), Some("https://example.com/.well-known/did.json"));
+        let did = Did::from_static("did:web:localhost%3A8080:u:alice");
+        assert_eq!(did.web_host().as_deref(), Some("localhost:8080"));
+        assert_eq!(did.to_did_web_url().as_deref(), Some("https://localhost:8080/u/alice/did.json"));
+        assert_eq!(Did::did_web("localhost:8080", &["u", "alice"]), Ok(did));
+        assert_eq!(Did::from_static("did:plc:abcdefg").to_did_web_url(), None);
+    }
+
+    #[test]
+    fn did_web_hosts_only_decode_ports() {
+        let did = Did::from_static("did:web:Localhost%3a8080");
+        assert_eq!(did.web_host().as_deref(), Some("localhost:8080"));
+        for smuggled in ["did:web:evil.example%2Fexample.com", "did:web:evil.example%40example.com"] {
+            let did = Did::try_create(smuggled.to_string()).unwrap();
+            assert_eq!(did.web_host(), None);
+            assert_eq!(did.to_did_web_url(), None);
+        }
+    }
+
+    #[test]
+    fn percent_encoding() {
+        assert!(Did::try_create("did:web:localhost%3a80".to_string()).is_ok());
+        let did = "did:web:localhost%3";
+        let error = Did::try_create(did.to_string()).unwrap_err();
+        assert_eq!(did.get(error.span()), Some("%"));
+    }
+
+    #[test]
+    fn storage() {
+        assert_eq!(std::mem::size_of::<Did>(), 48);
+        let plc = Did::try_create("did:plc:z72i7hdynmk6r22z27h6tvur".to_string()).unwrap();
+        assert!(matches!(plc.inner, Repr::Inline{..}));
+        let long = format!("did:web:{}.example", "a".repeat(60));
+        let web = Did::try_create(long.clone()).unwrap();
+        assert!(matches!(web.inner, Repr::Heap(_)));
+        assert_eq!(web.as_str(), long);
+        assert_eq!(plc, Did::from_static("did:plc:z72i7hdynmk6r22z27h6tvur"));
+    }
+
+    #[test]
+    fn error_spans() {
+        let did = "did:plc:abc#def";
+        let error = Did::try_create(did.to_string()).unwrap_err();
+        assert_eq!(error.code(), "did.invalid_identifier");
+        assert_eq!(did.get(error.span()), Some("#"));
+        let did = "DID:plc:abc";
+        let error = Did::try_create(did.to_string()).unwrap_err();
+        assert_eq!(did.get(error.span()), Some("DID:"));
+    }
+
+    #[test]
+    fn generated() {
+        use crate::arbitrary::{self, Arbitrary, Gen};
+        arbitrary::check(|g: &mut Gen| {
+            let did = Did::arbitrary(g);
+            assert_eq!(Did::try_create(did.to_string()), Ok(did.clone()));
+            let _ = Did::try_create(arbitrary::mutate(g, did.as_str()));
+        });
+    }
 }
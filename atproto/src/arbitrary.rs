@@ -0,0 +1,232 @@
+//! Random generation of identifiers for property-based tests
+//!
+//! Enabled by the `arbitrary` feature. [`Arbitrary`] produces valid values of each identifier
+//! type, spread across the corners of the grammar, and [`mutate`] corrupts a valid string in
+//! the ways real input tends to go wrong (truncation, stray separators, multi-byte chars,
+//! excess length) to produce adversarial inputs for the parsers.
+//!
+//! Everything is driven by a seeded [`Gen`] so failures are reproducible: [`check`] reports
+//! the seed of a failing case, and setting `ATPROTO_ARBITRARY_SEED` replays from it
+
+use crate::at_uri::AtUri;
+use crate::handle::Handle;
+use crate::nsid::Nsid;
+use crate::tid::Tid;
+use crate::DID::Did;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Number of cases [`check`] runs
+pub const CASES: u64 = 512;
+
+const LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const ALPHANUMERIC: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// Chars that are significant somewhere in identifier syntax, or are likely to trip up
+/// byte-oriented parsing
+const NASTY: &[&str] = &[
+    ".", ":", "/", "-", "_", "#", "?", "%", "@", " ", "\0", "\n", "A", "9", "é", "ß", "🦀",
+    "\u{200b}",
+];
+
+/// A small deterministic random number generator (SplitMix64)
+pub struct Gen {
+    state: u64,
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Gen {
+        Gen { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `low..=high`
+    pub fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next_u64() % (high - low + 1) as u64) as usize
+    }
+
+    /// True with probability `1/n`
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.next_u64().is_multiple_of(n)
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.range(0, items.len() - 1)]
+    }
+
+    /// A string of `len` chars drawn from `alphabet`
+    pub fn string(&mut self, alphabet: &[u8], len: usize) -> String {
+        (0..len).map(|_| *self.pick(alphabet) as char).collect()
+    }
+}
+
+/// Types which can be randomly generated
+pub trait Arbitrary: Sized {
+    /// A random valid value
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+/// A DNS label: alphanumeric with inner hyphens
+fn label(g: &mut Gen, alphabet: &[u8], max: usize) -> String {
+    let len = g.range(1, max);
+    let mut label = g.string(alphabet, len);
+    if len > 2 && g.one_in(4) {
+        label.replace_range(len / 2..len / 2 + 1, "-");
+    }
+    label
+}
+
+/// A domain name of at least two labels, with a TLD starting with a letter
+fn domain(g: &mut Gen, alphabet: &[u8]) -> Vec<String> {
+    let mut labels: Vec<_> = (0..g.range(1, 4)).map(|_| label(g, alphabet, 20)).collect();
+    let len = g.range(0, 8);
+    let tld = g.string(LOWER, 1) + &g.string(alphabet, len);
+    labels.push(tld);
+    labels
+}
+
+impl Arbitrary for Did {
+    fn arbitrary(g: &mut Gen) -> Did {
+        let did = if g.one_in(2) {
+            format!("did:plc:{}", g.string(BASE32, 24))
+        } else {
+            let mut did = format!(
+                "did:web:{}",
+                domain(g, b"abcdefghijklmnopqrstuvwxyz0123456789").join(".")
+            );
+            if g.one_in(4) {
+                did.push_str(&format!("%3A{}", g.range(1, 65535)));
+            }
+            if g.one_in(4) {
+                did.push(':');
+                did.push_str(&label(g, ALPHANUMERIC, 10));
+            }
+            did
+        };
+        Did::try_create(did).expect("generated DIDs are valid")
+    }
+}
+
+impl Arbitrary for Handle {
+    fn arbitrary(g: &mut Gen) -> Handle {
+        let handle = domain(g, ALPHANUMERIC).join(".");
+        Handle::try_create(handle).expect("generated handles are valid")
+    }
+}
+
+impl Arbitrary for Nsid {
+    fn arbitrary(g: &mut Gen) -> Nsid {
+        let mut segments = domain(g, b"abcdefghijklmnopqrstuvwxyz0123456789");
+        segments.reverse();
+        let len = g.range(0, 15);
+        let name = g.string(&ALPHANUMERIC[..52], 1) + &g.string(ALPHANUMERIC, len);
+        segments.push(name);
+        Nsid::try_create(segments.join(".")).expect("generated NSIDs are valid")
+    }
+}
+
+impl Arbitrary for Tid {
+    fn arbitrary(g: &mut Gen) -> Tid {
+        Tid::from_parts(g.next_u64(), g.next_u64() as u16)
+    }
+}
+
+impl Arbitrary for AtUri {
+    fn arbitrary(g: &mut Gen) -> AtUri {
+        let mut uri = match g.one_in(2) {
+            true => format!("at://{}", Did::arbitrary(g)),
+            false => format!("at://{}", Handle::arbitrary(g)),
+        };
+        if !g.one_in(4) {
+            uri.push('/');
+            uri.push_str(Nsid::arbitrary(g).as_str());
+            if !g.one_in(4) {
+                uri.push('/');
+                match g.one_in(2) {
+                    true => uri.push_str(Tid::arbitrary(g).as_str()),
+                    false => {
+                        let len = g.range(3, 20);
+                        uri.push_str(&g.string(b"abcXYZ019._:~-", len))
+                    }
+                }
+            }
+        }
+        AtUri::try_create(uri).expect("generated AT URIs are valid")
+    }
+}
+
+/// Corrupt `valid` with one to three random edits
+///
+/// The result is usually, but not necessarily, invalid
+pub fn mutate(g: &mut Gen, valid: &str) -> String {
+    let mut s = valid.to_string();
+    for _ in 0..g.range(1, 3) {
+        let boundaries: Vec<usize> = (0..=s.len()).filter(|&i| s.is_char_boundary(i)).collect();
+        let at = *g.pick(&boundaries);
+        match g.range(0, 5) {
+            0 => s.truncate(at),
+            1 => {
+                let nasty = *g.pick(NASTY);
+                s.insert_str(at, nasty);
+            }
+            2 => {
+                if let Some(c) = s[at..].chars().next() {
+                    s.replace_range(at..at + c.len_utf8(), "");
+                }
+            }
+            3 => {
+                let copy = s[at..].to_string();
+                s.push_str(&copy);
+            }
+            4 => s = s.repeat(g.range(2, 40)),
+            _ => s = (0..g.range(0, 12)).map(|_| *g.pick(NASTY)).collect(),
+        }
+    }
+    s
+}
+
+/// Run `property` against [`CASES`] generators, reporting the seed of any failing case
+pub fn check(property: impl Fn(&mut Gen)) {
+    let base = std::env::var("ATPROTO_ARBITRARY_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok());
+    let seeds: Vec<u64> = match base {
+        Some(seed) => vec![seed],
+        None => (0..CASES).collect(),
+    };
+    for seed in seeds {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| property(&mut Gen::new(seed))));
+        if let Err(cause) = result {
+            eprintln!("Property failed with ATPROTO_ARBITRARY_SEED={seed}");
+            panic::resume_unwind(cause);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deterministic() {
+        let a: Vec<_> = (0..8).map(|_| Gen::new(7).next_u64()).collect();
+        assert!(a.windows(2).all(|w| w[0] == w[1]));
+        let mut g = Gen::new(7);
+        assert_ne!(g.next_u64(), g.next_u64());
+    }
+
+    #[test]
+    fn mutations_stay_valid_utf8() {
+        check(|g| {
+            let did = Did::arbitrary(g);
+            // Would panic on a bad char boundary
+            let _ = mutate(g, did.as_str()).len();
+        });
+    }
+}
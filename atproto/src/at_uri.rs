@@ -0,0 +1,253 @@
+//! `at://` URIs, referencing repos, collections and records
+//!
+//! The spec is available [here](<https://atproto.com/specs/at-uri-scheme>). Only the
+//! restricted form used to reference records is supported: an authority, then optionally a
+//! collection and record key, with no query or fragment
+
+use crate::handle::Handle;
+use crate::nsid::Nsid;
+use crate::DID::Did;
+use crate::{span, split_with_offsets, within, SelfIndex, ValidationError};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Bound;
+use thiserror::Error;
+
+/// Longest URI allowed
+pub const MAX_LENGTH: usize = 8 * 1024;
+const MAX_RECORD_KEY_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// The repo an `at://` URI refers to
+pub enum Authority {
+    Did(Did),
+    Handle(Handle),
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Authority::Did(did) => write!(f, "{did}"),
+            Authority::Handle(handle) => write!(f, "{handle}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A syntactically valid `at://` URI such as `at://did:plc:abc/app.bsky.feed.post/3k`
+pub struct AtUri {
+    inner: String,
+    authority: Authority,
+    collection: Option<Nsid>,
+    rkey: Option<String>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors in validation of an `at://` URI
+pub enum AtUriValidationError {
+    #[error("URI is longer than {MAX_LENGTH} chars")]
+    TooLong,
+    #[error("Expected a URI starting with at:// - found {found}")]
+    InvalidPrefix { found: String, span: SelfIndex },
+    #[error("URI authority isn't a valid DID or handle - found {found}")]
+    InvalidAuthority { found: String, span: SelfIndex },
+    #[error("URI collection isn't a valid NSID - found {found}")]
+    InvalidCollection { found: String, span: SelfIndex },
+    #[error("URI record key is invalid - found {found}")]
+    InvalidRecordKey { found: String, span: SelfIndex },
+    #[error("URI has path segments after the record key - found {found}")]
+    TrailingSegments { found: String, span: SelfIndex },
+}
+
+impl ValidationError for AtUriValidationError {
+    fn span(&self) -> SelfIndex {
+        use AtUriValidationError::*;
+        match self {
+            TooLong => (Bound::Included(MAX_LENGTH), Bound::Unbounded),
+            InvalidPrefix { span, .. }
+            | InvalidAuthority { span, .. }
+            | InvalidCollection { span, .. }
+            | InvalidRecordKey { span, .. }
+            | TrailingSegments { span, .. } => *span,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        use AtUriValidationError::*;
+        match self {
+            TooLong => "at_uri.too_long",
+            InvalidPrefix { .. } => "at_uri.invalid_prefix",
+            InvalidAuthority { .. } => "at_uri.invalid_authority",
+            InvalidCollection { .. } => "at_uri.invalid_collection",
+            InvalidRecordKey { .. } => "at_uri.invalid_record_key",
+            TrailingSegments { .. } => "at_uri.trailing_segments",
+        }
+    }
+}
+
+fn valid_record_key(rkey: &str) -> bool {
+    (1..=MAX_RECORD_KEY_LENGTH).contains(&rkey.len())
+        && rkey != "."
+        && rkey != ".."
+        && rkey
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"._:~-".contains(&b))
+}
+
+impl AtUri {
+    pub fn try_create(uri: String) -> Result<AtUri, AtUriValidationError> {
+        use AtUriValidationError::*;
+        if uri.len() > MAX_LENGTH {
+            return Err(TooLong);
+        }
+        let Some(rest) = uri.strip_prefix("at://") else {
+            let found: String = uri.chars().take(5).collect();
+            return Err(InvalidPrefix {
+                span: span(0, found.len()),
+                found,
+            });
+        };
+        const PREFIX: usize = "at://".len();
+        let segments: Vec<(usize, &str)> = split_with_offsets(rest, '/')
+            .map(|(i, s)| (i + PREFIX, s))
+            .collect();
+
+        let (start, authority) = segments[0];
+        // Handles can't contain colons so there's no ambiguity. Spans of errors within the
+        // authority are narrowed to the part of it which was invalid
+        let invalid_authority = |inner: SelfIndex| InvalidAuthority {
+            found: authority.to_string(),
+            span: within(inner, start, authority.len()),
+        };
+        let authority = if authority.starts_with("did:") {
+            Did::try_create(authority.to_string())
+                .map(Authority::Did)
+                .map_err(|e| invalid_authority(e.span()))?
+        } else {
+            Handle::try_create(authority.to_string())
+                .map(Authority::Handle)
+                .map_err(|e| invalid_authority(e.span()))?
+        };
+
+        let collection = segments
+            .get(1)
+            .map(|&(start, c)| {
+                Nsid::try_create(c.to_string()).map_err(|e| InvalidCollection {
+                    found: c.to_string(),
+                    span: within(e.span(), start, c.len()),
+                })
+            })
+            .transpose()?;
+        let rkey = segments
+            .get(2)
+            .map(|&(start, r)| match valid_record_key(r) {
+                true => Ok(r.to_string()),
+                false => Err(InvalidRecordKey {
+                    found: r.to_string(),
+                    span: span(start, start + r.len()),
+                }),
+            })
+            .transpose()?;
+        if let Some(&(start, _)) = segments.get(3) {
+            return Err(TrailingSegments {
+                found: uri[start..].to_string(),
+                span: span(start, uri.len()),
+            });
+        }
+        Ok(AtUri {
+            inner: uri,
+            authority,
+            collection,
+            rkey,
+        })
+    }
+
+    pub fn authority(&self) -> &Authority {
+        &self.authority
+    }
+
+    pub fn collection(&self) -> Option<&Nsid> {
+        self.collection.as_ref()
+    }
+
+    pub fn rkey(&self) -> Option<&str> {
+        self.rkey.as_deref()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Display for AtUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{self, Arbitrary, Gen};
+
+    #[test]
+    fn valid() {
+        let uri = AtUri::try_create("at://did:plc:abc/app.bsky.feed.post/3k".to_string()).unwrap();
+        assert!(matches!(uri.authority(), Authority::Did(_)));
+        assert_eq!(
+            uri.collection().map(Nsid::as_str),
+            Some("app.bsky.feed.post")
+        );
+        assert_eq!(uri.rkey(), Some("3k"));
+
+        let uri = AtUri::try_create("at://alice.test".to_string()).unwrap();
+        assert!(matches!(uri.authority(), Authority::Handle(_)));
+        assert_eq!(uri.collection(), None);
+    }
+
+    #[test]
+    fn invalid() {
+        use AtUriValidationError::*;
+        let check = |u: &str| AtUri::try_create(u.to_string()).unwrap_err();
+        assert!(matches!(check("https://alice.test"), InvalidPrefix { .. }));
+        assert!(matches!(check("at://did:key:abc"), InvalidAuthority { .. }));
+        assert!(matches!(
+            check("at://alice.test/post"),
+            InvalidCollection { .. }
+        ));
+        assert!(matches!(
+            check("at://alice.test/app.bsky.feed.post/.."),
+            InvalidRecordKey { .. }
+        ));
+        assert!(matches!(
+            check("at://alice.test/app.bsky.feed.post/"),
+            InvalidRecordKey { .. }
+        ));
+        assert!(matches!(
+            check("at://alice.test/app.bsky.feed.post/a/b"),
+            TrailingSegments { .. }
+        ));
+    }
+
+    #[test]
+    fn error_spans() {
+        let uri = "at://did:plc:a#c/app.bsky.feed.post";
+        let error = AtUri::try_create(uri.to_string()).unwrap_err();
+        assert_eq!(error.code(), "at_uri.invalid_authority");
+        assert_eq!(uri.get(error.span()), Some("#"));
+
+        let uri = "at://alice.test/app.bsky.feed.post/a/b/c";
+        let error = AtUri::try_create(uri.to_string()).unwrap_err();
+        assert_eq!(uri.get(error.span()), Some("b/c"));
+    }
+
+    #[test]
+    fn generated() {
+        arbitrary::check(|g: &mut Gen| {
+            let uri = AtUri::arbitrary(g);
+            assert_eq!(AtUri::try_create(uri.to_string()), Ok(uri.clone()));
+            let _ = AtUri::try_create(arbitrary::mutate(g, uri.as_str()));
+        });
+    }
+}
@@ -0,0 +1,161 @@
+//! Handles, the human-readable DNS names accounts are known by
+//!
+//! The spec is available [here](<https://atproto.com/specs/handle>)
+
+use crate::{span, split_with_offsets, SelfIndex, ValidationError};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Bound;
+use thiserror::Error;
+
+/// Longest handle allowed, matching the limit on DNS names
+pub const MAX_LENGTH: usize = 253;
+const MAX_SEGMENT_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A syntactically valid handle such as `alice.bsky.social`
+pub struct Handle {
+    inner: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors in validation of a handle
+pub enum HandleValidationError {
+    #[error("Handle is longer than {MAX_LENGTH} chars")]
+    TooLong,
+    #[error("Expected a handle of at least two segments - found {found}")]
+    TooFewSegments { found: String },
+    #[error("Handle segment is empty or longer than {MAX_SEGMENT_LENGTH} chars - found {found}")]
+    InvalidSegmentLength { found: String, span: SelfIndex },
+    #[error("Handle segment contains invalid characters - found {found}")]
+    InvalidSegment { found: String, span: SelfIndex },
+    #[error("Handle's top level domain can't start with a digit - found {found}")]
+    InvalidTld { found: String, span: SelfIndex },
+}
+
+impl ValidationError for HandleValidationError {
+    fn span(&self) -> SelfIndex {
+        use HandleValidationError::*;
+        match self {
+            TooLong => (Bound::Included(MAX_LENGTH), Bound::Unbounded),
+            TooFewSegments { .. } => (Bound::Unbounded, Bound::Unbounded),
+            InvalidSegmentLength { span, .. }
+            | InvalidSegment { span, .. }
+            | InvalidTld { span, .. } => *span,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        use HandleValidationError::*;
+        match self {
+            TooLong => "handle.too_long",
+            TooFewSegments { .. } => "handle.too_few_segments",
+            InvalidSegmentLength { .. } => "handle.invalid_segment_length",
+            InvalidSegment { .. } => "handle.invalid_segment",
+            InvalidTld { .. } => "handle.invalid_tld",
+        }
+    }
+}
+
+impl Handle {
+    pub fn try_create(handle: String) -> Result<Handle, HandleValidationError> {
+        use HandleValidationError::*;
+        if handle.len() > MAX_LENGTH {
+            return Err(TooLong);
+        }
+        let segments: Vec<(usize, &str)> = split_with_offsets(&handle, '.').collect();
+        if segments.len() < 2 {
+            return Err(TooFewSegments { found: handle });
+        }
+        for &(start, segment) in &segments {
+            let span = span(start, start + segment.len());
+            if segment.is_empty() || segment.len() > MAX_SEGMENT_LENGTH {
+                return Err(InvalidSegmentLength {
+                    found: segment.to_string(),
+                    span,
+                });
+            }
+            if !segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                || segment.starts_with('-')
+                || segment.ends_with('-')
+            {
+                return Err(InvalidSegment {
+                    found: segment.to_string(),
+                    span,
+                });
+            }
+        }
+        let (start, tld) = segments[segments.len() - 1];
+        if tld.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(InvalidTld {
+                found: tld.to_string(),
+                span: span(start, start + tld.len()),
+            });
+        }
+        Ok(Handle { inner: handle })
+    }
+
+    /// Handles are case-insensitive, so compare and store them lowercased
+    pub fn normalized(&self) -> Handle {
+        Handle {
+            inner: self.inner.to_ascii_lowercase(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{self, Arbitrary, Gen};
+
+    #[test]
+    fn valid() {
+        for handle in ["alice.bsky.social", "XN--ls8h.test", "a.co", "8.cn.example"] {
+            assert!(Handle::try_create(handle.to_string()).is_ok(), "{handle}");
+        }
+        let handle = Handle::try_create("Alice.Test".to_string()).unwrap();
+        assert_eq!(handle.normalized().as_str(), "alice.test");
+    }
+
+    #[test]
+    fn invalid() {
+        use HandleValidationError::*;
+        let check = |h: &str| Handle::try_create(h.to_string()).unwrap_err();
+        assert!(matches!(check("localhost"), TooFewSegments { .. }));
+        assert!(matches!(check("alice..test"), InvalidSegmentLength { .. }));
+        assert!(matches!(check("-alice.test"), InvalidSegment { .. }));
+        assert!(matches!(check("al_ice.test"), InvalidSegment { .. }));
+        assert!(matches!(check("alice.1test"), InvalidTld { .. }));
+        assert_eq!(check(&"a.".repeat(127)), TooLong);
+    }
+
+    #[test]
+    fn error_spans() {
+        let handle = "alice.b_d.test";
+        let error = Handle::try_create(handle.to_string()).unwrap_err();
+        assert_eq!(error.code(), "handle.invalid_segment");
+        assert_eq!(handle.get(error.span()), Some("b_d"));
+    }
+
+    #[test]
+    fn generated() {
+        arbitrary::check(|g: &mut Gen| {
+            let handle = Handle::arbitrary(g);
+            assert_eq!(Handle::try_create(handle.to_string()), Ok(handle.clone()));
+            let _ = Handle::try_create(arbitrary::mutate(g, handle.as_str()));
+        });
+    }
+}
@@ -0,0 +1,182 @@
+//! Interning of DIDs
+//!
+//! The same few million DIDs appear over and over on the firehose. Interning them means each
+//! is allocated once, and an [`InternedDid`] is a pointer that clones, compares and hashes in
+//! constant time, which makes it the cheaper choice of map key on hot paths.
+//!
+//! There is a single process-wide pool, so that pointer equality always agrees with string
+//! equality. Entries live until [`InternedDid::purge`] finds them otherwise unreferenced
+
+use crate::DID::Did;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Number of independently locked parts of the pool, to limit contention between threads
+const SHARDS: usize = 16;
+
+type Shard = RwLock<HashSet<Arc<Did>>>;
+
+fn pool() -> &'static [Shard; SHARDS] {
+    static POOL: OnceLock<[Shard; SHARDS]> = OnceLock::new();
+    POOL.get_or_init(Default::default)
+}
+
+fn shard_for(did: &Did) -> &'static Shard {
+    let mut hasher = DefaultHasher::new();
+    did.hash(&mut hasher);
+    &pool()[hasher.finish() as usize % SHARDS]
+}
+
+#[derive(Clone)]
+/// A DID from the process-wide pool
+///
+/// Equality and hashing use the pointer rather than the string. Ordering still follows the
+/// string so that sorted output is stable
+pub struct InternedDid(Arc<Did>);
+
+impl InternedDid {
+    /// Get the pooled copy of `did`, adding it if this is the first time it's been seen
+    pub fn new(did: &Did) -> InternedDid {
+        let shard = shard_for(did);
+        if let Some(existing) = shard.read().unwrap().get(did) {
+            return InternedDid(existing.clone());
+        }
+        let mut shard = shard.write().unwrap();
+        // Another thread may have added it between dropping the read lock and taking this one
+        match shard.get(did) {
+            Some(existing) => InternedDid(existing.clone()),
+            None => {
+                let did = Arc::new(did.clone());
+                shard.insert(did.clone());
+                InternedDid(did)
+            }
+        }
+    }
+
+    pub fn as_did(&self) -> &Did {
+        &self.0
+    }
+
+    /// Drop pooled DIDs which no [`InternedDid`] refers to any more, returning how many
+    ///
+    /// Meant to be called periodically so the pool doesn't grow without bound
+    pub fn purge() -> usize {
+        let mut purged = 0;
+        for shard in pool() {
+            let mut shard = shard.write().unwrap();
+            let before = shard.len();
+            shard.retain(|did| Arc::strong_count(did) > 1);
+            purged += before - shard.len();
+        }
+        purged
+    }
+
+    /// Number of DIDs currently pooled
+    pub fn pool_size() -> usize {
+        pool().iter().map(|s| s.read().unwrap().len()).sum()
+    }
+}
+
+impl From<&Did> for InternedDid {
+    fn from(did: &Did) -> Self {
+        InternedDid::new(did)
+    }
+}
+
+impl Deref for InternedDid {
+    type Target = Did;
+
+    fn deref(&self) -> &Did {
+        &self.0
+    }
+}
+
+impl PartialEq for InternedDid {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for InternedDid {}
+
+impl Hash for InternedDid {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(Arc::as_ptr(&self.0), state)
+    }
+}
+
+impl PartialOrd for InternedDid {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InternedDid {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl fmt::Debug for InternedDid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for InternedDid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::thread;
+
+    fn did(s: &str) -> Did {
+        Did::try_create(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn shares_one_allocation() {
+        let a = InternedDid::new(&did("did:plc:interned1"));
+        let b = InternedDid::new(&did("did:plc:interned1"));
+        let c = InternedDid::new(&did("did:plc:interned2"));
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a < c);
+
+        let mut counts = HashMap::new();
+        for key in [&a, &b, &c] {
+            *counts.entry(key.clone()).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&a], 2);
+        assert_eq!(a.identifier(), "interned1");
+    }
+
+    #[test]
+    fn purge_drops_unreferenced() {
+        let kept = InternedDid::new(&did("did:plc:purgekept"));
+        drop(InternedDid::new(&did("did:plc:purgedropped")));
+        InternedDid::purge();
+        let shard = shard_for(&kept).read().unwrap();
+        assert!(shard.contains(kept.as_did()));
+        assert!(!shard.contains(&did("did:plc:purgedropped")));
+    }
+
+    #[test]
+    fn concurrent_interning_agrees() {
+        let interned: Vec<_> = (0..8)
+            .map(|_| thread::spawn(|| InternedDid::new(&did("did:plc:concurrent"))))
+            .map(|t| t.join().unwrap())
+            .collect();
+        assert!(interned.windows(2).all(|w| w[0] == w[1]));
+    }
+}
@@ -1,4 +1,72 @@
+//! The identifiers atproto is built on: DIDs, handles, NSIDs, TIDs and `at://` URIs, each
+//! validated as it's created
+//!
+//! Turning off the default `std` feature builds the identifier types with only `alloc`, for
+//! constrained consumers which want them without the rest of the bridge. The process-wide
+//! `intern` pool needs `std`
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use core::ops::Bound;
+
+#[allow(non_snake_case)]
 pub mod DID;
+pub mod at_uri;
+pub mod handle;
+#[cfg(feature = "std")]
+pub mod intern;
+pub mod macros;
+pub mod nsid;
+pub mod tid;
+
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod arbitrary;
+
+/// A byte range into a string being validated, usable with [`str::get`]
+pub type SelfIndex = (Bound<usize>, Bound<usize>);
+
+/// Common interface to the identifier validation errors
+///
+/// Lets logs and the admin UI point at the invalid part of an identifier without matching on
+/// each error type
+pub trait ValidationError: core::error::Error {
+    /// The part of the input which was invalid
+    fn span(&self) -> SelfIndex;
+    /// A stable, machine-readable identifier for the kind of error, e.g. `did.invalid_method`
+    fn code(&self) -> &'static str;
+}
+
+/// The span `start..end`
+pub(crate) fn span(start: usize, end: usize) -> SelfIndex {
+    (Bound::Included(start), Bound::Excluded(end))
+}
+
+/// Translate a span within the substring at `start..start + len` to one within the whole
+/// string, for errors found by validating part of the input
+pub(crate) fn within(inner: SelfIndex, start: usize, len: usize) -> SelfIndex {
+    let from = match inner.0 {
+        Bound::Included(i) => Bound::Included(start + i),
+        Bound::Excluded(i) => Bound::Excluded(start + i),
+        Bound::Unbounded => Bound::Included(start),
+    };
+    let to = match inner.1 {
+        Bound::Included(i) => Bound::Included(start + i),
+        Bound::Excluded(i) => Bound::Excluded(start + i),
+        Bound::Unbounded => Bound::Excluded(start + len),
+    };
+    (from, to)
+}
+
+/// Split `s` on `sep`, yielding each piece with its byte offset in `s`
+pub(crate) fn split_with_offsets(s: &str, sep: char) -> impl Iterator<Item = (usize, &str)> {
+    let mut offset = 0;
+    s.split(sep).map(move |piece| {
+        let start = offset;
+        offset += piece.len() + sep.len_utf8();
+        (start, piece)
+    })
+}
 
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
@@ -0,0 +1,271 @@
+//! Compile-time validated identifier literals
+//!
+//! [`did!`](crate::did) and [`at_uri!`](crate::at_uri) check their literal during constant
+//! evaluation, so a typo in a configuration constant or test fails the build instead of
+//! panicking at runtime.
+//!
+//! The checks are `const fn` restatements of the runtime parsers, which remain the source of
+//! truth (and the only place with detailed errors). The two are kept in agreement by property
+//! tests
+
+const MAX_HANDLE_LENGTH: usize = 253;
+const MAX_NSID_LENGTH: usize = 317;
+const MAX_NSID_AUTHORITY_LENGTH: usize = 253;
+const MAX_AT_URI_LENGTH: usize = 8 * 1024;
+const MAX_SEGMENT_LENGTH: usize = 63;
+const MAX_RECORD_KEY_LENGTH: usize = 512;
+
+/// A compile-time validated [`Did`](crate::DID::Did) constant
+///
+/// ```
+/// const BRIDGE: atproto::DID::Did = atproto::did!("did:web:bridge.example");
+/// assert_eq!(BRIDGE.identifier(), "bridge.example");
+/// ```
+///
+/// ```compile_fail
+/// const TYPO: atproto::DID::Did = atproto::did!("did:pcl:abc");
+/// ```
+#[macro_export]
+macro_rules! did {
+    ($did:literal) => {{
+        const DID: $crate::DID::Did = $crate::DID::Did::from_static($did);
+        DID
+    }};
+}
+
+/// A compile-time validated [`AtUri`](crate::at_uri::AtUri)
+///
+/// The literal is checked while compiling, but as an `AtUri` holds its parsed components
+/// the value itself is built at runtime, so this can't initialise a `const`
+///
+/// ```
+/// let uri = atproto::at_uri!("at://alice.test/app.bsky.feed.post/3k");
+/// assert_eq!(uri.rkey(), Some("3k"));
+/// ```
+///
+/// ```compile_fail
+/// let uri = atproto::at_uri!("at://alice.test/app.bsky.feed.post/a/b");
+/// ```
+#[macro_export]
+macro_rules! at_uri {
+    ($uri:literal) => {{
+        const _: () = assert!(
+            $crate::macros::valid_at_uri($uri),
+            concat!("Invalid AT URI literal: ", $uri)
+        );
+        $crate::at_uri::AtUri::try_create($uri.into())
+            .expect("AT URI literal is validated at compile time")
+    }};
+}
+
+const fn starts_with(b: &[u8], prefix: &[u8], at: usize) -> bool {
+    if b.len() < at + prefix.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < prefix.len() {
+        if b[at + i] != prefix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Index of the next `sep` at or after `from`, or `end` if there isn't one
+const fn find(b: &[u8], sep: u8, from: usize, end: usize) -> usize {
+    let mut i = from;
+    while i < end && b[i] != sep {
+        i += 1;
+    }
+    i
+}
+
+const fn valid_did_range(b: &[u8], start: usize, end: usize) -> bool {
+    if end - start <= 8 || !starts_with(b, b"did:", start) {
+        return false;
+    }
+    if !starts_with(b, b"web:", start + 4) && !starts_with(b, b"plc:", start + 4) {
+        return false;
+    }
+    let mut i = start + 8;
+    while i < end {
+        let c = b[i];
+        let percent_encoded = c == b'%'
+            && i + 2 < end
+            && b[i + 1].is_ascii_hexdigit()
+            && b[i + 2].is_ascii_hexdigit();
+        if !(percent_encoded || c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b':' | b'-'))
+        {
+            return false;
+        }
+        i += 1;
+    }
+    b[end - 1] != b':'
+}
+
+/// A DNS label of letters, digits and inner hyphens
+const fn valid_label(b: &[u8], start: usize, end: usize) -> bool {
+    if end == start || end - start > MAX_SEGMENT_LENGTH {
+        return false;
+    }
+    if b[start] == b'-' || b[end - 1] == b'-' {
+        return false;
+    }
+    let mut i = start;
+    while i < end {
+        if !(b[i].is_ascii_alphanumeric() || b[i] == b'-') {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn valid_handle_range(b: &[u8], start: usize, end: usize) -> bool {
+    if end - start > MAX_HANDLE_LENGTH {
+        return false;
+    }
+    let mut segment = start;
+    let mut segments = 0;
+    loop {
+        let next = find(b, b'.', segment, end);
+        if !valid_label(b, segment, next) {
+            return false;
+        }
+        segments += 1;
+        if next == end {
+            // The top level domain can't start with a digit
+            return segments >= 2 && !b[segment].is_ascii_digit();
+        }
+        segment = next + 1;
+    }
+}
+
+const fn valid_nsid_range(b: &[u8], start: usize, end: usize) -> bool {
+    if end - start > MAX_NSID_LENGTH {
+        return false;
+    }
+    let mut segment = start;
+    let mut segments = 0;
+    loop {
+        let next = find(b, b'.', segment, end);
+        segments += 1;
+        if next == end {
+            break;
+        }
+        // The first segment is the top level domain
+        if !valid_label(b, segment, next) || (segments == 1 && b[segment].is_ascii_digit()) {
+            return false;
+        }
+        segment = next + 1;
+    }
+    // `segment` is now the start of the name
+    if segments < 3 || segment - 1 - start > MAX_NSID_AUTHORITY_LENGTH {
+        return false;
+    }
+    if segment == end || end - segment > MAX_SEGMENT_LENGTH || !b[segment].is_ascii_alphabetic() {
+        return false;
+    }
+    let mut i = segment;
+    while i < end {
+        if !b[i].is_ascii_alphanumeric() {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn valid_record_key_range(b: &[u8], start: usize, end: usize) -> bool {
+    let len = end - start;
+    if len == 0 || len > MAX_RECORD_KEY_LENGTH {
+        return false;
+    }
+    if starts_with(b, b"..", start) && len == 2 || b[start] == b'.' && len == 1 {
+        return false;
+    }
+    let mut i = start;
+    while i < end {
+        let c = b[i];
+        if !(c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b':' | b'~' | b'-')) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[doc(hidden)]
+pub const fn valid_did(did: &str) -> bool {
+    valid_did_range(did.as_bytes(), 0, did.len())
+}
+
+#[doc(hidden)]
+pub const fn valid_at_uri(uri: &str) -> bool {
+    let b = uri.as_bytes();
+    if b.len() > MAX_AT_URI_LENGTH || !starts_with(b, b"at://", 0) {
+        return false;
+    }
+    let authority_end = find(b, b'/', 5, b.len());
+    let authority_valid = if starts_with(b, b"did:", 5) {
+        valid_did_range(b, 5, authority_end)
+    } else {
+        valid_handle_range(b, 5, authority_end)
+    };
+    if !authority_valid {
+        return false;
+    }
+    if authority_end == b.len() {
+        return true;
+    }
+    let collection_end = find(b, b'/', authority_end + 1, b.len());
+    if !valid_nsid_range(b, authority_end + 1, collection_end) {
+        return false;
+    }
+    if collection_end == b.len() {
+        return true;
+    }
+    let rkey_end = find(b, b'/', collection_end + 1, b.len());
+    rkey_end == b.len() && valid_record_key_range(b, collection_end + 1, rkey_end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{self, Arbitrary, Gen};
+    use crate::at_uri::AtUri;
+    use crate::DID::Did;
+
+    #[test]
+    fn literals() {
+        const DID: Did = crate::did!("did:plc:z72i7hdynmk6r22z27h6tvur");
+        assert_eq!(DID, Did::try_create(DID.to_string()).unwrap());
+        let uri = crate::at_uri!("at://did:plc:abc/app.bsky.feed.post/3k");
+        assert_eq!(
+            uri.collection().map(|c| c.as_str()),
+            Some("app.bsky.feed.post")
+        );
+    }
+
+    #[test]
+    fn agrees_with_parsers() {
+        arbitrary::check(|g: &mut Gen| {
+            let did = Did::arbitrary(g);
+            let did = arbitrary::mutate(g, did.as_str());
+            assert_eq!(
+                valid_did(&did),
+                Did::try_create(did.clone()).is_ok(),
+                "{did:?}"
+            );
+            let uri = AtUri::arbitrary(g);
+            assert!(valid_at_uri(uri.as_str()), "{uri}");
+            let uri = arbitrary::mutate(g, uri.as_str());
+            assert_eq!(
+                valid_at_uri(&uri),
+                AtUri::try_create(uri.clone()).is_ok(),
+                "{uri:?}"
+            );
+        });
+    }
+}
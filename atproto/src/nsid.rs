@@ -0,0 +1,185 @@
+//! Namespaced Identifiers, naming Lexicon schemas such as `app.bsky.feed.post`
+//!
+//! The spec is available [here](<https://atproto.com/specs/nsid>)
+
+use crate::{span, split_with_offsets, SelfIndex, ValidationError};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Bound;
+use thiserror::Error;
+
+/// Longest NSID allowed
+pub const MAX_LENGTH: usize = 317;
+const MAX_AUTHORITY_LENGTH: usize = 253;
+const MAX_SEGMENT_LENGTH: usize = 63;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A syntactically valid NSID
+///
+/// This is a reversed domain name (the authority) followed by a name, so `app.bsky.feed.post`
+/// is the `post` schema under `feed.bsky.app`
+pub struct Nsid {
+    inner: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors in validation of an NSID
+pub enum NsidValidationError {
+    #[error("NSID is longer than {MAX_LENGTH} chars")]
+    TooLong,
+    #[error("Expected an NSID of at least three segments - found {found}")]
+    TooFewSegments { found: String },
+    #[error("NSID authority is longer than {MAX_AUTHORITY_LENGTH} chars")]
+    AuthorityTooLong,
+    #[error("NSID segment is empty or longer than {MAX_SEGMENT_LENGTH} chars - found {found}")]
+    InvalidSegmentLength { found: String, span: SelfIndex },
+    #[error("NSID authority segment contains invalid characters - found {found}")]
+    InvalidAuthoritySegment { found: String, span: SelfIndex },
+    #[error("NSID name must be letters and digits, starting with a letter - found {found}")]
+    InvalidName { found: String, span: SelfIndex },
+}
+
+impl ValidationError for NsidValidationError {
+    fn span(&self) -> SelfIndex {
+        use NsidValidationError::*;
+        match self {
+            TooLong => (Bound::Included(MAX_LENGTH), Bound::Unbounded),
+            TooFewSegments { .. } => (Bound::Unbounded, Bound::Unbounded),
+            AuthorityTooLong => span(0, MAX_AUTHORITY_LENGTH),
+            InvalidSegmentLength { span, .. }
+            | InvalidAuthoritySegment { span, .. }
+            | InvalidName { span, .. } => *span,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        use NsidValidationError::*;
+        match self {
+            TooLong => "nsid.too_long",
+            TooFewSegments { .. } => "nsid.too_few_segments",
+            AuthorityTooLong => "nsid.authority_too_long",
+            InvalidSegmentLength { .. } => "nsid.invalid_segment_length",
+            InvalidAuthoritySegment { .. } => "nsid.invalid_authority_segment",
+            InvalidName { .. } => "nsid.invalid_name",
+        }
+    }
+}
+
+impl Nsid {
+    pub fn try_create(nsid: String) -> Result<Nsid, NsidValidationError> {
+        use NsidValidationError::*;
+        if nsid.len() > MAX_LENGTH {
+            return Err(TooLong);
+        }
+        let segments: Vec<(usize, &str)> = split_with_offsets(&nsid, '.').collect();
+        if segments.len() < 3 {
+            return Err(TooFewSegments { found: nsid });
+        }
+        let (&(name_start, name), authority) =
+            segments.split_last().expect("at least three segments");
+        if name_start - 1 > MAX_AUTHORITY_LENGTH {
+            return Err(AuthorityTooLong);
+        }
+        let span_of = |start: usize, segment: &str| span(start, start + segment.len());
+        for &(start, segment) in &segments {
+            if segment.is_empty() || segment.len() > MAX_SEGMENT_LENGTH {
+                return Err(InvalidSegmentLength {
+                    found: segment.to_string(),
+                    span: span_of(start, segment),
+                });
+            }
+        }
+        for &(start, segment) in authority {
+            if !segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                || segment.starts_with('-')
+                || segment.ends_with('-')
+                // The first segment is the top level domain
+                || (start == 0 && segment.starts_with(|c: char| c.is_ascii_digit()))
+            {
+                return Err(InvalidAuthoritySegment {
+                    found: segment.to_string(),
+                    span: span_of(start, segment),
+                });
+            }
+        }
+        if !name.bytes().all(|b| b.is_ascii_alphanumeric())
+            || !name.starts_with(|c: char| c.is_ascii_alphabetic())
+        {
+            return Err(InvalidName {
+                found: name.to_string(),
+                span: span_of(name_start, name),
+            });
+        }
+        Ok(Nsid { inner: nsid })
+    }
+
+    /// The authority as a domain name, e.g. `feed.bsky.app` for `app.bsky.feed.post`
+    pub fn authority(&self) -> String {
+        let (authority, _) = self.inner.rsplit_once('.').expect("validated NSID");
+        authority.rsplit('.').collect::<Vec<_>>().join(".")
+    }
+
+    /// The final segment, e.g. `post` for `app.bsky.feed.post`
+    pub fn name(&self) -> &str {
+        let (_, name) = self.inner.rsplit_once('.').expect("validated NSID");
+        name
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Display for Nsid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{self, Arbitrary, Gen};
+
+    #[test]
+    fn valid() {
+        let nsid = Nsid::try_create("app.bsky.feed.post".to_string()).unwrap();
+        assert_eq!(nsid.authority(), "feed.bsky.app");
+        assert_eq!(nsid.name(), "post");
+        assert!(Nsid::try_create("com.example.fooBar2".to_string()).is_ok());
+    }
+
+    #[test]
+    fn invalid() {
+        use NsidValidationError::*;
+        let check = |n: &str| Nsid::try_create(n.to_string()).unwrap_err();
+        assert!(matches!(check("app.bsky"), TooFewSegments { .. }));
+        assert!(matches!(check("app..post"), InvalidSegmentLength { .. }));
+        assert!(matches!(
+            check("1app.bsky.post"),
+            InvalidAuthoritySegment { .. }
+        ));
+        assert!(matches!(check("app.bsky.feed.2post"), InvalidName { .. }));
+        assert!(matches!(check("app.bsky.feed.po-st"), InvalidName { .. }));
+    }
+
+    #[test]
+    fn error_spans() {
+        let nsid = "app.bsky.feed.2post";
+        let error = Nsid::try_create(nsid.to_string()).unwrap_err();
+        assert_eq!(error.code(), "nsid.invalid_name");
+        assert_eq!(nsid.get(error.span()), Some("2post"));
+    }
+
+    #[test]
+    fn generated() {
+        arbitrary::check(|g: &mut Gen| {
+            let nsid = Nsid::arbitrary(g);
+            assert_eq!(Nsid::try_create(nsid.to_string()), Ok(nsid.clone()));
+            let _ = Nsid::try_create(arbitrary::mutate(g, nsid.as_str()));
+        });
+    }
+}
@@ -0,0 +1,170 @@
+//! Timestamp Identifiers, the sortable record keys used for most records
+//!
+//! The spec is available [here](<https://atproto.com/specs/tid>)
+
+use crate::{span, SelfIndex, ValidationError};
+use alloc::string::String;
+use core::fmt;
+use core::ops::Bound;
+use thiserror::Error;
+
+/// Every TID is exactly this long
+pub const LENGTH: usize = 13;
+const ALPHABET: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+const CLOCK_ID_BITS: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A syntactically valid TID
+///
+/// A TID encodes a 64-bit integer - microseconds since the UNIX epoch in the upper bits and a
+/// 10 bit clock identifier in the lower - in base32 such that sorting the strings sorts by time
+pub struct Tid {
+    inner: String,
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors in validation of a TID
+pub enum TidValidationError {
+    #[error("Expected a TID of exactly {LENGTH} chars - found {found}")]
+    InvalidLength { found: String },
+    #[error("TID contains chars outside the base32-sortable alphabet - found {found}")]
+    /// `span` covers the first offending char
+    InvalidChars { found: String, span: SelfIndex },
+    #[error("TID's first char must be one of 234567abcdefghij - found {found}")]
+    HighBitSet { found: String },
+}
+
+impl ValidationError for TidValidationError {
+    fn span(&self) -> SelfIndex {
+        use TidValidationError::*;
+        match self {
+            InvalidLength { .. } => (Bound::Unbounded, Bound::Unbounded),
+            InvalidChars { span, .. } => *span,
+            HighBitSet { .. } => span(0, 1),
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        use TidValidationError::*;
+        match self {
+            InvalidLength { .. } => "tid.invalid_length",
+            InvalidChars { .. } => "tid.invalid_chars",
+            HighBitSet { .. } => "tid.high_bit_set",
+        }
+    }
+}
+
+fn decode_char(c: u8) -> Option<u64> {
+    ALPHABET.iter().position(|&a| a == c).map(|p| p as u64)
+}
+
+impl Tid {
+    pub fn try_create(tid: String) -> Result<Tid, TidValidationError> {
+        use TidValidationError::*;
+        if tid.len() != LENGTH {
+            return Err(InvalidLength { found: tid });
+        }
+        if let Some((i, c)) = tid
+            .char_indices()
+            .find(|&(_, c)| !c.is_ascii() || decode_char(c as u8).is_none())
+        {
+            return Err(InvalidChars {
+                span: span(i, i + c.len_utf8()),
+                found: tid,
+            });
+        }
+        if decode_char(tid.as_bytes()[0]).is_some_and(|v| v >= 16) {
+            return Err(HighBitSet { found: tid });
+        }
+        Ok(Tid { inner: tid })
+    }
+
+    /// Build a TID from a timestamp and clock identifier, truncating each to fit
+    pub fn from_parts(timestamp_micros: u64, clock_id: u16) -> Tid {
+        let micros = timestamp_micros & ((1 << 53) - 1);
+        let clock_id = u64::from(clock_id) & ((1 << CLOCK_ID_BITS) - 1);
+        Tid::from_u64((micros << CLOCK_ID_BITS) | clock_id)
+    }
+
+    fn from_u64(value: u64) -> Tid {
+        let inner = (0..LENGTH)
+            .rev()
+            .map(|i| ALPHABET[((value >> (5 * i)) & 31) as usize] as char)
+            .collect();
+        Tid { inner }
+    }
+
+    /// The integer this TID encodes
+    pub fn as_u64(&self) -> u64 {
+        self.inner.bytes().fold(0, |acc, b| {
+            (acc << 5) | decode_char(b).expect("validated TID")
+        })
+    }
+
+    /// Microseconds since the UNIX epoch
+    pub fn timestamp_micros(&self) -> u64 {
+        (self.as_u64() >> CLOCK_ID_BITS) & ((1 << 53) - 1)
+    }
+
+    pub fn clock_id(&self) -> u16 {
+        (self.as_u64() & ((1 << CLOCK_ID_BITS) - 1)) as u16
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.inner
+    }
+}
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arbitrary::{self, Arbitrary, Gen};
+
+    #[test]
+    fn valid() {
+        let tid = Tid::try_create("3jzfcijpj2z2a".to_string()).unwrap();
+        assert_eq!(Tid::from_parts(tid.timestamp_micros(), tid.clock_id()), tid);
+        assert_eq!(Tid::from_parts(0, 0).as_str(), "2222222222222");
+    }
+
+    #[test]
+    fn invalid() {
+        use TidValidationError::*;
+        let check = |t: &str| Tid::try_create(t.to_string()).unwrap_err();
+        assert!(matches!(check("3jzfcijpj2z2"), InvalidLength { .. }));
+        assert!(matches!(check("3jzfcijpj2z21"), InvalidChars { .. }));
+        assert!(matches!(check("3JZFCIJPJ2Z2A"), InvalidChars { .. }));
+        assert!(matches!(check("zjzfcijpj2z2a"), HighBitSet { .. }));
+    }
+
+    #[test]
+    fn error_spans() {
+        let tid = "3jzfcijé2z2a";
+        let error = Tid::try_create(tid.to_string()).unwrap_err();
+        assert_eq!(error.code(), "tid.invalid_chars");
+        assert_eq!(tid.get(error.span()), Some("é"));
+    }
+
+    #[test]
+    fn sorts_by_time() {
+        let earlier = Tid::from_parts(1_700_000_000_000_000, 1023);
+        let later = Tid::from_parts(1_700_000_000_000_001, 0);
+        assert!(earlier < later);
+    }
+
+    #[test]
+    fn generated() {
+        arbitrary::check(|g: &mut Gen| {
+            let tid = Tid::arbitrary(g);
+            assert_eq!(Tid::try_create(tid.to_string()), Ok(tid.clone()));
+            assert_eq!(Tid::from_parts(tid.timestamp_micros(), tid.clock_id()), tid);
+            let _ = Tid::try_create(arbitrary::mutate(g, tid.as_str()));
+        });
+    }
+}
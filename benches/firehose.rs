@@ -0,0 +1,107 @@
+//! Firehose ingestion throughput
+//!
+//! Compares decoding every record in each commit against reading only the headers and
+//! decoding the records a filter wants, which is what the bridge does. The commits are
+//! typical of the firehose: mostly likes and follows, with the odd post
+
+use atproto::DID::Did;
+use fedibridge::firehose::{encode_commit, Action, Frame, Operation};
+use fedibridge::json::{self, Value};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const FRAMES: usize = 2_000;
+const ROUNDS: u32 = 5;
+
+fn frames() -> Vec<Vec<u8>> {
+    let record = |json: String| Some(json::parse(&json).unwrap());
+    (0..FRAMES)
+        .map(|n| {
+            let repo = Did::try_create(format!("did:plc:user{n}")).unwrap();
+            let subject = format!(
+                r#"{{"uri": "at://did:plc:other/app.bsky.feed.post/{n}", "cid": "bafyrei{n}"}}"#
+            );
+            let mut ops: Vec<(Operation, Option<Value>)> = (0..8)
+                .map(|i| {
+                    let like = format!(
+                        r#"{{"$type": "app.bsky.feed.like", "subject": {subject}, "createdAt": "2024-05-01T12:00:0{i}Z"}}"#
+                    );
+                    (op(&format!("app.bsky.feed.like/{n}{i}")), record(like))
+                })
+                .collect();
+            let follow = r#"{"$type": "app.bsky.graph.follow", "subject": "did:plc:other", "createdAt": "2024-05-01T12:00:00Z"}"#;
+            ops.push((op(&format!("app.bsky.graph.follow/{n}")), record(follow.to_string())));
+            if n % 4 == 0 {
+                let post = format!(
+                    r#"{{"$type": "app.bsky.feed.post", "text": "Post number {n}, with a little text in it", "langs": ["en"], "createdAt": "2024-05-01T12:00:00Z"}}"#
+                );
+                ops.push((op(&format!("app.bsky.feed.post/{n}")), record(post)));
+            }
+            encode_commit(n as i64, &repo, &ops)
+        })
+        .collect()
+}
+
+fn op(path: &str) -> Operation {
+    Operation {
+        action: Action::Create,
+        path: path.to_string(),
+    }
+}
+
+/// The best of a few rounds of `ingest` over every frame
+fn time(frames: &[Vec<u8>], ingest: impl Fn(&[u8]) -> Vec<Value>) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            for frame in frames {
+                black_box(ingest(frame));
+            }
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let frames = frames();
+    let bytes: usize = frames.iter().map(Vec::len).sum();
+
+    let everything = time(&frames, |message| {
+        let Ok(Frame::Commit(commit)) = Frame::parse(message) else {
+            return Vec::new();
+        };
+        let header = commit.header();
+        header
+            .ops
+            .iter()
+            .filter_map(|op| commit.record(&op.path).ok().flatten())
+            .map(|record| record.to_json())
+            .collect()
+    });
+    let wanted = time(&frames, |message| {
+        let Ok(Frame::Commit(commit)) = Frame::parse(message) else {
+            return Vec::new();
+        };
+        let header = commit.header();
+        header
+            .ops
+            .iter()
+            .filter(|op| op.collection() == "app.bsky.feed.post")
+            .filter_map(|op| commit.record(&op.path).ok().flatten())
+            .map(|record| record.to_json())
+            .collect()
+    });
+
+    println!("{FRAMES} commits, {bytes} bytes");
+    for (name, elapsed) in [("all records", everything), ("wanted records", wanted)] {
+        let per_second = FRAMES as f64 / elapsed.as_secs_f64();
+        let mib_per_second = bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+        println!("{name:>16}: {elapsed:>10.2?}  {per_second:>10.0} commits/s  {mib_per_second:>7.1} MiB/s");
+    }
+    println!(
+        "{:>16}: {:.1}x",
+        "speedup",
+        everything.as_secs_f64() / wanted.as_secs_f64()
+    );
+}
@@ -0,0 +1,49 @@
+/*
+ * fedibridge's C ABI, built with the `ffi` feature. See src/ffi.rs for the envelope each
+ * function returns and the error codes in it.
+ */
+
+#ifndef FEDIBRIDGE_H
+#define FEDIBRIDGE_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A bridge for resolving identities */
+typedef struct FedibridgeBridge FedibridgeBridge;
+
+/*
+ * Sends the JSON request record `request`, returning a response record which stays valid
+ * until the callback is next called on the same thread, or NULL if it couldn't be sent at all.
+ * It may be called from any thread.
+ */
+typedef const char *(*FedibridgeFetch)(void *context, const char *request);
+
+/* The library's version, which isn't freed */
+const char *fedibridge_version(void);
+
+/* Free a string returned by any other function */
+void fedibridge_string_free(char *s);
+
+/* A bridge fetching through `fetch` with `context`, or the platform's transport if NULL */
+FedibridgeBridge *fedibridge_bridge_new(FedibridgeFetch fetch, void *context);
+void fedibridge_bridge_free(FedibridgeBridge *bridge);
+
+char *fedibridge_parse_did(const char *did);
+char *fedibridge_parse_handle(const char *handle);
+
+char *fedibridge_resolve_did(const FedibridgeBridge *bridge, const char *did);
+char *fedibridge_resolve_handle(const FedibridgeBridge *bridge, const char *handle);
+
+/* `bridge` may be NULL, leaving mentions as links */
+char *fedibridge_html_to_rich_text(const FedibridgeBridge *bridge, const char *html);
+char *fedibridge_article_to_post(const char *object, size_t max_length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,448 @@
+//! The self-service API, where bridged people check on their own bridging
+//!
+//! | Method | Path                   | Action                                         |
+//! |--------|------------------------|------------------------------------------------|
+//! | GET    | `/account`             | The mapping, last bridged posts and deliveries |
+//! | PUT    | `/account/preferences` | Change preferences                             |
+//! | GET    | `/account/consent`     | Which terms were agreed to, and the current    |
+//! | POST   | `/account/consent`     | Agree to the current terms                     |
+//! | GET    | `/account/handle`      | What a `domain` needs to become the handle     |
+//! | POST   | `/account/handle`      | Switch to the `domain` in the body             |
+//! | GET    | `/account/receipts`    | Where one of their `post`s was delivered       |
+//! | POST   | `/account/lists`       | Import a Mastodon CSV export as Bluesky lists  |
+//!
+//! Each request must prove which bridged account it's from to one of the endpoints'
+//! [`Authenticator`]s. Fediverse accounts can sign their requests, checked by
+//! [`SignedByActor`] on a bridge with a [verifier](crate::signatures::SignatureVerifier) to
+//! check them with; other schemes, such as atproto service auth tokens, need asymmetric
+//! signatures checked by a backend. Requests nobody vouches for are refused with a 401
+//!
+//! Agreeing to the terms names the `version` in the body, so nobody agrees to terms which
+//! changed after they read them: an outdated version is refused with a 409
+//!
+//! A domain becomes the handle once it names the account, as [`handles::switch`]
+//! checks: until then switching is refused with a 400, and a domain another account already
+//! has with a 409
+//!
+//! The last bridged posts come from the [audit log](crate::audit), and where posts were
+//! delivered from [their receipts](crate::receipts), so both are only as old as retention
+//! allows. A `host` narrows receipts down to one instance's inboxes
+//!
+//! Importing [lists and follows](crate::lists) takes the export as the body, and a `name` for
+//! the list if it's of follows. Lists with nobody on Bluesky are left out, and if that's all
+//! of them the import is refused with a 400
+
+use crate::audit::AuditQuery;
+use crate::bridge::Bridge;
+use crate::consent::{self, ConsentError};
+use crate::delivery;
+use crate::digest::Network;
+use crate::handles::{self, HandleError};
+use crate::http::{Handler, Method, Request, Response};
+use crate::jobs::{Job, QueuedJob};
+use crate::json::{self, Value};
+use crate::lists::{self, ListError};
+use crate::receipts;
+use crate::repo::RepoError;
+use crate::signatures::{self, SignatureError};
+use crate::store::{Mapping, MappingStatus};
+use atproto::DID::Did;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Works out which bridged account a request is from
+pub trait Authenticator: Send + Sync {
+    /// The DID of the bridged account `request` proves it's from, if it does
+    fn authenticate(&self, bridge: &Bridge, request: &Request) -> Option<Did>;
+}
+
+/// Authenticates requests with an HTTP signature by a bridged fediverse account
+pub struct SignedByActor(());
+
+impl SignedByActor {
+    /// Signatures checked by `bridge`'s verifier. Without one none could be, so there's no
+    /// authenticating this way
+    pub fn new(bridge: &Bridge) -> Result<SignedByActor, SignatureError> {
+        match bridge.signature_verifier {
+            Some(_) => Ok(SignedByActor(())),
+            None => Err(SignatureError::NoVerifier),
+        }
+    }
+}
+
+impl Authenticator for SignedByActor {
+    fn authenticate(&self, bridge: &Bridge, request: &Request) -> Option<Did> {
+        let actor = signatures::verify(bridge, request).ok()?;
+        Some(bridge.identities.get_by_actor(&actor)?.did)
+    }
+}
+
+/// The self-service endpoints, in front of `inner`
+pub struct AccountEndpoints<H> {
+    bridge: Arc<Bridge>,
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    inner: H,
+}
+
+impl<H: Handler> AccountEndpoints<H> {
+    /// Endpoints no request can authenticate to until an authenticator is added
+    pub fn new(bridge: Arc<Bridge>, inner: H) -> AccountEndpoints<H> {
+        AccountEndpoints {
+            bridge,
+            authenticators: Vec::new(),
+            inner,
+        }
+    }
+
+    pub fn with_authenticator(
+        mut self,
+        authenticator: Arc<dyn Authenticator>,
+    ) -> AccountEndpoints<H> {
+        self.authenticators.push(authenticator);
+        self
+    }
+
+    /// The bridged account making `request`
+    fn authenticated(&self, request: &Request) -> Result<Mapping, Response> {
+        let unauthorized = || Response::error(401, "Not authenticated as a bridged account");
+        let did = self
+            .authenticators
+            .iter()
+            .find_map(|authenticator| authenticator.authenticate(&self.bridge, request))
+            .ok_or_else(unauthorized)?;
+        match self.bridge.identities.get(&did) {
+            Some(mapping) if mapping.status != MappingStatus::Passive => Ok(mapping),
+            _ => Err(unauthorized()),
+        }
+    }
+
+    /// The last post bridged from `mapping`'s account onto `network`
+    fn last_bridged(&self, mapping: &Mapping, network: Network) -> Value {
+        let actor = match network {
+            Network::Fediverse => mapping.actor.clone(),
+            Network::Bluesky => mapping.did.as_str().to_string(),
+        };
+        let query = AuditQuery {
+            network: Some(network),
+            actor: Some(actor),
+            ..AuditQuery::default()
+        };
+        let records = self.bridge.audit.query(&query);
+        let last = records.iter().find(|record| record.is_post());
+        last.map_or(Value::Null, |record| record.to_json())
+    }
+
+    fn status(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let theirs = |job: &QueuedJob| match &job.job {
+            Job::Deliver(delivery) => delivery.actor().as_deref() == Some(mapping.actor.as_str()),
+            _ => false,
+        };
+        let deliveries = |jobs: Vec<QueuedJob>| {
+            let jobs = jobs.iter().filter(|job| theirs(job));
+            Value::Array(jobs.filter_map(delivery::queued_json).collect())
+        };
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("mapping", mapping.to_json()),
+                (
+                    "consent",
+                    Value::from(self.bridge.consent_state(&mapping.did).as_str()),
+                ),
+                (
+                    "lastBridged",
+                    Value::object([
+                        ("fediverse", self.last_bridged(&mapping, Network::Fediverse)),
+                        ("bluesky", self.last_bridged(&mapping, Network::Bluesky)),
+                    ]),
+                ),
+                (
+                    "deliveries",
+                    Value::object([
+                        ("pending", deliveries(self.bridge.jobs.queued())),
+                        ("failed", deliveries(self.bridge.jobs.dead())),
+                    ]),
+                ),
+            ]),
+        ))
+    }
+
+    fn consent(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        Ok(Response::json(
+            200,
+            &consent::status_json(&self.bridge, &mapping.did),
+        ))
+    }
+
+    fn agree(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok());
+        let version = body.as_ref().and_then(|b| b.get("version")?.as_str());
+        let version = version.ok_or_else(|| Response::error(400, "Expected a version"))?;
+        match self.bridge.consent(&mapping.did, version) {
+            Ok(consent) => Ok(Response::json(200, &consent.to_json())),
+            Err(e @ ConsentError::NoTerms) => Err(Response::error(404, e.to_string())),
+            Err(e @ ConsentError::Outdated { .. }) => Err(Response::error(409, e.to_string())),
+            Err(e @ ConsentError::Io(_)) => Err(Response::error(500, e.to_string())),
+        }
+    }
+
+    fn receipts(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let post = request
+            .query_param("post")
+            .ok_or_else(|| Response::error(400, "Expected a post"))?;
+        let mut receipts = self
+            .bridge
+            .receipts
+            .for_post(post, request.query_param("host"));
+        receipts.retain(|receipt| receipt.actor == mapping.actor);
+        Ok(Response::json(
+            200,
+            &receipts::summary_json(post, &receipts),
+        ))
+    }
+
+    fn import_lists(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let csv = std::str::from_utf8(&request.body)
+            .map_err(|_| Response::error(400, "Expected a CSV export"))?;
+        let mut imported = Vec::new();
+        for mut list in lists::parse_export(csv) {
+            if list.name.is_none() {
+                list.name = request.query_param("name").map(str::to_string);
+            }
+            match lists::import(&self.bridge, &mapping, &list, SystemTime::now()) {
+                Ok(list) => imported.push(list.to_json()),
+                Err(ListError::Empty) => {}
+                Err(
+                    e @ ListError::Repo(RepoError::NoKey { .. } | RepoError::NotConsented { .. }),
+                ) => return Err(Response::error(409, e.to_string())),
+                Err(e @ ListError::Repo(RepoError::Throttled(_))) => {
+                    return Err(Response::error(429, e.to_string()))
+                }
+                Err(e) => return Err(Response::error(500, e.to_string())),
+            }
+        }
+        if imported.is_empty() {
+            return Err(Response::error(400, ListError::Empty.to_string()));
+        }
+        Ok(Response::json(200, &Value::Array(imported)))
+    }
+
+    fn handle_instructions(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let domain = request
+            .query_param("domain")
+            .ok_or_else(|| Response::error(400, "Expected a domain"))?;
+        let instructions = handles::instructions(&mapping.did, domain)
+            .map_err(|e| Response::error(400, e.to_string()))?;
+        Ok(Response::json(200, &instructions.to_json()))
+    }
+
+    fn switch_handle(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok());
+        let domain = body.as_ref().and_then(|b| b.get("domain")?.as_str());
+        let domain = domain.ok_or_else(|| Response::error(400, "Expected a domain"))?;
+        match handles::switch(&self.bridge, &mapping.did, domain) {
+            Ok(verified_by) => {
+                let handle = self
+                    .bridge
+                    .identities
+                    .get(&mapping.did)
+                    .and_then(|m| m.handle);
+                Ok(Response::json(
+                    200,
+                    &Value::object([
+                        ("handle", Value::from(handle)),
+                        ("verifiedBy", Value::from(verified_by.as_str())),
+                    ]),
+                ))
+            }
+            Err(e @ HandleError::Store(_)) => Err(Response::error(409, e.to_string())),
+            Err(e) => Err(Response::error(400, e.to_string())),
+        }
+    }
+
+    fn set_preferences(&self, request: &Request) -> Result<Response, Response> {
+        let mapping = self.authenticated(request)?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok())
+            .ok_or_else(|| Response::error(400, "Expected a JSON body"))?;
+        let preferences = mapping
+            .preferences
+            .with_changes(&body)
+            .map_err(|e| Response::error(400, e))?;
+        self.bridge
+            .identities
+            .set_preferences(&mapping.did, preferences)
+            .map_err(|e| Response::error(404, e.to_string()))?;
+        Ok(Response::new(204))
+    }
+}
+
+impl<H: Handler> Handler for AccountEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let result = match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["account"]) => self.status(request),
+            (Method::Put, ["account", "preferences"]) => self.set_preferences(request),
+            (Method::Get, ["account", "consent"]) => self.consent(request),
+            (Method::Post, ["account", "consent"]) => self.agree(request),
+            (Method::Get, ["account", "handle"]) => self.handle_instructions(request),
+            (Method::Post, ["account", "handle"]) => self.switch_handle(request),
+            (Method::Get, ["account", "receipts"]) => self.receipts(request),
+            (Method::Post, ["account", "lists"]) => self.import_lists(request),
+            _ => return self.inner.handle(request),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditRecord;
+    use crate::delivery::Delivery;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::receipts::{Outcome, Receipt};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::Write;
+
+    const ALICE_ACTOR: &str = "https://a.example/users/alice";
+
+    /// Trusts whichever DID the request names in `x-did`
+    struct Named;
+
+    impl Authenticator for Named {
+        fn authenticate(&self, _: &Bridge, request: &Request) -> Option<Did> {
+            Did::try_create(request.header("x-did")?.to_string()).ok()
+        }
+    }
+
+    fn endpoints() -> (AccountEndpoints<impl Handler>, Arc<Bridge>) {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let alice = atproto::did!("did:web:bridge.example:u:alice");
+        bridge
+            .identities
+            .insert(Mapping::new(alice.clone(), ALICE_ACTOR));
+        let owner = KeyOwner::Account(alice);
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let bridge = Arc::new(bridge);
+        let endpoints = AccountEndpoints::new(bridge.clone(), |_: &Request| Response::new(418))
+            .with_authenticator(Arc::new(Named));
+        (endpoints, bridge)
+    }
+
+    fn as_alice(method: Method, target: &str) -> Request {
+        Request::new(method, target).with_header("x-did", "did:web:bridge.example:u:alice")
+    }
+
+    #[test]
+    fn shows_a_bridged_account_its_own_status() {
+        let (endpoints, bridge) = endpoints();
+        let alice = atproto::did!("did:web:bridge.example:u:alice");
+        let write = Write::Create {
+            path: "app.bsky.feed.post/3k".to_string(),
+            record: Value::object([("text", Value::from("hi"))]),
+        };
+        bridge.commit(&alice, &[write]).unwrap();
+        let activity = format!(r#"{{"type": "Create", "actor": "{ALICE_ACTOR}", "object": "x"}}"#);
+        let delivery = Delivery::new("https://b.example/inbox", activity.clone());
+        let record = AuditRecord::delivered(&delivery, SystemTime::now()).unwrap();
+        bridge.audit.append(record).unwrap();
+        bridge.jobs.push(Job::Deliver(delivery.clone())).unwrap();
+        let other = Delivery::new(
+            "https://b.example/inbox",
+            r#"{"actor": "https://c.example"}"#,
+        );
+        bridge.jobs.push(Job::Deliver(other)).unwrap();
+
+        let response = endpoints.handle(&as_alice(Method::Get, "/account"));
+        assert_eq!(response.status, 200);
+        let status = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        let last = |network: &str| {
+            let record = status.get("lastBridged").and_then(|l| l.get(network));
+            record.and_then(|r| r.get("object")).cloned()
+        };
+        let post = "at://did:web:bridge.example:u:alice/app.bsky.feed.post/3k";
+        assert_eq!(last("bluesky"), Some(Value::from(post)));
+        assert_eq!(last("fediverse"), Some(Value::from("x")));
+        let pending = status.get("deliveries").and_then(|d| d.get("pending"));
+        assert_eq!(pending.and_then(Value::as_array).map(|p| p.len()), Some(1));
+
+        let receipt = Receipt::new(&delivery, Outcome::Delivered).unwrap();
+        bridge.receipts.append(receipt).unwrap();
+        let theirs = Receipt::new(&delivery, Outcome::Failed).unwrap();
+        let actor = "https://c.example".to_string();
+        bridge
+            .receipts
+            .append(Receipt {
+                actor,
+                inbox: "https://c.example/inbox".into(),
+                ..theirs
+            })
+            .unwrap();
+        let response = endpoints.handle(&as_alice(Method::Get, "/account/receipts?post=x"));
+        let summary = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        let receipts = summary.get("receipts").and_then(Value::as_array).unwrap();
+        // Only their own deliveries of it
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].get("outcome"), Some(&Value::from("delivered")));
+
+        let anonymous = Request::new(Method::Get, "/account");
+        assert_eq!(endpoints.handle(&anonymous).status, 401);
+        let stranger = anonymous.with_header("x-did", "did:plc:carol");
+        assert_eq!(endpoints.handle(&stranger).status, 401);
+        assert_eq!(
+            endpoints.handle(&Request::new(Method::Get, "/")).status,
+            418
+        );
+    }
+
+    #[test]
+    fn switches_to_a_verified_domain() {
+        let (endpoints, bridge) = endpoints();
+        let request = as_alice(Method::Get, "/account/handle?domain=alice.example");
+        let response = endpoints.handle(&request);
+        assert_eq!(response.status, 200);
+        let instructions = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        let txt = instructions.get("dns").and_then(|d| d.get("value"));
+        assert_eq!(
+            txt.and_then(Value::as_str),
+            Some("did=did:web:bridge.example:u:alice")
+        );
+
+        let switch =
+            as_alice(Method::Post, "/account/handle").with_body(r#"{"domain": "alice.example"}"#);
+        assert_eq!(endpoints.handle(&switch).status, 400);
+        let alice = atproto::did!("did:web:bridge.example:u:alice");
+        assert_eq!(bridge.identities.get(&alice).unwrap().handle, None);
+    }
+
+    #[test]
+    fn toggles_preferences() {
+        let (endpoints, bridge) = endpoints();
+        let request =
+            as_alice(Method::Put, "/account/preferences").with_body(r#"{"digests": true}"#);
+        assert_eq!(endpoints.handle(&request).status, 204);
+        let alice = atproto::did!("did:web:bridge.example:u:alice");
+        let mapping = bridge.identities.get(&alice).unwrap();
+        assert!(mapping.preferences.digests);
+        assert!(!mapping.preferences.bridge_dms);
+        let request = as_alice(Method::Put, "/account/preferences").with_body(r#"{"digests": 1}"#);
+        assert_eq!(endpoints.handle(&request).status, 400);
+    }
+}
@@ -0,0 +1,226 @@
+//! The keys fediverse actors publish
+//!
+//! Older software publishes an RSA key as PEM in the actor's `publicKey` block. Newer
+//! software is moving to Ed25519 keys declared as `Multikey`s in `assertionMethod`, as
+//! [FEP-521a](https://codeberg.org/fediverse/fep/src/branch/main/fep/521a/fep-521a.md) has
+//! it. Bridged accounts' actors publish whichever of their keys they have, and remote actors'
+//! keys are read from either.
+//!
+//! A key only counts if the actor owns it: a `publicKey`'s `owner` or a Multikey's
+//! `controller` must be the actor itself
+
+use crate::json::{one_or_many, Value};
+use crate::keys::{KeyOwner, KeyPurpose, KeyStore, StoredKey};
+use std::time::SystemTime;
+
+/// Defines `publicKey`
+pub const SECURITY_CONTEXT: &str = "https://w3id.org/security/v1";
+/// Defines `assertionMethod` and `Multikey`
+pub const MULTIKEY_CONTEXT: &str = "https://w3id.org/security/multikey/v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A key as an actor publishes it
+pub enum PublicKey {
+    /// A `publicKey`'s `publicKeyPem`, RSA in practice
+    Pem(String),
+    /// A Multikey's `publicKeyMultibase`, Ed25519 in practice
+    Multibase(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActorKey {
+    pub id: String,
+    pub key: PublicKey,
+}
+
+/// The keys `document` publishes which its actor owns, legacy ones first
+pub fn published_keys(document: &Value) -> Vec<ActorKey> {
+    let Some(actor) = document.get("id").and_then(Value::as_str) else {
+        return Vec::new();
+    };
+    let field = |key: &Value, name| key.get(name).and_then(Value::as_str).map(str::to_string);
+    let owned_by_actor = |key: &Value, name| key.get(name).and_then(Value::as_str) == Some(actor);
+    let legacy = one_or_many(document.get("publicKey"))
+        .into_iter()
+        .filter(|key| owned_by_actor(key, "owner"))
+        .filter_map(|key| {
+            Some(ActorKey {
+                id: field(key, "id")?,
+                key: PublicKey::Pem(field(key, "publicKeyPem")?),
+            })
+        });
+    let multikeys = one_or_many(document.get("assertionMethod"))
+        .into_iter()
+        .filter(|key| key.get("type").and_then(Value::as_str) == Some("Multikey"))
+        .filter(|key| owned_by_actor(key, "controller"))
+        .filter_map(|key| {
+            Some(ActorKey {
+                id: field(key, "id")?,
+                key: PublicKey::Multibase(field(key, "publicKeyMultibase")?),
+            })
+        });
+    legacy.chain(multikeys).collect()
+}
+
+/// The key `key_id` `document` publishes, if its actor owns it
+pub fn published_key(document: &Value, key_id: &str) -> Option<PublicKey> {
+    let mut keys = published_keys(document).into_iter();
+    keys.find(|key| key.id == key_id).map(|key| key.key)
+}
+
+/// The ID a key version is published under in `actor`'s document
+///
+/// The current HTTP signature key keeps the conventional `#main-key`, as that's what
+/// software caches it as. A [pre-rotated](KeyStore::prerotate) version is published alongside
+/// the version signing until it takes over, so it has an ID of its own, by its version
+pub fn key_id(actor: &str, key: &StoredKey) -> String {
+    let id = match key.purpose {
+        KeyPurpose::HttpSignature => format!("{actor}#main-key"),
+        _ => format!("{actor}#{}-key", key.keypair.algorithm.as_str()),
+    };
+    match key.signs_from {
+        Some(_) => format!("{id}-{}", key.version),
+        None => id,
+    }
+}
+
+/// Add `owner`'s current fediverse keys to the actor document `actor`, along with the
+/// contexts which define them. Keys pre-rotated to take over from them are published after
+/// them, as software reading only one key reads the first
+pub fn publish(keys: &KeyStore, owner: &KeyOwner, actor: &mut Value) {
+    let Value::Object(fields) = actor else {
+        return;
+    };
+    let Some(id) = fields.get("id").and_then(Value::as_str).map(str::to_string) else {
+        return;
+    };
+    let now = SystemTime::now();
+    let published = |purpose| {
+        let current = keys.current_at(owner, purpose, now);
+        current
+            .into_iter()
+            .chain(keys.upcoming(owner, purpose, now))
+    };
+    let mut contexts = Vec::new();
+    let mut blocks: Vec<Value> = published(KeyPurpose::HttpSignature)
+        .map(|key| {
+            Value::object([
+                ("id", Value::from(key_id(&id, &key))),
+                ("owner", Value::from(id.as_str())),
+                ("publicKeyPem", Value::from(key.keypair.public_key.as_str())),
+            ])
+        })
+        .collect();
+    if !blocks.is_empty() {
+        let block = match blocks.len() {
+            1 => blocks.remove(0),
+            _ => Value::Array(blocks),
+        };
+        fields.insert("publicKey".into(), block);
+        contexts.push(SECURITY_CONTEXT);
+    }
+    let multikeys: Vec<Value> = published(KeyPurpose::Assertion)
+        .map(|key| {
+            Value::object([
+                ("id", Value::from(key_id(&id, &key))),
+                ("type", Value::from("Multikey")),
+                ("controller", Value::from(id.as_str())),
+                (
+                    "publicKeyMultibase",
+                    Value::from(key.keypair.public_key.as_str()),
+                ),
+            ])
+        })
+        .collect();
+    if !multikeys.is_empty() {
+        fields.insert("assertionMethod".into(), Value::Array(multikeys));
+        contexts.push(MULTIKEY_CONTEXT);
+    }
+    let mut context = match fields.remove("@context") {
+        Some(Value::Array(context)) => context,
+        Some(context) => vec![context],
+        None => vec![Value::from("https://www.w3.org/ns/activitystreams")],
+    };
+    for extra in contexts {
+        if !context.iter().any(|c| c.as_str() == Some(extra)) {
+            context.push(Value::from(extra));
+        }
+    }
+    fields.insert("@context".into(), Value::Array(context));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+    use atproto::did;
+    use std::time::Duration;
+
+    const ACTOR: &str = "https://bridge.example/users/alice";
+
+    #[test]
+    fn publishes_both_kinds_of_key_and_reads_them_back() {
+        let keys = KeyStore::new();
+        let owner = KeyOwner::Account(did!("did:plc:alice"));
+        let generator = FakeGenerator::default();
+        for purpose in [KeyPurpose::HttpSignature, KeyPurpose::Assertion] {
+            keys.ensure(&owner, purpose, &generator).unwrap();
+        }
+        let mut actor = json::parse(&format!(r#"{{"id": "{ACTOR}", "type": "Person"}}"#)).unwrap();
+        publish(&keys, &owner, &mut actor);
+
+        let context = actor.get("@context").and_then(Value::as_array).unwrap();
+        assert!(context.contains(&Value::from(MULTIKEY_CONTEXT)));
+        let published = published_keys(&actor);
+        let ids: Vec<&str> = published.iter().map(|key| key.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [format!("{ACTOR}#main-key"), format!("{ACTOR}#ed25519-key")]
+        );
+        assert_eq!(
+            published_key(&actor, &format!("{ACTOR}#ed25519-key")),
+            Some(PublicKey::Multibase("ed25519-public-1".to_string()))
+        );
+
+        // Pre-rotated, both versions are published, the one signing first
+        let overlap = Duration::from_secs(60 * 60);
+        keys.prerotate(&owner, KeyPurpose::HttpSignature, &generator, overlap)
+            .unwrap();
+        publish(&keys, &owner, &mut actor);
+        let published = published_keys(&actor);
+        let ids: Vec<&str> = published.iter().map(|key| key.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                format!("{ACTOR}#main-key"),
+                format!("{ACTOR}#main-key-2"),
+                format!("{ACTOR}#ed25519-key")
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_keys_the_actor_doesnt_control() {
+        let actor = json::parse(&format!(
+            r#"{{"id": "{ACTOR}",
+                "publicKey": {{"id": "{ACTOR}#main-key", "owner": "https://evil.example",
+                    "publicKeyPem": "pem"}},
+                "assertionMethod": [
+                    {{"id": "{ACTOR}#a", "type": "Multikey", "controller": "{ACTOR}",
+                        "publicKeyMultibase": "z6Mk"}},
+                    {{"id": "{ACTOR}#b", "type": "Multikey", "controller": "https://evil.example",
+                        "publicKeyMultibase": "z6Mx"}}
+                ]}}"#
+        ))
+        .unwrap();
+        let published = published_keys(&actor);
+        assert_eq!(
+            published,
+            [ActorKey {
+                id: format!("{ACTOR}#a"),
+                key: PublicKey::Multibase("z6Mk".to_string()),
+            }]
+        );
+    }
+}
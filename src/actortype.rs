@@ -0,0 +1,144 @@
+//! Which kind of actor a bridged account is
+//!
+//! ActivityPub tells people (`Person`) apart from automated accounts (`Service`,
+//! `Application`), groups and organizations. atproto only has accounts, and automated ones
+//! declare themselves with the `bot` self-label. So a bridged Bluesky account is a `Person`
+//! unless it declares the label or sets its `bot` preference, when it's a `Service`.
+//!
+//! Fediverse actors bridged onto Bluesky keep what their type says about them:
+//!
+//! - `Service` and `Application` actors get the `bot` self-label
+//! - `Group` actors (forums, Lemmy communities and the like) share their members' posts by
+//!   announcing them, so their announces aren't bridged as reposts. The posts are bridged
+//!   from their authors instead
+//! - Any other type is treated as a `Person`
+
+use crate::json::Value;
+use crate::store::Mapping;
+
+/// The self-label automated accounts declare
+pub const BOT_LABEL: &str = "bot";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActorType {
+    Person,
+    Service,
+    Application,
+    Group,
+    Organization,
+}
+
+impl ActorType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActorType::Person => "Person",
+            ActorType::Service => "Service",
+            ActorType::Application => "Application",
+            ActorType::Group => "Group",
+            ActorType::Organization => "Organization",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ActorType> {
+        [
+            ActorType::Person,
+            ActorType::Service,
+            ActorType::Application,
+            ActorType::Group,
+            ActorType::Organization,
+        ]
+        .into_iter()
+        .find(|kind| kind.as_str() == s)
+    }
+
+    /// The type of the actor document `actor`, treating types we don't know as `Person`
+    pub fn of(actor: &Value) -> ActorType {
+        let kind = actor.get("type").and_then(Value::as_str);
+        kind.and_then(ActorType::parse).unwrap_or(ActorType::Person)
+    }
+
+    pub fn is_automated(&self) -> bool {
+        matches!(self, ActorType::Service | ActorType::Application)
+    }
+
+    /// Whether this actor's announces are bridged as reposts
+    pub fn bridges_announces(&self) -> bool {
+        *self != ActorType::Group
+    }
+
+    /// The self-labels a profile bridged from an actor of this type carries, if any
+    pub fn self_labels(&self) -> Option<Value> {
+        self.is_automated().then(|| {
+            Value::object([
+                ("$type", Value::from("com.atproto.label.defs#selfLabels")),
+                (
+                    "values",
+                    Value::Array(vec![Value::object([("val", Value::from(BOT_LABEL))])]),
+                ),
+            ])
+        })
+    }
+}
+
+/// Whether an `app.bsky.actor.profile` record declares its account automated
+pub fn self_labelled_bot(profile: &Value) -> bool {
+    let values = profile.get("labels").and_then(|l| l.get("values"));
+    let values = values.and_then(Value::as_array).unwrap_or_default();
+    values
+        .iter()
+        .any(|value| value.get("val").and_then(Value::as_str) == Some(BOT_LABEL))
+}
+
+/// The type of the actor bridging `mapping`'s Bluesky account, given its profile record
+pub fn for_account(mapping: &Mapping, profile: Option<&Value>) -> ActorType {
+    if mapping.preferences.bot || profile.is_some_and(self_labelled_bot) {
+        ActorType::Service
+    } else {
+        ActorType::Person
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::store::Preferences;
+    use atproto::did;
+
+    #[test]
+    fn bots_are_services_either_way() {
+        let mapping = Mapping::new(did!("did:plc:alice"), "https://bridge.example/u/alice");
+        let profile = json::parse(r#"{"displayName": "Alice"}"#).unwrap();
+        assert_eq!(for_account(&mapping, Some(&profile)), ActorType::Person);
+
+        let labelled = json::parse(
+            r#"{"labels": {"$type": "com.atproto.label.defs#selfLabels",
+                "values": [{"val": "bot"}]}}"#,
+        )
+        .unwrap();
+        assert_eq!(for_account(&mapping, Some(&labelled)), ActorType::Service);
+        let preferred = Mapping {
+            preferences: Preferences {
+                bot: true,
+                ..Preferences::default()
+            },
+            ..mapping
+        };
+        assert_eq!(for_account(&preferred, None), ActorType::Service);
+
+        let labels = ActorType::Service.self_labels().unwrap();
+        assert!(self_labelled_bot(&Value::object([("labels", labels)])));
+        assert_eq!(ActorType::Person.self_labels(), None);
+    }
+
+    #[test]
+    fn inbound_types_change_how_actors_are_bridged() {
+        let actor = |kind: &str| json::parse(&format!(r#"{{"type": "{kind}"}}"#)).unwrap();
+        assert!(ActorType::of(&actor("Application")).is_automated());
+        let group = ActorType::of(&actor("Group"));
+        assert!(!group.bridges_announces());
+        assert!(!group.is_automated());
+        assert_eq!(ActorType::of(&actor("Tombstone")), ActorType::Person);
+        assert!(ActorType::of(&actor("Person")).bridges_announces());
+    }
+}
@@ -0,0 +1,359 @@
+//! Addressing what the bridge sends, and working out where it goes
+//!
+//! Remote servers decide who sees an activity by its addressing, and many drop what's
+//! addressed inconsistently, so the [audience](crate::audience::Visibility) of everything
+//! sent is built the same way by [`Addressing`]:
+//!
+//! | Visibility | `to`                               | `cc`                          |
+//! |------------|------------------------------------|-------------------------------|
+//! | public     | the public collection              | followers, mentioned actors   |
+//! | unlisted   | followers                          | the public collection, mentioned actors |
+//! | followers  | followers                          | mentioned actors              |
+//! | direct     | mentioned actors                   |                               |
+//!
+//! and [`Addressing::apply`] puts it on the activity and the object it creates alike.
+//!
+//! At delivery, [`inboxes`] expands the addressing to where it's sent: each actor to its
+//! shared inbox, and the followers collection of a bridged account to those of its followers,
+//! as the [`FollowerStore`] has them from the `Follow`s and `Undo`s it's been sent. `bto` and
+//! `bcc` are delivered to but [stripped](strip_blind) from what's sent
+
+use crate::audience::Visibility;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::dm::PUBLIC;
+use crate::json::Value;
+use crate::storage::{JsonLog, StateDir};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::RwLock;
+
+/// The followers of bridged accounts, one JSON entry per line
+pub const FOLLOWERS_FILE: &str = "followers.jsonl";
+/// The fields an activity is addressed by
+const ADDRESS_FIELDS: [&str; 5] = ["to", "cc", "bto", "bcc", "audience"];
+
+/// The followers collection of `actor`
+pub fn followers_of(actor: &str) -> String {
+    format!("{actor}/followers")
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Who an activity is addressed to
+pub struct Addressing {
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+}
+
+impl Addressing {
+    /// What `actor` addresses something of `visibility` to
+    pub fn new(actor: &str, visibility: Visibility) -> Addressing {
+        let (public, followers) = (PUBLIC.to_string(), followers_of(actor));
+        let (to, cc) = match visibility {
+            Visibility::Public => (vec![public], vec![followers]),
+            Visibility::Unlisted => (vec![followers], vec![public]),
+            Visibility::Followers => (vec![followers], Vec::new()),
+            Visibility::Direct => (Vec::new(), Vec::new()),
+        };
+        Addressing { to, cc }
+    }
+
+    /// Also addressed to `mentioned`, who are its recipients if it's direct and copied in
+    /// otherwise
+    pub fn with_mentions<'a>(mut self, mentioned: impl IntoIterator<Item = &'a str>) -> Addressing {
+        let direct = self.to.is_empty();
+        for actor in mentioned {
+            if self
+                .to
+                .iter()
+                .chain(&self.cc)
+                .any(|address| address == actor)
+            {
+                continue;
+            }
+            match direct {
+                true => self.to.push(actor.to_string()),
+                false => self.cc.push(actor.to_string()),
+            }
+        }
+        self
+    }
+
+    /// The `to` and `cc` fields
+    pub fn fields(&self) -> [(&'static str, Value); 2] {
+        let field = |addresses: &[String]| {
+            Value::Array(addresses.iter().map(|a| Value::from(a.as_str())).collect())
+        };
+        [("to", field(&self.to)), ("cc", field(&self.cc))]
+    }
+
+    /// `activity`, and the object it carries if it's embedded, addressed to this
+    pub fn apply(&self, activity: &Value) -> Value {
+        let mut activity = activity.clone();
+        let Value::Object(fields) = &mut activity else {
+            return activity;
+        };
+        for (name, addresses) in self.fields() {
+            // Only addressed to where there's someone to address
+            match addresses.as_array().is_some_and(|a| a.is_empty()) {
+                true => fields.remove(name),
+                false => fields.insert(name.to_string(), addresses),
+            };
+        }
+        if let Some(object @ Value::Object(_)) = fields.get("object") {
+            let object = self.apply(object);
+            fields.insert("object".to_string(), object);
+        }
+        activity
+    }
+}
+
+/// The ID `value` is, or has if it's embedded
+fn id(value: Option<&Value>) -> Option<&str> {
+    value.and_then(|v| v.as_str().or_else(|| v.get("id")?.as_str()))
+}
+
+/// The addresses of `activity`, its object's among them
+fn addresses(activity: &Value) -> BTreeSet<&str> {
+    let own = ADDRESS_FIELDS
+        .iter()
+        .flat_map(|field| match activity.get(field) {
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            Some(item) => item.as_str().into_iter().collect(),
+            None => Vec::new(),
+        });
+    let mut addresses: BTreeSet<&str> = own.collect();
+    if let Some(object @ Value::Object(_)) = activity.get("object") {
+        addresses.extend(self::addresses(object));
+    }
+    addresses
+}
+
+/// `activity` without its blind recipients, as it's sent to each of them
+pub fn strip_blind(activity: &Value) -> Value {
+    let mut activity = activity.clone();
+    if let Value::Object(fields) = &mut activity {
+        fields.remove("bto");
+        fields.remove("bcc");
+        if let Some(object @ Value::Object(_)) = fields.get("object") {
+            let object = strip_blind(object);
+            fields.insert("object".to_string(), object);
+        }
+    }
+    activity
+}
+
+/// The inboxes `activity` from `actor` goes to: each actor it's addressed to, and each
+/// follower of a bridged account whose followers it's addressed to, by shared inbox
+pub fn inboxes(bridge: &Bridge, actor: &str, activity: &Value) -> Vec<String> {
+    let mut recipients = BTreeSet::new();
+    for address in addresses(activity) {
+        if matches!(address, PUBLIC | "as:Public" | "Public") || address == actor {
+            continue;
+        }
+        let owner = address.strip_suffix("/followers");
+        match owner.filter(|owner| bridge.identities.get_by_actor(owner).is_some()) {
+            Some(owner) => recipients.extend(bridge.followers.of(owner)),
+            // The followers of anyone else aren't known, and can't be delivered to
+            None if owner.is_some() => {}
+            None => {
+                recipients.insert(address.to_string());
+            }
+        }
+    }
+    recipients.remove(actor);
+    let inboxes = recipients
+        .iter()
+        .filter_map(|recipient| delivery::shared_inbox(&bridge.documents, recipient));
+    let inboxes: BTreeSet<String> = inboxes.collect();
+    inboxes.into_iter().collect()
+}
+
+/// The deliveries of `activity` from `actor`, one to each of its [`inboxes`]
+pub fn deliveries(bridge: &Bridge, actor: &str, activity: &Value) -> Vec<Delivery> {
+    let sent = strip_blind(activity).to_string();
+    let inboxes = inboxes(bridge, actor, activity);
+    let deliveries = inboxes.into_iter();
+    deliveries
+        .map(|inbox| Delivery::new(inbox, sent.clone()))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+/// The fediverse followers of each bridged account
+pub struct FollowerStore {
+    followers: RwLock<HashMap<String, BTreeSet<String>>>,
+    log: Option<JsonLog>,
+}
+
+impl FollowerStore {
+    /// The followers persisted in `dir`. Each line follows or unfollows
+    pub fn open(dir: StateDir) -> io::Result<FollowerStore> {
+        let (log, entries) = JsonLog::open(dir, FOLLOWERS_FILE)?;
+        let mut followers: HashMap<String, BTreeSet<String>> = HashMap::new();
+        for entry in entries {
+            let field = |name| entry.get(name).and_then(Value::as_str);
+            let (Some(actor), Some(follower)) = (field("actor"), field("follower")) else {
+                continue;
+            };
+            let of = followers.entry(actor.to_string()).or_default();
+            match entry.get("following").and_then(Value::as_bool) {
+                Some(false) => of.remove(follower),
+                _ => of.insert(follower.to_string()),
+            };
+        }
+        Ok(FollowerStore {
+            followers: RwLock::new(followers),
+            log: Some(log),
+        })
+    }
+
+    /// Who follows `actor`
+    pub fn of(&self, actor: &str) -> Vec<String> {
+        let followers = self.followers.read().unwrap();
+        let of = followers.get(actor).map(|of| of.iter().cloned().collect());
+        of.unwrap_or_default()
+    }
+
+    /// Note that `follower` follows `actor`, or with `following` false no longer does
+    pub fn set(&self, actor: &str, follower: &str, following: bool) -> io::Result<()> {
+        let mut followers = self.followers.write().unwrap();
+        let of = followers.entry(actor.to_string()).or_default();
+        let changed = match following {
+            true => of.insert(follower.to_string()),
+            false => of.remove(follower),
+        };
+        let Some(log) = self.log.as_ref().filter(|_| changed) else {
+            return Ok(());
+        };
+        let entry = Value::object([
+            ("actor", Value::from(actor)),
+            ("follower", Value::from(follower)),
+            ("following", Value::from(following)),
+        ]);
+        log.append([entry])
+    }
+
+    /// Keep what `activity`, sent to a bridged account of `bridge`, says of who follows it: a
+    /// `Follow` of it, or the `Undo` of one. Returns whether it said anything
+    pub fn received(&self, bridge: &Bridge, activity: &Value) -> io::Result<bool> {
+        let kind = activity.get("type").and_then(Value::as_str);
+        let (follow, following) = match kind {
+            Some("Follow") => (activity, true),
+            Some("Undo") => match activity.get("object") {
+                Some(follow) if follow.get("type").and_then(Value::as_str) == Some("Follow") => {
+                    (follow, false)
+                }
+                _ => return Ok(false),
+            },
+            _ => return Ok(false),
+        };
+        let (Some(follower), Some(actor)) = (id(activity.get("actor")), id(follow.get("object")))
+        else {
+            return Ok(false);
+        };
+        // Only the one who followed can take it back
+        if id(follow.get("actor")) != Some(follower)
+            || bridge.identities.get_by_actor(actor).is_none()
+        {
+            return Ok(false);
+        }
+        self.set(actor, follower, following)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::ResourceKind;
+    use crate::json;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+    use std::time::Instant;
+
+    const ALICE: &str = "https://bridge.example/ap/did:plc:alice";
+    const BOB: &str = "https://b.example/users/bob";
+    const CAROL: &str = "https://c.example/users/carol";
+
+    #[test]
+    fn builds_addressing_and_expands_followers_to_inboxes() {
+        let public = Addressing::new(ALICE, Visibility::Public).with_mentions([CAROL]);
+        assert_eq!(public.to, [PUBLIC]);
+        assert_eq!(public.cc, [followers_of(ALICE), CAROL.to_string()]);
+        let direct = Addressing::new(ALICE, Visibility::Direct).with_mentions([CAROL, CAROL]);
+        assert_eq!(
+            (direct.to.as_slice(), direct.cc.len()),
+            ([CAROL.to_string()].as_slice(), 0)
+        );
+        let create = json::parse(&format!(
+            r#"{{"type": "Create", "actor": "{ALICE}", "to": ["{BOB}"],
+                "object": {{"type": "Note", "content": "hi"}}}}"#
+        ))
+        .unwrap();
+        let addressed = direct.apply(&create);
+        assert_eq!(addressed.get("cc"), None);
+        let note_to = addressed.get("object").and_then(|note| note.get("to"));
+        assert_eq!(note_to, Some(&Value::Array(vec![Value::from(CAROL)])));
+
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            followers: FollowerStore::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        bridge
+            .identities
+            .insert(Mapping::new(did!("did:plc:alice"), ALICE));
+        let follow = |actor: &str| {
+            format!(
+                r#"{{"id": "{actor}#follow", "type": "Follow", "actor": "{actor}", "object": "{ALICE}"}}"#
+            )
+        };
+        for follower in [BOB, CAROL] {
+            let follow = json::parse(&follow(follower)).unwrap();
+            assert!(bridge.followers.received(&bridge, &follow).unwrap());
+        }
+        let undo = json::parse(&format!(
+            r#"{{"type": "Undo", "actor": "{CAROL}", "object": {}}}"#,
+            follow(BOB)
+        ))
+        .unwrap();
+        assert!(
+            !bridge.followers.received(&bridge, &undo).unwrap(),
+            "only Bob can unfollow"
+        );
+        assert_eq!(FollowerStore::open(dir).unwrap().of(ALICE), [BOB, CAROL]);
+
+        bridge.documents.insert(
+            ResourceKind::Actor,
+            BOB,
+            json::parse(
+                r#"{"inbox": "https://b.example/users/bob/inbox",
+                    "endpoints": {"sharedInbox": "https://b.example/inbox"}}"#,
+            )
+            .unwrap(),
+            1,
+            Instant::now(),
+        );
+        let note = public.apply(&create);
+        let mut hidden = note.clone();
+        if let Value::Object(fields) = &mut hidden {
+            fields.insert(
+                "bcc".into(),
+                Value::Array(vec![Value::from("https://d.example/dan")]),
+            );
+        }
+        let deliveries = deliveries(&bridge, ALICE, &hidden);
+        let inboxes: Vec<_> = deliveries.iter().map(|d| d.inbox.as_str()).collect();
+        assert_eq!(
+            inboxes,
+            [
+                "https://b.example/inbox",
+                "https://c.example/inbox",
+                "https://d.example/inbox"
+            ]
+        );
+        assert!(!deliveries[0].activity.contains("bcc"));
+    }
+}
@@ -0,0 +1,942 @@
+//! The operator admin API
+//!
+//! Every request must carry the configured token as `Authorization: Bearer <token>`.
+//!
+//! | Method | Path                                  | Action                              |
+//! |--------|---------------------------------------|-------------------------------------|
+//! | GET    | `/admin/identities?q=`                | List (or search) bridged identities |
+//! | POST   | `/admin/identities/{did}/pause`       | Stop bridging an identity           |
+//! | POST   | `/admin/identities/{did}/resume`      | Resume bridging an identity         |
+//! | PUT    | `/admin/identities/{did}/preferences` | Set an identity's preferences       |
+//! | DELETE | `/admin/identities/{did}`             | Remove a mapping                    |
+//! | POST   | `/admin/identities/{did}/unbridge`    | Unbridge an identity entirely       |
+//! | GET    | `/admin/identities/lookalikes`        | Handles looking like others'        |
+//! | GET    | `/admin/deletions`                    | Audit log of unbridged identities   |
+//! | GET    | `/admin/audit`                        | Query the log of bridge actions     |
+//! | GET    | `/admin/deliveries/failed`            | List permanently failed deliveries  |
+//! | POST   | `/admin/deliveries/{id}/retry`        | Requeue a failed delivery           |
+//! | GET    | `/admin/firehose`                     | Firehose cursor, lag and shard      |
+//! | GET    | `/admin/firehose/shedding`            | Events shed while behind            |
+//! | GET    | `/admin/signatures`                   | Verified key cache hit rate         |
+//! | GET    | `/admin/relays`                       | Which relays acknowledged crawling  |
+//! | GET    | `/admin/breakers`                     | Hosts left alone after failing      |
+//! | GET    | `/admin/peers`                        | What peer instances run and handle  |
+//! | GET    | `/admin/concurrency`                  | Deliveries let run at once          |
+//! | GET    | `/admin/upstreams`                    | Relays and PLC directories in use   |
+//! | DELETE | `/admin/breakers/{host}`              | Close a host's circuit              |
+//! | POST   | `/admin/backfills/{did}`              | Queue a backfill of a repo          |
+//! | GET    | `/admin/reconcile`                    | Drift found between account sides   |
+//! | POST   | `/admin/reconcile/{did}`              | Reconcile an identity's sides now   |
+//! | GET    | `/admin/quotas`                       | What accounts have used today       |
+//! | DELETE | `/admin/quotas/{did}`                 | Lift an identity's throttling       |
+//! | GET    | `/admin/identities/{did}/export`      | Export an identity's data           |
+//! | GET    | `/admin/identities/{did}/consent`     | Which terms an identity agreed to   |
+//! | POST   | `/admin/identities/import`            | Import another bridge's mappings    |
+//! | POST   | `/admin/identities/enroll?dryRun=`    | Enroll a CSV list of accounts       |
+//! | GET    | `/admin/approvals`                    | Accounts waiting to be approved     |
+//! | POST   | `/admin/approvals/{did}`              | Approve an account, bridging it     |
+//! | DELETE | `/admin/approvals/{did}`              | Reject an account's request         |
+//! | GET    | `/admin/suspensions`                  | Identities suspended by operators   |
+//! | POST   | `/admin/suspensions/{did}`            | Suspend an identity                 |
+//! | DELETE | `/admin/suspensions/{did}`            | Lift an identity's suspension       |
+//! | GET    | `/admin/defederations`                | Instances defederated               |
+//! | POST   | `/admin/defederations/{host}?purge=`  | Defederate an instance              |
+//! | DELETE | `/admin/defederations/{host}`         | Federate with an instance again     |
+//! | GET    | `/admin/moderation/events?kinds=`     | Stream moderation events            |
+//! | POST   | `/admin/moderation/decisions`         | Take a moderation tool's decision   |
+//! | GET    | `/admin/policy`                       | List federation policy rules        |
+//! | PUT    | `/admin/policy/{subject}`             | Set a domain or DID's actions       |
+//! | DELETE | `/admin/policy/{subject}`             | Remove a domain or DID's rule       |
+//! | GET    | `/admin/diagnose?identity=`           | Walk an account's resolution chain  |
+//! | GET    | `/admin/preview?url=`                 | What a post would be bridged as     |
+//! | GET    | `/admin/decisions`                    | Why events were or weren't bridged  |
+//! | GET    | `/admin/receipts?post=&host=`         | Where a post was delivered          |
+//! | GET    | `/admin/dry-run?limit=`               | Writes a dry run has held back      |
+//! | GET    | `/admin/stats?since=&until=&top=`     | Volumes and failures, by day        |
+
+use crate::approval;
+use crate::audit::AuditQuery;
+use crate::bridge::Bridge;
+use crate::consent;
+use crate::crypto::constant_time_eq;
+use crate::delivery;
+use crate::diagnose;
+use crate::digest::Network;
+use crate::dryrun::Captured;
+use crate::enroll;
+use crate::export;
+use crate::http::{Handler, Method, Request, Response};
+use crate::interop::{self, OptInError};
+use crate::jobs::{Job, JobError};
+use crate::json::{self, Value};
+use crate::lookalike;
+use crate::modstream::{self, ModerationDecision};
+use crate::normalize;
+use crate::policy::{PolicyError, Rule, Subject};
+use crate::preview;
+use crate::receipts;
+use crate::reconcile;
+use crate::sanctions::{self, SanctionError};
+use crate::stats::{StatsQuery, DEFAULT_TOP};
+use crate::store::{Mapping, MappingStatus};
+use crate::time::parse_rfc3339;
+use crate::trace::{Decision, DecisionQuery, Reason};
+use crate::unbridge::{self, DeletionReason, UnbridgeError};
+use atproto::DID::Did;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// How many audit records are returned when a query doesn't say
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+
+/// Handler for the admin API
+pub struct AdminApi {
+    bridge: Arc<Bridge>,
+    token: String,
+}
+
+fn parse_did(s: &str) -> Result<Did, Response> {
+    normalize::parse_did(s).map_err(|e| Response::error(400, e.to_string()))
+}
+
+/// The `reason` given in a request's JSON body, if any
+fn reason(request: &Request) -> Option<String> {
+    let body = std::str::from_utf8(&request.body).ok()?;
+    let body = json::parse(body).ok()?;
+    body.get("reason")?.as_str().map(str::to_string)
+}
+
+fn sanction_error(error: SanctionError) -> Response {
+    let status = match error {
+        SanctionError::NotBridged { .. } => 404,
+        SanctionError::AlreadySuspended { .. } => 409,
+        SanctionError::InvalidDomain { .. } => 400,
+        SanctionError::Io(_) => 500,
+    };
+    Response::error(status, error.to_string())
+}
+
+impl AdminApi {
+    pub fn new(bridge: Arc<Bridge>, token: impl Into<String>) -> AdminApi {
+        AdminApi {
+            bridge,
+            token: token.into(),
+        }
+    }
+
+    fn authorised(&self, request: &Request) -> bool {
+        request
+            .header("authorization")
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .is_some_and(|t| constant_time_eq(t.as_bytes(), self.token.as_bytes()))
+    }
+
+    fn list_identities(&self, request: &Request) -> Response {
+        let mappings = match request.query_param("q") {
+            Some(q) => self.bridge.identities.search(q),
+            None => self.bridge.identities.all(),
+        };
+        let items = mappings.iter().map(Mapping::to_json).collect();
+        Response::json(200, &Value::object([("identities", Value::Array(items))]))
+    }
+
+    fn set_status(&self, did: &str, status: MappingStatus) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        if status == MappingStatus::Active && self.bridge.sanctions.is_suspended(&did) {
+            let suspended = format!("{did} is suspended, and has to be unsuspended instead");
+            return Err(Response::error(409, suspended));
+        }
+        self.bridge
+            .identities
+            .set_status(&did, status)
+            .map_err(|e| Response::error(404, e.to_string()))?;
+        Ok(Response::new(204))
+    }
+
+    fn set_preferences(&self, did: &str, request: &Request) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok())
+            .ok_or_else(|| Response::error(400, "Expected a JSON body"))?;
+        let current = self
+            .bridge
+            .identities
+            .get(&did)
+            .ok_or_else(|| Response::error(404, format!("No mapping exists for {did}")))?;
+        let preferences = current
+            .preferences
+            .with_changes(&body)
+            .map_err(|e| Response::error(400, e))?;
+        self.bridge
+            .identities
+            .set_preferences(&did, preferences)
+            .map_err(|e| Response::error(404, e.to_string()))?;
+        Ok(Response::new(204))
+    }
+
+    fn remove_identity(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let removed = self
+            .bridge
+            .identities
+            .remove(&did)
+            .map_err(|e| Response::error(404, e.to_string()))?;
+        Ok(Response::json(200, &removed.to_json()))
+    }
+
+    fn approvals(&self) -> Response {
+        let waiting = self.bridge.approvals.all();
+        Response::json(
+            200,
+            &Value::Array(waiting.iter().map(|a| a.to_json()).collect()),
+        )
+    }
+
+    fn approve(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        match approval::approve(&self.bridge, &did) {
+            Ok(Some(mapping)) => Ok(Response::json(200, &mapping.to_json())),
+            Ok(None) => Err(Response::error(404, format!("{did} isn't waiting"))),
+            Err(e @ OptInError::Io(_)) => Err(Response::error(500, e.to_string())),
+            Err(e) => Err(Response::error(409, e.to_string())),
+        }
+    }
+
+    fn reject(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        match approval::reject(&self.bridge, &did) {
+            Ok(Some(_)) => Ok(Response::new(204)),
+            Ok(None) => Err(Response::error(404, format!("{did} isn't waiting"))),
+            Err(e) => Err(Response::error(500, e.to_string())),
+        }
+    }
+
+    /// Suspend an identity, with the optional `reason` in the body
+    fn suspend(&self, did: &str, request: &Request) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let reason = reason(request);
+        match sanctions::suspend(&self.bridge, &did, reason.as_deref(), SystemTime::now()) {
+            Ok(suspension) => Ok(Response::json(200, &suspension.to_json())),
+            Err(e) => Err(sanction_error(e)),
+        }
+    }
+
+    fn unsuspend(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        match sanctions::unsuspend(&self.bridge, &did) {
+            Ok(Some(_)) => Ok(Response::new(204)),
+            Ok(None) => Err(Response::error(404, format!("{did} isn't suspended"))),
+            Err(e) => Err(sanction_error(e)),
+        }
+    }
+
+    /// Defederate an instance, suspending the identities bridged from it with `purge=true`,
+    /// with the optional `reason` in the body
+    fn defederate(&self, domain: &str, request: &Request) -> Result<Response, Response> {
+        let purge = match request.query_param("purge") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return Err(Response::error(400, format!("Invalid purge {other}"))),
+        };
+        let reason = reason(request);
+        let now = SystemTime::now();
+        match sanctions::defederate(&self.bridge, domain, purge, reason.as_deref(), now) {
+            Ok(defederation) => Ok(Response::json(200, &defederation.to_json())),
+            Err(e) => Err(sanction_error(e)),
+        }
+    }
+
+    fn refederate(&self, domain: &str) -> Result<Response, Response> {
+        match sanctions::refederate(&self.bridge, domain) {
+            Ok(Some(_)) => Ok(Response::new(204)),
+            Ok(None) => Err(Response::error(404, format!("{domain} isn't defederated"))),
+            Err(e) => Err(sanction_error(e)),
+        }
+    }
+
+    /// Unlike removing the mapping, this deletes the identity's counterpart too. The
+    /// optional `requestedBy` in the body is kept in the audit record
+    fn unbridge(&self, did: &str, request: &Request) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok());
+        let requested_by = body
+            .as_ref()
+            .and_then(|b| b.get("requestedBy"))
+            .and_then(Value::as_str);
+        match unbridge::unbridge(&self.bridge, &did, DeletionReason::Requested, requested_by) {
+            Ok(record) => Ok(Response::json(200, &record.to_json())),
+            Err(e @ UnbridgeError::NotBridged { .. }) => Err(Response::error(404, e.to_string())),
+            Err(e @ UnbridgeError::UnknownHome { .. }) => Err(Response::error(409, e.to_string())),
+            Err(e @ UnbridgeError::Io(_)) => Err(Response::error(500, e.to_string())),
+        }
+    }
+
+    fn deletions(&self) -> Response {
+        let items = self
+            .bridge
+            .deletions
+            .all()
+            .iter()
+            .map(|r| r.to_json())
+            .collect();
+        Response::json(200, &Value::object([("deletions", Value::Array(items))]))
+    }
+
+    /// Filtered by `network`, `actor`, `object`, `source` (the event which caused each
+    /// action) and `since`, newest first, `limit` at a time
+    fn audit(&self, request: &Request) -> Result<Response, Response> {
+        let param = |name| request.query_param(name).map(str::to_string);
+        let invalid = |name| Response::error(400, format!("Invalid {name}"));
+        let query = AuditQuery {
+            network: match request.query_param("network") {
+                Some(network) => Some(Network::parse(network).ok_or_else(|| invalid("network"))?),
+                None => None,
+            },
+            actor: param("actor"),
+            object: param("object"),
+            source: param("source"),
+            since: match request.query_param("since") {
+                Some(since) => Some(parse_rfc3339(since).map_err(|_| invalid("since"))?),
+                None => None,
+            },
+            limit: match request.query_param("limit") {
+                Some(limit) => limit.parse().map_err(|_| invalid("limit"))?,
+                None => DEFAULT_AUDIT_LIMIT,
+            },
+        };
+        let items = self
+            .bridge
+            .audit
+            .query(&query)
+            .iter()
+            .map(|r| r.to_json())
+            .collect();
+        Ok(Response::json(
+            200,
+            &Value::object([("records", Value::Array(items))]),
+        ))
+    }
+
+    fn failed_deliveries(&self) -> Response {
+        let items = self
+            .bridge
+            .jobs
+            .dead()
+            .iter()
+            .filter_map(delivery::queued_json)
+            .collect();
+        Response::json(200, &Value::object([("deliveries", Value::Array(items))]))
+    }
+
+    fn retry_delivery(&self, id: &str) -> Result<Response, Response> {
+        let id = id
+            .parse()
+            .map_err(|_| Response::error(400, format!("Invalid delivery id {id}")))?;
+        let is_delivery = self
+            .bridge
+            .jobs
+            .dead()
+            .iter()
+            .any(|j| j.id == id && matches!(j.job, Job::Deliver(_)));
+        if !is_delivery {
+            return Err(Response::error(
+                404,
+                format!("No failed delivery with id {id}"),
+            ));
+        }
+        match self.bridge.jobs.retry(id) {
+            Err(e @ JobError::Io(_)) => return Err(Response::error(500, e.to_string())),
+            Err(e) => return Err(Response::error(404, e.to_string())),
+            Ok(()) => {}
+        }
+        Ok(Response::new(202))
+    }
+
+    fn firehose(&self) -> Response {
+        let cursor = &self.bridge.firehose;
+        Response::json(
+            200,
+            &Value::object([
+                ("head", Value::from(cursor.head())),
+                ("position", Value::from(cursor.position())),
+                ("lag", Value::from(cursor.lag())),
+                ("shard", Value::from(self.bridge.shard.to_string())),
+            ]),
+        )
+    }
+
+    fn signatures(&self) -> Response {
+        let keys = &self.bridge.verified_keys;
+        let mut stats = keys.stats().to_json();
+        if let Value::Object(fields) = &mut stats {
+            fields.insert("cached".into(), Value::from(keys.len()));
+        }
+        Response::json(200, &stats)
+    }
+
+    fn relays(&self) -> Response {
+        let relays = self.bridge.crawl.relays.iter().map(|relay| {
+            let mut status = self.bridge.relays.status(relay).to_json();
+            if let Value::Object(fields) = &mut status {
+                fields.insert("relay".into(), Value::from(relay.as_str()));
+            }
+            status
+        });
+        Response::json(
+            200,
+            &Value::object([("relays", Value::Array(relays.collect()))]),
+        )
+    }
+
+    fn reset_breaker(&self, host: &str) -> Result<Response, Response> {
+        if !self.bridge.breakers.reset(host, SystemTime::now()) {
+            return Err(Response::error(404, format!("{host}'s circuit isn't open")));
+        }
+        Ok(Response::new(204))
+    }
+
+    fn consent(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        if self.bridge.identities.get(&did).is_none() {
+            return Err(Response::error(404, format!("No mapping exists for {did}")));
+        }
+        Ok(Response::json(
+            200,
+            &consent::status_json(&self.bridge, &did),
+        ))
+    }
+
+    fn request_backfill(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        if self.bridge.identities.get(&did).is_none() {
+            return Err(Response::error(404, format!("No mapping exists for {did}")));
+        }
+        let jobs = &self.bridge.jobs;
+        let already_queued = jobs.contains(|j| matches!(j, Job::Backfill { did: d } if *d == did));
+        let queued = !already_queued;
+        if queued {
+            jobs.push(Job::Backfill { did })
+                .map_err(|e| Response::error(500, e.to_string()))?;
+        }
+        Ok(Response::json(
+            202,
+            &Value::object([("queued", Value::from(queued))]),
+        ))
+    }
+
+    fn reconcile(&self, did: &str) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let Some(mapping) = self.bridge.identities.get(&did) else {
+            return Err(Response::error(404, format!("No mapping exists for {did}")));
+        };
+        let reconciliation = reconcile::reconcile(&self.bridge, &mapping, SystemTime::now())
+            .map_err(|e| Response::error(502, e.to_string()))?;
+        Ok(Response::json(200, &reconciliation.to_json()))
+    }
+
+    /// The body is another bridge's export, as [`interop::import`] takes
+    fn import(&self, request: &Request) -> Result<Response, Response> {
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok())
+            .ok_or_else(|| Response::error(400, "Expected a JSON body"))?;
+        let report = interop::import(&self.bridge, &body);
+        Ok(Response::json(200, &report.to_json()))
+    }
+
+    /// The body is a CSV list of accounts, as [`enroll::enroll`] takes. With `?dryRun=true`
+    /// they're only checked
+    fn enroll(&self, request: &Request) -> Result<Response, Response> {
+        let body = std::str::from_utf8(&request.body)
+            .map_err(|_| Response::error(400, "Expected a CSV body"))?;
+        let dry_run = match request.query_param("dryRun") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(other) => return Err(Response::error(400, format!("Invalid dryRun {other}"))),
+        };
+        let report =
+            enroll::enroll(&self.bridge, body, dry_run).map_err(|e| Response::error(400, e))?;
+        Ok(Response::json(200, &report.to_json()))
+    }
+
+    /// As JSON, or with `?format=car` as a CAR archive
+    fn export(&self, did: &str, request: &Request) -> Result<Response, Response> {
+        let did = parse_did(did)?;
+        let export = export::export(&self.bridge, &did)
+            .ok_or_else(|| Response::error(404, format!("No mapping exists for {did}")))?;
+        match request.query_param("format") {
+            None | Some("json") => Ok(Response::json(200, &export)),
+            Some("car") => Ok(Response::new(200)
+                .with_header("content-type", export::CAR_MEDIA_TYPE)
+                .with_body(export::to_car(&export))),
+            Some(other) => Err(Response::error(400, format!("Unknown format {other}"))),
+        }
+    }
+
+    fn policy(&self) -> Response {
+        let items = self
+            .bridge
+            .policy
+            .rules()
+            .iter()
+            .map(Rule::to_json)
+            .collect();
+        Response::json(200, &Value::object([("rules", Value::Array(items))]))
+    }
+
+    /// The body is `{"actions": [...]}`, replacing whatever actions the subject had
+    fn set_policy(&self, subject: &str, request: &Request) -> Result<Response, Response> {
+        let subject: Subject = subject
+            .parse()
+            .map_err(|e: PolicyError| Response::error(400, e.to_string()))?;
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok())
+            .ok_or_else(|| Response::error(400, "Expected a JSON body"))?;
+        let rule = Value::object([
+            ("subject", Value::from(subject.to_string())),
+            (
+                "actions",
+                body.get("actions").cloned().unwrap_or(Value::Null),
+            ),
+        ]);
+        let rule = Rule::from_json(&rule).map_err(|e| Response::error(400, e.to_string()))?;
+        self.bridge
+            .policy
+            .set(rule.clone())
+            .map_err(|e| Response::error(500, e.to_string()))?;
+        Ok(Response::json(200, &rule.to_json()))
+    }
+
+    /// Take the decision in the body, a [`ModerationDecision`]
+    fn moderation_decision(&self, request: &Request) -> Result<Response, Response> {
+        let body = std::str::from_utf8(&request.body)
+            .ok()
+            .and_then(|b| json::parse(b).ok())
+            .ok_or_else(|| Response::error(400, "Expected a JSON body"))?;
+        let decided = ModerationDecision::from_json(&body)
+            .and_then(|decision| modstream::decide(&self.bridge, &decision));
+        match decided {
+            Ok(taken) => Ok(Response::json(200, &taken)),
+            Err(e) => Err(Response::error(e.status(), e.to_string())),
+        }
+    }
+
+    fn remove_policy(&self, subject: &str) -> Result<Response, Response> {
+        let subject: Subject = subject
+            .parse()
+            .map_err(|e: PolicyError| Response::error(400, e.to_string()))?;
+        match self.bridge.policy.remove(&subject) {
+            Ok(Some(_)) => Ok(Response::new(204)),
+            Ok(None) => Err(Response::error(
+                404,
+                format!("No rule exists for {subject}"),
+            )),
+            Err(e) => Err(Response::error(500, e.to_string())),
+        }
+    }
+
+    /// Filtered by `network`, `event`, `actor`, `rule` (which skipped it) and `since`, newest
+    /// first, `limit` at a time
+    fn decisions(&self, request: &Request) -> Result<Response, Response> {
+        let param = |name| request.query_param(name).map(str::to_string);
+        let invalid = |name| Response::error(400, format!("Invalid {name}"));
+        let query = DecisionQuery {
+            network: match request.query_param("network") {
+                Some(network) => Some(Network::parse(network).ok_or_else(|| invalid("network"))?),
+                None => None,
+            },
+            event: param("event"),
+            actor: param("actor"),
+            rule: match request.query_param("rule") {
+                Some(rule) => Some(Reason::parse(rule).ok_or_else(|| invalid("rule"))?),
+                None => None,
+            },
+            since: match request.query_param("since") {
+                Some(since) => Some(parse_rfc3339(since).map_err(|_| invalid("since"))?),
+                None => None,
+            },
+            limit: match request.query_param("limit") {
+                Some(limit) => limit.parse().map_err(|_| invalid("limit"))?,
+                None => DEFAULT_AUDIT_LIMIT,
+            },
+        };
+        let decisions = self.bridge.decisions.query(&query);
+        let items = decisions.iter().map(Decision::to_json).collect();
+        Ok(Response::json(
+            200,
+            &Value::object([("decisions", Value::Array(items))]),
+        ))
+    }
+
+    fn stats(&self, request: &Request) -> Result<Response, Response> {
+        let invalid = |name| Response::error(400, format!("Invalid {name}"));
+        let time = |name| match request.query_param(name) {
+            Some(time) => parse_rfc3339(time).map(Some).map_err(|_| invalid(name)),
+            None => Ok(None),
+        };
+        let query = StatsQuery {
+            since: time("since")?,
+            until: time("until")?,
+            top: match request.query_param("top") {
+                Some(top) => top.parse().map_err(|_| invalid("top"))?,
+                None => DEFAULT_TOP,
+            },
+        };
+        Ok(Response::json(
+            200,
+            &self.bridge.stats.report(&query).to_json(),
+        ))
+    }
+
+    fn dry_run(&self, request: &Request) -> Result<Response, Response> {
+        let Some(log) = &self.bridge.dry_run else {
+            return Err(Response::error(404, "This isn't a dry run"));
+        };
+        let limit = match request.query_param("limit") {
+            Some(limit) => limit
+                .parse()
+                .map_err(|_| Response::error(400, "Invalid limit"))?,
+            None => DEFAULT_AUDIT_LIMIT,
+        };
+        let items = log.recent(limit).iter().map(Captured::to_json).collect();
+        Ok(Response::json(
+            200,
+            &Value::object([("requests", Value::Array(items))]),
+        ))
+    }
+
+    fn receipts(&self, request: &Request) -> Result<Response, Response> {
+        let post = request
+            .query_param("post")
+            .ok_or_else(|| Response::error(400, "Missing post"))?;
+        let host = request.query_param("host");
+        let receipts = self.bridge.receipts.for_post(post, host);
+        Ok(Response::json(
+            200,
+            &receipts::summary_json(post, &receipts),
+        ))
+    }
+
+    fn diagnose(&self, request: &Request) -> Result<Response, Response> {
+        let identity = request
+            .query_param("identity")
+            .ok_or_else(|| Response::error(400, "Missing identity"))?;
+        let report = diagnose::diagnose(&self.bridge, identity);
+        Ok(Response::json(200, &report.to_json()))
+    }
+
+    fn preview(&self, request: &Request) -> Result<Response, Response> {
+        let url = request
+            .query_param("url")
+            .ok_or_else(|| Response::error(400, "Missing url"))?;
+        match preview::preview(&self.bridge, url, SystemTime::now()) {
+            Ok(preview) => Ok(Response::json(200, &preview.to_json())),
+            Err(e) => Err(Response::error(e.status(), e.to_string())),
+        }
+    }
+
+    fn route(&self, request: &Request) -> Result<Response, Response> {
+        use Method::*;
+        match (request.method, request.segments().as_slice()) {
+            (Get, ["admin", "identities"]) => Ok(self.list_identities(request)),
+            (Get, ["admin", "identities", "lookalikes"]) => {
+                let flags = lookalike::flagged(&self.bridge.identities);
+                let flags = flags.iter().map(|flag| flag.to_json()).collect();
+                Ok(Response::json(200, &Value::Array(flags)))
+            }
+            (Post, ["admin", "identities", "import"]) => self.import(request),
+            (Post, ["admin", "identities", "enroll"]) => self.enroll(request),
+            (Get, ["admin", "approvals"]) => Ok(self.approvals()),
+            (Post, ["admin", "approvals", did]) => self.approve(did),
+            (Delete, ["admin", "approvals", did]) => self.reject(did),
+            (Post, ["admin", "identities", did, "pause"]) => {
+                self.set_status(did, MappingStatus::Paused)
+            }
+            (Post, ["admin", "identities", did, "resume"]) => {
+                self.set_status(did, MappingStatus::Active)
+            }
+            (Put, ["admin", "identities", did, "preferences"]) => {
+                self.set_preferences(did, request)
+            }
+            (Delete, ["admin", "identities", did]) => self.remove_identity(did),
+            (Get, ["admin", "identities", did, "export"]) => self.export(did, request),
+            (Get, ["admin", "identities", did, "consent"]) => self.consent(did),
+            (Post, ["admin", "identities", did, "unbridge"]) => self.unbridge(did, request),
+            (Get, ["admin", "deletions"]) => Ok(self.deletions()),
+            (Get, ["admin", "audit"]) => self.audit(request),
+            (Get, ["admin", "deliveries", "failed"]) => Ok(self.failed_deliveries()),
+            (Post, ["admin", "deliveries", id, "retry"]) => self.retry_delivery(id),
+            (Get, ["admin", "firehose"]) => Ok(self.firehose()),
+            (Get, ["admin", "firehose", "shedding"]) => Ok(Response::json(
+                200,
+                &self.bridge.shedding.to_json(self.bridge.firehose.lag()),
+            )),
+            (Get, ["admin", "signatures"]) => Ok(self.signatures()),
+            (Get, ["admin", "relays"]) => Ok(self.relays()),
+            (Get, ["admin", "breakers"]) => Ok(Response::json(
+                200,
+                &self.bridge.breakers.to_json(SystemTime::now()),
+            )),
+            (Delete, ["admin", "breakers", host]) => self.reset_breaker(host),
+            (Get, ["admin", "upstreams"]) => {
+                Ok(Response::json(200, &self.bridge.upstreams.to_json()))
+            }
+            (Get, ["admin", "concurrency"]) => {
+                Ok(Response::json(200, &self.bridge.concurrency.to_json()))
+            }
+            (Get, ["admin", "peers"]) => Ok(Response::json(
+                200,
+                &Value::object([("peers", self.bridge.peers.to_json())]),
+            )),
+            (Post, ["admin", "backfills", did]) => self.request_backfill(did),
+            (Get, ["admin", "reconcile"]) => {
+                Ok(Response::json(200, &self.bridge.reconciliation.to_json()))
+            }
+            (Post, ["admin", "reconcile", did]) => self.reconcile(did),
+            (Get, ["admin", "quotas"]) => Ok(Response::json(
+                200,
+                &self.bridge.quota_usage.to_json(&self.bridge.quotas),
+            )),
+            (Delete, ["admin", "quotas", did]) => {
+                let did = parse_did(did)?;
+                self.bridge.quota_usage.reset(&did);
+                Ok(Response::new(204))
+            }
+            (Get, ["admin", "suspensions"]) => {
+                let suspensions = self.bridge.sanctions.suspensions();
+                let items = suspensions.iter().map(|s| s.to_json()).collect();
+                Ok(Response::json(
+                    200,
+                    &Value::object([("suspensions", Value::Array(items))]),
+                ))
+            }
+            (Post, ["admin", "suspensions", did]) => self.suspend(did, request),
+            (Delete, ["admin", "suspensions", did]) => self.unsuspend(did),
+            (Get, ["admin", "defederations"]) => {
+                let defederations = self.bridge.sanctions.defederations();
+                let items = defederations.iter().map(|d| d.to_json()).collect();
+                Ok(Response::json(
+                    200,
+                    &Value::object([("defederations", Value::Array(items))]),
+                ))
+            }
+            (Post, ["admin", "defederations", domain]) => self.defederate(domain, request),
+            (Delete, ["admin", "defederations", domain]) => self.refederate(domain),
+            (Get, ["admin", "moderation", "events"]) => {
+                modstream::subscribe(self.bridge.clone(), request)
+            }
+            (Post, ["admin", "moderation", "decisions"]) => self.moderation_decision(request),
+            (Get, ["admin", "policy"]) => Ok(self.policy()),
+            (Put, ["admin", "policy", subject]) => self.set_policy(subject, request),
+            (Delete, ["admin", "policy", subject]) => self.remove_policy(subject),
+            (Get, ["admin", "diagnose"]) => self.diagnose(request),
+            (Get, ["admin", "preview"]) => self.preview(request),
+            (Get, ["admin", "decisions"]) => self.decisions(request),
+            (Get, ["admin", "receipts"]) => self.receipts(request),
+            (Get, ["admin", "dry-run"]) => self.dry_run(request),
+            (Get, ["admin", "stats"]) => self.stats(request),
+            _ => Err(Response::error(404, "Not found")),
+        }
+    }
+}
+
+impl Handler for AdminApi {
+    fn handle(&self, request: &Request) -> Response {
+        if !self.authorised(request) {
+            return Response::error(401, "Missing or invalid admin token");
+        }
+        self.route(request).unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delivery::Delivery;
+    use crate::error::Category;
+    use crate::moderation::ModerationConfig;
+    use std::time::Duration;
+
+    const TOKEN: &str = "hunter2";
+
+    fn api() -> (AdminApi, Arc<Bridge>) {
+        let bridge = Arc::new(Bridge::new());
+        let did = atproto::did!("did:plc:aaaa");
+        bridge
+            .identities
+            .insert(Mapping::new(did, "https://bridge.example/users/aaaa"));
+        (AdminApi::new(bridge.clone(), TOKEN), bridge)
+    }
+
+    fn authed(method: Method, target: &str) -> Request {
+        Request::new(method, target).with_header("Authorization", &format!("Bearer {TOKEN}"))
+    }
+
+    #[test]
+    fn rejects_missing_and_wrong_tokens() {
+        let (api, _) = api();
+        let request = Request::new(Method::Get, "/admin/identities");
+        assert_eq!(api.handle(&request).status, 401);
+        let request = request.with_header("Authorization", "Bearer hunter3");
+        assert_eq!(api.handle(&request).status, 401);
+    }
+
+    #[test]
+    fn pause_then_list() {
+        let (api, bridge) = api();
+        let response = api.handle(&authed(
+            Method::Post,
+            "/admin/identities/did:plc:aaaa/pause",
+        ));
+        assert_eq!(response.status, 204);
+        let did = atproto::did!("did:plc:aaaa");
+        assert_eq!(
+            bridge.identities.get(&did).unwrap().status,
+            MappingStatus::Paused
+        );
+
+        let response = api.handle(&authed(Method::Get, "/admin/identities?q=aaaa"));
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains(r#""status":"paused""#));
+    }
+
+    #[test]
+    fn set_preferences() {
+        let (api, bridge) = api();
+        let target = "/admin/identities/did:plc:aaaa/preferences";
+        let request = authed(Method::Put, target).with_body(r#"{"requireAltText": true}"#);
+        assert_eq!(api.handle(&request).status, 204);
+        let did = atproto::did!("did:plc:aaaa");
+        assert!(
+            bridge
+                .identities
+                .get(&did)
+                .unwrap()
+                .preferences
+                .require_alt_text
+        );
+        let request = authed(Method::Put, target).with_body(r#"{"requireAltText": 1}"#);
+        assert_eq!(api.handle(&request).status, 400);
+    }
+
+    #[test]
+    fn unknown_identity_is_404() {
+        let (api, _) = api();
+        let response = api.handle(&authed(Method::Delete, "/admin/identities/did:plc:zzzz"));
+        assert_eq!(response.status, 404);
+        let response = api.handle(&authed(Method::Delete, "/admin/identities/not-a-did"));
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn retry_failed_delivery() {
+        let (api, bridge) = api();
+        let delivery = Delivery::new("https://remote.example/inbox", "{}");
+        let id = bridge.jobs.push(Job::Deliver(delivery)).unwrap();
+        for _ in 0..crate::jobs::MAX_ATTEMPTS {
+            let job = bridge
+                .jobs
+                .take(SystemTime::now() + Duration::from_secs(86400))
+                .unwrap();
+            bridge
+                .jobs
+                .fail(job.id, "timeout", Category::Retryable, SystemTime::now())
+                .unwrap();
+        }
+        let response = api.handle(&authed(Method::Get, "/admin/deliveries/failed"));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains("timeout"));
+
+        let target = format!("/admin/deliveries/{id}/retry");
+        assert_eq!(api.handle(&authed(Method::Post, &target)).status, 202);
+        assert_eq!(bridge.jobs.len(), 1);
+        assert_eq!(api.handle(&authed(Method::Post, &target)).status, 404);
+    }
+
+    #[test]
+    fn export_as_json_or_car() {
+        let (api, _) = api();
+        let response = api.handle(&authed(
+            Method::Get,
+            "/admin/identities/did:plc:aaaa/export",
+        ));
+        assert_eq!(response.status, 200);
+        let body = json::parse(&String::from_utf8(response.body).unwrap()).unwrap();
+        assert_eq!(
+            body.get("mapping").and_then(|m| m.get("actor")),
+            Some(&Value::from("https://bridge.example/users/aaaa"))
+        );
+        let target = "/admin/identities/did:plc:aaaa/export?format=car";
+        let response = api.handle(&authed(Method::Get, target));
+        assert_eq!(
+            response.header("content-type"),
+            Some(export::CAR_MEDIA_TYPE)
+        );
+        let target = "/admin/identities/did:plc:zzzz/export";
+        assert_eq!(api.handle(&authed(Method::Get, target)).status, 404);
+    }
+
+    #[test]
+    fn unbridge_is_audited() {
+        let (api, _) = api();
+        let target = "/admin/identities/did:plc:aaaa/unbridge";
+        // Without an instance actor, which side the identity is from is unknown
+        assert_eq!(api.handle(&authed(Method::Post, target)).status, 409);
+
+        let bridge = Arc::new(Bridge::new().with_moderation(ModerationConfig {
+            instance_actor: Some("https://bridge.example/actor".to_string()),
+            ..ModerationConfig::default()
+        }));
+        let did = atproto::did!("did:plc:aaaa");
+        bridge
+            .identities
+            .insert(Mapping::new(did, "https://bridge.example/users/aaaa"));
+        let api = AdminApi::new(bridge.clone(), TOKEN);
+        let request = authed(Method::Post, target).with_body(r#"{"requestedBy": "support#12"}"#);
+        assert_eq!(api.handle(&request).status, 200);
+        assert_eq!(api.handle(&authed(Method::Post, target)).status, 404);
+        let response = api.handle(&authed(Method::Get, "/admin/deletions"));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains(r#""requestedBy":"support#12""#));
+    }
+
+    #[test]
+    fn backfill_requires_mapping() {
+        let (api, bridge) = api();
+        let response = api.handle(&authed(Method::Post, "/admin/backfills/did:plc:aaaa"));
+        assert_eq!(response.status, 202);
+        let response = api.handle(&authed(Method::Post, "/admin/backfills/did:plc:aaaa"));
+        assert!(String::from_utf8(response.body)
+            .unwrap()
+            .contains(r#""queued":false"#));
+        assert_eq!(bridge.jobs.len(), 1);
+        let response = api.handle(&authed(Method::Post, "/admin/backfills/did:plc:zzzz"));
+        assert_eq!(response.status, 404);
+    }
+
+    #[test]
+    fn edit_policy() {
+        let (api, bridge) = api();
+        let request = authed(Method::Put, "/admin/policy/spam.example")
+            .with_body(r#"{"actions": ["silence", "cw:Spam"]}"#);
+        assert_eq!(api.handle(&request).status, 200);
+        assert!(bridge.policy.verdict(Some("spam.example"), None).silenced);
+        let response = api.handle(&authed(Method::Get, "/admin/policy"));
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains(r#""actions":["silence","cw:Spam"]"#));
+
+        let request =
+            authed(Method::Put, "/admin/policy/spam.example").with_body(r#"{"actions": ["mute"]}"#);
+        assert_eq!(api.handle(&request).status, 400);
+        let remove = authed(Method::Delete, "/admin/policy/spam.example");
+        assert_eq!(api.handle(&remove).status, 204);
+        assert_eq!(api.handle(&remove).status, 404);
+    }
+}
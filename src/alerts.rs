@@ -0,0 +1,556 @@
+//! Emails alerting operators to critical conditions
+//!
+//! A monitor checks, every [`AlertConfig::check_interval`], for:
+//!
+//! - `keyRotation`: one of the bridge's own keys is within a week of
+//!   [`AlertConfig::key_max_age`], so is due to be rotated
+//! - `deliveryFailures`: more than [`AlertConfig::failure_percent`] of delivery attempts since
+//!   the last check failed, going by the [statistics](crate::stats)
+//! - `firehoseSilent`: the relay hasn't sent an event for longer than
+//!   [`AlertConfig::firehose_silence`]
+//!
+//! Each alert is an email rendered from the subject and body templates, whose `{name}`
+//! placeholders are filled with the alert's fields: `summary`, `details`, `kind`, `at` and
+//! `suppressed`. Sending is rate limited per kind. While a condition holds it's emailed at
+//! most once per [`AlertConfig::min_interval`] (a day, for key rotations), and the next
+//! email counts the checks held back in `suppressed`. An email which couldn't be sent is
+//! tried again at the next check
+//!
+//! Emails go out over SMTP, without TLS, so to a relay on the same host or a trusted
+//! network, which is given any credentials with `AUTH PLAIN`
+
+use crate::bridge::Bridge;
+use crate::crypto::base64_encode;
+use crate::keys::{KeyOwner, KeyPurpose};
+use crate::shutdown::Shutdown;
+use crate::stats::StatsQuery;
+use crate::time::{format_http_date, format_rfc3339};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long before a key reaches its maximum age that it's alerted on
+pub const KEY_ROTATION_NOTICE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often a due key rotation is emailed at most, whatever the interval
+const KEY_ROTATION_REPEAT: Duration = Duration::from_secs(24 * 60 * 60);
+/// Failure rates over fewer attempts than this are too noisy to alert on
+const MIN_DELIVERY_ATTEMPTS: u64 = 20;
+pub const DEFAULT_SUBJECT: &str = "[fedibridge] {summary}";
+pub const DEFAULT_BODY: &str = "{summary}\n\n{details}\n\nChecked at {at}. {suppressed} \
+    earlier checks found this too, without an email being sent.\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    KeyRotation,
+    DeliveryFailures,
+    FirehoseSilent,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 3] = [
+        AlertKind::KeyRotation,
+        AlertKind::DeliveryFailures,
+        AlertKind::FirehoseSilent,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::KeyRotation => "keyRotation",
+            AlertKind::DeliveryFailures => "deliveryFailures",
+            AlertKind::FirehoseSilent => "firehoseSilent",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<AlertKind> {
+        AlertKind::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A critical condition the bridge is in
+pub enum Alert {
+    KeyRotationDue {
+        /// The keys due, with how old each is
+        keys: Vec<(KeyPurpose, Duration)>,
+        max_age: Duration,
+    },
+    DeliveryFailures {
+        failed: u64,
+        attempted: u64,
+        percent: u32,
+    },
+    FirehoseSilent {
+        silent_for: Duration,
+        threshold: Duration,
+    },
+}
+
+fn days(duration: Duration) -> u64 {
+    duration.as_secs() / (24 * 60 * 60)
+}
+
+impl Alert {
+    pub fn kind(&self) -> AlertKind {
+        match self {
+            Alert::KeyRotationDue { .. } => AlertKind::KeyRotation,
+            Alert::DeliveryFailures { .. } => AlertKind::DeliveryFailures,
+            Alert::FirehoseSilent { .. } => AlertKind::FirehoseSilent,
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        match self {
+            Alert::KeyRotationDue { keys, .. } => {
+                format!("{} of the bridge's keys are due to be rotated", keys.len())
+            }
+            Alert::DeliveryFailures {
+                failed, attempted, ..
+            } => format!("{failed} of {attempted} recent delivery attempts failed"),
+            Alert::FirehoseSilent { silent_for, .. } => format!(
+                "The firehose has been silent for {} seconds",
+                silent_for.as_secs()
+            ),
+        }
+    }
+
+    pub fn details(&self) -> String {
+        match self {
+            Alert::KeyRotationDue { keys, max_age } => {
+                let keys = keys.iter().map(|(purpose, age)| {
+                    format!("- {}: {} days old", purpose.as_str(), days(*age))
+                });
+                format!(
+                    "Keys are to be rotated once they're {} days old:\n{}",
+                    days(*max_age),
+                    keys.collect::<Vec<_>>().join("\n")
+                )
+            }
+            Alert::DeliveryFailures { percent, .. } => format!(
+                "More than {percent}% failed. The admin API's /admin/stats has the commonest reasons"
+            ),
+            Alert::FirehoseSilent { threshold, .. } => format!(
+                "No event has arrived from the relay for more than {} seconds",
+                threshold.as_secs()
+            ),
+        }
+    }
+}
+
+/// `template` with each `{name}` replaced by the value of the field `name`. Unknown
+/// placeholders are left as they are
+pub fn render(template: &str, fields: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + 1..];
+        let value = placeholder.find('}').and_then(|end| {
+            let field = fields.iter().find(|(name, _)| *name == &placeholder[..end]);
+            field.map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = placeholder;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Where alerts are sent from and to
+pub struct SmtpConfig {
+    /// The relay, as `host:port`
+    pub server: String,
+    /// The name given in `EHLO`
+    pub helo: String,
+    pub credentials: Option<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlertConfig {
+    /// Without a relay, no alerts are sent
+    pub smtp: Option<SmtpConfig>,
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    /// How often a condition which holds is emailed at most
+    pub min_interval: Duration,
+    pub check_interval: Duration,
+    /// How old the bridge's keys may get before they're due to be rotated, if they are
+    pub key_max_age: Option<Duration>,
+    /// The share of delivery attempts failing which is alerted on, if any
+    pub failure_percent: Option<u32>,
+    /// How long the firehose may be silent before it's alerted on, if at all
+    pub firehose_silence: Option<Duration>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> AlertConfig {
+        AlertConfig {
+            smtp: None,
+            from: "fedibridge@localhost".to_string(),
+            to: Vec::new(),
+            subject: DEFAULT_SUBJECT.to_string(),
+            body: DEFAULT_BODY.to_string(),
+            min_interval: Duration::from_secs(60 * 60),
+            check_interval: Duration::from_secs(60),
+            key_max_age: None,
+            failure_percent: Some(50),
+            firehose_silence: Some(Duration::from_secs(5 * 60)),
+        }
+    }
+}
+
+impl AlertConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.smtp.is_some() && !self.to.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// The message as sent in `DATA`, with CRLF line endings and dot-stuffed
+    pub fn to_message(&self, at: SystemTime) -> String {
+        let headers = [
+            format!("From: {}", self.from),
+            format!("To: {}", self.to.join(", ")),
+            format!("Subject: {}", self.subject.replace(['\r', '\n'], " ")),
+            format!("Date: {}", format_http_date(at).replace("GMT", "+0000")),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+        ];
+        let body = self.body.lines().map(|line| match line.starts_with('.') {
+            true => format!(".{line}"),
+            false => line.to_string(),
+        });
+        let lines: Vec<_> = headers
+            .into_iter()
+            .chain([String::new()])
+            .chain(body)
+            .collect();
+        lines.join("\r\n") + "\r\n"
+    }
+}
+
+/// Something which sends emails
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> io::Result<()>;
+}
+
+fn read_reply<S: Read>(stream: &mut S) -> io::Result<(u16, String)> {
+    let mut reply = String::new();
+    loop {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            if line.len() > 1024 {
+                return Err(io::Error::other("The SMTP server's reply is too long"));
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        let line = String::from_utf8_lossy(&line);
+        reply.push_str(&line);
+        let code = line.get(..3).and_then(|code| code.parse().ok());
+        let code = code.ok_or_else(|| io::Error::other("The SMTP server's reply is malformed"))?;
+        // Continuation lines have a hyphen after the code
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok((code, reply.trim_end().to_string()));
+        }
+    }
+}
+
+/// Send `command`, failing unless the reply's code starts with `expected`
+fn command<S: Read + Write>(stream: &mut S, command: &str, expected: u8) -> io::Result<()> {
+    if !command.is_empty() {
+        stream.write_all(format!("{command}\r\n").as_bytes())?;
+    }
+    let (code, reply) = read_reply(stream)?;
+    if code / 100 != u16::from(expected) {
+        return Err(io::Error::other(format!("The SMTP server replied {reply}")));
+    }
+    Ok(())
+}
+
+/// Send `email` over `stream`, already connected to an SMTP server
+pub fn deliver<S: Read + Write>(
+    stream: &mut S,
+    config: &SmtpConfig,
+    email: &Email,
+    at: SystemTime,
+) -> io::Result<()> {
+    command(stream, "", 2)?;
+    command(stream, &format!("EHLO {}", config.helo), 2)?;
+    if let Some((user, password)) = &config.credentials {
+        let token = base64_encode(format!("\0{user}\0{password}").as_bytes());
+        command(stream, &format!("AUTH PLAIN {token}"), 2)?;
+    }
+    command(stream, &format!("MAIL FROM:<{}>", email.from), 2)?;
+    for to in &email.to {
+        command(stream, &format!("RCPT TO:<{to}>"), 2)?;
+    }
+    command(stream, "DATA", 3)?;
+    command(stream, &format!("{}.", email.to_message(at)), 2)?;
+    // The email has been accepted, whatever happens now
+    let _ = command(stream, "QUIT", 2);
+    Ok(())
+}
+
+#[cfg(feature = "net")]
+/// Sends emails through an SMTP relay, a connection each
+pub struct Smtp(pub SmtpConfig);
+
+#[cfg(feature = "net")]
+impl Mailer for Smtp {
+    fn send(&self, email: &Email) -> io::Result<()> {
+        let timeout = Some(Duration::from_secs(30));
+        let mut stream = std::net::TcpStream::connect(&self.0.server)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        deliver(&mut stream, &self.0, email, SystemTime::now())
+    }
+}
+
+#[derive(Debug, Default)]
+/// What the monitor remembers between checks
+pub struct Monitor {
+    sent: HashMap<AlertKind, Instant>,
+    suppressed: HashMap<AlertKind, u64>,
+    /// The delivery attempts which had succeeded and failed as of the last check
+    deliveries: Option<(u64, u64)>,
+}
+
+impl Monitor {
+    /// The conditions the bridge is in now
+    pub fn check(&mut self, bridge: &Bridge, config: &AlertConfig, now: SystemTime) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        if let Some(max_age) = config.key_max_age {
+            let keys = KeyPurpose::ALL.into_iter().filter_map(|purpose| {
+                let key = bridge.keys.current(&KeyOwner::Bridge, purpose)?;
+                let age = now.duration_since(key.created_at).unwrap_or_default();
+                (age + KEY_ROTATION_NOTICE >= max_age).then_some((purpose, age))
+            });
+            let keys: Vec<_> = keys.collect();
+            if !keys.is_empty() {
+                alerts.push(Alert::KeyRotationDue { keys, max_age });
+            }
+        }
+
+        let query = StatsQuery {
+            top: usize::MAX,
+            ..StatsQuery::default()
+        };
+        let report = bridge.stats.report(&query);
+        let failures = report.failures.iter();
+        let failed: u64 = failures
+            .filter(|(reason, _)| reason.starts_with("deliver:"))
+            .map(|(_, count)| count)
+            .sum();
+        let delivered = report.totals.fediverse.activities;
+        let previous = self.deliveries.replace((delivered, failed));
+        if let (Some(percent), Some((was_delivered, was_failed))) =
+            (config.failure_percent, previous)
+        {
+            let failed = failed.saturating_sub(was_failed);
+            let attempted = failed + delivered.saturating_sub(was_delivered);
+            if attempted >= MIN_DELIVERY_ATTEMPTS && failed * 100 > attempted * u64::from(percent) {
+                alerts.push(Alert::DeliveryFailures {
+                    failed,
+                    attempted,
+                    percent,
+                });
+            }
+        }
+
+        let silent_for = bridge.firehose.silent_for(now);
+        if let Some((silent_for, threshold)) = silent_for.zip(config.firehose_silence) {
+            if silent_for > threshold {
+                alerts.push(Alert::FirehoseSilent {
+                    silent_for,
+                    threshold,
+                });
+            }
+        }
+        alerts
+    }
+
+    /// The email for `alert`, unless one was sent too recently
+    pub fn email(&mut self, config: &AlertConfig, alert: &Alert, now: SystemTime) -> Option<Email> {
+        let kind = alert.kind();
+        let interval = match kind {
+            AlertKind::KeyRotation => config.min_interval.max(KEY_ROTATION_REPEAT),
+            _ => config.min_interval,
+        };
+        if self
+            .sent
+            .get(&kind)
+            .is_some_and(|at| at.elapsed() < interval)
+        {
+            *self.suppressed.entry(kind).or_default() += 1;
+            return None;
+        }
+        let fields = [
+            ("summary", alert.summary()),
+            ("details", alert.details()),
+            ("kind", kind.as_str().to_string()),
+            ("at", format_rfc3339(now)),
+            (
+                "suppressed",
+                self.suppressed.get(&kind).copied().unwrap_or(0).to_string(),
+            ),
+        ];
+        Some(Email {
+            from: config.from.clone(),
+            to: config.to.clone(),
+            subject: render(&config.subject, &fields),
+            body: render(&config.body, &fields),
+        })
+    }
+
+    /// Record that the email for an alert of `kind` was sent
+    pub fn sent(&mut self, kind: AlertKind) {
+        self.sent.insert(kind, Instant::now());
+        self.suppressed.remove(&kind);
+    }
+}
+
+/// Check for alerts with a [`Monitor`] and email them, until shutdown. A failed email is only
+/// logged, to be tried again at the next check
+pub fn spawn(
+    bridge: Arc<Bridge>,
+    config: AlertConfig,
+    mailer: Arc<dyn Mailer>,
+    shutdown: Arc<Shutdown>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut monitor = Monitor::default();
+        let mut last_check: Option<Instant> = None;
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_check.is_some_and(|last| last.elapsed() < config.check_interval) {
+                continue;
+            }
+            last_check = Some(Instant::now());
+            let now = SystemTime::now();
+            for alert in monitor.check(&bridge, &config, now) {
+                let Some(email) = monitor.email(&config, &alert, now) else {
+                    continue;
+                };
+                match mailer.send(&email) {
+                    Ok(()) => monitor.sent(alert.kind()),
+                    Err(e) => eprintln!("Couldn't email the {} alert: {e}", alert.kind().as_str()),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// An SMTP server answering from a script, recording what it was sent
+    struct Scripted {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn email() -> Email {
+        Email {
+            from: "bridge@example.com".to_string(),
+            to: vec!["ops@example.com".to_string()],
+            subject: "Silent".to_string(),
+            body: "Line\n.dotted".to_string(),
+        }
+    }
+
+    #[test]
+    fn emails_are_sent_over_smtp() {
+        let config = SmtpConfig {
+            server: "127.0.0.1:25".to_string(),
+            helo: "bridge.example".to_string(),
+            credentials: Some(("ops".to_string(), "hunter2".to_string())),
+        };
+        let replies = "220 mx ready\r\n250-mx\r\n250 AUTH PLAIN\r\n235 ok\r\n250 ok\r\n\
+            250 ok\r\n354 go\r\n250 queued\r\n221 bye\r\n";
+        let mut server = Scripted {
+            replies: Cursor::new(replies.as_bytes().to_vec()),
+            sent: Vec::new(),
+        };
+        deliver(&mut server, &config, &email(), SystemTime::UNIX_EPOCH).unwrap();
+        let sent = String::from_utf8(server.sent).unwrap();
+        assert!(sent.starts_with(&format!(
+            "EHLO bridge.example\r\nAUTH PLAIN {}\r\nMAIL FROM:<bridge@example.com>\r\n\
+                RCPT TO:<ops@example.com>\r\nDATA\r\n",
+            base64_encode(b"\0ops\0hunter2")
+        )));
+        assert!(sent.contains("Subject: Silent\r\n"));
+        assert!(sent.ends_with("\r\nLine\r\n..dotted\r\n.\r\nQUIT\r\n"));
+
+        let mut refusing = Scripted {
+            replies: Cursor::new(b"220 mx\r\n250 mx\r\n535 no\r\n".to_vec()),
+            sent: Vec::new(),
+        };
+        assert!(deliver(&mut refusing, &config, &email(), SystemTime::UNIX_EPOCH).is_err());
+    }
+
+    #[test]
+    fn alerts_are_rendered_and_rate_limited() {
+        let bridge = Bridge::new();
+        let config = AlertConfig {
+            to: vec!["ops@example.com".to_string()],
+            subject: "{kind}: {summary} {unknown}".to_string(),
+            firehose_silence: Some(Duration::from_secs(60)),
+            ..AlertConfig::default()
+        };
+        let mut monitor = Monitor::default();
+        let now = SystemTime::now();
+        assert_eq!(monitor.check(&bridge, &config, now), []);
+        bridge.firehose.saw(1);
+        let later = now + Duration::from_secs(120);
+        let alerts = monitor.check(&bridge, &config, later);
+        assert!(matches!(alerts[..], [Alert::FirehoseSilent { .. }]));
+
+        let email = monitor.email(&config, &alerts[0], later).unwrap();
+        assert!(email
+            .subject
+            .starts_with("firehoseSilent: The firehose has been silent for 1"));
+        assert!(email.subject.ends_with(" {unknown}"));
+        monitor.sent(alerts[0].kind());
+        assert_eq!(monitor.email(&config, &alerts[0], later), None);
+        assert_eq!(monitor.suppressed[&AlertKind::FirehoseSilent], 1);
+    }
+}
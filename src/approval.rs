@@ -0,0 +1,217 @@
+//! Vetting accounts before they're bridged
+//!
+//! Bridges which want to know who they bridge, or to stay small, set
+//! `FEDIBRIDGE_REQUIRE_APPROVAL`. Then opting in doesn't start bridging an account, but asks
+//! to: [`Bridge::opt_in`] queues the request and refuses with
+//! [`OptInError::AwaitingApproval`], and the `approvalRequested` webhook fires. Operators
+//! look through the queue and decide with the admin API's `/admin/approvals`, or the
+//! `approvals`, `approve <did>` and `reject <did>` commands:
+//!
+//! - approving ([`approve`]) bridges the account as opting in would have
+//! - rejecting ([`reject`]) forgets the request, and the account may ask again
+//!
+//! Accounts already bridged aren't queued again, nor are those enrolled in bulk, whom an
+//! operator vouched for by enrolling them. The queue is kept at the root of the state
+//! directory, shared by every shard
+//!
+//! [`Bridge::opt_in`]: crate::bridge::Bridge::opt_in
+//! [`OptInError::AwaitingApproval`]: crate::interop::OptInError::AwaitingApproval
+
+use crate::bridge::Bridge;
+use crate::interop::OptInError;
+use crate::json::{self, Value};
+use crate::storage::StateDir;
+use crate::store::Mapping;
+use crate::time::{format_rfc3339, parse_rfc3339};
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+/// The approval queue's file in the state directory
+pub const APPROVALS_FILE: &str = "approvals.json";
+
+#[derive(Debug, Clone, PartialEq)]
+/// An account waiting to be bridged
+pub struct Application {
+    /// The mapping it'll be bridged with
+    pub mapping: Mapping,
+    pub requested_at: SystemTime,
+}
+
+impl Application {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("did", Value::from(self.mapping.did.as_str())),
+            ("actor", Value::from(self.mapping.actor.as_str())),
+            ("handle", Value::from(self.mapping.handle.clone())),
+            (
+                "requestedAt",
+                Value::from(format_rfc3339(self.requested_at)),
+            ),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// The accounts waiting for an operator to approve them, by DID
+pub struct ApprovalQueue {
+    pending: RwLock<BTreeMap<Did, Application>>,
+    /// Where the queue is kept, if anywhere
+    dir: Option<StateDir>,
+}
+
+impl ApprovalQueue {
+    /// The queue kept in `dir`
+    pub fn open(dir: StateDir) -> io::Result<ApprovalQueue> {
+        let mut pending = BTreeMap::new();
+        if let Some(contents) = dir.read(APPROVALS_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            for entry in saved.as_array().unwrap_or_default() {
+                let requested_at = entry.get("requestedAt").and_then(Value::as_str);
+                let (Some(mapping), Some(requested_at)) = (
+                    entry.get("mapping").and_then(Mapping::from_json),
+                    requested_at.and_then(|t| parse_rfc3339(t).ok()),
+                ) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid saved approval request",
+                    ));
+                };
+                let application = Application {
+                    mapping,
+                    requested_at,
+                };
+                pending.insert(application.mapping.did.clone(), application);
+            }
+        }
+        Ok(ApprovalQueue {
+            pending: RwLock::new(pending),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, pending: &BTreeMap<Did, Application>) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let entries = pending.values().map(|application| {
+            Value::object([
+                ("mapping", application.mapping.to_json()),
+                (
+                    "requestedAt",
+                    Value::from(format_rfc3339(application.requested_at)),
+                ),
+            ])
+        });
+        let saved = Value::Array(entries.collect());
+        dir.write(APPROVALS_FILE, saved.to_string().as_bytes())
+    }
+
+    /// Queue `mapping`'s account, returning whether it wasn't already. A second request
+    /// replaces the mapping, but keeps its place
+    pub fn submit(&self, mapping: Mapping, now: SystemTime) -> io::Result<bool> {
+        let mut pending = self.pending.write().unwrap();
+        let requested_at = pending.get(&mapping.did).map(|a| a.requested_at);
+        let application = Application {
+            mapping,
+            requested_at: requested_at.unwrap_or(now),
+        };
+        pending.insert(application.mapping.did.clone(), application);
+        self.save(&pending)?;
+        Ok(requested_at.is_none())
+    }
+
+    pub fn get(&self, did: &Did) -> Option<Application> {
+        self.pending.read().unwrap().get(did).cloned()
+    }
+
+    /// Every account waiting, longest waiting first
+    pub fn all(&self) -> Vec<Application> {
+        let mut all: Vec<_> = self.pending.read().unwrap().values().cloned().collect();
+        all.sort_by_key(|application| application.requested_at);
+        all
+    }
+
+    /// Take `did`'s request out of the queue
+    pub fn remove(&self, did: &Did) -> io::Result<Option<Application>> {
+        let mut pending = self.pending.write().unwrap();
+        let removed = pending.remove(did);
+        if removed.is_some() {
+            self.save(&pending)?;
+        }
+        Ok(removed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Start bridging `did`'s account as it asked to, returning its mapping, or `None` if it
+/// isn't waiting. An account which can't be bridged after all stays in the queue
+pub fn approve(bridge: &Bridge, did: &Did) -> Result<Option<Mapping>, OptInError> {
+    let Some(application) = bridge.approvals.get(did) else {
+        return Ok(None);
+    };
+    bridge.activate(application.mapping.clone())?;
+    bridge.approvals.remove(did)?;
+    Ok(Some(application.mapping))
+}
+
+/// Turn down `did`'s request, returning it if there was one
+pub fn reject(bridge: &Bridge, did: &Did) -> io::Result<Option<Application>> {
+    bridge.approvals.remove(did)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::MappingStatus;
+    use atproto::did;
+
+    #[test]
+    fn opting_in_waits_for_approval() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            approvals: ApprovalQueue::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        }
+        .with_approval_required(true);
+        let alice = did!("did:web:bridge.example:u:alice");
+        let mapping = Mapping::new(alice.clone(), "https://a.example/users/alice");
+        assert!(matches!(
+            bridge.opt_in(mapping.clone()),
+            Err(OptInError::AwaitingApproval)
+        ));
+        assert!(!bridge.identities.is_tracked(&alice));
+        let bob = did!("did:web:bridge.example:u:bob");
+        bridge
+            .opt_in(Mapping::new(bob.clone(), "https://b.example/users/bob"))
+            .unwrap_err();
+
+        // The queue outlives a restart
+        let bridge = Bridge {
+            approvals: ApprovalQueue::open(dir).unwrap(),
+            ..bridge
+        };
+        assert_eq!(bridge.approvals.all()[0].mapping, mapping);
+        assert_eq!(approve(&bridge, &alice).unwrap(), Some(mapping.clone()));
+        let bridged = bridge.identities.get(&alice).unwrap();
+        assert_eq!(bridged.status, MappingStatus::Active);
+        // Once bridged, opting in again isn't held up
+        assert!(bridge.opt_in(mapping).is_ok());
+
+        assert!(reject(&bridge, &bob).unwrap().is_some());
+        assert!(bridge.approvals.is_empty());
+        assert_eq!(approve(&bridge, &bob).unwrap(), None);
+        assert!(!bridge.identities.is_tracked(&bob));
+    }
+}
@@ -0,0 +1,425 @@
+//! Reading posts and profiles from the Bluesky AppView
+//!
+//! The bridge asks the AppView (`FEDIBRIDGE_APPVIEW`) for the context a post is translated
+//! with: the thread it's in, its author's profile, and who liked and reposted it.
+//! [`AppView`] makes those queries and hands back their views as types:
+//!
+//! | Query                         | Method                      | Gives                  |
+//! |-------------------------------|-----------------------------|------------------------|
+//! | `app.bsky.feed.getPostThread` | [`AppView::post_thread`]    | [`ThreadView`]         |
+//! | `app.bsky.actor.getProfile`   | [`AppView::profile`]        | [`ProfileView`]        |
+//! | `app.bsky.feed.getLikes`      | [`AppView::likes`]          | [`Page`] of [`Like`]s  |
+//! | `app.bsky.feed.getRepostedBy` | [`AppView::reposted_by`]    | [`Page`] of profiles   |
+//!
+//! Records are left as JSON, as their types are open-ended, and each [`PostView`] keeps the
+//! view it was read from for anything not typed here. A post or account which isn't there
+//! (the AppView says `400`) is `None` rather than an error
+
+use crate::bridge::Bridge;
+use crate::http::percent_encode;
+use crate::json::{self, Value};
+use crate::normalize;
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use atproto::DID::Did;
+use thiserror::Error;
+
+pub const GET_POST_THREAD: &str = "app.bsky.feed.getPostThread";
+pub const GET_PROFILE: &str = "app.bsky.actor.getProfile";
+pub const GET_LIKES: &str = "app.bsky.feed.getLikes";
+pub const GET_REPOSTED_BY: &str = "app.bsky.feed.getRepostedBy";
+/// The `$type` of a thread the AppView could show, rather than one not found or blocked
+pub(crate) const THREAD_VIEW: &str = "app.bsky.feed.defs#threadViewPost";
+const BLOCKED_POST: &str = "app.bsky.feed.defs#blockedPost";
+/// The most the AppView hands back a page
+const PAGE_LIMIT: usize = 100;
+
+#[derive(Debug, Error)]
+pub enum AppViewError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("The AppView responded to {method} with {status}")]
+    Rejected { method: &'static str, status: u16 },
+    #[error("The AppView's response to {method} wasn't a view")]
+    Malformed { method: &'static str },
+}
+
+/// A count the AppView gives, if it gives it
+fn count(view: &Value, name: &str) -> Option<u64> {
+    view.get(name)?.as_i64().and_then(|n| u64::try_from(n).ok())
+}
+
+fn text(view: &Value, name: &str) -> Option<String> {
+    view.get(name)?.as_str().map(str::to_string)
+}
+
+/// The values of the labels on a view
+fn labels(view: &Value) -> Vec<String> {
+    let labels = view.get("labels").and_then(Value::as_array);
+    let labels = labels.unwrap_or_default().iter();
+    labels.filter_map(|label| text(label, "val")).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An account, as much of it as the view has. Authors of posts come without their
+/// description, banner or counts
+pub struct ProfileView {
+    pub did: Did,
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub description: Option<String>,
+    pub avatar: Option<String>,
+    pub banner: Option<String>,
+    /// The values of the labels on it
+    pub labels: Vec<String>,
+    pub followers_count: Option<u64>,
+    pub follows_count: Option<u64>,
+    pub posts_count: Option<u64>,
+}
+
+impl ProfileView {
+    pub fn from_json(view: &Value) -> Option<ProfileView> {
+        Some(ProfileView {
+            did: normalize::parse_did(view.get("did")?.as_str()?).ok()?,
+            handle: text(view, "handle")?,
+            display_name: text(view, "displayName"),
+            description: text(view, "description"),
+            avatar: text(view, "avatar"),
+            banner: text(view, "banner"),
+            labels: labels(view),
+            followers_count: count(view, "followersCount"),
+            follows_count: count(view, "followsCount"),
+            posts_count: count(view, "postsCount"),
+        })
+    }
+
+    /// Whether it has a label with the value `val`
+    pub fn is_labeled(&self, val: &str) -> bool {
+        self.labels.iter().any(|label| label == val)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A post, with who wrote it and how it's been interacted with
+pub struct PostView {
+    pub uri: String,
+    pub cid: String,
+    pub author: ProfileView,
+    /// The `app.bsky.feed.post` record
+    pub record: Value,
+    pub reply_count: Option<u64>,
+    pub repost_count: Option<u64>,
+    pub like_count: Option<u64>,
+    pub quote_count: Option<u64>,
+    pub indexed_at: Option<String>,
+    pub labels: Vec<String>,
+    /// The threadgate record on the thread, if the view has one
+    pub threadgate: Option<Value>,
+    /// The view itself
+    pub view: Value,
+}
+
+impl PostView {
+    pub fn from_json(view: &Value) -> Option<PostView> {
+        Some(PostView {
+            uri: text(view, "uri")?,
+            cid: text(view, "cid")?,
+            author: ProfileView::from_json(view.get("author")?)?,
+            record: view.get("record")?.clone(),
+            reply_count: count(view, "replyCount"),
+            repost_count: count(view, "repostCount"),
+            like_count: count(view, "likeCount"),
+            quote_count: count(view, "quoteCount"),
+            indexed_at: text(view, "indexedAt"),
+            labels: labels(view),
+            threadgate: view
+                .get("threadgate")
+                .and_then(|gate| gate.get("record"))
+                .cloned(),
+            view: view.clone(),
+        })
+    }
+
+    /// The URI of the root of the thread it replies in, if it's a reply
+    pub fn root(&self) -> Option<&str> {
+        self.record.get("reply")?.get("root")?.get("uri")?.as_str()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A post in its thread, or where one would be
+pub enum ThreadView {
+    Post {
+        post: Box<PostView>,
+        /// What it replies to, as far up as was asked for
+        parent: Option<Box<ThreadView>>,
+        /// What replies to it, as far down as was asked for
+        replies: Vec<ThreadView>,
+    },
+    /// It's been deleted, or was never there
+    NotFound { uri: String },
+    /// Its author, or the viewer, has blocked the other
+    Blocked { uri: String },
+}
+
+impl ThreadView {
+    pub fn from_json(view: &Value) -> Option<ThreadView> {
+        let uri = || text(view, "uri");
+        match view.get("$type")?.as_str()? {
+            THREAD_VIEW => Some(ThreadView::Post {
+                post: Box::new(PostView::from_json(view.get("post")?)?),
+                parent: view
+                    .get("parent")
+                    .and_then(ThreadView::from_json)
+                    .map(Box::new),
+                replies: view
+                    .get("replies")
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(ThreadView::from_json)
+                    .collect(),
+            }),
+            BLOCKED_POST => Some(ThreadView::Blocked { uri: uri()? }),
+            _ => Some(ThreadView::NotFound { uri: uri()? }),
+        }
+    }
+
+    /// The post, if it could be shown
+    pub fn post(&self) -> Option<&PostView> {
+        match self {
+            ThreadView::Post { post, .. } => Some(post.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Someone's like of a post
+pub struct Like {
+    pub actor: ProfileView,
+    pub created_at: Option<String>,
+    pub indexed_at: Option<String>,
+}
+
+impl Like {
+    pub fn from_json(view: &Value) -> Option<Like> {
+        Some(Like {
+            actor: ProfileView::from_json(view.get("actor")?)?,
+            created_at: text(view, "createdAt"),
+            indexed_at: text(view, "indexedAt"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One page of a list the AppView pages through
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Where the next page starts, if there is one
+    pub cursor: Option<String>,
+}
+
+/// The AppView, as the bridge's transport reaches it
+pub struct AppView<'a> {
+    transport: &'a dyn HttpTransport,
+    base: &'a str,
+}
+
+impl<'a> AppView<'a> {
+    pub fn new(transport: &'a dyn HttpTransport, base: &'a str) -> AppView<'a> {
+        AppView {
+            transport,
+            base: base.trim_end_matches('/'),
+        }
+    }
+
+    /// The AppView `bridge` is configured with
+    pub fn of(bridge: &'a Bridge) -> AppView<'a> {
+        AppView::new(bridge.transport.as_ref(), &bridge.feeds.appview)
+    }
+
+    /// Call `method` with `params`, or `None` for a 400, as for something not there
+    fn query(
+        &self,
+        method: &'static str,
+        params: &[(&str, &str)],
+    ) -> Result<Option<Value>, AppViewError> {
+        let params: Vec<String> = params
+            .iter()
+            .map(|(name, value)| format!("{name}={}", percent_encode(value)))
+            .collect();
+        let url = format!("{}/xrpc/{method}?{}", self.base, params.join("&"));
+        let response = self.transport.send(&OutboundRequest::get(url))?;
+        if response.status == 400 {
+            return Ok(None);
+        }
+        if !response.is_success() {
+            let status = response.status;
+            return Err(AppViewError::Rejected { method, status });
+        }
+        let body = json::parse(&String::from_utf8_lossy(&response.body));
+        body.map(Some)
+            .map_err(|_| AppViewError::Malformed { method })
+    }
+
+    /// The thread around the post at `uri`, `depth` replies down and `parent_height`
+    /// parents up
+    pub fn post_thread(
+        &self,
+        uri: &str,
+        depth: u32,
+        parent_height: u32,
+    ) -> Result<Option<ThreadView>, AppViewError> {
+        let (depth, height) = (depth.to_string(), parent_height.to_string());
+        let params = [("uri", uri), ("depth", &depth), ("parentHeight", &height)];
+        let Some(body) = self.query(GET_POST_THREAD, &params)? else {
+            return Ok(None);
+        };
+        let thread = body.get("thread").and_then(ThreadView::from_json);
+        let thread = thread.ok_or(AppViewError::Malformed {
+            method: GET_POST_THREAD,
+        })?;
+        Ok(Some(thread))
+    }
+
+    /// The post at `uri` on its own, if it can be shown
+    pub fn post(&self, uri: &str) -> Result<Option<PostView>, AppViewError> {
+        let thread = self.post_thread(uri, 0, 0)?;
+        Ok(thread.and_then(|thread| thread.post().cloned()))
+    }
+
+    /// The profile of `actor`, a DID or handle
+    pub fn profile(&self, actor: &str) -> Result<Option<ProfileView>, AppViewError> {
+        let Some(body) = self.query(GET_PROFILE, &[("actor", actor)])? else {
+            return Ok(None);
+        };
+        let profile = ProfileView::from_json(&body);
+        let profile = profile.ok_or(AppViewError::Malformed {
+            method: GET_PROFILE,
+        })?;
+        Ok(Some(profile))
+    }
+
+    fn page<T>(
+        &self,
+        method: &'static str,
+        uri: &str,
+        cursor: Option<&str>,
+        items: &str,
+        parse: impl Fn(&Value) -> Option<T>,
+    ) -> Result<Page<T>, AppViewError> {
+        let limit = PAGE_LIMIT.to_string();
+        let mut params = vec![("uri", uri), ("limit", limit.as_str())];
+        params.extend(cursor.map(|cursor| ("cursor", cursor)));
+        let Some(body) = self.query(method, &params)? else {
+            return Ok(Page {
+                items: Vec::new(),
+                cursor: None,
+            });
+        };
+        let views = body.get(items).and_then(Value::as_array);
+        let views = views.ok_or(AppViewError::Malformed { method })?;
+        Ok(Page {
+            items: views.iter().filter_map(parse).collect(),
+            cursor: text(&body, "cursor"),
+        })
+    }
+
+    /// A page of who liked the post at `uri`, from `cursor`
+    pub fn likes(&self, uri: &str, cursor: Option<&str>) -> Result<Page<Like>, AppViewError> {
+        self.page(GET_LIKES, uri, cursor, "likes", Like::from_json)
+    }
+
+    /// A page of who reposted the post at `uri`, from `cursor`
+    pub fn reposted_by(
+        &self,
+        uri: &str,
+        cursor: Option<&str>,
+    ) -> Result<Page<ProfileView>, AppViewError> {
+        let parse = ProfileView::from_json;
+        self.page(GET_REPOSTED_BY, uri, cursor, "repostedBy", parse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    const APPVIEW: &str = "https://public.api.bsky.app/xrpc";
+    const POST: &str = "at://did:plc:alice/app.bsky.feed.post/1";
+
+    #[test]
+    fn reads_typed_views() {
+        let mock = MockTransport::new();
+        let uri = percent_encode(POST);
+        let author = r#"{"did": "did:plc:alice", "handle": "alice.test",
+            "labels": [{"val": "!no-unauthenticated"}]}"#;
+        mock.respond_json(
+            &format!("{APPVIEW}/{GET_POST_THREAD}?uri={uri}&depth=1&parentHeight=1"),
+            &format!(
+                r##"{{"thread": {{"$type": "{THREAD_VIEW}",
+                    "post": {{"uri": "{POST}", "cid": "bafy", "author": {author},
+                        "record": {{"text": "hi"}}, "likeCount": 3, "repostCount": 1}},
+                    "parent": {{"$type": "app.bsky.feed.defs#notFoundPost",
+                        "uri": "at://did:plc:bob/app.bsky.feed.post/0", "notFound": true}},
+                    "replies": [{{"$type": "{BLOCKED_POST}",
+                        "uri": "at://did:plc:carol/app.bsky.feed.post/2"}}]}}}}"##
+            ),
+        );
+        mock.respond_json(
+            &format!("{APPVIEW}/{GET_PROFILE}?actor=alice.test"),
+            r#"{"did": "did:plc:alice", "handle": "alice.test", "displayName": "Alice",
+                "followersCount": 12}"#,
+        );
+        mock.respond_json(
+            &format!("{APPVIEW}/{GET_LIKES}?uri={uri}&limit=100"),
+            &format!(
+                r#"{{"likes": [{{"actor": {author}, "createdAt": "2024-01-01T00:00:00Z"}},
+                    {{"actor": {{}}}}], "cursor": "next"}}"#
+            ),
+        );
+        mock.respond_json(
+            &format!("{APPVIEW}/{GET_REPOSTED_BY}?uri={uri}&limit=100&cursor=next"),
+            r#"{"repostedBy": []}"#,
+        );
+        mock.respond(
+            Method::Get,
+            &format!("{APPVIEW}/{GET_PROFILE}?actor=nobody.test"),
+            OutboundResponse::new(400),
+        );
+        let appview = AppView::new(&mock, "https://public.api.bsky.app/");
+
+        let thread = appview.post_thread(POST, 1, 1).unwrap().unwrap();
+        let ThreadView::Post {
+            post,
+            parent,
+            replies,
+        } = &thread
+        else {
+            panic!("expected a post");
+        };
+        assert_eq!(post.author.handle, "alice.test");
+        assert!(post.author.is_labeled("!no-unauthenticated"));
+        assert_eq!((post.like_count, post.repost_count), (Some(3), Some(1)));
+        assert_eq!(post.reply_count, None);
+        assert!(matches!(
+            parent.as_deref(),
+            Some(ThreadView::NotFound { .. })
+        ));
+        assert!(matches!(&replies[..], [ThreadView::Blocked { .. }]));
+
+        let profile = appview.profile("alice.test").unwrap().unwrap();
+        assert_eq!(profile.display_name.as_deref(), Some("Alice"));
+        assert_eq!(profile.followers_count, Some(12));
+        // Those it doesn't know are a 400
+        assert_eq!(appview.profile("nobody.test").unwrap(), None);
+
+        let likes = appview.likes(POST, None).unwrap();
+        assert_eq!(likes.items.len(), 1);
+        assert_eq!(likes.cursor.as_deref(), Some("next"));
+        let reposts = appview.reposted_by(POST, likes.cursor.as_deref()).unwrap();
+        assert_eq!(reposts.items, []);
+    }
+}
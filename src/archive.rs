@@ -0,0 +1,406 @@
+//! Archiving inbound events, and replaying them
+//!
+//! With `FEDIBRIDGE_ARCHIVE_EVENTS` set, every firehose message and inbox activity is kept as
+//! it arrived, by the [inbound pipeline](crate::inbound), before any of it is translated.
+//! After a translation bug is fixed, [`replay`] (`fedibridge replay`) runs the affected events
+//! through the current pipeline again to re-bridge what it got wrong or dropped. What was
+//! bridged the first time isn't bridged again: activities go through [`process_once`], and
+//! commits whose every record the [decision
+//! trace](crate::trace) has as bridged are left alone. Replayed commits are checked against
+//! the current [filter](crate::bridge::Bridge::wants) rather than the one they arrived under,
+//! and nothing its author has deleted since is [published again](crate::retraction).
+//!
+//! Archived events are appended to the shard's state directory, and dropped once older than
+//! [`RetentionConfig::archive_ttl`](crate::retention::RetentionConfig)
+
+use crate::bridge::Bridge;
+use crate::crypto::{hex_decode, hex_encode};
+use crate::dedup::process_once;
+use crate::firehose::Frame;
+use crate::json::Value;
+use crate::retraction::{activity_retracted, commit_retracted, outside_window};
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::trace::DecisionQuery;
+use atproto::DID::Did;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The archive's file in the state directory, one JSON event per line
+pub const ARCHIVE_FILE: &str = "events.jsonl";
+
+#[derive(Debug, Clone, PartialEq)]
+/// An event as it arrived
+pub enum Event {
+    /// A firehose message, undecoded
+    Frame(Vec<u8>),
+    /// An activity delivered to an inbox
+    Activity(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Archived {
+    pub at: SystemTime,
+    /// Whose it is, if it says: the repo of a firehose message, or an activity's `actor`
+    pub actor: Option<String>,
+    pub event: Event,
+}
+
+impl Archived {
+    pub fn new(event: Event, at: SystemTime) -> Archived {
+        let actor = match &event {
+            Event::Frame(message) => match Frame::parse(message) {
+                Ok(Frame::Commit(commit)) => Some(commit.repo.to_string()),
+                Ok(Frame::Account(account)) => Some(account.did.to_string()),
+                Ok(Frame::Identity(identity)) => Some(identity.did.to_string()),
+                _ => None,
+            },
+            Event::Activity(activity) => activity
+                .get("actor")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        };
+        Archived { at, actor, event }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let (kind, event) = match &self.event {
+            Event::Frame(message) => ("frame", Value::from(hex_encode(message))),
+            Event::Activity(activity) => ("activity", activity.clone()),
+        };
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("actor", Value::from(self.actor.clone())),
+            ("kind", Value::from(kind)),
+            ("event", event),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Archived> {
+        let event = value.get("event")?;
+        let event = match value.get("kind")?.as_str()? {
+            "frame" => Event::Frame(hex_decode(event.as_str()?)?),
+            "activity" => Event::Activity(event.clone()),
+            _ => return None,
+        };
+        Some(Archived {
+            at: parse_rfc3339(value.get("at")?.as_str()?).ok()?,
+            actor: value
+                .get("actor")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            event,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+/// Inbound events within the retention period, oldest first
+pub struct EventArchive {
+    events: Mutex<Vec<Archived>>,
+    log: Option<JsonLog>,
+}
+
+impl EventArchive {
+    /// An archive persisted in `dir`, with the events already there
+    pub fn open(dir: StateDir) -> io::Result<EventArchive> {
+        let (log, events) = JsonLog::open(dir, ARCHIVE_FILE)?;
+        let events = events.iter().filter_map(Archived::from_json).collect();
+        Ok(EventArchive {
+            events: Mutex::new(events),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, archived: Archived) -> io::Result<()> {
+        let mut events = self.events.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([archived.to_json()])?;
+        }
+        events.push(archived);
+        Ok(())
+    }
+
+    /// Events matching `query`, oldest first
+    pub fn query(&self, query: &ReplayQuery) -> Vec<Archived> {
+        let events = self.events.lock().unwrap();
+        let found = events.iter().filter(|archived| query.matches(archived));
+        found.cloned().collect()
+    }
+
+    /// Drop events older than `ttl` as of `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut events = self.events.lock().unwrap();
+        let before = events.len();
+        events.retain(|archived| now.duration_since(archived.at).unwrap_or_default() < ttl);
+        let pruned = before - events.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            log.rewrite(events.iter().map(Archived::to_json))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Archive `event`, if the bridge archives events. The event is handled either way, so
+/// failing to archive it is only logged
+pub fn archive(bridge: &Bridge, event: Event) {
+    let Some(archive) = &bridge.archive else {
+        return;
+    };
+    if let Err(e) = archive.append(Archived::new(event, SystemTime::now())) {
+        eprintln!("Couldn't archive an inbound event: {e}");
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which archived events to replay. Unset fields match everything
+pub struct ReplayQuery {
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    /// Only the events of this repo or actor
+    pub actor: Option<String>,
+}
+
+impl ReplayQuery {
+    fn matches(&self, archived: &Archived) -> bool {
+        self.since.is_none_or(|since| archived.at >= since)
+            && self.until.is_none_or(|until| archived.at < until)
+            && (self.actor.is_none() || self.actor == archived.actor)
+    }
+}
+
+/// The pipeline events are replayed through
+pub trait Replay {
+    /// Handle a firehose message the bridge wants
+    fn frame(&self, bridge: &Bridge, frame: &Frame<'_>) -> anyhow::Result<()>;
+    /// Handle an activity delivered to an inbox
+    fn activity(&self, bridge: &Bridge, activity: &Value) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// What came of a replay
+pub struct ReplayReport {
+    pub replayed: usize,
+    /// Events which were bridged before
+    pub duplicates: usize,
+    /// Events the bridge doesn't want any more, or which couldn't be read
+    pub skipped: usize,
+    /// Events whose post has been deleted at its origin since
+    pub retracted: usize,
+    /// Events which failed again, by actor, and why
+    pub failed: Vec<(Option<String>, String)>,
+}
+
+impl ReplayReport {
+    pub fn to_json(&self) -> Value {
+        let failed = self.failed.iter().map(|(actor, error)| {
+            Value::object([
+                ("actor", Value::from(actor.clone())),
+                ("error", Value::from(error.as_str())),
+            ])
+        });
+        Value::object([
+            ("replayed", Value::from(self.replayed)),
+            ("duplicates", Value::from(self.duplicates)),
+            ("skipped", Value::from(self.skipped)),
+            ("retracted", Value::from(self.retracted)),
+            ("failed", Value::Array(failed.collect())),
+        ])
+    }
+}
+
+/// Whether every record a commit of `did` wrote at `paths` has been bridged
+fn bridged(bridge: &Bridge, did: &Did, paths: &[String]) -> bool {
+    !paths.is_empty()
+        && paths.iter().all(|path| {
+            let query = DecisionQuery {
+                event: Some(format!("at://{did}/{path}")),
+                ..DecisionQuery::default()
+            };
+            let decisions = bridge.decisions.query(&query);
+            decisions.iter().any(|decision| decision.skipped.is_none())
+        })
+}
+
+/// Run the archived events matching `query` through `pipeline` again, oldest first
+pub fn replay(bridge: &Bridge, query: &ReplayQuery, pipeline: &dyn Replay) -> ReplayReport {
+    let mut report = ReplayReport::default();
+    let now = SystemTime::now();
+    let events = bridge.archive.as_ref().map(|archive| archive.query(query));
+    for archived in events.unwrap_or_default() {
+        let check = outside_window(bridge, archived.at, now);
+        let result = match &archived.event {
+            Event::Frame(message) => {
+                let (frame, header) = match Frame::parse(message) {
+                    Ok(Frame::Commit(commit)) => {
+                        let header = commit.header();
+                        (Frame::Commit(commit), header)
+                    }
+                    Ok(Frame::Account(account)) => {
+                        let header = account.header();
+                        (Frame::Account(account), header)
+                    }
+                    Ok(Frame::Identity(identity)) => {
+                        let header = identity.header();
+                        (Frame::Identity(identity), header)
+                    }
+                    Ok(Frame::Other(_)) | Err(_) => {
+                        report.skipped += 1;
+                        continue;
+                    }
+                };
+                let paths: Vec<String> = header.ops.iter().map(|op| op.path.clone()).collect();
+                if !bridge.wants(&header) {
+                    report.skipped += 1;
+                    continue;
+                }
+                if matches!(frame, Frame::Commit(_)) && bridged(bridge, &header.did, &paths) {
+                    report.duplicates += 1;
+                    continue;
+                }
+                let retracted = match &frame {
+                    Frame::Commit(_) if check => commit_retracted(bridge, &header),
+                    _ => Ok(false),
+                };
+                match retracted {
+                    Ok(true) => Ok(Some(false)),
+                    Ok(false) => pipeline.frame(bridge, &frame).map(|()| Some(true)),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            Event::Activity(activity) => process_once(bridge, activity, |activity| {
+                if check && activity_retracted(bridge, activity)? {
+                    return Ok(false);
+                }
+                pipeline.activity(bridge, activity).map(|()| true)
+            }),
+        };
+        match result {
+            Ok(Some(true)) => report.replayed += 1,
+            Ok(Some(false)) => report.retracted += 1,
+            Ok(None) => report.duplicates += 1,
+            Err(e) => report
+                .failed
+                .push((archived.actor.clone(), format!("{e:#}"))),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Network;
+    use crate::filter::Filter;
+    use crate::firehose::{encode_commit, Action, Operation};
+    use crate::http::Method;
+    use crate::json;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use crate::trace::{self, Decision};
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    #[derive(Default)]
+    struct Recorder(RefCell<Vec<String>>);
+
+    impl Replay for Recorder {
+        fn frame(&self, _: &Bridge, frame: &Frame<'_>) -> anyhow::Result<()> {
+            if let Frame::Commit(commit) = frame {
+                self.0
+                    .borrow_mut()
+                    .push(commit.header().ops[0].path.clone());
+            }
+            Ok(())
+        }
+
+        fn activity(&self, _: &Bridge, activity: &Value) -> anyhow::Result<()> {
+            let id = activity.get("id").and_then(Value::as_str).unwrap();
+            self.0.borrow_mut().push(id.to_string());
+            Ok(())
+        }
+    }
+
+    fn post(seq: i64, rkey: &str) -> Event {
+        let op = Operation {
+            action: Action::Create,
+            path: format!("app.bsky.feed.post/{rkey}"),
+        };
+        let record = Value::object([("text", Value::from("hi"))]);
+        Event::Frame(encode_commit(seq, &ALICE, &[(op, Some(record))]))
+    }
+
+    #[test]
+    fn replays_what_was_not_bridged() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            archive: Some(EventArchive::open(dir.clone()).unwrap()),
+            ..Bridge::new().with_filter(Filter::Tracked)
+        };
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://bridge.example/users/alice"));
+        archive(&bridge, post(1, "1"));
+        archive(&bridge, post(2, "2"));
+        let like = json::parse(
+            r#"{"id": "https://b.example/likes/1", "type": "Like",
+                "actor": "https://b.example/users/bob", "object": "https://bridge.example/p/1"}"#,
+        )
+        .unwrap();
+        archive(&bridge, Event::Activity(like.clone()));
+        let archived = EventArchive::open(dir).unwrap();
+        assert_eq!(archived.len(), 3);
+        let alice = ReplayQuery {
+            actor: Some(ALICE.to_string()),
+            ..ReplayQuery::default()
+        };
+        assert_eq!(archived.query(&alice).len(), 2);
+
+        // The first post went out, and the like was handled
+        let first = "at://did:plc:alice/app.bsky.feed.post/1";
+        let decision = Decision::bridged(Network::Bluesky, first, ALICE.as_str());
+        trace::record(&bridge, decision);
+        process_once(&bridge, &like, |_| Ok::<_, ()>(())).unwrap();
+
+        let pipeline = Recorder::default();
+        let report = replay(&bridge, &ReplayQuery::default(), &pipeline);
+        assert_eq!(pipeline.0.into_inner(), ["app.bsky.feed.post/2"]);
+        assert_eq!(report.replayed, 1);
+        assert_eq!(report.duplicates, 2);
+        assert!(report.failed.is_empty());
+
+        // Had Alice deleted it since, it wouldn't be
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            r##"{"id": "did:plc:alice", "service": [{"id": "#atproto_pds",
+                "type": "AtprotoPersonalDataServer", "serviceEndpoint": "https://pds.example"}]}"##,
+        );
+        mock.respond(
+            Method::Get,
+            "https://pds.example/xrpc/com.atproto.repo.getRecord\
+             ?repo=did%3Aplc%3Aalice&collection=app.bsky.feed.post&rkey=2",
+            OutboundResponse::new(400),
+        );
+        let bridge = bridge
+            .with_transport(mock)
+            .with_retraction_window(Duration::ZERO);
+        let pipeline = Recorder::default();
+        let report = replay(&bridge, &ReplayQuery::default(), &pipeline);
+        assert!(pipeline.0.into_inner().is_empty());
+        assert_eq!(report.retracted, 1);
+    }
+}
@@ -0,0 +1,162 @@
+//! Long-form fediverse posts
+//!
+//! `Article`s (WriteFreely, Plume) and `Page`s (Lemmy) can run to thousands of words of
+//! HTML, which no amount of cramming fits into a Bluesky post. So they're bridged as a teaser
+//! instead: a post with the title and summary, trimmed at a word boundary to the configured
+//! [length](ArticleConfig::max_length), and an `app.bsky.embed.external` card linking to the
+//! whole thing. Without a summary, as much of the opening as fits stands in for one
+
+use crate::html::decode_entities;
+use crate::json::Value;
+use crate::linkcard::LinkCard;
+use crate::richtext;
+
+/// Bluesky's limit on post text, in graphemes
+pub const MAX_POST_LENGTH: usize = 300;
+
+/// What an article's text is cut short with
+const ELLIPSIS: char = '…';
+
+#[derive(Debug, Clone, PartialEq)]
+/// How long-form posts are bridged
+pub struct ArticleConfig {
+    /// Post text is trimmed to this many characters, at most [`MAX_POST_LENGTH`]
+    pub max_length: usize,
+}
+
+impl Default for ArticleConfig {
+    fn default() -> Self {
+        ArticleConfig {
+            max_length: MAX_POST_LENGTH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A long-form post as a Bluesky post
+pub struct ArticlePost {
+    pub text: String,
+    /// The card linking to the whole article
+    pub card: LinkCard,
+    /// The article's header image, for the card's thumbnail
+    pub image: Option<String>,
+}
+
+/// Whether `object` is long-form, and bridged as an [`ArticlePost`]
+pub fn is_long_form(object: &Value) -> bool {
+    matches!(
+        object.get("type").and_then(Value::as_str),
+        Some("Article" | "Page")
+    )
+}
+
+/// The first URL of a field that may be a URL, a `Link`, an `Image` or a list of them
+pub(crate) fn first_url(value: &Value) -> Option<&str> {
+    match value {
+        Value::String(url) => Some(url),
+        Value::Array(values) => values.iter().find_map(first_url),
+        object => object
+            .get("href")
+            .or_else(|| object.get("url"))
+            .and_then(first_url),
+    }
+}
+
+/// `text` cut to at most `max` characters, ending at a word boundary where there is one
+pub fn trim(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    let cut: String = text.chars().take(max.saturating_sub(1)).collect();
+    let at_word = cut.rfind(char::is_whitespace).filter(|&end| end > 0);
+    let mut trimmed = cut[..at_word.unwrap_or(cut.len())].trim_end().to_string();
+    trimmed.push(ELLIPSIS);
+    trimmed
+}
+
+/// `object` as a teaser post, if it's long-form
+pub fn to_post(object: &Value, config: &ArticleConfig) -> Option<ArticlePost> {
+    if !is_long_form(object) {
+        return None;
+    }
+    let field = |name| object.get(name).and_then(Value::as_str);
+    let uri = object.get("url").and_then(first_url).or(field("id"))?;
+    let title = decode_entities(field("name").unwrap_or_default().trim());
+    let summary = field("summary").or(field("content")).unwrap_or_default();
+    let summary = richtext::from_html(summary).text;
+    let max = config.max_length.min(MAX_POST_LENGTH);
+    let text = match (title.is_empty(), summary.is_empty()) {
+        (false, false) => {
+            let title = trim(&title, max);
+            let room = max.saturating_sub(title.chars().count() + 2);
+            match room {
+                // Not even the ellipsis fits
+                0 | 1 => title,
+                room => format!("{title}\n\n{}", trim(&summary, room)),
+            }
+        }
+        (false, true) => trim(&title, max),
+        (true, _) => trim(&summary, max),
+    };
+    Some(ArticlePost {
+        text,
+        card: LinkCard {
+            uri: uri.to_string(),
+            title,
+            description: trim(&summary, MAX_POST_LENGTH),
+            thumbnail: None,
+        },
+        image: object.get("image").and_then(first_url).map(str::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn trims_at_word_boundaries() {
+        assert_eq!(trim("short enough", 20), "short enough");
+        assert_eq!(trim("one two three four", 12), "one two…");
+        assert_eq!(trim("unbroken", 5), "unbr…");
+        assert!(trim(&"word ".repeat(100), 300).chars().count() <= 300);
+    }
+
+    #[test]
+    fn articles_become_teasers_with_a_card() {
+        let article = json::parse(&format!(
+            r#"{{"type": "Article", "id": "https://blog.example/a/1",
+                "url": "https://blog.example/my-post", "name": "Seven things &amp; more",
+                "summary": "<p>{}</p>", "content": "<p>The full text</p>",
+                "image": {{"type": "Image", "url": "https://blog.example/header.png"}}}}"#,
+            "a long summary ".repeat(40)
+        ))
+        .unwrap();
+        let config = ArticleConfig { max_length: 100 };
+        let post = to_post(&article, &config).unwrap();
+        assert!(post
+            .text
+            .starts_with("Seven things & more\n\na long summary"));
+        assert!(post.text.chars().count() <= 100);
+        assert!(post.text.ends_with(ELLIPSIS));
+        assert_eq!(post.card.uri, "https://blog.example/my-post");
+        assert_eq!(post.card.title, "Seven things & more");
+        assert_eq!(
+            post.image.as_deref(),
+            Some("https://blog.example/header.png")
+        );
+
+        let page = json::parse(
+            r#"{"type": "Page", "id": "https://lemmy.example/post/1", "name": "A question",
+                "content": "<p>Does anyone know?</p>"}"#,
+        )
+        .unwrap();
+        let post = to_post(&page, &ArticleConfig::default()).unwrap();
+        assert_eq!(post.text, "A question\n\nDoes anyone know?");
+        assert_eq!(post.card.uri, "https://lemmy.example/post/1");
+
+        let note = json::parse(r#"{"type": "Note", "content": "hi"}"#).unwrap();
+        assert_eq!(to_post(&note, &ArticleConfig::default()), None);
+    }
+}
@@ -0,0 +1,176 @@
+//! Attribution footers on bridged posts
+//!
+//! Operators can have a footer appended to what's bridged each way, from a template per
+//! direction whose `{url}` is the original post and `{author}` whoever wrote it, as known
+//! on their own network. `{url}` becomes a link either way.
+//!
+//! - Onto the fediverse, the footer is a paragraph of HTML after the content, added to
+//!   deliveries from bridged accounts by the [`Attribution`] transformer, after the text
+//!   [stage](crate::transform::Stage)
+//! - Onto Bluesky, [`bluesky_footer`] appends it to a post's rich text, within Bluesky's
+//!   [length limit](MAX_POST_LENGTH): the post's own text is cut short to make room, and the
+//!   footer left off if it can't fit at all
+//!
+//! `FEDIBRIDGE_ATTRIBUTION_TO_FEDIVERSE` and `FEDIBRIDGE_ATTRIBUTION_TO_BLUESKY` set the
+//! templates, such as `Bridged from {url}`
+
+use crate::alerts::render;
+use crate::article::{first_url, trim, MAX_POST_LENGTH};
+use crate::html::escape;
+use crate::json::Value;
+use crate::richtext::{shorten_url, RichText};
+use crate::transform::{Context, Transformer};
+
+/// What separates a post's text from its footer
+const SEPARATOR: &str = "\n\n";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The footer templates for each direction, if any
+pub struct AttributionConfig {
+    pub to_fediverse: Option<String>,
+    pub to_bluesky: Option<String>,
+}
+
+/// `template` as a paragraph of HTML
+pub fn fediverse_footer(template: &str, url: &str, author: &str) -> String {
+    let link = format!("<a href=\"{}\">{}</a>", escape(url), escape(url));
+    let fields = [("url", link), ("author", escape(author))];
+    format!("<p>{}</p>", render(&escape(template), &fields))
+}
+
+/// `rich` followed by `template`, cut short so the whole is within [`MAX_POST_LENGTH`]
+/// graphemes. If the footer alone is too long, `rich` is returned as it was
+pub fn bluesky_footer(rich: &RichText, template: &str, url: &str, author: &str) -> RichText {
+    let mut footer = RichText::new();
+    let fields = [("author", author.to_string())];
+    for (i, part) in template.split("{url}").enumerate() {
+        if i > 0 {
+            footer.push_link(&shorten_url(url), url);
+        }
+        footer.push_str(&render(part, &fields));
+    }
+    let room = MAX_POST_LENGTH.checked_sub(footer.text.chars().count() + SEPARATOR.len());
+    let Some(room) = room.filter(|room| *room > 0) else {
+        return rich.clone();
+    };
+    let text = trim(&rich.text, room);
+    let mut attributed = RichText {
+        // Facets cut through by the trimming go, along with their text
+        facets: rich
+            .facets
+            .iter()
+            .filter(|facet| text.get(..facet.byte_end) == rich.text.get(..facet.byte_end))
+            .cloned()
+            .collect(),
+        text,
+    };
+    if !attributed.text.is_empty() {
+        attributed.push_str(SEPARATOR);
+    }
+    let offset = attributed.text.len();
+    attributed.text.push_str(&footer.text);
+    attributed
+        .facets
+        .extend(footer.facets.into_iter().map(|mut facet| {
+            facet.byte_start += offset;
+            facet.byte_end += offset;
+            facet
+        }));
+    attributed
+}
+
+/// Appends the fediverse footer to each bridged post's content
+pub struct Attribution(pub String);
+
+impl Transformer for Attribution {
+    fn name(&self) -> &str {
+        "attribution"
+    }
+
+    fn transform(&self, context: &Context, activity: &mut Value) {
+        if activity.get("type").and_then(Value::as_str) != Some("Create") {
+            return;
+        }
+        let Value::Object(fields) = activity else {
+            return;
+        };
+        let Some(Value::Object(object)) = fields.get_mut("object") else {
+            return;
+        };
+        let url = object.get("url").and_then(first_url);
+        let Some(url) = url.or(object.get("id").and_then(Value::as_str)) else {
+            return;
+        };
+        let mapping = context.mapping;
+        let author = mapping.handle.as_deref().unwrap_or(mapping.did.as_str());
+        let footer = fediverse_footer(&self.0, url, author);
+        let content = object.get("content").and_then(Value::as_str);
+        let content = format!("{}{footer}", content.unwrap_or_default());
+        object.insert("content".into(), Value::from(content));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::richtext::Feature;
+    use crate::store::Mapping;
+
+    const TEMPLATE: &str = "Bridged from {url} by {author}";
+    const URL: &str = "https://mastodon.example/@alice/1234567890";
+
+    #[test]
+    fn footers_fit_bluesky_posts() {
+        let mut rich = RichText::new();
+        rich.push_link("first.example", "https://first.example/");
+        rich.push_str(&" word".repeat(70));
+        rich.push_link("last.example", "https://last.example/");
+
+        let attributed = bluesky_footer(&rich, TEMPLATE, URL, "@alice@mastodon.example");
+        assert!(attributed.text.chars().count() <= MAX_POST_LENGTH);
+        assert!(attributed.text.ends_with(
+            "…\n\nBridged from mastodon.example/@alice/12345… by @alice@mastodon.example"
+        ));
+        // The trimmed-off link went with its text, and the footer's is in its place
+        assert_eq!(attributed.facets.len(), 2);
+        let link = &attributed.facets[1];
+        assert_eq!(
+            &attributed.text[link.byte_start..link.byte_end],
+            "mastodon.example/@alice/12345…"
+        );
+        assert_eq!(link.feature, Feature::Link { uri: URL.into() });
+
+        let short = bluesky_footer(&RichText::new(), "{url}", URL, "");
+        assert_eq!(short.text, "mastodon.example/@alice/12345…");
+        let too_long = "x".repeat(MAX_POST_LENGTH);
+        assert_eq!(bluesky_footer(&rich, &too_long, URL, ""), rich);
+    }
+
+    #[test]
+    fn footers_follow_fediverse_content() {
+        let mut mapping = Mapping::new(
+            atproto::did!("did:plc:alice"),
+            "https://bridge.example/ap/alice",
+        );
+        mapping.handle = Some("alice.bsky.social".to_string());
+        let context = Context {
+            mapping: &mapping,
+            inbox: "https://b.example/inbox",
+        };
+        let mut activity = json::parse(
+            r#"{"type": "Create", "object": {"type": "Note", "content": "<p>hi</p>",
+                "url": "https://bsky.app/profile/alice.bsky.social/post/3k"}}"#,
+        )
+        .unwrap();
+        Attribution("From {url} & {author}".to_string()).transform(&context, &mut activity);
+        let content = activity.get("object").and_then(|o| o.get("content"));
+        assert_eq!(
+            content.and_then(Value::as_str),
+            Some(
+                "<p>hi</p><p>From <a href=\"https://bsky.app/profile/alice.bsky.social/post/3k\">\
+                https://bsky.app/profile/alice.bsky.social/post/3k</a> &amp; alice.bsky.social</p>"
+            )
+        );
+    }
+}
@@ -0,0 +1,385 @@
+//! Who a post was meant for
+//!
+//! ActivityPub posts say who can see them through their addressing: the public collection in
+//! `to` is public, in `cc` only unlisted, a followers collection without it followers-only,
+//! and anything else direct. Everything bridged to Bluesky is public to the whole network,
+//! so followers-only and direct posts are never bridged, whatever anyone prefers. Where an
+//! activity and its object disagree, the narrower audience wins.
+//!
+//! Communities disagree on unlisted posts, so what becomes of them is an [`UnlistedPolicy`]:
+//! skipped, bridged [quietly](quieten), or bridged like any other. Authors can set one in
+//! their [preferences](Preferences::unlisted) and instances can have one in the
+//! [federation policy](crate::policy) (`unlisted:quiet`); where both do, the more cautious
+//! wins, and where neither does, unlisted posts are skipped
+//!
+//! Bluesky posts are all public, but a threadgate can limit who may reply to them. The
+//! fediverse can't be made to honour one, so replies from there are checked against it
+//! ([`reply_allowed`]) before they're bridged back
+
+use crate::dm::{self, PUBLIC};
+use crate::json::Value;
+use crate::richtext::{MENTION, TAG};
+use crate::store::Preferences;
+use atproto::DID::Did;
+use thiserror::Error;
+
+pub const THREADGATE_COLLECTION: &str = "app.bsky.feed.threadgate";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// Who can see a post, from widest to narrowest
+pub enum Visibility {
+    Public,
+    Unlisted,
+    Followers,
+    Direct,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Followers => "followers",
+            Visibility::Direct => "direct",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Visibility> {
+        [
+            Visibility::Public,
+            Visibility::Unlisted,
+            Visibility::Followers,
+            Visibility::Direct,
+        ]
+        .into_iter()
+        .find(|visibility| visibility.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// What becomes of unlisted posts, from most to least cautious
+pub enum UnlistedPolicy {
+    Skip,
+    /// Bridged, but kept out of feeds and hashtag searches
+    Quiet,
+    Bridge,
+}
+
+impl UnlistedPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnlistedPolicy::Skip => "skip",
+            UnlistedPolicy::Quiet => "quiet",
+            UnlistedPolicy::Bridge => "bridge",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<UnlistedPolicy> {
+        [
+            UnlistedPolicy::Skip,
+            UnlistedPolicy::Quiet,
+            UnlistedPolicy::Bridge,
+        ]
+        .into_iter()
+        .find(|policy| policy.as_str() == s)
+    }
+
+    /// The policy for an author who prefers `preferred`, on an instance with `instance`
+    pub fn resolve(
+        preferred: Option<UnlistedPolicy>,
+        instance: Option<UnlistedPolicy>,
+    ) -> UnlistedPolicy {
+        match (preferred, instance) {
+            (Some(preferred), Some(instance)) => preferred.min(instance),
+            (policy, None) | (None, policy) => policy.unwrap_or(UnlistedPolicy::Skip),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a post may be bridged
+pub struct Audience {
+    pub visibility: Visibility,
+    /// Whether it's to be [quietened](quieten) and left out of the bridge's feeds
+    pub quiet: bool,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum AudienceError {
+    #[error("{} posts aren't bridged", .0.as_str())]
+    NotPublic(Visibility),
+    #[error("unlisted posts are skipped")]
+    UnlistedSkipped,
+}
+
+fn is_public(address: &str) -> bool {
+    matches!(address, PUBLIC | "as:Public" | "Public")
+}
+
+/// Who `object` is addressed to; an activity's own addressing is taken into account along
+/// with its object's
+pub fn visibility(object: &Value) -> Visibility {
+    let field = |name| match object.get(name) {
+        Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+        Some(item) => item.as_str().into_iter().collect(),
+        None => Vec::new(),
+    };
+    let own = if field("to").into_iter().any(is_public) {
+        Visibility::Public
+    } else if dm::addresses(object).into_iter().any(is_public) {
+        Visibility::Unlisted
+    } else if dm::addresses(object)
+        .into_iter()
+        .any(|address| address.ends_with("/followers"))
+    {
+        Visibility::Followers
+    } else {
+        Visibility::Direct
+    };
+    match object.get("object") {
+        Some(inner @ Value::Object(_)) if inner.get("type").is_some() => own.max(visibility(inner)),
+        _ => own,
+    }
+}
+
+/// Whether `object` may be bridged for an author with `preferences`, on an instance whose
+/// policy for unlisted posts is `instance`, and how
+pub fn check(
+    object: &Value,
+    preferences: &Preferences,
+    instance: Option<UnlistedPolicy>,
+) -> Result<Audience, AudienceError> {
+    let visibility = visibility(object);
+    let quiet = match visibility {
+        Visibility::Public => false,
+        Visibility::Unlisted => match UnlistedPolicy::resolve(preferences.unlisted, instance) {
+            UnlistedPolicy::Skip => return Err(AudienceError::UnlistedSkipped),
+            UnlistedPolicy::Quiet => true,
+            UnlistedPolicy::Bridge => false,
+        },
+        narrower => return Err(AudienceError::NotPublic(narrower)),
+    };
+    Ok(Audience { visibility, quiet })
+}
+
+/// Take what would index a post record in hashtag searches and feeds out of it: its `tags`
+/// and tag facets
+pub fn quieten(record: &mut Value) {
+    let Value::Object(fields) = record else {
+        return;
+    };
+    fields.remove("tags");
+    let Some(Value::Array(facets)) = fields.get_mut("facets") else {
+        return;
+    };
+    for facet in facets.iter_mut() {
+        if let Value::Object(facet) = facet {
+            if let Some(Value::Array(features)) = facet.get_mut("features") {
+                features.retain(|f| f.get("$type").and_then(Value::as_str) != Some(TAG));
+            }
+        }
+    }
+    facets.retain(|facet| {
+        let features = facet.get("features").and_then(Value::as_array);
+        !features.unwrap_or_default().is_empty()
+    });
+    if facets.is_empty() {
+        fields.remove("facets");
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How a reply's author and the author of the post it replies to are connected
+pub struct Relationship {
+    /// The replier follows the post's author
+    pub follows_author: bool,
+    /// The post's author follows the replier
+    pub followed_by_author: bool,
+}
+
+/// Whether `replier` may reply to `post`, a post record with `threadgate` as its
+/// threadgate record if it has one
+///
+/// `in_list` says whether the replier is a member of the list with the given `at://` URI,
+/// for gates which allow a list. Rules this doesn't know allow no one
+pub fn reply_allowed(
+    threadgate: Option<&Value>,
+    post: &Value,
+    replier: &Did,
+    relationship: Relationship,
+    in_list: impl Fn(&str) -> bool,
+) -> bool {
+    // Without an `allow`, anyone can reply; with an empty one, no one can
+    let Some(rules) = threadgate.and_then(|gate| gate.get("allow")) else {
+        return true;
+    };
+    let mentioned = || {
+        let facets = post.get("facets").and_then(Value::as_array);
+        let features = facets.unwrap_or_default().iter().flat_map(|facet| {
+            let features = facet.get("features").and_then(Value::as_array);
+            features.unwrap_or_default()
+        });
+        features.into_iter().any(|feature| {
+            feature.get("$type").and_then(Value::as_str) == Some(MENTION)
+                && feature.get("did").and_then(Value::as_str) == Some(replier.as_str())
+        })
+    };
+    let rules = rules.as_array().unwrap_or_default();
+    rules.iter().any(|rule| {
+        let kind = rule
+            .get("$type")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        match kind.strip_prefix(THREADGATE_COLLECTION) {
+            Some("#mentionRule") => mentioned(),
+            Some("#followerRule") => relationship.follows_author,
+            Some("#followingRule") => relationship.followed_by_author,
+            Some("#listRule") => rule
+                .get("list")
+                .and_then(Value::as_str)
+                .is_some_and(&in_list),
+            _ => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use atproto::did;
+
+    fn note(to: &str, cc: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Note", "to": [{to}], "cc": [{cc}], "content": "hi"}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn only_public_posts_are_bridged_by_default() {
+        let public = format!("\"{PUBLIC}\"");
+        let followers = "\"https://a.example/users/bob/followers\"";
+        let bob = "\"https://a.example/users/bob\"";
+        let defaults = Preferences::default();
+        let opted_in = Preferences {
+            unlisted: Some(UnlistedPolicy::Bridge),
+            ..Preferences::default()
+        };
+
+        let post = note(&public, followers);
+        let audience = check(&post, &defaults, None).unwrap();
+        assert_eq!(audience.visibility, Visibility::Public);
+        assert!(!audience.quiet);
+        let unlisted = note(followers, &public);
+        assert_eq!(
+            check(&unlisted, &defaults, None),
+            Err(AudienceError::UnlistedSkipped)
+        );
+        let bridged = check(&unlisted, &opted_in, None).unwrap();
+        assert_eq!(bridged.visibility, Visibility::Unlisted);
+        assert!(!bridged.quiet);
+        assert_eq!(
+            check(
+                &note(followers, ""),
+                &opted_in,
+                Some(UnlistedPolicy::Bridge)
+            ),
+            Err(AudienceError::NotPublic(Visibility::Followers))
+        );
+        assert_eq!(
+            check(&note(bob, ""), &opted_in, None),
+            Err(AudienceError::NotPublic(Visibility::Direct))
+        );
+
+        // A public activity can't widen a followers-only object
+        let create = Value::object([
+            ("type", Value::from("Create")),
+            ("to", Value::Array(vec![Value::from(PUBLIC)])),
+            ("object", note(followers, "")),
+        ]);
+        assert_eq!(visibility(&create), Visibility::Followers);
+    }
+
+    #[test]
+    fn the_more_cautious_unlisted_policy_wins() {
+        use UnlistedPolicy::*;
+        assert_eq!(UnlistedPolicy::resolve(None, None), Skip);
+        assert_eq!(UnlistedPolicy::resolve(Some(Bridge), None), Bridge);
+        assert_eq!(UnlistedPolicy::resolve(None, Some(Quiet)), Quiet);
+        assert_eq!(UnlistedPolicy::resolve(Some(Bridge), Some(Quiet)), Quiet);
+        assert_eq!(UnlistedPolicy::resolve(Some(Skip), Some(Bridge)), Skip);
+
+        let unlisted = note("", &format!("\"{PUBLIC}\""));
+        let audience = check(&unlisted, &Preferences::default(), Some(Quiet)).unwrap();
+        assert!(audience.quiet);
+
+        let mut record = json::parse(&format!(
+            r##"{{"text": "#trains and https://a.example", "tags": ["rail"], "facets": [
+                {{"index": {{"byteStart": 0, "byteEnd": 7}},
+                  "features": [{{"$type": "{TAG}", "tag": "trains"}}]}},
+                {{"index": {{"byteStart": 12, "byteEnd": 29}},
+                  "features": [{{"$type": "app.bsky.richtext.facet#link", "uri": "https://a.example"}}]}}
+            ]}}"##
+        ))
+        .unwrap();
+        quieten(&mut record);
+        assert_eq!(record.get("tags"), None);
+        let facets = record.get("facets").and_then(Value::as_array).unwrap();
+        assert_eq!(facets.len(), 1);
+    }
+
+    #[test]
+    fn threadgates_limit_fediverse_replies() {
+        let alice = did!("did:plc:alice");
+        let bob = did!("did:plc:bob");
+        let post = json::parse(&format!(
+            r#"{{"text": "@alice", "facets": [{{"index": {{"byteStart": 0, "byteEnd": 6}},
+                "features": [{{"$type": "{MENTION}", "did": "did:plc:alice"}}]}}]}}"#
+        ))
+        .unwrap();
+        let gate = |rules: &str| {
+            json::parse(&format!(
+                r#"{{"$type": "{THREADGATE_COLLECTION}", "allow": [{rules}]}}"#
+            ))
+            .unwrap()
+        };
+        let none = Relationship::default();
+        let no_lists = |_: &str| false;
+
+        assert!(reply_allowed(None, &post, &bob, none, no_lists));
+        assert!(!reply_allowed(
+            Some(&gate("")),
+            &post,
+            &alice,
+            none,
+            no_lists
+        ));
+        let mentions = gate(r#"{"$type": "app.bsky.feed.threadgate#mentionRule"}"#);
+        assert!(reply_allowed(
+            Some(&mentions),
+            &post,
+            &alice,
+            none,
+            no_lists
+        ));
+        assert!(!reply_allowed(Some(&mentions), &post, &bob, none, no_lists));
+        let following = gate(r#"{"$type": "app.bsky.feed.threadgate#followingRule"}"#);
+        let followed = Relationship {
+            followed_by_author: true,
+            ..none
+        };
+        assert!(reply_allowed(
+            Some(&following),
+            &post,
+            &bob,
+            followed,
+            no_lists
+        ));
+        let list = gate(
+            r#"{"$type": "app.bsky.feed.threadgate#listRule", "list": "at://did:plc:x/app.bsky.graph.list/1"}"#,
+        );
+        let members = |list: &str| list == "at://did:plc:x/app.bsky.graph.list/1";
+        assert!(reply_allowed(Some(&list), &post, &bob, none, members));
+    }
+}
@@ -0,0 +1,330 @@
+//! An append-only log of what the bridge has done on either network
+//!
+//! Each activity delivered to the fediverse, and each report, chat message or repo write on
+//! Bluesky, is recorded once it has gone out, as an [`AuditRecord`]: who the bridge acted as,
+//! what it did to which object, why, and the event which set it off. Work is attributed
+//! through the [`Cause`] attached when it's queued.
+//!
+//! Records are appended to a file in the state directory and never rewritten, except to drop
+//! those older than [`RetentionConfig::audit_ttl`](crate::retention::RetentionConfig). The
+//! admin API's `GET /admin/audit` queries them, for answering "why did the bridge post this?"
+//! after the fact
+
+use crate::delivery::Delivery;
+use crate::digest::Network;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::repo::Write;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use atproto::DID::Did;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The audit log's file in the state directory, one JSON record per line
+pub const AUDIT_FILE: &str = "audit.jsonl";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Why the bridge did something
+pub struct Cause {
+    /// What brought it about, such as `"digest"` or `"DM bounce"`
+    pub reason: String,
+    /// The ID or URI of the record, activity or message it was in response to
+    pub event: Option<String>,
+}
+
+impl Cause {
+    pub fn new(reason: impl Into<String>, event: Option<&str>) -> Cause {
+        Cause {
+            reason: reason.into(),
+            event: event.map(str::to_string),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("reason", Value::from(self.reason.as_str())),
+            ("event", Value::from(self.event.clone())),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Option<Cause> {
+        let event = value.get("event").and_then(Value::as_str);
+        Some(Cause::new(value.get("reason")?.as_str()?, event))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One thing the bridge did
+pub struct AuditRecord {
+    pub at: SystemTime,
+    /// The network acted on
+    pub network: Network,
+    /// The activity type, or the XRPC procedure called
+    pub action: String,
+    /// Who the bridge acted as
+    pub actor: String,
+    /// What was acted on
+    pub object: Option<String>,
+    /// Why, if whatever queued the work said
+    pub cause: Option<Cause>,
+}
+
+impl AuditRecord {
+    /// A record of `delivery` having been sent
+    pub fn delivered(delivery: &Delivery, at: SystemTime) -> Option<AuditRecord> {
+        let activity = json::parse(&delivery.activity).ok()?;
+        let object = activity.get("object").and_then(|object| match object {
+            Value::Array(objects) => objects.first()?.as_str(),
+            object => object.as_str().or_else(|| object.get("id")?.as_str()),
+        });
+        Some(AuditRecord {
+            at,
+            network: Network::Fediverse,
+            action: activity.get("type")?.as_str()?.to_string(),
+            actor: activity.get("actor")?.as_str()?.to_string(),
+            object: object.map(str::to_string),
+            cause: delivery.cause.clone(),
+        })
+    }
+
+    /// A record of `write` having been committed to `did`'s repo
+    pub fn committed(did: &Did, write: &Write, at: SystemTime) -> AuditRecord {
+        AuditRecord {
+            at,
+            network: Network::Bluesky,
+            action: format!("com.atproto.repo.{}Record", write.action()),
+            actor: did.as_str().to_string(),
+            object: Some(format!("at://{did}/{}", write.path())),
+            cause: None,
+        }
+    }
+
+    /// A record of `job` having run, if it does anything worth auditing
+    pub fn for_job(job: &Job, at: SystemTime) -> Option<AuditRecord> {
+        let (action, actor, object, cause) = match job {
+            Job::Deliver(delivery) => return AuditRecord::delivered(delivery, at),
+            Job::CreateReport(report) => (
+                "com.atproto.moderation.createReport",
+                // Reports are filed by the bridge itself
+                None,
+                report.subject.as_str(),
+                &report.cause,
+            ),
+            Job::SendChatMessage(reply) => (
+                "chat.bsky.convo.sendMessage",
+                Some(reply.did.as_str()),
+                reply.convo_id.as_str(),
+                &reply.cause,
+            ),
+            _ => return None,
+        };
+        Some(AuditRecord {
+            at,
+            network: Network::Bluesky,
+            action: action.to_string(),
+            actor: actor.unwrap_or("the bridge").to_string(),
+            object: Some(object.to_string()),
+            cause: cause.clone(),
+        })
+    }
+
+    /// Whether this created a post: a `Create` activity, or an `app.bsky.feed.post` record
+    pub fn is_post(&self) -> bool {
+        match self.network {
+            Network::Fediverse => self.action == "Create",
+            Network::Bluesky => {
+                self.action == "com.atproto.repo.createRecord"
+                    && self
+                        .object
+                        .as_ref()
+                        .is_some_and(|o| o.contains("/app.bsky.feed.post/"))
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let cause = self.cause.as_ref();
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("network", Value::from(self.network.as_str())),
+            ("action", Value::from(self.action.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            ("object", Value::from(self.object.clone())),
+            ("reason", Value::from(cause.map(|c| c.reason.clone()))),
+            ("source", Value::from(cause.and_then(|c| c.event.clone()))),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<AuditRecord> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(AuditRecord {
+            at: parse_rfc3339(field("at")?).ok()?,
+            network: Network::parse(field("network")?)?,
+            action: field("action")?.to_string(),
+            actor: field("actor")?.to_string(),
+            object: field("object").map(str::to_string),
+            cause: field("reason").map(|reason| Cause::new(reason, field("source"))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which records to return. Unset fields match everything
+pub struct AuditQuery {
+    pub network: Option<Network>,
+    pub actor: Option<String>,
+    pub object: Option<String>,
+    /// The event records were caused by
+    pub source: Option<String>,
+    pub since: Option<SystemTime>,
+    /// At most this many records, newest first. Zero means no limit
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let source = record.cause.as_ref().and_then(|c| c.event.as_deref());
+        self.network.is_none_or(|network| network == record.network)
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| *actor == record.actor)
+            && (self.object.is_none() || self.object == record.object)
+            && (self.source.is_none() || self.source.as_deref() == source)
+            && self.since.is_none_or(|since| record.at >= since)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Everything the bridge has done, within the retention period, oldest first
+pub struct AuditLog {
+    records: Mutex<Vec<AuditRecord>>,
+    log: Option<JsonLog>,
+}
+
+impl AuditLog {
+    /// A log persisted in `dir`, with the records already there
+    pub fn open(dir: StateDir) -> io::Result<AuditLog> {
+        let (log, records) = JsonLog::open(dir, AUDIT_FILE)?;
+        let records = records.iter().filter_map(AuditRecord::from_json).collect();
+        Ok(AuditLog {
+            records: Mutex::new(records),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, record: AuditRecord) -> io::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([record.to_json()])?;
+        }
+        records.push(record);
+        Ok(())
+    }
+
+    /// Records matching `query`, newest first
+    pub fn query(&self, query: &AuditQuery) -> Vec<AuditRecord> {
+        let records = self.records.lock().unwrap();
+        let limit = match query.limit {
+            0 => usize::MAX,
+            limit => limit,
+        };
+        let found = records.iter().rev().filter(|record| query.matches(record));
+        found.take(limit).cloned().collect()
+    }
+
+    /// Drop records older than `ttl` as of `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+        records.retain(|record| now.duration_since(record.at).unwrap_or_default() < ttl);
+        let pruned = before - records.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            log.rewrite(records.iter().map(AuditRecord::to_json))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::time::{from_unix_millis, unix_millis};
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn note(id: &str) -> Delivery {
+        let activity = format!(
+            r#"{{"type": "Create", "actor": "https://bridge.example/users/alice",
+                "object": {{"id": "{id}", "type": "Note"}}}}"#
+        );
+        let source = format!("at://did:plc:alice/app.bsky.feed.post/{id}");
+        Delivery::new("https://b.example/inbox", activity)
+            .because(Cause::new("post", Some(&source)))
+    }
+
+    #[test]
+    fn records_survive_reopening_until_they_expire() {
+        let dir = temp_state_dir();
+        let log = AuditLog::open(dir.clone()).unwrap();
+        // Timestamps are kept to the millisecond
+        let now = from_unix_millis(unix_millis(SystemTime::now()));
+        let old = AuditRecord::delivered(&note("1"), now - DAY * 10).unwrap();
+        log.append(old).unwrap();
+        let record = AuditRecord::delivered(&note("2"), now).unwrap();
+        assert_eq!(record.action, "Create");
+        assert_eq!(record.object.as_deref(), Some("2"));
+        log.append(record.clone()).unwrap();
+        // A torn final line is skipped rather than failing to open
+        dir.append(AUDIT_FILE, b"{\"at\": \"20").unwrap();
+
+        let log = AuditLog::open(dir.clone()).unwrap();
+        assert_eq!(log.len(), 2);
+        let newest = AuditRecord::delivered(&note("3"), now).unwrap();
+        log.append(newest.clone()).unwrap();
+        assert_eq!(AuditLog::open(dir.clone()).unwrap().len(), 3);
+        assert_eq!(log.prune(DAY * 7, now).unwrap(), 1);
+        let log = AuditLog::open(dir).unwrap();
+        assert_eq!(log.query(&AuditQuery::default()), [newest, record]);
+    }
+
+    #[test]
+    fn queries_newest_first() {
+        let log = AuditLog::default();
+        let now = SystemTime::now();
+        for (n, id) in ["1", "2", "3"].into_iter().enumerate() {
+            let at = now + Duration::from_secs(n as u64);
+            log.append(AuditRecord::delivered(&note(id), at).unwrap())
+                .unwrap();
+        }
+        let objects = |query: &AuditQuery| -> Vec<Option<String>> {
+            log.query(query).into_iter().map(|r| r.object).collect()
+        };
+        let query = AuditQuery {
+            limit: 2,
+            ..AuditQuery::default()
+        };
+        assert_eq!(objects(&query), [Some("3".into()), Some("2".into())]);
+        let query = AuditQuery {
+            source: Some("at://did:plc:alice/app.bsky.feed.post/1".to_string()),
+            ..AuditQuery::default()
+        };
+        assert_eq!(objects(&query), [Some("1".into())]);
+        let query = AuditQuery {
+            network: Some(Network::Bluesky),
+            ..AuditQuery::default()
+        };
+        assert!(log.query(&query).is_empty());
+    }
+}
@@ -0,0 +1,269 @@
+//! Circuit breakers for remote hosts
+//!
+//! An instance which has gone down would otherwise have every delivery worker waiting out
+//! its timeouts, over and over, while deliveries everywhere else queue up behind it.
+//! [`CircuitBreakers`] count each host's consecutive failures (connection failures,
+//! timeouts and 5xx responses), and after [`BreakerConfig::threshold`] of them open its
+//! circuit for a [cool-down](BreakerConfig::cooldown). While it's open:
+//!
+//! - [`BreakerTransport`] refuses requests to the host without sending them, so fetches
+//!   from it fail straight away
+//! - deliveries to it are [parked](crate::jobs::JobQueue::park) until the cool-down ends,
+//!   without using up their attempts
+//!
+//! Once it ends, the next request is let through as a trial: its success closes the circuit,
+//! and its failure opens it for another cool-down.
+//!
+//! `FEDIBRIDGE_BREAKER_THRESHOLD` sets the threshold, zero turning breakers off, and
+//! `FEDIBRIDGE_BREAKER_COOLDOWN_SECS` the cool-down
+
+use crate::json::Value;
+use crate::time::format_rfc3339;
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use crate::url::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "net")]
+use crate::tls::Stream;
+
+pub const DEFAULT_THRESHOLD: u32 = 5;
+pub const DEFAULT_COOLDOWN: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerConfig {
+    /// Consecutive failures which open a host's circuit. Zero never does
+    pub threshold: u32,
+    /// How long it stays open
+    pub cooldown: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            threshold: DEFAULT_THRESHOLD,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HostState {
+    failures: u32,
+    open_until: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+/// How requests to each host have been going
+pub struct CircuitBreakers {
+    config: BreakerConfig,
+    /// Hosts whose last request failed
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: BreakerConfig) -> CircuitBreakers {
+        CircuitBreakers {
+            config,
+            hosts: Mutex::default(),
+        }
+    }
+
+    /// When `host`'s circuit closes, if it's open at `now`
+    pub fn open_until(&self, host: &str, now: SystemTime) -> Option<SystemTime> {
+        let hosts = self.hosts.lock().unwrap();
+        let until = hosts.get(&host.to_ascii_lowercase())?.open_until?;
+        (until > now).then_some(until)
+    }
+
+    /// Record whether a request to `host` failed at `now`. Returns when its circuit closes
+    /// if the failure opened it
+    pub fn record(&self, host: &str, failed: bool, now: SystemTime) -> Option<SystemTime> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = host.to_ascii_lowercase();
+        if !failed {
+            hosts.remove(&host);
+            return None;
+        }
+        let state = hosts.entry(host).or_default();
+        state.failures += 1;
+        if self.config.threshold == 0 || state.failures < self.config.threshold {
+            return None;
+        }
+        let until = now + self.config.cooldown;
+        state.open_until = Some(until);
+        Some(until)
+    }
+
+    /// Close `host`'s circuit, returning whether it was open at `now`
+    pub fn reset(&self, host: &str, now: SystemTime) -> bool {
+        let open = self.open_until(host, now).is_some();
+        self.hosts
+            .lock()
+            .unwrap()
+            .remove(&host.to_ascii_lowercase());
+        open
+    }
+
+    /// The hosts whose circuits are open at `now` and when each closes, ordered by host
+    pub fn open(&self, now: SystemTime) -> Vec<(String, SystemTime)> {
+        let hosts = self.hosts.lock().unwrap();
+        let mut open: Vec<_> = hosts
+            .iter()
+            .filter_map(|(host, state)| Some((host.clone(), state.open_until?)))
+            .filter(|(_, until)| *until > now)
+            .collect();
+        open.sort();
+        open
+    }
+
+    pub fn to_json(&self, now: SystemTime) -> Value {
+        let open = self.open(now).into_iter().map(|(host, until)| {
+            Value::object([
+                ("host", Value::from(host)),
+                ("until", Value::from(format_rfc3339(until))),
+            ])
+        });
+        Value::object([
+            ("threshold", Value::from(self.config.threshold)),
+            ("cooldownSecs", Value::from(self.config.cooldown.as_secs())),
+            ("open", Value::Array(open.collect())),
+        ])
+    }
+}
+
+/// Whether a request's outcome says anything about its host being up, and if so whether it
+/// failed
+fn failed(result: &Result<OutboundResponse, TransportError>) -> Option<bool> {
+    match result {
+        Ok(response) => Some(response.status >= 500),
+        Err(
+            TransportError::Connect { .. }
+            | TransportError::Timeout { .. }
+            | TransportError::MalformedResponse { .. },
+        ) => Some(true),
+        Err(_) => None,
+    }
+}
+
+/// A transport which passes requests on to another, except to hosts whose circuits are open
+pub struct BreakerTransport {
+    inner: Arc<dyn HttpTransport>,
+    breakers: Arc<CircuitBreakers>,
+}
+
+impl BreakerTransport {
+    pub fn new(inner: Arc<dyn HttpTransport>, breakers: Arc<CircuitBreakers>) -> BreakerTransport {
+        BreakerTransport { inner, breakers }
+    }
+}
+
+impl HttpTransport for BreakerTransport {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        let Ok(url) = Url::parse(&request.url) else {
+            return self.inner.send(request);
+        };
+        if let Some(until) = self.breakers.open_until(&url.host, SystemTime::now()) {
+            return Err(TransportError::CircuitOpen {
+                host: url.host,
+                until,
+            });
+        }
+        let result = self.inner.send(request);
+        if let Some(failed) = failed(&result) {
+            self.breakers.record(&url.host, failed, SystemTime::now());
+        }
+        result
+    }
+
+    #[cfg(feature = "net")]
+    fn open(&self, url: &str) -> Result<Box<dyn Stream>, TransportError> {
+        self.inner.open(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "full-bridge")]
+    use {
+        crate::delivery::tests::{signing_bridge, ACTOR},
+        crate::delivery::Delivery,
+        crate::http::Method,
+        crate::jobs::{Deferred, Job, JobHandler},
+        crate::transport::MockTransport,
+    };
+
+    #[test]
+    fn circuits_open_after_repeated_failures_then_let_a_trial_through() {
+        let breakers = CircuitBreakers::new(BreakerConfig {
+            threshold: 3,
+            cooldown: Duration::from_secs(60),
+        });
+        let now = SystemTime::now();
+        assert_eq!(breakers.record("down.example", true, now), None);
+        assert_eq!(breakers.record("down.example", true, now), None);
+        // A success in between starts the count again
+        breakers.record("flaky.example", true, now);
+        breakers.record("flaky.example", false, now);
+        assert_eq!(breakers.record("flaky.example", true, now), None);
+
+        let until = now + Duration::from_secs(60);
+        assert_eq!(breakers.record("Down.example", true, now), Some(until));
+        assert_eq!(breakers.open_until("down.example", now), Some(until));
+        assert_eq!(breakers.open(now), [("down.example".to_string(), until)]);
+
+        // After the cool-down, a failed trial opens it again
+        assert_eq!(breakers.open_until("down.example", until), None);
+        let later = until + Duration::from_secs(60);
+        assert_eq!(breakers.record("down.example", true, until), Some(later));
+        assert!(breakers.reset("down.example", until));
+        assert_eq!(breakers.open_until("down.example", until), None);
+
+        let off = CircuitBreakers::new(BreakerConfig {
+            threshold: 0,
+            ..BreakerConfig::default()
+        });
+        assert!((0..10).all(|_| off.record("down.example", true, now).is_none()));
+    }
+
+    #[cfg(feature = "full-bridge")]
+    #[test]
+    fn deliveries_to_an_open_circuit_are_parked() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond(
+            Method::Post,
+            "https://down.example/inbox",
+            OutboundResponse::new(503),
+        );
+        let bridge = signing_bridge(mock.clone()).with_circuit_breakers(BreakerConfig {
+            threshold: 1,
+            ..BreakerConfig::default()
+        });
+        let delivery = |n: u32| {
+            let activity = format!(
+                r#"{{"type": "Delete", "id": "https://a.example/{n}", "actor": "{ACTOR}"}}"#
+            );
+            Job::Deliver(Delivery::new("https://down.example/inbox", activity))
+        };
+        for n in 0..3 {
+            bridge.jobs.push(delivery(n)).unwrap();
+        }
+
+        let first = bridge.jobs.take(SystemTime::now()).unwrap();
+        let error = bridge.run(&first.job).unwrap_err();
+        bridge.failed(&first, &error);
+        // The rest wait out the cool-down, still with all their attempts
+        assert!(bridge.jobs.take(SystemTime::now()).is_none());
+        let queued = bridge.jobs.queued();
+        assert!(queued[1..].iter().all(|job| job.attempts == 0));
+        let until = bridge
+            .breakers
+            .open_until("down.example", SystemTime::now());
+        assert_eq!(Some(queued[1].run_at), until);
+        // And a delivery which comes due meanwhile is deferred without being attempted
+        let error = bridge.run(&delivery(3)).unwrap_err();
+        assert!(error.is::<Deferred>());
+        assert_eq!(mock.requests_to("https://down.example/inbox").len(), 1);
+    }
+}
@@ -0,0 +1,1482 @@
+//! Shared state of a running bridge
+
+use crate::addressing::FollowerStore;
+use crate::approval::ApprovalQueue;
+use crate::archive::EventArchive;
+use crate::article::ArticleConfig;
+use crate::attribution::{Attribution, AttributionConfig};
+use crate::audit::{AuditLog, AuditRecord};
+use crate::breaker::{BreakerConfig, BreakerTransport, CircuitBreakers};
+use crate::cache::{CacheConfig, FetchCache};
+use crate::car::Cid;
+use crate::community::{CommunityIndex, CommunityStrategy};
+use crate::concurrency::{self, ConcurrencyConfig, DeliveryConcurrency};
+use crate::consent::{self, Consent, ConsentError, ConsentLog, ConsentState, Terms};
+use crate::content::{self, ContentFilter};
+use crate::crawl::{CrawlConfig, Relays};
+use crate::dedup::SeenActivities;
+use crate::delivery::{self, Delivery, RequestSigner};
+use crate::digest::{DigestCollector, DigestConfig, Network};
+use crate::dm::{self, BounceLimiter, DmPolicy};
+use crate::dns::DnsResolver;
+use crate::dryrun::{DryRunTransport, ReviewLog};
+use crate::egress::EgressPolicy;
+use crate::engagement::EngagementCounts;
+use crate::error::BridgeError;
+use crate::feed::FeedConfig;
+use crate::filter::Filter;
+use crate::firehose::{EventHeader, FirehoseCursor, ProcessedEvents, Shard};
+use crate::image::{ImageCodec, ImageLimits};
+use crate::interop::{self, OptInError, OtherBridges};
+use crate::jobs::{Deferred, Job, JobHandler, JobQueue, QueuedJob};
+use crate::json::{self, Value};
+use crate::keys::{KeyGenerator, KeyOwner, KeyPurpose, KeyStore, Rotation, DEFAULT_KEY_OVERLAP};
+use crate::labeler::{self, LabelStore};
+use crate::labels::LabelPolicy;
+use crate::lexicon::QuarantineLog;
+use crate::linkcard::LinkCardConfig;
+use crate::markup::HtmlProfile;
+use crate::mirror;
+use crate::moderation::{self, ModerationConfig};
+use crate::modstream::ModerationStream;
+use crate::oauth::{JwkEncoder, OAuthConfig};
+use crate::objects::ObjectStore;
+use crate::orphans::{OrphanBuffer, DEFAULT_ORPHAN_WINDOW};
+use crate::parsing::ParsingConfig;
+use crate::peering::{PeerConfig, PeerMappings};
+use crate::peers::{self, PeerProfiles};
+use crate::policy::{self, FederationPolicy};
+use crate::provision::IdentityBackend;
+use crate::published::PublishedObjects;
+use crate::quota::{Charge, QuotaConfig, QuotaUsage, Throttled};
+use crate::reactions::ReactionConfig;
+use crate::receipts::{Outcome, Receipt, ReceiptLog};
+use crate::reconcile::{ReconcileConfig, ReconcileStats};
+use crate::replycontext::ReplyContext;
+use crate::repo::{Head, RepoError, RepoSigner, RepoStore, Write};
+use crate::resolver::Resolver;
+use crate::resync::GapDetector;
+use crate::retention::{MediaStore, RetentionConfig};
+use crate::retraction::DEFAULT_RETRACTION_WINDOW;
+use crate::sanctions::Sanctions;
+use crate::schedule::{self, Hold};
+use crate::seen::SeenObjects;
+use crate::shedding::{ShedConfig, Shedding};
+use crate::shutdown::Shutdown;
+use crate::signatures::{KeyCache, SeenSignatures, SignaturePolicies, SignatureVerifier};
+use crate::stats::{self, Stats};
+use crate::storage::StateDir;
+use crate::store::{IdentityStore, Mapping, MappingStatus, StoreError};
+use crate::templates::Templates;
+use crate::threadgate::ReplyGatePolicy;
+use crate::time::DEFAULT_MAX_CLOCK_SKEW;
+use crate::trace::{self, Decision, DecisionLog, Reason};
+use crate::transform::{Hook, Stage, Transformer, Transformers};
+use crate::transport::{self, HttpTransport};
+use crate::unbridge::DeletionLog;
+use crate::upstream::{UpstreamConfig, UpstreamKind, Upstreams};
+use crate::url::Url;
+use crate::webhooks::{self, WebhookConfig, WebhookEvent};
+use atproto::DID::Did;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How many times [`Bridge::update_repo`] builds its writes before giving up on a repo which
+/// keeps changing under it
+pub const MAX_SWAP_ATTEMPTS: u32 = 5;
+
+/// Everything the bridge's subsystems share
+///
+/// Each component handles its own locking so this can be freely shared behind an `Arc`
+pub struct Bridge {
+    pub identities: IdentityStore,
+    pub jobs: Arc<JobQueue>,
+    pub firehose: FirehoseCursor,
+    /// Commits handled since the cursor was last saved, so they aren't again after a crash
+    pub processed_events: ProcessedEvents,
+    /// Where the firehose has been, to notice events going missing
+    pub gaps: GapDetector,
+    /// Which accounts' firehose events this instance handles
+    pub shard: Shard,
+    /// Which firehose events are worth decoding
+    pub filter: Filter,
+    pub keys: KeyStore,
+    /// How long a pre-rotated key is published before it's signed with
+    pub key_overlap: Duration,
+    /// Cached remote documents (actors, DID documents, WebFinger, objects)
+    pub documents: Arc<FetchCache<Value>>,
+    /// What the software of peer instances handles
+    pub peers: PeerProfiles,
+    /// All outbound HTTP goes through this
+    pub transport: Arc<dyn HttpTransport>,
+    /// Which hosts have been failing, and are left alone for now
+    pub breakers: Arc<CircuitBreakers>,
+    /// How many deliveries run at once, overall and to each host
+    pub concurrency: Arc<DeliveryConcurrency>,
+    /// Which firehose events are shed while it's behind, and how many have been
+    pub shedding: Arc<Shedding>,
+    /// The relays and PLC directories to choose between, and which are up
+    pub upstreams: Upstreams,
+    /// What hostnames are resolved with, as the transport does
+    pub dns: Arc<DnsResolver>,
+    /// Where requests fetched with [`egress::fetch`](crate::egress::fetch) may go, redirects
+    /// included
+    pub egress: EgressPolicy,
+    /// In a dry run, the writes it has held back
+    pub dry_run: Option<Arc<ReviewLog>>,
+    pub moderation: ModerationConfig,
+    /// How labelled posts are presented on the fediverse
+    pub labels: LabelPolicy,
+    /// How far ahead of our clock incoming timestamps may be
+    pub max_clock_skew: Duration,
+    pub link_cards: LinkCardConfig,
+    /// How long-form posts are cut down to a teaser
+    pub articles: ArticleConfig,
+    /// Footers appended to bridged posts
+    pub attribution: AttributionConfig,
+    /// Which flavour of HTML bridged posts are written in
+    pub html_profile: HtmlProfile,
+    /// What's said of unbridged posts bridged replies reply to
+    pub reply_context: ReplyContext,
+    /// The wording of polls, truncation notices and DM bounces, by language
+    pub templates: Templates,
+    /// How emoji reactions are bridged
+    pub reactions: ReactionConfig,
+    /// How images over Bluesky's blob limit are downscaled before upload
+    pub images: ImageLimits,
+    /// Decodes and re-encodes oversized images. Without one, they can't be bridged
+    pub image_codec: Option<Arc<dyn ImageCodec>>,
+    /// How direct messages to bridged accounts are answered
+    pub dms: DmPolicy,
+    pub bounces: BounceLimiter,
+    /// How fediverse replies to gated Bluesky threads are handled
+    pub reply_gates: ReplyGatePolicy,
+    pub digests: DigestConfig,
+    /// How often both sides of each account are compared
+    pub reconcile: ReconcileConfig,
+    /// What comparing them has found
+    pub reconciliation: ReconcileStats,
+    /// How much each account may write a day
+    pub quotas: QuotaConfig,
+    /// How much they have
+    pub quota_usage: QuotaUsage,
+    /// Interactions waiting to go out in digests
+    pub digest_collector: DigestCollector,
+    /// How long replies are held for a parent which hasn't been bridged yet
+    pub orphan_window: Duration,
+    /// Replies held for their parent
+    pub orphans: OrphanBuffer,
+    /// How old an event is before it's checked for having been deleted at its origin
+    pub retraction_window: Duration,
+    /// How long re-hosted media is kept
+    pub retention: RetentionConfig,
+    /// Re-hosted media, where the bridge has somewhere to keep it
+    pub media: Option<MediaStore>,
+    /// Fetched objects and blobs, stored once each by their content
+    pub objects: Option<ObjectStore>,
+    /// Audit records of unbridged accounts
+    pub deletions: DeletionLog,
+    /// What the bridge has done on either network
+    pub audit: AuditLog,
+    /// What it decided about each event, and why
+    pub decisions: DecisionLog,
+    /// What became of each delivery of a post, by inbox
+    pub receipts: ReceiptLog,
+    /// What's been sent as bridged accounts, for it to be fetched by ID
+    pub published: PublishedObjects,
+    /// Who follows each bridged account on the fediverse, for its followers to be delivered to
+    pub followers: FollowerStore,
+    /// How bridged posts are doing on Bluesky, for those mirroring it
+    pub engagement: EngagementCounts,
+    /// Counts of what it did and what failed, by day
+    pub stats: Stats,
+    /// Inbound events as they arrived, if they're kept for replaying
+    pub archive: Option<EventArchive>,
+    /// Inbound activities already handled, so redeliveries aren't
+    pub seen_activities: SeenActivities,
+    /// Objects and records recently translated, however they arrived
+    pub seen_objects: SeenObjects,
+    /// Firehose records which failed validation against their lexicon
+    pub quarantine: QuarantineLog,
+    /// How strictly each subsystem parses what other servers send
+    pub parsing: ParsingConfig,
+    /// Per-domain and per-DID federation rules
+    pub policy: FederationPolicy,
+    /// Every post is checked by these before it's bridged
+    pub content_filters: Vec<Arc<dyn ContentFilter>>,
+    /// Checks inbound HTTP signatures. Without one, no request can be verified
+    pub signature_verifier: Option<Arc<dyn SignatureVerifier>>,
+    /// Remote keys which have recently verified a signature
+    pub verified_keys: KeyCache,
+    /// How strictly inbound signatures are checked, per instance
+    pub signature_policies: SignaturePolicies,
+    /// Signatures which have verified, so they aren't replayed
+    pub seen_signatures: SeenSignatures,
+    /// Where operators are notified of events
+    pub webhooks: WebhookConfig,
+    /// Operators' customizations of bridged posts
+    pub transformers: Transformers,
+    /// The atproto repos of bridged fediverse accounts
+    pub repos: RepoStore,
+    /// Signs repo commits. Without one, nothing can be written to the repos
+    pub repo_signer: Option<Arc<dyn RepoSigner>>,
+    /// Signs outbound deliveries. Without one, nothing can be delivered
+    pub request_signer: Option<Arc<dyn RequestSigner>>,
+    /// Which relays are asked to crawl the repos
+    pub crawl: CrawlConfig,
+    /// How they've answered
+    pub relays: Relays,
+    /// How the bridge describes itself to OAuth authorization servers
+    pub oauth: OAuthConfig,
+    /// Publishes the OAuth client keys. Without one, there's no JWKS to serve
+    pub jwk_encoder: Option<Arc<dyn JwkEncoder>>,
+    /// What accounts must agree to before they're bridged. Without terms, nobody needs to
+    pub terms: Option<Terms>,
+    /// Who has agreed to which terms
+    pub consents: ConsentLog,
+    /// Whether accounts opting in wait for an operator to approve them
+    pub approval_required: bool,
+    /// Those waiting
+    pub approvals: ApprovalQueue,
+    /// The network posts are only mirrored to, if the bridge isn't two-way
+    pub mirror_to: Option<Network>,
+    /// Identities suspended and instances defederated by operators
+    pub sanctions: Sanctions,
+    /// The feeds of bridged posts served to Bluesky
+    pub feeds: FeedConfig,
+    /// The DID the bridge labels what it publishes as. Without one, nothing is labelled
+    pub labeler: Option<Did>,
+    /// What it's labelled
+    pub issued_labels: LabelStore,
+    /// Reports, labels and policy hits, for moderation tooling to follow
+    pub moderation_events: ModerationStream,
+    /// Where other bridges run, so their accounts aren't bridged again
+    pub other_bridges: OtherBridges,
+    /// How fediverse communities are represented on Bluesky
+    pub community_strategy: CommunityStrategy,
+    /// Their posts, for their feeds
+    pub communities: CommunityIndex,
+    /// How identities are made for accounts being bridged. Without one, they must come with
+    /// their own
+    pub identity_backend: Option<Arc<dyn IdentityBackend>>,
+    /// Which other fedibridges are peered with
+    pub peering: PeerConfig,
+    /// Who they bridge
+    pub peer_mappings: PeerMappings,
+}
+
+impl Default for Bridge {
+    fn default() -> Self {
+        Bridge {
+            identities: IdentityStore::default(),
+            jobs: Arc::default(),
+            firehose: FirehoseCursor::default(),
+            processed_events: ProcessedEvents::default(),
+            gaps: GapDetector::default(),
+            shard: Shard::SINGLE,
+            filter: Filter::default(),
+            keys: KeyStore::default(),
+            key_overlap: DEFAULT_KEY_OVERLAP,
+            documents: Arc::default(),
+            peers: PeerProfiles::default(),
+            transport: transport::platform_default(),
+            breakers: Arc::default(),
+            concurrency: Arc::default(),
+            shedding: Arc::default(),
+            upstreams: Upstreams::default(),
+            dns: Arc::default(),
+            egress: EgressPolicy::default(),
+            dry_run: None,
+            moderation: ModerationConfig::default(),
+            labels: LabelPolicy::default(),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            link_cards: LinkCardConfig::default(),
+            articles: ArticleConfig::default(),
+            attribution: AttributionConfig::default(),
+            html_profile: HtmlProfile::default(),
+            reply_context: ReplyContext::default(),
+            templates: Templates::default(),
+            reactions: ReactionConfig::default(),
+            images: ImageLimits::default(),
+            image_codec: None,
+            dms: DmPolicy::default(),
+            bounces: BounceLimiter::default(),
+            reply_gates: ReplyGatePolicy::default(),
+            digests: DigestConfig::default(),
+            reconcile: ReconcileConfig::default(),
+            quotas: QuotaConfig::default(),
+            quota_usage: QuotaUsage::default(),
+            reconciliation: ReconcileStats::default(),
+            digest_collector: DigestCollector::default(),
+            orphan_window: DEFAULT_ORPHAN_WINDOW,
+            orphans: OrphanBuffer::default(),
+            retraction_window: DEFAULT_RETRACTION_WINDOW,
+            retention: RetentionConfig::default(),
+            media: None,
+            objects: None,
+            deletions: DeletionLog::default(),
+            audit: AuditLog::default(),
+            decisions: DecisionLog::default(),
+            receipts: ReceiptLog::default(),
+            published: PublishedObjects::default(),
+            followers: FollowerStore::default(),
+            engagement: EngagementCounts::default(),
+            stats: Stats::default(),
+            archive: None,
+            seen_activities: SeenActivities::default(),
+            seen_objects: SeenObjects::default(),
+            quarantine: QuarantineLog::default(),
+            parsing: ParsingConfig::default(),
+            policy: FederationPolicy::default(),
+            content_filters: Vec::new(),
+            signature_verifier: None,
+            verified_keys: KeyCache::default(),
+            signature_policies: SignaturePolicies::default(),
+            seen_signatures: SeenSignatures::default(),
+            webhooks: WebhookConfig::default(),
+            transformers: Transformers::default(),
+            repos: RepoStore::default(),
+            repo_signer: None,
+            request_signer: None,
+            crawl: CrawlConfig::default(),
+            relays: Relays::default(),
+            oauth: OAuthConfig::default(),
+            jwk_encoder: None,
+            terms: None,
+            consents: ConsentLog::default(),
+            feeds: FeedConfig::default(),
+            labeler: None,
+            issued_labels: LabelStore::default(),
+            moderation_events: ModerationStream::default(),
+            other_bridges: OtherBridges::default(),
+            community_strategy: CommunityStrategy::default(),
+            communities: CommunityIndex::default(),
+            identity_backend: None,
+            peering: PeerConfig::default(),
+            peer_mappings: PeerMappings::default(),
+            approval_required: false,
+            mirror_to: None,
+            approvals: ApprovalQueue::default(),
+            sanctions: Sanctions::default(),
+        }
+    }
+}
+
+impl Bridge {
+    pub fn new() -> Bridge {
+        Bridge::default()
+    }
+
+    pub fn with_webhooks(self, webhooks: WebhookConfig) -> Bridge {
+        Bridge { webhooks, ..self }
+    }
+
+    pub fn with_crawl(self, crawl: CrawlConfig) -> Bridge {
+        Bridge { crawl, ..self }
+    }
+
+    pub fn with_oauth(self, oauth: OAuthConfig) -> Bridge {
+        Bridge { oauth, ..self }
+    }
+
+    pub fn with_feeds(self, feeds: FeedConfig) -> Bridge {
+        Bridge { feeds, ..self }
+    }
+
+    /// Label what's published for bridged accounts as `labeler`
+    pub fn with_labeler(self, labeler: Did) -> Bridge {
+        Bridge {
+            labeler: Some(labeler),
+            ..self
+        }
+    }
+
+    pub fn with_community_strategy(self, community_strategy: CommunityStrategy) -> Bridge {
+        Bridge {
+            community_strategy,
+            ..self
+        }
+    }
+
+    pub fn with_peering(self, peering: PeerConfig) -> Bridge {
+        Bridge { peering, ..self }
+    }
+
+    /// Set how identities are made for accounts being bridged
+    pub fn with_identity_backend(self, backend: Arc<dyn IdentityBackend>) -> Bridge {
+        Bridge {
+            identity_backend: Some(backend),
+            ..self
+        }
+    }
+
+    /// Hold accounts opting in for an operator's [approval](crate::approval), or not
+    pub fn with_approval_required(self, approval_required: bool) -> Bridge {
+        Bridge {
+            approval_required,
+            ..self
+        }
+    }
+
+    /// Only mirror posts to `mirror_to`, writing nothing else to either network
+    pub fn with_mirror_only_to(self, mirror_to: Option<Network>) -> Bridge {
+        Bridge { mirror_to, ..self }
+    }
+
+    pub fn with_other_bridges(self, other_bridges: OtherBridges) -> Bridge {
+        Bridge {
+            other_bridges,
+            ..self
+        }
+    }
+
+    pub fn with_jwk_encoder(self, encoder: Arc<dyn JwkEncoder>) -> Bridge {
+        Bridge {
+            jwk_encoder: Some(encoder),
+            ..self
+        }
+    }
+
+    /// Require accounts to agree to `terms` before they're bridged
+    pub fn with_terms(self, terms: Terms) -> Bridge {
+        Bridge {
+            terms: Some(terms),
+            ..self
+        }
+    }
+
+    /// Where `did` stands with the current terms
+    pub fn consent_state(&self, did: &Did) -> ConsentState {
+        self.consents.state(did, self.terms.as_ref())
+    }
+
+    /// Record that `did` agreed to `version` of the terms, which must be the current one
+    pub fn consent(&self, did: &Did, version: &str) -> Result<Consent, ConsentError> {
+        self.consent_with_proof(did, version, None)
+    }
+
+    /// [`Bridge::consent`], given outside the bridge where `proof` shows
+    pub fn consent_with_proof(
+        &self,
+        did: &Did,
+        version: &str,
+        proof: Option<&str>,
+    ) -> Result<Consent, ConsentError> {
+        let terms = self.terms.as_ref().ok_or(ConsentError::NoTerms)?;
+        if terms.version != version {
+            return Err(ConsentError::Outdated {
+                current: terms.version.clone(),
+                found: version.to_string(),
+            });
+        }
+        let consent = Consent {
+            version: terms.version.clone(),
+            text: terms.text.clone(),
+            at: SystemTime::now(),
+            proof: proof.map(str::to_string),
+        };
+        self.consents.record(did, consent.clone())?;
+        Ok(consent)
+    }
+
+    /// Whether `did` is a bridged account held back until it agrees to the current terms
+    pub fn awaiting_consent(&self, did: &Did) -> bool {
+        !self.consent_state(did).allows_bridging()
+            && self
+                .identities
+                .get(did)
+                .is_some_and(|mapping| mapping.status != MappingStatus::Passive)
+    }
+
+    /// Start bridging an account, replacing any mapping it had, or with
+    /// [approval](crate::approval) required, ask an operator to. Accounts another bridge
+    /// already bridges are refused
+    pub fn opt_in(&self, mapping: Mapping) -> Result<Option<Mapping>, OptInError> {
+        let bridged = self
+            .identities
+            .get(&mapping.did)
+            .is_some_and(|m| m.status == MappingStatus::Active);
+        if !self.approval_required || bridged {
+            return self.activate(mapping);
+        }
+        if let Some(domain) = interop::bridged_elsewhere(self, &mapping) {
+            return Err(OptInError::BridgedElsewhere { domain });
+        }
+        let event = WebhookEvent::ApprovalRequested {
+            did: mapping.did.clone(),
+            actor: mapping.actor.clone(),
+        };
+        if self.approvals.submit(mapping, SystemTime::now())? {
+            webhooks::notify(self, event);
+        }
+        Err(OptInError::AwaitingApproval)
+    }
+
+    /// Start bridging an account now, replacing any mapping it had. Operators' webhooks hear
+    /// of it unless it was already bridged. Accounts another bridge already bridges, or with
+    /// a [lookalike](crate::lookalike) of another account's handle, are refused
+    pub fn activate(&self, mapping: Mapping) -> Result<Option<Mapping>, OptInError> {
+        if let Some(domain) = interop::bridged_elsewhere(self, &mapping) {
+            return Err(OptInError::BridgedElsewhere { domain });
+        }
+        let event = WebhookEvent::OptIn {
+            did: mapping.did.clone(),
+            actor: mapping.actor.clone(),
+        };
+        let replaced = self.identities.try_insert(mapping).map_err(|e| match e {
+            StoreError::Lookalike { handle, of } => OptInError::Lookalike { handle, of },
+            e => OptInError::Io(io::Error::other(e)),
+        })?;
+        if !replaced
+            .as_ref()
+            .is_some_and(|m| m.status == MappingStatus::Active)
+        {
+            webhooks::notify(self, event);
+        }
+        Ok(replaced)
+    }
+
+    /// Replace the outbound transport, e.g. with a mock for tests
+    pub fn with_transport(self, transport: Arc<dyn HttpTransport>) -> Bridge {
+        Bridge { transport, ..self }
+    }
+
+    /// Replace the resolver hostnames are looked up with, outside the transport
+    pub fn with_dns(self, dns: Arc<DnsResolver>) -> Bridge {
+        Bridge { dns, ..self }
+    }
+
+    /// Replace the policy fetches of remote documents and links check every hop against
+    pub fn with_egress(self, egress: EgressPolicy) -> Bridge {
+        Bridge { egress, ..self }
+    }
+
+    /// Keep inbound events in `archive`, so they can be [replayed](crate::archive::replay)
+    pub fn with_archive(self, archive: EventArchive) -> Bridge {
+        Bridge {
+            archive: Some(archive),
+            ..self
+        }
+    }
+
+    /// Make this a [dry run](crate::dryrun), capturing writes through the current transport
+    /// to `log` instead of sending them
+    pub fn with_dry_run(self, log: ReviewLog) -> Bridge {
+        let log = Arc::new(log);
+        let transport = Arc::new(DryRunTransport::new(self.transport.clone(), log.clone()));
+        Bridge {
+            transport,
+            dry_run: Some(log),
+            ..self
+        }
+    }
+
+    pub fn with_html_profile(self, html_profile: HtmlProfile) -> Bridge {
+        Bridge {
+            html_profile,
+            ..self
+        }
+    }
+
+    /// Say `reply_context` of unbridged posts that bridged replies reply to
+    pub fn with_reply_context(self, reply_context: ReplyContext) -> Bridge {
+        Bridge {
+            reply_context,
+            ..self
+        }
+    }
+
+    pub fn with_templates(self, templates: Templates) -> Bridge {
+        Bridge { templates, ..self }
+    }
+
+    /// Fail over between the relays and PLC directories of `config`
+    pub fn with_upstreams(self, config: UpstreamConfig) -> Bridge {
+        Bridge {
+            upstreams: Upstreams::new(config),
+            ..self
+        }
+    }
+
+    /// Tune how many deliveries run at once within `config`
+    pub fn with_delivery_concurrency(self, config: ConcurrencyConfig) -> Bridge {
+        Bridge {
+            concurrency: Arc::new(DeliveryConcurrency::new(config)),
+            ..self
+        }
+    }
+
+    /// Shed firehose events as `config` says while the bridge is behind
+    pub fn with_shedding(self, config: ShedConfig) -> Bridge {
+        Bridge {
+            shedding: Arc::new(Shedding::new(config)),
+            ..self
+        }
+    }
+
+    /// Stop sending requests to hosts which keep failing, for a while, through the
+    /// transport as it is
+    pub fn with_circuit_breakers(self, config: BreakerConfig) -> Bridge {
+        let breakers = Arc::new(CircuitBreakers::new(config));
+        let transport = Arc::new(BreakerTransport::new(
+            self.transport.clone(),
+            breakers.clone(),
+        ));
+        Bridge {
+            transport,
+            breakers,
+            ..self
+        }
+    }
+
+    /// Replace the keystore, e.g. with an encrypted one opened from the state directory
+    pub fn with_keys(self, keys: KeyStore) -> Bridge {
+        Bridge { keys, ..self }
+    }
+
+    /// How long keys are [pre-rotated](Bridge::prerotate_key) over
+    pub fn with_key_overlap(self, key_overlap: Duration) -> Bridge {
+        Bridge {
+            key_overlap,
+            ..self
+        }
+    }
+
+    /// Configure where moderation reports are sent
+    pub fn with_moderation(self, moderation: ModerationConfig) -> Bridge {
+        Bridge { moderation, ..self }
+    }
+
+    pub fn with_label_policy(self, labels: LabelPolicy) -> Bridge {
+        Bridge { labels, ..self }
+    }
+
+    pub fn with_max_clock_skew(self, max_clock_skew: Duration) -> Bridge {
+        Bridge {
+            max_clock_skew,
+            ..self
+        }
+    }
+
+    /// Configure link card generation for posts bridged to Bluesky
+    pub fn with_link_cards(self, link_cards: LinkCardConfig) -> Bridge {
+        Bridge { link_cards, ..self }
+    }
+
+    pub fn with_articles(self, articles: ArticleConfig) -> Bridge {
+        Bridge { articles, ..self }
+    }
+
+    /// Append footers to bridged posts, adding the [`Attribution`] transformer for those
+    /// bridged to the fediverse
+    pub fn with_attribution(self, attribution: AttributionConfig) -> Bridge {
+        let bridge = match &attribution.to_fediverse {
+            Some(template) => self.with_transformer(
+                Stage::Text,
+                Hook::After,
+                Arc::new(Attribution(template.clone())),
+            ),
+            None => self,
+        };
+        Bridge {
+            attribution,
+            ..bridge
+        }
+    }
+
+    pub fn with_reactions(self, reactions: ReactionConfig) -> Bridge {
+        Bridge { reactions, ..self }
+    }
+
+    pub fn with_image_limits(self, images: ImageLimits) -> Bridge {
+        Bridge { images, ..self }
+    }
+
+    /// Set the codec oversized images are re-encoded with
+    pub fn with_image_codec(self, codec: Arc<dyn ImageCodec>) -> Bridge {
+        Bridge {
+            image_codec: Some(codec),
+            ..self
+        }
+    }
+
+    pub fn with_dm_policy(self, dms: DmPolicy) -> Bridge {
+        Bridge { dms, ..self }
+    }
+
+    pub fn with_reply_gates(self, reply_gates: ReplyGatePolicy) -> Bridge {
+        Bridge {
+            reply_gates,
+            ..self
+        }
+    }
+
+    /// Configure interaction digests for bridged accounts
+    pub fn with_digests(self, digests: DigestConfig) -> Bridge {
+        Bridge { digests, ..self }
+    }
+
+    pub fn with_reconcile(self, reconcile: ReconcileConfig) -> Bridge {
+        Bridge { reconcile, ..self }
+    }
+
+    pub fn with_quotas(self, quotas: QuotaConfig) -> Bridge {
+        Bridge { quotas, ..self }
+    }
+
+    pub fn with_orphan_window(self, orphan_window: Duration) -> Bridge {
+        Bridge {
+            orphan_window,
+            ..self
+        }
+    }
+
+    pub fn with_retraction_window(self, retraction_window: Duration) -> Bridge {
+        Bridge {
+            retraction_window,
+            ..self
+        }
+    }
+
+    pub fn with_parsing(self, parsing: ParsingConfig) -> Bridge {
+        Bridge { parsing, ..self }
+    }
+
+    /// Replace the document cache with an empty one configured by `config`
+    pub fn with_document_cache(self, config: CacheConfig) -> Bridge {
+        Bridge {
+            documents: Arc::new(FetchCache::new(config)),
+            ..self
+        }
+    }
+
+    /// Keep re-hosted media in `media`, subject to `retention`
+    pub fn with_media_store(self, media: MediaStore, retention: RetentionConfig) -> Bridge {
+        Bridge {
+            media: Some(media),
+            retention,
+            ..self
+        }
+    }
+
+    /// Add filters posts must pass to be bridged, after any already added
+    pub fn with_content_filters(
+        mut self,
+        filters: impl IntoIterator<Item = Arc<dyn ContentFilter>>,
+    ) -> Bridge {
+        self.content_filters.extend(filters);
+        self
+    }
+
+    /// Run `transformer` on bridged posts at `hook` of `stage`, after any already added there
+    pub fn with_transformer(
+        mut self,
+        stage: Stage,
+        hook: Hook,
+        transformer: Arc<dyn Transformer>,
+    ) -> Bridge {
+        self.transformers.register(stage, hook, transformer);
+        self
+    }
+
+    /// Set the signer repo commits are signed with
+    pub fn with_repo_signer(self, signer: Arc<dyn RepoSigner>) -> Bridge {
+        Bridge {
+            repo_signer: Some(signer),
+            ..self
+        }
+    }
+
+    /// Set the signer outbound deliveries are signed with
+    pub fn with_request_signer(self, signer: Arc<dyn RequestSigner>) -> Bridge {
+        Bridge {
+            request_signer: Some(signer),
+            ..self
+        }
+    }
+
+    /// Commit `writes` to the repo of `did`, a bridged fediverse account, for relays to pick up
+    ///
+    /// Until it's agreed to the current terms, only deletions are committed
+    pub fn commit(&self, did: &Did, writes: &[Write]) -> Result<Head, RepoError> {
+        self.commit_swapped(did, writes, None)
+    }
+
+    /// Commit what `build` makes of `did`'s repo as it is, returning the commit and its
+    /// writes, or `None` if there was nothing to write
+    ///
+    /// Should the repo change in the meantime, `build` is run again on the repo as it's
+    /// become, up to [`MAX_SWAP_ATTEMPTS`] times, so the writes never undo what they didn't see
+    pub fn update_repo(
+        &self,
+        did: &Did,
+        mut build: impl FnMut() -> Result<Vec<Write>, RepoError>,
+    ) -> Result<Option<(Head, Vec<Write>)>, RepoError> {
+        let mut attempts = 0;
+        loop {
+            let swap = self.repos.head(did).map(|head| head.cid);
+            let writes = build()?;
+            if writes.is_empty() {
+                return Ok(None);
+            }
+            attempts += 1;
+            match self.commit_swapped(did, &writes, swap.as_ref()) {
+                Err(RepoError::SwapCommit { .. } | RepoError::SwapRecord { .. })
+                    if attempts < MAX_SWAP_ATTEMPTS => {}
+                result => return result.map(|head| Some((head, writes))),
+            }
+        }
+    }
+
+    fn commit_swapped(
+        &self,
+        did: &Did,
+        writes: &[Write],
+        swap: Option<&Cid>,
+    ) -> Result<Head, RepoError> {
+        if self.sanctions.is_suspended(did) {
+            return Err(RepoError::Suspended { did: did.clone() });
+        }
+        let deleting = writes.iter().all(|w| matches!(w, Write::Delete { .. }));
+        if !deleting && self.awaiting_consent(did) {
+            return Err(RepoError::NotConsented { did: did.clone() });
+        }
+        if let Some(write) = writes.iter().find(|w| !mirror::commit_allowed(self, w)) {
+            let path = write.path().to_string();
+            return Err(RepoError::MirrorOnly { path });
+        }
+        let signer = self.repo_signer.as_ref().ok_or(RepoError::NoSigner)?;
+        let owner = KeyOwner::Account(did.clone());
+        let key = self
+            .keys
+            .current(&owner, KeyPurpose::RepoSigning)
+            .ok_or_else(|| RepoError::NoKey { did: did.clone() })?;
+        let now = SystemTime::now();
+        if !deleting {
+            let charge = Charge::of_writes(writes);
+            self.quota_usage.charge(&self.quotas, did, charge, now)?;
+        }
+        let created = self.repos.head(did).is_none();
+        let head =
+            self.repos
+                .commit_swapped(did, writes, swap, &key.keypair, signer.as_ref(), now)?;
+        // Writes are counted against the instance of the account they're bridged from
+        let instance = self
+            .identities
+            .get(did)
+            .and_then(|m| Url::parse(&m.actor).ok());
+        let instance = instance.as_ref().map(|url| url.host.as_str());
+        for write in writes {
+            self.audited(Some(AuditRecord::committed(did, write, now)), instance);
+        }
+        // The commit has happened either way, so its labels are only logged if they fail
+        if let Err(e) = labeler::label_commit(self, did, writes, created) {
+            eprintln!("Couldn't label {did}'s commit: {e}");
+        }
+        Ok(head)
+    }
+
+    /// Charge `did`'s [media quota](crate::quota) for `bytes` about to be stored for it
+    pub fn charge_media(&self, did: &Did, bytes: u64) -> Result<(), Throttled> {
+        let charge = Charge::media(bytes);
+        let now = SystemTime::now();
+        self.quota_usage.charge(&self.quotas, did, charge, now)
+    }
+
+    /// Set the verifier inbound signatures are checked with
+    pub fn with_signature_verifier(self, verifier: Arc<dyn SignatureVerifier>) -> Bridge {
+        Bridge {
+            signature_verifier: Some(verifier),
+            ..self
+        }
+    }
+
+    /// Check inbound signatures as `policies` say
+    pub fn with_signature_policies(self, policies: SignaturePolicies) -> Bridge {
+        Bridge {
+            signature_policies: policies,
+            ..self
+        }
+    }
+
+    /// How long verified remote keys are trusted before their actor is fetched again
+    pub fn with_key_cache_ttl(self, ttl: Duration) -> Bridge {
+        Bridge {
+            verified_keys: KeyCache::new(ttl),
+            ..self
+        }
+    }
+
+    /// Replace the firehose event filter
+    pub fn with_filter(self, filter: Filter) -> Bridge {
+        Bridge { filter, ..self }
+    }
+
+    /// Whether this instance should decode and handle a firehose event
+    ///
+    /// Events of accounts the bridge knows are [traced](crate::trace) when they're skipped
+    pub fn wants(&self, event: &EventHeader) -> bool {
+        if !self.shard.owns(&event.did) {
+            return false;
+        }
+        let skipped = if !self.filter.matches(event, &self.identities) {
+            match self.identities.is_tracked(&event.did) {
+                true => Reason::Filtered,
+                false => Reason::NotOptedIn,
+            }
+        } else if self.policy.verdict(None, Some(&event.did)).denied {
+            Reason::PolicyDenied
+        } else if self.awaiting_consent(&event.did) {
+            Reason::AwaitingConsent
+        } else {
+            return true;
+        };
+        if self.identities.get(&event.did).is_some() {
+            let at = match event.ops.first() {
+                Some(op) => format!("at://{}/{}", event.did, op.path),
+                None => format!("at://{}", event.did),
+            };
+            let decision = Decision::skipped(Network::Bluesky, &at, event.did.as_str(), skipped);
+            trace::record(
+                self,
+                decision.with_detail(format!("Firehose event {}", event.seq)),
+            );
+        }
+        false
+    }
+
+    /// A DID resolver sharing this bridge's transport and document cache, resolving from the
+    /// active PLC directory
+    pub fn resolver(&self) -> Resolver {
+        let resolver = Resolver::new(self.transport.clone(), self.documents.clone());
+        match self.upstreams.active(UpstreamKind::PlcDirectory) {
+            Some(directory) => resolver.with_plc_directory(directory),
+            None => resolver,
+        }
+    }
+
+    /// Rotate a key and queue republishing it wherever the old one was advertised
+    ///
+    /// The bridge's own keys are served directly from the keystore so need no follow-up
+    pub fn rotate_key(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        generator: &dyn KeyGenerator,
+    ) -> anyhow::Result<Rotation> {
+        let rotation = self.keys.rotate(owner, purpose, generator)?;
+        if let Some(job) = republishing(owner, purpose) {
+            self.jobs.push(job)?;
+        }
+        Ok(rotation)
+    }
+
+    /// [Pre-rotate](KeyStore::prerotate) a key over the [overlap](Bridge::with_key_overlap),
+    /// and queue republishing it twice: now, for the new version to be seen before anything is
+    /// signed with it, and once it takes over, for the old one to stop being published
+    pub fn prerotate_key(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        generator: &dyn KeyGenerator,
+    ) -> anyhow::Result<Rotation> {
+        let rotation = self
+            .keys
+            .prerotate(owner, purpose, generator, self.key_overlap)?;
+        if let Some(job) = republishing(owner, purpose) {
+            if let Some(switch) = rotation.current.signs_from {
+                let priority = job.default_priority();
+                self.jobs.schedule(job.clone(), priority, switch)?;
+            }
+            self.jobs.push(job)?;
+        }
+        Ok(rotation)
+    }
+
+    /// Restore the persisted parts of `shard`'s state from the state dir `root`
+    pub fn load(root: &StateDir, shard: Shard) -> io::Result<Bridge> {
+        Ok(Bridge {
+            firehose: FirehoseCursor::load_shard(root, shard)?,
+            processed_events: ProcessedEvents::open(shard.state_dir(root)?)?,
+            jobs: Arc::new(JobQueue::open(shard.state_dir(root)?)?),
+            deletions: DeletionLog::open(shard.state_dir(root)?)?,
+            audit: AuditLog::open(shard.state_dir(root)?)?,
+            decisions: DecisionLog::open(shard.state_dir(root)?)?,
+            receipts: ReceiptLog::open(shard.state_dir(root)?)?,
+            stats: Stats::open(shard.state_dir(root)?)?,
+            orphans: OrphanBuffer::load(&shard.state_dir(root)?)?,
+            seen_activities: SeenActivities::open(shard.state_dir(root)?)?,
+            seen_objects: SeenObjects::open(shard.state_dir(root)?)?,
+            quarantine: QuarantineLog::open(shard.state_dir(root)?)?,
+            // Shared by every shard, so kept at the root
+            policy: FederationPolicy::open(root.clone())?,
+            consents: ConsentLog::open(root.clone())?,
+            approvals: ApprovalQueue::open(root.clone())?,
+            sanctions: Sanctions::open(root.clone())?,
+            published: PublishedObjects::open(root.clone())?,
+            followers: FollowerStore::open(root.clone())?,
+            issued_labels: LabelStore::open(root.clone())?,
+            communities: CommunityIndex::open(root.clone())?,
+            identities: IdentityStore::load(root)?,
+            repos: RepoStore::open(root.clone())?,
+            objects: Some(ObjectStore::open(root)?),
+            shard,
+            ..Bridge::default()
+        })
+    }
+
+    /// Arrange for the persisted state to be flushed to `root` on shutdown
+    pub fn flush_on_shutdown(self: &Arc<Self>, shutdown: &Shutdown, root: StateDir) {
+        let (bridge, dir) = (self.clone(), root.clone());
+        shutdown.on_shutdown("identity store", move || {
+            Ok(bridge.identities.save(&dir)?)
+        });
+        let (bridge, dir) = (self.clone(), root.clone());
+        shutdown.on_shutdown("firehose cursor", move || {
+            bridge.firehose.save(&bridge.shard.state_dir(&dir)?)?;
+            bridge
+                .processed_events
+                .compact(bridge.firehose.position())?;
+            Ok(())
+        });
+        let bridge = self.clone();
+        shutdown.on_shutdown("job queue", move || Ok(bridge.jobs.save()?));
+        let bridge = self.clone();
+        shutdown.on_shutdown("seen objects", move || Ok(bridge.seen_objects.save()?));
+        let bridge = self.clone();
+        shutdown.on_shutdown("statistics", move || Ok(bridge.stats.flush()?));
+        let bridge = self.clone();
+        shutdown.on_shutdown("unthreaded replies", move || {
+            Ok(bridge.orphans.save(&bridge.shard.state_dir(&root)?)?)
+        });
+    }
+}
+
+/// The job republishing `owner`'s key for `purpose` wherever it's advertised, if anything
+/// needs to. The bridge's own keys are served directly from the keystore
+fn republishing(owner: &KeyOwner, purpose: KeyPurpose) -> Option<Job> {
+    let KeyOwner::Account(did) = owner else {
+        return None;
+    };
+    let did = did.clone();
+    match purpose {
+        KeyPurpose::HttpSignature | KeyPurpose::Assertion => Some(Job::SyncProfile { did }),
+        KeyPurpose::RepoSigning => Some(Job::UpdateDidDocument { did }),
+        // Only the bridge has client, label, rotation and peering keys
+        KeyPurpose::OAuthClient
+        | KeyPurpose::LabelSigning
+        | KeyPurpose::PlcRotation
+        | KeyPurpose::PeerSigning => None,
+    }
+}
+
+impl Bridge {
+    /// Add what was just done to the audit log, and count it against the fediverse
+    /// `instance` it was to or from. The work has happened either way, so failing to record
+    /// it is only logged rather than failing the job and repeating it
+    fn audited(&self, record: Option<AuditRecord>, instance: Option<&str>) {
+        let Some(record) = record else {
+            return;
+        };
+        self.stats.record(&record, instance);
+        if let Err(e) = self.audit.append(record) {
+            eprintln!("Couldn't write to the audit log: {e}");
+        }
+    }
+
+    fn receipted(&self, receipt: Option<Receipt>) {
+        if let Some(Err(e)) = receipt.map(|receipt| self.receipts.append(receipt)) {
+            eprintln!("Couldn't write a delivery receipt: {e}");
+        }
+    }
+}
+
+impl Bridge {
+    /// A delivery as it may go out, or the rule refusing it
+    fn screen(&self, delivery: &Delivery) -> Result<Delivery, (Reason, String)> {
+        let delivery = policy::outbound(self, delivery)?;
+        if let Err(rejection) = content::check_delivery(self, &delivery) {
+            return Err((Reason::Filtered, rejection.to_string()));
+        }
+        if !consent::delivery_allowed(self, &delivery) {
+            let terms = self.terms.as_ref().map(|terms| terms.version.as_str());
+            let terms = format!("Hasn't agreed to terms {}", terms.unwrap_or_default());
+            return Err((Reason::AwaitingConsent, terms));
+        }
+        if !mirror::delivery_allowed(self, &delivery) {
+            let kind = json::parse(&delivery.activity).ok();
+            let kind = kind
+                .as_ref()
+                .and_then(|a| a.get("type")?.as_str().map(str::to_string));
+            return Err((
+                Reason::MirrorOnly,
+                format!("{} isn't mirrored", kind.unwrap_or_default()),
+            ));
+        }
+        if !interop::delivery_allowed(self, &delivery) {
+            return Err((
+                Reason::OtherBridge,
+                format!("{} is a bridge", delivery.inbox),
+            ));
+        }
+        Ok(delivery)
+    }
+}
+
+impl JobHandler for Bridge {
+    fn run(&self, job: &Job) -> anyhow::Result<()> {
+        match job {
+            // Deliveries the policy, filters or missing consent refuse, or which would go to other
+            // bridges, are complete as far as the queue is concerned. What the policy let through is what's audited, as it can rewrite it
+            Job::Deliver(d) => {
+                // A host which has been failing isn't tried again until its cool-down ends
+                let host = Url::parse(&d.inbox).map(|url| url.host).unwrap_or_default();
+                let now = SystemTime::now();
+                if let Some(until) = self.breakers.open_until(&host, now) {
+                    return Err(Deferred { until }.into());
+                }
+                // Posts wait for their account's schedule, and are merged as they do
+                match schedule::hold(self, d, now)? {
+                    Hold::Send => {}
+                    Hold::Until(until) => return Err(Deferred { until }.into()),
+                    Hold::Coalesced => return Ok(()),
+                }
+                // As are deliveries to a host with as many in flight as it's keeping up with
+                let Some(_slot) = self.concurrency.admit(&host) else {
+                    let until = now + concurrency::HOST_WAIT;
+                    return Err(Deferred { until }.into());
+                };
+                match self.screen(d) {
+                    Ok(d) => {
+                        let d = peers::adapted(self, &d);
+                        // Kept before it's sent, as its recipients may fetch it straight away
+                        self.published.published(self, &d)?;
+                        let started = Instant::now();
+                        let sent = delivery::deliver(self, &d);
+                        let overloaded = concurrency::overloaded(&sent);
+                        let latency = started.elapsed();
+                        self.concurrency
+                            .record(&host, latency, overloaded, Instant::now());
+                        sent?;
+                        let inbox = Url::parse(&d.inbox).ok();
+                        let instance = inbox.as_ref().map(|url| url.host.as_str());
+                        self.audited(AuditRecord::delivered(&d, SystemTime::now()), instance);
+                        if let Some(decision) = Decision::delivered(&d) {
+                            trace::record(self, decision);
+                        }
+                        self.receipted(Receipt::new(&d, Outcome::Delivered));
+                        Ok(())
+                    }
+                    Err((rule, detail)) => {
+                        if let Some(decision) = Decision::delivered(d) {
+                            let detail = format!("{detail}, delivering to {}", d.inbox);
+                            trace::record(self, decision.skipped_by(rule).with_detail(detail));
+                        }
+                        let receipt = Receipt::new(d, Outcome::Skipped);
+                        self.receipted(receipt.map(|r| r.with_detail(rule.as_str())));
+                        Ok(())
+                    }
+                }
+            }
+            // Reports and messages are interactions, which a mirror doesn't have
+            Job::CreateReport(_) | Job::SendChatMessage(_) if !mirror::job_allowed(self, job) => {
+                Ok(())
+            }
+            Job::CreateReport(report) => {
+                moderation::create_report(self.transport.as_ref(), &self.moderation, report)?;
+                self.audited(AuditRecord::for_job(job, SystemTime::now()), None);
+                Ok(())
+            }
+            Job::SendChatMessage(reply) => {
+                dm::send_chat(self.transport.as_ref(), &self.dms, reply)?;
+                self.audited(AuditRecord::for_job(job, SystemTime::now()), None);
+                Ok(())
+            }
+            Job::Notify(notification) => Ok(webhooks::send(
+                self.transport.as_ref(),
+                &self.webhooks,
+                notification,
+            )?),
+            // Killing these as needing someone keeps them visible to operators, rather than
+            // silently dropping them or retrying what can't succeed
+            Job::FetchMedia { .. }
+            | Job::Backfill { .. }
+            | Job::SyncProfile { .. }
+            | Job::UpdateDidDocument { .. }
+            | Job::DeleteActor { .. }
+            | Job::DeactivateAccount { .. } => {
+                let error = anyhow::anyhow!("No handler for {} jobs", job.kind());
+                Err(BridgeError::needs_human(error).into())
+            }
+        }
+    }
+
+    fn failed(&self, queued: &QueuedJob, error: &anyhow::Error) {
+        let reason = stats::failure_reason(queued.job.kind(), error);
+        self.stats.failed(SystemTime::now(), &reason);
+        let Job::Deliver(delivery) = &queued.job else {
+            return;
+        };
+        let receipt = Receipt::new(delivery, Outcome::Retrying);
+        self.receipted(receipt.map(|r| r.with_detail(format!("{error:#}"))));
+        // If this failure opened the inbox's circuit, what's queued for it waits it out
+        let Ok(host) = Url::parse(&delivery.inbox).map(|url| url.host) else {
+            return;
+        };
+        let Some(until) = self.breakers.open_until(&host, SystemTime::now()) else {
+            return;
+        };
+        let to_host = |job: &Job| match job {
+            Job::Deliver(d) => Url::parse(&d.inbox).is_ok_and(|url| url.host == host),
+            _ => false,
+        };
+        if let Err(e) = self.jobs.park(to_host, until) {
+            eprintln!("Couldn't park deliveries to {host}: {e}");
+        }
+    }
+
+    fn dead(&self, queued: &QueuedJob) {
+        if let Job::Deliver(delivery) = &queued.job {
+            let receipt = Receipt::new(delivery, Outcome::Failed);
+            let error = queued.last_error.clone();
+            self.receipted(receipt.map(|r| Receipt { detail: error, ..r }));
+            let event = WebhookEvent::DeliveryFailed {
+                job: queued.id,
+                inbox: delivery.inbox.clone(),
+                attempts: queued.attempts,
+                error: queued.last_error.clone(),
+            };
+            webhooks::notify(self, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditQuery, Cause};
+    use crate::delivery::Delivery;
+    use crate::http::Method;
+    use crate::jobs::spawn_workers;
+    use crate::repo::record_cid;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use std::time::Duration;
+
+    #[test]
+    fn repo_updates_build_again_on_conflict() {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(crate::repo::tests::HashSigner));
+        let alice = atproto::did!("did:plc:alice");
+        let generator = crate::keys::tests::FakeGenerator::default();
+        let owner = KeyOwner::Account(alice.clone());
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let path = "app.bsky.actor.profile/self".to_string();
+        let profile = |fields: &[(&'static str, &str)]| {
+            Value::object(fields.iter().map(|&(k, v)| (k, Value::from(v))))
+        };
+        let record = profile(&[("displayName", "Alice")]);
+        let create = Write::Create {
+            path: path.clone(),
+            record,
+        };
+        bridge.commit(&alice, &[create]).unwrap();
+
+        let mut builds = 0;
+        let (_, writes) = bridge
+            .update_repo(&alice, || {
+                builds += 1;
+                let (_, current) =
+                    bridge.repos.records(&alice, "app.bsky.actor.profile")[0].clone();
+                if builds == 1 {
+                    // Someone else changes it between the bridge reading and writing it
+                    let theirs = Write::Update {
+                        path: path.clone(),
+                        record: profile(&[("displayName", "Alice"), ("description", "hi")]),
+                        swap: None,
+                    };
+                    bridge.commit(&alice, &[theirs]).unwrap();
+                }
+                let mut ours = current.clone();
+                if let Value::Object(fields) = &mut ours {
+                    fields.insert("displayName".into(), Value::from("Alice A."));
+                }
+                Ok(vec![Write::Update {
+                    path: path.clone(),
+                    record: ours,
+                    swap: Some(record_cid(&current)),
+                }])
+            })
+            .unwrap()
+            .unwrap();
+        assert_eq!(builds, 2);
+        let Write::Update { record, .. } = &writes[0] else {
+            panic!("the profile is updated");
+        };
+        assert_eq!(
+            record,
+            &profile(&[("displayName", "Alice A."), ("description", "hi")])
+        );
+    }
+
+    #[test]
+    fn state_survives_shutdown() {
+        let dir = crate::storage::tests::temp_state_dir();
+        let bridge = Arc::new(Bridge::load(&dir, Shard::SINGLE).unwrap());
+        bridge.firehose.processed(1234);
+        let delivery = Delivery::new("https://remote.example/inbox", "{}");
+        bridge.jobs.push(Job::Deliver(delivery)).unwrap();
+
+        let shutdown = Shutdown::new();
+        bridge.flush_on_shutdown(&shutdown, dir.clone());
+        assert!(shutdown.run(Duration::from_secs(1)).is_clean());
+
+        let restored = Bridge::load(&dir, Shard::SINGLE).unwrap();
+        assert_eq!(restored.firehose.position(), 1234);
+        assert_eq!(restored.jobs.len(), 1);
+    }
+
+    #[test]
+    fn rotation_queues_republishing() {
+        let bridge = Bridge::new();
+        let generator = crate::keys::tests::FakeGenerator::default();
+        let did = atproto::did!("did:plc:aaaa");
+        let owner = KeyOwner::Account(did.clone());
+        bridge
+            .rotate_key(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        bridge
+            .rotate_key(&KeyOwner::Bridge, KeyPurpose::HttpSignature, &generator)
+            .unwrap();
+        let queued: Vec<_> = bridge.jobs.queued().into_iter().map(|j| j.job).collect();
+        assert_eq!(queued, vec![Job::UpdateDidDocument { did: did.clone() }]);
+
+        // Pre-rotated, the actor is updated now and again once the new key takes over
+        let bridge = Bridge::new();
+        for _ in 0..2 {
+            bridge
+                .prerotate_key(&owner, KeyPurpose::HttpSignature, &generator)
+                .unwrap();
+        }
+        let queued = bridge.jobs.queued();
+        let runs: Vec<SystemTime> = queued.iter().map(|j| j.run_at).collect();
+        assert_eq!(queued.len(), 3);
+        assert!(queued
+            .iter()
+            .all(|j| j.job == Job::SyncProfile { did: did.clone() }));
+        let switch = bridge.keys.history(&owner, KeyPurpose::HttpSignature)[1].signs_from;
+        assert!(runs.contains(&switch.unwrap()));
+    }
+
+    #[test]
+    fn wants_owned_events_passing_the_filter() {
+        let did = atproto::did!("did:plc:aaaa");
+        let event = EventHeader {
+            seq: 1,
+            did: did.clone(),
+            ops: Vec::new(),
+        };
+        let bridge = Bridge::new();
+        assert!(!bridge.wants(&event));
+        bridge.identities.insert(crate::store::Mapping::new(
+            did.clone(),
+            "https://bridge.example/users/a",
+        ));
+        assert!(bridge.wants(&event));
+        let other = Shard::new((Shard::of(&did, 2) + 1) % 2, 2).unwrap();
+        let bridge = Bridge {
+            shard: other,
+            ..Bridge::new().with_filter(Filter::All)
+        };
+        assert!(!bridge.wants(&event));
+        let bridge = Bridge::new().with_filter(Filter::All);
+        let rules = crate::policy::parse_rules("did:plc:aaaa=deny").unwrap();
+        bridge.policy.merge(rules).unwrap();
+        assert!(!bridge.wants(&event));
+    }
+
+    #[test]
+    fn delivery_jobs_go_through_transport() {
+        let mock = Arc::new(MockTransport::new());
+        let inbox = "https://remote.example/inbox";
+        mock.respond(Method::Post, inbox, OutboundResponse::new(202));
+        let bridge = Arc::new(crate::delivery::tests::signing_bridge(mock.clone()));
+        let activity = r#"{"type": "Delete", "actor": "https://bridge.example/users/alice",
+            "object": "https://bridge.example/users/alice/posts/1"}"#;
+        let source = "at://did:plc:aaaa/app.bsky.feed.post/1";
+        let delivery = Delivery::new(inbox, activity).because(Cause::new("post", Some(source)));
+        bridge.jobs.push(Job::Deliver(delivery)).unwrap();
+
+        let shutdown = Shutdown::new();
+        let workers = spawn_workers(bridge.jobs.clone(), bridge.clone(), 1, shutdown.clone());
+        while !bridge.jobs.is_empty() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        shutdown.run(Duration::from_secs(1));
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(mock.requests_to(inbox).len(), 1);
+        let audited = bridge.audit.query(&AuditQuery {
+            source: Some(source.to_string()),
+            ..AuditQuery::default()
+        });
+        assert_eq!(audited.len(), 1);
+        assert_eq!(audited[0].action, "Delete");
+        let receipts = bridge.receipts.for_post(source, Some("remote.example"));
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(receipts[0].outcome, Outcome::Delivered);
+    }
+
+    #[test]
+    fn skipped_events_are_traced() {
+        let did = atproto::did!("did:plc:aaaa");
+        let actor = "https://bridge.example/users/a";
+        let bridge = Bridge::new().with_filter(Filter::All);
+        bridge
+            .identities
+            .insert(crate::store::Mapping::new(did.clone(), actor));
+        let rules = "did:plc:aaaa=deny,denied.example=deny";
+        bridge
+            .policy
+            .merge(crate::policy::parse_rules(rules).unwrap())
+            .unwrap();
+        let path = "app.bsky.feed.post/1";
+        let event = EventHeader {
+            seq: 7,
+            did: did.clone(),
+            ops: vec![crate::firehose::Operation {
+                action: crate::firehose::Action::Create,
+                path: path.to_string(),
+            }],
+        };
+        assert!(!bridge.wants(&event));
+        // Nothing is traced of accounts the bridge has never heard of
+        let stranger = EventHeader {
+            did: atproto::did!("did:plc:bbbb"),
+            ..event.clone()
+        };
+        assert!(!Bridge::new().wants(&stranger));
+
+        let source = format!("at://{did}/{path}");
+        let activity = format!(r#"{{"type": "Create", "actor": "{actor}", "object": "x"}}"#);
+        let delivery = Delivery::new("https://social.denied.example/inbox", activity)
+            .because(Cause::new("post", Some(&source)));
+        bridge.run(&Job::Deliver(delivery)).unwrap();
+        let traced = bridge.decisions.query(&crate::trace::DecisionQuery {
+            event: Some(source),
+            ..Default::default()
+        });
+        let rules: Vec<_> = traced.iter().map(|d| d.skipped).collect();
+        assert_eq!(
+            rules,
+            [Some(Reason::PolicyDenied), Some(Reason::PolicyDenied)]
+        );
+        assert_eq!(
+            traced[0].detail.as_deref(),
+            Some("social.denied.example is denied, delivering to https://social.denied.example/inbox")
+        );
+    }
+}
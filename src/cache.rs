@@ -0,0 +1,688 @@
+//! Shared cache for remote documents
+//!
+//! Actor documents, DID documents, WebFinger results and remote objects all go through one
+//! cache with a per-kind freshness policy. An entry is *fresh* for its TTL, after which it is
+//! *stale* for a further window: stale entries are still served, but trigger a background
+//! refetch (stale-while-revalidate). Entries past the stale window are treated as misses.
+//!
+//! The cache is bounded by the total size of its entries, evicting the least recently used.
+//! Expired entries are otherwise only dropped when looked up, so long-running bridges
+//! [purge](FetchCache::purge_expired) them periodically; see [`crate::retention`]
+//!
+//! Entries keep the [`Validators`] they were served with, so that refetches can be
+//! conditional: with [`FetchCache::get_or_revalidate`], a server answering `304 Not Modified`
+//! renews the entry instead of sending it again
+//!
+//! A burst of activities about one account, such as replies to a popular post, can need the
+//! same document many times at once. Fetches of a key missing from the cache are coalesced:
+//! while one is in flight, others for the key wait for it and share what it fetched. A fetch
+//! which fails isn't shared, as its error can't be, and those waiting on it fetch for
+//! themselves
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The kinds of remote resource which are cached
+pub enum ResourceKind {
+    Actor,
+    DidDocument,
+    WebFinger,
+    Object,
+}
+
+impl ResourceKind {
+    const ALL: [ResourceKind; 4] = [
+        ResourceKind::Actor,
+        ResourceKind::DidDocument,
+        ResourceKind::WebFinger,
+        ResourceKind::Object,
+    ];
+
+    /// Defaults for how long each kind of resource stays fresh and then stale
+    ///
+    /// Identity documents change rarely, while remote objects are edited and liked often
+    pub fn default_policy(&self) -> Policy {
+        let (ttl, stale_for) = match self {
+            ResourceKind::Actor => (Duration::from_secs(3600), Duration::from_secs(86400)),
+            ResourceKind::DidDocument => (Duration::from_secs(3600), Duration::from_secs(86400)),
+            ResourceKind::WebFinger => (Duration::from_secs(86400), Duration::from_secs(604800)),
+            ResourceKind::Object => (Duration::from_secs(600), Duration::from_secs(3600)),
+        };
+        Policy { ttl, stale_for }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Freshness policy for one kind of resource
+pub struct Policy {
+    /// How long an entry is served without refetching
+    pub ttl: Duration,
+    /// How long after the TTL an entry may still be served while it's refetched
+    pub stale_for: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    /// Upper bound on the summed size of all entries
+    pub max_bytes: usize,
+    pub policies: HashMap<ResourceKind, Policy>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: 64 * 1024 * 1024,
+            policies: ResourceKind::ALL
+                .into_iter()
+                .map(|k| (k, k.default_policy()))
+                .collect(),
+        }
+    }
+}
+
+impl CacheConfig {
+    fn policy(&self, kind: ResourceKind) -> Policy {
+        self.policies
+            .get(&kind)
+            .copied()
+            .unwrap_or_else(|| kind.default_policy())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The result of looking up a key
+pub enum Lookup<V> {
+    Fresh(Arc<V>),
+    /// Usable, but should be refetched
+    Stale(Arc<V>),
+    Miss,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub fresh_hits: u64,
+    pub stale_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Entries dropped by [`FetchCache::purge_expired`]
+    pub purged: u64,
+    /// Refetches answered with `304 Not Modified`
+    pub not_modified: u64,
+    /// Misses answered by waiting on a fetch already in flight
+    pub coalesced: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What a server said identifies the version of a resource it sent, for conditional requests
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The result of a conditional fetch
+pub enum Fetched<V> {
+    /// A new version, with its size
+    Modified {
+        value: V,
+        size: usize,
+        validators: Validators,
+    },
+    /// The version the validators identify is still current
+    NotModified,
+}
+
+type Key = (ResourceKind, String);
+
+struct Entry<V> {
+    value: Arc<V>,
+    size: usize,
+    validators: Validators,
+    fetched_at: Instant,
+    last_used: u64,
+}
+
+/// A fetch in flight, and what it fetched once it's done: `None` if it failed
+struct Flight<V> {
+    fetched: Mutex<Option<Option<Arc<V>>>>,
+    done: Condvar,
+}
+
+/// Whoever is fetching a key, ending the flight however the fetch does
+struct Leading<'a, V> {
+    inner: &'a Mutex<Inner<V>>,
+    key: Key,
+    flight: Arc<Flight<V>>,
+    fetched: Option<Arc<V>>,
+}
+
+impl<V> Drop for Leading<'_, V> {
+    fn drop(&mut self) {
+        self.inner.lock().unwrap().fetching.remove(&self.key);
+        *self.flight.fetched.lock().unwrap() = Some(self.fetched.take());
+        self.flight.done.notify_all();
+    }
+}
+
+struct Inner<V> {
+    entries: HashMap<Key, Entry<V>>,
+    /// Entries by last use, oldest first
+    recency: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+    revalidating: HashSet<Key>,
+    /// Fetches of missing keys in flight
+    fetching: HashMap<Key, Arc<Flight<V>>>,
+    stats: CacheStats,
+}
+
+impl<V> Inner<V> {
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = tick;
+            self.recency.insert(tick, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry<V>> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+}
+
+/// A size-bounded, stale-while-revalidate cache of remote resources
+pub struct FetchCache<V> {
+    config: CacheConfig,
+    inner: Mutex<Inner<V>>,
+}
+
+impl<V> fmt::Debug for FetchCache<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock().unwrap();
+        f.debug_struct("FetchCache")
+            .field("entries", &inner.entries.len())
+            .field("bytes", &inner.bytes)
+            .field("stats", &inner.stats)
+            .finish()
+    }
+}
+
+impl<V: Send + Sync + 'static> Default for FetchCache<V> {
+    fn default() -> Self {
+        FetchCache::new(CacheConfig::default())
+    }
+}
+
+impl<V: Send + Sync + 'static> FetchCache<V> {
+    pub fn new(config: CacheConfig) -> FetchCache<V> {
+        FetchCache {
+            config,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+                bytes: 0,
+                revalidating: HashSet::new(),
+                fetching: HashMap::new(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Look up a key as of `now`
+    pub fn lookup(&self, kind: ResourceKind, key: &str, now: Instant) -> Lookup<V> {
+        let policy = self.config.policy(kind);
+        let key = (kind, key.to_string());
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.entries.get(&key) else {
+            inner.stats.misses += 1;
+            return Lookup::Miss;
+        };
+        let age = now.saturating_duration_since(entry.fetched_at);
+        let value = entry.value.clone();
+        let lookup = if age < policy.ttl {
+            inner.stats.fresh_hits += 1;
+            Lookup::Fresh(value)
+        } else if age < policy.ttl + policy.stale_for {
+            inner.stats.stale_hits += 1;
+            Lookup::Stale(value)
+        } else {
+            inner.stats.misses += 1;
+            inner.remove(&key);
+            return Lookup::Miss;
+        };
+        inner.touch(&key);
+        lookup
+    }
+
+    /// Insert a value fetched at `now`, evicting older entries to stay within size bounds
+    ///
+    /// Values larger than the whole cache aren't stored
+    pub fn insert(
+        &self,
+        kind: ResourceKind,
+        key: &str,
+        value: V,
+        size: usize,
+        now: Instant,
+    ) -> Arc<V> {
+        self.store(kind, key, Arc::new(value), size, Validators::default(), now)
+    }
+
+    fn store(
+        &self,
+        kind: ResourceKind,
+        key: &str,
+        value: Arc<V>,
+        size: usize,
+        validators: Validators,
+        now: Instant,
+    ) -> Arc<V> {
+        if size > self.config.max_bytes {
+            return value;
+        }
+        let key = (kind, key.to_string());
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.bytes + size > self.config.max_bytes {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+            inner.stats.evictions += 1;
+        }
+        inner.bytes += size;
+        inner.entries.insert(
+            key.clone(),
+            Entry {
+                value: value.clone(),
+                size,
+                validators,
+                fetched_at: now,
+                last_used: 0,
+            },
+        );
+        inner.touch(&key);
+        value
+    }
+
+    /// Drop an entry, e.g. after learning it has changed
+    pub fn invalidate(&self, kind: ResourceKind, key: &str) {
+        self.inner.lock().unwrap().remove(&(kind, key.to_string()));
+    }
+
+    /// Drop every entry past its stale window as of `now`, returning how many there were
+    pub fn purge_expired(&self, now: Instant) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let expired: Vec<Key> = inner
+            .entries
+            .iter()
+            .filter(|((kind, _), entry)| {
+                let policy = self.config.policy(*kind);
+                now.saturating_duration_since(entry.fetched_at) >= policy.ttl + policy.stale_for
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            inner.remove(key);
+        }
+        inner.stats.purged += expired.len() as u64;
+        expired.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    /// Summed size of all entries
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+
+    /// Get a value, fetching it on a miss
+    ///
+    /// `fetch` returns the value along with its size. Stale values are returned immediately
+    /// and refetched on a background thread; at most one refetch per key runs at a time, and
+    /// a failed refetch leaves the stale value in place
+    pub fn get_or_fetch<F, E>(
+        self: &Arc<Self>,
+        kind: ResourceKind,
+        key: &str,
+        fetch: F,
+    ) -> Result<Arc<V>, E>
+    where
+        F: Fn() -> Result<(V, usize), E> + Send + 'static,
+    {
+        self.get_or_revalidate(kind, key, move |_| {
+            let (value, size) = fetch()?;
+            Ok(Fetched::Modified {
+                value,
+                size,
+                validators: Validators::default(),
+            })
+        })
+    }
+
+    /// Get a value like [`get_or_fetch`](FetchCache::get_or_fetch), but with conditional
+    /// refetches
+    ///
+    /// `fetch` is given the validators of the cached version, if there is one, even when
+    /// it's past its stale window. Given validators, it may answer [`Fetched::NotModified`]
+    /// to renew that version; without them it must fetch the resource
+    pub fn get_or_revalidate<F, E>(
+        self: &Arc<Self>,
+        kind: ResourceKind,
+        key: &str,
+        fetch: F,
+    ) -> Result<Arc<V>, E>
+    where
+        F: Fn(Option<&Validators>) -> Result<Fetched<V>, E> + Send + 'static,
+    {
+        let cache_key = (kind, key.to_string());
+        // Looking up drops expired entries, so hold on to what can be revalidated first
+        let previous = self.revalidatable(&cache_key);
+        match self.lookup(kind, key, Instant::now()) {
+            Lookup::Fresh(value) => Ok(value),
+            Lookup::Stale(value) => {
+                if self
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .revalidating
+                    .insert(cache_key.clone())
+                {
+                    let cache = self.clone();
+                    thread::spawn(move || {
+                        let _ = cache.refetch(kind, &cache_key.1, previous, &fetch);
+                        cache.inner.lock().unwrap().revalidating.remove(&cache_key);
+                    });
+                }
+                Ok(value)
+            }
+            Lookup::Miss => {
+                let flight = match self.join(cache_key) {
+                    Ok(flight) => flight,
+                    Err(mut leading) => {
+                        let fetched = self.refetch(kind, key, previous, &fetch)?;
+                        leading.fetched = Some(fetched.clone());
+                        return Ok(fetched);
+                    }
+                };
+                let mut fetched = flight.fetched.lock().unwrap();
+                while fetched.is_none() {
+                    fetched = flight.done.wait(fetched).unwrap();
+                }
+                match fetched.clone().flatten() {
+                    Some(value) => {
+                        self.inner.lock().unwrap().stats.coalesced += 1;
+                        Ok(value)
+                    }
+                    None => {
+                        drop(fetched);
+                        self.refetch(kind, key, previous, &fetch)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The flight fetching `key`, or without one, a new flight for the caller to lead
+    fn join(&self, key: Key) -> Result<Arc<Flight<V>>, Leading<'_, V>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(flight) = inner.fetching.get(&key) {
+            return Ok(flight.clone());
+        }
+        let flight = Arc::new(Flight {
+            fetched: Mutex::new(None),
+            done: Condvar::new(),
+        });
+        inner.fetching.insert(key.clone(), flight.clone());
+        Err(Leading {
+            inner: &self.inner,
+            key,
+            flight,
+            fetched: None,
+        })
+    }
+
+    /// The cached value for a key, if it came with validators
+    fn revalidatable(&self, key: &Key) -> Option<(Arc<V>, usize, Validators)> {
+        let inner = self.inner.lock().unwrap();
+        let entry = inner
+            .entries
+            .get(key)
+            .filter(|entry| !entry.validators.is_empty())?;
+        Some((entry.value.clone(), entry.size, entry.validators.clone()))
+    }
+
+    fn refetch<F, E>(
+        &self,
+        kind: ResourceKind,
+        key: &str,
+        previous: Option<(Arc<V>, usize, Validators)>,
+        fetch: &F,
+    ) -> Result<Arc<V>, E>
+    where
+        F: Fn(Option<&Validators>) -> Result<Fetched<V>, E>,
+    {
+        let fetched = fetch(previous.as_ref().map(|(_, _, validators)| validators))?;
+        let (value, size, validators) = match fetched {
+            Fetched::Modified {
+                value,
+                size,
+                validators,
+            } => (Arc::new(value), size, validators),
+            Fetched::NotModified => {
+                self.inner.lock().unwrap().stats.not_modified += 1;
+                previous.expect("fetch answered NotModified without validators")
+            }
+        };
+        Ok(self.store(kind, key, value, size, validators, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn cache(max_bytes: usize) -> FetchCache<String> {
+        FetchCache::new(CacheConfig {
+            max_bytes,
+            ..CacheConfig::default()
+        })
+    }
+
+    #[test]
+    fn fresh_then_stale_then_expired() {
+        let cache = cache(1024);
+        let policy = ResourceKind::Object.default_policy();
+        let start = Instant::now();
+        cache.insert(
+            ResourceKind::Object,
+            "https://a.example/1",
+            "note".into(),
+            4,
+            start,
+        );
+
+        let lookup = |at| cache.lookup(ResourceKind::Object, "https://a.example/1", at);
+        assert!(matches!(lookup(start), Lookup::Fresh(_)));
+        assert!(matches!(lookup(start + policy.ttl), Lookup::Stale(_)));
+        assert!(matches!(
+            lookup(start + policy.ttl + policy.stale_for),
+            Lookup::Miss
+        ));
+        // Kinds don't share keys
+        assert!(matches!(
+            cache.lookup(ResourceKind::Actor, "https://a.example/1", start),
+            Lookup::Miss
+        ));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache(10);
+        let now = Instant::now();
+        cache.insert(ResourceKind::Actor, "a", "a".into(), 4, now);
+        cache.insert(ResourceKind::Actor, "b", "b".into(), 4, now);
+        // Using `a` makes `b` the eviction candidate
+        cache.lookup(ResourceKind::Actor, "a", now);
+        cache.insert(ResourceKind::Actor, "c", "c".into(), 4, now);
+
+        assert!(matches!(
+            cache.lookup(ResourceKind::Actor, "a", now),
+            Lookup::Fresh(_)
+        ));
+        assert!(matches!(
+            cache.lookup(ResourceKind::Actor, "b", now),
+            Lookup::Miss
+        ));
+        assert_eq!(cache.bytes(), 8);
+        assert_eq!(cache.stats().evictions, 1);
+        // Too big to ever fit
+        cache.insert(ResourceKind::Actor, "huge", "x".into(), 11, now);
+        assert_eq!(cache.bytes(), 8);
+
+        // Purging drops only what's past its stale window, whatever the kind
+        let policy = ResourceKind::Object.default_policy();
+        cache.insert(ResourceKind::Object, "d", "d".into(), 2, now);
+        let later = now + policy.ttl + policy.stale_for;
+        assert_eq!(cache.purge_expired(later), 1);
+        assert_eq!(cache.bytes(), 8);
+        assert_eq!(cache.stats().purged, 1);
+    }
+
+    #[test]
+    fn stale_values_revalidate_in_background() {
+        let mut config = CacheConfig::default();
+        config.policies.insert(
+            ResourceKind::DidDocument,
+            Policy {
+                ttl: Duration::ZERO,
+                stale_for: Duration::from_secs(60),
+            },
+        );
+        let cache = Arc::new(FetchCache::new(config));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetch = |fetches: Arc<AtomicUsize>| {
+            move || {
+                let n = fetches.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, ()>((format!("v{n}"), 2))
+            }
+        };
+
+        let first = cache.get_or_fetch(
+            ResourceKind::DidDocument,
+            "did:plc:aaaa",
+            fetch(fetches.clone()),
+        );
+        assert_eq!(*first.unwrap(), "v0");
+        let second = cache.get_or_fetch(
+            ResourceKind::DidDocument,
+            "did:plc:aaaa",
+            fetch(fetches.clone()),
+        );
+        assert_eq!(*second.unwrap(), "v0");
+
+        while fetches.load(Ordering::SeqCst) < 2
+            || !cache.inner.lock().unwrap().revalidating.is_empty()
+        {
+            thread::yield_now();
+        }
+        let third = cache.lookup(ResourceKind::DidDocument, "did:plc:aaaa", Instant::now());
+        assert_eq!(third, Lookup::Stale(Arc::new("v1".to_string())));
+    }
+
+    #[test]
+    fn concurrent_misses_share_one_fetch() {
+        let cache = Arc::new(cache(1024));
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+        let get = |cache: &Arc<FetchCache<String>>| {
+            let (cache, fetches, released) = (cache.clone(), fetches.clone(), released.clone());
+            thread::spawn(move || {
+                let fetch = move || {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    released.lock().unwrap().recv().unwrap();
+                    Ok::<_, ()>(("actor".to_string(), 5))
+                };
+                cache.get_or_fetch(ResourceKind::Actor, "https://a.example/users/alice", fetch)
+            })
+        };
+        let waiting: Vec<_> = (0..5).map(|_| get(&cache)).collect();
+        while cache.stats().misses < 5 {
+            thread::yield_now();
+        }
+        release.send(()).unwrap();
+        for waiting in waiting {
+            assert_eq!(*waiting.join().unwrap().unwrap(), "actor");
+        }
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().coalesced, 4);
+    }
+
+    #[test]
+    fn not_modified_renews_the_cached_version() {
+        let mut config = CacheConfig::default();
+        config.policies.insert(
+            ResourceKind::Actor,
+            Policy {
+                ttl: Duration::ZERO,
+                stale_for: Duration::ZERO,
+            },
+        );
+        let cache = Arc::new(FetchCache::new(config));
+        let validators = Validators {
+            etag: None,
+            last_modified: Some("Wed, 01 May 2024 12:00:00 GMT".to_string()),
+        };
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let fetch = |seen: Arc<Mutex<Vec<Option<Validators>>>>, validators: Validators| {
+            move |sent: Option<&Validators>| {
+                seen.lock().unwrap().push(sent.cloned());
+                Ok::<_, ()>(match sent {
+                    Some(_) => Fetched::NotModified,
+                    None => Fetched::Modified {
+                        value: "actor".to_string(),
+                        size: 5,
+                        validators: validators.clone(),
+                    },
+                })
+            }
+        };
+        let key = "https://a.example/users/alice";
+        let first = cache.get_or_revalidate(
+            ResourceKind::Actor,
+            key,
+            fetch(seen.clone(), validators.clone()),
+        );
+        assert_eq!(*first.unwrap(), "actor");
+
+        // Expired straight away, but revalidated rather than fetched again
+        let second = cache.get_or_revalidate(
+            ResourceKind::Actor,
+            key,
+            fetch(seen.clone(), validators.clone()),
+        );
+        assert_eq!(*second.unwrap(), "actor");
+        assert_eq!(*seen.lock().unwrap(), [None, Some(validators)]);
+        assert_eq!(cache.stats().not_modified, 1);
+    }
+}
@@ -0,0 +1,341 @@
+//! Writing and reading CAR archives
+//!
+//! A CAR (content-addressable archive) is how atproto moves repos around: a header naming
+//! root CIDs, then blocks, each prefixed by its length and CID. Blocks here are JSON values
+//! encoded as DAG-CBOR, the deterministic CBOR subset atproto uses, addressed by the
+//! SHA-256 of their encoding.
+//!
+//! Archives are read with [`read_car`], which hands out blocks as slices of the archive for
+//! [`crate::cbor`] to decode in place
+
+use crate::cbor::{self, CborError};
+use crate::crypto::sha256;
+use crate::json::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// CID version 1
+const CID_VERSION: u8 = 0x01;
+/// Multicodec for DAG-CBOR
+const DAG_CBOR: u8 = 0x71;
+/// Multicodec for raw bytes, which blobs are addressed as
+const RAW: u8 = 0x55;
+/// Multihash prefix for a 32-byte SHA-256 digest
+const SHA2_256: [u8; 2] = [0x12, 0x20];
+/// CBOR tag for a CID link
+const CID_TAG: u64 = 42;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// The CID of a DAG-CBOR block
+pub struct Cid([u8; 36]);
+
+impl Cid {
+    /// The CID addressing `block`, which is DAG-CBOR
+    pub fn for_dag_cbor(block: &[u8]) -> Cid {
+        let mut cid = [0; 36];
+        cid[..4].copy_from_slice(&[CID_VERSION, DAG_CBOR, SHA2_256[0], SHA2_256[1]]);
+        cid[4..].copy_from_slice(&sha256(block));
+        Cid(cid)
+    }
+
+    /// The CID addressing `data` as raw bytes, as blobs are
+    pub fn for_raw(data: &[u8]) -> Cid {
+        let mut cid = [0; 36];
+        cid[..4].copy_from_slice(&[CID_VERSION, RAW, SHA2_256[0], SHA2_256[1]]);
+        cid[4..].copy_from_slice(&sha256(data));
+        Cid(cid)
+    }
+
+    /// A binary CIDv1 addressed by SHA-256, which is what atproto uses
+    pub fn from_bytes(bytes: &[u8]) -> Option<Cid> {
+        let cid: [u8; 36] = bytes.try_into().ok()?;
+        // The codec is a varint, so one byte for anything below 0x80 (DAG-CBOR or raw)
+        let valid = cid[0] == CID_VERSION && cid[1] < 0x80 && cid[2..4] == SHA2_256;
+        valid.then_some(Cid(cid))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Cid {
+    /// Multibase base32, as CIDs are written in atproto
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+        let mut encoded = String::from("b");
+        let (mut buffer, mut bits) = (0u32, 0);
+        for &byte in &self.0 {
+            buffer = buffer << 8 | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                encoded.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+        if bits > 0 {
+            encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+        }
+        f.write_str(&encoded)
+    }
+}
+
+impl FromStr for Cid {
+    type Err = CborError;
+
+    /// Read a CID written out in multibase base32
+    fn from_str(s: &str) -> Result<Cid, CborError> {
+        let encoded = s.strip_prefix('b').ok_or(CborError::InvalidLink)?;
+        let mut bytes = Vec::with_capacity(36);
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in encoded.bytes() {
+            let value = match c {
+                b'a'..=b'z' => c - b'a',
+                b'2'..=b'7' => c - b'2' + 26,
+                _ => return Err(CborError::InvalidLink),
+            };
+            buffer = buffer << 5 | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        Cid::from_bytes(&bytes).ok_or(CborError::InvalidLink)
+    }
+}
+
+impl fmt::Debug for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Cid({self})")
+    }
+}
+
+/// A CBOR head: major type and argument, in the shortest form
+pub(crate) fn head(out: &mut Vec<u8>, major: u8, argument: u64) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend([major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(argument.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) fn encode_into(out: &mut Vec<u8>, value: &Value) {
+    if let Some(cid) = link(value) {
+        return encode_link(out, &cid);
+    }
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Int(n) if *n >= 0 => head(out, 0, *n as u64),
+        Value::Int(n) => head(out, 1, !(*n as u64)),
+        // DAG-CBOR has no NaN or infinities
+        Value::Float(f) if !f.is_finite() => out.push(0xf6),
+        Value::Float(f) => {
+            out.push(0xfb);
+            out.extend(f.to_be_bytes());
+        }
+        Value::String(s) => {
+            head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            head(out, 4, items.len() as u64);
+            for item in items {
+                encode_into(out, item);
+            }
+        }
+        Value::Object(entries) => {
+            // Keys are ordered shortest first, then bytewise
+            let mut keys: Vec<&String> = entries.keys().collect();
+            keys.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+            head(out, 5, keys.len() as u64);
+            for key in keys {
+                encode_into(out, &Value::from(key.as_str()));
+                encode_into(out, &entries[key]);
+            }
+        }
+    }
+}
+
+/// The CID in a `{"$link": cid}` object
+fn link(value: &Value) -> Option<Cid> {
+    match value {
+        Value::Object(entries) if entries.len() == 1 => {
+            entries.get("$link")?.as_str()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// Encode a JSON value as DAG-CBOR
+///
+/// Objects of the form `{"$link": cid}` are encoded as links, the inverse of
+/// [`Cbor::to_json`](crate::cbor::Cbor::to_json)
+pub fn encode_dag_cbor(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(&mut out, value);
+    out
+}
+
+/// A link to `cid`: tag 42 on a byte string of the CID after a 0x00 multibase prefix
+pub(crate) fn encode_link(out: &mut Vec<u8>, cid: &Cid) {
+    head(out, 6, CID_TAG);
+    head(out, 2, cid.0.len() as u64 + 1);
+    out.push(0x00);
+    out.extend_from_slice(&cid.0);
+}
+
+/// An unsigned LEB128 varint, as CAR length prefixes are written
+fn varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// Write a CARv1 archive of `blocks`, with `root` as its single root
+pub fn write_car(root: &Cid, blocks: &[Vec<u8>]) -> Vec<u8> {
+    // {"roots": [root], "version": 1}, by hand as the root is a link
+    let mut header = Vec::new();
+    head(&mut header, 5, 2);
+    encode_into(&mut header, &Value::from("roots"));
+    head(&mut header, 4, 1);
+    encode_link(&mut header, root);
+    encode_into(&mut header, &Value::from("version"));
+    encode_into(&mut header, &Value::from(1u32));
+
+    let mut car = Vec::new();
+    varint(&mut car, header.len() as u64);
+    car.extend(header);
+    for block in blocks {
+        let cid = Cid::for_dag_cbor(block);
+        varint(&mut car, (cid.0.len() + block.len()) as u64);
+        car.extend_from_slice(&cid.0);
+        car.extend_from_slice(block);
+    }
+    car
+}
+
+/// Read a varint, returning it and the bytes after it
+fn read_varint(bytes: &[u8]) -> Result<(u64, &[u8]), CborError> {
+    let mut n = 0u64;
+    for (i, &byte) in bytes.iter().enumerate().take(10) {
+        n |= u64::from(byte & 0x7f) << (7 * i);
+        if byte < 0x80 {
+            return Ok((n, &bytes[i + 1..]));
+        }
+    }
+    Err(CborError::Truncated)
+}
+
+/// Split a length-prefixed section off the front of `bytes`
+fn section(bytes: &[u8]) -> Result<(&[u8], &[u8]), CborError> {
+    let (len, rest) = read_varint(bytes)?;
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= rest.len())
+        .ok_or(CborError::Truncated)?;
+    Ok(rest.split_at(len))
+}
+
+#[derive(Debug, Clone)]
+/// The blocks of a CAR archive, in order, borrowing from it
+pub struct Blocks<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Blocks<'a> {
+    type Item = Result<(Cid, &'a [u8]), CborError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let block = section(self.rest).and_then(|(block, rest)| {
+            self.rest = rest;
+            let cid = block.get(..36).and_then(Cid::from_bytes);
+            Ok((cid.ok_or(CborError::InvalidLink)?, &block[36..]))
+        });
+        if block.is_err() {
+            self.rest = &[];
+        }
+        Some(block)
+    }
+}
+
+impl<'a> Blocks<'a> {
+    /// The block with this CID, stopping at anything malformed
+    pub fn find(self, cid: &Cid) -> Option<&'a [u8]> {
+        self.map_while(Result::ok)
+            .find(|(c, _)| c == cid)
+            .map(|(_, block)| block)
+    }
+}
+
+/// Read a CARv1 archive's header, returning its blocks
+pub fn read_car(car: &[u8]) -> Result<Blocks<'_>, CborError> {
+    let (header, rest) = section(car)?;
+    cbor::decode(header)?;
+    Ok(Blocks { rest })
+}
+
+/// The root of a CARv1 archive, such as the commit a repo export is of
+pub fn car_root(car: &[u8]) -> Result<Option<Cid>, CborError> {
+    let (header, _) = section(car)?;
+    let (header, _) = cbor::decode(header)?;
+    let roots = header.get("roots").and_then(|roots| roots.as_array());
+    Ok(roots.and_then(|roots| roots.iter().next()?.as_link()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn encodes_dag_cbor() {
+        let value = json::parse(r#"{"zz": [1, -1, 500, true, null], "a": "hi", "bb": 1.5}"#);
+        assert_eq!(
+            encode_dag_cbor(&value.unwrap()),
+            [
+                &[0xa3, 0x61, b'a', 0x62, b'h', b'i', 0x62, b'b', b'b'][..],
+                &[0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+                &[0x62, b'z', b'z', 0x85, 0x01, 0x20, 0x19, 0x01, 0xf4, 0xf5, 0xf6],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn writes_car_with_root() {
+        let block = encode_dag_cbor(&Value::from("hello"));
+        let root = Cid::for_dag_cbor(&block);
+        assert!(root.to_string().starts_with("bafyrei"));
+        let car = write_car(&root, std::slice::from_ref(&block));
+        let header_len = car[0] as usize;
+        let header = &car[1..1 + header_len];
+        // The root CID appears in the header, after the 0x00 multibase prefix
+        assert!(header
+            .windows(37)
+            .any(|w| w[0] == 0 && &w[1..] == root.as_bytes()));
+        let rest = &car[1 + header_len..];
+        assert_eq!(rest[0] as usize, 36 + block.len());
+        assert_eq!(&rest[1..37], root.as_bytes());
+        assert_eq!(&rest[37..], block);
+    }
+}
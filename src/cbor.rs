@@ -0,0 +1,342 @@
+//! Reading DAG-CBOR without copying
+//!
+//! Firehose frames and the blocks in them are DAG-CBOR. Decoding here borrows from the
+//! buffer the frame arrived in: strings and byte strings are slices of it, links are the
+//! CID's bytes, and arrays and maps are skipped over in place and only decoded as they're
+//! iterated. Nothing is allocated until a value is converted [to JSON](Cbor::to_json), which
+//! is only worth doing for the records the bridge will actually translate.
+//!
+//! Encoding lives in [`crate::car`]
+
+use crate::car::Cid;
+use crate::crypto::base64_encode;
+use crate::json::Value;
+use thiserror::Error;
+
+/// How deeply arrays and maps may nest, so hostile input can't exhaust the stack
+const MAX_DEPTH: usize = 64;
+/// CBOR tag for a CID link
+const CID_TAG: u64 = 42;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors decoding DAG-CBOR
+pub enum CborError {
+    #[error("CBOR ended unexpectedly")]
+    Truncated,
+    #[error("Unsupported CBOR item {byte:#04x}, which DAG-CBOR doesn't allow")]
+    Unsupported { byte: u8 },
+    #[error("CBOR nested more than {MAX_DEPTH} deep")]
+    TooDeep,
+    #[error("CBOR text isn't UTF-8")]
+    InvalidUtf8,
+    #[error("Invalid CID link")]
+    InvalidLink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A decoded DAG-CBOR value, borrowing from its buffer
+pub enum Cbor<'a> {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Bytes(&'a [u8]),
+    Text(&'a str),
+    /// A CID link, as its binary CID
+    Link(&'a [u8]),
+    Array(Items<'a>),
+    Map(Entries<'a>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The items of an array, decoded as they're iterated
+pub struct Items<'a> {
+    len: usize,
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The entries of a map, decoded as they're iterated
+pub struct Entries<'a> {
+    len: usize,
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CborError> {
+        if self.bytes.len() < n {
+            return Err(CborError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    /// A head's major type, and its argument or the byte it would be for errors
+    fn head(&mut self) -> Result<(u8, u64, u8), CborError> {
+        let byte = self.take(1)?[0];
+        let argument = match byte & 0x1f {
+            n @ 0..=23 => n as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            // Indefinite lengths aren't allowed in DAG-CBOR
+            _ => return Err(CborError::Unsupported { byte }),
+        };
+        Ok((byte >> 5, argument, byte))
+    }
+
+    fn len(&mut self, argument: u64) -> Result<usize, CborError> {
+        usize::try_from(argument)
+            .ok()
+            .filter(|&len| len <= self.bytes.len())
+            .ok_or(CborError::Truncated)
+    }
+
+    /// Skip `count` items, returning the bytes they took up
+    fn skip(&mut self, count: usize, depth: usize) -> Result<&'a [u8], CborError> {
+        let start = self.bytes;
+        for _ in 0..count {
+            self.value(depth)?;
+        }
+        Ok(&start[..start.len() - self.bytes.len()])
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Cbor<'a>, CborError> {
+        if depth > MAX_DEPTH {
+            return Err(CborError::TooDeep);
+        }
+        let (major, argument, byte) = self.head()?;
+        Ok(match major {
+            0 => Cbor::Int(i64::try_from(argument).map_err(|_| CborError::Unsupported { byte })?),
+            1 => Cbor::Int(
+                i64::try_from(argument)
+                    .map(|n| -1 - n)
+                    .map_err(|_| CborError::Unsupported { byte })?,
+            ),
+            2 => {
+                let len = self.len(argument)?;
+                Cbor::Bytes(self.take(len)?)
+            }
+            3 => {
+                let len = self.len(argument)?;
+                let text = std::str::from_utf8(self.take(len)?);
+                Cbor::Text(text.map_err(|_| CborError::InvalidUtf8)?)
+            }
+            4 => {
+                let len = self.len(argument)?;
+                Cbor::Array(Items {
+                    len,
+                    bytes: self.skip(len, depth + 1)?,
+                    depth: depth + 1,
+                })
+            }
+            5 => {
+                let len = self.len(argument)?;
+                Cbor::Map(Entries {
+                    len,
+                    bytes: self.skip(len * 2, depth + 1)?,
+                    depth: depth + 1,
+                })
+            }
+            // The only tag DAG-CBOR has is a link: a byte string of 0x00 then the CID
+            6 if argument == CID_TAG => match self.value(depth)? {
+                Cbor::Bytes([0x00, cid @ ..]) => Cbor::Link(cid),
+                _ => return Err(CborError::InvalidLink),
+            },
+            7 => match byte {
+                0xf4 => Cbor::Bool(false),
+                0xf5 => Cbor::Bool(true),
+                0xf6 => Cbor::Null,
+                0xfb => Cbor::Float(f64::from_bits(argument)),
+                _ => return Err(CborError::Unsupported { byte }),
+            },
+            _ => return Err(CborError::Unsupported { byte }),
+        })
+    }
+}
+
+/// Decode the first value in `bytes`, returning it and whatever follows
+pub fn decode(bytes: &[u8]) -> Result<(Cbor<'_>, &[u8]), CborError> {
+    let mut reader = Reader { bytes };
+    let value = reader.value(0)?;
+    Ok((value, reader.bytes))
+}
+
+impl<'a> Items<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cbor<'a>> + 'a {
+        let mut reader = Reader { bytes: self.bytes };
+        let depth = self.depth;
+        // Everything was checked to decode when the array was read
+        (0..self.len).map_while(move |_| reader.value(depth).ok())
+    }
+}
+
+impl<'a> Entries<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Each entry with a string key. Entries with other keys are left out
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, Cbor<'a>)> + 'a {
+        let mut reader = Reader { bytes: self.bytes };
+        let depth = self.depth;
+        (0..self.len)
+            .map_while(move |_| {
+                let key = reader.value(depth).ok()?;
+                Some((key, reader.value(depth).ok()?))
+            })
+            .filter_map(|(key, value)| match key {
+                Cbor::Text(key) => Some((key, value)),
+                _ => None,
+            })
+    }
+
+    pub fn get(&self, key: &str) -> Option<Cbor<'a>> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, value)| value)
+    }
+}
+
+impl<'a> Cbor<'a> {
+    /// Look up a key, if this is a map
+    pub fn get(&self, key: &str) -> Option<Cbor<'a>> {
+        match self {
+            Cbor::Map(entries) => entries.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Cbor::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Cbor::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Cbor::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Cbor::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_link(&self) -> Option<Cid> {
+        match self {
+            Cbor::Link(cid) => Cid::from_bytes(cid),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<Items<'a>> {
+        match self {
+            Cbor::Array(items) => Some(*items),
+            _ => None,
+        }
+    }
+
+    /// Convert to JSON the way atproto does: links become `{"$link": cid}` and byte strings
+    /// `{"$bytes": base64}`
+    pub fn to_json(&self) -> Value {
+        match self {
+            Cbor::Null => Value::Null,
+            Cbor::Bool(b) => Value::Bool(*b),
+            Cbor::Int(n) => Value::Int(*n),
+            Cbor::Float(f) => Value::Float(*f),
+            Cbor::Text(text) => Value::from(*text),
+            Cbor::Bytes(bytes) => {
+                // atproto leaves off the padding
+                let bytes = base64_encode(bytes).trim_end_matches('=').to_string();
+                Value::object([("$bytes", Value::from(bytes))])
+            }
+            Cbor::Link(cid) => {
+                let cid = Cid::from_bytes(cid).map(|cid| cid.to_string());
+                Value::object([("$link", Value::from(cid))])
+            }
+            Cbor::Array(items) => Value::Array(items.iter().map(|item| item.to_json()).collect()),
+            Cbor::Map(entries) => Value::Object(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_json()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::encode_dag_cbor;
+    use crate::json;
+
+    #[test]
+    fn decodes_what_is_encoded() {
+        let cid = Cid::for_dag_cbor(b"block");
+        let value = json::parse(&format!(
+            r#"{{"text": "hi ✨", "langs": ["en"], "n": -300, "big": 4294967296, "ok": true,
+                "none": null, "f": 1.5, "nested": {{"a": [1, [2, {{}}]]}}, "l": {{"$link": "{cid}"}}}}"#,
+        ))
+        .unwrap();
+        let encoded = encode_dag_cbor(&value);
+        let (decoded, rest) = decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.get("text").and_then(|t| t.as_str()), Some("hi ✨"));
+        assert_eq!(decoded.get("n").and_then(|n| n.as_i64()), Some(-300));
+        assert_eq!(decoded.get("l").and_then(|l| l.as_link()), Some(cid));
+        assert_eq!(decoded.to_json(), value);
+    }
+
+    #[test]
+    fn links_bytes_and_bad_input() {
+        let cid = Cid::for_dag_cbor(b"block");
+        // {"l": link, "b": h'0102ff'}
+        let mut encoded = vec![0xa2, 0x61, b'l', 0xd8, 0x2a, 0x58, 37, 0x00];
+        encoded.extend_from_slice(cid.as_bytes());
+        encoded.extend_from_slice(&[0x61, b'b', 0x43, 0x01, 0x02, 0xff]);
+        let (decoded, _) = decode(&encoded).unwrap();
+        assert_eq!(decoded.get("l").and_then(|l| l.as_link()), Some(cid));
+        assert_eq!(
+            decoded.get("b").unwrap().to_json(),
+            Value::object([("$bytes", Value::from("AQL/"))])
+        );
+
+        assert_eq!(decode(&encoded[..10]), Err(CborError::Truncated));
+        // An indefinite-length array
+        assert_eq!(decode(&[0x9f]), Err(CborError::Unsupported { byte: 0x9f }));
+        let deep = [vec![0x81; MAX_DEPTH + 1], vec![0xf6]].concat();
+        assert_eq!(decode(&deep), Err(CborError::TooDeep));
+    }
+}
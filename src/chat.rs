@@ -0,0 +1,265 @@
+//! Bridging Bluesky chat and fediverse direct messages
+//!
+//! Only built with the `chat` feature. A conversation is bridged when both people in it are
+//! bridged and have [opted in](crate::store::Preferences::bridge_dms); everyone else's
+//! messages are [bounced](dm) as usual:
+//!
+//! - a fediverse DM to a bridged Bluesky account is sent as the sender's bridged account, in
+//!   its chat with the recipient
+//! - a chat message in a bridged fediverse account's convo log ([`poll`]) is delivered as a
+//!   direct `Note` from the sender's bridged actor
+//!
+//! Convo logs are read with [`ChatClient`] and messages are sent through the job queue, by
+//! [`dm::send_chat`]. Only text and links are bridged, not
+//! attachments or embeds
+
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::dm::{self, ChatClient, ChatReply, DmError, LOG_CREATE_MESSAGE};
+use crate::http::percent_encode;
+use crate::jobs::Job;
+use crate::json::Value;
+use crate::markup;
+use crate::mentions::BLUESKY_PROFILE;
+use crate::normalize;
+use crate::richtext::{self, Facet, RichText};
+use crate::store::{Mapping, MappingStatus};
+use crate::time::unique_millis;
+use atproto::DID::Did;
+use std::io;
+
+/// Whether an account has agreed to have its DMs bridged
+fn consents(mapping: &Mapping) -> bool {
+    mapping.status == MappingStatus::Active && mapping.preferences.bridge_dms
+}
+
+/// Send a fediverse DM from `sender` on to Bluesky, if both sides have opted in
+///
+/// Returns whether it was bridged. Starting the conversation happens straight away, so a
+/// failure there is returned for the sending server to retry
+pub fn direct_message(
+    bridge: &Bridge,
+    sender: &str,
+    recipient: &Mapping,
+    object: &Value,
+) -> io::Result<bool> {
+    let from = bridge.identities.get_by_actor(sender);
+    let Some(from) = from.filter(|from| consents(from) && consents(recipient)) else {
+        return Ok(false);
+    };
+    let content = object.get("content").and_then(Value::as_str);
+    let rich = richtext::from_html(content.unwrap_or_default());
+    let client = ChatClient::new(bridge.transport.as_ref(), &bridge.dms);
+    let convo_id = client
+        .get_convo_for_members(&from.did, &[&recipient.did])
+        .map_err(io::Error::other)?;
+    bridge.jobs.push(Job::SendChatMessage(ChatReply {
+        did: from.did,
+        convo_id,
+        text: rich.text,
+        facets: rich.facets,
+        cause: Some(Cause::new(
+            "direct message",
+            object.get("id").and_then(Value::as_str),
+        )),
+    }))?;
+    Ok(true)
+}
+
+/// Deliver a Bluesky chat message to the fediverse, from an entry of the bridged account
+/// `recipient`'s convo log, if both sides have opted in
+///
+/// Returns whether it was bridged
+pub fn chat_message(bridge: &Bridge, recipient: &Did, entry: &Value) -> io::Result<bool> {
+    if entry.get("$type").and_then(Value::as_str) != Some(LOG_CREATE_MESSAGE) {
+        return Ok(false);
+    }
+    let Some(message) = entry.get("message") else {
+        return Ok(false);
+    };
+    let sender = message
+        .get("sender")
+        .and_then(|sender| sender.get("did"))
+        .and_then(Value::as_str)
+        .and_then(|did| normalize::parse_did(did).ok());
+    let to = bridge.identities.get(recipient).filter(consents);
+    let from = sender.and_then(|did| bridge.identities.get(&did));
+    let (Some(to), Some(from)) = (to, from.filter(consents)) else {
+        return Ok(false);
+    };
+    let Some(inbox) = delivery::shared_inbox(&bridge.documents, &to.actor) else {
+        return Ok(false);
+    };
+    let text = message.get("text").and_then(Value::as_str);
+    let facets: Vec<_> = message
+        .get("facets")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(Facet::from_json)
+        .collect();
+    let rich = RichText {
+        text: text.unwrap_or_default().to_string(),
+        facets,
+    };
+    let content = markup::to_html(&rich, bridge.html_profile, |did| {
+        let actor = bridge.identities.get(did).map(|mapping| mapping.actor);
+        actor.unwrap_or_else(|| format!("{BLUESKY_PROFILE}/{did}"))
+    });
+    let id = match message.get("id").and_then(Value::as_str) {
+        Some(id) => format!("{}/chat/{}", from.actor, percent_encode(id)),
+        None => format!("{}/chat/{}", from.actor, unique_millis()),
+    };
+    let activity = dm::direct_note(&id, &from.actor, &to.actor, &content, None);
+    let cause = Cause::new("chat message", message.get("id").and_then(Value::as_str));
+    bridge.jobs.push(Job::Deliver(
+        Delivery::new(inbox, activity.to_string()).because(cause),
+    ))?;
+    Ok(true)
+}
+
+/// Read `account`'s convo log after `cursor`, bridging (or bouncing) new messages
+///
+/// Returns the cursor to carry on from next time
+pub fn poll(
+    bridge: &Bridge,
+    account: &Did,
+    cursor: Option<&str>,
+) -> Result<Option<String>, DmError> {
+    let client = ChatClient::new(bridge.transport.as_ref(), &bridge.dms);
+    let mut cursor = cursor.map(str::to_string);
+    loop {
+        let page = client.get_log(account, cursor.as_deref())?;
+        if page.logs.is_empty() {
+            return Ok(page.cursor.or(cursor));
+        }
+        for entry in &page.logs {
+            dm::chat_message_received(bridge, account, entry)?;
+        }
+        match page.cursor {
+            Some(next) if Some(&next) != cursor.as_ref() => cursor = Some(next),
+            next => return Ok(next),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dm::{DmPolicy, GET_CONVO_FOR_MEMBERS, GET_LOG};
+    use crate::json;
+    use crate::richtext::Feature;
+    use crate::store::Preferences;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:plc:bob");
+    /// alice is a Bluesky account, and bob a fediverse one
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+    const BOB_ACTOR: &str = "https://b.example/users/bob";
+    const CHAT: &str = "https://chat.example/xrpc";
+
+    fn bridge(mock: &Arc<MockTransport>, consenting: bool) -> Bridge {
+        let bridge = Bridge::new()
+            .with_transport(mock.clone())
+            .with_dm_policy(DmPolicy {
+                chat_service: Some("https://chat.example".to_string()),
+                chat_token: Some("secret".to_string()),
+                ..DmPolicy::default()
+            });
+        for (did, actor, bridge_dms) in [(ALICE, ALICE_ACTOR, true), (BOB, BOB_ACTOR, consenting)] {
+            bridge.identities.insert(Mapping {
+                preferences: Preferences {
+                    bridge_dms,
+                    ..Preferences::default()
+                },
+                ..Mapping::new(did, actor)
+            });
+        }
+        bridge
+    }
+
+    fn direct(content: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Create", "actor": "{BOB_ACTOR}",
+                "object": {{"id": "https://b.example/notes/1", "type": "Note",
+                            "to": ["{ALICE_ACTOR}"], "content": "{content}"}}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn fediverse_dms_become_chat_messages() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            &format!("{CHAT}/{GET_CONVO_FOR_MEMBERS}?members=did%3Aplc%3Aalice"),
+            r#"{"convo": {"id": "convo1"}}"#,
+        );
+        let bridge = bridge(&mock, true);
+        let message = direct("<p>see <a href='https://x.example/'>this</a></p>");
+        assert_eq!(dm::direct_message_received(&bridge, &message).unwrap(), 1);
+        let request = &mock.requests()[0];
+        assert_eq!(request.header(dm::ACCOUNT_HEADER), Some(BOB.as_str()));
+        assert_eq!(request.header("authorization"), Some("Bearer secret"));
+        let jobs = bridge.jobs.queued();
+        let Job::SendChatMessage(reply) = &jobs[0].job else {
+            panic!("expected a chat message");
+        };
+        assert_eq!((&reply.did, reply.convo_id.as_str()), (&BOB, "convo1"));
+        assert_eq!(reply.text, "see this");
+        assert_eq!(
+            reply.facets[0].feature,
+            Feature::Link {
+                uri: "https://x.example/".to_string()
+            }
+        );
+
+        // Without bob's consent, alice bounces it instead
+        let bridge = self::bridge(&mock, false);
+        assert_eq!(dm::direct_message_received(&bridge, &message).unwrap(), 1);
+        assert!(matches!(bridge.jobs.queued()[0].job, Job::Deliver(_)));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn chat_messages_become_direct_notes() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            &format!("{CHAT}/{GET_LOG}?cursor=c1"),
+            r#"{"cursor": "c2", "logs": [{"$type": "chat.bsky.convo.defs#logCreateMessage",
+                "convoId": "convo1", "message": {"id": "m1", "text": "hi @bob <3\n\nbye",
+                "sender": {"did": "did:plc:alice"}, "facets": [{"index": {"byteStart": 3,
+                "byteEnd": 7}, "features": [{"$type": "app.bsky.richtext.facet#mention",
+                "did": "did:plc:bob"}]}]}}]}"#,
+        );
+        mock.respond_json(&format!("{CHAT}/{GET_LOG}?cursor=c2"), r#"{"logs": []}"#);
+        let bridge = bridge(&mock, true);
+        assert_eq!(
+            poll(&bridge, &BOB, Some("c1")).unwrap().as_deref(),
+            Some("c2")
+        );
+        let jobs = bridge.jobs.queued();
+        let Job::Deliver(delivery) = &jobs[0].job else {
+            panic!("expected a delivery");
+        };
+        assert_eq!(delivery.inbox, "https://b.example/inbox");
+        let activity = json::parse(&delivery.activity).unwrap();
+        assert_eq!(activity.get("actor"), Some(&Value::from(ALICE_ACTOR)));
+        let note = activity.get("object").unwrap();
+        assert!(dm::is_direct(note));
+        assert_eq!(
+            note.get("id"),
+            Some(&Value::from("https://bridge.example/users/alice/chat/m1"))
+        );
+        assert_eq!(
+            note.get("content"),
+            Some(&Value::from(
+                "<p>hi <a href=\"https://b.example/users/bob\" class=\"u-url mention\">@bob</a> \
+                 &lt;3</p><p>bye</p>"
+            ))
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Bridging fediverse groups, such as Lemmy communities
+//!
+//! A `Group` doesn't write posts of its own. It shares its members' posts by announcing
+//! them, wrapping the member's whole activity (`Announce { Create { Page } }`), or only the
+//! post's ID. So [`unwrap`] takes out the post and attributes it to its author, and it's
+//! bridged from them like any other post. Posts embedded from another host than the group's
+//! are refetched from their origin, as the group could otherwise put words in anyone's mouth.
+//!
+//! What the community itself becomes on Bluesky is up to the [`CommunityStrategy`]: nothing,
+//! a feed of its posts (served for feeds with the `community:<actor>` source), or a bridged
+//! account of its own which reposts them. Feed posts are indexed at the root of the state
+//! directory, as every shard shares them
+
+use crate::actortype::ActorType;
+use crate::bridge::Bridge;
+use crate::cache::{Fetched, ResourceKind};
+use crate::car::{self, Cid};
+use crate::delivery::ACTIVITY_JSON;
+use crate::json::{self, Value};
+use crate::repo::RepoError;
+use crate::rkeys;
+use crate::storage::StateDir;
+use crate::store::MappingStatus;
+use crate::time::format_rfc3339;
+use crate::transport::OutboundRequest;
+use crate::url::Url;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use thiserror::Error;
+
+pub const COMMUNITIES_FILE: &str = "communities.json";
+pub const REPOST_COLLECTION: &str = "app.bsky.feed.repost";
+
+/// Each community's feed keeps this many of its newest posts
+const MAX_INDEXED: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How fediverse communities are represented on Bluesky
+pub enum CommunityStrategy {
+    /// Only by their posts, bridged from their authors
+    #[default]
+    Posts,
+    /// By a feed of their posts
+    Feed,
+    /// By a bridged account of their own, reposting their posts. Communities without one are
+    /// only represented by their posts
+    Account,
+}
+
+impl CommunityStrategy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommunityStrategy::Posts => "posts",
+            CommunityStrategy::Feed => "feed",
+            CommunityStrategy::Account => "account",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<CommunityStrategy> {
+        [
+            CommunityStrategy::Posts,
+            CommunityStrategy::Feed,
+            CommunityStrategy::Account,
+        ]
+        .into_iter()
+        .find(|strategy| strategy.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A member's activity a community shared
+pub struct CommunityPost {
+    /// The group's actor
+    pub community: String,
+    /// The member's actor
+    pub author: String,
+    /// What the member did, to be bridged as if they'd sent it themselves
+    pub activity: Value,
+}
+
+#[derive(Debug, Error)]
+/// Errors unwrapping or bridging a community's post
+pub enum CommunityError {
+    #[error("Announced object has no author")]
+    Unattributed,
+    #[error("Activity by {actor} carries an object attributed to {author}")]
+    Misattributed { actor: String, author: String },
+    #[error("Couldn't fetch {url}: {reason}")]
+    Fetch { url: String, reason: String },
+    #[error(transparent)]
+    Repo(#[from] RepoError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The activities a community may share, rather than posts on their own
+const ACTIVITIES: &[&str] = &["Create", "Update", "Delete", "Like", "Dislike", "Undo"];
+
+/// The first actor an object is `attributedTo`, which may be a list of IDs or actors
+fn attributed_to(object: &Value) -> Option<String> {
+    let attribution = object.get("attributedTo")?;
+    let first = match attribution {
+        Value::Array(actors) => actors.first()?,
+        actor => actor,
+    };
+    let id = first.as_str().or_else(|| first.get("id")?.as_str())?;
+    Some(id.to_string())
+}
+
+fn host(url: &str) -> Option<String> {
+    Url::parse(url).ok().map(|url| url.host)
+}
+
+/// Fetch an object from its origin, through the document cache
+fn fetch(bridge: &Bridge, url: &str) -> Result<Arc<Value>, CommunityError> {
+    let transport = bridge.transport.clone();
+    let target = url.to_string();
+    let failed = |reason| CommunityError::Fetch {
+        url: url.to_string(),
+        reason,
+    };
+    bridge
+        .documents
+        .get_or_revalidate(ResourceKind::Object, url, move |validators| {
+            let request = OutboundRequest::get(&target)
+                .with_header("accept", ACTIVITY_JSON)
+                .if_modified(validators);
+            let response = transport.send(&request).map_err(|e| e.to_string())?;
+            if response.status == 304 && validators.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            if !response.is_success() {
+                return Err(format!("status {}", response.status));
+            }
+            let body = String::from_utf8_lossy(&response.body);
+            let document = json::parse(&body).map_err(|e| e.to_string())?;
+            Ok(Fetched::Modified {
+                value: document,
+                size: response.body.len(),
+                validators: response.validators(),
+            })
+        })
+        .map_err(failed)
+}
+
+/// The member's activity in `announce`, if it's a group's announce of one
+///
+/// `announcer` is the actor document of whoever sent the announce. Announces by anyone else
+/// are reposts, and left alone
+pub fn unwrap(
+    bridge: &Bridge,
+    announce: &Value,
+    announcer: &Value,
+) -> Result<Option<CommunityPost>, CommunityError> {
+    if announce.get("type").and_then(Value::as_str) != Some("Announce")
+        || ActorType::of(announcer) != ActorType::Group
+    {
+        return Ok(None);
+    }
+    let Some(community) = announcer.get("id").and_then(Value::as_str) else {
+        return Ok(None);
+    };
+    let community_host = host(community);
+    let mut object = match announce.get("object") {
+        Some(Value::String(url)) => (*fetch(bridge, url)?).clone(),
+        Some(object) => object.clone(),
+        None => return Ok(None),
+    };
+    let from_elsewhere = |object: &Value| {
+        let id = object.get("id").and_then(Value::as_str);
+        id.filter(|id| host(id) != community_host)
+            .map(str::to_string)
+    };
+    if let Some(id) = from_elsewhere(&object) {
+        object = (*fetch(bridge, &id)?).clone();
+    }
+    let kind = object.get("type").and_then(Value::as_str);
+    let activity = if kind.is_some_and(|kind| ACTIVITIES.contains(&kind)) {
+        // As a member's own activity, its object has to be theirs too
+        if let Some(inner) = object.get("object").filter(|o| o.get("type").is_some()) {
+            let actor = object.get("actor").and_then(Value::as_str);
+            match (actor, attributed_to(inner)) {
+                (Some(actor), Some(author)) if actor != author => {
+                    return Err(CommunityError::Misattributed {
+                        actor: actor.to_string(),
+                        author,
+                    })
+                }
+                _ => {}
+            }
+        }
+        object
+    } else {
+        let author = attributed_to(&object).ok_or(CommunityError::Unattributed)?;
+        Value::object([
+            ("type", Value::from("Create")),
+            ("actor", Value::from(author)),
+            ("object", object),
+        ])
+    };
+    let author = activity.get("actor").and_then(Value::as_str);
+    let author = author.ok_or(CommunityError::Unattributed)?.to_string();
+    Ok(Some(CommunityPost {
+        community: community.to_string(),
+        author,
+        activity,
+    }))
+}
+
+#[derive(Debug, Default)]
+/// The bridged posts of each community, for their feeds
+pub struct CommunityIndex {
+    /// Each community's `(createdAt, post URI)`s, oldest first
+    posts: RwLock<HashMap<String, Vec<(String, String)>>>,
+    dir: Option<StateDir>,
+}
+
+impl CommunityIndex {
+    /// An index persisted in `dir`, with the posts already there
+    pub fn open(dir: StateDir) -> io::Result<CommunityIndex> {
+        let mut posts: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        if let Some(contents) = dir.read(COMMUNITIES_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let communities = saved.get("communities").and_then(Value::as_array);
+            for community in communities.unwrap_or_default() {
+                let Some(actor) = community.get("actor").and_then(Value::as_str) else {
+                    continue;
+                };
+                let entries = community.get("posts").and_then(Value::as_array);
+                let entries = entries.unwrap_or_default().iter().filter_map(|post| {
+                    let field = |name| Some(post.get(name)?.as_str()?.to_string());
+                    Some((field("createdAt")?, field("uri")?))
+                });
+                posts.insert(actor.to_string(), entries.collect());
+            }
+        }
+        Ok(CommunityIndex {
+            posts: RwLock::new(posts),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, posts: &HashMap<String, Vec<(String, String)>>) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let communities = posts.iter().map(|(actor, entries)| {
+            let entries = entries.iter().map(|(created_at, uri)| {
+                Value::object([
+                    ("createdAt", Value::from(created_at.as_str())),
+                    ("uri", Value::from(uri.as_str())),
+                ])
+            });
+            Value::object([
+                ("actor", Value::from(actor.as_str())),
+                ("posts", Value::Array(entries.collect())),
+            ])
+        });
+        let saved = Value::object([("communities", Value::Array(communities.collect()))]);
+        dir.write(COMMUNITIES_FILE, saved.to_string().as_bytes())
+    }
+
+    pub fn add(&self, community: &str, created_at: &str, uri: &str) -> io::Result<()> {
+        let mut posts = self.posts.write().unwrap();
+        let entries = posts.entry(community.to_string()).or_default();
+        if !entries.iter().any(|(_, existing)| existing == uri) {
+            entries.push((created_at.to_string(), uri.to_string()));
+            if entries.len() > MAX_INDEXED {
+                entries.remove(0);
+            }
+        }
+        self.save(&posts)
+    }
+
+    /// `community`'s `(createdAt, post URI)`s, oldest first
+    pub fn posts(&self, community: &str) -> Vec<(String, String)> {
+        let posts = self.posts.read().unwrap();
+        posts.get(community).cloned().unwrap_or_default()
+    }
+}
+
+/// Represent `post`'s community now that it's been bridged as `record`, at `uri`
+pub fn bridged(
+    bridge: &Bridge,
+    post: &CommunityPost,
+    uri: &str,
+    record: &Value,
+) -> Result<(), CommunityError> {
+    match bridge.community_strategy {
+        CommunityStrategy::Posts => {}
+        CommunityStrategy::Feed => {
+            let created_at = record.get("createdAt").and_then(Value::as_str);
+            let now = format_rfc3339(SystemTime::now());
+            bridge
+                .communities
+                .add(&post.community, created_at.unwrap_or(&now), uri)?;
+        }
+        CommunityStrategy::Account => {
+            let account = bridge.identities.get_by_actor(&post.community);
+            let Some(account) = account.filter(|m| m.status == MappingStatus::Active) else {
+                return Ok(());
+            };
+            let cid = Cid::for_dag_cbor(&car::encode_dag_cbor(record));
+            let repost = Value::object([
+                (
+                    "subject",
+                    Value::object([
+                        ("uri", Value::from(uri)),
+                        ("cid", Value::from(cid.to_string())),
+                    ]),
+                ),
+                ("createdAt", Value::from(format_rfc3339(SystemTime::now()))),
+            ]);
+            let same = |repost: &Value| {
+                let subject = repost.get("subject").and_then(|s| s.get("uri"));
+                subject.and_then(Value::as_str) == Some(uri)
+            };
+            rkeys::create(bridge, &account.did, REPOST_COLLECTION, uri, repost, same)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    const COMMUNITY: &str = "https://lemmy.example/c/rust";
+    const AUTHOR: &str = "https://lemmy.example/u/alice";
+
+    fn group() -> Value {
+        json::parse(&format!(r#"{{"id": "{COMMUNITY}", "type": "Group"}}"#)).unwrap()
+    }
+
+    #[test]
+    fn unwraps_announced_posts_to_their_authors() {
+        let bridge = Bridge::new();
+        let announce = json::parse(&format!(
+            r#"{{"type": "Announce", "actor": "{COMMUNITY}", "object": {{
+                "type": "Create", "actor": "{AUTHOR}", "object": {{
+                    "id": "https://lemmy.example/post/1", "type": "Page",
+                    "attributedTo": "{AUTHOR}", "name": "Hello"}}}}}}"#
+        ))
+        .unwrap();
+        let post = unwrap(&bridge, &announce, &group()).unwrap().unwrap();
+        assert_eq!(
+            (post.community.as_str(), post.author.as_str()),
+            (COMMUNITY, AUTHOR)
+        );
+        assert_eq!(post.activity.get("type"), Some(&Value::from("Create")));
+
+        let person = json::parse(r#"{"id": "https://a.example/u/bob", "type": "Person"}"#);
+        assert!(unwrap(&bridge, &announce, &person.unwrap())
+            .unwrap()
+            .is_none());
+
+        let forged = json::parse(&format!(
+            r#"{{"type": "Announce", "object": {{"id": "https://lemmy.example/a/1",
+                "type": "Create", "actor": "{AUTHOR}", "object": {{"type": "Note",
+                    "attributedTo": "https://lemmy.example/u/mallory"}}}}}}"#
+        ))
+        .unwrap();
+        let refused = unwrap(&bridge, &forged, &group());
+        assert!(matches!(refused, Err(CommunityError::Misattributed { .. })));
+    }
+
+    #[test]
+    fn posts_from_elsewhere_are_refetched() {
+        let mock = Arc::new(MockTransport::new());
+        let note = "https://other.example/notes/1";
+        mock.respond_json(
+            note,
+            r#"{"id": "https://other.example/notes/1", "type": "Note",
+                "attributedTo": [{"type": "Person", "id": "https://other.example/u/carol"}]}"#,
+        );
+        let bridge = Bridge::new().with_transport(mock.clone());
+        let announce = json::parse(&format!(
+            r#"{{"type": "Announce", "object": {{"id": "{note}", "type": "Note",
+                "attributedTo": "https://other.example/u/mallory"}}}}"#
+        ))
+        .unwrap();
+        let post = unwrap(&bridge, &announce, &group()).unwrap().unwrap();
+        assert_eq!(post.author, "https://other.example/u/carol");
+        assert_eq!(mock.requests_to(note).len(), 1);
+
+        let dir = crate::storage::tests::temp_state_dir();
+        let index = CommunityIndex::open(dir.clone()).unwrap();
+        index
+            .add(COMMUNITY, "2024-06-01T00:00:00Z", "at://did:plc:carol/p/1")
+            .unwrap();
+        index
+            .add(COMMUNITY, "2024-06-01T00:00:00Z", "at://did:plc:carol/p/1")
+            .unwrap();
+        let reopened = CommunityIndex::open(dir).unwrap();
+        assert_eq!(reopened.posts(COMMUNITY).len(), 1);
+    }
+
+    #[test]
+    fn communities_are_feeds_or_accounts() {
+        use crate::feed::{self, FeedSource};
+        use crate::keys::{tests::FakeGenerator, KeyOwner, KeyPurpose};
+        use crate::repo::tests::HashSigner;
+        use crate::store::Mapping;
+        use atproto::did;
+
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_community_strategy(CommunityStrategy::Feed);
+        let (alice, rust) = (did!("did:plc:alice"), did!("did:plc:rust"));
+        bridge
+            .identities
+            .insert(Mapping::new(alice.clone(), AUTHOR));
+        let post = CommunityPost {
+            community: COMMUNITY.to_string(),
+            author: AUTHOR.to_string(),
+            activity: Value::Null,
+        };
+        let uri = "at://did:plc:alice/app.bsky.feed.post/1";
+        let record = json::parse(r#"{"text": "hi", "createdAt": "2024-06-01T00:00:00Z"}"#);
+        let record = record.unwrap();
+        bridged(&bridge, &post, uri, &record).unwrap();
+        let source = FeedSource::parse(&format!("community:{COMMUNITY}")).unwrap();
+        let (posts, _) = feed::skeleton(&bridge, &source, None, None, 10).unwrap();
+        assert_eq!(posts, [uri]);
+
+        let bridge = bridge.with_community_strategy(CommunityStrategy::Account);
+        bridge
+            .identities
+            .insert(Mapping::new(rust.clone(), COMMUNITY));
+        let owner = KeyOwner::Account(rust.clone());
+        let generator = FakeGenerator::default();
+        let purpose = KeyPurpose::RepoSigning;
+        bridge.keys.ensure(&owner, purpose, &generator).unwrap();
+        bridged(&bridge, &post, uri, &record).unwrap();
+        let reposts = bridge.repos.records(&rust, REPOST_COLLECTION);
+        let subject = reposts[0].1.get("subject").and_then(|s| s.get("uri"));
+        assert_eq!(subject, Some(&Value::from(uri)));
+    }
+}
@@ -0,0 +1,228 @@
+//! Upgrading records written against older lexicons
+//!
+//! Repos keep every record as it was written, so a backfill of an old account turns up forms
+//! the lexicons have since left behind, which would fail [validation](crate::lexicon) though
+//! nothing is wrong with them. Before a record is validated, each of the [`SHIMS`] for its
+//! collection rewrites a known historical form into today's:
+//!
+//! | Shim                 | Collection              | Until   | Upgrade                           |
+//! |----------------------|-------------------------|---------|-----------------------------------|
+//! | `post-entities`      | `app.bsky.feed.post`    | 2023-03 | `entities` become `facets`        |
+//! | `image-alt`          | `app.bsky.feed.post`    | 2023-03 | images without `alt` get `""`     |
+//! | `follow-declaration` | `app.bsky.graph.follow` | 2023-04 | a declaration subject is its DID  |
+//!
+//! Each shim is dated with the lexicon revision which retired its form, and only touches
+//! records still in it, so a current record passes through as it was. Nothing else is
+//! guessed at: a record no shim recognises is validated as it came
+
+use crate::json::Value;
+use crate::richtext::{LINK, MENTION};
+use std::collections::BTreeMap;
+
+type Fields = BTreeMap<String, Value>;
+
+const IMAGES: &str = "app.bsky.embed.images";
+const RECORD_WITH_MEDIA: &str = "app.bsky.embed.recordWithMedia";
+
+#[derive(Debug, Clone, Copy)]
+/// An upgrade from one historical form of a collection's records
+pub struct Shim {
+    pub name: &'static str,
+    pub collection: &'static str,
+    /// The lexicon revision, as `YYYY-MM`, which retired the form
+    pub until: &'static str,
+    /// Rewrite a record in the form, returning whether it was
+    apply: fn(&mut Fields) -> bool,
+}
+
+/// Every shim, oldest first
+pub const SHIMS: &[Shim] = &[
+    Shim {
+        name: "post-entities",
+        collection: "app.bsky.feed.post",
+        until: "2023-03",
+        apply: entities_to_facets,
+    },
+    Shim {
+        name: "image-alt",
+        collection: "app.bsky.feed.post",
+        until: "2023-03",
+        apply: empty_alt_text,
+    },
+    Shim {
+        name: "follow-declaration",
+        collection: "app.bsky.graph.follow",
+        until: "2023-04",
+        apply: declaration_subject,
+    },
+];
+
+/// The UTF-8 offset in `text` of its `utf16`th UTF-16 code unit, as entities were indexed by
+fn byte_offset(text: &str, utf16: i64) -> Option<usize> {
+    let mut units = 0;
+    for (offset, c) in text.char_indices() {
+        if units == utf16 {
+            return Some(offset);
+        }
+        units += c.len_utf16() as i64;
+    }
+    (units == utf16).then_some(text.len())
+}
+
+/// Posts' mentions and links were `entities` of a text slice and a type, before facets
+fn entities_to_facets(post: &mut Fields) -> bool {
+    let Some(Value::Array(entities)) = post.remove("entities") else {
+        return false;
+    };
+    // Facets written alongside are newer than the entities
+    if post.contains_key("facets") {
+        return true;
+    }
+    let text = post.get("text").and_then(Value::as_str).unwrap_or_default();
+    let facets: Vec<Value> = entities
+        .iter()
+        .filter_map(|entity| {
+            let index = entity.get("index")?;
+            let start = byte_offset(text, index.get("start")?.as_i64()?)?;
+            let end = byte_offset(text, index.get("end")?.as_i64()?)?;
+            let value = entity.get("value")?.as_str()?;
+            let (kind, field) = match entity.get("type")?.as_str()? {
+                "mention" => (MENTION, "did"),
+                "link" => (LINK, "uri"),
+                _ => return None,
+            };
+            let feature =
+                Value::object([("$type", Value::from(kind)), (field, Value::from(value))]);
+            let index = Value::object([
+                ("byteStart", Value::from(start)),
+                ("byteEnd", Value::from(end)),
+            ]);
+            Some(Value::object([
+                ("index", index),
+                ("features", Value::Array(vec![feature])),
+            ]))
+        })
+        .collect();
+    if !facets.is_empty() {
+        post.insert("facets".to_string(), Value::Array(facets));
+    }
+    true
+}
+
+/// Images' alt text was optional at first
+fn empty_alt_text(post: &mut Fields) -> bool {
+    let mut embed = post.get_mut("embed");
+    let of = |embed: &Option<&mut Value>, kind| {
+        let found = embed.as_ref().and_then(|e| e.get("$type")?.as_str());
+        found == Some(kind)
+    };
+    if of(&embed, RECORD_WITH_MEDIA) {
+        embed = embed.and_then(|e| match e {
+            Value::Object(fields) => fields.get_mut("media"),
+            _ => None,
+        });
+    }
+    if !of(&embed, IMAGES) {
+        return false;
+    }
+    let Some(Value::Object(embed)) = embed else {
+        return false;
+    };
+    let Some(Value::Array(images)) = embed.get_mut("images") else {
+        return false;
+    };
+    let mut upgraded = false;
+    for image in images.iter_mut() {
+        if let Value::Object(image) = image {
+            if !image.contains_key("alt") {
+                image.insert("alt".to_string(), Value::from(""));
+                upgraded = true;
+            }
+        }
+    }
+    upgraded
+}
+
+/// Follows were of an account's declaration record, alongside its DID
+fn declaration_subject(follow: &mut Fields) -> bool {
+    let did = match follow.get("subject") {
+        Some(subject @ Value::Object(_)) if subject.get("declarationCid").is_some() => {
+            subject.get("did").cloned()
+        }
+        _ => return false,
+    };
+    match did {
+        Some(did @ Value::String(_)) => {
+            follow.insert("subject".to_string(), did);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// `record`, from `collection`, in today's form, and the names of the shims which upgraded it
+pub fn upgrade(collection: &str, record: &Value) -> (Value, Vec<&'static str>) {
+    let mut record = record.clone();
+    let mut applied = Vec::new();
+    if let Value::Object(fields) = &mut record {
+        for shim in SHIMS.iter().filter(|shim| shim.collection == collection) {
+            if (shim.apply)(fields) {
+                applied.push(shim.name);
+            }
+        }
+    }
+    (record, applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::lexicon::validate;
+
+    #[test]
+    fn historical_forms_are_upgraded() {
+        let post = json::parse(
+            r#"{"$type": "app.bsky.feed.post", "text": "é @bob.test see https://a.example",
+                "createdAt": "2023-01-10T12:00:00Z",
+                "entities": [
+                    {"index": {"start": 2, "end": 11}, "type": "mention", "value": "did:plc:bob"},
+                    {"index": {"start": 16, "end": 33}, "type": "link",
+                        "value": "https://a.example"}
+                ],
+                "embed": {"$type": "app.bsky.embed.images", "images": [
+                    {"image": {"cid": "bafy", "mimeType": "image/png"}}]}}"#,
+        )
+        .unwrap();
+        let (upgraded, applied) = upgrade("app.bsky.feed.post", &post);
+        assert_eq!(applied, ["post-entities", "image-alt"]);
+        assert!(upgraded.get("entities").is_none());
+        let facets = upgraded.get("facets").and_then(Value::as_array).unwrap();
+        let index = facets[0].get("index").unwrap();
+        // The é is one UTF-16 unit, but two bytes
+        assert_eq!(index.get("byteStart"), Some(&Value::from(3_usize)));
+        assert_eq!(index.get("byteEnd"), Some(&Value::from(12_usize)));
+        let feature = &facets[1].get("features").and_then(Value::as_array).unwrap()[0];
+        assert_eq!(feature.get("uri"), Some(&Value::from("https://a.example")));
+        let embed = upgraded.get("embed").unwrap();
+        let image = &embed.get("images").and_then(Value::as_array).unwrap()[0];
+        assert_eq!(image.get("alt"), Some(&Value::from("")));
+        assert_eq!(validate("app.bsky.feed.post", &upgraded), Ok(()));
+        // Already upgraded, nothing more is done
+        assert_eq!(
+            upgrade("app.bsky.feed.post", &upgraded),
+            (upgraded, Vec::new())
+        );
+
+        let follow = json::parse(
+            r#"{"$type": "app.bsky.graph.follow", "createdAt": "2023-01-10T12:00:00Z",
+                "subject": {"did": "did:plc:bob", "declarationCid": "bafy"}}"#,
+        )
+        .unwrap();
+        assert!(validate("app.bsky.graph.follow", &follow).is_err());
+        let (upgraded, applied) = upgrade("app.bsky.graph.follow", &follow);
+        assert_eq!(applied, ["follow-declaration"]);
+        assert_eq!(upgraded.get("subject"), Some(&Value::from("did:plc:bob")));
+        assert_eq!(validate("app.bsky.graph.follow", &upgraded), Ok(()));
+    }
+}
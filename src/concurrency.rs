@@ -0,0 +1,355 @@
+//! Tuning how many deliveries run at once
+//!
+//! A fixed number of workers is either too few for a large box, leaving deliveries queued
+//! while it idles, or too many for a small instance on the other end. [`DeliveryConcurrency`]
+//! instead keeps limits which follow how deliveries are going, additive-increase
+//! multiplicative-decrease as TCP does:
+//!
+//! - each delivery answered within [`ConcurrencyConfig::target_latency`] raises its limit
+//!   by one over the limit, so by about one for each limit's worth of them
+//! - one which is slower, times out, fails to connect, or is answered `429` or `5xx` cuts it
+//!   by [`BACKOFF`], at most once per target latency so a burst of failures counts once
+//!
+//! There's a limit for each host, between one and [`ConcurrencyConfig::host_max`], which
+//! a delivery to a host at its limit waits [`HOST_WAIT`] in the queue for; and one for all
+//! of them, between [`ConcurrencyConfig::min`] and [`ConcurrencyConfig::max`], which
+//! [`spawn_workers`] keeps idle workers to. Hosts which have recovered their limit are
+//! forgotten once nothing is in flight to them.
+//!
+//! `FEDIBRIDGE_DELIVERY_WORKERS_MIN`, `FEDIBRIDGE_DELIVERY_WORKERS_MAX`,
+//! `FEDIBRIDGE_DELIVERY_HOST_MAX` and `FEDIBRIDGE_DELIVERY_TARGET_LATENCY_SECS` set them,
+//! and the admin API's `GET /admin/concurrency` reports where the limits are
+
+use crate::delivery::DeliveryError;
+use crate::jobs::{self, JobHandler, JobQueue, WORKER_POLL_INTERVAL};
+use crate::json::Value;
+use crate::shutdown::Shutdown;
+use crate::transport::TransportError;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// What a limit is multiplied by when deliveries are failing or slow
+pub const BACKOFF: f64 = 0.7;
+/// How long a delivery to a host at its limit waits before trying again
+pub const HOST_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConcurrencyConfig {
+    /// The fewest jobs run at once, however deliveries are going
+    pub min: usize,
+    /// The most jobs run at once, and the workers spawned to run them
+    pub max: usize,
+    /// The most deliveries to one host at once
+    pub host_max: usize,
+    /// How long a delivery may take and still count as going well
+    pub target_latency: Duration,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            min: 2,
+            max: 64,
+            host_max: 8,
+            target_latency: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Limit {
+    limit: f64,
+    in_flight: usize,
+    last_backoff: Option<Instant>,
+}
+
+impl Limit {
+    fn new(limit: usize) -> Limit {
+        Limit {
+            limit: limit as f64,
+            in_flight: 0,
+            last_backoff: None,
+        }
+    }
+
+    fn admits(&self) -> bool {
+        self.in_flight < self.current()
+    }
+
+    fn current(&self) -> usize {
+        self.limit as usize
+    }
+
+    fn record(&mut self, ok: bool, range: (usize, usize), wait: Duration, now: Instant) {
+        let (floor, ceiling) = (range.0.max(1) as f64, range.1.max(range.0).max(1) as f64);
+        if ok {
+            self.limit = (self.limit + 1.0 / self.limit).min(ceiling);
+            return;
+        }
+        if self.last_backoff.is_some_and(|at| now < at + wait) {
+            return;
+        }
+        self.limit = (self.limit * BACKOFF).max(floor);
+        self.last_backoff = Some(now);
+    }
+}
+
+#[derive(Debug)]
+struct Limits {
+    global: Limit,
+    hosts: HashMap<String, Limit>,
+}
+
+#[derive(Debug)]
+/// The limits on deliveries run at once, overall and to each host
+pub struct DeliveryConcurrency {
+    config: ConcurrencyConfig,
+    limits: Mutex<Limits>,
+    released: Condvar,
+}
+
+impl Default for DeliveryConcurrency {
+    fn default() -> Self {
+        DeliveryConcurrency::new(ConcurrencyConfig::default())
+    }
+}
+
+/// A worker's place under the overall limit, given back when it's dropped
+pub struct WorkerSlot<'a> {
+    concurrency: &'a DeliveryConcurrency,
+}
+
+impl Drop for WorkerSlot<'_> {
+    fn drop(&mut self) {
+        self.concurrency.limits.lock().unwrap().global.in_flight -= 1;
+        self.concurrency.released.notify_one();
+    }
+}
+
+/// A delivery's place under its host's limit, given back when it's dropped
+pub struct HostSlot<'a> {
+    concurrency: &'a DeliveryConcurrency,
+    host: String,
+}
+
+impl Drop for HostSlot<'_> {
+    fn drop(&mut self) {
+        let host_max = self.concurrency.config.host_max;
+        let mut limits = self.concurrency.limits.lock().unwrap();
+        let Some(limit) = limits.hosts.get_mut(&self.host) else {
+            return;
+        };
+        limit.in_flight -= 1;
+        if limit.in_flight == 0 && limit.current() >= host_max {
+            limits.hosts.remove(&self.host);
+        }
+    }
+}
+
+/// Whether a delivery's outcome says its host, or the bridge, is struggling
+pub fn overloaded(result: &Result<(), DeliveryError>) -> bool {
+    match result {
+        Ok(()) => false,
+        Err(DeliveryError::Rejected { status, .. }) => *status == 429 || *status >= 500,
+        Err(DeliveryError::Transport(e)) => matches!(
+            e,
+            TransportError::Connect { .. } | TransportError::Timeout { .. }
+        ),
+        Err(_) => false,
+    }
+}
+
+impl DeliveryConcurrency {
+    /// Limits starting from the least overall and the most for each host
+    pub fn new(config: ConcurrencyConfig) -> DeliveryConcurrency {
+        DeliveryConcurrency {
+            config,
+            limits: Mutex::new(Limits {
+                global: Limit::new(config.min.clamp(1, config.max.max(1))),
+                hosts: HashMap::new(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn config(&self) -> ConcurrencyConfig {
+        self.config
+    }
+
+    /// A place under the overall limit, waiting up to `timeout` for one
+    pub fn worker(&self, timeout: Duration) -> Option<WorkerSlot<'_>> {
+        let limits = self.limits.lock().unwrap();
+        let (mut limits, _) = self
+            .released
+            .wait_timeout_while(limits, timeout, |limits| !limits.global.admits())
+            .unwrap();
+        if !limits.global.admits() {
+            return None;
+        }
+        limits.global.in_flight += 1;
+        Some(WorkerSlot { concurrency: self })
+    }
+
+    /// A place under `host`'s limit, if it isn't at it
+    pub fn admit(&self, host: &str) -> Option<HostSlot<'_>> {
+        let host = host.to_ascii_lowercase();
+        let mut limits = self.limits.lock().unwrap();
+        let limit = limits
+            .hosts
+            .entry(host.clone())
+            .or_insert_with(|| Limit::new(self.config.host_max.max(1)));
+        if !limit.admits() {
+            return None;
+        }
+        limit.in_flight += 1;
+        Some(HostSlot {
+            concurrency: self,
+            host,
+        })
+    }
+
+    /// Record how a delivery to `host`, finishing at `now`, went
+    pub fn record(&self, host: &str, latency: Duration, overloaded: bool, now: Instant) {
+        let config = self.config;
+        let ok = !overloaded && latency <= config.target_latency;
+        let mut limits = self.limits.lock().unwrap();
+        let wait = config.target_latency;
+        if let Some(limit) = limits.hosts.get_mut(&host.to_ascii_lowercase()) {
+            limit.record(ok, (1, config.host_max), wait, now);
+        }
+        limits
+            .global
+            .record(ok, (config.min, config.max), wait, now);
+        drop(limits);
+        // A raised limit may let a waiting worker in
+        self.released.notify_all();
+    }
+
+    /// The overall limit
+    pub fn limit(&self) -> usize {
+        self.limits.lock().unwrap().global.current()
+    }
+
+    /// `host`'s limit
+    pub fn host_limit(&self, host: &str) -> usize {
+        let limits = self.limits.lock().unwrap();
+        let limit = limits.hosts.get(&host.to_ascii_lowercase());
+        limit.map_or(self.config.host_max, Limit::current)
+    }
+
+    /// The limits, with the hosts held below the most, ordered by host
+    pub fn to_json(&self) -> Value {
+        let limits = self.limits.lock().unwrap();
+        let mut hosts: Vec<_> = limits
+            .hosts
+            .iter()
+            .filter(|(_, limit)| limit.current() < self.config.host_max)
+            .collect();
+        hosts.sort_by(|a, b| a.0.cmp(b.0));
+        let hosts = hosts.into_iter().map(|(host, limit)| {
+            Value::object([
+                ("host", Value::from(host.as_str())),
+                ("limit", Value::from(limit.current())),
+                ("inFlight", Value::from(limit.in_flight)),
+            ])
+        });
+        Value::object([
+            ("limit", Value::from(limits.global.current())),
+            ("inFlight", Value::from(limits.global.in_flight)),
+            ("min", Value::from(self.config.min)),
+            ("max", Value::from(self.config.max)),
+            ("hostMax", Value::from(self.config.host_max)),
+            (
+                "targetLatencyMs",
+                Value::from(self.config.target_latency.as_millis() as u64),
+            ),
+            ("hosts", Value::Array(hosts.collect())),
+        ])
+    }
+}
+
+/// Spawn workers running jobs from the queue until shutdown is requested, as many at once as
+/// `concurrency`'s overall limit lets
+///
+/// [`ConcurrencyConfig::max`] are spawned, those over the limit waiting for a place
+pub fn spawn_workers(
+    queue: Arc<JobQueue>,
+    handler: Arc<dyn JobHandler>,
+    concurrency: Arc<DeliveryConcurrency>,
+    shutdown: Arc<Shutdown>,
+) -> Vec<JoinHandle<()>> {
+    (0..concurrency.config.max.max(1))
+        .map(|_| {
+            let (queue, handler) = (queue.clone(), handler.clone());
+            let (concurrency, shutdown) = (concurrency.clone(), shutdown.clone());
+            thread::spawn(move || {
+                while !shutdown.is_requested() {
+                    let Some(slot) = concurrency.worker(WORKER_POLL_INTERVAL) else {
+                        continue;
+                    };
+                    let Some(guard) = shutdown.begin() else {
+                        break;
+                    };
+                    let Some(job) = queue.wait_take(WORKER_POLL_INTERVAL) else {
+                        continue;
+                    };
+                    jobs::run_job(&queue, handler.as_ref(), &job);
+                    drop(guard);
+                    drop(slot);
+                }
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_rise_slowly_and_fall_fast() {
+        let config = ConcurrencyConfig {
+            min: 2,
+            max: 10,
+            host_max: 4,
+            target_latency: Duration::from_secs(1),
+        };
+        let concurrency = DeliveryConcurrency::new(config);
+        let fast = Duration::from_millis(100);
+        let now = Instant::now();
+        assert_eq!(concurrency.limit(), 2);
+        for _ in 0..20 {
+            concurrency.record("a.example", fast, false, now);
+        }
+        assert_eq!(concurrency.limit(), 6);
+        for _ in 0..100 {
+            concurrency.record("a.example", fast, false, now);
+        }
+        assert_eq!(concurrency.limit(), 10);
+
+        // Slow deliveries to a host cut its limit, once per target latency
+        let slots: Vec<_> = (0..4).map(|_| concurrency.admit("b.example")).collect();
+        assert!(slots.iter().all(Option::is_some));
+        assert!(concurrency.admit("B.example").is_none());
+        concurrency.record("b.example", Duration::from_secs(3), false, now);
+        concurrency.record("b.example", fast, true, now);
+        assert_eq!(concurrency.host_limit("b.example"), 2);
+        assert_eq!(concurrency.limit(), 7);
+        let later = now + Duration::from_secs(2);
+        concurrency.record("b.example", fast, true, later);
+        assert_eq!(concurrency.host_limit("b.example"), 1);
+        assert_eq!(concurrency.limit(), 4);
+        let json = concurrency.to_json();
+        let hosts = json.get("hosts").and_then(Value::as_array).unwrap();
+        assert_eq!(hosts[0].get("inFlight").and_then(Value::as_i64), Some(4));
+
+        // Until everything in flight to it is done, another waits
+        drop(slots);
+        assert!(concurrency.admit("b.example").is_some());
+        let workers: Vec<_> = (0..4).map(|_| concurrency.worker(fast)).collect();
+        assert!(workers.iter().all(Option::is_some));
+        assert!(concurrency.worker(Duration::ZERO).is_none());
+    }
+}
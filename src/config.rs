@@ -0,0 +1,1191 @@
+//! Bridge configuration, read from the environment
+
+use crate::alerts::{AlertConfig, SmtpConfig};
+use crate::article::{ArticleConfig, MAX_POST_LENGTH};
+use crate::attribution::AttributionConfig;
+use crate::breaker::BreakerConfig;
+use crate::cache::{CacheConfig, Policy, ResourceKind};
+use crate::community::CommunityStrategy;
+use crate::concurrency::ConcurrencyConfig;
+use crate::content::{ContentFilterConfig, SpamHeuristics};
+use crate::crawl::CrawlConfig;
+use crate::digest::{DigestConfig, Network};
+use crate::discovery::AboutConfig;
+use crate::dm::DmPolicy;
+use crate::dns::{AddressPreference, DnsConfig, Upstream};
+use crate::egress::EgressPolicy;
+use crate::feed::{Feed, FeedConfig, FeedSource};
+use crate::filter::{Filter, FilterError};
+use crate::firehose::Shard;
+use crate::image::ImageLimits;
+use crate::interop::OtherBridges;
+use crate::jobs;
+use crate::keys::DEFAULT_KEY_OVERLAP;
+use crate::labels::LabelPolicy;
+use crate::linkcard::LinkCardConfig;
+use crate::markup::HtmlProfile;
+use crate::moderation::ModerationConfig;
+use crate::normalize;
+use crate::oauth::OAuthConfig;
+use crate::orphans::DEFAULT_ORPHAN_WINDOW;
+use crate::parsing::ParsingConfig;
+use crate::payload::PayloadLimits;
+use crate::peering::PeerConfig;
+use crate::policy::{self, Rule};
+use crate::provision::{IdentityConfig, IdentityKind};
+use crate::proxy::{ProxyError, ProxyRules};
+use crate::quota::QuotaConfig;
+use crate::ratelimit::{Limit, RateLimitConfig};
+use crate::reactions::{ReactionConfig, ReactionLikes};
+use crate::reconcile::ReconcileConfig;
+use crate::replycontext::ReplyContext;
+use crate::retention::RetentionConfig;
+use crate::retraction::DEFAULT_RETRACTION_WINDOW;
+use crate::secrets::{SecretError, Secrets};
+use crate::shedding::ShedConfig;
+use crate::signatures::{SignaturePolicies, SignaturePolicy, DEFAULT_KEY_TTL};
+use crate::templates::{Message, Templates};
+use crate::threadgate::ReplyGatePolicy;
+use crate::time::DEFAULT_MAX_CLOCK_SKEW;
+use crate::tls::TlsConfig;
+use crate::transport::PoolConfig;
+use crate::upstream::UpstreamConfig;
+use crate::webhooks::{EventKind, WebhookConfig};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors in the provided configuration
+pub enum ConfigError {
+    #[error("Invalid value for {var} - found {found}")]
+    Invalid { var: &'static str, found: String },
+    #[error("Invalid FEDIBRIDGE_FIREHOSE_FILTER - {0}")]
+    Filter(#[from] FilterError),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+}
+
+/// The variables which are secrets, so can be read from [elsewhere](crate::secrets)
+pub const SECRET_VARS: [&str; 7] = [
+    "FEDIBRIDGE_ADMIN_TOKEN",
+    "FEDIBRIDGE_KEYSTORE_PASSPHRASE",
+    "FEDIBRIDGE_REPORT_TOKEN",
+    "FEDIBRIDGE_CHAT_TOKEN",
+    "FEDIBRIDGE_WEBHOOK_SECRET",
+    "FEDIBRIDGE_ALERT_SMTP_PASSWORD",
+    "FEDIBRIDGE_IDENTITY_PDS_INVITE_CODE",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+/// Configuration for the admin API
+pub struct AdminConfig {
+    pub listen: SocketAddr,
+    /// Bearer token required on every admin request
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Where the public endpoints are served, if anywhere
+    pub listen: Option<SocketAddr>,
+    /// The public hostname bridged accounts' handles and `did:web` identities are under.
+    /// Without one, the bridge doesn't resolve them itself
+    pub hostname: Option<String>,
+    /// The admin API is only served when a token has been configured
+    pub admin: Option<AdminConfig>,
+    /// Where persisted state (cursors, queues) lives
+    pub state_dir: PathBuf,
+    /// How long shutdown waits for in-flight work before flushing anyway
+    pub shutdown_timeout: Duration,
+    /// Passphrase the keystore is encrypted with. Without one, keys aren't persisted
+    pub keystore_passphrase: Option<String>,
+    /// The part of the firehose this instance consumes
+    pub shard: Shard,
+    /// Which firehose events to handle, as a [`Filter`] expression
+    pub firehose_filter: Filter,
+    pub moderation: ModerationConfig,
+    /// How labelled posts are presented on the fediverse
+    pub label_policy: LabelPolicy,
+    /// How far ahead of the bridge's clock incoming timestamps may be
+    pub max_clock_skew: Duration,
+    /// How strictly what other servers send is parsed, per subsystem
+    pub parsing: ParsingConfig,
+    pub link_cards: LinkCardConfig,
+    pub articles: ArticleConfig,
+    /// Footers appended to bridged posts
+    pub attribution: AttributionConfig,
+    /// Which flavour of HTML bridged posts are written in
+    pub html_profile: HtmlProfile,
+    /// What's said of unbridged posts bridged replies reply to
+    pub reply_context: ReplyContext,
+    /// The wording of degraded content, as far as `FEDIBRIDGE_DM_BOUNCE_MESSAGE` changes it
+    pub templates: Templates,
+    /// A file of templates by message and language, over those
+    pub templates_file: Option<PathBuf>,
+    pub reactions: ReactionConfig,
+    /// How oversized images are fitted into Bluesky's blob limit
+    pub images: ImageLimits,
+    pub dms: DmPolicy,
+    /// How replies to gated Bluesky threads are handled
+    pub reply_gates: ReplyGatePolicy,
+    pub digests: DigestConfig,
+    /// How often both sides of each account are compared and repaired
+    pub reconcile: ReconcileConfig,
+    /// How much each account may write a day
+    pub quotas: QuotaConfig,
+    /// How long replies are held for a parent which hasn't been bridged yet
+    pub orphan_window: Duration,
+    /// How old a backfilled or replayed event is before it's checked for having been deleted
+    pub retraction_window: Duration,
+    /// Size and freshness of the remote document cache
+    pub cache: CacheConfig,
+    /// How long re-hosted media is kept
+    pub retention: RetentionConfig,
+    /// Where re-hosted media is redirected to be served from, if anywhere but the bridge
+    pub media_cdn: Option<String>,
+    /// What the bridge's description says of it beyond its configuration
+    pub about: AboutConfig,
+    /// Limits on requests to the public endpoints
+    pub rate_limits: RateLimitConfig,
+    /// Limits on activities posted to inboxes
+    pub inbox_limits: PayloadLimits,
+    /// Federation rules, set over any edited through the admin API at startup
+    pub policy: Vec<Rule>,
+    /// Built-in filters posts must pass to be bridged
+    pub content_filters: ContentFilterConfig,
+    /// How long a remote key which verified a signature is trusted
+    pub key_cache_ttl: Duration,
+    /// How long a pre-rotated key is published before it's signed with
+    pub key_overlap: Duration,
+    /// How strictly inbound signatures are checked, per class of instance
+    pub signatures: SignaturePolicies,
+    /// How outbound connections are pooled
+    pub connections: PoolConfig,
+    /// Extra trust roots and a client certificate for outbound TLS
+    pub tls: TlsConfig,
+    /// Which proxies outbound connections go through
+    pub proxies: ProxyRules,
+    /// Where outbound requests may go
+    pub egress: EgressPolicy,
+    /// When requests to failing hosts are given a rest
+    pub breakers: BreakerConfig,
+    /// How many deliveries run at once, as they adapt to how they're going
+    pub concurrency: ConcurrencyConfig,
+    /// Which firehose events are shed, past which lag
+    pub shedding: ShedConfig,
+    /// The relays and PLC directories failed over between
+    pub upstreams: UpstreamConfig,
+    /// Who hostnames are resolved by, and how their addresses are tried
+    pub dns: DnsConfig,
+    /// Jobs held in memory before more are spilled to disk
+    pub job_capacity: usize,
+    /// Where operators are told of opt-ins, failures and lag
+    pub webhooks: WebhookConfig,
+    /// Who is emailed about critical conditions, and when
+    pub alerts: AlertConfig,
+    /// A file with the body template for alert emails, instead of the default
+    pub alert_template: Option<PathBuf>,
+    /// Relays asked to crawl the bridge's repos. Only used with a `hostname`
+    pub crawl: CrawlConfig,
+    /// How the bridge describes itself as an OAuth client. Only served with a `hostname`
+    pub oauth: OAuthConfig,
+    /// The version of the terms accounts must agree to, and the file with their text.
+    /// Without them, nobody needs to agree to anything
+    pub terms: Option<(String, PathBuf)>,
+    /// Whether accounts opting in wait for an operator to approve them
+    pub require_approval: bool,
+    /// Feeds of bridged posts. Only served with a `hostname`
+    pub feeds: FeedConfig,
+    /// Whether the bridge labels what it publishes, as its `did:web`. Only with a `hostname`
+    pub labeler: bool,
+    /// Whether writes to either network are captured for review instead of sent
+    pub dry_run: bool,
+    /// Whether inbound events are archived, to be replayed
+    pub archive_events: bool,
+    /// Where other bridges run, so their accounts aren't bridged again
+    pub other_bridges: OtherBridges,
+    /// How fediverse communities are represented on Bluesky
+    pub community_strategy: CommunityStrategy,
+    /// The network posts are only mirrored to, if the bridge isn't two-way
+    pub mirror_to: Option<Network>,
+    /// How identities are made for accounts being bridged
+    pub identity: IdentityConfig,
+    /// Other fedibridges told who this one bridges, and asked who they do. Only served with
+    /// a `hostname`
+    pub peering: PeerConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: None,
+            hostname: None,
+            admin: None,
+            state_dir: PathBuf::from("state"),
+            shutdown_timeout: Duration::from_secs(30),
+            keystore_passphrase: None,
+            shard: Shard::SINGLE,
+            firehose_filter: Filter::default(),
+            moderation: ModerationConfig::default(),
+            label_policy: LabelPolicy::default(),
+            max_clock_skew: DEFAULT_MAX_CLOCK_SKEW,
+            parsing: ParsingConfig::default(),
+            link_cards: LinkCardConfig::default(),
+            articles: ArticleConfig::default(),
+            attribution: AttributionConfig::default(),
+            html_profile: HtmlProfile::default(),
+            reply_context: ReplyContext::default(),
+            templates: Templates::default(),
+            templates_file: None,
+            reactions: ReactionConfig::default(),
+            images: ImageLimits::default(),
+            dms: DmPolicy::default(),
+            reply_gates: ReplyGatePolicy::default(),
+            digests: DigestConfig::default(),
+            reconcile: ReconcileConfig::default(),
+            quotas: QuotaConfig::default(),
+            orphan_window: DEFAULT_ORPHAN_WINDOW,
+            retraction_window: DEFAULT_RETRACTION_WINDOW,
+            cache: CacheConfig::default(),
+            retention: RetentionConfig::default(),
+            media_cdn: None,
+            about: AboutConfig::default(),
+            rate_limits: RateLimitConfig::default(),
+            inbox_limits: PayloadLimits::default(),
+            policy: Vec::new(),
+            content_filters: ContentFilterConfig::default(),
+            key_cache_ttl: DEFAULT_KEY_TTL,
+            key_overlap: DEFAULT_KEY_OVERLAP,
+            signatures: SignaturePolicies::default(),
+            connections: PoolConfig::default(),
+            tls: TlsConfig::default(),
+            proxies: ProxyRules::default(),
+            egress: EgressPolicy::default(),
+            breakers: BreakerConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            shedding: ShedConfig::default(),
+            upstreams: UpstreamConfig::default(),
+            dns: DnsConfig::default(),
+            job_capacity: jobs::DEFAULT_CAPACITY,
+            webhooks: WebhookConfig::default(),
+            alerts: AlertConfig::default(),
+            alert_template: None,
+            crawl: CrawlConfig::default(),
+            oauth: OAuthConfig::default(),
+            terms: None,
+            require_approval: false,
+            feeds: FeedConfig::default(),
+            labeler: false,
+            dry_run: false,
+            archive_events: false,
+            other_bridges: OtherBridges::default(),
+            community_strategy: CommunityStrategy::default(),
+            mirror_to: None,
+            identity: IdentityConfig::default(),
+            peering: PeerConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Load configuration from `FEDIBRIDGE_*` environment variables
+    pub fn from_env() -> Result<Config, ConfigError> {
+        let env = |var: &str| std::env::var(var).ok();
+        let secrets = Secrets::from_vars(env)?;
+        Config::from_vars_with_secrets(env, &secrets)
+    }
+
+    /// Load configuration using `lookup` to read variables, and `secrets` to read the
+    /// [`SECRET_VARS`] set as references to them
+    pub fn from_vars_with_secrets(
+        lookup: impl Fn(&str) -> Option<String>,
+        secrets: &Secrets,
+    ) -> Result<Config, ConfigError> {
+        let read = secrets.read_all(&lookup, &SECRET_VARS)?;
+        Config::from_vars(|var| match SECRET_VARS.contains(&var) {
+            true => read.get(var).cloned(),
+            false => lookup(var),
+        })
+    }
+
+    /// Load configuration using `lookup` to read variables
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let listen = match lookup("FEDIBRIDGE_LISTEN") {
+            Some(listen) => Some(listen.parse().map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_LISTEN",
+                found: listen,
+            })?),
+            None => None,
+        };
+        let admin = match lookup("FEDIBRIDGE_ADMIN_TOKEN") {
+            Some(token) if !token.is_empty() => {
+                let listen = lookup("FEDIBRIDGE_ADMIN_LISTEN")
+                    .unwrap_or_else(|| "127.0.0.1:8081".to_string());
+                let listen = listen.parse().map_err(|_| ConfigError::Invalid {
+                    var: "FEDIBRIDGE_ADMIN_LISTEN",
+                    found: listen,
+                })?;
+                Some(AdminConfig { listen, token })
+            }
+            _ => None,
+        };
+        let defaults = Config::default();
+        let state_dir = lookup("FEDIBRIDGE_STATE_DIR").map_or(defaults.state_dir, PathBuf::from);
+        let seconds = |var, default| match lookup(var) {
+            Some(secs) => secs
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| ConfigError::Invalid { var, found: secs }),
+            None => Ok(default),
+        };
+        let shutdown_timeout = seconds(
+            "FEDIBRIDGE_SHUTDOWN_TIMEOUT_SECS",
+            defaults.shutdown_timeout,
+        )?;
+        let max_clock_skew = seconds("FEDIBRIDGE_MAX_CLOCK_SKEW_SECS", defaults.max_clock_skew)?;
+        let keystore_passphrase =
+            lookup("FEDIBRIDGE_KEYSTORE_PASSPHRASE").filter(|p| !p.is_empty());
+        let shard = match lookup("FEDIBRIDGE_SHARD") {
+            Some(shard) => shard.parse().map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_SHARD",
+                found: shard,
+            })?,
+            None => defaults.shard,
+        };
+        let firehose_filter = match lookup("FEDIBRIDGE_FIREHOSE_FILTER") {
+            Some(filter) => filter.parse()?,
+            None => defaults.firehose_filter,
+        };
+        let nonempty = |var| lookup(var).filter(|v: &String| !v.is_empty());
+        let moderation = ModerationConfig {
+            report_service: nonempty("FEDIBRIDGE_REPORT_SERVICE"),
+            report_token: nonempty("FEDIBRIDGE_REPORT_TOKEN"),
+            instance_actor: nonempty("FEDIBRIDGE_INSTANCE_ACTOR"),
+        };
+        let label_policy = match lookup("FEDIBRIDGE_LABEL_POLICY") {
+            Some(policy) => policy.parse().map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_LABEL_POLICY",
+                found: policy,
+            })?,
+            None => defaults.label_policy,
+        };
+        let parsing = match lookup("FEDIBRIDGE_PARSING") {
+            Some(parsing) => parsing.parse().map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_PARSING",
+                found: parsing,
+            })?,
+            None => defaults.parsing,
+        };
+        let policy = match lookup("FEDIBRIDGE_POLICY") {
+            Some(rules) => policy::parse_rules(&rules).map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_POLICY",
+                found: rules,
+            })?,
+            None => defaults.policy,
+        };
+        let flag = |var, default| match lookup(var).as_deref() {
+            Some("true" | "1") => Ok(true),
+            Some("false" | "0") => Ok(false),
+            Some(other) => Err(ConfigError::Invalid {
+                var,
+                found: other.to_string(),
+            }),
+            None => Ok(default),
+        };
+        let link_cards = LinkCardConfig {
+            enabled: flag("FEDIBRIDGE_LINK_CARDS", defaults.link_cards.enabled)?,
+            timeout: seconds(
+                "FEDIBRIDGE_LINK_CARD_TIMEOUT_SECS",
+                defaults.link_cards.timeout,
+            )?,
+            ..defaults.link_cards
+        };
+        let articles = ArticleConfig {
+            max_length: match number(
+                &lookup,
+                "FEDIBRIDGE_ARTICLE_MAX_LENGTH",
+                defaults.articles.max_length,
+            )? {
+                length @ 1..=MAX_POST_LENGTH => length,
+                length => {
+                    return Err(ConfigError::Invalid {
+                        var: "FEDIBRIDGE_ARTICLE_MAX_LENGTH",
+                        found: length.to_string(),
+                    })
+                }
+            },
+        };
+        fn number<T: std::str::FromStr>(
+            lookup: &impl Fn(&str) -> Option<String>,
+            var: &'static str,
+            default: T,
+        ) -> Result<T, ConfigError> {
+            match lookup(var) {
+                Some(n) => n
+                    .parse()
+                    .map_err(|_| ConfigError::Invalid { var, found: n }),
+                None => Ok(default),
+            }
+        }
+        let images = ImageLimits {
+            max_dimension: number(
+                &lookup,
+                "FEDIBRIDGE_IMAGE_MAX_DIMENSION",
+                defaults.images.max_dimension,
+            )?,
+            quality: number(&lookup, "FEDIBRIDGE_IMAGE_QUALITY", defaults.images.quality)?,
+            min_quality: number(
+                &lookup,
+                "FEDIBRIDGE_IMAGE_MIN_QUALITY",
+                defaults.images.min_quality,
+            )?,
+            ..defaults.images
+        };
+        let dms = DmPolicy {
+            bounce: flag("FEDIBRIDGE_DM_BOUNCE", defaults.dms.bounce)?,
+            chat_service: nonempty("FEDIBRIDGE_CHAT_SERVICE"),
+            chat_token: nonempty("FEDIBRIDGE_CHAT_TOKEN"),
+        };
+        let digests = DigestConfig {
+            enabled: flag("FEDIBRIDGE_DIGESTS", defaults.digests.enabled)?,
+            interval: seconds("FEDIBRIDGE_DIGEST_INTERVAL_SECS", defaults.digests.interval)?,
+            account: match nonempty("FEDIBRIDGE_DIGEST_ACCOUNT") {
+                Some(did) => {
+                    Some(
+                        normalize::parse_did(&did).map_err(|_| ConfigError::Invalid {
+                            var: "FEDIBRIDGE_DIGEST_ACCOUNT",
+                            found: did,
+                        })?,
+                    )
+                }
+                None => None,
+            },
+        };
+        let mut cache = CacheConfig {
+            max_bytes: number(
+                &lookup,
+                "FEDIBRIDGE_CACHE_MAX_BYTES",
+                defaults.cache.max_bytes,
+            )?,
+            ..defaults.cache
+        };
+        let objects = ResourceKind::Object.default_policy();
+        cache.policies.insert(
+            ResourceKind::Object,
+            Policy {
+                ttl: seconds("FEDIBRIDGE_OBJECT_CACHE_TTL_SECS", objects.ttl)?,
+                ..objects
+            },
+        );
+        // Zero means no limit
+        let quota = |var, default: Option<u64>| {
+            number(&lookup, var, default.unwrap_or_default()).map(|n| Some(n).filter(|&n| n > 0))
+        };
+        let quotas = QuotaConfig {
+            posts_per_day: quota(
+                "FEDIBRIDGE_QUOTA_POSTS_PER_DAY",
+                defaults.quotas.posts_per_day,
+            )?,
+            media_bytes_per_day: quota(
+                "FEDIBRIDGE_QUOTA_MEDIA_BYTES_PER_DAY",
+                defaults.quotas.media_bytes_per_day,
+            )?,
+            follows_per_day: quota(
+                "FEDIBRIDGE_QUOTA_FOLLOWS_PER_DAY",
+                defaults.quotas.follows_per_day,
+            )?,
+            throttle: seconds("FEDIBRIDGE_QUOTA_THROTTLE_SECS", defaults.quotas.throttle)?,
+        };
+        let retention = RetentionConfig {
+            media_ttl: Some(seconds(
+                "FEDIBRIDGE_MEDIA_TTL_SECS",
+                defaults.retention.media_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            media_max_bytes: Some(number(
+                &lookup,
+                "FEDIBRIDGE_MEDIA_MAX_BYTES",
+                defaults.retention.media_max_bytes.unwrap_or_default(),
+            )?)
+            .filter(|&max| max > 0),
+            audit_ttl: Some(seconds(
+                "FEDIBRIDGE_AUDIT_TTL_SECS",
+                defaults.retention.audit_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            seen_ttl: seconds(
+                "FEDIBRIDGE_SEEN_ACTIVITY_TTL_SECS",
+                defaults.retention.seen_ttl,
+            )?,
+            quarantine_ttl: Some(seconds(
+                "FEDIBRIDGE_QUARANTINE_TTL_SECS",
+                defaults.retention.quarantine_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            decision_ttl: Some(seconds(
+                "FEDIBRIDGE_DECISION_TTL_SECS",
+                defaults.retention.decision_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            receipt_ttl: Some(seconds(
+                "FEDIBRIDGE_RECEIPT_TTL_SECS",
+                defaults.retention.receipt_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            archive_ttl: Some(seconds(
+                "FEDIBRIDGE_ARCHIVE_TTL_SECS",
+                defaults.retention.archive_ttl.unwrap_or_default(),
+            )?)
+            .filter(|ttl| !ttl.is_zero()),
+            cleanup_interval: seconds(
+                "FEDIBRIDGE_CLEANUP_INTERVAL_SECS",
+                defaults.retention.cleanup_interval,
+            )?,
+        };
+        // A sustained rate of zero means no limit
+        let limit = |burst, per_minute, default: Option<Limit>| {
+            let default = default.unwrap_or(Limit {
+                burst: 0,
+                per_minute: 0,
+            });
+            let limit = Limit {
+                burst: number(&lookup, burst, default.burst)?,
+                per_minute: number(&lookup, per_minute, default.per_minute)?,
+            };
+            Ok::<_, ConfigError>(Some(limit).filter(|limit| limit.per_minute > 0))
+        };
+        let rate_limits = RateLimitConfig {
+            instance: limit(
+                "FEDIBRIDGE_RATE_LIMIT_INSTANCE_BURST",
+                "FEDIBRIDGE_RATE_LIMIT_INSTANCE_PER_MINUTE",
+                defaults.rate_limits.instance,
+            )?,
+            actor: limit(
+                "FEDIBRIDGE_RATE_LIMIT_ACTOR_BURST",
+                "FEDIBRIDGE_RATE_LIMIT_ACTOR_PER_MINUTE",
+                defaults.rate_limits.actor,
+            )?,
+        };
+        // Zero means no limit
+        let payload = |var, default: Option<usize>| {
+            number(&lookup, var, default.unwrap_or_default()).map(|n| Some(n).filter(|&n| n > 0))
+        };
+        let inbox_limits = PayloadLimits {
+            max_bytes: payload(
+                "FEDIBRIDGE_INBOX_MAX_BYTES",
+                defaults.inbox_limits.max_bytes,
+            )?,
+            max_depth: payload(
+                "FEDIBRIDGE_INBOX_MAX_DEPTH",
+                defaults.inbox_limits.max_depth,
+            )?,
+            max_items: payload(
+                "FEDIBRIDGE_INBOX_MAX_ITEMS",
+                defaults.inbox_limits.max_items,
+            )?,
+        };
+        let list = |var| -> Vec<String> {
+            let entries = lookup(var).unwrap_or_default();
+            let entries = entries.split(',').map(str::trim).filter(|e| !e.is_empty());
+            entries.map(str::to_string).collect()
+        };
+        let content_filters = ContentFilterConfig {
+            keywords: list("FEDIBRIDGE_BLOCKED_KEYWORDS"),
+            link_domains: list("FEDIBRIDGE_BLOCKED_LINK_DOMAINS"),
+            spam: flag(
+                "FEDIBRIDGE_SPAM_FILTER",
+                defaults.content_filters.spam.is_some(),
+            )?
+            .then(SpamHeuristics::default),
+        };
+        let connections = PoolConfig {
+            max_per_host: number(
+                &lookup,
+                "FEDIBRIDGE_MAX_CONNECTIONS_PER_HOST",
+                defaults.connections.max_per_host,
+            )?,
+            idle_timeout: seconds(
+                "FEDIBRIDGE_CONNECTION_IDLE_SECS",
+                defaults.connections.idle_timeout,
+            )?,
+        };
+        let events = list("FEDIBRIDGE_WEBHOOK_EVENTS").into_iter().map(|event| {
+            EventKind::parse(&event).ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_WEBHOOK_EVENTS",
+                found: event,
+            })
+        });
+        // A lag threshold of zero means no lag alerts
+        let lag_threshold = number(
+            &lookup,
+            "FEDIBRIDGE_FIREHOSE_LAG_ALERT",
+            defaults.webhooks.lag_threshold.unwrap_or_default(),
+        )?;
+        let webhooks = WebhookConfig {
+            urls: list("FEDIBRIDGE_WEBHOOKS"),
+            events: events.collect::<Result<_, _>>()?,
+            secret: nonempty("FEDIBRIDGE_WEBHOOK_SECRET"),
+            lag_threshold: Some(lag_threshold).filter(|&threshold| threshold > 0),
+            check_interval: seconds(
+                "FEDIBRIDGE_WEBHOOK_CHECK_INTERVAL_SECS",
+                defaults.webhooks.check_interval,
+            )?,
+        };
+        let crawl = CrawlConfig {
+            relays: list("FEDIBRIDGE_RELAYS"),
+            interval: seconds("FEDIBRIDGE_CRAWL_INTERVAL_SECS", defaults.crawl.interval)?,
+            retry_interval: seconds("FEDIBRIDGE_CRAWL_RETRY_SECS", defaults.crawl.retry_interval)?,
+        };
+        let peering = PeerConfig {
+            peers: list("FEDIBRIDGE_PEERS"),
+            interval: seconds("FEDIBRIDGE_PEER_SYNC_SECS", defaults.peering.interval)?,
+        };
+        let oauth = OAuthConfig {
+            client_name: nonempty("FEDIBRIDGE_OAUTH_CLIENT_NAME")
+                .unwrap_or(defaults.oauth.client_name),
+            scope: nonempty("FEDIBRIDGE_OAUTH_SCOPE").unwrap_or(defaults.oauth.scope),
+            redirect_uris: list("FEDIBRIDGE_OAUTH_REDIRECT_URIS"),
+        };
+        // Each feed is `<rkey>=<source>`
+        let feeds = list("FEDIBRIDGE_FEEDS").into_iter().map(|feed| {
+            let parsed = feed.split_once('=').and_then(|(rkey, source)| {
+                Some(Feed {
+                    rkey: rkey.to_string(),
+                    source: FeedSource::parse(source)?,
+                })
+            });
+            parsed.ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_FEEDS",
+                found: feed,
+            })
+        });
+        let publisher = match nonempty("FEDIBRIDGE_FEED_PUBLISHER") {
+            Some(did) => Some(
+                normalize::parse_did(&did).map_err(|_| ConfigError::Invalid {
+                    var: "FEDIBRIDGE_FEED_PUBLISHER",
+                    found: did,
+                })?,
+            ),
+            None => defaults.feeds.publisher,
+        };
+        let feeds = FeedConfig {
+            feeds: feeds.collect::<Result<_, _>>()?,
+            publisher,
+            appview: nonempty("FEDIBRIDGE_APPVIEW").unwrap_or(defaults.feeds.appview),
+        };
+        // Replacing the defaults, so operators can drop one as well as add one
+        let other_bridges = match list("FEDIBRIDGE_OTHER_BRIDGES") {
+            domains if domains.is_empty() => defaults.other_bridges,
+            domains => OtherBridges {
+                domains: domains.iter().map(|d| d.to_ascii_lowercase()).collect(),
+            },
+        };
+        let reactions = ReactionConfig {
+            likes: nonempty("FEDIBRIDGE_REACTIONS").map_or(defaults.reactions.likes, |likes| {
+                ReactionLikes::parse(&likes)
+            }),
+            annotate: flag("FEDIBRIDGE_REACTION_ANNOTATE", defaults.reactions.annotate)?,
+            outbound: nonempty("FEDIBRIDGE_REACTION_EMOJI").or(defaults.reactions.outbound),
+        };
+        let html_profile = match nonempty("FEDIBRIDGE_HTML_PROFILE") {
+            Some(profile) => HtmlProfile::parse(&profile).ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_HTML_PROFILE",
+                found: profile,
+            })?,
+            None => defaults.html_profile,
+        };
+        let reply_context = match nonempty("FEDIBRIDGE_REPLY_CONTEXT") {
+            Some(context) => ReplyContext::parse(&context).ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_REPLY_CONTEXT",
+                found: context,
+            })?,
+            None => defaults.reply_context,
+        };
+        let mirror_to = match nonempty("FEDIBRIDGE_MIRROR_ONLY_TO") {
+            Some(network) => Some(Network::parse(&network).ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_MIRROR_ONLY_TO",
+                found: network,
+            })?),
+            None => defaults.mirror_to,
+        };
+        let community_strategy = match nonempty("FEDIBRIDGE_COMMUNITIES") {
+            Some(strategy) => CommunityStrategy::parse(&strategy).ok_or(ConfigError::Invalid {
+                var: "FEDIBRIDGE_COMMUNITIES",
+                found: strategy,
+            })?,
+            None => defaults.community_strategy,
+        };
+        let identity = IdentityConfig {
+            backend: match nonempty("FEDIBRIDGE_IDENTITY_BACKEND") {
+                Some(backend) => IdentityKind::parse(&backend).ok_or(ConfigError::Invalid {
+                    var: "FEDIBRIDGE_IDENTITY_BACKEND",
+                    found: backend,
+                })?,
+                None => defaults.identity.backend,
+            },
+            pds: nonempty("FEDIBRIDGE_IDENTITY_PDS"),
+            invite_code: nonempty("FEDIBRIDGE_IDENTITY_PDS_INVITE_CODE"),
+        };
+        // Accounts can't be created on a PDS without knowing which
+        if identity.backend == IdentityKind::Pds && identity.pds.is_none() {
+            return Err(ConfigError::Invalid {
+                var: "FEDIBRIDGE_IDENTITY_PDS",
+                found: String::new(),
+            });
+        }
+        // The version is what people agree to, so the text can't be published without one
+        let terms = match (
+            nonempty("FEDIBRIDGE_TERMS_VERSION"),
+            nonempty("FEDIBRIDGE_TERMS_FILE"),
+        ) {
+            (Some(version), Some(file)) => Some((version, PathBuf::from(file))),
+            (None, None) => None,
+            (version, _) => {
+                return Err(ConfigError::Invalid {
+                    var: match version {
+                        Some(_) => "FEDIBRIDGE_TERMS_FILE",
+                        None => "FEDIBRIDGE_TERMS_VERSION",
+                    },
+                    found: String::new(),
+                })
+            }
+        };
+        // A certificate is no use without its key, or the other way round
+        let client_identity = match (
+            nonempty("FEDIBRIDGE_TLS_CLIENT_CERT"),
+            nonempty("FEDIBRIDGE_TLS_CLIENT_KEY"),
+        ) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            (cert, _) => {
+                return Err(ConfigError::Invalid {
+                    var: match cert {
+                        Some(_) => "FEDIBRIDGE_TLS_CLIENT_KEY",
+                        None => "FEDIBRIDGE_TLS_CLIENT_CERT",
+                    },
+                    found: String::new(),
+                })
+            }
+        };
+        let tls = TlsConfig {
+            extra_roots: nonempty("FEDIBRIDGE_TLS_ROOTS").map(PathBuf::from),
+            client_identity,
+        };
+        let proxy = nonempty("FEDIBRIDGE_PROXY");
+        let proxy_rules = lookup("FEDIBRIDGE_PROXY_RULES").unwrap_or_default();
+        let proxies = ProxyRules::parse(proxy.as_deref(), &proxy_rules).map_err(|e| {
+            let (var, found) = match e {
+                ProxyError::InvalidProxy(found) if proxy.as_ref() == Some(&found) => {
+                    ("FEDIBRIDGE_PROXY", found)
+                }
+                _ => ("FEDIBRIDGE_PROXY_RULES", proxy_rules.clone()),
+            };
+            ConfigError::Invalid { var, found }
+        })?;
+        let schemes = list("FEDIBRIDGE_EGRESS_SCHEMES");
+        if let Some(scheme) = schemes
+            .iter()
+            .find(|s| !matches!(s.as_str(), "http" | "https"))
+        {
+            return Err(ConfigError::Invalid {
+                var: "FEDIBRIDGE_EGRESS_SCHEMES",
+                found: scheme.clone(),
+            });
+        }
+        let ports = list("FEDIBRIDGE_EGRESS_PORTS").into_iter().map(|port| {
+            port.parse().map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_EGRESS_PORTS",
+                found: port,
+            })
+        });
+        let ports = ports.collect::<Result<Vec<u16>, _>>()?;
+        let allowed_hosts = list("FEDIBRIDGE_EGRESS_ALLOWED_HOSTS").into_iter();
+        let egress = EgressPolicy {
+            schemes: if schemes.is_empty() {
+                defaults.egress.schemes
+            } else {
+                schemes
+            },
+            ports: if ports.is_empty() {
+                defaults.egress.ports
+            } else {
+                ports
+            },
+            max_redirects: number(
+                &lookup,
+                "FEDIBRIDGE_EGRESS_MAX_REDIRECTS",
+                defaults.egress.max_redirects,
+            )?,
+            allowed_hosts: allowed_hosts
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+        };
+        let breakers = BreakerConfig {
+            threshold: number(
+                &lookup,
+                "FEDIBRIDGE_BREAKER_THRESHOLD",
+                defaults.breakers.threshold,
+            )?,
+            cooldown: seconds(
+                "FEDIBRIDGE_BREAKER_COOLDOWN_SECS",
+                defaults.breakers.cooldown,
+            )?,
+        };
+        let concurrency = ConcurrencyConfig {
+            min: number(
+                &lookup,
+                "FEDIBRIDGE_DELIVERY_WORKERS_MIN",
+                defaults.concurrency.min,
+            )?,
+            max: number(
+                &lookup,
+                "FEDIBRIDGE_DELIVERY_WORKERS_MAX",
+                defaults.concurrency.max,
+            )?,
+            host_max: number(
+                &lookup,
+                "FEDIBRIDGE_DELIVERY_HOST_MAX",
+                defaults.concurrency.host_max,
+            )?,
+            target_latency: seconds(
+                "FEDIBRIDGE_DELIVERY_TARGET_LATENCY_SECS",
+                defaults.concurrency.target_latency,
+            )?,
+        };
+        // A threshold of zero means that shedding is off
+        let threshold = |var| {
+            let lag = number(&lookup, var, 0)?;
+            Ok::<_, ConfigError>(Some(lag).filter(|&lag| lag > 0))
+        };
+        let shedding = ShedConfig {
+            unbridged_lag: threshold("FEDIBRIDGE_SHED_UNBRIDGED_LAG")?,
+            defer_lag: threshold("FEDIBRIDGE_SHED_DEFER_LAG")?,
+            essential_lag: threshold("FEDIBRIDGE_SHED_ESSENTIAL_LAG")?,
+            defer_capacity: number(
+                &lookup,
+                "FEDIBRIDGE_SHED_DEFER_CAPACITY",
+                defaults.shedding.defer_capacity,
+            )?,
+        };
+        let plc_directories = list("FEDIBRIDGE_PLC_DIRECTORIES");
+        let upstreams = UpstreamConfig {
+            relays: list("FEDIBRIDGE_FIREHOSE_RELAYS"),
+            plc_directories: match plc_directories.is_empty() {
+                true => defaults.upstreams.plc_directories,
+                false => plc_directories,
+            },
+            check_interval: seconds(
+                "FEDIBRIDGE_UPSTREAM_CHECK_SECS",
+                defaults.upstreams.check_interval,
+            )?,
+        };
+        let signature_default = SignaturePolicy {
+            max_skew: seconds(
+                "FEDIBRIDGE_SIGNATURE_MAX_SKEW_SECS",
+                defaults.signatures.default.max_skew,
+            )?,
+            require_digest: flag(
+                "FEDIBRIDGE_REQUIRE_DIGEST",
+                defaults.signatures.default.require_digest,
+            )?,
+            replay_window: seconds(
+                "FEDIBRIDGE_SIGNATURE_REPLAY_SECS",
+                defaults.signatures.default.replay_window,
+            )?,
+        };
+        let classes = lookup("FEDIBRIDGE_SIGNATURE_CLASSES").unwrap_or_default();
+        let class_domains = lookup("FEDIBRIDGE_SIGNATURE_CLASS_DOMAINS").unwrap_or_default();
+        // Classes are checked alone first, to blame the right variable
+        SignaturePolicies::parse(signature_default, &classes, "").map_err(|found| {
+            ConfigError::Invalid {
+                var: "FEDIBRIDGE_SIGNATURE_CLASSES",
+                found,
+            }
+        })?;
+        let signatures = SignaturePolicies::parse(signature_default, &classes, &class_domains)
+            .map_err(|found| ConfigError::Invalid {
+                var: "FEDIBRIDGE_SIGNATURE_CLASS_DOMAINS",
+                found,
+            })?;
+        let servers = list("FEDIBRIDGE_DNS_SERVERS");
+        let servers = servers.into_iter().map(|server| {
+            // Without a port, DNS's own
+            let with_port = match server.parse::<std::net::IpAddr>() {
+                Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+                Err(_) => server.parse(),
+            };
+            with_port.map_err(|_| ConfigError::Invalid {
+                var: "FEDIBRIDGE_DNS_SERVERS",
+                found: server,
+            })
+        });
+        let servers = servers.collect::<Result<Vec<SocketAddr>, _>>()?;
+        let upstream = match (servers.is_empty(), nonempty("FEDIBRIDGE_DNS_OVER_HTTPS")) {
+            (true, None) => defaults.dns.upstream.clone(),
+            (false, None) => Upstream::Servers(servers),
+            (true, Some(url)) => Upstream::OverHttps(url),
+            // Only one of them can be asked
+            (false, Some(url)) => {
+                return Err(ConfigError::Invalid {
+                    var: "FEDIBRIDGE_DNS_OVER_HTTPS",
+                    found: url,
+                })
+            }
+        };
+        let attempt_delay = number(
+            &lookup,
+            "FEDIBRIDGE_HAPPY_EYEBALLS_MS",
+            defaults
+                .dns
+                .attempt_delay
+                .map_or(0, |delay| delay.as_millis() as u64),
+        )?;
+        let dns = DnsConfig {
+            upstream,
+            min_ttl: seconds("FEDIBRIDGE_DNS_MIN_TTL_SECS", defaults.dns.min_ttl)?,
+            max_ttl: seconds("FEDIBRIDGE_DNS_MAX_TTL_SECS", defaults.dns.max_ttl)?,
+            prefer: match nonempty("FEDIBRIDGE_DNS_PREFER") {
+                Some(prefer) => AddressPreference::parse(&prefer).ok_or(ConfigError::Invalid {
+                    var: "FEDIBRIDGE_DNS_PREFER",
+                    found: prefer,
+                })?,
+                None => defaults.dns.prefer,
+            },
+            // Zero tries one address at a time
+            attempt_delay: Some(Duration::from_millis(attempt_delay)).filter(|d| !d.is_zero()),
+        };
+        // As with TLS, a user is no use without their password
+        let credentials = match (
+            nonempty("FEDIBRIDGE_ALERT_SMTP_USER"),
+            nonempty("FEDIBRIDGE_ALERT_SMTP_PASSWORD"),
+        ) {
+            (Some(user), Some(password)) => Some((user, password)),
+            (None, None) => None,
+            (user, _) => {
+                return Err(ConfigError::Invalid {
+                    var: match user {
+                        Some(_) => "FEDIBRIDGE_ALERT_SMTP_PASSWORD",
+                        None => "FEDIBRIDGE_ALERT_SMTP_USER",
+                    },
+                    found: String::new(),
+                })
+            }
+        };
+        let smtp = nonempty("FEDIBRIDGE_ALERT_SMTP").map(|server| SmtpConfig {
+            // Without a port, SMTP's own
+            server: match server.rsplit_once(':') {
+                Some((_, port)) if port.parse::<u16>().is_ok() => server,
+                _ => format!("{server}:25"),
+            },
+            helo: nonempty("FEDIBRIDGE_HOSTNAME").unwrap_or_else(|| "localhost".to_string()),
+            credentials,
+        });
+        // Zero turns a check off
+        let failure_percent = number(
+            &lookup,
+            "FEDIBRIDGE_ALERT_FAILURE_PERCENT",
+            defaults.alerts.failure_percent.unwrap_or_default(),
+        )?;
+        let firehose_silence = seconds(
+            "FEDIBRIDGE_FIREHOSE_SILENCE_SECS",
+            defaults.alerts.firehose_silence.unwrap_or_default(),
+        )?;
+        let var = "FEDIBRIDGE_KEY_MAX_AGE_DAYS";
+        let days = number(&lookup, var, 0u64)?;
+        let key_max_age = days.checked_mul(24 * 60 * 60).map(Duration::from_secs);
+        let key_max_age = key_max_age.ok_or_else(|| ConfigError::Invalid {
+            var,
+            found: days.to_string(),
+        })?;
+        let alerts = AlertConfig {
+            smtp,
+            from: nonempty("FEDIBRIDGE_ALERT_FROM").unwrap_or(defaults.alerts.from.clone()),
+            to: list("FEDIBRIDGE_ALERT_TO"),
+            subject: nonempty("FEDIBRIDGE_ALERT_SUBJECT")
+                .unwrap_or(defaults.alerts.subject.clone()),
+            min_interval: seconds(
+                "FEDIBRIDGE_ALERT_INTERVAL_SECS",
+                defaults.alerts.min_interval,
+            )?,
+            key_max_age: Some(key_max_age).filter(|age| !age.is_zero()),
+            failure_percent: Some(failure_percent).filter(|percent| *percent > 0),
+            firehose_silence: Some(firehose_silence).filter(|silence| !silence.is_zero()),
+            ..defaults.alerts.clone()
+        };
+        Ok(Config {
+            listen,
+            hostname: nonempty("FEDIBRIDGE_HOSTNAME"),
+            admin,
+            state_dir,
+            shutdown_timeout,
+            keystore_passphrase,
+            shard,
+            firehose_filter,
+            moderation,
+            label_policy,
+            max_clock_skew,
+            parsing,
+            link_cards,
+            articles,
+            attribution: AttributionConfig {
+                to_fediverse: nonempty("FEDIBRIDGE_ATTRIBUTION_TO_FEDIVERSE"),
+                to_bluesky: nonempty("FEDIBRIDGE_ATTRIBUTION_TO_BLUESKY"),
+            },
+            html_profile,
+            reply_context,
+            templates: match nonempty("FEDIBRIDGE_DM_BOUNCE_MESSAGE") {
+                Some(message) => defaults.templates.with(Message::DmBounce, None, &message),
+                None => defaults.templates,
+            },
+            templates_file: nonempty("FEDIBRIDGE_TEMPLATES_FILE").map(PathBuf::from),
+            reactions,
+            images,
+            dms,
+            reply_gates: ReplyGatePolicy {
+                enforce: flag("FEDIBRIDGE_REPLY_GATES", defaults.reply_gates.enforce)?,
+                bounce: flag("FEDIBRIDGE_REPLY_BOUNCE", defaults.reply_gates.bounce)?,
+            },
+            digests,
+            reconcile: ReconcileConfig {
+                enabled: flag("FEDIBRIDGE_RECONCILE", defaults.reconcile.enabled)?,
+                interval: seconds(
+                    "FEDIBRIDGE_RECONCILE_INTERVAL_SECS",
+                    defaults.reconcile.interval,
+                )?,
+            },
+            quotas,
+            orphan_window: seconds("FEDIBRIDGE_ORPHAN_WINDOW_SECS", defaults.orphan_window)?,
+            retraction_window: seconds(
+                "FEDIBRIDGE_RETRACTION_WINDOW_SECS",
+                defaults.retraction_window,
+            )?,
+            cache,
+            retention,
+            media_cdn: nonempty("FEDIBRIDGE_MEDIA_CDN_URL")
+                .map(|cdn| cdn.trim_end_matches('/').to_string()),
+            about: AboutConfig {
+                contact: nonempty("FEDIBRIDGE_CONTACT"),
+                opt_in: nonempty("FEDIBRIDGE_OPT_IN_INSTRUCTIONS"),
+                documentation: nonempty("FEDIBRIDGE_ABOUT_URL"),
+            },
+            rate_limits,
+            inbox_limits,
+            policy,
+            content_filters,
+            key_cache_ttl: seconds("FEDIBRIDGE_KEY_CACHE_TTL_SECS", defaults.key_cache_ttl)?,
+            key_overlap: seconds("FEDIBRIDGE_KEY_OVERLAP_SECS", defaults.key_overlap)?,
+            signatures,
+            connections,
+            tls,
+            proxies,
+            egress,
+            breakers,
+            concurrency,
+            shedding,
+            upstreams,
+            dns,
+            job_capacity: number(
+                &lookup,
+                "FEDIBRIDGE_JOB_QUEUE_CAPACITY",
+                defaults.job_capacity,
+            )?,
+            webhooks,
+            alerts,
+            alert_template: nonempty("FEDIBRIDGE_ALERT_TEMPLATE").map(PathBuf::from),
+            crawl,
+            oauth,
+            terms,
+            require_approval: flag("FEDIBRIDGE_REQUIRE_APPROVAL", defaults.require_approval)?,
+            feeds,
+            labeler: flag("FEDIBRIDGE_LABELER", defaults.labeler)?,
+            dry_run: flag("FEDIBRIDGE_DRY_RUN", defaults.dry_run)?,
+            archive_events: flag("FEDIBRIDGE_ARCHIVE_EVENTS", defaults.archive_events)?,
+            other_bridges,
+            community_strategy,
+            mirror_to,
+            identity,
+            peering,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_disabled_without_token() {
+        assert_eq!(Config::from_vars(|_| None), Ok(Config::default()));
+    }
+
+    #[test]
+    fn invalid_listen_address() {
+        let config = Config::from_vars(|var| match var {
+            "FEDIBRIDGE_ADMIN_TOKEN" => Some("secret".to_string()),
+            "FEDIBRIDGE_ADMIN_LISTEN" => Some("nowhere".to_string()),
+            _ => None,
+        });
+        assert!(matches!(config, Err(ConfigError::Invalid { .. })));
+    }
+
+    #[test]
+    fn shard() {
+        let config =
+            Config::from_vars(|var| (var == "FEDIBRIDGE_SHARD").then(|| "2/3".to_string()));
+        assert_eq!(config.unwrap().shard, Shard::new(2, 3).unwrap());
+        let config =
+            Config::from_vars(|var| (var == "FEDIBRIDGE_SHARD").then(|| "3/3".to_string()));
+        assert!(matches!(config, Err(ConfigError::Invalid { .. })));
+    }
+
+    #[test]
+    fn key_max_age() {
+        let days = |days: &str| {
+            let days = days.to_string();
+            Config::from_vars(move |var| {
+                (var == "FEDIBRIDGE_KEY_MAX_AGE_DAYS").then(|| days.clone())
+            })
+        };
+        let week = Duration::from_secs(7 * 24 * 60 * 60);
+        assert_eq!(days("7").unwrap().alerts.key_max_age, Some(week));
+        assert!(matches!(
+            days(&u64::MAX.to_string()),
+            Err(ConfigError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn firehose_filter() {
+        let config = Config::from_vars(|var| {
+            (var == "FEDIBRIDGE_FIREHOSE_FILTER").then(|| "tracked or action:delete".to_string())
+        });
+        assert_eq!(
+            config.unwrap().firehose_filter,
+            "tracked or action:delete".parse().unwrap()
+        );
+        let config = Config::from_vars(|var| {
+            (var == "FEDIBRIDGE_FIREHOSE_FILTER").then(|| "tracked or".to_string())
+        });
+        assert!(matches!(config, Err(ConfigError::Filter(_))));
+    }
+
+    #[test]
+    fn webhooks() {
+        let config = Config::from_vars(|var| match var {
+            "FEDIBRIDGE_WEBHOOKS" => Some("https://a.example/hook, https://b.example/".into()),
+            "FEDIBRIDGE_WEBHOOK_EVENTS" => Some("optIn,firehoseLag".into()),
+            "FEDIBRIDGE_FIREHOSE_LAG_ALERT" => Some("0".into()),
+            _ => None,
+        });
+        let webhooks = config.unwrap().webhooks;
+        assert_eq!(webhooks.urls.len(), 2);
+        assert_eq!(webhooks.events, [EventKind::OptIn, EventKind::FirehoseLag]);
+        assert_eq!(webhooks.lag_threshold, None);
+        let config = Config::from_vars(|var| {
+            (var == "FEDIBRIDGE_WEBHOOK_EVENTS").then(|| "optIn,everything".to_string())
+        });
+        assert!(matches!(config, Err(ConfigError::Invalid { .. })));
+    }
+}
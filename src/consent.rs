@@ -0,0 +1,327 @@
+//! Consent to the bridge's terms
+//!
+//! With [`Terms`] configured, an account is only bridged once it has agreed to their current
+//! version. Each agreement is kept as a [`Consent`] with the exact text agreed to, so what
+//! someone agreed to can be shown after the terms have changed. Publishing a new version
+//! requires everyone to agree again: until they do, nothing of theirs is bridged in either
+//! direction, except deletions so nothing lingers on the other network.
+//!
+//! The history is appended to a log at the root of the state directory, as every shard
+//! shares it
+
+use crate::delivery::Delivery;
+use crate::json::{self, Value};
+use crate::normalize;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// The consents' file in the state directory, one JSON consent per line
+pub const CONSENTS_FILE: &str = "consents.jsonl";
+/// Where every account's history was saved as a single JSON object, before [`CONSENTS_FILE`]
+const OLD_CONSENTS_FILE: &str = "consents.json";
+
+#[derive(Debug, Clone, PartialEq)]
+/// The terms people agree to before being bridged
+pub struct Terms {
+    /// Changes whenever the text does
+    pub version: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An account's agreement to a version of the terms
+pub struct Consent {
+    pub version: String,
+    /// The text as it was when they agreed
+    pub text: String,
+    pub at: SystemTime,
+    /// Where an agreement given outside the bridge can be seen, for accounts an operator
+    /// [enrolled](crate::enroll) on their behalf
+    pub proof: Option<String>,
+}
+
+impl Consent {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("version", Value::from(self.version.as_str())),
+            ("text", Value::from(self.text.as_str())),
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("proof", Value::from(self.proof.clone())),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Consent> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(Consent {
+            version: field("version")?.to_string(),
+            text: field("text")?.to_string(),
+            at: parse_rfc3339(field("at")?).ok()?,
+            proof: field("proof").map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where an account stands with the terms
+pub enum ConsentState {
+    /// No terms are configured
+    NotRequired,
+    /// They've agreed to the current version
+    Current,
+    /// They agreed to an older version, and need to agree again
+    Outdated { agreed: String },
+    /// They've never agreed
+    Missing,
+}
+
+impl ConsentState {
+    /// Whether the account can be bridged
+    pub fn allows_bridging(&self) -> bool {
+        matches!(self, ConsentState::NotRequired | ConsentState::Current)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsentState::NotRequired => "notRequired",
+            ConsentState::Current => "current",
+            ConsentState::Outdated { .. } => "outdated",
+            ConsentState::Missing => "missing",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors recording consent
+pub enum ConsentError {
+    #[error("No terms are configured")]
+    NoTerms,
+    #[error("The current terms are version {current}, not {found}")]
+    Outdated { current: String, found: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Default)]
+/// Every agreement to the terms, oldest first for each account
+pub struct ConsentLog {
+    consents: RwLock<HashMap<Did, Vec<Consent>>>,
+    log: Option<JsonLog>,
+}
+
+impl ConsentLog {
+    /// A log persisted in `dir`, with the consents already there
+    ///
+    /// Histories saved in the old single `consents.json` are moved into the log
+    pub fn open(dir: StateDir) -> io::Result<ConsentLog> {
+        let (log, lines) = JsonLog::open(dir.clone(), CONSENTS_FILE)?;
+        let mut consents: HashMap<Did, Vec<Consent>> = HashMap::new();
+        for line in &lines {
+            let did = line.get("did").and_then(Value::as_str);
+            let Some(Ok(did)) = did.map(normalize::parse_did) else {
+                continue;
+            };
+            if let Some(consent) = Consent::from_json(line) {
+                consents.entry(did).or_default().push(consent);
+            }
+        }
+        if let Some(contents) = dir.read(OLD_CONSENTS_FILE)? {
+            let old = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let accounts = old.get("accounts").and_then(Value::as_array);
+            for account in accounts.unwrap_or_default() {
+                let did = account.get("did").and_then(Value::as_str);
+                let Some(Ok(did)) = did.map(normalize::parse_did) else {
+                    continue;
+                };
+                let history = account.get("consents").and_then(Value::as_array);
+                let history = history.unwrap_or_default().iter();
+                let history: Vec<Consent> = history.filter_map(Consent::from_json).collect();
+                consents.entry(did).or_default().splice(0..0, history);
+            }
+            let histories = consents
+                .iter()
+                .flat_map(|(did, history)| history.iter().map(move |consent| line(did, consent)));
+            log.rewrite(histories)?;
+            dir.remove(OLD_CONSENTS_FILE)?;
+        }
+        Ok(ConsentLog {
+            consents: RwLock::new(consents),
+            log: Some(log),
+        })
+    }
+
+    pub fn record(&self, did: &Did, consent: Consent) -> io::Result<()> {
+        let mut consents = self.consents.write().unwrap();
+        if let Some(log) = &self.log {
+            log.append([line(did, &consent)])?;
+        }
+        consents.entry(did.clone()).or_default().push(consent);
+        Ok(())
+    }
+
+    /// Everything `did` has agreed to, oldest first
+    pub fn history(&self, did: &Did) -> Vec<Consent> {
+        let consents = self.consents.read().unwrap();
+        consents.get(did).cloned().unwrap_or_default()
+    }
+
+    /// Where `did` stands with `terms`
+    pub fn state(&self, did: &Did, terms: Option<&Terms>) -> ConsentState {
+        let Some(terms) = terms else {
+            return ConsentState::NotRequired;
+        };
+        let consents = self.consents.read().unwrap();
+        match consents.get(did).and_then(|history| history.last()) {
+            Some(consent) if consent.version == terms.version => ConsentState::Current,
+            Some(consent) => ConsentState::Outdated {
+                agreed: consent.version.clone(),
+            },
+            None => ConsentState::Missing,
+        }
+    }
+}
+
+/// The line of the log for `did`'s agreement `consent`
+fn line(did: &Did, consent: &Consent) -> Value {
+    let mut line = consent.to_json();
+    if let Value::Object(fields) = &mut line {
+        fields.insert("did".to_string(), Value::from(did.as_str()));
+    }
+    line
+}
+
+/// Where `did` stands with the bridge's terms, and everything it's agreed to
+pub fn status_json(bridge: &crate::bridge::Bridge, did: &Did) -> Value {
+    let history = bridge.consents.history(did);
+    Value::object([
+        ("state", Value::from(bridge.consent_state(did).as_str())),
+        (
+            "currentVersion",
+            Value::from(bridge.terms.as_ref().map(|terms| terms.version.as_str())),
+        ),
+        (
+            "consents",
+            Value::Array(history.iter().map(Consent::to_json).collect()),
+        ),
+    ])
+}
+
+/// Whether `delivery` can go out as far as its sender's consent goes
+///
+/// Only bridged accounts' deliveries need it, and deletions always go
+pub fn delivery_allowed(bridge: &crate::bridge::Bridge, delivery: &Delivery) -> bool {
+    let Ok(activity) = json::parse(&delivery.activity) else {
+        return true;
+    };
+    if activity.get("type").and_then(Value::as_str) == Some("Delete") {
+        return true;
+    }
+    let actor = activity.get("actor").and_then(Value::as_str);
+    match actor.and_then(|actor| bridge.identities.get_by_actor(actor)) {
+        Some(mapping) => !bridge.awaiting_consent(&mapping.did),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    fn terms(version: &str) -> Terms {
+        Terms {
+            version: version.to_string(),
+            text: format!("Terms {version}"),
+        }
+    }
+
+    #[test]
+    fn new_terms_need_agreeing_to_again() {
+        let dir = temp_state_dir();
+        let log = ConsentLog::open(dir.clone()).unwrap();
+        let v1 = terms("1");
+        assert_eq!(log.state(&ALICE, None), ConsentState::NotRequired);
+        assert_eq!(log.state(&ALICE, Some(&v1)), ConsentState::Missing);
+        let consent = Consent {
+            version: v1.version.clone(),
+            text: v1.text.clone(),
+            at: SystemTime::now(),
+            proof: None,
+        };
+        log.record(&ALICE, consent).unwrap();
+        assert_eq!(log.state(&ALICE, Some(&v1)), ConsentState::Current);
+
+        // Histories in the old single file are moved into the log
+        let bob = did!("did:plc:bob");
+        let old = format!(
+            r#"{{"accounts": [{{"did": "{bob}", "consents": [{}]}}]}}"#,
+            log.history(&ALICE)[0].to_json()
+        );
+        dir.write(OLD_CONSENTS_FILE, old.as_bytes()).unwrap();
+        ConsentLog::open(dir.clone()).unwrap();
+        assert_eq!(dir.read(OLD_CONSENTS_FILE).unwrap(), None);
+
+        let log = ConsentLog::open(dir).unwrap();
+        assert_eq!(log.state(&bob, Some(&v1)), ConsentState::Current);
+        let state = log.state(&ALICE, Some(&terms("2")));
+        let outdated = ConsentState::Outdated {
+            agreed: "1".to_string(),
+        };
+        assert_eq!(state, outdated);
+        assert!(!state.allows_bridging());
+        assert_eq!(log.history(&ALICE)[0].text, "Terms 1");
+    }
+
+    #[test]
+    fn bridging_waits_for_consent() {
+        use crate::bridge::Bridge;
+        use crate::repo::tests::HashSigner;
+        use crate::repo::{RepoError, Write};
+        use crate::store::Mapping;
+
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_terms(terms("2"));
+        let actor = "https://a.example/users/alice";
+        bridge.identities.insert(Mapping::new(ALICE, actor));
+        let generator = crate::keys::tests::FakeGenerator::default();
+        let owner = crate::keys::KeyOwner::Account(ALICE);
+        let purpose = crate::keys::KeyPurpose::RepoSigning;
+        bridge.keys.ensure(&owner, purpose, &generator).unwrap();
+        let post = |path: &str| Write::Create {
+            path: path.to_string(),
+            record: Value::object([("text", Value::from("hi"))]),
+        };
+
+        assert!(bridge.awaiting_consent(&ALICE));
+        let refused = bridge.commit(&ALICE, &[post("app.bsky.feed.post/1")]);
+        assert!(matches!(refused, Err(RepoError::NotConsented { .. })));
+        let create = format!(r#"{{"type": "Create", "actor": "{actor}"}}"#);
+        let delete = format!(r#"{{"type": "Delete", "actor": "{actor}"}}"#);
+        let inbox = "https://b.example/inbox";
+        assert!(!delivery_allowed(
+            &bridge,
+            &Delivery::new(inbox, create.clone())
+        ));
+        assert!(delivery_allowed(&bridge, &Delivery::new(inbox, delete)));
+
+        let outdated = bridge.consent(&ALICE, "1");
+        assert!(matches!(outdated, Err(ConsentError::Outdated { .. })));
+        assert_eq!(bridge.consent(&ALICE, "2").unwrap().text, "Terms 2");
+        assert!(!bridge.awaiting_consent(&ALICE));
+        assert!(bridge
+            .commit(&ALICE, &[post("app.bsky.feed.post/1")])
+            .is_ok());
+        assert!(delivery_allowed(&bridge, &Delivery::new(inbox, create)));
+    }
+}
@@ -0,0 +1,392 @@
+//! Content filters run on posts before they're bridged
+//!
+//! A [`ContentFilter`] sees each post as a [`Post`]: its text, the links in it and how many
+//! accounts and tags it mentions, whichever network it came from. Any filter can refuse a
+//! post, and refused posts aren't bridged. Built in are [`KeywordFilter`],
+//! [`LinkDomainFilter`] and [`SpamHeuristics`], configured through a
+//! [`ContentFilterConfig`]; operators can compile in their own by implementing the trait and
+//! adding them with [`Bridge::with_content_filters`].
+//!
+//! Posts from bridged Bluesky accounts are checked as their deliveries are sent
+//! ([`check_delivery`])
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::json::{self, Value};
+use crate::modstream::EventKind;
+use crate::richtext::{self, Feature, LINK, MENTION, TAG};
+use crate::url::Url;
+use crate::webhooks::{self, WebhookEvent};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What filters see of a post
+pub struct Post {
+    /// The author's actor ID or DID
+    pub author: String,
+    /// Plain text, including any content warning
+    pub text: String,
+    pub links: Vec<String>,
+    pub mentions: usize,
+    pub hashtags: usize,
+}
+
+impl Post {
+    /// An ActivityPub object, such as a `Note`
+    pub fn from_object(author: &str, object: &Value) -> Post {
+        let field = |name| object.get(name).and_then(Value::as_str).unwrap_or_default();
+        let rich = richtext::from_html(field("content"));
+        let text = match field("summary") {
+            "" => rich.text,
+            summary => format!("{summary}\n\n{}", rich.text),
+        };
+        let tags = object
+            .get("tag")
+            .and_then(Value::as_array)
+            .unwrap_or_default();
+        let count = |kind| {
+            let is_kind = |tag: &&Value| tag.get("type").and_then(Value::as_str) == Some(kind);
+            tags.iter().filter(is_kind).count()
+        };
+        Post {
+            author: author.to_string(),
+            text,
+            links: rich
+                .facets
+                .into_iter()
+                .filter_map(|facet| match facet.feature {
+                    Feature::Link { uri } => Some(uri),
+                    Feature::Mention { .. } | Feature::Tag { .. } => None,
+                })
+                .collect(),
+            mentions: count("Mention"),
+            hashtags: count("Hashtag"),
+        }
+    }
+
+    /// An `app.bsky.feed.post` record
+    pub fn from_record(author: &str, record: &Value) -> Post {
+        let mut post = Post {
+            author: author.to_string(),
+            text: record
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            ..Post::default()
+        };
+        let facets = record
+            .get("facets")
+            .and_then(Value::as_array)
+            .unwrap_or_default();
+        let features = facets
+            .iter()
+            .filter_map(|facet| facet.get("features")?.as_array())
+            .flatten();
+        for feature in features {
+            match feature.get("$type").and_then(Value::as_str) {
+                Some(LINK) => post.links.extend(
+                    feature
+                        .get("uri")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                ),
+                Some(MENTION) => post.mentions += 1,
+                Some(TAG) => post.hashtags += 1,
+                _ => {}
+            }
+        }
+        let external = record
+            .get("embed")
+            .and_then(|embed| embed.get("external"))
+            .and_then(|external| external.get("uri"))
+            .and_then(Value::as_str);
+        post.links.extend(external.map(str::to_string));
+        post
+    }
+}
+
+/// Something deciding whether posts may be bridged
+pub trait ContentFilter: Send + Sync {
+    /// Identifies the filter when it refuses a post
+    fn name(&self) -> &str;
+
+    /// Why `post` mustn't be bridged, or `None` if it may be
+    fn reject(&self, post: &Post) -> Option<String>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Why a post was refused, and by which filter
+pub struct Rejection {
+    pub filter: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} refused the post: {}", self.filter, self.reason)
+    }
+}
+
+/// Run `post` past each of `filters`, stopping at the first to refuse it
+pub fn check(filters: &[Arc<dyn ContentFilter>], post: &Post) -> Result<(), Rejection> {
+    for filter in filters {
+        if let Some(reason) = filter.reject(post) {
+            return Err(Rejection {
+                filter: filter.name().to_string(),
+                reason,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Refuses posts containing any of a list of words or phrases, ignoring case
+pub struct KeywordFilter {
+    keywords: Vec<String>,
+}
+
+impl KeywordFilter {
+    pub fn new(keywords: impl IntoIterator<Item = impl Into<String>>) -> KeywordFilter {
+        KeywordFilter {
+            keywords: keywords
+                .into_iter()
+                .map(|k| k.into().to_lowercase())
+                .collect(),
+        }
+    }
+}
+
+/// Whether `phrase` appears in `text` on word boundaries
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    text.match_indices(phrase).any(|(at, _)| {
+        !is_word(text[..at].chars().next_back())
+            && !is_word(text[at + phrase.len()..].chars().next())
+    })
+}
+
+impl ContentFilter for KeywordFilter {
+    fn name(&self) -> &str {
+        "keywords"
+    }
+
+    fn reject(&self, post: &Post) -> Option<String> {
+        let text = post.text.to_lowercase();
+        let keyword = self
+            .keywords
+            .iter()
+            .find(|keyword| !keyword.is_empty() && contains_phrase(&text, keyword))?;
+        Some(format!("contains {keyword:?}"))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Refuses posts linking to any of a list of domains, or their subdomains
+pub struct LinkDomainFilter {
+    domains: Vec<String>,
+}
+
+impl LinkDomainFilter {
+    pub fn new(domains: impl IntoIterator<Item = impl Into<String>>) -> LinkDomainFilter {
+        LinkDomainFilter {
+            domains: domains
+                .into_iter()
+                .map(|d| d.into().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+}
+
+impl ContentFilter for LinkDomainFilter {
+    fn name(&self) -> &str {
+        "link domains"
+    }
+
+    fn reject(&self, post: &Post) -> Option<String> {
+        post.links.iter().find_map(|link| {
+            let host = Url::parse(link).ok()?.host;
+            self.domains
+                .iter()
+                .any(|domain| {
+                    host == *domain
+                        || host
+                            .strip_suffix(domain.as_str())
+                            .is_some_and(|sub| sub.ends_with('.'))
+                })
+                .then(|| format!("links to {host}"))
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Refuses posts stuffed with links, mentions or hashtags, as spam tends to be
+pub struct SpamHeuristics {
+    pub max_links: usize,
+    pub max_mentions: usize,
+    pub max_hashtags: usize,
+}
+
+impl Default for SpamHeuristics {
+    fn default() -> Self {
+        SpamHeuristics {
+            max_links: 5,
+            max_mentions: 10,
+            max_hashtags: 15,
+        }
+    }
+}
+
+impl ContentFilter for SpamHeuristics {
+    fn name(&self) -> &str {
+        "spam heuristics"
+    }
+
+    fn reject(&self, post: &Post) -> Option<String> {
+        let over = |what, count: usize, max| (count > max).then(|| format!("{count} {what}"));
+        over("links", post.links.len(), self.max_links)
+            .or_else(|| over("mentions", post.mentions, self.max_mentions))
+            .or_else(|| over("hashtags", post.hashtags, self.max_hashtags))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Which built-in filters are enabled
+pub struct ContentFilterConfig {
+    pub keywords: Vec<String>,
+    pub link_domains: Vec<String>,
+    pub spam: Option<SpamHeuristics>,
+}
+
+impl ContentFilterConfig {
+    /// The enabled filters
+    pub fn filters(&self) -> Vec<Arc<dyn ContentFilter>> {
+        let mut filters: Vec<Arc<dyn ContentFilter>> = Vec::new();
+        if !self.keywords.is_empty() {
+            filters.push(Arc::new(KeywordFilter::new(self.keywords.clone())));
+        }
+        if !self.link_domains.is_empty() {
+            filters.push(Arc::new(LinkDomainFilter::new(self.link_domains.clone())));
+        }
+        if let Some(spam) = &self.spam {
+            filters.push(Arc::new(spam.clone()));
+        }
+        filters
+    }
+}
+
+/// Whether the bridge's filters let a delivery's post through, or which refused it
+///
+/// Only deliveries creating or updating an object are checked; everything else passes
+pub fn check_delivery(bridge: &Bridge, delivery: &Delivery) -> Result<(), Rejection> {
+    if bridge.content_filters.is_empty() {
+        return Ok(());
+    }
+    let Ok(activity) = json::parse(&delivery.activity) else {
+        return Ok(());
+    };
+    let kind = activity.get("type").and_then(Value::as_str);
+    let (Some("Create" | "Update"), Some(actor), Some(object)) = (
+        kind,
+        activity.get("actor").and_then(Value::as_str),
+        activity
+            .get("object")
+            .filter(|o| o.get("content").is_some()),
+    ) else {
+        return Ok(());
+    };
+    match check(&bridge.content_filters, &Post::from_object(actor, object)) {
+        Ok(()) => Ok(()),
+        Err(rejection) => {
+            let id = object.get("id").and_then(Value::as_str);
+            eprintln!("Not bridging {}: {rejection}", id.unwrap_or(actor));
+            let details = Value::object([
+                ("object", Value::from(id)),
+                ("filter", Value::from(rejection.filter.as_str())),
+                ("reason", Value::from(rejection.reason.as_str())),
+            ]);
+            let events = &bridge.moderation_events;
+            events.publish(EventKind::PolicyHit, actor, details);
+            let event = WebhookEvent::PolicyViolation {
+                author: actor.to_string(),
+                object: id.map(str::to_string),
+                filter: rejection.filter.clone(),
+                reason: rejection.reason.clone(),
+            };
+            webhooks::notify(bridge, event);
+            Err(rejection)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(text: &str, links: &[&str]) -> Post {
+        Post {
+            author: "https://a.example/users/alice".to_string(),
+            text: text.to_string(),
+            links: links.iter().map(|l| l.to_string()).collect(),
+            ..Post::default()
+        }
+    }
+
+    #[test]
+    fn built_in_filters() {
+        let keywords = KeywordFilter::new(["crypto giveaway", "scam"]);
+        assert!(keywords
+            .reject(&post("Huge CRYPTO giveaway!", &[]))
+            .is_some());
+        assert!(keywords.reject(&post("Scamp the dog", &[])).is_none());
+        let domains = LinkDomainFilter::new(["spam.example"]);
+        let spam = post("look", &["https://www.spam.example/offer"]);
+        assert_eq!(
+            domains.reject(&spam),
+            Some("links to www.spam.example".to_string())
+        );
+        assert!(domains
+            .reject(&post("look", &["https://notspam.example"]))
+            .is_none());
+        let heuristics = SpamHeuristics {
+            max_links: 1,
+            ..SpamHeuristics::default()
+        };
+        let links = post("", &["https://a.example", "https://b.example"]);
+        assert_eq!(heuristics.reject(&links), Some("2 links".to_string()));
+
+        let filters: Vec<Arc<dyn ContentFilter>> = vec![Arc::new(keywords), Arc::new(domains)];
+        assert_eq!(
+            check(&filters, &spam),
+            Err(Rejection {
+                filter: "link domains".to_string(),
+                reason: "links to www.spam.example".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn posts_from_either_network() {
+        let object = json::parse(
+            r##"{"type": "Note", "summary": "cw",
+                "content": "<p>hi <a href=\"https://x.example/a\">x</a> #tag</p>",
+                "tag": [{"type": "Hashtag", "name": "#tag"}, {"type": "Mention"}]}"##,
+        );
+        let post = Post::from_object("https://a.example/users/alice", &object.unwrap());
+        assert_eq!(post.text, "cw\n\nhi x #tag");
+        assert_eq!(post.links, ["https://x.example/a"]);
+        assert_eq!((post.mentions, post.hashtags), (1, 1));
+
+        let record = json::parse(&format!(
+            r#"{{"text": "hi @bob #tag", "facets": [
+                    {{"features": [{{"$type": "{MENTION}", "did": "did:plc:bob"}}]}},
+                    {{"features": [{{"$type": "{TAG}", "tag": "tag"}}]}}],
+                "embed": {{"external": {{"uri": "https://y.example"}}}}}}"#
+        ));
+        let post = Post::from_record("did:plc:alice", &record.unwrap());
+        assert_eq!(post.links, ["https://y.example"]);
+        assert_eq!((post.mentions, post.hashtags), (1, 1));
+    }
+}
@@ -0,0 +1,237 @@
+//! Asking relays to crawl the bridge
+//!
+//! Relays only subscribe to the PDSes they know of, so once the bridge hosts any repos it
+//! asks each configured relay to crawl its hostname with `com.atproto.sync.requestCrawl`: on
+//! startup, then again every [`CrawlConfig::interval`] in case a relay has forgotten it. A
+//! relay which hasn't acknowledged a request yet is asked again sooner, every
+//! [`CrawlConfig::retry_interval`]. The admin API reports which have
+
+use crate::bridge::Bridge;
+use crate::json::Value;
+use crate::shutdown::Shutdown;
+use crate::time::format_rfc3339;
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+pub const REQUEST_CRAWL: &str = "com.atproto.sync.requestCrawl";
+
+/// How often the relays are checked on
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which relays are asked to crawl the bridge, and how often
+pub struct CrawlConfig {
+    /// Base URLs of the relays, e.g. `https://bsky.network`
+    pub relays: Vec<String>,
+    /// How often a relay which has acknowledged is asked again
+    pub interval: Duration,
+    /// How often a relay which hasn't is asked again
+    pub retry_interval: Duration,
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        CrawlConfig {
+            relays: Vec::new(),
+            interval: Duration::from_secs(6 * 60 * 60),
+            retry_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// How a relay has responded to the bridge's crawl requests
+pub struct RelayStatus {
+    /// When the bridge last asked it to crawl
+    pub requested_at: Option<SystemTime>,
+    /// When it last acknowledged a request
+    pub acknowledged_at: Option<SystemTime>,
+    /// Why the latest request failed, if it did
+    pub last_error: Option<String>,
+}
+
+impl RelayStatus {
+    /// Whether the latest request was acknowledged
+    pub fn acknowledged(&self) -> bool {
+        self.acknowledged_at.is_some() && self.last_error.is_none()
+    }
+
+    /// Whether it's time to ask the relay again
+    fn due(&self, config: &CrawlConfig, now: SystemTime) -> bool {
+        let interval = match self.acknowledged() {
+            true => config.interval,
+            false => config.retry_interval,
+        };
+        self.requested_at
+            .is_none_or(|requested| now.duration_since(requested).unwrap_or_default() >= interval)
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("acknowledged", Value::from(self.acknowledged())),
+            (
+                "requestedAt",
+                Value::from(self.requested_at.map(format_rfc3339)),
+            ),
+            (
+                "acknowledgedAt",
+                Value::from(self.acknowledged_at.map(format_rfc3339)),
+            ),
+            ("lastError", Value::from(self.last_error.clone())),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// What each relay has made of the bridge's crawl requests
+pub struct Relays {
+    statuses: Mutex<HashMap<String, RelayStatus>>,
+}
+
+impl Relays {
+    /// The status of `relay`, which is the default if it was never asked
+    pub fn status(&self, relay: &str) -> RelayStatus {
+        let statuses = self.statuses.lock().unwrap();
+        statuses.get(relay).cloned().unwrap_or_default()
+    }
+
+    fn record(&self, relay: &str, at: SystemTime, result: &Result<(), CrawlError>) {
+        let mut statuses = self.statuses.lock().unwrap();
+        let status = statuses.entry(relay.to_string()).or_default();
+        status.requested_at = Some(at);
+        match result {
+            Ok(()) => {
+                status.acknowledged_at = Some(at);
+                status.last_error = None;
+            }
+            Err(e) => status.last_error = Some(e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors asking a relay to crawl
+pub enum CrawlError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Relay responded with {status}")]
+    Rejected { status: u16 },
+}
+
+/// Ask `relay` to crawl the PDS at `hostname`
+pub fn request_crawl(
+    transport: &dyn HttpTransport,
+    relay: &str,
+    hostname: &str,
+) -> Result<(), CrawlError> {
+    let url = format!("{}/xrpc/{REQUEST_CRAWL}", relay.trim_end_matches('/'));
+    let body = Value::object([("hostname", Value::from(hostname))]).to_string();
+    let request = OutboundRequest::post(&url, body).with_header("content-type", "application/json");
+    let response = transport.send(&request)?;
+    if !response.is_success() {
+        return Err(CrawlError::Rejected {
+            status: response.status,
+        });
+    }
+    Ok(())
+}
+
+/// Ask every relay which is due to crawl `hostname`, returning how many were asked
+///
+/// Nothing is asked until the bridge hosts a repo, as there'd be nothing to crawl
+pub fn request_due(bridge: &Bridge, hostname: &str, now: SystemTime) -> usize {
+    if bridge.repos.is_empty() {
+        return 0;
+    }
+    let due = bridge.crawl.relays.iter();
+    let due = due.filter(|relay| bridge.relays.status(relay).due(&bridge.crawl, now));
+    let mut asked = 0;
+    for relay in due {
+        let result = request_crawl(bridge.transport.as_ref(), relay, hostname);
+        if let Err(e) = &result {
+            eprintln!("Couldn't ask {relay} to crawl: {e}");
+        }
+        bridge.relays.record(relay, now, &result);
+        asked += 1;
+    }
+    asked
+}
+
+/// Start a thread asking relays to crawl `hostname` as they come due, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, hostname: String, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_check = None;
+        while !shutdown.is_requested() {
+            if last_check.is_some_and(|at: Instant| at.elapsed() < CHECK_INTERVAL) {
+                thread::sleep(Duration::from_millis(250));
+                continue;
+            }
+            last_check = Some(Instant::now());
+            request_due(&bridge, &hostname, SystemTime::now());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::json;
+    use crate::repo::tests::{key, HashSigner};
+    use crate::repo::Write;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    const GOOD: &str = "https://relay.example";
+    const BAD: &str = "https://down.example/";
+
+    #[test]
+    fn asks_relays_until_they_acknowledge() {
+        let mock = Arc::new(MockTransport::new());
+        let good = format!("{GOOD}/xrpc/{REQUEST_CRAWL}");
+        let bad = format!("https://down.example/xrpc/{REQUEST_CRAWL}");
+        mock.respond(Method::Post, &good, OutboundResponse::new(200))
+            .respond(Method::Post, &good, OutboundResponse::new(200))
+            .respond(Method::Post, &bad, OutboundResponse::new(503))
+            .respond(Method::Post, &bad, OutboundResponse::new(200));
+        let bridge = Bridge::new()
+            .with_transport(mock.clone())
+            .with_crawl(CrawlConfig {
+                relays: vec![GOOD.to_string(), BAD.to_string()],
+                ..CrawlConfig::default()
+            });
+        let start = SystemTime::now();
+        assert_eq!(request_due(&bridge, "bridge.example", start), 0);
+
+        let write = Write::Create {
+            path: "app.bsky.feed.post/1".to_string(),
+            record: Value::object([("text", Value::from("hi"))]),
+        };
+        let did = atproto::did!("did:web:bridge.example:u:alice");
+        bridge
+            .repos
+            .commit(&did, &[write], &key(), &HashSigner, start)
+            .unwrap();
+        assert_eq!(request_due(&bridge, "bridge.example", start), 2);
+        let body = json::parse(&String::from_utf8_lossy(&mock.requests_to(&good)[0].body));
+        let hostname = body.unwrap().get("hostname").cloned();
+        assert_eq!(hostname, Some(Value::from("bridge.example")));
+        assert!(bridge.relays.status(GOOD).acknowledged());
+        let status = bridge.relays.status(BAD);
+        assert_eq!(
+            status.last_error.as_deref(),
+            Some("Relay responded with 503")
+        );
+
+        // Only the relay which failed is retried soon
+        let retry = start + bridge.crawl.retry_interval;
+        assert_eq!(request_due(&bridge, "bridge.example", retry), 1);
+        assert!(bridge.relays.status(BAD).acknowledged());
+        let later = start + bridge.crawl.interval;
+        assert_eq!(request_due(&bridge, "bridge.example", later), 1);
+        assert_eq!(mock.requests_to(&good).len(), 2);
+    }
+}
@@ -0,0 +1,262 @@
+//! Handling each inbound activity once
+//!
+//! Mastodon redelivers an activity until it gets a 2xx, and relays pass on what an inbox has
+//! already had directly, so the same activity can arrive many times. Each is keyed by its
+//! `id` and by a digest of its content (without any signature, which a relay may add), and
+//! [`process_once`] only hands it on if neither key has been handled before. The `id` is
+//! taken as its actor's, as only the actor is checked against the signer: anyone else
+//! claiming it first can't have the actor's own activity dropped as a redelivery. Keys are only
+//! kept once handling succeeds, so a failure is retried when the activity comes again, and
+//! a redelivery arriving mid-handling is dropped rather than handled alongside. A `Create`
+//! which wraps an object already created in another is dropped as well, by the [index of
+//! objects seen](crate::seen).
+//!
+//! Every activity delivered to an inbox is handled through [`process_once`] by the [inbound
+//! pipeline](crate::inbound), and so is each record from the firehose, keyed by its AT URI
+//! and content.
+//!
+//! Handled keys are appended to the shard's state directory, and dropped once older than
+//! [`RetentionConfig::seen_ttl`](crate::retention::RetentionConfig::seen_ttl), by which time
+//! nothing will redeliver them
+
+use crate::bridge::Bridge;
+use crate::crypto::{hex_encode, sha256};
+use crate::json::{id_of, Value};
+use crate::seen::translate_once;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{from_unix_millis, unix_millis};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+pub const SEEN_FILE: &str = "seen-activities.jsonl";
+
+/// The digest of an activity's content, leaving out its signature
+pub fn digest(activity: &Value) -> String {
+    let mut activity = activity.clone();
+    if let Value::Object(fields) = &mut activity {
+        fields.remove("signature");
+    }
+    hex_encode(&sha256(activity.to_string().as_bytes()))
+}
+
+/// The keys an activity is known by
+fn keys(activity: &Value) -> Vec<String> {
+    let actor = id_of(activity.get("actor")).unwrap_or_default();
+    let id = activity.get("id").and_then(Value::as_str);
+    let id = id.map(|id| format!("id:{actor} {id}"));
+    id.into_iter()
+        .chain([format!("digest:{}", digest(activity))])
+        .collect()
+}
+
+#[derive(Debug, Default)]
+/// The activities which have been handled, within their retention period
+pub struct SeenActivities {
+    /// When each key was handled, or `None` while it's being handled
+    seen: Mutex<HashMap<String, Option<SystemTime>>>,
+    log: Option<JsonLog>,
+}
+
+impl SeenActivities {
+    /// A set persisted in `dir`, with the keys already there
+    pub fn open(dir: StateDir) -> io::Result<SeenActivities> {
+        let (log, lines) = JsonLog::open(dir, SEEN_FILE)?;
+        let seen = lines
+            .iter()
+            .filter_map(|line| {
+                let key = line.get("key")?.as_str()?.to_string();
+                let at = from_unix_millis(line.get("at")?.as_i64()?);
+                Some((key, Some(at)))
+            })
+            .collect();
+        Ok(SeenActivities {
+            seen: Mutex::new(seen),
+            log: Some(log),
+        })
+    }
+
+    /// Whether `activity` has been handled, or is being handled
+    pub fn seen(&self, activity: &Value) -> bool {
+        let seen = self.seen.lock().unwrap();
+        keys(activity).iter().any(|key| seen.contains_key(key))
+    }
+
+    /// Start handling `keys`, unless any of them has been
+    fn claim(&self, keys: &[String]) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        if keys.iter().any(|key| seen.contains_key(key)) {
+            return false;
+        }
+        seen.extend(keys.iter().map(|key| (key.clone(), None)));
+        true
+    }
+
+    fn finish(&self, keys: &[String], now: SystemTime) -> io::Result<()> {
+        let mut seen = self.seen.lock().unwrap();
+        seen.extend(keys.iter().map(|key| (key.clone(), Some(now))));
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        log.append(keys.iter().map(|key| line(key, now)))
+    }
+
+    fn abandon(&self, keys: &[String]) {
+        let mut seen = self.seen.lock().unwrap();
+        for key in keys {
+            seen.remove(key);
+        }
+    }
+
+    /// Forget keys handled more than `ttl` before `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut seen = self.seen.lock().unwrap();
+        let before = seen.len();
+        seen.retain(|_, at| at.is_none_or(|at| now.duration_since(at).unwrap_or_default() < ttl));
+        let pruned = before - seen.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            let handled = seen.iter().filter_map(|(key, at)| Some((key, (*at)?)));
+            log.rewrite(handled.map(|(key, at)| line(key, at)))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn line(key: &str, at: SystemTime) -> Value {
+    Value::object([
+        ("key", Value::from(key)),
+        ("at", Value::from(unix_millis(at))),
+    ])
+}
+
+/// The IRI of the object `activity` creates, if it's a `Create`
+fn created(activity: &Value) -> Option<&str> {
+    if activity.get("type").and_then(Value::as_str) != Some("Create") {
+        return None;
+    }
+    let object = activity.get("object")?;
+    object.as_str().or_else(|| object.get("id")?.as_str())
+}
+
+/// Handle `activity` with `handle`, unless it has been already
+///
+/// Returns `None` for a redelivery, or a `Create` of an object [recently
+/// translated](crate::seen) under another activity. It's only marked as handled if `handle`
+/// succeeds; the work has happened either way by then, so failing to persist that is only
+/// logged
+pub fn process_once<T, E>(
+    bridge: &Bridge,
+    activity: &Value,
+    handle: impl FnOnce(&Value) -> Result<T, E>,
+) -> Result<Option<T>, E> {
+    let keys = keys(activity);
+    if !bridge.seen_activities.claim(&keys) {
+        return Ok(None);
+    }
+    let handled = match created(activity) {
+        // Nothing says for sure whether an object the filter may have had was translated,
+        // so it's translated again
+        Some(object) => translate_once(bridge, object, |_| false, || handle(activity)),
+        None => handle(activity).map(Some),
+    };
+    match handled {
+        Ok(handled) => {
+            if let Err(e) = bridge.seen_activities.finish(&keys, SystemTime::now()) {
+                eprintln!("Couldn't record a handled activity: {e}");
+            }
+            Ok(handled)
+        }
+        Err(e) => {
+            bridge.seen_activities.abandon(&keys);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::storage::tests::temp_state_dir;
+    use std::cell::Cell;
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn like(id: &str, signature: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Like", "id": "{id}", "actor": "https://a.example/users/bob",
+                "object": "https://bridge.example/p/1", "signature": {{"signatureValue": "{signature}"}}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn redeliveries_are_handled_once() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            seen_activities: SeenActivities::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        let likes = Cell::new(0);
+        let handle = |_: &Value| -> Result<(), &str> {
+            likes.set(likes.get() + 1);
+            Ok(())
+        };
+        let first = like("https://a.example/likes/1", "a");
+        assert_eq!(process_once(&bridge, &first, handle), Ok(Some(())));
+        assert_eq!(process_once(&bridge, &first, handle), Ok(None));
+        // Relayed with another signature, it's the same activity
+        let relayed = like("https://a.example/likes/1", "b");
+        assert_eq!(process_once(&bridge, &relayed, handle), Ok(None));
+        assert_eq!(likes.get(), 1);
+        // Someone else's activity claiming the ID first doesn't stop the actor's own
+        let mut spoofed = like("https://a.example/likes/3", "c");
+        if let Value::Object(fields) = &mut spoofed {
+            fields.insert("actor".to_string(), Value::from("https://e.example/eve"));
+        }
+        assert_eq!(process_once(&bridge, &spoofed, handle), Ok(Some(())));
+        let genuine = like("https://a.example/likes/3", "a");
+        assert_eq!(process_once(&bridge, &genuine, handle), Ok(Some(())));
+        assert_eq!(likes.get(), 3);
+
+        // A failure is retried
+        let second = like("https://a.example/likes/2", "a");
+        assert_eq!(
+            process_once(&bridge, &second, |_| Err::<(), _>("down")),
+            Err("down")
+        );
+        assert_eq!(process_once(&bridge, &second, handle), Ok(Some(())));
+
+        // The same post in another activity is translated once
+        let create = |id: &str| {
+            let create = format!(
+                r#"{{"type": "Create", "id": "{id}", "object": {{"id": "https://a.example/notes/1"}}}}"#
+            );
+            json::parse(&create).unwrap()
+        };
+        assert_eq!(
+            process_once(&bridge, &create("https://a.example/c/1"), handle),
+            Ok(Some(()))
+        );
+        assert_eq!(
+            process_once(&bridge, &create("https://r.example/c/1"), handle),
+            Ok(None)
+        );
+        assert_eq!(likes.get(), 5);
+
+        let reopened = SeenActivities::open(dir).unwrap();
+        assert!(reopened.seen(&first) && reopened.seen(&second));
+        let later = SystemTime::now() + DAY * 30;
+        assert_eq!(reopened.prune(DAY * 7, later).unwrap(), 12);
+        assert!(!reopened.seen(&first));
+    }
+}
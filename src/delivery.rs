@@ -0,0 +1,293 @@
+//! Outbound ActivityPub deliveries
+//!
+//! Deliveries are already-serialised activities addressed to a single inbox. They're run as
+//! [`Job::Deliver`] jobs, so retries and dead deliveries are handled by the job queue.
+//!
+//! Each is signed over `(request-target) host date digest` with the sending actor's
+//! [current](crate::keys::KeyStore::current_at) HTTP signature key, so a pre-rotated key only
+//! signs from its switch time. The signature algorithms are supplied by a [`RequestSigner`]
+
+use crate::actorkeys;
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::cache::{FetchCache, Lookup, ResourceKind};
+use crate::crypto::{base64_encode, sha256};
+use crate::jobs::{Job, QueuedJob};
+use crate::json::{self, Value};
+use crate::keys::{KeyOwner, KeyPair, KeyPurpose, StoredKey};
+use crate::time::format_http_date;
+use crate::transport::{OutboundRequest, TransportError};
+use crate::url::{Url, UrlError};
+use std::time::{Instant, SystemTime};
+use thiserror::Error;
+
+/// The media type for ActivityPub documents
+pub const ACTIVITY_JSON: &str = "application/activity+json";
+
+/// The headers deliveries' signatures cover
+const SIGNED_HEADERS: &str = "(request-target) host date digest";
+
+/// Signs outbound requests with an actor's key
+pub trait RequestSigner: Send + Sync {
+    /// Sign `signed` with `key`: RSASSA-PKCS1-v1_5 with SHA-256 for RSA keys, as fediverse
+    /// software expects
+    fn sign(&self, key: &KeyPair, signed: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A single activity addressed to a single inbox
+pub struct Delivery {
+    pub inbox: String,
+    /// The serialised activity
+    pub activity: String,
+    /// Why it's being sent, for the audit log
+    pub cause: Option<Cause>,
+}
+
+impl Delivery {
+    pub fn new(inbox: impl Into<String>, activity: impl Into<String>) -> Delivery {
+        Delivery {
+            inbox: inbox.into(),
+            activity: activity.into(),
+            cause: None,
+        }
+    }
+
+    pub fn because(self, cause: Cause) -> Delivery {
+        Delivery {
+            cause: Some(cause),
+            ..self
+        }
+    }
+
+    /// The actor the activity is from
+    pub fn actor(&self) -> Option<String> {
+        let activity = json::parse(&self.activity).ok()?;
+        Some(activity.get("actor")?.as_str()?.to_string())
+    }
+
+    /// The post the activity bridges, by the event it was caused by if it says, else its
+    /// object or the activity's own ID
+    pub fn post(&self) -> Option<String> {
+        if let Some(event) = self.cause.as_ref().and_then(|c| c.event.clone()) {
+            return Some(event);
+        }
+        let activity = json::parse(&self.activity).ok()?;
+        let object = activity.get("object").and_then(|object| match object {
+            Value::Array(objects) => objects.first()?.as_str(),
+            object => object.as_str().or_else(|| object.get("id")?.as_str()),
+        });
+        let id = activity.get("id").and_then(Value::as_str);
+        object.or(id).map(str::to_string)
+    }
+}
+
+/// Where to deliver to `actor`, preferring the shared inbox of its instance
+///
+/// Falls back to the conventional `/inbox` when the actor's document isn't cached
+pub fn shared_inbox(documents: &FetchCache<Value>, actor: &str) -> Option<String> {
+    let document = match documents.lookup(ResourceKind::Actor, actor, Instant::now()) {
+        Lookup::Fresh(document) | Lookup::Stale(document) => Some(document),
+        Lookup::Miss => None,
+    };
+    let advertised = document.and_then(|d| {
+        d.get("endpoints")
+            .and_then(|e| e.get("sharedInbox"))
+            .or_else(|| d.get("inbox"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    });
+    advertised.or_else(|| Some(format!("{}/inbox", Url::parse(actor).ok()?.origin())))
+}
+
+#[derive(Debug, Error)]
+/// Errors delivering an activity
+pub enum DeliveryError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("{inbox} rejected the activity with status {status}")]
+    Rejected { inbox: String, status: u16 },
+    #[error("No request signer is configured")]
+    NoSigner,
+    #[error("{actor} has no HTTP signature key, or isn't the bridge's to sign as")]
+    NoKey { actor: String },
+    #[error("Couldn't sign the delivery: {0:#}")]
+    Signing(anyhow::Error),
+    #[error(transparent)]
+    Url(#[from] UrlError),
+}
+
+/// The key `delivery` is signed with at `now`: its actor's current HTTP signature key, or the
+/// bridge's own for its instance actor
+pub fn signing_key(bridge: &Bridge, delivery: &Delivery, now: SystemTime) -> Option<StoredKey> {
+    let actor = delivery.actor()?;
+    let owner = if bridge.moderation.instance_actor.as_deref() == Some(actor.as_str()) {
+        KeyOwner::Bridge
+    } else {
+        KeyOwner::Account(bridge.identities.get_by_actor(&actor)?.did)
+    };
+    bridge
+        .keys
+        .current_at(&owner, KeyPurpose::HttpSignature, now)
+}
+
+/// Sign `request` as `actor` with `key`, adding the `date` and `digest` it covers
+pub fn sign(
+    request: OutboundRequest,
+    actor: &str,
+    key: &StoredKey,
+    signer: &dyn RequestSigner,
+    now: SystemTime,
+) -> Result<OutboundRequest, DeliveryError> {
+    let url = Url::parse(&request.url)?;
+    let date = format_http_date(now);
+    let digest = format!("SHA-256={}", base64_encode(&sha256(&request.body)));
+    let method = request.method.as_str().to_ascii_lowercase();
+    let signed = format!(
+        "(request-target): {method} {}\nhost: {}\ndate: {date}\ndigest: {digest}",
+        url.path,
+        url.authority()
+    );
+    let signature = signer
+        .sign(&key.keypair, signed.as_bytes())
+        .map_err(DeliveryError::Signing)?;
+    let header = format!(
+        r#"keyId="{}",algorithm="rsa-sha256",headers="{SIGNED_HEADERS}",signature="{}""#,
+        actorkeys::key_id(actor, key),
+        base64_encode(&signature)
+    );
+    Ok(request
+        .with_header("date", &date)
+        .with_header("digest", &digest)
+        .with_header("signature", &header))
+}
+
+/// POST an activity to its inbox, signed with its actor's current key
+pub fn deliver(bridge: &Bridge, delivery: &Delivery) -> Result<(), DeliveryError> {
+    let signer = bridge
+        .request_signer
+        .as_ref()
+        .ok_or(DeliveryError::NoSigner)?;
+    let now = SystemTime::now();
+    let actor = delivery.actor().unwrap_or_default();
+    let key = signing_key(bridge, delivery, now).ok_or_else(|| DeliveryError::NoKey {
+        actor: actor.clone(),
+    })?;
+    let request = OutboundRequest::post(&delivery.inbox, delivery.activity.as_bytes())
+        .with_header("content-type", ACTIVITY_JSON)
+        .with_header("accept", ACTIVITY_JSON);
+    let request = sign(request, &actor, &key, signer.as_ref(), now)?;
+    let response = bridge.transport.send(&request)?;
+    if !response.is_success() {
+        return Err(DeliveryError::Rejected {
+            inbox: delivery.inbox.clone(),
+            status: response.status,
+        });
+    }
+    Ok(())
+}
+
+/// A queued delivery's ID, inbox, attempts and last error, as the APIs list them
+pub fn queued_json(job: &QueuedJob) -> Option<Value> {
+    let Job::Deliver(delivery) = &job.job else {
+        return None;
+    };
+    Some(Value::object([
+        ("id", Value::from(job.id)),
+        ("inbox", Value::from(delivery.inbox.as_str())),
+        ("attempts", Value::from(job.attempts)),
+        ("lastError", Value::from(job.last_error.clone())),
+    ]))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::http::{Method, Request};
+    use crate::keys::tests::FakeGenerator;
+    use crate::signatures::{signing_string, SignatureParams};
+    use crate::store::Mapping;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    pub(crate) const ACTOR: &str = "https://bridge.example/users/alice";
+    const ACTIVITY: &str = r#"{"type":"Create","actor":"https://bridge.example/users/alice"}"#;
+
+    /// "Signs" by hashing the private key and signed string together
+    pub(crate) struct HashRequestSigner;
+
+    impl RequestSigner for HashRequestSigner {
+        fn sign(&self, key: &KeyPair, signed: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(sha256(&[key.private_key.as_slice(), signed].concat()).to_vec())
+        }
+    }
+
+    /// A bridge which can sign as [`ACTOR`], and whose deliveries are sent to `mock`
+    pub(crate) fn signing_bridge(mock: Arc<MockTransport>) -> Bridge {
+        let bridge = Bridge::new()
+            .with_transport(mock)
+            .with_request_signer(Arc::new(HashRequestSigner))
+            .with_key_overlap(Duration::from_secs(3600));
+        let did = atproto::did!("did:plc:alice");
+        bridge.identities.insert(Mapping::new(did.clone(), ACTOR));
+        let owner = KeyOwner::Account(did);
+        let generator = FakeGenerator::default();
+        let purpose = KeyPurpose::HttpSignature;
+        bridge.keys.ensure(&owner, purpose, &generator).unwrap();
+        bridge
+    }
+
+    #[test]
+    fn deliver_posts_signed_activity() {
+        let mock = Arc::new(MockTransport::new());
+        let inbox = "https://remote.example/inbox";
+        mock.respond(Method::Post, inbox, OutboundResponse::new(202));
+        let bridge = signing_bridge(mock.clone());
+        let owner = KeyOwner::Account(atproto::did!("did:plc:alice"));
+        let purpose = KeyPurpose::HttpSignature;
+        let generator = FakeGenerator::default();
+        let first = bridge.keys.current(&owner, purpose).unwrap();
+        // Pre-rotated, so the new key is published but doesn't sign until the overlap ends
+        let rotation = bridge.prerotate_key(&owner, purpose, &generator).unwrap();
+        assert!(rotation.current.signs_from.unwrap() > SystemTime::now());
+        deliver(&bridge, &Delivery::new(inbox, ACTIVITY)).unwrap();
+
+        let sent = &mock.requests()[0];
+        assert_eq!(sent.header("content-type"), Some(ACTIVITY_JSON));
+        assert_eq!(sent.body, ACTIVITY.as_bytes());
+        let params = SignatureParams::parse(sent.header("signature").unwrap()).unwrap();
+        assert_eq!(params.key_id, format!("{ACTOR}#main-key"));
+        // It verifies as inbound signatures are checked
+        let mut received = Request::new(Method::Post, "/inbox")
+            .with_header("host", "remote.example")
+            .with_body(ACTIVITY);
+        for (name, value) in &sent.headers {
+            received = received.with_header(name, value);
+        }
+        let signed = signing_string(&received, &params.headers).unwrap();
+        let expected = HashRequestSigner.sign(&first.keypair, signed.as_bytes());
+        assert_eq!(params.signature, base64_encode(&expected.unwrap()));
+        let digest = format!("SHA-256={}", base64_encode(&sha256(ACTIVITY.as_bytes())));
+        assert_eq!(sent.header("digest"), Some(digest.as_str()));
+
+        // Nor does an unknown actor's delivery go unsigned
+        let unknown = r#"{"type":"Create","actor":"https://bridge.example/users/nobody"}"#;
+        assert!(matches!(
+            deliver(&bridge, &Delivery::new(inbox, unknown)),
+            Err(DeliveryError::NoKey { .. })
+        ));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn error_status_is_rejection() {
+        let mock = Arc::new(MockTransport::new());
+        let inbox = "https://remote.example/inbox";
+        mock.respond(Method::Post, inbox, OutboundResponse::new(401));
+        assert!(matches!(
+            deliver(&signing_bridge(mock), &Delivery::new(inbox, ACTIVITY)),
+            Err(DeliveryError::Rejected { status: 401, .. })
+        ));
+    }
+}
@@ -0,0 +1,553 @@
+//! Diagnosing why an account can't be reached through the bridge
+//!
+//! Following an account through the bridge depends on a chain of lookups on its own network,
+//! any of which can fail quietly. [`diagnose`] walks the whole chain for one identity and
+//! reports every step, so an operator can see where it breaks:
+//!
+//! - a fediverse account (`user@host` or an actor URL): its host's DNS, WebFinger, the actor
+//!   document and the keys it publishes
+//! - an atproto account (a handle or a DID): its handle's DNS and `/.well-known/atproto-did`,
+//!   the DID document and whether it claims the handle back, whether its PDS answers and the
+//!   repo signing key it publishes
+//!
+//! and for either, whether it's bridged and not refused by federation policy. Steps which
+//! need one that failed are skipped. Fresh copies of the documents are fetched, rather than
+//! whatever the cache holds. The admin API serves this as `GET /admin/diagnose`, and
+//! `fedibridge diagnose <identity>` prints it
+
+use crate::actorkeys::{self, PublicKey};
+use crate::bridge::Bridge;
+use crate::cache::ResourceKind;
+use crate::delivery::ACTIVITY_JSON;
+use crate::json::{self, Value};
+use crate::mentions::{self, handle_from_document, webfinger_link};
+use crate::normalize;
+use crate::store::MappingStatus;
+use crate::transport::OutboundRequest;
+use crate::url::Url;
+use atproto::DID::Did;
+use std::io;
+use std::net::IpAddr;
+
+const DESCRIBE_SERVER: &str = "com.atproto.server.describeServer";
+const BASE58: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    /// Not tried, as a step it needs failed or it doesn't apply
+    Skipped,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Passed => "passed",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// One step of resolving an identity
+pub struct Step {
+    /// What was checked, such as `webfinger` or `did-document`
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// What was found, or what went wrong
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Every step of resolving an identity, in order
+pub struct Report {
+    pub identity: String,
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    /// Whether every step passed
+    pub fn passed(&self) -> bool {
+        self.steps
+            .iter()
+            .all(|step| step.outcome == Outcome::Passed)
+    }
+
+    /// The step named `name`, if it was reached
+    pub fn step(&self, name: &str) -> Option<&Step> {
+        self.steps.iter().find(|step| step.name == name)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let steps = self.steps.iter().map(|step| {
+            Value::object([
+                ("step", Value::from(step.name)),
+                ("outcome", Value::from(step.outcome.as_str())),
+                ("detail", Value::from(step.detail.as_str())),
+            ])
+        });
+        Value::object([
+            ("identity", Value::from(self.identity.as_str())),
+            ("passed", Value::Bool(self.passed())),
+            ("steps", Value::Array(steps.collect())),
+        ])
+    }
+
+    fn record<T>(&mut self, name: &'static str, result: Result<(T, String), String>) -> Option<T> {
+        let (outcome, detail, value) = match result {
+            Ok((value, detail)) => (Outcome::Passed, detail, Some(value)),
+            Err(detail) => (Outcome::Failed, detail, None),
+        };
+        self.steps.push(Step {
+            name,
+            outcome,
+            detail,
+        });
+        value
+    }
+
+    fn skip(&mut self, names: &[&'static str], because: &str) {
+        for name in names {
+            self.steps.push(Step {
+                name,
+                outcome: Outcome::Skipped,
+                detail: format!("needs {because}"),
+            });
+        }
+    }
+}
+
+/// Walk the resolution chain of `identity`: `user@host`, an actor URL, a handle or a DID
+pub fn diagnose(bridge: &Bridge, identity: &str) -> Report {
+    diagnose_with(bridge, identity, |host| bridge.dns.addresses(host))
+}
+
+/// [`diagnose`], resolving hostnames with `lookup`
+pub fn diagnose_with(
+    bridge: &Bridge,
+    identity: &str,
+    lookup: impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+) -> Report {
+    let identity = identity.trim();
+    let mut report = Report {
+        identity: identity.to_string(),
+        steps: Vec::new(),
+    };
+    let account = identity.strip_prefix('@').unwrap_or(identity);
+    if identity.starts_with("did:") {
+        match normalize::parse_did(identity) {
+            Ok(did) => atproto_account(bridge, &mut report, &lookup, did, None),
+            Err(e) => {
+                report.record::<()>("did", Err(e.to_string()));
+            }
+        }
+    } else if identity.starts_with("https://") || identity.starts_with("http://") {
+        fediverse_actor(bridge, &mut report, &lookup, identity);
+    } else if account.contains('@') {
+        fediverse_account(bridge, &mut report, &lookup, account);
+    } else {
+        atproto_handle(bridge, &mut report, &lookup, &normalize::handle(account));
+    }
+    report
+}
+
+fn dns(
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    host: &str,
+) -> Option<()> {
+    let result = match lookup(host) {
+        Ok(addresses) if !addresses.is_empty() => {
+            let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+            Ok(((), format!("{host} resolves to {}", addresses.join(", "))))
+        }
+        Ok(_) => Err(format!("{host} has no addresses")),
+        Err(e) => Err(format!("Couldn't resolve {host}: {e}")),
+    };
+    report.record("dns", result)
+}
+
+/// GET `url` as `accept`, returning the body of a success
+fn fetch(bridge: &Bridge, url: &str, accept: &str) -> Result<Vec<u8>, String> {
+    let request = OutboundRequest::get(url).with_header("accept", accept);
+    let response = bridge
+        .transport
+        .send(&request)
+        .map_err(|e| format!("Couldn't fetch {url}: {e}"))?;
+    if !response.is_success() {
+        return Err(format!("{url} responded with {}", response.status));
+    }
+    Ok(response.body)
+}
+
+fn fediverse_account(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    account: &str,
+) {
+    let host = account.rsplit_once('@').map_or(account, |(_, host)| host);
+    if dns(report, lookup, host).is_none() {
+        return report.skip(&["webfinger", "actor", "keys", "bridged"], "dns");
+    }
+    bridge
+        .documents
+        .invalidate(ResourceKind::WebFinger, account);
+    let actor = mentions::webfinger(bridge, account)
+        .map_err(|e| e.to_string())
+        .and_then(|document| {
+            let actor = webfinger_link(&document, "self", Some(ACTIVITY_JSON));
+            let actor = actor.ok_or("The WebFinger response links to no ActivityPub actor")?;
+            Ok((actor.to_string(), format!("{account} is {actor}")))
+        });
+    let Some(actor) = report.record("webfinger", actor) else {
+        return report.skip(&["actor", "keys", "bridged"], "webfinger");
+    };
+    actor_document(bridge, report, &actor);
+}
+
+fn fediverse_actor(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    actor: &str,
+) {
+    let url = Url::parse(actor).map_err(|e| format!("Invalid actor URL: {e}"));
+    let Some(url) = report.record("url", url.map(|url| (url, actor.to_string()))) else {
+        return;
+    };
+    if dns(report, lookup, &url.host).is_none() {
+        return report.skip(&["actor", "keys", "bridged"], "dns");
+    }
+    actor_document(bridge, report, actor);
+}
+
+fn actor_document(bridge: &Bridge, report: &mut Report, actor: &str) {
+    let document = fetch(bridge, actor, ACTIVITY_JSON).and_then(|body| {
+        let document = json::parse(&String::from_utf8_lossy(&body))
+            .map_err(|e| format!("{actor} isn't JSON: {e}"))?;
+        let id = document.get("id").and_then(Value::as_str);
+        let origin = |url: &str| Url::parse(url).ok().map(|url| url.origin());
+        if id.and_then(origin).is_none() || id.and_then(origin) != origin(actor) {
+            return Err(format!("{actor} claims to be {}", id.unwrap_or("nothing")));
+        }
+        let kind = document.get("type").and_then(Value::as_str).unwrap_or("?");
+        let detail = format!("{} is a {kind}", id.unwrap_or(actor));
+        Ok((document, detail))
+    });
+    let Some(document) = report.record("actor", document) else {
+        return report.skip(&["keys", "bridged"], "actor");
+    };
+    let keys = actorkeys::published_keys(&document);
+    let valid: Vec<&str> = keys
+        .iter()
+        .filter(|key| match &key.key {
+            PublicKey::Pem(pem) => pem_shaped(pem),
+            PublicKey::Multibase(key) => multikey_type(key) == Some("Ed25519"),
+        })
+        .map(|key| key.id.as_str())
+        .collect();
+    let keys = match (keys.len(), valid.len()) {
+        (0, _) => Err("The actor publishes no keys it owns, so its signatures can't be checked"),
+        (_, 0) => Err("None of the actor's keys are well-formed"),
+        _ => Ok(((), format!("Publishes {}", valid.join(", ")))),
+    };
+    report.record("keys", keys.map_err(str::to_string));
+    let id = document.get("id").and_then(Value::as_str).unwrap_or(actor);
+    let host = Url::parse(id).ok().map(|url| url.host);
+    let mapping = bridge.identities.get_by_actor(id);
+    bridged(bridge, report, mapping, host.as_deref());
+}
+
+fn atproto_handle(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    handle: &str,
+) {
+    let Some(did) = well_known(bridge, report, lookup, handle) else {
+        return report.skip(
+            &["did-document", "handle", "pds", "keys", "bridged"],
+            "well-known",
+        );
+    };
+    atproto_account(bridge, report, lookup, did, Some(handle));
+}
+
+/// Check the DNS and `/.well-known/atproto-did` of `handle`, returning the DID it names
+fn well_known(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    handle: &str,
+) -> Option<Did> {
+    if dns(report, lookup, handle).is_none() {
+        report.skip(&["well-known"], "dns");
+        return None;
+    }
+    let url = format!("https://{handle}/.well-known/atproto-did");
+    let did = fetch(bridge, &url, "text/plain").and_then(|body| {
+        let did = String::from_utf8_lossy(&body).trim().to_string();
+        let did = normalize::parse_did(&did).map_err(|_| format!("{url} gave {did:?}"))?;
+        // Handles can also be verified by a `_atproto` TXT record, which isn't checked here
+        Ok((did.clone(), format!("{handle} claims to be {did}")))
+    });
+    report.record("well-known", did)
+}
+
+fn atproto_account(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    did: Did,
+    handle: Option<&str>,
+) {
+    bridge
+        .documents
+        .invalidate(ResourceKind::DidDocument, did.as_str());
+    let document = bridge
+        .resolver()
+        .resolve(&did)
+        .map(|document| (document, format!("Resolved {did}")))
+        .map_err(|e| e.to_string());
+    let Some(document) = report.record("did-document", document) else {
+        return report.skip(&["handle", "pds", "keys", "bridged"], "did-document");
+    };
+    handle_claimed(bridge, report, lookup, &did, &document, handle);
+    let pds = pds(&document).ok_or_else(|| "The DID document names no PDS".to_string());
+    let pds = pds.and_then(|pds| {
+        let url = format!("{pds}/xrpc/{DESCRIBE_SERVER}");
+        fetch(bridge, &url, "application/json")?;
+        Ok(((), format!("{pds} is up")))
+    });
+    report.record("pds", pds);
+    let key = signing_key(&document);
+    let key = match key.map(|key| (key, multikey_type(key))) {
+        Some((key, Some(kind @ ("P-256" | "secp256k1")))) => Ok(((), format!("{kind} key {key}"))),
+        Some((key, _)) => Err(format!(
+            "The repo signing key {key} isn't a P-256 or secp256k1 key"
+        )),
+        None => Err("The DID document has no #atproto signing key".to_string()),
+    };
+    report.record("keys", key);
+    let mapping = bridge.identities.get(&did);
+    bridged(bridge, report, mapping, None);
+}
+
+/// Check the DID document claims the handle resolved to it, or that the handle it claims
+/// resolves back to it
+fn handle_claimed(
+    bridge: &Bridge,
+    report: &mut Report,
+    lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+    did: &Did,
+    document: &Value,
+    handle: Option<&str>,
+) {
+    let claimed = handle_from_document(document);
+    let result = match (handle, claimed) {
+        (Some(handle), Some(claimed)) if claimed.eq_ignore_ascii_case(handle) => {
+            Ok(((), format!("{did} claims {handle}")))
+        }
+        (Some(handle), claimed) => Err(format!(
+            "{did} claims {}, not {handle}",
+            claimed.as_deref().unwrap_or("no handle")
+        )),
+        (None, None) => Err(format!("{did} claims no handle")),
+        (None, Some(claimed)) => {
+            let resolved = well_known(bridge, report, lookup, &claimed);
+            match resolved {
+                Some(resolved) if resolved == *did => Ok(((), format!("{claimed} is {did}"))),
+                Some(resolved) => Err(format!("{claimed} claims to be {resolved}, not {did}")),
+                None => Err(format!("{claimed} doesn't resolve to {did}")),
+            }
+        }
+    };
+    report.record("handle", result);
+}
+
+fn pds(document: &Value) -> Option<String> {
+    let services = document.get("service").and_then(Value::as_array);
+    let pds = services.unwrap_or_default().iter().find(|service| {
+        let id = service.get("id").and_then(Value::as_str);
+        id.is_some_and(|id| id.ends_with("#atproto_pds"))
+    })?;
+    let endpoint = pds.get("serviceEndpoint")?.as_str()?;
+    Some(endpoint.trim_end_matches('/').to_string())
+}
+
+fn signing_key(document: &Value) -> Option<&str> {
+    let methods = document.get("verificationMethod").and_then(Value::as_array);
+    let method = methods.unwrap_or_default().iter().find(|method| {
+        let id = method.get("id").and_then(Value::as_str);
+        id.is_some_and(|id| id.ends_with("#atproto"))
+    })?;
+    method.get("publicKeyMultibase")?.as_str()
+}
+
+fn bridged(
+    bridge: &Bridge,
+    report: &mut Report,
+    mapping: Option<crate::store::Mapping>,
+    domain: Option<&str>,
+) {
+    let result = match mapping {
+        None => Err("Not bridged: the account hasn't opted in".to_string()),
+        Some(mapping) if bridge.policy.verdict(domain, Some(&mapping.did)).denied => Err(format!(
+            "Bridged as {}, but refused by federation policy",
+            mapping.did
+        )),
+        Some(mapping) => match mapping.status {
+            MappingStatus::Active => Ok(((), format!("Bridged as {}", mapping.did))),
+            status => Err(format!(
+                "Bridged as {}, but {}",
+                mapping.did,
+                status.as_str()
+            )),
+        },
+    };
+    report.record("bridged", result);
+}
+
+/// Whether `pem` looks like a PEM public key
+pub(crate) fn pem_shaped(pem: &str) -> bool {
+    let pem = pem.trim();
+    let body = pem
+        .strip_prefix("-----BEGIN PUBLIC KEY-----")
+        .and_then(|pem| pem.strip_suffix("-----END PUBLIC KEY-----"));
+    body.is_some_and(|body| {
+        let body: String = body.split_whitespace().collect();
+        !body.is_empty()
+            && body
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"+/=".contains(&b))
+    })
+}
+
+/// Decode base58btc
+fn base58_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = BASE58.iter().position(|&d| d == c)? as u32;
+        for byte in bytes.iter_mut().rev() {
+            carry += u32::from(*byte) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|&c| c == b'1').count();
+    Some([vec![0; zeros], bytes].concat())
+}
+
+/// The curve of a multibase-encoded multikey, if it's a well-formed key on one
+pub(crate) fn multikey_type(multibase: &str) -> Option<&'static str> {
+    let key = base58_decode(multibase.strip_prefix('z')?)?;
+    match key.as_slice() {
+        [0xe7, 0x01, point @ ..] if point.len() == 33 => Some("secp256k1"),
+        [0x80, 0x24, point @ ..] if point.len() == 33 => Some("P-256"),
+        [0xed, 0x01, point @ ..] if point.len() == 32 => Some("Ed25519"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Mapping;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+    /// `did:key:zQ3sh...` style secp256k1 key from the atproto docs
+    const K256: &str = "zQ3shXjHeiBuRCKmM36cuYnm7YEMzhGnCmCyW92sRJ9pribSF";
+
+    fn resolves(_: &str) -> io::Result<Vec<IpAddr>> {
+        Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))])
+    }
+
+    #[test]
+    fn walks_the_atproto_chain() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            &format!(
+                r##"{{"id": "did:plc:alice", "alsoKnownAs": ["at://alice.example"],
+                    "verificationMethod": [{{"id": "did:plc:alice#atproto", "type": "Multikey",
+                        "publicKeyMultibase": "{K256}"}}],
+                    "service": [{{"id": "#atproto_pds", "type": "AtprotoPersonalDataServer",
+                        "serviceEndpoint": "https://pds.example"}}]}}"##
+            ),
+        );
+        mock.respond(
+            crate::http::Method::Get,
+            "https://alice.example/.well-known/atproto-did",
+            crate::transport::OutboundResponse::new(200).with_body("did:plc:alice\n"),
+        );
+        mock.respond_json(
+            "https://pds.example/xrpc/com.atproto.server.describeServer",
+            "{}",
+        );
+        let bridge = Bridge::new().with_transport(mock.clone());
+        let report = diagnose_with(&bridge, "alice.example", resolves);
+        let outcomes: Vec<_> = report.steps.iter().map(|s| (s.name, s.outcome)).collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("dns", Outcome::Passed),
+                ("well-known", Outcome::Passed),
+                ("did-document", Outcome::Passed),
+                ("handle", Outcome::Passed),
+                ("pds", Outcome::Passed),
+                ("keys", Outcome::Passed),
+                ("bridged", Outcome::Failed),
+            ]
+        );
+        assert_eq!(
+            report.step("keys").unwrap().detail,
+            format!("secp256k1 key {K256}")
+        );
+        assert!(!report.passed());
+
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://bridge.example/users/alice"));
+        assert!(diagnose_with(&bridge, "did:plc:alice", resolves).passed());
+    }
+
+    #[test]
+    fn stops_where_the_fediverse_chain_breaks() {
+        let unresolvable = |host: &str| Err(io::Error::other(format!("no such host {host}")));
+        let bridge = Bridge::new().with_transport(Arc::new(MockTransport::new()));
+        let report = diagnose_with(&bridge, "@bob@b.example", unresolvable);
+        assert_eq!(report.steps[0].outcome, Outcome::Failed);
+        assert!(report.steps[1..]
+            .iter()
+            .all(|step| step.outcome == Outcome::Skipped));
+
+        // Found, but without a key its signatures can't be checked
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://b.example/.well-known/webfinger?resource=acct:bob@b.example",
+            r#"{"links": [{"rel": "self", "type": "application/activity+json",
+                "href": "https://b.example/users/bob"}]}"#,
+        );
+        mock.respond_json(
+            "https://b.example/users/bob",
+            r#"{"id": "https://b.example/users/bob", "type": "Person"}"#,
+        );
+        let bridge = Bridge::new().with_transport(mock);
+        let report = diagnose_with(&bridge, "bob@b.example", resolves);
+        assert_eq!(report.step("actor").unwrap().outcome, Outcome::Passed);
+        assert_eq!(report.step("keys").unwrap().outcome, Outcome::Failed);
+        let json = report.to_json();
+        assert_eq!(json.get("passed"), Some(&Value::Bool(false)));
+    }
+}
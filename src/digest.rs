@@ -0,0 +1,660 @@
+//! Digests of interactions with bridged accounts
+//!
+//! Someone bridged onto another network rarely looks there, so replies, likes and follows
+//! their bridged presence gets would otherwise go unseen. Accounts which opt in
+//! ([`Preferences::digests`](crate::store::Preferences::digests)) have these collected, and
+//! once the oldest has waited [`DigestConfig::interval`] they're summarised in a message on the
+//! account's home network: a chat message from the bridge's Bluesky account, or a direct note
+//! from its instance actor on the fediverse.
+//!
+//! Accounts whose actor is on the bridge's own domain (that of the instance actor) are
+//! Bluesky accounts, the rest are from the fediverse. Pending digests are kept in memory
+
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::dm::{self, ChatClient, ChatReply, DmError};
+use crate::html;
+use crate::jobs::Job;
+use crate::json::{id_of, Value};
+use crate::mentions::BLUESKY_PROFILE;
+use crate::normalize;
+use crate::shutdown::Shutdown;
+use crate::store::{Mapping, MappingStatus};
+use crate::time::unique_millis;
+use crate::url::Url;
+use atproto::at_uri::{AtUri, Authority};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often [`spawn`]'s thread looks for digests which are due
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Interactions listed individually in a digest, beyond which they're only counted
+const MAX_LISTED: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DigestConfig {
+    pub enabled: bool,
+    /// How long interactions are collected before being sent
+    pub interval: Duration,
+    /// The Bluesky account digests are sent from. Without one, Bluesky accounts get none
+    pub account: Option<Did>,
+}
+
+impl Default for DigestConfig {
+    fn default() -> Self {
+        DigestConfig {
+            enabled: false,
+            interval: Duration::from_secs(24 * 60 * 60),
+            account: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Network {
+    Bluesky,
+    Fediverse,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Bluesky => "Bluesky",
+            Network::Fediverse => "the fediverse",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Network::Bluesky => "bluesky",
+            Network::Fediverse => "fediverse",
+        }
+    }
+
+    pub fn parse(network: &str) -> Option<Network> {
+        match network {
+            "bluesky" => Some(Network::Bluesky),
+            "fediverse" => Some(Network::Fediverse),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InteractionKind {
+    Reply,
+    Like,
+    Follow,
+}
+
+impl InteractionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InteractionKind::Reply => "reply",
+            InteractionKind::Like => "like",
+            InteractionKind::Follow => "follow",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something that happened to a bridged account on the other network
+pub struct Interaction {
+    pub kind: InteractionKind,
+    /// Who did it, as an actor IRI or Bluesky handle or DID
+    pub from: String,
+    /// Link to the post replied to or liked
+    pub subject: Option<String>,
+}
+
+impl Interaction {
+    fn describe(&self) -> String {
+        let subject = self.subject.as_deref().unwrap_or("your post");
+        match self.kind {
+            InteractionKind::Reply => format!("{} replied to {subject}", self.from),
+            InteractionKind::Like => format!("{} liked {subject}", self.from),
+            InteractionKind::Follow => format!("{} followed you", self.from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The interactions to tell an account about
+pub struct Digest {
+    pub did: Did,
+    pub home: Network,
+    pub interactions: Vec<Interaction>,
+}
+
+impl Digest {
+    /// Where the interactions happened
+    fn elsewhere(&self) -> Network {
+        match self.home {
+            Network::Bluesky => Network::Fediverse,
+            Network::Fediverse => Network::Bluesky,
+        }
+    }
+
+    /// The digest as lines of text: a summary, then what happened
+    pub fn lines(&self) -> Vec<String> {
+        let count = |kind, one: &str, many: &str| {
+            let n = self.interactions.iter().filter(|i| i.kind == kind).count();
+            match n {
+                0 => None,
+                1 => Some(format!("1 {one}")),
+                n => Some(format!("{n} {many}")),
+            }
+        };
+        let counts: Vec<String> = [
+            count(InteractionKind::Reply, "reply", "replies"),
+            count(InteractionKind::Like, "like", "likes"),
+            count(InteractionKind::Follow, "new follower", "new followers"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let counts = match counts.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+            None => String::new(),
+        };
+        let mut lines = vec![format!(
+            "Your bridged account on {} got {counts}:",
+            self.elsewhere().name()
+        )];
+        let listed = self.interactions.iter().take(MAX_LISTED);
+        lines.extend(listed.map(|i| format!("- {}", i.describe())));
+        if self.interactions.len() > MAX_LISTED {
+            let more = self.interactions.len() - MAX_LISTED;
+            lines.push(format!("…and {more} more"));
+        }
+        lines
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    home: Network,
+    /// When the oldest interaction was recorded
+    since: Instant,
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Default)]
+/// Interactions waiting to be sent
+pub struct DigestCollector {
+    pending: Mutex<HashMap<Did, Pending>>,
+}
+
+impl DigestCollector {
+    pub fn record(&self, did: &Did, home: Network, interaction: Interaction, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry(did.clone()).or_insert_with(|| Pending {
+            home,
+            since: now,
+            interactions: Vec::new(),
+        });
+        entry.interactions.push(interaction);
+    }
+
+    /// Take the digests whose oldest interaction is at least `interval` old
+    pub fn take_due(&self, interval: Duration, now: Instant) -> Vec<Digest> {
+        let mut pending = self.pending.lock().unwrap();
+        let due: Vec<Did> = pending
+            .iter()
+            .filter(|(_, p)| now.saturating_duration_since(p.since) >= interval)
+            .map(|(did, _)| did.clone())
+            .collect();
+        due.into_iter()
+            .filter_map(|did| {
+                let Pending {
+                    home, interactions, ..
+                } = pending.remove(&did)?;
+                Some(Digest {
+                    did,
+                    home,
+                    interactions,
+                })
+            })
+            .collect()
+    }
+
+    /// Interactions waiting to go out in `did`'s next digest
+    pub fn pending(&self, did: &Did) -> Vec<Interaction> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .get(did)
+            .map(|p| p.interactions.clone())
+            .unwrap_or_default()
+    }
+
+    /// Drop whatever is waiting to go out in `did`'s next digest
+    pub fn discard(&self, did: &Did) {
+        self.pending.lock().unwrap().remove(did);
+    }
+
+    /// Put back a digest which couldn't be sent, as if collected since `since`
+    pub fn restore(&self, digest: Digest, since: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        let entry = pending.entry(digest.did).or_insert_with(|| Pending {
+            home: digest.home,
+            since,
+            interactions: Vec::new(),
+        });
+        entry.since = entry.since.min(since);
+        entry.interactions.splice(0..0, digest.interactions);
+    }
+}
+
+/// Which network an account is from: Bluesky accounts have actors alongside the instance
+/// actor, so this can only be told when that's configured
+pub(crate) fn home_network(bridge: &Bridge, mapping: &Mapping) -> Option<Network> {
+    let instance_actor = bridge.moderation.instance_actor.as_deref()?;
+    let origin = |iri| Url::parse(iri).ok().map(|url| url.origin());
+    match origin(&mapping.actor) == origin(instance_actor) {
+        true => Some(Network::Bluesky),
+        false => Some(Network::Fediverse),
+    }
+}
+
+/// Which network an account is from, when digests can be sent there
+fn home(bridge: &Bridge, mapping: &Mapping) -> Option<Network> {
+    match home_network(bridge, mapping)? {
+        Network::Bluesky => bridge.digests.account.as_ref().map(|_| Network::Bluesky),
+        Network::Fediverse => Some(Network::Fediverse),
+    }
+}
+
+/// Record an interaction with `mapping`, if it wants digests and it happened away from home
+fn record(bridge: &Bridge, mapping: &Mapping, on: Network, interaction: Interaction) -> bool {
+    if !bridge.digests.enabled
+        || mapping.status != MappingStatus::Active
+        || !mapping.preferences.digests
+    {
+        return false;
+    }
+    let Some(home) = home(bridge, mapping).filter(|home| *home != on) else {
+        return false;
+    };
+    bridge
+        .digest_collector
+        .record(&mapping.did, home, interaction, Instant::now());
+    true
+}
+
+/// Record a fediverse `Like`, `Follow` or reply to a bridged account for its digest
+///
+/// Returns whether it was recorded
+pub fn activity_received(bridge: &Bridge, activity: &Value) -> bool {
+    let Some(actor) = activity.get("actor").and_then(Value::as_str) else {
+        return false;
+    };
+    let object = activity.get("object");
+    let (kind, subject) = match activity.get("type").and_then(Value::as_str) {
+        Some("Like") => (InteractionKind::Like, id_of(object)),
+        Some("Follow") => (InteractionKind::Follow, id_of(object)),
+        Some("Create") if object.is_some_and(|object| !dm::is_direct(object)) => (
+            InteractionKind::Reply,
+            id_of(object.and_then(|object| object.get("inReplyTo"))),
+        ),
+        _ => return false,
+    };
+    let Some(mapping) = subject.and_then(|s| bridge.identities.get_by_object(s)) else {
+        return false;
+    };
+    if mapping.actor == actor {
+        return false;
+    }
+    let interaction = Interaction {
+        kind,
+        from: actor.to_string(),
+        subject: subject
+            .filter(|_| kind != InteractionKind::Follow)
+            .map(str::to_string),
+    };
+    record(bridge, &mapping, Network::Fediverse, interaction)
+}
+
+/// A link to the Bluesky post an `at://` URI refers to
+fn post_link(uri: &AtUri) -> Option<String> {
+    let Authority::Did(did) = uri.authority() else {
+        return None;
+    };
+    Some(format!("{BLUESKY_PROFILE}/{did}/post/{}", uri.rkey()?))
+}
+
+/// Record a Bluesky like, follow or reply to a bridged account for its digest, from a record
+/// `author` created in `collection`
+///
+/// Returns whether it was recorded
+pub fn record_received(bridge: &Bridge, author: &Did, collection: &str, record: &Value) -> bool {
+    let uri = |value: Option<&Value>| {
+        let uri = value?.get("uri")?.as_str()?;
+        AtUri::try_create(uri.to_string()).ok()
+    };
+    let (kind, subject) = match collection {
+        "app.bsky.feed.like" => (InteractionKind::Like, uri(record.get("subject"))),
+        "app.bsky.feed.post" => (
+            InteractionKind::Reply,
+            uri(record.get("reply").and_then(|reply| reply.get("parent"))),
+        ),
+        "app.bsky.graph.follow" => {
+            let subject = record.get("subject").and_then(Value::as_str);
+            let Some(did) = subject.and_then(|s| normalize::parse_did(s).ok()) else {
+                return false;
+            };
+            return match bridge.identities.get(&did) {
+                Some(mapping) if &mapping.did != author => {
+                    let interaction = Interaction {
+                        kind: InteractionKind::Follow,
+                        from: display(bridge, author),
+                        subject: None,
+                    };
+                    self::record(bridge, &mapping, Network::Bluesky, interaction)
+                }
+                _ => false,
+            };
+        }
+        _ => return false,
+    };
+    let Some(subject) = subject else {
+        return false;
+    };
+    let Authority::Did(did) = subject.authority() else {
+        return false;
+    };
+    let Some(mapping) = bridge.identities.get(did).filter(|m| &m.did != author) else {
+        return false;
+    };
+    let interaction = Interaction {
+        kind,
+        from: display(bridge, author),
+        subject: post_link(&subject),
+    };
+    self::record(bridge, &mapping, Network::Bluesky, interaction)
+}
+
+/// How a Bluesky account is named in a digest: by handle, if the bridge knows it
+fn display(bridge: &Bridge, did: &Did) -> String {
+    match bridge
+        .identities
+        .get(did)
+        .and_then(|mapping| mapping.handle)
+    {
+        Some(handle) => format!("@{handle}"),
+        None => did.to_string(),
+    }
+}
+
+/// Queue the digest for an account
+fn send(bridge: &Bridge, digest: &Digest) -> Result<bool, DmError> {
+    let Some(mapping) = bridge.identities.get(&digest.did) else {
+        return Ok(false);
+    };
+    match digest.home {
+        Network::Bluesky => {
+            let Some(account) = &bridge.digests.account else {
+                return Ok(false);
+            };
+            let client = ChatClient::new(bridge.transport.as_ref(), &bridge.dms);
+            let convo_id = client.get_convo_for_members(account, &[&digest.did])?;
+            bridge.jobs.push(Job::SendChatMessage(ChatReply {
+                did: account.clone(),
+                convo_id,
+                text: digest.lines().join("\n"),
+                facets: Vec::new(),
+                cause: Some(Cause::new("digest", None)),
+            }))?;
+        }
+        Network::Fediverse => {
+            let Some(instance_actor) = &bridge.moderation.instance_actor else {
+                return Ok(false);
+            };
+            let Some(inbox) = delivery::shared_inbox(&bridge.documents, &mapping.actor) else {
+                return Ok(false);
+            };
+            let lines: Vec<String> = digest.lines().iter().map(|l| html::escape(l)).collect();
+            let content = format!("<p>{}</p>", lines.join("<br>"));
+            let id = format!("{instance_actor}#digests/{}", unique_millis());
+            let note = dm::direct_note(&id, instance_actor, &mapping.actor, &content, None);
+            let delivery = Delivery::new(inbox, note.to_string());
+            bridge
+                .jobs
+                .push(Job::Deliver(delivery.because(Cause::new("digest", None))))?;
+        }
+    }
+    Ok(true)
+}
+
+/// Queue every digest which is due, returning how many were
+///
+/// Digests which fail are kept for the next attempt
+pub fn send_due(bridge: &Bridge, now: Instant) -> Result<usize, DmError> {
+    let mut due = bridge
+        .digest_collector
+        .take_due(bridge.digests.interval, now)
+        .into_iter();
+    let mut sent = 0;
+    while let Some(digest) = due.next() {
+        match send(bridge, &digest) {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => {
+                let since = now.checked_sub(bridge.digests.interval).unwrap_or(now);
+                for digest in std::iter::once(digest).chain(due) {
+                    bridge.digest_collector.restore(digest, since);
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Start a thread sending digests as they come due, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_check = Instant::now();
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_check.elapsed() < CHECK_INTERVAL {
+                continue;
+            }
+            last_check = Instant::now();
+            if let Err(e) = send_due(&bridge, last_check) {
+                eprintln!("Couldn't send interaction digests: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dm::{DmPolicy, GET_CONVO_FOR_MEMBERS};
+    use crate::json;
+    use crate::moderation::ModerationConfig;
+    use crate::store::Preferences;
+    use crate::transport::MockTransport;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:plc:bob");
+    const BRIDGE: Did = did!("did:plc:bridge");
+    /// alice is a Bluesky account, and bob a fediverse one
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+    const BOB_ACTOR: &str = "https://b.example/users/bob";
+
+    fn bridge(mock: &Arc<MockTransport>) -> Bridge {
+        let bridge = Bridge::new()
+            .with_transport(mock.clone())
+            .with_moderation(ModerationConfig {
+                instance_actor: Some("https://bridge.example/actor".to_string()),
+                ..ModerationConfig::default()
+            })
+            .with_dm_policy(DmPolicy {
+                chat_service: Some("https://chat.example".to_string()),
+                ..DmPolicy::default()
+            })
+            .with_digests(DigestConfig {
+                enabled: true,
+                account: Some(BRIDGE),
+                ..DigestConfig::default()
+            });
+        for (did, actor) in [(ALICE, ALICE_ACTOR), (BOB, BOB_ACTOR)] {
+            bridge.identities.insert(Mapping {
+                preferences: Preferences {
+                    digests: true,
+                    ..Preferences::default()
+                },
+                ..Mapping::new(did, actor)
+            });
+        }
+        bridge
+    }
+
+    #[test]
+    fn summarises_interactions() {
+        let interaction = |kind, from: &str| Interaction {
+            kind,
+            from: from.to_string(),
+            subject: Some("https://x.example/1".to_string()),
+        };
+        let mut digest = Digest {
+            did: ALICE,
+            home: Network::Bluesky,
+            interactions: vec![
+                interaction(InteractionKind::Like, "a"),
+                interaction(InteractionKind::Reply, "b"),
+                interaction(InteractionKind::Like, "c"),
+            ],
+        };
+        assert_eq!(
+            digest.lines(),
+            [
+                "Your bridged account on the fediverse got 1 reply and 2 likes:",
+                "- a liked https://x.example/1",
+                "- b replied to https://x.example/1",
+                "- c liked https://x.example/1",
+            ]
+        );
+        digest.home = Network::Fediverse;
+        digest.interactions = vec![interaction(InteractionKind::Follow, "@d"); 12];
+        let lines = digest.lines();
+        assert_eq!(
+            lines[0],
+            "Your bridged account on Bluesky got 12 new followers:"
+        );
+        assert_eq!(lines[1], "- @d followed you");
+        assert_eq!(lines.last().unwrap(), "…and 2 more");
+    }
+
+    #[test]
+    fn collects_interactions_away_from_home() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = bridge(&mock);
+        let like = |actor: &str, object: &str| {
+            json::parse(&format!(
+                r#"{{"type": "Like", "actor": "{actor}", "object": "{object}"}}"#
+            ))
+            .unwrap()
+        };
+        let post = format!("{ALICE_ACTOR}/posts/1");
+        assert!(activity_received(
+            &bridge,
+            &like("https://c.example/carol", &post)
+        ));
+        // Bob's fediverse posts are liked on the fediverse itself, and alice likes her own
+        assert!(!activity_received(
+            &bridge,
+            &like("https://c.example/carol", BOB_ACTOR)
+        ));
+        assert!(!activity_received(&bridge, &like(ALICE_ACTOR, &post)));
+        let reply = json::parse(
+            r#"{"text": "hi", "reply": {"parent": {"uri": "at://did:plc:bob/app.bsky.feed.post/3k"},
+                "root": {"uri": "at://did:plc:bob/app.bsky.feed.post/3k"}}}"#,
+        )
+        .unwrap();
+        assert!(record_received(
+            &bridge,
+            &ALICE,
+            "app.bsky.feed.post",
+            &reply
+        ));
+
+        let later = Instant::now() + Duration::from_secs(24 * 60 * 60);
+        let mut due = bridge
+            .digest_collector
+            .take_due(bridge.digests.interval, later);
+        due.sort_by(|a, b| a.did.cmp(&b.did));
+        assert_eq!(due[0].home, Network::Bluesky);
+        assert_eq!(due[1].home, Network::Fediverse);
+        assert_eq!(
+            due[1].interactions,
+            [Interaction {
+                kind: InteractionKind::Reply,
+                from: ALICE.to_string(),
+                subject: Some("https://bsky.app/profile/did:plc:bob/post/3k".to_string()),
+            }]
+        );
+        assert!(bridge
+            .digest_collector
+            .take_due(Duration::ZERO, later)
+            .is_empty());
+    }
+
+    #[test]
+    fn sends_digests_home() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            &format!("https://chat.example/xrpc/{GET_CONVO_FOR_MEMBERS}?members=did%3Aplc%3Aalice"),
+            r#"{"convo": {"id": "convo1"}}"#,
+        );
+        let bridge = bridge(&mock);
+        let follow = |actor: &str, object: &str| {
+            json::parse(&format!(
+                r#"{{"type": "Follow", "actor": "{actor}", "object": "{object}"}}"#
+            ))
+            .unwrap()
+        };
+        assert!(activity_received(
+            &bridge,
+            &follow("https://c.example/carol", ALICE_ACTOR)
+        ));
+        let follow = Value::object([("subject", Value::from(BOB.as_str()))]);
+        assert!(record_received(
+            &bridge,
+            &ALICE,
+            "app.bsky.graph.follow",
+            &follow
+        ));
+        assert_eq!(send_due(&bridge, Instant::now()).unwrap(), 0);
+
+        let later = Instant::now() + bridge.digests.interval;
+        assert_eq!(send_due(&bridge, later).unwrap(), 2);
+        let mut chat = None;
+        let mut note = None;
+        for job in bridge.jobs.queued() {
+            match job.job {
+                Job::SendChatMessage(reply) => chat = Some(reply),
+                Job::Deliver(delivery) => note = Some(json::parse(&delivery.activity).unwrap()),
+                other => panic!("unexpected job {other:?}"),
+            }
+        }
+        let chat = chat.unwrap();
+        assert_eq!((&chat.did, chat.convo_id.as_str()), (&BRIDGE, "convo1"));
+        assert!(chat
+            .text
+            .ends_with("\n- https://c.example/carol followed you"));
+        let note = note.unwrap();
+        assert_eq!(
+            note.get("actor"),
+            Some(&Value::from("https://bridge.example/actor"))
+        );
+        assert!(dm::is_direct(note.get("object").unwrap()));
+    }
+}
@@ -0,0 +1,256 @@
+//! Describing the bridge to clients and other bridges
+//!
+//! Someone deciding whether to be bridged, or software enrolling them, needs to know which
+//! way the bridge goes, how to opt in and what it will and won't do. [`document`] says so as
+//! JSON, served at [`DISCOVERY_PATH`]:
+//!
+//! - `directions`: the networks posts are bridged from and onto, which is one way for a
+//!   [`mirror`]
+//! - `optIn`: how to opt in (`FEDIBRIDGE_OPT_IN_INSTRUCTIONS`), whether an operator approves
+//!   accounts, the terms to agree to and where bridged people manage their bridging
+//! - `policies`: what becomes of DMs, replies to gated threads and communities, and whether
+//!   posts are attributed
+//! - `contact` (`FEDIBRIDGE_CONTACT`) and `documentation` (`FEDIBRIDGE_ABOUT_URL`)
+//!
+//! The bridge's [NodeInfo](https://nodeinfo.diaspora.software) 2.1, linked from
+//! `/.well-known/nodeinfo`, has the same document as its `metadata.bridge`, as that's where
+//! software looking an instance up reads first
+
+use crate::bridge::Bridge;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::Value;
+use crate::mirror;
+use crate::store::MappingStatus;
+use std::sync::Arc;
+
+/// Where the description is served
+pub const DISCOVERY_PATH: &str = "/.well-known/bridge";
+/// Where the NodeInfo document is served, as `/.well-known/nodeinfo` links it
+pub const NODEINFO_DOCUMENT_PATH: &str = "/nodeinfo/2.1";
+const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.1";
+const NODEINFO_PROFILE: &str = "http://nodeinfo.diaspora.software/ns/schema/2.1#";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What operators say of their bridge, beyond what its configuration shows
+pub struct AboutConfig {
+    /// How to reach them
+    pub contact: Option<String>,
+    /// How to ask to be bridged
+    pub opt_in: Option<String>,
+    /// Where to read more
+    pub documentation: Option<String>,
+}
+
+/// The bridge at `hostname`, described as [`DISCOVERY_PATH`] serves it
+pub fn document(bridge: &Bridge, hostname: &str, about: &AboutConfig) -> Value {
+    let directions = mirror::directions(bridge).into_iter().map(|(from, to)| {
+        Value::object([
+            ("from", Value::from(from.as_str())),
+            ("to", Value::from(to.as_str())),
+        ])
+    });
+    let terms = bridge.terms.as_ref().map(|terms| terms.version.as_str());
+    let opt_in = Value::object([
+        ("instructions", Value::from(about.opt_in.clone())),
+        ("approvalRequired", Value::from(bridge.approval_required)),
+        ("terms", Value::from(terms)),
+        (
+            "account",
+            Value::from(format!("https://{hostname}/account")),
+        ),
+    ]);
+    let policies = Value::object([
+        ("directMessages", Value::from("opt-in")),
+        ("bounceDirectMessages", Value::from(bridge.dms.bounce)),
+        (
+            "mirrorOnlyTo",
+            Value::from(bridge.mirror_to.map(|to| to.as_str())),
+        ),
+        (
+            "replyGates",
+            Value::object([
+                ("enforced", Value::from(bridge.reply_gates.enforce)),
+                ("bounced", Value::from(bridge.reply_gates.bounce)),
+            ]),
+        ),
+        (
+            "communities",
+            Value::from(bridge.community_strategy.as_str()),
+        ),
+        (
+            "attribution",
+            Value::object([
+                (
+                    "toFediverse",
+                    Value::from(bridge.attribution.to_fediverse.is_some()),
+                ),
+                (
+                    "toBluesky",
+                    Value::from(bridge.attribution.to_bluesky.is_some()),
+                ),
+            ]),
+        ),
+    ]);
+    Value::object([
+        ("software", Value::from(env!("CARGO_PKG_NAME"))),
+        ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+        ("hostname", Value::from(hostname)),
+        ("directions", Value::Array(directions.collect())),
+        ("optIn", opt_in),
+        ("policies", policies),
+        ("contact", Value::from(about.contact.clone())),
+        ("documentation", Value::from(about.documentation.clone())),
+    ])
+}
+
+/// The bridge's NodeInfo 2.1, with its [`document`] in the metadata
+pub fn nodeinfo(bridge: &Bridge, hostname: &str, about: &AboutConfig) -> Value {
+    let users = bridge
+        .identities
+        .all()
+        .iter()
+        .filter(|mapping| mapping.status == MappingStatus::Active)
+        .count();
+    Value::object([
+        ("version", Value::from("2.1")),
+        (
+            "software",
+            Value::object([
+                ("name", Value::from(env!("CARGO_PKG_NAME"))),
+                ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+            ]),
+        ),
+        ("protocols", Value::Array(vec![Value::from("activitypub")])),
+        (
+            "services",
+            Value::object([
+                ("inbound", Value::Array(Vec::new())),
+                ("outbound", Value::Array(vec![Value::from("atproto")])),
+            ]),
+        ),
+        ("openRegistrations", Value::from(!bridge.approval_required)),
+        (
+            "usage",
+            Value::object([("users", Value::object([("total", Value::from(users))]))]),
+        ),
+        (
+            "metadata",
+            Value::object([("bridge", document(bridge, hostname, about))]),
+        ),
+    ])
+}
+
+/// The description and NodeInfo for `hostname`, in front of `inner`. Without a hostname,
+/// everything is passed on
+pub struct DiscoveryEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    about: AboutConfig,
+    inner: H,
+}
+
+impl<H: Handler> DiscoveryEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> DiscoveryEndpoints<H> {
+        DiscoveryEndpoints {
+            bridge,
+            hostname,
+            about: AboutConfig::default(),
+            inner,
+        }
+    }
+
+    /// Describe the bridge with what operators say of it
+    pub fn with_about(self, about: AboutConfig) -> DiscoveryEndpoints<H> {
+        DiscoveryEndpoints { about, ..self }
+    }
+}
+
+impl<H: Handler> Handler for DiscoveryEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(hostname) = &self.hostname else {
+            return self.inner.handle(request);
+        };
+        let cached = |response: Response| response.with_header("cache-control", "max-age=3600");
+        match (request.method, request.segments().as_slice()) {
+            (Method::Get, [".well-known", "bridge"]) => cached(Response::json(
+                200,
+                &document(&self.bridge, hostname, &self.about),
+            )),
+            (Method::Get, [".well-known", "nodeinfo"]) => {
+                let link = Value::object([
+                    ("rel", Value::from(NODEINFO_SCHEMA)),
+                    (
+                        "href",
+                        Value::from(format!("https://{hostname}{NODEINFO_DOCUMENT_PATH}")),
+                    ),
+                ]);
+                let links = Value::object([("links", Value::Array(vec![link]))]);
+                cached(Response::json(200, &links))
+            }
+            (Method::Get, ["nodeinfo", "2.1"]) => {
+                let nodeinfo = nodeinfo(&self.bridge, hostname, &self.about);
+                let content_type = format!("application/json; profile=\"{NODEINFO_PROFILE}\"");
+                let response = Response::new(200).with_header("content-type", &content_type);
+                cached(response.with_body(nodeinfo.to_string()))
+            }
+            _ => self.inner.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    #[test]
+    fn describes_the_bridge_and_links_it_from_nodeinfo() {
+        let bridge = Bridge::new().with_approval_required(true);
+        let alice = Mapping::new(did!("did:plc:alice"), "https://bridge.example/ap/alice");
+        bridge.identities.insert(alice);
+        let about = AboutConfig {
+            contact: Some("admin@bridge.example".to_string()),
+            opt_in: Some("Follow @bridge.bridge.example".to_string()),
+            documentation: None,
+        };
+        let endpoints = DiscoveryEndpoints::new(
+            Arc::new(bridge),
+            Some("bridge.example".to_string()),
+            |_: &Request| Response::new(404),
+        )
+        .with_about(about);
+        let get = |path: &str| {
+            let response = endpoints.handle(&Request::new(Method::Get, path));
+            assert_eq!(response.status, 200, "{path}");
+            json::parse(&String::from_utf8_lossy(&response.body)).unwrap()
+        };
+
+        let described = get(DISCOVERY_PATH);
+        let opt_in = described.get("optIn").unwrap();
+        assert_eq!(opt_in.get("approvalRequired"), Some(&Value::from(true)));
+        assert_eq!(
+            opt_in.get("instructions").and_then(Value::as_str),
+            Some("Follow @bridge.bridge.example")
+        );
+        assert_eq!(
+            described.get("contact").and_then(Value::as_str),
+            Some("admin@bridge.example")
+        );
+        let directions = described.get("directions").and_then(Value::as_array);
+        assert_eq!(directions.map(|d| d.len()), Some(2));
+
+        let links = get("/.well-known/nodeinfo");
+        let href = links.get("links").and_then(Value::as_array).unwrap()[0].get("href");
+        let href = href.and_then(Value::as_str).unwrap();
+        let nodeinfo = get(href.strip_prefix("https://bridge.example").unwrap());
+        assert_eq!(nodeinfo.get("openRegistrations"), Some(&Value::from(false)));
+        let users = nodeinfo
+            .get("usage")
+            .and_then(|u| u.get("users")?.get("total"));
+        assert_eq!(users.and_then(Value::as_i64), Some(1));
+        let metadata = nodeinfo.get("metadata").and_then(|m| m.get("bridge"));
+        assert_eq!(metadata, Some(&described));
+    }
+}
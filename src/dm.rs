@@ -0,0 +1,570 @@
+//! Direct messages to bridged accounts
+//!
+//! DMs aren't bridged like posts: a "direct" ActivityPub post is only a narrowly addressed
+//! note, which would become a public Bluesky post, and Bluesky chat runs on a separate
+//! service. Builds with the `chat` feature can bridge them between people who have both opted
+//! in (see the `chat` module). Rather than drop the rest without a word, the bridge answers
+//! with a configurable bounce from the bridged account explaining why, at most once per
+//! sender and recipient every [`BOUNCE_COOLDOWN`] so that two bridges can't bounce at each
+//! other forever
+
+use crate::addressing::Addressing;
+use crate::audience::Visibility;
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::html;
+use crate::http::percent_encode;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::language;
+use crate::richtext::Facet;
+use crate::store::{Mapping, MappingStatus};
+use crate::templates::Message;
+use crate::time::unique_millis;
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The collection which addresses a post to everyone
+pub const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+/// The XRPC method chat replies are sent with
+pub const SEND_MESSAGE: &str = "chat.bsky.convo.sendMessage";
+/// The XRPC methods [`ChatClient`] reads with
+pub const GET_CONVO_FOR_MEMBERS: &str = "chat.bsky.convo.getConvoForMembers";
+pub const GET_LOG: &str = "chat.bsky.convo.getLog";
+/// The convo log entry for a new message
+pub const LOG_CREATE_MESSAGE: &str = "chat.bsky.convo.defs#logCreateMessage";
+/// Names which of the bridge's accounts a chat service request is made as
+pub const ACCOUNT_HEADER: &str = "x-bridge-account";
+/// How long before the same sender gets another bounce from the same account
+pub const BOUNCE_COOLDOWN: Duration = Duration::from_secs(3600);
+
+pub const DEFAULT_BOUNCE_MESSAGE: &str = "This account is bridged from another network, and \
+     the bridge can't deliver direct messages. Try mentioning them in a public post instead.";
+
+#[derive(Debug, Clone, PartialEq)]
+/// How direct messages to bridged accounts are answered
+pub struct DmPolicy {
+    /// Whether to bounce DMs at all, rather than dropping them
+    pub bounce: bool,
+    /// Base URL of the XRPC service chat replies are sent through. Without one, chat bounces
+    /// are queued but can't be sent
+    pub chat_service: Option<String>,
+    /// Bearer token letting the bridge use chat as any of its accounts, named in the
+    /// [`ACCOUNT_HEADER`]
+    pub chat_token: Option<String>,
+}
+
+impl Default for DmPolicy {
+    fn default() -> Self {
+        DmPolicy {
+            bounce: true,
+            chat_service: None,
+            chat_token: None,
+        }
+    }
+}
+
+impl DmPolicy {
+    /// A request to the chat service's XRPC `method` (including any query string), made as
+    /// `account`. Procedures have a `body`, queries don't
+    pub fn chat_request(
+        &self,
+        account: &Did,
+        method: &str,
+        body: Option<Value>,
+    ) -> Result<OutboundRequest, DmError> {
+        let service = self.chat_service.as_ref().ok_or(DmError::NotConfigured)?;
+        let url = format!("{}/xrpc/{method}", service.trim_end_matches('/'));
+        let mut request = match body {
+            Some(body) => OutboundRequest::post(url, body.to_string())
+                .with_header("content-type", "application/json"),
+            None => OutboundRequest::get(url),
+        }
+        .with_header(ACCOUNT_HEADER, account.as_str());
+        if let Some(token) = &self.chat_token {
+            request = request.with_header("authorization", &format!("Bearer {token}"));
+        }
+        Ok(request)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A chat message to send from a bridged account
+pub struct ChatReply {
+    /// The bridged account replying
+    pub did: Did,
+    pub convo_id: String,
+    pub text: String,
+    pub facets: Vec<Facet>,
+    pub cause: Option<Cause>,
+}
+
+#[derive(Debug, Error)]
+/// Errors sending a chat reply
+pub enum DmError {
+    #[error("No chat service is configured")]
+    NotConfigured,
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Chat service rejected the request with status {status}")]
+    Rejected { status: u16 },
+    #[error("Invalid response from {method}")]
+    Malformed { method: String },
+}
+
+#[derive(Debug, Default)]
+/// Remembers recent bounces, so each conversation gets one rather than one per message
+pub struct BounceLimiter {
+    sent: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl BounceLimiter {
+    /// Whether `recipient` may bounce a message from `sender` now, recording it if so
+    pub fn allow(&self, sender: &str, recipient: &str, now: Instant) -> bool {
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, at| now.duration_since(*at) < BOUNCE_COOLDOWN);
+        let key = (sender.to_string(), recipient.to_string());
+        if sent.contains_key(&key) {
+            return false;
+        }
+        sent.insert(key, now);
+        true
+    }
+}
+
+/// Everyone an object is addressed to
+pub(crate) fn addresses(object: &Value) -> Vec<&str> {
+    ["to", "cc", "bto", "bcc", "audience"]
+        .iter()
+        .flat_map(|field| match object.get(field) {
+            Some(Value::Array(items)) => items.iter().filter_map(Value::as_str).collect(),
+            Some(item) => item.as_str().into_iter().collect(),
+            None => Vec::new(),
+        })
+        .collect()
+}
+
+/// Whether an object is a direct message: addressed only to particular actors, with no
+/// public or followers collection among its audience
+pub fn is_direct(object: &Value) -> bool {
+    match object.get("type").and_then(Value::as_str) {
+        // Pleroma's chats are always private
+        Some("ChatMessage") => return true,
+        Some("Note" | "Article" | "Question") => {}
+        _ => return false,
+    }
+    let addresses = addresses(object);
+    !addresses.is_empty()
+        && !addresses.iter().any(|address| {
+            matches!(*address, PUBLIC | "as:Public" | "Public") || address.ends_with("/followers")
+        })
+}
+
+/// A `Create` of a `Note` with ID `id` from `actor`, addressed only to `recipient`
+pub(crate) fn direct_note(
+    id: &str,
+    actor: &str,
+    recipient: &str,
+    content: &str,
+    in_reply_to: Option<&str>,
+) -> Value {
+    let mut note = vec![
+        ("id", Value::from(id)),
+        ("type", Value::from("Note")),
+        ("attributedTo", Value::from(actor)),
+        ("content", Value::from(content)),
+        (
+            "tag",
+            Value::Array(vec![Value::object([
+                ("type", Value::from("Mention")),
+                ("href", Value::from(recipient)),
+            ])]),
+        ),
+    ];
+    if let Some(in_reply_to) = in_reply_to {
+        note.push(("inReplyTo", Value::from(in_reply_to)));
+    }
+    let activity = Value::object([
+        (
+            "@context",
+            Value::from("https://www.w3.org/ns/activitystreams"),
+        ),
+        ("id", Value::from(format!("{id}/activity"))),
+        ("type", Value::from("Create")),
+        ("actor", Value::from(actor)),
+        ("object", Value::object(note)),
+    ]);
+    let addressing = Addressing::new(actor, Visibility::Direct).with_mentions([recipient]);
+    addressing.apply(&activity)
+}
+
+/// The bounce `recipient` sends back to `sender`, as a direct reply
+fn bounce_activity(
+    recipient: &Mapping,
+    sender: &str,
+    in_reply_to: Option<&str>,
+    message: &str,
+) -> Value {
+    let id = format!("{}/bounces/{}", recipient.actor, unique_millis());
+    let content = format!("<p>{}</p>", html::escape(message));
+    direct_note(&id, &recipient.actor, sender, &content, in_reply_to)
+}
+
+/// Bounce a fediverse `Create` if it's a direct message to bridged accounts, returning how
+/// many bounces (or bridged messages) were queued
+pub fn direct_message_received(bridge: &Bridge, activity: &Value) -> io::Result<usize> {
+    if activity.get("type").and_then(Value::as_str) != Some("Create") {
+        return Ok(0);
+    }
+    let (Some(object), Some(sender)) = (
+        activity.get("object"),
+        activity.get("actor").and_then(Value::as_str),
+    ) else {
+        return Ok(0);
+    };
+    if !is_direct(object) || bridge.policy.for_actor(&bridge.identities, sender).denied {
+        return Ok(0);
+    }
+    let mut recipients: Vec<Mapping> = Vec::new();
+    for address in addresses(object) {
+        let Some(mapping) = bridge.identities.get_by_actor(address) else {
+            continue;
+        };
+        if mapping.status != MappingStatus::Passive && !recipients.contains(&mapping) {
+            recipients.push(mapping);
+        }
+    }
+    let inbox = delivery::shared_inbox(&bridge.documents, sender);
+    let in_reply_to = object.get("id").and_then(Value::as_str);
+    let mut bounced = 0;
+    for recipient in recipients {
+        #[cfg(feature = "chat")]
+        if crate::chat::direct_message(bridge, sender, &recipient, object)? {
+            bounced += 1;
+            continue;
+        }
+        let Some(inbox) = &inbox else {
+            continue;
+        };
+        if !bridge.dms.bounce
+            || !bridge
+                .bounces
+                .allow(sender, &recipient.actor, Instant::now())
+        {
+            continue;
+        }
+        let languages = [
+            language::languages_of(object),
+            recipient.preferences.languages.clone(),
+        ];
+        let message = bridge
+            .templates
+            .render(Message::DmBounce, &languages.concat(), &[]);
+        let bounce = bounce_activity(&recipient, sender, in_reply_to, &message);
+        let delivery = Delivery::new(inbox, bounce.to_string());
+        bridge.jobs.push(Job::Deliver(
+            delivery.because(Cause::new("DM bounce", in_reply_to)),
+        ))?;
+        bounced += 1;
+    }
+    Ok(bounced)
+}
+
+/// Bounce a Bluesky chat message sent to a bridged account, from an entry of its convo log
+///
+/// Returns whether a bounce (or the bridged message) was queued
+pub fn chat_message_received(bridge: &Bridge, recipient: &Did, entry: &Value) -> io::Result<bool> {
+    if entry.get("$type").and_then(Value::as_str) != Some(LOG_CREATE_MESSAGE) {
+        return Ok(false);
+    }
+    let convo_id = entry.get("convoId").and_then(Value::as_str);
+    let sender = entry
+        .get("message")
+        .and_then(|m| m.get("sender"))
+        .and_then(|s| s.get("did"))
+        .and_then(Value::as_str);
+    let (Some(convo_id), Some(sender)) = (convo_id, sender) else {
+        return Ok(false);
+    };
+    // The bridged account's own messages, including earlier bounces, show up in the log too
+    if sender == recipient.as_str() {
+        return Ok(false);
+    }
+    #[cfg(feature = "chat")]
+    if crate::chat::chat_message(bridge, recipient, entry)? {
+        return Ok(true);
+    }
+    if !bridge.dms.bounce
+        || !bridge.identities.is_tracked(recipient)
+        || !bridge
+            .bounces
+            .allow(sender, recipient.as_str(), Instant::now())
+    {
+        return Ok(false);
+    }
+    let languages = bridge
+        .identities
+        .get(recipient)
+        .map(|mapping| mapping.preferences.languages)
+        .unwrap_or_default();
+    bridge.jobs.push(Job::SendChatMessage(ChatReply {
+        did: recipient.clone(),
+        convo_id: convo_id.to_string(),
+        text: bridge.templates.render(Message::DmBounce, &languages, &[]),
+        facets: Vec::new(),
+        cause: Some(Cause::new(
+            "DM bounce",
+            entry
+                .get("message")
+                .and_then(|m| m.get("id"))
+                .and_then(Value::as_str),
+        )),
+    }))?;
+    Ok(true)
+}
+
+/// Send a chat message through the configured chat service
+pub fn send_chat(
+    transport: &dyn HttpTransport,
+    policy: &DmPolicy,
+    reply: &ChatReply,
+) -> Result<(), DmError> {
+    let mut message = vec![("text", Value::from(reply.text.as_str()))];
+    if !reply.facets.is_empty() {
+        let facets = reply.facets.iter().map(Facet::to_json).collect();
+        message.push(("facets", Value::Array(facets)));
+    }
+    let body = Value::object([
+        ("convoId", Value::from(reply.convo_id.as_str())),
+        ("message", Value::object(message)),
+    ]);
+    let request = policy.chat_request(&reply.did, SEND_MESSAGE, Some(body))?;
+    let response = transport.send(&request)?;
+    if !response.is_success() {
+        return Err(DmError::Rejected {
+            status: response.status,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A page of an account's convo log
+pub struct LogPage {
+    pub logs: Vec<Value>,
+    /// Where the next page starts
+    pub cursor: Option<String>,
+}
+
+/// A `chat.bsky.convo` client, through the chat service the bridge uses as its accounts
+pub struct ChatClient<'a> {
+    transport: &'a dyn HttpTransport,
+    policy: &'a DmPolicy,
+}
+
+impl<'a> ChatClient<'a> {
+    pub fn new(transport: &'a dyn HttpTransport, policy: &'a DmPolicy) -> ChatClient<'a> {
+        ChatClient { transport, policy }
+    }
+
+    /// Call the query `method` as `account`
+    fn query(
+        &self,
+        account: &Did,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, DmError> {
+        let mut path = method.to_string();
+        for (i, (name, value)) in params.iter().enumerate() {
+            path.push(if i == 0 { '?' } else { '&' });
+            path.push_str(&format!("{name}={}", percent_encode(value)));
+        }
+        let request = self.policy.chat_request(account, &path, None)?;
+        let response = self.transport.send(&request)?;
+        if !response.is_success() {
+            return Err(DmError::Rejected {
+                status: response.status,
+            });
+        }
+        std::str::from_utf8(&response.body)
+            .ok()
+            .and_then(|body| json::parse(body).ok())
+            .ok_or_else(|| DmError::Malformed {
+                method: method.to_string(),
+            })
+    }
+
+    /// The ID of `account`'s conversation with `members`, which is started if need be
+    pub fn get_convo_for_members(
+        &self,
+        account: &Did,
+        members: &[&Did],
+    ) -> Result<String, DmError> {
+        let params: Vec<_> = members.iter().map(|m| ("members", m.as_str())).collect();
+        let response = self.query(account, GET_CONVO_FOR_MEMBERS, &params)?;
+        response
+            .get("convo")
+            .and_then(|convo| convo.get("id"))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| DmError::Malformed {
+                method: GET_CONVO_FOR_MEMBERS.to_string(),
+            })
+    }
+
+    /// The entries of `account`'s convo log (across all its conversations) after `cursor`
+    pub fn get_log(&self, account: &Did, cursor: Option<&str>) -> Result<LogPage, DmError> {
+        let params: Vec<_> = cursor
+            .map(|cursor| ("cursor", cursor))
+            .into_iter()
+            .collect();
+        let response = self.query(account, GET_LOG, &params)?;
+        let logs = response
+            .get("logs")
+            .and_then(Value::as_array)
+            .ok_or_else(|| DmError::Malformed {
+                method: GET_LOG.to_string(),
+            })?;
+        Ok(LogPage {
+            logs: logs.to_vec(),
+            cursor: response
+                .get("cursor")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::templates::Templates;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ACTOR: &str = "https://bridge.example/users/alice";
+
+    fn bridge() -> Bridge {
+        let bridge = Bridge::new();
+        bridge.identities.insert(Mapping::new(ALICE, ACTOR));
+        bridge
+    }
+
+    fn create(to: &str, cc: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Create", "actor": "https://a.example/users/bob",
+                "object": {{"id": "https://a.example/notes/1", "type": "Note",
+                            "to": [{to}], "cc": [{cc}], "content": "hi"}}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn detects_direct_messages() {
+        let to_alice = format!("\"{ACTOR}\"");
+        assert!(is_direct(create(&to_alice, "").get("object").unwrap()));
+        for (to, cc) in [
+            (format!("\"{PUBLIC}\""), to_alice.clone()),
+            (
+                "\"https://a.example/users/bob/followers\"".to_string(),
+                to_alice.clone(),
+            ),
+            (to_alice.clone(), "\"as:Public\"".to_string()),
+        ] {
+            assert!(!is_direct(create(&to, &cc).get("object").unwrap()));
+        }
+        assert!(is_direct(
+            &json::parse(r#"{"type": "ChatMessage", "to": "https://x.example/u"}"#).unwrap()
+        ));
+    }
+
+    #[test]
+    fn direct_messages_bounce_once() {
+        let bridge = bridge();
+        let message = create(&format!("\"{ACTOR}\""), "");
+        assert_eq!(direct_message_received(&bridge, &message).unwrap(), 1);
+        let jobs = bridge.jobs.queued();
+        let Job::Deliver(delivery) = &jobs[0].job else {
+            panic!("expected a delivery");
+        };
+        assert_eq!(delivery.inbox, "https://a.example/inbox");
+        let bounce = json::parse(&delivery.activity).unwrap();
+        assert_eq!(bounce.get("actor"), Some(&Value::from(ACTOR)));
+        let note = bounce.get("object").unwrap();
+        assert_eq!(
+            note.get("inReplyTo"),
+            Some(&Value::from("https://a.example/notes/1"))
+        );
+        assert!(is_direct(note));
+        // Further messages in the same conversation aren't bounced again
+        assert_eq!(direct_message_received(&bridge, &message).unwrap(), 0);
+
+        let public = create(&format!("\"{PUBLIC}\""), &format!("\"{ACTOR}\""));
+        assert_eq!(direct_message_received(&bridge, &public).unwrap(), 0);
+        let quiet = Bridge {
+            dms: DmPolicy {
+                bounce: false,
+                ..DmPolicy::default()
+            },
+            ..self::bridge()
+        };
+        assert_eq!(direct_message_received(&quiet, &message).unwrap(), 0);
+
+        // Bounces are worded in the language the message was written in
+        let german = Bridge {
+            templates: Templates::new().with(Message::DmBounce, Some("de"), "Keine DMs"),
+            ..self::bridge()
+        };
+        let message = json::parse(&format!(
+            r#"{{"type": "Create", "actor": "https://a.example/users/bob",
+                "object": {{"id": "https://a.example/notes/2", "type": "Note", "to": ["{ACTOR}"],
+                            "contentMap": {{"de": "hallo"}}}}}}"#
+        ))
+        .unwrap();
+        assert_eq!(direct_message_received(&german, &message).unwrap(), 1);
+        let Job::Deliver(delivery) = &german.jobs.queued()[0].job else {
+            panic!("expected a delivery");
+        };
+        assert!(delivery.activity.contains("<p>Keine DMs</p>"));
+    }
+
+    #[test]
+    fn chat_messages_bounce() {
+        let bridge = bridge();
+        let entry = |sender: &str| {
+            Value::object([
+                ("$type", Value::from(LOG_CREATE_MESSAGE)),
+                ("convoId", Value::from("convo1")),
+                (
+                    "message",
+                    Value::object([
+                        ("text", Value::from("hello?")),
+                        ("sender", Value::object([("did", Value::from(sender))])),
+                    ]),
+                ),
+            ])
+        };
+        assert!(!chat_message_received(&bridge, &ALICE, &entry(ALICE.as_str())).unwrap());
+        assert!(chat_message_received(&bridge, &ALICE, &entry("did:plc:bob")).unwrap());
+        assert!(!chat_message_received(&bridge, &ALICE, &entry("did:plc:bob")).unwrap());
+        let jobs = bridge.jobs.queued();
+        assert_eq!(
+            jobs[0].job,
+            Job::SendChatMessage(ChatReply {
+                did: ALICE,
+                convo_id: "convo1".to_string(),
+                text: DEFAULT_BOUNCE_MESSAGE.to_string(),
+                facets: Vec::new(),
+                cause: Some(Cause::new("DM bounce", None)),
+            })
+        );
+    }
+}
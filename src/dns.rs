@@ -0,0 +1,529 @@
+//! Resolving names the way operators configure
+//!
+//! Containers are often given a resolver which is slow, filters what it answers, or won't be
+//! asked for TXT records at all, so the bridge can bring its own. A [`DnsResolver`] asks the
+//! system's resolver unless its [`DnsConfig`] names DNS servers, asked over UDP, or a
+//! DNS-over-HTTPS endpoint, asked through the bridge's transport. Answers are cached for their
+//! TTL, kept within the configured bounds, and addresses are put in the configured
+//! [`AddressPreference`]'s order for [`StdTransport`](crate::transport::StdTransport), which
+//! races each against the last after [`DnsConfig::attempt_delay`] (RFC 8305's happy eyeballs).
+//!
+//! Besides addresses, the resolver answers the `_atproto` TXT lookups which verify handles
+
+use crate::normalize;
+use crate::transport::{HttpTransport, OutboundRequest};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const A: u16 = 1;
+const TXT: u16 = 16;
+const AAAA: u16 = 28;
+
+/// How long a DNS server has to answer over UDP before the next is asked
+#[cfg(feature = "net")]
+const UDP_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The order addresses are tried in
+pub enum AddressPreference {
+    /// Whatever order the answer had
+    System,
+    Ipv4,
+    Ipv6,
+    /// Alternately, IPv6 first, so a broken path on either family costs one attempt delay
+    #[default]
+    Interleave,
+}
+
+impl AddressPreference {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddressPreference::System => "system",
+            AddressPreference::Ipv4 => "ipv4",
+            AddressPreference::Ipv6 => "ipv6",
+            AddressPreference::Interleave => "interleave",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<AddressPreference> {
+        [
+            AddressPreference::System,
+            AddressPreference::Ipv4,
+            AddressPreference::Ipv6,
+            AddressPreference::Interleave,
+        ]
+        .into_iter()
+        .find(|preference| preference.as_str() == s)
+    }
+
+    pub fn order(&self, addresses: Vec<IpAddr>) -> Vec<IpAddr> {
+        let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) =
+            addresses.iter().partition(|address| address.is_ipv6());
+        match self {
+            AddressPreference::System => addresses,
+            AddressPreference::Ipv4 => v4.into_iter().chain(v6).collect(),
+            AddressPreference::Ipv6 => v6.into_iter().chain(v4).collect(),
+            AddressPreference::Interleave => {
+                let mut ordered = Vec::with_capacity(addresses.len());
+                let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+                loop {
+                    match (v6.next(), v4.next()) {
+                        (None, None) => return ordered,
+                        (first, second) => ordered.extend(first.into_iter().chain(second)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Who names are resolved by
+pub enum Upstream {
+    /// The system's resolver, which can only be asked for addresses
+    #[default]
+    System,
+    /// DNS servers over UDP, in order, falling back to TCP for answers too large for UDP
+    Servers(Vec<SocketAddr>),
+    /// A DNS-over-HTTPS endpoint, such as `https://dns.example/dns-query`
+    OverHttps(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsConfig {
+    pub upstream: Upstream,
+    /// Answers are cached for at least this long, and failures and the system resolver's
+    /// answers (which don't say) for exactly this long
+    pub min_ttl: Duration,
+    /// Answers are cached for at most this long. Zero turns caching off
+    pub max_ttl: Duration,
+    pub prefer: AddressPreference,
+    /// How long a connection attempt has before the next address is tried alongside it, or
+    /// `None` to try them one at a time
+    pub attempt_delay: Option<Duration>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            upstream: Upstream::default(),
+            min_ttl: Duration::from_secs(30),
+            max_ttl: Duration::from_secs(60 * 60),
+            prefer: AddressPreference::default(),
+            attempt_delay: Some(Duration::from_millis(250)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Address(IpAddr),
+    Text(String),
+}
+
+#[derive(Debug)]
+struct Cached {
+    records: Result<Vec<Record>, String>,
+    expires: Instant,
+}
+
+/// A caching resolver, asking its [`Upstream`]
+pub struct DnsResolver {
+    pub config: DnsConfig,
+    /// What DNS-over-HTTPS queries are sent through
+    transport: Option<Arc<dyn HttpTransport>>,
+    cache: Mutex<HashMap<(String, u16), Cached>>,
+}
+
+impl std::fmt::Debug for DnsResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("DnsResolver")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        DnsResolver::new(DnsConfig::default(), None)
+    }
+}
+
+impl DnsResolver {
+    /// A resolver sending DNS-over-HTTPS queries, if it's configured to, through `transport`
+    pub fn new(config: DnsConfig, transport: Option<Arc<dyn HttpTransport>>) -> DnsResolver {
+        DnsResolver {
+            config,
+            transport,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// The addresses of `host`, in the preferred order
+    pub fn addresses(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(address) = host.parse() {
+            return Ok(vec![address]);
+        }
+        let mut addresses = Vec::new();
+        let mut failure = None;
+        let qtypes: &[u16] = match self.config.upstream {
+            // The system's resolver answers for both families at once
+            Upstream::System => &[A],
+            _ => &[AAAA, A],
+        };
+        for &qtype in qtypes {
+            match self.records(host, qtype) {
+                Ok(records) => {
+                    addresses.extend(records.into_iter().filter_map(|record| match record {
+                        Record::Address(address) => Some(address),
+                        Record::Text(_) => None,
+                    }))
+                }
+                Err(e) => failure = Some(e),
+            }
+        }
+        match failure {
+            Some(e) if addresses.is_empty() => Err(e),
+            _ => Ok(self.config.prefer.order(addresses)),
+        }
+    }
+
+    /// The TXT records of `name`, each one's strings joined
+    pub fn txt(&self, name: &str) -> io::Result<Vec<String>> {
+        let records = self.records(name, TXT)?;
+        let texts = records.into_iter().filter_map(|record| match record {
+            Record::Text(text) => Some(text),
+            Record::Address(_) => None,
+        });
+        Ok(texts.collect())
+    }
+
+    /// The DID `handle`'s `_atproto` TXT record names, if it has one
+    pub fn atproto_did(&self, handle: &str) -> io::Result<Option<Did>> {
+        let texts = self.txt(&format!("_atproto.{handle}"))?;
+        Ok(texts.into_iter().find_map(|text| {
+            let did = text.strip_prefix("did=")?;
+            normalize::parse_did(did).ok()
+        }))
+    }
+
+    /// Drop whatever is cached for `name`, so the next lookup asks again
+    pub fn forget(&self, name: &str) {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.cache
+            .lock()
+            .unwrap()
+            .retain(|(cached, _), _| *cached != name);
+    }
+
+    fn records(&self, name: &str, qtype: u16) -> io::Result<Vec<Record>> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let key = (name, qtype);
+        let now = Instant::now();
+        if let Some(cached) = self.cache.lock().unwrap().get(&key) {
+            if cached.expires > now {
+                return cached.records.clone().map_err(io::Error::other);
+            }
+        }
+        let (records, ttl) = match self.ask(&key.0, qtype) {
+            Ok((records, ttl)) => (Ok(records), ttl),
+            Err(e) => (Err(e.to_string()), Duration::ZERO),
+        };
+        let ttl = ttl.clamp(
+            self.config.min_ttl,
+            self.config.max_ttl.max(self.config.min_ttl),
+        );
+        let mut cache = self.cache.lock().unwrap();
+        if self.config.max_ttl.is_zero() {
+            cache.clear();
+        } else {
+            cache.retain(|_, cached| cached.expires > now);
+            cache.insert(
+                key,
+                Cached {
+                    records: records.clone(),
+                    expires: now + ttl,
+                },
+            );
+        }
+        records.map_err(io::Error::other)
+    }
+
+    /// Ask upstream, returning what it answered and for how long that can be cached
+    fn ask(&self, name: &str, qtype: u16) -> io::Result<(Vec<Record>, Duration)> {
+        let (records, ttl) = match &self.config.upstream {
+            Upstream::System => {
+                let addresses = system_addresses(name, qtype)?;
+                (addresses.into_iter().map(Record::Address).collect(), 0)
+            }
+            Upstream::Servers(servers) => ask_servers(servers, &query(random_id()?, name, qtype))
+                .and_then(|response| parse_response(&response, qtype))?,
+            Upstream::OverHttps(url) => {
+                let transport = self.transport.as_ref().ok_or_else(|| {
+                    io::Error::other("Nothing to send DNS-over-HTTPS queries through")
+                })?;
+                // RFC 8484 asks for an ID of zero, so the answers can be cached by URL
+                let request = OutboundRequest::post(url, query(0, name, qtype))
+                    .with_header("content-type", "application/dns-message")
+                    .with_header("accept", "application/dns-message");
+                let response = transport.send(&request).map_err(io::Error::other)?;
+                if !response.is_success() {
+                    let status = response.status;
+                    return Err(io::Error::other(format!("{url} responded with {status}")));
+                }
+                parse_response(&response.body, qtype)?
+            }
+        };
+        Ok((records, Duration::from_secs(ttl.into())))
+    }
+}
+
+fn system_addresses(name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    if qtype != A {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "The system resolver can only be asked for addresses; configure DNS servers",
+        ));
+    }
+    use std::net::ToSocketAddrs;
+    let addresses = (name, 443).to_socket_addrs()?;
+    Ok(addresses.map(|address| address.ip()).collect())
+}
+
+fn random_id() -> io::Result<u16> {
+    let mut id = [0; 2];
+    crate::crypto::random_bytes(&mut id)?;
+    Ok(u16::from_be_bytes(id))
+}
+
+/// A recursive query for `name`'s `qtype` records
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend(id.to_be_bytes());
+    // Recursion desired, and one question
+    query.extend([1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        query.push(label.len().min(63) as u8);
+        query.extend(&label.as_bytes()[..label.len().min(63)]);
+    }
+    query.push(0);
+    query.extend(qtype.to_be_bytes());
+    query.extend(1u16.to_be_bytes());
+    query
+}
+
+fn malformed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "The DNS answer is malformed")
+}
+
+/// Skip over a possibly compressed name at `at`, returning where what follows it starts
+fn skip_name(message: &[u8], mut at: usize) -> io::Result<usize> {
+    loop {
+        let len = *message.get(at).ok_or_else(malformed)?;
+        match len {
+            0 => return Ok(at + 1),
+            // A pointer elsewhere ends the name here
+            len if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+/// The `qtype` records in a response, and the shortest TTL among them. A name which doesn't
+/// exist has no records
+fn parse_response(message: &[u8], qtype: u16) -> io::Result<(Vec<Record>, u32)> {
+    let u16_at = |at: usize| -> io::Result<u16> {
+        let bytes = message.get(at..at + 2).ok_or_else(malformed)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    };
+    let flags = u16_at(2)?;
+    match flags & 0xf {
+        0 | 3 => {}
+        rcode => return Err(io::Error::other(format!("The DNS server answered {rcode}"))),
+    }
+    let (questions, answers) = (u16_at(4)?, u16_at(6)?);
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(message, at)? + 4;
+    }
+    let mut records = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        at = skip_name(message, at)?;
+        let rtype = u16_at(at)?;
+        let rttl = u32::from(u16_at(at + 4)?) << 16 | u32::from(u16_at(at + 6)?);
+        let len = u16_at(at + 8)? as usize;
+        let data = message.get(at + 10..at + 10 + len).ok_or_else(malformed)?;
+        at += 10 + len;
+        if rtype != qtype {
+            // Such as the CNAMEs leading to the records asked for
+            continue;
+        }
+        let record = match (rtype, data.len()) {
+            (A, 4) => Record::Address(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (AAAA, 16) => Record::Address(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            (TXT, _) => {
+                // One or more length-prefixed strings, which make up one value
+                let mut text = Vec::new();
+                let mut rest = data;
+                while let [len, tail @ ..] = rest {
+                    let len = (*len as usize).min(tail.len());
+                    text.extend(&tail[..len]);
+                    rest = &tail[len..];
+                }
+                Record::Text(String::from_utf8_lossy(&text).into_owned())
+            }
+            _ => return Err(malformed()),
+        };
+        ttl = ttl.min(rttl);
+        records.push(record);
+    }
+    let ttl = if records.is_empty() { 0 } else { ttl };
+    Ok((records, ttl))
+}
+
+#[cfg(feature = "net")]
+fn ask_servers(servers: &[SocketAddr], query: &[u8]) -> io::Result<Vec<u8>> {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, UdpSocket};
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No DNS servers configured");
+    for server in servers {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let asked = UdpSocket::bind(local).and_then(|socket| {
+            socket.connect(server)?;
+            socket.set_read_timeout(Some(UDP_TIMEOUT))?;
+            socket.send(query)?;
+            let mut response = vec![0; 4096];
+            loop {
+                let len = socket.recv(&mut response)?;
+                // Anything else is a stray answer to an earlier query
+                if response[..len.min(2)] == query[..2] {
+                    response.truncate(len);
+                    return Ok(response);
+                }
+            }
+        });
+        let response = match asked {
+            // Truncated, so asked again over TCP
+            Ok(response) if response.get(2).is_some_and(|flags| flags & 0x02 != 0) => {
+                TcpStream::connect_timeout(server, UDP_TIMEOUT).and_then(|mut stream| {
+                    stream.set_read_timeout(Some(UDP_TIMEOUT))?;
+                    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+                    stream.write_all(query)?;
+                    let mut len = [0; 2];
+                    stream.read_exact(&mut len)?;
+                    let mut response = vec![0; u16::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut response)?;
+                    Ok(response)
+                })
+            }
+            asked => asked,
+        };
+        match response {
+            Ok(response) => return Ok(response),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(not(feature = "net"))]
+fn ask_servers(_: &[SocketAddr], _: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DNS servers can only be asked with the net feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    /// An answer to `query` with `records` of its type, each `(ttl, data)`
+    fn answer(query: &[u8], qtype: u16, records: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut answer = query.to_vec();
+        answer[2] |= 0x80;
+        answer[7] = records.len() as u8;
+        for (ttl, data) in records {
+            // A pointer to the question's name
+            answer.extend([0xc0, 12]);
+            answer.extend(qtype.to_be_bytes());
+            answer.extend(1u16.to_be_bytes());
+            answer.extend(ttl.to_be_bytes());
+            answer.extend((data.len() as u16).to_be_bytes());
+            answer.extend(*data);
+        }
+        answer
+    }
+
+    #[test]
+    fn over_https_with_caching_and_preference() {
+        let url = "https://dns.example/dns-query";
+        let mock = Arc::new(MockTransport::new());
+        let respond = |qtype, records: &[(u32, &[u8])]| {
+            let body = answer(&query(0, "pds.example", qtype), qtype, records);
+            mock.respond(
+                Method::Post,
+                url,
+                OutboundResponse::new(200).with_body(body),
+            );
+        };
+        respond(
+            AAAA,
+            &[(
+                300,
+                &[0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+            )],
+        );
+        respond(A, &[(60, &[192, 0, 2, 1]), (60, &[192, 0, 2, 2])]);
+        let config = DnsConfig {
+            upstream: Upstream::OverHttps(url.to_string()),
+            ..DnsConfig::default()
+        };
+        let resolver = DnsResolver::new(config, Some(mock.clone()));
+        let addresses: Vec<String> = resolver
+            .addresses("pds.example")
+            .unwrap()
+            .iter()
+            .map(IpAddr::to_string)
+            .collect();
+        assert_eq!(addresses, ["2001:db8::1", "192.0.2.1", "192.0.2.2"]);
+        // Both answers are cached, under the name however it's written
+        resolver.addresses("PDS.example.").unwrap();
+        assert_eq!(mock.requests_to(url).len(), 2);
+        assert_eq!(resolver.addresses("192.0.2.9").unwrap().len(), 1);
+
+        let ipv4 = AddressPreference::Ipv4.order(resolver.addresses("pds.example").unwrap());
+        assert!(ipv4[0].is_ipv4() && ipv4[2].is_ipv6());
+    }
+
+    #[test]
+    fn atproto_txt_records_name_dids() {
+        let query = query(7, "_atproto.alice.example", TXT);
+        // Split across two strings, as long values are
+        let response = answer(&query, TXT, &[(60, b"\x08did=did:\x0dplc:alice1234")]);
+        let (records, ttl) = parse_response(&response, TXT).unwrap();
+        assert_eq!(records, [Record::Text("did=did:plc:alice1234".to_string())]);
+        assert_eq!(ttl, 60);
+
+        let mut nxdomain = answer(&query, TXT, &[]);
+        nxdomain[3] |= 3;
+        assert_eq!(parse_response(&nxdomain, TXT).unwrap().0, []);
+        let mut refused = answer(&query, TXT, &[]);
+        refused[3] |= 5;
+        assert!(parse_response(&refused, TXT).is_err());
+        assert!(DnsResolver::default()
+            .txt("_atproto.alice.example")
+            .is_err());
+    }
+}
@@ -0,0 +1,486 @@
+//! Checking a deployment's environment before it goes live
+//!
+//! Most of what stops a bridge working isn't in its own code but around it: a hostname which
+//! doesn't resolve, no TLS in front of it, relays it can't reach, a skewed clock which makes
+//! every signature it sends look expired. [`checkup`] tries each of these and reports what it
+//! found, with a fix for anything which failed:
+//!
+//! - `dns`: the configured hostname resolves
+//! - `tls`: it answers over HTTPS
+//! - `relays`: each configured relay answers its health check
+//! - `plc`: the PLC directory answers its health check
+//! - `clock`: the local clock agrees with those servers' `Date` headers, to within
+//!   [`Bridge::max_clock_skew`]
+//! - `keys`: every key in the keystore is well-formed for its algorithm
+//! - `state`: the state directory is writable, and laid out in a [version](FORMAT_VERSION)
+//!   this build reads
+//! - `webfinger`: the instance actor can be found by WebFinger, as remote servers look it up
+//!
+//! `fedibridge doctor` prints the checkup
+
+use crate::bridge::Bridge;
+use crate::cache::ResourceKind;
+use crate::delivery::ACTIVITY_JSON;
+use crate::diagnose::{multikey_type, pem_shaped, Outcome};
+use crate::json::{self, Value};
+use crate::keys::{KeyAlgorithm, KeyOwner, KeyPurpose, StoredKey};
+use crate::mentions::{self, webfinger_link};
+use crate::storage::{StateDir, FORMAT_FILE, FORMAT_VERSION};
+use crate::time::parse_http_date;
+use crate::transport::{OutboundRequest, OutboundResponse};
+use crate::url::Url;
+use std::io;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+/// A check's finding, or what went wrong and how to fix it
+type Finding = Result<String, (String, String)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// One check of the environment
+pub struct Check {
+    /// What was checked, such as `dns` or `clock`
+    pub name: &'static str,
+    pub outcome: Outcome,
+    /// What was found, or what went wrong
+    pub detail: String,
+    /// What to do about it, for checks which failed or couldn't be tried
+    pub fix: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Every check of the environment, in order
+pub struct Checkup {
+    pub checks: Vec<Check>,
+}
+
+impl Checkup {
+    /// Whether no check failed. Those which didn't apply don't count against it
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.outcome != Outcome::Failed)
+    }
+
+    /// The check named `name`
+    pub fn check(&self, name: &str) -> Option<&Check> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let checks = self.checks.iter().map(|check| {
+            Value::object([
+                ("check", Value::from(check.name)),
+                ("outcome", Value::from(check.outcome.as_str())),
+                ("detail", Value::from(check.detail.as_str())),
+                ("fix", Value::from(check.fix.clone())),
+            ])
+        });
+        Value::object([
+            ("passed", Value::Bool(self.passed())),
+            ("checks", Value::Array(checks.collect())),
+        ])
+    }
+
+    fn record(&mut self, name: &'static str, finding: Finding) -> bool {
+        let (outcome, detail, fix) = match finding {
+            Ok(detail) => (Outcome::Passed, detail, None),
+            Err((detail, fix)) => (Outcome::Failed, detail, Some(fix)),
+        };
+        self.checks.push(Check {
+            name,
+            outcome,
+            detail,
+            fix,
+        });
+        outcome == Outcome::Passed
+    }
+
+    fn skip(&mut self, name: &'static str, because: &str, fix: Option<&str>) {
+        self.checks.push(Check {
+            name,
+            outcome: Outcome::Skipped,
+            detail: because.to_string(),
+            fix: fix.map(str::to_string),
+        });
+    }
+}
+
+/// What the servers asked agree the time is
+struct Clocks {
+    /// Each server's `Date`, less the local time it was answered at, in seconds
+    offsets: Vec<(String, i64)>,
+}
+
+impl Clocks {
+    fn note(&mut self, server: &str, response: &OutboundResponse) {
+        let Some(date) = response.header("date").and_then(parse_http_date) else {
+            return;
+        };
+        let now = SystemTime::now();
+        let offset = match date.duration_since(now) {
+            Ok(ahead) => ahead.as_secs() as i64,
+            Err(behind) => -(behind.duration().as_secs() as i64),
+        };
+        self.offsets.push((server.to_string(), offset));
+    }
+}
+
+/// Check the environment of `bridge`, served as `hostname` from `state_dir`
+pub fn checkup(bridge: &Bridge, hostname: Option<&str>, state_dir: &StateDir) -> Checkup {
+    checkup_with(bridge, hostname, state_dir, |host| {
+        bridge.dns.addresses(host)
+    })
+}
+
+/// [`checkup`], resolving hostnames with `lookup`
+pub fn checkup_with(
+    bridge: &Bridge,
+    hostname: Option<&str>,
+    state_dir: &StateDir,
+    lookup: impl Fn(&str) -> io::Result<Vec<IpAddr>>,
+) -> Checkup {
+    let mut checkup = Checkup::default();
+    let mut clocks = Clocks {
+        offsets: Vec::new(),
+    };
+    match hostname {
+        Some(hostname) => {
+            if checkup.record("dns", dns(&lookup, hostname)) {
+                checkup.record("tls", tls(bridge, &mut clocks, hostname));
+            } else {
+                checkup.skip("tls", "Needs dns", None);
+            }
+        }
+        None => {
+            let fix = Some("Set FEDIBRIDGE_HOSTNAME to the bridge's public hostname");
+            checkup.skip("dns", "No hostname configured", fix);
+            checkup.skip("tls", "No hostname configured", fix);
+        }
+    }
+    if bridge.crawl.relays.is_empty() {
+        let fix = "Set FEDIBRIDGE_RELAYS for the network to crawl the bridge's repos";
+        checkup.skip("relays", "No relays configured", Some(fix));
+    } else {
+        checkup.record("relays", relays(bridge, &mut clocks));
+    }
+    checkup.record("plc", plc(bridge, &mut clocks));
+    match clock(bridge, &clocks) {
+        Some(finding) => {
+            checkup.record("clock", finding);
+        }
+        None => checkup.skip("clock", "No server answered with a Date header", None),
+    }
+    checkup.record("keys", keys(bridge));
+    checkup.record("state", state(state_dir));
+    match &bridge.moderation.instance_actor {
+        Some(actor) => {
+            checkup.record("webfinger", webfinger(bridge, actor));
+        }
+        None => {
+            let fix = "Set FEDIBRIDGE_INSTANCE_ACTOR for the bridge to federate as itself";
+            checkup.skip("webfinger", "No instance actor configured", Some(fix));
+        }
+    }
+    checkup
+}
+
+/// GET `url`, returning any response at all
+fn get(bridge: &Bridge, url: &str, accept: &str) -> Result<OutboundResponse, String> {
+    let request = OutboundRequest::get(url).with_header("accept", accept);
+    bridge
+        .transport
+        .send(&request)
+        .map_err(|e| format!("Couldn't reach {url}: {e}"))
+}
+
+fn dns(lookup: &impl Fn(&str) -> io::Result<Vec<IpAddr>>, hostname: &str) -> Finding {
+    let fix = || {
+        format!(
+            "Add A or AAAA records for {hostname} pointing at the bridge, or check its spelling"
+        )
+    };
+    match lookup(hostname) {
+        Ok(addresses) if !addresses.is_empty() => {
+            let addresses: Vec<String> = addresses.iter().map(IpAddr::to_string).collect();
+            Ok(format!("{hostname} resolves to {}", addresses.join(", ")))
+        }
+        Ok(_) => Err((format!("{hostname} has no addresses"), fix())),
+        Err(e) => Err((format!("Couldn't resolve {hostname}: {e}"), fix())),
+    }
+}
+
+fn tls(bridge: &Bridge, clocks: &mut Clocks, hostname: &str) -> Finding {
+    // The bridge's own did:web document is served whatever else is enabled
+    let url = format!("https://{hostname}/.well-known/did.json");
+    let response = get(bridge, &url, "application/json").map_err(|e| {
+        let fix = format!(
+            "Serve {hostname} over HTTPS with a certificate the bridge trusts, adding its CA \
+             with FEDIBRIDGE_TLS_ROOTS if it's a private one"
+        );
+        (e, fix)
+    })?;
+    clocks.note(hostname, &response);
+    Ok(format!(
+        "https://{hostname} answered with {}",
+        response.status
+    ))
+}
+
+fn relays(bridge: &Bridge, clocks: &mut Clocks) -> Finding {
+    let mut healthy = Vec::new();
+    let mut failing = Vec::new();
+    for relay in &bridge.crawl.relays {
+        let url = format!("{}/xrpc/_health", relay.trim_end_matches('/'));
+        match get(bridge, &url, "application/json") {
+            Ok(response) if response.is_success() => {
+                clocks.note(relay, &response);
+                healthy.push(relay.as_str());
+            }
+            Ok(response) => failing.push(format!("{url} responded with {}", response.status)),
+            Err(e) => failing.push(e),
+        }
+    }
+    if !failing.is_empty() {
+        let fix = "Check the relays' URLs in FEDIBRIDGE_RELAYS, and that outbound connections \
+                   to them aren't blocked";
+        return Err((failing.join("; "), fix.to_string()));
+    }
+    Ok(format!("{} answered", healthy.join(", ")))
+}
+
+fn plc(bridge: &Bridge, clocks: &mut Clocks) -> Finding {
+    let resolver = bridge.resolver();
+    let directory = resolver.plc_directory().trim_end_matches('/');
+    let url = format!("{directory}/_health");
+    let fix = || {
+        format!("Check that outbound connections to {directory} aren't blocked, as did:plc DIDs can't be resolved without it")
+    };
+    let response = get(bridge, &url, "application/json").map_err(|e| (e, fix()))?;
+    if !response.is_success() {
+        let detail = format!("{url} responded with {}", response.status);
+        return Err((detail, fix()));
+    }
+    clocks.note(directory, &response);
+    Ok(format!("{directory} answered"))
+}
+
+/// Whether the local clock is within the bridge's tolerance of the servers', if any said
+fn clock(bridge: &Bridge, clocks: &Clocks) -> Option<Finding> {
+    // One server's clock may be off too, so only the closest is trusted
+    let (server, offset) = clocks
+        .offsets
+        .iter()
+        .min_by_key(|(_, offset)| offset.unsigned_abs())?;
+    let skew = Duration::from_secs(offset.unsigned_abs());
+    let direction = if *offset > 0 { "behind" } else { "ahead of" };
+    let detail = format!("{}s {direction} {server}", skew.as_secs());
+    if skew <= bridge.max_clock_skew {
+        return Some(Ok(format!(
+            "Within {}s: {detail}",
+            bridge.max_clock_skew.as_secs()
+        )));
+    }
+    let fix = "Synchronise the system clock with NTP. Remote servers refuse signatures dated \
+               too far from their own time";
+    Some(Err((detail, fix.to_string())))
+}
+
+/// Whether `key`'s public half is published in the form its algorithm calls for
+fn well_formed(key: &StoredKey) -> bool {
+    let public_key = &key.keypair.public_key;
+    match key.keypair.algorithm {
+        KeyAlgorithm::Rsa => pem_shaped(public_key),
+        KeyAlgorithm::Secp256k1 => multikey_type(public_key) == Some("secp256k1"),
+        KeyAlgorithm::P256 => multikey_type(public_key) == Some("P-256"),
+        KeyAlgorithm::Ed25519 => multikey_type(public_key) == Some("Ed25519"),
+    }
+}
+
+fn keys(bridge: &Bridge) -> Finding {
+    let accounts = bridge.identities.all().into_iter();
+    let owners =
+        std::iter::once(KeyOwner::Bridge).chain(accounts.map(|m| KeyOwner::Account(m.did)));
+    let mut checked = 0;
+    let mut malformed = Vec::new();
+    for owner in owners {
+        for purpose in KeyPurpose::ALL {
+            let Some(key) = bridge.keys.current(&owner, purpose) else {
+                continue;
+            };
+            checked += 1;
+            if !well_formed(&key) {
+                malformed.push(key.id());
+            }
+        }
+    }
+    if !malformed.is_empty() {
+        let detail = format!("Malformed: {}", malformed.join(", "));
+        let fix = "Restore the keystore from a snapshot, or rotate these keys for new ones";
+        return Err((detail, fix.to_string()));
+    }
+    Ok(format!("{checked} current keys are well-formed"))
+}
+
+fn state(state_dir: &StateDir) -> Finding {
+    let path = state_dir.path().display();
+    let probe = "doctor-probe";
+    if let Err(e) = state_dir
+        .write(probe, b"")
+        .and_then(|()| state_dir.remove(probe))
+    {
+        let fix = format!("Make {path} writable by the user the bridge runs as");
+        return Err((format!("Couldn't write to {path}: {e}"), fix));
+    }
+    match state_dir.format_version() {
+        Ok(None) => Ok(format!(
+            "Not versioned yet, it's recorded as version {FORMAT_VERSION} on the next start"
+        )),
+        Ok(Some(version)) if version > FORMAT_VERSION => Err((
+            format!("Laid out as version {version}, but this build reads {FORMAT_VERSION}"),
+            "Run the release which wrote it, or restore a snapshot taken before upgrading"
+                .to_string(),
+        )),
+        Ok(Some(version)) => Ok(format!("Laid out as version {version}")),
+        Err(e) => Err((
+            format!("Couldn't read {FORMAT_FILE}: {e}"),
+            format!("Restore {path} from a snapshot"),
+        )),
+    }
+}
+
+fn webfinger(bridge: &Bridge, actor: &str) -> Finding {
+    let fetch_fix =
+        || format!("Serve the instance actor at {actor}, or correct FEDIBRIDGE_INSTANCE_ACTOR");
+    let response = get(bridge, actor, ACTIVITY_JSON).map_err(|e| (e, fetch_fix()))?;
+    if !response.is_success() {
+        let detail = format!("{actor} responded with {}", response.status);
+        return Err((detail, fetch_fix()));
+    }
+    let document = std::str::from_utf8(&response.body)
+        .ok()
+        .and_then(|body| json::parse(body).ok());
+    let username = document
+        .as_ref()
+        .and_then(|document| document.get("preferredUsername")?.as_str());
+    let host = Url::parse(actor).map(|url| url.host).ok();
+    let (Some(username), Some(host)) = (username, host) else {
+        let detail = format!("{actor} has no preferredUsername to look up");
+        return Err((
+            detail,
+            "Give the instance actor a preferredUsername".to_string(),
+        ));
+    };
+    let account = format!("{username}@{host}");
+    bridge
+        .documents
+        .invalidate(ResourceKind::WebFinger, &account);
+    let fix = format!(
+        "Serve /.well-known/webfinger on {host}, answering for acct:{account} with a self link to \
+         {actor}"
+    );
+    let document =
+        mentions::webfinger(bridge, &account).map_err(|e| (e.to_string(), fix.clone()))?;
+    match webfinger_link(&document, "self", Some(ACTIVITY_JSON)) {
+        Some(link) if link == actor => Ok(format!("{account} is {actor}")),
+        Some(link) => Err((format!("{account} links to {link}, not {actor}"), fix)),
+        None => Err((format!("{account} links to no ActivityPub actor"), fix)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::moderation::ModerationConfig;
+    use crate::storage::tests::temp_state_dir;
+    use crate::time::format_http_date;
+    use crate::transport::MockTransport;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    const ACTOR: &str = "https://bridge.example/actor";
+
+    fn resolves(_: &str) -> io::Result<Vec<IpAddr>> {
+        Ok(vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))])
+    }
+
+    fn dated_json(body: &str, date: &str) -> OutboundResponse {
+        OutboundResponse::new(200)
+            .with_header("content-type", "application/json")
+            .with_header("date", date)
+            .with_body(body)
+    }
+
+    #[test]
+    fn a_healthy_deployment_passes() {
+        let mock = Arc::new(MockTransport::new());
+        let date = format_http_date(SystemTime::now());
+        mock.respond(
+            Method::Get,
+            "https://bridge.example/.well-known/did.json",
+            dated_json(r#"{"id": "did:web:bridge.example"}"#, &date),
+        );
+        mock.respond(
+            Method::Get,
+            "https://plc.directory/_health",
+            dated_json(r#"{"version": "1"}"#, &date),
+        );
+        mock.respond_json(
+            ACTOR,
+            r#"{"id": "https://bridge.example/actor", "preferredUsername": "bridge"}"#,
+        );
+        mock.respond_json(
+            "https://bridge.example/.well-known/webfinger?resource=acct:bridge@bridge.example",
+            r#"{"links": [{"rel": "self", "type": "application/activity+json",
+                "href": "https://bridge.example/actor"}]}"#,
+        );
+        let bridge = Bridge::new()
+            .with_transport(mock)
+            .with_moderation(ModerationConfig {
+                instance_actor: Some(ACTOR.to_string()),
+                ..ModerationConfig::default()
+            });
+        let dir = temp_state_dir();
+        dir.stamp_format().unwrap();
+
+        let checkup = checkup_with(&bridge, Some("bridge.example"), &dir, resolves);
+        assert!(checkup.passed(), "{checkup:?}");
+        assert_eq!(checkup.check("clock").unwrap().outcome, Outcome::Passed);
+        assert_eq!(checkup.check("relays").unwrap().outcome, Outcome::Skipped);
+        assert_eq!(
+            checkup.check("state").unwrap().detail,
+            "Laid out as version 1"
+        );
+    }
+
+    #[test]
+    fn failures_come_with_fixes() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond(
+            Method::Get,
+            "https://plc.directory/_health",
+            dated_json("{}", "Mon, 01 Jan 2001 00:00:00 GMT"),
+        );
+        let bridge = Bridge::new().with_transport(mock);
+        let dir = temp_state_dir();
+        dir.write(FORMAT_FILE, b"99").unwrap();
+        let unresolvable = |host: &str| Err(io::Error::other(format!("no such host {host}")));
+
+        let checkup = checkup_with(&bridge, Some("bridge.example"), &dir, unresolvable);
+        assert!(!checkup.passed());
+        let failed: Vec<&str> = checkup
+            .checks
+            .iter()
+            .filter(|check| check.outcome == Outcome::Failed)
+            .map(|check| check.name)
+            .collect();
+        assert_eq!(failed, ["dns", "clock", "state"]);
+        assert!(checkup
+            .checks
+            .iter()
+            .filter(|check| check.outcome == Outcome::Failed)
+            .all(|check| check.fix.is_some()));
+        assert!(checkup.check("clock").unwrap().detail.contains("ahead of"));
+    }
+}
@@ -0,0 +1,213 @@
+//! Running the bridge without writing to either network
+//!
+//! In a dry run the whole pipeline runs as usual and reads from both networks, but every
+//! request which would change something elsewhere (deliveries to fediverse inboxes, XRPC
+//! procedures, PLC operations) is captured to a review log instead of sent, and answered as
+//! if it had succeeded. Operators can check their configuration and what translated posts
+//! look like before going live, with `FEDIBRIDGE_DRY_RUN` set and the admin API's
+//! `GET /admin/dry-run` listing what was captured.
+//!
+//! Only requests are held back. The bridge's own state, such as the repos it hosts, is still
+//! written, so a dry run is best pointed at a state directory which won't be used live
+
+use crate::http::Method;
+use crate::json::Value;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+#[cfg(feature = "net")]
+use crate::tls::Stream;
+
+/// The review log's file in the state directory, one JSON request per line
+pub const REVIEW_FILE: &str = "dry-run.jsonl";
+
+/// Headers left out of the review log, as they hold credentials
+const SECRET_HEADERS: [&str; 2] = ["authorization", "dpop"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A request which would have been sent
+pub struct Captured {
+    pub at: SystemTime,
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// The body, as text. What the bridge writes is JSON
+    pub body: String,
+}
+
+impl Captured {
+    pub fn new(request: &OutboundRequest, at: SystemTime) -> Captured {
+        let headers = request
+            .headers
+            .iter()
+            .filter(|(name, _)| !SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()));
+        Captured {
+            at,
+            method: request.method,
+            url: request.url.clone(),
+            headers: headers.cloned().collect(),
+            body: String::from_utf8_lossy(&request.body).into_owned(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| Value::Array(vec![name.as_str().into(), value.as_str().into()]));
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("method", Value::from(self.method.as_str())),
+            ("url", Value::from(self.url.as_str())),
+            ("headers", Value::Array(headers.collect())),
+            ("body", Value::from(self.body.as_str())),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Captured> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let headers = value
+            .get("headers")?
+            .as_array()?
+            .iter()
+            .filter_map(|header| {
+                let [name, value] = header.as_array()? else {
+                    return None;
+                };
+                Some((name.as_str()?.to_string(), value.as_str()?.to_string()))
+            });
+        Some(Captured {
+            at: parse_rfc3339(field("at")?).ok()?,
+            method: Method::parse(field("method")?)?,
+            url: field("url")?.to_string(),
+            headers: headers.collect(),
+            body: field("body")?.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every request a dry run has held back, oldest first
+pub struct ReviewLog {
+    captured: Mutex<Vec<Captured>>,
+    log: Option<JsonLog>,
+}
+
+impl ReviewLog {
+    /// A log persisted in `dir`, with the requests already there
+    pub fn open(dir: StateDir) -> io::Result<ReviewLog> {
+        let (log, captured) = JsonLog::open(dir, REVIEW_FILE)?;
+        let captured = captured.iter().filter_map(Captured::from_json).collect();
+        Ok(ReviewLog {
+            captured: Mutex::new(captured),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, captured: Captured) -> io::Result<()> {
+        let mut all = self.captured.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([captured.to_json()])?;
+        }
+        all.push(captured);
+        Ok(())
+    }
+
+    /// The last `limit` requests, newest first. Zero means all of them
+    pub fn recent(&self, limit: usize) -> Vec<Captured> {
+        let all = self.captured.lock().unwrap();
+        let limit = match limit {
+            0 => usize::MAX,
+            limit => limit,
+        };
+        all.iter().rev().take(limit).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.captured.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A transport which passes reads on to another, and captures everything else
+pub struct DryRunTransport {
+    inner: Arc<dyn HttpTransport>,
+    log: Arc<ReviewLog>,
+}
+
+impl DryRunTransport {
+    pub fn new(inner: Arc<dyn HttpTransport>, log: Arc<ReviewLog>) -> DryRunTransport {
+        DryRunTransport { inner, log }
+    }
+}
+
+impl HttpTransport for DryRunTransport {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        if matches!(request.method, Method::Get | Method::Head) {
+            return self.inner.send(request);
+        }
+        // Failing to record it loses nothing a live run wouldn't have sent anyway
+        if let Err(e) = self.log.append(Captured::new(request, SystemTime::now())) {
+            eprintln!(
+                "Couldn't capture {} {}: {e}",
+                request.method.as_str(),
+                request.url
+            );
+        }
+        // An empty object stands in for whatever an XRPC procedure would have answered
+        Ok(OutboundResponse::new(200)
+            .with_header("content-type", "application/json")
+            .with_body("{}"))
+    }
+
+    #[cfg(feature = "net")]
+    /// Connections are only opened to read from, as the firehose is
+    fn open(&self, url: &str) -> Result<Box<dyn Stream>, TransportError> {
+        self.inner.open(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn writes_are_captured_and_reads_pass_through() {
+        let dir = temp_state_dir();
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json("https://b.example/users/bob", r#"{"type": "Person"}"#);
+        let log = Arc::new(ReviewLog::open(dir.clone()).unwrap());
+        let transport = DryRunTransport::new(mock.clone(), log.clone());
+
+        let read = OutboundRequest::get("https://b.example/users/bob");
+        assert_eq!(
+            transport.send(&read).unwrap().body,
+            br#"{"type": "Person"}"#
+        );
+        let delivery = OutboundRequest::post("https://b.example/inbox", r#"{"type": "Create"}"#)
+            .with_header("signature", "keyId=\"k\"")
+            .with_header("Authorization", "Bearer secret");
+        assert!(transport.send(&delivery).unwrap().is_success());
+        assert!(mock.requests_to("https://b.example/inbox").is_empty());
+
+        let log = ReviewLog::open(dir).unwrap();
+        let captured = log.recent(0);
+        assert_eq!(captured.len(), 1);
+        assert_eq!(captured[0].method, Method::Post);
+        assert_eq!(captured[0].body, r#"{"type": "Create"}"#);
+        let headers: Vec<&str> = captured[0]
+            .headers
+            .iter()
+            .map(|(n, _)| n.as_str())
+            .collect();
+        assert_eq!(headers, ["signature"]);
+    }
+}
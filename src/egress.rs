@@ -0,0 +1,330 @@
+//! Keeping outbound requests off the operator's own network
+//!
+//! The bridge fetches whatever remote users point it at: actors, keys, media and the pages
+//! links go to. Unchecked, that makes it a way to probe the network it runs on, so an
+//! [`EgressPolicy`] says where requests may go:
+//!
+//! - over its [schemes](EgressPolicy::schemes), to its [ports](EgressPolicy::ports)
+//! - never to loopback, private, link-local or other special-purpose addresses, cloud
+//!   metadata services among them ([`is_public_ip`]), nor to names which are local by
+//!   convention, such as `localhost`, `*.internal` and single labels
+//! - except for the [hosts](EgressPolicy::allowed_hosts) the operator runs alongside the
+//!   bridge, such as their own PDS or relay
+//!
+//! [`StdTransport`](crate::transport::StdTransport) holds every request to its
+//! [policy](crate::transport::StdTransport::with_egress), checking each URL and then every
+//! address its host resolves to. It only connects to addresses it checked, so a name can't
+//! be rebound to a private address in between. [`fetch`] follows redirects, up to
+//! [`EgressPolicy::max_redirects`] and checking each one, as link cards and media need.
+//!
+//! `FEDIBRIDGE_EGRESS_SCHEMES`, `FEDIBRIDGE_EGRESS_PORTS` and
+//! `FEDIBRIDGE_EGRESS_ALLOWED_HOSTS` are comma-separated lists, and
+//! `FEDIBRIDGE_EGRESS_MAX_REDIRECTS` caps redirects
+
+use crate::http::Method;
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use crate::url::Url;
+use std::net::{IpAddr, Ipv4Addr};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Why a request was refused
+pub enum EgressError {
+    #[error("Refusing to fetch {url} over {scheme}")]
+    Scheme { url: String, scheme: String },
+    #[error("Refusing to fetch {url} from port {port}")]
+    Port { url: String, port: u16 },
+    #[error("Refusing to fetch {url}, which isn't a public address")]
+    Private { url: String },
+    #[error("Refusing to connect to {host}, which resolves to {ip}")]
+    Address { host: String, ip: IpAddr },
+    #[error("Too many redirects fetching {url}")]
+    TooManyRedirects { url: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where outbound requests may go
+pub struct EgressPolicy {
+    pub schemes: Vec<String>,
+    pub ports: Vec<u16>,
+    /// Redirects [`fetch`] follows before giving up
+    pub max_redirects: usize,
+    /// Lowercased hosts which may be anywhere, on any port, covering their subdomains
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for EgressPolicy {
+    fn default() -> Self {
+        EgressPolicy {
+            schemes: vec!["https".to_string(), "http".to_string()],
+            ports: vec![80, 443],
+            max_redirects: 3,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl EgressPolicy {
+    /// Whether `host` is one the operator allowed
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|allowed| {
+            host == *allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        })
+    }
+
+    /// Whether `url` may be requested, as far as can be told before its host is resolved
+    pub fn check(&self, url: &Url) -> Result<(), EgressError> {
+        if !self
+            .schemes
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(&url.scheme))
+        {
+            return Err(EgressError::Scheme {
+                url: url.to_string(),
+                scheme: url.scheme.clone(),
+            });
+        }
+        let host = url.host.trim_start_matches('[').trim_end_matches(']');
+        if self.is_allowed_host(host) {
+            return Ok(());
+        }
+        let port = url.port_or_default();
+        if !self.ports.contains(&port) {
+            return Err(EgressError::Port {
+                url: url.to_string(),
+                port,
+            });
+        }
+        let public = match host.parse::<IpAddr>() {
+            Ok(ip) => is_public_ip(ip),
+            Err(_) => is_public_name(host),
+        };
+        if !public {
+            return Err(EgressError::Private {
+                url: url.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether `host` may be reached at `ip`, one of the addresses it resolved to
+    pub fn check_address(&self, host: &str, ip: IpAddr) -> Result<(), EgressError> {
+        if is_public_ip(ip) || self.is_allowed_host(host) {
+            return Ok(());
+        }
+        Err(EgressError::Address {
+            host: host.to_string(),
+            ip,
+        })
+    }
+}
+
+/// Whether a name could be public: not local by convention (`localhost`, `*.local`, single
+/// labels) or a numeric address in disguise
+fn is_public_name(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    let Some((_, tld)) = host.rsplit_once('.') else {
+        return false;
+    };
+    // Numeric final labels are addresses in disguise, like `127.1` or `0x7f.1`
+    let numeric = tld.bytes().all(|b| b.is_ascii_digit()) || tld.starts_with("0x");
+    let local = ["localhost", "local", "internal", "lan", "home.arpa"]
+        .iter()
+        .any(|suffix| host == *suffix || host.ends_with(&format!(".{suffix}")));
+    !numeric && !local
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || a == 0
+        || a >= 240
+        // Carrier-grade NAT, IETF protocol assignments and benchmarking
+        || (a == 100 && (64..128).contains(&b))
+        || (a, b, c) == (192, 0, 0)
+        || (a == 198 && (b == 18 || b == 19)))
+}
+
+/// Whether `ip` is a public address, rather than loopback, private, link-local or another
+/// special-purpose one. Addresses embedding an IPv4 address are judged by it
+pub fn is_public_ip(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V4(ip) => return is_public_ipv4(ip),
+        IpAddr::V6(ip) => ip,
+    };
+    if let Some(mapped) = ip.to_ipv4_mapped() {
+        return is_public_ipv4(mapped);
+    }
+    let segments = ip.segments();
+    let octets = ip.octets();
+    let embedded =
+        |at: usize| Ipv4Addr::new(octets[at], octets[at + 1], octets[at + 2], octets[at + 3]);
+    // NAT64 addresses reach whatever IPv4 address they embed
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        return is_public_ipv4(embedded(12));
+    }
+    // As do 6to4 addresses, through a relay, after their prefix
+    if segments[0] == 0x2002 {
+        return is_public_ipv4(embedded(2));
+    }
+    // Teredo addresses reach the client whose address ends them, inverted, via the server
+    // after their prefix
+    if segments[..2] == [0x2001, 0] {
+        let client = !u32::from(embedded(12));
+        return is_public_ipv4(embedded(4)) && is_public_ipv4(Ipv4Addr::from(client));
+    }
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link-local, the deprecated site-local and documentation ranges
+        || segments[0] & 0xfe00 == 0xfc00
+        || segments[0] & 0xffc0 == 0xfe80
+        || segments[0] & 0xffc0 == 0xfec0
+        || segments[..2] == [0x2001, 0xdb8])
+}
+
+/// Send `request` where `policy` allows, following redirects as long as they stay allowed
+///
+/// Returns the URL the response came from. Only `GET` and `HEAD` requests are redirected;
+/// others get the redirect as their response
+pub fn fetch(
+    transport: &dyn HttpTransport,
+    policy: &EgressPolicy,
+    request: &OutboundRequest,
+) -> Result<(Url, OutboundResponse), TransportError> {
+    let mut url = Url::parse(&request.url)?;
+    let mut request = request.clone();
+    for _ in 0..=policy.max_redirects {
+        policy.check(&url)?;
+        request.url = url.to_string();
+        let response = transport.send(&request)?;
+        let location = response.header("location");
+        let redirected = matches!(request.method, Method::Get | Method::Head);
+        match (response.status, location) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) if redirected => {
+                url = url.join(location)?
+            }
+            _ => return Ok((url, response)),
+        }
+    }
+    Err(EgressError::TooManyRedirects {
+        url: url.to_string(),
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    fn allowed(policy: &EgressPolicy, url: &str) -> bool {
+        policy.check(&Url::parse(url).unwrap()).is_ok()
+    }
+
+    #[test]
+    fn refuses_private_destinations() {
+        let policy = EgressPolicy::default();
+        assert!(allowed(&policy, "https://news.example/story"));
+        assert!(allowed(&policy, "http://93.184.215.14/"));
+        assert!(allowed(&policy, "https://[2606:4700::1111]/"));
+        assert!(allowed(&policy, "http://[2002:5db8:d70e::1]/"));
+        for private in [
+            "http://localhost/",
+            "http://printer.local/",
+            "http://metadata.google.internal/",
+            "http://intranet/",
+            "http://127.0.0.1/",
+            "http://127.1/",
+            "http://10.1.2.3/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:192.168.0.1]/",
+            "http://[64:ff9b::a00:1]/",
+            "http://[2002:7f00:1::]/",
+            "http://[2001:0:5db8:d70e::f5fe:fffe]/",
+            "http://[2001:0:a00:1::5db8:d70e]/",
+            "http://[fec0::1]/",
+            "https://news.example:8443/",
+        ] {
+            assert!(!allowed(&policy, private), "{private}");
+        }
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert!(policy
+            .check_address("news.example", ip("10.0.0.1"))
+            .is_err());
+        assert!(policy
+            .check_address("news.example", ip("2606:4700::1111"))
+            .is_ok());
+
+        let policy = EgressPolicy {
+            allowed_hosts: vec!["pds.internal".to_string()],
+            ..EgressPolicy::default()
+        };
+        assert!(allowed(&policy, "http://pds.internal:2583/xrpc/_health"));
+        assert!(allowed(&policy, "http://a.pds.internal/"));
+        assert!(!allowed(&policy, "http://notpds.internal/"));
+        assert!(policy.check_address("pds.internal", ip("10.0.0.1")).is_ok());
+        let https = EgressPolicy {
+            schemes: vec!["https".to_string()],
+            ..EgressPolicy::default()
+        };
+        assert!(!allowed(&https, "http://news.example/"));
+    }
+
+    #[test]
+    fn redirects_are_checked_and_capped() {
+        let mock = MockTransport::new();
+        let redirect = |to: &str| OutboundResponse::new(302).with_header("location", to);
+        mock.respond(Method::Get, "https://a.example/", redirect("/b"))
+            .respond(
+                Method::Get,
+                "https://a.example/b",
+                redirect("https://c.example/"),
+            )
+            .respond(
+                Method::Get,
+                "https://c.example/",
+                OutboundResponse::new(200),
+            )
+            .respond(
+                Method::Get,
+                "https://d.example/",
+                redirect("http://10.0.0.1/"),
+            )
+            .respond(Method::Get, "https://loop.example/", redirect("/"));
+        let policy = EgressPolicy::default();
+        let get = OutboundRequest::get;
+
+        let (url, response) = fetch(&mock, &policy, &get("https://a.example/")).unwrap();
+        assert_eq!(
+            (url.to_string().as_str(), response.status),
+            ("https://c.example/", 200)
+        );
+        // A public page can't redirect the bridge somewhere private
+        assert!(matches!(
+            fetch(&mock, &policy, &get("https://d.example/")),
+            Err(TransportError::Forbidden(EgressError::Private { url })) if url == "http://10.0.0.1/"
+        ));
+        assert_eq!(mock.requests_to("http://10.0.0.1/").len(), 0);
+        assert!(matches!(
+            fetch(&mock, &policy, &get("https://loop.example/")),
+            Err(TransportError::Forbidden(
+                EgressError::TooManyRedirects { .. }
+            ))
+        ));
+        assert_eq!(mock.requests_to("https://loop.example/").len(), 4);
+    }
+}
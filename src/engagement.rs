@@ -0,0 +1,344 @@
+//! Showing on the fediverse how a bridged post is doing on Bluesky
+//!
+//! Likes and reposts of a bridged post on Bluesky never reach the fediverse, so to the
+//! people reading the bridged copy, nobody seems to have engaged with it. An account can
+//! choose to have them mirrored ([`Preferences::engagement`](crate::store::Preferences)):
+//!
+//! - [`EngagementMirror::Collections`] serves the bridged note with `likes` and `shares`
+//!   collections holding the counts, which Mastodon shows when it fetches the note
+//! - [`EngagementMirror::Footer`] does that, and every [`REFRESH`] also sends an `Update`
+//!   of notes published in the last [`MIRROR_WINDOW`] whose counts have changed, with the
+//!   counts in a last paragraph, to the inboxes it was delivered to
+//!
+//! The counts come from the AppView's view of the Bluesky post the note was bridged from,
+//! and are kept for [`REFRESH`] so that serving a note doesn't ask every time
+
+use crate::appview::AppView;
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::jobs::Job;
+use crate::json::Value;
+use crate::preview::bluesky_post;
+use crate::published::Published;
+use crate::receipts::Outcome;
+use crate::shutdown::Shutdown;
+use crate::time::{format_rfc3339, parse_rfc3339, unique_millis};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long counts are kept before they're asked for again, and how often footers update
+pub const REFRESH: Duration = Duration::from_secs(15 * 60);
+/// How old a note's footer is kept updated until
+pub const MIRROR_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// The class of the paragraph counts are shown in, so the next update replaces it
+const FOOTER_CLASS: &str = "bridged-engagement";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a post's engagement on Bluesky is mirrored
+pub enum EngagementMirror {
+    /// As counts on the note's collections
+    Collections,
+    /// As those, and as a paragraph kept up to date
+    Footer,
+}
+
+impl EngagementMirror {
+    pub const ALL: [EngagementMirror; 2] =
+        [EngagementMirror::Collections, EngagementMirror::Footer];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EngagementMirror::Collections => "collections",
+            EngagementMirror::Footer => "footer",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<EngagementMirror> {
+        EngagementMirror::ALL
+            .into_iter()
+            .find(|mirror| mirror.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub likes: u64,
+    pub shares: u64,
+}
+
+#[derive(Debug, Default)]
+/// The counts of notes' Bluesky posts, as last asked for and as last sent
+pub struct EngagementCounts {
+    fetched: Mutex<HashMap<String, (Counts, Instant)>>,
+    sent: Mutex<HashMap<String, Counts>>,
+}
+
+/// How the owner of `published` has its engagement mirrored, if it does
+fn mirror(bridge: &Bridge, published: &Published) -> Option<EngagementMirror> {
+    let owner = published.document.get("attributedTo")?.as_str()?;
+    bridge
+        .identities
+        .get_by_actor(owner)?
+        .preferences
+        .engagement
+}
+
+/// The counts for the note `id`, if its owner mirrors them. They're asked for again once
+/// older than [`REFRESH`] at `now`, and the last kept while the AppView can't be reached
+pub fn counts(bridge: &Bridge, id: &str, published: &Published, now: Instant) -> Option<Counts> {
+    mirror(bridge, published)?;
+    let cached = bridge.engagement.fetched.lock().unwrap().get(id).copied();
+    if let Some((counts, _)) = cached.filter(|(_, at)| now < *at + REFRESH) {
+        return Some(counts);
+    }
+    let uri = published.origin.as_deref().and_then(bluesky_post)?;
+    let fetched = match AppView::of(bridge).post(&uri) {
+        Ok(Some(post)) => Counts {
+            likes: post.like_count.unwrap_or_default(),
+            shares: post.repost_count.unwrap_or_default(),
+        },
+        Ok(None) => return None,
+        Err(e) => {
+            eprintln!("Couldn't fetch the engagement of {uri}: {e}");
+            return cached.map(|(counts, _)| counts);
+        }
+    };
+    let mut cache = bridge.engagement.fetched.lock().unwrap();
+    cache.insert(id.to_string(), (fetched, now));
+    Some(fetched)
+}
+
+/// A collection of `total` interactions, at `id`
+pub fn collection(id: &str, total: u64) -> Value {
+    Value::object([
+        ("id", Value::from(id)),
+        ("type", Value::from("Collection")),
+        ("totalItems", Value::from(total)),
+    ])
+}
+
+/// `note`, the object `id`, with `likes` and `shares` collections of `counts`
+pub fn with_collections(note: &Value, id: &str, counts: Counts) -> Value {
+    let mut note = note.clone();
+    if let Value::Object(fields) = &mut note {
+        let likes = collection(&format!("{id}/likes"), counts.likes);
+        fields.insert("likes".to_string(), likes);
+        let shares = collection(&format!("{id}/shares"), counts.shares);
+        fields.insert("shares".to_string(), shares);
+    }
+    note
+}
+
+/// `content` with its last footer, if any, replaced by one of `counts`
+pub fn with_footer(content: &str, counts: Counts) -> String {
+    let marker = format!("<p class=\"{FOOTER_CLASS}\">");
+    let body = match content.rfind(&marker) {
+        Some(at) => &content[..at],
+        None => content,
+    };
+    let plural = |n: u64, one: &str, many: &str| match n {
+        1 => format!("1 {one}"),
+        n => format!("{n} {many}"),
+    };
+    format!(
+        "{body}{marker}{} and {} on Bluesky</p>",
+        plural(counts.likes, "like", "likes"),
+        plural(counts.shares, "repost", "reposts")
+    )
+}
+
+/// Queue `Update`s of the notes whose owners keep footers, published in the last
+/// [`MIRROR_WINDOW`] before `now`, whose counts have changed since they were last sent.
+/// Returns how many notes were updated
+pub fn refresh(bridge: &Bridge, now: SystemTime) -> usize {
+    let mut updated = 0;
+    for (id, published) in bridge.published.all() {
+        if published.deleted || mirror(bridge, &published) != Some(EngagementMirror::Footer) {
+            continue;
+        }
+        let note = &published.document;
+        let when = note.get("published").and_then(Value::as_str);
+        let when = when.and_then(|when| parse_rfc3339(when).ok());
+        if when.is_none_or(|when| when + MIRROR_WINDOW < now) {
+            continue;
+        }
+        let Some(counts) = self::counts(bridge, &id, &published, Instant::now()) else {
+            continue;
+        };
+        let sent = bridge.engagement.sent.lock().unwrap().get(&id).copied();
+        if sent.unwrap_or_default() == counts {
+            continue;
+        }
+        // The notes went where their receipts say, whether by the post they were bridged
+        // from or by their own IDs
+        let origin = published.origin.as_deref().and_then(bluesky_post);
+        let mut inboxes: Vec<String> = [Some(id.as_str()), origin.as_deref()]
+            .into_iter()
+            .flatten()
+            .flat_map(|post| bridge.receipts.for_post(post, None))
+            .filter(|receipt| receipt.outcome == Outcome::Delivered)
+            .map(|receipt| receipt.inbox)
+            .collect();
+        inboxes.sort();
+        inboxes.dedup();
+        let content = note.get("content").and_then(Value::as_str);
+        let mut note = with_collections(note, &id, counts);
+        if let Value::Object(fields) = &mut note {
+            let content = with_footer(content.unwrap_or_default(), counts);
+            fields.insert("content".to_string(), Value::from(content));
+            fields.remove("contentMap");
+            fields.insert("updated".to_string(), Value::from(format_rfc3339(now)));
+        }
+        let field = |name| note.get(name).cloned().unwrap_or(Value::Array(Vec::new()));
+        let update = Value::object([
+            (
+                "@context",
+                Value::from("https://www.w3.org/ns/activitystreams"),
+            ),
+            (
+                "id",
+                Value::from(format!("{id}#engagement-{}", unique_millis())),
+            ),
+            ("type", Value::from("Update")),
+            (
+                "actor",
+                note.get("attributedTo").cloned().unwrap_or(Value::Null),
+            ),
+            ("to", field("to")),
+            ("cc", field("cc")),
+            ("object", note.clone()),
+        ]);
+        for inbox in inboxes {
+            let job = Job::Deliver(Delivery::new(inbox, update.to_string()));
+            if let Err(e) = bridge.jobs.push(job) {
+                eprintln!("Couldn't queue the engagement update of {id}: {e}");
+            }
+        }
+        bridge.engagement.sent.lock().unwrap().insert(id, counts);
+        updated += 1;
+    }
+    updated
+}
+
+/// Start a thread refreshing footers every [`REFRESH`], until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_run = Instant::now();
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_run.elapsed() < REFRESH {
+                continue;
+            }
+            last_run = Instant::now();
+            refresh(&bridge, SystemTime::now());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appview::{GET_POST_THREAD, THREAD_VIEW};
+    use crate::audit::Cause;
+    use crate::http::percent_encode;
+    use crate::json;
+    use crate::receipts::Receipt;
+    use crate::store::Mapping;
+    use crate::transport::MockTransport;
+    use atproto::did;
+
+    const ALICE: &str = "https://bridge.example/ap/did:plc:alice";
+    const NOTE: &str = "https://bridge.example/ap/did:plc:alice/notes/1";
+    const POST: &str = "at://did:plc:alice/app.bsky.feed.post/1";
+
+    #[test]
+    fn mirrors_counts_for_those_who_ask() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            &format!(
+                "https://public.api.bsky.app/xrpc/{GET_POST_THREAD}?uri={}&depth=0&parentHeight=0",
+                percent_encode(POST)
+            ),
+            &format!(
+                r#"{{"thread": {{"$type": "{THREAD_VIEW}", "post": {{"uri": "{POST}",
+                    "cid": "bafy", "author": {{"did": "did:plc:alice", "handle": "alice.test"}},
+                    "record": {{}}, "likeCount": 3, "repostCount": 1}}}}}}"#
+            ),
+        );
+        let bridge = Bridge::new().with_transport(mock.clone());
+        let mut alice = Mapping::new(did!("did:plc:alice"), ALICE);
+        bridge.identities.insert(alice.clone());
+        let now = SystemTime::now();
+        let note = json::parse(&format!(
+            r#"{{"id": "{NOTE}", "type": "Note", "attributedTo": "{ALICE}",
+                "content": "<p>hi</p>", "published": "{}",
+                "to": ["https://www.w3.org/ns/activitystreams#Public"]}}"#,
+            format_rfc3339(now)
+        ))
+        .unwrap();
+        let published = Published {
+            document: note.clone(),
+            origin: Some("https://bsky.app/profile/did:plc:alice/post/1".to_string()),
+            deleted: false,
+        };
+        bridge.published.publish(NOTE, published.clone()).unwrap();
+        let create = Delivery::new(
+            "https://m.example/inbox",
+            format!(r#"{{"type": "Create", "actor": "{ALICE}", "object": "{NOTE}"}}"#),
+        )
+        .because(Cause::new("post", Some(POST)));
+        let receipt = Receipt::new(&create, Outcome::Delivered).unwrap();
+        bridge.receipts.append(receipt).unwrap();
+
+        // Nobody asked
+        assert_eq!(counts(&bridge, NOTE, &published, Instant::now()), None);
+        assert_eq!(refresh(&bridge, now), 0);
+
+        alice.preferences.engagement = Some(EngagementMirror::Collections);
+        bridge.identities.insert(alice.clone());
+        let counts = counts(&bridge, NOTE, &published, Instant::now()).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                likes: 3,
+                shares: 1
+            }
+        );
+        let served = with_collections(&note, NOTE, counts);
+        let likes = served
+            .get("likes")
+            .and_then(|likes| likes.get("totalItems"));
+        assert_eq!(likes.and_then(Value::as_i64), Some(3));
+        // Only footers are sent
+        assert_eq!(refresh(&bridge, now), 0);
+
+        alice.preferences.engagement = Some(EngagementMirror::Footer);
+        bridge.identities.insert(alice);
+        assert_eq!(refresh(&bridge, now), 1);
+        let queued = bridge.jobs.queued();
+        let Job::Deliver(update) = &queued[0].job else {
+            panic!("expected a delivery");
+        };
+        assert_eq!(update.inbox, "https://m.example/inbox");
+        let update = json::parse(&update.activity).unwrap();
+        let content = update.get("object").and_then(|note| note.get("content"));
+        let footer = "<p>hi</p><p class=\"bridged-engagement\">3 likes and 1 repost on Bluesky</p>";
+        assert_eq!(content.and_then(Value::as_str), Some(footer));
+        assert_eq!(
+            with_footer(
+                footer,
+                Counts {
+                    likes: 1,
+                    shares: 0
+                }
+            )
+            .matches("<p")
+            .count(),
+            2
+        );
+        // Unchanged, it isn't sent again
+        assert_eq!(refresh(&bridge, now), 0);
+    }
+}
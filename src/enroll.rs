@@ -0,0 +1,288 @@
+//! Enrolling many accounts at once
+//!
+//! An instance admin opting their whole community in gathers everyone's agreement
+//! themselves, then hands the bridge a CSV of the accounts with a header row of
+//! `acct,did,consent_version,proof`:
+//!
+//! - `acct`, the fediverse account as `user@host`, found with WebFinger
+//! - `did`, the DID it's bridged as
+//! - `consent_version`, the version of the [terms](crate::consent) they agreed to, which
+//!   has to be the current one if the bridge has terms
+//! - `proof`, where their agreement can be seen, such as the post they replied to. It's
+//!   kept with their consent
+//!
+//! Every row is checked before anything is enrolled, and [`enroll`] reports on each one, so
+//! a bad row is fixed and the list sent again without enrolling anyone twice
+
+use crate::bridge::Bridge;
+use crate::delivery::ACTIVITY_JSON;
+use crate::json::Value;
+use crate::mappings::csv_rows;
+use crate::mentions::{self, webfinger_link};
+use crate::normalize;
+use crate::store::{Mapping, MappingStatus};
+use atproto::DID::Did;
+use std::collections::HashMap;
+
+/// The header row enrollment lists start with
+pub const HEADER: [&str; 4] = ["acct", "did", "consent_version", "proof"];
+
+#[derive(Debug, Clone, PartialEq)]
+/// What came of one row
+pub enum Outcome {
+    /// Checked, and would be enrolled
+    Valid,
+    Enrolled,
+    /// Already bridged, as the row has it
+    AlreadyEnrolled,
+    /// Not a valid row
+    Invalid(String),
+    /// A valid row, which couldn't be enrolled
+    Refused(String),
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Valid => "valid",
+            Outcome::Enrolled => "enrolled",
+            Outcome::AlreadyEnrolled => "alreadyEnrolled",
+            Outcome::Invalid(_) => "invalid",
+            Outcome::Refused(_) => "refused",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What came of the row on `line` of the list
+pub struct RowResult {
+    pub line: usize,
+    pub acct: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnrollmentReport {
+    pub results: Vec<RowResult>,
+}
+
+impl EnrollmentReport {
+    /// How many rows came to `outcome`, by its [name](Outcome::as_str)
+    pub fn count(&self, outcome: &str) -> usize {
+        let results = self.results.iter();
+        results.filter(|r| r.outcome.as_str() == outcome).count()
+    }
+
+    pub fn to_json(&self) -> Value {
+        let results = self.results.iter().map(|result| {
+            let reason = match &result.outcome {
+                Outcome::Invalid(reason) | Outcome::Refused(reason) => Some(reason.as_str()),
+                _ => None,
+            };
+            Value::object([
+                ("line", Value::from(result.line)),
+                ("acct", Value::from(result.acct.as_str())),
+                ("outcome", Value::from(result.outcome.as_str())),
+                ("reason", Value::from(reason)),
+            ])
+        });
+        let outcomes = ["valid", "enrolled", "alreadyEnrolled", "invalid", "refused"];
+        let counts = outcomes.map(|outcome| (outcome, Value::from(self.count(outcome))));
+        Value::object([
+            ("counts", Value::object(counts)),
+            ("results", Value::Array(results.collect())),
+        ])
+    }
+}
+
+/// A row which has been checked
+struct Row {
+    line: usize,
+    acct: String,
+    did: Did,
+    version: String,
+    proof: String,
+}
+
+/// Check the row on `line`, the first of its account and DID in the list
+fn check(bridge: &Bridge, line: usize, fields: &[String]) -> Result<Row, String> {
+    let [acct, did, version, proof] = fields else {
+        return Err(format!("Expected 4 fields, not {}", fields.len()));
+    };
+    let acct = normalize::acct(acct);
+    match acct.split_once('@') {
+        Some((user, host)) if !user.is_empty() && !host.is_empty() => {}
+        _ => return Err(format!("{acct} isn't a user@host account")),
+    }
+    let did = normalize::parse_did(did).map_err(|_| format!("{did} isn't a DID"))?;
+    let proof = proof.trim();
+    if proof.is_empty() {
+        return Err("No proof of consent".to_string());
+    }
+    let version = version.trim();
+    if let Some(terms) = &bridge.terms {
+        if version != terms.version {
+            return Err(format!(
+                "Agreed to version {version:?} of the terms, not the current {}",
+                terms.version
+            ));
+        }
+    }
+    Ok(Row {
+        line,
+        acct,
+        did,
+        version: version.to_string(),
+        proof: proof.to_string(),
+    })
+}
+
+/// Enroll the accounts listed in `csv`, or with `dry_run` only check they could be
+pub fn enroll(bridge: &Bridge, csv: &str, dry_run: bool) -> Result<EnrollmentReport, String> {
+    let mut rows = csv_rows(csv).map_err(|e| e.to_string())?.into_iter();
+    let header = rows.next().unwrap_or_default();
+    if header.iter().map(|f| f.trim()).ne(HEADER) {
+        return Err(format!("Expected a header row of {}", HEADER.join(",")));
+    }
+    let mut report = EnrollmentReport::default();
+    let mut checked = Vec::new();
+    let (mut accts, mut dids) = (HashMap::new(), HashMap::new());
+    for (line, fields) in (2..).zip(rows) {
+        let row = check(bridge, line, &fields).and_then(|row| {
+            let earlier = accts.get(&row.acct).or(dids.get(&row.did));
+            match earlier {
+                Some(earlier) => Err(format!("Also on line {earlier}")),
+                None => Ok(row),
+            }
+        });
+        match row {
+            Ok(row) => {
+                accts.insert(row.acct.clone(), line);
+                dids.insert(row.did.clone(), line);
+                checked.push(row);
+            }
+            Err(reason) => report.results.push(RowResult {
+                line,
+                acct: fields.first().cloned().unwrap_or_default(),
+                outcome: Outcome::Invalid(reason),
+            }),
+        }
+    }
+    for row in checked {
+        let outcome = enroll_row(bridge, &row, dry_run);
+        report.results.push(RowResult {
+            line: row.line,
+            acct: row.acct,
+            outcome,
+        });
+    }
+    report.results.sort_by_key(|result| result.line);
+    Ok(report)
+}
+
+fn enroll_row(bridge: &Bridge, row: &Row, dry_run: bool) -> Outcome {
+    let document = match mentions::webfinger(bridge, &row.acct) {
+        Ok(document) => document,
+        Err(e) => return Outcome::Refused(e.to_string()),
+    };
+    let Some(actor) = webfinger_link(&document, "self", Some(ACTIVITY_JSON)) else {
+        return Outcome::Refused(format!("WebFinger has no actor for {}", row.acct));
+    };
+    if let Some(existing) = bridge.identities.get(&row.did) {
+        return match existing.status {
+            MappingStatus::Passive => Outcome::Refused(format!("{} is a Bluesky account", row.did)),
+            _ if existing.actor == actor => Outcome::AlreadyEnrolled,
+            _ => Outcome::Refused(format!("{} is bridged as {}", row.did, existing.actor)),
+        };
+    }
+    if let Some(existing) = bridge.identities.get_by_actor(actor) {
+        return Outcome::Refused(format!("{actor} is bridged as {}", existing.did));
+    }
+    if dry_run {
+        return Outcome::Valid;
+    }
+    // Enrolling is an operator's doing, so needs no approval
+    if let Err(e) = bridge.activate(Mapping::new(row.did.clone(), actor)) {
+        return Outcome::Refused(e.to_string());
+    }
+    if bridge.terms.is_some() {
+        let consent = bridge.consent_with_proof(&row.did, &row.version, Some(&row.proof));
+        if let Err(e) = consent {
+            return Outcome::Refused(format!("Enrolled, but consent wasn't recorded: {e}"));
+        }
+    }
+    Outcome::Enrolled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consent::Terms;
+    use crate::transport::MockTransport;
+    use std::sync::Arc;
+
+    fn webfinger(mock: &MockTransport, user: &str) {
+        mock.respond_json(
+            &format!("https://a.example/.well-known/webfinger?resource=acct:{user}@a.example"),
+            &format!(
+                r#"{{"links": [{{"rel": "self", "type": "{ACTIVITY_JSON}",
+                    "href": "https://a.example/users/{user}"}}]}}"#
+            ),
+        );
+    }
+
+    #[test]
+    fn enrolls_valid_rows_and_reports_the_rest() {
+        let mock = Arc::new(MockTransport::new());
+        webfinger(&mock, "alice");
+        webfinger(&mock, "bob");
+        let bridge = Bridge::new().with_transport(mock).with_terms(Terms {
+            version: "2".to_string(),
+            text: "Be nice".to_string(),
+        });
+        let bob = atproto::did!("did:plc:bob");
+        bridge
+            .identities
+            .insert(Mapping::new(bob.clone(), "https://a.example/users/bob"));
+        let csv = "acct,did,consent_version,proof\n\
+            @Alice@a.example,did:plc:alice,2,https://a.example/@admin/1\n\
+            bob@a.example,did:plc:bob,2,https://a.example/@admin/1\n\
+            carol@a.example,did:plc:carol,1,https://a.example/@admin/1\n\
+            alice@a.example,did:plc:alice2,2,https://a.example/@admin/1\n\
+            dave@a.example,did:plc:dave,2,\n\
+            erin@a.example,not a did,2,x\n\
+            frank@a.example,did:plc:frank,2,https://a.example/@admin/1\n";
+
+        let checked = enroll(&bridge, csv, true).unwrap();
+        let outcomes: Vec<_> = checked.results.iter().map(|r| r.outcome.as_str()).collect();
+        let expected = [
+            "valid",
+            "alreadyEnrolled",
+            "invalid",
+            "invalid",
+            "invalid",
+            "invalid",
+            "refused",
+        ];
+        assert_eq!(outcomes, expected);
+        assert_eq!(
+            checked.results[3].outcome,
+            Outcome::Invalid("Also on line 2".into())
+        );
+        let alice = atproto::did!("did:plc:alice");
+        assert!(!bridge.identities.contains(&alice));
+
+        let enrolled = enroll(&bridge, csv, false).unwrap();
+        assert_eq!(enrolled.results[0].outcome, Outcome::Enrolled);
+        assert_eq!(enrolled.count("enrolled"), 1);
+        assert_eq!(
+            bridge.identities.get(&alice).unwrap().actor,
+            "https://a.example/users/alice"
+        );
+        let consent = bridge.consents.history(&alice).pop().unwrap();
+        assert_eq!(consent.proof.as_deref(), Some("https://a.example/@admin/1"));
+        assert!(bridge.consent_state(&alice).allows_bridging());
+
+        assert!(enroll(&bridge, "did,acct\n", false).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! Keeping translated posts within what Bluesky takes of each entity
+//!
+//! A fediverse post can carry more than a Bluesky record may: dozens of hashtags, more
+//! images than an embed holds, links and mentions beyond counting. A record beyond the limits
+//! is refused by the PDS after it's been translated, so [`fit`] brings it within them first,
+//! the same way every time:
+//!
+//! - facets with an index outside the text, or not on character boundaries, are dropped;
+//!   those over the same text are merged into one with each of their features, and of those
+//!   overlapping, the highest-priority is kept
+//! - beyond [`MAX_FACETS`], the lowest-priority facets go first, and of those alike the last.
+//!   Mentions come before links, and links before hashtags
+//! - `tags` lose those already tagged in the text, empty ones and repeats, those longer than
+//!   [`MAX_TAG_LENGTH`], then those beyond [`MAX_TAGS`]
+//! - an embed's images beyond [`MAX_IMAGES`] are dropped, its text having
+//!   [linked to them](crate::media::split_overflow) already where it was translated
+//!
+//! What was dropped is said, for previews to show
+
+use crate::json::Value;
+use crate::media::MAX_IMAGES;
+use crate::richtext::{LINK, MENTION, TAG};
+
+/// The most facets a bridged post is given
+pub const MAX_FACETS: usize = 100;
+/// The most `tags` a post can have, besides those in its text
+pub const MAX_TAGS: usize = 8;
+/// The longest tag, in characters
+pub const MAX_TAG_LENGTH: usize = 64;
+/// The longest tag, in bytes
+const MAX_TAG_BYTES: usize = 640;
+/// Embeds which have images, directly or as their `media`
+const IMAGES: &str = "app.bsky.embed.images";
+const RECORD_WITH_MEDIA: &str = "app.bsky.embed.recordWithMedia";
+
+#[derive(Debug, Clone, PartialEq)]
+/// A record brought within the limits
+pub struct Fitted {
+    pub record: Value,
+    /// What was dropped to fit, in words
+    pub dropped: Vec<String>,
+}
+
+/// A facet's byte range and features
+struct Span {
+    start: usize,
+    end: usize,
+    features: Vec<Value>,
+}
+
+impl Span {
+    fn from_json(facet: &Value, text: &str) -> Option<Span> {
+        let index = facet.get("index")?;
+        let offset = |name| usize::try_from(index.get(name)?.as_i64()?).ok();
+        let (start, end) = (offset("byteStart")?, offset("byteEnd")?);
+        let features = facet.get("features")?.as_array()?.to_vec();
+        let valid = start < end && text.get(start..end).is_some() && !features.is_empty();
+        valid.then_some(Span {
+            start,
+            end,
+            features,
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        Value::object([
+            (
+                "index",
+                Value::object([
+                    ("byteStart", Value::from(self.start)),
+                    ("byteEnd", Value::from(self.end)),
+                ]),
+            ),
+            ("features", Value::Array(self.features.clone())),
+        ])
+    }
+
+    /// Its priority, lower first: that of its most important feature
+    fn priority(&self) -> usize {
+        let priority = |feature: &Value| match feature.get("$type").and_then(Value::as_str) {
+            Some(MENTION) => 0,
+            Some(LINK) => 1,
+            Some(TAG) => 2,
+            _ => 3,
+        };
+        self.features.iter().map(priority).min().unwrap_or(3)
+    }
+
+    /// The hashtags it tags, lowercased
+    fn tags(&self) -> impl Iterator<Item = String> + '_ {
+        let tags = self
+            .features
+            .iter()
+            .filter(|feature| feature.get("$type").and_then(Value::as_str) == Some(TAG));
+        tags.filter_map(|tag| Some(tag.get("tag")?.as_str()?.to_lowercase()))
+    }
+}
+
+/// Plural `noun`, if there are `n` of them
+fn counted(n: usize, noun: &str) -> String {
+    match n {
+        1 => format!("1 {noun}"),
+        n => format!("{n} {noun}s"),
+    }
+}
+
+/// The facets of a post with `text`, within the limits, and how many were dropped rather than
+/// merged
+fn fit_facets(facets: &[Value], text: &str) -> (Vec<Span>, usize) {
+    let mut spans: Vec<Span> = Vec::new();
+    let mut merged = 0;
+    for span in facets
+        .iter()
+        .filter_map(|facet| Span::from_json(facet, text))
+    {
+        match spans
+            .iter_mut()
+            .find(|other| (other.start, other.end) == (span.start, span.end))
+        {
+            Some(same) => {
+                merged += 1;
+                let new = span.features.into_iter();
+                let new: Vec<Value> = new.filter(|f| !same.features.contains(f)).collect();
+                same.features.extend(new);
+            }
+            None => spans.push(span),
+        }
+    }
+    // Of those overlapping, the most important is kept, and the earliest of those alike
+    let mut ranked: Vec<usize> = (0..spans.len()).collect();
+    ranked.sort_by_key(|&i| (spans[i].priority(), spans[i].start));
+    let mut kept: Vec<usize> = Vec::new();
+    for i in ranked {
+        let overlaps = kept
+            .iter()
+            .any(|&k| spans[k].start < spans[i].end && spans[i].start < spans[k].end);
+        if !overlaps && kept.len() < MAX_FACETS {
+            kept.push(i);
+        }
+    }
+    let dropped = facets.len() - merged - kept.len();
+    kept.sort_by_key(|&i| spans[i].start);
+    let mut spans: Vec<Option<Span>> = spans.into_iter().map(Some).collect();
+    let kept = kept.into_iter().filter_map(|i| spans[i].take()).collect();
+    (kept, dropped)
+}
+
+/// `tags` within the limits, without those tagged in the text, and how many were dropped
+fn fit_tags(tags: &[Value], tagged: &[String]) -> (Vec<Value>, usize) {
+    let mut kept: Vec<&str> = Vec::new();
+    for tag in tags.iter().filter_map(Value::as_str) {
+        let tag = tag.trim().trim_start_matches('#');
+        let lower = tag.to_lowercase();
+        let fits = tag.chars().count() <= MAX_TAG_LENGTH && tag.len() <= MAX_TAG_BYTES;
+        let repeated = tagged.contains(&lower) || kept.iter().any(|k| k.to_lowercase() == lower);
+        if !tag.is_empty() && fits && !repeated && kept.len() < MAX_TAGS {
+            kept.push(tag);
+        }
+    }
+    let dropped = tags.len() - kept.len();
+    (kept.into_iter().map(Value::from).collect(), dropped)
+}
+
+/// `embed` with no more images than it can hold, and how many were dropped
+fn fit_embed(embed: &Value) -> (Value, usize) {
+    let mut embed = embed.clone();
+    let kind = embed
+        .get("$type")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let Value::Object(fields) = &mut embed else {
+        return (embed, 0);
+    };
+    let images = match kind.as_deref() {
+        Some(IMAGES) => fields.get_mut("images"),
+        Some(RECORD_WITH_MEDIA) => match fields.get_mut("media") {
+            Some(Value::Object(media)) if media.get("$type") == Some(&Value::from(IMAGES)) => {
+                media.get_mut("images")
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    match images {
+        Some(Value::Array(images)) if images.len() > MAX_IMAGES => {
+            let dropped = images.len() - MAX_IMAGES;
+            images.truncate(MAX_IMAGES);
+            (embed, dropped)
+        }
+        _ => (embed, 0),
+    }
+}
+
+/// `record`, an `app.bsky.feed.post` or its rich text, brought within Bluesky's limits
+pub fn fit(record: &Value) -> Fitted {
+    let mut record = record.clone();
+    let mut dropped = Vec::new();
+    let Value::Object(fields) = &mut record else {
+        return Fitted { record, dropped };
+    };
+    let text = fields.get("text").and_then(Value::as_str);
+    let text = text.unwrap_or_default().to_string();
+    let mut tagged = Vec::new();
+    if let Some(Value::Array(facets)) = fields.get("facets") {
+        let (spans, gone) = fit_facets(facets, &text);
+        if gone > 0 {
+            dropped.push(format!("{} beyond {MAX_FACETS}", counted(gone, "facet")));
+        }
+        tagged = spans.iter().flat_map(Span::tags).collect();
+        let facets = spans.iter().map(Span::to_json).collect();
+        fields.insert("facets".to_string(), Value::Array(facets));
+    }
+    if let Some(Value::Array(tags)) = fields.get("tags") {
+        let (tags, gone) = fit_tags(tags, &tagged);
+        if gone > 0 {
+            dropped.push(format!("{} beyond {MAX_TAGS}", counted(gone, "tag")));
+        }
+        match tags.is_empty() {
+            true => fields.remove("tags"),
+            false => fields.insert("tags".to_string(), Value::Array(tags)),
+        };
+    }
+    if let Some(embed) = fields.get("embed") {
+        let (embed, gone) = fit_embed(embed);
+        if gone > 0 {
+            dropped.push(format!("{} beyond {MAX_IMAGES}", counted(gone, "image")));
+        }
+        fields.insert("embed".to_string(), embed);
+    }
+    Fitted { record, dropped }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn facet(start: usize, end: usize, kind: &str, field: &str, value: &str) -> String {
+        format!(
+            r#"{{"index": {{"byteStart": {start}, "byteEnd": {end}}},
+                "features": [{{"$type": "{kind}", "{field}": "{value}"}}]}}"#
+        )
+    }
+
+    #[test]
+    fn records_are_brought_within_the_limits() {
+        let text = format!("@bob https://x.example #rail {}", "#t ".repeat(120));
+        let mut facets = vec![
+            facet(0, 4, MENTION, "did", "did:plc:bob"),
+            // The same text linked too is merged into the mention
+            facet(0, 4, LINK, "uri", "https://b.example/bob"),
+            facet(5, 22, LINK, "uri", "https://x.example"),
+            // Overlapping the link, and less important
+            facet(10, 22, TAG, "tag", "x"),
+            facet(23, 28, TAG, "tag", "Rail"),
+            // Beyond the text
+            facet(500, 510, TAG, "tag", "gone"),
+        ];
+        facets.extend((0..120).map(|n| facet(29 + 3 * n, 31 + 3 * n, TAG, "tag", "t")));
+        let tags: Vec<String> = ["RAIL", "#", "#Trains", "trains"]
+            .into_iter()
+            .map(String::from)
+            .chain((0..10).map(|n| format!("more{n}")))
+            .chain(["x".repeat(65)])
+            .collect();
+        let tags: Vec<String> = tags.iter().map(|tag| format!("\"{tag}\"")).collect();
+        let images = [r#"{"alt": ""}"#; 6].join(",");
+        let record = json::parse(&format!(
+            r#"{{"text": "{text}", "facets": [{}], "tags": [{}],
+                "embed": {{"$type": "app.bsky.embed.recordWithMedia",
+                    "media": {{"$type": "app.bsky.embed.images", "images": [{images}]}}}}}}"#,
+            facets.join(","),
+            tags.join(","),
+        ))
+        .unwrap();
+
+        let fitted = fit(&record);
+        let facets = fitted
+            .record
+            .get("facets")
+            .and_then(Value::as_array)
+            .unwrap();
+        assert_eq!(facets.len(), MAX_FACETS);
+        let features = |i: usize| facets[i].get("features").and_then(Value::as_array);
+        assert_eq!(features(0).map(|f| f.len()), Some(2));
+        assert_eq!(
+            features(1).and_then(|f| f[0].get("$type")?.as_str()),
+            Some(LINK)
+        );
+        assert_eq!(
+            Span::from_json(&facets[2], &text).map(|s| s.start),
+            Some(23)
+        );
+        let tags = fitted.record.get("tags").and_then(Value::as_array).unwrap();
+        assert_eq!(tags.len(), MAX_TAGS);
+        assert_eq!(tags[0], Value::from("Trains"));
+        let media = fitted.record.get("embed").and_then(|e| e.get("media"));
+        let images = media.and_then(|m| m.get("images")?.as_array());
+        assert_eq!(images.map(|i| i.len()), Some(MAX_IMAGES));
+        assert_eq!(
+            fitted.dropped,
+            [
+                "25 facets beyond 100",
+                "7 tags beyond 8",
+                "2 images beyond 4"
+            ]
+        );
+        // Within the limits, it's as it was
+        assert_eq!(fit(&fitted.record).record, fitted.record);
+    }
+}
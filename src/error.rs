@@ -0,0 +1,244 @@
+//! What's to be done about a failure
+//!
+//! Not every failure is worth retrying. A PDS answering `502` will likely be back soon, but a
+//! record its lexicon rejects will be rejected however often it's tried, and a `401` waits for
+//! someone to fix the credentials. So each error a job fails with is put in a [`Category`]:
+//!
+//! | Category      | For instance                                    | The job                 |
+//! |---------------|-------------------------------------------------|-------------------------|
+//! | `retryable`   | timeouts, `429` and `5xx`, a throttled account  | is retried, backing off |
+//! | `permanent`   | other `4xx`, invalid records or URLs            | is dead at once         |
+//! | `needs-auth`  | `401` and `403`, a missing signing key          | is dead at once         |
+//! | `needs-human` | work nothing handles, an unconfigured service   | is dead at once         |
+//!
+//! Handlers which know better wrap their error in a [`BridgeError`] of the category it's in;
+//! otherwise it's [classified](classify) by what it is. Anything unrecognized is retryable, as
+//! every failure used to be. Dead jobs keep their category, for operators to tell apart
+//! those waiting on them from those which can be dropped
+
+use crate::delivery::DeliveryError;
+use crate::dm::DmError;
+use crate::lexicon::Invalid;
+use crate::moderation::ReportError;
+use crate::quota::Throttled;
+use crate::repo::RepoError;
+use crate::resolver::ResolveError;
+use crate::resync::ResyncError;
+use crate::transport::TransportError;
+use crate::webhooks::WebhookError;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What's to be done about a failure
+pub enum Category {
+    /// Try again later
+    Retryable,
+    /// Trying again won't help
+    Permanent,
+    /// The bridge's credentials were refused, or it has none
+    NeedsAuth,
+    /// Someone has to look at it
+    NeedsHuman,
+}
+
+impl Category {
+    pub const ALL: [Category; 4] = [
+        Category::Retryable,
+        Category::Permanent,
+        Category::NeedsAuth,
+        Category::NeedsHuman,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Retryable => "retryable",
+            Category::Permanent => "permanent",
+            Category::NeedsAuth => "needs-auth",
+            Category::NeedsHuman => "needs-human",
+        }
+    }
+
+    pub fn parse(category: &str) -> Option<Category> {
+        Category::ALL.into_iter().find(|c| c.as_str() == category)
+    }
+
+    /// The category of a response with `status`
+    pub fn of_status(status: u16) -> Category {
+        match status {
+            401 | 403 => Category::NeedsAuth,
+            408 | 425 | 429 => Category::Retryable,
+            400..=499 => Category::Permanent,
+            _ => Category::Retryable,
+        }
+    }
+
+    fn of_transport(error: &TransportError) -> Category {
+        match error {
+            TransportError::InvalidUrl(_)
+            | TransportError::UnsupportedScheme { .. }
+            | TransportError::Forbidden(_) => Category::Permanent,
+            _ => Category::Retryable,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// An error, with what's to be done about it
+pub struct BridgeError {
+    pub category: Category,
+    pub error: anyhow::Error,
+}
+
+impl BridgeError {
+    pub fn new(category: Category, error: impl Into<anyhow::Error>) -> BridgeError {
+        BridgeError {
+            category,
+            error: error.into(),
+        }
+    }
+
+    pub fn permanent(error: impl Into<anyhow::Error>) -> BridgeError {
+        BridgeError::new(Category::Permanent, error)
+    }
+
+    pub fn needs_human(error: impl Into<anyhow::Error>) -> BridgeError {
+        BridgeError::new(Category::NeedsHuman, error)
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
+
+impl std::error::Error for BridgeError {}
+
+impl From<anyhow::Error> for BridgeError {
+    /// `error` as it was classified, if it's a [`BridgeError`] already, or [as it
+    /// classifies](classify)
+    fn from(error: anyhow::Error) -> BridgeError {
+        match error.downcast::<BridgeError>() {
+            Ok(error) => error,
+            Err(error) => BridgeError::new(classify(&error), error),
+        }
+    }
+}
+
+/// What's to be done about `error`, by the first error in its chain that says
+pub fn classify(error: &anyhow::Error) -> Category {
+    error
+        .chain()
+        .find_map(|error| {
+            if let Some(error) = error.downcast_ref::<BridgeError>() {
+                return Some(error.category);
+            }
+            if let Some(error) = error.downcast_ref::<TransportError>() {
+                return Some(Category::of_transport(error));
+            }
+            if let Some(error) = error.downcast_ref::<DeliveryError>() {
+                return Some(match error {
+                    DeliveryError::Transport(e) => Category::of_transport(e),
+                    DeliveryError::Rejected { status, .. } => Category::of_status(*status),
+                    DeliveryError::NoSigner | DeliveryError::NoKey { .. } => Category::NeedsAuth,
+                    DeliveryError::Signing(_) => Category::Retryable,
+                    DeliveryError::Url(_) => Category::Permanent,
+                });
+            }
+            if let Some(error) = error.downcast_ref::<ResolveError>() {
+                return Some(match error {
+                    ResolveError::Transport(e) => Category::of_transport(e),
+                    ResolveError::Rejected { status, .. } => Category::of_status(*status),
+                    ResolveError::NotFound { .. } | ResolveError::InvalidDocument { .. } => {
+                        Category::Permanent
+                    }
+                });
+            }
+            if let Some(error) = error.downcast_ref::<ResyncError>() {
+                return Some(match error {
+                    ResyncError::Resolve(ResolveError::Transport(e)) => Category::of_transport(e),
+                    ResyncError::Rejected { status, .. } => Category::of_status(*status),
+                    _ => Category::Retryable,
+                });
+            }
+            if let Some(error) = error.downcast_ref::<WebhookError>() {
+                return Some(match error {
+                    WebhookError::Transport(e) => Category::of_transport(e),
+                    WebhookError::Rejected { status } => Category::of_status(*status),
+                });
+            }
+            if let Some(error) = error.downcast_ref::<ReportError>() {
+                return Some(match error {
+                    ReportError::NotConfigured => Category::NeedsHuman,
+                    ReportError::Transport(e) => Category::of_transport(e),
+                    ReportError::Rejected { status } => Category::of_status(*status),
+                });
+            }
+            if let Some(error) = error.downcast_ref::<DmError>() {
+                return Some(match error {
+                    DmError::NotConfigured => Category::NeedsHuman,
+                    DmError::Transport(e) => Category::of_transport(e),
+                    DmError::Rejected { status } => Category::of_status(*status),
+                    DmError::Io(_) | DmError::Malformed { .. } => Category::Retryable,
+                });
+            }
+            if let Some(error) = error.downcast_ref::<RepoError>() {
+                return Some(match error {
+                    RepoError::InvalidPath { .. }
+                    | RepoError::AlreadyExists { .. }
+                    | RepoError::NotFound { .. }
+                    | RepoError::MirrorOnly { .. } => Category::Permanent,
+                    RepoError::NoKey { .. } | RepoError::NoSigner => Category::NeedsAuth,
+                    RepoError::NotConsented { .. } | RepoError::Suspended { .. } => {
+                        Category::NeedsHuman
+                    }
+                    RepoError::SwapRecord { .. }
+                    | RepoError::SwapCommit { .. }
+                    | RepoError::Throttled(_)
+                    | RepoError::Signing(_)
+                    | RepoError::Io(_) => Category::Retryable,
+                });
+            }
+            if error.is::<Invalid>() {
+                return Some(Category::Permanent);
+            }
+            if error.is::<Throttled>() || error.is::<io::Error>() {
+                return Some(Category::Retryable);
+            }
+            None
+        })
+        .unwrap_or(Category::Retryable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_what_may_succeed_later_is_retried() {
+        let rejected = |status| {
+            anyhow::Error::from(DeliveryError::Rejected {
+                inbox: "https://a.example/inbox".to_string(),
+                status,
+            })
+        };
+        assert_eq!(classify(&rejected(502)), Category::Retryable);
+        assert_eq!(classify(&rejected(429)), Category::Retryable);
+        assert_eq!(classify(&rejected(401)), Category::NeedsAuth);
+        assert_eq!(classify(&rejected(422)), Category::Permanent);
+        let invalid = Invalid::Missing {
+            field: "text".to_string(),
+        };
+        let context = anyhow::Error::from(invalid).context("Couldn't bridge the post");
+        assert_eq!(classify(&context), Category::Permanent);
+        assert_eq!(classify(&anyhow::anyhow!("Something")), Category::Retryable);
+
+        // What a handler says goes, and isn't lost classifying it again
+        let error = anyhow::Error::from(BridgeError::needs_human(rejected(502)));
+        assert_eq!(classify(&error), Category::NeedsHuman);
+        let error = BridgeError::from(error);
+        assert_eq!(error.category, Category::NeedsHuman);
+        assert!(error.to_string().contains("status 502"));
+    }
+}
@@ -0,0 +1,220 @@
+//! Exporting what the bridge holds about one account
+//!
+//! For transparency and data-portability requests, [`export`] gathers everything the bridge
+//! has about an account into one JSON document:
+//!
+//! - its mapping and preferences
+//! - the public halves of its keys. Private keys never leave the keystore
+//! - queued and failed jobs involving it, which carry the posts and activities being
+//!   bridged, and the media they reference
+//! - interactions waiting to go out in its next digest
+//!
+//! The same document is available as a CAR archive ([`to_car`]), in a single DAG-CBOR block,
+//! for tools which expect atproto's archive format. Operators get either from the admin API,
+//! or with `fedibridge export <did|handle> [path]`, which writes a CAR to a path ending in
+//! `.car`
+
+use crate::bridge::Bridge;
+use crate::car::{self, Cid};
+use crate::jobs::{Job, QueuedJob};
+use crate::json::{self, Value};
+use crate::keys::{KeyOwner, KeyPurpose, StoredKey};
+use crate::store::Mapping;
+use crate::time::{format_rfc3339, unix_millis};
+use atproto::DID::Did;
+use std::time::SystemTime;
+
+/// Served as the content type of a CAR export
+pub const CAR_MEDIA_TYPE: &str = "application/vnd.ipld.car";
+
+fn key_json(key: &StoredKey) -> Value {
+    Value::object([
+        ("id", Value::from(key.id())),
+        ("purpose", Value::from(key.purpose.as_str())),
+        ("algorithm", Value::from(key.keypair.algorithm.as_str())),
+        ("publicKey", Value::from(key.keypair.public_key.as_str())),
+        ("createdAt", Value::from(unix_millis(key.created_at))),
+        ("retiredAt", Value::from(key.retired_at.map(unix_millis))),
+    ])
+}
+
+/// Whether a job acts for or on the account
+pub(crate) fn involves(job: &Job, mapping: &Mapping) -> bool {
+    match job {
+        Job::Deliver(delivery) => json::parse(&delivery.activity)
+            .is_ok_and(|activity| activity.get("actor") == Some(&Value::from(&*mapping.actor))),
+        Job::FetchMedia { .. } | Job::Notify(_) => false,
+        Job::Backfill { did }
+        | Job::SyncProfile { did }
+        | Job::UpdateDidDocument { did }
+        | Job::DeleteActor { did }
+        | Job::DeactivateAccount { did } => *did == mapping.did,
+        Job::CreateReport(report) => report.subject == mapping.did,
+        Job::SendChatMessage(reply) => reply.did == mapping.did,
+    }
+}
+
+/// URLs of media attached to the objects of queued activities
+fn media_urls(jobs: &[QueuedJob]) -> Vec<Value> {
+    let mut urls = Vec::new();
+    for job in jobs {
+        let Job::Deliver(delivery) = &job.job else {
+            continue;
+        };
+        let Ok(activity) = json::parse(&delivery.activity) else {
+            continue;
+        };
+        let attachments = activity
+            .get("object")
+            .and_then(|object| object.get("attachment"))
+            .and_then(Value::as_array)
+            .unwrap_or_default();
+        for attachment in attachments {
+            if let Some(url) = attachment.get("url").and_then(Value::as_str) {
+                urls.push(Value::from(url));
+            }
+        }
+    }
+    urls
+}
+
+/// Everything held about `did`, or `None` if the bridge has no mapping for it
+pub fn export(bridge: &Bridge, did: &Did) -> Option<Value> {
+    let mapping = bridge.identities.get(did)?;
+    let owner = KeyOwner::Account(did.clone());
+    let purposes = [
+        KeyPurpose::HttpSignature,
+        KeyPurpose::Assertion,
+        KeyPurpose::RepoSigning,
+    ];
+    let keys = purposes
+        .into_iter()
+        .flat_map(|purpose| bridge.keys.history(&owner, purpose))
+        .map(|key| key_json(&key))
+        .collect();
+    let queued: Vec<_> = bridge
+        .jobs
+        .queued()
+        .into_iter()
+        .filter(|job| involves(&job.job, &mapping))
+        .collect();
+    let failed: Vec<_> = bridge
+        .jobs
+        .dead()
+        .into_iter()
+        .filter(|job| involves(&job.job, &mapping))
+        .collect();
+    let media = [media_urls(&queued), media_urls(&failed)].concat();
+    let digest = bridge
+        .digest_collector
+        .pending(did)
+        .iter()
+        .map(|interaction| {
+            Value::object([
+                ("kind", Value::from(interaction.kind.as_str())),
+                ("from", Value::from(interaction.from.as_str())),
+                ("subject", Value::from(interaction.subject.clone())),
+            ])
+        })
+        .collect();
+    Some(Value::object([
+        ("did", Value::from(did.as_str())),
+        ("exportedAt", Value::from(format_rfc3339(SystemTime::now()))),
+        ("mapping", mapping.to_json()),
+        ("keys", Value::Array(keys)),
+        (
+            "queuedJobs",
+            Value::Array(queued.iter().map(QueuedJob::to_json).collect()),
+        ),
+        (
+            "failedJobs",
+            Value::Array(failed.iter().map(QueuedJob::to_json).collect()),
+        ),
+        ("media", Value::Array(media)),
+        ("pendingDigest", Value::Array(digest)),
+    ]))
+}
+
+/// An export as a CARv1 archive, rooted at its single block
+pub fn to_car(export: &Value) -> Vec<u8> {
+    let block = car::encode_dag_cbor(export);
+    car::write_car(&Cid::for_dag_cbor(&block), &[block])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delivery::Delivery;
+    use crate::digest::{Interaction, InteractionKind, Network};
+    use crate::keys::tests::FakeGenerator;
+    use atproto::did;
+    use std::time::Instant;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:plc:bob");
+
+    #[test]
+    fn exports_everything_about_one_account() {
+        let bridge = Bridge::new();
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://bridge.example/users/alice"));
+        bridge
+            .identities
+            .insert(Mapping::new(BOB, "https://bridge.example/users/bob"));
+        let owner = KeyOwner::Account(ALICE);
+        let generator = FakeGenerator::default();
+        let key = bridge
+            .keys
+            .ensure(&owner, KeyPurpose::HttpSignature, &generator);
+        key.unwrap();
+        for actor in ["alice", "bob"] {
+            let activity = format!(
+                r#"{{"type": "Create", "actor": "https://bridge.example/users/{actor}",
+                    "object": {{"attachment": [{{"url": "https://cdn.example/{actor}.jpg"}}]}}}}"#
+            );
+            let delivery = Delivery::new("https://a.example/inbox", activity);
+            bridge.jobs.push(Job::Deliver(delivery)).unwrap();
+        }
+        bridge.jobs.push(Job::SyncProfile { did: BOB }).unwrap();
+        let interaction = Interaction {
+            kind: InteractionKind::Like,
+            from: "https://a.example/users/carol".to_string(),
+            subject: None,
+        };
+        let collector = &bridge.digest_collector;
+        collector.record(&ALICE, Network::Bluesky, interaction, Instant::now());
+
+        let export = export(&bridge, &ALICE).unwrap();
+        assert_eq!(export.get("did"), Some(&Value::from("did:plc:alice")));
+        let keys = export.get("keys").and_then(Value::as_array).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].get("privateKey"), None);
+        let queued = export.get("queuedJobs").and_then(Value::as_array).unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(
+            export.get("media"),
+            Some(&Value::Array(vec![Value::from(
+                "https://cdn.example/alice.jpg"
+            )]))
+        );
+        let digest = export
+            .get("pendingDigest")
+            .and_then(Value::as_array)
+            .unwrap();
+        assert_eq!(digest[0].get("kind"), Some(&Value::from("like")));
+
+        assert_eq!(super::export(&bridge, &did!("did:plc:nobody")), None);
+    }
+
+    #[test]
+    fn car_export_holds_the_document() {
+        let export = Value::object([("did", Value::from("did:plc:alice"))]);
+        let car = to_car(&export);
+        let block = car::encode_dag_cbor(&export);
+        assert!(car.ends_with(&block));
+        let cid = Cid::for_dag_cbor(&block);
+        let prefix = &car[car.len() - block.len() - 36..car.len() - block.len()];
+        assert_eq!(prefix, cid.as_bytes());
+    }
+}
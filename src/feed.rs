@@ -0,0 +1,478 @@
+//! A feed generator of bridged posts, so Bluesky users can find fediverse content in feeds
+//!
+//! Each configured [`Feed`] is served by `app.bsky.feed.getFeedSkeleton` as the posts in
+//! bridged accounts' repos, newest first, chosen by its [`FeedSource`]:
+//!
+//! - `instance:<host>`: everything bridged from accounts on one fediverse instance
+//! - `community:<actor>`: everything bridged from a fediverse
+//!   [community](crate::community), when communities are represented by feeds
+//! - `following`: everything bridged from accounts the viewer follows. The viewer is whoever
+//!   the AppView's service auth token names, checked by a [`ViewerVerifier`] backend, and
+//!   their follows are fetched from [`FeedConfig::appview`]
+//!
+//! The feeds are published as `app.bsky.feed.generator` records by
+//! [`FeedConfig::publisher`], naming the bridge's `did:web` as the service that generates
+//! them. `describeFeedGenerator` lists them, and that DID's document names the bridge as
+//! its feed generator. Only posts of active accounts go in a feed
+
+use crate::bridge::Bridge;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::{self, Value};
+use crate::normalize;
+use crate::store::{Mapping, MappingStatus};
+use crate::transport::{OutboundRequest, TransportError};
+use crate::url::Url;
+use atproto::at_uri::{AtUri, Authority};
+use atproto::DID::Did;
+use std::collections::HashSet;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub const GET_FEED_SKELETON: &str = "app.bsky.feed.getFeedSkeleton";
+pub const DESCRIBE_FEED_GENERATOR: &str = "app.bsky.feed.describeFeedGenerator";
+pub const GENERATOR_COLLECTION: &str = "app.bsky.feed.generator";
+pub const POST_COLLECTION: &str = "app.bsky.feed.post";
+
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 100;
+/// At most this many pages of a viewer's follows are fetched, 100 at a time
+const MAX_FOLLOW_PAGES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which bridged posts a feed has
+pub enum FeedSource {
+    /// Posts from accounts on this fediverse host
+    Instance(String),
+    /// Posts shared in this fediverse community
+    Community(String),
+    /// Posts from accounts the viewer follows
+    Following,
+}
+
+impl FeedSource {
+    /// Parse `instance:<host>`, `community:<actor>` or `following`
+    pub fn parse(source: &str) -> Option<FeedSource> {
+        match source.split_once(':') {
+            Some(("instance", host)) if !host.is_empty() => {
+                Some(FeedSource::Instance(host.to_ascii_lowercase()))
+            }
+            Some(("community", actor)) if !actor.is_empty() => {
+                Some(FeedSource::Community(actor.to_string()))
+            }
+            None if source == "following" => Some(FeedSource::Following),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    /// The record key of the feed's `app.bsky.feed.generator` record
+    pub rkey: String,
+    pub source: FeedSource,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The feeds the bridge generates
+pub struct FeedConfig {
+    pub feeds: Vec<Feed>,
+    /// Whose repo the feeds' generator records are in. Without one, feeds are taken to be
+    /// published by the bridge's own `did:web`
+    pub publisher: Option<Did>,
+    /// Where viewers' follows are looked up
+    pub appview: String,
+}
+
+impl Default for FeedConfig {
+    fn default() -> Self {
+        FeedConfig {
+            feeds: Vec::new(),
+            publisher: None,
+            appview: "https://public.api.bsky.app".to_string(),
+        }
+    }
+}
+
+/// Works out which Bluesky account a feed request is for, from its service auth token
+pub trait ViewerVerifier: Send + Sync {
+    /// The DID of the viewer the request's token was issued for, if it's valid for
+    /// `audience`, the feed generator's DID
+    fn viewer(&self, request: &Request, audience: &str) -> Option<Did>;
+}
+
+#[derive(Debug, Error)]
+/// Errors generating a feed
+pub enum FeedError {
+    #[error("The feed needs to know who's viewing it")]
+    AuthRequired,
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Follows lookup responded with {status}")]
+    Follows { status: u16 },
+}
+
+/// A page of a feed: its post URIs, and the cursor for the next page if there might be one
+pub type Page = (Vec<String>, Option<String>);
+
+/// Whether `mapping` is of an account on `host`
+fn on_instance(mapping: &Mapping, host: &str) -> bool {
+    Url::parse(&mapping.actor).is_ok_and(|url| url.host == host)
+}
+
+/// The DIDs `viewer` follows, as far as the AppView's first pages go
+pub fn follows(bridge: &Bridge, viewer: &Did) -> Result<HashSet<Did>, FeedError> {
+    let mut follows = HashSet::new();
+    let mut cursor: Option<String> = None;
+    for _ in 0..MAX_FOLLOW_PAGES {
+        let mut url = format!(
+            "{}/xrpc/app.bsky.graph.getFollows?actor={viewer}&limit=100",
+            bridge.feeds.appview.trim_end_matches('/')
+        );
+        if let Some(cursor) = &cursor {
+            url.push_str(&format!("&cursor={}", crate::http::percent_encode(cursor)));
+        }
+        let response = bridge.transport.send(&OutboundRequest::get(url))?;
+        if !response.is_success() {
+            return Err(FeedError::Follows {
+                status: response.status,
+            });
+        }
+        let page = json::parse(&String::from_utf8_lossy(&response.body)).unwrap_or(Value::Null);
+        let subjects = page.get("follows").and_then(Value::as_array);
+        let dids = subjects
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subject| normalize::parse_did(subject.get("did")?.as_str()?).ok());
+        follows.extend(dids);
+        cursor = page
+            .get("cursor")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    Ok(follows)
+}
+
+/// Up to `limit` posts of `source`, after `cursor`, newest first
+///
+/// Cursors are the last post's `createdAt` and URI, so pages stay put as posts are added
+pub fn skeleton(
+    bridge: &Bridge,
+    source: &FeedSource,
+    viewer: Option<&Did>,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<Page, FeedError> {
+    let after = match cursor {
+        Some(cursor) => Some(cursor.split_once("::").ok_or(FeedError::InvalidCursor)?),
+        None => None,
+    };
+    let mappings = bridge.identities.all().into_iter();
+    let mappings = mappings.filter(|mapping| mapping.status == MappingStatus::Active);
+    let accounts: Vec<Mapping> = match source {
+        FeedSource::Instance(host) => mappings.filter(|m| on_instance(m, host)).collect(),
+        FeedSource::Community(_) => Vec::new(),
+        FeedSource::Following => {
+            let follows = follows(bridge, viewer.ok_or(FeedError::AuthRequired)?)?;
+            mappings.filter(|m| follows.contains(&m.did)).collect()
+        }
+    };
+    let mut posts: Vec<(String, String)> = match source {
+        FeedSource::Community(actor) => {
+            let active = |uri: &str| {
+                let author = AtUri::try_create(uri.to_string()).ok();
+                let author = author.and_then(|uri| match uri.authority() {
+                    Authority::Did(did) => bridge.identities.get(did),
+                    _ => None,
+                });
+                author.is_some_and(|mapping| mapping.status == MappingStatus::Active)
+            };
+            let posts = bridge.communities.posts(actor).into_iter();
+            posts.filter(|(_, uri)| active(uri)).collect()
+        }
+        _ => Vec::new(),
+    };
+    for mapping in &accounts {
+        for (rkey, record) in bridge.repos.records(&mapping.did, POST_COLLECTION) {
+            let created_at = record.get("createdAt").and_then(Value::as_str);
+            let uri = format!("at://{}/{POST_COLLECTION}/{rkey}", mapping.did);
+            posts.push((created_at.unwrap_or_default().to_string(), uri));
+        }
+    }
+    posts.sort_by(|a, b| b.cmp(a));
+    let posts = posts.into_iter().filter(|(created_at, uri)| {
+        after.is_none_or(|after| (created_at.as_str(), uri.as_str()) < after)
+    });
+    let page: Vec<(String, String)> = posts.take(limit).collect();
+    let cursor = match page.last() {
+        Some((created_at, uri)) if page.len() == limit => Some(format!("{created_at}::{uri}")),
+        _ => None,
+    };
+    Ok((page.into_iter().map(|(_, uri)| uri).collect(), cursor))
+}
+
+/// The feed generator endpoints for the bridge at `hostname`, in front of `inner`. Without
+/// a hostname or any feeds, everything is passed on
+pub struct FeedEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    verifier: Option<Arc<dyn ViewerVerifier>>,
+    inner: H,
+}
+
+impl<H: Handler> FeedEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> FeedEndpoints<H> {
+        FeedEndpoints {
+            bridge,
+            hostname,
+            verifier: None,
+            inner,
+        }
+    }
+
+    /// Check viewers' tokens with `verifier`. Without one, only feeds which don't depend on
+    /// the viewer can be served
+    pub fn with_viewer_verifier(self, verifier: Arc<dyn ViewerVerifier>) -> FeedEndpoints<H> {
+        FeedEndpoints {
+            verifier: Some(verifier),
+            ..self
+        }
+    }
+
+    /// The at:// URI of a feed
+    fn uri(&self, service: &str, feed: &Feed) -> String {
+        let publisher = self.bridge.feeds.publisher.as_ref();
+        let publisher = publisher.map_or(service, |publisher| publisher.as_str());
+        format!("at://{publisher}/{GENERATOR_COLLECTION}/{}", feed.rkey)
+    }
+
+    /// The configured feed `uri` names
+    fn feed(&self, service: &str, uri: &str) -> Option<&Feed> {
+        let uri = AtUri::try_create(uri.to_string()).ok()?;
+        let publisher = self.bridge.feeds.publisher.as_ref();
+        let published = match uri.authority() {
+            Authority::Did(did) => publisher.map_or(did.as_str() == service, |p| p == did),
+            Authority::Handle(_) => false,
+        };
+        let collection = uri.collection().map(|c| c.as_str());
+        if !published || collection != Some(GENERATOR_COLLECTION) {
+            return None;
+        }
+        let rkey = uri.rkey()?;
+        self.bridge
+            .feeds
+            .feeds
+            .iter()
+            .find(|feed| feed.rkey == rkey)
+    }
+
+    fn get_feed_skeleton(&self, service: &str, request: &Request) -> Result<Response, Response> {
+        let uri = request
+            .query_param("feed")
+            .ok_or_else(|| Response::xrpc_error(400, "InvalidRequest", "Missing feed"))?;
+        let feed = self
+            .feed(service, uri)
+            .ok_or_else(|| Response::xrpc_error(400, "UnknownFeed", "Unknown feed"))?;
+        let limit = match request.query_param("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| (1..=MAX_LIMIT).contains(limit))
+                .ok_or_else(|| Response::xrpc_error(400, "InvalidRequest", "Invalid limit"))?,
+            None => DEFAULT_LIMIT,
+        };
+        let viewer = self
+            .verifier
+            .as_ref()
+            .and_then(|verifier| verifier.viewer(request, service));
+        let cursor = request.query_param("cursor");
+        let (posts, cursor) = skeleton(&self.bridge, &feed.source, viewer.as_ref(), cursor, limit)
+            .map_err(|e| match e {
+                FeedError::AuthRequired => {
+                    Response::xrpc_error(401, "AuthenticationRequired", e.to_string())
+                }
+                FeedError::InvalidCursor => {
+                    Response::xrpc_error(400, "InvalidRequest", e.to_string())
+                }
+                FeedError::Transport(_) | FeedError::Follows { .. } => {
+                    Response::xrpc_error(502, "UpstreamFailure", e.to_string())
+                }
+            })?;
+        let posts = posts
+            .into_iter()
+            .map(|uri| Value::object([("post", Value::from(uri))]));
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("feed", Value::Array(posts.collect())),
+                ("cursor", Value::from(cursor)),
+            ]),
+        ))
+    }
+
+    fn describe(&self, service: &str) -> Response {
+        let feeds = self.bridge.feeds.feeds.iter();
+        let feeds =
+            feeds.map(|feed| Value::object([("uri", Value::from(self.uri(service, feed)))]));
+        Response::json(
+            200,
+            &Value::object([
+                ("did", Value::from(service)),
+                ("feeds", Value::Array(feeds.collect())),
+            ]),
+        )
+    }
+}
+
+impl<H: Handler> Handler for FeedEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let hostname = self.hostname.as_ref();
+        let Some(hostname) = hostname.filter(|_| !self.bridge.feeds.feeds.is_empty()) else {
+            return self.inner.handle(request);
+        };
+        let service = format!("did:web:{hostname}");
+        let result = match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["xrpc", GET_FEED_SKELETON]) => self.get_feed_skeleton(&service, request),
+            (Method::Get, ["xrpc", DESCRIBE_FEED_GENERATOR]) => Ok(self.describe(&service)),
+            _ => return self.inner.handle(request),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::Write;
+    use crate::transport::MockTransport;
+
+    const SERVICE: &str = "did:web:bridge.example";
+
+    /// Trusts whichever DID the request names in `x-viewer`
+    struct Named;
+
+    impl ViewerVerifier for Named {
+        fn viewer(&self, request: &Request, audience: &str) -> Option<Did> {
+            assert_eq!(audience, SERVICE);
+            Did::try_create(request.header("x-viewer")?.to_string()).ok()
+        }
+    }
+
+    /// A bridge with alice on a.example posting twice, and bob on b.example once
+    fn bridge(mock: Arc<MockTransport>) -> Arc<Bridge> {
+        let feeds = FeedConfig {
+            feeds: vec![
+                Feed {
+                    rkey: "a-example".to_string(),
+                    source: FeedSource::Instance("a.example".to_string()),
+                },
+                Feed {
+                    rkey: "following".to_string(),
+                    source: FeedSource::Following,
+                },
+            ],
+            ..FeedConfig::default()
+        };
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_transport(mock)
+            .with_feeds(feeds);
+        let generator = FakeGenerator::default();
+        let accounts = [
+            ("alice", "https://a.example/users/alice", 2),
+            ("bob", "https://b.example/users/bob", 1),
+        ];
+        for (name, actor, posts) in accounts {
+            let did = Did::try_create(format!("did:plc:{name}")).unwrap();
+            bridge.identities.insert(Mapping::new(did.clone(), actor));
+            let owner = KeyOwner::Account(did.clone());
+            bridge
+                .keys
+                .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+                .unwrap();
+            for n in 0..posts {
+                let write = Write::Create {
+                    path: format!("{POST_COLLECTION}/{name}{n}"),
+                    record: Value::object([
+                        ("text", Value::from("hi")),
+                        (
+                            "createdAt",
+                            Value::from(format!("2026-01-0{}T00:00:00Z", n + 1)),
+                        ),
+                    ]),
+                };
+                bridge.commit(&did, &[write]).unwrap();
+            }
+        }
+        Arc::new(bridge)
+    }
+
+    fn endpoints(bridge: Arc<Bridge>) -> FeedEndpoints<impl Handler> {
+        let hostname = Some("bridge.example".to_string());
+        FeedEndpoints::new(bridge, hostname, |_: &Request| Response::new(418))
+            .with_viewer_verifier(Arc::new(Named))
+    }
+
+    fn skeleton_request(rkey: &str, extra: &str) -> Request {
+        let target = format!(
+            "/xrpc/{GET_FEED_SKELETON}?feed=at://{SERVICE}/{GENERATOR_COLLECTION}/{rkey}{extra}"
+        );
+        Request::new(Method::Get, &target)
+    }
+
+    fn posts(response: &Response) -> (Vec<String>, Option<String>) {
+        assert_eq!(response.status, 200);
+        let page = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        let feed = page.get("feed").and_then(Value::as_array).unwrap();
+        let uris = feed.iter().filter_map(|item| item.get("post")?.as_str());
+        let cursor = page.get("cursor").and_then(Value::as_str);
+        (
+            uris.map(str::to_string).collect(),
+            cursor.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn pages_through_an_instances_posts() {
+        let endpoints = endpoints(bridge(Arc::new(MockTransport::new())));
+        let post = |name| format!("at://did:plc:alice/{POST_COLLECTION}/{name}");
+        let (first, cursor) = posts(&endpoints.handle(&skeleton_request("a-example", "&limit=1")));
+        assert_eq!(first, [post("alice1")]);
+        let cursor = crate::http::percent_encode(&cursor.unwrap());
+        let next = skeleton_request("a-example", &format!("&limit=1&cursor={cursor}"));
+        let (second, _) = posts(&endpoints.handle(&next));
+        assert_eq!(second, [post("alice0")]);
+
+        assert_eq!(endpoints.handle(&skeleton_request("nope", "")).status, 400);
+        let describe = Request::new(Method::Get, &format!("/xrpc/{DESCRIBE_FEED_GENERATOR}"));
+        let description =
+            json::parse(std::str::from_utf8(&endpoints.handle(&describe).body).unwrap()).unwrap();
+        assert_eq!(description.get("did"), Some(&Value::from(SERVICE)));
+        let other = Request::new(Method::Get, "/other");
+        assert_eq!(endpoints.handle(&other).status, 418);
+    }
+
+    #[test]
+    fn follows_feed_needs_a_viewer() {
+        let mock = Arc::new(MockTransport::new());
+        let url = "https://public.api.bsky.app/xrpc/app.bsky.graph.getFollows?actor=did:plc:carol&limit=100";
+        mock.respond_json(url, r#"{"follows": [{"did": "did:plc:bob"}]}"#);
+        let endpoints = endpoints(bridge(mock));
+        assert_eq!(
+            endpoints.handle(&skeleton_request("following", "")).status,
+            401
+        );
+        let request = skeleton_request("following", "").with_header("x-viewer", "did:plc:carol");
+        let (feed, cursor) = posts(&endpoints.handle(&request));
+        assert_eq!(feed, [format!("at://did:plc:bob/{POST_COLLECTION}/bob0")]);
+        assert_eq!(cursor, None);
+    }
+}
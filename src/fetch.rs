@@ -0,0 +1,182 @@
+//! Fetching through the embedder, for WebAssembly
+//!
+//! `wasm32-unknown-unknown` has no sockets, so built for it without the `net` feature the
+//! bridge has no [`StdTransport`](crate::transport::StdTransport). A web frontend validating
+//! identifiers or previewing translations uses a [`FetchTransport`] instead, which hands each
+//! request to the host through two functions the module imports from `fedibridge`:
+//!
+//! - `fetch(request: *const u8, len: usize) -> usize` sends the request recorded in `len` bytes
+//!   at `request`, and returns the length of the response record
+//! - `fetch_response(into: *mut u8)` copies that response record into the module's memory
+//!
+//! Records are JSON. A request is `{"method", "url", "headers": [[name, value]], "body",
+//! "timeout"}`, with the body hex-encoded and the timeout in milliseconds if there is one, and
+//! a response either `{"status", "headers", "body"}` the same way or `{"error": reason}` if it
+//! couldn't be sent. Calls into the host are synchronous, so the host answers before
+//! returning: from a worker with a synchronous `XMLHttpRequest`, or with JavaScript Promise
+//! Integration suspending the module while `fetch()` resolves.
+//!
+//! The platform has no clock either, so previews should use the functions which take the
+//! current time, such as [`publish_time`](crate::time::publish_time), rather than those which
+//! read it
+
+use crate::crypto::{hex_decode, hex_encode};
+use crate::json::{self, Value};
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use std::sync::Arc;
+
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "fedibridge")]
+extern "C" {
+    fn fetch(request: *const u8, len: usize) -> usize;
+    fn fetch_response(into: *mut u8);
+}
+
+/// Send a request record through the imported host functions
+#[cfg(target_arch = "wasm32")]
+fn through_imports(request: &[u8]) -> Vec<u8> {
+    // SAFETY: the host only reads `len` bytes of `request`, and writes as many bytes to the
+    // response as it said it would
+    unsafe {
+        let len = fetch(request.as_ptr(), request.len());
+        let mut response = vec![0; len];
+        fetch_response(response.as_mut_ptr());
+        response
+    }
+}
+
+type Host = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+#[derive(Clone)]
+/// A transport which has its host send requests, by exchanging JSON records
+pub struct FetchTransport {
+    host: Arc<Host>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Default for FetchTransport {
+    fn default() -> Self {
+        FetchTransport::new()
+    }
+}
+
+impl FetchTransport {
+    /// Fetching through the functions the module imports from `fedibridge`
+    #[cfg(target_arch = "wasm32")]
+    pub fn new() -> FetchTransport {
+        FetchTransport::with_host(through_imports)
+    }
+
+    /// Fetching through `host`, which takes a request record and returns a response record
+    pub fn with_host(host: impl Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static) -> FetchTransport {
+        FetchTransport {
+            host: Arc::new(host),
+        }
+    }
+}
+
+fn headers(headers: &[(String, String)]) -> Value {
+    let pairs = headers
+        .iter()
+        .map(|(name, value)| Value::Array(vec![name.as_str().into(), value.as_str().into()]));
+    Value::Array(pairs.collect())
+}
+
+fn request_record(request: &OutboundRequest) -> Value {
+    let timeout = request.timeout.map(|timeout| timeout.as_millis() as i64);
+    Value::object([
+        ("method", Value::from(request.method.as_str())),
+        ("url", Value::from(request.url.as_str())),
+        ("headers", headers(&request.headers)),
+        ("body", Value::from(hex_encode(&request.body))),
+        ("timeout", Value::from(timeout)),
+    ])
+}
+
+fn response_from_record(url: &str, record: &[u8]) -> Result<OutboundResponse, TransportError> {
+    let malformed = |reason: &str| TransportError::MalformedResponse {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    };
+    let record = std::str::from_utf8(record)
+        .ok()
+        .and_then(|record| json::parse(record).ok())
+        .ok_or_else(|| malformed("the host's record isn't JSON"))?;
+    if let Some(reason) = record.get("error").and_then(Value::as_str) {
+        return Err(TransportError::Connect {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+    let status = record
+        .get("status")
+        .and_then(Value::as_i64)
+        .and_then(|status| u16::try_from(status).ok())
+        .ok_or_else(|| malformed("the host's record has no status"))?;
+    let body = match record.get("body").and_then(Value::as_str) {
+        Some(body) => hex_decode(body).ok_or_else(|| malformed("the body isn't hex"))?,
+        None => Vec::new(),
+    };
+    let mut response = OutboundResponse::new(status).with_body(body);
+    let headers = record.get("headers").and_then(Value::as_array);
+    for header in headers.unwrap_or_default() {
+        if let Some([Value::String(name), Value::String(value)]) = header.as_array() {
+            response = response.with_header(name, value);
+        }
+    }
+    Ok(response)
+}
+
+impl HttpTransport for FetchTransport {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        let record = request_record(request).to_string();
+        response_from_record(&request.url, &(self.host)(record.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echoes requests back, as a host would answering with what it was asked for
+    fn echo(request: &[u8]) -> Vec<u8> {
+        let request = json::parse(std::str::from_utf8(request).unwrap()).unwrap();
+        if request.get("url").and_then(Value::as_str) == Some("https://down.example/") {
+            return br#"{"error": "NetworkError when attempting to fetch resource"}"#.to_vec();
+        }
+        let body = format!(
+            "{} {}",
+            request.get("method").and_then(Value::as_str).unwrap(),
+            request.get("url").and_then(Value::as_str).unwrap()
+        );
+        Value::object([
+            ("status", Value::Int(200)),
+            ("headers", request.get("headers").unwrap().clone()),
+            ("body", Value::from(hex_encode(body.as_bytes()))),
+        ])
+        .to_string()
+        .into_bytes()
+    }
+
+    #[test]
+    fn requests_round_trip_through_the_host() {
+        let transport = FetchTransport::with_host(echo);
+        let request = OutboundRequest::post("https://b.example/inbox", "{}")
+            .with_header("Content-Type", "application/activity+json");
+        let response = transport.send(&request).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"POST https://b.example/inbox");
+        assert_eq!(
+            response.header("content-type"),
+            Some("application/activity+json")
+        );
+
+        let down = transport.send(&OutboundRequest::get("https://down.example/"));
+        assert!(matches!(down, Err(TransportError::Connect { .. })));
+        let garbled = FetchTransport::with_host(|_| b"<html>".to_vec());
+        assert!(matches!(
+            garbled.send(&request),
+            Err(TransportError::MalformedResponse { .. })
+        ));
+    }
+}
@@ -0,0 +1,404 @@
+//! A C ABI around the identity and translation core
+//!
+//! With the `ffi` feature, mobile apps and services in other languages can embed the bridge's
+//! identifier validation, identity resolution and post translation rather than reimplementing
+//! them. `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`) builds a
+//! library for them, and `include/fedibridge.h` declares what's here.
+//!
+//! The ABI is stable: functions are only ever added, and their results only gain fields.
+//! Strings are NUL-terminated UTF-8. Functions taking input return a JSON envelope, either
+//! `{"ok": ...}` or `{"error": {"code", "message"}}`, as a string for the caller to free with
+//! [`fedibridge_string_free`]. Invalid identifiers have the [`ValidationError::code`] of what
+//! was wrong with them, and otherwise the codes are:
+//!
+//! - `invalid_argument`: a null or non-UTF-8 string, a null bridge where one is needed, or JSON
+//!   which doesn't parse
+//! - `resolution_failed`: a document couldn't be fetched, or didn't check out
+//! - `panic`: a bug in the bridge, caught before it could unwind into the caller
+//!
+//! Resolving identities needs a bridge from [`fedibridge_bridge_new`], which makes its requests
+//! through the embedder's [`FedibridgeFetch`] callback with the JSON records the
+//! [`fetch`](crate::fetch) module describes
+
+use crate::article::{self, ArticleConfig};
+use crate::bridge::Bridge;
+use crate::entities;
+use crate::fetch::FetchTransport;
+use crate::json::{self, Value};
+use crate::mentions::{self, handle_from_document};
+use crate::normalize;
+use crate::richtext::{self, RichText};
+use atproto::handle::Handle;
+use atproto::ValidationError;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+/// Sends the request record `request`, returning a response record which stays valid until
+/// the callback is next called on the same thread, or null if it couldn't be sent at all.
+/// It's given back the `context` the bridge was created with, and may be called from any
+/// thread
+pub type FedibridgeFetch =
+    unsafe extern "C" fn(context: *mut c_void, request: *const c_char) -> *const c_char;
+
+struct Callback {
+    fetch: FedibridgeFetch,
+    context: *mut c_void,
+}
+
+// SAFETY: embedders pass a callback and context which can be used from any thread, as
+// `FedibridgeFetch` documents
+unsafe impl Send for Callback {}
+unsafe impl Sync for Callback {}
+
+impl Callback {
+    fn call(&self, request: &[u8]) -> Vec<u8> {
+        let request = CString::new(request).expect("JSON escapes NULs");
+        // SAFETY: the callback is the embedder's, and copes with its own context
+        let response = unsafe { (self.fetch)(self.context, request.as_ptr()) };
+        if response.is_null() {
+            return br#"{"error": "The embedder sent no response"}"#.to_vec();
+        }
+        // SAFETY: a non-null response is a NUL-terminated string, valid until the next call
+        unsafe { CStr::from_ptr(response) }.to_bytes().to_vec()
+    }
+}
+
+struct Failure {
+    code: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: &'static str, message: impl Into<String>) -> Failure {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid(error: impl ValidationError) -> Failure {
+        Failure::new(error.code(), error.to_string())
+    }
+}
+
+/// Run `f`, returning its result as an envelope for the caller to free
+fn respond(f: impl FnOnce() -> Result<Value, Failure>) -> *mut c_char {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(Failure::new("panic", "The bridge panicked")));
+    let envelope = match result {
+        Ok(value) => Value::object([("ok", value)]),
+        Err(failure) => Value::object([(
+            "error",
+            Value::object([
+                ("code", Value::from(failure.code)),
+                ("message", Value::from(failure.message)),
+            ]),
+        )]),
+    };
+    CString::new(envelope.to_string())
+        .expect("JSON escapes NULs")
+        .into_raw()
+}
+
+/// # Safety
+///
+/// `s` is null or a NUL-terminated string which outlives the call
+unsafe fn text<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(Failure::new("invalid_argument", format!("No {name} given")));
+    }
+    // SAFETY: promised by the caller
+    let s = unsafe { CStr::from_ptr(s) };
+    s.to_str()
+        .map_err(|_| Failure::new("invalid_argument", format!("The {name} isn't UTF-8")))
+}
+
+/// # Safety
+///
+/// `bridge` is null or was returned by [`fedibridge_bridge_new`], not yet freed
+unsafe fn bridge_ref<'a>(bridge: *const Bridge) -> Result<&'a Bridge, Failure> {
+    // SAFETY: promised by the caller
+    let bridge = unsafe { bridge.as_ref() };
+    bridge.ok_or_else(|| Failure::new("invalid_argument", "No bridge given"))
+}
+
+fn resolution_failed(error: impl ToString) -> Failure {
+    Failure::new("resolution_failed", error.to_string())
+}
+
+fn rich_text_json(rich_text: &RichText) -> Value {
+    let json = Value::object([
+        ("text", Value::from(rich_text.text.as_str())),
+        ("facets", rich_text.facets_json()),
+    ]);
+    entities::fit(&json).record
+}
+
+/// The library's version, as a static string which isn't freed
+#[no_mangle]
+pub extern "C" fn fedibridge_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// Free a string returned by any other function
+///
+/// # Safety
+///
+/// `s` is null or a string returned by this library, not yet freed
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        // SAFETY: promised by the caller
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+/// A bridge for resolving identities, making requests through `fetch` with `context`, or
+/// without one through the platform's own transport. Free it with [`fedibridge_bridge_free`]
+#[no_mangle]
+pub extern "C" fn fedibridge_bridge_new(
+    fetch: Option<FedibridgeFetch>,
+    context: *mut c_void,
+) -> *mut Bridge {
+    let bridge = match fetch {
+        Some(fetch) => {
+            let callback = Callback { fetch, context };
+            let transport = FetchTransport::with_host(move |request| callback.call(request));
+            Bridge::new().with_transport(Arc::new(transport))
+        }
+        None => Bridge::new(),
+    };
+    Box::into_raw(Box::new(bridge))
+}
+
+/// # Safety
+///
+/// `bridge` is null or was returned by [`fedibridge_bridge_new`], not yet freed, and isn't
+/// being used by another thread
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_bridge_free(bridge: *mut Bridge) {
+    if !bridge.is_null() {
+        // SAFETY: promised by the caller
+        drop(unsafe { Box::from_raw(bridge) });
+    }
+}
+
+/// Validate a DID, returning it
+///
+/// # Safety
+///
+/// `did` is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_parse_did(did: *const c_char) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let did = unsafe { text(did, "DID") }?;
+        let did = normalize::parse_did(did).map_err(Failure::invalid)?;
+        Ok(Value::from(did.as_str()))
+    })
+}
+
+/// Validate a handle, returning it normalized
+///
+/// # Safety
+///
+/// `handle` is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_parse_handle(handle: *const c_char) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let handle = unsafe { text(handle, "handle") }?;
+        let handle = Handle::try_create(handle.to_string()).map_err(Failure::invalid)?;
+        Ok(Value::from(handle.normalized().as_str()))
+    })
+}
+
+/// Resolve a DID to its document
+///
+/// # Safety
+///
+/// `bridge` is null or was returned by [`fedibridge_bridge_new`] and not yet freed, and `did`
+/// is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_resolve_did(
+    bridge: *const Bridge,
+    did: *const c_char,
+) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let (bridge, did) = unsafe { (bridge_ref(bridge)?, text(did, "DID")?) };
+        let did = normalize::parse_did(did).map_err(Failure::invalid)?;
+        let document = bridge.resolver().resolve(&did).map_err(resolution_failed)?;
+        Ok(Value::clone(&document))
+    })
+}
+
+/// Resolve a handle to `{"did", "document"}`, checking the document claims the handle back
+///
+/// The handle's `_atproto` TXT record is asked first, if the bridge's resolver can be asked
+/// for TXT records, and then `/.well-known/atproto-did`
+///
+/// # Safety
+///
+/// `bridge` is null or was returned by [`fedibridge_bridge_new`] and not yet freed, and
+/// `handle` is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_resolve_handle(
+    bridge: *const Bridge,
+    handle: *const c_char,
+) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let (bridge, handle) = unsafe { (bridge_ref(bridge)?, text(handle, "handle")?) };
+        let handle = Handle::try_create(handle.to_string())
+            .map_err(Failure::invalid)?
+            .normalized();
+        let did = match bridge.dns.atproto_did(handle.as_str()) {
+            Ok(Some(did)) => did,
+            _ => {
+                let url = format!("https://{}/.well-known/atproto-did", handle.as_str());
+                let request = crate::transport::OutboundRequest::get(&url);
+                let response = bridge.transport.send(&request).map_err(resolution_failed)?;
+                if !response.is_success() {
+                    let status = response.status;
+                    return Err(resolution_failed(format!("{url} responded with {status}")));
+                }
+                let did = String::from_utf8_lossy(&response.body).trim().to_string();
+                normalize::parse_did(&did)
+                    .map_err(|_| resolution_failed(format!("{url} gave {did:?}")))?
+            }
+        };
+        let document = bridge.resolver().resolve(&did).map_err(resolution_failed)?;
+        if handle_from_document(&document).as_deref() != Some(handle.as_str()) {
+            let message = format!("{did} doesn't claim {}", handle.as_str());
+            return Err(resolution_failed(message));
+        }
+        Ok(Value::object([
+            ("did", Value::from(did.as_str())),
+            ("document", Value::clone(&document)),
+        ]))
+    })
+}
+
+/// Translate a fediverse post's HTML to Bluesky rich text, `{"text", "facets"}`
+///
+/// With a bridge, mentions are resolved to the accounts they're of. Without one (null) they're
+/// left as links
+///
+/// # Safety
+///
+/// `bridge` is null or was returned by [`fedibridge_bridge_new`] and not yet freed, and `html`
+/// is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_html_to_rich_text(
+    bridge: *const Bridge,
+    html: *const c_char,
+) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let (bridge, html) = unsafe { (bridge.as_ref(), text(html, "HTML")?) };
+        let rich_text = match bridge {
+            Some(bridge) => mentions::rich_text(bridge, html),
+            None => richtext::from_html(html),
+        };
+        Ok(rich_text_json(&rich_text))
+    })
+}
+
+/// Translate a long-form fediverse object (an `Article` or `Page`) to a teaser post of at most
+/// `max_length` characters, `{"text", "embed", "image"}`, or null if it isn't long-form
+///
+/// # Safety
+///
+/// `object` is null or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn fedibridge_article_to_post(
+    object: *const c_char,
+    max_length: usize,
+) -> *mut c_char {
+    respond(|| {
+        // SAFETY: promised by the caller
+        let object = unsafe { text(object, "object") }?;
+        let object = json::parse(object)
+            .map_err(|e| Failure::new("invalid_argument", format!("Invalid object: {e}")))?;
+        let config = ArticleConfig { max_length };
+        let Some(post) = article::to_post(&object, &config) else {
+            return Ok(Value::Null);
+        };
+        Ok(Value::object([
+            ("text", Value::from(post.text)),
+            ("embed", post.card.to_embed(None)),
+            ("image", Value::from(post.image)),
+        ]))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        /// The last response, kept until the next request as the callback promises
+        static RESPONSE: RefCell<CString> = RefCell::default();
+    }
+
+    /// Parse and free an envelope
+    fn envelope(returned: *mut c_char) -> Value {
+        let parsed = unsafe { json::parse(CStr::from_ptr(returned).to_str().unwrap()) };
+        unsafe { fedibridge_string_free(returned) };
+        parsed.unwrap()
+    }
+
+    fn c(s: &str) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    unsafe extern "C" fn plc(_: *mut c_void, request: *const c_char) -> *const c_char {
+        let request = unsafe { CStr::from_ptr(request) }.to_str().unwrap();
+        assert!(request.contains("https://plc.directory/did:plc:alice"));
+        let body = crate::crypto::hex_encode(br#"{"id": "did:plc:alice"}"#);
+        let response = c(&format!(r#"{{"status": 200, "body": "{body}"}}"#));
+        RESPONSE.with(|kept| kept.replace(response));
+        RESPONSE.with(|kept| kept.borrow().as_ptr())
+    }
+
+    #[test]
+    fn identifiers_are_validated_with_stable_errors() {
+        let handle = c("Alice.Bsky.Social");
+        let ok = envelope(unsafe { fedibridge_parse_handle(handle.as_ptr()) });
+        assert_eq!(ok.get("ok"), Some(&Value::from("alice.bsky.social")));
+        let did = c("did:example:alice");
+        let error = envelope(unsafe { fedibridge_parse_did(did.as_ptr()) });
+        let code = |error: &Value| {
+            let code = error.get("error").and_then(|e| e.get("code"));
+            code.and_then(Value::as_str).map(str::to_string)
+        };
+        assert_eq!(code(&error).as_deref(), Some("did.invalid_method"));
+        let null = envelope(unsafe { fedibridge_parse_did(std::ptr::null()) });
+        assert_eq!(code(&null).as_deref(), Some("invalid_argument"));
+
+        let article = c(r#"{"type": "Article", "id": "https://b.example/a/1",
+            "name": "On bridges", "summary": "<p>Why they matter</p>"}"#);
+        let post = envelope(unsafe { fedibridge_article_to_post(article.as_ptr(), 300) });
+        let text = post.get("ok").and_then(|ok| ok.get("text"));
+        assert_eq!(
+            text.and_then(Value::as_str),
+            Some("On bridges\n\nWhy they matter")
+        );
+    }
+
+    #[test]
+    fn resolution_goes_through_the_embedder() {
+        let bridge = fedibridge_bridge_new(Some(plc), std::ptr::null_mut());
+        let did = c("did:plc:alice");
+        let document = envelope(unsafe { fedibridge_resolve_did(bridge, did.as_ptr()) });
+        let id = document.get("ok").and_then(|ok| ok.get("id"));
+        assert_eq!(id.and_then(Value::as_str), Some("did:plc:alice"));
+        unsafe { fedibridge_bridge_free(bridge) };
+        let none = envelope(unsafe { fedibridge_resolve_did(std::ptr::null(), did.as_ptr()) });
+        let code = none.get("error").and_then(|e| e.get("code"));
+        assert_eq!(code.and_then(Value::as_str), Some("invalid_argument"));
+    }
+}
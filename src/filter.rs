@@ -0,0 +1,401 @@
+//! Filter expressions for firehose events
+//!
+//! Nearly all of the firehose is irrelevant to the bridge, so events are checked against a
+//! [`Filter`] using only their [`EventHeader`], before their records are decoded.
+//!
+//! Filters are written as expressions such as `tracked and collection:app.bsky.feed.*`.
+//! The terms are:
+//!
+//! - `all`, matching everything
+//! - `tracked`, matching accounts in the identity store
+//! - a DID such as `did:plc:abc`, matching that account
+//! - `collection:<nsid>`, matching operations on a collection, or with a trailing `.*` on every
+//!   collection under a prefix
+//! - `action:create`, `action:update` or `action:delete`
+//!
+//! combined with `and`, `or`, `not` and parentheses, with `not` binding tightest and `or`
+//! loosest. Operation terms (collections and actions) match an event if any one of its
+//! operations satisfies the whole expression
+
+use crate::firehose::{Action, EventHeader, Operation};
+use crate::normalize;
+use crate::store::IdentityStore;
+use atproto::nsid::Nsid;
+use atproto::DID::Did;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors parsing a filter expression
+pub enum FilterError {
+    #[error("Filter expression ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("Unexpected {found:?} at offset {offset} of filter expression")]
+    UnexpectedToken { found: String, offset: usize },
+    #[error("Invalid collection in filter - found {found}")]
+    InvalidCollection { found: String },
+    #[error("Invalid action in filter - found {found}")]
+    InvalidAction { found: String },
+    #[error("Invalid DID in filter - found {found}")]
+    InvalidDid { found: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which collections a `collection:` term matches
+pub enum CollectionPattern {
+    Exact(Nsid),
+    /// Every collection starting with this prefix, which ends in a `.`
+    Prefix(String),
+}
+
+impl CollectionPattern {
+    pub fn matches(&self, collection: &str) -> bool {
+        match self {
+            CollectionPattern::Exact(nsid) => nsid.as_str() == collection,
+            CollectionPattern::Prefix(prefix) => collection.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for CollectionPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectionPattern::Exact(nsid) => write!(f, "{nsid}"),
+            CollectionPattern::Prefix(prefix) => write!(f, "{prefix}*"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A parsed filter expression
+pub enum Filter {
+    All,
+    Tracked,
+    Did(Did),
+    Collection(CollectionPattern),
+    Action(Action),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Default for Filter {
+    /// Only events from accounts the bridge knows about
+    fn default() -> Self {
+        Filter::Tracked
+    }
+}
+
+impl Filter {
+    /// Whether the bridge should handle `event`
+    pub fn matches(&self, event: &EventHeader, identities: &IdentityStore) -> bool {
+        if event.ops.is_empty() {
+            return self.matches_op(event, None, identities);
+        }
+        event
+            .ops
+            .iter()
+            .any(|op| self.matches_op(event, Some(op), identities))
+    }
+
+    fn matches_op(
+        &self,
+        event: &EventHeader,
+        op: Option<&Operation>,
+        identities: &IdentityStore,
+    ) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Tracked => identities.is_tracked(&event.did),
+            Filter::Did(did) => event.did == *did,
+            Filter::Collection(pattern) => op.is_some_and(|op| pattern.matches(op.collection())),
+            Filter::Action(action) => op.is_some_and(|op| op.action == *action),
+            Filter::Not(inner) => !inner.matches_op(event, op, identities),
+            Filter::And(filters) => filters.iter().all(|f| f.matches_op(event, op, identities)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches_op(event, op, identities)),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter<'_>, filters: &[Filter], op: &str| {
+            f.write_str("(")?;
+            for (i, filter) in filters.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {op} ")?;
+                }
+                write!(f, "{filter}")?;
+            }
+            f.write_str(")")
+        };
+        match self {
+            Filter::All => f.write_str("all"),
+            Filter::Tracked => f.write_str("tracked"),
+            Filter::Did(did) => write!(f, "{did}"),
+            Filter::Collection(pattern) => write!(f, "collection:{pattern}"),
+            Filter::Action(action) => write!(f, "action:{}", action.as_str()),
+            Filter::Not(inner) => write!(f, "not {inner}"),
+            Filter::And(filters) => join(f, filters, "and"),
+            Filter::Or(filters) => join(f, filters, "or"),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Filter, FilterError> {
+        let mut parser = Parser {
+            tokens: tokenize(s),
+            next: 0,
+        };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.next) {
+            Some(&(offset, found)) => Err(FilterError::UnexpectedToken {
+                found: found.to_string(),
+                offset,
+            }),
+            None => Ok(filter),
+        }
+    }
+}
+
+/// Split into words and parentheses, with the offset of each
+fn tokenize(s: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in s.char_indices() {
+        if c.is_whitespace() || c == '(' || c == ')' {
+            if let Some(start) = start.take() {
+                tokens.push((start, &s[start..i]));
+            }
+            if !c.is_whitespace() {
+                tokens.push((i, &s[i..i + 1]));
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(start) = start {
+        tokens.push((start, &s[start..]));
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<(usize, &'a str)>,
+    next: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.next).map(|&(_, t)| t)
+    }
+
+    fn take(&mut self) -> Result<(usize, &'a str), FilterError> {
+        let token = *self
+            .tokens
+            .get(self.next)
+            .ok_or(FilterError::UnexpectedEnd)?;
+        self.next += 1;
+        Ok(token)
+    }
+
+    /// Parse `operand (keyword operand)*`, flattening runs of the same operator
+    fn chain(
+        &mut self,
+        keyword: &str,
+        operand: fn(&mut Self) -> Result<Filter, FilterError>,
+        combine: fn(Vec<Filter>) -> Filter,
+    ) -> Result<Filter, FilterError> {
+        let mut filters = vec![operand(self)?];
+        while self.peek() == Some(keyword) {
+            self.next += 1;
+            filters.push(operand(self)?);
+        }
+        Ok(match filters.len() {
+            1 => filters.pop().expect("checked length"),
+            _ => combine(filters),
+        })
+    }
+
+    fn or(&mut self) -> Result<Filter, FilterError> {
+        self.chain("or", Parser::and, Filter::Or)
+    }
+
+    fn and(&mut self) -> Result<Filter, FilterError> {
+        self.chain("and", Parser::unary, Filter::And)
+    }
+
+    fn unary(&mut self) -> Result<Filter, FilterError> {
+        use FilterError::*;
+        let (offset, token) = self.take()?;
+        let unexpected = || UnexpectedToken {
+            found: token.to_string(),
+            offset,
+        };
+        match token {
+            "not" => Ok(Filter::Not(Box::new(self.unary()?))),
+            "(" => {
+                let inner = self.or()?;
+                match self.take()? {
+                    (_, ")") => Ok(inner),
+                    (offset, found) => Err(UnexpectedToken {
+                        found: found.to_string(),
+                        offset,
+                    }),
+                }
+            }
+            "all" => Ok(Filter::All),
+            "tracked" => Ok(Filter::Tracked),
+            ")" | "and" | "or" => Err(unexpected()),
+            _ if token.starts_with("did:") => {
+                normalize::parse_did(token)
+                    .map(Filter::Did)
+                    .map_err(|_| InvalidDid {
+                        found: token.to_string(),
+                    })
+            }
+            _ => {
+                match token.split_once(':') {
+                    Some(("collection", pattern)) => Ok(Filter::Collection(collection(pattern)?)),
+                    Some(("action", action)) => Action::parse(action)
+                        .map(Filter::Action)
+                        .ok_or_else(|| InvalidAction {
+                            found: action.to_string(),
+                        }),
+                    _ => Err(unexpected()),
+                }
+            }
+        }
+    }
+}
+
+fn collection(pattern: &str) -> Result<CollectionPattern, FilterError> {
+    let invalid = || FilterError::InvalidCollection {
+        found: pattern.to_string(),
+    };
+    match pattern.strip_suffix('*') {
+        Some(prefix) => {
+            let valid = prefix.ends_with('.')
+                && prefix[..prefix.len() - 1].split('.').all(|s| {
+                    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                });
+            match valid {
+                true => Ok(CollectionPattern::Prefix(prefix.to_string())),
+                false => Err(invalid()),
+            }
+        }
+        None => Nsid::try_create(pattern.to_string())
+            .map(CollectionPattern::Exact)
+            .map_err(|_| invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    fn event(did: Did, ops: &[(Action, &str)]) -> EventHeader {
+        EventHeader {
+            seq: 1,
+            did,
+            ops: ops
+                .iter()
+                .map(|&(action, path)| Operation {
+                    action,
+                    path: path.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn filter(s: &str) -> Filter {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_with_precedence() {
+        assert_eq!(
+            filter("tracked and not action:delete or did:plc:aaaa"),
+            Filter::Or(vec![
+                Filter::And(vec![
+                    Filter::Tracked,
+                    Filter::Not(Box::new(Filter::Action(Action::Delete)))
+                ]),
+                Filter::Did(did!("did:plc:aaaa")),
+            ])
+        );
+        let nested = filter("tracked and (collection:app.bsky.feed.* or action:delete)");
+        assert_eq!(filter(&nested.to_string()), nested);
+    }
+
+    #[test]
+    fn parse_errors() {
+        use FilterError::*;
+        let check = |s: &str| s.parse::<Filter>().unwrap_err();
+        assert_eq!(check("tracked and"), UnexpectedEnd);
+        assert_eq!(
+            check("tracked tracked"),
+            UnexpectedToken {
+                found: "tracked".into(),
+                offset: 8
+            }
+        );
+        assert!(matches!(check("(all"), UnexpectedEnd));
+        assert!(matches!(
+            check("collection:app.bsky"),
+            InvalidCollection { .. }
+        ));
+        assert!(matches!(check("collection:app*"), InvalidCollection { .. }));
+        assert!(matches!(check("action:like"), InvalidAction { .. }));
+        assert!(matches!(check("did:pcl:aaaa"), InvalidDid { .. }));
+    }
+
+    #[test]
+    fn matches_events() {
+        let identities = IdentityStore::new();
+        identities.insert(Mapping::new(
+            did!("did:plc:aaaa"),
+            "https://bridge.example/users/a",
+        ));
+        let tracked_post = event(
+            did!("did:plc:aaaa"),
+            &[
+                (Action::Create, "app.bsky.feed.like/1"),
+                (Action::Create, "app.bsky.feed.post/2"),
+            ],
+        );
+        let untracked_post = event(
+            did!("did:plc:bbbb"),
+            &[(Action::Create, "app.bsky.feed.post/3")],
+        );
+        let matches = |f: &str, e: &EventHeader| filter(f).matches(e, &identities);
+
+        assert!(matches("tracked", &tracked_post));
+        assert!(!matches("tracked", &untracked_post));
+        assert!(matches("collection:app.bsky.feed.post", &untracked_post));
+        assert!(matches("collection:app.bsky.*", &tracked_post));
+        assert!(!matches("collection:app.bsky.graph.*", &tracked_post));
+        // Both conditions have to hold for the same operation
+        assert!(!matches(
+            "collection:app.bsky.feed.like and action:delete",
+            &event(
+                did!("did:plc:aaaa"),
+                &[
+                    (Action::Create, "app.bsky.feed.like/1"),
+                    (Action::Delete, "app.bsky.feed.post/2"),
+                ],
+            )
+        ));
+        // Events without operations never match operation terms
+        let no_ops = event(did!("did:plc:aaaa"), &[]);
+        assert!(matches("did:plc:aaaa", &no_ops));
+        assert!(!matches("tracked and action:create", &no_ops));
+        assert!(matches("not collection:app.bsky.feed.post", &no_ops));
+    }
+}
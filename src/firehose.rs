@@ -0,0 +1,874 @@
+//! Tracking of the bridge's position in the atproto firehose
+//!
+//! To scale past a single instance the firehose can be consumed by several shards. Every
+//! shard reads the whole stream but only handles events for the DIDs it [owns](Shard::owns),
+//! so the accounts are partitioned between shards and no event is processed twice. Each shard
+//! keeps its cursor (and the rest of its state) in its own subdirectory of the state dir.
+//!
+//! When the shard layout changes a shard has no cursor of its own yet, so it resumes from the
+//! lowest cursor of any previous layout. Events are replayed rather than skipped: a shard
+//! taking over an account may re-handle some of that account's recent events, but never misses
+//! any.
+//!
+//! A crash loses whatever handling happened since the cursor was last saved, so each commit
+//! handled is also noted in a [`ProcessedEvents`] window, by sequence number and commit CID,
+//! as it's handled. The events replayed after a restart are recognised there and dropped
+//! rather than handled twice, and the window only holds events newer than the saved cursor.
+//!
+//! Messages from the relay are read as [`Frame`]s, which borrow from the message: a commit's
+//! [header](CommitFrame::header) is available for filtering without touching its blocks, and
+//! only the records that are wanted are [decoded](CommitFrame::record)
+
+use crate::car::{self, Cid};
+use crate::cbor::{self, Cbor, CborError, Items};
+use crate::json::Value;
+use crate::normalize;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{from_unix_millis, unix_millis};
+use atproto::DID::Did;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+const CURSOR_FILE: &str = "firehose-cursor";
+const PROCESSED_FILE: &str = "firehose-processed.jsonl";
+/// The most handled events remembered, beyond which the oldest are forgotten
+pub const PROCESSED_WINDOW: usize = 100_000;
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors describing a shard
+pub enum ShardError {
+    #[error("Expected a shard as index/count - found {found}")]
+    Malformed { found: String },
+    #[error("Shard index {index} is out of range for {count} shards")]
+    OutOfRange { index: u32, count: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// One of `count` partitions of the firehose, by DID
+pub struct Shard {
+    index: u32,
+    count: u32,
+}
+
+impl Default for Shard {
+    fn default() -> Self {
+        Shard::SINGLE
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Shard {
+    type Err = ShardError;
+
+    /// Parses `index/count`, e.g. `0/4`
+    fn from_str(s: &str) -> Result<Shard, ShardError> {
+        let malformed = || ShardError::Malformed {
+            found: s.to_string(),
+        };
+        let (index, count) = s.split_once('/').ok_or_else(malformed)?;
+        let index = index.trim().parse().map_err(|_| malformed())?;
+        let count = count.trim().parse().map_err(|_| malformed())?;
+        Shard::new(index, count)
+    }
+}
+
+impl Shard {
+    /// The only shard when the bridge isn't sharded
+    pub const SINGLE: Shard = Shard { index: 0, count: 1 };
+
+    pub fn new(index: u32, count: u32) -> Result<Shard, ShardError> {
+        if index >= count {
+            return Err(ShardError::OutOfRange { index, count });
+        }
+        Ok(Shard { index, count })
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Which of `count` shards handles `did`
+    ///
+    /// This has to agree between instances (and versions) so uses FNV-1a rather than the
+    /// randomly seeded std hasher
+    pub fn of(did: &Did, count: u32) -> u32 {
+        let hash = did.as_str().bytes().fold(0xcbf29ce484222325u64, |h, b| {
+            (h ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        });
+        (hash % u64::from(count.max(1))) as u32
+    }
+
+    /// Whether events for `did` should be handled by this shard
+    pub fn owns(&self, did: &Did) -> bool {
+        Shard::of(did, self.count) == self.index
+    }
+
+    fn dir_name(&self) -> String {
+        format!("shard-{}-of-{}", self.index, self.count)
+    }
+
+    /// The directory this shard keeps its state in
+    ///
+    /// An unsharded bridge uses `root` itself, so existing state carries over
+    pub fn state_dir(&self, root: &StateDir) -> io::Result<StateDir> {
+        if *self == Shard::SINGLE {
+            return Ok(root.clone());
+        }
+        root.subdir(&self.dir_name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What a commit did to a record
+pub enum Action {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+
+    pub fn parse(action: &str) -> Option<Action> {
+        match action {
+            "create" => Some(Action::Create),
+            "update" => Some(Action::Update),
+            "delete" => Some(Action::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// One record operation in a commit
+pub struct Operation {
+    pub action: Action,
+    /// `<collection>/<rkey>`
+    pub path: String,
+}
+
+impl Operation {
+    /// The NSID part of the path
+    pub fn collection(&self) -> &str {
+        self.path.split_once('/').map_or(&self.path, |(c, _)| c)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The parts of a firehose event available without decoding its CAR blocks
+///
+/// A commit frame lists its repo and operations in the envelope, so events can be
+/// [filtered](crate::filter::Filter) on these before paying for decoding the records
+pub struct EventHeader {
+    pub seq: i64,
+    pub did: Did,
+    pub ops: Vec<Operation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Why an account is inactive
+pub enum AccountStatus {
+    Deactivated,
+    Suspended,
+    Takendown,
+    Deleted,
+}
+
+impl AccountStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Deactivated => "deactivated",
+            AccountStatus::Suspended => "suspended",
+            AccountStatus::Takendown => "takendown",
+            AccountStatus::Deleted => "deleted",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<AccountStatus> {
+        match status {
+            "deactivated" => Some(AccountStatus::Deactivated),
+            "suspended" => Some(AccountStatus::Suspended),
+            "takendown" => Some(AccountStatus::Takendown),
+            "deleted" => Some(AccountStatus::Deleted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An `#account` event, announcing whether an account is active
+pub struct AccountEvent {
+    pub seq: i64,
+    pub did: Did,
+    pub active: bool,
+    /// Why the account is inactive. Statuses the bridge doesn't know are left out
+    pub status: Option<AccountStatus>,
+}
+
+impl AccountEvent {
+    /// The header used to filter this event, which has no operations
+    pub fn header(&self) -> EventHeader {
+        EventHeader {
+            seq: self.seq,
+            did: self.did.clone(),
+            ops: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An `#identity` event, announcing that an account's handle or DID document may have changed
+pub struct IdentityEvent {
+    pub seq: i64,
+    pub did: Did,
+    pub handle: Option<String>,
+}
+
+impl IdentityEvent {
+    /// The header used to filter this event, which has no operations
+    pub fn header(&self) -> EventHeader {
+        EventHeader {
+            seq: self.seq,
+            did: self.did.clone(),
+            ops: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors reading a firehose message
+pub enum FrameError {
+    #[error(transparent)]
+    Cbor(#[from] CborError),
+    #[error("Malformed firehose message - {reason}")]
+    Malformed { reason: &'static str },
+    #[error("The relay sent an error: {error}")]
+    Relay { error: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A message from the firehose
+pub enum Frame<'a> {
+    Commit(CommitFrame<'a>),
+    Account(AccountEvent),
+    Identity(IdentityEvent),
+    /// A message the bridge has no use for, such as `#info`, by type
+    Other(&'a str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A `#commit` message, borrowing from the buffer it was read from
+pub struct CommitFrame<'a> {
+    pub seq: i64,
+    pub repo: Did,
+    /// The CID of the commit
+    pub commit: Option<Cid>,
+    /// The repo revision the commit made, and the one it followed
+    pub rev: Option<&'a str>,
+    pub since: Option<&'a str>,
+    /// Whether the relay left the commit's blocks out for being too big, so its records have
+    /// to be fetched from the repo
+    pub too_big: bool,
+    ops: Items<'a>,
+    /// The commit's CAR slice
+    blocks: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Read a message: a header saying what it is, followed by its body
+    pub fn parse(message: &'a [u8]) -> Result<Frame<'a>, FrameError> {
+        use FrameError::{Malformed, Relay};
+        let (header, body) = cbor::decode(message)?;
+        let (body, _) = cbor::decode(body)?;
+        let text = |value: Cbor<'a>, key, reason| {
+            value
+                .get(key)
+                .and_then(|v| v.as_str())
+                .ok_or(Malformed { reason })
+        };
+        if header.get("op").and_then(|op| op.as_i64()) == Some(-1) {
+            let error = text(body, "error", "error without a name")?;
+            return Err(Relay {
+                error: error.to_string(),
+            });
+        }
+        let seq = || {
+            body.get("seq")
+                .and_then(|seq| seq.as_i64())
+                .ok_or(Malformed {
+                    reason: "missing seq",
+                })
+        };
+        let did = |key| {
+            let did = text(body, key, "missing DID")?;
+            normalize::parse_did(did).map_err(|_| Malformed {
+                reason: "invalid DID",
+            })
+        };
+        Ok(match text(header, "t", "missing type")? {
+            "#commit" => Frame::Commit(CommitFrame {
+                seq: seq()?,
+                repo: did("repo")?,
+                commit: body.get("commit").and_then(|commit| commit.as_link()),
+                rev: body.get("rev").and_then(|rev| rev.as_str()),
+                since: body.get("since").and_then(|since| since.as_str()),
+                too_big: body
+                    .get("tooBig")
+                    .and_then(|too_big| too_big.as_bool())
+                    .unwrap_or_default(),
+                ops: body
+                    .get("ops")
+                    .and_then(|ops| ops.as_array())
+                    .ok_or(Malformed {
+                        reason: "missing ops",
+                    })?,
+                blocks: body
+                    .get("blocks")
+                    .and_then(|blocks| blocks.as_bytes())
+                    .unwrap_or_default(),
+            }),
+            "#account" => Frame::Account(AccountEvent {
+                seq: seq()?,
+                did: did("did")?,
+                active: body
+                    .get("active")
+                    .and_then(|active| active.as_bool())
+                    .ok_or(Malformed {
+                        reason: "missing active",
+                    })?,
+                status: body
+                    .get("status")
+                    .and_then(|status| AccountStatus::parse(status.as_str()?)),
+            }),
+            "#identity" => Frame::Identity(IdentityEvent {
+                seq: seq()?,
+                did: did("did")?,
+                handle: body
+                    .get("handle")
+                    .and_then(|handle| Some(normalize::handle(handle.as_str()?))),
+            }),
+            other => Frame::Other(other),
+        })
+    }
+}
+
+impl<'a> CommitFrame<'a> {
+    /// Each operation, with the CID of the record it wrote unless it was a delete
+    fn operations(&self) -> impl Iterator<Item = (Operation, Option<Cid>)> + 'a {
+        self.ops.iter().filter_map(|op| {
+            let action = Action::parse(op.get("action")?.as_str()?)?;
+            let path = op.get("path")?.as_str()?.to_string();
+            Some((Operation { action, path }, op.get("cid")?.as_link()))
+        })
+    }
+
+    /// The repo and operations, for deciding whether the commit is worth handling
+    pub fn header(&self) -> EventHeader {
+        EventHeader {
+            seq: self.seq,
+            did: self.repo.clone(),
+            ops: self.operations().map(|(op, _)| op).collect(),
+        }
+    }
+
+    /// The record written at `path`, decoding only its block. `None` if the commit deleted
+    /// it, didn't touch it or left its block out
+    pub fn record(&self, path: &str) -> Result<Option<Cbor<'a>>, FrameError> {
+        let Some(cid) = self
+            .operations()
+            .find(|(op, _)| op.path == path)
+            .and_then(|(_, cid)| cid)
+        else {
+            return Ok(None);
+        };
+        let Some(block) = car::read_car(self.blocks)?.find(&cid) else {
+            return Ok(None);
+        };
+        Ok(Some(cbor::decode(block)?.0))
+    }
+}
+
+/// Encode a `#commit` message as a relay sends it, with each operation's record in its blocks
+///
+/// The bridge only reads the firehose, so this is for mocks and benchmarks
+pub fn encode_commit(seq: i64, repo: &Did, ops: &[(Operation, Option<Value>)]) -> Vec<u8> {
+    let text = |out: &mut Vec<u8>, text: &str| car::encode_into(out, &Value::from(text));
+    let commit = car::encode_dag_cbor(&Value::object([("did", Value::from(repo.as_str()))]));
+    let mut blocks = vec![commit];
+    let mut message = Vec::new();
+    car::encode_into(
+        &mut message,
+        &Value::object([("op", Value::from(1i64)), ("t", Value::from("#commit"))]),
+    );
+    car::head(&mut message, 5, 5);
+    text(&mut message, "seq");
+    car::encode_into(&mut message, &Value::from(seq));
+    text(&mut message, "ops");
+    car::head(&mut message, 4, ops.len() as u64);
+    for (op, record) in ops {
+        car::head(&mut message, 5, 3);
+        text(&mut message, "cid");
+        match record {
+            Some(record) => {
+                let block = car::encode_dag_cbor(record);
+                car::encode_link(&mut message, &Cid::for_dag_cbor(&block));
+                blocks.push(block);
+            }
+            None => car::encode_into(&mut message, &Value::Null),
+        }
+        text(&mut message, "path");
+        text(&mut message, &op.path);
+        text(&mut message, "action");
+        text(&mut message, op.action.as_str());
+    }
+    text(&mut message, "repo");
+    text(&mut message, repo.as_str());
+    text(&mut message, "blocks");
+    let commit = Cid::for_dag_cbor(&blocks[0]);
+    let car = car::write_car(&commit, &blocks);
+    car::head(&mut message, 2, car.len() as u64);
+    message.extend(car);
+    text(&mut message, "commit");
+    car::encode_link(&mut message, &commit);
+    message
+}
+
+#[derive(Debug)]
+/// The bridge's cursor into the firehose
+///
+/// `head` is the latest sequence number seen from the relay and `processed` is the latest one
+/// the bridge has finished handling. The difference is the consumer lag
+pub struct FirehoseCursor {
+    head: AtomicI64,
+    processed: AtomicI64,
+    /// When the relay last sent an event, in Unix milliseconds, or 0 if it hasn't since startup
+    seen_at: AtomicI64,
+}
+
+impl Default for FirehoseCursor {
+    fn default() -> Self {
+        FirehoseCursor::new(0)
+    }
+}
+
+impl FirehoseCursor {
+    /// Create a cursor resuming from `seq`
+    pub fn new(seq: i64) -> FirehoseCursor {
+        FirehoseCursor {
+            head: AtomicI64::new(seq),
+            processed: AtomicI64::new(seq),
+            seen_at: AtomicI64::new(0),
+        }
+    }
+
+    /// Record that the relay has sent an event with this sequence number
+    pub fn saw(&self, seq: i64) {
+        self.head.fetch_max(seq, Ordering::Relaxed);
+        let now = unix_millis(SystemTime::now());
+        self.seen_at.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Record that the event with this sequence number has been fully handled
+    pub fn processed(&self, seq: i64) {
+        self.processed.fetch_max(seq, Ordering::Relaxed);
+        self.saw(seq);
+    }
+
+    /// Resume from `seq` instead, as on switching to a relay which numbers events differently
+    pub fn reset(&self, seq: i64) {
+        self.head.store(seq, Ordering::Relaxed);
+        self.processed.store(seq, Ordering::Relaxed);
+    }
+
+    pub fn head(&self) -> i64 {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    /// The sequence number to resume from after a restart
+    pub fn position(&self) -> i64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// How long it's been since the relay last sent an event, if it has since startup
+    pub fn silent_for(&self, now: SystemTime) -> Option<Duration> {
+        let seen_at = self.seen_at.load(Ordering::Relaxed);
+        let seen_at = (seen_at > 0).then(|| from_unix_millis(seen_at))?;
+        Some(now.duration_since(seen_at).unwrap_or_default())
+    }
+
+    /// Number of events seen but not yet handled
+    pub fn lag(&self) -> i64 {
+        (self.head() - self.position()).max(0)
+    }
+
+    /// Persist the resume position
+    pub fn save(&self, dir: &StateDir) -> io::Result<()> {
+        dir.write(CURSOR_FILE, self.position().to_string().as_bytes())
+    }
+
+    /// Load the persisted resume position, starting from 0 if there is none
+    pub fn load(dir: &StateDir) -> io::Result<FirehoseCursor> {
+        let Some(contents) = dir.read(CURSOR_FILE)? else {
+            return Ok(FirehoseCursor::default());
+        };
+        String::from_utf8_lossy(&contents)
+            .trim()
+            .parse()
+            .map(FirehoseCursor::new)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Load the cursor for `shard` from the shared state dir `root`
+    ///
+    /// A shard without a cursor of its own resumes from the lowest cursor left by any shard
+    /// layout, so resharding never skips events
+    pub fn load_shard(root: &StateDir, shard: Shard) -> io::Result<FirehoseCursor> {
+        let dir = shard.state_dir(root)?;
+        if dir.read(CURSOR_FILE)?.is_some() {
+            return FirehoseCursor::load(&dir);
+        }
+        let mut lowest: Option<i64> = None;
+        let mut consider = |dir: &StateDir| -> io::Result<()> {
+            if dir.read(CURSOR_FILE)?.is_some() {
+                let position = FirehoseCursor::load(dir)?.position();
+                lowest = Some(lowest.map_or(position, |l| l.min(position)));
+            }
+            Ok(())
+        };
+        consider(root)?;
+        for name in root.subdirs()? {
+            if name.starts_with("shard-") {
+                consider(&root.subdir(&name)?)?;
+            }
+        }
+        Ok(FirehoseCursor::new(lowest.unwrap_or_default()))
+    }
+}
+
+#[derive(Debug, Default)]
+/// Commits handled since the cursor was last saved, by sequence number and commit CID
+///
+/// The CID tells apart commits which share a sequence number, as after a relay's sequence
+/// has been reset. Another relay numbers the same commits differently, so on switching to one
+/// those handled are [carried over](ProcessedEvents::carry_over) by CID alone
+pub struct ProcessedEvents {
+    /// The commits at each sequence number, and whether each has been handled or is still
+    /// being handled
+    events: Mutex<BTreeMap<i64, BTreeMap<Cid, bool>>>,
+    /// The commits handled from the relay consumed before this one
+    carried: Mutex<HashSet<Cid>>,
+    log: Option<JsonLog>,
+}
+
+impl ProcessedEvents {
+    /// A window persisted in `dir`, with the events already there
+    pub fn open(dir: StateDir) -> io::Result<ProcessedEvents> {
+        let (log, lines) = JsonLog::open(dir, PROCESSED_FILE)?;
+        let mut events: BTreeMap<i64, BTreeMap<Cid, bool>> = BTreeMap::new();
+        let mut carried = HashSet::new();
+        for line in lines {
+            let seq = line.get("seq").and_then(Value::as_i64);
+            let commit = line.get("commit").and_then(Value::as_str);
+            match (seq, commit.map(str::parse)) {
+                (Some(seq), Some(Ok(commit))) => {
+                    events.entry(seq).or_default().insert(commit, true);
+                }
+                (None, Some(Ok(commit))) => {
+                    carried.insert(commit);
+                }
+                _ => {}
+            }
+        }
+        Ok(ProcessedEvents {
+            events: Mutex::new(events),
+            carried: Mutex::new(carried),
+            log: Some(log),
+        })
+    }
+
+    /// Start handling the commit `commit` at `seq`, unless it has been already
+    pub fn claim(&self, seq: i64, commit: &Cid) -> bool {
+        if self.carried.lock().unwrap().contains(commit) {
+            return false;
+        }
+        let mut events = self.events.lock().unwrap();
+        let commits = events.entry(seq).or_default();
+        if commits.contains_key(commit) {
+            return false;
+        }
+        commits.insert(*commit, false);
+        true
+    }
+
+    /// Record that the commit claimed at `seq` has been handled
+    pub fn handled(&self, seq: i64, commit: &Cid) -> io::Result<()> {
+        let mut events = self.events.lock().unwrap();
+        events.entry(seq).or_default().insert(*commit, true);
+        while events.len() > PROCESSED_WINDOW {
+            events.pop_first();
+        }
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        log.append([line(seq, commit)])
+    }
+
+    /// Give up the claim on the commit `commit` at `seq`, which couldn't be handled, so it's
+    /// handled when it comes again
+    pub fn abandon(&self, seq: i64, commit: &Cid) {
+        let mut events = self.events.lock().unwrap();
+        if let Some(commits) = events.get_mut(&seq) {
+            if commits.get(commit) == Some(&false) {
+                commits.remove(commit);
+            }
+            if commits.is_empty() {
+                events.remove(&seq);
+            }
+        }
+    }
+
+    /// Forget the events at or before `position`, which a relay won't send again once the
+    /// cursor there is saved. Returns how many there were
+    pub fn compact(&self, position: i64) -> io::Result<usize> {
+        let mut events = self.events.lock().unwrap();
+        let kept = events.split_off(&(position + 1));
+        let forgotten = std::mem::replace(&mut *events, kept);
+        let Some(log) = &self.log else {
+            return Ok(forgotten.len());
+        };
+        self.rewrite(log, &events)?;
+        Ok(forgotten.len())
+    }
+
+    /// Move the commits handled so far out of the window to be recognised by CID alone, as
+    /// another relay with its own sequence numbers replays them. They replace any carried over
+    /// before. Returns how many there were
+    pub fn carry_over(&self) -> io::Result<usize> {
+        let mut events = self.events.lock().unwrap();
+        let handled = events.values().flat_map(|commits| {
+            let commits = commits.iter().filter(|(_, handled)| **handled);
+            commits.map(|(commit, _)| *commit)
+        });
+        let handled: HashSet<Cid> = handled.collect();
+        let count = handled.len();
+        *self.carried.lock().unwrap() = handled;
+        events.clear();
+        if let Some(log) = &self.log {
+            self.rewrite(log, &events)?;
+        }
+        Ok(count)
+    }
+
+    /// Replace the persisted window with the handled commits of `events` and those carried
+    fn rewrite(
+        &self,
+        log: &JsonLog,
+        events: &BTreeMap<i64, BTreeMap<Cid, bool>>,
+    ) -> io::Result<()> {
+        let handled = events.iter().flat_map(|(seq, commits)| {
+            let commits = commits.iter().filter(|(_, handled)| **handled);
+            commits.map(move |(commit, _)| line(*seq, commit))
+        });
+        let carried = self.carried.lock().unwrap();
+        let carried = carried
+            .iter()
+            .map(|commit| Value::object([("commit", Value::from(commit.to_string()))]));
+        log.rewrite(handled.chain(carried))
+    }
+
+    pub fn len(&self) -> usize {
+        self.events
+            .lock()
+            .unwrap()
+            .values()
+            .map(BTreeMap::len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn line(seq: i64, commit: &Cid) -> Value {
+    Value::object([
+        ("seq", Value::from(seq)),
+        ("commit", Value::from(commit.to_string())),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn lag_tracks_unprocessed_events() {
+        let cursor = FirehoseCursor::new(10);
+        cursor.saw(15);
+        cursor.processed(12);
+        assert_eq!(cursor.lag(), 3);
+        // Out of order completion never moves the cursor backwards
+        cursor.processed(11);
+        assert_eq!(cursor.position(), 12);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = crate::storage::tests::temp_state_dir();
+        assert_eq!(FirehoseCursor::load(&dir).unwrap().position(), 0);
+        let cursor = FirehoseCursor::new(7);
+        cursor.saw(9);
+        cursor.save(&dir).unwrap();
+        // Only handled events count, anything merely seen is replayed on restart
+        assert_eq!(FirehoseCursor::load(&dir).unwrap().position(), 7);
+    }
+
+    #[test]
+    fn handled_commits_are_remembered_until_the_cursor_passes_them() {
+        let dir = crate::storage::tests::temp_state_dir();
+        let events = ProcessedEvents::open(dir.clone()).unwrap();
+        let (first, second) = (Cid::for_raw(b"first"), Cid::for_raw(b"second"));
+        assert!(events.claim(7, &first));
+        assert!(
+            !events.claim(7, &first),
+            "a commit being handled is claimed"
+        );
+        events.handled(7, &first).unwrap();
+        // The same sequence number from a reset relay is another commit
+        assert!(events.claim(7, &second));
+        // One which couldn't be handled is handled when it comes again, unlike a handled one
+        events.abandon(7, &second);
+        events.abandon(7, &first);
+        assert!(events.claim(7, &second));
+        assert!(!events.claim(7, &first));
+        assert!(events.claim(8, &first));
+        events.handled(8, &first).unwrap();
+
+        // After a crash, only what was handled is remembered
+        let reopened = ProcessedEvents::open(dir.clone()).unwrap();
+        assert!(!reopened.claim(7, &first));
+        assert!(reopened.claim(7, &second));
+        assert_eq!(reopened.compact(7).unwrap(), 1);
+        assert_eq!(ProcessedEvents::open(dir).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn shards_partition_dids() {
+        let shards: Vec<_> = (0..4).map(|i| Shard::new(i, 4).unwrap()).collect();
+        for n in 0..100 {
+            let did = Did::try_create(format!("did:plc:account{n}")).unwrap();
+            assert_eq!(shards.iter().filter(|s| s.owns(&did)).count(), 1);
+            assert!(Shard::SINGLE.owns(&did));
+        }
+        assert_eq!("1/4".parse(), Ok(shards[1]));
+        assert_eq!(
+            "4/4".parse::<Shard>(),
+            Err(ShardError::OutOfRange { index: 4, count: 4 })
+        );
+        assert!(matches!(
+            "1".parse::<Shard>(),
+            Err(ShardError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn resharding_resumes_from_lowest_cursor() {
+        let root = crate::storage::tests::temp_state_dir();
+        for (i, position) in [(0, 50), (1, 20)] {
+            let shard = Shard::new(i, 2).unwrap();
+            FirehoseCursor::new(position)
+                .save(&shard.state_dir(&root).unwrap())
+                .unwrap();
+        }
+        let old = Shard::new(0, 2).unwrap();
+        assert_eq!(
+            FirehoseCursor::load_shard(&root, old).unwrap().position(),
+            50
+        );
+        let new = Shard::new(2, 3).unwrap();
+        assert_eq!(
+            FirehoseCursor::load_shard(&root, new).unwrap().position(),
+            20
+        );
+    }
+
+    #[test]
+    fn decodes_only_wanted_records() {
+        let repo = Did::try_create("did:plc:alice".to_string()).unwrap();
+        let post = json::parse(r#"{"$type": "app.bsky.feed.post", "text": "hi"}"#).unwrap();
+        let like = json::parse(r#"{"$type": "app.bsky.feed.like"}"#).unwrap();
+        let op = |action, path: &str| Operation {
+            action,
+            path: path.to_string(),
+        };
+        let message = encode_commit(
+            7,
+            &repo,
+            &[
+                (
+                    op(Action::Create, "app.bsky.feed.post/1"),
+                    Some(post.clone()),
+                ),
+                (op(Action::Create, "app.bsky.feed.like/2"), Some(like)),
+                (op(Action::Delete, "app.bsky.feed.post/0"), None),
+            ],
+        );
+        let Frame::Commit(commit) = Frame::parse(&message).unwrap() else {
+            panic!("not a commit");
+        };
+        let header = commit.header();
+        assert_eq!((header.seq, &header.did), (7, &repo));
+        assert!(commit.commit.is_some());
+        assert_eq!(header.ops[2], op(Action::Delete, "app.bsky.feed.post/0"));
+        let record = commit.record("app.bsky.feed.post/1").unwrap().unwrap();
+        assert_eq!(record.to_json(), post);
+        assert_eq!(commit.record("app.bsky.feed.post/0"), Ok(None));
+
+        // Other message types
+        let mut message = car::encode_dag_cbor(&Value::object([
+            ("op", Value::from(1i64)),
+            ("t", Value::from("#identity")),
+        ]));
+        message.extend(car::encode_dag_cbor(
+            &json::parse(r#"{"seq": 8, "did": "did:plc:alice", "handle": "alice.example"}"#)
+                .unwrap(),
+        ));
+        assert!(matches!(
+            Frame::parse(&message),
+            Ok(Frame::Identity(IdentityEvent { seq: 8, .. }))
+        ));
+        let mut error = car::encode_dag_cbor(&Value::object([("op", Value::from(-1i64))]));
+        error.extend(car::encode_dag_cbor(&Value::object([(
+            "error",
+            Value::from("FutureCursor"),
+        )])));
+        assert_eq!(
+            Frame::parse(&error),
+            Err(FrameError::Relay {
+                error: "FutureCursor".to_string()
+            })
+        );
+    }
+}
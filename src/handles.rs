@@ -0,0 +1,216 @@
+//! Custom domains as bridged accounts' handles
+//!
+//! A bridged account starts with a handle the bridge allocates under its hostname, and can
+//! switch to a domain its owner controls. The domain has to name the account's DID first, in
+//! either of the places handle resolution looks, which [`instructions`] spells out:
+//!
+//! - a TXT record at `_atproto.<domain>` of `did=<did>`
+//! - `https://<domain>/.well-known/atproto-did`, answering with the DID as plain text
+//!
+//! [`switch`] checks both then changes the handle, refusing if any other mapping has it, so
+//! two accounts never share one. DNS changes take a while to be seen, so [`poll`] keeps
+//! checking until a deadline. The old handle stops resolving as soon as the switch is made,
+//! as the bridge only resolves handles its mappings have
+
+use crate::bridge::Bridge;
+use crate::json::Value;
+use crate::normalize;
+use crate::store::{MappingStatus, StoreError};
+use crate::transport::OutboundRequest;
+use atproto::handle::Handle;
+use atproto::DID::Did;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum HandleError {
+    #[error("{0} isn't a domain that can be a handle")]
+    InvalidDomain(String),
+    #[error("{did} isn't a bridged account")]
+    NotBridged { did: Did },
+    #[error(
+        "Neither _atproto.{domain} nor https://{domain}/.well-known/atproto-did names {did} yet"
+    )]
+    Unverified { domain: String, did: Did },
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Where a domain was found to name its account
+pub enum VerifiedBy {
+    Dns,
+    WellKnown,
+}
+
+impl VerifiedBy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VerifiedBy::Dns => "dns",
+            VerifiedBy::WellKnown => "well-known",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What a domain has to serve to become `did`'s handle, either of which will do
+pub struct Instructions {
+    pub domain: String,
+    pub did: Did,
+}
+
+impl Instructions {
+    /// The name of the TXT record
+    pub fn txt_name(&self) -> String {
+        format!("_atproto.{}", self.domain)
+    }
+
+    pub fn txt_value(&self) -> String {
+        format!("did={}", self.did.as_str())
+    }
+
+    pub fn well_known_url(&self) -> String {
+        format!("https://{}/.well-known/atproto-did", self.domain)
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("domain", Value::from(self.domain.as_str())),
+            (
+                "dns",
+                Value::object([
+                    ("type", Value::from("TXT")),
+                    ("name", Value::from(self.txt_name())),
+                    ("value", Value::from(self.txt_value())),
+                ]),
+            ),
+            (
+                "wellKnown",
+                Value::object([
+                    ("url", Value::from(self.well_known_url())),
+                    ("body", Value::from(self.did.as_str())),
+                ]),
+            ),
+        ])
+    }
+}
+
+/// What `domain` has to serve to become `did`'s handle
+pub fn instructions(did: &Did, domain: &str) -> Result<Instructions, HandleError> {
+    let domain = normalize::handle(domain);
+    let handle = Handle::try_create(domain.clone())
+        .map_err(|_| HandleError::InvalidDomain(domain.clone()))?;
+    Ok(Instructions {
+        domain: handle.as_str().to_string(),
+        did: did.clone(),
+    })
+}
+
+/// Where `domain` names `did`, checking DNS first as handle resolution does
+pub fn verify(bridge: &Bridge, did: &Did, domain: &str) -> Result<VerifiedBy, HandleError> {
+    let wanted = instructions(did, domain)?;
+    // What was seen while waiting for the record to propagate doesn't count
+    bridge.dns.forget(&wanted.txt_name());
+    if let Ok(Some(named)) = bridge.dns.atproto_did(&wanted.domain) {
+        if named == *did {
+            return Ok(VerifiedBy::Dns);
+        }
+    }
+    let request = OutboundRequest::get(wanted.well_known_url()).with_header("accept", "text/plain");
+    if let Ok(response) = bridge.transport.send(&request) {
+        let body = String::from_utf8_lossy(&response.body);
+        if response.is_success() && body.trim() == did.as_str() {
+            return Ok(VerifiedBy::WellKnown);
+        }
+    }
+    Err(HandleError::Unverified {
+        domain: wanted.domain,
+        did: did.clone(),
+    })
+}
+
+/// Make `domain` the handle of the bridged account `did`, once it names `did`
+pub fn switch(bridge: &Bridge, did: &Did, domain: &str) -> Result<VerifiedBy, HandleError> {
+    match bridge.identities.get(did) {
+        Some(mapping) if mapping.status != MappingStatus::Passive => {}
+        _ => return Err(HandleError::NotBridged { did: did.clone() }),
+    }
+    let verified_by = verify(bridge, did, domain)?;
+    let domain = instructions(did, domain)?.domain;
+    bridge.identities.claim_handle(did, &domain)?;
+    Ok(verified_by)
+}
+
+/// [`switch`], retrying every `interval` while the domain doesn't name `did`, until `timeout`
+pub fn poll(
+    bridge: &Bridge,
+    did: &Did,
+    domain: &str,
+    interval: Duration,
+    timeout: Duration,
+) -> Result<VerifiedBy, HandleError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match switch(bridge, did, domain) {
+            Err(HandleError::Unverified { .. }) if Instant::now() + interval < deadline => {
+                std::thread::sleep(interval);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::store::Mapping;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use std::sync::Arc;
+
+    #[test]
+    fn switches_once_the_domain_names_the_account() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new().with_transport(mock.clone());
+        let alice = atproto::did!("did:web:bridge.example:u:alice");
+        let mut mapping = Mapping::new(alice.clone(), "https://a.example/users/alice");
+        mapping.handle = Some("alice.a.example.bridge.example".to_string());
+        bridge.identities.insert(mapping);
+        let bob = atproto::did!("did:web:bridge.example:u:bob");
+        let mut mapping = Mapping::new(bob.clone(), "https://a.example/users/bob");
+        mapping.handle = Some("bob.example".to_string());
+        bridge.identities.insert(mapping);
+
+        let wanted = instructions(&alice, "Alice.Example.").unwrap();
+        assert_eq!(wanted.txt_name(), "_atproto.alice.example");
+        assert_eq!(wanted.txt_value(), "did=did:web:bridge.example:u:alice");
+        assert!(instructions(&alice, "localhost").is_err());
+
+        let url = "https://alice.example/.well-known/atproto-did";
+        assert!(matches!(
+            switch(&bridge, &alice, "alice.example"),
+            Err(HandleError::Unverified { .. })
+        ));
+        let named = OutboundResponse::new(200).with_body("did:web:bridge.example:u:alice\n");
+        mock.respond(Method::Get, url, named);
+        assert_eq!(
+            switch(&bridge, &alice, "alice.example"),
+            Ok(VerifiedBy::WellKnown)
+        );
+        let mapping = bridge.identities.get(&alice).unwrap();
+        assert_eq!(mapping.handle.as_deref(), Some("alice.example"));
+
+        // A domain already another account's handle stays theirs, even if it names alice
+        let url = "https://bob.example/.well-known/atproto-did";
+        let named = OutboundResponse::new(200).with_body("did:web:bridge.example:u:alice");
+        mock.respond(Method::Get, url, named);
+        assert!(matches!(
+            switch(&bridge, &alice, "bob.example"),
+            Err(HandleError::Store(StoreError::HandleTaken { .. }))
+        ));
+        assert_eq!(
+            bridge.identities.get(&bob).unwrap().handle.as_deref(),
+            Some("bob.example")
+        );
+    }
+}
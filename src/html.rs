@@ -0,0 +1,268 @@
+//! Just enough HTML for post content and page metadata
+//!
+//! Fediverse posts are HTML fragments and link previews need a page's `<head>`, neither of
+//! which needs a real parser: [`tokenize`] splits markup into text and tags, decoding
+//! character references, and consumers pick out the few elements they care about. Comments,
+//! doctypes, scripts and styles are skipped
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A piece of markup
+pub enum Token {
+    /// Text with character references decoded
+    Text(String),
+    /// A start tag, with lowercased names and decoded attribute values
+    Start {
+        name: String,
+        attributes: Vec<(String, String)>,
+    },
+    End {
+        name: String,
+    },
+}
+
+impl Token {
+    /// Whether this is a start tag for `element`
+    pub fn is_start(&self, element: &str) -> bool {
+        matches!(self, Token::Start { name, .. } if name == element)
+    }
+
+    /// Whether this is an end tag for `element`
+    pub fn is_end(&self, element: &str) -> bool {
+        matches!(self, Token::End { name } if name == element)
+    }
+
+    /// An attribute of a start tag
+    pub fn attribute(&self, attribute: &str) -> Option<&str> {
+        match self {
+            Token::Start { attributes, .. } => attributes
+                .iter()
+                .find(|(name, _)| name == attribute)
+                .map(|(_, value)| value.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Whether a start tag's `class` includes `class`
+    pub fn has_class(&self, class: &str) -> bool {
+        self.attribute("class")
+            .is_some_and(|classes| classes.split_ascii_whitespace().any(|c| c == class))
+    }
+}
+
+/// Replace character references with the characters they stand for
+///
+/// Anything which doesn't look like a reference is left alone, so a stray `&` survives
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        decoded.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let entity = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Escape text for inclusion in markup, inside elements or quoted attributes
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parse the attributes of a start tag from `i`, returning them and the offset past the tag
+fn attributes(html: &str, mut i: usize) -> (Vec<(String, String)>, usize) {
+    let b = html.as_bytes();
+    let mut attributes = Vec::new();
+    loop {
+        while i < b.len() && (b[i].is_ascii_whitespace() || b[i] == b'/') {
+            i += 1;
+        }
+        if i >= b.len() || b[i] == b'>' {
+            return (attributes, (i + 1).min(b.len()));
+        }
+        let name_start = i;
+        while i < b.len() && !b[i].is_ascii_whitespace() && !matches!(b[i], b'=' | b'>' | b'/') {
+            i += 1;
+        }
+        let name = html[name_start..i].to_ascii_lowercase();
+        while i < b.len() && b[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let mut value = String::new();
+        if b.get(i) == Some(&b'=') {
+            i += 1;
+            while i < b.len() && b[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            let (start, end, next) = match b.get(i) {
+                Some(&quote @ (b'"' | b'\'')) => {
+                    let end = html[i + 1..]
+                        .find(quote as char)
+                        .map_or(b.len(), |e| i + 1 + e);
+                    (i + 1, end, (end + 1).min(b.len()))
+                }
+                _ => {
+                    let end = html[i..]
+                        .find(|c: char| c.is_ascii_whitespace() || c == '>')
+                        .map_or(b.len(), |e| i + e);
+                    (i, end, end)
+                }
+            };
+            value = decode_entities(&html[start..end]);
+            i = next;
+        }
+        attributes.push((name, value));
+    }
+}
+
+/// Split markup into text and tags
+pub fn tokenize(html: &str) -> Vec<Token> {
+    let b = html.as_bytes();
+    let mut tokens = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+    let skip_past = |from: usize, marker: &str| {
+        html[from..]
+            .find(marker)
+            .map_or(html.len(), |e| from + e + marker.len())
+    };
+    while i < b.len() {
+        if b[i] != b'<' {
+            i += 1;
+            continue;
+        }
+        let next = b.get(i + 1).copied().unwrap_or_default();
+        let closing = next == b'/';
+        let name_start = if closing { i + 2 } else { i + 1 };
+        let is_tag = b.get(name_start).is_some_and(u8::is_ascii_alphabetic);
+        if !is_tag && !matches!(next, b'!' | b'?') {
+            i += 1;
+            continue;
+        }
+        if text_start < i {
+            tokens.push(Token::Text(decode_entities(&html[text_start..i])));
+        }
+        if html[i..].starts_with("<!--") {
+            i = skip_past(i + 4, "-->");
+        } else if !is_tag {
+            i = skip_past(i, ">");
+        } else {
+            let name_end = html[name_start..]
+                .find(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+                .map_or(html.len(), |e| name_start + e);
+            let name = html[name_start..name_end].to_ascii_lowercase();
+            if closing {
+                i = skip_past(name_end, ">");
+                tokens.push(Token::End { name });
+            } else {
+                let (attributes, end) = attributes(html, name_end);
+                i = end;
+                // Scripts and styles aren't content, and may contain anything
+                if matches!(name.as_str(), "script" | "style") {
+                    let lower = html[i..].to_ascii_lowercase();
+                    i = lower
+                        .find(&format!("</{name}"))
+                        .map_or(html.len(), |e| skip_past(i + e, ">"));
+                } else {
+                    tokens.push(Token::Start { name, attributes });
+                }
+            }
+        }
+        text_start = i;
+    }
+    if text_start < b.len() {
+        tokens.push(Token::Text(decode_entities(&html[text_start..])));
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &amp; b &lt;3"), "a & b <3");
+        assert_eq!(decode_entities("&#39;&#x1F600;&quot;"), "'😀\"");
+        assert_eq!(
+            decode_entities("fish & chips &bogus; &#xzz;"),
+            "fish & chips &bogus; &#xzz;"
+        );
+        let text = "<a href=\"x\">Tom & Jerry's</a>";
+        assert_eq!(decode_entities(&escape(text)), text);
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = tokenize(
+            "<!-- hi --><P Class='a b'>1 &lt; 2<br/><a href=x&amp;y data-x>z</a></p>\
+             <script>if (a < b) {}</script>x < y",
+        );
+        let start = |name: &str, attributes: &[(&str, &str)]| Token::Start {
+            name: name.to_string(),
+            attributes: attributes
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+        };
+        let text = |text: &str| Token::Text(text.to_string());
+        let end = |name: &str| Token::End {
+            name: name.to_string(),
+        };
+        assert_eq!(
+            tokens,
+            vec![
+                start("p", &[("class", "a b")]),
+                text("1 < 2"),
+                start("br", &[]),
+                start("a", &[("href", "x&y"), ("data-x", "")]),
+                text("z"),
+                end("a"),
+                end("p"),
+                text("x < y"),
+            ]
+        );
+        assert!(tokens[0].has_class("b"));
+        assert_eq!(tokens[3].attribute("href"), Some("x&y"));
+    }
+}
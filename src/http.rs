@@ -0,0 +1,697 @@
+//! A small HTTP/1.1 server layer
+//!
+//! The bridge only needs to answer simple request/response endpoints (admin API, inboxes,
+//! well-known documents), so this implements just enough of HTTP/1.1 on top of `std::net`
+//! to serve them. Handlers are plain `Request -> Response` functions which keeps them easy
+//! to test without a socket. A response can hand its connection on to an [`Upgrade`] for
+//! the few endpoints that stream, such as WebSocket subscriptions. Serving them, with
+//! [`serve`], needs the `net` feature
+
+use crate::json::Value;
+use crate::shutdown::Shutdown;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+#[cfg(feature = "net")]
+use {std::io::BufReader, std::net::TcpListener, std::thread, std::time::Duration};
+
+/// How often an idle listener checks whether shutdown has been requested
+#[cfg(feature = "net")]
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Largest request body that will be read into memory
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// Longest request line or header line, newline included
+pub const MAX_HEADER_LINE: usize = 8 * 1024;
+/// The most headers a request may have
+pub const MAX_HEADERS: usize = 100;
+/// How long a connection may go without sending or accepting anything before it's dropped
+#[cfg(feature = "net")]
+pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Method> {
+        match s {
+            "GET" => Some(Method::Get),
+            "HEAD" => Some(Method::Head),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors reading a request off the wire
+pub enum HttpError {
+    #[error("Malformed request line - found {found}")]
+    MalformedRequestLine { found: String },
+    #[error("Unsupported method {found}")]
+    UnsupportedMethod { found: String },
+    #[error("Malformed header - found {found}")]
+    MalformedHeader { found: String },
+    #[error("Request body of {size} bytes exceeds the limit")]
+    BodyTooLarge { size: usize },
+    #[error("Invalid or repeated Content-Length - found {found}")]
+    InvalidContentLength { found: String },
+    /// A chunked body, which has to be sent with a `Content-Length` instead
+    #[error("A request body needs a Content-Length, not chunked transfer coding")]
+    LengthRequired,
+    #[error("Unsupported transfer coding {found}")]
+    UnsupportedTransferEncoding { found: String },
+    #[error("A request or header line is longer than {MAX_HEADER_LINE} bytes")]
+    LineTooLong,
+    #[error("More than {MAX_HEADERS} headers")]
+    TooManyHeaders,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An incoming HTTP request
+pub struct Request {
+    pub method: Method,
+    /// The path and query exactly as requested
+    pub target: String,
+    /// The percent-decoded path, without the query string
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    /// Headers with lowercased names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// The address the request came from, when it was read from a connection
+    pub peer: Option<IpAddr>,
+}
+
+impl Request {
+    /// Construct a request from a target such as `/path?query=value`
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, parse_query(query)),
+            None => (target, Vec::new()),
+        };
+        Request {
+            method,
+            target: target.to_string(),
+            path: percent_decode(path),
+            query,
+            headers: Vec::new(),
+            body: Vec::new(),
+            peer: None,
+        }
+    }
+
+    /// Builder style helper for adding a header
+    pub fn with_header(mut self, name: &str, value: &str) -> Request {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    /// Builder style helper for setting the body
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Request {
+        self.body = body.into();
+        self
+    }
+
+    /// Get the first header with the (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Get the first query parameter with the name
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The path split into its non-empty segments
+    pub fn segments(&self) -> Vec<&str> {
+        self.path.split('/').filter(|s| !s.is_empty()).collect()
+    }
+
+    /// Read a request from a stream
+    pub fn read_from<R: BufRead>(reader: &mut R) -> Result<Request, HttpError> {
+        Request::read_limited(reader, |_| MAX_BODY_SIZE)
+    }
+
+    /// Read a request from a stream, refusing a body longer than `limit` says the request,
+    /// as yet with only its head, may have. Nothing beyond the limit is read, nor is memory
+    /// set aside for more than arrives
+    pub fn read_limited<R: BufRead>(
+        reader: &mut R,
+        limit: impl FnOnce(&Request) -> usize,
+    ) -> Result<Request, HttpError> {
+        use HttpError::*;
+        let mut line = String::new();
+        read_line(reader, &mut line)?;
+        let mut parts = line.trim_end().split(' ');
+        let (Some(method), Some(target), Some(_version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(MalformedRequestLine { found: line });
+        };
+        let method = Method::parse(method).ok_or_else(|| UnsupportedMethod {
+            found: method.to_string(),
+        })?;
+        let mut request = Request::new(method, target);
+
+        loop {
+            line.clear();
+            read_line(reader, &mut line)?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if request.headers.len() == MAX_HEADERS {
+                return Err(TooManyHeaders);
+            }
+            let (name, value) = header.split_once(':').ok_or_else(|| MalformedHeader {
+                found: header.to_string(),
+            })?;
+            request = request.with_header(name.trim(), value.trim());
+        }
+
+        if let Some(coding) = request.header("transfer-encoding") {
+            if coding.to_ascii_lowercase().contains("chunked") {
+                return Err(LengthRequired);
+            }
+            return Err(UnsupportedTransferEncoding {
+                found: coding.to_string(),
+            });
+        }
+        let length = content_length(&request)?;
+        if length > limit(&request).min(MAX_BODY_SIZE) {
+            return Err(BodyTooLarge { size: length });
+        }
+        let mut body = Vec::with_capacity(length.min(64 * 1024));
+        reader.take(length as u64).read_to_end(&mut body)?;
+        if body.len() < length {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        request.body = body;
+        Ok(request)
+    }
+}
+
+/// The length of `request`'s body, if it has one, refusing one given more than once (even
+/// the same each time) or not as digits alone, so it can't be read differently by a proxy
+fn content_length(request: &Request) -> Result<usize, HttpError> {
+    let lengths = request.headers.iter();
+    let mut lengths = lengths.filter(|(name, _)| name.eq_ignore_ascii_case("content-length"));
+    let Some((_, length)) = lengths.next() else {
+        return Ok(0);
+    };
+    let invalid = || HttpError::InvalidContentLength {
+        found: length.clone(),
+    };
+    let digits = !length.is_empty() && length.bytes().all(|b| b.is_ascii_digit());
+    if !digits || lengths.next().is_some() {
+        return Err(invalid());
+    }
+    length.parse().map_err(|_| invalid())
+}
+
+/// Read a line into `line`, refusing one longer than [`MAX_HEADER_LINE`] without reading the
+/// rest of it
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> Result<(), HttpError> {
+    let read = reader.take(MAX_HEADER_LINE as u64).read_line(line)?;
+    if read == MAX_HEADER_LINE && !line.ends_with('\n') {
+        return Err(HttpError::LineTooLong);
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+/// Takes over a connection once the response upgrading it has been written, until the
+/// connection or the server is shut down
+pub struct Upgrade(Arc<UpgradeFn>);
+
+type UpgradeFn = dyn Fn(TcpStream, &Shutdown) -> io::Result<()> + Send + Sync;
+
+impl Upgrade {
+    pub fn new(
+        run: impl Fn(TcpStream, &Shutdown) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Upgrade {
+        Upgrade(Arc::new(run))
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Upgrade")
+    }
+}
+
+impl PartialEq for Upgrade {
+    fn eq(&self, other: &Upgrade) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Part of a file sent as a response body, read as it's written rather than all at once
+pub struct FileBody {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An outgoing HTTP response
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Sent in place of `body`, if set
+    pub file: Option<FileBody>,
+    /// What the connection is handed to after a `101 Switching Protocols`
+    pub upgrade: Option<Upgrade>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            file: None,
+            upgrade: None,
+        }
+    }
+
+    /// Switch the connection to another protocol, run by `upgrade`
+    pub fn switching_protocols(upgrade: Upgrade) -> Response {
+        Response {
+            upgrade: Some(upgrade),
+            ..Response::new(101)
+        }
+    }
+
+    /// A response with a JSON body
+    pub fn json(status: u16, value: &Value) -> Response {
+        Response::new(status)
+            .with_header("content-type", "application/json")
+            .with_body(value.to_string())
+    }
+
+    /// A JSON error response of the form `{"error": message}`
+    pub fn error(status: u16, message: impl Into<String>) -> Response {
+        Response::json(
+            status,
+            &Value::object([("error", Value::String(message.into()))]),
+        )
+    }
+
+    /// An XRPC error response, with a machine-readable `error` name and a `message`
+    pub fn xrpc_error(status: u16, error: &str, message: impl Into<String>) -> Response {
+        Response::json(
+            status,
+            &Value::object([
+                ("error", Value::from(error)),
+                ("message", Value::String(message.into())),
+            ]),
+        )
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    pub fn with_file(self, file: FileBody) -> Response {
+        Response {
+            file: Some(file),
+            ..self
+        }
+    }
+
+    /// Get the first header with the (case-insensitive) name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Write the response to a stream
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        for (name, value) in &self.headers {
+            write!(writer, "{name}: {value}\r\n")?;
+        }
+        if self.upgrade.is_some() {
+            write!(writer, "connection: upgrade\r\n\r\n")?;
+            return writer.flush();
+        }
+        // A response to `HEAD` says how long the body would have been
+        if self.header("content-length").is_none() {
+            let length = self
+                .file
+                .as_ref()
+                .map_or(self.body.len() as u64, |f| f.length);
+            write!(writer, "content-length: {length}\r\n")?;
+        }
+        write!(writer, "connection: close\r\n\r\n")?;
+        match &self.file {
+            Some(file) => {
+                let mut opened = File::open(&file.path)?;
+                opened.seek(SeekFrom::Start(file.offset))?;
+                io::copy(&mut opened.take(file.length), writer)?;
+            }
+            None => writer.write_all(&self.body)?,
+        }
+        writer.flush()
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        416 => "Range Not Satisfiable",
+        421 => "Misdirected Request",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Decode `%XX` escapes. `+` is left alone, query strings translate it separately
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = |b: u8| (b as char).to_digit(16);
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                out.push((hi * 16 + lo) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Escape everything but unreserved characters, for use in a query string
+pub fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(&k.replace('+', " ")),
+                percent_decode(&v.replace('+', " ")),
+            )
+        })
+        .collect()
+}
+
+/// Anything that can answer requests
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+
+    /// The longest body `request`, as yet with only its head, may have. It's checked against
+    /// the `Content-Length` before any of the body is read
+    fn body_limit(&self, _request: &Request) -> usize {
+        MAX_BODY_SIZE
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+#[cfg(feature = "net")]
+fn handle_connection(
+    stream: TcpStream,
+    handler: &dyn Handler,
+    shutdown: &Arc<Shutdown>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // Waiting on a client which hasn't sent its request yet doesn't hold up shutdown
+    let request = Request::read_limited(&mut reader, |head| handler.body_limit(head));
+    let Some(_guard) = shutdown.begin() else {
+        return Response::error(503, "Shutting down").write_to(&mut &stream);
+    };
+    let response = match request {
+        Ok(mut request) => {
+            request.peer = stream.peer_addr().ok().map(|addr| addr.ip());
+            handler.handle(&request)
+        }
+        Err(HttpError::BodyTooLarge { .. }) => Response::error(413, "Request body too large"),
+        Err(e @ HttpError::LengthRequired) => Response::error(411, e.to_string()),
+        Err(e @ HttpError::UnsupportedTransferEncoding { .. }) => {
+            Response::error(501, e.to_string())
+        }
+        Err(e @ (HttpError::LineTooLong | HttpError::TooManyHeaders)) => {
+            Response::error(431, e.to_string())
+        }
+        Err(HttpError::Io(e)) => return Err(e),
+        Err(e) => Response::error(400, e.to_string()),
+    };
+    let mut stream = stream;
+    response.write_to(&mut stream)?;
+    match response.upgrade {
+        Some(Upgrade(run)) => {
+            // Streams go quiet for as long as there's nothing to send
+            stream.set_read_timeout(None)?;
+            stream.set_write_timeout(None)?;
+            run(stream, shutdown)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Serve requests from the listener, one thread per connection, until shutdown is requested
+///
+/// A connection holds an in-flight guard once its request has been read, so that shutdown
+/// waits for responses to be written but not for clients which are yet to send anything.
+/// Those are dropped after [`SOCKET_TIMEOUT`]
+#[cfg(feature = "net")]
+pub fn serve(
+    listener: TcpListener,
+    handler: Arc<dyn Handler>,
+    shutdown: Arc<Shutdown>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    while !shutdown.is_requested() {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let handler = handler.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            // A single misbehaving client isn't worth taking anything else down for
+            let _ = handle_connection(stream, handler.as_ref(), &shutdown);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let raw = b"POST /admin/x%3Ay?q=a+b&n=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\nhi";
+        let request = Request::read_from(&mut &raw[..]).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/admin/x:y");
+        assert_eq!(request.query_param("q"), Some("a b"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.body, b"hi");
+    }
+
+    #[test]
+    fn oversized_body_rejected() {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(matches!(
+            Request::read_from(&mut raw.as_bytes()),
+            Err(HttpError::BodyTooLarge { .. })
+        ))
+    }
+
+    #[test]
+    fn unreadable_body_lengths_rejected() {
+        let read = |head: &str| Request::read_from(&mut format!("{head}\r\n\r\nhi").as_bytes());
+        for length in [
+            "Content-Length: x",
+            "Content-Length: +2",
+            "Content-Length: 2, 2",
+        ] {
+            let read = read(&format!("POST / HTTP/1.1\r\n{length}"));
+            assert!(
+                matches!(read, Err(HttpError::InvalidContentLength { .. })),
+                "{length}"
+            );
+        }
+        let repeated = read("POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 2");
+        assert!(matches!(
+            repeated,
+            Err(HttpError::InvalidContentLength { .. })
+        ));
+        let chunked = read("POST / HTTP/1.1\r\nTransfer-Encoding: chunked");
+        assert!(matches!(chunked, Err(HttpError::LengthRequired)));
+        let gzip = read("POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nContent-Length: 2");
+        assert!(matches!(
+            gzip,
+            Err(HttpError::UnsupportedTransferEncoding { .. })
+        ));
+    }
+
+    #[test]
+    fn oversized_headers_rejected() {
+        let long = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "a".repeat(MAX_HEADER_LINE)
+        );
+        assert!(matches!(
+            Request::read_from(&mut long.as_bytes()),
+            Err(HttpError::LineTooLong)
+        ));
+        let many = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-A: b\r\n".repeat(MAX_HEADERS + 1)
+        );
+        assert!(matches!(
+            Request::read_from(&mut many.as_bytes()),
+            Err(HttpError::TooManyHeaders)
+        ));
+        let enough = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS));
+        assert!(Request::read_from(&mut enough.as_bytes()).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn serve_stops_on_shutdown() {
+        use std::io::Read;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let handler: Arc<dyn Handler> = Arc::new(|_: &Request| Response::new(204));
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || serve(listener, handler, shutdown))
+        };
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 204"));
+
+        // A client which never finishes its request doesn't hold up the drain
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        shutdown.request();
+        server.join().unwrap().unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_millis(500);
+        assert!(shutdown.wait_drained(deadline));
+        drop(idle);
+    }
+
+    #[test]
+    fn percent_decode_trailing_escape() {
+        assert_eq!(percent_decode("a%3"), "a%3");
+        assert_eq!(percent_decode("%41%42"), "AB");
+        assert_eq!(
+            percent_encode("did:plc:a b/é"),
+            "did%3Aplc%3Aa%20b%2F%C3%A9"
+        );
+        assert_eq!(
+            percent_decode(&percent_encode("did:plc:a b/é")),
+            "did:plc:a b/é"
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! Resolving the handles and `did:web` identities the bridge allocates
+//!
+//! Bridged accounts are given handles under the bridge's hostname and `did:web` DIDs it
+//! hosts, so Bluesky clients can resolve them without any other PDS being involved:
+//!
+//! - `com.atproto.identity.resolveHandle`: the DID for a handle under the hostname
+//! - `/.well-known/atproto-did`: the same, for the handle the request's `Host` names, as
+//!   handle resolution over HTTPS asks for it
+//! - `did.json`: the DID document of a `did:web` under the hostname, at
+//!   [`Did::to_did_web_url`]. It names the account's handle, its repo signing key and the
+//!   bridge as its PDS. The bridge's own `did:web`, at `/.well-known/did.json`, names the
+//!   services it runs as that DID: its [labeler](crate::labeler) and
+//!   [feed generator](crate::feed)
+//!
+//! Only bridged accounts are resolved, not passive mappings. Anything else is passed on to
+//! the handler these endpoints wrap
+
+use crate::bridge::Bridge;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::Value;
+use crate::keys::{KeyOwner, KeyPurpose, StoredKey};
+use crate::peering::PEERING_ID;
+use crate::store::{Mapping, MappingStatus};
+use atproto::DID::Did;
+use std::sync::Arc;
+
+pub const RESOLVE_HANDLE: &str = "com.atproto.identity.resolveHandle";
+pub const DID_DOCUMENT_CONTENT_TYPE: &str = "application/did+json";
+
+/// The identity endpoints for `hostname`, in front of `inner`. Without a hostname, everything
+/// is passed on
+pub struct IdentityEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> IdentityEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> IdentityEndpoints<H> {
+        IdentityEndpoints {
+            bridge,
+            hostname: hostname.map(|hostname| hostname.to_ascii_lowercase()),
+            inner,
+        }
+    }
+
+    /// Whether the bridge allocated `handle` under `hostname`, so is the one to resolve it
+    fn allocated(hostname: &str, handle: &str) -> bool {
+        let handle = handle.to_ascii_lowercase();
+        handle
+            .strip_suffix(hostname)
+            .is_some_and(|name| name.len() > 1 && name.ends_with('.'))
+    }
+
+    /// The bridged account with a handle the bridge allocated
+    fn by_handle(&self, hostname: &str, handle: &str) -> Option<Mapping> {
+        let mapping = self.bridge.identities.get_by_handle(handle)?;
+        let bridged = mapping.status != MappingStatus::Passive;
+        (Self::allocated(hostname, handle) && bridged).then_some(mapping)
+    }
+
+    fn resolve_handle(&self, hostname: &str, request: &Request) -> Response {
+        let Some(handle) = request.query_param("handle") else {
+            return Response::xrpc_error(400, "InvalidRequest", "Missing handle");
+        };
+        match self.by_handle(hostname, handle) {
+            Some(mapping) => Response::json(
+                200,
+                &Value::object([("did", Value::from(mapping.did.as_str()))]),
+            ),
+            None => Response::xrpc_error(400, "HandleNotFound", "Unable to resolve handle"),
+        }
+    }
+
+    fn well_known_did(&self, hostname: &str, request: &Request) -> Response {
+        let host = request.header("host").unwrap_or_default();
+        // The port, if any, isn't part of the handle
+        let handle = host.rsplit_once(':').map_or(host, |(handle, _)| handle);
+        match self.by_handle(hostname, handle) {
+            Some(mapping) => Response::new(200)
+                .with_header("content-type", "text/plain")
+                .with_body(mapping.did.as_str()),
+            None => Response::error(404, "No such handle"),
+        }
+    }
+
+    fn did_document(&self, hostname: &str, path: &[&str]) -> Response {
+        let not_found = || Response::error(404, "No such DID");
+        let Ok(did) = Did::did_web(hostname, path) else {
+            return not_found();
+        };
+        let document = match self.bridge.identities.get(&did) {
+            Some(mapping) if mapping.status != MappingStatus::Passive => {
+                Some(self.document(hostname, &mapping))
+            }
+            Some(_) => None,
+            None if path.is_empty() => service_document(&self.bridge, hostname),
+            None => None,
+        };
+        match document {
+            Some(document) => Response::new(200)
+                .with_header("content-type", DID_DOCUMENT_CONTENT_TYPE)
+                .with_body(document.to_string()),
+            None => not_found(),
+        }
+    }
+
+    /// The DID document of a bridged account, hosted at `hostname`
+    pub fn document(&self, hostname: &str, mapping: &Mapping) -> Value {
+        let did = mapping.did.as_str();
+        let also_known_as = mapping
+            .handle
+            .iter()
+            .map(|h| Value::from(format!("at://{h}")));
+        let owner = KeyOwner::Account(mapping.did.clone());
+        let key = self.bridge.keys.current(&owner, KeyPurpose::RepoSigning);
+        let verification_methods = key.map(|key| multikey(did, "atproto", &key));
+        let pds = service("atproto_pds", "AtprotoPersonalDataServer", hostname);
+        did_document(
+            did,
+            also_known_as.collect(),
+            verification_methods.into_iter().collect(),
+            vec![pds],
+        )
+    }
+}
+
+/// A verification method for `key`
+fn multikey(did: &str, id: &str, key: &StoredKey) -> Value {
+    Value::object([
+        ("id", Value::from(format!("{did}#{id}"))),
+        ("type", Value::from("Multikey")),
+        ("controller", Value::from(did)),
+        (
+            "publicKeyMultibase",
+            Value::from(key.keypair.public_key.as_str()),
+        ),
+    ])
+}
+
+/// A service the bridge at `hostname` runs
+fn service(id: &str, kind: &str, hostname: &str) -> Value {
+    Value::object([
+        ("id", Value::from(format!("#{id}"))),
+        ("type", Value::from(kind)),
+        (
+            "serviceEndpoint",
+            Value::from(format!("https://{hostname}")),
+        ),
+    ])
+}
+
+fn did_document(
+    did: &str,
+    also_known_as: Vec<Value>,
+    verification_methods: Vec<Value>,
+    services: Vec<Value>,
+) -> Value {
+    let contexts = [
+        "https://www.w3.org/ns/did/v1",
+        "https://w3id.org/security/multikey/v1",
+    ];
+    Value::object([
+        (
+            "@context",
+            Value::Array(contexts.into_iter().map(Value::from).collect()),
+        ),
+        ("id", Value::from(did)),
+        ("alsoKnownAs", Value::Array(also_known_as)),
+        ("verificationMethod", Value::Array(verification_methods)),
+        ("service", Value::Array(services)),
+    ])
+}
+
+/// The document of the bridge's own `did:web` at `hostname`, naming the services it runs as
+/// that DID, if it runs any
+pub fn service_document(bridge: &Bridge, hostname: &str) -> Option<Value> {
+    let did = Did::did_web(hostname, &[]).ok()?;
+    let mut methods = Vec::new();
+    let mut services = Vec::new();
+    // The labeler's declaration is in a repo of its own
+    if bridge.repos.head(&did).is_some() {
+        let owner = KeyOwner::Account(did.clone());
+        let key = bridge.keys.current(&owner, KeyPurpose::RepoSigning);
+        methods.extend(key.map(|key| multikey(did.as_str(), "atproto", &key)));
+        services.push(service(
+            "atproto_pds",
+            "AtprotoPersonalDataServer",
+            hostname,
+        ));
+    }
+    if bridge.labeler.as_ref() == Some(&did) {
+        let key = bridge
+            .keys
+            .current(&KeyOwner::Bridge, KeyPurpose::LabelSigning);
+        methods.extend(key.map(|key| multikey(did.as_str(), "atproto_label", &key)));
+        services.push(service("atproto_labeler", "AtprotoLabeler", hostname));
+    }
+    if !bridge.feeds.feeds.is_empty() {
+        services.push(service("bsky_fg", "BskyFeedGenerator", hostname));
+    }
+    let peering = bridge
+        .keys
+        .current(&KeyOwner::Bridge, KeyPurpose::PeerSigning);
+    if let Some(key) = peering {
+        methods.push(multikey(did.as_str(), PEERING_ID, &key));
+        services.push(service(PEERING_ID, "FedibridgePeering", hostname));
+    }
+    let runs_any = !services.is_empty();
+    runs_any.then(|| did_document(did.as_str(), Vec::new(), methods, services))
+}
+
+impl<H: Handler> Handler for IdentityEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(hostname) = &self.hostname else {
+            return self.inner.handle(request);
+        };
+        match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["xrpc", RESOLVE_HANDLE]) => self.resolve_handle(hostname, request),
+            (Method::Get, [".well-known", "atproto-did"]) => self.well_known_did(hostname, request),
+            (Method::Get, [".well-known", "did.json"]) => self.did_document(hostname, &[]),
+            (Method::Get, [path @ .., "did.json"]) if !path.is_empty() => {
+                self.did_document(hostname, path)
+            }
+            _ => self.inner.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+
+    fn endpoints() -> IdentityEndpoints<impl Handler> {
+        let bridge = Bridge::new();
+        let did = Did::did_web("bridge.example", &["u", "alice"]).unwrap();
+        let mut alice = Mapping::new(did.clone(), "https://a.example/users/alice");
+        alice.handle = Some("alice.a.example.bridge.example".to_string());
+        bridge.identities.insert(alice);
+        let mut bob = Mapping::new(atproto::did!("did:plc:bob"), "https://bsky.app/profile/bob");
+        bob.handle = Some("bob.bridge.example".to_string());
+        bob.status = MappingStatus::Passive;
+        bridge.identities.insert(bob);
+        let owner = KeyOwner::Account(did);
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let hostname = Some("Bridge.example".to_string());
+        IdentityEndpoints::new(Arc::new(bridge), hostname, |_: &Request| Response::new(418))
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(&response.body).unwrap()
+    }
+
+    #[test]
+    fn resolves_allocated_handles() {
+        let endpoints = endpoints();
+        let resolve = |handle| {
+            let target = format!("/xrpc/{RESOLVE_HANDLE}?handle={handle}");
+            endpoints.handle(&Request::new(Method::Get, &target))
+        };
+        let resolved = resolve("Alice.a.example.bridge.example");
+        assert_eq!(resolved.status, 200);
+        let resolved = json::parse(body(&resolved)).unwrap();
+        let did = "did:web:bridge.example:u:alice";
+        assert_eq!(resolved.get("did"), Some(&Value::from(did)));
+        assert_eq!(resolve("bob.bridge.example").status, 400);
+        assert_eq!(resolve("alice.a.example").status, 400);
+
+        let well_known = Request::new(Method::Get, "/.well-known/atproto-did")
+            .with_header("host", "alice.a.example.bridge.example:443");
+        assert_eq!(body(&endpoints.handle(&well_known)), did);
+        let other = Request::new(Method::Get, "/.well-known/atproto-did")
+            .with_header("host", "carol.bridge.example");
+        assert_eq!(endpoints.handle(&other).status, 404);
+    }
+
+    #[test]
+    fn serves_did_documents() {
+        let endpoints = endpoints();
+        let response = endpoints.handle(&Request::new(Method::Get, "/u/alice/did.json"));
+        assert_eq!(
+            response.header("content-type"),
+            Some(DID_DOCUMENT_CONTENT_TYPE)
+        );
+        let document = json::parse(body(&response)).unwrap();
+        assert_eq!(
+            document.get("id"),
+            Some(&Value::from("did:web:bridge.example:u:alice"))
+        );
+        let aka = document
+            .get("alsoKnownAs")
+            .and_then(Value::as_array)
+            .unwrap();
+        assert_eq!(aka, &[Value::from("at://alice.a.example.bridge.example")]);
+        let methods = document.get("verificationMethod").and_then(Value::as_array);
+        let key = methods.unwrap()[0].get("publicKeyMultibase");
+        assert_eq!(key, Some(&Value::from("secp256k1-public-0")));
+        let service = &document.get("service").and_then(Value::as_array).unwrap()[0];
+        assert_eq!(
+            service.get("serviceEndpoint"),
+            Some(&Value::from("https://bridge.example"))
+        );
+
+        let missing = Request::new(Method::Get, "/u/carol/did.json");
+        assert_eq!(endpoints.handle(&missing).status, 404);
+        assert_eq!(
+            endpoints
+                .handle(&Request::new(Method::Get, "/other"))
+                .status,
+            418
+        );
+    }
+
+    #[test]
+    fn serves_the_bridges_own_document() {
+        let bridge = Bridge::new().with_labeler(atproto::did!("did:web:bridge.example"));
+        let generator = FakeGenerator::default();
+        crate::labeler::label_key(&bridge, &generator).unwrap();
+        let hostname = Some("bridge.example".to_string());
+        let endpoints =
+            IdentityEndpoints::new(Arc::new(bridge), hostname, |_: &Request| Response::new(418));
+        let response = endpoints.handle(&Request::new(Method::Get, "/.well-known/did.json"));
+        let document = json::parse(body(&response)).unwrap();
+        let methods = document.get("verificationMethod").and_then(Value::as_array);
+        let label_key = methods.unwrap()[0].get("id");
+        assert_eq!(
+            label_key,
+            Some(&Value::from("did:web:bridge.example#atproto_label"))
+        );
+        let services = document.get("service").and_then(Value::as_array).unwrap();
+        let kinds: Vec<_> = services.iter().filter_map(|s| s.get("type")).collect();
+        assert_eq!(kinds, [&Value::from("AtprotoLabeler")]);
+
+        let unlabelled = IdentityEndpoints::new(
+            Arc::new(Bridge::new()),
+            Some("bridge.example".to_string()),
+            |_: &Request| Response::new(418),
+        );
+        let response = unlabelled.handle(&Request::new(Method::Get, "/.well-known/did.json"));
+        assert_eq!(response.status, 404);
+    }
+}
@@ -0,0 +1,327 @@
+//! Fitting images into Bluesky's blob limits
+//!
+//! Fediverse servers accept far larger images than Bluesky does, so an image over the limit
+//! is downscaled and re-encoded as JPEG before upload rather than failing the post. Quality
+//! is lowered first, down to a floor, and only then is the image shrunk further.
+//!
+//! Decoding and encoding are delegated to an [`ImageCodec`], as the bridge can't pull in an
+//! image library; resampling and the search for a size which fits are done here
+
+use crate::metadata::{self, MetadataError};
+use std::fmt;
+use thiserror::Error;
+
+/// The largest image blob Bluesky accepts, in bytes
+pub const MAX_IMAGE_SIZE: usize = 1_000_000;
+/// Each attempt after the quality floor is reached shrinks the image by this much
+const SHRINK_FACTOR: f64 = 0.75;
+/// Quality is lowered in steps of this much
+const QUALITY_STEP: u8 = 10;
+/// Images aren't shrunk below this on their longest side
+const MIN_DIMENSION: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+/// How images are fitted into the blob limit
+pub struct ImageLimits {
+    /// Largest encoded size allowed
+    pub max_size: usize,
+    /// Longest side allowed, in pixels
+    pub max_dimension: u32,
+    /// JPEG quality (1-100) re-encoded images start at
+    pub quality: u8,
+    /// Quality isn't lowered below this, the image is shrunk instead
+    pub min_quality: u8,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        ImageLimits {
+            max_size: MAX_IMAGE_SIZE,
+            max_dimension: 2000,
+            quality: 90,
+            min_quality: 60,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+/// A decoded image, as 8-bit RGBA pixels in rows from the top left
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl fmt::Debug for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Image({}x{})", self.width, self.height)
+    }
+}
+
+impl Image {
+    /// Scale down to `width` by `height`, averaging the pixels each one covers
+    pub fn resize(&self, width: u32, height: u32) -> Image {
+        let (width, height) = (width.max(1), height.max(1));
+        let span = |i: u32, to: u32, from: u32| {
+            let start = (i as u64 * from as u64 / to as u64) as u32;
+            let end = ((i as u64 + 1) * from as u64 / to as u64) as u32;
+            start..end.max(start + 1).min(from)
+        };
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+        for y in 0..height {
+            let rows = span(y, height, self.height);
+            for x in 0..width {
+                let columns = span(x, width, self.width);
+                let mut sum = [0u64; 4];
+                for sy in rows.clone() {
+                    for sx in columns.clone() {
+                        let i = (sy as usize * self.width as usize + sx as usize) * 4;
+                        for (total, &channel) in sum.iter_mut().zip(&self.pixels[i..i + 4]) {
+                            *total += channel as u64;
+                        }
+                    }
+                }
+                let count = rows.len() as u64 * columns.len() as u64;
+                pixels.extend(sum.map(|total| ((total + count / 2) / count) as u8));
+            }
+        }
+        Image {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Composite onto white, as JPEG has no transparency
+    pub fn flatten(&mut self) {
+        for pixel in self.pixels.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u32;
+            for channel in &mut pixel[..3] {
+                *channel = ((*channel as u32 * alpha + 255 * (255 - alpha) + 127) / 255) as u8;
+            }
+            pixel[3] = 255;
+        }
+    }
+
+    /// The size this would be with its longest side at most `max`, keeping its aspect ratio
+    fn fitted(&self, max: u32) -> (u32, u32) {
+        let longest = self.width.max(self.height);
+        if longest <= max {
+            return (self.width, self.height);
+        }
+        let scale = |side: u32| (side as u64 * max as u64 + longest as u64 / 2) / longest as u64;
+        (scale(self.width) as u32, scale(self.height) as u32)
+    }
+}
+
+/// Decodes and encodes images for [`fit_image`]
+pub trait ImageCodec: Send + Sync {
+    fn decode(&self, data: &[u8], mime_type: &str) -> anyhow::Result<Image>;
+    /// Encode as JPEG at `quality` (1-100)
+    fn encode_jpeg(&self, image: &Image, quality: u8) -> anyhow::Result<Vec<u8>>;
+}
+
+#[derive(Debug, Error)]
+/// Errors fitting an image into the blob limit
+pub enum ImageError {
+    #[error("Couldn't decode image: {0:#}")]
+    Decode(anyhow::Error),
+    #[error("Couldn't encode image: {0:#}")]
+    Encode(anyhow::Error),
+    #[error("Couldn't get image under {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error(transparent)]
+    Metadata(#[from] MetadataError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A re-encoded image
+pub struct Encoded {
+    pub data: Vec<u8>,
+    pub mime_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Downscale and re-encode an image which is over `limits`, or `None` if it's fine as it is
+pub fn fit_image(
+    codec: &dyn ImageCodec,
+    limits: &ImageLimits,
+    data: &[u8],
+    mime_type: &str,
+) -> Result<Option<Encoded>, ImageError> {
+    use ImageError::*;
+    if data.len() <= limits.max_size {
+        return Ok(None);
+    }
+    let mut original = codec.decode(data, mime_type).map_err(Decode)?;
+    original.flatten();
+    let (mut width, mut height) = original.fitted(limits.max_dimension);
+    let mut quality = limits.quality.clamp(1, 100);
+    let min_quality = limits.min_quality.clamp(1, quality);
+    loop {
+        let image = match (width, height) == (original.width, original.height) {
+            true => original.clone(),
+            false => original.resize(width, height),
+        };
+        let encoded = codec.encode_jpeg(&image, quality).map_err(Encode)?;
+        if encoded.len() <= limits.max_size {
+            return Ok(Some(Encoded {
+                data: encoded,
+                mime_type: "image/jpeg",
+                width,
+                height,
+            }));
+        }
+        if quality > min_quality {
+            quality = quality.saturating_sub(QUALITY_STEP).max(min_quality);
+            continue;
+        }
+        if width.max(height) <= MIN_DIMENSION {
+            return Err(TooLarge {
+                limit: limits.max_size,
+            });
+        }
+        let longest = (width.max(height) as f64 * SHRINK_FACTOR) as u32;
+        (width, height) = original.fitted(longest.max(MIN_DIMENSION));
+    }
+}
+
+/// The image to upload in place of `data`: stripped of [metadata], and re-encoded with
+/// `codec` if it's still over `limits`
+///
+/// Without a codec, images which are too large can't be fitted and are refused
+pub fn for_upload(
+    codec: Option<&dyn ImageCodec>,
+    limits: &ImageLimits,
+    data: Vec<u8>,
+    mime_type: String,
+) -> Result<(Vec<u8>, String), ImageError> {
+    let data = metadata::strip(&data, &mime_type)?;
+    if data.len() <= limits.max_size {
+        return Ok((data, mime_type));
+    }
+    let Some(codec) = codec else {
+        return Err(ImageError::TooLarge {
+            limit: limits.max_size,
+        });
+    };
+    match fit_image(codec, limits, &data, &mime_type)? {
+        Some(fitted) => Ok((fitted.data, fitted.mime_type.to_string())),
+        None => Ok((data, mime_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Stores pixels raw, with an encoded size proportional to the pixel count and quality
+    #[derive(Default)]
+    struct FakeCodec {
+        encoded: Mutex<Vec<(u32, u32, u8)>>,
+    }
+
+    impl ImageCodec for FakeCodec {
+        fn decode(&self, data: &[u8], _: &str) -> anyhow::Result<Image> {
+            let side = |i: usize| u32::from_be_bytes(data[i..i + 4].try_into().unwrap());
+            let (width, height) = (side(0), side(4));
+            let pixels = vec![200; width as usize * height as usize * 4];
+            Ok(Image {
+                width,
+                height,
+                pixels,
+            })
+        }
+
+        fn encode_jpeg(&self, image: &Image, quality: u8) -> anyhow::Result<Vec<u8>> {
+            let (width, height) = (image.width, image.height);
+            self.encoded.lock().unwrap().push((width, height, quality));
+            let size = width as usize * height as usize * quality as usize / 100;
+            Ok(vec![0; size])
+        }
+    }
+
+    fn header(width: u32, height: u32, size: usize) -> Vec<u8> {
+        let mut data = [width.to_be_bytes(), height.to_be_bytes()].concat();
+        data.resize(size, 0);
+        data
+    }
+
+    #[test]
+    fn resizes_by_averaging() {
+        let mut image = Image {
+            width: 4,
+            height: 2,
+            pixels: [
+                [0, 0, 0, 255],
+                [255, 255, 255, 255],
+                [10, 20, 30, 0],
+                [10, 20, 30, 0],
+            ]
+            .repeat(2)
+            .concat(),
+        };
+        let half = image.resize(2, 1);
+        assert_eq!(half.pixels, [128, 128, 128, 255, 10, 20, 30, 0]);
+        image.flatten();
+        assert_eq!(&image.pixels[8..12], [255, 255, 255, 255]);
+        assert_eq!(image.fitted(2), (2, 1));
+        assert_eq!(image.fitted(3), (3, 2));
+    }
+
+    #[test]
+    fn small_images_are_left_alone() {
+        let codec = FakeCodec::default();
+        let limits = ImageLimits::default();
+        let data = header(4000, 3000, 500_000);
+        assert_eq!(
+            fit_image(&codec, &limits, &data, "image/bmp").unwrap(),
+            None
+        );
+        assert!(codec.encoded.lock().unwrap().is_empty());
+        let upload = for_upload(None, &limits, data.clone(), "image/bmp".to_string());
+        assert_eq!(upload.unwrap(), (data, "image/bmp".to_string()));
+        let large = header(4000, 3000, 2_000_000);
+        assert!(matches!(
+            for_upload(None, &limits, large, "image/bmp".to_string()),
+            Err(ImageError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn lowers_quality_then_size() {
+        let codec = FakeCodec::default();
+        let limits = ImageLimits {
+            max_size: 1_000_000,
+            ..ImageLimits::default()
+        };
+        let data = header(4000, 3000, 3_000_000);
+        let fitted = fit_image(&codec, &limits, &data, "image/jpeg")
+            .unwrap()
+            .unwrap();
+        assert_eq!(fitted.mime_type, "image/jpeg");
+        assert!(fitted.data.len() <= 1_000_000);
+        assert_eq!(
+            *codec.encoded.lock().unwrap(),
+            [
+                (2000, 1500, 90),
+                (2000, 1500, 80),
+                (2000, 1500, 70),
+                (2000, 1500, 60),
+                (1500, 1125, 60),
+                (1125, 844, 60),
+            ]
+        );
+
+        let tiny = ImageLimits {
+            max_size: 10,
+            ..ImageLimits::default()
+        };
+        assert!(matches!(
+            fit_image(&codec, &tiny, &data, "image/jpeg"),
+            Err(ImageError::TooLarge { limit: 10 })
+        ));
+    }
+}
@@ -0,0 +1,305 @@
+//! Where inbound events come into the bridge
+//!
+//! Every activity delivered to an inbox goes through [`receive_activity`], and every firehose
+//! message through [`receive_frame`]. Each is [archived](crate::archive) as it arrived, if the
+//! bridge archives events, then handed on unless it was handled before: activities by
+//! [`process_once`], commits by the [events already processed](crate::firehose::ProcessedEvents)
+//! and their records by [`process_once`] again. What they're handed on to is the [`Replay`]
+//! pipeline [`Bridge`] implements, which is the same one a [replay](crate::archive::replay)
+//! runs archived events through again.
+//!
+//! [`InboxEndpoints`] serves the inboxes: the shared `POST /inbox`, and `POST <actor>/inbox`
+//! for each bridged actor under the bridge's hostname. A delivery must be signed by the actor
+//! it's from, and is answered with a 202 once it's been handled
+
+use crate::archive::{archive, Event, Replay};
+use crate::bridge::Bridge;
+use crate::dedup::process_once;
+use crate::digest;
+use crate::dm;
+use crate::firehose::{Action, Frame};
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::{self, Value};
+use crate::lexicon;
+use crate::moderation;
+use crate::resync;
+use crate::signatures;
+use crate::threadgate::{self, Verdict};
+use std::sync::Arc;
+
+/// Handle `frame`'s records, each only once if `once`
+fn handle_frame(bridge: &Bridge, frame: &Frame<'_>, once: bool) -> anyhow::Result<()> {
+    resync::observe(bridge, frame);
+    let Frame::Commit(commit) = frame else {
+        return Ok(());
+    };
+    for op in commit.header().ops {
+        if op.action == Action::Delete {
+            continue;
+        }
+        let Some(record) = commit.record(&op.path)? else {
+            continue;
+        };
+        let record = record.to_json();
+        let handle = |record: &Value| {
+            if let Some(record) = lexicon::admit(bridge, &commit.repo, &op.path, record) {
+                digest::record_received(bridge, &commit.repo, op.collection(), &record);
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if !once {
+            handle(&record)?;
+            continue;
+        }
+        // Keyed by its content, so an edit is handled again while a resync's copy isn't
+        let uri = format!("at://{}/{}", commit.repo, op.path);
+        let keyed = Value::object([("uri", Value::from(uri)), ("record", record.clone())]);
+        process_once(bridge, &keyed, |_| handle(&record))?;
+    }
+    Ok(())
+}
+
+impl Replay for Bridge {
+    fn frame(&self, bridge: &Bridge, frame: &Frame<'_>) -> anyhow::Result<()> {
+        handle_frame(bridge, frame, false)
+    }
+
+    fn activity(&self, bridge: &Bridge, activity: &Value) -> anyhow::Result<()> {
+        let object = activity.get("object");
+        match activity.get("type").and_then(Value::as_str) {
+            Some("Flag") => {
+                moderation::flag_received(bridge, activity)?;
+            }
+            Some("Create") => {
+                if let Verdict::Refused { .. } = threadgate::reply_received(bridge, activity)? {
+                    return Ok(());
+                }
+                if dm::direct_message_received(bridge, activity)? > 0 {
+                    return Ok(());
+                }
+                digest::activity_received(bridge, activity);
+            }
+            Some("Update") => {
+                // Only an actor's own update says anything of its keys, legacy or Multikey
+                let actor = activity.get("actor").and_then(Value::as_str);
+                let document = object.filter(|o| {
+                    let keys = o.get("publicKey").is_some() || o.get("assertionMethod").is_some();
+                    keys && actor.is_some() && o.get("id").and_then(Value::as_str) == actor
+                });
+                if let Some(document) = document {
+                    bridge.verified_keys.actor_updated(document);
+                }
+            }
+            Some("Delete") => {
+                let actor = activity.get("actor").and_then(Value::as_str);
+                if actor.is_some() && object.and_then(Value::as_str) == actor {
+                    bridge
+                        .verified_keys
+                        .invalidate_actor(actor.unwrap_or_default());
+                }
+            }
+            _ => {
+                digest::activity_received(bridge, activity);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Archive and handle an activity delivered to an inbox, returning `None` if it was a
+/// redelivery
+pub fn receive_activity(bridge: &Bridge, activity: &Value) -> anyhow::Result<Option<()>> {
+    archive(bridge, Event::Activity(activity.clone()));
+    process_once(bridge, activity, |activity| {
+        bridge.activity(bridge, activity)
+    })
+}
+
+/// Archive and handle a firehose message, returning whether it was handled rather than
+/// unwanted or handled before
+///
+/// Each record is handled [once](process_once) too, as the commits handled are only known
+/// around the cursor, and a relay resumed from an older one brings back records which were
+/// handled long before
+pub fn receive_frame(bridge: &Bridge, message: &[u8]) -> anyhow::Result<bool> {
+    archive(bridge, Event::Frame(message.to_vec()));
+    let frame = Frame::parse(message)?;
+    let header = match &frame {
+        Frame::Commit(commit) => commit.header(),
+        Frame::Account(account) => account.header(),
+        Frame::Identity(identity) => identity.header(),
+        Frame::Other(_) => return Ok(false),
+    };
+    if !bridge.wants(&header) {
+        return Ok(false);
+    }
+    let commit = match &frame {
+        Frame::Commit(commit) => commit.commit,
+        _ => None,
+    };
+    if let Some(cid) = &commit {
+        if !bridge.processed_events.claim(header.seq, cid) {
+            return Ok(false);
+        }
+    }
+    if let Err(e) = handle_frame(bridge, &frame, true) {
+        if let Some(cid) = &commit {
+            bridge.processed_events.abandon(header.seq, cid);
+        }
+        return Err(e);
+    }
+    if let Some(cid) = &commit {
+        bridge.processed_events.handled(header.seq, cid)?;
+    }
+    Ok(true)
+}
+
+/// The bridge's inboxes, in front of `inner`
+pub struct InboxEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> InboxEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> InboxEndpoints<H> {
+        InboxEndpoints {
+            bridge,
+            hostname,
+            inner,
+        }
+    }
+
+    /// Whether `path` is the inbox of a bridged actor
+    fn is_inbox(&self, path: &str) -> bool {
+        let Some(actor) = path.trim_end_matches('/').strip_suffix("/inbox") else {
+            return false;
+        };
+        match &self.hostname {
+            // The shared inbox
+            _ if actor.is_empty() => true,
+            Some(hostname) => self.is_bridged(&format!("https://{hostname}{actor}")),
+            None => false,
+        }
+    }
+
+    fn is_bridged(&self, actor: &str) -> bool {
+        let mapping = self.bridge.identities.get_by_actor(actor);
+        mapping.is_some() || self.bridge.moderation.instance_actor.as_deref() == Some(actor)
+    }
+
+    fn deliver(&self, request: &Request) -> Response {
+        let signer = match signatures::verify(&self.bridge, request) {
+            Ok(signer) => signer,
+            Err(e) => return Response::error(401, e.to_string()),
+        };
+        let Ok(activity) = json::parse(&String::from_utf8_lossy(&request.body)) else {
+            return Response::error(400, "The body isn't a JSON activity");
+        };
+        let actor = activity.get("actor").and_then(Value::as_str);
+        if actor != Some(signer.as_str()) {
+            return Response::error(401, format!("Signed by {signer}, not the activity's actor"));
+        }
+        match receive_activity(&self.bridge, &activity) {
+            Ok(_) => Response::new(202),
+            Err(e) => Response::error(500, format!("Couldn't handle the activity: {e:#}")),
+        }
+    }
+}
+
+impl<H: Handler> Handler for InboxEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        match request.method {
+            Method::Post if self.is_inbox(&request.path) => self.deliver(request),
+            _ => self.inner.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actorkeys::PublicKey;
+    use crate::archive::EventArchive;
+    use crate::firehose::{encode_commit, Operation};
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+    use std::time::Instant;
+
+    struct NotFound;
+
+    impl Handler for NotFound {
+        fn handle(&self, _: &Request) -> Response {
+            Response::error(404, "Not found")
+        }
+    }
+
+    #[test]
+    fn inbound_events_are_archived_and_handled_once() {
+        let bridge = Arc::new(Bridge {
+            archive: Some(EventArchive::open(temp_state_dir()).unwrap()),
+            ..Bridge::new()
+        });
+        let alice = did!("did:plc:alice");
+        let actor = "https://bridge.example/users/alice";
+        bridge.identities.insert(Mapping::new(alice.clone(), actor));
+        let endpoints =
+            InboxEndpoints::new(bridge.clone(), Some("bridge.example".into()), NotFound);
+        // Without a signature, nothing is taken in
+        let like = r#"{"id": "https://b.example/likes/1", "type": "Like",
+            "actor": "https://b.example/users/bob", "object": "https://bridge.example/p/1"}"#;
+        let unsigned = Request::new(Method::Post, "/users/alice/inbox").with_body(like);
+        assert_eq!(endpoints.handle(&unsigned).status, 401);
+        let elsewhere = Request::new(Method::Post, "/users/nobody/inbox").with_body(like);
+        assert_eq!(endpoints.handle(&elsewhere).status, 404);
+
+        let like = json::parse(like).unwrap();
+        assert_eq!(receive_activity(&bridge, &like).unwrap(), Some(()));
+        assert_eq!(receive_activity(&bridge, &like).unwrap(), None);
+
+        let op = Operation {
+            action: Action::Create,
+            path: "app.bsky.feed.post/1".to_string(),
+        };
+        let record = Value::object([("text", Value::from("hi"))]);
+        let message = encode_commit(1, &alice, &[(op.clone(), Some(record.clone()))]);
+        assert!(receive_frame(&bridge, &message).unwrap());
+        assert!(!receive_frame(&bridge, &message).unwrap());
+        let seen = bridge.seen_activities.len();
+        // The same record in another commit isn't handled again, though an edit of it is
+        let again = encode_commit(2, &alice, &[(op.clone(), Some(record))]);
+        assert!(receive_frame(&bridge, &again).unwrap());
+        assert_eq!(bridge.seen_activities.len(), seen);
+        let edit = Value::object([("text", Value::from("hello"))]);
+        let edited = encode_commit(3, &alice, &[(op, Some(edit))]);
+        assert!(receive_frame(&bridge, &edited).unwrap());
+        assert_eq!(bridge.seen_activities.len(), seen + 1);
+        // Everything that arrived is archived, redeliveries too
+        assert_eq!(bridge.archive.as_ref().unwrap().len(), 6);
+
+        // Keys are only dropped by their own actor's update, of either kind of key
+        let carol = "https://c.example/users/carol";
+        let key = PublicKey::Multibase("z6Mk".to_string());
+        bridge
+            .verified_keys
+            .insert(&format!("{carol}#key"), carol, &key, Instant::now());
+        let update = |actor: &str, id: &str| {
+            let document = Value::object([
+                ("id", Value::from(carol)),
+                ("assertionMethod", Value::Array(Vec::new())),
+            ]);
+            Value::object([
+                ("id", Value::from(id)),
+                ("type", Value::from("Update")),
+                ("actor", Value::from(actor)),
+                ("object", document),
+            ])
+        };
+        let forged = update("https://b.example/users/bob", "https://b.example/u/1");
+        receive_activity(&bridge, &forged).unwrap();
+        assert_eq!(bridge.verified_keys.len(), 1);
+        receive_activity(&bridge, &update(carol, "https://c.example/u/1")).unwrap();
+        assert!(bridge.verified_keys.is_empty());
+    }
+}
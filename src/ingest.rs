@@ -0,0 +1,406 @@
+//! Handling firehose events in parallel
+//!
+//! [`Lanes`] hands events to a pool of worker threads, partitioned by repo so that each
+//! account's events are still handled in the order the relay sent them, while a slow
+//! translation only holds up the accounts sharing its lane. Each lane's queue is bounded:
+//! once one fills, [`Lanes::submit`] blocks, pushing back on the firehose reader instead of
+//! buffering without limit.
+//!
+//! Lanes finish out of order, so the bridge's [cursor](crate::firehose::FirehoseCursor) only
+//! moves past an event once it and every event before it have been handled. A restart may
+//! replay a few events, but never skips one, and commits submitted with
+//! [`Lanes::submit_commit`] which were handled before it are dropped rather than handled twice
+//! (see [`ProcessedEvents`](crate::firehose::ProcessedEvents))
+//!
+//! Events [offered](Lanes::offer) rather than submitted may be [shed](crate::shedding) while the
+//! bridge is behind. Deferred events are held by the lanes, and handed to them, outside of the
+//! cursor's reckoning, once it has caught up
+
+use crate::bridge::Bridge;
+use crate::car::Cid;
+use crate::firehose::{Action, EventHeader, Shard};
+use crate::shedding::{Outcome, Triage};
+use atproto::DID::Did;
+use std::collections::{BTreeSet, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LaneConfig {
+    /// How many events are handled at once
+    pub lanes: usize,
+    /// How many events each lane holds before submitting more blocks
+    pub queue_depth: usize,
+}
+
+impl Default for LaneConfig {
+    fn default() -> Self {
+        LaneConfig {
+            lanes: 8,
+            queue_depth: 64,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Which submitted events haven't been handled yet
+struct Progress {
+    pending: BTreeSet<i64>,
+    /// The latest event submitted or skipped
+    latest: i64,
+}
+
+impl Progress {
+    /// The latest event which it and everything before it have been handled
+    fn watermark(&self) -> i64 {
+        match self.pending.first() {
+            Some(first) => first - 1,
+            None => self.latest,
+        }
+    }
+}
+
+/// Worker lanes handling firehose events, in order per repo
+pub struct Lanes<T> {
+    bridge: Arc<Bridge>,
+    senders: Vec<SyncSender<(i64, Option<Cid>, T)>>,
+    workers: Vec<JoinHandle<()>>,
+    progress: Arc<Mutex<Progress>>,
+    /// Events deferred while the bridge is behind, oldest first
+    deferred: Mutex<VecDeque<(EventHeader, T)>>,
+}
+
+impl<T: Send + 'static> Lanes<T> {
+    /// Start the lanes, each handling its events with `handle`
+    ///
+    /// A panic handling an event is logged and the event counted as handled, so that one
+    /// bad event can't hold the cursor back forever
+    pub fn new<F>(bridge: Arc<Bridge>, config: LaneConfig, handle: F) -> Lanes<T>
+    where
+        F: Fn(&Bridge, T) + Send + Sync + 'static,
+    {
+        let handle = Arc::new(handle);
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let (senders, workers) = (0..config.lanes.max(1))
+            .map(|_| {
+                let (sender, receiver) =
+                    mpsc::sync_channel::<(i64, Option<Cid>, T)>(config.queue_depth);
+                let bridge = bridge.clone();
+                let handle = handle.clone();
+                let progress = progress.clone();
+                let worker = thread::spawn(move || {
+                    for (seq, commit, event) in receiver {
+                        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle(&bridge, event);
+                        }));
+                        if handled.is_err() {
+                            eprintln!("Handling firehose event {seq} panicked, skipping it");
+                        }
+                        let recorded = commit.map_or(Ok(()), |commit| {
+                            bridge.processed_events.handled(seq, &commit)
+                        });
+                        if let Err(e) = recorded {
+                            eprintln!("Couldn't record firehose event {seq} as handled: {e}");
+                        }
+                        let mut progress = progress.lock().unwrap();
+                        progress.pending.remove(&seq);
+                        bridge.firehose.processed(progress.watermark());
+                    }
+                });
+                (sender, worker)
+            })
+            .unzip();
+        Lanes {
+            bridge,
+            senders,
+            workers,
+            progress,
+            deferred: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The lane handling `did`'s events
+    fn lane(&self, did: &Did) -> usize {
+        // Every DID in a shard leaves the same remainder modulo the shard count, so that's
+        // divided out rather than putting them all in the same lane
+        let shards = self.bridge.shard.count();
+        let lanes = self.senders.len() as u32;
+        (Shard::of(did, shards * lanes) / shards) as usize
+    }
+
+    /// Queue the event with sequence number `seq` for `did`'s lane, blocking while it's full
+    ///
+    /// Events must be submitted (or [skipped](Lanes::skip)) in the order they were sent
+    pub fn submit(&self, seq: i64, did: &Did, event: T) {
+        self.send(seq, did, None, event);
+    }
+
+    /// [Submit](Lanes::submit) the event for the commit `commit`, unless it has been handled
+    /// before, in which case it's [skipped](Lanes::skip). Returns whether it was submitted
+    pub fn submit_commit(&self, seq: i64, did: &Did, commit: Cid, event: T) -> bool {
+        if !self.bridge.processed_events.claim(seq, &commit) {
+            self.skip(seq);
+            return false;
+        }
+        self.send(seq, did, Some(commit), event);
+        true
+    }
+
+    fn send(&self, seq: i64, did: &Did, commit: Option<Cid>, event: T) {
+        {
+            let mut progress = self.progress.lock().unwrap();
+            progress.pending.insert(seq);
+            progress.latest = progress.latest.max(seq);
+        }
+        self.bridge.firehose.saw(seq);
+        self.senders[self.lane(did)]
+            .send((seq, commit, event))
+            .expect("lanes run until their senders are dropped");
+    }
+
+    /// Count an event as handled without handling it, e.g. because it was filtered out
+    pub fn skip(&self, seq: i64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.latest = progress.latest.max(seq);
+        self.bridge.firehose.processed(progress.watermark());
+    }
+
+    /// [Submit](Lanes::submit_commit) the event `header` describes, as the commit `commit` if
+    /// it is one, unless the bridge is far enough behind for it to be shed or deferred
+    pub fn offer(&self, header: &EventHeader, commit: Option<Cid>, event: T) -> Triage {
+        let shedding = &self.bridge.shedding;
+        let lag = self.bridge.firehose.lag();
+        let triage = shedding.triage(header, lag);
+        match triage {
+            Triage::Handle => {
+                self.cancel_deferred(header);
+                if !shedding.deferring(lag) {
+                    self.replay_deferred();
+                }
+                match commit {
+                    Some(commit) => {
+                        self.submit_commit(header.seq, &header.did, commit, event);
+                    }
+                    None => self.submit(header.seq, &header.did, event),
+                }
+            }
+            Triage::Defer => {
+                shedding.record(Outcome::Deferred, header);
+                let mut deferred = self.deferred.lock().unwrap();
+                deferred.push_back((header.clone(), event));
+                while deferred.len() > shedding.config.defer_capacity {
+                    if let Some((oldest, _)) = deferred.pop_front() {
+                        shedding.record(Outcome::Overflowed, &oldest);
+                    }
+                }
+                drop(deferred);
+                self.skip(header.seq);
+            }
+            Triage::Shed => {
+                shedding.record(Outcome::Shed, header);
+                self.skip(header.seq);
+            }
+        }
+        triage
+    }
+
+    /// Forget the deferred events writing records `header` deletes
+    fn cancel_deferred(&self, header: &EventHeader) {
+        let deleted = header.ops.iter().filter(|op| op.action == Action::Delete);
+        let deleted: Vec<&str> = deleted.map(|op| op.path.as_str()).collect();
+        if deleted.is_empty() {
+            return;
+        }
+        self.deferred.lock().unwrap().retain(|(held, _)| {
+            held.did != header.did || !held.ops.iter().any(|op| deleted.contains(&&*op.path))
+        });
+    }
+
+    /// Hand the deferred events to their lanes. The cursor has already moved past them
+    fn replay_deferred(&self) {
+        let deferred = std::mem::take(&mut *self.deferred.lock().unwrap());
+        for (header, event) in deferred {
+            self.bridge.shedding.record(Outcome::Replayed, &header);
+            self.senders[self.lane(&header.did)]
+                .send((header.seq, None, event))
+                .expect("lanes run until their senders are dropped");
+        }
+    }
+
+    /// Events submitted but not yet handled
+    pub fn pending(&self) -> usize {
+        self.progress.lock().unwrap().pending.len()
+    }
+
+    /// Events deferred, not yet handed to the lanes
+    pub fn deferred(&self) -> usize {
+        self.deferred.lock().unwrap().len()
+    }
+
+    /// Stop taking events, returning once every queued one, and every deferred one, has been
+    /// handled
+    pub fn finish(self) {
+        self.replay_deferred();
+        drop(self.senders);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firehose::Operation;
+    use crate::shedding::ShedConfig;
+    use atproto::did;
+    use std::sync::mpsc::Receiver;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    /// A DID whose events go to a different lane from `ALICE`'s
+    fn other_lane<T: Send + 'static>(lanes: &Lanes<T>) -> Did {
+        (0..)
+            .map(|n| Did::try_create(format!("did:plc:bob{n}")).unwrap())
+            .find(|did| lanes.lane(did) != lanes.lane(&ALICE))
+            .unwrap()
+    }
+
+    #[test]
+    fn slow_lanes_hold_back_only_their_repos_and_the_cursor() {
+        let bridge = Arc::new(Bridge::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = mpsc::channel::<()>();
+        let gate = Mutex::new(gate);
+        let config = LaneConfig {
+            lanes: 2,
+            queue_depth: 4,
+        };
+        let lanes = {
+            let handled = handled.clone();
+            Lanes::new(bridge.clone(), config, move |_, (did, seq): (Did, i64)| {
+                if seq == 1 {
+                    let gate: &Receiver<()> = &gate.lock().unwrap();
+                    gate.recv().unwrap();
+                }
+                handled.lock().unwrap().push((did, seq));
+            })
+        };
+        let bob = other_lane(&lanes);
+        lanes.submit(1, &ALICE, (ALICE, 1));
+        lanes.submit(2, &bob, (bob.clone(), 2));
+        lanes.submit(3, &ALICE, (ALICE, 3));
+        lanes.submit(4, &bob, (bob.clone(), 4));
+
+        // Bob's lane carries on while Alice's first event is stuck
+        while handled.lock().unwrap().len() < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(
+            *handled.lock().unwrap(),
+            [(bob.clone(), 2), (bob.clone(), 4)]
+        );
+        assert_eq!(bridge.firehose.position(), 0);
+        assert_eq!(bridge.firehose.head(), 4);
+
+        release.send(()).unwrap();
+        lanes.finish();
+        let handled = handled.lock().unwrap();
+        let alice: Vec<i64> = handled
+            .iter()
+            .filter(|(did, _)| *did == ALICE)
+            .map(|&(_, seq)| seq)
+            .collect();
+        assert_eq!(alice, [1, 3]);
+        assert_eq!(bridge.firehose.position(), 4);
+    }
+
+    #[test]
+    fn skipped_and_failed_events_still_advance_the_cursor() {
+        let bridge = Arc::new(Bridge::new());
+        let lanes = Lanes::new(bridge.clone(), LaneConfig::default(), |_, seq: i64| {
+            assert_ne!(seq, 2, "bad event");
+        });
+        lanes.submit(1, &ALICE, 1);
+        lanes.submit(2, &ALICE, 2);
+        lanes.skip(5);
+        while lanes.pending() > 0 {
+            thread::yield_now();
+        }
+        assert_eq!(bridge.firehose.position(), 5);
+        lanes.finish();
+    }
+
+    #[test]
+    fn lagging_lanes_shed_and_defer_events() {
+        let bridge = Bridge::new().with_shedding(ShedConfig {
+            defer_lag: Some(10),
+            essential_lag: Some(50),
+            ..ShedConfig::default()
+        });
+        let bridge = Arc::new(bridge);
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let lanes = {
+            let handled = handled.clone();
+            Lanes::new(bridge.clone(), LaneConfig::default(), move |_, seq: i64| {
+                handled.lock().unwrap().push(seq);
+            })
+        };
+        let event = |seq: i64, action: Action, path: &str| EventHeader {
+            seq,
+            did: ALICE,
+            ops: vec![Operation {
+                action,
+                path: path.to_string(),
+            }],
+        };
+        bridge.firehose.saw(100);
+        let like = event(1, Action::Create, "app.bsky.feed.like/3k");
+        assert_eq!(lanes.offer(&like, None, 1), Triage::Defer);
+        let follow = event(2, Action::Create, "app.bsky.graph.follow/3k");
+        assert_eq!(lanes.offer(&follow, None, 2), Triage::Shed);
+        // Liked and unliked while deferred, so never liked at all
+        let liked = event(3, Action::Create, "app.bsky.feed.like/3l");
+        assert_eq!(lanes.offer(&liked, None, 3), Triage::Defer);
+        let unliked = event(4, Action::Delete, "app.bsky.feed.like/3l");
+        assert_eq!(lanes.offer(&unliked, None, 4), Triage::Handle);
+        assert_eq!(lanes.deferred(), 1);
+
+        // Caught up, the deferred like is handled
+        bridge.firehose.processed(100);
+        let post = event(101, Action::Create, "app.bsky.feed.post/3m");
+        assert_eq!(lanes.offer(&post, None, 101), Triage::Handle);
+        assert_eq!(lanes.deferred(), 0);
+        lanes.finish();
+        let mut handled = handled.lock().unwrap().clone();
+        handled.sort();
+        assert_eq!(handled, [1, 4, 101]);
+        let shedding = &bridge.shedding;
+        assert_eq!(shedding.count(Outcome::Deferred), 2);
+        assert_eq!(shedding.count(Outcome::Replayed), 1);
+        assert_eq!(shedding.count(Outcome::Shed), 1);
+        assert_eq!(bridge.firehose.position(), 101);
+    }
+
+    #[test]
+    fn replayed_commits_are_dropped() {
+        let bridge = Arc::new(Bridge::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let lanes = {
+            let handled = handled.clone();
+            Lanes::new(bridge.clone(), LaneConfig::default(), move |_, seq: i64| {
+                handled.lock().unwrap().push(seq);
+            })
+        };
+        let commit = Cid::for_raw(b"commit");
+        assert!(lanes.submit_commit(1, &ALICE, commit, 1));
+        assert!(lanes.submit_commit(2, &ALICE, Cid::for_raw(b"other"), 2));
+        // A cursor rewound to before both replays them
+        assert!(!lanes.submit_commit(1, &ALICE, commit, 1));
+        lanes.finish();
+        assert_eq!(*handled.lock().unwrap(), [1, 2]);
+        assert_eq!(bridge.firehose.position(), 2);
+    }
+}
@@ -0,0 +1,379 @@
+//! Fetching and decoding what the bridge reads, for operators to look at
+//!
+//! When something bridges oddly, the first question is what the bridge was given. These
+//! fetch it fresh and decode it with the same parsers the bridge uses, as JSON:
+//!
+//! - [`did_document`]: the DID document of a DID, or of the DID a handle resolves to
+//! - [`record`]: a record by its `at://` URI, fetched from its PDS with `sync.getRecord` and
+//!   decoded from DAG-CBOR, along with the commit it's in
+//! - [`car()`]: every block of a CAR archive, such as a repo export, decoded
+//! - [`object`]: an ActivityPub object, with whether its `id` and author are of the origin it
+//!   was fetched from, and whether the key any signature it carries names is one its actor
+//!   publishes. The signature itself isn't checked, as that needs the object canonicalized
+//!
+//! `fedibridge inspect did|record|car|object <target>` prints them
+
+use crate::actorkeys;
+use crate::bridge::Bridge;
+use crate::cache::ResourceKind;
+use crate::car::{self, Cid};
+use crate::cbor::{self, CborError};
+use crate::delivery::ACTIVITY_JSON;
+use crate::http::percent_encode;
+use crate::json::{self, Value};
+use crate::normalize;
+use crate::resolver::ResolveError;
+use crate::resync::{self, ResyncError};
+use crate::transport::OutboundRequest;
+use crate::url::Url;
+use atproto::at_uri::{AtUri, Authority};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use thiserror::Error;
+
+pub const SYNC_GET_RECORD: &str = "com.atproto.sync.getRecord";
+
+#[derive(Debug, Error)]
+pub enum InspectError {
+    #[error("{0} isn't a DID or a handle")]
+    Identity(String),
+    #[error("{0} isn't the at:// URI of a record")]
+    Uri(String),
+    #[error("{0} doesn't resolve to a DID")]
+    Unresolved(String),
+    #[error("There's no record at {0}")]
+    NotFound(String),
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error(transparent)]
+    Repo(#[from] ResyncError),
+    #[error("Malformed CAR - {0}")]
+    Car(#[from] CborError),
+    #[error("Malformed CAR - {0}")]
+    Incomplete(&'static str),
+    #[error("{0}")]
+    Fetch(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What's known of an object's signature
+pub enum Signed {
+    Unsigned,
+    /// Its key is one its actor publishes
+    KeyPublished,
+    /// Its actor doesn't publish its key
+    KeyUnpublished,
+    /// Its actor couldn't be fetched
+    KeyUnavailable,
+}
+
+impl Signed {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Signed::Unsigned => "unsigned",
+            Signed::KeyPublished => "keyPublished",
+            Signed::KeyUnpublished => "keyUnpublished",
+            Signed::KeyUnavailable => "keyUnavailable",
+        }
+    }
+}
+
+/// GET `url` as `accept`, returning the body of a success
+fn fetch(bridge: &Bridge, url: &str, accept: &str) -> Result<Vec<u8>, InspectError> {
+    let request = OutboundRequest::get(url).with_header("accept", accept);
+    let response = bridge
+        .transport
+        .send(&request)
+        .map_err(|e| InspectError::Fetch(format!("Couldn't fetch {url}: {e}")))?;
+    if !response.is_success() {
+        let status = response.status;
+        return Err(InspectError::Fetch(format!(
+            "{url} responded with {status}"
+        )));
+    }
+    Ok(response.body)
+}
+
+/// The DID `handle` names by its `_atproto` TXT record, or else its
+/// `/.well-known/atproto-did`
+fn resolve_handle(bridge: &Bridge, handle: &str) -> Result<Did, InspectError> {
+    if let Ok(Some(did)) = bridge.dns.atproto_did(handle) {
+        return Ok(did);
+    }
+    let url = format!("https://{handle}/.well-known/atproto-did");
+    let body = fetch(bridge, &url, "text/plain")?;
+    let did = String::from_utf8_lossy(&body).trim().to_string();
+    normalize::parse_did(&did).map_err(|_| InspectError::Unresolved(handle.to_string()))
+}
+
+/// A fresh copy of the DID document of `identity`, a DID or a handle
+pub fn did_document(bridge: &Bridge, identity: &str) -> Result<Value, InspectError> {
+    let identity = identity.trim().trim_start_matches('@');
+    let did = match identity.starts_with("did:") {
+        true => normalize::parse_did(identity)
+            .map_err(|_| InspectError::Identity(identity.to_string()))?,
+        false if identity.contains('.') => resolve_handle(bridge, &normalize::handle(identity))?,
+        false => return Err(InspectError::Identity(identity.to_string())),
+    };
+    bridge
+        .documents
+        .invalidate(ResourceKind::DidDocument, did.as_str());
+    Ok(bridge.resolver().resolve(&did)?.as_ref().clone())
+}
+
+/// The CID of the record at `path` under the tree node `cid`, if it's there
+fn find_in_tree(
+    blocks: &HashMap<Cid, &[u8]>,
+    cid: &Cid,
+    path: &str,
+) -> Result<Option<Cid>, InspectError> {
+    let node = blocks
+        .get(cid)
+        .ok_or(InspectError::Incomplete("missing tree node"))?;
+    let node = cbor::decode(node)?.0;
+    // The subtree holding the keys before the entry being looked at
+    let mut below = node.get("l").and_then(|l| l.as_link());
+    let mut key: Vec<u8> = Vec::new();
+    let entries = node.get("e").and_then(|e| e.as_array());
+    for entry in entries.iter().flat_map(|entries| entries.iter()) {
+        let shared = entry.get("p").and_then(|p| p.as_i64());
+        let rest = entry.get("k").and_then(|k| k.as_bytes());
+        let (Some(shared), Some(rest)) = (shared, rest) else {
+            return Err(InspectError::Incomplete("malformed tree entry"));
+        };
+        key.truncate(usize::try_from(shared).unwrap_or_default());
+        key.extend_from_slice(rest);
+        match path.as_bytes().cmp(&key) {
+            std::cmp::Ordering::Equal => return Ok(entry.get("v").and_then(|v| v.as_link())),
+            std::cmp::Ordering::Less => break,
+            std::cmp::Ordering::Greater => below = entry.get("t").and_then(|t| t.as_link()),
+        }
+    }
+    match below {
+        Some(below) => find_in_tree(blocks, &below, path),
+        None => Ok(None),
+    }
+}
+
+/// The record at the `at://` URI `uri`, decoded from what its PDS proves it with
+pub fn record(bridge: &Bridge, uri: &str) -> Result<Value, InspectError> {
+    let invalid = || InspectError::Uri(uri.to_string());
+    let parsed = AtUri::try_create(uri.trim().to_string()).map_err(|_| invalid())?;
+    let (Some(collection), Some(rkey)) = (parsed.collection(), parsed.rkey()) else {
+        return Err(invalid());
+    };
+    let did = match parsed.authority() {
+        Authority::Did(did) => did.clone(),
+        Authority::Handle(handle) => resolve_handle(bridge, &normalize::handle(handle.as_str()))?,
+    };
+    let params = format!(
+        "did={}&collection={}&rkey={}",
+        percent_encode(did.as_str()),
+        percent_encode(collection.as_str()),
+        percent_encode(rkey)
+    );
+    let not_found = || InspectError::NotFound(parsed.to_string());
+    let proof = resync::query(bridge, &did, SYNC_GET_RECORD, &params)?.ok_or_else(not_found)?;
+    let root = car::car_root(&proof)?.ok_or(InspectError::Incomplete("no root"))?;
+    let blocks = car::read_car(&proof)?.collect::<Result<HashMap<_, _>, _>>()?;
+    let missing = |what| InspectError::Incomplete(what);
+    let commit = cbor::decode(blocks.get(&root).ok_or(missing("missing commit"))?)?.0;
+    let data = commit.get("data").and_then(|data| data.as_link());
+    let data = data.ok_or(missing("malformed commit"))?;
+    let path = format!("{collection}/{rkey}");
+    let cid = find_in_tree(&blocks, &data, &path)?.ok_or_else(not_found)?;
+    let record = cbor::decode(blocks.get(&cid).ok_or(missing("missing record"))?)?.0;
+    Ok(Value::object([
+        ("uri", Value::from(format!("at://{did}/{path}"))),
+        ("cid", Value::from(cid.to_string())),
+        ("commit", commit.to_json()),
+        ("value", record.to_json()),
+    ]))
+}
+
+/// Every block of the CAR archive `archive`, by CID, decoded where it's DAG-CBOR
+pub fn car(archive: &[u8]) -> Result<Value, InspectError> {
+    let root = car::car_root(archive)?;
+    let blocks = car::read_car(archive)?.map(|block| {
+        let (cid, block) = block?;
+        // Blobs are raw bytes rather than DAG-CBOR
+        let value = match cbor::decode(block) {
+            Ok((value, [])) => value.to_json(),
+            _ => Value::object([("size", Value::from(block.len()))]),
+        };
+        Ok(Value::object([
+            ("cid", Value::from(cid.to_string())),
+            ("value", value),
+        ]))
+    });
+    let blocks = blocks.collect::<Result<Vec<_>, CborError>>()?;
+    Ok(Value::object([
+        ("root", Value::from(root.map(|root| root.to_string()))),
+        ("blocks", Value::Array(blocks)),
+    ]))
+}
+
+/// Whether `a` and `b` are URLs of the same origin
+fn same_origin(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+/// The ID of `value`, whether it's given as one or as an object, or the first of several
+fn id_of(value: &Value) -> Option<&str> {
+    match value {
+        Value::Array(values) => values.first().and_then(id_of),
+        Value::Object(_) => value.get("id").and_then(Value::as_str),
+        value => value.as_str(),
+    }
+}
+
+/// The signature `object` carries, an LD signature or an integrity proof, and the key it names
+fn signature(bridge: &Bridge, object: &Value) -> Value {
+    let found = match (object.get("signature"), object.get("proof")) {
+        (Some(signature), _) => Some((signature, "creator")),
+        (None, Some(Value::Array(proofs))) => {
+            proofs.first().map(|proof| (proof, "verificationMethod"))
+        }
+        (None, Some(proof)) => Some((proof, "verificationMethod")),
+        (None, None) => None,
+    };
+    let Some((signature, field)) = found else {
+        return Value::object([("status", Value::from(Signed::Unsigned.as_str()))]);
+    };
+    let key_id = signature.get(field).and_then(id_of).unwrap_or_default();
+    let actor = key_id.split('#').next().unwrap_or_default();
+    let status = match fetch(bridge, actor, ACTIVITY_JSON) {
+        Ok(body) => {
+            let document = json::parse(&String::from_utf8_lossy(&body)).unwrap_or(Value::Null);
+            match actorkeys::published_key(&document, key_id) {
+                Some(_) => Signed::KeyPublished,
+                None => Signed::KeyUnpublished,
+            }
+        }
+        Err(_) => Signed::KeyUnavailable,
+    };
+    let kind = signature.get("type").cloned().unwrap_or(Value::Null);
+    Value::object([
+        ("status", Value::from(status.as_str())),
+        ("type", kind),
+        ("key", Value::from(key_id)),
+    ])
+}
+
+/// A fresh copy of the ActivityPub object at `url`, with what can be told of its authenticity
+pub fn object(bridge: &Bridge, url: &str) -> Result<Value, InspectError> {
+    let url = url.trim();
+    let body = fetch(bridge, url, ACTIVITY_JSON)?;
+    let object = json::parse(&String::from_utf8_lossy(&body))
+        .map_err(|e| InspectError::Fetch(format!("{url} isn't JSON: {e}")))?;
+    let origin = |field: &str| {
+        let value = object.get(field).and_then(id_of);
+        Value::object([
+            ("value", Value::from(value)),
+            (
+                "sameOrigin",
+                Value::from(value.is_some_and(|v| same_origin(v, url))),
+            ),
+        ])
+    };
+    let author = match object.get("attributedTo") {
+        Some(_) => "attributedTo",
+        None => "actor",
+    };
+    Ok(Value::object([
+        ("url", Value::from(url)),
+        ("id", origin("id")),
+        ("author", origin(author)),
+        ("signature", signature(bridge, &object)),
+        ("object", object.clone()),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::Write;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn records_and_objects_are_decoded_for_inspection() {
+        // A repo the bridge hosts stands in for Alice's PDS, its export for getRecord's proof
+        let host = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let alice = did!("did:plc:alice");
+        let owner = KeyOwner::Account(alice.clone());
+        let generator = FakeGenerator::default();
+        host.keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let post = |text| Value::object([("text", Value::from(text))]);
+        let writes = ["1", "2", "3"].map(|rkey| Write::Create {
+            path: format!("app.bsky.feed.post/{rkey}"),
+            record: post(rkey),
+        });
+        host.commit(&alice, &writes).unwrap();
+        let proof = host.repos.export(&alice).unwrap();
+
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            r##"{"id": "did:plc:alice", "service": [{"id": "#atproto_pds",
+                "type": "AtprotoPersonalDataServer", "serviceEndpoint": "https://pds.example"}]}"##,
+        );
+        let params = "did=did%3Aplc%3Aalice&collection=app.bsky.feed.post";
+        for rkey in ["2", "4"] {
+            mock.respond(
+                Method::Get,
+                &format!("https://pds.example/xrpc/{SYNC_GET_RECORD}?{params}&rkey={rkey}"),
+                OutboundResponse::new(200).with_body(proof.clone()),
+            );
+        }
+        mock.respond_json(
+            "https://a.example/notes/1",
+            r##"{"id": "https://a.example/notes/1", "attributedTo": "https://b.example/mallory",
+                "signature": {"type": "RsaSignature2017",
+                    "creator": "https://b.example/mallory#main-key"}}"##,
+        );
+        mock.respond_json(
+            "https://b.example/mallory",
+            r#"{"id": "https://b.example/mallory", "publicKey": {
+                "id": "https://b.example/mallory#main-key",
+                "owner": "https://b.example/mallory", "publicKeyPem": "PEM"}}"#,
+        );
+        let bridge = Bridge::new().with_transport(mock);
+
+        let document = did_document(&bridge, "did:plc:alice").unwrap();
+        assert_eq!(document.get("id"), Some(&Value::from("did:plc:alice")));
+        assert!(matches!(
+            did_document(&bridge, "alice"),
+            Err(InspectError::Identity(_))
+        ));
+
+        let uri = "at://did:plc:alice/app.bsky.feed.post/2";
+        let inspected = record(&bridge, uri).unwrap();
+        assert_eq!(inspected.get("value"), Some(&post("2")));
+        let gone = record(&bridge, "at://did:plc:alice/app.bsky.feed.post/4");
+        assert!(matches!(gone, Err(InspectError::NotFound(_))));
+        let blocks = car(&proof).unwrap();
+        let head = host.repos.head(&alice).unwrap();
+        assert_eq!(blocks.get("root"), Some(&Value::from(head.cid.to_string())));
+        let blocks = blocks.get("blocks").and_then(Value::as_array).unwrap();
+        let records = blocks.iter().filter_map(|b| b.get("value")?.get("text"));
+        assert_eq!(records.count(), 3);
+
+        let inspected = object(&bridge, "https://a.example/notes/1").unwrap();
+        let same = |field: &str| inspected.get(field)?.get("sameOrigin").cloned();
+        assert_eq!(same("id"), Some(Value::from(true)));
+        assert_eq!(same("author"), Some(Value::from(false)));
+        let signed = inspected.get("signature").and_then(|s| s.get("status"));
+        assert_eq!(signed, Some(&Value::from(Signed::KeyPublished.as_str())));
+    }
+}
@@ -0,0 +1,249 @@
+//! Living alongside other bridges, such as Bridgy Fed
+//!
+//! An account another bridge already bridges would be bridged twice, and posts bridged back
+//! and forth could loop. So accounts are recognised by their markers, each naming one of
+//! [`OtherBridges`]' domains: a fediverse actor on it, a Bluesky handle under it, or a DID
+//! document with it as the PDS or in `alsoKnownAs`. Such accounts can't opt in, and nothing
+//! is delivered to inboxes on those domains.
+//!
+//! People moving from Bridgy Fed can keep their bridged identity by having their mappings
+//! imported with [`import`], as exported in the form
+//! `{"mappings": [{"did": ..., "actor": ..., "handle": ...}]}`
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::json::Value;
+use crate::normalize;
+use crate::store::Mapping;
+use crate::url::Url;
+use atproto::DID::Did;
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+/// The domains other bridges run on
+pub struct OtherBridges {
+    /// Lowercased domains, covering their subdomains
+    pub domains: Vec<String>,
+}
+
+impl Default for OtherBridges {
+    fn default() -> Self {
+        OtherBridges {
+            // Bridgy Fed, and the Nostr bridges
+            domains: ["brid.gy", "mostr.pub", "momostr.pink"]
+                .map(str::to_string)
+                .to_vec(),
+        }
+    }
+}
+
+impl OtherBridges {
+    /// The domain of the bridge `host` is on, if it's one of theirs
+    pub fn for_host(&self, host: &str) -> Option<&str> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let domains = self.domains.iter();
+        let mut domains = domains.filter(|domain| {
+            host == **domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|rest| rest.ends_with('.'))
+        });
+        domains.next().map(String::as_str)
+    }
+
+    /// The domain of the bridge `url` is on, if it's one of theirs
+    pub fn for_url(&self, url: &str) -> Option<&str> {
+        self.for_host(&Url::parse(url).ok()?.host)
+    }
+
+    /// The bridge a DID document marks its account as bridged by, if any
+    pub fn for_did_document(&self, document: &Value) -> Option<&str> {
+        let services = document.get("service").and_then(Value::as_array);
+        let endpoints = services
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|service| self.for_url(service.get("serviceEndpoint")?.as_str()?));
+        let aliases = document.get("alsoKnownAs").and_then(Value::as_array);
+        let handles = aliases
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|alias| self.for_host(alias.as_str()?.strip_prefix("at://")?));
+        endpoints.chain(handles).next()
+    }
+
+    /// The bridge already bridging `mapping`'s account, as far as its actor and handle show
+    pub fn for_mapping(&self, mapping: &Mapping) -> Option<&str> {
+        let handle = mapping.handle.as_deref();
+        self.for_url(&mapping.actor)
+            .or_else(|| handle.and_then(|handle| self.for_host(handle)))
+    }
+}
+
+#[derive(Debug, Error)]
+/// Why an account can't be bridged
+pub enum OptInError {
+    #[error("Already bridged by {domain}")]
+    BridgedElsewhere { domain: String },
+    #[error("Waiting for an operator to approve it")]
+    AwaitingApproval,
+    #[error("Its handle {handle} looks like {of}, another account's handle")]
+    Lookalike { handle: String, of: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// The bridge already bridging `mapping`'s account, if another is
+///
+/// Besides its actor and handle, and whether a [peer](crate::peering) bridges it, a
+/// `did:plc`'s document is checked. It's fetched if need be, and an account whose document
+/// can't be fetched is given the benefit of the doubt
+pub fn bridged_elsewhere(bridge: &Bridge, mapping: &Mapping) -> Option<String> {
+    let others = &bridge.other_bridges;
+    if let Some(domain) = others.for_mapping(mapping) {
+        return Some(domain.to_string());
+    }
+    if let Some(peer) = bridge.peer_mappings.bridging(mapping) {
+        return Some(peer);
+    }
+    if !mapping.did.as_str().starts_with("did:plc:") {
+        return None;
+    }
+    let document = bridge.resolver().resolve(&mapping.did).ok()?;
+    others.for_did_document(&document).map(str::to_string)
+}
+
+/// Whether `delivery` can go out without another bridge bridging it back
+pub fn delivery_allowed(bridge: &Bridge, delivery: &Delivery) -> bool {
+    bridge.other_bridges.for_url(&delivery.inbox).is_none()
+}
+
+#[derive(Debug, Default, PartialEq)]
+/// What came of importing mappings
+pub struct ImportReport {
+    pub imported: Vec<Did>,
+    /// Entries which were malformed or conflicted with existing mappings, and why
+    pub skipped: Vec<(usize, String)>,
+}
+
+impl ImportReport {
+    pub fn to_json(&self) -> Value {
+        let skipped = self.skipped.iter().map(|(index, reason)| {
+            Value::object([
+                ("index", Value::from(*index)),
+                ("reason", Value::from(reason.as_str())),
+            ])
+        });
+        Value::object([
+            (
+                "imported",
+                Value::Array(self.imported.iter().map(|d| d.as_str().into()).collect()),
+            ),
+            ("skipped", Value::Array(skipped.collect())),
+        ])
+    }
+}
+
+/// Import another bridge's mappings, leaving existing ones alone
+///
+/// Imported accounts are bridged straight away. They're still marked as the other bridge's
+/// until they've moved, so aren't checked as opting in would check them
+pub fn import(bridge: &Bridge, export: &Value) -> ImportReport {
+    let mut report = ImportReport::default();
+    let entries = export.get("mappings").and_then(Value::as_array);
+    for (index, entry) in entries.unwrap_or_default().iter().enumerate() {
+        let did = entry.get("did").and_then(Value::as_str);
+        let did = did.and_then(|did| normalize::parse_did(did).ok());
+        let actor = entry.get("actor").and_then(Value::as_str);
+        let (Some(did), Some(actor)) = (did, actor) else {
+            let reason = "Expected a did and actor".to_string();
+            report.skipped.push((index, reason));
+            continue;
+        };
+        if bridge.identities.contains(&did) {
+            let reason = format!("{did} already has a mapping");
+            report.skipped.push((index, reason));
+            continue;
+        }
+        if bridge.identities.get_by_actor(actor).is_some() {
+            let reason = format!("{actor} already has a mapping");
+            report.skipped.push((index, reason));
+            continue;
+        }
+        let mut mapping = Mapping::new(did.clone(), actor);
+        mapping.handle = entry
+            .get("handle")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if let Err(e) = bridge.identities.try_insert(mapping) {
+            report.skipped.push((index, e.to_string()));
+            continue;
+        }
+        report.imported.push(did);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn recognises_other_bridges_accounts() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:bridgy",
+            r##"{"id": "did:plc:bridgy", "alsoKnownAs": ["at://someone.mastodon.example.ap.brid.gy"],
+                "service": [{"id": "#atproto_pds", "serviceEndpoint": "https://atproto.brid.gy"}]}"##,
+        );
+        mock.respond_json(
+            "https://plc.directory/did:plc:native",
+            r#"{"id": "did:plc:native", "service": [{"serviceEndpoint": "https://pds.example"}]}"#,
+        );
+        let bridge = Bridge::new().with_transport(mock);
+        let bridged = Mapping::new(did!("did:plc:bridgy"), "https://fed.example/users/bridgy");
+        let domain = bridged_elsewhere(&bridge, &bridged);
+        assert_eq!(domain.as_deref(), Some("brid.gy"));
+        let native = Mapping::new(did!("did:plc:native"), "https://fed.example/users/native");
+        assert_eq!(bridged_elsewhere(&bridge, &native), None);
+        let actor = Mapping::new(did!("did:web:x"), "https://bsky.brid.gy/ap/did:plc:x");
+        assert_eq!(bridge.other_bridges.for_mapping(&actor), Some("brid.gy"));
+        assert_eq!(bridge.other_bridges.for_host("notbrid.gy"), None);
+
+        let opted_in = bridge.opt_in(native.clone());
+        assert!(matches!(opted_in, Ok(None)));
+        let refused = bridge.opt_in(bridged);
+        assert!(matches!(
+            refused,
+            Err(OptInError::BridgedElsewhere { domain }) if domain == "brid.gy"
+        ));
+
+        let to_bridgy = Delivery::new("https://bsky.brid.gy/inbox", "{}");
+        assert!(!delivery_allowed(&bridge, &to_bridgy));
+    }
+
+    #[test]
+    fn imports_mappings_it_doesnt_have() {
+        let bridge = Bridge::new();
+        let existing = Mapping::new(did!("did:plc:existing"), "https://a.example/users/e");
+        bridge.identities.insert(existing);
+        let export = json::parse(
+            r#"{"mappings": [
+                {"did": "did:plc:moving", "actor": "https://a.example/users/m", "handle": "m.ap.brid.gy"},
+                {"did": "did:plc:existing", "actor": "https://a.example/users/x"},
+                {"actor": "https://a.example/users/nodid"}
+            ]}"#,
+        )
+        .unwrap();
+        let report = import(&bridge, &export);
+        assert_eq!(report.imported, [did!("did:plc:moving")]);
+        let skipped: Vec<usize> = report.skipped.iter().map(|(index, _)| *index).collect();
+        assert_eq!(skipped, [1, 2]);
+        let moving = bridge.identities.get(&did!("did:plc:moving")).unwrap();
+        assert_eq!(moving.handle.as_deref(), Some("m.ap.brid.gy"));
+    }
+}
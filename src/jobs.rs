@@ -0,0 +1,1301 @@
+//! The background job queue
+//!
+//! All deferred work (deliveries, media fetches, backfills, profile syncs) goes through a
+//! single prioritised queue. Jobs can be scheduled to run at a later time, failed jobs are
+//! retried with exponential backoff, and jobs which exhaust their attempts are parked as dead
+//! until an operator retries them.
+//!
+//! A queue opened with [`JobQueue::open`] appends every change to a journal in its state
+//! directory, so queued work survives a crash. The journal is compacted into a snapshot of the
+//! whole queue once it's grown as long as the queue, so a change costs the same however big
+//! the backlog is. Jobs which were running at the time are run again on restart, which means
+//! job handlers need to tolerate repeats.
+//!
+//! At most the queue's [capacity](JobQueue::set_capacity) of jobs are held in memory, so a
+//! long outage at a busy destination can't grow the bridge without bound. Beyond that, a
+//! persistent queue spills new jobs to its state directory in batches and reloads them, in
+//! order, as room frees up; an in-memory queue refuses them with
+//! [`io::ErrorKind::WouldBlock`], pushing back on whatever produced them
+//!
+//! Work which can't run for now, such as deliveries to a host whose
+//! [circuit](crate::breaker) is open, is [parked](JobQueue::park) until later, or
+//! [`Deferred`] by its handler, without using up its attempts. Work which will never succeed
+//! as it is isn't retried at all: a failure which isn't [retryable](crate::error::Category)
+//! kills its job at once
+
+use crate::audit::Cause;
+use crate::delivery::Delivery;
+use crate::dm::ChatReply;
+use crate::error::{BridgeError, Category};
+use crate::json::{self, Value};
+use crate::moderation::Report;
+use crate::richtext::Facet;
+use crate::shutdown::Shutdown;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, from_unix_millis, unix_millis};
+use crate::webhooks::Notification;
+use atproto::DID::Did;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Number of attempts before a job is considered dead
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubling for each subsequent attempt
+pub const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Longest an idle worker sleeps before rechecking for shutdown
+pub(crate) const WORKER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const QUEUE_FILE: &str = "jobs.json";
+
+/// Fewest changes journalled before the queue is compacted
+const COMPACT_AFTER: usize = 10_000;
+
+/// Jobs held in memory by default, not counting dead ones
+pub const DEFAULT_CAPACITY: usize = 50_000;
+
+/// Most jobs written to or reloaded from one spill file
+const SPILL_BATCH: usize = 1_000;
+
+fn spill_file(batch: u64) -> String {
+    format!("jobs-spill-{batch}.json")
+}
+
+/// The journal of changes since the snapshot of `generation`, one JSON change per line
+fn journal_file(generation: u64) -> String {
+    format!("jobs-journal-{generation}.jsonl")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Priority> {
+        match s {
+            "low" => Some(Priority::Low),
+            "normal" => Some(Priority::Normal),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A unit of deferred work
+pub enum Job {
+    Deliver(Delivery),
+    FetchMedia {
+        url: String,
+    },
+    Backfill {
+        did: Did,
+    },
+    SyncProfile {
+        did: Did,
+    },
+    /// Republish an account's DID document, e.g. after its signing key rotated
+    UpdateDidDocument {
+        did: Did,
+    },
+    /// Send an actor `Delete` for an account which is gone from atproto
+    DeleteActor {
+        did: Did,
+    },
+    /// Deactivate the repo of a fediverse actor which is gone from its instance
+    DeactivateAccount {
+        did: Did,
+    },
+    /// Pass a fediverse report about a bridged account on to atproto moderation
+    CreateReport(Report),
+    /// Answer a Bluesky chat message from a bridged account
+    SendChatMessage(ChatReply),
+    /// Send an operator's webhook
+    Notify(Notification),
+}
+
+impl Job {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Job::Deliver(_) => "deliver",
+            Job::FetchMedia { .. } => "fetchMedia",
+            Job::Backfill { .. } => "backfill",
+            Job::SyncProfile { .. } => "syncProfile",
+            Job::UpdateDidDocument { .. } => "updateDidDocument",
+            Job::DeleteActor { .. } => "deleteActor",
+            Job::DeactivateAccount { .. } => "deactivateAccount",
+            Job::CreateReport(_) => "createReport",
+            Job::SendChatMessage(_) => "sendChatMessage",
+            Job::Notify(_) => "notify",
+        }
+    }
+
+    /// The priority used by [`JobQueue::push`]
+    ///
+    /// Bulk work such as backfills yields to anything which affects live traffic, while DID
+    /// document updates jump the queue since signatures don't verify until they're published
+    pub fn default_priority(&self) -> Priority {
+        match self {
+            Job::UpdateDidDocument { .. } => Priority::High,
+            Job::Deliver(_)
+            | Job::FetchMedia { .. }
+            | Job::DeleteActor { .. }
+            | Job::DeactivateAccount { .. }
+            | Job::CreateReport(_)
+            | Job::SendChatMessage(_)
+            | Job::Notify(_) => Priority::Normal,
+            Job::Backfill { .. } | Job::SyncProfile { .. } => Priority::Low,
+        }
+    }
+
+    /// Why the job was queued, if whatever queued it said
+    pub fn cause(&self) -> Option<&Cause> {
+        match self {
+            Job::Deliver(delivery) => delivery.cause.as_ref(),
+            Job::CreateReport(report) => report.cause.as_ref(),
+            Job::SendChatMessage(reply) => reply.cause.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let mut fields = vec![("kind", Value::from(self.kind()))];
+        match self {
+            Job::Deliver(delivery) => {
+                fields.push(("inbox", Value::from(delivery.inbox.as_str())));
+                fields.push(("activity", Value::from(delivery.activity.as_str())));
+            }
+            Job::FetchMedia { url } => fields.push(("url", Value::from(url.as_str()))),
+            Job::Backfill { did }
+            | Job::SyncProfile { did }
+            | Job::UpdateDidDocument { did }
+            | Job::DeleteActor { did }
+            | Job::DeactivateAccount { did } => fields.push(("did", Value::from(did.as_str()))),
+            Job::CreateReport(report) => {
+                fields.push(("did", Value::from(report.subject.as_str())));
+                fields.push(("reason", Value::from(report.reason.as_str())));
+            }
+            Job::SendChatMessage(reply) => {
+                fields.push(("did", Value::from(reply.did.as_str())));
+                fields.push(("convoId", Value::from(reply.convo_id.as_str())));
+                fields.push(("text", Value::from(reply.text.as_str())));
+                let facets = reply.facets.iter().map(Facet::to_json).collect();
+                fields.push(("facets", Value::Array(facets)));
+            }
+            Job::Notify(notification) => {
+                fields.push(("url", Value::from(notification.url.as_str())));
+                fields.push(("body", Value::from(notification.body.as_str())));
+            }
+        }
+        if let Some(cause) = self.cause() {
+            fields.push(("cause", cause.to_json()));
+        }
+        Value::object(fields)
+    }
+
+    fn from_json(value: &Value) -> Option<Job> {
+        let field = |name| value.get(name).and_then(Value::as_str).map(str::to_string);
+        let did = || Did::try_create(field("did")?).ok();
+        let cause = value.get("cause").and_then(Cause::from_json);
+        match value.get("kind")?.as_str()? {
+            "deliver" => Some(Job::Deliver(Delivery {
+                cause,
+                ..Delivery::new(field("inbox")?, field("activity")?)
+            })),
+            "fetchMedia" => Some(Job::FetchMedia { url: field("url")? }),
+            "backfill" => Some(Job::Backfill { did: did()? }),
+            "syncProfile" => Some(Job::SyncProfile { did: did()? }),
+            "updateDidDocument" => Some(Job::UpdateDidDocument { did: did()? }),
+            "deleteActor" => Some(Job::DeleteActor { did: did()? }),
+            "deactivateAccount" => Some(Job::DeactivateAccount { did: did()? }),
+            "createReport" => Some(Job::CreateReport(Report {
+                subject: did()?,
+                reason: field("reason")?,
+                cause,
+            })),
+            "sendChatMessage" => Some(Job::SendChatMessage(ChatReply {
+                did: did()?,
+                convo_id: field("convoId")?,
+                text: field("text")?,
+                facets: value
+                    .get("facets")
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(Facet::from_json)
+                    .collect(),
+                cause,
+            })),
+            "notify" => Some(Job::Notify(Notification {
+                url: field("url")?,
+                body: field("body")?,
+            })),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A job along with its scheduling state
+pub struct QueuedJob {
+    pub id: u64,
+    pub job: Job,
+    pub priority: Priority,
+    /// The earliest time the job may run
+    pub run_at: SystemTime,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// What's to be done about the last error
+    pub category: Option<Category>,
+}
+
+impl QueuedJob {
+    pub(crate) fn to_json(&self) -> Value {
+        Value::object([
+            ("id", Value::from(self.id)),
+            ("job", self.job.to_json()),
+            ("priority", Value::from(self.priority.as_str())),
+            ("runAt", Value::from(unix_millis(self.run_at))),
+            ("attempts", Value::from(self.attempts)),
+            ("lastError", Value::from(self.last_error.clone())),
+            ("category", Value::from(self.category.map(|c| c.as_str()))),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<QueuedJob> {
+        Some(QueuedJob {
+            id: u64::try_from(value.get("id")?.as_i64()?).ok()?,
+            job: Job::from_json(value.get("job")?)?,
+            priority: Priority::parse(value.get("priority")?.as_str()?)?,
+            run_at: from_unix_millis(value.get("runAt")?.as_i64()?),
+            attempts: u32::try_from(value.get("attempts")?.as_i64()?).ok()?,
+            last_error: value
+                .get("lastError")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            category: value
+                .get("category")
+                .and_then(Value::as_str)
+                .and_then(Category::parse),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors from job queue operations
+pub enum JobError {
+    #[error("No dead job with id {id}")]
+    NotDead { id: u64 },
+    #[error("No running job with id {id}")]
+    NotRunning { id: u64 },
+    /// The change was made in memory, but couldn't be persisted
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error("Deferred until {}", format_rfc3339(*.until))]
+/// Returned by a [`JobHandler`] for a job which can't run yet. The job is rescheduled for
+/// `until` without counting it as an attempt
+pub struct Deferred {
+    pub until: SystemTime,
+}
+
+#[derive(Debug)]
+struct Inner {
+    capacity: usize,
+    next_id: u64,
+    jobs: BTreeMap<u64, QueuedJob>,
+    /// Jobs which may run now, highest priority then oldest first
+    ready: BTreeSet<(Reverse<Priority>, u64)>,
+    /// Jobs waiting for their scheduled time
+    delayed: BTreeSet<(SystemTime, u64)>,
+    running: BTreeSet<u64>,
+    dead: BTreeSet<u64>,
+    /// Jobs which arrived while the queue was full, oldest first, waiting to be spilled
+    overflow: Vec<QueuedJob>,
+    /// Spilled batches and how many jobs are in each, oldest first
+    spilled: VecDeque<(u64, usize)>,
+    next_batch: u64,
+    /// Jobs changed since the journal was last appended to
+    changed: BTreeSet<u64>,
+    /// Jobs which have arrived in the overflow since then
+    overflowed: BTreeSet<u64>,
+    /// Whether there's been a change the journal can't record, so the queue must be compacted
+    compact: bool,
+    /// The snapshot the journal follows, and how many changes are in it
+    generation: u64,
+    journalled: usize,
+    journal: Option<JsonLog>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Inner {
+            capacity: DEFAULT_CAPACITY,
+            next_id: 0,
+            jobs: BTreeMap::new(),
+            ready: BTreeSet::new(),
+            delayed: BTreeSet::new(),
+            running: BTreeSet::new(),
+            dead: BTreeSet::new(),
+            overflow: Vec::new(),
+            spilled: VecDeque::new(),
+            next_batch: 0,
+            changed: BTreeSet::new(),
+            overflowed: BTreeSet::new(),
+            compact: false,
+            generation: 0,
+            journalled: 0,
+            journal: None,
+        }
+    }
+}
+
+impl Inner {
+    /// Jobs in memory which aren't dead
+    fn in_memory(&self) -> usize {
+        self.jobs.len() - self.dead.len()
+    }
+
+    fn spill_batch(&self) -> usize {
+        SPILL_BATCH.min(self.capacity).max(1)
+    }
+
+    fn queue(&mut self, job: QueuedJob, now: SystemTime) {
+        if job.run_at <= now {
+            self.ready.insert((Reverse(job.priority), job.id));
+        } else {
+            self.delayed.insert((job.run_at, job.id));
+        }
+        self.changed.insert(job.id);
+        self.jobs.insert(job.id, job);
+    }
+
+    fn bury(&mut self, job: QueuedJob) {
+        self.dead.insert(job.id);
+        self.changed.insert(job.id);
+        self.jobs.insert(job.id, job);
+    }
+
+    fn overflow(&mut self, job: QueuedJob) {
+        self.overflowed.insert(job.id);
+        self.overflow.push(job);
+    }
+
+    /// Drop job `id` from memory, wherever it's held
+    fn remove(&mut self, id: u64) -> Option<QueuedJob> {
+        self.changed.insert(id);
+        let Some(job) = self.jobs.remove(&id) else {
+            let at = self.overflow.iter().position(|job| job.id == id)?;
+            return Some(self.overflow.remove(at));
+        };
+        self.ready.remove(&(Reverse(job.priority), id));
+        self.delayed.remove(&(job.run_at, id));
+        self.dead.remove(&id);
+        Some(job)
+    }
+
+    /// Apply a change read back from the journal
+    fn replay(&mut self, change: &Value, now: SystemTime) -> Option<()> {
+        let job = || QueuedJob::from_json(change.get("job")?);
+        match change.get("change")?.as_str()? {
+            "put" => {
+                let job = job()?;
+                self.remove(job.id);
+                self.next_id = self.next_id.max(job.id + 1);
+                match change.get("dead") {
+                    Some(Value::Bool(true)) => self.bury(job),
+                    _ => self.queue(job, now),
+                }
+            }
+            "overflow" => {
+                let job = job()?;
+                self.next_id = self.next_id.max(job.id + 1);
+                self.overflow(job);
+            }
+            "drop" => {
+                self.remove(u64::try_from(change.get("id")?.as_i64()?).ok()?);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// The changes since the journal was last appended to
+    fn changes(&self) -> Vec<Value> {
+        let line = |change: &str, job: &QueuedJob| {
+            let dead = self.dead.contains(&job.id);
+            let fields = [
+                ("change", Value::from(change)),
+                ("job", job.to_json()),
+                ("dead", Value::Bool(dead)),
+            ];
+            Value::object(fields)
+        };
+        let overflowed = self
+            .overflow
+            .iter()
+            .filter(|j| self.overflowed.contains(&j.id));
+        let overflowed = overflowed.map(|job| line("overflow", job));
+        let changed = self.changed.iter().map(|id| match self.jobs.get(id) {
+            Some(job) => line("put", job),
+            None => {
+                let fields = [("change", Value::from("drop")), ("id", Value::from(*id))];
+                Value::object(fields)
+            }
+        });
+        overflowed.chain(changed).collect()
+    }
+
+    /// Move delayed jobs whose time has come onto the ready set
+    fn promote(&mut self, now: SystemTime) {
+        while let Some(&(run_at, id)) = self.delayed.first() {
+            if run_at > now {
+                break;
+            }
+            self.delayed.pop_first();
+            let priority = self.jobs[&id].priority;
+            self.ready.insert((Reverse(priority), id));
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        // Running jobs are saved as if they hadn't started, so a crash reruns them
+        let queued = self
+            .jobs
+            .values()
+            .filter(|j| !self.dead.contains(&j.id))
+            .map(QueuedJob::to_json)
+            .collect();
+        let dead = self.dead.iter().map(|id| self.jobs[id].to_json()).collect();
+        let overflow = self.overflow.iter().map(QueuedJob::to_json).collect();
+        let spilled = self
+            .spilled
+            .iter()
+            .map(|&(batch, jobs)| {
+                Value::object([("batch", Value::from(batch)), ("jobs", Value::from(jobs))])
+            })
+            .collect();
+        Value::object([
+            ("nextId", Value::from(self.next_id)),
+            ("journal", Value::from(self.generation)),
+            ("queued", Value::Array(queued)),
+            ("dead", Value::Array(dead)),
+            ("overflow", Value::Array(overflow)),
+            ("spilled", Value::Array(spilled)),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// A prioritised queue of delayed and retryable jobs
+pub struct JobQueue {
+    inner: Mutex<Inner>,
+    available: Condvar,
+    dir: Option<StateDir>,
+}
+
+impl JobQueue {
+    /// An in-memory queue
+    pub fn new() -> JobQueue {
+        JobQueue::default()
+    }
+
+    /// Open a queue which is persisted to `dir`, restoring any previously saved jobs
+    pub fn open(dir: StateDir) -> io::Result<JobQueue> {
+        let invalid =
+            |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{QUEUE_FILE}: {msg}"));
+        let mut inner = Inner::default();
+        if let Some(contents) = dir.read(QUEUE_FILE)? {
+            let state = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| invalid(&e.to_string()))?;
+            let list = |key: &str| -> io::Result<Vec<QueuedJob>> {
+                state
+                    .get(key)
+                    .and_then(Value::as_array)
+                    .ok_or_else(|| invalid(&format!("missing {key}")))?
+                    .iter()
+                    .map(|j| QueuedJob::from_json(j).ok_or_else(|| invalid("malformed job")))
+                    .collect()
+            };
+            inner.next_id = state
+                .get("nextId")
+                .and_then(Value::as_i64)
+                .and_then(|i| u64::try_from(i).ok())
+                .ok_or_else(|| invalid("missing nextId"))?;
+            let now = SystemTime::now();
+            for job in list("queued")? {
+                inner.queue(job, now);
+            }
+            for job in list("dead")? {
+                inner.bury(job);
+            }
+            // Queues saved before spilling existed have neither of these
+            if state.get("overflow").is_some() {
+                inner.overflow = list("overflow")?;
+            }
+            let spilled = state.get("spilled").and_then(Value::as_array);
+            for batch in spilled.unwrap_or_default() {
+                let count = |key| usize::try_from(batch.get(key)?.as_i64()?).ok();
+                let (Some(batch), Some(jobs)) = (count("batch"), count("jobs")) else {
+                    return Err(invalid("malformed spilled batch"));
+                };
+                inner.spilled.push_back((batch as u64, jobs));
+                inner.next_batch = inner.next_batch.max(batch as u64 + 1);
+            }
+            // Queues saved before the journal existed have no generation
+            let generation = state.get("journal").and_then(Value::as_i64);
+            inner.generation = generation.and_then(|g| u64::try_from(g).ok()).unwrap_or(0);
+        }
+        let (journal, changes) = JsonLog::open(dir.clone(), &journal_file(inner.generation))?;
+        let now = SystemTime::now();
+        for change in &changes {
+            inner.replay(change, now);
+        }
+        inner.journalled = changes.len();
+        inner.journal = Some(journal);
+        inner.changed.clear();
+        inner.overflowed.clear();
+        // This journal may have been left behind by a crash mid-compaction
+        if let Some(previous) = inner.generation.checked_sub(1) {
+            dir.remove(&journal_file(previous))?;
+        }
+        let queue = JobQueue {
+            inner: Mutex::new(inner),
+            available: Condvar::new(),
+            dir: Some(dir),
+        };
+        if queue.inner.lock().unwrap().journalled > 0 {
+            queue.save()?;
+        }
+        Ok(queue)
+    }
+
+    /// Write the whole queue out if it is persistent, compacting its journal
+    pub fn save(&self) -> io::Result<()> {
+        match &self.dir {
+            Some(dir) => Self::compact(dir, &mut self.inner.lock().unwrap()),
+            None => Ok(()),
+        }
+    }
+
+    fn compact(dir: &StateDir, inner: &mut MutexGuard<'_, Inner>) -> io::Result<()> {
+        let journal = journal_file(inner.generation);
+        inner.generation += 1;
+        let written = dir.write(QUEUE_FILE, inner.to_json().to_string().as_bytes());
+        if let Err(e) = written {
+            inner.generation -= 1;
+            return Err(e);
+        }
+        inner.changed.clear();
+        inner.overflowed.clear();
+        inner.compact = false;
+        inner.journalled = 0;
+        inner.journal = Some(JsonLog::open(dir.clone(), &journal_file(inner.generation))?.0);
+        dir.remove(&journal)
+    }
+
+    /// Journal what's changed if the queue is persistent, compacting it instead once the
+    /// journal is as long as the queue, or if a change can't be journalled
+    fn persist(&self, inner: &mut MutexGuard<'_, Inner>) -> io::Result<()> {
+        let (Some(dir), Some(journal)) = (&self.dir, inner.journal.clone()) else {
+            inner.changed.clear();
+            inner.overflowed.clear();
+            return Ok(());
+        };
+        let changes = inner.changed.len() + inner.overflowed.len();
+        let limit = COMPACT_AFTER.max(inner.jobs.len() + inner.overflow.len());
+        if inner.compact || inner.journalled + changes > limit {
+            return Self::compact(dir, inner);
+        }
+        if changes == 0 {
+            return Ok(());
+        }
+        journal.append(inner.changes())?;
+        inner.changed.clear();
+        inner.overflowed.clear();
+        inner.journalled += changes;
+        Ok(())
+    }
+
+    /// Apply a change, persist it, and wake workers
+    ///
+    /// In-memory state is always updated, even if persisting fails
+    fn update<T>(&self, f: impl FnOnce(&mut Inner) -> T) -> io::Result<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let result = f(&mut inner);
+        let reloaded = self.rebalance(&mut inner);
+        self.available.notify_all();
+        self.persist(&mut inner)?;
+        // Only now that the reloaded jobs are saved with the rest can their batches go
+        for batch in reloaded? {
+            self.dir
+                .as_ref()
+                .expect("only persistent queues spill")
+                .remove(&spill_file(batch))?;
+        }
+        Ok(result)
+    }
+
+    /// Spill a full batch of overflow to disk, and reload spilled jobs as room frees up,
+    /// returning the reloaded batches
+    fn rebalance(&self, inner: &mut Inner) -> io::Result<Vec<u64>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let batch_size = inner.spill_batch();
+        if inner.overflow.len() >= batch_size {
+            let batch = inner.next_batch;
+            let jobs = Value::Array(inner.overflow.iter().map(QueuedJob::to_json).collect());
+            dir.write(&spill_file(batch), jobs.to_string().as_bytes())?;
+            inner.next_batch += 1;
+            inner.spilled.push_back((batch, inner.overflow.len()));
+            inner.overflow.clear();
+            inner.compact = true;
+        }
+        let now = SystemTime::now();
+        let mut reloaded = Vec::new();
+        loop {
+            let room = inner.capacity.saturating_sub(inner.in_memory());
+            match inner.spilled.front() {
+                // A batch bigger than the whole capacity, which it was lowered below, still
+                // has to be reloaded at some point
+                Some(&(batch, jobs)) if jobs <= room || inner.in_memory() == 0 => {
+                    for job in self.read_batch(batch)? {
+                        inner.queue(job, now);
+                    }
+                    inner.spilled.pop_front();
+                    reloaded.push(batch);
+                    inner.compact = true;
+                }
+                Some(_) => break,
+                None if room > 0 && !inner.overflow.is_empty() => {
+                    let take = room.min(inner.overflow.len());
+                    for job in inner.overflow.drain(..take).collect::<Vec<_>>() {
+                        inner.queue(job, now);
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(reloaded)
+    }
+
+    fn read_batch(&self, batch: u64) -> io::Result<Vec<QueuedJob>> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, spill_file(batch));
+        let dir = self.dir.as_ref().expect("only persistent queues spill");
+        let contents = dir.read(&spill_file(batch))?.ok_or_else(invalid)?;
+        let jobs = json::parse(&String::from_utf8_lossy(&contents)).map_err(|_| invalid())?;
+        let jobs = jobs.as_array().ok_or_else(invalid)?;
+        jobs.iter()
+            .map(|job| QueuedJob::from_json(job).ok_or_else(invalid))
+            .collect()
+    }
+
+    /// Every spilled job, by batch
+    fn spilled(&self, inner: &Inner) -> io::Result<Vec<(u64, Vec<QueuedJob>)>> {
+        let batches = inner.spilled.iter();
+        batches
+            .map(|&(batch, _)| Ok((batch, self.read_batch(batch)?)))
+            .collect()
+    }
+
+    /// Every spilled job, for looking through. A batch which can't be read is logged and
+    /// left out, so the rest still can be
+    fn readable_spilled(&self, inner: &Inner) -> Vec<QueuedJob> {
+        let batches = inner.spilled.iter();
+        let read = batches.filter_map(|&(batch, _)| match self.read_batch(batch) {
+            Ok(jobs) => Some(jobs),
+            Err(e) => {
+                eprintln!("Couldn't read spilled jobs in batch {batch}: {e}");
+                None
+            }
+        });
+        read.flatten().collect()
+    }
+
+    /// Change how many jobs are held in memory
+    pub fn set_capacity(&self, capacity: usize) -> io::Result<()> {
+        self.update(|inner| inner.capacity = capacity.max(1))
+    }
+
+    /// Queue a job to run as soon as possible with its default priority
+    pub fn push(&self, job: Job) -> io::Result<u64> {
+        let priority = job.default_priority();
+        self.schedule(job, priority, SystemTime::now())
+    }
+
+    /// Queue a job to run no earlier than `run_at`
+    ///
+    /// If the queue is full a persistent queue spills the job, and an in-memory one refuses
+    /// it with [`io::ErrorKind::WouldBlock`]
+    pub fn schedule(&self, job: Job, priority: Priority, run_at: SystemTime) -> io::Result<u64> {
+        let spills = self.dir.is_some();
+        let id = self.update(|inner| {
+            let full = inner.in_memory() >= inner.capacity;
+            if full && !spills {
+                return None;
+            }
+            let id = inner.next_id;
+            inner.next_id += 1;
+            let job = QueuedJob {
+                id,
+                job,
+                priority,
+                run_at,
+                attempts: 0,
+                last_error: None,
+                category: None,
+            };
+            // Once jobs have spilled, later ones queue up behind them
+            if full || !inner.overflow.is_empty() || !inner.spilled.is_empty() {
+                inner.overflow(job);
+            } else {
+                inner.queue(job, SystemTime::now());
+            }
+            Some(id)
+        })?;
+        id.ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "The job queue is full"))
+    }
+
+    /// Take the next job which is due at `now`, marking it as running
+    pub fn take(&self, now: SystemTime) -> Option<QueuedJob> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.promote(now);
+        let (_, id) = inner.ready.pop_first()?;
+        inner.running.insert(id);
+        Some(inner.jobs[&id].clone())
+    }
+
+    /// Like [`JobQueue::take`], but waits up to `timeout` for a job to become due
+    pub fn wait_take(&self, timeout: Duration) -> Option<QueuedJob> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = SystemTime::now();
+        inner.promote(now);
+        if inner.ready.is_empty() {
+            // Sleep no longer than it takes for the next delayed job to become due
+            let until_next = inner
+                .delayed
+                .first()
+                .and_then(|(run_at, _)| run_at.duration_since(now).ok())
+                .unwrap_or(timeout);
+            inner = self
+                .available
+                .wait_timeout(inner, timeout.min(until_next))
+                .unwrap()
+                .0;
+        }
+        drop(inner);
+        self.take(SystemTime::now())
+    }
+
+    /// Mark a running job as finished
+    pub fn complete(&self, id: u64) -> io::Result<()> {
+        self.update(|inner| {
+            if inner.running.remove(&id) {
+                inner.remove(id);
+            }
+        })
+    }
+
+    /// Record a failed run, rescheduling the job with backoff or marking it dead, as it is at
+    /// once if the failure isn't retryable. A job which died is returned
+    pub fn fail(
+        &self,
+        id: u64,
+        error: impl Into<String>,
+        category: Category,
+        now: SystemTime,
+    ) -> Result<Option<QueuedJob>, JobError> {
+        let error = error.into();
+        let mut died = None;
+        let result = self.update(|inner| {
+            if !inner.running.remove(&id) {
+                return Err(JobError::NotRunning { id });
+            }
+            let mut job = inner.jobs.remove(&id).expect("running jobs are tracked");
+            job.attempts += 1;
+            job.last_error = Some(error);
+            job.category = Some(category);
+            if job.attempts >= MAX_ATTEMPTS || category != Category::Retryable {
+                died = Some(job.clone());
+                inner.bury(job);
+            } else {
+                job.run_at = now + BASE_RETRY_DELAY * 2u32.pow(job.attempts - 1);
+                inner.queue(job, now);
+            }
+            Ok(())
+        });
+        result??;
+        Ok(died)
+    }
+
+    /// Reschedule a running job for `until`, without counting the run as an attempt
+    pub fn defer(&self, id: u64, until: SystemTime) -> Result<(), JobError> {
+        let result = self.update(|inner| {
+            if !inner.running.remove(&id) {
+                return Err(JobError::NotRunning { id });
+            }
+            let mut job = inner.jobs.remove(&id).expect("running jobs are tracked");
+            job.run_at = until;
+            inner.queue(job, SystemTime::now());
+            Ok(())
+        });
+        result?
+    }
+
+    /// Hold every matching job in memory which is waiting to run before `until` back until
+    /// then, returning how many. Their attempts are left as they were
+    pub fn park(&self, predicate: impl Fn(&Job) -> bool, until: SystemTime) -> io::Result<usize> {
+        self.update(|inner| {
+            let parked: Vec<QueuedJob> = inner
+                .jobs
+                .values()
+                .filter(|j| j.run_at < until && predicate(&j.job))
+                .filter(|j| !inner.running.contains(&j.id) && !inner.dead.contains(&j.id))
+                .cloned()
+                .collect();
+            for mut job in parked.iter().cloned() {
+                inner.ready.remove(&(Reverse(job.priority), job.id));
+                inner.delayed.remove(&(job.run_at, job.id));
+                job.run_at = until;
+                inner.queue(job, SystemTime::now());
+            }
+            parked.len()
+        })
+    }
+
+    /// Drop each job held in memory which repeats an earlier one, and isn't running or dead,
+    /// returning how many
+    pub fn dedup(&self) -> io::Result<usize> {
+        self.update(|inner| {
+            let mut seen = HashSet::new();
+            let repeats: Vec<QueuedJob> = inner
+                .jobs
+                .values()
+                .filter(|j| !inner.running.contains(&j.id) && !inner.dead.contains(&j.id))
+                .filter(|j| !seen.insert(j.job.to_json().to_string()))
+                .cloned()
+                .collect();
+            for job in &repeats {
+                inner.remove(job.id);
+            }
+            repeats.len()
+        })
+    }
+
+    /// Drop every matching job which isn't running, whether it's queued, spilled or dead,
+    /// returning how many
+    pub fn cancel(&self, predicate: impl Fn(&Job) -> bool) -> io::Result<usize> {
+        self.update(|inner| {
+            let mut spilled = 0;
+            for (batch, mut jobs) in self.spilled(inner)? {
+                let before = jobs.len();
+                jobs.retain(|job| !predicate(&job.job));
+                if jobs.len() == before {
+                    continue;
+                }
+                spilled += before - jobs.len();
+                let contents = Value::Array(jobs.iter().map(QueuedJob::to_json).collect());
+                let dir = self.dir.as_ref().expect("only persistent queues spill");
+                dir.write(&spill_file(batch), contents.to_string().as_bytes())?;
+                for entry in inner.spilled.iter_mut().filter(|(b, _)| *b == batch) {
+                    entry.1 = jobs.len();
+                }
+            }
+            let overflowed = inner.overflow.len();
+            inner.overflow.retain(|job| !predicate(&job.job));
+            let overflowed = overflowed - inner.overflow.len();
+            // Neither spilled nor overflowed jobs are journalled as they're dropped
+            inner.compact |= spilled > 0 || overflowed > 0;
+            let cancelled: Vec<QueuedJob> = inner
+                .jobs
+                .values()
+                .filter(|j| !inner.running.contains(&j.id) && predicate(&j.job))
+                .cloned()
+                .collect();
+            for job in &cancelled {
+                inner.remove(job.id);
+            }
+            Ok(cancelled.len() + overflowed + spilled)
+        })?
+    }
+
+    /// Dead jobs, ordered by id
+    pub fn dead(&self) -> Vec<QueuedJob> {
+        let inner = self.inner.lock().unwrap();
+        inner.dead.iter().map(|id| inner.jobs[id].clone()).collect()
+    }
+
+    /// Requeue a dead job with a fresh set of attempts
+    pub fn retry(&self, id: u64) -> Result<(), JobError> {
+        let result = self.update(|inner| {
+            if !inner.dead.remove(&id) {
+                return Err(JobError::NotDead { id });
+            }
+            let mut job = inner.jobs.remove(&id).expect("dead jobs are tracked");
+            job.attempts = 0;
+            job.run_at = SystemTime::now();
+            inner.queue(job, SystemTime::now());
+            Ok(())
+        });
+        result?
+    }
+
+    /// Jobs which are waiting to run or running, ordered by id, including spilled ones
+    pub fn queued(&self) -> Vec<QueuedJob> {
+        let inner = self.inner.lock().unwrap();
+        let in_memory = inner.jobs.values().filter(|j| !inner.dead.contains(&j.id));
+        let spilled = self.readable_spilled(&inner);
+        let mut queued: Vec<QueuedJob> = in_memory
+            .chain(&inner.overflow)
+            .cloned()
+            .chain(spilled)
+            .collect();
+        queued.sort_by_key(|job| job.id);
+        queued
+    }
+
+    /// Whether a matching job is waiting to run or running
+    pub fn contains(&self, predicate: impl Fn(&Job) -> bool) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let in_memory = inner
+            .jobs
+            .values()
+            .filter(|j| !inner.dead.contains(&j.id))
+            .chain(&inner.overflow)
+            .any(|j| predicate(&j.job));
+        in_memory
+            || self
+                .readable_spilled(&inner)
+                .iter()
+                .any(|j| predicate(&j.job))
+    }
+
+    /// Jobs waiting to run or running, including spilled ones
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let spilled: usize = inner.spilled.iter().map(|&(_, jobs)| jobs).sum();
+        inner.in_memory() + inner.overflow.len() + spilled
+    }
+
+    /// Jobs which have been spilled to disk, or are waiting to be
+    pub fn spilled_len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        let spilled: usize = inner.spilled.iter().map(|&(_, jobs)| jobs).sum();
+        inner.overflow.len() + spilled
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Something which can run jobs
+pub trait JobHandler: Send + Sync {
+    fn run(&self, job: &Job) -> anyhow::Result<()>;
+
+    /// Called each time `job` fails, before it's retried or marked dead
+    fn failed(&self, _job: &QueuedJob, _error: &anyhow::Error) {}
+
+    /// Called when `job` has failed for the last time
+    fn dead(&self, _job: &QueuedJob) {}
+}
+
+impl<F> JobHandler for F
+where
+    F: Fn(&Job) -> anyhow::Result<()> + Send + Sync,
+{
+    fn run(&self, job: &Job) -> anyhow::Result<()> {
+        self(job)
+    }
+}
+
+/// Run a job taken from `queue`, and complete, defer or fail it by how it went
+pub(crate) fn run_job(queue: &JobQueue, handler: &dyn JobHandler, job: &QueuedJob) {
+    match handler.run(&job.job) {
+        Ok(()) => {
+            let _ = queue.complete(job.id);
+        }
+        Err(e) if e.is::<Deferred>() => {
+            let until = e.downcast_ref::<Deferred>().unwrap().until;
+            let _ = queue.defer(job.id, until);
+        }
+        Err(e) => {
+            handler.failed(job, &e);
+            let BridgeError { category, error } = BridgeError::from(e);
+            let failed = queue.fail(job.id, format!("{error:#}"), category, SystemTime::now());
+            if let Ok(Some(dead)) = failed {
+                handler.dead(&dead);
+            }
+        }
+    }
+}
+
+/// Spawn `count` workers running jobs from the queue until shutdown is requested
+///
+/// Each running job holds an in-flight guard, so shutdown waits for it to finish
+pub fn spawn_workers(
+    queue: Arc<JobQueue>,
+    handler: Arc<dyn JobHandler>,
+    count: usize,
+    shutdown: Arc<Shutdown>,
+) -> Vec<JoinHandle<()>> {
+    (0..count)
+        .map(|_| {
+            let (queue, handler, shutdown) = (queue.clone(), handler.clone(), shutdown.clone());
+            thread::spawn(move || {
+                while !shutdown.is_requested() {
+                    let Some(guard) = shutdown.begin() else {
+                        break;
+                    };
+                    let Some(job) = queue.wait_take(WORKER_POLL_INTERVAL) else {
+                        continue;
+                    };
+                    run_job(&queue, handler.as_ref(), &job);
+                    drop(guard);
+                }
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn did(s: &str) -> Did {
+        Did::try_create(s.to_string()).unwrap()
+    }
+
+    fn media(url: &str) -> Job {
+        Job::FetchMedia {
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn priority_then_fifo() {
+        let queue = JobQueue::new();
+        queue
+            .push(Job::Backfill {
+                did: did("did:plc:aaaa"),
+            })
+            .unwrap();
+        queue.push(media("https://a.example/1.png")).unwrap();
+        queue.push(media("https://a.example/2.png")).unwrap();
+        let now = SystemTime::now();
+        let order: Vec<_> = std::iter::from_fn(|| queue.take(now).map(|j| j.job.kind())).collect();
+        assert_eq!(order, ["fetchMedia", "fetchMedia", "backfill"]);
+    }
+
+    #[test]
+    fn delayed_jobs_wait_their_turn() {
+        let queue = JobQueue::new();
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+        queue
+            .schedule(media("https://a.example"), Priority::High, later)
+            .unwrap();
+        assert!(queue.take(now).is_none());
+        assert!(queue.take(later).is_some());
+    }
+
+    #[test]
+    fn parked_and_deferred_jobs_keep_their_attempts() {
+        let queue = JobQueue::new();
+        let now = SystemTime::now();
+        let until = now + Duration::from_secs(300);
+        queue.push(media("https://a.example/1.png")).unwrap();
+        queue.push(media("https://b.example/1.png")).unwrap();
+        let job = queue.take(now).unwrap();
+        queue.defer(job.id, until).unwrap();
+        let on_b = |job: &Job| matches!(job, Job::FetchMedia { url } if url.contains("b.example"));
+        assert_eq!(queue.park(on_b, until).unwrap(), 1);
+        assert!(queue.take(now).is_none());
+        let due: Vec<_> = std::iter::from_fn(|| queue.take(until)).collect();
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|job| job.attempts == 0));
+    }
+
+    #[test]
+    fn failures_back_off_then_die() {
+        let queue = JobQueue::new();
+        let id = queue.push(media("https://a.example")).unwrap();
+        let mut now = SystemTime::now();
+        for attempt in 1..MAX_ATTEMPTS {
+            let job = queue.take(now).unwrap();
+            queue
+                .fail(job.id, "timeout", Category::Retryable, now)
+                .unwrap();
+            assert!(queue.take(now).is_none());
+            now += BASE_RETRY_DELAY * 2u32.pow(attempt - 1);
+        }
+        let job = queue.take(now).unwrap();
+        queue
+            .fail(job.id, "timeout", Category::Retryable, now)
+            .unwrap();
+        assert_eq!(queue.dead()[0].attempts, MAX_ATTEMPTS);
+        assert!(queue.is_empty());
+
+        queue.retry(id).unwrap();
+        assert!(matches!(queue.retry(id), Err(JobError::NotDead { id: dead }) if dead == id));
+        let job = queue.take(SystemTime::now()).unwrap();
+        assert_eq!(job.attempts, 0);
+
+        // What won't ever succeed isn't retried
+        let died = queue.fail(id, "410", Category::Permanent, now).unwrap();
+        assert_eq!(died.unwrap().category, Some(Category::Permanent));
+        assert_eq!(queue.dead()[0].attempts, 1);
+    }
+
+    #[test]
+    fn running_jobs_survive_a_crash() {
+        let dir = crate::storage::tests::temp_state_dir();
+        let queue = JobQueue::open(dir.clone()).unwrap();
+        queue
+            .push(Job::SyncProfile {
+                did: did("did:plc:aaaa"),
+            })
+            .unwrap();
+        let done = queue.push(media("https://a.example")).unwrap();
+        let running = queue.take(SystemTime::now()).unwrap();
+        queue.complete(running.id).unwrap();
+        assert_eq!(running.id, done);
+        // Taken but never completed before the "crash"
+        queue.take(SystemTime::now()).unwrap();
+
+        let restored = JobQueue::open(dir).unwrap();
+        let job = restored.take(SystemTime::now()).unwrap();
+        assert_eq!(
+            job.job,
+            Job::SyncProfile {
+                did: did("did:plc:aaaa")
+            }
+        );
+        assert_eq!(restored.push(media("https://b.example")).unwrap(), 2);
+    }
+
+    #[test]
+    fn changes_are_journalled_then_compacted() {
+        let dir = crate::storage::tests::temp_state_dir();
+        let queue = JobQueue::open(dir.clone()).unwrap();
+        for n in 0..3 {
+            queue
+                .push(media(&format!("https://a.example/{n}")))
+                .unwrap();
+        }
+        let now = SystemTime::now();
+        let done = queue.take(now).unwrap();
+        queue.complete(done.id).unwrap();
+        let dead = queue.take(now).unwrap();
+        queue
+            .fail(dead.id, "410", Category::Permanent, now)
+            .unwrap();
+        // Only the journal has been written to so far
+        assert!(dir.read(QUEUE_FILE).unwrap().is_none());
+        let journal = dir.read(&journal_file(0)).unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&journal).lines().count(), 5);
+
+        // Torn by a crash mid-append
+        dir.append(&journal_file(0), br#"{"change": "pu"#).unwrap();
+        let restored = JobQueue::open(dir.clone()).unwrap();
+        assert_eq!(restored.queued().len(), 1);
+        assert_eq!(restored.dead()[0].last_error.as_deref(), Some("410"));
+        assert_eq!(restored.push(media("https://b.example")).unwrap(), 3);
+        // Reopening compacted the journal into a snapshot, with a fresh one after it
+        assert!(dir.read(QUEUE_FILE).unwrap().is_some());
+        assert!(dir.read(&journal_file(0)).unwrap().is_none());
+        assert!(dir.read(&journal_file(1)).unwrap().is_some());
+        assert_eq!(JobQueue::open(dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn workers_run_jobs_until_shutdown() {
+        let queue = Arc::new(JobQueue::new());
+        let ran = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let ran = ran.clone();
+            Arc::new(move |job: &Job| {
+                ran.fetch_add(1, Ordering::SeqCst);
+                match job {
+                    Job::FetchMedia { url } if url.contains("broken") => anyhow::bail!("404"),
+                    _ => Ok(()),
+                }
+            })
+        };
+        let shutdown = Shutdown::new();
+        let workers = spawn_workers(queue.clone(), handler, 2, shutdown.clone());
+        queue.push(media("https://a.example")).unwrap();
+        queue.push(media("https://broken.example")).unwrap();
+        while ran.load(Ordering::SeqCst) < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(shutdown.run(Duration::from_secs(1)).drained);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        let remaining = queue.queued();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].last_error.as_deref(), Some("404"));
+    }
+
+    #[test]
+    fn full_queues_spill_or_refuse() {
+        let queue = JobQueue::new();
+        queue.set_capacity(1).unwrap();
+        queue.push(media("https://a.example/1")).unwrap();
+        let refused = queue.push(media("https://a.example/2")).unwrap_err();
+        assert_eq!(refused.kind(), io::ErrorKind::WouldBlock);
+
+        let dir = crate::storage::tests::temp_state_dir();
+        let queue = JobQueue::open(dir.clone()).unwrap();
+        queue.set_capacity(2).unwrap();
+        for n in 0..7 {
+            queue
+                .push(media(&format!("https://a.example/{n}")))
+                .unwrap();
+        }
+        // Two in memory, two in each of two spilled batches and one waiting to spill
+        assert_eq!((queue.len(), queue.spilled_len()), (7, 5));
+        assert!(dir.read(&spill_file(1)).unwrap().is_some());
+        assert!(queue.contains(|job| *job == media("https://a.example/6")));
+        assert_eq!(
+            queue
+                .cancel(|job| *job == media("https://a.example/3"))
+                .unwrap(),
+            1
+        );
+
+        // Spilled jobs survive a restart, and come back in order as room frees up
+        let queue = JobQueue::open(dir.clone()).unwrap();
+        queue.set_capacity(2).unwrap();
+        let mut order = Vec::new();
+        while let Some(job) = queue.take(SystemTime::now()) {
+            queue.complete(job.id).unwrap();
+            order.push(job.id);
+        }
+        assert_eq!(order, [0, 1, 2, 4, 5, 6]);
+        assert_eq!(queue.spilled_len(), 0);
+        assert!(dir.read(&spill_file(0)).unwrap().is_none());
+    }
+}
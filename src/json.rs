@@ -0,0 +1,458 @@
+//! A minimal JSON value type and parser
+//!
+//! This only covers what the bridge's own APIs and persisted state need, and is deliberately
+//! not a general purpose serialisation framework
+
+use std::collections::BTreeMap;
+use std::fmt;
+use thiserror::Error;
+
+/// Nesting depth beyond which parsing is refused, so hostile input can't overflow the stack
+pub const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors parsing JSON text
+pub enum ParseError {
+    #[error("Unexpected end of input")]
+    UnexpectedEnd,
+    #[error("Unexpected character {found:?} at byte {offset}")]
+    UnexpectedChar { found: char, offset: usize },
+    #[error("Invalid number at byte {offset}")]
+    InvalidNumber { offset: usize },
+    #[error("Invalid escape sequence at byte {offset}")]
+    InvalidEscape { offset: usize },
+    #[error("Nesting deeper than {MAX_DEPTH} levels")]
+    TooDeep,
+    #[error("Trailing characters at byte {offset}")]
+    TrailingCharacters { offset: usize },
+}
+
+/// A JSON value
+///
+/// Objects are kept in a `BTreeMap` so that output is deterministic
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    /// Build an object from an iterator of key/value pairs
+    pub fn object<K, I>(entries: I) -> Value
+    where
+        K: Into<String>,
+        I: IntoIterator<Item = (K, Value)>,
+    {
+        Value::Object(entries.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Look up a key if this is an object
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// The ID of an object, which may be given inline or by reference
+pub fn id_of(value: Option<&Value>) -> Option<&str> {
+    value.and_then(|v| v.as_str().or_else(|| v.get("id").and_then(Value::as_str)))
+}
+
+/// A field which may hold one value or a list of them
+pub fn one_or_many(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+        Some(Value::Array(values)) => values.iter().collect(),
+        Some(value) => vec![value],
+        None => Vec::new(),
+    }
+}
+
+/// Parse a complete JSON document
+pub fn parse(input: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { input, pos: 0 };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != input.len() {
+        return Err(ParseError::TrailingCharacters { offset: parser.pos });
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.bump() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(ParseError::UnexpectedChar {
+                found: c,
+                offset: self.pos - c.len_utf8(),
+            }),
+            None => Err(ParseError::UnexpectedEnd),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\n' | '\r' | '\t')) {
+            self.pos += 1;
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, ParseError> {
+        if self.input[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            match self.peek() {
+                Some(found) => Err(ParseError::UnexpectedChar {
+                    found,
+                    offset: self.pos,
+                }),
+                None => Err(ParseError::UnexpectedEnd),
+            }
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(ParseError::TooDeep);
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(ParseError::UnexpectedEnd),
+            Some('n') => self.literal("null", Value::Null),
+            Some('t') => self.literal("true", Value::Bool(true)),
+            Some('f') => self.literal("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                self.skip_whitespace();
+                if self.peek() == Some(']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.bump() {
+                        Some(',') => continue,
+                        Some(']') => return Ok(Value::Array(items)),
+                        Some(found) => {
+                            return Err(ParseError::UnexpectedChar {
+                                found,
+                                offset: self.pos - 1,
+                            })
+                        }
+                        None => return Err(ParseError::UnexpectedEnd),
+                    }
+                }
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut map = BTreeMap::new();
+                self.skip_whitespace();
+                if self.peek() == Some('}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(map));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.skip_whitespace();
+                    self.expect(':')?;
+                    map.insert(key, self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.bump() {
+                        Some(',') => continue,
+                        Some('}') => return Ok(Value::Object(map)),
+                        Some(found) => {
+                            return Err(ParseError::UnexpectedChar {
+                                found,
+                                offset: self.pos - 1,
+                            })
+                        }
+                        None => return Err(ParseError::UnexpectedEnd),
+                    }
+                }
+            }
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(found) => Err(ParseError::UnexpectedChar {
+                found,
+                offset: self.pos,
+            }),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.pos += 1;
+        }
+        let text = &self.input[start..self.pos];
+        if let Ok(i) = text.parse::<i64>() {
+            return Ok(Value::Int(i));
+        }
+        text.parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| ParseError::InvalidNumber { offset: start })
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let offset = self.pos;
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .ok_or(ParseError::UnexpectedEnd)?;
+        let code =
+            u32::from_str_radix(digits, 16).map_err(|_| ParseError::InvalidEscape { offset })?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            let offset = self.pos;
+            match self.bump().ok_or(ParseError::UnexpectedEnd)? {
+                '"' => return Ok(out),
+                '\\' => match self.bump().ok_or(ParseError::UnexpectedEnd)? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'u' => {
+                        let mut code = self.hex4()?;
+                        // Surrogate pairs encode characters outside the basic multilingual plane
+                        if (0xD800..0xDC00).contains(&code)
+                            && self.input[self.pos..].starts_with("\\u")
+                        {
+                            self.pos += 2;
+                            let low = self.hex4()?;
+                            code = 0x10000
+                                + ((code - 0xD800) << 10)
+                                + (low.wrapping_sub(0xDC00) & 0x3FF);
+                        }
+                        out.push(char::from_u32(code).ok_or(ParseError::InvalidEscape { offset })?);
+                    }
+                    _ => return Err(ParseError::InvalidEscape { offset }),
+                },
+                c if (c as u32) < 0x20 => {
+                    return Err(ParseError::UnexpectedChar { found: c, offset })
+                }
+                c => out.push(c),
+            }
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(i: i64) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(i: u32) -> Self {
+        Value::Int(i as i64)
+    }
+}
+
+impl From<u64> for Value {
+    /// Values above `i64::MAX` saturate, which is fine for the counters and ids the bridge emits
+    fn from(i: u64) -> Self {
+        Value::Int(i64::try_from(i).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<usize> for Value {
+    fn from(i: usize) -> Self {
+        Value::from(i as u64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(o: Option<T>) -> Self {
+        o.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(v: Vec<T>) -> Self {
+        Value::Array(v.into_iter().map(Into::into).collect())
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+    /// Compact JSON serialisation
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Int(i) => write!(f, "{i}"),
+            // JSON has no representation for NaN or infinities
+            Value::Float(x) if !x.is_finite() => f.write_str("null"),
+            Value::Float(x) => write!(f, "{x}"),
+            Value::String(s) => write_escaped(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(map) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_escaped(f, k)?;
+                    write!(f, ":{v}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialise_nested() {
+        let v = Value::object([
+            ("b", Value::from(vec![1i64, 2])),
+            ("a", Value::from("x\"y")),
+            ("c", Value::Null),
+        ]);
+        assert_eq!(v.to_string(), r#"{"a":"x\"y","b":[1,2],"c":null}"#)
+    }
+
+    #[test]
+    fn control_characters_escaped() {
+        assert_eq!(Value::from("\u{1}\n").to_string(), r#""\u0001\n""#)
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":{"c":"d\"e"},"f":"\ud83d\ude00"}"#;
+        let value = parse(text).unwrap();
+        assert_eq!(value.get("f").and_then(Value::as_str), Some("\u{1F600}"));
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert_eq!(
+            parse("[1,]"),
+            Err(ParseError::UnexpectedChar {
+                found: ']',
+                offset: 3
+            })
+        );
+        assert_eq!(
+            parse("{} x"),
+            Err(ParseError::TrailingCharacters { offset: 3 })
+        );
+        assert_eq!(parse(&"[".repeat(MAX_DEPTH + 2)), Err(ParseError::TooDeep));
+        assert_eq!(parse("\"abc"), Err(ParseError::UnexpectedEnd));
+    }
+}
@@ -0,0 +1,724 @@
+//! Key management
+//!
+//! The keystore holds every private key the bridge signs with: RSA keys for HTTP signatures
+//! on ActivityPub requests, and secp256k1 or P-256 keys for signing atproto repo commits.
+//! Keys belong either to the bridge itself or to an individual bridged account.
+//!
+//! Generating keys is delegated to a [`KeyGenerator`], so the store only deals with key
+//! material and lifecycle. Rotating a key retires (but keeps) the previous version so that
+//! signatures made with it can still be checked while remote caches catch up.
+//!
+//! A key can also be [pre-rotated](KeyStore::prerotate): the new version is published straight
+//! away, but the previous one goes on signing for an overlap window, so that by the time
+//! anything is signed with the new one, servers have had the chance to see it published.
+//!
+//! A persistent keystore is encrypted at rest: the passphrase is stretched with
+//! PBKDF2-HMAC-SHA256 and the serialised keys are encrypted with ChaCha20 then authenticated
+//! with HMAC-SHA256. Each save uses a fresh nonce
+
+use crate::crypto::{self, chacha20, constant_time_eq, hex_decode, hex_encode, hmac_sha256};
+use crate::json::{self, Value};
+use crate::storage::StateDir;
+use crate::time::{from_unix_millis, unix_millis};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+const KEYSTORE_FILE: &str = "keys.json";
+
+/// PBKDF2 rounds used when deriving the keystore's master key
+pub const DEFAULT_KDF_ITERATIONS: u32 = 200_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyAlgorithm {
+    Rsa,
+    Secp256k1,
+    P256,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::Rsa => "rsa",
+            KeyAlgorithm::Secp256k1 => "secp256k1",
+            KeyAlgorithm::P256 => "p256",
+            KeyAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<KeyAlgorithm> {
+        match s {
+            "rsa" => Some(KeyAlgorithm::Rsa),
+            "secp256k1" => Some(KeyAlgorithm::Secp256k1),
+            "p256" => Some(KeyAlgorithm::P256),
+            "ed25519" => Some(KeyAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What a key is used to sign
+pub enum KeyPurpose {
+    /// HTTP signatures on ActivityPub requests, advertised as the actor's `publicKey`
+    HttpSignature,
+    /// Newer fediverse signatures, advertised as a Multikey in the actor's `assertionMethod`
+    Assertion,
+    /// atproto repo commits, advertised in the DID document
+    RepoSigning,
+    /// The bridge's OAuth client assertions, advertised in its JWKS
+    OAuthClient,
+    /// Labels from the bridge's labeler, advertised in its DID document
+    LabelSigning,
+    /// The bridge's rotation key for the `did:plc`s it makes, listed in their PLC operations
+    PlcRotation,
+    /// The bridge's assertions of who it bridges, for its peers, advertised in its DID document
+    PeerSigning,
+}
+
+impl KeyPurpose {
+    pub const ALL: [KeyPurpose; 7] = [
+        KeyPurpose::HttpSignature,
+        KeyPurpose::Assertion,
+        KeyPurpose::RepoSigning,
+        KeyPurpose::OAuthClient,
+        KeyPurpose::LabelSigning,
+        KeyPurpose::PlcRotation,
+        KeyPurpose::PeerSigning,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyPurpose::HttpSignature => "httpSignature",
+            KeyPurpose::Assertion => "assertion",
+            KeyPurpose::RepoSigning => "repoSigning",
+            KeyPurpose::OAuthClient => "oauthClient",
+            KeyPurpose::LabelSigning => "labelSigning",
+            KeyPurpose::PlcRotation => "plcRotation",
+            KeyPurpose::PeerSigning => "peerSigning",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<KeyPurpose> {
+        match s {
+            "httpSignature" => Some(KeyPurpose::HttpSignature),
+            "assertion" => Some(KeyPurpose::Assertion),
+            "repoSigning" => Some(KeyPurpose::RepoSigning),
+            "oauthClient" => Some(KeyPurpose::OAuthClient),
+            "labelSigning" => Some(KeyPurpose::LabelSigning),
+            "plcRotation" => Some(KeyPurpose::PlcRotation),
+            "peerSigning" => Some(KeyPurpose::PeerSigning),
+            _ => None,
+        }
+    }
+
+    /// RSA is what the fediverse universally verifies, Ed25519 is what it's moving to for
+    /// Multikeys, secp256k1 is atproto's default, and atproto OAuth requires ES256
+    pub fn default_algorithm(&self) -> KeyAlgorithm {
+        match self {
+            KeyPurpose::HttpSignature => KeyAlgorithm::Rsa,
+            KeyPurpose::Assertion => KeyAlgorithm::Ed25519,
+            KeyPurpose::RepoSigning
+            | KeyPurpose::LabelSigning
+            | KeyPurpose::PlcRotation
+            | KeyPurpose::PeerSigning => KeyAlgorithm::Secp256k1,
+            KeyPurpose::OAuthClient => KeyAlgorithm::P256,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Who a key belongs to
+pub enum KeyOwner {
+    /// The bridge's own instance actor and service identity
+    Bridge,
+    Account(Did),
+}
+
+impl fmt::Display for KeyOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyOwner::Bridge => f.write_str("bridge"),
+            KeyOwner::Account(did) => write!(f, "{did}"),
+        }
+    }
+}
+
+impl KeyOwner {
+    fn parse(s: &str) -> Option<KeyOwner> {
+        match s {
+            "bridge" => Some(KeyOwner::Bridge),
+            did => Did::try_create(did.to_string()).ok().map(KeyOwner::Account),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+/// Key material produced by a [`KeyGenerator`]
+pub struct KeyPair {
+    pub algorithm: KeyAlgorithm,
+    /// Encoded private key (PKCS#8 DER for RSA, raw scalar bytes for elliptic curves)
+    pub private_key: Vec<u8>,
+    /// Public key in its published form (PEM for RSA, multibase for elliptic curves)
+    pub public_key: String,
+}
+
+impl fmt::Debug for KeyPair {
+    /// Never print private keys, not even by accident in a log line
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("algorithm", &self.algorithm)
+            .field("public_key", &self.public_key)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Something which can create new keys
+pub trait KeyGenerator: Send + Sync {
+    fn generate(&self, algorithm: KeyAlgorithm) -> anyhow::Result<KeyPair>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A version of a key held in the store
+pub struct StoredKey {
+    pub owner: KeyOwner,
+    pub purpose: KeyPurpose,
+    /// Starts at 1 and increases with each rotation
+    pub version: u32,
+    pub keypair: KeyPair,
+    pub created_at: SystemTime,
+    /// When this version was replaced by a rotation, or will be by a pre-rotation
+    pub retired_at: Option<SystemTime>,
+    /// When a pre-rotated version starts being signed with. Until then it's only published
+    pub signs_from: Option<SystemTime>,
+}
+
+impl StoredKey {
+    /// A stable identifier for this key version
+    pub fn id(&self) -> String {
+        format!("{}#{}-{}", self.owner, self.purpose.as_str(), self.version)
+    }
+
+    fn to_json(&self) -> Value {
+        Value::object([
+            ("owner", Value::from(self.owner.to_string())),
+            ("purpose", Value::from(self.purpose.as_str())),
+            ("version", Value::from(self.version)),
+            ("algorithm", Value::from(self.keypair.algorithm.as_str())),
+            (
+                "privateKey",
+                Value::from(hex_encode(&self.keypair.private_key)),
+            ),
+            ("publicKey", Value::from(self.keypair.public_key.as_str())),
+            ("createdAt", Value::from(unix_millis(self.created_at))),
+            ("retiredAt", Value::from(self.retired_at.map(unix_millis))),
+            ("signsFrom", Value::from(self.signs_from.map(unix_millis))),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<StoredKey> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(StoredKey {
+            owner: KeyOwner::parse(field("owner")?)?,
+            purpose: KeyPurpose::parse(field("purpose")?)?,
+            version: u32::try_from(value.get("version")?.as_i64()?).ok()?,
+            keypair: KeyPair {
+                algorithm: KeyAlgorithm::parse(field("algorithm")?)?,
+                private_key: hex_decode(field("privateKey")?)?,
+                public_key: field("publicKey")?.to_string(),
+            },
+            created_at: from_unix_millis(value.get("createdAt")?.as_i64()?),
+            retired_at: value
+                .get("retiredAt")
+                .and_then(Value::as_i64)
+                .map(from_unix_millis),
+            signs_from: value
+                .get("signsFrom")
+                .and_then(Value::as_i64)
+                .map(from_unix_millis),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The outcome of a rotation
+///
+/// Whoever rotated the key is responsible for republishing `current` wherever the old key
+/// was advertised (the actor document, the DID document)
+pub struct Rotation {
+    pub previous: Option<StoredKey>,
+    pub current: StoredKey,
+}
+
+#[derive(Debug, Error)]
+/// Errors from keystore operations
+pub enum KeyError {
+    #[error("Couldn't generate a {algorithm} key")]
+    Generation {
+        algorithm: &'static str,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Keystore couldn't be decrypted - wrong passphrase or corrupted file")]
+    Decryption,
+    #[error("Version {version} of the key is already waiting to be signed with")]
+    RotationPending { version: u32 },
+    #[error("Keystore file is malformed - {reason}")]
+    Malformed { reason: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+struct Persistence {
+    dir: StateDir,
+    salt: [u8; 16],
+    iterations: u32,
+    master: [u8; 32],
+}
+
+impl Persistence {
+    fn subkeys(&self) -> ([u8; 32], [u8; 32]) {
+        (
+            hmac_sha256(&self.master, b"fedibridge keystore encryption"),
+            hmac_sha256(&self.master, b"fedibridge keystore authentication"),
+        )
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Value, KeyError> {
+        let (enc_key, mac_key) = self.subkeys();
+        let mut nonce = [0u8; 12];
+        crypto::random_bytes(&mut nonce)?;
+        let mut ciphertext = plaintext.to_vec();
+        chacha20(&enc_key, &nonce, 1, &mut ciphertext);
+        let mac = hmac_sha256(&mac_key, &[&nonce[..], &ciphertext].concat());
+        Ok(Value::object([
+            ("version", Value::from(1i64)),
+            ("kdf", Value::from("pbkdf2-sha256")),
+            ("iterations", Value::from(self.iterations)),
+            ("salt", Value::from(hex_encode(&self.salt))),
+            ("nonce", Value::from(hex_encode(&nonce))),
+            ("ciphertext", Value::from(hex_encode(&ciphertext))),
+            ("mac", Value::from(hex_encode(&mac))),
+        ]))
+    }
+
+    fn open(&self, sealed: &Value) -> Result<Vec<u8>, KeyError> {
+        let field = |name: &str| {
+            sealed
+                .get(name)
+                .and_then(Value::as_str)
+                .and_then(hex_decode)
+                .ok_or_else(|| KeyError::Malformed {
+                    reason: format!("missing {name}"),
+                })
+        };
+        let (nonce, mut ciphertext, mac) = (field("nonce")?, field("ciphertext")?, field("mac")?);
+        let nonce: [u8; 12] = nonce.try_into().map_err(|_| KeyError::Malformed {
+            reason: "bad nonce".to_string(),
+        })?;
+        let (enc_key, mac_key) = self.subkeys();
+        let expected = hmac_sha256(&mac_key, &[&nonce[..], &ciphertext].concat());
+        if !constant_time_eq(&expected, &mac) {
+            return Err(KeyError::Decryption);
+        }
+        chacha20(&enc_key, &nonce, 1, &mut ciphertext);
+        Ok(ciphertext)
+    }
+}
+
+/// How long a pre-rotated key is published before it's signed with, unless configured
+pub const DEFAULT_KEY_OVERLAP: Duration = Duration::from_secs(2 * 24 * 60 * 60);
+
+type Slot = (KeyOwner, KeyPurpose);
+
+/// Whether `key` is signed with by `now`, rather than waiting for its pre-rotation's overlap
+fn signs_by(key: &StoredKey, now: SystemTime) -> bool {
+    key.signs_from.is_none_or(|from| from <= now)
+}
+
+/// Store of signing keys, optionally encrypted on disk
+pub struct KeyStore {
+    /// Every version of each key, oldest first
+    keys: RwLock<HashMap<Slot, Vec<StoredKey>>>,
+    persistence: Option<Persistence>,
+}
+
+impl Default for KeyStore {
+    fn default() -> Self {
+        KeyStore::new()
+    }
+}
+
+impl fmt::Debug for KeyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyStore")
+            .field("slots", &self.keys.read().unwrap().len())
+            .field("persistent", &self.persistence.is_some())
+            .finish()
+    }
+}
+
+impl KeyStore {
+    /// An in-memory keystore
+    pub fn new() -> KeyStore {
+        KeyStore {
+            keys: RwLock::new(HashMap::new()),
+            persistence: None,
+        }
+    }
+
+    /// Open (or create) the encrypted keystore in `dir`
+    ///
+    /// `iterations` is only used when creating a new keystore, existing ones keep theirs
+    pub fn open(dir: StateDir, passphrase: &str, iterations: u32) -> Result<KeyStore, KeyError> {
+        let malformed = |reason: &str| KeyError::Malformed {
+            reason: reason.to_string(),
+        };
+        let Some(contents) = dir.read(KEYSTORE_FILE)? else {
+            let mut salt = [0u8; 16];
+            crypto::random_bytes(&mut salt)?;
+            let master = crypto::pbkdf2_sha256(passphrase.as_bytes(), &salt, iterations);
+            return Ok(KeyStore {
+                keys: RwLock::new(HashMap::new()),
+                persistence: Some(Persistence {
+                    dir,
+                    salt,
+                    iterations,
+                    master,
+                }),
+            });
+        };
+        let sealed = json::parse(&String::from_utf8_lossy(&contents))
+            .map_err(|e| malformed(&e.to_string()))?;
+        let salt: [u8; 16] = sealed
+            .get("salt")
+            .and_then(Value::as_str)
+            .and_then(hex_decode)
+            .and_then(|s| s.try_into().ok())
+            .ok_or_else(|| malformed("bad salt"))?;
+        let iterations = sealed
+            .get("iterations")
+            .and_then(Value::as_i64)
+            .and_then(|i| u32::try_from(i).ok())
+            .ok_or_else(|| malformed("bad iterations"))?;
+        let persistence = Persistence {
+            dir,
+            salt,
+            iterations,
+            master: crypto::pbkdf2_sha256(passphrase.as_bytes(), &salt, iterations),
+        };
+        let plaintext = persistence.open(&sealed)?;
+        let state = json::parse(&String::from_utf8_lossy(&plaintext))
+            .map_err(|e| malformed(&e.to_string()))?;
+        let mut keys: HashMap<Slot, Vec<StoredKey>> = HashMap::new();
+        for key in state
+            .get("keys")
+            .and_then(Value::as_array)
+            .ok_or_else(|| malformed("missing keys"))?
+        {
+            let key = StoredKey::from_json(key).ok_or_else(|| malformed("bad key"))?;
+            keys.entry((key.owner.clone(), key.purpose))
+                .or_default()
+                .push(key);
+        }
+        for versions in keys.values_mut() {
+            versions.sort_by_key(|k| k.version);
+        }
+        Ok(KeyStore {
+            keys: RwLock::new(keys),
+            persistence: Some(persistence),
+        })
+    }
+
+    fn save(&self, keys: &HashMap<Slot, Vec<StoredKey>>) -> Result<(), KeyError> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        let all = keys.values().flatten().map(StoredKey::to_json).collect();
+        let plaintext = Value::object([("keys", Value::Array(all))]).to_string();
+        let sealed = persistence.seal(plaintext.as_bytes())?;
+        Ok(persistence
+            .dir
+            .write(KEYSTORE_FILE, sealed.to_string().as_bytes())?)
+    }
+
+    /// The key currently used for signing
+    pub fn current(&self, owner: &KeyOwner, purpose: KeyPurpose) -> Option<StoredKey> {
+        self.current_at(owner, purpose, SystemTime::now())
+    }
+
+    /// The key used for signing at `now`
+    pub fn current_at(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        now: SystemTime,
+    ) -> Option<StoredKey> {
+        let keys = self.keys.read().unwrap();
+        let versions = keys.get(&(owner.clone(), purpose))?;
+        versions.iter().rev().find(|k| signs_by(k, now)).cloned()
+    }
+
+    /// The pre-rotated key which is published but not yet signed with at `now`, if there is one
+    pub fn upcoming(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        now: SystemTime,
+    ) -> Option<StoredKey> {
+        let keys = self.keys.read().unwrap();
+        let latest = keys.get(&(owner.clone(), purpose))?.last()?;
+        (!signs_by(latest, now)).then(|| latest.clone())
+    }
+
+    /// Every version of a key, oldest first
+    pub fn history(&self, owner: &KeyOwner, purpose: KeyPurpose) -> Vec<StoredKey> {
+        let keys = self.keys.read().unwrap();
+        keys.get(&(owner.clone(), purpose))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Find a key version by its [`StoredKey::id`], including retired versions
+    pub fn by_id(&self, id: &str) -> Option<StoredKey> {
+        let keys = self.keys.read().unwrap();
+        keys.values().flatten().find(|k| k.id() == id).cloned()
+    }
+
+    /// The current key, generating the first version if there isn't one yet
+    ///
+    /// This is how bridged accounts get their per-account keys
+    pub fn ensure(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        generator: &dyn KeyGenerator,
+    ) -> Result<StoredKey, KeyError> {
+        if let Some(key) = self.current(owner, purpose) {
+            return Ok(key);
+        }
+        let mut keys = self.keys.write().unwrap();
+        // Someone else may have generated it while we didn't hold the lock
+        if let Some(key) = keys.get(&(owner.clone(), purpose)).and_then(|v| v.last()) {
+            return Ok(key.clone());
+        }
+        let key = Self::generate(owner, purpose, 1, generator)?;
+        keys.insert((owner.clone(), purpose), vec![key.clone()]);
+        self.save(&keys)?;
+        Ok(key)
+    }
+
+    /// Replace the current key with a freshly generated version
+    pub fn rotate(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        generator: &dyn KeyGenerator,
+    ) -> Result<Rotation, KeyError> {
+        let mut keys = self.keys.write().unwrap();
+        let versions = keys.entry((owner.clone(), purpose)).or_default();
+        let now = SystemTime::now();
+        let version = versions.last().map_or(1, |k| k.version + 1);
+        let current = Self::generate(owner, purpose, version, generator)?;
+        let previous = versions.last_mut().map(|k| {
+            k.retired_at = Some(now);
+            k.clone()
+        });
+        versions.push(current.clone());
+        self.save(&keys)?;
+        Ok(Rotation { previous, current })
+    }
+
+    /// Generate the next version of a key, to be published now but only signed with once
+    /// `overlap` has passed. Until then the current version goes on signing
+    ///
+    /// Without a current version, the first is signed with straight away
+    pub fn prerotate(
+        &self,
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        generator: &dyn KeyGenerator,
+        overlap: Duration,
+    ) -> Result<Rotation, KeyError> {
+        let mut keys = self.keys.write().unwrap();
+        let versions = keys.entry((owner.clone(), purpose)).or_default();
+        let now = SystemTime::now();
+        if let Some(pending) = versions.last().filter(|k| !signs_by(k, now)) {
+            return Err(KeyError::RotationPending {
+                version: pending.version,
+            });
+        }
+        let version = versions.last().map_or(1, |k| k.version + 1);
+        let mut current = Self::generate(owner, purpose, version, generator)?;
+        let switch = now + overlap;
+        let previous = versions.last_mut().map(|k| {
+            k.retired_at = Some(switch);
+            k.clone()
+        });
+        if previous.is_some() {
+            current.signs_from = Some(switch);
+        }
+        versions.push(current.clone());
+        self.save(&keys)?;
+        Ok(Rotation { previous, current })
+    }
+
+    fn generate(
+        owner: &KeyOwner,
+        purpose: KeyPurpose,
+        version: u32,
+        generator: &dyn KeyGenerator,
+    ) -> Result<StoredKey, KeyError> {
+        let algorithm = purpose.default_algorithm();
+        let keypair = generator
+            .generate(algorithm)
+            .map_err(|source| KeyError::Generation {
+                algorithm: algorithm.as_str(),
+                source,
+            })?;
+        Ok(StoredKey {
+            owner: owner.clone(),
+            purpose,
+            version,
+            keypair,
+            created_at: SystemTime::now(),
+            retired_at: None,
+            signs_from: None,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Produces distinct, obviously fake keys
+    #[derive(Default)]
+    pub(crate) struct FakeGenerator {
+        counter: AtomicU32,
+    }
+
+    impl KeyGenerator for FakeGenerator {
+        fn generate(&self, algorithm: KeyAlgorithm) -> anyhow::Result<KeyPair> {
+            let n = self.counter.fetch_add(1, Ordering::SeqCst);
+            Ok(KeyPair {
+                algorithm,
+                private_key: n.to_be_bytes().to_vec(),
+                public_key: format!("{}-public-{n}", algorithm.as_str()),
+            })
+        }
+    }
+
+    fn account() -> KeyOwner {
+        KeyOwner::Account(atproto::did!("did:plc:aaaa"))
+    }
+
+    #[test]
+    fn ensure_is_idempotent() {
+        let store = KeyStore::new();
+        let generator = FakeGenerator::default();
+        let first = store
+            .ensure(&account(), KeyPurpose::HttpSignature, &generator)
+            .unwrap();
+        let second = store
+            .ensure(&account(), KeyPurpose::HttpSignature, &generator)
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.keypair.algorithm, KeyAlgorithm::Rsa);
+        assert_eq!(first.id(), "did:plc:aaaa#httpSignature-1");
+        assert!(store
+            .current(&KeyOwner::Bridge, KeyPurpose::HttpSignature)
+            .is_none());
+    }
+
+    #[test]
+    fn rotation_retires_previous_version() {
+        let store = KeyStore::new();
+        let generator = FakeGenerator::default();
+        let first = store
+            .rotate(&KeyOwner::Bridge, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        assert_eq!(first.previous, None);
+        let second = store
+            .rotate(&KeyOwner::Bridge, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        assert_eq!(second.current.version, 2);
+        assert!(second.previous.unwrap().retired_at.is_some());
+        assert_eq!(
+            store.current(&KeyOwner::Bridge, KeyPurpose::RepoSigning),
+            Some(second.current)
+        );
+        assert!(store.by_id("bridge#repoSigning-1").is_some());
+    }
+
+    #[test]
+    fn prerotation_signs_with_the_old_key_until_the_overlap_passes() {
+        let store = KeyStore::new();
+        let generator = FakeGenerator::default();
+        let owner = account();
+        let purpose = KeyPurpose::HttpSignature;
+        let overlap = Duration::from_secs(60 * 60);
+        let first = store
+            .prerotate(&owner, purpose, &generator, overlap)
+            .unwrap();
+        assert_eq!(first.current.signs_from, None);
+        let second = store
+            .prerotate(&owner, purpose, &generator, overlap)
+            .unwrap();
+        let switch = second.current.signs_from.unwrap();
+        assert_eq!(second.previous.as_ref().unwrap().retired_at, Some(switch));
+        assert!(matches!(
+            store.prerotate(&owner, purpose, &generator, overlap),
+            Err(KeyError::RotationPending { version: 2 })
+        ));
+
+        let before = switch - Duration::from_secs(1);
+        let signing = store.current_at(&owner, purpose, before);
+        assert_eq!(signing.map(|k| k.version), Some(1));
+        let upcoming = store.upcoming(&owner, purpose, before);
+        assert_eq!(upcoming.map(|k| k.version), Some(2));
+        assert_eq!(
+            store.current_at(&owner, purpose, switch),
+            Some(second.current)
+        );
+        assert_eq!(store.upcoming(&owner, purpose, switch), None);
+    }
+
+    #[test]
+    fn persisted_encrypted() {
+        let dir = crate::storage::tests::temp_state_dir();
+        let generator = FakeGenerator::default();
+        let store = KeyStore::open(dir.clone(), "correct horse", 10).unwrap();
+        let key = store
+            .ensure(&account(), KeyPurpose::HttpSignature, &generator)
+            .unwrap();
+
+        let raw = String::from_utf8(dir.read(KEYSTORE_FILE).unwrap().unwrap()).unwrap();
+        assert!(!raw.contains(&key.keypair.public_key));
+
+        let reopened = KeyStore::open(dir.clone(), "correct horse", 10).unwrap();
+        // Timestamps are only persisted to millisecond precision
+        let restored = reopened
+            .current(&account(), KeyPurpose::HttpSignature)
+            .unwrap();
+        assert_eq!(restored.id(), key.id());
+        assert_eq!(restored.keypair, key.keypair);
+        assert!(matches!(
+            KeyStore::open(dir, "battery staple", 10),
+            Err(KeyError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn debug_hides_private_key() {
+        let keypair = FakeGenerator::default()
+            .generate(KeyAlgorithm::P256)
+            .unwrap();
+        assert!(!format!("{keypair:?}").contains("private_key"));
+    }
+}
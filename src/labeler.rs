@@ -0,0 +1,615 @@
+//! The bridge's labeler, marking what it publishes on Bluesky as bridged
+//!
+//! With a labeler DID configured, each post the bridge commits for a fediverse account is
+//! labelled [`BRIDGED`] and with its origin as `from-<domain>`, as is the account itself when
+//! its repo is created, so clients subscribed to the labeler can show where content came
+//! from. Labels are signed with the bridge's [label key](KeyPurpose::LabelSigning) by the
+//! [`RepoSigner`], as they're signed just as commits are.
+//!
+//! Labels are sequenced, and kept at the root of the state directory as every shard shares
+//! them. They're served by `com.atproto.label.queryLabels`, and streamed from any cursor by
+//! `com.atproto.label.subscribeLabels`. [`declare`] commits the labeler's
+//! `app.bsky.labeler.service` record, describing its labels, to its own repo
+
+use crate::bridge::Bridge;
+use crate::car;
+use crate::crypto::{base64_encode, hex_decode, hex_encode};
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::{self, Value};
+use crate::keys::{KeyGenerator, KeyOwner, KeyPair, KeyPurpose, StoredKey};
+use crate::modstream::EventKind;
+use crate::repo::{record_cid, Head, RepoError, RepoSigner, Write};
+use crate::storage::StateDir;
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::url::Url;
+use crate::websocket::{self, WebSocket, GOING_AWAY};
+use atproto::DID::Did;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// The labels' file, at the root of the state directory
+pub const LABELS_FILE: &str = "labels.json";
+pub const QUERY_LABELS: &str = "com.atproto.label.queryLabels";
+pub const SUBSCRIBE_LABELS: &str = "com.atproto.label.subscribeLabels";
+pub const SERVICE_COLLECTION: &str = "app.bsky.labeler.service";
+/// The label on everything the bridge publishes for fediverse accounts
+pub const BRIDGED: &str = "bridged-from-fediverse";
+
+const DEFAULT_QUERY_LIMIT: usize = 50;
+const MAX_QUERY_LIMIT: usize = 250;
+/// How long a subscription waits for labels before checking on its client
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, PartialEq)]
+/// A label the labeler has signed
+pub struct IssuedLabel {
+    pub seq: i64,
+    /// The labeler
+    pub src: Did,
+    /// The labelled record's at:// URI, or an account's DID
+    pub uri: String,
+    pub val: String,
+    /// Whether this retracts an earlier label with the same value
+    pub neg: bool,
+    pub cts: SystemTime,
+    pub sig: Vec<u8>,
+}
+
+impl IssuedLabel {
+    /// The label as it's signed, without its signature or sequence number
+    fn unsigned(&self) -> Value {
+        let mut label = Value::object([
+            ("ver", Value::from(1i64)),
+            ("src", Value::from(self.src.as_str())),
+            ("uri", Value::from(self.uri.as_str())),
+            ("val", Value::from(self.val.as_str())),
+            ("cts", Value::from(format_rfc3339(self.cts))),
+        ]);
+        if let (true, Value::Object(fields)) = (self.neg, &mut label) {
+            fields.insert("neg".into(), Value::from(true));
+        }
+        label
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut label = self.unsigned();
+        if let Value::Object(fields) = &mut label {
+            // atproto leaves off the padding
+            let sig = base64_encode(&self.sig).trim_end_matches('=').to_string();
+            let sig = Value::object([("$bytes", Value::from(sig))]);
+            fields.insert("sig".into(), sig);
+        }
+        label
+    }
+
+    /// The signed label as DAG-CBOR, with its signature as bytes
+    fn encode(&self, out: &mut Vec<u8>) {
+        let Value::Object(mut fields) = self.unsigned() else {
+            return;
+        };
+        fields.insert("sig".into(), Value::Null);
+        // Keys in DAG-CBOR order: shortest first, then bytewise
+        let mut keys: Vec<&String> = fields.keys().collect();
+        keys.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+        car::head(out, 5, keys.len() as u64);
+        for key in keys {
+            car::encode_into(out, &Value::from(key.as_str()));
+            match key.as_str() {
+                "sig" => {
+                    car::head(out, 2, self.sig.len() as u64);
+                    out.extend_from_slice(&self.sig);
+                }
+                _ => car::encode_into(out, &fields[key]),
+            }
+        }
+    }
+
+    fn to_stored(&self) -> Value {
+        let mut label = self.unsigned();
+        if let Value::Object(fields) = &mut label {
+            fields.insert("seq".into(), Value::from(self.seq));
+            fields.insert("sig".into(), Value::from(hex_encode(&self.sig)));
+        }
+        label
+    }
+
+    fn from_stored(value: &Value) -> Option<IssuedLabel> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(IssuedLabel {
+            seq: value.get("seq")?.as_i64()?,
+            src: Did::try_create(field("src")?.to_string()).ok()?,
+            uri: field("uri")?.to_string(),
+            val: field("val")?.to_string(),
+            neg: value
+                .get("neg")
+                .and_then(Value::as_bool)
+                .unwrap_or_default(),
+            cts: parse_rfc3339(field("cts")?).ok()?,
+            sig: hex_decode(field("sig")?)?,
+        })
+    }
+}
+
+/// The label naming where a fediverse actor is from, e.g. `from-mastodon-social`
+///
+/// Label values are lowercase letters and hyphens, so anything else in the domain is a hyphen
+pub fn origin_label(actor: &str) -> Option<String> {
+    let host = Url::parse(actor).ok()?.host;
+    let host = host.chars().map(|c| match c.is_ascii_lowercase() {
+        true => c,
+        false => '-',
+    });
+    let label = format!("from-{}", host.collect::<String>());
+    (label.len() <= 128).then_some(label)
+}
+
+#[derive(Debug, Error)]
+/// Errors issuing labels
+pub enum LabelError {
+    #[error("No signer is configured")]
+    NoSigner,
+    #[error("The labeler has no label signing key")]
+    NoKey,
+    #[error("Couldn't sign the label: {0:#}")]
+    Signing(anyhow::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Default)]
+struct Labels {
+    labels: Vec<IssuedLabel>,
+    /// The latest label's sequence number
+    seq: i64,
+}
+
+#[derive(Debug, Default)]
+/// Every label the labeler has issued, in sequence
+pub struct LabelStore {
+    inner: Mutex<Labels>,
+    /// Signalled as each label is issued
+    issued: Condvar,
+    dir: Option<StateDir>,
+}
+
+impl LabelStore {
+    /// The labels persisted in `dir`
+    pub fn open(dir: StateDir) -> io::Result<LabelStore> {
+        let mut inner = Labels::default();
+        if let Some(contents) = dir.read(LABELS_FILE)? {
+            let stored = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let labels = stored.get("labels").and_then(Value::as_array);
+            let labels = labels.unwrap_or_default().iter();
+            inner.labels = labels.filter_map(IssuedLabel::from_stored).collect();
+            inner.seq = inner.labels.last().map_or(0, |label| label.seq);
+        }
+        Ok(LabelStore {
+            inner: Mutex::new(inner),
+            issued: Condvar::new(),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, inner: &Labels) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let labels = inner.labels.iter().map(IssuedLabel::to_stored).collect();
+        let stored = Value::object([("labels", Value::Array(labels))]);
+        dir.write(LABELS_FILE, stored.to_string().as_bytes())
+    }
+
+    /// Sign and sequence `val` on `uri` from `src`
+    pub fn issue(
+        &self,
+        src: &Did,
+        uri: &str,
+        val: &str,
+        key: &KeyPair,
+        signer: &dyn RepoSigner,
+        now: SystemTime,
+    ) -> Result<IssuedLabel, LabelError> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut label = IssuedLabel {
+            seq: inner.seq + 1,
+            src: src.clone(),
+            uri: uri.to_string(),
+            val: val.to_string(),
+            neg: false,
+            cts: now,
+            sig: Vec::new(),
+        };
+        let unsigned = car::encode_dag_cbor(&label.unsigned());
+        label.sig = signer.sign(key, &unsigned).map_err(LabelError::Signing)?;
+        inner.seq = label.seq;
+        inner.labels.push(label.clone());
+        let saved = self.save(&inner);
+        drop(inner);
+        self.issued.notify_all();
+        saved?;
+        Ok(label)
+    }
+
+    /// The latest label's sequence number
+    pub fn seq(&self) -> i64 {
+        self.inner.lock().unwrap().seq
+    }
+
+    /// Every label after `after`
+    pub fn since(&self, after: i64) -> Vec<IssuedLabel> {
+        let inner = self.inner.lock().unwrap();
+        let labels = inner.labels.iter().filter(|label| label.seq > after);
+        labels.cloned().collect()
+    }
+
+    /// Labels after `after`, waiting up to `timeout` for one if there aren't any yet
+    pub fn wait(&self, after: i64, timeout: Duration) -> Vec<IssuedLabel> {
+        let inner = self.inner.lock().unwrap();
+        let (inner, _) = self
+            .issued
+            .wait_timeout_while(inner, timeout, |inner| inner.seq <= after)
+            .unwrap();
+        let labels = inner.labels.iter().filter(|label| label.seq > after);
+        labels.cloned().collect()
+    }
+
+    /// Up to `limit` labels after `after` on URIs matching one of `patterns`, which match
+    /// exactly or, ending in `*`, by prefix
+    pub fn query(&self, patterns: &[&str], after: i64, limit: usize) -> Vec<IssuedLabel> {
+        let matches = |uri: &str| {
+            patterns
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => uri.starts_with(prefix),
+                    None => uri == *pattern,
+                })
+        };
+        let inner = self.inner.lock().unwrap();
+        let labels = inner.labels.iter();
+        let labels = labels.filter(|label| label.seq > after && matches(&label.uri));
+        labels.take(limit).cloned().collect()
+    }
+}
+
+/// The labeler's current label key, generating it if it doesn't have one yet
+pub fn label_key(bridge: &Bridge, generator: &dyn KeyGenerator) -> anyhow::Result<StoredKey> {
+    let owner = KeyOwner::Bridge;
+    Ok(bridge
+        .keys
+        .ensure(&owner, KeyPurpose::LabelSigning, generator)?)
+}
+
+/// Label what a commit to `did`'s repo published, returning how many labels were issued
+///
+/// Only bridged fediverse accounts' posts are labelled, and the account itself when its
+/// repo is `created`. Without a labeler, nothing is
+pub fn label_commit(
+    bridge: &Bridge,
+    did: &Did,
+    writes: &[Write],
+    created: bool,
+) -> Result<usize, LabelError> {
+    let Some(src) = &bridge.labeler else {
+        return Ok(0);
+    };
+    let Some(mapping) = bridge.identities.get(did) else {
+        return Ok(0);
+    };
+    let posts = writes.iter().filter_map(|write| match write {
+        Write::Create { path, .. } if path.starts_with("app.bsky.feed.post/") => {
+            Some(format!("at://{did}/{path}"))
+        }
+        _ => None,
+    });
+    let subjects: Vec<String> = created
+        .then(|| did.as_str().to_string())
+        .into_iter()
+        .chain(posts)
+        .collect();
+    if subjects.is_empty() {
+        return Ok(0);
+    }
+    let signer = bridge.repo_signer.as_ref().ok_or(LabelError::NoSigner)?;
+    let key = bridge
+        .keys
+        .current(&KeyOwner::Bridge, KeyPurpose::LabelSigning)
+        .ok_or(LabelError::NoKey)?;
+    let values: Vec<String> = [Some(BRIDGED.to_string()), origin_label(&mapping.actor)]
+        .into_iter()
+        .flatten()
+        .collect();
+    let now = SystemTime::now();
+    let mut issued = 0;
+    for subject in &subjects {
+        for val in &values {
+            let labels = &bridge.issued_labels;
+            labels.issue(src, subject, val, &key.keypair, signer.as_ref(), now)?;
+            let details = Value::object([("val", Value::from(val.as_str()))]);
+            let events = &bridge.moderation_events;
+            events.publish(EventKind::Label, subject, details);
+            issued += 1;
+        }
+    }
+    Ok(issued)
+}
+
+/// The labeler's `app.bsky.labeler.service` record
+pub fn declaration(now: SystemTime) -> Value {
+    let locale = Value::object([
+        ("lang", Value::from("en")),
+        ("name", Value::from("Bridged from the fediverse")),
+        (
+            "description",
+            Value::from("Posted on the fediverse, and bridged to Bluesky by this bridge"),
+        ),
+    ]);
+    let definition = Value::object([
+        ("identifier", Value::from(BRIDGED)),
+        ("severity", Value::from("inform")),
+        ("blurs", Value::from("none")),
+        ("defaultSetting", Value::from("warn")),
+        ("adultOnly", Value::from(false)),
+        ("locales", Value::Array(vec![locale])),
+    ]);
+    Value::object([
+        ("$type", Value::from(SERVICE_COLLECTION)),
+        (
+            "policies",
+            Value::object([
+                ("labelValues", Value::Array(vec![Value::from(BRIDGED)])),
+                ("labelValueDefinitions", Value::Array(vec![definition])),
+            ]),
+        ),
+        ("createdAt", Value::from(format_rfc3339(now))),
+    ])
+}
+
+/// Commit the labeler's declaration to its repo, so the AppView knows it's a labeler,
+/// unless the same policies are already declared
+pub fn declare(bridge: &Bridge, labeler: &Did) -> Result<Option<Head>, RepoError> {
+    let committed = bridge.update_repo(labeler, || {
+        let path = format!("{SERVICE_COLLECTION}/self");
+        let record = declaration(SystemTime::now());
+        let declared = bridge.repos.records(labeler, SERVICE_COLLECTION);
+        Ok(match declared.iter().find(|(rkey, _)| rkey == "self") {
+            Some((_, declared)) if declared.get("policies") == record.get("policies") => vec![],
+            Some((_, declared)) => vec![Write::Update {
+                path,
+                record,
+                swap: Some(record_cid(declared)),
+            }],
+            None => vec![Write::Create { path, record }],
+        })
+    })?;
+    Ok(committed.map(|(head, _)| head))
+}
+
+/// A `#labels` frame of `labels`, sequenced as the last of them
+fn labels_frame(labels: &[IssuedLabel]) -> Vec<u8> {
+    let mut frame = car::encode_dag_cbor(&Value::object([
+        ("op", Value::from(1i64)),
+        ("t", Value::from("#labels")),
+    ]));
+    let seq = labels.last().map_or(0, |label| label.seq);
+    car::head(&mut frame, 5, 2);
+    car::encode_into(&mut frame, &Value::from("seq"));
+    car::encode_into(&mut frame, &Value::from(seq));
+    car::encode_into(&mut frame, &Value::from("labels"));
+    car::head(&mut frame, 4, labels.len() as u64);
+    for label in labels {
+        label.encode(&mut frame);
+    }
+    frame
+}
+
+/// Send each of `labels` in its own frame, returning the last's sequence number, or `seq` if
+/// there were none
+fn send(socket: &mut WebSocket, labels: &[IssuedLabel], seq: i64) -> io::Result<i64> {
+    for label in labels {
+        socket.send(&labels_frame(std::slice::from_ref(label)))?;
+    }
+    Ok(labels.last().map_or(seq, |label| label.seq))
+}
+
+/// The labeler endpoints, in front of `inner`. Without a labeler, everything is passed on
+pub struct LabelerEndpoints<H> {
+    bridge: Arc<Bridge>,
+    inner: H,
+}
+
+impl<H: Handler> LabelerEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, inner: H) -> LabelerEndpoints<H> {
+        LabelerEndpoints { bridge, inner }
+    }
+
+    fn query_labels(&self, labeler: &Did, request: &Request) -> Result<Response, Response> {
+        let invalid = |message| Response::xrpc_error(400, "InvalidRequest", message);
+        let params = |name| {
+            let values = request.query.iter().filter(move |(n, _)| n == name);
+            values.map(|(_, value)| value.as_str())
+        };
+        let patterns: Vec<&str> = params("uriPatterns").collect();
+        if patterns.is_empty() {
+            return Err(invalid("Missing uriPatterns"));
+        }
+        let limit = match request.query_param("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| (1..=MAX_QUERY_LIMIT).contains(limit))
+                .ok_or_else(|| invalid("Invalid limit"))?,
+            None => DEFAULT_QUERY_LIMIT,
+        };
+        let after = match request.query_param("cursor") {
+            Some(cursor) => cursor.parse().map_err(|_| invalid("Invalid cursor"))?,
+            None => 0,
+        };
+        let mut sources = params("sources").peekable();
+        let ours = sources.peek().is_none() || sources.any(|src| src == labeler.as_str());
+        let labels = match ours {
+            true => self.bridge.issued_labels.query(&patterns, after, limit),
+            false => Vec::new(),
+        };
+        let cursor = match labels.last() {
+            Some(label) if labels.len() == limit => Some(label.seq.to_string()),
+            _ => None,
+        };
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("cursor", Value::from(cursor)),
+                (
+                    "labels",
+                    Value::Array(labels.iter().map(IssuedLabel::to_json).collect()),
+                ),
+            ]),
+        ))
+    }
+
+    fn subscribe_labels(&self, request: &Request) -> Result<Response, Response> {
+        let cursor = match request.query_param("cursor") {
+            Some(cursor) => Some(
+                cursor
+                    .parse::<i64>()
+                    .map_err(|_| Response::xrpc_error(400, "InvalidRequest", "Invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let bridge = self.bridge.clone();
+        Ok(websocket::accept(request, move |mut socket, shutdown| {
+            let mut seq = match cursor {
+                Some(cursor) if cursor > bridge.issued_labels.seq() => {
+                    let mut frame =
+                        car::encode_dag_cbor(&Value::object([("op", Value::from(-1i64))]));
+                    frame.extend(car::encode_dag_cbor(&Value::object([
+                        ("error", Value::from("FutureCursor")),
+                        ("message", Value::from("Cursor is ahead of the stream")),
+                    ])));
+                    socket.send(&frame)?;
+                    return socket.close(GOING_AWAY);
+                }
+                Some(cursor) => send(&mut socket, &bridge.issued_labels.since(cursor), cursor)?,
+                None => bridge.issued_labels.seq(),
+            };
+            while !shutdown.is_requested() {
+                let labels = bridge.issued_labels.wait(seq, POLL_INTERVAL);
+                seq = send(&mut socket, &labels, seq)?;
+                if !socket.poll()? {
+                    return Ok(());
+                }
+            }
+            socket.close(GOING_AWAY)
+        }))
+    }
+}
+
+impl<H: Handler> Handler for LabelerEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(labeler) = &self.bridge.labeler else {
+            return self.inner.handle(request);
+        };
+        let result = match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["xrpc", QUERY_LABELS]) => self.query_labels(labeler, request),
+            (Method::Get, ["xrpc", SUBSCRIBE_LABELS]) => self.subscribe_labels(request),
+            _ => return self.inner.handle(request),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cbor;
+    use crate::keys::tests::FakeGenerator;
+    use crate::repo::tests::HashSigner;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const LABELER: Did = did!("did:web:bridge.example");
+
+    fn bridge() -> Bridge {
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_labeler(LABELER);
+        let mapping = Mapping::new(ALICE, "https://mastodon.example/users/alice");
+        bridge.identities.insert(mapping);
+        let generator = FakeGenerator::default();
+        let owner = KeyOwner::Account(ALICE);
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        label_key(&bridge, &generator).unwrap();
+        bridge
+    }
+
+    fn post(n: u32) -> Write {
+        Write::Create {
+            path: format!("app.bsky.feed.post/{n}"),
+            record: Value::object([("text", Value::from("hi"))]),
+        }
+    }
+
+    #[test]
+    fn labels_bridged_posts_and_accounts() {
+        let bridge = Arc::new(bridge());
+        bridge.commit(&ALICE, &[post(1)]).unwrap();
+        bridge.commit(&ALICE, &[post(2)]).unwrap();
+        // The account once, then each post, each as bridged and from its instance
+        assert_eq!(bridge.issued_labels.seq(), 6);
+        let labels = bridge.issued_labels.since(0);
+        assert_eq!(labels[0].uri, "did:plc:alice");
+        assert_eq!(labels[1].val, "from-mastodon-example");
+        assert_eq!(labels[2].uri, "at://did:plc:alice/app.bsky.feed.post/1");
+
+        let endpoints = LabelerEndpoints::new(bridge.clone(), |_: &Request| Response::new(418));
+        let target = format!("/xrpc/{QUERY_LABELS}?uriPatterns=at://did:plc:alice/*&limit=3");
+        let response = endpoints.handle(&Request::new(Method::Get, &target));
+        let page = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        let labels = page.get("labels").and_then(Value::as_array).unwrap();
+        assert_eq!(labels.len(), 3);
+        assert_eq!(
+            labels[0].get("src"),
+            Some(&Value::from("did:web:bridge.example"))
+        );
+        assert!(labels[0].get("sig").and_then(|s| s.get("$bytes")).is_some());
+        assert_eq!(page.get("cursor"), Some(&Value::from("5")));
+
+        let unknown = format!("{target}&sources=did:plc:other");
+        let response = endpoints.handle(&Request::new(Method::Get, &unknown));
+        let page = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        assert_eq!(
+            page.get("labels")
+                .and_then(Value::as_array)
+                .map(|l| l.len()),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn labels_persist_and_encode_with_their_signatures() {
+        let dir = temp_state_dir();
+        let store = LabelStore::open(dir.clone()).unwrap();
+        let now = SystemTime::now();
+        let key = crate::repo::tests::key();
+        let label = store
+            .issue(&LABELER, "did:plc:alice", BRIDGED, &key, &HashSigner, now)
+            .unwrap();
+        let reopened = LabelStore::open(dir).unwrap().since(0);
+        assert_eq!(reopened[0].to_json(), label.to_json());
+
+        let frame = labels_frame(std::slice::from_ref(&label));
+        let (_, body) = cbor::decode(&frame).unwrap();
+        let (body, _) = cbor::decode(body).unwrap();
+        let body = body.to_json();
+        assert_eq!(body.get("seq"), Some(&Value::from(1i64)));
+        let encoded = body.get("labels").and_then(Value::as_array).unwrap()[0].clone();
+        assert_eq!(encoded, label.to_json());
+    }
+}
@@ -0,0 +1,296 @@
+//! How labelled Bluesky posts are presented on the fediverse
+//!
+//! Labels from the AppView (`porn`, `graphic-media` and so on) have no direct ActivityPub
+//! equivalent, so a [`LabelPolicy`] maps each one to an action: leave the post alone, mark it
+//! `sensitive` with a content warning `summary`, or don't bridge it at all.
+//!
+//! The policy starts from [`LabelPolicy::default`] and can be overridden with entries like
+//! `porn=sensitive:NSFW,nudity=ignore,!hide=drop`
+
+use crate::json::Value;
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A label attached to a post
+pub struct Label {
+    /// The labeler which applied it
+    pub src: Did,
+    pub val: String,
+    /// Whether this retracts an earlier label with the same value
+    pub neg: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What to do with a post carrying a label
+pub enum LabelAction {
+    Ignore,
+    /// Mark the post sensitive, with this content warning
+    Sensitive {
+        summary: String,
+    },
+    /// Don't bridge the post
+    Drop,
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors parsing a label policy
+pub enum LabelPolicyError {
+    #[error("Expected a label=action entry - found {found}")]
+    Malformed { found: String },
+    #[error("Unknown label action - found {found}")]
+    UnknownAction { found: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How a post should be presented once its labels are taken into account
+pub enum Presentation {
+    Normal,
+    /// Sensitive, with the content warnings of every label which applied
+    Sensitive {
+        summary: String,
+    },
+    Drop,
+}
+
+impl Presentation {
+    /// Apply this to an ActivityPub object
+    ///
+    /// An existing summary is kept ahead of the label warnings. Returns false if the object
+    /// shouldn't be bridged
+    pub fn apply(&self, object: &mut Value) -> bool {
+        let summary = match self {
+            Presentation::Normal => return true,
+            Presentation::Drop => return false,
+            Presentation::Sensitive { summary } => summary,
+        };
+        let Value::Object(fields) = object else {
+            return true;
+        };
+        fields.insert("sensitive".into(), Value::Bool(true));
+        let summary = match fields.get("summary").and_then(Value::as_str) {
+            Some(existing) if !existing.is_empty() => format!("{existing}; {summary}"),
+            _ => summary.clone(),
+        };
+        fields.insert("summary".into(), Value::String(summary));
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Maps label values to [`LabelAction`]s
+pub struct LabelPolicy {
+    actions: BTreeMap<String, LabelAction>,
+}
+
+impl Default for LabelPolicy {
+    /// Content warnings for Bluesky's self-labels and global moderation labels, and
+    /// dropping anything Bluesky hides
+    fn default() -> Self {
+        let sensitive = |summary: &str| LabelAction::Sensitive {
+            summary: summary.to_string(),
+        };
+        let actions = [
+            ("porn", sensitive("Adult content")),
+            ("sexual", sensitive("Sexually suggestive")),
+            ("nudity", sensitive("Nudity")),
+            ("graphic-media", sensitive("Graphic media")),
+            ("gore", sensitive("Graphic media")),
+            ("!warn", sensitive("Content warning")),
+            ("!hide", LabelAction::Drop),
+            ("!takedown", LabelAction::Drop),
+        ];
+        LabelPolicy {
+            actions: actions
+                .into_iter()
+                .map(|(label, action)| (label.to_string(), action))
+                .collect(),
+        }
+    }
+}
+
+impl LabelPolicy {
+    /// A policy which ignores every label
+    pub fn empty() -> LabelPolicy {
+        LabelPolicy {
+            actions: BTreeMap::new(),
+        }
+    }
+
+    /// Set the action for a label, replacing any existing one
+    pub fn set(&mut self, label: impl Into<String>, action: LabelAction) {
+        self.actions.insert(label.into(), action);
+    }
+
+    /// The action for a label. Labels without one are ignored
+    pub fn action(&self, label: &str) -> &LabelAction {
+        self.actions.get(label).unwrap_or(&LabelAction::Ignore)
+    }
+
+    /// Decide how to present a post with these labels
+    ///
+    /// Retracted labels don't count, and a drop anywhere wins over any warnings
+    pub fn presentation(&self, labels: &[Label]) -> Presentation {
+        let mut summaries: Vec<&str> = Vec::new();
+        for label in labels {
+            let retracted = labels
+                .iter()
+                .any(|l| l.neg && l.val == label.val && l.src == label.src);
+            if label.neg || retracted {
+                continue;
+            }
+            match self.action(&label.val) {
+                LabelAction::Ignore => {}
+                LabelAction::Drop => return Presentation::Drop,
+                LabelAction::Sensitive { summary } => {
+                    if !summaries.contains(&summary.as_str()) {
+                        summaries.push(summary);
+                    }
+                }
+            }
+        }
+        match summaries.is_empty() {
+            true => Presentation::Normal,
+            false => Presentation::Sensitive {
+                summary: summaries.join(", "),
+            },
+        }
+    }
+
+    /// Apply overrides of the form `label=action,...` on top of this policy
+    ///
+    /// The actions are `ignore`, `drop`, `sensitive` (using the label as the warning) and
+    /// `sensitive:<warning>`
+    pub fn with_overrides(mut self, overrides: &str) -> Result<LabelPolicy, LabelPolicyError> {
+        use LabelPolicyError::*;
+        for entry in overrides
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            let (label, action) = entry.split_once('=').ok_or_else(|| Malformed {
+                found: entry.to_string(),
+            })?;
+            let label = label.trim();
+            if label.is_empty() {
+                return Err(Malformed {
+                    found: entry.to_string(),
+                });
+            }
+            let action = match action.trim() {
+                "ignore" => LabelAction::Ignore,
+                "drop" => LabelAction::Drop,
+                "sensitive" => LabelAction::Sensitive {
+                    summary: label.to_string(),
+                },
+                other => match other.strip_prefix("sensitive:") {
+                    Some(summary) => LabelAction::Sensitive {
+                        summary: summary.trim().to_string(),
+                    },
+                    None => {
+                        return Err(UnknownAction {
+                            found: other.to_string(),
+                        })
+                    }
+                },
+            };
+            self.set(label, action);
+        }
+        Ok(self)
+    }
+}
+
+impl FromStr for LabelPolicy {
+    type Err = LabelPolicyError;
+
+    /// The default policy with overrides applied
+    fn from_str(s: &str) -> Result<LabelPolicy, LabelPolicyError> {
+        LabelPolicy::default().with_overrides(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use atproto::did;
+
+    const LABELER: Did = did!("did:plc:labeler");
+
+    fn label(val: &str, neg: bool) -> Label {
+        Label {
+            src: LABELER,
+            val: val.to_string(),
+            neg,
+        }
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = LabelPolicy::default();
+        assert_eq!(policy.presentation(&[]), Presentation::Normal);
+        assert_eq!(
+            policy.presentation(&[
+                label("porn", false),
+                label("graphic-media", false),
+                label("gore", false),
+                label("spam", false),
+            ]),
+            Presentation::Sensitive {
+                summary: "Adult content, Graphic media".to_string()
+            }
+        );
+        assert_eq!(
+            policy.presentation(&[label("nudity", false), label("!hide", false)]),
+            Presentation::Drop
+        );
+        // A retracted label no longer applies
+        assert_eq!(
+            policy.presentation(&[label("porn", false), label("porn", true)]),
+            Presentation::Normal
+        );
+    }
+
+    #[test]
+    fn overrides() {
+        let policy: LabelPolicy = "porn=sensitive:NSFW, nudity=ignore, spam=drop, gay=sensitive"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            policy.action("porn"),
+            &LabelAction::Sensitive {
+                summary: "NSFW".to_string()
+            }
+        );
+        assert_eq!(policy.action("nudity"), &LabelAction::Ignore);
+        assert_eq!(policy.action("spam"), &LabelAction::Drop);
+        assert_eq!(
+            policy.action("sexual"),
+            LabelPolicy::default().action("sexual")
+        );
+        assert_eq!(
+            "porn".parse::<LabelPolicy>(),
+            Err(LabelPolicyError::Malformed {
+                found: "porn".to_string()
+            })
+        );
+        assert!(matches!(
+            "porn=blur".parse::<LabelPolicy>(),
+            Err(LabelPolicyError::UnknownAction { .. })
+        ));
+    }
+
+    #[test]
+    fn applies_to_objects() {
+        let mut note = json::parse(r#"{"type": "Note", "summary": "spoilers"}"#).unwrap();
+        let presentation = Presentation::Sensitive {
+            summary: "Nudity".to_string(),
+        };
+        assert!(presentation.apply(&mut note));
+        assert_eq!(note.get("sensitive"), Some(&Value::Bool(true)));
+        assert_eq!(note.get("summary"), Some(&Value::from("spoilers; Nudity")));
+        assert!(!Presentation::Drop.apply(&mut note));
+    }
+}
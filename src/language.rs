@@ -0,0 +1,294 @@
+//! Language tags on bridged posts
+//!
+//! ActivityPub objects carry their language as the keys of `contentMap` (with a top-level
+//! `language` from some implementations), while Bluesky posts list up to three in `langs`.
+//! Both are meant to be BCP-47, but in practice tags turn up as `en_US`, `ENG` or `und`, so
+//! they're [normalized](normalize) on the way through in either direction.
+//!
+//! Accounts can choose to only have posts in [some languages](crate::store::Preferences::languages)
+//! bridged. A post's languages are the ones it declares, or failing that the one its
+//! writing system gives away ([`detect`]). Posts whose language can't be told either way are
+//! bridged, as they'd otherwise be lost to everyone writing in Latin script without tagging
+
+use crate::json::Value;
+use crate::parsing::ParseMode;
+use crate::richtext;
+use std::collections::BTreeMap;
+
+/// Most languages a Bluesky post may list
+pub const MAX_POST_LANGS: usize = 3;
+
+/// Deprecated or three-letter codes which have a preferred two-letter form
+const ALIASES: &[(&str, &str)] = &[
+    ("iw", "he"),
+    ("in", "id"),
+    ("ji", "yi"),
+    ("jw", "jv"),
+    ("mo", "ro"),
+    ("ara", "ar"),
+    ("deu", "de"),
+    ("ger", "de"),
+    ("eng", "en"),
+    ("fra", "fr"),
+    ("fre", "fr"),
+    ("ita", "it"),
+    ("jpn", "ja"),
+    ("kor", "ko"),
+    ("nld", "nl"),
+    ("dut", "nl"),
+    ("por", "pt"),
+    ("rus", "ru"),
+    ("spa", "es"),
+    ("zho", "zh"),
+    ("chi", "zh"),
+];
+
+/// A tag in canonical BCP-47 form, or `None` if it isn't one or says nothing useful
+///
+/// Underscores are accepted as separators, and subtags are recased: `en_us` becomes `en-US`
+/// and `ZH-hant-tw` becomes `zh-Hant-TW`. The undetermined and "multiple" tags are dropped
+/// as they don't help anyone filter
+pub fn normalize(tag: &str) -> Option<String> {
+    normalize_with(tag, ParseMode::Lenient)
+}
+
+/// [`normalize`] in `mode`: strictly, a tag which isn't already canonical is dropped
+pub fn normalize_with(tag: &str, mode: ParseMode) -> Option<String> {
+    let mut subtags = tag.trim().split(['-', '_']);
+    let language = subtags.next()?.to_ascii_lowercase();
+    let valid_language = matches!(language.len(), 2..=3 | 5..=8)
+        && language.bytes().all(|b| b.is_ascii_alphabetic());
+    if !valid_language || matches!(language.as_str(), "und" | "mul" | "zxx") {
+        return None;
+    }
+    let language = ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == language)
+        .map_or(language, |(_, preferred)| preferred.to_string());
+    let mut normalized = vec![language];
+    for subtag in subtags {
+        if subtag.is_empty()
+            || subtag.len() > 8
+            || !subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+        let after_singleton = normalized.iter().any(|s| s.len() == 1);
+        let is_alpha = subtag.bytes().all(|b| b.is_ascii_alphabetic());
+        let subtag = match subtag.len() {
+            // Extensions and private use keep their contents lowercase
+            _ if after_singleton => subtag.to_ascii_lowercase(),
+            4 if is_alpha => {
+                let mut script = subtag.to_ascii_lowercase();
+                script[..1].make_ascii_uppercase();
+                script
+            }
+            2 if is_alpha => subtag.to_ascii_uppercase(),
+            _ => subtag.to_ascii_lowercase(),
+        };
+        normalized.push(subtag);
+    }
+    let normalized = normalized.join("-");
+    (!mode.is_strict() || normalized == tag).then_some(normalized)
+}
+
+/// Normalize tags, dropping invalid ones and duplicates while keeping their order
+fn normalize_all<'a>(tags: impl IntoIterator<Item = &'a str>, mode: ParseMode) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags.into_iter().filter_map(|tag| normalize_with(tag, mode)) {
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    normalized
+}
+
+/// The `langs` for a Bluesky post bridged from an ActivityPub object
+///
+/// A top-level `language` comes first, followed by the `contentMap` keys
+pub fn from_ap(object: &Value) -> Vec<String> {
+    from_ap_with(object, ParseMode::Lenient)
+}
+
+/// [`from_ap`] in `mode`, normalizing tags as [`normalize_with`] does
+pub fn from_ap_with(object: &Value, mode: ParseMode) -> Vec<String> {
+    let language = object.get("language").and_then(Value::as_str);
+    let content_map = match object.get("contentMap") {
+        Some(Value::Object(map)) => map.keys().map(String::as_str).collect(),
+        _ => Vec::new(),
+    };
+    let mut langs = normalize_all(language.into_iter().chain(content_map), mode);
+    langs.truncate(MAX_POST_LANGS);
+    langs
+}
+
+/// The `contentMap` for an ActivityPub object bridged from a Bluesky post
+///
+/// The post's text is attributed to its first valid language. Posts with none get no
+/// `contentMap`, leaving receivers to guess
+pub fn to_content_map(langs: &[String], content: &str) -> Option<Value> {
+    let primary = normalize_all(langs.iter().map(String::as_str), ParseMode::Lenient)
+        .into_iter()
+        .next()?;
+    Some(Value::Object(BTreeMap::from([(
+        primary,
+        Value::from(content),
+    )])))
+}
+
+/// Writing systems which mostly belong to one language, with the ranges of their letters
+const SCRIPTS: &[(&str, &[(char, char)])] = &[
+    // Checked before Han, as Japanese mixes kanji with kana
+    ("ja", &[('\u{3040}', '\u{30ff}')]),
+    ("ko", &[('\u{1100}', '\u{11ff}'), ('\u{ac00}', '\u{d7af}')]),
+    ("zh", &[('\u{4e00}', '\u{9fff}')]),
+    ("el", &[('\u{0370}', '\u{03ff}')]),
+    ("he", &[('\u{0590}', '\u{05ff}')]),
+    ("ar", &[('\u{0600}', '\u{06ff}')]),
+    ("hi", &[('\u{0900}', '\u{097f}')]),
+    ("th", &[('\u{0e00}', '\u{0e7f}')]),
+    ("ka", &[('\u{10a0}', '\u{10ff}')]),
+    ("hy", &[('\u{0530}', '\u{058f}')]),
+];
+
+/// The language `text` is in, if its writing system says: when most of its letters are in one
+/// of a few scripts which mostly belong to one language. Latin and Cyrillic text is anyone's
+pub fn detect(text: &str) -> Option<&'static str> {
+    let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+    let in_script = |ranges: &[(char, char)]| {
+        let count = text
+            .chars()
+            .filter(|c| ranges.iter().any(|(from, to)| (from..=to).contains(&c)));
+        count.count()
+    };
+    let (language, count) = SCRIPTS
+        .iter()
+        .map(|(language, ranges)| (*language, in_script(ranges)))
+        .find(|(language, count)| *count > 0 && (*language == "ja" || *count * 2 > letters))?;
+    // Kana alone can be a few characters of decoration; Japanese text has plenty of them
+    (language != "ja" || count * 5 > letters).then_some(language)
+}
+
+/// The languages of an ActivityPub object, declared or [detected](detect)
+pub fn languages_of(object: &Value) -> Vec<String> {
+    let declared = from_ap(object);
+    if !declared.is_empty() {
+        return declared;
+    }
+    let content = object
+        .get("content")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let text = richtext::from_html(content).text;
+    detect(&text).map(str::to_string).into_iter().collect()
+}
+
+/// Whether `tag` is one of `selected`, or a more specific form of one (`en-GB` of `en`)
+fn selects(selected: &str, tag: &str) -> bool {
+    let (selected, tag) = (selected.to_ascii_lowercase(), tag.to_ascii_lowercase());
+    tag == selected
+        || tag
+            .strip_prefix(&selected)
+            .is_some_and(|rest| rest.starts_with('-'))
+}
+
+/// Whether an account which only wants posts in `selected` languages has `object` bridged
+///
+/// Every post is when nothing's selected, and so is any post whose language can't be told
+pub fn allowed(object: &Value, selected: &[String]) -> bool {
+    if selected.is_empty() {
+        return true;
+    }
+    let languages = languages_of(object);
+    languages.is_empty()
+        || languages
+            .iter()
+            .any(|tag| selected.iter().any(|selected| selects(selected, tag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn normalizes_tags() {
+        let check = |tag: &str| normalize(tag);
+        assert_eq!(check("en"), Some("en".to_string()));
+        assert_eq!(check("en_us"), Some("en-US".to_string()));
+        assert_eq!(check("ZH-hant-tw"), Some("zh-Hant-TW".to_string()));
+        assert_eq!(check("es-419"), Some("es-419".to_string()));
+        assert_eq!(check("ENG"), Some("en".to_string()));
+        assert_eq!(check("iw"), Some("he".to_string()));
+        assert_eq!(
+            check("de-CH-x-Phonebk"),
+            Some("de-CH-x-phonebk".to_string())
+        );
+        assert_eq!(check("und"), None);
+        assert_eq!(check("e"), None);
+        assert_eq!(check("en--us"), None);
+        assert_eq!(check("english language"), None);
+        assert_eq!(check(""), None);
+        // Strictly, only tags already in canonical form are kept
+        let strict = |tag| normalize_with(tag, ParseMode::Strict);
+        assert_eq!(strict("zh-Hant-TW"), Some("zh-Hant-TW".to_string()));
+        assert_eq!(strict("en_us"), None);
+        assert_eq!(strict("ENG"), None);
+    }
+
+    #[test]
+    fn ap_to_bluesky() {
+        let note = json::parse(
+            r#"{"language": "en_GB", "contentMap": {"en-gb": "Hi", "de": "Hallo", "und": "?",
+                "fr": "Salut", "ja": "やあ"}}"#,
+        )
+        .unwrap();
+        assert_eq!(from_ap(&note), vec!["en-GB", "de", "fr"]);
+        assert!(from_ap(&json::parse(r#"{"content": "Hi"}"#).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn bluesky_to_ap() {
+        let langs = vec!["xx yy".to_string(), "pt_br".to_string(), "en".to_string()];
+        let content_map = to_content_map(&langs, "Olá").unwrap();
+        assert_eq!(content_map.get("pt-BR"), Some(&Value::from("Olá")));
+        assert_eq!(to_content_map(&[], "Hi"), None);
+        // Round trips back to the same tag
+        let note = Value::object([("contentMap", content_map)]);
+        assert_eq!(from_ap(&note), vec!["pt-BR"]);
+    }
+
+    #[test]
+    fn filters_by_declared_or_detected_language() {
+        assert_eq!(detect("今日はいい天気ですね"), Some("ja"));
+        assert_eq!(detect("今天天气很好"), Some("zh"));
+        assert_eq!(detect("오늘 날씨 좋네요"), Some("ko"));
+        assert_eq!(detect("Καλημέρα"), Some("el"));
+        assert_eq!(detect("Good morning ツ"), None);
+        assert_eq!(detect("Доброе утро"), None);
+
+        let english = vec!["en".to_string()];
+        let note = |json: &str| json::parse(json).unwrap();
+        assert!(allowed(
+            &note(r#"{"contentMap": {"en-GB": "Hi"}}"#),
+            &english
+        ));
+        assert!(!allowed(
+            &note(r#"{"contentMap": {"de": "Hallo"}}"#),
+            &english
+        ));
+        assert!(!allowed(
+            &note(r#"{"content": "<p>こんにちは、世界</p>"}"#),
+            &english
+        ));
+        assert!(allowed(
+            &note(r#"{"content": "<p>Hallo Welt</p>"}"#),
+            &english
+        ));
+        assert!(allowed(&note(r#"{"contentMap": {"de": "Hallo"}}"#), &[]));
+        assert!(!allowed(
+            &note(r#"{"language": "en"}"#),
+            &["en-US".to_string()]
+        ));
+    }
+}
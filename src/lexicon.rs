@@ -0,0 +1,523 @@
+//! Checking firehose records against their lexicons
+//!
+//! Anyone can write anything to their repo, so a record off the firehose may be missing
+//! fields its lexicon requires, have them of the wrong type, or be of a collection the bridge
+//! has no schema for. Each record is [validated](validate) against the [`LEXICONS`] of the
+//! collections the bridge translates before it's translated, once [upgraded](crate::compat)
+//! from any form older lexicons allowed, and one which fails is
+//! [quarantined](admit) instead: logged with the reason to the state directory's
+//! [`QuarantineLog`] for an operator to look into, rather than bridged malformed or left to
+//! trip up translation.
+//!
+//! Only what the lexicons say of the fields the bridge reads is checked, as strictly as the
+//! bridge's [parsing modes](crate::parsing) say. String limits are
+//! checked in bytes: there's no grapheme segmentation here, and each lexicon's byte limit
+//! bounds its grapheme one. Unknown fields are allowed, as lexicons evolve by adding them
+
+use crate::bridge::Bridge;
+use crate::compat;
+use crate::digest::Network;
+use crate::json::Value;
+use crate::parsing::{ParsingConfig, Subsystem};
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339, parse_rfc3339_with};
+use crate::trace::{self, Decision, Reason};
+use atproto::at_uri::AtUri;
+use atproto::DID::Did;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// The quarantine log's file in the state directory, one JSON record per line
+pub const QUARANTINE_FILE: &str = "quarantine.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a field holds
+pub enum Kind {
+    /// A string of at most this many bytes
+    String(Option<usize>),
+    Integer,
+    Boolean,
+    /// An RFC 3339 timestamp
+    Datetime,
+    Did,
+    AtUri,
+    /// An object, with its `$type` saying which of several it is
+    Union,
+    /// A reference to a blob
+    Blob,
+    /// A `com.atproto.repo.strongRef`, the URI and CID of a record
+    StrongRef,
+    /// An object, of fields which aren't checked
+    Object,
+    /// At most this many items of a kind
+    Array(&'static Kind, Option<usize>),
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::String(_) => "string",
+            Kind::Integer => "integer",
+            Kind::Boolean => "boolean",
+            Kind::Datetime => "datetime",
+            Kind::Did => "DID",
+            Kind::AtUri => "at:// URI",
+            Kind::Union => "typed object",
+            Kind::Blob => "blob",
+            Kind::StrongRef => "strong ref",
+            Kind::Object => "object",
+            Kind::Array(..) => "array",
+        }
+    }
+
+    /// Check that `value`, the record's `field`, is of this kind
+    fn check(&self, field: &str, value: &Value, parsing: &ParsingConfig) -> Result<(), Invalid> {
+        let text = value.as_str();
+        let is = |kind_matches: bool| {
+            kind_matches
+                .then_some(())
+                .ok_or_else(|| Invalid::WrongKind {
+                    field: field.to_string(),
+                    expected: self.name(),
+                })
+        };
+        match *self {
+            Kind::String(max) => {
+                is(text.is_some())?;
+                let length = text.unwrap_or_default().len();
+                match max.filter(|&max| length > max) {
+                    Some(max) => Err(Invalid::TooLong {
+                        field: field.to_string(),
+                        max,
+                    }),
+                    None => Ok(()),
+                }
+            }
+            Kind::Integer => is(value.as_i64().is_some()),
+            Kind::Boolean => is(matches!(value, Value::Bool(_))),
+            Kind::Datetime => {
+                let mode = parsing.mode(Subsystem::Timestamps);
+                is(text.is_some_and(|text| parse_rfc3339_with(text, mode).is_ok()))
+            }
+            Kind::Did => is(text.is_some_and(|text| Did::try_create(text.to_string()).is_ok())),
+            Kind::AtUri => is(text.is_some_and(|text| AtUri::try_create(text.to_string()).is_ok())),
+            Kind::Union => is(value.get("$type").and_then(Value::as_str).is_some()),
+            Kind::Blob => {
+                let mime = value.get("mimeType").and_then(Value::as_str);
+                let link = value.get("ref").and_then(|r| r.get("$link"));
+                // Old records refer to blobs by a bare CID, rather than a typed link
+                let legacy = value
+                    .get("cid")
+                    .filter(|_| !parsing.mode(Subsystem::Lexicons).is_strict());
+                is(mime.is_some() && link.or(legacy).and_then(Value::as_str).is_some())
+            }
+            Kind::StrongRef => {
+                is(matches!(value, Value::Object(_)))?;
+                let uri = value.get("uri").unwrap_or(&Value::Null);
+                Kind::AtUri.check(&format!("{field}.uri"), uri, parsing)?;
+                let cid = value.get("cid").unwrap_or(&Value::Null);
+                Kind::String(None).check(&format!("{field}.cid"), cid, parsing)
+            }
+            Kind::Object => is(matches!(value, Value::Object(_))),
+            Kind::Array(kind, max) => {
+                let items = value.as_array();
+                is(items.is_some())?;
+                let items = items.unwrap_or_default();
+                if let Some(max) = max.filter(|&max| items.len() > max) {
+                    return Err(Invalid::TooLong {
+                        field: field.to_string(),
+                        max,
+                    });
+                }
+                items
+                    .iter()
+                    .enumerate()
+                    .try_for_each(|(i, item)| kind.check(&format!("{field}[{i}]"), item, parsing))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: Kind,
+    pub required: bool,
+}
+
+const fn required(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: true,
+    }
+}
+
+const fn optional(name: &'static str, kind: Kind) -> Field {
+    Field {
+        name,
+        kind,
+        required: false,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The schema of a collection's records
+pub struct Lexicon {
+    pub nsid: &'static str,
+    pub fields: &'static [Field],
+}
+
+/// The lexicons of every collection the bridge translates
+pub const LEXICONS: &[Lexicon] = &[
+    Lexicon {
+        nsid: "app.bsky.feed.post",
+        fields: &[
+            required("text", Kind::String(Some(3000))),
+            required("createdAt", Kind::Datetime),
+            optional("reply", Kind::Object),
+            optional("reply.root", Kind::StrongRef),
+            optional("reply.parent", Kind::StrongRef),
+            optional("embed", Kind::Union),
+            optional("facets", Kind::Array(&Kind::Object, None)),
+            optional("langs", Kind::Array(&Kind::String(None), Some(3))),
+            optional("labels", Kind::Union),
+            optional("tags", Kind::Array(&Kind::String(Some(640)), Some(8))),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.feed.like",
+        fields: &[
+            required("subject", Kind::StrongRef),
+            required("createdAt", Kind::Datetime),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.feed.repost",
+        fields: &[
+            required("subject", Kind::StrongRef),
+            required("createdAt", Kind::Datetime),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.feed.threadgate",
+        fields: &[
+            required("post", Kind::AtUri),
+            required("createdAt", Kind::Datetime),
+            optional("allow", Kind::Array(&Kind::Union, Some(5))),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.graph.follow",
+        fields: &[
+            required("subject", Kind::Did),
+            required("createdAt", Kind::Datetime),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.graph.block",
+        fields: &[
+            required("subject", Kind::Did),
+            required("createdAt", Kind::Datetime),
+        ],
+    },
+    Lexicon {
+        nsid: "app.bsky.actor.profile",
+        fields: &[
+            optional("displayName", Kind::String(Some(640))),
+            optional("description", Kind::String(Some(2560))),
+            optional("avatar", Kind::Blob),
+            optional("banner", Kind::Blob),
+            optional("labels", Kind::Union),
+            optional("pinnedPost", Kind::StrongRef),
+        ],
+    },
+];
+
+/// The lexicon of `collection`, if the bridge has one
+pub fn lexicon(collection: &str) -> Option<&'static Lexicon> {
+    LEXICONS.iter().find(|lexicon| lexicon.nsid == collection)
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+/// Why a record doesn't match its lexicon
+pub enum Invalid {
+    #[error("No lexicon for {collection}")]
+    UnknownCollection { collection: String },
+    #[error("Expected a record of type {expected} - found {found:?}")]
+    WrongType {
+        expected: &'static str,
+        found: Option<String>,
+    },
+    #[error("Missing required field {field}")]
+    Missing { field: String },
+    #[error("Expected {field} to be a {expected}")]
+    WrongKind {
+        field: String,
+        expected: &'static str,
+    },
+    #[error("{field} is longer than {max}")]
+    TooLong { field: String, max: usize },
+}
+
+/// The field at a dotted `path` in `record`
+fn field<'a>(record: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(record, |value, name| value.get(name))
+        .filter(|value| !matches!(value, Value::Null))
+}
+
+/// Check `record`, from `collection`, against that collection's lexicon, leniently
+pub fn validate(collection: &str, record: &Value) -> Result<(), Invalid> {
+    validate_with(collection, record, &ParsingConfig::default())
+}
+
+/// [`validate`], with timestamps and blobs parsed as `parsing` says
+pub fn validate_with(
+    collection: &str,
+    record: &Value,
+    parsing: &ParsingConfig,
+) -> Result<(), Invalid> {
+    let lexicon = lexicon(collection).ok_or_else(|| Invalid::UnknownCollection {
+        collection: collection.to_string(),
+    })?;
+    let found = record.get("$type").and_then(Value::as_str);
+    if found != Some(lexicon.nsid) {
+        return Err(Invalid::WrongType {
+            expected: lexicon.nsid,
+            found: found.map(str::to_string),
+        });
+    }
+    for spec in lexicon.fields {
+        match field(record, spec.name) {
+            Some(value) => spec.kind.check(spec.name, value, parsing)?,
+            None if spec.required => {
+                return Err(Invalid::Missing {
+                    field: spec.name.to_string(),
+                })
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A record kept out of translation
+pub struct Quarantined {
+    pub at: SystemTime,
+    pub did: Did,
+    /// `<collection>/<rkey>`
+    pub path: String,
+    pub reason: String,
+    pub record: Value,
+}
+
+impl Quarantined {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("did", Value::from(self.did.as_str())),
+            ("path", Value::from(self.path.as_str())),
+            ("reason", Value::from(self.reason.as_str())),
+            ("record", self.record.clone()),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Quarantined> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(Quarantined {
+            at: parse_rfc3339(field("at")?).ok()?,
+            did: Did::try_create(field("did")?.to_string()).ok()?,
+            path: field("path")?.to_string(),
+            reason: field("reason")?.to_string(),
+            record: value.get("record")?.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+/// Records which failed validation, within the retention period, oldest first
+pub struct QuarantineLog {
+    records: Mutex<Vec<Quarantined>>,
+    log: Option<JsonLog>,
+}
+
+impl QuarantineLog {
+    /// A log persisted in `dir`, with the records already there
+    pub fn open(dir: StateDir) -> io::Result<QuarantineLog> {
+        let (log, records) = JsonLog::open(dir, QUARANTINE_FILE)?;
+        let records = records.iter().filter_map(Quarantined::from_json).collect();
+        Ok(QuarantineLog {
+            records: Mutex::new(records),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, record: Quarantined) -> io::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([record.to_json()])?;
+        }
+        records.push(record);
+        Ok(())
+    }
+
+    /// The latest `limit` records, newest first
+    pub fn recent(&self, limit: usize) -> Vec<Quarantined> {
+        let records = self.records.lock().unwrap();
+        records.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Drop records older than `ttl` as of `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut records = self.records.lock().unwrap();
+        let before = records.len();
+        records.retain(|record| now.duration_since(record.at).unwrap_or_default() < ttl);
+        let pruned = before - records.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            log.rewrite(records.iter().map(Quarantined::to_json))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The record at `path` of `did`'s repo, [upgraded](crate::compat) from any older form, if
+/// it may be translated, quarantining it as it was if not
+///
+/// Failing to write the quarantine log is only logged: the record is kept out either way
+pub fn admit(bridge: &Bridge, did: &Did, path: &str, record: &Value) -> Option<Value> {
+    let collection = path.split_once('/').map_or(path, |(c, _)| c);
+    let (upgraded, _) = compat::upgrade(collection, record);
+    let Err(invalid) = validate_with(collection, &upgraded, &bridge.parsing) else {
+        return Some(upgraded);
+    };
+    let quarantined = Quarantined {
+        at: SystemTime::now(),
+        did: did.clone(),
+        path: path.to_string(),
+        reason: invalid.to_string(),
+        record: record.clone(),
+    };
+    if let Err(e) = bridge.quarantine.append(quarantined) {
+        eprintln!("Couldn't quarantine at://{did}/{path} ({invalid}): {e}");
+    }
+    let uri = format!("at://{did}/{path}");
+    let decision = Decision::skipped(Network::Bluesky, &uri, did.as_str(), Reason::Invalid);
+    trace::record(bridge, decision.with_detail(invalid.to_string()));
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::storage::tests::temp_state_dir;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    fn post(fields: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"$type": "app.bsky.feed.post", "createdAt": "2024-05-01T12:00:00Z", {fields}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_are_checked_against_their_lexicon() {
+        assert_eq!(
+            validate("app.bsky.feed.post", &post(r#""text": "hi""#)),
+            Ok(())
+        );
+        let reply = post(
+            r#""text": "hi", "reply": {"root": {"uri": "at://did:plc:bob/app.bsky.feed.post/1",
+                "cid": "bafy"}, "parent": {"uri": "not a uri", "cid": "bafy"}}"#,
+        );
+        assert_eq!(
+            validate("app.bsky.feed.post", &reply),
+            Err(Invalid::WrongKind {
+                field: "reply.parent.uri".to_string(),
+                expected: "at:// URI"
+            })
+        );
+        assert_eq!(
+            validate("app.bsky.feed.post", &post(r#""langs": ["en"]"#)),
+            Err(Invalid::Missing {
+                field: "text".to_string()
+            })
+        );
+        let long = post(&format!(r#""text": "{}""#, "a".repeat(3001)));
+        assert!(matches!(
+            validate("app.bsky.feed.post", &long),
+            Err(Invalid::TooLong { max: 3000, .. })
+        ));
+        let tags = post(r#""text": "hi", "tags": ["a", 1]"#);
+        assert!(matches!(
+            validate("app.bsky.feed.post", &tags),
+            Err(Invalid::WrongKind { field, .. }) if field == "tags[1]"
+        ));
+        // A record claiming to be of another collection
+        assert!(matches!(
+            validate("app.bsky.feed.like", &post(r#""text": "hi""#)),
+            Err(Invalid::WrongType { .. })
+        ));
+        // Blobs by a bare CID are only allowed leniently
+        let profile = json::parse(
+            r#"{"$type": "app.bsky.actor.profile", "avatar": {"cid": "bafy", "mimeType": "image/png"}}"#,
+        )
+        .unwrap();
+        assert_eq!(validate("app.bsky.actor.profile", &profile), Ok(()));
+        let strict = ParsingConfig::strict();
+        assert!(validate_with("app.bsky.actor.profile", &profile, &strict).is_err());
+        assert!(matches!(
+            validate("com.example.thing", &post(r#""text": "hi""#)),
+            Err(Invalid::UnknownCollection { .. })
+        ));
+    }
+
+    #[test]
+    fn invalid_records_are_quarantined() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            quarantine: QuarantineLog::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        let valid = post(r#""text": "hi""#);
+        assert_eq!(
+            admit(&bridge, &ALICE, "app.bsky.feed.post/1", &valid),
+            Some(valid)
+        );
+        let invalid = post(r#""text": 5"#);
+        assert!(admit(&bridge, &ALICE, "app.bsky.feed.post/2", &invalid).is_none());
+        let unknown = Value::object([("$type", Value::from("com.example.thing"))]);
+        assert!(admit(&bridge, &ALICE, "com.example.thing/3", &unknown).is_none());
+        // One in a form older lexicons allowed is upgraded rather than quarantined
+        let legacy = json::parse(
+            r#"{"$type": "app.bsky.graph.follow", "createdAt": "2023-01-10T12:00:00Z",
+                "subject": {"did": "did:plc:bob", "declarationCid": "bafy"}}"#,
+        )
+        .unwrap();
+        let admitted = admit(&bridge, &ALICE, "app.bsky.graph.follow/4", &legacy).unwrap();
+        assert_eq!(admitted.get("subject"), Some(&Value::from("did:plc:bob")));
+
+        let log = QuarantineLog::open(dir).unwrap();
+        let recent = log.recent(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[1].path, "app.bsky.feed.post/2");
+        assert_eq!(recent[1].record, invalid);
+        assert_eq!(recent[1].reason, "Expected text to be a string");
+        let later = SystemTime::now() + Duration::from_secs(86400);
+        assert_eq!(log.prune(Duration::from_secs(3600), later).unwrap(), 2);
+    }
+}
@@ -0,0 +1,291 @@
+//! Fedibridge - bridging Bluesky (atproto) and the fediverse (ActivityPub)
+//!
+//! The binary in `main.rs` wires these modules together, but they're exposed as a library so
+//! that the individual pieces can be tested and reused. So they needn't all be compiled, they're
+//! split by feature, each with those before it that it needs:
+//!
+//! - `identity`, the default: the atproto identifier types, re-exported, and their canonical
+//!   forms ([`normalize`]). [`json`] is always there
+//! - `client`: outbound HTTP ([`transport`]) and what it's built on, keys and the state
+//!   directory, shared by the clients
+//! - `xrpc-client`: resolving DIDs and reading repos and the firehose
+//! - `ap-client`: fediverse actors' keys and post content
+//! - `full-bridge`: the bridge itself - its endpoints, stores, jobs and media pipeline - which
+//!   the binary needs, with `net` to serve over sockets
+
+#[cfg(feature = "identity")]
+pub use atproto;
+
+#[cfg(feature = "full-bridge")]
+pub mod account;
+#[cfg(feature = "ap-client")]
+pub mod actorkeys;
+#[cfg(feature = "full-bridge")]
+pub mod actortype;
+#[cfg(feature = "full-bridge")]
+pub mod addressing;
+#[cfg(feature = "full-bridge")]
+pub mod admin;
+#[cfg(feature = "full-bridge")]
+pub mod alerts;
+#[cfg(feature = "full-bridge")]
+pub mod approval;
+#[cfg(feature = "full-bridge")]
+pub mod appview;
+#[cfg(feature = "full-bridge")]
+pub mod archive;
+#[cfg(feature = "full-bridge")]
+pub mod article;
+#[cfg(feature = "full-bridge")]
+pub mod attribution;
+#[cfg(feature = "full-bridge")]
+pub mod audience;
+#[cfg(feature = "full-bridge")]
+pub mod audit;
+#[cfg(feature = "client")]
+pub mod breaker;
+#[cfg(feature = "full-bridge")]
+pub mod bridge;
+#[cfg(feature = "client")]
+pub mod cache;
+#[cfg(feature = "xrpc-client")]
+pub mod car;
+#[cfg(feature = "xrpc-client")]
+pub mod cbor;
+#[cfg(feature = "chat")]
+pub mod chat;
+#[cfg(feature = "full-bridge")]
+pub mod community;
+#[cfg(feature = "full-bridge")]
+pub mod compat;
+#[cfg(feature = "full-bridge")]
+pub mod concurrency;
+#[cfg(feature = "full-bridge")]
+pub mod config;
+#[cfg(feature = "full-bridge")]
+pub mod consent;
+#[cfg(feature = "full-bridge")]
+pub mod content;
+#[cfg(feature = "full-bridge")]
+pub mod crawl;
+#[cfg(feature = "client")]
+pub mod crypto;
+#[cfg(feature = "full-bridge")]
+pub mod dedup;
+#[cfg(feature = "full-bridge")]
+pub mod delivery;
+#[cfg(feature = "full-bridge")]
+pub mod diagnose;
+#[cfg(feature = "full-bridge")]
+pub mod digest;
+#[cfg(feature = "full-bridge")]
+pub mod discovery;
+#[cfg(feature = "full-bridge")]
+pub mod dm;
+#[cfg(feature = "client")]
+pub mod dns;
+#[cfg(feature = "full-bridge")]
+pub mod doctor;
+#[cfg(feature = "full-bridge")]
+pub mod dryrun;
+#[cfg(feature = "client")]
+pub mod egress;
+#[cfg(feature = "full-bridge")]
+pub mod engagement;
+#[cfg(feature = "full-bridge")]
+pub mod enroll;
+#[cfg(feature = "full-bridge")]
+pub mod entities;
+#[cfg(feature = "full-bridge")]
+pub mod error;
+#[cfg(feature = "full-bridge")]
+pub mod export;
+#[cfg(feature = "full-bridge")]
+pub mod feed;
+#[cfg(feature = "client")]
+pub mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "full-bridge")]
+pub mod filter;
+#[cfg(feature = "xrpc-client")]
+pub mod firehose;
+#[cfg(feature = "full-bridge")]
+pub mod handles;
+#[cfg(feature = "ap-client")]
+pub mod html;
+#[cfg(feature = "client")]
+pub mod http;
+#[cfg(feature = "full-bridge")]
+pub mod identity;
+#[cfg(feature = "full-bridge")]
+pub mod image;
+#[cfg(feature = "full-bridge")]
+pub mod inbound;
+#[cfg(feature = "full-bridge")]
+pub mod ingest;
+#[cfg(feature = "full-bridge")]
+pub mod inspect;
+#[cfg(feature = "full-bridge")]
+pub mod interop;
+#[cfg(feature = "full-bridge")]
+pub mod jobs;
+pub mod json;
+#[cfg(feature = "client")]
+pub mod keys;
+#[cfg(feature = "full-bridge")]
+pub mod labeler;
+#[cfg(feature = "full-bridge")]
+pub mod labels;
+#[cfg(feature = "ap-client")]
+pub mod language;
+#[cfg(feature = "full-bridge")]
+pub mod lexicon;
+#[cfg(feature = "full-bridge")]
+pub mod linkcard;
+#[cfg(feature = "full-bridge")]
+pub mod lists;
+#[cfg(feature = "full-bridge")]
+pub mod lookalike;
+#[cfg(feature = "full-bridge")]
+pub mod mappings;
+#[cfg(feature = "ap-client")]
+pub mod markup;
+#[cfg(feature = "full-bridge")]
+pub mod media;
+#[cfg(feature = "full-bridge")]
+pub mod mentions;
+#[cfg(feature = "full-bridge")]
+pub mod metadata;
+#[cfg(feature = "full-bridge")]
+pub mod mirror;
+#[cfg(feature = "ap-client")]
+pub mod misskey;
+#[cfg(feature = "full-bridge")]
+pub mod moderation;
+#[cfg(feature = "full-bridge")]
+pub mod modstream;
+#[cfg(feature = "identity")]
+pub mod normalize;
+#[cfg(feature = "full-bridge")]
+pub mod oauth;
+#[cfg(feature = "full-bridge")]
+pub mod objects;
+#[cfg(feature = "full-bridge")]
+pub mod orphans;
+#[cfg(feature = "full-bridge")]
+pub mod parents;
+#[cfg(feature = "client")]
+pub mod parsing;
+#[cfg(feature = "full-bridge")]
+pub mod payload;
+#[cfg(feature = "full-bridge")]
+pub mod peering;
+#[cfg(feature = "full-bridge")]
+pub mod peers;
+#[cfg(feature = "full-bridge")]
+pub mod peertube;
+#[cfg(feature = "full-bridge")]
+pub mod permalink;
+#[cfg(feature = "full-bridge")]
+pub mod pinned;
+#[cfg(feature = "full-bridge")]
+pub mod policy;
+#[cfg(feature = "full-bridge")]
+pub mod polls;
+#[cfg(feature = "full-bridge")]
+pub mod preview;
+#[cfg(feature = "full-bridge")]
+pub mod profilefields;
+#[cfg(feature = "full-bridge")]
+pub mod provision;
+#[cfg(feature = "client")]
+pub mod proxy;
+#[cfg(feature = "full-bridge")]
+pub mod published;
+#[cfg(feature = "full-bridge")]
+pub mod quota;
+#[cfg(feature = "full-bridge")]
+pub mod ratelimit;
+#[cfg(feature = "full-bridge")]
+pub mod reactions;
+#[cfg(feature = "full-bridge")]
+pub mod receipts;
+#[cfg(feature = "full-bridge")]
+pub mod reconcile;
+#[cfg(feature = "full-bridge")]
+pub mod recovery;
+#[cfg(feature = "full-bridge")]
+pub mod rehosted;
+#[cfg(feature = "full-bridge")]
+pub mod replycontext;
+#[cfg(feature = "full-bridge")]
+pub mod repo;
+#[cfg(feature = "xrpc-client")]
+pub mod resolver;
+#[cfg(feature = "full-bridge")]
+pub mod resync;
+#[cfg(feature = "full-bridge")]
+pub mod retention;
+#[cfg(feature = "full-bridge")]
+pub mod retraction;
+#[cfg(feature = "ap-client")]
+pub mod richtext;
+#[cfg(feature = "full-bridge")]
+pub mod rkeys;
+#[cfg(feature = "client")]
+pub mod runtime;
+#[cfg(feature = "full-bridge")]
+pub mod sanctions;
+#[cfg(feature = "full-bridge")]
+pub mod schedule;
+#[cfg(feature = "full-bridge")]
+pub mod secrets;
+#[cfg(feature = "full-bridge")]
+pub mod seen;
+#[cfg(feature = "full-bridge")]
+pub mod shedding;
+#[cfg(feature = "client")]
+pub mod shutdown;
+#[cfg(feature = "full-bridge")]
+pub mod signatures;
+#[cfg(feature = "full-bridge")]
+pub mod snapshot;
+#[cfg(feature = "full-bridge")]
+pub mod stats;
+#[cfg(feature = "full-bridge")]
+pub mod status;
+#[cfg(feature = "client")]
+pub mod storage;
+#[cfg(feature = "full-bridge")]
+pub mod store;
+#[cfg(all(feature = "full-bridge", feature = "net"))]
+pub mod subscribe;
+#[cfg(feature = "full-bridge")]
+pub mod sync;
+#[cfg(feature = "full-bridge")]
+pub mod templates;
+#[cfg(feature = "full-bridge")]
+pub mod tenants;
+#[cfg(feature = "full-bridge")]
+pub mod threadgate;
+#[cfg(feature = "client")]
+pub mod time;
+#[cfg(feature = "client")]
+pub mod tls;
+#[cfg(feature = "full-bridge")]
+pub mod trace;
+#[cfg(feature = "full-bridge")]
+pub mod transform;
+#[cfg(feature = "client")]
+pub mod transport;
+#[cfg(feature = "full-bridge")]
+pub mod unbridge;
+#[cfg(feature = "full-bridge")]
+pub mod upstream;
+#[cfg(feature = "client")]
+pub mod url;
+#[cfg(feature = "full-bridge")]
+pub mod webhooks;
+#[cfg(feature = "full-bridge")]
+pub mod websocket;
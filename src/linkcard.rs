@@ -0,0 +1,478 @@
+//! Link cards for bridged fediverse posts
+//!
+//! Bluesky doesn't unfurl links itself: the client writing a post fetches the page's Open
+//! Graph metadata and attaches an `app.bsky.embed.external` card, thumbnail included. Posts
+//! bridged from the fediverse get the same treatment for their first bare link when
+//! [enabled](LinkCardConfig::enabled).
+//!
+//! Fetching arbitrary links on behalf of remote users is an easy way to probe the bridge's
+//! own network, so every request (redirects included) goes through [`egress::fetch`] with
+//! the bridge's [`EgressPolicy`], and is bounded by the configured timeout and size limits
+
+use crate::egress::{self, EgressPolicy};
+use crate::html::{tokenize, Token};
+use crate::json::Value;
+use crate::metadata;
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use crate::url::{Url, UrlError};
+use std::time::Duration;
+use thiserror::Error;
+
+/// The embed type for link cards
+pub const EXTERNAL: &str = "app.bsky.embed.external";
+/// The XRPC method thumbnails are uploaded with
+pub const UPLOAD_BLOB: &str = "com.atproto.repo.uploadBlob";
+const USER_AGENT: &str = concat!("fedibridge/", env!("CARGO_PKG_VERSION"));
+/// Thumbnail formats Bluesky accepts
+const THUMBNAIL_TYPES: &[&str] = &["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+#[derive(Debug, Clone, PartialEq)]
+/// Whether and how link cards are fetched
+pub struct LinkCardConfig {
+    pub enabled: bool,
+    /// Applied to each request made for a card
+    pub timeout: Duration,
+    /// Pages larger than this are abandoned
+    pub max_page_size: usize,
+    /// Thumbnails larger than this are left off the card
+    pub max_thumbnail_size: usize,
+}
+
+impl Default for LinkCardConfig {
+    fn default() -> Self {
+        LinkCardConfig {
+            enabled: false,
+            timeout: Duration::from_secs(5),
+            max_page_size: 512 * 1024,
+            max_thumbnail_size: 1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors building a link card
+pub enum LinkCardError {
+    #[error("Link cards are disabled")]
+    Disabled,
+    #[error(transparent)]
+    InvalidUrl(#[from] UrlError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Fetching {url} failed with status {status}")]
+    Status { url: String, status: u16 },
+    #[error("Expected {expected} from {url} - found {found}")]
+    UnexpectedType {
+        url: String,
+        expected: &'static str,
+        found: String,
+    },
+    #[error("Response from {url} exceeded {limit} bytes")]
+    TooLarge { url: String, limit: usize },
+    #[error("{url} has no title to make a card from")]
+    NoMetadata { url: String },
+    #[error("Blob upload failed with status {status}")]
+    UploadRejected { status: u16 },
+    #[error("Blob upload returned no blob")]
+    MalformedUpload,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An image to upload as a card's thumbnail
+pub struct Thumbnail {
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The contents of a link card, before its thumbnail is uploaded
+pub struct LinkCard {
+    /// The link as it appeared in the post, rather than wherever it redirected to
+    pub uri: String,
+    pub title: String,
+    pub description: String,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl LinkCard {
+    /// This card as an embed, with the blob returned by uploading its thumbnail
+    pub fn to_embed(&self, thumb: Option<Value>) -> Value {
+        let mut external = vec![
+            ("uri", Value::from(self.uri.as_str())),
+            ("title", Value::from(self.title.as_str())),
+            ("description", Value::from(self.description.as_str())),
+        ];
+        if let Some(thumb) = thumb {
+            external.push(("thumb", thumb));
+        }
+        Value::object([
+            ("$type", Value::from(EXTERNAL)),
+            ("external", Value::object(external)),
+        ])
+    }
+}
+
+/// The first link in a fediverse post's content which isn't a mention or hashtag
+///
+/// Posts with attachments don't get a card, as their media takes the embed
+pub fn first_bare_link(object: &Value) -> Option<String> {
+    let has_attachments = match object.get("attachment") {
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(_) => true,
+        None => false,
+    };
+    if has_attachments {
+        return None;
+    }
+    let tagged: Vec<&str> = match object.get("tag") {
+        Some(Value::Array(tags)) => tags
+            .iter()
+            .filter_map(|t| t.get("href").and_then(Value::as_str))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let content = object.get("content").and_then(Value::as_str)?;
+    tokenize(content).into_iter().find_map(|token| {
+        let is_tag = token.has_class("mention")
+            || token.has_class("hashtag")
+            || token
+                .attribute("rel")
+                .is_some_and(|rel| rel.split_ascii_whitespace().any(|r| r == "tag"));
+        let href = token.attribute("href").filter(|_| token.is_start("a"))?;
+        (!is_tag && !tagged.contains(&href) && Url::parse(href).is_ok()).then(|| href.to_string())
+    })
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The metadata a card is made from
+pub struct OpenGraph {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    /// Resolved against the page's URL
+    pub image: Option<Url>,
+}
+
+/// Read a page's Open Graph metadata, falling back to Twitter cards and plain HTML
+pub fn parse_open_graph(html: &str, page: &Url) -> OpenGraph {
+    // Metadata belongs in the head, and stopping there avoids scanning whole articles
+    let tokens = tokenize(html);
+    let head_end = tokens
+        .iter()
+        .position(|t| t.is_end("head") || t.is_start("body"))
+        .unwrap_or(tokens.len());
+    let head = &tokens[..head_end];
+    let meta = |keys: &[&str]| -> Option<String> {
+        keys.iter().find_map(|key| {
+            head.iter().filter(|t| t.is_start("meta")).find_map(|meta| {
+                let name = meta.attribute("property").or(meta.attribute("name"))?;
+                name.eq_ignore_ascii_case(key)
+                    .then(|| meta.attribute("content"))
+                    .flatten()
+                    .map(clean)
+                    .filter(|v| !v.is_empty())
+            })
+        })
+    };
+    let title_element =
+        head.iter()
+            .position(|t| t.is_start("title"))
+            .and_then(|i| match head.get(i + 1) {
+                Some(Token::Text(title)) => Some(clean(title)).filter(|t| !t.is_empty()),
+                _ => None,
+            });
+    OpenGraph {
+        title: meta(&["og:title", "twitter:title"]).or(title_element),
+        description: meta(&["og:description", "twitter:description", "description"]),
+        image: meta(&[
+            "og:image",
+            "og:image:url",
+            "og:image:secure_url",
+            "twitter:image",
+        ])
+        .and_then(|image| page.join(&image).ok()),
+    }
+}
+
+/// Collapse runs of whitespace, as a browser would
+fn clean(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// GET a public URL, following redirects as long as they stay public
+fn fetch(
+    transport: &dyn HttpTransport,
+    policy: &EgressPolicy,
+    config: &LinkCardConfig,
+    url: &str,
+    accept: &str,
+    limit: usize,
+) -> Result<(Url, OutboundResponse), LinkCardError> {
+    let request = OutboundRequest::get(url)
+        .with_header("accept", accept)
+        .with_header("user-agent", USER_AGENT)
+        .with_timeout(config.timeout);
+    let (url, response) = egress::fetch(transport, policy, &request)?;
+    if !response.is_success() {
+        return Err(LinkCardError::Status {
+            url: url.to_string(),
+            status: response.status,
+        });
+    }
+    if response.body.len() > limit {
+        return Err(LinkCardError::TooLarge {
+            url: url.to_string(),
+            limit,
+        });
+    }
+    Ok((url, response))
+}
+
+/// The media type of a response, without parameters
+fn media_type(response: &OutboundResponse) -> String {
+    let content_type = response.header("content-type").unwrap_or_default();
+    let media_type = content_type.split(';').next().unwrap_or_default();
+    media_type.trim().to_ascii_lowercase()
+}
+
+/// Fetch an image to use as a card's thumbnail, if it's public, small enough and of a type
+/// Bluesky accepts, with its [metadata] stripped
+pub fn fetch_thumbnail(
+    transport: &dyn HttpTransport,
+    policy: &EgressPolicy,
+    config: &LinkCardConfig,
+    image: &str,
+) -> Option<Thumbnail> {
+    let (_, response) = fetch(
+        transport,
+        policy,
+        config,
+        image,
+        "image/*",
+        config.max_thumbnail_size,
+    )
+    .ok()?;
+    let mime_type = media_type(&response);
+    if !THUMBNAIL_TYPES.contains(&mime_type.as_str()) {
+        return None;
+    }
+    let data = metadata::strip(&response.body, &mime_type).ok()?;
+    Some(Thumbnail { mime_type, data })
+}
+
+/// Fetch a link and build its card
+///
+/// A missing or unusable thumbnail doesn't stop the card being made, as native clients
+/// would post it without one too
+pub fn fetch_card(
+    transport: &dyn HttpTransport,
+    policy: &EgressPolicy,
+    config: &LinkCardConfig,
+    link: &str,
+) -> Result<LinkCard, LinkCardError> {
+    if !config.enabled {
+        return Err(LinkCardError::Disabled);
+    }
+    let accept = "text/html,application/xhtml+xml";
+    let (page, response) = fetch(
+        transport,
+        policy,
+        config,
+        link,
+        accept,
+        config.max_page_size,
+    )?;
+    let found = media_type(&response);
+    if found != "text/html" && found != "application/xhtml+xml" {
+        return Err(LinkCardError::UnexpectedType {
+            url: page.to_string(),
+            expected: "an HTML page",
+            found,
+        });
+    }
+    let metadata = parse_open_graph(&String::from_utf8_lossy(&response.body), &page);
+    let title = metadata.title.ok_or_else(|| LinkCardError::NoMetadata {
+        url: page.to_string(),
+    })?;
+    let thumbnail = metadata
+        .image
+        .and_then(|image| fetch_thumbnail(transport, policy, config, &image.to_string()));
+    Ok(LinkCard {
+        uri: link.to_string(),
+        title,
+        description: metadata.description.unwrap_or_default(),
+        thumbnail,
+    })
+}
+
+/// Upload a thumbnail to a PDS, returning the blob to put in the card
+pub fn upload_thumbnail(
+    transport: &dyn HttpTransport,
+    service: &str,
+    token: &str,
+    thumbnail: &Thumbnail,
+) -> Result<Value, LinkCardError> {
+    let url = format!("{}/xrpc/{UPLOAD_BLOB}", service.trim_end_matches('/'));
+    let request = OutboundRequest::post(url, thumbnail.data.clone())
+        .with_header("content-type", &thumbnail.mime_type)
+        .with_header("authorization", &format!("Bearer {token}"));
+    let response = transport.send(&request)?;
+    if !response.is_success() {
+        return Err(LinkCardError::UploadRejected {
+            status: response.status,
+        });
+    }
+    let body = crate::json::parse(&String::from_utf8_lossy(&response.body))
+        .map_err(|_| LinkCardError::MalformedUpload)?;
+    body.get("blob")
+        .cloned()
+        .ok_or(LinkCardError::MalformedUpload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::egress::EgressError;
+    use crate::http::Method;
+    use crate::json;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn finds_bare_links() {
+        let note = json::parse(
+            r#"{"content": "<p><span class=\"h-card\"><a href=\"https://a.example/@bob\" class=\"u-url mention\">@bob</a></span> see <a href=\"https://a.example/tags/rust\" rel=\"tag\">#rust</a> and <a href='https://news.example/story?a=1&amp;b=2' rel=nofollow>news.example/story</a></p>"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            first_bare_link(&note),
+            Some("https://news.example/story?a=1&b=2".to_string())
+        );
+        let with_media = json::parse(
+            r#"{"content": "<a href=\"https://news.example/\">x</a>", "attachment": [{"type": "Image"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(first_bare_link(&with_media), None);
+        let tagged = json::parse(
+            r#"{"content": "<a href=\"https://b.example/users/c\">@c</a>", "tag": [{"type": "Mention", "href": "https://b.example/users/c"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(first_bare_link(&tagged), None);
+    }
+
+    #[test]
+    fn refuses_private_destinations() {
+        // A public page can't redirect the bridge somewhere private
+        let mock = MockTransport::new();
+        mock.respond(
+            Method::Get,
+            "https://news.example/",
+            OutboundResponse::new(302).with_header("location", "http://127.0.0.1/admin"),
+        );
+        let config = LinkCardConfig {
+            enabled: true,
+            ..LinkCardConfig::default()
+        };
+        assert!(matches!(
+            fetch_card(&mock, &EgressPolicy::default(), &config, "https://news.example/"),
+            Err(LinkCardError::Transport(TransportError::Forbidden(EgressError::Private { url })))
+                if url == "http://127.0.0.1/admin"
+        ));
+        assert_eq!(mock.requests().len(), 1);
+        assert_eq!(mock.requests()[0].timeout, Some(config.timeout));
+        // Unless the configured policy allows it
+        let allowed = EgressPolicy {
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..EgressPolicy::default()
+        };
+        assert!(!matches!(
+            fetch_card(&mock, &allowed, &config, "https://news.example/"),
+            Err(LinkCardError::Transport(TransportError::Forbidden(_)))
+        ));
+        assert_eq!(mock.requests()[2].url, "http://127.0.0.1/admin");
+        assert!(matches!(
+            fetch_card(
+                &mock,
+                &EgressPolicy::default(),
+                &LinkCardConfig::default(),
+                "https://news.example/"
+            ),
+            Err(LinkCardError::Disabled)
+        ));
+    }
+
+    #[test]
+    fn builds_card_with_thumbnail() {
+        let mock = MockTransport::new();
+        mock.respond(
+            Method::Get,
+            "https://short.example/x",
+            OutboundResponse::new(301).with_header("location", "https://news.example/story"),
+        )
+        .respond(
+            Method::Get,
+            "https://news.example/story",
+            OutboundResponse::new(200)
+                .with_header("content-type", "text/html; charset=utf-8")
+                .with_body(
+                    r#"<html><head><title>Fallback</title>
+                    <meta property="og:title" content="Big &amp; important  news">
+                    <meta name=description content="What happened">
+                    <meta property="og:image" content="/img/lead.jpg"></head>
+                    <body><meta property="og:title" content="Not this"></body></html>"#,
+                ),
+        )
+        .respond(
+            Method::Get,
+            "https://news.example/img/lead.jpg",
+            OutboundResponse::new(200)
+                .with_header("content-type", "image/jpeg")
+                .with_body(vec![0xff, 0xd8, 0xff, 0xd9]),
+        )
+        .respond(
+            Method::Post,
+            "https://pds.example/xrpc/com.atproto.repo.uploadBlob",
+            OutboundResponse::new(200).with_body(
+                r#"{"blob": {"$type": "blob", "ref": {"$link": "bafythumb"}, "mimeType": "image/jpeg", "size": 3}}"#,
+            ),
+        );
+        let config = LinkCardConfig {
+            enabled: true,
+            ..LinkCardConfig::default()
+        };
+        let card = fetch_card(
+            &mock,
+            &EgressPolicy::default(),
+            &config,
+            "https://short.example/x",
+        )
+        .unwrap();
+        assert_eq!(card.uri, "https://short.example/x");
+        assert_eq!(card.title, "Big & important news");
+        assert_eq!(card.description, "What happened");
+        let thumbnail = card.thumbnail.clone().unwrap();
+        assert_eq!(thumbnail.mime_type, "image/jpeg");
+
+        let blob = upload_thumbnail(&mock, "https://pds.example/", "token", &thumbnail).unwrap();
+        let embed = card.to_embed(Some(blob));
+        assert_eq!(embed.get("$type"), Some(&Value::from(EXTERNAL)));
+        let external = embed.get("external").unwrap();
+        assert_eq!(
+            external.get("thumb").and_then(|t| t.get("size")),
+            Some(&Value::from(3u32))
+        );
+        let upload = &mock.requests_to("https://pds.example/xrpc/com.atproto.repo.uploadBlob")[0];
+        assert_eq!(upload.header("content-type"), Some("image/jpeg"));
+
+        // Thumbnails which are too large are left off rather than failing the card
+        let config = LinkCardConfig {
+            max_thumbnail_size: 2,
+            ..config
+        };
+        let card = fetch_card(
+            &mock,
+            &EgressPolicy::default(),
+            &config,
+            "https://news.example/story",
+        )
+        .unwrap();
+        assert_eq!(card.thumbnail, None);
+    }
+}
@@ -0,0 +1,464 @@
+//! Lists and starter packs
+//!
+//! A Bluesky curated list or starter pack has nothing like it on the fediverse, so a bridged
+//! Bluesky account publishing one sends a post about it instead ([`to_activity`]): its name
+//! and description, who's on it ([`members`], from the AppView) and a link to it on Bluesky.
+//! Moderation lists are for muting and blocking, so aren't announced.
+//!
+//! The other way, a bridged fediverse account can import one of Mastodon's CSV exports, of
+//! its lists or who it follows ([`parse_export`]), as curated lists in its repo ([`import`])
+//! with `POST /account/lists`. Only accounts which are on Bluesky, bridged either way, can be
+//! added; the rest are skipped
+
+use crate::addressing::Addressing;
+use crate::audience::Visibility;
+use crate::bridge::Bridge;
+use crate::delivery::ACTIVITY_JSON;
+use crate::html::escape;
+use crate::http::percent_encode;
+use crate::json::{self, Value};
+use crate::mentions::{webfinger, webfinger_link, BLUESKY_PROFILE};
+use crate::normalize;
+use crate::repo::{RepoError, Write};
+use crate::store::{Mapping, MappingStatus};
+use crate::time::{format_rfc3339, unique_millis};
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::at_uri::AtUri;
+use atproto::tid::Tid;
+use atproto::DID::Did;
+use std::time::SystemTime;
+use thiserror::Error;
+
+pub const LIST_COLLECTION: &str = "app.bsky.graph.list";
+pub const LIST_ITEM_COLLECTION: &str = "app.bsky.graph.listitem";
+pub const STARTER_PACK_COLLECTION: &str = "app.bsky.graph.starterpack";
+/// The purpose of a list of accounts worth following
+pub const CURATE_LIST: &str = "app.bsky.graph.defs#curatelist";
+const REFERENCE_LIST: &str = "app.bsky.graph.defs#referencelist";
+/// The header of Mastodon's export of who an account follows
+const FOLLOWS_HEADER: &str = "Account address";
+/// What a list imported from a follows export is called, without another name
+pub const DEFAULT_IMPORT_NAME: &str = "Imported follows";
+/// The longest name Bluesky allows a list, in characters
+const MAX_NAME_LENGTH: usize = 64;
+/// How many members a post about a list names
+const MAX_LISTED: usize = 10;
+/// How many pages of members are fetched
+const MAX_MEMBER_PAGES: usize = 10;
+
+#[derive(Debug, Error)]
+/// Errors bridging lists
+pub enum ListError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("List lookup responded with {status}")]
+    Lookup { status: u16 },
+    #[error("Nothing to import")]
+    Empty,
+    #[error(transparent)]
+    Repo(#[from] RepoError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Someone on a list
+pub struct Member {
+    pub did: Did,
+    pub handle: Option<String>,
+    pub display_name: Option<String>,
+}
+
+/// The members of the list `uri`, as far as the AppView's first pages go
+pub fn members(bridge: &Bridge, uri: &str) -> Result<Vec<Member>, ListError> {
+    let mut members = Vec::new();
+    let mut cursor: Option<String> = None;
+    for _ in 0..MAX_MEMBER_PAGES {
+        let mut url = format!(
+            "{}/xrpc/app.bsky.graph.getList?list={}&limit=100",
+            bridge.feeds.appview.trim_end_matches('/'),
+            percent_encode(uri)
+        );
+        if let Some(cursor) = &cursor {
+            url.push_str(&format!("&cursor={}", percent_encode(cursor)));
+        }
+        let response = bridge.transport.send(&OutboundRequest::get(url))?;
+        if !response.is_success() {
+            return Err(ListError::Lookup {
+                status: response.status,
+            });
+        }
+        let page = json::parse(&String::from_utf8_lossy(&response.body)).unwrap_or(Value::Null);
+        let items = page.get("items").and_then(Value::as_array);
+        members.extend(items.unwrap_or_default().iter().filter_map(|item| {
+            let subject = item.get("subject")?;
+            let field = |name| {
+                subject
+                    .get(name)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+            };
+            Some(Member {
+                did: normalize::parse_did(&field("did")?).ok()?,
+                handle: field("handle"),
+                display_name: field("displayName").filter(|name| !name.trim().is_empty()),
+            })
+        }));
+        cursor = page
+            .get("cursor")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    Ok(members)
+}
+
+/// Where the list or starter pack `uri` is on Bluesky, if it's one
+pub fn bluesky_page(uri: &AtUri) -> Option<String> {
+    let (authority, rkey) = (uri.authority(), uri.rkey()?);
+    match uri.collection().map(|c| c.as_str()) {
+        Some(LIST_COLLECTION) => Some(format!("{BLUESKY_PROFILE}/{authority}/lists/{rkey}")),
+        Some(STARTER_PACK_COLLECTION) => {
+            Some(format!("https://bsky.app/starter-pack/{authority}/{rkey}"))
+        }
+        _ => None,
+    }
+}
+
+/// A link to `member`: their bridged actor if they have one, or else their Bluesky profile
+fn member_link(bridge: &Bridge, member: &Member) -> String {
+    let bridged = bridge
+        .identities
+        .get(&member.did)
+        .filter(|m| m.status == MappingStatus::Active);
+    let href = match bridged {
+        Some(mapping) => mapping.actor,
+        None => format!("{BLUESKY_PROFILE}/{}", member.did),
+    };
+    let handle = match &member.handle {
+        Some(handle) => format!("@{handle}"),
+        None => member.did.to_string(),
+    };
+    let name = match &member.display_name {
+        Some(name) => format!("{} ({})", escape(name), escape(&handle)),
+        None => escape(&handle),
+    };
+    format!("<a href=\"{}\">{name}</a>", escape(&href))
+}
+
+/// The `Create` of a post by `author` about their list or starter pack `record`, at `uri`,
+/// with `members` on it, unless it's a list which isn't for following
+pub fn to_activity(
+    bridge: &Bridge,
+    author: &Mapping,
+    uri: &AtUri,
+    record: &Value,
+    members: &[Member],
+) -> Option<Value> {
+    let field = |name| record.get(name).and_then(Value::as_str);
+    let (kind, path) = match uri.collection().map(|c| c.as_str()) {
+        Some(LIST_COLLECTION) if matches!(field("purpose"), Some(CURATE_LIST | REFERENCE_LIST)) => {
+            ("list", "lists")
+        }
+        Some(STARTER_PACK_COLLECTION) => ("starter pack", "starter-packs"),
+        _ => return None,
+    };
+    let page = bluesky_page(uri)?;
+    let id = format!("{}/{path}/{}", author.actor, uri.rkey()?);
+    let name = field("name").unwrap_or(kind);
+    let mut content = format!(
+        "<p>New {kind}: <a href=\"{}\">{}</a></p>",
+        escape(&page),
+        escape(name)
+    );
+    if let Some(description) = field("description").filter(|d| !d.trim().is_empty()) {
+        content.push_str(&format!("<p>{}</p>", escape(description)));
+    }
+    if !members.is_empty() {
+        let listed: Vec<_> = members
+            .iter()
+            .take(MAX_LISTED)
+            .map(|member| format!("<li>{}</li>", member_link(bridge, member)))
+            .collect();
+        content.push_str(&format!("<ul>{}</ul>", listed.concat()));
+        if members.len() > MAX_LISTED {
+            content.push_str(&format!("<p>and {} more</p>", members.len() - MAX_LISTED));
+        }
+    }
+    let audience = Addressing::new(&author.actor, Visibility::Public);
+    let published = field("createdAt").map(str::to_string);
+    let mut note = vec![
+        ("id", Value::from(id.as_str())),
+        ("type", Value::from("Note")),
+        ("attributedTo", Value::from(author.actor.as_str())),
+        ("content", Value::from(content)),
+        ("url", Value::from(page.as_str())),
+        ("published", Value::from(published)),
+    ];
+    note.extend(audience.fields());
+    let mut activity = vec![
+        (
+            "@context",
+            Value::from("https://www.w3.org/ns/activitystreams"),
+        ),
+        ("id", Value::from(format!("{id}/activity"))),
+        ("type", Value::from("Create")),
+        ("actor", Value::from(author.actor.as_str())),
+        ("object", Value::object(note)),
+    ];
+    activity.extend(audience.fields());
+    Some(Value::object(activity))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A list in a CSV export, or who an account follows if it has no name
+pub struct ExportedList {
+    pub name: Option<String>,
+    /// `user@host` addresses
+    pub accounts: Vec<String>,
+}
+
+/// The fields of a line of CSV
+fn fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(String::new()),
+            (c, _) => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+/// The lists in `csv`, one of Mastodon's exports: of lists, as lines of a list name and
+/// address, or of follows, with a header and an address leading each line
+pub fn parse_export(csv: &str) -> Vec<ExportedList> {
+    let mut lines = csv
+        .lines()
+        .map(fields)
+        .filter(|f| f.iter().any(|f| !f.is_empty()));
+    let Some(first) = lines.next() else {
+        return Vec::new();
+    };
+    let address = |field: &str| {
+        let field = field.trim_start_matches('@');
+        field.split_once('@').map(|_| field.to_string())
+    };
+    if first[0] == FOLLOWS_HEADER {
+        let accounts = lines.filter_map(|f| address(&f[0])).collect();
+        return vec![ExportedList {
+            name: None,
+            accounts,
+        }];
+    }
+    let mut lists: Vec<ExportedList> = Vec::new();
+    for line in std::iter::once(first).chain(lines) {
+        let (Some(name), Some(account)) = (line.first(), line.get(1).and_then(|a| address(a)))
+        else {
+            continue;
+        };
+        match lists.iter_mut().find(|l| l.name.as_ref() == Some(name)) {
+            Some(list) => list.accounts.push(account),
+            None => lists.push(ExportedList {
+                name: Some(name.clone()),
+                accounts: vec![account],
+            }),
+        }
+    }
+    lists
+}
+
+/// The DID of the account at `address` on Bluesky, if it's bridged
+fn resolve(bridge: &Bridge, address: &str) -> Option<Did> {
+    let document = webfinger(bridge, address).ok()?;
+    let actor = webfinger_link(&document, "self", Some(ACTIVITY_JSON))?;
+    let mapping = bridge.identities.get_by_actor(actor)?;
+    (mapping.status == MappingStatus::Active).then_some(mapping.did)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A list imported into an account's repo
+pub struct Imported {
+    pub uri: String,
+    pub name: String,
+    pub added: usize,
+    /// The addresses which aren't on Bluesky
+    pub skipped: Vec<String>,
+}
+
+impl Imported {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("uri", Value::from(self.uri.as_str())),
+            ("name", Value::from(self.name.as_str())),
+            ("added", Value::from(self.added)),
+            (
+                "skipped",
+                Value::Array(
+                    self.skipped
+                        .iter()
+                        .map(|s| Value::from(s.as_str()))
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+}
+
+/// Create `list` as a curated list in `owner`'s repo, of those on it who are on Bluesky
+pub fn import(
+    bridge: &Bridge,
+    owner: &Mapping,
+    list: &ExportedList,
+    now: SystemTime,
+) -> Result<Imported, ListError> {
+    let name = list.name.as_deref().unwrap_or(DEFAULT_IMPORT_NAME);
+    let name: String = name.chars().take(MAX_NAME_LENGTH).collect();
+    let created_at = Value::from(format_rfc3339(now));
+    let rkey = || Tid::from_parts(unique_millis() * 1000, 0);
+    let list_rkey = rkey();
+    let uri = format!("at://{}/{LIST_COLLECTION}/{list_rkey}", owner.did);
+    let mut writes = vec![Write::Create {
+        path: format!("{LIST_COLLECTION}/{list_rkey}"),
+        record: Value::object([
+            ("$type", Value::from(LIST_COLLECTION)),
+            ("purpose", Value::from(CURATE_LIST)),
+            ("name", Value::from(name.as_str())),
+            ("createdAt", created_at.clone()),
+        ]),
+    }];
+    let mut skipped = Vec::new();
+    let mut added = Vec::new();
+    for account in &list.accounts {
+        match resolve(bridge, account).filter(|did| *did != owner.did) {
+            Some(did) if !added.contains(&did) => added.push(did),
+            Some(_) => {}
+            None => skipped.push(account.clone()),
+        }
+    }
+    if added.is_empty() {
+        return Err(ListError::Empty);
+    }
+    for did in &added {
+        writes.push(Write::Create {
+            path: format!("{LIST_ITEM_COLLECTION}/{}", rkey()),
+            record: Value::object([
+                ("$type", Value::from(LIST_ITEM_COLLECTION)),
+                ("subject", Value::from(did.as_str())),
+                ("list", Value::from(uri.as_str())),
+                ("createdAt", created_at.clone()),
+            ]),
+        });
+    }
+    bridge.commit(&owner.did, &writes)?;
+    Ok(Imported {
+        uri,
+        name,
+        added: added.len(),
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: &str = "https://a.example/users/alice";
+    const CAROL: &str = "https://bridge.example/ap/did:plc:carol";
+
+    #[test]
+    fn mastodon_exports_are_imported_as_lists() {
+        let exported = parse_export("Friends,carol.bsky.social@bridge.example\n\"Best, friends\",bob@b.example\nFriends,@dave@d.example\n");
+        assert_eq!(exported.len(), 2);
+        assert_eq!(
+            exported[0].accounts,
+            ["carol.bsky.social@bridge.example", "dave@d.example"]
+        );
+        assert_eq!(exported[1].name.as_deref(), Some("Best, friends"));
+        let follows = parse_export("Account address,Show boosts,Notify on new posts,Languages\nbob@b.example,true,false,\n");
+        assert_eq!(
+            follows,
+            [ExportedList {
+                name: None,
+                accounts: vec!["bob@b.example".to_string()]
+            }]
+        );
+
+        let mock = Arc::new(MockTransport::new());
+        let self_link = format!(
+            r#"{{"links": [{{"rel": "self", "type": "{ACTIVITY_JSON}", "href": "{CAROL}"}}]}}"#
+        );
+        mock.respond_json("https://bridge.example/.well-known/webfinger?resource=acct:carol.bsky.social@bridge.example", &self_link);
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_transport(mock);
+        let alice = Mapping::new(did!("did:web:bridge.example:u:alice"), ALICE);
+        bridge.identities.insert(alice.clone());
+        bridge
+            .identities
+            .insert(Mapping::new(did!("did:plc:carol"), CAROL));
+        let owner = KeyOwner::Account(alice.did.clone());
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &FakeGenerator::default())
+            .unwrap();
+
+        let imported = import(&bridge, &alice, &exported[0], SystemTime::now()).unwrap();
+        assert_eq!((imported.name.as_str(), imported.added), ("Friends", 1));
+        assert_eq!(imported.skipped, ["dave@d.example"]);
+        let items = bridge.repos.records(&alice.did, LIST_ITEM_COLLECTION);
+        assert_eq!(
+            items[0].1.get("subject"),
+            Some(&Value::from("did:plc:carol"))
+        );
+        assert_eq!(
+            items[0].1.get("list"),
+            Some(&Value::from(imported.uri.as_str()))
+        );
+        assert!(matches!(
+            import(&bridge, &alice, &exported[1], SystemTime::now()),
+            Err(ListError::Empty)
+        ));
+
+        // And a Bluesky starter pack is announced as a post
+        let carol = bridge.identities.get(&did!("did:plc:carol")).unwrap();
+        let uri =
+            AtUri::try_create(format!("at://did:plc:carol/{STARTER_PACK_COLLECTION}/3k")).unwrap();
+        let record = json::parse(
+            r#"{"name": "Birders", "list": "at://did:plc:carol/app.bsky.graph.list/3j"}"#,
+        )
+        .unwrap();
+        let members = [Member {
+            did: did!("did:plc:carol"),
+            handle: Some("carol.bsky.social".to_string()),
+            display_name: None,
+        }];
+        let activity = to_activity(&bridge, &carol, &uri, &record, &members).unwrap();
+        let content = activity
+            .get("object")
+            .and_then(|o| o.get("content")?.as_str())
+            .unwrap();
+        assert!(content
+            .contains(r#"<a href="https://bsky.app/starter-pack/did:plc:carol/3k">Birders</a>"#));
+        assert!(content.contains(&format!(
+            r#"<li><a href="{CAROL}">@carol.bsky.social</a></li>"#
+        )));
+        let list = AtUri::try_create(format!("at://did:plc:carol/{LIST_COLLECTION}/3j")).unwrap();
+        let modlist =
+            json::parse(r#"{"name": "Spam", "purpose": "app.bsky.graph.defs#modlist"}"#).unwrap();
+        assert_eq!(to_activity(&bridge, &carol, &list, &modlist, &[]), None);
+    }
+}
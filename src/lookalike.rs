@@ -0,0 +1,260 @@
+//! Handles made to look like others
+//!
+//! Canonicalizing handles ([`normalize::handle`]) makes each spelling of one handle the same,
+//! but `pаypal.example` with a Cyrillic `а` is a handle of its own, and looks like
+//! `paypal.example` wherever it's shown. So is `rnastodon.example`, at a glance. Handles are
+//! compared by their [`skeleton`]: what's left once accents are dropped and each letter or
+//! digit that's confusable with a Latin one is taken for it, as Unicode's confusables are.
+//!
+//! A handle whose skeleton is another's is a lookalike of it, and the
+//! [identity store](crate::store::IdentityStore::try_insert) won't have an account bridged
+//! or given a handle that is one of an account it already has, if it's made with characters
+//! beyond ASCII ([`deceptive`]). Those which are all ASCII, as `dare` and `clare` are, those
+//! which slip through, as the handles of Bluesky accounts change on their own, and handles
+//! mixing the letters of more than one script ([`mixed_script`]), are [`flagged`] for
+//! operators to look at
+
+use crate::json::Value;
+use crate::normalize;
+use crate::store::IdentityStore;
+use atproto::DID::Did;
+use std::collections::HashMap;
+
+/// Characters confusable with a Latin letter or digit, and what they're taken for
+const CONFUSABLES: [(char, char); 42] = [
+    // Cyrillic
+    ('а', 'a'),
+    ('в', 'b'),
+    ('ԁ', 'd'),
+    ('е', 'e'),
+    ('ё', 'e'),
+    ('һ', 'h'),
+    ('і', 'i'),
+    ('ї', 'i'),
+    ('ј', 'j'),
+    ('к', 'k'),
+    ('ӏ', 'l'),
+    ('м', 'm'),
+    ('н', 'h'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('ԛ', 'q'),
+    ('г', 'r'),
+    ('ѕ', 's'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('ԝ', 'w'),
+    ('х', 'x'),
+    ('ь', 'b'),
+    // Greek
+    ('α', 'a'),
+    ('β', 'b'),
+    ('ε', 'e'),
+    ('η', 'n'),
+    ('ι', 'i'),
+    ('κ', 'k'),
+    ('ν', 'v'),
+    ('ο', 'o'),
+    ('ρ', 'p'),
+    ('τ', 't'),
+    ('υ', 'u'),
+    ('χ', 'x'),
+    ('γ', 'y'),
+    // Latin lookalikes of Latin letters, and digits
+    ('ı', 'i'),
+    ('ɡ', 'g'),
+    ('ℓ', 'l'),
+    ('0', 'o'),
+    ('1', 'l'),
+    ('5', 's'),
+];
+/// Pairs of letters which together look like another
+const LIGATURES: [(&str, &str); 3] = [("rn", "m"), ("vv", "w"), ("cl", "d")];
+/// Characters which can't be seen
+const INVISIBLE: [char; 5] = ['\u{200b}', '\u{200c}', '\u{200d}', '\u{2060}', '\u{feff}'];
+
+/// What `handle` looks like, for it to be compared with others
+pub fn skeleton(handle: &str) -> String {
+    let handle = normalize::unicode_handle(&normalize::handle(handle));
+    let mut skeleton = String::with_capacity(handle.len());
+    for c in handle.chars() {
+        // An accent is dropped, whether combining or composed onto its letter
+        if INVISIBLE.contains(&c) || ('\u{300}'..='\u{36f}').contains(&c) {
+            continue;
+        }
+        let c = match c {
+            // Fullwidth forms of ASCII
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(u32::from(c) - 0xfee0).unwrap_or(c),
+            c => normalize::base_letter(c),
+        };
+        let confused = CONFUSABLES.iter().find(|(confusable, _)| *confusable == c);
+        skeleton.push(confused.map_or(c, |(_, latin)| *latin));
+    }
+    LIGATURES.iter().fold(skeleton, |skeleton, (pair, letter)| {
+        skeleton.replace(pair, letter)
+    })
+}
+
+/// Whether `handle` is written with characters beyond ASCII, which a lookalike made of
+/// them would be on purpose. One made only of ASCII, as `rn` for `m` or `1` for `l`, is as
+/// likely to be a name of its own
+pub fn deceptive(handle: &str) -> bool {
+    !normalize::unicode_handle(&normalize::handle(handle)).is_ascii()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Other,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c {
+        _ if !c.is_alphabetic() => None,
+        'a'..='z' | 'A'..='Z' | '\u{c0}'..='\u{24f}' => Some(Script::Latin),
+        '\u{370}'..='\u{3ff}' | '\u{1f00}'..='\u{1fff}' => Some(Script::Greek),
+        '\u{400}'..='\u{52f}' => Some(Script::Cyrillic),
+        _ => Some(Script::Other),
+    }
+}
+
+/// Whether a label of `handle` has the letters of more than one script
+pub fn mixed_script(handle: &str) -> bool {
+    let handle = normalize::unicode_handle(&normalize::handle(handle));
+    handle.split('.').any(|label| {
+        let mut scripts = label.chars().filter_map(script);
+        let first = scripts.next();
+        scripts.any(|script| Some(script) != first)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A bridged account's handle operators should look at
+pub struct Flag {
+    pub did: Did,
+    pub handle: String,
+    /// Whether it mixes scripts
+    pub mixed_script: bool,
+    /// The handles of other accounts it looks like
+    pub resembles: Vec<String>,
+}
+
+impl Flag {
+    pub fn to_json(&self) -> Value {
+        let resembles = self.resembles.iter().map(|h| Value::from(h.as_str()));
+        Value::object([
+            ("did", Value::from(self.did.as_str())),
+            ("handle", Value::from(self.handle.as_str())),
+            (
+                "shownAs",
+                Value::from(normalize::unicode_handle(&self.handle)),
+            ),
+            ("mixedScript", Value::from(self.mixed_script)),
+            ("resembles", Value::Array(resembles.collect())),
+        ])
+    }
+}
+
+/// The accounts of `store` whose handles look like others' or mix scripts, by handle
+pub fn flagged(store: &IdentityStore) -> Vec<Flag> {
+    let mappings = store.all();
+    let handles = mappings
+        .iter()
+        .filter_map(|m| Some((m.did.clone(), m.handle.clone()?)));
+    let handles: Vec<(Did, String)> = handles.collect();
+    let mut by_skeleton: HashMap<String, Vec<&str>> = HashMap::new();
+    for (_, handle) in &handles {
+        by_skeleton
+            .entry(skeleton(handle))
+            .or_default()
+            .push(handle);
+    }
+    let mut flags: Vec<Flag> = handles
+        .iter()
+        .filter_map(|(did, handle)| {
+            let alike = by_skeleton.get(&skeleton(handle)).into_iter().flatten();
+            let resembles: Vec<String> = alike
+                .filter(|other| *other != handle)
+                .map(|other| other.to_string())
+                .collect();
+            let mixed_script = mixed_script(handle);
+            (mixed_script || !resembles.is_empty()).then(|| Flag {
+                did: did.clone(),
+                handle: handle.clone(),
+                mixed_script,
+                resembles,
+            })
+        })
+        .collect();
+    flags.sort_by(|a, b| a.handle.cmp(&b.handle));
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Mapping, StoreError};
+    use atproto::did;
+
+    #[test]
+    fn lookalikes_are_refused_and_flagged() {
+        assert_eq!(skeleton("paypal.example"), skeleton("pаypal.example"));
+        assert_eq!(skeleton("mastodon.example"), skeleton("rnastodon.example"));
+        assert_eq!(skeleton("zoe.example"), skeleton("zoë.example"));
+        assert_eq!(skeleton("bob.example"), skeleton("b\u{200b}ob.example"));
+        assert_ne!(skeleton("alice.example"), skeleton("alicia.example"));
+        assert!(mixed_script("pаypal.example"));
+        assert!(mixed_script(&normalize::handle("pаypal.example")));
+        assert!(!mixed_script("paypal.example"));
+        assert!(!mixed_script("пример.example"));
+
+        let store = IdentityStore::new();
+        let mut paypal = Mapping::new(did!("did:plc:paypal"), "https://a.example/paypal");
+        paypal.handle = Some("paypal.example".to_string());
+        store.insert(paypal);
+        store.insert(Mapping::new(
+            did!("did:plc:mallory"),
+            "https://m.example/mallory",
+        ));
+        let mallory = did!("did:plc:mallory");
+        assert!(matches!(
+            store.claim_handle(&mallory, "pаypal.example"),
+            Err(StoreError::Lookalike { .. })
+        ));
+        assert!(store.claim_handle(&mallory, "mallory.example").is_ok());
+        // Nor can a lookalike come in with a new mapping
+        let mut eve = Mapping::new(did!("did:plc:eve"), "https://m.example/eve");
+        eve.handle = Some("paypaӏ.example".to_string());
+        assert!(matches!(
+            store.try_insert(eve.clone()),
+            Err(StoreError::Lookalike { .. })
+        ));
+        assert!(!store.contains(&eve.did));
+        // Though one all in ASCII is only flagged, as it may well be a name of its own
+        let mut dare = Mapping::new(did!("did:plc:dare"), "https://m.example/dare");
+        dare.handle = Some("dare.example".to_string());
+        store.try_insert(dare.clone()).unwrap();
+        let clare = Mapping::new(did!("did:plc:clare"), "https://m.example/clare");
+        store.try_insert(clare.clone()).unwrap();
+        assert!(store.claim_handle(&clare.did, "clare.example").is_ok());
+        assert_eq!(flagged(&store).len(), 2);
+        store.remove(&dare.did).unwrap();
+        store.remove(&clare.did).unwrap();
+        // The account itself can be spelled however it likes
+        let paypal = did!("did:plc:paypal");
+        assert!(store.lookalike_of(&paypal, "PayPal.example").is_none());
+        assert!(flagged(&store).is_empty());
+
+        // An account's own handle changing isn't refused, but is flagged
+        store
+            .set_handle(&mallory, Some("paypa1.example".to_string()))
+            .unwrap();
+        let flags = flagged(&store);
+        assert_eq!(flags.len(), 2);
+        assert_eq!(flags[1].handle, "paypal.example");
+        assert_eq!(flags[1].resembles, ["paypa1.example"]);
+        assert!(!flags[0].mixed_script);
+    }
+}
@@ -1,3 +1,977 @@
-fn main() {
-    println!("Hello, world!");
+use anyhow::Context;
+use atproto::DID::Did;
+use fedibridge::account::{AccountEndpoints, SignedByActor};
+use fedibridge::admin::AdminApi;
+use fedibridge::alerts::{self, Smtp};
+use fedibridge::approval::{self, ApprovalQueue};
+use fedibridge::archive::{self, EventArchive, ReplayQuery, ReplayReport};
+use fedibridge::bridge::Bridge;
+use fedibridge::concurrency;
+use fedibridge::config::Config;
+use fedibridge::consent::Terms;
+use fedibridge::crawl;
+use fedibridge::diagnose;
+use fedibridge::digest;
+use fedibridge::discovery::DiscoveryEndpoints;
+use fedibridge::dns::DnsResolver;
+use fedibridge::doctor;
+use fedibridge::dryrun::ReviewLog;
+use fedibridge::engagement;
+use fedibridge::export;
+use fedibridge::feed::FeedEndpoints;
+use fedibridge::firehose::Shard;
+use fedibridge::handles;
+use fedibridge::http::{self, Handler};
+use fedibridge::identity::IdentityEndpoints;
+use fedibridge::inbound::InboxEndpoints;
+use fedibridge::ingest::LaneConfig;
+use fedibridge::inspect;
+use fedibridge::json::Value;
+use fedibridge::keys::{KeyStore, DEFAULT_KDF_ITERATIONS};
+use fedibridge::labeler::{self, LabelerEndpoints};
+use fedibridge::mappings::{Export, Format};
+use fedibridge::moderation::ReportEndpoint;
+use fedibridge::normalize;
+use fedibridge::oauth::ClientMetadataEndpoints;
+use fedibridge::orphans::OrphanBuffer;
+use fedibridge::payload::Bounded;
+use fedibridge::peering::{self, PeeringEndpoints};
+use fedibridge::preview;
+use fedibridge::provision::{self, IdentityKind};
+use fedibridge::published::ObjectEndpoints;
+use fedibridge::ratelimit::RateLimited;
+use fedibridge::reconcile;
+use fedibridge::recovery;
+use fedibridge::rehosted::MediaEndpoints;
+use fedibridge::retention::{self, MediaStore};
+use fedibridge::sanctions;
+use fedibridge::secrets::Secrets;
+use fedibridge::shutdown::Shutdown;
+use fedibridge::snapshot;
+use fedibridge::stats::{Directions, Stats, StatsQuery};
+use fedibridge::storage::StateDir;
+use fedibridge::store::IdentityStore;
+use fedibridge::subscribe;
+use fedibridge::sync::SyncEndpoints;
+use fedibridge::tenants::{self, Tenant, TenantRouter};
+use fedibridge::time::{format_rfc3339, parse_rfc3339};
+use fedibridge::tls::RustlsConnector;
+use fedibridge::transport::StdTransport;
+use fedibridge::upstream;
+use fedibridge::webhooks;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+static TERMINATE: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn install_signal_handlers() {
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    // Only async-signal-safe work is allowed in here, so just flip the flag
+    extern "C" fn on_signal(_: i32) {
+        TERMINATE.store(true, Ordering::SeqCst);
+    }
+    // SAFETY: the handler only performs an atomic store
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_signal_handlers() {}
+
+/// Archive the state directory of a stopped bridge to `path`
+fn take_snapshot(state_dir: &StateDir, path: &str) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("Couldn't create {path}"))?;
+    let manifest = snapshot::snapshot(state_dir, BufWriter::new(file))
+        .with_context(|| format!("Couldn't snapshot to {path}"))?;
+    println!("Saved {} files to {path}", manifest.files.len());
+    Ok(())
+}
+
+/// Unpack the snapshot at `path` into an empty state directory
+fn restore_snapshot(state_dir: &StateDir, path: &str) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("Couldn't open {path}"))?;
+    let manifest = snapshot::restore(BufReader::new(file), state_dir)
+        .with_context(|| format!("Couldn't restore {path}"))?;
+    println!(
+        "Restored {} files, as of {}",
+        manifest.files.len(),
+        fedibridge::time::format_rfc3339(manifest.created_at)
+    );
+    Ok(())
+}
+
+/// Report on `shard`'s statistics, kept in `state_dir`
+fn print_stats(state_dir: &StateDir, shard: Shard) -> anyhow::Result<()> {
+    let stats = shard
+        .state_dir(state_dir)
+        .and_then(Stats::open)
+        .context("Couldn't open the statistics")?;
+    let report = stats.report(&StatsQuery::default());
+    let row = |name: &str, directions: &Directions| {
+        let (fediverse, bluesky) = (directions.fediverse, directions.bluesky);
+        println!(
+            "{name:<24} {:>10} {:>8} {:>10} {:>8}",
+            fediverse.activities, fediverse.posts, bluesky.activities, bluesky.posts
+        );
+    };
+    println!(
+        "{:<24} {:>10} {:>8} {:>10} {:>8}",
+        "", "Fediverse", "Posts", "Bluesky", "Posts"
+    );
+    for (day, directions) in &report.days {
+        row(day, directions);
+    }
+    row("Total", &report.totals);
+    if !report.instances.is_empty() {
+        println!("\nBusiest instances");
+        for (instance, directions) in &report.instances {
+            row(instance, directions);
+        }
+    }
+    if !report.failures.is_empty() {
+        println!("\nCommonest failures");
+        for (reason, count) in &report.failures {
+            println!("{count:>8} {reason}");
+        }
+    }
+    Ok(())
+}
+
+/// Write the mappings kept in `state_dir` to `path`, in the format its extension names
+fn export_mappings(state_dir: &StateDir, shard: Shard, path: &str) -> anyhow::Result<()> {
+    let identities = IdentityStore::load(state_dir).context("Couldn't load identities")?;
+    let orphans = shard
+        .state_dir(state_dir)
+        .and_then(|dir| OrphanBuffer::load(&dir))
+        .context("Couldn't load unthreaded replies")?;
+    let export = Export::collect(&identities, &orphans);
+    std::fs::write(path, export.write(Format::for_path(path)))
+        .with_context(|| format!("Couldn't write {path}"))?;
+    println!(
+        "Exported {} mappings and {} unthreaded replies to {path}",
+        export.identities.len(),
+        export.threads.len()
+    );
+    Ok(())
+}
+
+/// Merge the mappings exported to `path` into those kept in `state_dir`
+fn import_mappings(state_dir: &StateDir, shard: Shard, path: &str) -> anyhow::Result<()> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Couldn't read {path}"))?;
+    let export = Export::parse(Format::for_path(path), &contents)
+        .with_context(|| format!("Couldn't import {path}"))?;
+    let identities = IdentityStore::load(state_dir).context("Couldn't load identities")?;
+    let shard_dir = shard.state_dir(state_dir)?;
+    let orphans = OrphanBuffer::load(&shard_dir).context("Couldn't load unthreaded replies")?;
+    let report = export.import(&identities, &orphans);
+    identities
+        .save(state_dir)
+        .context("Couldn't save identities")?;
+    orphans
+        .save(&shard_dir)
+        .context("Couldn't save unthreaded replies")?;
+    println!(
+        "Added {} mappings and {} unthreaded replies; {} were already here",
+        report.added, report.threads, report.unchanged
+    );
+    for did in &report.conflicts {
+        eprintln!(
+            "Left out {}, which conflicts with a mapping already here",
+            did.as_str()
+        );
+    }
+    Ok(())
+}
+
+fn print_car(path: &str) -> anyhow::Result<()> {
+    let archive = std::fs::read(path).with_context(|| format!("Couldn't read {path}"))?;
+    println!("{}", inspect::car(&archive)?);
+    Ok(())
+}
+
+fn print_diagnosis(bridge: &Bridge, identity: &str) -> anyhow::Result<()> {
+    let report = diagnose::diagnose(bridge, identity);
+    for step in &report.steps {
+        println!(
+            "{:<8} {:<13} {}",
+            step.outcome.as_str(),
+            step.name,
+            step.detail
+        );
+    }
+    if !report.passed() {
+        anyhow::bail!("{identity} can't be followed through the bridge");
+    }
+    Ok(())
+}
+
+/// Make `domain` the handle of the bridged account `did`, in a stopped bridge, once the domain
+/// names it
+fn switch_handle(
+    bridge: &Bridge,
+    state_dir: &StateDir,
+    did: &str,
+    domain: &str,
+) -> anyhow::Result<()> {
+    let did = normalize::parse_did(did).map_err(|e| anyhow::anyhow!("{did}: {e}"))?;
+    let instructions = handles::instructions(&did, domain)?;
+    println!(
+        "To make {} the handle of {did}, either:",
+        instructions.domain
+    );
+    println!(
+        "  Add a TXT record at {} of {}",
+        instructions.txt_name(),
+        instructions.txt_value()
+    );
+    println!(
+        "  Serve {did} as plain text at {}",
+        instructions.well_known_url()
+    );
+    println!("Waiting for either to be seen...");
+    let verified_by = handles::poll(
+        bridge,
+        &did,
+        domain,
+        Duration::from_secs(30),
+        Duration::from_secs(60 * 60),
+    )?;
+    bridge
+        .identities
+        .save(state_dir)
+        .context("Couldn't save the identity store")?;
+    println!(
+        "{did} is now {}, verified by {}",
+        instructions.domain,
+        verified_by.as_str()
+    );
+    Ok(())
+}
+
+fn print_approvals(state_dir: &StateDir) -> anyhow::Result<()> {
+    let approvals =
+        ApprovalQueue::open(state_dir.clone()).context("Couldn't load the approval queue")?;
+    if approvals.is_empty() {
+        println!("Nobody is waiting for approval");
+    }
+    for application in approvals.all() {
+        let mapping = &application.mapping;
+        println!(
+            "{}  {}  {}  since {}",
+            mapping.did.as_str(),
+            mapping.actor,
+            mapping.handle.as_deref().unwrap_or("-"),
+            format_rfc3339(application.requested_at)
+        );
+    }
+    Ok(())
+}
+
+fn decide_approval(
+    bridge: &Bridge,
+    state_dir: &StateDir,
+    approve: bool,
+    did: &str,
+) -> anyhow::Result<()> {
+    let did = normalize::parse_did(did).map_err(|e| anyhow::anyhow!("{did}: {e}"))?;
+    let waiting = || format!("{did} isn't waiting for approval");
+    if !approve {
+        approval::reject(bridge, &did)?.with_context(waiting)?;
+        println!("Rejected {did}");
+        return Ok(());
+    }
+    let mapping = approval::approve(bridge, &did)?.with_context(waiting)?;
+    bridge
+        .identities
+        .save(state_dir)
+        .context("Couldn't save the identity store")?;
+    println!("{did} is now bridged as {}", mapping.actor);
+    Ok(())
+}
+
+/// Impose or lift a sanction, in a stopped bridge, as `command` says
+fn sanction(bridge: &Bridge, state_dir: &StateDir, command: &[&str]) -> anyhow::Result<()> {
+    let parse_did =
+        |did: &str| normalize::parse_did(did).map_err(|e| anyhow::anyhow!("{did}: {e}"));
+    let now = SystemTime::now();
+    match command {
+        ["suspend", did, reason @ ..] => {
+            let reason = (!reason.is_empty()).then(|| reason.join(" "));
+            let suspension = sanctions::suspend(bridge, &parse_did(did)?, reason.as_deref(), now)?;
+            println!("Suspended {}", suspension.did);
+        }
+        ["unsuspend", did] => {
+            let did = parse_did(did)?;
+            sanctions::unsuspend(bridge, &did)?
+                .with_context(|| format!("{did} isn't suspended"))?;
+            println!("{did} is no longer suspended");
+        }
+        ["defederate", domain, rest @ ..] => {
+            let purge = rest.first() == Some(&"--purge");
+            let reason = rest.get(usize::from(purge)..).unwrap_or_default();
+            let reason = (!reason.is_empty()).then(|| reason.join(" "));
+            let defederation =
+                sanctions::defederate(bridge, domain, purge, reason.as_deref(), now)?;
+            println!("Defederated {}", defederation.domain);
+        }
+        ["refederate", domain] => {
+            sanctions::refederate(bridge, domain)?
+                .with_context(|| format!("{domain} isn't defederated"))?;
+            println!("Federating with {domain} again");
+        }
+        _ => unreachable!("Not a sanction"),
+    }
+    bridge
+        .identities
+        .save(state_dir)
+        .context("Couldn't save the identity store")
+}
+
+/// Replay the archived events `args` pick out (`--since`, `--until`, `--actor`) through the
+/// bridge's pipeline
+fn replay_events(bridge: &Bridge, args: &[&str]) -> anyhow::Result<ReplayReport> {
+    anyhow::ensure!(
+        bridge.archive.is_some(),
+        "FEDIBRIDGE_ARCHIVE_EVENTS isn't set, so there's nothing to replay"
+    );
+    let mut query = ReplayQuery::default();
+    let time = |time: &str| parse_rfc3339(time).with_context(|| format!("{time}: not a time"));
+    for option in args.chunks(2) {
+        match option {
+            ["--since", since] => query.since = Some(time(since)?),
+            ["--until", until] => query.until = Some(time(until)?),
+            ["--actor", actor] => query.actor = Some(actor.to_string()),
+            _ => anyhow::bail!(
+                "Usage: fedibridge replay [--since <time>] [--until <time>] [--actor <did|actor>]"
+            ),
+        }
+    }
+    Ok(archive::replay(bridge, &query, bridge))
+}
+
+/// Everything the bridge holds about `account`, a DID or a handle
+fn export_account(bridge: &Bridge, account: &str) -> anyhow::Result<Value> {
+    let did = match account.strip_prefix('@').unwrap_or(account) {
+        did if did.starts_with("did:") => {
+            normalize::parse_did(did).map_err(|e| anyhow::anyhow!("{did}: {e}"))?
+        }
+        handle => {
+            let mapping = bridge.identities.get_by_handle(handle);
+            mapping
+                .with_context(|| format!("No account has the handle {handle}"))?
+                .did
+        }
+    };
+    export::export(bridge, &did).with_context(|| format!("No mapping exists for {did}"))
+}
+
+/// Write the export of `account` to `path`, as a CAR archive if it ends in `.car`, or print it
+fn print_export(bridge: &Bridge, account: &str, path: Option<&str>) -> anyhow::Result<()> {
+    let export = export_account(bridge, account)?;
+    let Some(path) = path else {
+        println!("{export}");
+        return Ok(());
+    };
+    let contents = match path.ends_with(".car") {
+        true => export::to_car(&export),
+        false => export.to_string().into_bytes(),
+    };
+    std::fs::write(path, contents).with_context(|| format!("Couldn't write {path}"))?;
+    println!("Exported {account} to {path}");
+    Ok(())
+}
+
+fn print_checkup(
+    bridge: &Bridge,
+    hostname: Option<&str>,
+    state_dir: &StateDir,
+) -> anyhow::Result<()> {
+    let checkup = doctor::checkup(bridge, hostname, state_dir);
+    for check in &checkup.checks {
+        println!(
+            "{:<8} {:<10} {}",
+            check.outcome.as_str(),
+            check.name,
+            check.detail
+        );
+        if let Some(fix) = &check.fix {
+            println!("{:<19} Fix: {fix}", "");
+        }
+    }
+    if !checkup.passed() {
+        anyhow::bail!("Some checks failed");
+    }
+    Ok(())
+}
+
+/// Open the state directory `config` names
+fn open_state_dir(config: &Config) -> anyhow::Result<StateDir> {
+    StateDir::open(&config.state_dir).with_context(|| {
+        format!(
+            "Couldn't open state directory {}",
+            config.state_dir.display()
+        )
+    })
+}
+
+/// The bridge `config` describes, with its state restored from `state_dir`
+fn build(config: &Config, state_dir: &StateDir) -> anyhow::Result<Bridge> {
+    let material = config
+        .tls
+        .load()
+        .context("Couldn't load the TLS configuration")?;
+    let tls = Arc::new(RustlsConnector::new(&material).context("Couldn't set up TLS")?);
+    // DNS-over-HTTPS queries go through a transport of their own, as the bridge's needs them
+    // answered before it can send anything
+    let doh = StdTransport::default()
+        .with_tls(tls.clone())
+        .with_proxies(config.proxies.clone());
+    let dns = Arc::new(DnsResolver::new(config.dns.clone(), Some(Arc::new(doh))));
+    let mut bridge = Bridge::load(state_dir, config.shard)
+        .context("Couldn't load bridge state")?
+        .with_filter(config.firehose_filter.clone())
+        .with_moderation(config.moderation.clone())
+        .with_label_policy(config.label_policy.clone())
+        .with_max_clock_skew(config.max_clock_skew)
+        .with_parsing(config.parsing.clone())
+        .with_link_cards(config.link_cards.clone())
+        .with_articles(config.articles.clone())
+        .with_attribution(config.attribution.clone())
+        .with_html_profile(config.html_profile)
+        .with_reply_context(config.reply_context)
+        .with_templates(config.templates.clone())
+        .with_reactions(config.reactions.clone())
+        .with_image_limits(config.images.clone())
+        .with_dm_policy(config.dms.clone())
+        .with_reply_gates(config.reply_gates)
+        .with_digests(config.digests.clone())
+        .with_reconcile(config.reconcile)
+        .with_quotas(config.quotas)
+        .with_orphan_window(config.orphan_window)
+        .with_retraction_window(config.retraction_window)
+        .with_document_cache(config.cache.clone())
+        .with_content_filters(config.content_filters.filters())
+        .with_key_cache_ttl(config.key_cache_ttl)
+        .with_key_overlap(config.key_overlap)
+        .with_signature_policies(config.signatures.clone())
+        .with_webhooks(config.webhooks.clone())
+        .with_crawl(config.crawl.clone())
+        .with_oauth(config.oauth.clone())
+        .with_feeds(config.feeds.clone())
+        .with_other_bridges(config.other_bridges.clone())
+        .with_approval_required(config.require_approval)
+        .with_community_strategy(config.community_strategy)
+        .with_mirror_only_to(config.mirror_to)
+        .with_peering(config.peering.clone())
+        .with_transport(Arc::new(
+            StdTransport::default()
+                .with_pool(config.connections)
+                .with_tls(tls)
+                .with_proxies(config.proxies.clone())
+                .with_dns(dns.clone())
+                .with_egress(config.egress.clone()),
+        ))
+        .with_circuit_breakers(config.breakers)
+        .with_delivery_concurrency(config.concurrency)
+        .with_shedding(config.shedding)
+        .with_upstreams(config.upstreams.clone())
+        .with_dns(dns)
+        .with_egress(config.egress.clone())
+        .with_media_store(
+            MediaStore::open(state_dir).context("Couldn't open media store")?,
+            config.retention.clone(),
+        );
+    bridge
+        .policy
+        .merge(config.policy.clone())
+        .context("Couldn't save the federation policy")?;
+    bridge
+        .jobs
+        .set_capacity(config.job_capacity)
+        .context("Couldn't resize the job queue")?;
+    if config.dry_run {
+        let log = config
+            .shard
+            .state_dir(state_dir)
+            .and_then(ReviewLog::open)
+            .context("Couldn't open the dry run's review log")?;
+        eprintln!("Dry run: writes to either network are captured for review, not sent");
+        bridge = bridge.with_dry_run(log);
+    }
+    if config.archive_events {
+        let archive = config
+            .shard
+            .state_dir(state_dir)
+            .and_then(EventArchive::open)
+            .context("Couldn't open the event archive")?;
+        bridge = bridge.with_archive(archive);
+    }
+    if let Some(path) = &config.templates_file {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read the templates from {}", path.display()))?;
+        let templates = bridge
+            .templates
+            .clone()
+            .merge_json(&contents)
+            .with_context(|| format!("Couldn't read the templates from {}", path.display()))?;
+        bridge = bridge.with_templates(templates);
+    }
+    if let Some((version, path)) = &config.terms {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read the terms from {}", path.display()))?;
+        bridge = bridge.with_terms(Terms {
+            version: version.clone(),
+            text,
+        });
+    }
+    match &config.keystore_passphrase {
+        Some(passphrase) => {
+            let keys = KeyStore::open(state_dir.clone(), passphrase, DEFAULT_KDF_ITERATIONS)
+                .context("Couldn't open keystore")?;
+            bridge = bridge.with_keys(keys);
+        }
+        None => eprintln!("No keystore passphrase configured, keys won't be persisted"),
+    }
+    match &config.hostname {
+        Some(hostname) if config.labeler => {
+            bridge = bridge.with_labeler(Did::did_web(hostname, &[])?);
+        }
+        None if config.labeler => {
+            eprintln!("FEDIBRIDGE_LABELER is set but FEDIBRIDGE_HOSTNAME isn't, so nothing will be labelled");
+        }
+        _ => {}
+    }
+    match provision::backend(&config.identity, config.hostname.as_deref()) {
+        Ok(backend) => bridge = bridge.with_identity_backend(backend),
+        Err(e) if config.identity.backend != IdentityKind::default() => {
+            eprintln!("No identities can be made for bridged accounts: {e}");
+        }
+        Err(_) => {}
+    }
+    peering::ready(&bridge).context("FEDIBRIDGE_PEERS is set, but the bridge can't peer")?;
+    // Deliveries are never sent unsigned, as servers would only refuse them
+    if bridge.request_signer.is_none() && !config.dry_run {
+        eprintln!("No request signer is configured, so nothing will be delivered to the fediverse");
+    }
+    Ok(bridge)
+}
+
+/// What a bridge serves, and where
+struct Endpoints {
+    public: Option<(SocketAddr, Arc<dyn Handler>)>,
+    admin: Option<(SocketAddr, Arc<dyn Handler>)>,
+}
+
+/// Set `bridge` to work until `shutdown`, returning the endpoints it's to serve
+fn start(
+    config: &Config,
+    state_dir: StateDir,
+    bridge: Bridge,
+    shutdown: &Arc<Shutdown>,
+) -> anyhow::Result<Endpoints> {
+    state_dir
+        .stamp_format()
+        .context("Couldn't record the state directory's version")?;
+    let bridge = Arc::new(bridge);
+    if let Some(labeler) = &bridge.labeler {
+        if let Err(e) = labeler::declare(&bridge, labeler) {
+            eprintln!("Couldn't declare the labeler: {e}");
+        }
+    }
+    let shard_dir = config
+        .shard
+        .state_dir(&state_dir)
+        .context("Couldn't open the shard's state directory")?;
+    if recovery::begin(&shard_dir).context("Couldn't mark the shard as running")? {
+        let recovered = recovery::recover(&bridge).context("Couldn't recover")?;
+        println!(
+            "Recovered from an unclean shutdown: {}",
+            recovered.to_json()
+        );
+    }
+    bridge.flush_on_shutdown(shutdown, state_dir);
+    if config.digests.enabled {
+        digest::spawn(bridge.clone(), shutdown.clone());
+    }
+    if config.reconcile.enabled {
+        reconcile::spawn(bridge.clone(), shutdown.clone());
+    }
+    engagement::spawn(bridge.clone(), shutdown.clone());
+    upstream::spawn(bridge.clone(), shard_dir.clone(), shutdown.clone());
+    retention::spawn(bridge.clone(), shutdown.clone());
+    concurrency::spawn_workers(
+        bridge.jobs.clone(),
+        bridge.clone(),
+        bridge.concurrency.clone(),
+        shutdown.clone(),
+    );
+    if config.alerts.is_enabled() {
+        let mut alerting = config.alerts.clone();
+        if let Some(path) = &config.alert_template {
+            alerting.body = std::fs::read_to_string(path).with_context(|| {
+                format!("Couldn't read the alert template from {}", path.display())
+            })?;
+        }
+        let smtp = alerting.smtp.clone().expect("checked it's enabled");
+        alerts::spawn(
+            bridge.clone(),
+            alerting,
+            Arc::new(Smtp(smtp)),
+            shutdown.clone(),
+        );
+    }
+    if !config.webhooks.urls.is_empty() {
+        webhooks::spawn(bridge.clone(), shutdown.clone());
+    }
+    if !config.peering.peers.is_empty() {
+        peering::spawn(bridge.clone(), shutdown.clone());
+    }
+    if !config.upstreams.relays.is_empty() {
+        if config.shard.count() > 1 {
+            println!("Consuming firehose shard {}", config.shard);
+        }
+        subscribe::spawn(bridge.clone(), LaneConfig::default(), shutdown.clone());
+    }
+    match &config.hostname {
+        Some(hostname) if !config.crawl.relays.is_empty() => {
+            crawl::spawn(bridge.clone(), hostname.clone(), shutdown.clone());
+        }
+        None if !config.crawl.relays.is_empty() => {
+            eprintln!("Relays are configured but FEDIBRIDGE_HOSTNAME isn't, so they won't be asked to crawl");
+        }
+        _ => {}
+    }
+    // Registered last, so the mark is only cleared once everything else has flushed
+    shutdown.on_shutdown("running mark", move || Ok(recovery::finish(&shard_dir)?));
+
+    // A signature can't be checked without a verifier, so rather than refuse every signed
+    // request, fediverse accounts don't authenticate by them at all
+    let signed_by_actor = SignedByActor::new(&bridge);
+    if signed_by_actor.is_err() && config.listen.is_some() {
+        eprintln!("No signature verifier is configured, so inboxes refuse every delivery and fediverse accounts can't use the self-service API");
+    }
+    let public = config.listen.map(|listen| {
+        let accounts = AccountEndpoints::new(
+            bridge.clone(),
+            PeeringEndpoints::new(
+                bridge.clone(),
+                config.hostname.clone(),
+                InboxEndpoints::new(
+                    bridge.clone(),
+                    config.hostname.clone(),
+                    ReportEndpoint::new(bridge.clone()),
+                ),
+            ),
+        );
+        let accounts = match signed_by_actor {
+            Ok(signed) => accounts.with_authenticator(Arc::new(signed)),
+            Err(_) => accounts,
+        };
+        let handler: Arc<dyn Handler> = Arc::new(
+            RateLimited::new(
+                Bounded::new(
+                    LabelerEndpoints::new(
+                        bridge.clone(),
+                        FeedEndpoints::new(
+                            bridge.clone(),
+                            config.hostname.clone(),
+                            IdentityEndpoints::new(
+                                bridge.clone(),
+                                config.hostname.clone(),
+                                DiscoveryEndpoints::new(
+                                    bridge.clone(),
+                                    config.hostname.clone(),
+                                    ClientMetadataEndpoints::new(
+                                        bridge.clone(),
+                                        config.hostname.clone(),
+                                        SyncEndpoints::new(
+                                            bridge.clone(),
+                                            ObjectEndpoints::new(
+                                                bridge.clone(),
+                                                config.hostname.clone(),
+                                                MediaEndpoints::new(bridge.clone(), accounts)
+                                                    .with_cdn(config.media_cdn.clone()),
+                                            ),
+                                        ),
+                                    ),
+                                )
+                                .with_about(config.about.clone()),
+                            ),
+                        ),
+                    ),
+                    config.inbox_limits,
+                ),
+                config.rate_limits.clone(),
+            )
+            .with_trace(bridge.clone()),
+        );
+        (listen, handler)
+    });
+    let admin = config.admin.as_ref().map(|admin| {
+        let handler: Arc<dyn Handler> =
+            Arc::new(AdminApi::new(bridge.clone(), admin.token.clone()));
+        (admin.listen, handler)
+    });
+    Ok(Endpoints { public, admin })
+}
+
+/// Start each tenant's bridge, and serve them all
+fn run_tenants(tenants: &[Tenant]) -> anyhow::Result<()> {
+    let shutdown = Shutdown::new();
+    install_signal_handlers();
+    let mut sites = Vec::new();
+    for tenant in tenants {
+        let state_dir = open_state_dir(&tenant.config)?;
+        let bridge = build(&tenant.config, &state_dir)
+            .with_context(|| format!("Couldn't start {}", tenant.domain))?;
+        println!("Bridging {}", tenant.domain);
+        let endpoints = start(&tenant.config, state_dir, bridge, &shutdown)?;
+        sites.push((Some(tenant.domain.clone()), endpoints));
+    }
+    let timeout = tenants.iter().map(|t| t.config.shutdown_timeout).max();
+    run(sites, shutdown, timeout.unwrap_or_default())
+}
+
+/// Serve the endpoints of `sites`, by tenant if they're tenants', until terminated, then shut
+/// down within `timeout`
+fn run(
+    sites: Vec<(Option<String>, Endpoints)>,
+    shutdown: Arc<Shutdown>,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    type Served = Vec<(Option<String>, Arc<dyn Handler>)>;
+    let mut listening: Vec<(SocketAddr, &str, Served)> = Vec::new();
+    for (domain, endpoints) in sites {
+        let endpoints = [
+            ("Public endpoints", endpoints.public),
+            ("Admin API", endpoints.admin),
+        ];
+        for (name, (listen, handler)) in endpoints
+            .into_iter()
+            .filter_map(|(name, endpoint)| Some((name, endpoint?)))
+        {
+            match listening
+                .iter_mut()
+                .find(|(at, of, _)| (*at, *of) == (listen, name))
+            {
+                Some((_, _, served)) => served.push((domain.clone(), handler)),
+                None => listening.push((listen, name, vec![(domain.clone(), handler)])),
+            }
+        }
+    }
+    let mut servers = Vec::new();
+    for (listen, name, served) in listening {
+        let listener = TcpListener::bind(listen)
+            .with_context(|| format!("Couldn't bind {name} to {listen}"))?;
+        let domains: Vec<&str> = served.iter().filter_map(|(d, _)| d.as_deref()).collect();
+        match domains.is_empty() {
+            true => println!("{name} listening on {listen}"),
+            false => println!("{name} of {} listening on {listen}", domains.join(", ")),
+        }
+        let handler: Arc<dyn Handler> = match &served[..] {
+            [(None, handler)] => handler.clone(),
+            _ => {
+                let router =
+                    served
+                        .iter()
+                        .fold(TenantRouter::new(), |router, (domain, handler)| {
+                            router
+                                .with_tenant(domain.as_deref().unwrap_or_default(), handler.clone())
+                        });
+                Arc::new(router)
+            }
+        };
+        let shutdown = shutdown.clone();
+        servers.push(thread::spawn(move || {
+            http::serve(listener, handler, shutdown)
+        }));
+    }
+
+    while !TERMINATE.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    println!("Shutting down");
+    let report = shutdown.run(timeout);
+    for server in servers {
+        if let Ok(Err(e)) = server.join() {
+            eprintln!("Server error: {e}");
+        }
+    }
+    if !report.drained {
+        eprintln!("Shutdown deadline passed with work still in flight");
+    }
+    for (name, error) in &report.failed_hooks {
+        eprintln!("Failed to flush {name}: {error:#}");
+    }
+    anyhow::ensure!(report.is_clean(), "Unclean shutdown");
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let env = |var: &str| std::env::var(var).ok();
+    let secrets = Secrets::from_vars(env)?;
+    let hosted = tenants::from_vars_with_secrets(env, &secrets)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let config = match tenants::selected(&hosted, env)? {
+        Some(tenant) => tenant.config.clone(),
+        None if hosted.is_empty() => Config::from_vars_with_secrets(env, &secrets)?,
+        None => {
+            anyhow::ensure!(
+                args.is_empty(),
+                "Set FEDIBRIDGE_TENANT to the tenant to run this against"
+            );
+            return run_tenants(&hosted);
+        }
+    };
+    let state_dir = open_state_dir(&config)?;
+    let mut diagnosing = None;
+    let mut previewing = None;
+    let mut doctoring = false;
+    let mut handling = None;
+    let mut deciding = None;
+    let mut sanctioning = None;
+    let mut inspecting = None;
+    let mut replaying = None;
+    let mut exporting = None;
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {}
+        ["snapshot", path] => return take_snapshot(&state_dir, path),
+        ["restore", path] => return restore_snapshot(&state_dir, path),
+        ["diagnose", identity] => diagnosing = Some(identity),
+        ["inspect", "car", path] => return print_car(path),
+        ["inspect", what @ ("did" | "record" | "object"), target] => {
+            inspecting = Some((what, target))
+        }
+        ["preview", url] => previewing = Some(url),
+        ["doctor"] => doctoring = true,
+        ["stats"] => return print_stats(&state_dir, config.shard),
+        ["replay", ref options @ ..] => replaying = Some(options.to_vec()),
+        ["export", account] => exporting = Some((account, None)),
+        ["export", account, path] => exporting = Some((account, Some(path))),
+        ["export-mappings", path] => return export_mappings(&state_dir, config.shard, path),
+        ["import-mappings", path] => return import_mappings(&state_dir, config.shard, path),
+        ["handle", did, domain] => handling = Some((did, domain)),
+        ["approvals"] => return print_approvals(&state_dir),
+        ["approve", did] => deciding = Some((true, did)),
+        ["reject", did] => deciding = Some((false, did)),
+        ["suspend", _, ..] | ["unsuspend", _] | ["defederate", _, ..] | ["refederate", _] => {
+            sanctioning = Some(&args)
+        }
+        _ => anyhow::bail!(
+            "Usage: fedibridge [snapshot <archive> | restore <archive> | diagnose <identity> | inspect did|record|object|car <target> | preview <url> | doctor | handle <did> <domain> | approvals | approve <did> | reject <did> | suspend <did> [reason] | unsuspend <did> | defederate <domain> [--purge] [reason] | refederate <domain> | stats | replay [--since <time>] [--until <time>] [--actor <id>] | export <did|handle> [path] | export-mappings <path> | import-mappings <path>]"
+        ),
+    }
+    let bridge = build(&config, &state_dir)?;
+    if let Some(identity) = diagnosing {
+        return print_diagnosis(&bridge, identity);
+    }
+    if let Some((what, target)) = inspecting {
+        let inspected = match what {
+            "did" => inspect::did_document(&bridge, target),
+            "record" => inspect::record(&bridge, target),
+            _ => inspect::object(&bridge, target),
+        };
+        println!("{}", inspected?);
+        return Ok(());
+    }
+    if let Some(url) = previewing {
+        let preview = preview::preview(&bridge, url, SystemTime::now())?;
+        println!("{}", preview.to_json());
+        return Ok(());
+    }
+    if doctoring {
+        return print_checkup(&bridge, config.hostname.as_deref(), &state_dir);
+    }
+    if let Some((did, domain)) = handling {
+        return switch_handle(&bridge, &state_dir, did, domain);
+    }
+    if let Some((approve, did)) = deciding {
+        return decide_approval(&bridge, &state_dir, approve, did);
+    }
+    if let Some(args) = sanctioning {
+        let command: Vec<&str> = args.iter().map(String::as_str).collect();
+        return sanction(&bridge, &state_dir, &command);
+    }
+    if let Some(options) = replaying {
+        let report = replay_events(&bridge, &options)?;
+        println!("{}", report.to_json());
+        return Ok(());
+    }
+    if let Some((account, path)) = exporting {
+        return print_export(&bridge, account, path);
+    }
+    let shutdown = Shutdown::new();
+    install_signal_handlers();
+    let endpoints = start(&config, state_dir, bridge, &shutdown)?;
+    run(vec![(None, endpoints)], shutdown, config.shutdown_timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fedibridge::archive::Event;
+    use fedibridge::json;
+    use fedibridge::store::Mapping;
+
+    #[test]
+    fn replay_runs_archived_events_through_the_pipeline() {
+        let dir = std::env::temp_dir().join(format!("fedibridge-replay-{}", std::process::id()));
+        let archive = EventArchive::open(StateDir::open(&dir).unwrap()).unwrap();
+        let bridge = Bridge::new().with_archive(archive);
+        let like = |n| {
+            json::parse(&format!(
+                r#"{{"id": "https://b.example/likes/{n}", "type": "Like",
+                    "actor": "https://b.example/users/bob", "object": "https://bridge.example/p/1"}}"#
+            ))
+            .unwrap()
+        };
+        archive::archive(&bridge, Event::Activity(like(1)));
+        archive::archive(&bridge, Event::Activity(like(2)));
+        assert!(replay_events(&bridge, &["--actor"]).is_err());
+
+        let report = replay_events(&bridge, &["--actor", "https://b.example/users/bob"]).unwrap();
+        assert_eq!((report.replayed, report.duplicates), (2, 0));
+        // What was handled isn't handled again
+        let report = replay_events(&bridge, &[]).unwrap();
+        assert_eq!((report.replayed, report.duplicates), (0, 2));
+        let report = replay_events(&bridge, &["--since", "2999-01-01T00:00:00Z"]).unwrap();
+        assert_eq!(report.duplicates, 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn accounts_are_exported_by_did_or_handle() {
+        let bridge = Bridge::new();
+        let did = Did::try_create("did:plc:alice".to_string()).unwrap();
+        let mut mapping = Mapping::new(did, "https://bridge.example/users/alice");
+        mapping.handle = Some("alice.example".to_string());
+        bridge.identities.insert(mapping);
+        let export = export_account(&bridge, "did:plc:alice").unwrap();
+        let by_handle = export_account(&bridge, "@alice.example").unwrap();
+        assert_eq!(by_handle.get("mapping"), export.get("mapping"));
+        assert!(export_account(&bridge, "bob.example").is_err());
+        assert!(export_account(&bridge, "did:plc:bob").is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("fedibridge-export-{}.car", std::process::id()));
+        let path = path.to_str().unwrap();
+        print_export(&bridge, "alice.example", Some(path)).unwrap();
+        let car = std::fs::read(path).unwrap();
+        let actor = b"https://bridge.example/users/alice";
+        assert!(car.windows(actor.len()).any(|window| window == actor));
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,400 @@
+//! Moving mappings between deployments
+//!
+//! An [`Export`] carries what another bridge needs to carry on where this one left off: its
+//! identity mappings, and the replies it bridged without their parent, so they're still
+//! threaded if the parent turns up there. It's written as JSON or CSV ([`Format`]), with a
+//! SHA-256 checksum of the mappings it holds which is checked when it's read back, so that a
+//! truncated or hand-edited file is refused rather than half imported. The checksum is of
+//! the same content in either format, so converting one to the other keeps it.
+//!
+//! [`Export::import`] merges an export into a bridge's stores, for moving to new storage or
+//! merging two bridges. Mappings the bridge already has are left as they are, and those
+//! conflicting with one of its own (another mapping for the DID, or its actor or handle
+//! belonging to another DID) are left out and reported.
+//!
+//! `fedibridge export-mappings <path>` and `import-mappings <path>` run these against the
+//! state directory of a stopped bridge, telling the format by the path's extension
+
+use crate::crypto::{hex_encode, sha256};
+use crate::json::{self, Value};
+use crate::orphans::OrphanBuffer;
+use crate::store::{IdentityStore, Mapping};
+use atproto::DID::Did;
+use thiserror::Error;
+
+/// The version of the export format
+pub const VERSION: i64 = 1;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum MappingError {
+    #[error("Not a mapping export: {0}")]
+    Invalid(String),
+    #[error("The export's checksum is {expected}, but its mappings' is {found}")]
+    Checksum { expected: String, found: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub const ALL: [Format; 2] = [Format::Json, Format::Csv];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Format> {
+        Format::ALL
+            .into_iter()
+            .find(|format| format.as_str().eq_ignore_ascii_case(s))
+    }
+
+    /// The format of a file at `path`, by its extension; JSON unless it's `.csv`
+    pub fn for_path(path: &str) -> Format {
+        path.rsplit_once('.')
+            .and_then(|(_, extension)| Format::parse(extension))
+            .unwrap_or(Format::Json)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+/// A reply bridged without its parent
+pub struct ThreadLink {
+    /// The ActivityPub ID of the post it replies to
+    pub parent: String,
+    /// The `at://` URI it was bridged as
+    pub reply: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Export {
+    pub identities: Vec<Mapping>,
+    pub threads: Vec<ThreadLink>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// What [`Export::import`] made of an export
+pub struct ImportReport {
+    pub added: usize,
+    /// Mappings the bridge already had, just the same
+    pub unchanged: usize,
+    /// The DIDs of mappings left out for conflicting with the bridge's own, or having a
+    /// [lookalike](crate::lookalike) of one of their handles
+    pub conflicts: Vec<Did>,
+    /// Replies added to be threaded
+    pub threads: usize,
+}
+
+impl Export {
+    /// What `identities` and `orphans` hold
+    pub fn collect(identities: &IdentityStore, orphans: &OrphanBuffer) -> Export {
+        let threads = orphans.unthreaded().into_iter();
+        Export {
+            identities: identities.all(),
+            threads: threads
+                .map(|(parent, reply)| ThreadLink { parent, reply })
+                .collect(),
+        }
+    }
+
+    /// The SHA-256 of each mapping's JSON and each reply's parent and URI, one to a line
+    pub fn checksum(&self) -> String {
+        let mut content = String::new();
+        for mapping in &self.identities {
+            content.push_str(&mapping.to_json().to_string());
+            content.push('\n');
+        }
+        for link in &self.threads {
+            content.push_str(&format!("{}\t{}\n", link.parent, link.reply));
+        }
+        hex_encode(&sha256(content.as_bytes()))
+    }
+
+    /// The export as a file in `format`
+    pub fn write(&self, format: Format) -> String {
+        match format {
+            Format::Json => self.to_json().to_string(),
+            Format::Csv => self.to_csv(),
+        }
+    }
+
+    /// An export written in `format`, if its checksum holds
+    pub fn parse(format: Format, contents: &str) -> Result<Export, MappingError> {
+        let (export, expected) = match format {
+            Format::Json => Export::from_json(contents)?,
+            Format::Csv => Export::from_csv(contents)?,
+        };
+        let found = export.checksum();
+        if expected != found {
+            return Err(MappingError::Checksum { expected, found });
+        }
+        Ok(export)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let threads = self.threads.iter().map(|link| {
+            Value::object([
+                ("parent", Value::from(link.parent.as_str())),
+                ("reply", Value::from(link.reply.as_str())),
+            ])
+        });
+        Value::object([
+            ("version", Value::from(VERSION)),
+            (
+                "identities",
+                Value::Array(self.identities.iter().map(Mapping::to_json).collect()),
+            ),
+            ("threads", Value::Array(threads.collect())),
+            ("checksum", Value::from(self.checksum())),
+        ])
+    }
+
+    /// One row per mapping and reply, of their kind, key and value, and a last row of the
+    /// checksum. A mapping's value is its JSON
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("kind,key,value\r\n");
+        let mut row = |kind: &str, key: &str, value: &str| {
+            let fields = [kind, key, value].map(csv_field);
+            csv.push_str(&fields.join(","));
+            csv.push_str("\r\n");
+        };
+        for mapping in &self.identities {
+            row(
+                "identity",
+                mapping.did.as_str(),
+                &mapping.to_json().to_string(),
+            );
+        }
+        for link in &self.threads {
+            row("thread", &link.parent, &link.reply);
+        }
+        row("checksum", "sha256", &self.checksum());
+        csv
+    }
+
+    fn from_json(contents: &str) -> Result<(Export, String), MappingError> {
+        let invalid = MappingError::Invalid;
+        let value = json::parse(contents).map_err(|e| invalid(e.to_string()))?;
+        match value.get("version").and_then(Value::as_i64) {
+            Some(VERSION) => {}
+            version => return Err(invalid(format!("unknown version {version:?}"))),
+        }
+        let array = |name| {
+            value
+                .get(name)
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+        };
+        let mut export = Export::default();
+        for mapping in array("identities") {
+            let mapping = Mapping::from_json(mapping);
+            export
+                .identities
+                .push(mapping.ok_or_else(|| invalid("invalid mapping".into()))?);
+        }
+        for link in array("threads") {
+            let field = |name| link.get(name).and_then(Value::as_str).map(str::to_string);
+            let (Some(parent), Some(reply)) = (field("parent"), field("reply")) else {
+                return Err(invalid("invalid thread".into()));
+            };
+            export.threads.push(ThreadLink { parent, reply });
+        }
+        let checksum = value.get("checksum").and_then(Value::as_str);
+        let checksum = checksum.ok_or_else(|| invalid("no checksum".into()))?;
+        Ok((export, checksum.to_string()))
+    }
+
+    fn from_csv(contents: &str) -> Result<(Export, String), MappingError> {
+        let invalid = MappingError::Invalid;
+        let mut rows = csv_rows(contents)?.into_iter();
+        if rows.next().as_deref() != Some(&["kind", "key", "value"].map(String::from)[..]) {
+            return Err(invalid("no header row".into()));
+        }
+        let mut export = Export::default();
+        for (line, row) in rows.enumerate() {
+            let [kind, key, value] = <[String; 3]>::try_from(row)
+                .map_err(|row| invalid(format!("row {} has {} fields", line + 2, row.len())))?;
+            match kind.as_str() {
+                "identity" => {
+                    let mapping = json::parse(&value)
+                        .ok()
+                        .and_then(|value| Mapping::from_json(&value))
+                        .filter(|mapping| mapping.did.as_str() == key);
+                    export
+                        .identities
+                        .push(mapping.ok_or_else(|| invalid(format!("invalid mapping {key}")))?);
+                }
+                "thread" => export.threads.push(ThreadLink {
+                    parent: key,
+                    reply: value,
+                }),
+                "checksum" if key == "sha256" => return Ok((export, value)),
+                kind => return Err(invalid(format!("unknown row kind {kind}"))),
+            }
+        }
+        Err(invalid("no checksum".into()))
+    }
+
+    /// Add what's new in the export to `identities` and `orphans`
+    pub fn import(&self, identities: &IdentityStore, orphans: &OrphanBuffer) -> ImportReport {
+        let mut report = ImportReport::default();
+        for mapping in &self.identities {
+            match identities.get(&mapping.did) {
+                Some(existing) if existing == *mapping => report.unchanged += 1,
+                Some(_) => report.conflicts.push(mapping.did.clone()),
+                None => {
+                    let handle = mapping.handle.as_deref();
+                    if identities.get_by_actor(&mapping.actor).is_some()
+                        || handle.and_then(|h| identities.get_by_handle(h)).is_some()
+                        || identities.try_insert(mapping.clone()).is_err()
+                    {
+                        report.conflicts.push(mapping.did.clone());
+                    } else {
+                        report.added += 1;
+                    }
+                }
+            }
+        }
+        let known = orphans.unthreaded();
+        for link in &self.threads {
+            if !known.contains(&(link.parent.clone(), link.reply.clone())) {
+                orphans.bridged_unthreaded(&link.parent, &link.reply);
+                report.threads += 1;
+            }
+        }
+        report
+    }
+}
+
+/// `field` quoted if it needs to be
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// The rows of CSV `contents`, with quoted fields unquoted
+pub(crate) fn csv_rows(contents: &str) -> Result<Vec<Vec<String>>, MappingError> {
+    let mut rows = Vec::new();
+    let (mut row, mut field) = (Vec::new(), String::new());
+    let mut chars = contents.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            _ if quoted => field.push(c),
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(MappingError::Invalid("unterminated quoted field".into()));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MappingStatus;
+
+    fn bridge_a() -> (IdentityStore, OrphanBuffer) {
+        let identities = IdentityStore::new();
+        let mut alice = Mapping::new(
+            atproto::did!("did:plc:alice"),
+            "https://a.example/users/alice",
+        );
+        alice.handle = Some("alice.a.example.bridge.example".to_string());
+        alice.preferences.languages = vec!["en".to_string()];
+        identities.insert(alice);
+        let mut bob = Mapping::new(atproto::did!("did:plc:bob"), "https://a.example/users/bob");
+        bob.status = MappingStatus::Passive;
+        identities.insert(bob);
+        let orphans = OrphanBuffer::default();
+        orphans.bridged_unthreaded(
+            "https://c.example/notes/1,\"2\"",
+            "at://did:plc:alice/app.bsky.feed.post/3k",
+        );
+        (identities, orphans)
+    }
+
+    #[test]
+    fn exports_round_trip_and_are_checked() {
+        let (identities, orphans) = bridge_a();
+        let export = Export::collect(&identities, &orphans);
+        assert_eq!(export.identities.len(), 2);
+        for format in Format::ALL {
+            let written = export.write(format);
+            assert_eq!(Export::parse(format, &written), Ok(export.clone()));
+        }
+        assert_eq!(Format::for_path("/tmp/mappings.CSV"), Format::Csv);
+        assert_eq!(Format::for_path("mappings"), Format::Json);
+
+        let tampered = export
+            .to_csv()
+            .replace("a.example/users/bob", "b.example/users/bob");
+        assert!(matches!(
+            Export::parse(Format::Csv, &tampered),
+            Err(MappingError::Checksum { .. })
+        ));
+        let unchecked = export
+            .to_json()
+            .to_string()
+            .replace(r#""checksum""#, r#""nope""#);
+        assert!(Export::parse(Format::Json, &unchecked).is_err());
+    }
+
+    #[test]
+    fn imports_merge_without_overriding() {
+        let (identities, orphans) = bridge_a();
+        let export = Export::collect(&identities, &orphans);
+
+        let theirs = IdentityStore::new();
+        // Their own bob, and a carol with alice's actor
+        theirs.insert(Mapping::new(
+            atproto::did!("did:plc:bob"),
+            "https://b.example/users/bob",
+        ));
+        theirs.insert(Mapping::new(
+            atproto::did!("did:plc:carol"),
+            "https://a.example/users/alice",
+        ));
+        let their_orphans = OrphanBuffer::default();
+        let report = export.import(&theirs, &their_orphans);
+        assert_eq!(report.added, 0);
+        assert_eq!(report.conflicts.len(), 2);
+        assert_eq!(report.threads, 1);
+        assert_eq!(
+            theirs.get(&atproto::did!("did:plc:bob")).unwrap().actor,
+            "https://b.example/users/bob"
+        );
+        assert_eq!(their_orphans.unthreaded(), orphans.unthreaded());
+
+        let empty = IdentityStore::new();
+        let report = export.import(&empty, &their_orphans);
+        assert_eq!((report.added, report.threads), (2, 0));
+        assert_eq!(export.import(&empty, &their_orphans).unchanged, 2);
+    }
+}
@@ -0,0 +1,199 @@
+//! Fediverse post content from Bluesky rich text
+//!
+//! Bluesky text and facets become HTML paragraphs, line breaks and links, but how clients
+//! render them depends on the markup. Which is written is the bridge's [`HtmlProfile`]:
+//!
+//! - `generic`: plain links, with mentions marked up as `u-url mention`
+//! - `mastodon`: the subset Mastodon itself sends, which its clients and the apps built on
+//!   its API know how to show. Mentions are `h-card`s showing `@user`, hashtags are
+//!   `mention hashtag` links with `rel="tag"`, and links show their URL the way Mastodon's
+//!   own do, with the scheme in an `invisible` span and anything past 30 characters
+//!   hidden behind an `ellipsis`
+//!
+//! `FEDIBRIDGE_HTML_PROFILE` selects the profile
+
+use crate::html::escape;
+use crate::http::percent_encode;
+use crate::richtext::{is_url_text, Feature, RichText};
+use atproto::DID::Did;
+
+/// Where a hashtag's posts are, on Bluesky
+pub const BLUESKY_HASHTAG: &str = "https://bsky.app/hashtag";
+
+/// How much of a URL Mastodon shows before cutting it short
+const MASTODON_DISPLAY_URL: usize = 30;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Which flavour of HTML post content is written in
+pub enum HtmlProfile {
+    #[default]
+    Generic,
+    Mastodon,
+}
+
+impl HtmlProfile {
+    pub const ALL: [HtmlProfile; 2] = [HtmlProfile::Generic, HtmlProfile::Mastodon];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HtmlProfile::Generic => "generic",
+            HtmlProfile::Mastodon => "mastodon",
+        }
+    }
+
+    pub fn parse(profile: &str) -> Option<HtmlProfile> {
+        HtmlProfile::ALL.into_iter().find(|p| p.as_str() == profile)
+    }
+}
+
+/// Text as post content, with paragraphs and line breaks
+fn paragraphs(text: &str) -> String {
+    let escaped = escape(text);
+    escaped.replace("\n\n", "</p><p>").replace('\n', "<br>")
+}
+
+/// A link's URL as Mastodon displays it
+fn mastodon_url(uri: &str) -> String {
+    let prefix = ["https://www.", "http://www.", "https://", "http://"]
+        .into_iter()
+        .find(|prefix| uri.starts_with(prefix))
+        .unwrap_or_default();
+    let rest = &uri[prefix.len()..];
+    let shown = rest
+        .char_indices()
+        .nth(MASTODON_DISPLAY_URL)
+        .map_or(rest.len(), |(i, _)| i);
+    let (shown, hidden) = rest.split_at(shown);
+    let class = if hidden.is_empty() {
+        ""
+    } else {
+        " class=\"ellipsis\""
+    };
+    format!(
+        "<span class=\"invisible\">{}</span><span{class}>{}</span><span class=\"invisible\">{}</span>",
+        escape(prefix),
+        escape(shown),
+        escape(hidden)
+    )
+}
+
+/// `label`, less the `sigil` it starts with, in a span after it
+fn mastodon_tagged(sigil: char, label: &str) -> String {
+    let name = label.strip_prefix(sigil).unwrap_or(label);
+    format!("{sigil}<span>{}</span>", paragraphs(name))
+}
+
+/// `rich` as post content in `profile`, with mentions linking to `actor`'s answer for
+/// their DID
+///
+/// Facets overlapping an earlier one, or not on character boundaries, are left as text
+pub fn to_html(rich: &RichText, profile: HtmlProfile, actor: impl Fn(&Did) -> String) -> String {
+    let text = rich.text.as_str();
+    let mut facets: Vec<_> = rich.facets.iter().collect();
+    facets.sort_by_key(|facet| facet.byte_start);
+    let mut content = String::from("<p>");
+    let mut at = 0;
+    for facet in facets {
+        let Some(label) = text.get(facet.byte_start..facet.byte_end) else {
+            continue;
+        };
+        if facet.byte_start < at {
+            continue;
+        }
+        content.push_str(&paragraphs(&text[at..facet.byte_start]));
+        let generic = |href: &str, attributes: &str| {
+            format!(
+                "<a href=\"{}\"{attributes}>{}</a>",
+                escape(href),
+                paragraphs(label)
+            )
+        };
+        let link = match (&facet.feature, profile) {
+            (Feature::Link { uri }, HtmlProfile::Generic) => generic(uri, ""),
+            (Feature::Mention { did }, HtmlProfile::Generic) => {
+                generic(&actor(did), " class=\"u-url mention\"")
+            }
+            (Feature::Tag { tag }, HtmlProfile::Generic) => {
+                generic(&format!("{BLUESKY_HASHTAG}/{}", percent_encode(tag)), " rel=\"tag\"")
+            }
+            (Feature::Link { uri }, HtmlProfile::Mastodon) => {
+                let shown = match is_url_text(label, uri) {
+                    true => mastodon_url(uri),
+                    false => paragraphs(label),
+                };
+                format!(
+                    "<a href=\"{}\" rel=\"nofollow noopener\" target=\"_blank\">{shown}</a>",
+                    escape(uri)
+                )
+            }
+            (Feature::Mention { did }, HtmlProfile::Mastodon) => format!(
+                "<span class=\"h-card\" translate=\"no\"><a href=\"{}\" class=\"u-url mention\">{}</a></span>",
+                escape(&actor(did)),
+                mastodon_tagged('@', label)
+            ),
+            (Feature::Tag { tag }, HtmlProfile::Mastodon) => format!(
+                "<a href=\"{BLUESKY_HASHTAG}/{}\" class=\"mention hashtag\" rel=\"tag\">{}</a>",
+                percent_encode(tag),
+                mastodon_tagged('#', label)
+            ),
+        };
+        content.push_str(&link);
+        at = facet.byte_end;
+    }
+    content.push_str(&paragraphs(&text[at..]));
+    content.push_str("</p>");
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rich() -> RichText {
+        let mut rich = RichText::new();
+        rich.push_str("hi ");
+        let bob = atproto::did!("did:plc:bob");
+        rich.push_facet("@bob.bsky.social", Feature::Mention { did: bob });
+        rich.push_str(", see ");
+        let story = "https://www.news.example/2024/05/01/a-very-long-headline";
+        rich.push_link("news.example/2024/05…", story);
+        rich.push_str(" and ");
+        rich.push_link("this", "https://x.example/");
+        rich.push_str("\n\n");
+        let tag = "rust".to_string();
+        rich.push_facet("#rust", Feature::Tag { tag });
+        rich
+    }
+
+    fn actor(did: &Did) -> String {
+        format!("https://bridge.example/ap/{did}")
+    }
+
+    #[test]
+    fn generic_html_is_plain_links() {
+        assert_eq!(
+            to_html(&rich(), HtmlProfile::Generic, actor),
+            "<p>hi <a href=\"https://bridge.example/ap/did:plc:bob\" class=\"u-url mention\">\
+             @bob.bsky.social</a>, see <a href=\"https://www.news.example/2024/05/01/a-very-long-headline\">\
+             news.example/2024/05…</a> and <a href=\"https://x.example/\">this</a></p><p>\
+             <a href=\"https://bsky.app/hashtag/rust\" rel=\"tag\">#rust</a></p>"
+        );
+    }
+
+    #[test]
+    fn mastodon_html_has_its_microformats() {
+        assert_eq!(
+            to_html(&rich(), HtmlProfile::Mastodon, actor),
+            "<p>hi <span class=\"h-card\" translate=\"no\">\
+             <a href=\"https://bridge.example/ap/did:plc:bob\" class=\"u-url mention\">\
+             @<span>bob.bsky.social</span></a></span>, see \
+             <a href=\"https://www.news.example/2024/05/01/a-very-long-headline\" \
+             rel=\"nofollow noopener\" target=\"_blank\"><span class=\"invisible\">https://www.</span>\
+             <span class=\"ellipsis\">news.example/2024/05/01/a-very</span>\
+             <span class=\"invisible\">-long-headline</span></a> and \
+             <a href=\"https://x.example/\" rel=\"nofollow noopener\" target=\"_blank\">this</a></p><p>\
+             <a href=\"https://bsky.app/hashtag/rust\" class=\"mention hashtag\" rel=\"tag\">\
+             #<span>rust</span></a></p>"
+        );
+    }
+}
@@ -0,0 +1,548 @@
+//! Images and video attached to posts
+//!
+//! Attachments are translated through [`MediaItem`], which carries alt text between an
+//! ActivityPub attachment's `name` and a Bluesky embed's `alt`. Alt text is part of the item
+//! rather than of its location, so it survives the bridge re-hosting the file
+//! ([`MediaItem::rehosted`]). The file itself is [stripped](crate::metadata) of EXIF and
+//! similar metadata before it's re-hosted.
+//!
+//! Accounts which [require alt text](crate::store::Preferences::require_alt_text) only have
+//! described media bridged; see [`split_undescribed`]
+//!
+//! Bluesky limits videos to [`MAX_VIDEO_SIZE`] and [`MAX_VIDEO_DURATION`]. Rather than fail
+//! the whole post over a video beyond them, it's linked to instead, with a card
+//! ([`video_link_card`])
+//!
+//! Posts can have at most [`MAX_IMAGES`] images, where Pixelfed and others allow more. The
+//! first four are embedded in order and the rest are linked to from the post's text
+//! ([`split_overflow`], [`overflow_text`])
+
+use crate::egress::EgressPolicy;
+use crate::json::Value;
+use crate::linkcard::{self, LinkCard, LinkCardConfig};
+use crate::store::Preferences;
+use crate::time::parse_duration;
+use crate::transport::HttpTransport;
+use std::time::Duration;
+
+/// Embed types which carry images or video, directly or through `media`
+const IMAGES: &str = "app.bsky.embed.images";
+const VIDEO: &str = "app.bsky.embed.video";
+const RECORD_WITH_MEDIA: &str = "app.bsky.embed.recordWithMedia";
+
+/// The largest video Bluesky accepts, in bytes
+pub const MAX_VIDEO_SIZE: u64 = 100_000_000;
+/// The longest video Bluesky accepts
+pub const MAX_VIDEO_DURATION: Duration = Duration::from_secs(180);
+/// The most images a Bluesky post can embed
+pub const MAX_IMAGES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+/// A single image or video
+pub struct MediaItem {
+    pub url: String,
+    pub mime_type: Option<String>,
+    /// Alt text, kept exactly as written
+    pub alt: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Size in bytes, where the source says or once it's been fetched
+    pub size: Option<u64>,
+    /// Running time of a video, where the source says
+    pub duration: Option<Duration>,
+    /// A still image standing in for a video, where the source has one
+    pub preview: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which of Bluesky's limits a video is beyond
+pub enum VideoLimit {
+    Size,
+    Duration,
+}
+
+impl MediaItem {
+    pub fn new(url: impl Into<String>) -> MediaItem {
+        MediaItem {
+            url: url.into(),
+            mime_type: None,
+            alt: None,
+            width: None,
+            height: None,
+            size: None,
+            duration: None,
+            preview: None,
+        }
+    }
+
+    pub fn with_alt(self, alt: impl Into<String>) -> MediaItem {
+        MediaItem {
+            alt: Some(alt.into()),
+            ..self
+        }
+    }
+
+    /// Whether there's any alt text beyond whitespace
+    pub fn has_alt_text(&self) -> bool {
+        self.alt.as_ref().is_some_and(|a| !a.trim().is_empty())
+    }
+
+    /// The same media at a new location, e.g. after the bridge has re-hosted it
+    pub fn rehosted(&self, url: impl Into<String>) -> MediaItem {
+        MediaItem {
+            url: url.into(),
+            ..self.clone()
+        }
+    }
+
+    fn is_video(&self) -> bool {
+        self.mime_type
+            .as_ref()
+            .is_some_and(|m| m.starts_with("video/"))
+    }
+
+    /// Which of Bluesky's limits this is beyond, if it's a video. Limits on things the source
+    /// didn't say can't be checked until the video is fetched
+    pub fn exceeded_video_limit(&self) -> Option<VideoLimit> {
+        if !self.is_video() {
+            return None;
+        }
+        if self.size.is_some_and(|size| size > MAX_VIDEO_SIZE) {
+            return Some(VideoLimit::Size);
+        }
+        if self.duration.is_some_and(|d| d > MAX_VIDEO_DURATION) {
+            return Some(VideoLimit::Duration);
+        }
+        None
+    }
+
+    /// This item as an ActivityPub attachment
+    pub fn to_attachment(&self) -> Value {
+        let kind = match &self.mime_type {
+            Some(m) if m.starts_with("image/") => "Image",
+            Some(m) if m.starts_with("video/") => "Video",
+            _ => "Document",
+        };
+        let mut fields = vec![
+            ("type", Value::from(kind)),
+            ("url", Value::from(self.url.as_str())),
+        ];
+        if let Some(mime_type) = &self.mime_type {
+            fields.push(("mediaType", Value::from(mime_type.as_str())));
+        }
+        if let Some(alt) = self.alt.as_ref().filter(|_| self.has_alt_text()) {
+            fields.push(("name", Value::from(alt.as_str())));
+        }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            fields.push(("width", Value::from(width)));
+            fields.push(("height", Value::from(height)));
+        }
+        Value::object(fields)
+    }
+}
+
+fn dimension(value: Option<&Value>) -> Option<u32> {
+    value?.as_i64().and_then(|v| u32::try_from(v).ok())
+}
+
+/// The media attached to an ActivityPub object
+///
+/// Attachments without a usable URL are skipped
+pub fn from_attachments(object: &Value) -> Vec<MediaItem> {
+    let attachments = match object.get("attachment") {
+        Some(Value::Array(items)) => items.iter().collect(),
+        Some(item) => vec![item],
+        None => Vec::new(),
+    };
+    attachments
+        .into_iter()
+        .filter_map(|attachment| {
+            // `url` may be a string, a Link, or several of either
+            let url = match attachment.get("url")? {
+                Value::Array(urls) => urls.first()?,
+                url => url,
+            };
+            let size = url.get("size").or_else(|| attachment.get("size"));
+            let url = url
+                .as_str()
+                .or_else(|| url.get("href").and_then(Value::as_str))?;
+            let field = |name| attachment.get(name).and_then(Value::as_str);
+            let preview = match attachment.get("icon") {
+                Some(Value::Array(icons)) => icons.first(),
+                icon => icon,
+            };
+            Some(MediaItem {
+                url: url.to_string(),
+                mime_type: field("mediaType").map(str::to_string),
+                alt: field("name").map(str::to_string),
+                width: dimension(attachment.get("width")),
+                height: dimension(attachment.get("height")),
+                size: size
+                    .and_then(Value::as_i64)
+                    .and_then(|s| u64::try_from(s).ok()),
+                duration: field("duration").and_then(parse_duration),
+                preview: preview
+                    .and_then(|p| p.as_str().or_else(|| p.get("url")?.as_str()))
+                    .map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// The media in a Bluesky post's embed
+///
+/// `blob_url` gives the URL a blob can be fetched from, given its CID
+pub fn from_embed(embed: &Value, blob_url: impl Fn(&str) -> String) -> Vec<MediaItem> {
+    let item = |entry: &Value, blob_field: &str| -> Option<MediaItem> {
+        let blob = entry.get(blob_field)?;
+        let cid = blob.get("ref")?.get("$link")?.as_str()?;
+        let aspect = entry.get("aspectRatio");
+        Some(MediaItem {
+            url: blob_url(cid),
+            mime_type: blob
+                .get("mimeType")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            alt: entry.get("alt").and_then(Value::as_str).map(str::to_string),
+            width: dimension(aspect.and_then(|a| a.get("width"))),
+            height: dimension(aspect.and_then(|a| a.get("height"))),
+            size: blob
+                .get("size")
+                .and_then(Value::as_i64)
+                .and_then(|s| u64::try_from(s).ok()),
+            duration: None,
+            preview: None,
+        })
+    };
+    match embed.get("$type").and_then(Value::as_str) {
+        Some(IMAGES) => embed
+            .get("images")
+            .and_then(Value::as_array)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|image| item(image, "image"))
+            .collect(),
+        Some(VIDEO) => item(embed, "video").into_iter().collect(),
+        Some(RECORD_WITH_MEDIA) => embed
+            .get("media")
+            .map(|media| from_embed(media, blob_url))
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// A Bluesky embed for uploaded media, given each item along with its blob
+///
+/// Alt text is required by the lexicon, so undescribed items get an empty one. Video can't be
+/// mixed with images, so a video is embedded on its own and anything else dropped. Images
+/// beyond [`MAX_IMAGES`] are dropped too
+pub fn to_embed(uploaded: &[(MediaItem, Value)]) -> Option<Value> {
+    let with_alt = |item: &MediaItem, fields: &mut Vec<(&str, Value)>| {
+        fields.push(("alt", Value::from(item.alt.clone().unwrap_or_default())));
+        if let (Some(width), Some(height)) = (item.width, item.height) {
+            fields.push((
+                "aspectRatio",
+                Value::object([
+                    ("width", Value::from(width)),
+                    ("height", Value::from(height)),
+                ]),
+            ));
+        }
+    };
+    if let Some((video, blob)) = uploaded.iter().find(|(item, _)| item.is_video()) {
+        let mut fields = vec![("$type", Value::from(VIDEO)), ("video", blob.clone())];
+        with_alt(video, &mut fields);
+        return Some(Value::object(fields));
+    }
+    if uploaded.is_empty() {
+        return None;
+    }
+    let images = uploaded
+        .iter()
+        .take(MAX_IMAGES)
+        .map(|(item, blob)| {
+            let mut fields = vec![("image", blob.clone())];
+            with_alt(item, &mut fields);
+            Value::object(fields)
+        })
+        .collect();
+    Some(Value::object([
+        ("$type", Value::from(IMAGES)),
+        ("images", Value::Array(images)),
+    ]))
+}
+
+/// The video among `items` which is beyond Bluesky's limits, if any
+///
+/// This is the video [`to_embed`] would have used, so when there is one the post should get
+/// its [`video_link_card`] instead
+pub fn oversized_video(items: &[MediaItem]) -> Option<(&MediaItem, VideoLimit)> {
+    let video = items.iter().find(|item| item.is_video())?;
+    Some((video, video.exceeded_video_limit()?))
+}
+
+/// `h:mm:ss`, or `m:ss` for less than an hour
+fn running_time(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{hours}:{:02}:{:02}", seconds / 60 % 60, seconds % 60),
+    }
+}
+
+/// A card linking to a video which is beyond Bluesky's limits, at `link` (normally the post
+/// it's attached to)
+///
+/// The thumbnail is the video's preview image if it has one, or else the page's own, when
+/// link cards are enabled. Either way it's fetched with the same care as any link card
+pub fn video_link_card(
+    transport: &dyn HttpTransport,
+    policy: &EgressPolicy,
+    config: &LinkCardConfig,
+    video: &MediaItem,
+    link: &str,
+    limit: VideoLimit,
+) -> LinkCard {
+    let title = match &video.alt {
+        Some(alt) if video.has_alt_text() => alt.trim().to_string(),
+        _ => "Video".to_string(),
+    };
+    let description = match (limit, video.size, video.duration) {
+        (VideoLimit::Size, Some(size), _) => format!(
+            "A {} MB video, too large to play on Bluesky. Watch it on the original post",
+            size.div_ceil(1_000_000)
+        ),
+        (VideoLimit::Duration, _, Some(duration)) => format!(
+            "A {} video, too long to play on Bluesky. Watch it on the original post",
+            running_time(duration)
+        ),
+        _ => "This video can't be played on Bluesky. Watch it on the original post".to_string(),
+    };
+    let thumbnail = match &video.preview {
+        Some(preview) => linkcard::fetch_thumbnail(transport, policy, config, preview),
+        None => linkcard::fetch_card(transport, policy, config, link)
+            .ok()
+            .and_then(|card| card.thumbnail),
+    };
+    LinkCard {
+        uri: link.to_string(),
+        title,
+        description,
+        thumbnail,
+    }
+}
+
+/// Split media into what a post's embed can hold and what's beyond [`MAX_IMAGES`], keeping
+/// their order
+///
+/// A video is embedded on its own, so nothing overflows alongside one
+pub fn split_overflow(mut items: Vec<MediaItem>) -> (Vec<MediaItem>, Vec<MediaItem>) {
+    if items.iter().any(MediaItem::is_video) || items.len() <= MAX_IMAGES {
+        return (items, Vec::new());
+    }
+    let overflow = items.split_off(MAX_IMAGES);
+    (items, overflow)
+}
+
+/// A line for the end of a post's text pointing to the images which didn't fit, at `link`
+/// (normally the original post)
+pub fn overflow_text(overflow: &[MediaItem], link: &str) -> Option<String> {
+    match overflow.len() {
+        0 => None,
+        1 => Some(format!("+1 more image: {link}")),
+        more => Some(format!("+{more} more images: {link}")),
+    }
+}
+
+/// Split media into what may be bridged for an account and what may not
+///
+/// Everything is allowed unless the account requires alt text, in which case undescribed
+/// items are returned separately
+pub fn split_undescribed(
+    items: Vec<MediaItem>,
+    preferences: &Preferences,
+) -> (Vec<MediaItem>, Vec<MediaItem>) {
+    if !preferences.require_alt_text {
+        return (items, Vec::new());
+    }
+    items.into_iter().partition(MediaItem::has_alt_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::json;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    fn blob(cid: &str, mime_type: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"$type": "blob", "ref": {{"$link": "{cid}"}}, "mimeType": "{mime_type}", "size": 1}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn alt_text_round_trips_from_fediverse() {
+        let note = json::parse(
+            r#"{"attachment": [
+                {"type": "Image", "mediaType": "image/png", "url": "https://a.example/1.png",
+                 "name": "A cat, asleep", "width": 640, "height": 480},
+                {"type": "Document", "url": {"type": "Link", "href": "https://a.example/2.jpg"}},
+                {"type": "Document"}
+            ]}"#,
+        )
+        .unwrap();
+        let items = from_attachments(&note);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].alt.as_deref(), Some("A cat, asleep"));
+        assert!(!items[1].has_alt_text());
+
+        // The bridge re-hosts the files as blobs, which mustn't lose the descriptions
+        let uploaded: Vec<_> = items
+            .iter()
+            .map(|item| {
+                (
+                    item.rehosted("https://bridge.example/blob"),
+                    blob("bafy", "image/png"),
+                )
+            })
+            .collect();
+        let embed = to_embed(&uploaded).unwrap();
+        let back = from_embed(&embed, |cid| format!("https://pds.example/{cid}"));
+        assert_eq!(back[0].alt.as_deref(), Some("A cat, asleep"));
+        assert_eq!((back[0].width, back[0].height), (Some(640), Some(480)));
+        assert_eq!(back[1].alt.as_deref(), Some(""));
+        assert_eq!(
+            back[0].to_attachment().get("name"),
+            items[0].to_attachment().get("name")
+        );
+        assert_eq!(back[1].to_attachment().get("name"), None);
+    }
+
+    #[test]
+    fn alt_text_round_trips_from_bluesky() {
+        let embed = Value::object([
+            ("$type", Value::from(RECORD_WITH_MEDIA)),
+            (
+                "media",
+                Value::object([
+                    ("$type", Value::from(VIDEO)),
+                    ("video", blob("bafyvideo", "video/mp4")),
+                    ("alt", Value::from("Waves on a beach")),
+                ]),
+            ),
+        ]);
+        let items = from_embed(&embed, |cid| format!("https://pds.example/{cid}"));
+        assert_eq!(items[0].url, "https://pds.example/bafyvideo");
+        let attachment = items[0]
+            .rehosted("https://cdn.example/v.mp4")
+            .to_attachment();
+        assert_eq!(attachment.get("type"), Some(&Value::from("Video")));
+        assert_eq!(
+            attachment.get("name"),
+            Some(&Value::from("Waves on a beach"))
+        );
+        assert_eq!(
+            from_attachments(&Value::object([("attachment", attachment)]))[0].alt,
+            items[0].alt
+        );
+    }
+
+    #[test]
+    fn oversized_videos_become_link_cards() {
+        let note = json::parse(
+            r#"{"attachment": [
+                {"type": "Image", "mediaType": "image/png", "url": "https://a.example/1.png"},
+                {"type": "Video", "mediaType": "video/mp4", "url": "https://a.example/v.mp4",
+                 "name": "Our concert", "duration": "PT4M5S",
+                 "icon": {"type": "Image", "url": "https://a.example/v.jpg"}}
+            ]}"#,
+        )
+        .unwrap();
+        let items = from_attachments(&note);
+        let (video, limit) = oversized_video(&items).unwrap();
+        assert_eq!(limit, VideoLimit::Duration);
+
+        let mock = MockTransport::new();
+        mock.respond(
+            Method::Get,
+            "https://a.example/v.jpg",
+            OutboundResponse::new(200)
+                .with_header("content-type", "image/jpeg")
+                .with_body(vec![0xff, 0xd8, 0xff, 0xd9]),
+        );
+        let config = LinkCardConfig::default();
+        let card = video_link_card(
+            &mock,
+            &EgressPolicy::default(),
+            &config,
+            video,
+            "https://a.example/@b/1",
+            limit,
+        );
+        assert_eq!(card.title, "Our concert");
+        assert!(card.description.starts_with("A 4:05 video, too long"));
+        assert_eq!(card.thumbnail.unwrap().mime_type, "image/jpeg");
+
+        // Sizes come from blobs, and short enough videos are fine
+        let mut short = items[1].clone();
+        short.duration = Some(Duration::from_secs(60));
+        assert_eq!(oversized_video(&[short.clone()]), None);
+        short.size = Some(150_000_000);
+        assert_eq!(short.exceeded_video_limit(), Some(VideoLimit::Size));
+        let embed = Value::object([
+            ("$type", Value::from(VIDEO)),
+            ("video", blob("bafyvideo", "video/mp4")),
+        ]);
+        assert_eq!(from_embed(&embed, |cid| cid.to_string())[0].size, Some(1));
+    }
+
+    #[test]
+    fn images_beyond_four_overflow_in_order() {
+        let items: Vec<_> = (1..=6)
+            .map(|n| MediaItem {
+                mime_type: Some("image/jpeg".to_string()),
+                ..MediaItem::new(format!("https://pixelfed.example/{n}.jpg"))
+                    .with_alt(format!("Photo {n}"))
+            })
+            .collect();
+        let (embedded, overflow) = split_overflow(items.clone());
+        assert_eq!(embedded, items[..4]);
+        assert_eq!(overflow, items[4..]);
+        assert_eq!(
+            overflow_text(&overflow, "https://pixelfed.example/p/1").as_deref(),
+            Some("+2 more images: https://pixelfed.example/p/1")
+        );
+        assert_eq!(overflow_text(&[], "https://pixelfed.example/p/1"), None);
+
+        let uploaded: Vec<_> = items
+            .iter()
+            .map(|item| (item.clone(), blob("bafy", "image/jpeg")))
+            .collect();
+        let embed = to_embed(&uploaded).unwrap();
+        let alts: Vec<_> = from_embed(&embed, str::to_string)
+            .into_iter()
+            .map(|item| item.alt.unwrap())
+            .collect();
+        assert_eq!(alts, ["Photo 1", "Photo 2", "Photo 3", "Photo 4"]);
+    }
+
+    #[test]
+    fn undescribed_media_refused_when_required() {
+        let items = vec![
+            MediaItem::new("https://a.example/1").with_alt("A chart"),
+            MediaItem::new("https://a.example/2").with_alt("  "),
+            MediaItem::new("https://a.example/3"),
+        ];
+        let (allowed, refused) = split_undescribed(items.clone(), &Preferences::default());
+        assert_eq!((allowed.len(), refused.len()), (3, 0));
+        let strict = Preferences {
+            require_alt_text: true,
+            ..Preferences::default()
+        };
+        let (allowed, refused) = split_undescribed(items, &strict);
+        assert_eq!(allowed.len(), 1);
+        assert_eq!(refused.len(), 2);
+    }
+}
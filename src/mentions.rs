@@ -0,0 +1,328 @@
+//! Resolving mentions across the bridge
+//!
+//! A mention only translates into a native mention on the other network when the mentioned
+//! account is bridged. Anyone else is resolved on demand and linked to on their own network
+//! instead, so the mention isn't lost:
+//!
+//! - atproto accounts are resolved through their DID document and remembered as a
+//!   [passive](MappingStatus::Passive) mapping pointing at their Bluesky profile
+//! - fediverse accounts are looked up with WebFinger, whose (cached) result gives their
+//!   profile page. They have no DID until they're bridged, so there's no mapping to make
+
+use crate::bridge::Bridge;
+use crate::cache::{Fetched, ResourceKind};
+use crate::delivery::ACTIVITY_JSON;
+use crate::json::{self, Value};
+use crate::resolver::ResolveError;
+use crate::richtext::{self, Feature, RichText};
+use crate::store::{Mapping, MappingStatus};
+use crate::transport::{OutboundRequest, TransportError};
+use crate::url::Url;
+use atproto::DID::Did;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// Where Bluesky profiles are linked to
+pub const BLUESKY_PROFILE: &str = "https://bsky.app/profile";
+
+const JRD_JSON: &str = "application/jrd+json";
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors resolving a mentioned account
+pub enum MentionError {
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Couldn't tell which account {found} is")]
+    Unrecognised { found: String },
+    #[error("WebFinger lookup for {account} failed with status {status}")]
+    WebFinger { account: String, status: u16 },
+    #[error("Invalid WebFinger response for {account}")]
+    InvalidWebFinger { account: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where a mention should point
+pub enum MentionTarget {
+    /// A bridged account, which can be mentioned natively
+    Bridged(Mapping),
+    /// An account which isn't bridged, linked to at its profile on its own network
+    Native { profile: String },
+}
+
+impl MentionTarget {
+    /// The facet for this mention in a Bluesky post
+    pub fn facet(&self) -> Feature {
+        match self {
+            MentionTarget::Bridged(mapping) => Feature::Mention {
+                did: mapping.did.clone(),
+            },
+            MentionTarget::Native { profile } => Feature::Link {
+                uri: profile.clone(),
+            },
+        }
+    }
+
+    /// Where this mention links to in an ActivityPub object's content
+    pub fn href(&self) -> &str {
+        match self {
+            MentionTarget::Bridged(mapping) => &mapping.actor,
+            MentionTarget::Native { profile } => profile,
+        }
+    }
+
+    /// The `Mention` tag for this in an ActivityPub object
+    ///
+    /// Only bridged accounts get one, as there's no actor to notify otherwise
+    pub fn ap_tag(&self, name: &str) -> Option<Value> {
+        match self {
+            MentionTarget::Bridged(mapping) => Some(Value::object([
+                ("type", Value::from("Mention")),
+                ("href", Value::from(mapping.actor.as_str())),
+                ("name", Value::from(name)),
+            ])),
+            MentionTarget::Native { .. } => None,
+        }
+    }
+}
+
+/// The handle a DID document claims, from its `at://` alias
+pub fn handle_from_document(document: &Value) -> Option<String> {
+    document
+        .get("alsoKnownAs")?
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_str)
+        .find_map(|alias| alias.strip_prefix("at://"))
+        .map(str::to_string)
+}
+
+/// Resolve an atproto account mentioned in a Bluesky post
+///
+/// Accounts the bridge doesn't know are resolved and remembered as passive mappings
+pub fn resolve_did(bridge: &Bridge, did: &Did) -> Result<MentionTarget, MentionError> {
+    if let Some(mapping) = bridge.identities.get(did) {
+        return Ok(match mapping.status {
+            MappingStatus::Passive => MentionTarget::Native {
+                profile: mapping.actor,
+            },
+            _ => MentionTarget::Bridged(mapping),
+        });
+    }
+    let document = bridge.resolver().resolve(did)?;
+    let mut mapping = Mapping::new(did.clone(), format!("{BLUESKY_PROFILE}/{did}"));
+    mapping.handle = handle_from_document(&document);
+    mapping.status = MappingStatus::Passive;
+    let profile = mapping.actor.clone();
+    bridge.identities.insert(mapping);
+    Ok(MentionTarget::Native { profile })
+}
+
+/// The `user@host` account a fediverse mention refers to
+///
+/// Mastodon writes mentions of local accounts as just `@user`, so the host is taken from the
+/// actor's IRI when the text doesn't have one
+fn account(href: &str, name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('@');
+    match name.split_once('@') {
+        Some((user, host)) if !user.is_empty() && !host.is_empty() => Some(name.to_string()),
+        Some(_) => None,
+        None if name.is_empty() => None,
+        None => Some(format!("{name}@{}", Url::parse(href).ok()?.authority())),
+    }
+}
+
+/// Look up `user@host` with WebFinger, through the document cache
+pub fn webfinger(bridge: &Bridge, account: &str) -> Result<Arc<Value>, MentionError> {
+    let host = account.rsplit_once('@').map(|(_, host)| host);
+    let url = host
+        .and_then(|host| Url::parse(&format!("https://{host}")).ok())
+        .ok_or_else(|| MentionError::Unrecognised {
+            found: account.to_string(),
+        })?;
+    let url = format!(
+        "{}/.well-known/webfinger?resource=acct:{account}",
+        url.origin()
+    );
+    let transport = bridge.transport.clone();
+    let key = account;
+    let account = account.to_string();
+    bridge
+        .documents
+        .get_or_revalidate(ResourceKind::WebFinger, key, move |validators| {
+            let request = OutboundRequest::get(&url)
+                .with_header("accept", JRD_JSON)
+                .if_modified(validators);
+            let response = transport.send(&request)?;
+            if response.status == 304 && validators.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            if !response.is_success() {
+                return Err(MentionError::WebFinger {
+                    account: account.clone(),
+                    status: response.status,
+                });
+            }
+            let invalid = || MentionError::InvalidWebFinger {
+                account: account.clone(),
+            };
+            let body = std::str::from_utf8(&response.body).map_err(|_| invalid())?;
+            let document = json::parse(body).map_err(|_| invalid())?;
+            Ok(Fetched::Modified {
+                value: document,
+                size: response.body.len(),
+                validators: response.validators(),
+            })
+        })
+}
+
+/// The `href` of a WebFinger link with relation `rel`, and `type` if given
+pub fn webfinger_link<'a>(document: &'a Value, rel: &str, kind: Option<&str>) -> Option<&'a str> {
+    document
+        .get("links")?
+        .as_array()?
+        .iter()
+        .filter(|link| link.get("rel").and_then(Value::as_str) == Some(rel))
+        .filter(|link| kind.is_none() || link.get("type").and_then(Value::as_str) == kind)
+        .find_map(|link| link.get("href").and_then(Value::as_str))
+}
+
+/// Resolve a fediverse account mentioned in a post, from its `Mention`'s href and name
+pub fn resolve_actor(
+    bridge: &Bridge,
+    href: &str,
+    name: &str,
+) -> Result<MentionTarget, MentionError> {
+    let bridged = |actor: &str| {
+        bridge
+            .identities
+            .get_by_actor(actor)
+            .filter(|m| m.status != MappingStatus::Passive)
+    };
+    if let Some(mapping) = bridged(href) {
+        return Ok(MentionTarget::Bridged(mapping));
+    }
+    let account = account(href, name).ok_or_else(|| MentionError::Unrecognised {
+        found: name.to_string(),
+    })?;
+    let document = webfinger(bridge, &account)?;
+    // The href may have been a profile page rather than the actor itself
+    let actor = webfinger_link(&document, "self", Some(ACTIVITY_JSON));
+    if let Some(mapping) = actor.and_then(bridged) {
+        return Ok(MentionTarget::Bridged(mapping));
+    }
+    let profile = webfinger_link(&document, "http://webfinger.net/rel/profile-page", None)
+        .or(actor)
+        .unwrap_or(href);
+    Ok(MentionTarget::Native {
+        profile: profile.to_string(),
+    })
+}
+
+/// Convert fediverse post content to Bluesky rich text, resolving its mentions
+///
+/// Mentions which can't be resolved at all still link to whatever they linked to originally
+pub fn rich_text(bridge: &Bridge, html: &str) -> RichText {
+    richtext::from_html_with(html, |href, name| {
+        Some(match resolve_actor(bridge, href, name) {
+            Ok(target) => target.facet(),
+            Err(_) => Feature::Link {
+                uri: href.to_string(),
+            },
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const CAROL: Did = did!("did:plc:carol");
+
+    fn bridge(mock: &Arc<MockTransport>) -> Bridge {
+        Bridge::new().with_transport(mock.clone())
+    }
+
+    #[test]
+    fn unbridged_atproto_accounts_become_passive() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:carol",
+            r#"{"id": "did:plc:carol", "alsoKnownAs": ["at://carol.example"]}"#,
+        );
+        let bridge = bridge(&mock);
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://bridge.example/users/alice"));
+        let alice = resolve_did(&bridge, &ALICE).unwrap();
+        assert_eq!(
+            alice.ap_tag("@alice").unwrap().get("href"),
+            Some(&Value::from("https://bridge.example/users/alice"))
+        );
+
+        let carol = resolve_did(&bridge, &CAROL).unwrap();
+        assert_eq!(carol.href(), "https://bsky.app/profile/did:plc:carol");
+        assert_eq!(carol.ap_tag("@carol.example"), None);
+        let mapping = bridge.identities.get(&CAROL).unwrap();
+        assert_eq!(mapping.status, MappingStatus::Passive);
+        assert_eq!(mapping.handle.as_deref(), Some("carol.example"));
+        assert!(!bridge.identities.is_tracked(&CAROL));
+        // The second mention is answered from the passive mapping
+        resolve_did(&bridge, &CAROL).unwrap();
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn unbridged_fediverse_accounts_link_to_profile() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://b.example/.well-known/webfinger?resource=acct:bob@b.example",
+            r#"{"subject": "acct:bob@b.example", "links": [
+                {"rel": "self", "type": "application/activity+json",
+                 "href": "https://b.example/users/bob"},
+                {"rel": "http://webfinger.net/rel/profile-page", "type": "text/html",
+                 "href": "https://b.example/@bob"}]}"#,
+        );
+        let bridge = bridge(&mock);
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://a.example/users/alice"));
+        let rich = rich_text(
+            &bridge,
+            "<p>hi <a href=\"https://a.example/@alice\" class=\"u-url mention\">@alice@a.example</a> \
+             and <a href=\"https://b.example/@bob\" class=\"u-url mention\">@bob</a> and \
+             <a href=\"https://c.example/@dan\" class=\"mention\">@dan</a></p>",
+        );
+        assert_eq!(rich.text, "hi @alice@a.example and @bob and @dan");
+        let features: Vec<_> = rich.facets.iter().map(|f| f.feature.clone()).collect();
+        assert_eq!(
+            features[1..],
+            [
+                Feature::Link {
+                    uri: "https://b.example/@bob".to_string()
+                },
+                // c.example has no WebFinger, the mention still links where it did
+                Feature::Link {
+                    uri: "https://c.example/@dan".to_string()
+                },
+            ]
+        );
+        // alice is bridged, but was mentioned by profile page rather than actor IRI
+        assert!(matches!(features[0], Feature::Link { .. }));
+        mock.respond_json(
+            "https://a.example/.well-known/webfinger?resource=acct:alice@a.example",
+            r#"{"links": [{"rel": "self", "type": "application/activity+json",
+                "href": "https://a.example/users/alice"}]}"#,
+        );
+        assert_eq!(
+            resolve_actor(&bridge, "https://a.example/@alice", "@alice@a.example")
+                .unwrap()
+                .facet(),
+            Feature::Mention { did: ALICE }
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Stripping metadata from re-hosted media
+//!
+//! Apps and servers on both networks strip EXIF and similar metadata (GPS position, camera details)
+//! from uploads, but the bridge fetches originals from wherever they're hosted. Everything
+//! it re-hosts goes through [`strip`] first, so bridging a post never reintroduces location
+//! data its author's server had removed.
+//!
+//! Only the container is rewritten; image and video data are copied untouched. Metadata
+//! which affects how media is displayed, such as colour profiles, is kept
+
+use thiserror::Error;
+
+/// PNG chunks carrying text, timestamps or EXIF
+const PNG_DROPPED: &[&[u8; 4]] = &[b"eXIf", b"tEXt", b"zTXt", b"iTXt", b"tIME"];
+/// MP4 boxes carrying user data, which are blanked wherever they appear in these
+const MP4_CONTAINERS: &[&[u8; 4]] = &[b"moov", b"trak", b"mdia"];
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors stripping metadata
+pub enum MetadataError {
+    #[error("Malformed {mime_type}, so its metadata couldn't be stripped")]
+    Malformed { mime_type: String },
+}
+
+/// `data` without any metadata that could identify where or how it was made
+///
+/// Types with no metadata to strip are returned unchanged. Files which can't be parsed are
+/// refused rather than re-hosted as they are
+pub fn strip(data: &[u8], mime_type: &str) -> Result<Vec<u8>, MetadataError> {
+    let stripped = match mime_type {
+        "image/jpeg" => strip_jpeg(data),
+        "image/png" => strip_png(data),
+        "image/webp" => strip_webp(data),
+        "video/mp4" | "video/quicktime" => strip_mp4(data),
+        _ => Some(data.to_vec()),
+    };
+    stripped.ok_or_else(|| MetadataError::Malformed {
+        mime_type: mime_type.to_string(),
+    })
+}
+
+fn u16_be(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn u32_be(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+}
+
+fn u32_le(data: &[u8], at: usize) -> Option<usize> {
+    let bytes = data.get(at..at + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?) as usize)
+}
+
+/// Drop APP segments other than JFIF, ICC profiles and Adobe's colour transform, and
+/// comments
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut stripped = data[..2].to_vec();
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xff {
+            return None;
+        }
+        let marker = *data[at..].iter().find(|&&byte| byte != 0xff)?;
+        at += data[at..].iter().take_while(|&&byte| byte == 0xff).count() + 1;
+        // Standalone markers have no segment
+        if matches!(marker, 0x01 | 0xd0..=0xd7) {
+            stripped.extend([0xff, marker]);
+            continue;
+        }
+        if marker == 0xd9 {
+            stripped.extend([0xff, marker]);
+            return Some(stripped);
+        }
+        let length = u16_be(data, at)?;
+        let segment = data.get(at..at + length).filter(|_| length >= 2)?;
+        let payload = &segment[2..];
+        let keep = match marker {
+            0xe0 => payload.starts_with(b"JFIF\0") || payload.starts_with(b"JFXX\0"),
+            0xe2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xee => payload.starts_with(b"Adobe"),
+            0xe1..=0xef | 0xfe => false,
+            _ => true,
+        };
+        if keep {
+            stripped.extend([0xff, marker]);
+            stripped.extend_from_slice(segment);
+        }
+        at += length;
+        // Entropy-coded data follows the start of scan, and is copied as it is
+        if marker == 0xda {
+            stripped.extend_from_slice(&data[at..]);
+            return Some(stripped);
+        }
+    }
+}
+
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut stripped = SIGNATURE.to_vec();
+    let mut at = SIGNATURE.len();
+    loop {
+        let length = u32_be(data, at)?;
+        let chunk = data.get(at..(at + 12).checked_add(length)?)?;
+        let kind = &chunk[4..8];
+        if !PNG_DROPPED.iter().any(|dropped| &dropped[..] == kind) {
+            stripped.extend_from_slice(chunk);
+        }
+        at += chunk.len();
+        if kind == b"IEND" {
+            return Some(stripped);
+        }
+    }
+}
+
+/// Drop EXIF and XMP chunks, along with the extended header's flags saying they're present
+fn strip_webp(data: &[u8]) -> Option<Vec<u8>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let end = u32_le(data, 4)?.checked_add(8)?.min(data.len());
+    let mut stripped = data[..12].to_vec();
+    let mut at = 12;
+    while at < end {
+        let length = u32_le(data, at + 4)?;
+        let padded = length + length % 2;
+        let chunk = data.get(at..(at + 8).checked_add(padded)?.min(end));
+        let chunk = chunk.filter(|chunk| chunk.len() >= 8)?;
+        match &chunk[..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let flags = stripped.len() + 8;
+                stripped.extend_from_slice(chunk);
+                *stripped.get_mut(flags)? &= !0x0c;
+            }
+            _ => stripped.extend_from_slice(chunk),
+        }
+        at += chunk.len();
+    }
+    let size = u32::try_from(stripped.len() - 8).ok()?;
+    stripped[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(stripped)
+}
+
+/// Blank `udta` and `meta` boxes by turning them into `free` space, rather than removing
+/// them, so the offsets of media data elsewhere in the file stay valid
+fn strip_mp4(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = data.to_vec();
+    blank_boxes(&mut stripped, 0, data.len())?;
+    Some(stripped)
+}
+
+fn blank_boxes(data: &mut [u8], mut at: usize, end: usize) -> Option<()> {
+    while at < end {
+        let (header, size) = match u32_be(data, at)? {
+            0 => (8, end - at),
+            1 => {
+                let large = data.get(at + 8..at + 16)?;
+                (
+                    16,
+                    usize::try_from(u64::from_be_bytes(large.try_into().ok()?)).ok()?,
+                )
+            }
+            size => (8, size),
+        };
+        if size < header || at.checked_add(size)? > end {
+            return None;
+        }
+        let kind = &data[at + 4..at + 8];
+        if kind == b"udta" || kind == b"meta" {
+            data[at + 4..at + 8].copy_from_slice(b"free");
+            data[at + header..at + size].fill(0);
+        } else if MP4_CONTAINERS
+            .iter()
+            .any(|container| &container[..] == kind)
+        {
+            blank_boxes(data, at + header, at + size)?;
+        }
+        at += size;
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let length = (payload.len() as u16 + 2).to_be_bytes();
+        [&[0xff, marker][..], &length, payload].concat()
+    }
+
+    fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u32).to_be_bytes();
+        [&length[..], kind, data, &[0; 4]].concat()
+    }
+
+    #[test]
+    fn strips_jpeg_exif() {
+        let jfif = segment(0xe0, b"JFIF\0\x01\x01");
+        let icc = segment(0xe2, b"ICC_PROFILE\0\x01\x01");
+        let scan = [
+            segment(0xda, b"\x01\x02"),
+            b"\x12\xff\x00\x34\xff\xd9".to_vec(),
+        ];
+        let jpeg = [
+            vec![0xff, 0xd8],
+            jfif.clone(),
+            segment(0xe1, b"Exif\0\0GPS 51.5N"),
+            segment(0xe1, b"http://ns.adobe.com/xap/1.0/\0<x/>"),
+            segment(0xfe, b"taken on my phone"),
+            icc.clone(),
+            segment(0xdb, b"\x00\x10"),
+            scan.concat(),
+        ]
+        .concat();
+        let expected = [
+            vec![0xff, 0xd8],
+            jfif,
+            icc,
+            segment(0xdb, b"\x00\x10"),
+            scan.concat(),
+        ]
+        .concat();
+        assert_eq!(strip(&jpeg, "image/jpeg").unwrap(), expected);
+        assert_eq!(
+            strip(&jpeg[..20], "image/jpeg"),
+            Err(MetadataError::Malformed {
+                mime_type: "image/jpeg".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn strips_png_text_and_exif() {
+        let signature = b"\x89PNG\r\n\x1a\n".to_vec();
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let image = png_chunk(b"IDAT", b"pixels");
+        let end = png_chunk(b"IEND", b"");
+        let png = [
+            signature.clone(),
+            header.clone(),
+            png_chunk(b"eXIf", b"MM\0*GPS"),
+            png_chunk(b"tEXt", b"Author\0someone"),
+            image.clone(),
+            end.clone(),
+        ]
+        .concat();
+        let expected = [signature, header, image, end].concat();
+        assert_eq!(strip(&png, "image/png").unwrap(), expected);
+        // Types without metadata pass through
+        assert_eq!(strip(b"GIF89a", "image/gif").unwrap(), b"GIF89a");
+    }
+
+    #[test]
+    fn strips_webp_and_mp4_metadata() {
+        let chunk = |kind: &[u8; 4], data: &[u8]| {
+            let length = (data.len() as u32).to_le_bytes();
+            let padding = vec![0; data.len() % 2];
+            [&kind[..], &length, data, &padding].concat()
+        };
+        let riff = |chunks: Vec<Vec<u8>>| {
+            let body = [b"WEBP".to_vec(), chunks.concat()].concat();
+            [
+                b"RIFF".to_vec(),
+                (body.len() as u32).to_le_bytes().to_vec(),
+                body,
+            ]
+            .concat()
+        };
+        let mut flags = [0u8; 10];
+        flags[0] = 0x0c;
+        let webp = riff(vec![
+            chunk(b"VP8X", &flags),
+            chunk(b"VP8 ", b"frame"),
+            chunk(b"EXIF", b"GPS"),
+            chunk(b"XMP ", b"<x/>"),
+        ]);
+        let expected = riff(vec![chunk(b"VP8X", &[0; 10]), chunk(b"VP8 ", b"frame")]);
+        assert_eq!(strip(&webp, "image/webp").unwrap(), expected);
+
+        let mp4_box = |kind: &[u8; 4], data: &[u8]| {
+            [&(data.len() as u32 + 8).to_be_bytes()[..], kind, data].concat()
+        };
+        let udta = mp4_box(b"udta", b"\xa9xyz+51.5-000.1/");
+        let mdat = mp4_box(b"mdat", b"frames");
+        let moov = |udta: &[u8]| {
+            mp4_box(
+                b"moov",
+                &[mp4_box(b"mvhd", b"header"), udta.to_vec()].concat(),
+            )
+        };
+        let mp4 = [mp4_box(b"ftyp", b"isom"), moov(&udta), mdat.clone()].concat();
+        let blanked = mp4_box(b"free", &[0; 16]);
+        let expected = [mp4_box(b"ftyp", b"isom"), moov(&blanked), mdat].concat();
+        assert_eq!(strip(&mp4, "video/mp4").unwrap(), expected);
+        assert_eq!(strip(&mp4, "video/mp4").unwrap().len(), mp4.len());
+    }
+}
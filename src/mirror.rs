@@ -0,0 +1,142 @@
+//! Mirroring one way only
+//!
+//! An institution wanting a broadcast presence on the other network, without the bridge
+//! interacting for it, can set `FEDIBRIDGE_MIRROR_ONLY_TO` to the network posts are mirrored
+//! to (`fediverse` or `bluesky`). Posts, their edits and deletions, boosts and profiles go that
+//! way as usual, and nothing else is written anywhere:
+//!
+//! - nothing goes back to the network posts come from: no likes, follows or replies are
+//!   bridged there, and no reports or messages are sent
+//! - on the network mirrored to, no one is liked, followed back, blocked or listed
+//!
+//! Follows of the mirrored accounts are still accepted, as that's how people see their posts,
+//! and deleting what was written before is still allowed
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::digest::Network;
+use crate::jobs::Job;
+use crate::json;
+use crate::repo::Write;
+
+/// What's sent to the fediverse when mirroring to it: posts and what becomes of them, and
+/// answers to follows
+const FEDIVERSE_CONTENT: [&str; 7] = [
+    "Create", "Update", "Delete", "Announce", "Undo", "Accept", "Reject",
+];
+/// What's committed for fediverse accounts when mirroring to Bluesky
+const BLUESKY_CONTENT: [&str; 5] = [
+    "app.bsky.feed.post",
+    "app.bsky.feed.repost",
+    "app.bsky.actor.profile",
+    "app.bsky.feed.threadgate",
+    "app.bsky.feed.postgate",
+];
+
+/// Whether `delivery` may go to its fediverse inbox
+pub fn delivery_allowed(bridge: &Bridge, delivery: &Delivery) -> bool {
+    let Some(to) = bridge.mirror_to else {
+        return true;
+    };
+    let activity = json::parse(&delivery.activity).ok();
+    let kind = activity.as_ref().and_then(|a| a.get("type")?.as_str());
+    match (to, kind) {
+        (Network::Fediverse, Some(kind)) => FEDIVERSE_CONTENT.contains(&kind),
+        // The fediverse is where posts come from, so it's only answered when followed
+        (Network::Bluesky, Some(kind)) => matches!(kind, "Accept" | "Reject"),
+        (_, None) => false,
+    }
+}
+
+/// Whether `write` may be committed to a bridged fediverse account's repo
+pub fn commit_allowed(bridge: &Bridge, write: &Write) -> bool {
+    if matches!(write, Write::Delete { .. }) {
+        return true;
+    }
+    match bridge.mirror_to {
+        None => true,
+        // Bluesky is where posts come from, so nothing new is written there
+        Some(Network::Fediverse) => false,
+        Some(Network::Bluesky) => {
+            let collection = write.path().split('/').next().unwrap_or_default();
+            BLUESKY_CONTENT.contains(&collection)
+        }
+    }
+}
+
+/// Whether `job`, which isn't a delivery, may be run: reports and messages are interactions
+pub fn job_allowed(bridge: &Bridge, job: &Job) -> bool {
+    bridge.mirror_to.is_none() || !matches!(job, Job::CreateReport(_) | Job::SendChatMessage(_))
+}
+
+/// The ways posts go across the bridge, from and to, as [discovery](crate::discovery)
+/// describes them
+pub fn directions(bridge: &Bridge) -> Vec<(Network, Network)> {
+    let both = [
+        (Network::Bluesky, Network::Fediverse),
+        (Network::Fediverse, Network::Bluesky),
+    ];
+    let mirrored = |(_, to): &(Network, Network)| bridge.mirror_to.is_none_or(|only| only == *to);
+    both.into_iter().filter(mirrored).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::Value;
+
+    fn delivery(kind: &str) -> Delivery {
+        Delivery::new(
+            "https://m.example/inbox",
+            format!(r#"{{"type": "{kind}", "actor": "https://bridge.example/ap/alice"}}"#),
+        )
+    }
+
+    fn create(collection: &str) -> Write {
+        Write::Create {
+            path: format!("{collection}/3k"),
+            record: Value::Object(Default::default()),
+        }
+    }
+
+    #[test]
+    fn only_content_goes_the_mirrored_way() {
+        let both = Bridge::new();
+        assert!(delivery_allowed(&both, &delivery("Like")));
+        assert!(commit_allowed(&both, &create("app.bsky.feed.like")));
+        assert_eq!(directions(&both).len(), 2);
+
+        let to_fediverse = Bridge::new().with_mirror_only_to(Some(Network::Fediverse));
+        assert!(delivery_allowed(&to_fediverse, &delivery("Create")));
+        assert!(delivery_allowed(&to_fediverse, &delivery("Accept")));
+        assert!(!delivery_allowed(&to_fediverse, &delivery("Like")));
+        assert!(!delivery_allowed(&to_fediverse, &delivery("Follow")));
+        assert!(!commit_allowed(
+            &to_fediverse,
+            &create("app.bsky.feed.like")
+        ));
+        assert!(!commit_allowed(
+            &to_fediverse,
+            &create("app.bsky.feed.post")
+        ));
+        let delete = Write::Delete {
+            path: "app.bsky.feed.post/3k".to_string(),
+            swap: None,
+        };
+        assert!(commit_allowed(&to_fediverse, &delete));
+        assert_eq!(
+            directions(&to_fediverse),
+            [(Network::Bluesky, Network::Fediverse)]
+        );
+
+        let to_bluesky = Bridge::new().with_mirror_only_to(Some(Network::Bluesky));
+        assert!(!delivery_allowed(&to_bluesky, &delivery("Create")));
+        assert!(delivery_allowed(&to_bluesky, &delivery("Accept")));
+        assert!(commit_allowed(&to_bluesky, &create("app.bsky.feed.post")));
+        assert!(!commit_allowed(&to_bluesky, &create("app.bsky.feed.like")));
+        assert!(!commit_allowed(
+            &to_bluesky,
+            &create("app.bsky.graph.follow")
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+//! Misskey and its forks (Firefish, Sharkey, Iceshrimp)
+//!
+//! Their objects stray from plain AS2 in ways which trip up a naive reading:
+//!
+//! - Quotes are named by one of several fields (`quoteUrl`, `_misskey_quote`, Fedibird's
+//!   `quoteUri`, or an FEP-e232 `Link` tag), and the content ends with an `RE: <link>`
+//!   fallback for software which doesn't know any of them
+//! - MFM, Misskey's markup, leaks into content as `$[x2 text]`-style functions where the
+//!   HTML rendering leaves them in, and is all there is when `content` is missing
+//! - Emoji reactions arrive as `EmojiReact`, or as a `Like` with a `_misskey_reaction`
+//!
+//! [`normalize`] irons these out before an object is bridged: the quote is left in
+//! `quoteUrl` alone with the fallback stripped, MFM is flattened to its text, and reactions
+//! become plain `Like`s, as Bluesky has nothing else. Fields nothing reads (`isCat` and the
+//! like) are left alone
+
+use crate::html;
+use crate::json::Value;
+
+/// Where quoted posts are named, most specific first
+pub const QUOTE_FIELDS: &[&str] = &["_misskey_quote", "quoteUrl", "quoteUri"];
+
+/// The `rel` of FEP-e232 quote links
+const QUOTE_REL: &str = "https://misskey-hub.net/ns#_misskey_quote";
+
+/// Tags MFM has, with no meaning outside it
+const MFM_TAGS: &[&str] = &["center", "small", "plain"];
+
+/// The post `object` quotes, if any
+pub fn quote(object: &Value) -> Option<String> {
+    let field = QUOTE_FIELDS
+        .iter()
+        .find_map(|name| object.get(name)?.as_str());
+    let tags = object
+        .get("tag")
+        .and_then(Value::as_array)
+        .unwrap_or_default();
+    let link = tags.iter().find_map(|tag| {
+        let rel = tag.get("rel");
+        let rels = match rel {
+            Some(Value::Array(rels)) => rels.iter().filter_map(Value::as_str).collect(),
+            Some(rel) => rel.as_str().into_iter().collect(),
+            None => Vec::new(),
+        };
+        let quotes =
+            tag.get("type").and_then(Value::as_str) == Some("Link") && rels.contains(&QUOTE_REL);
+        quotes.then(|| tag.get("href")?.as_str()).flatten()
+    });
+    field.or(link).map(str::to_string)
+}
+
+/// MFM `text` without its markup
+///
+/// Functions (`$[name.args text]`) keep their text, MFM's own tags are dropped, and so are
+/// bold and strikethrough markers
+pub fn flatten_mfm(text: &str) -> String {
+    let mut flat = String::with_capacity(text.len());
+    // How many functions are open, so their closing brackets can be dropped
+    let mut open = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some(function) = rest.strip_prefix("$[") {
+            let name_end = function.find(char::is_whitespace).unwrap_or(function.len());
+            rest = function[name_end..]
+                .strip_prefix(char::is_whitespace)
+                .unwrap_or(&function[name_end..]);
+            open += 1;
+            continue;
+        }
+        if c == ']' && open > 0 {
+            open -= 1;
+            rest = &rest[1..];
+            continue;
+        }
+        let tag = MFM_TAGS.iter().find_map(|tag| {
+            let open_tag = format!("<{tag}>");
+            let close_tag = format!("</{tag}>");
+            [open_tag, close_tag]
+                .into_iter()
+                .find(|t| rest.starts_with(t.as_str()))
+                .map(|t| t.len())
+        });
+        if let Some(len) = tag {
+            rest = &rest[len..];
+            continue;
+        }
+        if rest.starts_with("**") || rest.starts_with("~~") {
+            rest = &rest[2..];
+            continue;
+        }
+        flat.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    flat
+}
+
+/// `content` without the `RE: <link>` fallback for `quote`
+fn strip_quote_fallback(content: &str, quote: &str) -> String {
+    // Newer versions mark it up, as Mastodon does
+    for start in [
+        "<span class=\"quote-inline\">",
+        "<p class=\"quote-inline\">",
+    ] {
+        let element = &start[1..start.find(' ').unwrap_or(1)];
+        if let Some(begin) = content.find(start) {
+            let end_tag = format!("</{element}>");
+            if let Some(length) = content[begin..].find(&end_tag) {
+                let end = begin + length + end_tag.len();
+                return format!("{}{}", &content[..begin], &content[end..])
+                    .trim_end_matches("<br>")
+                    .to_string();
+            }
+        }
+    }
+    let Some(begin) = content.rfind("RE: ") else {
+        return content.to_string();
+    };
+    let fallback = &content[begin..];
+    let text = html::tokenize(fallback)
+        .into_iter()
+        .filter_map(|token| match token {
+            html::Token::Text(text) => Some(text),
+            _ => None,
+        });
+    let text: String = text.collect();
+    match text.trim().strip_prefix("RE: ") {
+        Some(url) if url.trim() == quote => {
+            let mut kept = content[..begin].trim_end();
+            while let Some(shorter) = kept.strip_suffix("<br>") {
+                kept = shorter.trim_end();
+            }
+            // Leave any paragraph the fallback closed closed
+            let closing = fallback.rfind("</p>").map_or("", |_| "</p>");
+            let kept = kept.strip_suffix("<p>").unwrap_or(kept);
+            format!(
+                "{kept}{}",
+                if kept.ends_with("</p>") { "" } else { closing }
+            )
+        }
+        _ => content.to_string(),
+    }
+}
+
+/// `object` with Misskey's quirks ironed out, as described in the module docs
+pub fn normalize(object: &Value) -> Value {
+    let Value::Object(fields) = object else {
+        return object.clone();
+    };
+    let mut fields = fields.clone();
+    if fields.get("type").and_then(Value::as_str) == Some("EmojiReact")
+        || fields.contains_key("_misskey_reaction")
+    {
+        fields.insert("type".into(), Value::from("Like"));
+        fields.remove("_misskey_reaction");
+        fields.remove("content");
+        fields.remove("tag");
+        return Value::Object(fields);
+    }
+    if let Some(inner @ Value::Object(_)) = fields.get("object") {
+        let inner = normalize(inner);
+        fields.insert("object".into(), inner);
+    }
+    let quoted = quote(object);
+    for name in QUOTE_FIELDS {
+        fields.remove(*name);
+    }
+    let source = fields.remove("_misskey_content");
+    let content = match fields.get("content").and_then(Value::as_str) {
+        Some(content) => Some(content.to_string()),
+        // Notes with nothing but a quote have no content at all
+        None => source
+            .as_ref()
+            .and_then(Value::as_str)
+            .map(|mfm| html::escape(&flatten_mfm(mfm)).replace('\n', "<br>")),
+    };
+    if let Some(mut content) = content {
+        if content.contains("$[") {
+            content = flatten_mfm(&content);
+        }
+        if let Some(quote) = &quoted {
+            content = strip_quote_fallback(&content, quote);
+        }
+        fields.insert("content".into(), Value::from(content));
+    }
+    if let Some(quote) = quoted {
+        fields.insert("quoteUrl".into(), Value::from(quote));
+    }
+    Value::Object(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn flattens_mfm() {
+        assert_eq!(
+            flatten_mfm("$[x2 big] and $[fg.color=f00 red]"),
+            "big and red"
+        );
+        assert_eq!(flatten_mfm("$[spin $[bounce nested]]!"), "nested!");
+        assert_eq!(flatten_mfm("<center>**hi**</center> [link]"), "hi [link]");
+    }
+
+    #[test]
+    fn normalizes_quotes_and_reactions() {
+        let note = json::parse(
+            r#"{"type": "Note", "_misskey_quote": "https://misskey.example/notes/q",
+                "quoteUrl": "https://misskey.example/notes/q", "isCat": true,
+                "content": "<p>look $[tada at this]<br><br>RE: <a href=\"https://misskey.example/notes/q\">https://misskey.example/notes/q</a></p>",
+                "_misskey_content": "look $[tada at this]"}"#,
+        )
+        .unwrap();
+        let normalized = normalize(&note);
+        assert_eq!(
+            normalized.get("content"),
+            Some(&Value::from("<p>look at this</p>"))
+        );
+        let quoted = Value::from("https://misskey.example/notes/q");
+        assert_eq!(normalized.get("quoteUrl"), Some(&quoted));
+        assert_eq!(normalized.get("_misskey_quote"), None);
+        assert_eq!(normalized.get("isCat"), Some(&Value::from(true)));
+
+        let tagged = json::parse(
+            r#"{"type": "Note", "content": "<p>hm</p>", "tag": [{"type": "Link",
+                "rel": "https://misskey-hub.net/ns#_misskey_quote", "href": "https://a.example/n/1"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(quote(&tagged), Some("https://a.example/n/1".to_string()));
+
+        let reaction = json::parse(
+            r#"{"type": "Like", "_misskey_reaction": ":blobcat:", "content": ":blobcat:",
+                "object": "https://bsky.example/p/1"}"#,
+        )
+        .unwrap();
+        let like = normalize(&reaction);
+        assert_eq!(like.get("type"), Some(&Value::from("Like")));
+        assert_eq!(like.get("content"), None);
+        let react = json::parse(r#"{"type": "EmojiReact", "content": "🎉"}"#).unwrap();
+        assert_eq!(normalize(&react).get("type"), Some(&Value::from("Like")));
+    }
+}
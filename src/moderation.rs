@@ -0,0 +1,382 @@
+//! Bridging moderation reports
+//!
+//! A fediverse `Flag` against a bridged account or its posts becomes a
+//! `com.atproto.moderation.createReport` call to the configured report service, run as a
+//! [`Job::CreateReport`] so it's retried like any other outbound request.
+//!
+//! In the other direction [`ReportEndpoint`] accepts `createReport` calls about bridged
+//! fediverse content (as a labeler or moderation service would) and forwards them to the
+//! origin instance as a `Flag` from the bridge's instance actor, where they reach its admins
+//!
+//! Records can only be reported by their AT URI, since the bridge doesn't track the CIDs
+//! which `createReport` wants for them, so reports always target the whole account and list
+//! the reported objects in their reason
+
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::http::{Handler, Method, Request, Response};
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::modstream::EventKind;
+use crate::normalize;
+use crate::store::IdentityStore;
+use crate::time::unique_millis;
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use atproto::at_uri::{AtUri, Authority};
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The XRPC method reports are made with
+pub const CREATE_REPORT: &str = "com.atproto.moderation.createReport";
+/// The reason type used for reports from the fediverse, which don't categorise them
+pub const REASON_OTHER: &str = "com.atproto.moderation.defs#reasonOther";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Where reports are sent, and who from
+pub struct ModerationConfig {
+    /// Base URL of the XRPC service `createReport` is called on. Without one, `Flag`s are
+    /// queued but can't be sent
+    pub report_service: Option<String>,
+    /// Bearer token for the report service
+    pub report_token: Option<String>,
+    /// IRI of the actor forwarded reports are sent from. Without one, [`ReportEndpoint`]
+    /// refuses reports
+    pub instance_actor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A report about a bridged atproto account
+pub struct Report {
+    pub subject: Did,
+    pub reason: String,
+    pub cause: Option<Cause>,
+}
+
+#[derive(Debug, Error)]
+/// Errors sending a report
+pub enum ReportError {
+    #[error("No report service is configured")]
+    NotConfigured,
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Report service rejected the report with status {status}")]
+    Rejected { status: u16 },
+}
+
+/// The ids of a property holding a single object or link, or an array of them
+fn ids(value: Option<&Value>) -> Vec<&str> {
+    fn id(v: &Value) -> Option<&str> {
+        v.as_str().or_else(|| v.get("id").and_then(Value::as_str))
+    }
+    match value {
+        Some(Value::Array(items)) => items.iter().filter_map(id).collect(),
+        Some(v) => id(v).into_iter().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// The reports to make for a `Flag` activity, one per bridged account it concerns
+///
+/// Objects are attributed to an account when they are its actor or are under the actor's
+/// IRI. Anything else in the flag isn't bridged content and is ignored
+pub fn reports_from_flag(flag: &Value, identities: &IdentityStore) -> Vec<Report> {
+    if flag.get("type").and_then(Value::as_str) != Some("Flag") {
+        return Vec::new();
+    }
+    let mut objects: BTreeMap<Did, Vec<&str>> = BTreeMap::new();
+    for object in ids(flag.get("object")) {
+        if let Some(mapping) = identities.get_by_object(object) {
+            let reported = objects.entry(mapping.did).or_default();
+            if object != mapping.actor {
+                reported.push(object);
+            }
+        }
+    }
+    let reporter = flag
+        .get("actor")
+        .and_then(Value::as_str)
+        .unwrap_or("an unknown actor");
+    let content = flag.get("content").and_then(Value::as_str).unwrap_or("");
+    let flag_id = flag.get("id").and_then(Value::as_str);
+    objects
+        .into_iter()
+        .map(|(subject, reported)| {
+            let mut reason = format!("Reported from the fediverse by {reporter}");
+            if !reported.is_empty() {
+                reason.push_str(&format!(" about {}", reported.join(", ")));
+            }
+            if !content.is_empty() {
+                reason.push_str(&format!(": {content}"));
+            }
+            Report {
+                subject,
+                reason,
+                cause: Some(Cause::new("fediverse report", flag_id)),
+            }
+        })
+        .collect()
+}
+
+/// Queue the reports for a received `Flag`, returning how many there were
+pub fn flag_received(bridge: &Bridge, flag: &Value) -> io::Result<usize> {
+    let reporter = flag.get("actor").and_then(Value::as_str);
+    if reporter.is_some_and(|actor| bridge.policy.for_actor(&bridge.identities, actor).denied) {
+        return Ok(0);
+    }
+    let reports = reports_from_flag(flag, &bridge.identities);
+    let count = reports.len();
+    for report in reports {
+        let details = Value::object([
+            ("to", Value::from("bluesky")),
+            ("reason", Value::from(report.reason.as_str())),
+            ("flag", Value::from(flag.get("id").and_then(Value::as_str))),
+        ]);
+        let events = &bridge.moderation_events;
+        events.publish(EventKind::Report, report.subject.as_str(), details);
+        bridge.jobs.push(Job::CreateReport(report))?;
+    }
+    Ok(count)
+}
+
+/// Call `createReport` on the configured report service
+pub fn create_report(
+    transport: &dyn HttpTransport,
+    config: &ModerationConfig,
+    report: &Report,
+) -> Result<(), ReportError> {
+    let service = config
+        .report_service
+        .as_ref()
+        .ok_or(ReportError::NotConfigured)?;
+    let body = Value::object([
+        ("reasonType", Value::from(REASON_OTHER)),
+        ("reason", Value::from(report.reason.as_str())),
+        (
+            "subject",
+            Value::object([
+                ("$type", Value::from("com.atproto.admin.defs#repoRef")),
+                ("did", Value::from(report.subject.as_str())),
+            ]),
+        ),
+    ]);
+    let url = format!("{}/xrpc/{CREATE_REPORT}", service.trim_end_matches('/'));
+    let mut request = OutboundRequest::post(url, body.to_string())
+        .with_header("content-type", "application/json");
+    if let Some(token) = &config.report_token {
+        request = request.with_header("authorization", &format!("Bearer {token}"));
+    }
+    let response = transport.send(&request)?;
+    if !response.is_success() {
+        return Err(ReportError::Rejected {
+            status: response.status,
+        });
+    }
+    Ok(())
+}
+
+/// Handler for `createReport` calls about bridged fediverse content
+pub struct ReportEndpoint {
+    bridge: Arc<Bridge>,
+}
+
+impl ReportEndpoint {
+    pub fn new(bridge: Arc<Bridge>) -> ReportEndpoint {
+        ReportEndpoint { bridge }
+    }
+
+    fn create_report(&self, request: &Request) -> Result<Response, Response> {
+        let instance_actor = self
+            .bridge
+            .moderation
+            .instance_actor
+            .as_ref()
+            .ok_or_else(|| Response::error(503, "Report forwarding isn't configured"))?;
+        let invalid = |message: &str| Response::error(400, message);
+        let body = std::str::from_utf8(&request.body).map_err(|_| invalid("Invalid body"))?;
+        let body = json::parse(body).map_err(|e| invalid(&e.to_string()))?;
+        let reason_type = body
+            .get("reasonType")
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid("Missing reasonType"))?;
+        let subject = body
+            .get("subject")
+            .ok_or_else(|| invalid("Missing subject"))?;
+        let (did, uri) = match (subject.get("did"), subject.get("uri")) {
+            (Some(did), _) => {
+                let did = did.as_str().ok_or_else(|| invalid("Invalid subject"))?;
+                (normalize::parse_did(did), None)
+            }
+            (None, Some(uri)) => {
+                let uri = uri.as_str().ok_or_else(|| invalid("Invalid subject"))?;
+                let uri =
+                    AtUri::try_create(uri.to_string()).map_err(|e| invalid(&e.to_string()))?;
+                let Authority::Did(did) = uri.authority() else {
+                    return Err(invalid("Subject URI must use a DID"));
+                };
+                (Ok(did.clone()), Some(uri))
+            }
+            (None, None) => return Err(invalid("Invalid subject")),
+        };
+        let did = did.map_err(|e| invalid(&e.to_string()))?;
+        let mapping = self
+            .bridge
+            .identities
+            .get(&did)
+            .ok_or_else(|| Response::error(404, format!("{did} isn't bridged content")))?;
+        let inbox = delivery::shared_inbox(&self.bridge.documents, &mapping.actor)
+            .ok_or_else(|| Response::error(500, "Couldn't find the origin instance's inbox"))?;
+
+        let mut content = format!("Reported from Bluesky ({reason_type})");
+        if let Some(uri) = &uri {
+            content.push_str(&format!(" about {uri}"));
+        }
+        if let Some(reason) = body.get("reason").and_then(Value::as_str) {
+            content.push_str(&format!(": {reason}"));
+        }
+        let id = unique_millis();
+        let flag = Value::object([
+            (
+                "@context",
+                Value::from("https://www.w3.org/ns/activitystreams"),
+            ),
+            ("id", Value::from(format!("{instance_actor}#reports/{id}"))),
+            ("type", Value::from("Flag")),
+            ("actor", Value::from(instance_actor.as_str())),
+            ("object", Value::Array(vec![Value::from(mapping.actor)])),
+            ("content", Value::from(content)),
+        ]);
+        self.bridge
+            .jobs
+            .push(Job::Deliver(
+                Delivery::new(inbox, flag.to_string()).because(Cause::new("Bluesky report", None)),
+            ))
+            .map_err(|e| Response::error(500, e.to_string()))?;
+        let details = Value::object([
+            ("to", Value::from("fediverse")),
+            ("reasonType", Value::from(reason_type)),
+            ("reason", body.get("reason").cloned().unwrap_or(Value::Null)),
+            ("uri", Value::from(uri.as_ref().map(|uri| uri.to_string()))),
+        ]);
+        let events = &self.bridge.moderation_events;
+        events.publish(EventKind::Report, did.as_str(), details);
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("id", Value::from(id)),
+                ("reasonType", Value::from(reason_type)),
+                ("subject", subject.clone()),
+            ]),
+        ))
+    }
+}
+
+impl Handler for ReportEndpoint {
+    fn handle(&self, request: &Request) -> Response {
+        match (request.method, request.segments().as_slice()) {
+            (Method::Post, ["xrpc", method]) if *method == CREATE_REPORT => {
+                self.create_report(request).unwrap_or_else(|e| e)
+            }
+            _ => Response::error(404, "Not found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Mapping;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+    const BOB: Did = did!("did:plc:bob");
+    const BOB_ACTOR: &str = "https://remote.example/users/bob";
+
+    fn bridge() -> Bridge {
+        let bridge = Bridge::new().with_moderation(ModerationConfig {
+            report_service: Some("https://mod.example".to_string()),
+            report_token: Some("secret".to_string()),
+            instance_actor: Some("https://bridge.example/actor".to_string()),
+        });
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        bridge.identities.insert(Mapping::new(BOB, BOB_ACTOR));
+        bridge
+    }
+
+    #[test]
+    fn flags_become_reports() {
+        let bridge = bridge();
+        let flag = json::parse(&format!(
+            r#"{{
+                "id": "https://remote.example/flags/1",
+                "type": "Flag",
+                "actor": "https://remote.example/actor",
+                "content": "spam",
+                "object": ["{ALICE_ACTOR}", "{ALICE_ACTOR}/posts/1", "https://elsewhere.example/1"]
+            }}"#
+        ))
+        .unwrap();
+        assert_eq!(
+            reports_from_flag(&flag, &bridge.identities),
+            vec![Report {
+                subject: ALICE,
+                reason: format!(
+                    "Reported from the fediverse by https://remote.example/actor about \
+                     {ALICE_ACTOR}/posts/1: spam"
+                ),
+                cause: Some(Cause::new(
+                    "fediverse report",
+                    Some("https://remote.example/flags/1")
+                )),
+            }]
+        );
+        assert_eq!(flag_received(&bridge, &flag).unwrap(), 1);
+
+        let mock = MockTransport::new();
+        let url = format!("https://mod.example/xrpc/{CREATE_REPORT}");
+        mock.respond(Method::Post, &url, OutboundResponse::new(200));
+        let report = &reports_from_flag(&flag, &bridge.identities)[0];
+        create_report(&mock, &bridge.moderation, report).unwrap();
+        let sent = &mock.requests_to(&url)[0];
+        assert_eq!(sent.header("authorization"), Some("Bearer secret"));
+        let body = json::parse(std::str::from_utf8(&sent.body).unwrap()).unwrap();
+        assert_eq!(
+            body.get("subject").and_then(|s| s.get("did")),
+            Some(&Value::from(ALICE.as_str()))
+        );
+        assert!(matches!(
+            create_report(&mock, &ModerationConfig::default(), report),
+            Err(ReportError::NotConfigured)
+        ));
+    }
+
+    #[test]
+    fn reports_are_forwarded_to_origin() {
+        let bridge = Arc::new(bridge());
+        let endpoint = ReportEndpoint::new(bridge.clone());
+        let report = |subject: &str| {
+            let body = format!(r#"{{"reasonType": "{REASON_OTHER}", "subject": {subject}}}"#);
+            endpoint.handle(
+                &Request::new(Method::Post, &format!("/xrpc/{CREATE_REPORT}")).with_body(body),
+            )
+        };
+        let response = report(r#"{"uri": "at://did:plc:bob/app.bsky.feed.post/3k"}"#);
+        assert_eq!(response.status, 200);
+        let queued = bridge.jobs.queued();
+        let Job::Deliver(delivery) = &queued[0].job else {
+            panic!("expected a delivery, got {:?}", queued[0].job);
+        };
+        assert_eq!(delivery.inbox, "https://remote.example/inbox");
+        let flag = json::parse(&delivery.activity).unwrap();
+        assert_eq!(flag.get("type"), Some(&Value::from("Flag")));
+        assert_eq!(ids(flag.get("object")), vec![BOB_ACTOR]);
+
+        assert_eq!(report(r#"{"did": "did:plc:carol"}"#).status, 404);
+        assert_eq!(report(r#"{"did": "nope"}"#).status, 400);
+    }
+}
@@ -0,0 +1,356 @@
+//! A stream of moderation events, for tooling outside the bridge to govern it by
+//!
+//! Fediverse instances are moderated with tools of their own, which want to see what crosses
+//! the bridge as it happens rather than poll for it. Each event moderators would want to know
+//! of is sequenced in a [`ModerationStream`]:
+//!
+//! - `report`: a report passed on, from the fediverse to Bluesky or the other way
+//! - `label`: a label the [labeler](crate::labeler) applied
+//! - `policyHit`: a post a [content filter](crate::content) refused
+//! - `decision`: a decision a tool pushed back, once it's been taken
+//!
+//! The admin API streams them from `GET /admin/moderation/events` over a WebSocket, one JSON
+//! text message each, from after `cursor` if it's given. `kinds` narrows them to a
+//! comma-separated list of those above. Only the latest [`BACKLOG_LEN`] are kept, in memory,
+//! so a tool which falls behind further than that, or across a restart, misses some.
+//!
+//! Tools decide with `POST /admin/moderation/decisions`, as a [`ModerationDecision`]:
+//! `{"action": "suspend", "did": ..., "reason": ...}` suspends a bridged identity, as
+//! [suspending](crate::sanctions::suspend) it from the admin API does, and
+//! `{"action": "stripMedia", "subject": ...}` has the media stripped from what a domain or
+//! DID sends, adding to its [policy](crate::policy) rule
+
+use crate::bridge::Bridge;
+use crate::http::{Request, Response};
+use crate::json::Value;
+use crate::normalize;
+use crate::policy::{PolicyAction, PolicyError, Rule, Subject};
+use crate::sanctions::{self, SanctionError};
+use crate::time::format_rfc3339;
+use crate::websocket::{self, WebSocket, GOING_AWAY};
+use atproto::DID::Did;
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// The most events kept for tools to catch up on
+pub const BACKLOG_LEN: usize = 10_000;
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Report,
+    Label,
+    PolicyHit,
+    Decision,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 4] = [
+        EventKind::Report,
+        EventKind::Label,
+        EventKind::PolicyHit,
+        EventKind::Decision,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Report => "report",
+            EventKind::Label => "label",
+            EventKind::PolicyHit => "policyHit",
+            EventKind::Decision => "decision",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EventKind> {
+        EventKind::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModerationEvent {
+    pub seq: i64,
+    pub kind: EventKind,
+    /// The account, post or instance it concerns
+    pub subject: String,
+    pub details: Value,
+    pub at: SystemTime,
+}
+
+impl ModerationEvent {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("seq", Value::from(self.seq)),
+            ("type", Value::from(self.kind.as_str())),
+            ("subject", Value::from(self.subject.as_str())),
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("details", self.details.clone()),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+struct Backlog {
+    events: VecDeque<ModerationEvent>,
+    /// The latest event's sequence number
+    seq: i64,
+}
+
+#[derive(Debug, Default)]
+/// The latest moderation events, in sequence
+pub struct ModerationStream {
+    inner: Mutex<Backlog>,
+    /// Signalled as each event is published
+    published: Condvar,
+}
+
+impl ModerationStream {
+    /// Sequence an event of `kind` about `subject`, returning its sequence number
+    pub fn publish(&self, kind: EventKind, subject: &str, details: Value) -> i64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.seq += 1;
+        let event = ModerationEvent {
+            seq: inner.seq,
+            kind,
+            subject: subject.to_string(),
+            details,
+            at: SystemTime::now(),
+        };
+        if inner.events.len() == BACKLOG_LEN {
+            inner.events.pop_front();
+        }
+        inner.events.push_back(event);
+        let seq = inner.seq;
+        drop(inner);
+        self.published.notify_all();
+        seq
+    }
+
+    /// The latest event's sequence number
+    pub fn seq(&self) -> i64 {
+        self.inner.lock().unwrap().seq
+    }
+
+    /// Every event kept after `after`
+    pub fn since(&self, after: i64) -> Vec<ModerationEvent> {
+        let inner = self.inner.lock().unwrap();
+        let events = inner.events.iter().filter(|event| event.seq > after);
+        events.cloned().collect()
+    }
+
+    /// Events after `after`, waiting up to `timeout` for one if there aren't any yet
+    pub fn wait(&self, after: i64, timeout: Duration) -> Vec<ModerationEvent> {
+        let inner = self.inner.lock().unwrap();
+        let (inner, _) = self
+            .published
+            .wait_timeout_while(inner, timeout, |inner| inner.seq <= after)
+            .unwrap();
+        let events = inner.events.iter().filter(|event| event.seq > after);
+        events.cloned().collect()
+    }
+}
+
+/// Send those of `events` of `kinds`, returning the sequence number of the last, or `seq` if
+/// there were none
+fn send(
+    socket: &mut WebSocket,
+    events: &[ModerationEvent],
+    kinds: &[EventKind],
+    seq: i64,
+) -> io::Result<i64> {
+    for event in events.iter().filter(|event| kinds.contains(&event.kind)) {
+        socket.send_text(&event.to_json().to_string())?;
+    }
+    Ok(events.last().map_or(seq, |event| event.seq))
+}
+
+/// Stream `bridge`'s moderation events to the WebSocket `request` opens, from after its
+/// `cursor` and of its `kinds`
+pub fn subscribe(bridge: Arc<Bridge>, request: &Request) -> Result<Response, Response> {
+    let invalid = |message: String| Response::error(400, message);
+    let cursor = match request.query_param("cursor") {
+        Some(cursor) => Some(
+            cursor
+                .parse::<i64>()
+                .map_err(|_| invalid(format!("Invalid cursor {cursor}")))?,
+        ),
+        None => None,
+    };
+    let kinds = match request.query_param("kinds") {
+        Some(kinds) => kinds
+            .split(',')
+            .map(|kind| EventKind::parse(kind.trim()).ok_or(kind))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|kind| invalid(format!("Invalid kind {kind}")))?,
+        None => EventKind::ALL.to_vec(),
+    };
+    Ok(websocket::accept(request, move |mut socket, shutdown| {
+        let events = &bridge.moderation_events;
+        let mut seq = match cursor {
+            Some(cursor) => send(&mut socket, &events.since(cursor), &kinds, cursor)?,
+            None => events.seq(),
+        };
+        while !shutdown.is_requested() {
+            let waited = events.wait(seq, POLL_INTERVAL);
+            seq = send(&mut socket, &waited, &kinds, seq)?;
+            if !socket.poll()? {
+                return Ok(());
+            }
+        }
+        socket.close(GOING_AWAY)
+    }))
+}
+
+#[derive(Debug, Error)]
+pub enum DecisionError {
+    #[error("Malformed decision - {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Policy(#[from] PolicyError),
+    #[error(transparent)]
+    Sanction(#[from] SanctionError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl DecisionError {
+    /// The status the admin API answers with
+    pub fn status(&self) -> u16 {
+        match self {
+            DecisionError::Malformed(_) | DecisionError::Policy(_) => 400,
+            DecisionError::Sanction(SanctionError::NotBridged { .. }) => 404,
+            DecisionError::Sanction(SanctionError::AlreadySuspended { .. }) => 409,
+            DecisionError::Sanction(SanctionError::InvalidDomain { .. }) => 400,
+            DecisionError::Sanction(SanctionError::Io(_)) | DecisionError::Io(_) => 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a moderation tool has decided
+pub enum ModerationDecision {
+    Suspend { did: Did, reason: Option<String> },
+    StripMedia { subject: Subject },
+}
+
+impl ModerationDecision {
+    pub fn from_json(value: &Value) -> Result<ModerationDecision, DecisionError> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let missing = |name: &str| DecisionError::Malformed(format!("it has no {name}"));
+        match field("action") {
+            Some("suspend") => {
+                let did = field("did").ok_or_else(|| missing("did"))?;
+                let did = normalize::parse_did(did)
+                    .map_err(|e| DecisionError::Malformed(e.to_string()))?;
+                let reason = field("reason").map(str::to_string);
+                Ok(ModerationDecision::Suspend { did, reason })
+            }
+            Some("stripMedia") => {
+                let subject = field("subject").ok_or_else(|| missing("subject"))?;
+                Ok(ModerationDecision::StripMedia {
+                    subject: subject.parse()?,
+                })
+            }
+            Some(other) => Err(DecisionError::Malformed(format!("{other} isn't an action"))),
+            None => Err(missing("action")),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        match self {
+            ModerationDecision::Suspend { did, reason } => Value::object([
+                ("action", Value::from("suspend")),
+                ("did", Value::from(did.as_str())),
+                ("reason", Value::from(reason.clone())),
+            ]),
+            ModerationDecision::StripMedia { subject } => Value::object([
+                ("action", Value::from("stripMedia")),
+                ("subject", Value::from(subject.to_string())),
+            ]),
+        }
+    }
+
+    fn subject(&self) -> String {
+        match self {
+            ModerationDecision::Suspend { did, .. } => did.to_string(),
+            ModerationDecision::StripMedia { subject } => subject.to_string(),
+        }
+    }
+}
+
+/// Take `decision`, publishing it as a `decision` event, and return what it did
+pub fn decide(bridge: &Bridge, decision: &ModerationDecision) -> Result<Value, DecisionError> {
+    let taken = match decision {
+        ModerationDecision::Suspend { did, reason } => {
+            let suspension = sanctions::suspend(bridge, did, reason.as_deref(), SystemTime::now())?;
+            suspension.to_json()
+        }
+        ModerationDecision::StripMedia { subject } => {
+            let rules = bridge.policy.rules();
+            let mut rule = rules
+                .into_iter()
+                .find(|rule| rule.subject == *subject)
+                .unwrap_or_else(|| Rule {
+                    subject: subject.clone(),
+                    actions: Vec::new(),
+                });
+            rule.add(PolicyAction::StripMedia);
+            bridge.policy.set(rule.clone())?;
+            rule.to_json()
+        }
+    };
+    let details = Value::object([("decision", decision.to_json()), ("taken", taken.clone())]);
+    let events = &bridge.moderation_events;
+    events.publish(EventKind::Decision, &decision.subject(), details);
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    #[test]
+    fn events_are_sequenced_and_decisions_taken() {
+        let bridge = Bridge::new();
+        let alice = did!("did:plc:alice");
+        bridge
+            .identities
+            .insert(Mapping::new(alice.clone(), "https://a.example/users/alice"));
+        let events = &bridge.moderation_events;
+        let details = Value::object([("val", Value::from("bridged"))]);
+        events.publish(EventKind::Label, "at://did:plc:alice", details);
+
+        let suspend = json::parse(r#"{"action": "suspend", "did": "did:plc:alice"}"#).unwrap();
+        let suspend = ModerationDecision::from_json(&suspend).unwrap();
+        decide(&bridge, &suspend).unwrap();
+        assert!(bridge.sanctions.is_suspended(&alice));
+        let again = decide(&bridge, &suspend).unwrap_err();
+        assert_eq!(again.status(), 409);
+        let strip = json::parse(r#"{"action": "stripMedia", "subject": "spam.example"}"#);
+        let strip = ModerationDecision::from_json(&strip.unwrap()).unwrap();
+        decide(&bridge, &strip).unwrap();
+        assert!(
+            bridge
+                .policy
+                .verdict(Some("spam.example"), None)
+                .strip_media
+        );
+        let unknown = json::parse(r#"{"action": "ban"}"#).unwrap();
+        assert!(ModerationDecision::from_json(&unknown).is_err());
+
+        let kinds: Vec<_> = events.since(0).iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            [EventKind::Label, EventKind::Decision, EventKind::Decision]
+        );
+        assert_eq!(events.since(2).len(), 1);
+        assert!(events.wait(3, Duration::from_millis(1)).is_empty());
+        let event = events.since(2).remove(0).to_json();
+        assert_eq!(event.get("subject"), Some(&Value::from("spam.example")));
+    }
+}
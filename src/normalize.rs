@@ -0,0 +1,325 @@
+//! Canonical DIDs and handles
+//!
+//! The same account can be written more than one way. Handles are case-insensitive, and may
+//! come fully qualified with a trailing dot, or with the `@` they're shown with; so may the
+//! host of a `did:web`. Anything keyed by the strings as given would take each spelling for
+//! an account of its own, so they're canonicalized wherever they come into the bridge:
+//!
+//! - handles ([`handle`]) are lowercased, without the `@` or trailing dots. Labels which
+//!   aren't ASCII have accents composed onto the letters they're written over, as NFC would
+//!   for Latin letters, and are [punycode](https://www.rfc-editor.org/rfc/rfc3492)-encoded,
+//!   so `bücher.example` and `xn--bcher-kva.example` are the one handle
+//! - fediverse accounts ([`acct`]) are `user@host`, the user lowercased and composed like a
+//!   label, and the host as a handle
+//! - `did:web` hosts ([`did`], [`parse_did`]) are lowercased without a trailing dot, leaving
+//!   the path after them as it was
+//! - `did:plc` identifiers are left as they are, being case-sensitive
+//!
+//! The [identity store](crate::store::IdentityStore) canonicalizes what it's given too, so
+//! mappings saved before this are merged as they're loaded. Handles which are canonically
+//! different but look alike are the business of [`lookalike`](crate::lookalike)
+
+use atproto::DID::{Did, DidValidationError};
+use std::borrow::Cow;
+
+const DID_WEB: &str = "did:web:";
+/// What a punycode-encoded label starts with
+const ACE_PREFIX: &str = "xn--";
+/// What labels are separated by, in scripts with dots of their own too
+const DOTS: [char; 4] = ['.', '\u{3002}', '\u{ff0e}', '\u{ff61}'];
+/// Combining marks, and the Latin letters they compose with
+const COMPOSED: [(char, char, char); 38] = [
+    ('a', '\u{300}', 'à'),
+    ('e', '\u{300}', 'è'),
+    ('i', '\u{300}', 'ì'),
+    ('o', '\u{300}', 'ò'),
+    ('u', '\u{300}', 'ù'),
+    ('a', '\u{301}', 'á'),
+    ('c', '\u{301}', 'ć'),
+    ('e', '\u{301}', 'é'),
+    ('i', '\u{301}', 'í'),
+    ('n', '\u{301}', 'ń'),
+    ('o', '\u{301}', 'ó'),
+    ('s', '\u{301}', 'ś'),
+    ('u', '\u{301}', 'ú'),
+    ('y', '\u{301}', 'ý'),
+    ('z', '\u{301}', 'ź'),
+    ('a', '\u{302}', 'â'),
+    ('e', '\u{302}', 'ê'),
+    ('i', '\u{302}', 'î'),
+    ('o', '\u{302}', 'ô'),
+    ('u', '\u{302}', 'û'),
+    ('a', '\u{303}', 'ã'),
+    ('n', '\u{303}', 'ñ'),
+    ('o', '\u{303}', 'õ'),
+    ('a', '\u{308}', 'ä'),
+    ('e', '\u{308}', 'ë'),
+    ('i', '\u{308}', 'ï'),
+    ('o', '\u{308}', 'ö'),
+    ('u', '\u{308}', 'ü'),
+    ('y', '\u{308}', 'ÿ'),
+    ('a', '\u{30a}', 'å'),
+    ('u', '\u{30a}', 'ů'),
+    ('c', '\u{30c}', 'č'),
+    ('e', '\u{30c}', 'ě'),
+    ('r', '\u{30c}', 'ř'),
+    ('s', '\u{30c}', 'š'),
+    ('z', '\u{30c}', 'ž'),
+    ('c', '\u{327}', 'ç'),
+    ('s', '\u{327}', 'ş'),
+];
+
+/// `did` canonicalized, borrowed if it already was
+fn canonical(did: &str) -> Cow<'_, str> {
+    let trimmed = did.trim();
+    let Some(rest) = trimmed.strip_prefix(DID_WEB) else {
+        return Cow::Borrowed(trimmed);
+    };
+    let (host, path) = rest.split_at(rest.find(':').unwrap_or(rest.len()));
+    let bare = host.trim_end_matches('.');
+    if bare.len() == host.len() && !bare.bytes().any(|b| b.is_ascii_uppercase()) {
+        return Cow::Borrowed(trimmed);
+    }
+    // A port's colon is percent-encoded, and stays in the usual uppercase
+    let host = bare.to_ascii_lowercase().replace("%3a", "%3A");
+    Cow::Owned(format!("{DID_WEB}{host}{path}"))
+}
+
+/// The canonical form of `did`, borrowed if it is already
+pub fn did(did: &Did) -> Cow<'_, Did> {
+    match canonical(did.as_str()) {
+        Cow::Borrowed(s) if s == did.as_str() => Cow::Borrowed(did),
+        s => Did::try_create(s.into_owned()).map_or(Cow::Borrowed(did), Cow::Owned),
+    }
+}
+
+/// Parse `did`, as given by anyone, into its canonical form
+pub fn parse_did(did: &str) -> Result<Did, DidValidationError> {
+    Did::try_create(canonical(did).into_owned())
+}
+
+/// The canonical form of `handle`
+pub fn handle(handle: &str) -> String {
+    let handle = handle.trim().trim_start_matches('@').trim_end_matches(DOTS);
+    if handle.is_ascii() {
+        return handle.to_ascii_lowercase();
+    }
+    let labels: Vec<String> = handle.split(DOTS).map(label).collect();
+    labels.join(".")
+}
+
+/// The canonical form of the fediverse account `acct`
+pub fn acct(acct: &str) -> String {
+    let acct = acct
+        .trim()
+        .trim_start_matches("acct:")
+        .trim_start_matches('@');
+    match acct.rsplit_once('@') {
+        Some((user, host)) => format!("{}@{}", compose(&user.to_lowercase()), handle(host)),
+        None => compose(&acct.to_lowercase()),
+    }
+}
+
+/// `handle` as it's shown, its punycode-encoded labels decoded
+pub fn unicode_handle(handle: &str) -> String {
+    let decoded = handle.split('.').map(|label| {
+        let encoded = label
+            .get(..ACE_PREFIX.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(ACE_PREFIX));
+        let decoded = encoded.and_then(|_| punycode_decode(&label[ACE_PREFIX.len()..]));
+        decoded.unwrap_or_else(|| label.to_string())
+    });
+    decoded.collect::<Vec<_>>().join(".")
+}
+
+/// The letter `c` is, without the accent composed onto it
+pub fn base_letter(c: char) -> char {
+    let base = COMPOSED.iter().find(|(_, _, composed)| *composed == c);
+    base.map_or(c, |(base, _, _)| *base)
+}
+
+/// A label of a handle in its canonical form, lowercase and encoded if it isn't ASCII
+fn label(label: &str) -> String {
+    let label = compose(&label.to_lowercase());
+    if label.is_ascii() {
+        return label;
+    }
+    match punycode_encode(&label) {
+        Some(encoded) => format!("{ACE_PREFIX}{encoded}"),
+        None => label,
+    }
+}
+
+/// `s` with the combining marks following Latin letters composed onto them
+fn compose(s: &str) -> String {
+    let mut composed = String::with_capacity(s.len());
+    for c in s.chars() {
+        let last = composed.chars().next_back();
+        let onto = COMPOSED
+            .iter()
+            .find(|(base, mark, _)| Some(*base) == last && *mark == c);
+        match onto {
+            Some((_, _, letter)) => {
+                composed.pop();
+                composed.push(*letter);
+            }
+            None => composed.push(c),
+        }
+    }
+    composed
+}
+
+// Punycode's parameters, as RFC 3492 gives them
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+fn threshold(k: u32, bias: u32) -> u32 {
+    k.saturating_sub(bias).clamp(TMIN, TMAX)
+}
+
+fn adapt(delta: u32, points: u32, first: bool) -> u32 {
+    let mut delta = delta / if first { DAMP } else { 2 };
+    delta += delta / points;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+fn digit(d: u32) -> char {
+    match d {
+        0..=25 => (b'a' + d as u8) as char,
+        _ => (b'0' + (d - 26) as u8) as char,
+    }
+}
+
+/// `label` punycode-encoded, without the prefix saying it is
+fn punycode_encode(label: &str) -> Option<String> {
+    let points: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut encoded: String = label.chars().filter(char::is_ascii).collect();
+    let basic = encoded.len() as u32;
+    if basic > 0 {
+        encoded.push('-');
+    }
+    let (mut n, mut delta, mut bias, mut handled) = (INITIAL_N, 0u32, INITIAL_BIAS, basic);
+    while (handled as usize) < points.len() {
+        let m = points.iter().copied().filter(|&p| p >= n).min()?;
+        delta = delta.checked_add((m - n).checked_mul(handled + 1)?)?;
+        n = m;
+        for &point in &points {
+            if point < n {
+                delta = delta.checked_add(1)?;
+            }
+            if point != n {
+                continue;
+            }
+            let (mut q, mut k) = (delta, BASE);
+            loop {
+                let t = threshold(k, bias);
+                if q < t {
+                    break;
+                }
+                encoded.push(digit(t + (q - t) % (BASE - t)));
+                q = (q - t) / (BASE - t);
+                k += BASE;
+            }
+            encoded.push(digit(q));
+            bias = adapt(delta, handled + 1, handled == basic);
+            delta = 0;
+            handled += 1;
+        }
+        delta += 1;
+        n += 1;
+    }
+    Some(encoded)
+}
+
+/// The label punycode-encoded as `encoded`, if it's valid punycode
+fn punycode_decode(encoded: &str) -> Option<String> {
+    let (basic, extended) = match encoded.rfind('-') {
+        Some(at) => (&encoded[..at], &encoded[at + 1..]),
+        None => ("", encoded),
+    };
+    let mut decoded: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (INITIAL_N, 0u32, INITIAL_BIAS);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let (old, mut w, mut k) = (i, 1u32, BASE);
+        loop {
+            let d = match digits.next()? {
+                b @ b'a'..=b'z' => b - b'a',
+                b @ b'A'..=b'Z' => b - b'A',
+                b @ b'0'..=b'9' => b - b'0' + 26,
+                _ => return None,
+            } as u32;
+            i = i.checked_add(d.checked_mul(w)?)?;
+            let t = threshold(k, bias);
+            if d < t {
+                break;
+            }
+            w = w.checked_mul(BASE - t)?;
+            k += BASE;
+        }
+        let len = decoded.len() as u32 + 1;
+        bias = adapt(i - old, len, old == 0);
+        n = n.checked_add(i / len)?;
+        i %= len;
+        decoded.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(decoded.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "full-bridge")]
+    use crate::store::{IdentityStore, Mapping};
+    use atproto::did;
+
+    #[test]
+    fn canonicalizes_only_what_is_case_insensitive() {
+        let web = parse_did(" did:web:Bridge.Example.:u:Alice").unwrap();
+        assert_eq!(web.as_str(), "did:web:bridge.example:u:Alice");
+        let port = parse_did("did:web:LocalHost%3a8080").unwrap();
+        assert_eq!(port.as_str(), "did:web:localhost%3A8080");
+        let plc = did!("did:plc:ewvi7nxzyoun6zhxrhs64oiz");
+        assert!(matches!(did(&plc), Cow::Borrowed(_)));
+        let mixed = parse_did("did:plc:EWVI7nxzyoun6zhxrhs64oiz").unwrap();
+        assert_ne!(mixed, plc);
+        assert_eq!(*did(&did!("did:web:A.example")), did!("did:web:a.example"));
+
+        assert_eq!(handle("@Alice.BSky.Social."), "alice.bsky.social");
+        assert_eq!(handle("Bücher.example"), "xn--bcher-kva.example");
+        // Decomposed, the accent is composed onto its letter first
+        assert_eq!(handle("bu\u{308}cher。example"), "xn--bcher-kva.example");
+        assert_eq!(handle("münchen.example"), "xn--mnchen-3ya.example");
+        assert_eq!(unicode_handle("xn--bcher-kva.example"), "bücher.example");
+        assert_eq!(unicode_handle("xn--zz.example"), "xn--zz.example");
+        assert_eq!(acct("@Zoë@Bücher.Example"), "zoë@xn--bcher-kva.example");
+        assert_eq!(base_letter('ö'), 'o');
+    }
+
+    #[cfg(feature = "full-bridge")]
+    #[test]
+    fn the_store_takes_each_spelling_for_one_identity() {
+        let store = IdentityStore::new();
+        let mut mapping = Mapping::new(did!("did:web:Alice.Example"), "https://a.example/alice");
+        mapping.handle = Some("Alice.Example.".to_string());
+        store.insert(mapping);
+        assert!(store.contains(&did!("did:web:alice.example")));
+        assert!(store.get_by_handle("@alice.example").is_some());
+        store.insert(Mapping::new(
+            did!("did:web:alice.example."),
+            "https://a.example/alice",
+        ));
+        assert_eq!(store.len(), 1);
+    }
+}
@@ -0,0 +1,227 @@
+//! The bridge's atproto OAuth client metadata
+//!
+//! Before anyone can authorize the bridge to act for their Bluesky account, the
+//! authorization server fetches the bridge's client metadata from its `client_id`, which is
+//! the URL it's served at. The bridge is a confidential client: it authenticates with
+//! `private_key_jwt` assertions signed by its [client key](KeyPurpose::OAuthClient),
+//! published in the JWKS the metadata points to.
+//!
+//! The JWKS has the current key and any retired within [`RETIRED_KEY_OVERLAP`], so assertions
+//! made just before a rotation still verify. Turning a key into a JWK needs its curve point,
+//! which like signing is left to a [`JwkEncoder`] backend
+
+use crate::bridge::Bridge;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::Value;
+use crate::keys::{KeyGenerator, KeyOwner, KeyPair, KeyPurpose, StoredKey};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub const CLIENT_METADATA_PATH: &str = "/oauth/client-metadata.json";
+pub const JWKS_PATH: &str = "/oauth/jwks.json";
+pub const CALLBACK_PATH: &str = "/oauth/callback";
+
+/// How long a rotated client key stays in the JWKS
+pub const RETIRED_KEY_OVERLAP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Produces the public JWK of a key
+pub trait JwkEncoder: Send + Sync {
+    /// The key's public parameters, e.g. `kty`, `crv`, `x` and `y` for an elliptic curve key
+    fn public_jwk(&self, key: &KeyPair) -> anyhow::Result<Value>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How the bridge describes itself as an OAuth client
+pub struct OAuthConfig {
+    /// Shown to people authorizing the bridge
+    pub client_name: String,
+    pub scope: String,
+    /// Where authorization servers may send people back to. Empty means just the bridge's
+    /// own [`CALLBACK_PATH`]
+    pub redirect_uris: Vec<String>,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        OAuthConfig {
+            client_name: "fedibridge".to_string(),
+            scope: "atproto transition:generic".to_string(),
+            redirect_uris: Vec::new(),
+        }
+    }
+}
+
+impl OAuthConfig {
+    /// The client metadata document for a bridge served at `hostname`
+    pub fn client_metadata(&self, hostname: &str) -> Value {
+        let origin = format!("https://{hostname}");
+        let redirect_uris = match self.redirect_uris.is_empty() {
+            true => vec![format!("{origin}{CALLBACK_PATH}")],
+            false => self.redirect_uris.clone(),
+        };
+        let strings = |strings: &[&str]| Value::Array(strings.iter().map(|&s| s.into()).collect());
+        Value::object([
+            (
+                "client_id",
+                Value::from(format!("{origin}{CLIENT_METADATA_PATH}")),
+            ),
+            ("application_type", Value::from("web")),
+            ("client_name", Value::from(self.client_name.as_str())),
+            ("client_uri", Value::from(origin.as_str())),
+            ("scope", Value::from(self.scope.as_str())),
+            (
+                "grant_types",
+                strings(&["authorization_code", "refresh_token"]),
+            ),
+            ("response_types", strings(&["code"])),
+            (
+                "redirect_uris",
+                Value::Array(redirect_uris.into_iter().map(Value::from).collect()),
+            ),
+            ("dpop_bound_access_tokens", Value::from(true)),
+            ("token_endpoint_auth_method", Value::from("private_key_jwt")),
+            ("token_endpoint_auth_signing_alg", Value::from("ES256")),
+            ("jwks_uri", Value::from(format!("{origin}{JWKS_PATH}"))),
+        ])
+    }
+}
+
+/// The bridge's current client key, generating it if it doesn't have one yet
+pub fn client_key(bridge: &Bridge, generator: &dyn KeyGenerator) -> anyhow::Result<StoredKey> {
+    let owner = KeyOwner::Bridge;
+    Ok(bridge
+        .keys
+        .ensure(&owner, KeyPurpose::OAuthClient, generator)?)
+}
+
+/// The client keys authorization servers should accept assertions from
+pub fn jwks(bridge: &Bridge, encoder: &dyn JwkEncoder, now: SystemTime) -> anyhow::Result<Value> {
+    let history = bridge
+        .keys
+        .history(&KeyOwner::Bridge, KeyPurpose::OAuthClient);
+    let valid = history.iter().filter(|key| {
+        key.retired_at.is_none_or(|retired| {
+            now.duration_since(retired).unwrap_or_default() < RETIRED_KEY_OVERLAP
+        })
+    });
+    let mut keys = Vec::new();
+    // Newest first, so clients picking the first key use the current one
+    for key in valid.rev() {
+        let mut jwk = encoder.public_jwk(&key.keypair)?;
+        if let Value::Object(fields) = &mut jwk {
+            fields.insert("kid".into(), Value::from(key.id()));
+            fields.insert("use".into(), Value::from("sig"));
+            fields.insert("alg".into(), Value::from("ES256"));
+        }
+        keys.push(jwk);
+    }
+    Ok(Value::object([("keys", Value::Array(keys))]))
+}
+
+/// Serves the client metadata and JWKS in front of `inner`. Without a hostname to make the
+/// `client_id` from, everything is passed on
+pub struct ClientMetadataEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> ClientMetadataEndpoints<H> {
+    pub fn new(
+        bridge: Arc<Bridge>,
+        hostname: Option<String>,
+        inner: H,
+    ) -> ClientMetadataEndpoints<H> {
+        ClientMetadataEndpoints {
+            bridge,
+            hostname,
+            inner,
+        }
+    }
+
+    fn jwks(&self) -> Response {
+        let Some(encoder) = &self.bridge.jwk_encoder else {
+            return Response::error(503, "No JWK encoder is configured");
+        };
+        match jwks(&self.bridge, encoder.as_ref(), SystemTime::now()) {
+            Ok(jwks) => Response::json(200, &jwks),
+            Err(e) => Response::error(500, format!("Couldn't encode the client keys: {e}")),
+        }
+    }
+}
+
+impl<H: Handler> Handler for ClientMetadataEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(hostname) = &self.hostname else {
+            return self.inner.handle(request);
+        };
+        match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["oauth", "client-metadata.json"]) => {
+                Response::json(200, &self.bridge.oauth.client_metadata(hostname))
+            }
+            (Method::Get, ["oauth", "jwks.json"]) => self.jwks(),
+            _ => self.inner.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+
+    /// Passes the public key through as the JWK's `x`
+    struct FakeEncoder;
+
+    impl JwkEncoder for FakeEncoder {
+        fn public_jwk(&self, key: &KeyPair) -> anyhow::Result<Value> {
+            Ok(Value::object([
+                ("kty", Value::from("EC")),
+                ("x", Value::from(key.public_key.as_str())),
+            ]))
+        }
+    }
+
+    fn get(handler: &impl Handler, target: &str) -> Value {
+        let response = handler.handle(&Request::new(Method::Get, target));
+        assert_eq!(response.status, 200);
+        json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_metadata_and_recent_keys() {
+        let bridge = Arc::new(Bridge::new().with_jwk_encoder(Arc::new(FakeEncoder)));
+        let generator = FakeGenerator::default();
+        let first = client_key(&bridge, &generator).unwrap();
+        assert_eq!(first.keypair.algorithm, crate::keys::KeyAlgorithm::P256);
+        assert_eq!(client_key(&bridge, &generator).unwrap(), first);
+        bridge
+            .rotate_key(&KeyOwner::Bridge, KeyPurpose::OAuthClient, &generator)
+            .unwrap();
+        let hostname = Some("bridge.example".to_string());
+        let handler = ClientMetadataEndpoints::new(bridge.clone(), hostname, |_: &Request| {
+            Response::new(418)
+        });
+
+        let metadata = get(&handler, CLIENT_METADATA_PATH);
+        let client_id = "https://bridge.example/oauth/client-metadata.json";
+        assert_eq!(metadata.get("client_id"), Some(&Value::from(client_id)));
+        let redirects = metadata.get("redirect_uris").and_then(Value::as_array);
+        let callback = Value::from("https://bridge.example/oauth/callback");
+        assert_eq!(redirects, Some([callback].as_slice()));
+        let jwks_uri = metadata.get("jwks_uri").and_then(Value::as_str).unwrap();
+        assert_eq!(jwks_uri, "https://bridge.example/oauth/jwks.json");
+
+        let kids = |jwks: &Value| -> Vec<String> {
+            let keys = jwks.get("keys").and_then(Value::as_array).unwrap();
+            let kids = keys.iter().filter_map(|k| k.get("kid")?.as_str());
+            kids.map(str::to_string).collect()
+        };
+        let kids_now = kids(&get(&handler, JWKS_PATH));
+        assert_eq!(kids_now, ["bridge#oauthClient-2", "bridge#oauthClient-1"]);
+        let later = SystemTime::now() + RETIRED_KEY_OVERLAP;
+        let jwks = jwks(&bridge, &FakeEncoder, later).unwrap();
+        assert_eq!(kids(&jwks), ["bridge#oauthClient-2"]);
+    }
+}
@@ -0,0 +1,262 @@
+//! Content-addressed store of fetched objects and blobs
+//!
+//! The same image is often attached to many posts, and the same remote object fetched for
+//! many activities. Each is kept once here, named by the CID of its bytes (addressed as raw
+//! bytes, as atproto addresses blobs), with:
+//!
+//! - the posts or records [referring](ObjectStore::put) to it, so it's removed once the last
+//!   of them [releases](ObjectStore::release) it
+//! - the URLs it was fetched from, so a second post attaching the same URL finds it
+//!   ([`ObjectStore::locate`])
+//! - the accounts it has been uploaded to as a blob, so it's uploaded once per repo
+//!   ([`ObjectStore::uploaded`])
+//!
+//! Since the name is the hash, [`ObjectStore::verify`] only has to re-hash what's on disk to
+//! know it's intact. The index is kept in the `objects` subdirectory of the state directory,
+//! alongside the objects themselves
+
+use crate::car::Cid;
+use crate::json::{self, Value};
+use crate::storage::StateDir;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::RwLock;
+
+/// Where objects are kept in the state directory
+pub const OBJECTS_DIR: &str = "objects";
+pub const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Entry {
+    size: u64,
+    /// What refers to it, e.g. the `at://` URIs of posts embedding it
+    referrers: BTreeSet<String>,
+    /// URLs it was fetched from
+    sources: BTreeSet<String>,
+    /// DIDs of the repos it has been uploaded to
+    uploaded: BTreeSet<String>,
+}
+
+impl Entry {
+    fn to_json(&self, cid: &Cid) -> Value {
+        let strings = |set: &BTreeSet<String>| {
+            Value::Array(set.iter().map(|s| Value::from(s.as_str())).collect())
+        };
+        Value::object([
+            ("cid", Value::from(cid.to_string())),
+            ("size", Value::from(self.size as i64)),
+            ("referrers", strings(&self.referrers)),
+            ("sources", strings(&self.sources)),
+            ("uploaded", strings(&self.uploaded)),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<(Cid, Entry)> {
+        let strings = |name| {
+            let values = value
+                .get(name)
+                .and_then(Value::as_array)
+                .unwrap_or_default();
+            let strings = values.iter().filter_map(Value::as_str).map(str::to_string);
+            strings.collect()
+        };
+        let cid = value.get("cid")?.as_str()?.parse().ok()?;
+        let entry = Entry {
+            size: u64::try_from(value.get("size")?.as_i64()?).ok()?,
+            referrers: strings("referrers"),
+            sources: strings("sources"),
+            uploaded: strings("uploaded"),
+        };
+        Some((cid, entry))
+    }
+}
+
+#[derive(Debug)]
+/// Objects and blobs, stored once each by their content
+pub struct ObjectStore {
+    entries: RwLock<HashMap<Cid, Entry>>,
+    dir: StateDir,
+}
+
+impl ObjectStore {
+    /// Open (creating if needed) the object store in a state directory
+    pub fn open(root: &StateDir) -> io::Result<ObjectStore> {
+        let dir = root.subdir(OBJECTS_DIR)?;
+        let mut entries = HashMap::new();
+        if let Some(contents) = dir.read(INDEX_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let saved = saved.get("objects").and_then(Value::as_array);
+            entries.extend(
+                saved
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(Entry::from_json),
+            );
+        }
+        Ok(ObjectStore {
+            entries: RwLock::new(entries),
+            dir,
+        })
+    }
+
+    fn save(&self, entries: &HashMap<Cid, Entry>) -> io::Result<()> {
+        let objects = entries.iter().map(|(cid, entry)| entry.to_json(cid));
+        let saved = Value::object([("objects", Value::Array(objects.collect()))]);
+        self.dir.write(INDEX_FILE, saved.to_string().as_bytes())
+    }
+
+    /// Store `data`, fetched from `source` if it was, on behalf of `referrer`
+    ///
+    /// Data already stored isn't written again, only referred to once more
+    pub fn put(&self, data: &[u8], source: Option<&str>, referrer: &str) -> io::Result<Cid> {
+        let cid = Cid::for_raw(data);
+        let mut entries = self.entries.write().unwrap();
+        let name = cid.to_string();
+        if !entries.contains_key(&cid) || !self.dir.path().join(&name).exists() {
+            self.dir.write(&name, data)?;
+        }
+        let entry = entries.entry(cid).or_default();
+        entry.size = data.len() as u64;
+        entry.referrers.insert(referrer.to_string());
+        entry.sources.extend(source.map(str::to_string));
+        self.save(&entries)?;
+        Ok(cid)
+    }
+
+    pub fn get(&self, cid: &Cid) -> io::Result<Option<Vec<u8>>> {
+        if !self.entries.read().unwrap().contains_key(cid) {
+            return Ok(None);
+        }
+        self.dir.read(&cid.to_string())
+    }
+
+    /// The stored object fetched from `url`, if there is one
+    pub fn locate(&self, url: &str) -> Option<Cid> {
+        let entries = self.entries.read().unwrap();
+        let mut found = entries
+            .iter()
+            .filter(|(_, entry)| entry.sources.contains(url));
+        found.next().map(|(cid, _)| *cid)
+    }
+
+    /// How many things refer to `cid`
+    pub fn references(&self, cid: &Cid) -> usize {
+        let entries = self.entries.read().unwrap();
+        entries.get(cid).map_or(0, |entry| entry.referrers.len())
+    }
+
+    /// `referrer` no longer needs `cid`. Returns whether that was the last reference, and the
+    /// data was removed
+    pub fn release(&self, cid: &Cid, referrer: &str) -> io::Result<bool> {
+        let mut entries = self.entries.write().unwrap();
+        let Some(entry) = entries.get_mut(cid) else {
+            return Ok(false);
+        };
+        if !entry.referrers.remove(referrer) {
+            return Ok(false);
+        }
+        let unreferenced = entry.referrers.is_empty();
+        if unreferenced {
+            entries.remove(cid);
+            self.dir.remove(&cid.to_string())?;
+        }
+        self.save(&entries)?;
+        Ok(unreferenced)
+    }
+
+    /// Whether `cid` has been uploaded to `did`'s repo as a blob
+    pub fn uploaded(&self, cid: &Cid, did: &str) -> bool {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(cid)
+            .is_some_and(|entry| entry.uploaded.contains(did))
+    }
+
+    /// Record that `cid` has been uploaded to `did`'s repo, so it isn't again
+    pub fn mark_uploaded(&self, cid: &Cid, did: &str) -> io::Result<()> {
+        let mut entries = self.entries.write().unwrap();
+        let Some(entry) = entries.get_mut(cid) else {
+            return Ok(());
+        };
+        if entry.uploaded.insert(did.to_string()) {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+
+    /// Whether what's stored for `cid` still hashes to it
+    pub fn verify(&self, cid: &Cid) -> io::Result<bool> {
+        Ok(self
+            .get(cid)?
+            .is_some_and(|data| Cid::for_raw(&data) == *cid))
+    }
+
+    /// Re-hash everything stored, removing the data of anything which no longer matches its
+    /// CID so that it's fetched again. Returns the CIDs of what was removed
+    pub fn verify_all(&self) -> io::Result<Vec<Cid>> {
+        let cids: Vec<Cid> = self.entries.read().unwrap().keys().copied().collect();
+        let mut corrupt = Vec::new();
+        for cid in cids {
+            if !self.verify(&cid)? {
+                self.dir.remove(&cid.to_string())?;
+                corrupt.push(cid);
+            }
+        }
+        Ok(corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+
+    #[test]
+    fn identical_data_is_stored_once_until_released() {
+        let root = temp_state_dir();
+        let store = ObjectStore::open(&root).unwrap();
+        let image = b"not really a png";
+        let (first, second) = (
+            "at://did:plc:alice/app.bsky.feed.post/1",
+            "at://did:plc:bob/app.bsky.feed.post/2",
+        );
+        let cid = store
+            .put(image, Some("https://a.example/cat.png"), first)
+            .unwrap();
+        assert_eq!(
+            store
+                .put(image, Some("https://b.example/same-cat.png"), second)
+                .unwrap(),
+            cid
+        );
+        assert!(cid.to_string().starts_with("bafkrei"));
+        assert_eq!(store.references(&cid), 2);
+        assert_eq!(store.locate("https://b.example/same-cat.png"), Some(cid));
+        store.mark_uploaded(&cid, "did:plc:alice").unwrap();
+
+        let reopened = ObjectStore::open(&root).unwrap();
+        assert!(reopened.uploaded(&cid, "did:plc:alice"));
+        assert!(!reopened.uploaded(&cid, "did:plc:bob"));
+        assert!(!reopened.release(&cid, first).unwrap());
+        assert!(reopened.release(&cid, second).unwrap());
+        assert_eq!(reopened.get(&cid).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupted_data_fails_verification() {
+        let root = temp_state_dir();
+        let store = ObjectStore::open(&root).unwrap();
+        let cid = store.put(b"hello", None, "at://a/p/1").unwrap();
+        assert!(store.verify(&cid).unwrap());
+        root.subdir(OBJECTS_DIR)
+            .unwrap()
+            .write(&cid.to_string(), b"jello")
+            .unwrap();
+        assert_eq!(store.verify_all().unwrap(), [cid]);
+        assert_eq!(store.get(&cid).unwrap(), None);
+        // Fetching it again puts it back
+        store.put(b"hello", None, "at://a/p/1").unwrap();
+        assert!(store.verify(&cid).unwrap());
+    }
+}
@@ -0,0 +1,300 @@
+//! Replies which arrive before their parent
+//!
+//! Fediverse servers deliver each post of a thread on its own, so a reply can arrive before
+//! the post it replies to has been bridged. Bridged then, it would start a thread of its own.
+//! Instead it's [held](OrphanBuffer::hold) until its parent is bridged, and handed back to be
+//! bridged as a reply then ([`parent_bridged`]), for up to the bridge's
+//! [`orphan_window`](crate::bridge::Bridge::orphan_window). Once that's passed it's bridged
+//! anyway ([`OrphanBuffer::take_expired`]) and remembered, so that if the parent does turn up
+//! the reply's record is repaired to point at it ([`repair`]).
+//!
+//! Held replies are kept in memory, keyed by their parent's ActivityPub ID. Those bridged
+//! without their parent are [saved](OrphanBuffer::save) to the shard's state directory, as a
+//! parent can turn up long after a restart
+
+use crate::bridge::Bridge;
+use crate::json::{self, Value};
+use crate::pinned::POST_COLLECTION;
+use crate::repo::{record_cid, RepoError, Write};
+use crate::storage::StateDir;
+use atproto::at_uri::{AtUri, Authority};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long replies are held for their parent by default
+pub const DEFAULT_ORPHAN_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const UNTHREADED_FILE: &str = "unthreaded.json";
+
+#[derive(Debug, Clone, PartialEq)]
+/// A reply held for its parent
+pub struct Orphan {
+    /// The ActivityPub ID of the post it replies to
+    pub parent: String,
+    /// The activity it arrived in
+    pub activity: Value,
+    pub since: Instant,
+}
+
+#[derive(Debug, Default)]
+/// Replies waiting for their parent
+pub struct OrphanBuffer {
+    held: Mutex<HashMap<String, Vec<Orphan>>>,
+    /// The `at://` URIs of replies bridged without their parent, by the parent's ID
+    unthreaded: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl OrphanBuffer {
+    /// The replies saved in `dir` as bridged without their parent, none held
+    pub fn load(dir: &StateDir) -> io::Result<OrphanBuffer> {
+        let buffer = OrphanBuffer::default();
+        if let Some(contents) = dir.read(UNTHREADED_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let links = saved.get("unthreaded").and_then(Value::as_array);
+            for link in links.unwrap_or_default() {
+                let field = |name| link.get(name).and_then(Value::as_str);
+                let (Some(parent), Some(uri)) = (field("parent"), field("uri")) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid saved reply",
+                    ));
+                };
+                buffer.bridged_unthreaded(parent, uri);
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Persist the replies bridged without their parent to `dir`
+    pub fn save(&self, dir: &StateDir) -> io::Result<()> {
+        let links = self.unthreaded().into_iter().map(|(parent, uri)| {
+            Value::object([("parent", Value::from(parent)), ("uri", Value::from(uri))])
+        });
+        let saved = Value::object([("unthreaded", Value::Array(links.collect()))]);
+        dir.write(UNTHREADED_FILE, saved.to_string().as_bytes())
+    }
+
+    /// Hold `activity`, a reply to `parent`, until its parent is bridged
+    pub fn hold(&self, parent: &str, activity: Value, now: Instant) {
+        let orphan = Orphan {
+            parent: parent.to_string(),
+            activity,
+            since: now,
+        };
+        let mut held = self.held.lock().unwrap();
+        held.entry(parent.to_string()).or_default().push(orphan);
+    }
+
+    /// Take the replies which have waited `window` for their parent, oldest first
+    pub fn take_expired(&self, window: Duration, now: Instant) -> Vec<Orphan> {
+        let mut held = self.held.lock().unwrap();
+        let mut expired = Vec::new();
+        held.retain(|_, orphans| {
+            let (waited, waiting): (Vec<Orphan>, _) = orphans
+                .drain(..)
+                .partition(|o| now.saturating_duration_since(o.since) >= window);
+            expired.extend(waited);
+            *orphans = waiting;
+            !orphans.is_empty()
+        });
+        expired.sort_by_key(|o| o.since);
+        expired
+    }
+
+    /// Remember that the reply to `parent` bridged as `uri` went without it, to be
+    /// [repaired](repair) if it turns up
+    pub fn bridged_unthreaded(&self, parent: &str, uri: &str) {
+        let mut unthreaded = self.unthreaded.lock().unwrap();
+        unthreaded
+            .entry(parent.to_string())
+            .or_default()
+            .push(uri.to_string());
+    }
+
+    /// The parent ID and `at://` URI of each reply bridged without its parent, sorted
+    pub fn unthreaded(&self) -> Vec<(String, String)> {
+        let unthreaded = self.unthreaded.lock().unwrap();
+        let mut links: Vec<_> = unthreaded
+            .iter()
+            .flat_map(|(parent, uris)| uris.iter().map(|uri| (parent.clone(), uri.clone())))
+            .collect();
+        links.sort();
+        links
+    }
+
+    /// How many replies are being held
+    pub fn held(&self) -> usize {
+        self.held.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Take what's waiting on `parent`: the replies held for it, and the `at://` URIs of
+    /// those bridged without it
+    fn take(&self, parent: &str) -> (Vec<Orphan>, Vec<String>) {
+        let held = self.held.lock().unwrap().remove(parent);
+        let unthreaded = self.unthreaded.lock().unwrap().remove(parent);
+        (held.unwrap_or_default(), unthreaded.unwrap_or_default())
+    }
+}
+
+/// The ActivityPub ID of the post a `Create` activity's object replies to
+pub fn parent_of(activity: &Value) -> Option<&str> {
+    if activity.get("type").and_then(Value::as_str) != Some("Create") {
+        return None;
+    }
+    let parent = activity.get("object")?.get("inReplyTo")?;
+    parent
+        .as_str()
+        .or_else(|| parent.get("id").and_then(Value::as_str))
+}
+
+/// The `reply` field of a post replying to `parent`, the post record at `uri` with CID `cid`:
+/// in the same thread as it, or starting one at it
+pub fn reply_ref(uri: &str, cid: &str, parent: &Value) -> Value {
+    let strong_ref = Value::object([("uri", Value::from(uri)), ("cid", Value::from(cid))]);
+    let root = parent.get("reply").and_then(|reply| reply.get("root"));
+    Value::object([
+        ("root", root.cloned().unwrap_or(strong_ref.clone())),
+        ("parent", strong_ref),
+    ])
+}
+
+/// Thread the bridged post at `uri` under a parent, given as its `reply` field, if it has
+/// none yet
+///
+/// Returns whether the post changed
+pub fn repair(bridge: &Bridge, uri: &str, reply: &Value) -> Result<bool, RepoError> {
+    let Ok(parsed) = AtUri::try_create(uri.to_string()) else {
+        return Ok(false);
+    };
+    let (Authority::Did(did), Some(rkey)) = (parsed.authority(), parsed.rkey()) else {
+        return Ok(false);
+    };
+    if parsed.collection().map(|c| c.as_str()) != Some(POST_COLLECTION) {
+        return Ok(false);
+    }
+    let updated = bridge.update_repo(did, || {
+        let posts = bridge.repos.records(did, POST_COLLECTION).into_iter();
+        let Some((_, post)) = posts.into_iter().find(|(key, _)| key == rkey) else {
+            return Ok(Vec::new());
+        };
+        let Value::Object(mut fields) = post.clone() else {
+            return Ok(Vec::new());
+        };
+        if fields.contains_key("reply") {
+            return Ok(Vec::new());
+        }
+        fields.insert("reply".into(), reply.clone());
+        Ok(vec![Write::Update {
+            path: format!("{POST_COLLECTION}/{rkey}"),
+            record: Value::Object(fields),
+            swap: Some(record_cid(&post)),
+        }])
+    })?;
+    Ok(updated.is_some())
+}
+
+/// The post with ActivityPub ID `parent` has been bridged as the record at `uri` with CID
+/// `cid`: repair the replies to it bridged without it, and return those held for it to be
+/// bridged as replies
+pub fn parent_bridged(
+    bridge: &Bridge,
+    parent: &str,
+    uri: &str,
+    cid: &str,
+    record: &Value,
+) -> Result<Vec<Value>, RepoError> {
+    let (held, unthreaded) = bridge.orphans.take(parent);
+    let reply = reply_ref(uri, cid, record);
+    for orphan in unthreaded {
+        repair(bridge, &orphan, &reply)?;
+    }
+    Ok(held.into_iter().map(|orphan| orphan.activity).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use atproto::did;
+    use atproto::DID::Did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const PARENT: &str = "https://a.example/notes/1";
+
+    fn reply(id: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Create", "id": "{id}",
+                "object": {{"type": "Note", "inReplyTo": "{PARENT}"}}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn replies_wait_for_their_parent_until_the_window_passes() {
+        let bridge = Bridge::new();
+        let start = Instant::now();
+        let (first, second) = (reply("https://b.example/1"), reply("https://b.example/2"));
+        assert_eq!(parent_of(&first), Some(PARENT));
+        bridge.orphans.hold(PARENT, first.clone(), start);
+        let later = start + Duration::from_secs(60);
+        bridge.orphans.hold(PARENT, second.clone(), later);
+        assert_eq!(bridge.orphans.held(), 2);
+
+        let expired = bridge.orphans.take_expired(Duration::from_secs(60), later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].activity, first);
+
+        let parent = Value::object([("text", Value::from("hello"))]);
+        let uri = "at://did:plc:alice/app.bsky.feed.post/3k";
+        let held = parent_bridged(&bridge, PARENT, uri, "bafy", &parent).unwrap();
+        assert_eq!(held, [second]);
+        assert_eq!(bridge.orphans.held(), 0);
+    }
+
+    #[test]
+    fn replies_bridged_without_their_parent_are_repaired() {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let owner = KeyOwner::Account(ALICE);
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let orphan = Value::object([("text", Value::from("me too"))]);
+        let write = Write::Create {
+            path: format!("{POST_COLLECTION}/3l"),
+            record: orphan,
+        };
+        bridge.commit(&ALICE, &[write]).unwrap();
+        let orphan_uri = "at://did:plc:alice/app.bsky.feed.post/3l";
+        bridge.orphans.bridged_unthreaded(PARENT, orphan_uri);
+
+        // The parent is itself a reply, so the orphan joins its thread
+        let parent = json::parse(
+            r#"{"text": "hi", "reply": {
+                "root": {"uri": "at://did:plc:bob/app.bsky.feed.post/3a", "cid": "bafyroot"},
+                "parent": {"uri": "at://did:plc:bob/app.bsky.feed.post/3a", "cid": "bafyroot"}}}"#,
+        )
+        .unwrap();
+        let uri = "at://did:plc:bob/app.bsky.feed.post/3k";
+        parent_bridged(&bridge, PARENT, uri, "bafyparent", &parent).unwrap();
+        let post = &bridge.repos.records(&ALICE, POST_COLLECTION)[0].1;
+        let reply = post.get("reply").unwrap();
+        let field = |name, part| reply.get(name).and_then(|r| r.get(part)).cloned();
+        assert_eq!(field("parent", "uri"), Some(Value::from(uri)));
+        assert_eq!(field("parent", "cid"), Some(Value::from("bafyparent")));
+        assert_eq!(
+            field("root", "uri"),
+            Some(Value::from("at://did:plc:bob/app.bsky.feed.post/3a"))
+        );
+
+        // Only once
+        assert!(!repair(&bridge, orphan_uri, &reply_ref(uri, "bafy", &parent)).unwrap());
+    }
+}
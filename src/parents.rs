@@ -0,0 +1,234 @@
+//! Fetching the posts replies reply to
+//!
+//! A reply can arrive for a post the bridge has never seen, which bridged on its own would
+//! reply to nothing anyone on the other network can see. Its parent is fetched on demand
+//! instead: a fediverse post by its `inReplyTo` ([`fetch_object`]), a Bluesky one from the
+//! AppView's `getPostThread` ([`fetch_post`]). Either is only handed back if its author is
+//! bridged and the post public, so that the caller can bridge it as context ahead of the reply
+//! and then release whatever was [held](crate::orphans) for it
+
+use crate::appview::{AppView, AppViewError, PostView};
+use crate::audience::{visibility, Visibility};
+use crate::bridge::Bridge;
+use crate::cache::{Fetched, ResourceKind};
+use crate::delivery::ACTIVITY_JSON;
+use crate::digest::Network;
+use crate::json::{self, id_of, Value};
+use crate::store::{Mapping, MappingStatus};
+use crate::trace::{self, Decision, Reason};
+use crate::transport::{OutboundRequest, TransportError};
+use crate::url::Url;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The label Bluesky accounts use to ask not to be shown to logged-out viewers
+const NO_UNAUTHENTICATED: &str = "!no-unauthenticated";
+
+#[derive(Debug, Error)]
+pub enum ParentError {
+    #[error("Couldn't fetch {url}: {reason}")]
+    Fetch { url: String, reason: String },
+    #[error(transparent)]
+    AppView(#[from] AppViewError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+}
+
+/// The bridged account of `author`, if it may have posts bridged, or the rule it's skipped by
+fn bridged(
+    bridge: &Bridge,
+    author: Option<Mapping>,
+    domain: Option<&str>,
+) -> Result<Mapping, Reason> {
+    let author = author.filter(|mapping| mapping.status == MappingStatus::Active);
+    let author = author.ok_or(Reason::NotOptedIn)?;
+    match bridge.policy.verdict(domain, Some(&author.did)).denied {
+        true => Err(Reason::PolicyDenied),
+        false => Ok(author),
+    }
+}
+
+/// Trace the decision about a parent `event` by `actor`
+fn traced(bridge: &Bridge, network: Network, event: &str, actor: &str, skipped: Option<Reason>) {
+    let decision = Decision::bridged(network, event, actor);
+    let decision = match skipped {
+        Some(rule) => decision.skipped_by(rule),
+        None => decision,
+    };
+    trace::record(
+        bridge,
+        decision.with_detail("Fetched as the parent of a reply"),
+    );
+}
+
+/// Fetch an object from its origin, through the document cache
+pub(crate) fn fetch(bridge: &Bridge, url: &str) -> Result<Arc<Value>, ParentError> {
+    let transport = bridge.transport.clone();
+    let target = url.to_string();
+    let failed = |reason| ParentError::Fetch {
+        url: url.to_string(),
+        reason,
+    };
+    bridge
+        .documents
+        .get_or_revalidate(ResourceKind::Object, url, move |validators| {
+            let request = OutboundRequest::get(&target)
+                .with_header("accept", ACTIVITY_JSON)
+                .if_modified(validators);
+            let response = transport.send(&request).map_err(|e| e.to_string())?;
+            if response.status == 304 && validators.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            if !response.is_success() {
+                return Err(format!("status {}", response.status));
+            }
+            let body = String::from_utf8_lossy(&response.body);
+            let document = json::parse(&body).map_err(|e| e.to_string())?;
+            Ok(Fetched::Modified {
+                value: document,
+                size: response.body.len(),
+                validators: response.validators(),
+            })
+        })
+        .map_err(failed)
+}
+
+/// The fediverse post `object` replies to, fetched from its origin, if it may be bridged
+///
+/// It has to be where its ID says it is, public, and by a bridged account
+pub fn fetch_object(bridge: &Bridge, object: &Value) -> Result<Option<Value>, ParentError> {
+    let Some(parent) = id_of(object.get("inReplyTo")) else {
+        return Ok(None);
+    };
+    let fetched = fetch(bridge, parent)?;
+    let origin = |url: &str| Url::parse(url).ok().map(|url| url.origin());
+    if origin(parent).is_none() || id_of(Some(&*fetched)).map(origin) != Some(origin(parent)) {
+        return Ok(None);
+    }
+    let author = match fetched.get("attributedTo") {
+        Some(Value::Array(actors)) => id_of(actors.first()),
+        actor => id_of(actor),
+    };
+    let host = Url::parse(parent).ok().map(|url| url.host);
+    let mapping = author.and_then(|actor| bridge.identities.get_by_actor(actor));
+    let skipped = match visibility(&fetched) {
+        Visibility::Public => bridged(bridge, mapping, host.as_deref()).err(),
+        _ => Some(Reason::NotPublic),
+    };
+    let actor = author.unwrap_or_default();
+    traced(bridge, Network::Fediverse, parent, actor, skipped);
+    if skipped.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(Value::clone(&fetched)))
+}
+
+/// The Bluesky post at `uri`, as the AppView's view of it, if it may be bridged
+///
+/// Its author has to be bridged, and not have asked to be hidden from logged-out viewers
+pub fn fetch_post(bridge: &Bridge, uri: &str) -> Result<Option<PostView>, ParentError> {
+    // Deleted posts are a 400 `NotFound`, which is as good as not being allowed
+    let Some(post) = AppView::of(bridge).post(uri)? else {
+        return Ok(None);
+    };
+    let mapping = bridge.identities.get(&post.author.did);
+    let skipped = match post.author.is_labeled(NO_UNAUTHENTICATED) {
+        // They've asked not to be seen beyond Bluesky
+        true => Some(Reason::NotPublic),
+        false => bridged(bridge, mapping, None).err(),
+    };
+    traced(
+        bridge,
+        Network::Bluesky,
+        uri,
+        post.author.did.as_str(),
+        skipped,
+    );
+    if skipped.is_some() {
+        return Ok(None);
+    }
+    Ok(Some(post))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appview::{GET_POST_THREAD, THREAD_VIEW};
+    use crate::http::percent_encode;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use atproto::DID::Did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ALICE_ACTOR: &str = "https://a.example/users/alice";
+
+    fn bridge(mock: &Arc<MockTransport>) -> Bridge {
+        let bridge = Bridge::new().with_transport(mock.clone());
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        bridge
+    }
+
+    #[test]
+    fn fetches_public_parents_by_bridged_accounts() {
+        let mock = Arc::new(MockTransport::new());
+        let public = "https://www.w3.org/ns/activitystreams#Public";
+        mock.respond_json(
+            "https://a.example/notes/1",
+            &format!(
+                r#"{{"id": "https://a.example/notes/1", "type": "Note",
+                    "attributedTo": "{ALICE_ACTOR}", "to": ["{public}"]}}"#
+            ),
+        );
+        mock.respond_json(
+            "https://a.example/notes/2",
+            &format!(
+                r#"{{"id": "https://a.example/notes/2", "type": "Note",
+                    "attributedTo": "{ALICE_ACTOR}", "to": ["{ALICE_ACTOR}/followers"]}}"#
+            ),
+        );
+        mock.respond_json(
+            "https://a.example/notes/3",
+            &format!(
+                r#"{{"id": "https://elsewhere.example/notes/3", "type": "Note",
+                    "attributedTo": "{ALICE_ACTOR}", "to": ["{public}"]}}"#
+            ),
+        );
+        let bridge = bridge(&mock);
+        let reply = |parent: &str| Value::object([("inReplyTo", Value::from(parent))]);
+        let parent = fetch_object(&bridge, &reply("https://a.example/notes/1")).unwrap();
+        assert!(parent.is_some());
+        for refused in ["https://a.example/notes/2", "https://a.example/notes/3"] {
+            assert_eq!(fetch_object(&bridge, &reply(refused)).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn fetches_bluesky_parents_from_the_appview() {
+        let mock = Arc::new(MockTransport::new());
+        let thread = |uri: &str, did: &str, labels: &str| {
+            let url = format!(
+                "https://public.api.bsky.app/xrpc/{GET_POST_THREAD}?uri={}&depth=0&parentHeight=0",
+                percent_encode(uri)
+            );
+            let body = format!(
+                r##"{{"thread": {{"$type": "{THREAD_VIEW}", "post": {{"uri": "{uri}", "cid": "bafy",
+                    "author": {{"did": "{did}", "handle": "{did}.test", "labels": [{labels}]}},
+                    "record": {{"text": "hi"}}}}}}}}"##
+            );
+            mock.respond_json(&url, &body);
+        };
+        let (first, second, third) = (
+            "at://did:plc:alice/app.bsky.feed.post/1",
+            "at://did:plc:alice/app.bsky.feed.post/2",
+            "at://did:plc:bob/app.bsky.feed.post/3",
+        );
+        thread(first, "did:plc:alice", "");
+        thread(second, "did:plc:alice", r#"{"val": "!no-unauthenticated"}"#);
+        thread(third, "did:plc:bob", "");
+        let bridge = bridge(&mock);
+        let post = fetch_post(&bridge, first).unwrap().unwrap();
+        assert_eq!(post.cid, "bafy");
+        assert_eq!(fetch_post(&bridge, second).unwrap(), None);
+        assert_eq!(fetch_post(&bridge, third).unwrap(), None);
+    }
+}
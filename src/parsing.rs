@@ -0,0 +1,190 @@
+//! How strictly what other servers send is parsed
+//!
+//! The fediverse and the atproto network both send plenty which is slightly off-spec. In
+//! production it's better bridged than refused, so by default each subsystem parses
+//! [leniently](ParseMode::Lenient), applying compatibility fixups documented where it parses.
+//! Tests and validation tooling want to know about anything off-spec instead, so each
+//! [`Subsystem`] can be made [strict](ParseMode::Strict) on its own, or all of them at once.
+//!
+//! The fixups are:
+//!
+//! - [`Timestamps`](Subsystem::Timestamps): a lowercase `t` or a space between date and time,
+//!   and a lowercase `z`
+//! - [`Lexicons`](Subsystem::Lexicons): blobs referred to by a bare CID, as records written
+//!   before typed blob links are
+//! - [`Languages`](Subsystem::Languages): tags with `_` separators, wrongly cased subtags or
+//!   deprecated codes, which are [normalized](crate::language::normalize) rather than dropped
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ParseMode {
+    /// Reject anything off-spec
+    Strict,
+    /// Apply compatibility fixups
+    #[default]
+    Lenient,
+}
+
+impl ParseMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseMode::Strict => "strict",
+            ParseMode::Lenient => "lenient",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<ParseMode> {
+        match mode {
+            "strict" => Some(ParseMode::Strict),
+            "lenient" => Some(ParseMode::Lenient),
+            _ => None,
+        }
+    }
+
+    pub fn is_strict(&self) -> bool {
+        *self == ParseMode::Strict
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A part of the bridge which parses what other servers send
+pub enum Subsystem {
+    Timestamps,
+    Lexicons,
+    Languages,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 3] = [
+        Subsystem::Timestamps,
+        Subsystem::Lexicons,
+        Subsystem::Languages,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Subsystem::Timestamps => "timestamps",
+            Subsystem::Lexicons => "lexicons",
+            Subsystem::Languages => "languages",
+        }
+    }
+
+    pub fn parse(subsystem: &str) -> Option<Subsystem> {
+        Subsystem::ALL
+            .into_iter()
+            .find(|known| known.as_str() == subsystem)
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors reading a parsing configuration
+pub enum ParsingError {
+    #[error("Expected strict or lenient - found {found}")]
+    UnknownMode { found: String },
+    #[error("Unknown parsing subsystem {found}")]
+    UnknownSubsystem { found: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// The parse mode of each subsystem
+pub struct ParsingConfig {
+    /// The mode of subsystems without one of their own
+    pub default: ParseMode,
+    pub overrides: BTreeMap<Subsystem, ParseMode>,
+}
+
+impl ParsingConfig {
+    /// Every subsystem strict
+    pub fn strict() -> ParsingConfig {
+        ParsingConfig {
+            default: ParseMode::Strict,
+            overrides: BTreeMap::new(),
+        }
+    }
+
+    /// Parse `subsystem` in `mode`, whatever the default
+    pub fn with(mut self, subsystem: Subsystem, mode: ParseMode) -> ParsingConfig {
+        self.overrides.insert(subsystem, mode);
+        self
+    }
+
+    pub fn mode(&self, subsystem: Subsystem) -> ParseMode {
+        self.overrides
+            .get(&subsystem)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl fmt::Display for ParsingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.default.as_str())?;
+        for (subsystem, mode) in &self.overrides {
+            write!(f, ",{}={}", subsystem.as_str(), mode.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ParsingConfig {
+    type Err = ParsingError;
+
+    /// Parses a default mode followed by any per-subsystem ones, e.g.
+    /// `lenient,lexicons=strict`. Either part may be left out
+    fn from_str(s: &str) -> Result<ParsingConfig, ParsingError> {
+        let mode = |mode: &str| {
+            ParseMode::parse(mode.trim()).ok_or_else(|| ParsingError::UnknownMode {
+                found: mode.trim().to_string(),
+            })
+        };
+        let mut config = ParsingConfig::default();
+        for part in s.split(',').filter(|part| !part.trim().is_empty()) {
+            let Some((subsystem, subsystem_mode)) = part.split_once('=') else {
+                config.default = mode(part)?;
+                continue;
+            };
+            let subsystem = Subsystem::parse(subsystem.trim()).ok_or_else(|| {
+                ParsingError::UnknownSubsystem {
+                    found: subsystem.trim().to_string(),
+                }
+            })?;
+            config.overrides.insert(subsystem, mode(subsystem_mode)?);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_can_be_set_per_subsystem() {
+        let config: ParsingConfig = "strict, languages=lenient".parse().unwrap();
+        assert_eq!(config.mode(Subsystem::Timestamps), ParseMode::Strict);
+        assert_eq!(config.mode(Subsystem::Languages), ParseMode::Lenient);
+        assert_eq!(config.to_string(), "strict,languages=lenient");
+        assert_eq!(config.to_string().parse(), Ok(config));
+
+        let lexicons: ParsingConfig = "lexicons=strict".parse().unwrap();
+        assert_eq!(
+            lexicons,
+            ParsingConfig::default().with(Subsystem::Lexicons, ParseMode::Strict)
+        );
+        assert_eq!(lexicons.mode(Subsystem::Timestamps), ParseMode::Lenient);
+        assert_eq!(
+            "languages=loose".parse::<ParsingConfig>(),
+            Err(ParsingError::UnknownMode {
+                found: "loose".to_string()
+            })
+        );
+        assert!(matches!(
+            "html=strict".parse::<ParsingConfig>(),
+            Err(ParsingError::UnknownSubsystem { .. })
+        ));
+    }
+}
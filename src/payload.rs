@@ -0,0 +1,212 @@
+//! Refusing inbox payloads too big or too complex to parse
+//!
+//! Activities are parsed into a tree in memory, and a hostile instance can craft one which is
+//! small on the wire but costs far more to hold: objects nested as deep as the parser allows,
+//! or arrays of hundreds of thousands of empty strings. So before anything parses it, the
+//! body of each `POST` to an [inbox](crate::inbound), and of any other `POST` or `PUT` of
+//! JSON, is [checked](check) against the bridge's limits:
+//!
+//! | Limit       | Refused when                                    | Set by                       |
+//! |-------------|-------------------------------------------------|------------------------------|
+//! | `max_bytes` | the body is longer, with `413`                  | `FEDIBRIDGE_INBOX_MAX_BYTES` |
+//! | `max_depth` | arrays and objects nest deeper, with `400`      | `FEDIBRIDGE_INBOX_MAX_DEPTH` |
+//! | `max_items` | an array or object has more entries, with `400` | `FEDIBRIDGE_INBOX_MAX_ITEMS` |
+//!
+//! The length is checked by the `Content-Length`, as its [body limit](Handler::body_limit),
+//! before the body is read, so none of one too long is. Checking the rest only scans the
+//! bytes, keeping a count for each level of nesting, and stops at the first limit gone over.
+//! Zero leaves a limit unset, though no body is ever read beyond [`MAX_BODY_SIZE`], nor parsed
+//! deeper than [`MAX_DEPTH`](crate::json::MAX_DEPTH)
+
+use crate::http::{Handler, Method, Request, Response, MAX_BODY_SIZE};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Limits on the activities posted to inboxes. `None` leaves one unset
+pub struct PayloadLimits {
+    pub max_bytes: Option<usize>,
+    pub max_depth: Option<usize>,
+    /// Most entries in any one array or object
+    pub max_items: Option<usize>,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        PayloadLimits {
+            max_bytes: Some(256 * 1024),
+            max_depth: Some(32),
+            max_items: Some(1_000),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+/// Why a payload was refused
+pub enum PayloadError {
+    #[error("Body of {size} bytes is over the limit of {limit}")]
+    TooLarge { size: usize, limit: usize },
+    #[error("Nested deeper than {limit} levels")]
+    TooDeep { limit: usize },
+    #[error("An array or object has more than {limit} entries")]
+    TooLong { limit: usize },
+}
+
+impl PayloadError {
+    /// The status a request refused for this is answered with
+    pub fn status(&self) -> u16 {
+        match self {
+            PayloadError::TooLarge { .. } => 413,
+            PayloadError::TooDeep { .. } | PayloadError::TooLong { .. } => 400,
+        }
+    }
+}
+
+/// Check `body` against `limits`, without parsing it. Malformed JSON isn't refused here, but
+/// left for the parser to find
+pub fn check(body: &[u8], limits: &PayloadLimits) -> Result<(), PayloadError> {
+    if let Some(limit) = limits.max_bytes.filter(|&limit| body.len() > limit) {
+        return Err(PayloadError::TooLarge {
+            size: body.len(),
+            limit,
+        });
+    }
+    // The entries after the first in each array or object still open, by their separators
+    let mut separators: Vec<usize> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                if let Some(limit) = limits.max_depth.filter(|&l| separators.len() >= l) {
+                    return Err(PayloadError::TooDeep { limit });
+                }
+                separators.push(0);
+            }
+            b']' | b'}' => {
+                separators.pop();
+            }
+            b',' => {
+                let Some(count) = separators.last_mut() else {
+                    continue;
+                };
+                *count += 1;
+                if let Some(limit) = limits.max_items.filter(|&l| *count >= l) {
+                    return Err(PayloadError::TooLong { limit });
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether `request` posts to an inbox, shared or an actor's, or sends other JSON
+fn limited(request: &Request) -> bool {
+    let json = request
+        .header("content-type")
+        .is_some_and(|kind| kind.contains("json"));
+    match request.method {
+        Method::Post => json || request.path.trim_end_matches('/').ends_with("/inbox"),
+        Method::Put => json,
+        _ => false,
+    }
+}
+
+/// Refuses activities posted to inboxes, and other JSON bodies, over an inner handler's
+/// [`PayloadLimits`]
+pub struct Bounded<H> {
+    inner: H,
+    limits: PayloadLimits,
+}
+
+impl<H: Handler> Bounded<H> {
+    pub fn new(inner: H, limits: PayloadLimits) -> Bounded<H> {
+        Bounded { inner, limits }
+    }
+}
+
+impl<H: Handler> Handler for Bounded<H> {
+    fn handle(&self, request: &Request) -> Response {
+        if !limited(request) {
+            return self.inner.handle(request);
+        }
+        match check(&request.body, &self.limits) {
+            Ok(()) => self.inner.handle(request),
+            Err(e) => Response::error(e.status(), e.to_string()),
+        }
+    }
+
+    fn body_limit(&self, request: &Request) -> usize {
+        match (limited(request), self.limits.max_bytes) {
+            (true, Some(limit)) => limit.min(MAX_BODY_SIZE),
+            _ => self.inner.body_limit(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpError;
+    use std::io::Read;
+
+    #[test]
+    fn refuses_hostile_payloads_before_parsing() {
+        let limits = PayloadLimits {
+            max_bytes: Some(64),
+            max_depth: Some(3),
+            max_items: Some(3),
+        };
+        assert_eq!(check(br#"{"a": [[1, 2, 3]]}"#, &limits), Ok(()));
+        assert_eq!(
+            check(br#"{"a": [[[1]]]}"#, &limits),
+            Err(PayloadError::TooDeep { limit: 3 })
+        );
+        assert_eq!(
+            check(br#"[1, 2, 3, 4]"#, &limits),
+            Err(PayloadError::TooLong { limit: 3 })
+        );
+        // What's in strings isn't structure
+        assert_eq!(check(br#"["[[[,,,\"[[["]"#, &limits), Ok(()));
+        let large = format!("[\"{}\"]", "a".repeat(64));
+        assert!(matches!(
+            check(large.as_bytes(), &limits),
+            Err(PayloadError::TooLarge { size: 68, .. })
+        ));
+        let unlimited = PayloadLimits {
+            max_bytes: None,
+            max_depth: None,
+            max_items: None,
+        };
+        assert_eq!(check(large.as_bytes(), &unlimited), Ok(()));
+
+        let ok = |_: &Request| Response::new(202);
+        let bounded = Bounded::new(ok, limits);
+        let post = |path: &str| Request::new(Method::Post, path).with_body(large.clone());
+        assert_eq!(bounded.handle(&post("/users/alice/inbox")).status, 413);
+        assert_eq!(bounded.handle(&post("/inbox")).status, 413);
+        let json = post("/account/preferences").with_header("content-type", "application/json");
+        assert_eq!(bounded.handle(&json).status, 413);
+        // Only inboxes and JSON are limited
+        assert_eq!(bounded.handle(&post("/account/lists")).status, 202);
+
+        // And a body over the limit isn't read at all
+        let raw = "POST /inbox HTTP/1.1\r\nContent-Length: 65\r\n\r\n";
+        let mut stream = raw.as_bytes().chain(large.as_bytes());
+        let read = Request::read_limited(&mut stream, |head| bounded.body_limit(head));
+        assert!(matches!(read, Err(HttpError::BodyTooLarge { size: 65 })));
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, large);
+    }
+}
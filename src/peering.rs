@@ -0,0 +1,500 @@
+//! Peering with other fedibridge instances
+//!
+//! Anyone can run a fedibridge anywhere, so unlike [`OtherBridges`](crate::interop::OtherBridges)
+//! their accounts can't be recognised by domain. Instead, bridges which peer tell each other
+//! who they bridge. Each serves assertions of its active mappings at [`MAPPINGS_PATH`], signed
+//! with its [peering key](KeyPurpose::PeerSigning), which its `did:web` document lists as
+//! `#fedibridge_peering`. Every [`PeerConfig::interval`], a bridge fetches each of its
+//! [`PeerConfig::peers`]' assertions, keeping those which verify with the peer's key, name
+//! the peer as their bridge and were issued within [`FRESH_FOR`] intervals, and an account one
+//! of them bridges can't [opt in](crate::interop::bridged_elsewhere).
+//!
+//! A peer's assertions replace whatever it asserted before, so an account it stops bridging
+//! can opt in here once it's next fetched. One bridged both here and by a peer already, as
+//! when they started peering, is reported as a conflict for operators to settle
+//!
+//! Checking a peer's signatures takes the bridge's [`SignatureVerifier`], and making its own
+//! its [repo signer](crate::repo::RepoSigner), so a bridge with peers but either missing isn't
+//! [`ready`] to peer, and the binary refuses to start
+//!
+//! [`SignatureVerifier`]: crate::signatures::SignatureVerifier
+
+use crate::actorkeys::PublicKey;
+use crate::bridge::Bridge;
+use crate::car;
+use crate::crypto::base64_encode;
+use crate::http::{percent_encode, Handler, Method, Request, Response};
+use crate::json::{self, Value};
+use crate::keys::{KeyGenerator, KeyOwner, KeyPurpose, StoredKey};
+use crate::resolver::ResolveError;
+use crate::shutdown::Shutdown;
+use crate::store::{Mapping, MappingStatus};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Where a bridge serves its assertions
+pub const MAPPINGS_PATH: &str = "/.well-known/fedibridge/mappings";
+/// The id of the peering key and service in a bridge's DID document
+pub const PEERING_ID: &str = "fedibridge_peering";
+/// The most assertions served at once, and the default
+pub const MAX_PAGE: usize = 1000;
+/// The most pages fetched from a peer in one sync, so one can't keep it going forever
+const MAX_PAGES: usize = 1000;
+/// How many [`PeerConfig::interval`]s old an assertion can be, so one captured while a peer
+/// bridged an account can't be replayed once it's stopped
+pub const FRESH_FOR: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Which bridges are peered with, and how often they're asked who they bridge
+pub struct PeerConfig {
+    /// The peers' hostnames, whose `did:web`s they sign as
+    pub peers: Vec<String>,
+    pub interval: Duration,
+}
+
+impl Default for PeerConfig {
+    fn default() -> Self {
+        PeerConfig {
+            peers: Vec::new(),
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PeeringError {
+    #[error("No repo signer is configured")]
+    NoSigner,
+    #[error("No signature verifier is configured")]
+    NoVerifier,
+    #[error("The bridge has no peering key")]
+    NoKey,
+    #[error("{0} isn't a hostname")]
+    Hostname(String),
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error("{0}'s DID document lists no peering key")]
+    NoPeerKey(String),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("{peer} refused with status {status}")]
+    Rejected { peer: String, status: u16 },
+    #[error("{0} answered with something other than assertions")]
+    Malformed(String),
+    #[error("Couldn't sign an assertion: {0:#}")]
+    Signing(anyhow::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A bridge's signed statement that it bridges an account
+pub struct Assertion {
+    /// The bridge, as its `did:web`
+    pub bridge: Did,
+    pub did: Did,
+    pub actor: String,
+    pub handle: Option<String>,
+    pub issued_at: SystemTime,
+    /// The signature of the rest, in base64
+    pub sig: String,
+}
+
+impl Assertion {
+    fn unsigned(&self) -> Value {
+        Value::object([
+            ("bridge", Value::from(self.bridge.as_str())),
+            ("did", Value::from(self.did.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            ("handle", Value::from(self.handle.as_deref())),
+            ("issuedAt", Value::from(format_rfc3339(self.issued_at))),
+        ])
+    }
+
+    /// What the signature is of: the assertion without it, encoded as DAG-CBOR
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        car::encode_dag_cbor(&self.unsigned())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut value = self.unsigned();
+        if let Value::Object(fields) = &mut value {
+            fields.insert("sig".to_string(), Value::from(self.sig.as_str()));
+        }
+        value
+    }
+
+    pub fn from_json(value: &Value) -> Option<Assertion> {
+        let did = |name| Did::try_create(value.get(name)?.as_str()?.to_string()).ok();
+        let text = |name| Some(value.get(name)?.as_str()?.to_string());
+        Some(Assertion {
+            bridge: did("bridge")?,
+            did: did("did")?,
+            actor: text("actor")?,
+            handle: text("handle"),
+            issued_at: parse_rfc3339(value.get("issuedAt")?.as_str()?).ok()?,
+            sig: text("sig")?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+/// What the bridge's peers assert they bridge, by peer hostname
+pub struct PeerMappings {
+    inner: Mutex<HashMap<String, Vec<Assertion>>>,
+}
+
+impl PeerMappings {
+    /// Replace what `peer` asserted before with `assertions`
+    pub fn replace(&self, peer: &str, assertions: Vec<Assertion>) {
+        self.inner
+            .lock()
+            .unwrap()
+            .insert(peer.to_string(), assertions);
+    }
+
+    /// The peer bridging `mapping`'s account, by its DID or actor, if any does
+    pub fn bridging(&self, mapping: &Mapping) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut peers = inner.iter().filter(|(_, assertions)| {
+            let same = |a: &Assertion| a.did == mapping.did || a.actor == mapping.actor;
+            assertions.iter().any(same)
+        });
+        peers.next().map(|(peer, _)| peer.clone())
+    }
+}
+
+/// The bridge's current peering key, generating it if it doesn't have one yet
+pub fn peering_key(bridge: &Bridge, generator: &dyn KeyGenerator) -> anyhow::Result<StoredKey> {
+    Ok(bridge
+        .keys
+        .ensure(&KeyOwner::Bridge, KeyPurpose::PeerSigning, generator)?)
+}
+
+/// Signed assertions of the bridge at `hostname`'s active mappings, in order of DID, of up
+/// to `limit` after the DID `cursor`
+pub fn assertions(
+    bridge: &Bridge,
+    hostname: &str,
+    cursor: Option<&str>,
+    limit: usize,
+    now: SystemTime,
+) -> Result<Vec<Assertion>, PeeringError> {
+    let signer = bridge.repo_signer.as_ref().ok_or(PeeringError::NoSigner)?;
+    let key = bridge
+        .keys
+        .current(&KeyOwner::Bridge, KeyPurpose::PeerSigning)
+        .ok_or(PeeringError::NoKey)?;
+    let did =
+        Did::did_web(hostname, &[]).map_err(|_| PeeringError::Hostname(hostname.to_string()))?;
+    let mut mappings = bridge.identities.all();
+    mappings.retain(|m| m.status == MappingStatus::Active);
+    mappings.retain(|m| cursor.is_none_or(|cursor| m.did.as_str() > cursor));
+    mappings.sort_by(|a, b| a.did.as_str().cmp(b.did.as_str()));
+    mappings.truncate(limit);
+    let assert = |mapping: Mapping| {
+        let mut assertion = Assertion {
+            bridge: did.clone(),
+            did: mapping.did,
+            actor: mapping.actor,
+            handle: mapping.handle,
+            issued_at: now,
+            sig: String::new(),
+        };
+        let sig = signer
+            .sign(&key.keypair, &assertion.signed_bytes())
+            .map_err(PeeringError::Signing)?;
+        assertion.sig = base64_encode(&sig);
+        Ok(assertion)
+    };
+    mappings.into_iter().map(assert).collect()
+}
+
+/// The peering key a bridge's DID document lists
+fn peer_key(document: &Value) -> Option<PublicKey> {
+    let methods = document.get("verificationMethod").and_then(Value::as_array);
+    let suffix = format!("#{PEERING_ID}");
+    let method = methods.unwrap_or_default().iter().find(|method| {
+        let id = method.get("id").and_then(Value::as_str);
+        id.is_some_and(|id| id.ends_with(&suffix))
+    })?;
+    let key = method.get("publicKeyMultibase")?.as_str()?;
+    Some(PublicKey::Multibase(key.to_string()))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What came of fetching a peer's assertions
+pub struct Synced {
+    pub accepted: usize,
+    /// Assertions which didn't verify, were of another bridge, or were stale
+    pub rejected: usize,
+    /// Accounts bridged here as well as by the peer
+    pub conflicts: Vec<Did>,
+}
+
+/// Fetch the assertions of the peer at `peer`, replacing what it asserted before
+pub fn sync_peer(bridge: &Bridge, peer: &str) -> Result<Synced, PeeringError> {
+    let verifier = bridge.signature_verifier.as_ref();
+    let verifier = verifier.ok_or(PeeringError::NoVerifier)?;
+    let did = Did::did_web(peer, &[]).map_err(|_| PeeringError::Hostname(peer.to_string()))?;
+    let document = bridge.resolver().resolve(&did)?;
+    let key = peer_key(&document).ok_or_else(|| PeeringError::NoPeerKey(peer.to_string()))?;
+    let now = SystemTime::now();
+    let oldest = now.checked_sub(bridge.peering.interval.saturating_mul(FRESH_FOR));
+    let mut synced = Synced::default();
+    let mut accepted = Vec::new();
+    let mut cursor: Option<String> = None;
+    for _ in 0..MAX_PAGES {
+        let mut url = format!("https://{peer}{MAPPINGS_PATH}?limit={MAX_PAGE}");
+        if let Some(cursor) = &cursor {
+            url.push_str(&format!("&cursor={}", percent_encode(cursor)));
+        }
+        let response = bridge.transport.send(&OutboundRequest::get(url))?;
+        if !response.is_success() {
+            let status = response.status;
+            return Err(PeeringError::Rejected {
+                peer: peer.to_string(),
+                status,
+            });
+        }
+        let malformed = || PeeringError::Malformed(peer.to_string());
+        let page = json::parse(&String::from_utf8_lossy(&response.body));
+        let page = page.map_err(|_| malformed())?;
+        let entries = page.get("assertions").and_then(Value::as_array);
+        for entry in entries.ok_or_else(malformed)? {
+            let assertion = Assertion::from_json(entry).filter(|a| {
+                let signed = a.signed_bytes();
+                let fresh = oldest.is_none_or(|oldest| a.issued_at >= oldest);
+                a.bridge == did && fresh && verifier.verify(&key, &signed, &a.sig)
+            });
+            match assertion {
+                Some(assertion) => accepted.push(assertion),
+                None => synced.rejected += 1,
+            }
+        }
+        cursor = page
+            .get("cursor")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if cursor.is_none() {
+            break;
+        }
+    }
+    for assertion in &accepted {
+        let here = bridge.identities.get(&assertion.did);
+        let here = here.or_else(|| bridge.identities.get_by_actor(&assertion.actor));
+        if here.is_some_and(|m| m.status == MappingStatus::Active) {
+            synced.conflicts.push(assertion.did.clone());
+        }
+    }
+    synced.accepted = accepted.len();
+    bridge.peer_mappings.replace(peer, accepted);
+    Ok(synced)
+}
+
+/// Fetch every peer's assertions, logging what came of it
+pub fn sync(bridge: &Bridge) {
+    for peer in &bridge.peering.peers {
+        match sync_peer(bridge, peer) {
+            Ok(synced) if !synced.conflicts.is_empty() => {
+                let conflicts: Vec<&str> = synced.conflicts.iter().map(Did::as_str).collect();
+                let conflicts = conflicts.join(", ");
+                eprintln!("{peer} bridges accounts bridged here too: {conflicts}");
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Couldn't fetch who {peer} bridges: {e}"),
+        }
+    }
+}
+
+/// Whether `bridge` has what it needs to peer with its peers, if it has any
+pub fn ready(bridge: &Bridge) -> Result<(), PeeringError> {
+    if bridge.peering.peers.is_empty() {
+        return Ok(());
+    }
+    if bridge.signature_verifier.is_none() {
+        return Err(PeeringError::NoVerifier);
+    }
+    if bridge.repo_signer.is_none() {
+        return Err(PeeringError::NoSigner);
+    }
+    Ok(())
+}
+
+/// Sync with the bridge's peers every [`PeerConfig::interval`] until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_sync = None;
+        while !shutdown.is_requested() {
+            let interval = bridge.peering.interval;
+            if last_sync.is_some_and(|at: Instant| at.elapsed() < interval) {
+                thread::sleep(Duration::from_millis(250));
+                continue;
+            }
+            last_sync = Some(Instant::now());
+            sync(&bridge);
+        }
+    })
+}
+
+/// Serves the bridge's assertions to its peers
+pub struct PeeringEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> PeeringEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> PeeringEndpoints<H> {
+        PeeringEndpoints {
+            bridge,
+            hostname,
+            inner,
+        }
+    }
+
+    fn mappings(&self, hostname: &str, request: &Request) -> Response {
+        let limit = request.query_param("limit").and_then(|l| l.parse().ok());
+        let limit = limit.unwrap_or(MAX_PAGE).clamp(1, MAX_PAGE);
+        let cursor = request.query_param("cursor");
+        let now = SystemTime::now();
+        match assertions(&self.bridge, hostname, cursor, limit, now) {
+            Ok(assertions) => {
+                let last = assertions.last().filter(|_| assertions.len() == limit);
+                let cursor = last.map(|a| a.did.as_str().to_string());
+                let assertions = assertions.iter().map(Assertion::to_json).collect();
+                Response::json(
+                    200,
+                    &Value::object([
+                        ("assertions", Value::Array(assertions)),
+                        ("cursor", Value::from(cursor)),
+                    ]),
+                )
+            }
+            Err(e @ (PeeringError::NoSigner | PeeringError::NoKey)) => {
+                Response::error(503, e.to_string())
+            }
+            Err(e) => Response::error(500, format!("Couldn't assert the mappings: {e}")),
+        }
+    }
+}
+
+impl<H: Handler> Handler for PeeringEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let Some(hostname) = &self.hostname else {
+            return self.inner.handle(request);
+        };
+        match (request.method, request.segments().as_slice()) {
+            (Method::Get, [".well-known", "fedibridge", "mappings"]) => {
+                self.mappings(hostname, request)
+            }
+            _ => self.inner.handle(request),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyAlgorithm, KeyPair};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::RepoSigner;
+    use crate::signatures::SignatureVerifier;
+    use crate::transport::MockTransport;
+    use atproto::did;
+
+    /// Checks [`HashSigner`]'s signatures by signing again with the private key
+    struct HashVerifier(Vec<u8>);
+
+    impl SignatureVerifier for HashVerifier {
+        fn verify(&self, _: &PublicKey, signed: &[u8], signature: &str) -> bool {
+            let key = KeyPair {
+                algorithm: KeyAlgorithm::Secp256k1,
+                private_key: self.0.clone(),
+                public_key: String::new(),
+            };
+            base64_encode(&HashSigner.sign(&key, signed).unwrap()) == signature
+        }
+    }
+
+    #[test]
+    fn peers_accounts_cant_opt_in() {
+        let generator = FakeGenerator::default();
+        let peer = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let key = peering_key(&peer, &generator).unwrap();
+        let alice = Mapping::new(did!("did:plc:alice"), "https://a.example/users/alice");
+        peer.identities.insert(alice.clone());
+        let mut paused = Mapping::new(did!("did:plc:bob"), "https://a.example/users/bob");
+        paused.status = MappingStatus::Paused;
+        peer.identities.insert(paused);
+        let since_epoch = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs());
+        let asserted = assertions(&peer, "peer.example", None, MAX_PAGE, now).unwrap();
+        assert_eq!(asserted.len(), 1);
+        assert_eq!(
+            Assertion::from_json(&asserted[0].to_json()).as_ref(),
+            Some(&asserted[0])
+        );
+        let mut forged = asserted[0].clone();
+        forged.did = did!("did:plc:carol");
+        // Nor is one from long enough ago that the peer may have stopped bridging since
+        let long_ago = now - PeerConfig::default().interval * (FRESH_FOR + 1);
+        let stale = assertions(&peer, "peer.example", None, MAX_PAGE, long_ago).unwrap();
+
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://peer.example/.well-known/did.json",
+            &format!(
+                r#"{{"id": "did:web:peer.example", "verificationMethod": [{{
+                    "id": "did:web:peer.example#{PEERING_ID}", "type": "Multikey",
+                    "publicKeyMultibase": "{}"}}]}}"#,
+                key.keypair.public_key
+            ),
+        );
+        let page = Value::object([
+            (
+                "assertions",
+                Value::Array(vec![asserted[0].to_json(), forged.to_json()]),
+            ),
+            ("cursor", Value::from("did:plc:alice")),
+        ]);
+        let url = format!("https://peer.example{MAPPINGS_PATH}?limit={MAX_PAGE}");
+        mock.respond_json(&url, &page.to_string());
+        let page = Value::object([("assertions", Value::Array(vec![stale[0].to_json()]))]);
+        let next = format!("{url}&cursor=did%3Aplc%3Aalice");
+        mock.respond_json(&next, &page.to_string());
+        let mut bridge = Bridge::new().with_transport(mock).with_peering(PeerConfig {
+            peers: vec!["peer.example".to_string()],
+            ..PeerConfig::default()
+        });
+        assert!(matches!(ready(&bridge), Err(PeeringError::NoVerifier)));
+        bridge.signature_verifier = Some(Arc::new(HashVerifier(key.keypair.private_key)));
+        assert!(matches!(ready(&bridge), Err(PeeringError::NoSigner)));
+        let synced = sync_peer(&bridge, "peer.example").unwrap();
+        assert_eq!(
+            synced,
+            Synced {
+                accepted: 1,
+                rejected: 2,
+                conflicts: Vec::new(),
+            }
+        );
+        assert_eq!(
+            bridge.peer_mappings.bridging(&alice).as_deref(),
+            Some("peer.example")
+        );
+        let carol = Mapping::new(did!("did:plc:carol"), "https://a.example/users/carol");
+        assert_eq!(bridge.peer_mappings.bridging(&carol), None);
+        assert_eq!(
+            crate::interop::bridged_elsewhere(&bridge, &alice).as_deref(),
+            Some("peer.example")
+        );
+    }
+}
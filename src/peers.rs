@@ -0,0 +1,381 @@
+//! What the software of each peer instance can handle
+//!
+//! The fediverse agrees on little beyond plain notes and likes. Some software shows emoji
+//! reactions, some quote posts and show them as quotes, and some verify RFC 9421 HTTP
+//! message signatures as well as the older drafts; the rest ignore what they don't know, or
+//! worse, drop the activity. So the bridge asks each instance what it runs, from its
+//! [NodeInfo](https://nodeinfo.diaspora.software), and keeps a [`Profile`] of what that
+//! software is known to support:
+//!
+//! | Capability  | Supported by                                                   |
+//! |-------------|----------------------------------------------------------------|
+//! | `reactions` | Misskey and its forks, Pleroma, Akkoma, Fedibird               |
+//! | `quotes`    | Misskey and its forks, Pleroma, Akkoma, Fedibird, Mastodon 4.5 |
+//! | `rfc9421`   | Mastodon 4.5                                                   |
+//!
+//! Instances are only probed once something going to them depends on what they support, and
+//! their profile is kept for [`PROFILE_TTL`], or [`RETRY_AFTER`] if they couldn't say.
+//! Those which can't are assumed to support none of it. As a delivery is sent it's
+//! [adapted](adapt) to the profile of the instance it's going to: reactions become plain
+//! `Like`s where they wouldn't be shown, and quotes fall back to an `RE: <link>` in the
+//! content where they won't be seen as quotes. The admin API's `GET /admin/peers` lists the
+//! profiles known
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::html;
+use crate::json::{self, Value};
+use crate::misskey::{self, QUOTE_FIELDS};
+use crate::time::format_rfc3339;
+use crate::transport::{OutboundRequest, TransportError};
+use crate::url::Url;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Where an instance says where its NodeInfo is
+pub const NODEINFO_PATH: &str = "/.well-known/nodeinfo";
+/// How long an instance's profile is kept before it's probed again
+pub const PROFILE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long until an instance which couldn't say what it runs is asked again
+pub const RETRY_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// The `rel` of the NodeInfo 2.x schemas, before the minor version
+const SCHEMA_REL: &str = "http://nodeinfo.diaspora.software/ns/schema/2.";
+/// Software showing emoji reactions and quotes, as its NodeInfo names it
+const MISSKEY_LIKE: &[&str] = &[
+    "misskey",
+    "sharkey",
+    "firefish",
+    "iceshrimp",
+    "foundkey",
+    "cherrypick",
+    "catodon",
+    "pleroma",
+    "akkoma",
+    "fedibird",
+];
+
+#[derive(Debug, Error)]
+/// Why an instance couldn't say what it runs
+pub enum ProbeError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("{url} responded with {status}")]
+    Rejected { url: String, status: u16 },
+    #[error("{url} doesn't link to NodeInfo 2")]
+    NoNodeInfo { url: String },
+    #[error("{url} isn't valid NodeInfo")]
+    Malformed { url: String },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What a peer's software handles, beyond plain notes and likes
+pub struct Capabilities {
+    /// `EmojiReact`s are shown as reactions
+    pub reactions: bool,
+    /// Quotes are shown as quotes, from `quoteUrl` and the like
+    pub quotes: bool,
+    /// RFC 9421 HTTP message signatures are verified
+    pub rfc9421: bool,
+}
+
+impl Capabilities {
+    /// What `software` at `version` is known to handle
+    pub fn of(software: &str, version: &str) -> Capabilities {
+        let software = software.to_ascii_lowercase();
+        if MISSKEY_LIKE.contains(&software.as_str()) {
+            return Capabilities {
+                reactions: true,
+                quotes: true,
+                rfc9421: false,
+            };
+        }
+        let mut numbers = version.split(|c: char| !c.is_ascii_digit());
+        let mut number = || numbers.next().and_then(|n| n.parse::<u32>().ok());
+        let release = (number().unwrap_or(0), number().unwrap_or(0));
+        let modern_mastodon = software == "mastodon" && release >= (4, 5);
+        Capabilities {
+            reactions: false,
+            quotes: modern_mastodon,
+            rfc9421: modern_mastodon,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a peer instance runs, and so what it handles
+pub struct Profile {
+    /// The software's name and version, unless the instance couldn't say
+    pub software: Option<(String, String)>,
+    pub capabilities: Capabilities,
+    pub probed_at: SystemTime,
+}
+
+impl Profile {
+    /// Whether it's still to be relied on at `now`
+    fn fresh(&self, now: SystemTime) -> bool {
+        let ttl = match self.software {
+            Some(_) => PROFILE_TTL,
+            None => RETRY_AFTER,
+        };
+        now.duration_since(self.probed_at).unwrap_or_default() < ttl
+    }
+
+    pub fn to_json(&self, host: &str) -> Value {
+        let software = self.software.as_ref();
+        let capabilities = self.capabilities;
+        Value::object([
+            ("host", Value::from(host)),
+            (
+                "software",
+                Value::from(software.map(|(name, _)| name.clone())),
+            ),
+            ("version", Value::from(software.map(|(_, v)| v.clone()))),
+            (
+                "capabilities",
+                Value::object([
+                    ("reactions", Value::from(capabilities.reactions)),
+                    ("quotes", Value::from(capabilities.quotes)),
+                    ("rfc9421", Value::from(capabilities.rfc9421)),
+                ]),
+            ),
+            ("probedAt", Value::from(format_rfc3339(self.probed_at))),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// The profiles of the peer instances probed, by host
+pub struct PeerProfiles {
+    profiles: Mutex<BTreeMap<String, Profile>>,
+}
+
+impl PeerProfiles {
+    pub fn get(&self, host: &str) -> Option<Profile> {
+        let profiles = self.profiles.lock().unwrap();
+        profiles.get(&host.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn insert(&self, host: &str, profile: Profile) {
+        let mut profiles = self.profiles.lock().unwrap();
+        profiles.insert(host.to_ascii_lowercase(), profile);
+    }
+
+    /// Every profile, by host
+    pub fn to_json(&self) -> Value {
+        let profiles = self.profiles.lock().unwrap();
+        let all = profiles.iter().map(|(host, p)| p.to_json(host));
+        Value::Array(all.collect())
+    }
+}
+
+fn get_json(bridge: &Bridge, url: &str) -> Result<Value, ProbeError> {
+    let request = OutboundRequest::get(url).with_header("accept", "application/json");
+    let response = bridge.transport.send(&request)?;
+    if !response.is_success() {
+        return Err(ProbeError::Rejected {
+            url: url.to_string(),
+            status: response.status,
+        });
+    }
+    json::parse(&String::from_utf8_lossy(&response.body)).map_err(|_| ProbeError::Malformed {
+        url: url.to_string(),
+    })
+}
+
+/// Ask `host` what software it runs, returning its name and version
+pub fn probe(bridge: &Bridge, host: &str) -> Result<(String, String), ProbeError> {
+    let url = format!("https://{host}{NODEINFO_PATH}");
+    let links = get_json(bridge, &url)?;
+    let links = links.get("links").and_then(Value::as_array);
+    // Instances link every schema they serve, and the latest sorts last
+    let href = links
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|link| {
+            let rel = link.get("rel")?.as_str()?;
+            if !rel.starts_with(SCHEMA_REL) {
+                return None;
+            }
+            Some((rel, link.get("href")?.as_str()?))
+        })
+        .max_by_key(|(rel, _)| *rel)
+        .map(|(_, href)| href.to_string())
+        .ok_or(ProbeError::NoNodeInfo { url })?;
+    let nodeinfo = get_json(bridge, &href)?;
+    let software = nodeinfo.get("software");
+    let field = |name| software.and_then(|s| s.get(name)).and_then(Value::as_str);
+    let name = field("name").ok_or(ProbeError::Malformed { url: href })?;
+    Ok((
+        name.to_string(),
+        field("version").unwrap_or_default().to_string(),
+    ))
+}
+
+/// `host`'s profile at `now`, probing it if there isn't a fresh one
+pub fn profile(bridge: &Bridge, host: &str, now: SystemTime) -> Profile {
+    if let Some(profile) = bridge.peers.get(host).filter(|p| p.fresh(now)) {
+        return profile;
+    }
+    let software = match probe(bridge, host) {
+        Ok(software) => Some(software),
+        Err(e) => {
+            eprintln!("Couldn't tell what {host} runs: {e}");
+            None
+        }
+    };
+    let capabilities = software
+        .as_ref()
+        .map_or_else(Capabilities::default, |(name, version)| {
+            Capabilities::of(name, version)
+        });
+    let profile = Profile {
+        software,
+        capabilities,
+        probed_at: now,
+    };
+    bridge.peers.insert(host, profile.clone());
+    profile
+}
+
+/// The quote `activity`'s object names, if it creates or updates one that does
+fn quoted(activity: &Value) -> Option<String> {
+    let kind = activity.get("type")?.as_str()?;
+    if !matches!(kind, "Create" | "Update") {
+        return None;
+    }
+    misskey::quote(activity.get("object")?)
+}
+
+/// Whether how `activity` is represented depends on what its recipient handles
+fn depends_on_peer(activity: &Value) -> bool {
+    activity.get("type").and_then(Value::as_str) == Some("EmojiReact") || quoted(activity).is_some()
+}
+
+/// Change `activity` into what an instance with `capabilities` handles best, returning
+/// whether it had to be
+pub fn adapt(capabilities: &Capabilities, activity: &mut Value) -> bool {
+    let quote = quoted(activity);
+    let Value::Object(fields) = activity else {
+        return false;
+    };
+    let mut changed = false;
+    if fields.get("type").and_then(Value::as_str) == Some("EmojiReact") && !capabilities.reactions {
+        fields.insert("type".to_string(), Value::from("Like"));
+        fields.remove("content");
+        fields.remove("tag");
+        changed = true;
+    }
+    let (Some(quote), Some(Value::Object(object))) = (quote, fields.get_mut("object")) else {
+        return changed;
+    };
+    if capabilities.quotes {
+        // Each reads a field of its own, so all of them are set
+        for field in QUOTE_FIELDS {
+            if object.get(*field).and_then(Value::as_str) != Some(quote.as_str()) {
+                object.insert(field.to_string(), Value::from(quote.as_str()));
+                changed = true;
+            }
+        }
+        return changed;
+    }
+    for field in QUOTE_FIELDS {
+        changed |= object.remove(*field).is_some();
+    }
+    let content = object.get("content").and_then(Value::as_str);
+    let content = content.unwrap_or_default();
+    if !content.contains(quote.as_str()) {
+        let link = html::escape(&quote);
+        let fallback = format!(r#"<p>RE: <a href="{link}">{link}</a></p>"#);
+        object.insert(
+            "content".to_string(),
+            Value::from(format!("{content}{fallback}")),
+        );
+        changed = true;
+    }
+    changed
+}
+
+/// `delivery` as its inbox's instance handles it best
+pub fn adapted(bridge: &Bridge, delivery: &Delivery) -> Delivery {
+    let Ok(mut activity) = json::parse(&delivery.activity) else {
+        return delivery.clone();
+    };
+    let Ok(inbox) = Url::parse(&delivery.inbox) else {
+        return delivery.clone();
+    };
+    if !depends_on_peer(&activity) {
+        return delivery.clone();
+    }
+    let profile = profile(bridge, &inbox.host, SystemTime::now());
+    if !adapt(&profile.capabilities, &mut activity) {
+        return delivery.clone();
+    }
+    Delivery {
+        activity: activity.to_string(),
+        ..delivery.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+    use std::sync::Arc;
+
+    #[test]
+    fn deliveries_suit_what_peers_run() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new().with_transport(mock.clone());
+        mock.respond_json(
+            "https://mk.example/.well-known/nodeinfo",
+            r#"{"links": [
+                {"rel": "http://nodeinfo.diaspora.software/ns/schema/2.0",
+                 "href": "https://mk.example/nodeinfo/2.0"},
+                {"rel": "http://nodeinfo.diaspora.software/ns/schema/2.1",
+                 "href": "https://mk.example/nodeinfo/2.1"}]}"#,
+        );
+        mock.respond_json(
+            "https://mk.example/nodeinfo/2.1",
+            r#"{"version": "2.1", "software": {"name": "sharkey", "version": "2024.11.1"}}"#,
+        );
+        let react = r#"{"type": "EmojiReact", "actor": "https://bridge.example/ap/alice",
+                        "object": "https://mk.example/notes/1", "content": "🎉"}"#;
+        let sent = adapted(&bridge, &Delivery::new("https://mk.example/inbox", react));
+        assert_eq!(sent.activity, react);
+        let profile = bridge.peers.get("mk.example").unwrap();
+        assert!(profile.capabilities.reactions);
+
+        // Software which can't say gets what everything handles, and is asked again later
+        let sent = adapted(&bridge, &Delivery::new("https://old.example/inbox", react));
+        let like = json::parse(&sent.activity).unwrap();
+        assert_eq!(like.get("type"), Some(&Value::from("Like")));
+        assert!(like.get("content").is_none());
+        let unknown = bridge.peers.get("old.example").unwrap();
+        assert_eq!(unknown.software, None);
+        assert!(!unknown.fresh(unknown.probed_at + RETRY_AFTER));
+
+        let mut quote = json::parse(
+            r#"{"type": "Create", "object": {"type": "Note", "content": "<p>Look</p>",
+                "quoteUrl": "https://b.example/notes/2"}}"#,
+        )
+        .unwrap();
+        let mut fallback = quote.clone();
+        assert!(adapt(&Capabilities::of("Mastodon", "4.5.0"), &mut quote));
+        assert_eq!(
+            quote.get("object").unwrap().get("_misskey_quote"),
+            Some(&Value::from("https://b.example/notes/2"))
+        );
+        assert!(adapt(&Capabilities::of("mastodon", "4.3.2"), &mut fallback));
+        let object = fallback.get("object").unwrap();
+        assert!(object.get("quoteUrl").is_none());
+        let content = object.get("content").and_then(Value::as_str).unwrap();
+        assert!(content.ends_with(
+            r#"RE: <a href="https://b.example/notes/2">https://b.example/notes/2</a></p>"#
+        ));
+        // Once adapted, there's nothing more to do
+        assert!(!adapt(&Capabilities::default(), &mut fallback));
+    }
+}
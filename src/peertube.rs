@@ -0,0 +1,258 @@
+//! PeerTube videos
+//!
+//! PeerTube publishes a `Video` object rather than a note with a video attached. Its `url` is
+//! a list of links: the watch page (`text/html`), one MP4 per resolution, and HLS playlists
+//! whose own `tag`s list further MP4s. Its `icon`s are thumbnails, and its `attributedTo`
+//! names both the uploader and the channel (a `Group`) it was published to.
+//!
+//! A video short and small enough for Bluesky is bridged as a native video, using the largest
+//! MP4 within [`MAX_VIDEO_SIZE`]. Anything else becomes a post linking to the watch page with
+//! a card and the video's thumbnail. Either way the post is bridged from the channel, as
+//! that's what people follow
+
+use crate::article::{trim, MAX_POST_LENGTH};
+use crate::html::decode_entities;
+use crate::json::{one_or_many, Value};
+use crate::linkcard::LinkCard;
+use crate::media::{MediaItem, MAX_VIDEO_DURATION, MAX_VIDEO_SIZE};
+use crate::richtext;
+use crate::time::parse_duration;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+/// One of a video's files
+pub struct VideoFile {
+    pub url: String,
+    pub mime_type: String,
+    pub height: Option<u32>,
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A PeerTube `Video` object
+pub struct Video {
+    pub title: String,
+    /// As plain text
+    pub description: String,
+    /// The watch page
+    pub page: String,
+    /// The channel it was published to, or else the uploader
+    pub author: Option<String>,
+    pub duration: Option<Duration>,
+    pub thumbnail: Option<String>,
+    /// Its MP4s, from every resolution and playlist
+    pub files: Vec<VideoFile>,
+    /// Its HLS playlists
+    pub streams: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How a video is embedded in the post it's bridged as
+pub enum VideoEmbed {
+    /// Uploaded as a native video
+    Upload(MediaItem),
+    /// Linked to, with the thumbnail for the card
+    Card {
+        card: LinkCard,
+        thumbnail: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A video as a Bluesky post
+pub struct VideoPost {
+    pub text: String,
+    pub embed: VideoEmbed,
+}
+
+fn number<T: TryFrom<i64>>(value: Option<&Value>) -> Option<T> {
+    value?.as_i64().and_then(|v| T::try_from(v).ok())
+}
+
+/// The MP4s among `links`, and among the links the playlists among them list
+fn files(links: &[&Value], streams: &mut Vec<String>) -> Vec<VideoFile> {
+    let mut files = Vec::new();
+    for link in links {
+        let field = |name| link.get(name).and_then(Value::as_str);
+        let (Some(href), Some(mime_type)) = (field("href"), field("mediaType")) else {
+            continue;
+        };
+        if mime_type == "application/x-mpegURL" {
+            streams.push(href.to_string());
+            files.extend(self::files(&one_or_many(link.get("tag")), streams));
+        } else if mime_type.starts_with("video/") {
+            files.push(VideoFile {
+                url: href.to_string(),
+                mime_type: mime_type.to_string(),
+                height: number(link.get("height")),
+                size: number(link.get("size")),
+            });
+        }
+    }
+    files
+}
+
+/// `object` as a [`Video`], if it's one
+pub fn parse(object: &Value) -> Option<Video> {
+    if object.get("type").and_then(Value::as_str) != Some("Video") {
+        return None;
+    }
+    let field = |name| object.get(name).and_then(Value::as_str);
+    let links = one_or_many(object.get("url"));
+    let page = links
+        .iter()
+        .find(|link| link.get("mediaType").and_then(Value::as_str) == Some("text/html"))
+        .and_then(|link| link.get("href")?.as_str())
+        .or(field("id"))?;
+    let mut streams = Vec::new();
+    let files = files(&links, &mut streams);
+    // The largest thumbnail, as PeerTube lists a small one too
+    let icons = one_or_many(object.get("icon"));
+    let thumbnail = icons
+        .into_iter()
+        .max_by_key(|icon| number::<u32>(icon.get("width")).unwrap_or(0))
+        .and_then(|icon| icon.get("url")?.as_str());
+    let attributed = one_or_many(object.get("attributedTo"));
+    let author = attributed
+        .iter()
+        .find(|actor| actor.get("type").and_then(Value::as_str) == Some("Group"))
+        .or(attributed.first())
+        .and_then(|actor| actor.as_str().or_else(|| actor.get("id")?.as_str()));
+    let description = field("content").or(field("summary")).unwrap_or_default();
+    Some(Video {
+        title: decode_entities(field("name").unwrap_or_default().trim()),
+        description: richtext::from_html(description).text,
+        page: page.to_string(),
+        author: author.map(str::to_string),
+        duration: field("duration").and_then(parse_duration),
+        thumbnail: thumbnail.map(str::to_string),
+        files,
+        streams,
+    })
+}
+
+impl Video {
+    /// The file to upload, if the video is within Bluesky's limits: the tallest MP4 of a
+    /// known size within them
+    pub fn upload(&self) -> Option<&VideoFile> {
+        if self.duration.is_none_or(|d| d > MAX_VIDEO_DURATION) {
+            return None;
+        }
+        self.files
+            .iter()
+            .filter(|file| file.mime_type == "video/mp4")
+            .filter(|file| file.size.is_some_and(|size| size <= MAX_VIDEO_SIZE))
+            .max_by_key(|file| file.height.unwrap_or(0))
+    }
+
+    /// This video as a post, uploaded where it can be
+    pub fn to_post(&self) -> VideoPost {
+        let text = match (self.title.is_empty(), self.description.is_empty()) {
+            (false, false) => {
+                let title = trim(&self.title, MAX_POST_LENGTH);
+                match MAX_POST_LENGTH.saturating_sub(title.chars().count() + 2) {
+                    0 | 1 => title,
+                    room => format!("{title}\n\n{}", trim(&self.description, room)),
+                }
+            }
+            (false, true) => trim(&self.title, MAX_POST_LENGTH),
+            (true, _) => trim(&self.description, MAX_POST_LENGTH),
+        };
+        let embed = match self.upload() {
+            Some(file) => VideoEmbed::Upload(MediaItem {
+                mime_type: Some(file.mime_type.clone()),
+                height: file.height,
+                size: file.size,
+                duration: self.duration,
+                preview: self.thumbnail.clone(),
+                ..MediaItem::new(file.url.as_str()).with_alt(self.title.as_str())
+            }),
+            None => VideoEmbed::Card {
+                card: LinkCard {
+                    uri: self.page.clone(),
+                    title: self.title.clone(),
+                    description: trim(&self.description, MAX_POST_LENGTH),
+                    thumbnail: None,
+                },
+                thumbnail: self.thumbnail.clone(),
+            },
+        };
+        VideoPost { text, embed }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn video(duration: &str, size: u64) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Video", "id": "https://tube.example/videos/watch/1",
+                "name": "Building a shed", "duration": "{duration}",
+                "content": "<p>Part one</p>",
+                "attributedTo": [
+                    {{"type": "Person", "id": "https://tube.example/accounts/alice"}},
+                    {{"type": "Group", "id": "https://tube.example/video-channels/sheds"}}
+                ],
+                "icon": [
+                    {{"url": "https://tube.example/small.jpg", "width": 280}},
+                    {{"url": "https://tube.example/large.jpg", "width": 1280}}
+                ],
+                "url": [
+                    {{"type": "Link", "mediaType": "text/html", "href": "https://tube.example/w/1"}},
+                    {{"type": "Link", "mediaType": "video/mp4", "href": "https://tube.example/480.mp4",
+                        "height": 480, "size": 2000}},
+                    {{"type": "Link", "mediaType": "application/x-mpegURL",
+                        "href": "https://tube.example/master.m3u8", "tag": [
+                        {{"type": "Link", "mediaType": "video/mp4",
+                            "href": "https://tube.example/1080.mp4", "height": 1080, "size": {size}}}
+                    ]}}
+                ]}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_variants_and_streams() {
+        let video = parse(&video("PT2M", 5000)).unwrap();
+        assert_eq!(video.page, "https://tube.example/w/1");
+        assert_eq!(
+            video.author.as_deref(),
+            Some("https://tube.example/video-channels/sheds")
+        );
+        assert_eq!(
+            video.thumbnail.as_deref(),
+            Some("https://tube.example/large.jpg")
+        );
+        assert_eq!(video.streams, ["https://tube.example/master.m3u8"]);
+        assert_eq!(video.files.len(), 2);
+        assert_eq!(video.duration, Some(Duration::from_secs(120)));
+        assert_eq!(parse(&json::parse(r#"{"type": "Note"}"#).unwrap()), None);
+    }
+
+    #[test]
+    fn uploads_what_fits_and_links_to_the_rest() {
+        let post = parse(&video("PT2M", 5000)).unwrap().to_post();
+        assert_eq!(post.text, "Building a shed\n\nPart one");
+        let VideoEmbed::Upload(item) = post.embed else {
+            panic!("expected an upload, got {:?}", post.embed);
+        };
+        assert_eq!(item.url, "https://tube.example/1080.mp4");
+        assert_eq!(item.alt.as_deref(), Some("Building a shed"));
+
+        // Too big at 1080p, but 480p fits
+        let post = parse(&video("PT2M", MAX_VIDEO_SIZE + 1)).unwrap().to_post();
+        let VideoEmbed::Upload(item) = post.embed else {
+            panic!("expected an upload, got {:?}", post.embed);
+        };
+        assert_eq!(item.url, "https://tube.example/480.mp4");
+
+        let post = parse(&video("PT1H", 5000)).unwrap().to_post();
+        let VideoEmbed::Card { card, thumbnail } = post.embed else {
+            panic!("expected a card, got {:?}", post.embed);
+        };
+        assert_eq!(card.uri, "https://tube.example/w/1");
+        assert_eq!(thumbnail.as_deref(), Some("https://tube.example/large.jpg"));
+    }
+}
@@ -0,0 +1,261 @@
+//! Web pages for bridged posts
+//!
+//! People follow links to bridged posts too, and a browser asking for one is shown a small
+//! page rather than its JSON ([`page`]): the post's text and first image, who wrote it, and
+//! links to the original on Bluesky and the bridged copy. OpenGraph tags give link previews
+//! something to show, and the page names its [oEmbed](https://oembed.com) at
+//! [`OEMBED_PATH`] ([`oembed`]) for sites which embed links that way. Deleted posts are a
+//! page saying so
+
+use crate::article::trim;
+use crate::bridge::Bridge;
+use crate::delivery::ACTIVITY_JSON;
+use crate::html::escape;
+use crate::http::percent_encode;
+use crate::json::Value;
+use crate::mentions::BLUESKY_PROFILE;
+use crate::published::Published;
+use crate::richtext;
+
+/// Where oEmbed is answered, for `?url=` a published object
+pub const OEMBED_PATH: &str = "/oembed";
+/// How much of a post's text is its description
+const DESCRIPTION_LENGTH: usize = 200;
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a page shows of an object
+pub struct Summary {
+    pub title: String,
+    pub text: String,
+    pub author: String,
+    /// The author's Bluesky profile, if they're bridged
+    pub author_url: Option<String>,
+    pub image: Option<String>,
+}
+
+/// The URL of an image, given as a link or as an object with one
+fn image_url(image: &Value) -> Option<&str> {
+    match image.get("url").unwrap_or(image) {
+        Value::String(url) => Some(url),
+        link => link.get("href").and_then(Value::as_str),
+    }
+}
+
+/// What to show of `document`, or of the object in it if it's an activity
+pub fn summarize(bridge: &Bridge, document: &Value) -> Summary {
+    let object = match document.get("object") {
+        Some(object @ Value::Object(_)) => object,
+        _ => document,
+    };
+    let field = |name| object.get(name).and_then(Value::as_str);
+    let is_actor = field("inbox").is_some() || field("preferredUsername").is_some();
+    let author_id = match object.get("attributedTo").or_else(|| document.get("actor")) {
+        Some(Value::Array(actors)) => actors.first().and_then(Value::as_str),
+        actor => actor.and_then(Value::as_str),
+    };
+    let author_id = if is_actor { field("id") } else { author_id };
+    let mapping = author_id.and_then(|actor| bridge.identities.get_by_actor(actor));
+    let author = match &mapping {
+        Some(mapping) => match &mapping.handle {
+            Some(handle) => format!("@{handle}"),
+            None => mapping.did.to_string(),
+        },
+        None => author_id.unwrap_or("Someone").to_string(),
+    };
+    let html = if is_actor {
+        field("summary")
+    } else {
+        field("content")
+    };
+    let text = richtext::from_html(html.unwrap_or_default()).text;
+    let attachments = match object.get("attachment") {
+        Some(Value::Array(attachments)) => attachments.iter().collect(),
+        attachment => attachment.into_iter().collect::<Vec<_>>(),
+    };
+    let image = attachments
+        .into_iter()
+        .filter(|a| {
+            let media = a.get("mediaType").and_then(Value::as_str);
+            media.unwrap_or_default().starts_with("image/")
+                || a.get("type").and_then(Value::as_str) == Some("Image")
+        })
+        .chain(object.get("icon").filter(|_| is_actor))
+        .find_map(image_url);
+    let title = match (is_actor, field("name")) {
+        (true, Some(name)) => format!("{name} ({author})"),
+        (true, None) => author.clone(),
+        (false, _) => format!("Post by {author}"),
+    };
+    Summary {
+        title,
+        text: text.trim().to_string(),
+        author_url: mapping.map(|m| format!("{BLUESKY_PROFILE}/{}", m.did)),
+        author,
+        image: image.map(str::to_string),
+    }
+}
+
+/// Where the oEmbed for the object `id` is, on `hostname`
+pub fn oembed_url(hostname: &str, id: &str) -> String {
+    format!(
+        "https://{hostname}{OEMBED_PATH}?url={}&format=json",
+        percent_encode(id)
+    )
+}
+
+/// The page for the published object `id`, on `hostname`
+pub fn page(bridge: &Bridge, hostname: &str, id: &str, published: &Published) -> String {
+    let meta = |property: &str, content: &str| {
+        format!(
+            "<meta property=\"{property}\" content=\"{}\">\n",
+            escape(content)
+        )
+    };
+    let mut head = String::new();
+    let mut body = String::new();
+    let title = if published.deleted {
+        body.push_str("<p>This post has been deleted.</p>\n");
+        "Deleted post".to_string()
+    } else {
+        let summary = summarize(bridge, &published.document);
+        let description = trim(&summary.text, DESCRIPTION_LENGTH);
+        head.push_str(&meta("og:type", "article"));
+        head.push_str(&meta("og:site_name", hostname));
+        head.push_str(&meta("og:title", &summary.title));
+        head.push_str(&meta("og:description", &description));
+        head.push_str(&meta("og:url", id));
+        head.push_str(&format!(
+            "<meta name=\"description\" content=\"{}\">\n",
+            escape(&description)
+        ));
+        if let Some(image) = &summary.image {
+            head.push_str(&meta("og:image", image));
+        }
+        head.push_str(&format!(
+            "<link rel=\"alternate\" type=\"application/json+oembed\" href=\"{}\" title=\"{}\">\n",
+            escape(&oembed_url(hostname, id)),
+            escape(&summary.title)
+        ));
+        let author = match &summary.author_url {
+            Some(url) => format!(
+                "<a href=\"{}\">{}</a>",
+                escape(url),
+                escape(&summary.author)
+            ),
+            None => escape(&summary.author),
+        };
+        body.push_str(&format!("<p><strong>{author}</strong></p>\n"));
+        for paragraph in summary.text.split("\n\n").filter(|p| !p.trim().is_empty()) {
+            let paragraph = escape(paragraph).replace('\n', "<br>");
+            body.push_str(&format!("<p>{paragraph}</p>\n"));
+        }
+        if let Some(image) = &summary.image {
+            body.push_str(&format!(
+                "<p><img src=\"{}\" alt=\"\"></p>\n",
+                escape(image)
+            ));
+        }
+        summary.title
+    };
+    if let Some(origin) = &published.origin {
+        head.push_str(&format!(
+            "<link rel=\"canonical\" href=\"{}\">\n",
+            escape(origin)
+        ));
+    }
+    head.push_str(&format!(
+        "<link rel=\"alternate\" type=\"{ACTIVITY_JSON}\" href=\"{}\">\n",
+        escape(id)
+    ));
+    let mut links = Vec::new();
+    if let Some(origin) = &published.origin {
+        links.push(format!(
+            "<a href=\"{}\">View the original on Bluesky</a>",
+            escape(origin)
+        ));
+    }
+    links.push(format!(
+        "<a href=\"{}\" type=\"{ACTIVITY_JSON}\">Bridged copy</a>",
+        escape(id)
+    ));
+    body.push_str(&format!("<p>{}</p>\n", links.join(" · ")));
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n{head}</head>\n<body>\n<article>\n{body}</article>\n</body>\n</html>\n",
+        escape(&title)
+    )
+}
+
+/// The oEmbed for the published object `id`, a link to it as `hostname` publishes it
+pub fn oembed(bridge: &Bridge, hostname: &str, id: &str, published: &Published) -> Value {
+    let summary = summarize(bridge, &published.document);
+    let mut fields = vec![
+        ("version", Value::from("1.0")),
+        ("type", Value::from("link")),
+        ("title", Value::from(summary.title)),
+        ("author_name", Value::from(summary.author)),
+        ("author_url", Value::from(summary.author_url)),
+        ("provider_name", Value::from(hostname)),
+        ("provider_url", Value::from(format!("https://{hostname}/"))),
+        (
+            "url",
+            Value::from(published.origin.as_deref().unwrap_or(id)),
+        ),
+    ];
+    if let Some(image) = summary.image {
+        fields.push(("thumbnail_url", Value::from(image)));
+    }
+    Value::object(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    #[test]
+    fn pages_show_posts_and_link_both_copies() {
+        let bridge = Bridge::new();
+        let mut alice = Mapping::new(did!("did:plc:alice"), "https://bridge.example/ap/alice");
+        alice.handle = Some("alice.bsky.social".to_string());
+        bridge.identities.insert(alice);
+        let id = "https://bridge.example/ap/alice/posts/3k";
+        let published = Published {
+            document: json::parse(
+                r#"{"id": "https://bridge.example/ap/alice/posts/3k", "type": "Note",
+                    "attributedTo": "https://bridge.example/ap/alice",
+                    "content": "<p>Fish &amp; <b>chips</b></p>",
+                    "attachment": [{"type": "Document", "mediaType": "image/jpeg",
+                                    "url": "https://cdn.example/fish.jpg"}]}"#,
+            )
+            .unwrap(),
+            origin: Some("https://bsky.app/profile/did:plc:alice/post/3k".to_string()),
+            deleted: false,
+        };
+        let html = page(&bridge, "bridge.example", id, &published);
+        assert!(html.contains(r#"<meta property="og:title" content="Post by @alice.bsky.social">"#));
+        assert!(html.contains(r#"<meta property="og:description" content="Fish &amp; chips">"#));
+        assert!(
+            html.contains(r#"<meta property="og:image" content="https://cdn.example/fish.jpg">"#)
+        );
+        assert!(html.contains(
+            r#"<a href="https://bsky.app/profile/did:plc:alice/post/3k">View the original on Bluesky</a>"#
+        ));
+        assert!(html.contains(&escape(&oembed_url("bridge.example", id))));
+
+        let oembed = oembed(&bridge, "bridge.example", id, &published);
+        assert_eq!(
+            oembed.get("author_url"),
+            Some(&Value::from("https://bsky.app/profile/did:plc:alice"))
+        );
+        let deleted = Published {
+            deleted: true,
+            ..published
+        };
+        let html = page(&bridge, "bridge.example", id, &deleted);
+        assert!(html.contains("This post has been deleted.") && !html.contains("chips"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Pinned posts
+//!
+//! The fediverse pins posts by adding them to the actor's `featured` collection, announced
+//! with `Add` and `Remove` activities targeting it. Bluesky pins one post, as the profile
+//! record's `pinnedPost`. So:
+//!
+//! - A bridged fediverse actor's first pinned post which has been bridged becomes their
+//!   profile's `pinnedPost` ([`featured_synced`], [`set_pinned`]), and `Add`s and `Remove`s
+//!   to their collection are read with [`received`]
+//! - A bridged Bluesky account's pinned post is its actor's `featured` collection
+//!   ([`featured_collection`]), and a change to it is sent on as an `Add` or `Remove`
+//!   ([`profile_changed`], [`to_activity`])
+//!
+//! The caller maps between posts' ActivityPub IDs and `at://` URIs, in whichever direction
+
+use crate::bridge::Bridge;
+use crate::json::{id_of, Value};
+use crate::repo::{record_cid, RepoError, Write};
+use atproto::at_uri::{AtUri, Authority};
+use atproto::DID::Did;
+
+pub const PROFILE_COLLECTION: &str = "app.bsky.actor.profile";
+pub const POST_COLLECTION: &str = "app.bsky.feed.post";
+/// The record key of an account's profile record
+const PROFILE_RKEY: &str = "self";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A post pinned or unpinned, by its ActivityPub ID or `at://` URI depending on which way
+/// it's going
+pub enum PinChange {
+    Pinned(String),
+    Unpinned(String),
+}
+
+/// The IDs of the items in a `featured` collection, in order, from its first page if it has
+/// them there
+pub fn featured_items(collection: &Value) -> Vec<String> {
+    let page = collection
+        .get("first")
+        .filter(|page| matches!(page, Value::Object(_)));
+    let items = [collection, page.unwrap_or(collection)]
+        .into_iter()
+        .find_map(|c| c.get("orderedItems").or_else(|| c.get("items")));
+    let items = items.and_then(Value::as_array).unwrap_or_default();
+    items
+        .iter()
+        .filter_map(|item| id_of(Some(item)))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The change `activity` makes to the `featured` collection of `actor` (its actor
+/// document), if it's an `Add` or `Remove` targeting it
+pub fn received(activity: &Value, actor: &Value) -> Option<PinChange> {
+    let field = |name| id_of(activity.get(name));
+    let featured = id_of(actor.get("featured"))?;
+    if field("actor") != id_of(actor.get("id")) || field("target") != Some(featured) {
+        return None;
+    }
+    let object = field("object")?.to_string();
+    match activity.get("type").and_then(Value::as_str)? {
+        "Add" => Some(PinChange::Pinned(object)),
+        "Remove" => Some(PinChange::Unpinned(object)),
+        _ => None,
+    }
+}
+
+/// Set (or with `None`, clear) the `pinnedPost` of `did`'s profile to its post at `uri`
+///
+/// Posts which aren't in `did`'s repo can't be pinned. Returns whether the profile changed
+pub fn set_pinned(bridge: &Bridge, did: &Did, uri: Option<&str>) -> Result<bool, RepoError> {
+    let changed = bridge.update_repo(did, || {
+        let post = match uri {
+            Some(uri) => {
+                let Some(post) = own_post(bridge, did, uri) else {
+                    return Ok(Vec::new());
+                };
+                Some(post)
+            }
+            None => None,
+        };
+        let existing = bridge
+            .repos
+            .records(did, PROFILE_COLLECTION)
+            .into_iter()
+            .find(|(rkey, _)| rkey == PROFILE_RKEY)
+            .map(|(_, profile)| profile);
+        let mut profile = match &existing {
+            Some(Value::Object(fields)) => fields.clone(),
+            Some(_) | None => Default::default(),
+        };
+        let pinned = post.map(|(uri, record)| {
+            Value::object([
+                ("uri", Value::from(uri)),
+                ("cid", Value::from(record_cid(&record).to_string())),
+            ])
+        });
+        if profile.get("pinnedPost") == pinned.as_ref() {
+            return Ok(Vec::new());
+        }
+        match pinned {
+            Some(pinned) => profile.insert("pinnedPost".into(), pinned),
+            None => profile.remove("pinnedPost"),
+        };
+        profile.insert("$type".into(), Value::from(PROFILE_COLLECTION));
+        let path = format!("{PROFILE_COLLECTION}/{PROFILE_RKEY}");
+        let record = Value::Object(profile);
+        Ok(vec![match existing {
+            Some(existing) => Write::Update {
+                path,
+                record,
+                swap: Some(record_cid(&existing)),
+            },
+            None => Write::Create { path, record },
+        }])
+    })?;
+    Ok(changed.is_some())
+}
+
+/// `did`'s post at `uri`, if it's theirs
+fn own_post(bridge: &Bridge, did: &Did, uri: &str) -> Option<(String, Value)> {
+    let parsed = AtUri::try_create(uri.to_string()).ok()?;
+    let owner = match parsed.authority() {
+        Authority::Did(owner) => owner,
+        Authority::Handle(_) => return None,
+    };
+    if owner != did || parsed.collection().map(|c| c.as_str()) != Some(POST_COLLECTION) {
+        return None;
+    }
+    let rkey = parsed.rkey()?;
+    let posts = bridge.repos.records(did, POST_COLLECTION).into_iter();
+    let (_, record) = posts.into_iter().find(|(key, _)| key == rkey)?;
+    Some((uri.to_string(), record))
+}
+
+/// Pin `did`'s first post in its `featured` collection which has been bridged, as
+/// `bridged` maps it to an `at://` URI, or unpin if none has
+///
+/// Returns whether the profile changed
+pub fn featured_synced(
+    bridge: &Bridge,
+    did: &Did,
+    featured: &Value,
+    bridged: impl Fn(&str) -> Option<String>,
+) -> Result<bool, RepoError> {
+    let pinned = featured_items(featured)
+        .iter()
+        .filter_map(|id| bridged(id))
+        .find(|uri| own_post(bridge, did, uri).is_some());
+    set_pinned(bridge, did, pinned.as_deref())
+}
+
+/// The pin changes between two versions of a profile record, as `at://` URIs
+pub fn profile_changed(old: Option<&Value>, new: &Value) -> Vec<PinChange> {
+    let pinned = |profile: Option<&Value>| {
+        let uri = profile?.get("pinnedPost")?.get("uri")?.as_str()?;
+        Some(uri.to_string())
+    };
+    match (pinned(old), pinned(Some(new))) {
+        (old, new) if old == new => Vec::new(),
+        (old, new) => {
+            let unpinned = old.map(PinChange::Unpinned);
+            unpinned
+                .into_iter()
+                .chain(new.map(PinChange::Pinned))
+                .collect()
+        }
+    }
+}
+
+/// The URL of `actor`'s `featured` collection
+pub fn featured_url(actor: &str) -> String {
+    format!("{actor}/featured")
+}
+
+/// `actor`'s `featured` collection, with the ActivityPub ID of its pinned post if it has one
+pub fn featured_collection(actor: &str, pinned: Option<&str>) -> Value {
+    let items: Vec<Value> = pinned.into_iter().map(Value::from).collect();
+    Value::object([
+        (
+            "@context",
+            Value::from("https://www.w3.org/ns/activitystreams"),
+        ),
+        ("id", Value::from(featured_url(actor))),
+        ("type", Value::from("OrderedCollection")),
+        ("totalItems", Value::from(items.len() as i64)),
+        ("orderedItems", Value::Array(items)),
+    ])
+}
+
+/// The activity with ID `id` telling `actor`'s followers about `change`, whose post is
+/// given by its ActivityPub ID
+pub fn to_activity(id: &str, actor: &str, change: &PinChange) -> Value {
+    let (kind, object) = match change {
+        PinChange::Pinned(object) => ("Add", object),
+        PinChange::Unpinned(object) => ("Remove", object),
+    };
+    Value::object([
+        (
+            "@context",
+            Value::from("https://www.w3.org/ns/activitystreams"),
+        ),
+        ("id", Value::from(id)),
+        ("type", Value::from(kind)),
+        ("actor", Value::from(actor)),
+        ("object", Value::from(object.as_str())),
+        ("target", Value::from(featured_url(actor))),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    #[test]
+    fn featured_posts_become_the_pinned_post() {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let owner = KeyOwner::Account(ALICE);
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let post = Value::object([("text", Value::from("hello"))]);
+        let write = Write::Create {
+            path: format!("{POST_COLLECTION}/3k"),
+            record: post,
+        };
+        bridge.commit(&ALICE, &[write]).unwrap();
+
+        let featured = json::parse(
+            r#"{"type": "OrderedCollection", "orderedItems": [
+                {"id": "https://a.example/notes/unbridged"}, "https://a.example/notes/1"]}"#,
+        )
+        .unwrap();
+        let bridged = |id: &str| {
+            (id == "https://a.example/notes/1")
+                .then(|| "at://did:plc:alice/app.bsky.feed.post/3k".to_string())
+        };
+        assert!(featured_synced(&bridge, &ALICE, &featured, bridged).unwrap());
+        assert!(!featured_synced(&bridge, &ALICE, &featured, bridged).unwrap());
+        let profile = &bridge.repos.records(&ALICE, PROFILE_COLLECTION)[0].1;
+        let pinned = profile.get("pinnedPost").unwrap();
+        assert_eq!(
+            pinned.get("uri"),
+            Some(&Value::from("at://did:plc:alice/app.bsky.feed.post/3k"))
+        );
+        assert!(pinned.get("cid").is_some());
+
+        // Someone else's post can't be pinned
+        let other = "at://did:plc:bob/app.bsky.feed.post/3k";
+        assert!(!set_pinned(&bridge, &ALICE, Some(other)).unwrap());
+
+        let empty = json::parse(r#"{"type": "OrderedCollection", "orderedItems": []}"#).unwrap();
+        assert!(featured_synced(&bridge, &ALICE, &empty, bridged).unwrap());
+        let profile = &bridge.repos.records(&ALICE, PROFILE_COLLECTION)[0].1;
+        assert_eq!(profile.get("pinnedPost"), None);
+    }
+
+    #[test]
+    fn pins_travel_as_adds_and_removes() {
+        let actor = "https://bridge.example/users/alice";
+        let pinned = |uri: &str| {
+            json::parse(&format!(
+                r#"{{"pinnedPost": {{"uri": "{uri}", "cid": "bafy"}}}}"#
+            ))
+            .unwrap()
+        };
+        let (first, second) = (pinned("at://a/p/1"), pinned("at://a/p/2"));
+        assert_eq!(
+            profile_changed(None, &first),
+            [PinChange::Pinned("at://a/p/1".to_string())]
+        );
+        assert_eq!(profile_changed(Some(&first), &first), []);
+        assert_eq!(
+            profile_changed(Some(&first), &second),
+            [
+                PinChange::Unpinned("at://a/p/1".to_string()),
+                PinChange::Pinned("at://a/p/2".to_string())
+            ]
+        );
+
+        let change = PinChange::Pinned("https://bridge.example/posts/2".to_string());
+        let add = to_activity("https://bridge.example/pins/1", actor, &change);
+        assert_eq!(add.get("type"), Some(&Value::from("Add")));
+        let document = json::parse(&format!(
+            r#"{{"id": "{actor}", "featured": "{actor}/featured"}}"#
+        ))
+        .unwrap();
+        assert_eq!(received(&add, &document), Some(change));
+        let collection = featured_collection(actor, Some("https://bridge.example/posts/2"));
+        assert_eq!(
+            featured_items(&collection),
+            ["https://bridge.example/posts/2"]
+        );
+
+        let elsewhere = json::parse(&format!(
+            r#"{{"type": "Add", "actor": "{actor}", "object": "x",
+                "target": "https://bridge.example/collections/other"}}"#
+        ))
+        .unwrap();
+        assert_eq!(received(&elsewhere, &document), None);
+    }
+}
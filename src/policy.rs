@@ -0,0 +1,630 @@
+//! Federation policy: per-domain and per-DID rules
+//!
+//! Operators attach actions to subjects, which are a domain (covering its subdomains), a DID,
+//! or `*` for everything:
+//!
+//! - `allow` and `deny` decide whether the bridge talks to the subject at all. The most
+//!   specific rule wins, so `*=deny,friends.example=allow` only federates with
+//!   `friends.example`
+//! - `media-strip` drops attachments from its posts
+//! - `cw` (or `cw:<warning>`) marks its posts sensitive behind a content warning
+//! - `silence` takes its posts out of public timelines, leaving them unlisted
+//! - `unlisted:skip`, `unlisted:quiet` or `unlisted:bridge` decides what becomes of its
+//!   unlisted posts (see [`audience`](crate::audience)). The most specific rule wins here too
+//!
+//! Every matching rule's content actions apply. The policy is consulted on inbound
+//! activities and firehose events, and again when [deliveries](crate::delivery) are sent.
+//! Posts in languages an account hasn't [chosen](crate::store::Preferences::languages) are
+//! refused there too. Rules come from configuration as entries like
+//! `spam.example=silence,did:plc:abc=cw:Spoilers` and can be edited through the admin API,
+//! which persists them in the state directory
+
+use crate::audience::UnlistedPolicy;
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::dm::PUBLIC;
+use crate::json::{self, Value};
+use crate::labels::Presentation;
+use crate::language;
+use crate::normalize;
+use crate::storage::StateDir;
+use crate::store::IdentityStore;
+use crate::trace::Reason;
+use crate::transform::{Context, Stage};
+use crate::url::Url;
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// The policy's file in the state directory
+const POLICY_FILE: &str = "policy.json";
+/// The warning `cw` uses when it isn't given one
+const DEFAULT_WARNING: &str = "Content warning";
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors parsing policy rules
+pub enum PolicyError {
+    #[error("Expected a subject=action entry - found {found}")]
+    Malformed { found: String },
+    #[error("Expected a domain, DID or * - found {found}")]
+    InvalidSubject { found: String },
+    #[error("Unknown policy action - found {found}")]
+    UnknownAction { found: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What a rule applies to
+pub enum Subject {
+    Everything,
+    /// A lowercased domain and its subdomains
+    Domain(String),
+    Did(Did),
+}
+
+impl Subject {
+    /// How specific a match of this subject is, or `None` if it doesn't match
+    fn specificity(&self, domain: Option<&str>, did: Option<&Did>) -> Option<usize> {
+        match self {
+            Subject::Everything => Some(0),
+            Subject::Domain(d) => {
+                let host = domain?;
+                let matches = host == d || host.strip_suffix(d.as_str())?.ends_with('.');
+                matches.then_some(1 + d.len())
+            }
+            Subject::Did(d) => (did? == d).then_some(usize::MAX),
+        }
+    }
+}
+
+impl FromStr for Subject {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Subject, PolicyError> {
+        let invalid = || PolicyError::InvalidSubject {
+            found: s.to_string(),
+        };
+        if s == "*" {
+            return Ok(Subject::Everything);
+        }
+        if s.starts_with("did:") {
+            return normalize::parse_did(s)
+                .map(Subject::Did)
+                .map_err(|_| invalid());
+        }
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '.';
+        if s.is_empty() || s.starts_with('.') || !s.chars().all(valid) {
+            return Err(invalid());
+        }
+        Ok(Subject::Domain(s.to_ascii_lowercase()))
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subject::Everything => f.write_str("*"),
+            Subject::Domain(domain) => f.write_str(domain),
+            Subject::Did(did) => f.write_str(did.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something a rule does to its subject
+pub enum PolicyAction {
+    Allow,
+    Deny,
+    StripMedia,
+    ForceSensitive { warning: String },
+    Silence,
+    Unlisted(UnlistedPolicy),
+}
+
+impl FromStr for PolicyAction {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<PolicyAction, PolicyError> {
+        Ok(match s {
+            "allow" => PolicyAction::Allow,
+            "deny" => PolicyAction::Deny,
+            "media-strip" => PolicyAction::StripMedia,
+            "silence" => PolicyAction::Silence,
+            "cw" => PolicyAction::ForceSensitive {
+                warning: DEFAULT_WARNING.to_string(),
+            },
+            other => match other.strip_prefix("cw:") {
+                Some(warning) => PolicyAction::ForceSensitive {
+                    warning: warning.trim().to_string(),
+                },
+                None => match other
+                    .strip_prefix("unlisted:")
+                    .and_then(UnlistedPolicy::parse)
+                {
+                    Some(policy) => PolicyAction::Unlisted(policy),
+                    None => {
+                        return Err(PolicyError::UnknownAction {
+                            found: other.to_string(),
+                        })
+                    }
+                },
+            },
+        })
+    }
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyAction::Allow => f.write_str("allow"),
+            PolicyAction::Deny => f.write_str("deny"),
+            PolicyAction::StripMedia => f.write_str("media-strip"),
+            PolicyAction::ForceSensitive { warning } => write!(f, "cw:{warning}"),
+            PolicyAction::Silence => f.write_str("silence"),
+            PolicyAction::Unlisted(policy) => write!(f, "unlisted:{}", policy.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Every action applying to one subject
+pub struct Rule {
+    pub subject: Subject,
+    pub actions: Vec<PolicyAction>,
+}
+
+impl Rule {
+    pub fn to_json(&self) -> Value {
+        let actions = self.actions.iter().map(|a| Value::from(a.to_string()));
+        Value::object([
+            ("subject", Value::from(self.subject.to_string())),
+            ("actions", Value::Array(actions.collect())),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Result<Rule, PolicyError> {
+        let malformed = || PolicyError::Malformed {
+            found: value.to_string(),
+        };
+        let subject = value.get("subject").and_then(Value::as_str);
+        let actions = value.get("actions").and_then(Value::as_array);
+        let (Some(subject), Some(actions)) = (subject, actions) else {
+            return Err(malformed());
+        };
+        let actions = actions
+            .iter()
+            .map(|action| action.as_str().ok_or_else(malformed)?.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            subject: subject.parse()?,
+            actions,
+        })
+    }
+
+    /// Add an action, with `allow` and `deny` replacing each other, as do `unlisted` ones
+    pub fn add(&mut self, action: PolicyAction) {
+        let access = |a: &PolicyAction| matches!(a, PolicyAction::Allow | PolicyAction::Deny);
+        let unlisted = |a: &PolicyAction| matches!(a, PolicyAction::Unlisted(_));
+        if access(&action) {
+            self.actions.retain(|a| !access(a));
+        }
+        if unlisted(&action) {
+            self.actions.retain(|a| !unlisted(a));
+        }
+        if !self.actions.contains(&action) {
+            self.actions.push(action);
+        }
+    }
+}
+
+/// Parse `subject=action,...` entries into rules, one per subject
+pub fn parse_rules(entries: &str) -> Result<Vec<Rule>, PolicyError> {
+    let mut rules: Vec<Rule> = Vec::new();
+    for entry in entries.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (subject, action) = entry
+            .split_once('=')
+            .ok_or_else(|| PolicyError::Malformed {
+                found: entry.to_string(),
+            })?;
+        let subject: Subject = subject.trim().parse()?;
+        let action = action.trim().parse()?;
+        match rules.iter_mut().find(|rule| rule.subject == subject) {
+            Some(rule) => rule.add(action),
+            None => rules.push(Rule {
+                subject,
+                actions: vec![action],
+            }),
+        }
+    }
+    Ok(rules)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What the policy says about one actor or instance
+pub struct Verdict {
+    pub denied: bool,
+    pub strip_media: bool,
+    pub silenced: bool,
+    /// Content warnings of every rule forcing one
+    pub warning: Option<String>,
+    /// What becomes of unlisted posts, if a rule says
+    pub unlisted: Option<UnlistedPolicy>,
+}
+
+/// Move the public collection from an activity or object's `to` into its `cc`
+fn unlist(fields: &mut BTreeMap<String, Value>) {
+    let Some(Value::Array(to)) = fields.get_mut("to") else {
+        return;
+    };
+    let before = to.len();
+    to.retain(|address| address.as_str() != Some(PUBLIC));
+    if to.len() == before {
+        return;
+    }
+    match fields.get_mut("cc") {
+        Some(Value::Array(cc)) => cc.push(Value::from(PUBLIC)),
+        _ => {
+            fields.insert("cc".into(), Value::Array(vec![Value::from(PUBLIC)]));
+        }
+    }
+}
+
+impl Verdict {
+    /// Whether nothing needs to change about what's bridged
+    pub fn is_unrestricted(&self) -> bool {
+        *self == Verdict::default()
+    }
+
+    /// Apply this to an activity and its object. Returns false if it shouldn't be bridged
+    pub fn apply(&self, activity: &mut Value) -> bool {
+        if self.denied {
+            return false;
+        }
+        for stage in Stage::ALL {
+            self.apply_stage(stage, activity);
+        }
+        true
+    }
+
+    /// Apply the part of this concerning one stage of translation
+    pub fn apply_stage(&self, stage: Stage, activity: &mut Value) {
+        let Value::Object(fields) = activity else {
+            return;
+        };
+        match stage {
+            Stage::Text => {
+                if let (Some(summary), Some(object)) = (&self.warning, fields.get_mut("object")) {
+                    let summary = summary.clone();
+                    Presentation::Sensitive { summary }.apply(object);
+                }
+            }
+            Stage::Media => {
+                if let (true, Some(Value::Object(object))) =
+                    (self.strip_media, fields.get_mut("object"))
+                {
+                    object.remove("attachment");
+                }
+            }
+            Stage::Audience if self.silenced => {
+                unlist(fields);
+                if let Some(Value::Object(object)) = fields.get_mut("object") {
+                    unlist(object);
+                }
+            }
+            Stage::Audience => {}
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// The rules in force, editable at runtime
+pub struct FederationPolicy {
+    rules: Mutex<Vec<Rule>>,
+    dir: Option<StateDir>,
+}
+
+impl FederationPolicy {
+    /// A policy persisted in `dir`, with the rules already there
+    pub fn open(dir: StateDir) -> io::Result<FederationPolicy> {
+        let rules = match dir.read(POLICY_FILE)? {
+            Some(contents) => {
+                let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+                let policy = json::parse(&String::from_utf8_lossy(&contents))
+                    .map_err(|e| invalid(e.to_string()))?;
+                let rules = policy.get("rules").and_then(Value::as_array);
+                rules
+                    .unwrap_or_default()
+                    .iter()
+                    .map(Rule::from_json)
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| invalid(e.to_string()))?
+            }
+            None => Vec::new(),
+        };
+        Ok(FederationPolicy {
+            rules: Mutex::new(rules),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, rules: &[Rule]) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let all = rules.iter().map(Rule::to_json).collect();
+        let policy = Value::object([("rules", Value::Array(all))]);
+        dir.write(POLICY_FILE, policy.to_string().as_bytes())
+    }
+
+    pub fn rules(&self) -> Vec<Rule> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// Set the actions for a subject, replacing any it had
+    pub fn set(&self, rule: Rule) -> io::Result<()> {
+        self.merge(vec![rule])
+    }
+
+    /// Set several subjects' actions at once
+    pub fn merge(&self, new: Vec<Rule>) -> io::Result<()> {
+        let mut rules = self.rules.lock().unwrap();
+        for rule in new {
+            match rules.iter_mut().find(|r| r.subject == rule.subject) {
+                Some(existing) => *existing = rule,
+                None => rules.push(rule),
+            }
+        }
+        self.save(&rules)
+    }
+
+    /// Remove a subject's rule, returning it if there was one
+    pub fn remove(&self, subject: &Subject) -> io::Result<Option<Rule>> {
+        let mut rules = self.rules.lock().unwrap();
+        let Some(index) = rules.iter().position(|r| r.subject == *subject) else {
+            return Ok(None);
+        };
+        let removed = rules.remove(index);
+        self.save(&rules)?;
+        Ok(Some(removed))
+    }
+
+    /// The verdict for whatever is on `domain` or identified by `did`
+    pub fn verdict(&self, domain: Option<&str>, did: Option<&Did>) -> Verdict {
+        let domain = domain.map(str::to_ascii_lowercase);
+        let rules = self.rules.lock().unwrap();
+        let mut matching: Vec<_> = rules
+            .iter()
+            .filter_map(|rule| Some((rule.subject.specificity(domain.as_deref(), did)?, rule)))
+            .collect();
+        matching.sort_by_key(|(specificity, _)| *specificity);
+        let mut verdict = Verdict::default();
+        let mut warnings: Vec<&str> = Vec::new();
+        for action in matching.iter().flat_map(|(_, rule)| &rule.actions) {
+            match action {
+                PolicyAction::Allow => verdict.denied = false,
+                PolicyAction::Deny => verdict.denied = true,
+                PolicyAction::StripMedia => verdict.strip_media = true,
+                PolicyAction::Silence => verdict.silenced = true,
+                PolicyAction::Unlisted(policy) => verdict.unlisted = Some(*policy),
+                PolicyAction::ForceSensitive { warning } => {
+                    if !warnings.contains(&warning.as_str()) {
+                        warnings.push(warning);
+                    }
+                }
+            }
+        }
+        verdict.warning = (!warnings.is_empty()).then(|| warnings.join(", "));
+        verdict
+    }
+
+    /// The verdict for a remote actor, by the host of its id and the DID it's bridged as
+    pub fn for_actor(&self, identities: &IdentityStore, actor: &str) -> Verdict {
+        let host = Url::parse(actor).ok().map(|url| url.host);
+        let did = identities.get_by_actor(actor).map(|m| m.did);
+        self.verdict(host.as_deref(), did.as_ref())
+    }
+}
+
+/// A delivery as the policy allows it to be sent, or the rule refusing it and why
+///
+/// Deliveries to denied instances are refused, and the rules for the account the activity
+/// is from are applied to it, stage by stage amid the bridge's [transformers](crate::transform)
+pub fn outbound(bridge: &Bridge, delivery: &Delivery) -> Result<Delivery, (Reason, String)> {
+    let Ok(inbox) = Url::parse(&delivery.inbox) else {
+        let invalid = format!("{} isn't a valid inbox", delivery.inbox);
+        return Err((Reason::PolicyDenied, invalid));
+    };
+    if bridge.policy.verdict(Some(&inbox.host), None).denied {
+        return Err((Reason::PolicyDenied, format!("{} is denied", inbox.host)));
+    }
+    let Ok(mut activity) = json::parse(&delivery.activity) else {
+        return Ok(delivery.clone());
+    };
+    let Some(actor) = activity.get("actor").and_then(Value::as_str) else {
+        return Ok(delivery.clone());
+    };
+    // A bridged account's actor is on the bridge's own domain, so only its DID's rules count
+    let Some(mapping) = bridge.identities.get_by_actor(actor) else {
+        return Ok(delivery.clone());
+    };
+    let verdict = bridge.policy.verdict(None, Some(&mapping.did));
+    if verdict.denied {
+        return Err((Reason::PolicyDenied, format!("{} is denied", mapping.did)));
+    }
+    let creates = activity.get("type").and_then(Value::as_str) == Some("Create");
+    let object = activity
+        .get("object")
+        .filter(|object| object.get("type").is_some());
+    if creates
+        && !object.is_none_or(|object| language::allowed(object, &mapping.preferences.languages))
+    {
+        let languages = mapping.preferences.languages.join(", ");
+        return Err((Reason::Filtered, format!("Not in {languages}")));
+    }
+    if verdict.is_unrestricted() && bridge.transformers.is_empty() {
+        return Ok(delivery.clone());
+    }
+    let context = Context {
+        mapping: &mapping,
+        inbox: &delivery.inbox,
+    };
+    for stage in Stage::ALL {
+        bridge
+            .transformers
+            .run(stage, &context, &mut activity, |activity| {
+                verdict.apply_stage(stage, activity)
+            });
+    }
+    Ok(Delivery {
+        activity: activity.to_string(),
+        ..delivery.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use crate::transform::{Hook, Transformer};
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+
+    fn policy(entries: &str) -> FederationPolicy {
+        let policy = FederationPolicy::default();
+        policy.merge(parse_rules(entries).unwrap()).unwrap();
+        policy
+    }
+
+    #[test]
+    fn most_specific_access_rule_wins() {
+        let policy = policy("*=deny,friends.example=allow,bad.friends.example=deny");
+        let denied = |domain| policy.verdict(Some(domain), None).denied;
+        assert!(denied("other.example"));
+        assert!(!denied("friends.example"));
+        assert!(!denied("social.friends.example"));
+        assert!(denied("bad.friends.example"));
+        // Suffixes only match on a label boundary
+        assert!(denied("notfriends.example"));
+
+        let policy = self::policy("*=unlisted:skip,friends.example=unlisted:quiet");
+        let unlisted = |domain| policy.verdict(Some(domain), None).unlisted;
+        assert_eq!(unlisted("other.example"), Some(UnlistedPolicy::Skip));
+        assert_eq!(unlisted("friends.example"), Some(UnlistedPolicy::Quiet));
+
+        let policy = self::policy(&format!(
+            "spam.example=silence,spam.example=media-strip,{ALICE}=cw:Spoilers,{ALICE}=cw"
+        ));
+        assert_eq!(
+            policy.verdict(Some("Spam.Example"), Some(&ALICE)),
+            Verdict {
+                denied: false,
+                strip_media: true,
+                silenced: true,
+                warning: Some("Spoilers, Content warning".to_string()),
+                unlisted: None,
+            }
+        );
+        assert_eq!(
+            parse_rules("spam.example=mute"),
+            Err(PolicyError::UnknownAction {
+                found: "mute".to_string()
+            })
+        );
+        assert!(matches!(
+            parse_rules("https://spam.example=deny"),
+            Err(PolicyError::InvalidSubject { .. })
+        ));
+    }
+
+    /// Attaches an image to the object
+    struct Attach(&'static str);
+
+    impl Transformer for Attach {
+        fn name(&self) -> &str {
+            "attach"
+        }
+
+        fn transform(&self, _: &Context, activity: &mut Value) {
+            if let Value::Object(fields) = activity {
+                if let Some(Value::Object(object)) = fields.get_mut("object") {
+                    let attachment = Value::object([("url", Value::from(self.0))]);
+                    object.insert("attachment".into(), Value::Array(vec![attachment]));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn outbound_deliveries_follow_the_policy() {
+        // Only what transformers add after the policy strips media survives
+        let bridge = Bridge::new()
+            .with_transformer(Stage::Media, Hook::Before, Arc::new(Attach("before")))
+            .with_transformer(Stage::Media, Hook::After, Arc::new(Attach("after")));
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        let rules = format!("blocked.example=deny,{ALICE}=silence,{ALICE}=media-strip");
+        bridge.policy.merge(parse_rules(&rules).unwrap()).unwrap();
+        let activity = format!(
+            r#"{{"type": "Create", "actor": "{ALICE_ACTOR}", "to": ["{PUBLIC}"],
+                "object": {{"type": "Note", "to": ["{PUBLIC}"], "cc": ["{ALICE_ACTOR}/followers"],
+                    "attachment": [{{"url": "https://cdn.example/a.jpg"}}]}}}}"#
+        );
+        let blocked = Delivery::new("https://social.blocked.example/inbox", activity.clone());
+        assert_eq!(
+            outbound(&bridge, &blocked).map_err(|(rule, _)| rule),
+            Err(Reason::PolicyDenied)
+        );
+
+        let delivery = Delivery::new("https://b.example/inbox", activity);
+        let sent = outbound(&bridge, &delivery).unwrap();
+        let sent = json::parse(&sent.activity).unwrap();
+        let object = sent.get("object").unwrap();
+        let attachment = object.get("attachment").and_then(Value::as_array).unwrap();
+        assert_eq!(attachment[0].get("url"), Some(&Value::from("after")));
+        assert_eq!(object.get("to"), Some(&Value::Array(Vec::new())));
+        let cc = object.get("cc").and_then(Value::as_array).unwrap();
+        assert!(cc.contains(&Value::from(PUBLIC)));
+    }
+
+    #[test]
+    fn outbound_posts_follow_language_preferences() {
+        let bridge = Bridge::new();
+        let mut mapping = Mapping::new(ALICE, ALICE_ACTOR);
+        mapping.preferences.languages = vec!["en".to_string()];
+        bridge.identities.insert(mapping);
+        let post = |lang: &str| {
+            let activity = format!(
+                r#"{{"type": "Create", "actor": "{ALICE_ACTOR}",
+                    "object": {{"type": "Note", "contentMap": {{"{lang}": "..."}}}}}}"#
+            );
+            Delivery::new("https://b.example/inbox", activity)
+        };
+        assert!(outbound(&bridge, &post("en-US")).is_ok());
+        assert_eq!(
+            outbound(&bridge, &post("de")),
+            Err((Reason::Filtered, "Not in en".to_string()))
+        );
+        let delete = format!(r#"{{"type": "Delete", "actor": "{ALICE_ACTOR}", "object": "x"}}"#);
+        assert!(outbound(&bridge, &Delivery::new("https://b.example/inbox", delete)).is_ok());
+    }
+
+    #[test]
+    fn edits_persist() {
+        let dir = temp_state_dir();
+        let policy = FederationPolicy::open(dir.clone()).unwrap();
+        policy
+            .merge(parse_rules("a.example=deny,b.example=silence").unwrap())
+            .unwrap();
+        let subject = Subject::Domain("a.example".to_string());
+        assert!(policy.remove(&subject).unwrap().is_some());
+        assert_eq!(policy.remove(&subject).unwrap(), None);
+        let reopened = FederationPolicy::open(dir).unwrap();
+        assert_eq!(reopened.rules(), parse_rules("b.example=silence").unwrap());
+    }
+}
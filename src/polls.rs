@@ -0,0 +1,78 @@
+//! Fediverse polls, as Bluesky text posts
+//!
+//! Bluesky has no polls, so a `Question` is bridged as its question and options in words,
+//! linking to the original for voting. The wording is the [`poll`](Message::Poll) template
+//! in the poll's language
+
+use crate::json::Value;
+use crate::language;
+use crate::richtext;
+use crate::templates::{Message, Templates};
+
+/// The names of a poll's options, whether it allows one choice or several
+pub fn options(object: &Value) -> Vec<String> {
+    let choices = object.get("oneOf").or_else(|| object.get("anyOf"));
+    let Some(Value::Array(choices)) = choices else {
+        return Vec::new();
+    };
+    choices
+        .iter()
+        .filter_map(|choice| choice.get("name")?.as_str())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// `object` as the text of a Bluesky post, if it's a poll, cut short if it has to be
+pub fn to_text(object: &Value, templates: &Templates) -> Option<String> {
+    if object.get("type").and_then(Value::as_str) != Some("Question") {
+        return None;
+    }
+    let url = ["url", "id"]
+        .into_iter()
+        .find_map(|field| object.get(field)?.as_str())?;
+    let content = object.get("content").and_then(Value::as_str);
+    let question = richtext::from_html(content.unwrap_or_default()).text;
+    let options: Vec<_> = options(object)
+        .iter()
+        .map(|option| format!("- {option}"))
+        .collect();
+    let languages = language::languages_of(object);
+    let text = templates.render(
+        Message::Poll,
+        &languages,
+        &[
+            ("question", question.trim().to_string()),
+            ("options", options.join("\n")),
+            ("url", url.to_string()),
+        ],
+    );
+    Some(templates.fit(&text, &languages, url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn polls_are_written_out_in_their_language() {
+        let poll = json::parse(
+            r#"{"type": "Question", "id": "https://a.example/polls/1",
+                "content": "<p>Tabs or spaces?</p>", "contentMap": {"de": "<p>Tabs or spaces?</p>"},
+                "oneOf": [{"type": "Note", "name": "Tabs"}, {"type": "Note", "name": "Spaces"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            to_text(&poll, &Templates::new()).unwrap(),
+            "Tabs or spaces?\n\n- Tabs\n- Spaces\n\nVote on the original post: https://a.example/polls/1"
+        );
+        let german = Templates::new().with(Message::Poll, Some("de"), "{question} {options}");
+        assert_eq!(
+            to_text(&poll, &german).unwrap(),
+            "Tabs or spaces? - Tabs\n- Spaces"
+        );
+        let note = json::parse(r#"{"type": "Note", "id": "https://a.example/1"}"#).unwrap();
+        assert_eq!(to_text(&note, &Templates::new()), None);
+    }
+}
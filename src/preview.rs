@@ -0,0 +1,342 @@
+//! Previewing what a post becomes on the other network
+//!
+//! [`preview`] takes a post by its URL and translates it as the bridge would, without
+//! publishing anything: a Bluesky post (an `at://` URI, or its `bsky.app` page) into the
+//! `Note` sent to the fediverse, and a fediverse post into the `app.bsky.feed.post` record
+//! written to its author's repo. People can check how their formatting comes through, and
+//! operators can keep real posts, with what they translate to, as regression tests.
+//!
+//! Bluesky posts are read from the AppView and fediverse posts fetched from their origin.
+//! Either is previewed whether or not its author is bridged, saying which. The admin API has
+//! this as `GET /admin/preview?url=`, and the binary as `fedibridge preview <url>`
+
+use crate::appview::{AppView, PostView};
+use crate::article;
+use crate::attribution;
+use crate::bridge::Bridge;
+use crate::digest::Network;
+use crate::dm::PUBLIC;
+use crate::entities;
+use crate::feed::POST_COLLECTION;
+use crate::json::Value;
+use crate::language;
+use crate::lexicon;
+use crate::markup;
+use crate::mentions::{self, BLUESKY_PROFILE};
+use crate::parents::{self, ParentError};
+use crate::replycontext;
+use crate::richtext::{Facet, RichText};
+use crate::store::MappingStatus;
+use crate::time::format_rfc3339;
+use atproto::at_uri::AtUri;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// The most languages a Bluesky post lists
+const MAX_LANGS: usize = 3;
+
+#[derive(Debug, Error)]
+pub enum PreviewError {
+    #[error("{url} isn't a Bluesky post or a fediverse URL")]
+    Unrecognized { url: String },
+    #[error("There's no post at {url}")]
+    NotFound { url: String },
+    #[error(transparent)]
+    Fetch(#[from] ParentError),
+}
+
+impl PreviewError {
+    /// The status the admin API answers with
+    pub fn status(&self) -> u16 {
+        match self {
+            PreviewError::Unrecognized { .. } => 400,
+            PreviewError::NotFound { .. } => 404,
+            PreviewError::Fetch(_) => 502,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A post, and what the bridge would make of it
+pub struct Preview {
+    /// The network the post is from
+    pub from: Network,
+    /// The post as it was read
+    pub original: Value,
+    /// What would be published on the other network
+    pub translated: Value,
+    /// Whether its author is bridged, as they'd have to be for it to be published at all
+    pub bridged: bool,
+    /// What the other network would refuse in it, or was dropped for it not to
+    pub problems: Vec<String>,
+}
+
+impl Preview {
+    pub fn to_json(&self) -> Value {
+        let problems = self.problems.iter().map(|p| Value::from(p.as_str()));
+        Value::object([
+            ("from", Value::from(self.from.as_str())),
+            ("bridged", Value::from(self.bridged)),
+            ("original", self.original.clone()),
+            ("translated", self.translated.clone()),
+            ("problems", Value::Array(problems.collect())),
+        ])
+    }
+}
+
+/// The `at://` URI of the Bluesky post `url` is, given as one or as its `bsky.app` page
+pub(crate) fn bluesky_post(url: &str) -> Option<String> {
+    let uri = match url.strip_prefix(BLUESKY_PROFILE) {
+        Some(page) => {
+            let [actor, "post", rkey] = page.trim_matches('/').split('/').collect::<Vec<_>>()[..]
+            else {
+                return None;
+            };
+            format!("at://{actor}/{POST_COLLECTION}/{rkey}")
+        }
+        None if url.starts_with("at://") => url.to_string(),
+        None => return None,
+    };
+    let parsed = AtUri::try_create(uri).ok()?;
+    let is_post = parsed.collection().map(|c| c.as_str()) == Some(POST_COLLECTION);
+    (is_post && parsed.rkey().is_some()).then(|| parsed.as_str().to_string())
+}
+
+/// Preview the post at `url`, as it would be bridged at `now`
+pub fn preview(bridge: &Bridge, url: &str, now: SystemTime) -> Result<Preview, PreviewError> {
+    let url = url.trim();
+    if let Some(uri) = bluesky_post(url) {
+        return to_fediverse(bridge, &uri);
+    }
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return to_bluesky(bridge, url, now);
+    }
+    Err(PreviewError::Unrecognized {
+        url: url.to_string(),
+    })
+}
+
+/// The AppView's view of the post at `uri`, whoever wrote it
+fn fetch_post(bridge: &Bridge, uri: &str) -> Result<PostView, PreviewError> {
+    // Deleted posts are a 400 `NotFound`
+    let post = AppView::of(bridge).post(uri).map_err(ParentError::from)?;
+    post.ok_or_else(|| PreviewError::NotFound {
+        url: uri.to_string(),
+    })
+}
+
+fn to_fediverse(bridge: &Bridge, uri: &str) -> Result<Preview, PreviewError> {
+    let post = fetch_post(bridge, uri)?;
+    let record = &post.record;
+    let mapping = bridge.identities.get(&post.author.did);
+    let bridged = mapping
+        .as_ref()
+        .is_some_and(|m| m.status == MappingStatus::Active);
+    let facets = record.get("facets").and_then(Value::as_array);
+    let rich = RichText {
+        text: record
+            .get("text")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        facets: facets
+            .unwrap_or_default()
+            .iter()
+            .filter_map(Facet::from_json)
+            .collect(),
+    };
+    let mut content = markup::to_html(&rich, bridge.html_profile, |did| {
+        let actor = bridge.identities.get(did).map(|mapping| mapping.actor);
+        actor.unwrap_or_else(|| format!("{BLUESKY_PROFILE}/{did}"))
+    });
+    let author_id = post.author.did.as_str();
+    let page = match uri.split('/').next_back() {
+        Some(rkey) => format!("{BLUESKY_PROFILE}/{author_id}/post/{rkey}"),
+        None => uri.to_string(),
+    };
+    let handle = format!("@{}", post.author.handle);
+    if let Some(template) = &bridge.attribution.to_fediverse {
+        content.push_str(&attribution::fediverse_footer(template, &page, &handle));
+    }
+    if let Some(parent) = replycontext::bluesky_parent(bridge, record) {
+        content.insert_str(0, &replycontext::fediverse_header(&parent));
+    }
+    let actor = match &mapping {
+        Some(mapping) => mapping.actor.clone(),
+        None => format!("{BLUESKY_PROFILE}/{author_id}"),
+    };
+    let langs: Vec<String> = record
+        .get("langs")
+        .and_then(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|lang| Some(lang.as_str()?.to_string()))
+        .collect();
+    let mut note = vec![
+        ("type", Value::from("Note")),
+        ("attributedTo", Value::from(actor.as_str())),
+        ("to", Value::Array(vec![Value::from(PUBLIC)])),
+        (
+            "cc",
+            Value::Array(vec![Value::from(format!("{actor}/followers"))]),
+        ),
+        ("content", Value::from(content.as_str())),
+        ("url", Value::from(page)),
+    ];
+    if let Some(content_map) = language::to_content_map(&langs, &content) {
+        note.push(("contentMap", content_map));
+    }
+    if let Some(created) = record.get("createdAt") {
+        note.push(("published", created.clone()));
+    }
+    Ok(Preview {
+        from: Network::Bluesky,
+        original: post.view,
+        translated: Value::object(note),
+        bridged,
+        problems: Vec::new(),
+    })
+}
+
+fn to_bluesky(bridge: &Bridge, url: &str, now: SystemTime) -> Result<Preview, PreviewError> {
+    let fetched = parents::fetch(bridge, url)?;
+    let object = match fetched.get("object") {
+        Some(object @ Value::Object(_)) => object.clone(),
+        _ => Value::clone(&fetched),
+    };
+    let author = match object.get("attributedTo") {
+        Some(Value::Array(actors)) => actors.first().and_then(Value::as_str),
+        actor => actor.and_then(Value::as_str),
+    };
+    let mapping = author.and_then(|actor| bridge.identities.get_by_actor(actor));
+    let bridged = mapping
+        .as_ref()
+        .is_some_and(|m| m.status == MappingStatus::Active);
+    let link = match object.get("url") {
+        Some(Value::String(link)) => link.as_str(),
+        _ => object.get("id").and_then(Value::as_str).unwrap_or(url),
+    };
+    let mut record = vec![("$type", Value::from(POST_COLLECTION))];
+    if let Some(post) = article::to_post(&object, &bridge.articles) {
+        record.push(("text", Value::from(post.text)));
+        record.push(("embed", post.card.to_embed(None)));
+    } else {
+        let content = object.get("content").and_then(Value::as_str);
+        let mut rich = mentions::rich_text(bridge, content.unwrap_or_default());
+        if let Some(template) = &bridge.attribution.to_bluesky {
+            let handle = mapping.as_ref().and_then(|m| m.handle.clone());
+            let author = handle.or(author.map(str::to_string)).unwrap_or_default();
+            rich = attribution::bluesky_footer(&rich, template, link, &author);
+        }
+        if let Some(parent) = replycontext::fediverse_parent(bridge, &object) {
+            rich = replycontext::bluesky_header(&rich, &parent);
+        }
+        record.push(("text", Value::from(rich.text.as_str())));
+        if !rich.facets.is_empty() {
+            record.push(("facets", rich.facets_json()));
+        }
+    }
+    let mut langs = language::from_ap(&object);
+    langs.truncate(MAX_LANGS);
+    if !langs.is_empty() {
+        let langs = langs.into_iter().map(Value::from);
+        record.push(("langs", Value::Array(langs.collect())));
+    }
+    let created = object.get("published").and_then(Value::as_str);
+    let created = created.map_or_else(|| format_rfc3339(now), str::to_string);
+    record.push(("createdAt", Value::from(created)));
+    let fitted = entities::fit(&Value::object(record));
+    let record = fitted.record;
+    let mut problems = fitted.dropped;
+    if let Err(invalid) = lexicon::validate_with(POST_COLLECTION, &record, &bridge.parsing) {
+        problems.push(invalid.to_string());
+    }
+    Ok(Preview {
+        from: Network::Fediverse,
+        original: object,
+        translated: record,
+        bridged,
+        problems,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appview::GET_POST_THREAD;
+    use crate::http::percent_encode;
+    use crate::json;
+    use crate::store::Mapping;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn previews_posts_either_way_without_publishing() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new().with_transport(mock.clone());
+        let alice = did!("did:plc:alice");
+        bridge.identities.insert(Mapping::new(
+            alice.clone(),
+            "https://bridge.example/users/alice",
+        ));
+        let uri = "at://did:plc:alice/app.bsky.feed.post/3k";
+        mock.respond_json(
+            &format!(
+                "https://public.api.bsky.app/xrpc/{GET_POST_THREAD}?uri={}&depth=0&parentHeight=0",
+                percent_encode(uri)
+            ),
+            r#"{"thread": {"$type": "app.bsky.feed.defs#threadViewPost",
+                "post": {"uri": "at://did:plc:alice/app.bsky.feed.post/3k", "cid": "bafy",
+                "author": {"did": "did:plc:alice", "handle": "alice.example"},
+                "record": {"text": "hi <there>", "langs": ["en"],
+                    "createdAt": "2026-01-01T00:00:00Z"}}}}"#,
+        );
+        let now = SystemTime::now();
+        let page = "https://bsky.app/profile/did:plc:alice/post/3k";
+        let bluesky = preview(&bridge, page, now).unwrap();
+        assert_eq!(bluesky.from, Network::Bluesky);
+        assert!(bluesky.bridged);
+        let note = &bluesky.translated;
+        assert_eq!(
+            note.get("content").and_then(Value::as_str),
+            Some("<p>hi &lt;there&gt;</p>")
+        );
+        assert_eq!(
+            note.get("published").and_then(Value::as_str),
+            Some("2026-01-01T00:00:00Z")
+        );
+        assert!(note
+            .get("contentMap")
+            .and_then(|map| map.get("en"))
+            .is_some());
+
+        let note = "https://a.example/notes/1";
+        mock.respond_json(
+            note,
+            r#"{"id": "https://a.example/notes/1", "type": "Note",
+                "attributedTo": "https://a.example/users/bob", "content": "<p>hello</p>",
+                "contentMap": {"de": "<p>hello</p>"}, "published": "2026-01-02T00:00:00Z"}"#,
+        );
+        let fediverse = preview(&bridge, note, now).unwrap();
+        assert_eq!(fediverse.from, Network::Fediverse);
+        assert!(!fediverse.bridged);
+        let record = &fediverse.translated;
+        assert_eq!(record.get("text").and_then(Value::as_str), Some("hello"));
+        assert_eq!(
+            record.get("langs"),
+            Some(&json::parse(r#"["de"]"#).unwrap())
+        );
+        assert!(fediverse.problems.is_empty(), "{:?}", fediverse.problems);
+        // Reading is all it does
+        assert!(mock
+            .requests()
+            .iter()
+            .all(|r| r.method == crate::http::Method::Get));
+
+        assert!(matches!(
+            preview(&bridge, "mailto:bob@a.example", now),
+            Err(PreviewError::Unrecognized { .. })
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Profile fields and verified links
+//!
+//! Mastodon profiles carry a few name and value fields, published as `PropertyValue`s in the
+//! actor's `attachment`. A link in one is verified when the page it links to links back to
+//! the profile with `rel="me"`, so the exact URL matters. Bluesky profiles only have a bio and
+//! a `website`. So:
+//!
+//! - A fediverse actor's fields are added to its bridged bio as `name: value` lines, with
+//!   links written out in full so the verification URL survives, and the first link becomes
+//!   the profile's `website`
+//! - A Bluesky profile's `website` becomes a field on its actor, linked with `rel="me"`, so
+//!   whoever owns the site can link back and have it verified on the fediverse
+
+use crate::article::trim;
+use crate::html;
+use crate::json::Value;
+use crate::richtext::{self, shorten_url};
+
+/// Bluesky's limit on a profile's bio, in graphemes
+pub const MAX_BIO_LENGTH: usize = 256;
+/// The name a Bluesky profile's `website` is given as a field
+pub const WEBSITE_FIELD: &str = "Website";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A profile field
+pub struct ProfileField {
+    pub name: String,
+    /// As plain text
+    pub value: String,
+    /// The URL the value links to, if it's a link
+    pub link: Option<String>,
+}
+
+impl ProfileField {
+    /// This field as a line of a Bluesky bio
+    fn to_line(&self) -> String {
+        format!(
+            "{}: {}",
+            self.name,
+            self.link.as_ref().unwrap_or(&self.value)
+        )
+    }
+}
+
+/// The first `http(s)` link in `value`
+fn first_link(value: &str) -> Option<String> {
+    html::tokenize(value)
+        .iter()
+        .filter(|token| token.is_start("a"))
+        .filter_map(|token| token.attribute("href"))
+        .find(|href| href.starts_with("https://") || href.starts_with("http://"))
+        .map(str::to_string)
+}
+
+/// The fields `actor` publishes, in order
+pub fn from_actor(actor: &Value) -> Vec<ProfileField> {
+    let attachments = match actor.get("attachment") {
+        Some(Value::Array(attachments)) => attachments.iter().collect(),
+        Some(attachment) => vec![attachment],
+        None => Vec::new(),
+    };
+    attachments
+        .into_iter()
+        .filter(|a| a.get("type").and_then(Value::as_str) == Some("PropertyValue"))
+        .filter_map(|attachment| {
+            let name = attachment.get("name")?.as_str()?.trim();
+            let value = attachment.get("value")?.as_str()?;
+            let text = richtext::from_html(value).text;
+            (!name.is_empty() && !text.is_empty()).then(|| ProfileField {
+                name: html::decode_entities(name),
+                value: text,
+                link: first_link(value),
+            })
+        })
+        .collect()
+}
+
+/// A Bluesky bio from a fediverse actor's `summary`, as plain text, and its `fields`
+///
+/// Fields are added after the summary for as long as they fit whole. A summary which doesn't
+/// fit on its own is trimmed and gets no fields
+pub fn to_bio(summary: &str, fields: &[ProfileField]) -> String {
+    let summary = summary.trim();
+    if summary.chars().count() >= MAX_BIO_LENGTH {
+        return trim(summary, MAX_BIO_LENGTH);
+    }
+    let mut bio = summary.to_string();
+    for (i, field) in fields.iter().enumerate() {
+        let separator = match (bio.is_empty(), i) {
+            (true, _) => "",
+            (false, 0) => "\n\n",
+            (false, _) => "\n",
+        };
+        let line = format!("{separator}{}", field.to_line());
+        if bio.chars().count() + line.chars().count() > MAX_BIO_LENGTH {
+            break;
+        }
+        bio.push_str(&line);
+    }
+    bio
+}
+
+/// The `website` of a profile bridged with `fields`: the first one that links somewhere
+pub fn website(fields: &[ProfileField]) -> Option<&str> {
+    fields.iter().find_map(|field| field.link.as_deref())
+}
+
+/// The `attachment` for the actor of a Bluesky account with `profile` as its profile record
+pub fn to_attachment(profile: &Value) -> Vec<Value> {
+    let website = profile.get("website").and_then(Value::as_str);
+    let website = website.filter(|url| url.starts_with("https://") || url.starts_with("http://"));
+    website
+        .map(|url| {
+            let link = format!(
+                "<a href=\"{}\" rel=\"me nofollow noopener\" target=\"_blank\">{}</a>",
+                html::escape(url),
+                html::escape(&shorten_url(url))
+            );
+            Value::object([
+                ("type", Value::from("PropertyValue")),
+                ("name", Value::from(WEBSITE_FIELD)),
+                ("value", Value::from(link)),
+            ])
+        })
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn fields_join_the_bio_with_links_in_full() {
+        let actor = json::parse(
+            r#"{"type": "Person", "attachment": [
+                {"type": "PropertyValue", "name": "Blog",
+                 "value": "<a href=\"https://blog.example/about/me\" rel=\"me nofollow noopener\" target=\"_blank\"><span class=\"invisible\">https://</span><span>blog.example/about/me</span></a>"},
+                {"type": "PropertyValue", "name": "Pronouns", "value": "they/them"},
+                {"type": "IdentityProof", "name": "keybase"}
+            ]}"#,
+        )
+        .unwrap();
+        let fields = from_actor(&actor);
+        assert_eq!(fields.len(), 2);
+        assert_eq!(website(&fields), Some("https://blog.example/about/me"));
+        assert_eq!(
+            to_bio("Writes about trains", &fields),
+            "Writes about trains\n\nBlog: https://blog.example/about/me\nPronouns: they/them"
+        );
+
+        // Fields are left out rather than cut short
+        let long = "a".repeat(MAX_BIO_LENGTH - 20);
+        assert_eq!(to_bio(&long, &fields), long);
+        let longer = "a ".repeat(MAX_BIO_LENGTH);
+        assert!(to_bio(&longer, &fields).chars().count() <= MAX_BIO_LENGTH);
+    }
+
+    #[test]
+    fn websites_become_rel_me_fields() {
+        let profile = json::parse(r#"{"website": "https://alice.example/"}"#).unwrap();
+        let attachment = to_attachment(&profile);
+        let value = attachment[0].get("value").and_then(Value::as_str).unwrap();
+        assert!(value.contains("href=\"https://alice.example/\""));
+        assert!(value.contains("rel=\"me"));
+        let actor = Value::object([("attachment", Value::Array(attachment))]);
+        assert_eq!(website(&from_actor(&actor)), Some("https://alice.example/"));
+
+        let unsafe_link = json::parse(r#"{"website": "javascript:alert(1)"}"#).unwrap();
+        assert_eq!(to_attachment(&unsafe_link), []);
+    }
+}
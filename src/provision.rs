@@ -0,0 +1,516 @@
+//! Making the identities bridged fediverse accounts are known by on atproto
+//!
+//! A fediverse account being bridged needs a DID and a handle before anything is written as
+//! it. How they're anchored is up to the operator, with `FEDIBRIDGE_IDENTITY_BACKEND`
+//! choosing an [`IdentityBackend`]:
+//!
+//! - `web`, the default: a `did:web` under the bridge's hostname, at `u/{name}`, whose
+//!   document the bridge [serves](crate::identity) itself ([`WebIdentities`])
+//! - `plc`: a `did:plc` registered with the PLC directory by a genesis operation signed with
+//!   the bridge's [rotation key](KeyPurpose::PlcRotation), so the bridge can update it and
+//!   the account can later move away from the bridge ([`PlcIdentities`])
+//! - `pds`: an account created on the PDS at `FEDIBRIDGE_IDENTITY_PDS` with
+//!   `com.atproto.server.createAccount`, with `FEDIBRIDGE_IDENTITY_PDS_INVITE_CODE` if it
+//!   needs one. The PDS makes the DID and hosts the repo ([`PdsIdentities`])
+//!
+//! The bridge hosts the repos of the first two, under a repo signing key [`provision`]
+//! generates, and names itself their PDS. Handles are `{name}.{hostname}` for those, and
+//! `{name}.{host}` under the PDS's host for the last
+
+use crate::bridge::Bridge;
+use crate::car;
+use crate::crypto::{base64_encode, hex_encode, random_bytes, sha256};
+use crate::json::{self, Value};
+use crate::keys::{KeyAlgorithm, KeyError, KeyGenerator, KeyOwner, KeyPair, KeyPurpose};
+use crate::resolver::DEFAULT_PLC_DIRECTORY;
+use crate::store::Mapping;
+use crate::transport::{OutboundRequest, TransportError};
+use crate::upstream::UpstreamKind;
+use crate::url::Url;
+use atproto::DID::Did;
+use std::io;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub const CREATE_ACCOUNT: &str = "com.atproto.server.createAccount";
+/// How many characters of the hash of a genesis operation a `did:plc` has
+const PLC_ID_LENGTH: usize = 24;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Where bridged accounts' identities are anchored
+pub enum IdentityKind {
+    #[default]
+    Web,
+    Plc,
+    Pds,
+}
+
+impl IdentityKind {
+    pub const ALL: [IdentityKind; 3] = [IdentityKind::Web, IdentityKind::Plc, IdentityKind::Pds];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdentityKind::Web => "web",
+            IdentityKind::Plc => "plc",
+            IdentityKind::Pds => "pds",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<IdentityKind> {
+        IdentityKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which backend identities are made with
+pub struct IdentityConfig {
+    pub backend: IdentityKind,
+    /// The PDS accounts are created on, for `pds`
+    pub pds: Option<String>,
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ProvisionError {
+    #[error("{0} isn't a name a handle can start with")]
+    Name(String),
+    #[error("{0} is bridged already")]
+    Taken(String),
+    #[error("The bridge has no hostname to make identities under")]
+    NoHostname,
+    #[error("No identity backend is configured")]
+    NoBackend,
+    #[error("No repo signer is configured")]
+    NoSigner,
+    #[error("Couldn't sign the genesis operation: {0:#}")]
+    Signing(anyhow::Error),
+    #[error(transparent)]
+    Keys(#[from] KeyError),
+    #[error(transparent)]
+    Generating(anyhow::Error),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("{service} refused with status {status}: {message}")]
+    Rejected {
+        service: String,
+        status: u16,
+        message: String,
+    },
+    #[error("{0} answered with something other than an account")]
+    Malformed(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An account made on another PDS, which the bridge writes to as its owner
+pub struct HostedAccount {
+    pub pds: String,
+    pub password: String,
+    pub refresh_jwt: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An identity made for an account
+pub struct Provisioned {
+    pub did: Did,
+    pub handle: String,
+    /// The account on the PDS hosting its repo, if the bridge doesn't
+    pub hosted: Option<HostedAccount>,
+}
+
+/// Makes identities for bridged accounts
+pub trait IdentityBackend: Send + Sync {
+    fn kind(&self) -> IdentityKind;
+
+    /// Make an identity for the account to be handled as `name`, which is a valid DNS label.
+    /// Identities whose repos the bridge hosts are signed for with `key`, and any keys of the
+    /// backend's own are made by `generator`
+    fn provision(
+        &self,
+        bridge: &Bridge,
+        name: &str,
+        key: &KeyPair,
+        generator: &dyn KeyGenerator,
+    ) -> Result<Provisioned, ProvisionError>;
+}
+
+/// A generator handing out a key generated beforehand, to keep under a DID made with it
+struct Generated(KeyPair);
+
+impl KeyGenerator for Generated {
+    fn generate(&self, _: KeyAlgorithm) -> anyhow::Result<KeyPair> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Whether `name` can be the first label of a handle
+fn valid_name(name: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+    (1..=63).contains(&name.len())
+        && name.chars().all(allowed)
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// Why `service` refused a request, as its XRPC error says
+fn rejected(service: &str, status: u16, body: &[u8]) -> ProvisionError {
+    let error = json::parse(&String::from_utf8_lossy(body)).ok();
+    let message = error
+        .as_ref()
+        .and_then(|e| e.get("message").or_else(|| e.get("error"))?.as_str())
+        .unwrap_or_default();
+    ProvisionError::Rejected {
+        service: service.to_string(),
+        status,
+        message: message.to_string(),
+    }
+}
+
+/// `did:web` identities under the bridge's hostname
+pub struct WebIdentities {
+    pub hostname: String,
+}
+
+impl IdentityBackend for WebIdentities {
+    fn kind(&self) -> IdentityKind {
+        IdentityKind::Web
+    }
+
+    fn provision(
+        &self,
+        _: &Bridge,
+        name: &str,
+        _: &KeyPair,
+        _: &dyn KeyGenerator,
+    ) -> Result<Provisioned, ProvisionError> {
+        let did = Did::did_web(&self.hostname, &["u", name])
+            .map_err(|_| ProvisionError::Name(name.to_string()))?;
+        Ok(Provisioned {
+            did,
+            handle: format!("{name}.{}", self.hostname),
+            hosted: None,
+        })
+    }
+}
+
+/// `did:plc` identities registered with the bridge's PLC directory
+pub struct PlcIdentities {
+    pub hostname: String,
+}
+
+impl PlcIdentities {
+    /// The signed genesis operation of an identity handled as `handle`, signed for with `key`
+    fn genesis(
+        &self,
+        bridge: &Bridge,
+        handle: &str,
+        key: &KeyPair,
+        generator: &dyn KeyGenerator,
+    ) -> Result<Value, ProvisionError> {
+        let signer = bridge
+            .repo_signer
+            .as_ref()
+            .ok_or(ProvisionError::NoSigner)?;
+        // Made with the first identity, and shared by every one after it
+        let rotation = bridge
+            .keys
+            .ensure(&KeyOwner::Bridge, KeyPurpose::PlcRotation, generator)?;
+        let did_key = |key: &KeyPair| Value::from(format!("did:key:{}", key.public_key));
+        let mut operation = Value::object([
+            ("type", Value::from("plc_operation")),
+            (
+                "rotationKeys",
+                Value::Array(vec![did_key(&rotation.keypair)]),
+            ),
+            (
+                "verificationMethods",
+                Value::object([("atproto", did_key(key))]),
+            ),
+            (
+                "alsoKnownAs",
+                Value::Array(vec![Value::from(format!("at://{handle}"))]),
+            ),
+            (
+                "services",
+                Value::object([(
+                    "atproto_pds",
+                    Value::object([
+                        ("type", Value::from("AtprotoPersonalDataServer")),
+                        (
+                            "endpoint",
+                            Value::from(format!("https://{}", self.hostname)),
+                        ),
+                    ]),
+                )]),
+            ),
+            ("prev", Value::Null),
+        ]);
+        let sig = signer
+            .sign(&rotation.keypair, &car::encode_dag_cbor(&operation))
+            .map_err(ProvisionError::Signing)?;
+        let sig = base64_encode(&sig)
+            .replace('+', "-")
+            .replace('/', "_")
+            .trim_end_matches('=')
+            .to_string();
+        if let Value::Object(fields) = &mut operation {
+            fields.insert("sig".to_string(), Value::from(sig));
+        }
+        Ok(operation)
+    }
+}
+
+/// The `did:plc` a signed genesis operation makes
+pub fn plc_did(operation: &Value) -> Did {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let digest = sha256(&car::encode_dag_cbor(operation));
+    let mut id = String::from("did:plc:");
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in &digest {
+        buffer = buffer << 8 | byte as u32;
+        bits += 8;
+        while bits >= 5 && id.len() < "did:plc:".len() + PLC_ID_LENGTH {
+            bits -= 5;
+            id.push(ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    Did::try_create(id).expect("a did:plc is made of base32")
+}
+
+impl IdentityBackend for PlcIdentities {
+    fn kind(&self) -> IdentityKind {
+        IdentityKind::Plc
+    }
+
+    fn provision(
+        &self,
+        bridge: &Bridge,
+        name: &str,
+        key: &KeyPair,
+        generator: &dyn KeyGenerator,
+    ) -> Result<Provisioned, ProvisionError> {
+        let handle = format!("{name}.{}", self.hostname);
+        let operation = self.genesis(bridge, &handle, key, generator)?;
+        let did = plc_did(&operation);
+        let directory = bridge.upstreams.active(UpstreamKind::PlcDirectory);
+        let directory = directory.unwrap_or(DEFAULT_PLC_DIRECTORY);
+        let url = format!("{}/{did}", directory.trim_end_matches('/'));
+        let request = OutboundRequest::post(url, operation.to_string())
+            .with_header("content-type", "application/json");
+        let response = bridge.transport.send(&request)?;
+        if !response.is_success() {
+            return Err(rejected(directory, response.status, &response.body));
+        }
+        Ok(Provisioned {
+            did,
+            handle,
+            hosted: None,
+        })
+    }
+}
+
+/// Accounts created on another PDS
+pub struct PdsIdentities {
+    /// The PDS's base URL
+    pub pds: String,
+    pub invite_code: Option<String>,
+}
+
+impl IdentityBackend for PdsIdentities {
+    fn kind(&self) -> IdentityKind {
+        IdentityKind::Pds
+    }
+
+    fn provision(
+        &self,
+        bridge: &Bridge,
+        name: &str,
+        _: &KeyPair,
+        _: &dyn KeyGenerator,
+    ) -> Result<Provisioned, ProvisionError> {
+        let pds = self.pds.trim_end_matches('/');
+        let host = Url::parse(pds).map(|url| url.host).unwrap_or_default();
+        let mut password = [0; 24];
+        random_bytes(&mut password)?;
+        let password = hex_encode(&password);
+        let mut body = vec![
+            ("handle", Value::from(format!("{name}.{host}"))),
+            ("password", Value::from(password.as_str())),
+        ];
+        if let Some(code) = &self.invite_code {
+            body.push(("inviteCode", Value::from(code.as_str())));
+        }
+        let url = format!("{pds}/xrpc/{CREATE_ACCOUNT}");
+        let request = OutboundRequest::post(url, Value::object(body).to_string())
+            .with_header("content-type", "application/json");
+        let response = bridge.transport.send(&request)?;
+        if !response.is_success() {
+            return Err(rejected(pds, response.status, &response.body));
+        }
+        let created = json::parse(&String::from_utf8_lossy(&response.body)).ok();
+        let field = |name| Some(created.as_ref()?.get(name)?.as_str()?.to_string());
+        let malformed = || ProvisionError::Malformed(pds.to_string());
+        let did = field("did").and_then(|did| Did::try_create(did).ok());
+        Ok(Provisioned {
+            did: did.ok_or_else(malformed)?,
+            handle: field("handle").ok_or_else(malformed)?,
+            hosted: Some(HostedAccount {
+                pds: pds.to_string(),
+                password,
+                refresh_jwt: field("refreshJwt").ok_or_else(malformed)?,
+            }),
+        })
+    }
+}
+
+/// The backend `config` chooses, for a bridge at `hostname`
+pub fn backend(
+    config: &IdentityConfig,
+    hostname: Option<&str>,
+) -> Result<Arc<dyn IdentityBackend>, ProvisionError> {
+    let hostname = || {
+        hostname
+            .map(str::to_string)
+            .ok_or(ProvisionError::NoHostname)
+    };
+    Ok(match (config.backend, &config.pds) {
+        (IdentityKind::Web, _) => Arc::new(WebIdentities {
+            hostname: hostname()?,
+        }),
+        (IdentityKind::Plc, _) => Arc::new(PlcIdentities {
+            hostname: hostname()?,
+        }),
+        (IdentityKind::Pds, pds) => Arc::new(PdsIdentities {
+            pds: pds.clone().unwrap_or_default(),
+            invite_code: config.invite_code.clone(),
+        }),
+    })
+}
+
+/// Make an identity for the fediverse `actor` with the bridge's backend, to be handled as
+/// `name`, and the mapping bridging it as that identity, for it to [opt in](Bridge::opt_in)
+/// with. Its repo signing key, if the bridge is to host its repo, and any keys the backend
+/// needs are made by `generator`
+pub fn provision(
+    bridge: &Bridge,
+    actor: &str,
+    name: &str,
+    generator: &dyn KeyGenerator,
+) -> Result<(Mapping, Provisioned), ProvisionError> {
+    let name = name.to_ascii_lowercase();
+    if !valid_name(&name) {
+        return Err(ProvisionError::Name(name));
+    }
+    if bridge.identities.get_by_actor(actor).is_some() {
+        return Err(ProvisionError::Taken(actor.to_string()));
+    }
+    let backend = bridge.identity_backend.as_ref();
+    let backend = backend.ok_or(ProvisionError::NoBackend)?;
+    let key = generator
+        .generate(KeyPurpose::RepoSigning.default_algorithm())
+        .map_err(ProvisionError::Generating)?;
+    let provisioned = backend.provision(bridge, &name, &key, generator)?;
+    if provisioned.hosted.is_none() {
+        let owner = KeyOwner::Account(provisioned.did.clone());
+        let generated = Generated(key);
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generated)?;
+    }
+    let mapping = Mapping {
+        handle: Some(provisioned.handle.clone()),
+        ..Mapping::new(provisioned.did.clone(), actor)
+    };
+    Ok((mapping, provisioned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::keys::tests::FakeGenerator;
+    use crate::repo::tests::HashSigner;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    const ACTOR: &str = "https://mastodon.example/users/alice";
+
+    fn key() -> KeyPair {
+        KeyPair {
+            algorithm: KeyAlgorithm::Secp256k1,
+            private_key: vec![1; 32],
+            public_key: "zQ3shalice".to_string(),
+        }
+    }
+
+    #[test]
+    fn each_backend_anchors_an_identity() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = |kind: IdentityKind| {
+            let config = IdentityConfig {
+                backend: kind,
+                pds: Some("https://pds.example".to_string()),
+                invite_code: Some("invite".to_string()),
+            };
+            Bridge::new()
+                .with_transport(mock.clone())
+                .with_repo_signer(Arc::new(HashSigner))
+                .with_identity_backend(backend(&config, Some("bridge.example")).unwrap())
+        };
+        let generator = Generated(key());
+
+        let web = bridge(IdentityKind::Web);
+        let (mapping, _) = provision(&web, ACTOR, "Alice", &generator).unwrap();
+        assert_eq!(mapping.did.as_str(), "did:web:bridge.example:u:alice");
+        assert_eq!(mapping.handle.as_deref(), Some("alice.bridge.example"));
+        let owner = KeyOwner::Account(mapping.did.clone());
+        let stored = web.keys.current(&owner, KeyPurpose::RepoSigning).unwrap();
+        assert_eq!(stored.keypair, key());
+        assert!(matches!(
+            provision(&web, ACTOR, "-alice", &generator),
+            Err(ProvisionError::Name(_))
+        ));
+
+        let plc = bridge(IdentityKind::Plc);
+        let identities = PlcIdentities {
+            hostname: "bridge.example".to_string(),
+        };
+        let handle = "alice.bridge.example";
+        // The rotation key made first, the genesis operation can be told beforehand
+        let rotation = FakeGenerator::default();
+        let genesis = identities.genesis(&plc, handle, &key(), &rotation);
+        let did = plc_did(&genesis.unwrap());
+        assert_eq!(did.as_str().len(), "did:plc:".len() + PLC_ID_LENGTH);
+        let url = format!("https://plc.directory/{did}");
+        mock.respond(Method::Post, &url, OutboundResponse::new(200));
+        let (mapping, _) = provision(&plc, ACTOR, "alice", &generator).unwrap();
+        assert_eq!(mapping.did, did);
+        let sent = mock.requests_to(&url);
+        let operation = json::parse(&String::from_utf8_lossy(&sent[0].body)).unwrap();
+        let methods = operation.get("verificationMethods");
+        let atproto = methods.and_then(|m| m.get("atproto")?.as_str());
+        assert_eq!(atproto, Some("did:key:zQ3shalice"));
+        assert!(operation.get("sig").is_some());
+
+        mock.respond(
+            Method::Post,
+            &format!("https://pds.example/xrpc/{CREATE_ACCOUNT}"),
+            OutboundResponse::new(200).with_body(
+                r#"{"did": "did:plc:hosted", "handle": "alice.pds.example",
+                    "accessJwt": "a", "refreshJwt": "r"}"#,
+            ),
+        );
+        let pds = bridge(IdentityKind::Pds);
+        let (mapping, provisioned) = provision(&pds, ACTOR, "alice", &generator).unwrap();
+        assert_eq!(mapping.did.as_str(), "did:plc:hosted");
+        let hosted = provisioned.hosted.unwrap();
+        assert_eq!(hosted.refresh_jwt, "r");
+        let owner = KeyOwner::Account(mapping.did);
+        assert!(pds.keys.current(&owner, KeyPurpose::RepoSigning).is_none());
+        let sent = mock.requests_to(&format!("https://pds.example/xrpc/{CREATE_ACCOUNT}"));
+        let body = json::parse(&String::from_utf8_lossy(&sent[0].body)).unwrap();
+        assert_eq!(body.get("inviteCode"), Some(&Value::from("invite")));
+    }
+}
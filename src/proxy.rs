@@ -0,0 +1,314 @@
+//! Sending outbound traffic through proxies
+//!
+//! [`StdTransport`](crate::transport::StdTransport) can reach servers through a SOCKS5 or HTTP
+//! `CONNECT` proxy, for deployments whose egress is restricted and for instances only
+//! reachable over Tor. A [`ProxyRules`] has one proxy for everything, and any number for
+//! particular destinations: the longest domain suffix matching a host wins. Hosts under
+//! `.onion` are never dialled directly, so they need a rule or a default proxy to be reached.
+//!
+//! Operators set `FEDIBRIDGE_PROXY` to a proxy URL, and `FEDIBRIDGE_PROXY_RULES` to rules such
+//! as `onion=socks5h://127.0.0.1:9050, internal.example=direct`. Proxies are named by URL,
+//! `socks5h://` (or `socks5://`) or `http://`. Names are always resolved by SOCKS proxies,
+//! which is what Tor needs and leaks nothing to the local resolver
+//!
+//! Plain `http` requests are tunnelled with `CONNECT` too, which proxies may only allow to
+//! port 443
+
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ProxyError {
+    #[error("{0} isn't a socks5h://, socks5:// or http:// proxy URL")]
+    InvalidProxy(String),
+    #[error("The proxy rule {0:?} isn't domain=proxy or domain=direct")]
+    InvalidRule(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    /// An HTTP proxy, tunnelling with `CONNECT`
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A proxy connections are tunnelled through
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Proxy {
+    /// A proxy from a URL such as `socks5h://127.0.0.1:9050`
+    pub fn parse(url: &str) -> Result<Proxy, ProxyError> {
+        let invalid = || ProxyError::InvalidProxy(url.to_string());
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let (kind, default_port) = match scheme.to_ascii_lowercase().as_str() {
+            "socks5" | "socks5h" => (ProxyKind::Socks5, 1080),
+            "http" => (ProxyKind::Http, 8080),
+            _ => return Err(invalid()),
+        };
+        let authority = rest.trim_end_matches('/');
+        if authority.is_empty() || authority.contains(['/', '@']) {
+            return Err(invalid());
+        }
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (authority, default_port),
+        };
+        Ok(Proxy {
+            kind,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port,
+        })
+    }
+
+    /// Ask the proxy to connect `stream`, already connected to it, to `host:port`
+    pub fn tunnel<S: Read + Write>(&self, stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+        match self.kind {
+            ProxyKind::Socks5 => socks5(stream, host, port),
+            ProxyKind::Http => http_connect(stream, host, port),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How a host is reached
+pub enum Route<'a> {
+    Direct,
+    Through(&'a Proxy),
+    /// A `.onion` host, with no proxy to reach it through
+    Unreachable,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Which proxy, if any, each destination is reached through
+pub struct ProxyRules {
+    pub default: Option<Proxy>,
+    /// Domain suffixes, each with its proxy or `None` to connect directly
+    pub destinations: Vec<(String, Option<Proxy>)>,
+}
+
+impl ProxyRules {
+    /// Rules with `default` for everything, and `rules` such as `onion=socks5h://tor:9050`
+    /// separated by commas
+    pub fn parse(default: Option<&str>, rules: &str) -> Result<ProxyRules, ProxyError> {
+        let default = default.map(Proxy::parse).transpose()?;
+        let mut destinations = Vec::new();
+        for rule in rules
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+        {
+            let invalid = || ProxyError::InvalidRule(rule.to_string());
+            let (domain, proxy) = rule.split_once('=').ok_or_else(invalid)?;
+            let domain = domain.trim().trim_matches('.').to_ascii_lowercase();
+            if domain.is_empty() {
+                return Err(invalid());
+            }
+            let proxy = match proxy.trim() {
+                "direct" => None,
+                proxy => Some(Proxy::parse(proxy)?),
+            };
+            destinations.push((domain, proxy));
+        }
+        Ok(ProxyRules {
+            default,
+            destinations,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.destinations.is_empty()
+    }
+
+    pub fn route(&self, host: &str) -> Route<'_> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let matching = self.destinations.iter().filter(|(domain, _)| {
+            host == *domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        });
+        let proxy = match matching.max_by_key(|(domain, _)| domain.len()) {
+            Some((_, proxy)) => proxy.as_ref(),
+            None => self.default.as_ref(),
+        };
+        match proxy {
+            Some(proxy) => Route::Through(proxy),
+            None if host == "onion" || host.ends_with(".onion") => Route::Unreachable,
+            None => Route::Direct,
+        }
+    }
+}
+
+fn refused(reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionRefused, reason.into())
+}
+
+/// RFC 1928's handshake, without authentication and with the proxy resolving `host`
+fn socks5<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    stream.write_all(&[5, 1, 0])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        return Err(refused("The SOCKS proxy wants authentication"));
+    }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut request = vec![5, 1, 0];
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Ok(std::net::IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| refused("The host name is too long"))?;
+            request.extend([3, len]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(refused(format!(
+            "The SOCKS proxy couldn't connect (reply {})",
+            reply[1]
+        )));
+    }
+    // The address the proxy bound, which isn't needed, then its port
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        _ => return Err(refused("The SOCKS proxy's reply is malformed")),
+    };
+    stream.read_exact(&mut vec![0; bound + 2])
+}
+
+/// An HTTP `CONNECT` tunnel, reading the response a byte at a time so nothing past it is lost
+fn http_connect<S: Read + Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    let authority = format!("{host}:{port}");
+    let request = format!("CONNECT {authority} HTTP/1.1\r\nhost: {authority}\r\n\r\n");
+    stream.write_all(request.as_bytes())?;
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > 8 * 1024 {
+            return Err(refused("The proxy's response is too long"));
+        }
+        let mut byte = [0];
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let status = head.split(' ').nth(1).unwrap_or_default();
+    if !status.starts_with('2') {
+        let line = head.lines().next().unwrap_or_default();
+        return Err(refused(format!("The proxy refused the tunnel: {line}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// A proxy which answers with a script, recording what it was sent
+    struct Scripted {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Scripted {
+        fn new(replies: &[u8]) -> Scripted {
+            Scripted {
+                replies: Cursor::new(replies.to_vec()),
+                sent: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn destinations_route_by_longest_suffix() {
+        let rules = ProxyRules::parse(
+            Some("http://egress.internal:3128"),
+            "onion=socks5h://127.0.0.1:9050, corp.example=direct",
+        )
+        .unwrap();
+        let tor = Proxy {
+            kind: ProxyKind::Socks5,
+            host: "127.0.0.1".to_string(),
+            port: 9050,
+        };
+        assert_eq!(rules.route("abcdef.onion"), Route::Through(&tor));
+        assert_eq!(rules.route("pds.corp.example"), Route::Direct);
+        assert_eq!(
+            rules.route("notcorp.example"),
+            Route::Through(&rules.default.clone().unwrap())
+        );
+
+        let direct = ProxyRules::default();
+        assert_eq!(direct.route("mastodon.example"), Route::Direct);
+        assert_eq!(direct.route("abcdef.onion"), Route::Unreachable);
+        assert!(ProxyRules::parse(None, "onion").is_err());
+        assert!(ProxyRules::parse(Some("ftp://proxy.example"), "").is_err());
+    }
+
+    #[test]
+    fn tunnels_are_negotiated() {
+        let tor = Proxy::parse("socks5h://127.0.0.1:9050").unwrap();
+        let mut proxy = Scripted::new(&[5, 0, 5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        tor.tunnel(&mut proxy, "abc.onion", 443).unwrap();
+        let mut expected = vec![5, 1, 0, 5, 1, 0, 3, 9];
+        expected.extend(b"abc.onion");
+        expected.extend(443u16.to_be_bytes());
+        assert_eq!(proxy.sent, expected);
+        let mut refusing = Scripted::new(&[5, 0, 5, 4, 0, 1]);
+        assert!(tor.tunnel(&mut refusing, "abc.onion", 443).is_err());
+
+        let egress = Proxy::parse("http://egress.internal:3128").unwrap();
+        let mut proxy = Scripted::new(b"HTTP/1.1 200 Connection established\r\n\r\nHTTP/1.1");
+        egress.tunnel(&mut proxy, "b.example", 443).unwrap();
+        assert!(proxy
+            .sent
+            .starts_with(b"CONNECT b.example:443 HTTP/1.1\r\n"));
+        // What follows the proxy's response is the server's
+        assert_eq!(proxy.replies.position(), 39);
+        let mut forbidden = Scripted::new(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        assert!(egress.tunnel(&mut forbidden, "b.example", 25).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+//! Serving the ActivityPub objects the bridge publishes
+//!
+//! Many servers don't trust the copy of an object delivered to them, and fetch it from its ID
+//! to be sure it's from where it says, or fetch one they were never sent, such as the post a
+//! reply is to. So every activity going out as a bridged account is kept by its ID as it was
+//! sent, along with the object in it: notes, and the actors `Update`s send
+//! ([`PublishedObjects::published`]). [`ObjectEndpoints`] answers for them at their IRIs:
+//!
+//! - to requests accepting `application/activity+json` (or ActivityStreams'
+//!   `application/ld+json`), with the JSON
+//! - to browsers following a link, with a [web page](crate::permalink) of it, linking to
+//!   where it came from on Bluesky
+//! - with `likes` and `shares` collections counting its engagement on Bluesky, and those
+//!   collections at `{id}/likes` and `{id}/shares`, if its owner [mirrors](crate::engagement) it
+//! - for an object since deleted, or of a [suspended](crate::sanctions) identity, with
+//!   `410 Gone` and a `Tombstone`
+//!
+//! Objects are only kept if they're under their actor's origin, and the actor is bridged.
+//! They're appended to the state directory as they change, and shared by every shard
+
+use crate::bridge::Bridge;
+use crate::delivery::{Delivery, ACTIVITY_JSON};
+use crate::engagement;
+use crate::feed::POST_COLLECTION;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::{self, id_of, Value};
+use crate::mentions::BLUESKY_PROFILE;
+use crate::permalink::{self, OEMBED_PATH};
+use crate::sanctions;
+use crate::storage::{JsonLog, StateDir};
+use crate::url::Url;
+use atproto::at_uri::{AtUri, Authority};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+/// The published objects' file in the state directory, one JSON entry per line
+pub const PUBLISHED_FILE: &str = "published.jsonl";
+/// The JSON-LD media type ActivityStreams also answers to
+const LD_JSON: &str = "application/ld+json";
+
+#[derive(Debug, Clone, PartialEq)]
+/// An object as it was last published
+pub struct Published {
+    pub document: Value,
+    /// Where it came from on Bluesky, for browsers to be sent to
+    pub origin: Option<String>,
+    pub deleted: bool,
+}
+
+impl Published {
+    /// What's served for it: the document, or a `Tombstone` once deleted
+    pub fn to_json(&self, id: &str) -> Value {
+        if !self.deleted {
+            return self.document.clone();
+        }
+        let former = self.document.get("type").cloned();
+        Value::object([
+            ("id", Value::from(id)),
+            ("type", Value::from("Tombstone")),
+            ("formerType", former.unwrap_or(Value::Null)),
+        ])
+    }
+}
+
+/// The Bluesky page of the record an `at://` URI names: a post's, or else its repo's profile
+fn bluesky_page(uri: &str) -> Option<String> {
+    let uri = AtUri::try_create(uri.to_string()).ok()?;
+    let Authority::Did(did) = uri.authority() else {
+        return None;
+    };
+    match (uri.collection().map(|c| c.as_str()), uri.rkey()) {
+        (Some(POST_COLLECTION), Some(rkey)) => Some(format!("{BLUESKY_PROFILE}/{did}/post/{rkey}")),
+        _ => Some(format!("{BLUESKY_PROFILE}/{did}")),
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every object published, by ID
+pub struct PublishedObjects {
+    objects: RwLock<HashMap<String, Published>>,
+    log: Option<JsonLog>,
+}
+
+impl PublishedObjects {
+    /// The objects persisted in `dir`. Each line replaces any before it for the same ID
+    pub fn open(dir: StateDir) -> io::Result<PublishedObjects> {
+        let (log, entries) = JsonLog::open(dir, PUBLISHED_FILE)?;
+        let mut objects = HashMap::new();
+        for entry in entries {
+            let (Some(id), Some(document)) = (entry.get("id"), entry.get("document")) else {
+                continue;
+            };
+            let published = Published {
+                document: document.clone(),
+                origin: entry
+                    .get("origin")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                deleted: entry
+                    .get("deleted")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false),
+            };
+            objects.insert(id.as_str().unwrap_or_default().to_string(), published);
+        }
+        Ok(PublishedObjects {
+            objects: RwLock::new(objects),
+            log: Some(log),
+        })
+    }
+
+    pub fn get(&self, id: &str) -> Option<Published> {
+        self.objects.read().unwrap().get(id).cloned()
+    }
+
+    /// Keep `published` as the object `id`, if it's changed
+    pub fn publish(&self, id: &str, published: Published) -> io::Result<()> {
+        let mut objects = self.objects.write().unwrap();
+        if objects.get(id) == Some(&published) {
+            return Ok(());
+        }
+        if let Some(log) = &self.log {
+            let entry = Value::object([
+                ("id", Value::from(id)),
+                ("document", published.document.clone()),
+                ("origin", Value::from(published.origin.clone())),
+                ("deleted", Value::Bool(published.deleted)),
+            ]);
+            log.append([entry])?;
+        }
+        objects.insert(id.to_string(), published);
+        Ok(())
+    }
+
+    /// Keep what `delivery` publishes as one of `bridge`'s accounts: its activity, the object
+    /// in it, and the deletion a `Delete` makes. Returns how many objects changed
+    pub fn published(&self, bridge: &Bridge, delivery: &Delivery) -> io::Result<usize> {
+        let Ok(activity) = json::parse(&delivery.activity) else {
+            return Ok(0);
+        };
+        let Some(actor) = id_of(activity.get("actor")) else {
+            return Ok(0);
+        };
+        if bridge.identities.get_by_actor(actor).is_none() {
+            return Ok(0);
+        }
+        let origin = Url::parse(actor).ok().map(|url| url.origin());
+        let ours = |id: &str| Url::parse(id).ok().map(|url| url.origin()) == origin;
+        let from_bluesky = delivery
+            .cause
+            .as_ref()
+            .and_then(|cause| cause.event.as_deref())
+            .and_then(bluesky_page);
+        let mut changed = 0;
+        let mut keep = |document: &Value, deleted: bool| -> io::Result<()> {
+            let Some(id) = id_of(Some(document)).filter(|id| ours(id)) else {
+                return Ok(());
+            };
+            let url = document.get("url").and_then(Value::as_str);
+            let origin = url.filter(|url| *url != id).map(str::to_string);
+            let document = match (deleted, self.get(id)) {
+                // A deletion usually only names the object, so what it was is kept
+                (true, Some(existing)) => existing.document,
+                _ => document.clone(),
+            };
+            let published = Published {
+                document,
+                origin: origin.or_else(|| from_bluesky.clone()),
+                deleted,
+            };
+            if self.get(id).as_ref() != Some(&published) {
+                changed += 1;
+            }
+            self.publish(id, published)
+        };
+        keep(&activity, false)?;
+        let deleting = activity.get("type").and_then(Value::as_str) == Some("Delete");
+        match activity.get("object") {
+            Some(object @ Value::Object(_)) if !deleting => keep(object, false)?,
+            Some(object) if deleting => {
+                let id = id_of(Some(object)).unwrap_or_default();
+                keep(&Value::object([("id", Value::from(id))]), true)?
+            }
+            _ => {}
+        }
+        Ok(changed)
+    }
+
+    /// Every object, with its ID
+    pub fn all(&self) -> Vec<(String, Published)> {
+        let objects = self.objects.read().unwrap();
+        objects
+            .iter()
+            .map(|(id, published)| (id.clone(), published.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether a request's `Accept` header asks for ActivityPub JSON rather than a web page
+pub fn wants_activity_json(accept: Option<&str>) -> bool {
+    let Some(accept) = accept else {
+        return true;
+    };
+    let types: Vec<_> = accept
+        .split(',')
+        .map(|t| {
+            t.split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .collect();
+    let activity = types
+        .iter()
+        .any(|t| t == ACTIVITY_JSON || t == LD_JSON || t == "application/json");
+    activity || !types.iter().any(|t| t == "text/html")
+}
+
+/// The published objects of bridged accounts under `hostname`, in front of `inner`. Without a
+/// hostname, everything is passed on
+pub struct ObjectEndpoints<H> {
+    bridge: Arc<Bridge>,
+    hostname: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> ObjectEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, hostname: Option<String>, inner: H) -> ObjectEndpoints<H> {
+        ObjectEndpoints {
+            bridge,
+            hostname,
+            inner,
+        }
+    }
+}
+
+impl<H: Handler> Handler for ObjectEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let (Method::Get | Method::Head, Some(hostname)) = (request.method, &self.hostname) else {
+            return self.inner.handle(request);
+        };
+        if request.path == OEMBED_PATH {
+            let url = request.query_param("url").unwrap_or_default();
+            let published = self.bridge.published.get(url).filter(|p| !p.deleted);
+            return match (published, request.query_param("format")) {
+                (None, _) => Response::error(404, "Not found"),
+                (Some(published), None | Some("json")) => {
+                    let oembed = permalink::oembed(&self.bridge, hostname, url, &published);
+                    Response::json(200, &oembed)
+                }
+                (Some(_), Some(_)) => Response::error(501, "Only JSON oEmbed is available"),
+            };
+        }
+        let id = format!("https://{hostname}{}", request.path);
+        if let Some(response) = self.engagement(&id) {
+            return response;
+        }
+        let Some(mut published) = self.bridge.published.get(&id) else {
+            return self.inner.handle(request);
+        };
+        published.deleted |= sanctions::tombstoned(&self.bridge, &published.document);
+        let status = if published.deleted { 410 } else { 200 };
+        if !wants_activity_json(request.header("accept")) {
+            let page = permalink::page(&self.bridge, hostname, &id, &published);
+            return Response::new(status)
+                .with_header("content-type", "text/html; charset=utf-8")
+                .with_header("vary", "accept")
+                .with_body(page);
+        }
+        let mut document = published.to_json(&id);
+        if !published.deleted {
+            let counts = engagement::counts(&self.bridge, &id, &published, Instant::now());
+            if let Some(counts) = counts {
+                document = engagement::with_collections(&document, &id, counts);
+            }
+        }
+        Response::new(status)
+            .with_header("content-type", ACTIVITY_JSON)
+            .with_header("vary", "accept")
+            .with_body(document.to_string())
+    }
+}
+
+impl<H> ObjectEndpoints<H> {
+    /// The `likes` or `shares` collection `id` of a note mirroring its engagement
+    fn engagement(&self, id: &str) -> Option<Response> {
+        let (note, collection) = id.rsplit_once('/')?;
+        if collection != "likes" && collection != "shares" {
+            return None;
+        }
+        let published = self.bridge.published.get(note).filter(|p| !p.deleted)?;
+        let counts = engagement::counts(&self.bridge, note, &published, Instant::now())?;
+        let total = match collection {
+            "likes" => counts.likes,
+            _ => counts.shares,
+        };
+        let mut collection = engagement::collection(id, total);
+        if let Value::Object(fields) = &mut collection {
+            let context = Value::from("https://www.w3.org/ns/activitystreams");
+            fields.insert("@context".to_string(), context);
+        }
+        let response = Response::new(200)
+            .with_header("content-type", ACTIVITY_JSON)
+            .with_body(collection.to_string());
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Cause;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    const ALICE: &str = "https://bridge.example/ap/did:plc:alice";
+
+    #[test]
+    fn published_objects_are_served_by_id() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            published: PublishedObjects::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        bridge
+            .identities
+            .insert(Mapping::new(did!("did:plc:alice"), ALICE));
+        let note = format!("{ALICE}/posts/3k");
+        let create = format!(
+            r#"{{"id": "{note}/activity", "type": "Create", "actor": "{ALICE}",
+                "object": {{"id": "{note}", "type": "Note", "content": "hi"}}}}"#
+        );
+        let delivery = Delivery::new("https://b.example/inbox", create).because(Cause::new(
+            "post",
+            Some("at://did:plc:alice/app.bsky.feed.post/3k"),
+        ));
+        assert_eq!(bridge.published.published(&bridge, &delivery).unwrap(), 2);
+        // Delivering it to another inbox changes nothing
+        assert_eq!(bridge.published.published(&bridge, &delivery).unwrap(), 0);
+        let spoofed = Delivery::new(
+            "https://b.example/inbox",
+            r#"{"id": "https://b.example/1", "type": "Create", "actor": "https://b.example/u"}"#,
+        );
+        assert_eq!(bridge.published.published(&bridge, &spoofed).unwrap(), 0);
+
+        let bridge = Arc::new(Bridge {
+            published: PublishedObjects::open(dir).unwrap(),
+            ..bridge
+        });
+        let endpoints = ObjectEndpoints::new(
+            bridge.clone(),
+            Some("bridge.example".to_string()),
+            |_: &Request| Response::error(404, "Not found"),
+        );
+        let get = |accept: &str| {
+            let request = Request::new(Method::Get, "/ap/did:plc:alice/posts/3k");
+            endpoints.handle(&request.with_header("accept", accept))
+        };
+        let response =
+            get("application/ld+json; profile=\"https://www.w3.org/ns/activitystreams\"");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-type"), Some(ACTIVITY_JSON));
+        let document = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        assert_eq!(document.get("content"), Some(&Value::from("hi")));
+        let response = get("text/html,application/xhtml+xml");
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            response.header("content-type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        let delete = format!(
+            r#"{{"id": "{note}#delete", "type": "Delete", "actor": "{ALICE}", "object": "{note}"}}"#
+        );
+        let delivery = Delivery::new("https://b.example/inbox", delete);
+        bridge.published.published(&bridge, &delivery).unwrap();
+        let response = get(ACTIVITY_JSON);
+        assert_eq!(response.status, 410);
+        let tombstone = json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap();
+        assert_eq!(tombstone.get("formerType"), Some(&Value::from("Note")));
+        let unknown = Request::new(Method::Get, "/ap/did:plc:alice/posts/4k");
+        assert_eq!(endpoints.handle(&unknown).status, 404);
+    }
+}
@@ -0,0 +1,304 @@
+//! Per-account quotas
+//!
+//! One runaway account, a bot posting every few seconds or following and unfollowing in a
+//! loop, could use up what the bridge's PDS allows, or its storage, for everyone. So each
+//! bridged account has daily quotas:
+//!
+//! | Quota     | Counts                             | Set by                                 |
+//! |-----------|------------------------------------|----------------------------------------|
+//! | `posts`   | post records created               | `FEDIBRIDGE_QUOTA_POSTS_PER_DAY`       |
+//! | `media`   | bytes of media stored              | `FEDIBRIDGE_QUOTA_MEDIA_BYTES_PER_DAY` |
+//! | `follows` | follow records created or deleted  | `FEDIBRIDGE_QUOTA_FOLLOWS_PER_DAY`     |
+//!
+//! Commits are charged as they're made ([`Bridge::commit`]), and media before it's stored
+//! ([`Bridge::charge_media`]). Going over a quota is refused with [`Throttled`], and the
+//! account is throttled for `FEDIBRIDGE_QUOTA_THROTTLE_SECS`: nothing more is written for it
+//! until then, except deletions, which always go through and aren't charged. Zero leaves a
+//! quota unlimited.
+//!
+//! A day of usage starts with an account's first charge, and is only counted in memory, so a
+//! restart starts afresh. The admin API's `GET /admin/quotas` lists what accounts have used
+//!
+//! [`Bridge::commit`]: crate::bridge::Bridge::commit
+//! [`Bridge::charge_media`]: crate::bridge::Bridge::charge_media
+
+use crate::feed::POST_COLLECTION;
+use crate::json::Value;
+use crate::reconcile::FOLLOW_COLLECTION;
+use crate::repo::Write;
+use crate::time::format_rfc3339;
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// How long a quota's usage is counted over
+pub const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Something an account's use of is limited
+pub enum Quota {
+    Posts,
+    Media,
+    Follows,
+}
+
+impl Quota {
+    pub const ALL: [Quota; 3] = [Quota::Posts, Quota::Media, Quota::Follows];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quota::Posts => "posts",
+            Quota::Media => "media",
+            Quota::Follows => "follows",
+        }
+    }
+
+    pub fn parse(quota: &str) -> Option<Quota> {
+        Quota::ALL.into_iter().find(|q| q.as_str() == quota)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Each account's daily quotas. `None` leaves one unlimited
+pub struct QuotaConfig {
+    pub posts_per_day: Option<u64>,
+    pub media_bytes_per_day: Option<u64>,
+    pub follows_per_day: Option<u64>,
+    /// How long an account going over a quota is throttled
+    pub throttle: Duration,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        QuotaConfig {
+            posts_per_day: Some(1_000),
+            media_bytes_per_day: Some(1 << 30),
+            follows_per_day: Some(1_000),
+            throttle: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn limit(&self, quota: Quota) -> Option<u64> {
+        match quota {
+            Quota::Posts => self.posts_per_day,
+            Quota::Media => self.media_bytes_per_day,
+            Quota::Follows => self.follows_per_day,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+#[error("{did} went over its {} quota, and is throttled until {}", .quota.as_str(), format_rfc3339(*.until))]
+/// Why something wasn't written for an account
+pub struct Throttled {
+    pub did: Did,
+    pub quota: Quota,
+    pub until: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// How much of each quota something uses
+pub struct Charge {
+    pub posts: u64,
+    pub media_bytes: u64,
+    pub follows: u64,
+}
+
+impl Charge {
+    /// What committing `writes` uses
+    pub fn of_writes(writes: &[Write]) -> Charge {
+        fn collection(write: &Write) -> &str {
+            write.path().split('/').next().unwrap_or_default()
+        }
+        let count = |matching: &dyn Fn(&Write) -> bool| {
+            writes.iter().filter(|w| matching(w)).count() as u64
+        };
+        Charge {
+            posts: count(&|w| {
+                matches!(w, Write::Create { .. }) && collection(w) == POST_COLLECTION
+            }),
+            media_bytes: 0,
+            follows: count(&|w| {
+                !matches!(w, Write::Update { .. }) && collection(w) == FOLLOW_COLLECTION
+            }),
+        }
+    }
+
+    pub fn media(bytes: u64) -> Charge {
+        Charge {
+            media_bytes: bytes,
+            ..Charge::default()
+        }
+    }
+
+    fn of(&self, quota: Quota) -> u64 {
+        match quota {
+            Quota::Posts => self.posts,
+            Quota::Media => self.media_bytes,
+            Quota::Follows => self.follows,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Charge::default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What an account has used today
+pub struct Usage {
+    /// When the day being counted started
+    pub since: SystemTime,
+    pub used: Charge,
+    /// The quota it went over, and until when it's throttled
+    pub throttled: Option<(Quota, SystemTime)>,
+}
+
+impl Usage {
+    pub fn to_json(&self, did: &Did, config: &QuotaConfig) -> Value {
+        let quotas = Quota::ALL.map(|quota| {
+            let usage = Value::object([
+                ("used", Value::from(self.used.of(quota))),
+                ("limit", Value::from(config.limit(quota))),
+            ]);
+            (quota.as_str(), usage)
+        });
+        let throttled = self.throttled.map(|(quota, until)| {
+            Value::object([
+                ("quota", Value::from(quota.as_str())),
+                ("until", Value::from(format_rfc3339(until))),
+            ])
+        });
+        Value::object([
+            ("did", Value::from(did.as_str())),
+            ("since", Value::from(format_rfc3339(self.since))),
+            ("quotas", Value::object(quotas)),
+            ("throttled", throttled.unwrap_or(Value::Null)),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every account's usage today
+pub struct QuotaUsage {
+    accounts: Mutex<BTreeMap<Did, Usage>>,
+}
+
+impl QuotaUsage {
+    /// Charge `did` with `charge`, unless it's throttled or it would go over one of
+    /// `config`'s quotas, which throttles it
+    pub fn charge(
+        &self,
+        config: &QuotaConfig,
+        did: &Did,
+        charge: Charge,
+        now: SystemTime,
+    ) -> Result<(), Throttled> {
+        let mut accounts = self.accounts.lock().unwrap();
+        if charge.is_empty() && !accounts.contains_key(did) {
+            return Ok(());
+        }
+        let usage = accounts.entry(did.clone()).or_insert_with(|| Usage {
+            since: now,
+            used: Charge::default(),
+            throttled: None,
+        });
+        let throttled = |quota, until| Throttled {
+            did: did.clone(),
+            quota,
+            until,
+        };
+        match usage.throttled {
+            Some((quota, until)) if until > now => return Err(throttled(quota, until)),
+            Some(_) => usage.throttled = None,
+            None => {}
+        }
+        if now.duration_since(usage.since).unwrap_or_default() >= DAY {
+            usage.since = now;
+            usage.used = Charge::default();
+        }
+        let over = Quota::ALL.into_iter().find(|&quota| {
+            let used = usage.used.of(quota) + charge.of(quota);
+            config.limit(quota).is_some_and(|limit| used > limit)
+        });
+        if let Some(quota) = over {
+            let until = now + config.throttle;
+            usage.throttled = Some((quota, until));
+            return Err(throttled(quota, until));
+        }
+        usage.used = Charge {
+            posts: usage.used.posts + charge.posts,
+            media_bytes: usage.used.media_bytes + charge.media_bytes,
+            follows: usage.used.follows + charge.follows,
+        };
+        Ok(())
+    }
+
+    pub fn get(&self, did: &Did) -> Option<Usage> {
+        self.accounts.lock().unwrap().get(did).cloned()
+    }
+
+    /// Every account's usage, with those throttled first
+    pub fn to_json(&self, config: &QuotaConfig) -> Value {
+        let accounts = self.accounts.lock().unwrap();
+        let mut all: Vec<_> = accounts.iter().collect();
+        all.sort_by_key(|(_, usage)| usage.throttled.is_none());
+        let all = all.iter().map(|(did, usage)| usage.to_json(did, config));
+        Value::Array(all.collect())
+    }
+
+    /// Lift `did`'s throttling, and forget what it's used today. Returns whether it had used
+    /// anything
+    pub fn reset(&self, did: &Did) -> bool {
+        self.accounts.lock().unwrap().remove(did).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atproto::did;
+
+    #[test]
+    fn going_over_a_quota_throttles_the_account() {
+        let config = QuotaConfig {
+            posts_per_day: Some(2),
+            throttle: Duration::from_secs(60),
+            ..QuotaConfig::default()
+        };
+        let usage = QuotaUsage::default();
+        let alice = did!("did:plc:alice");
+        let post = |rkey: &str| Write::Create {
+            path: format!("{POST_COLLECTION}/{rkey}"),
+            record: Value::Null,
+        };
+        let now = SystemTime::UNIX_EPOCH + DAY;
+        let writes = [post("1"), post("2")];
+        assert_eq!(Charge::of_writes(&writes).posts, 2);
+        usage
+            .charge(&config, &alice, Charge::of_writes(&writes), now)
+            .unwrap();
+        let over = usage.charge(&config, &alice, Charge::of_writes(&[post("3")]), now);
+        let until = now + Duration::from_secs(60);
+        assert_eq!(over.unwrap_err().until, until);
+        // Throttled, it can't follow either, though that quota is fine
+        let follow = Write::Create {
+            path: format!("{FOLLOW_COLLECTION}/1"),
+            record: Value::Null,
+        };
+        let follows = Charge::of_writes(&[follow]);
+        let soon = now + Duration::from_secs(30);
+        assert!(usage.charge(&config, &alice, follows, soon).is_err());
+        usage.charge(&config, &alice, follows, until).unwrap();
+        // The next day, posting starts over
+        let tomorrow = now + DAY;
+        usage
+            .charge(&config, &alice, Charge::of_writes(&writes), tomorrow)
+            .unwrap();
+        assert_eq!(usage.get(&alice).unwrap().used.posts, 2);
+    }
+}
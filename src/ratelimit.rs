@@ -0,0 +1,272 @@
+//! Rate limiting inbound requests
+//!
+//! Every request to the public endpoints is charged against two token buckets: one for the
+//! remote instance it came from and one for the actor who signed it. A misbehaving server,
+//! or one account setting off a reply storm, is answered with `429 Too Many Requests` and a
+//! `Retry-After` instead of being handled.
+//!
+//! The actor is the `keyId` of the request's HTTP signature, without its fragment, and the
+//! instance is that key's host. Unsigned requests have no actor, and are charged to the
+//! address they came from in place of an instance.
+//!
+//! Given the bridge ([`RateLimited::with_trace`]), activities refused are
+//! [traced](crate::trace) as over the limit
+
+use crate::bridge::Bridge;
+use crate::digest::Network;
+use crate::http::{Handler, Request, Response};
+use crate::json::{self, Value};
+use crate::signatures::SignatureParams;
+use crate::trace::{self, Decision, Reason};
+use crate::url::Url;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Beyond this many buckets, full ones are forgotten, as they'd behave the same fresh
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// A token bucket's size and refill rate
+pub struct Limit {
+    /// Requests allowed at once, after a quiet period
+    pub burst: u32,
+    /// Requests allowed per minute, sustained
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn refill_interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Limits on inbound requests. `None` leaves that key unlimited
+pub struct RateLimitConfig {
+    pub instance: Option<Limit>,
+    pub actor: Option<Limit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            instance: Some(Limit {
+                burst: 300,
+                per_minute: 600,
+            }),
+            actor: Some(Limit {
+                burst: 30,
+                per_minute: 60,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    limit: Limit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated);
+        let refilled = elapsed.as_secs_f64() / self.limit.refill_interval().as_secs_f64();
+        (self.tokens + refilled).min(self.limit.burst as f64)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Token buckets by key
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Take a token from each key's bucket, or if any is empty take none and return how long
+    /// until it has one again
+    pub fn check(&self, keys: &[(String, Limit)], now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|_, bucket| bucket.refilled(now) < bucket.limit.burst as f64);
+        }
+        let mut refilled = Vec::with_capacity(keys.len());
+        for (key, limit) in keys {
+            let tokens = match buckets.get(key) {
+                Some(bucket) => bucket.refilled(now),
+                None => limit.burst as f64,
+            };
+            if tokens < 1.0 {
+                return Err(limit.refill_interval().mul_f64(1.0 - tokens));
+            }
+            refilled.push(tokens);
+        }
+        for ((key, limit), tokens) in keys.iter().zip(refilled) {
+            let bucket = Bucket {
+                limit: *limit,
+                tokens: tokens - 1.0,
+                updated: now,
+            };
+            buckets.insert(key.clone(), bucket);
+        }
+        Ok(())
+    }
+}
+
+/// A handler which rate limits requests before passing them on
+pub struct RateLimited<H> {
+    inner: H,
+    config: RateLimitConfig,
+    limiter: RateLimiter,
+    /// Where refused activities are traced
+    bridge: Option<Arc<Bridge>>,
+}
+
+impl<H: Handler> RateLimited<H> {
+    pub fn new(inner: H, config: RateLimitConfig) -> RateLimited<H> {
+        RateLimited {
+            inner,
+            config,
+            limiter: RateLimiter::default(),
+            bridge: None,
+        }
+    }
+
+    /// Trace the activities refused in `bridge`'s decision trace
+    pub fn with_trace(self, bridge: Arc<Bridge>) -> RateLimited<H> {
+        RateLimited {
+            bridge: Some(bridge),
+            ..self
+        }
+    }
+
+    fn trace_refused(&self, request: &Request, keys: &[(String, Limit)]) {
+        let Some(bridge) = &self.bridge else {
+            return;
+        };
+        let Ok(activity) = json::parse(&String::from_utf8_lossy(&request.body)) else {
+            return;
+        };
+        let field = |name| activity.get(name).and_then(Value::as_str);
+        let object = activity.get("object");
+        let object = object.and_then(|o| o.as_str().or_else(|| o.get("id")?.as_str()));
+        let (Some(event), Some(actor)) = (object.or(field("id")), field("actor")) else {
+            return;
+        };
+        let buckets: Vec<&str> = keys.iter().map(|(key, _)| key.as_str()).collect();
+        let decision = Decision::skipped(Network::Fediverse, event, actor, Reason::OverLimit);
+        let detail = format!("Over the limit for {}", buckets.join(" or "));
+        trace::record(bridge, decision.with_detail(detail));
+    }
+
+    /// The buckets a request is charged to
+    fn keys(&self, request: &Request) -> Vec<(String, Limit)> {
+        let params = request.header("signature").map(SignatureParams::parse);
+        let actor = match &params {
+            Some(Ok(params)) => Some(params.actor()),
+            _ => None,
+        };
+        let instance = match actor.map(Url::parse) {
+            Some(Ok(url)) => Some(format!("instance {}", url.host)),
+            _ => request.peer.map(|peer| format!("address {peer}")),
+        };
+        let mut keys = Vec::new();
+        if let (Some(instance), Some(limit)) = (instance, self.config.instance) {
+            keys.push((instance, limit));
+        }
+        if let (Some(actor), Some(limit)) = (actor, self.config.actor) {
+            keys.push((format!("actor {actor}"), limit));
+        }
+        keys
+    }
+}
+
+impl<H: Handler> Handler for RateLimited<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let keys = self.keys(request);
+        match self.limiter.check(&keys, Instant::now()) {
+            Ok(()) => self.inner.handle(request),
+            Err(retry_after) => {
+                self.trace_refused(request, &keys);
+                // Retry-After is in whole seconds, so round up rather than invite an early retry
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                Response::error(429, "Too many requests")
+                    .with_header("retry-after", &seconds.to_string())
+            }
+        }
+    }
+
+    fn body_limit(&self, request: &Request) -> usize {
+        self.inner.body_limit(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+
+    const LIMIT: Limit = Limit {
+        burst: 2,
+        per_minute: 60,
+    };
+
+    fn signed(key_id: &str) -> Request {
+        let signature = format!(r#"keyId="{key_id}",algorithm="rsa-sha256",signature="abc""#);
+        Request::new(Method::Post, "/inbox").with_header("Signature", &signature)
+    }
+
+    #[test]
+    fn buckets_refill_at_the_sustained_rate() {
+        let limiter = RateLimiter::default();
+        let keys = [("instance a.example".to_string(), LIMIT)];
+        let start = Instant::now();
+        assert_eq!(limiter.check(&keys, start), Ok(()));
+        assert_eq!(limiter.check(&keys, start), Ok(()));
+        let later = start + Duration::from_millis(250);
+        assert_eq!(limiter.check(&keys, later), Err(Duration::from_millis(750)));
+        assert_eq!(limiter.check(&keys, start + Duration::from_secs(1)), Ok(()));
+        // One empty bucket refuses the request without charging the others
+        let other = ("actor https://b.example/users/bob".to_string(), LIMIT);
+        let both = [other.clone(), keys[0].clone()];
+        let at = start + Duration::from_secs(1);
+        assert!(limiter.check(&both, at).is_err());
+        assert_eq!(limiter.check(std::slice::from_ref(&other), at), Ok(()));
+        assert_eq!(limiter.check(&[other], at), Ok(()));
+    }
+
+    #[test]
+    fn limits_by_instance_and_actor() {
+        let ok = |_: &Request| Response::json(200, &crate::json::Value::Null);
+        let config = RateLimitConfig {
+            instance: Some(Limit {
+                burst: 3,
+                per_minute: 60,
+            }),
+            actor: Some(LIMIT),
+        };
+        let handler = RateLimited::new(ok, config);
+        let bob = signed("https://b.example/users/bob#main-key");
+        assert_eq!(handler.handle(&bob).status, 200);
+        assert_eq!(handler.handle(&bob).status, 200);
+        let limited = handler.handle(&bob);
+        assert_eq!(limited.status, 429);
+        assert_eq!(limited.header("retry-after"), Some("1"));
+        // Another actor on the same instance has its own bucket, but shares the instance's
+        let carol = signed("https://b.example/users/carol#main-key");
+        assert_eq!(handler.handle(&carol).status, 200);
+        assert_eq!(handler.handle(&carol).status, 429);
+        let dave = signed("https://d.example/users/dave#main-key");
+        assert_eq!(handler.handle(&dave).status, 200);
+
+        // Unsigned requests are limited by address
+        let mut unsigned = Request::new(Method::Get, "/users/alice");
+        unsigned.peer = Some("192.0.2.1".parse().unwrap());
+        for _ in 0..3 {
+            assert_eq!(handler.handle(&unsigned).status, 200);
+        }
+        assert_eq!(handler.handle(&unsigned).status, 429);
+    }
+}
@@ -0,0 +1,342 @@
+//! Emoji reactions
+//!
+//! Pleroma, Akkoma and Misskey let people react to posts with any emoji, sent as an
+//! `EmojiReact` (or Misskey's `Like` with a `_misskey_reaction`) whose `content` is the emoji.
+//! Bluesky only has likes, so reactions to bridged posts become likes, all of them or only
+//! those with the emoji the operator picks. They can keep the emoji in the like record, as a
+//! `reaction` field Bluesky ignores. Undoing a reaction deletes the like it became.
+//!
+//! The other way, a Bluesky like can be sent as a reaction with a default emoji to software
+//! which shows reactions, rather than as a `Like` it would show as a favourite
+//!
+//! Reactions are read here before [`misskey::normalize`](crate::misskey::normalize) turns
+//! them into plain `Like`s, dropping the emoji
+
+use crate::bridge::Bridge;
+use crate::json::{id_of, Value};
+use crate::repo::{record_cid, RepoError, Write};
+use crate::rkeys;
+use crate::time::format_rfc3339;
+use atproto::DID::Did;
+use std::time::SystemTime;
+
+pub const LIKE_COLLECTION: &str = "app.bsky.feed.like";
+
+/// Contexts declared by the software which shows `EmojiReact`s
+const REACTION_CONTEXTS: &[&str] = &["https://misskey-hub.net/ns", "litepub"];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Which emoji reactions are bridged as likes
+pub enum ReactionLikes {
+    #[default]
+    All,
+    /// Only reactions with one of these emoji
+    Only(Vec<String>),
+    None,
+}
+
+impl ReactionLikes {
+    /// Parse `all`, `none` or a comma-separated list of emoji
+    pub fn parse(s: &str) -> ReactionLikes {
+        match s.trim() {
+            "all" => ReactionLikes::All,
+            "none" => ReactionLikes::None,
+            emoji => ReactionLikes::Only(
+                emoji
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|e| !e.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+        }
+    }
+
+    pub fn allows(&self, emoji: &str) -> bool {
+        match self {
+            ReactionLikes::All => true,
+            ReactionLikes::Only(allowed) => allowed.iter().any(|e| e == emoji),
+            ReactionLikes::None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// How emoji reactions are bridged
+pub struct ReactionConfig {
+    pub likes: ReactionLikes,
+    /// Whether like records keep the emoji they were bridged from
+    pub annotate: bool,
+    /// The emoji Bluesky likes are sent as to software which shows reactions. Without one,
+    /// they're sent as `Like`s everywhere
+    pub outbound: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An emoji reaction to a post
+pub struct Reaction {
+    pub id: Option<String>,
+    pub actor: String,
+    pub object: String,
+    pub emoji: String,
+}
+
+/// The reaction `activity` is, if it's an `EmojiReact` or a Misskey reaction
+pub fn reaction(activity: &Value) -> Option<Reaction> {
+    let field = |name| activity.get(name).and_then(Value::as_str);
+    let emoji = match field("type")? {
+        "EmojiReact" => field("content")?,
+        "Like" => field("_misskey_reaction")?,
+        _ => return None,
+    };
+    Some(Reaction {
+        id: field("id").map(str::to_string),
+        actor: field("actor")?.to_string(),
+        object: id_of(activity.get("object"))?.to_string(),
+        emoji: emoji.trim().to_string(),
+    })
+}
+
+/// The reaction an `Undo` takes back, if it embeds one
+pub fn undone(activity: &Value) -> Option<Reaction> {
+    if activity.get("type").and_then(Value::as_str) != Some("Undo") {
+        return None;
+    }
+    let undone = reaction(activity.get("object")?)?;
+    let actor = activity.get("actor").and_then(Value::as_str);
+    (actor == Some(undone.actor.as_str())).then_some(undone)
+}
+
+/// The like record `reaction` becomes, liking the post at `uri` with CID `cid`, if the
+/// configuration bridges it
+pub fn to_like(
+    reaction: &Reaction,
+    uri: &str,
+    cid: &str,
+    config: &ReactionConfig,
+) -> Option<Value> {
+    if !config.likes.allows(&reaction.emoji) {
+        return None;
+    }
+    let mut like = vec![
+        ("$type", Value::from(LIKE_COLLECTION)),
+        (
+            "subject",
+            Value::object([("uri", Value::from(uri)), ("cid", Value::from(cid))]),
+        ),
+        ("createdAt", Value::from(format_rfc3339(SystemTime::now()))),
+    ];
+    if config.annotate {
+        like.push(("reaction", Value::from(reaction.emoji.as_str())));
+    }
+    Some(Value::object(like))
+}
+
+/// Like the post at `uri` as `reactor`, the bridged account of the reaction's actor
+///
+/// Returns the like's record key, if the reaction was bridged
+pub fn reaction_received(
+    bridge: &Bridge,
+    reactor: &Did,
+    reaction: &Reaction,
+    uri: &str,
+    cid: &str,
+) -> Result<Option<String>, RepoError> {
+    let Some(like) = to_like(reaction, uri, cid, &bridge.reactions) else {
+        return Ok(None);
+    };
+    let origin = match &reaction.id {
+        Some(id) => id.clone(),
+        None => format!("{} {}", reaction.actor, reaction.object),
+    };
+    let same = |like: &Value| {
+        let subject = like.get("subject").and_then(|s| s.get("uri"));
+        subject.and_then(Value::as_str) == Some(uri)
+    };
+    let claimed = rkeys::create(bridge, reactor, LIKE_COLLECTION, &origin, like, same)?;
+    Ok(Some(claimed.rkey().to_string()))
+}
+
+/// Delete `reactor`'s likes of the post at `uri`, as the reaction they were bridged from was
+/// undone
+///
+/// Returns how many were deleted
+pub fn reaction_undone(bridge: &Bridge, reactor: &Did, uri: &str) -> Result<usize, RepoError> {
+    let committed = bridge.update_repo(reactor, || {
+        let likes = bridge.repos.records(reactor, LIKE_COLLECTION).into_iter();
+        Ok(likes
+            .filter(|(_, like)| {
+                let subject = like.get("subject").and_then(|s| s.get("uri"));
+                subject.and_then(Value::as_str) == Some(uri)
+            })
+            .map(|(rkey, like)| Write::Delete {
+                path: format!("{LIKE_COLLECTION}/{rkey}"),
+                swap: Some(record_cid(&like)),
+            })
+            .collect())
+    })?;
+    Ok(committed.map_or(0, |(_, writes)| writes.len()))
+}
+
+/// Whether the actor document `recipient` is from software which shows `EmojiReact`s
+pub fn shows_reactions(recipient: &Value) -> bool {
+    let context = match recipient.get("@context") {
+        Some(Value::Array(context)) => context.iter().collect(),
+        Some(context) => vec![context],
+        None => Vec::new(),
+    };
+    let mentions = |value: &Value| match value {
+        Value::String(s) => REACTION_CONTEXTS.iter().any(|c| s.contains(c)),
+        Value::Object(terms) => terms.values().any(|term| {
+            term.as_str()
+                .is_some_and(|s| REACTION_CONTEXTS.iter().any(|c| s.contains(c)))
+        }),
+        _ => false,
+    };
+    context.into_iter().any(mentions)
+}
+
+/// The activity with ID `id` sending `actor`'s like of `object` to `recipient`: an
+/// `EmojiReact` with the default emoji if it shows them, otherwise a `Like`
+pub fn like_activity(
+    id: &str,
+    actor: &str,
+    object: &str,
+    recipient: Option<&Value>,
+    config: &ReactionConfig,
+) -> Value {
+    let emoji = config
+        .outbound
+        .as_deref()
+        .filter(|_| recipient.is_some_and(shows_reactions));
+    let mut activity = vec![
+        (
+            "@context",
+            Value::from("https://www.w3.org/ns/activitystreams"),
+        ),
+        ("id", Value::from(id)),
+        (
+            "type",
+            Value::from(if emoji.is_some() {
+                "EmojiReact"
+            } else {
+                "Like"
+            }),
+        ),
+        ("actor", Value::from(actor)),
+        ("object", Value::from(object)),
+    ];
+    if let Some(emoji) = emoji {
+        activity.push(("content", Value::from(emoji)));
+    }
+    Value::object(activity)
+}
+
+/// The `Undo` of `activity`, for when the like it was sent for is deleted
+pub fn undo_activity(activity: &Value) -> Value {
+    let mut activity = activity.clone();
+    let context = match &mut activity {
+        Value::Object(fields) => fields.remove("@context"),
+        _ => None,
+    };
+    let field = |name| activity.get(name).cloned().unwrap_or(Value::Null);
+    let id = activity
+        .get("id")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    Value::object([
+        (
+            "@context",
+            context.unwrap_or(Value::from("https://www.w3.org/ns/activitystreams")),
+        ),
+        ("id", Value::from(format!("{id}/undo"))),
+        ("type", Value::from("Undo")),
+        ("actor", field("actor")),
+        ("object", activity.clone()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    #[test]
+    fn reactions_become_likes_by_configuration() {
+        let react = json::parse(
+            r#"{"type": "EmojiReact", "id": "https://pleroma.example/a/1", "content": "🎉",
+                "actor": "https://pleroma.example/u/bob", "object": "https://bridge.example/p/1"}"#,
+        )
+        .unwrap();
+        let reaction = reaction(&react).unwrap();
+        assert_eq!(reaction.emoji, "🎉");
+        let (uri, cid) = ("at://did:plc:alice/app.bsky.feed.post/3k", "bafy");
+        let like = to_like(&reaction, uri, cid, &ReactionConfig::default()).unwrap();
+        assert_eq!(like.get("reaction"), None);
+        let annotated = ReactionConfig {
+            annotate: true,
+            ..ReactionConfig::default()
+        };
+        let like = to_like(&reaction, uri, cid, &annotated).unwrap();
+        assert_eq!(like.get("reaction"), Some(&Value::from("🎉")));
+
+        let hearts = ReactionConfig {
+            likes: ReactionLikes::parse("❤, 👍"),
+            ..ReactionConfig::default()
+        };
+        assert_eq!(to_like(&reaction, uri, cid, &hearts), None);
+        let misskey = json::parse(
+            r#"{"type": "Like", "_misskey_reaction": "👍", "actor": "https://mk.example/u/c",
+                "object": {"id": "https://bridge.example/p/1"}}"#,
+        )
+        .unwrap();
+        let reaction = super::reaction(&misskey).unwrap();
+        assert!(to_like(&reaction, uri, cid, &hearts).is_some());
+
+        let undo = Value::object([
+            ("type", Value::from("Undo")),
+            ("actor", Value::from("https://mk.example/u/c")),
+            ("object", misskey),
+        ]);
+        assert_eq!(undone(&undo), Some(reaction));
+    }
+
+    #[test]
+    fn likes_are_sent_as_reactions_where_they_show() {
+        let config = ReactionConfig {
+            outbound: Some("❤".to_string()),
+            ..ReactionConfig::default()
+        };
+        let misskey = json::parse(
+            r#"{"@context": ["https://www.w3.org/ns/activitystreams",
+                {"misskey": "https://misskey-hub.net/ns#", "isCat": "misskey:isCat"}]}"#,
+        )
+        .unwrap();
+        let mastodon =
+            json::parse(r#"{"@context": "https://www.w3.org/ns/activitystreams"}"#).unwrap();
+        let (id, actor, object) = (
+            "https://bridge.example/likes/1",
+            "https://bridge.example/u/alice",
+            "https://mk.example/notes/1",
+        );
+        let react = like_activity(id, actor, object, Some(&misskey), &config);
+        assert_eq!(react.get("type"), Some(&Value::from("EmojiReact")));
+        assert_eq!(react.get("content"), Some(&Value::from("❤")));
+        let like = like_activity(id, actor, object, Some(&mastodon), &config);
+        assert_eq!(like.get("type"), Some(&Value::from("Like")));
+        let plain = like_activity(
+            id,
+            actor,
+            object,
+            Some(&misskey),
+            &ReactionConfig::default(),
+        );
+        assert_eq!(plain.get("type"), Some(&Value::from("Like")));
+
+        let undo = undo_activity(&react);
+        assert_eq!(undo.get("actor"), Some(&Value::from(actor)));
+        let undone = undo.get("object").unwrap();
+        assert_eq!(undone.get("content"), Some(&Value::from("❤")));
+        assert_eq!(undone.get("@context"), None);
+    }
+}
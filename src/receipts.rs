@@ -0,0 +1,266 @@
+//! Receipts for each delivery of a bridged post
+//!
+//! A post going out to the fediverse is delivered to the inbox of every instance one of its
+//! audience is on, and each can go its own way: accepted, retried after a timeout, given up
+//! on, or never sent at all because the federation policy refuses it. Every outcome is
+//! recorded as a [`Receipt`] against the post, as the [decision trace](crate::trace) names
+//! it, so "did my post reach mastodon.social?" is answered by the latest receipt for each
+//! inbox rather than by grepping logs.
+//!
+//! Like the audit log, receipts are appended to the shard's state directory and dropped once
+//! older than [`RetentionConfig::receipt_ttl`](crate::retention::RetentionConfig). The admin
+//! API's `GET /admin/receipts` and the self-service `GET /account/receipts` look them up
+
+use crate::delivery::Delivery;
+use crate::json::Value;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::url::Url;
+use std::collections::HashSet;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The receipts' file in the state directory, one JSON receipt per line
+pub const RECEIPTS_FILE: &str = "receipts.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// What became of a delivery
+pub enum Outcome {
+    /// The inbox accepted it
+    Delivered,
+    /// It failed, and will be tried again
+    Retrying,
+    /// It failed for the last time
+    Failed,
+    /// It wasn't sent, as the bridge refused it
+    Skipped,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 4] = [
+        Outcome::Delivered,
+        Outcome::Retrying,
+        Outcome::Failed,
+        Outcome::Skipped,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Delivered => "delivered",
+            Outcome::Retrying => "retrying",
+            Outcome::Failed => "failed",
+            Outcome::Skipped => "skipped",
+        }
+    }
+
+    pub fn parse(outcome: &str) -> Option<Outcome> {
+        Outcome::ALL.into_iter().find(|o| o.as_str() == outcome)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What became of delivering a post to one inbox
+pub struct Receipt {
+    pub at: SystemTime,
+    /// The post, by the event which caused its delivery, its object or the activity's ID
+    pub post: String,
+    /// Who it was delivered as
+    pub actor: String,
+    pub inbox: String,
+    pub outcome: Outcome,
+    /// Why it failed or was skipped
+    pub detail: Option<String>,
+}
+
+impl Receipt {
+    /// A receipt for `delivery`, if it's of a post
+    pub fn new(delivery: &Delivery, outcome: Outcome) -> Option<Receipt> {
+        Some(Receipt {
+            at: SystemTime::now(),
+            post: delivery.post()?,
+            actor: delivery.actor()?,
+            inbox: delivery.inbox.clone(),
+            outcome,
+            detail: None,
+        })
+    }
+
+    pub fn with_detail(self, detail: impl Into<String>) -> Receipt {
+        Receipt {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+
+    /// The host of the inbox, which is its instance
+    pub fn host(&self) -> Option<String> {
+        Url::parse(&self.inbox).ok().map(|url| url.host)
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("post", Value::from(self.post.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            ("inbox", Value::from(self.inbox.as_str())),
+            ("outcome", Value::from(self.outcome.as_str())),
+            ("detail", Value::from(self.detail.clone())),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Receipt> {
+        let field = |name| value.get(name)?.as_str().map(str::to_string);
+        Some(Receipt {
+            at: parse_rfc3339(value.get("at")?.as_str()?).ok()?,
+            post: field("post")?,
+            actor: field("actor")?,
+            inbox: field("inbox")?,
+            outcome: Outcome::parse(value.get("outcome")?.as_str()?)?,
+            detail: field("detail"),
+        })
+    }
+}
+
+/// The latest receipt for each inbox, ordered by inbox, and how many came to each outcome
+pub fn summary_json(post: &str, receipts: &[Receipt]) -> Value {
+    let counts = Outcome::ALL.map(|outcome| {
+        let count = receipts.iter().filter(|r| r.outcome == outcome).count();
+        (outcome.as_str(), Value::from(count))
+    });
+    Value::object([
+        ("post", Value::from(post)),
+        ("counts", Value::object(counts)),
+        (
+            "receipts",
+            Value::Array(receipts.iter().map(Receipt::to_json).collect()),
+        ),
+    ])
+}
+
+#[derive(Debug, Default)]
+/// Every delivery's receipts, within the retention period, oldest first
+pub struct ReceiptLog {
+    receipts: Mutex<Vec<Receipt>>,
+    log: Option<JsonLog>,
+}
+
+impl ReceiptLog {
+    /// A log persisted in `dir`, with the receipts already there
+    pub fn open(dir: StateDir) -> io::Result<ReceiptLog> {
+        let (log, receipts) = JsonLog::open(dir, RECEIPTS_FILE)?;
+        let receipts = receipts.iter().filter_map(Receipt::from_json).collect();
+        Ok(ReceiptLog {
+            receipts: Mutex::new(receipts),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, receipt: Receipt) -> io::Result<()> {
+        let mut receipts = self.receipts.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([receipt.to_json()])?;
+        }
+        receipts.push(receipt);
+        Ok(())
+    }
+
+    /// The latest receipt for each inbox `post` went to, ordered by inbox. With a `host`,
+    /// only those for inboxes on it
+    pub fn for_post(&self, post: &str, host: Option<&str>) -> Vec<Receipt> {
+        let receipts = self.receipts.lock().unwrap();
+        let mut latest: Vec<Receipt> = Vec::new();
+        for receipt in receipts.iter().rev().filter(|r| r.post == post) {
+            if !latest.iter().any(|r| r.inbox == receipt.inbox) {
+                latest.push(receipt.clone());
+            }
+        }
+        if let Some(host) = host {
+            latest.retain(|r| r.host().is_some_and(|h| h.eq_ignore_ascii_case(host)));
+        }
+        latest.sort_by(|a, b| a.inbox.cmp(&b.inbox));
+        latest
+    }
+
+    /// The post and inbox of each delivery an inbox accepted
+    pub fn delivered(&self) -> HashSet<(String, String)> {
+        let receipts = self.receipts.lock().unwrap();
+        let delivered = receipts.iter().filter(|r| r.outcome == Outcome::Delivered);
+        delivered
+            .map(|r| (r.post.clone(), r.inbox.clone()))
+            .collect()
+    }
+
+    /// Drop receipts older than `ttl` as of `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut receipts = self.receipts.lock().unwrap();
+        let before = receipts.len();
+        receipts.retain(|receipt| now.duration_since(receipt.at).unwrap_or_default() < ttl);
+        let pruned = before - receipts.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            log.rewrite(receipts.iter().map(Receipt::to_json))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.receipts.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::Cause;
+    use crate::storage::tests::temp_state_dir;
+
+    #[test]
+    fn latest_receipt_per_inbox_survives_reopening() {
+        let dir = temp_state_dir();
+        let log = ReceiptLog::open(dir.clone()).unwrap();
+        let post = "at://did:plc:aaaa/app.bsky.feed.post/1";
+        let activity = r#"{"type": "Create", "actor": "https://bridge.example/users/a",
+            "object": {"id": "https://bridge.example/users/a/posts/1"}}"#;
+        let delivery =
+            |inbox: &str| Delivery::new(inbox, activity).because(Cause::new("post", Some(post)));
+        let social = delivery("https://mastodon.social/inbox");
+        let other = delivery("https://other.example/inbox");
+        log.append(
+            Receipt::new(&social, Outcome::Retrying)
+                .unwrap()
+                .with_detail("timeout"),
+        )
+        .unwrap();
+        log.append(Receipt::new(&other, Outcome::Skipped).unwrap())
+            .unwrap();
+        log.append(Receipt::new(&social, Outcome::Delivered).unwrap())
+            .unwrap();
+
+        let log = ReceiptLog::open(dir).unwrap();
+        let outcomes = |receipts: Vec<Receipt>| {
+            let outcomes = receipts.into_iter().map(|r| (r.inbox, r.outcome));
+            outcomes.collect::<Vec<_>>()
+        };
+        assert_eq!(
+            outcomes(log.for_post(post, None)),
+            [
+                (
+                    "https://mastodon.social/inbox".to_string(),
+                    Outcome::Delivered
+                ),
+                ("https://other.example/inbox".to_string(), Outcome::Skipped),
+            ]
+        );
+        assert_eq!(log.for_post(post, Some("Mastodon.social")).len(), 1);
+        assert!(log
+            .for_post("at://did:plc:aaaa/app.bsky.feed.post/2", None)
+            .is_empty());
+        let summary = summary_json(post, &log.for_post(post, None));
+        let delivered = summary.get("counts").and_then(|c| c.get("delivered"));
+        assert_eq!(delivered.and_then(Value::as_i64), Some(1));
+    }
+}
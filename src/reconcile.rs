@@ -0,0 +1,580 @@
+//! Reconciling both sides of bridged accounts
+//!
+//! However carefully events are followed, the two sides of an account drift apart: an
+//! activity never arrives, a commit is missed, a delivery is given up on. Every
+//! [`ReconcileConfig::interval`], [`spawn`]'s thread compares the two sides of each active
+//! account and repairs what it can ([`reconcile`]):
+//!
+//! | Compared          | Fediverse side                  | Bluesky side           | Repair              |
+//! |-------------------|---------------------------------|------------------------|---------------------|
+//! | follows           | the actor's `following`         | follow records         | records created, or deleted |
+//! | likes             | the size of the actor's `liked` | like records           | none, only reported |
+//! | recent posts      | `Create`s in the actor's outbox | posts created then     | a backfill queued   |
+//! | failed deliveries | dead deliveries as the actor    |                        | retried             |
+//!
+//! The first three are of fediverse accounts, whose repos the bridge hosts. Follows of
+//! accounts which aren't bridged can't be, so only those of bridged accounts are compared,
+//! and follow records are only deleted when the whole `following` collection was on its first
+//! page. Posts published in the last [`GRACE`] may still be on their way, so aren't missing yet.
+//!
+//! What each run found and repaired is counted by kind of [`Drift`], which the admin API's
+//! `GET /admin/reconcile` reports. `FEDIBRIDGE_RECONCILE` turns reconciliation on and
+//! `FEDIBRIDGE_RECONCILE_INTERVAL_SECS` sets how often it runs
+
+use crate::audience::{visibility, Visibility};
+use crate::bridge::Bridge;
+use crate::delivery::ACTIVITY_JSON;
+use crate::feed::POST_COLLECTION;
+use crate::jobs::Job;
+use crate::json::{self, id_of, Value};
+use crate::repo::{record_cid, RepoError, Write};
+use crate::rkeys;
+use crate::shutdown::Shutdown;
+use crate::store::{Mapping, MappingStatus};
+use crate::time::{format_rfc3339, parse_rfc3339, unique_millis};
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::tid::Tid;
+use atproto::DID::Did;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+pub const FOLLOW_COLLECTION: &str = "app.bsky.graph.follow";
+pub const LIKE_COLLECTION: &str = "app.bsky.feed.like";
+/// How long a post has to be on its way before it counts as missing
+pub const GRACE: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconcileConfig {
+    pub enabled: bool,
+    /// How long between runs
+    pub interval: Duration,
+}
+
+impl Default for ReconcileConfig {
+    fn default() -> Self {
+        ReconcileConfig {
+            enabled: false,
+            interval: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// A way the two sides of an account differ
+pub enum Drift {
+    /// A bridged account followed on the fediverse has no follow record
+    MissingFollow,
+    /// A follow record for an account no longer followed on the fediverse
+    StaleFollow,
+    /// More or fewer like records than likes on the fediverse
+    LikeCount,
+    /// A recent public post on the fediverse which isn't in the repo
+    MissingPost,
+    /// A delivery as the account which was given up on
+    FailedDelivery,
+}
+
+impl Drift {
+    pub const ALL: [Drift; 5] = [
+        Drift::MissingFollow,
+        Drift::StaleFollow,
+        Drift::LikeCount,
+        Drift::MissingPost,
+        Drift::FailedDelivery,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Drift::MissingFollow => "missingFollow",
+            Drift::StaleFollow => "staleFollow",
+            Drift::LikeCount => "likeCount",
+            Drift::MissingPost => "missingPost",
+            Drift::FailedDelivery => "failedDelivery",
+        }
+    }
+
+    pub fn parse(drift: &str) -> Option<Drift> {
+        Drift::ALL.into_iter().find(|d| d.as_str() == drift)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One difference between the two sides of an account
+pub struct Discrepancy {
+    pub drift: Drift,
+    /// What differs, e.g. the actor followed or the post missing
+    pub detail: String,
+    pub repaired: bool,
+}
+
+impl Discrepancy {
+    fn new(drift: Drift, detail: impl Into<String>, repaired: bool) -> Discrepancy {
+        Discrepancy {
+            drift,
+            detail: detail.into(),
+            repaired,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("drift", Value::from(self.drift.as_str())),
+            ("detail", Value::from(self.detail.as_str())),
+            ("repaired", Value::Bool(self.repaired)),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// How one account's two sides compared
+pub struct Reconciliation {
+    pub did: Did,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Reconciliation {
+    pub fn to_json(&self) -> Value {
+        let discrepancies = self.discrepancies.iter().map(Discrepancy::to_json);
+        Value::object([
+            ("did", Value::from(self.did.as_str())),
+            ("discrepancies", Value::Array(discrepancies.collect())),
+        ])
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors reconciling an account
+pub enum ReconcileError {
+    #[error("Couldn't fetch {url}: {reason}")]
+    Fetch { url: String, reason: String },
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Repo(#[from] RepoError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Fetch an ActivityPub document as it is now, rather than as it was cached
+fn fetch(bridge: &Bridge, url: &str) -> Result<Value, ReconcileError> {
+    let request = OutboundRequest::get(url).with_header("accept", ACTIVITY_JSON);
+    let response = bridge.transport.send(&request)?;
+    let failed = |reason| ReconcileError::Fetch {
+        url: url.to_string(),
+        reason,
+    };
+    if !response.is_success() {
+        return Err(failed(format!("status {}", response.status)));
+    }
+    json::parse(&String::from_utf8_lossy(&response.body)).map_err(|e| failed(e.to_string()))
+}
+
+/// A collection's first page of items, and whether that's all of them
+fn first_page(bridge: &Bridge, url: &str) -> Result<(Vec<Value>, bool), ReconcileError> {
+    let collection = fetch(bridge, url)?;
+    let items = |page: &Value| {
+        let items = page.get("orderedItems").or_else(|| page.get("items"));
+        items.and_then(Value::as_array).map(<[Value]>::to_vec)
+    };
+    let items = match (items(&collection), collection.get("first")) {
+        (Some(items), _) => items,
+        (None, Some(Value::String(first))) => items(&fetch(bridge, first)?).unwrap_or_default(),
+        (None, Some(first)) => items(first).unwrap_or_default(),
+        (None, None) => Vec::new(),
+    };
+    let total = collection.get("totalItems").and_then(Value::as_i64);
+    let complete = total.and_then(|n| usize::try_from(n).ok()) == Some(items.len());
+    Ok((items, complete))
+}
+
+/// Compare the accounts `mapping` follows on the fediverse with its follow records, creating
+/// and deleting records to match
+fn follows(
+    bridge: &Bridge,
+    mapping: &Mapping,
+    actor: &Value,
+) -> Result<Vec<Discrepancy>, ReconcileError> {
+    let Some(url) = id_of(actor.get("following")) else {
+        return Ok(Vec::new());
+    };
+    let (items, complete) = first_page(bridge, url)?;
+    let followed: BTreeMap<String, String> = items
+        .iter()
+        .filter_map(|item| bridge.identities.get_by_actor(id_of(Some(item))?))
+        .filter(|m| m.status == MappingStatus::Active)
+        .map(|m| (m.did.to_string(), m.actor))
+        .collect();
+    let subject = |record: &Value| record.get("subject")?.as_str().map(str::to_string);
+    let mut discrepancies = Vec::new();
+    bridge.update_repo(&mapping.did, || {
+        discrepancies.clear();
+        let records = bridge.repos.records(&mapping.did, FOLLOW_COLLECTION);
+        let recorded: BTreeSet<String> = records.iter().filter_map(|(_, r)| subject(r)).collect();
+        let mut writes = Vec::new();
+        for (did, actor) in &followed {
+            if recorded.contains(did) {
+                continue;
+            }
+            let record = Value::object([
+                ("$type", Value::from(FOLLOW_COLLECTION)),
+                ("subject", Value::from(did.as_str())),
+                ("createdAt", Value::from(format_rfc3339(SystemTime::now()))),
+            ]);
+            let taken = |rkey: &str| {
+                let path = format!("{FOLLOW_COLLECTION}/{rkey}");
+                records.iter().any(|(key, _)| key == rkey)
+                    || writes.iter().any(|w: &Write| w.path() == path)
+            };
+            let rkey = rkeys::free(actor, taken)
+                .unwrap_or_else(|| Tid::from_parts(unique_millis() * 1000, writes.len() as u16));
+            writes.push(Write::Create {
+                path: format!("{FOLLOW_COLLECTION}/{rkey}"),
+                record,
+            });
+            discrepancies.push(Discrepancy::new(Drift::MissingFollow, actor.as_str(), true));
+        }
+        // Without the whole collection, anything not seen could be on a later page
+        if complete {
+            for (rkey, record) in &records {
+                let Some(did) = subject(record).filter(|did| !followed.contains_key(did)) else {
+                    continue;
+                };
+                writes.push(Write::Delete {
+                    path: format!("{FOLLOW_COLLECTION}/{rkey}"),
+                    swap: Some(record_cid(record)),
+                });
+                discrepancies.push(Discrepancy::new(Drift::StaleFollow, did, true));
+            }
+        }
+        Ok(writes)
+    })?;
+    Ok(discrepancies)
+}
+
+/// Compare the number of posts `mapping` has liked on the fediverse with its like records
+fn likes(
+    bridge: &Bridge,
+    mapping: &Mapping,
+    actor: &Value,
+) -> Result<Vec<Discrepancy>, ReconcileError> {
+    let Some(url) = id_of(actor.get("liked")) else {
+        return Ok(Vec::new());
+    };
+    let liked = fetch(bridge, url)?
+        .get("totalItems")
+        .and_then(Value::as_i64);
+    let recorded = bridge.repos.records(&mapping.did, LIKE_COLLECTION).len();
+    match liked.and_then(|n| usize::try_from(n).ok()) {
+        Some(liked) if liked != recorded => Ok(vec![Discrepancy::new(
+            Drift::LikeCount,
+            format!("{liked} on the fediverse, {recorded} on Bluesky"),
+            false,
+        )]),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Look for `mapping`'s recent public posts which are missing from its repo, by when they
+/// were published, and queue a backfill if any are
+fn posts(
+    bridge: &Bridge,
+    mapping: &Mapping,
+    actor: &Value,
+    now: SystemTime,
+) -> Result<Vec<Discrepancy>, ReconcileError> {
+    let Some(url) = id_of(actor.get("outbox")) else {
+        return Ok(Vec::new());
+    };
+    let (items, _) = first_page(bridge, url)?;
+    let created_at = |record: &Value| parse_rfc3339(record.get("createdAt")?.as_str()?).ok();
+    let recorded: BTreeSet<SystemTime> = bridge
+        .repos
+        .records(&mapping.did, POST_COLLECTION)
+        .iter()
+        .filter_map(|(_, record)| created_at(record))
+        .collect();
+    let published = |object: &Value| parse_rfc3339(object.get("published")?.as_str()?).ok();
+    let missing: Vec<&str> = items
+        .iter()
+        .filter(|item| item.get("type").and_then(Value::as_str) == Some("Create"))
+        .filter_map(|item| item.get("object").filter(|o| matches!(o, Value::Object(_))))
+        .filter(|object| visibility(object) == Visibility::Public)
+        .filter(|object| {
+            published(object).is_some_and(|at| {
+                now.duration_since(at).unwrap_or_default() >= GRACE && !recorded.contains(&at)
+            })
+        })
+        .filter_map(|object| id_of(Some(object)))
+        .collect();
+    if missing.is_empty() {
+        return Ok(Vec::new());
+    }
+    let did = &mapping.did;
+    let queued = bridge
+        .jobs
+        .contains(|j| matches!(j, Job::Backfill { did: d } if d == did));
+    if !queued {
+        bridge.jobs.push(Job::Backfill { did: did.clone() })?;
+    }
+    let missing = missing
+        .into_iter()
+        .map(|object| Discrepancy::new(Drift::MissingPost, object, true));
+    Ok(missing.collect())
+}
+
+/// Retry the deliveries as `mapping` which were given up on
+fn deliveries(bridge: &Bridge, mapping: &Mapping) -> Vec<Discrepancy> {
+    let dead = bridge
+        .jobs
+        .dead()
+        .into_iter()
+        .filter_map(|queued| match queued.job {
+            Job::Deliver(d) if d.actor().as_deref() == Some(mapping.actor.as_str()) => {
+                Some((queued.id, d.inbox))
+            }
+            _ => None,
+        });
+    dead.map(|(id, inbox)| {
+        let repaired = bridge.jobs.retry(id).is_ok();
+        Discrepancy::new(Drift::FailedDelivery, inbox, repaired)
+    })
+    .collect()
+}
+
+/// Compare the two sides of `mapping`'s account as of `now`, repairing what differs
+pub fn reconcile(
+    bridge: &Bridge,
+    mapping: &Mapping,
+    now: SystemTime,
+) -> Result<Reconciliation, ReconcileError> {
+    let mut discrepancies = deliveries(bridge, mapping);
+    // Only fediverse accounts have repos here to compare
+    if bridge.repos.head(&mapping.did).is_some() {
+        let actor = fetch(bridge, &mapping.actor)?;
+        discrepancies.extend(follows(bridge, mapping, &actor)?);
+        discrepancies.extend(likes(bridge, mapping, &actor)?);
+        discrepancies.extend(posts(bridge, mapping, &actor, now)?);
+    }
+    Ok(Reconciliation {
+        did: mapping.did.clone(),
+        discrepancies,
+    })
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    runs: u64,
+    last_run: Option<SystemTime>,
+    accounts: u64,
+    errors: u64,
+    found: BTreeMap<Drift, u64>,
+    repaired: BTreeMap<Drift, u64>,
+}
+
+#[derive(Debug, Default)]
+/// What reconciliation has found and repaired since the bridge started
+pub struct ReconcileStats {
+    totals: Mutex<Totals>,
+}
+
+impl ReconcileStats {
+    fn record(&self, at: SystemTime, results: &[Result<Reconciliation, ReconcileError>]) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.runs += 1;
+        totals.last_run = Some(at);
+        for result in results {
+            totals.accounts += 1;
+            let Ok(reconciliation) = result else {
+                totals.errors += 1;
+                continue;
+            };
+            for discrepancy in &reconciliation.discrepancies {
+                *totals.found.entry(discrepancy.drift).or_default() += 1;
+                if discrepancy.repaired {
+                    *totals.repaired.entry(discrepancy.drift).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let totals = self.totals.lock().unwrap();
+        let counts = |counts: &BTreeMap<Drift, u64>| {
+            Value::object(Drift::ALL.map(|drift| {
+                let count = counts.get(&drift).copied().unwrap_or_default();
+                (drift.as_str(), Value::from(count))
+            }))
+        };
+        Value::object([
+            ("runs", Value::from(totals.runs)),
+            ("lastRun", Value::from(totals.last_run.map(format_rfc3339))),
+            ("accounts", Value::from(totals.accounts)),
+            ("errors", Value::from(totals.errors)),
+            ("found", counts(&totals.found)),
+            ("repaired", counts(&totals.repaired)),
+        ])
+    }
+}
+
+/// Reconcile every active account, counting what was found in [`Bridge::reconciliation`].
+/// Returns the accounts which had discrepancies
+pub fn reconcile_all(bridge: &Bridge, now: SystemTime) -> Vec<Reconciliation> {
+    let active = bridge.identities.all().into_iter();
+    let active = active.filter(|mapping| mapping.status == MappingStatus::Active);
+    let results: Vec<_> = active
+        .map(|mapping| {
+            let result = reconcile(bridge, &mapping, now);
+            if let Err(e) = &result {
+                eprintln!("Couldn't reconcile {}: {e}", mapping.did);
+            }
+            result
+        })
+        .collect();
+    bridge.reconciliation.record(now, &results);
+    results
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|r| !r.discrepancies.is_empty())
+        .collect()
+}
+
+/// Start a thread reconciling every account each interval, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_run = Instant::now();
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_run.elapsed() < bridge.reconcile.interval {
+                continue;
+            }
+            last_run = Instant::now();
+            reconcile_all(&bridge, SystemTime::now());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delivery::Delivery;
+    use crate::error::Category;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::transport::MockTransport;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ALICE_ACTOR: &str = "https://a.example/users/alice";
+    const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+    #[test]
+    fn drift_between_the_sides_is_repaired() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new()
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_transport(mock.clone());
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        let owner = KeyOwner::Account(ALICE);
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        for (name, actor) in [
+            ("bob", "https://b.example/users/bob"),
+            ("carol", "https://bridge.example/carol"),
+        ] {
+            let did = Did::try_create(format!("did:plc:{name}")).unwrap();
+            bridge.identities.insert(Mapping::new(did, actor));
+        }
+        // Alice's repo follows Carol, but she's since followed Bob instead
+        let follow = Value::object([
+            ("$type", Value::from(FOLLOW_COLLECTION)),
+            ("subject", Value::from("did:plc:carol")),
+        ]);
+        let post = Value::object([("createdAt", Value::from("2026-01-01T00:00:00Z"))]);
+        let writes = [
+            Write::Create {
+                path: format!("{FOLLOW_COLLECTION}/1"),
+                record: follow,
+            },
+            Write::Create {
+                path: format!("{POST_COLLECTION}/1"),
+                record: post,
+            },
+        ];
+        bridge.commit(&ALICE, &writes).unwrap();
+        mock.respond_json(
+            ALICE_ACTOR,
+            &format!(
+                r#"{{"id": "{ALICE_ACTOR}", "following": "{ALICE_ACTOR}/following",
+                    "outbox": "{ALICE_ACTOR}/outbox"}}"#
+            ),
+        )
+        .respond_json(
+            &format!("{ALICE_ACTOR}/following"),
+            r#"{"totalItems": 2, "orderedItems": ["https://b.example/users/bob", "https://c.example/users/dan"]}"#,
+        )
+        .respond_json(
+            &format!("{ALICE_ACTOR}/outbox"),
+            &format!(
+                r#"{{"first": {{"orderedItems": [
+                    {{"type": "Create", "object": {{"id": "{ALICE_ACTOR}/1", "to": ["{PUBLIC}"],
+                        "published": "2026-01-01T00:00:00Z"}}}},
+                    {{"type": "Create", "object": {{"id": "{ALICE_ACTOR}/2", "to": ["{PUBLIC}"],
+                        "published": "2026-01-02T00:00:00Z"}}}}]}}}}"#
+            ),
+        );
+        let activity = format!(r#"{{"type": "Create", "actor": "{ALICE_ACTOR}"}}"#);
+        let id = bridge
+            .jobs
+            .push(Job::Deliver(Delivery::new(
+                "https://b.example/inbox",
+                activity,
+            )))
+            .unwrap();
+        for _ in 0..crate::jobs::MAX_ATTEMPTS {
+            let later = SystemTime::now() + Duration::from_secs(86400);
+            let job = bridge.jobs.take(later).unwrap();
+            bridge
+                .jobs
+                .fail(job.id, "timeout", Category::Retryable, SystemTime::now())
+                .unwrap();
+        }
+
+        let now = parse_rfc3339("2026-01-03T00:00:00Z").unwrap();
+        let reconciled = reconcile_all(&bridge, now);
+        let drifts: Vec<_> = reconciled[0]
+            .discrepancies
+            .iter()
+            .map(|d| (d.drift, d.detail.as_str()))
+            .collect();
+        assert_eq!(
+            drifts,
+            [
+                (Drift::FailedDelivery, "https://b.example/inbox"),
+                (Drift::MissingFollow, "https://b.example/users/bob"),
+                (Drift::StaleFollow, "did:plc:carol"),
+                (Drift::MissingPost, "https://a.example/users/alice/2"),
+            ]
+        );
+        let subjects: Vec<_> = bridge
+            .repos
+            .records(&ALICE, FOLLOW_COLLECTION)
+            .into_iter()
+            .filter_map(|(_, r)| r.get("subject")?.as_str().map(str::to_string))
+            .collect();
+        assert_eq!(subjects, ["did:plc:bob"]);
+        let queued = bridge.jobs.queued();
+        assert!(queued.iter().any(|j| j.id == id));
+        assert!(queued.iter().any(|j| j.job == Job::Backfill { did: ALICE }));
+        let stats = bridge.reconciliation.to_json();
+        let found = stats.get("found").and_then(|f| f.get("missingPost"));
+        assert_eq!(found.and_then(Value::as_i64), Some(1));
+    }
+}
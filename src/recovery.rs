@@ -0,0 +1,249 @@
+//! Repairing what an unclean shutdown leaves behind
+//!
+//! A shard marks its state directory as [running](RUNNING_FILE) when it starts, and clears the
+//! mark as the last thing it does when shutting down. Finding the mark still there on
+//! startup, the bridge stopped without flushing, and [`recover`] repairs what it may have
+//! stopped in the middle of before anything else runs:
+//!
+//! - an event handled part of the way through is handled again in full, so jobs it had
+//!   already queued are queued twice. Each job repeating an earlier one is dropped
+//! - a delivery running when the bridge stopped is run again, though it may have reached its
+//!   inbox. A `Create` with a receipt saying it was delivered there is dropped
+//! - a delivery of an object which has since been deleted would bring it back, so `Create`s
+//!   and `Update`s of those are dropped
+//! - the latest commits to the repos the bridge hosts are only kept in memory for relays to
+//!   catch up from, so a relay may not have seen them. The [`VERIFIED_REPOS`] most recently
+//!   committed to are checked against each relay's `getLatestCommit`, and those a relay is
+//!   behind on are committed to again with no writes. Seeing it doesn't follow on from the
+//!   revision it has, the relay resyncs the repo
+//!
+//! What was repaired is reported when the bridge starts
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::repo::Head;
+use crate::storage::StateDir;
+use crate::sync::GET_LATEST_COMMIT;
+use crate::transport::OutboundRequest;
+use std::io;
+
+/// The mark a running shard leaves in its state directory
+pub const RUNNING_FILE: &str = "running";
+/// How many of the repos most recently committed to are checked against each relay
+pub const VERIFIED_REPOS: usize = 100;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What was repaired
+pub struct Recovered {
+    /// Jobs dropped as repeats of others
+    pub repeated: usize,
+    /// Deliveries dropped as they'd been delivered already
+    pub delivered: usize,
+    /// Deliveries dropped as their objects have been deleted since
+    pub deleted: usize,
+    /// Repos committed to again for a relay which was behind on them
+    pub resynced: usize,
+    /// Relays which couldn't be checked
+    pub unverified: Vec<String>,
+}
+
+impl Recovered {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("repeated", Value::from(self.repeated)),
+            ("delivered", Value::from(self.delivered)),
+            ("deleted", Value::from(self.deleted)),
+            ("resynced", Value::from(self.resynced)),
+            ("unverified", Value::from(self.unverified.clone())),
+        ])
+    }
+}
+
+/// Mark `dir` as a running shard's, returning whether the last to run stopped without
+/// clearing its mark
+pub fn begin(dir: &StateDir) -> io::Result<bool> {
+    let unclean = dir.read(RUNNING_FILE)?.is_some();
+    dir.write(RUNNING_FILE, b"")?;
+    Ok(unclean)
+}
+
+/// Clear the mark [`begin`] left in `dir`
+pub fn finish(dir: &StateDir) -> io::Result<()> {
+    dir.remove(RUNNING_FILE)
+}
+
+/// The type and object ID of the activity `delivery` sends
+fn activity(delivery: &Delivery) -> Option<(String, Option<String>)> {
+    let activity = json::parse(&delivery.activity).ok()?;
+    let kind = activity.get("type")?.as_str()?.to_string();
+    let object = activity.get("object").and_then(|object| {
+        let id = object.as_str().or_else(|| object.get("id")?.as_str());
+        id.map(str::to_string)
+    });
+    Some((kind, object))
+}
+
+/// Whether `relay` has an older revision of the repo `head` is of, or `None` if it couldn't
+/// be asked
+fn relay_behind(bridge: &Bridge, relay: &str, head: &Head) -> Option<bool> {
+    let relay = relay.trim_end_matches('/');
+    let url = format!("{relay}/xrpc/{GET_LATEST_COMMIT}?did={}", head.did);
+    let response = bridge.transport.send(&OutboundRequest::get(url)).ok()?;
+    // One which doesn't know the repo at all is left to crawl it
+    if matches!(response.status, 400 | 404) {
+        return Some(false);
+    }
+    if !response.is_success() {
+        return None;
+    }
+    let latest = json::parse(&String::from_utf8_lossy(&response.body)).ok()?;
+    let rev = latest.get("rev")?.as_str()?;
+    Some(rev < head.rev.as_str())
+}
+
+/// Repair what an unclean shutdown left of `bridge`'s jobs, and of the relays' copies of the
+/// repos its shard owns
+pub fn recover(bridge: &Bridge) -> io::Result<Recovered> {
+    let mut recovered = Recovered {
+        repeated: bridge.jobs.dedup()?,
+        ..Recovered::default()
+    };
+    let delivered = bridge.receipts.delivered();
+    recovered.delivered = bridge.jobs.cancel(|job| match job {
+        Job::Deliver(d) => {
+            let creates = activity(d).is_some_and(|(kind, _)| kind == "Create");
+            let post = d.post().unwrap_or_default();
+            creates && delivered.contains(&(post, d.inbox.clone()))
+        }
+        _ => false,
+    })?;
+    recovered.deleted = bridge.jobs.cancel(|job| {
+        let Job::Deliver(d) = job else {
+            return false;
+        };
+        let Some((kind, Some(object))) = activity(d) else {
+            return false;
+        };
+        let deleted = bridge.published.get(&object).is_some_and(|p| p.deleted);
+        matches!(kind.as_str(), "Create" | "Update") && deleted
+    })?;
+
+    let mut heads = bridge.repos.heads(None, usize::MAX);
+    heads.retain(|head| bridge.shard.owns(&head.did));
+    // Revisions are TIDs, which sort by when they were made
+    heads.sort_by(|a, b| b.rev.cmp(&a.rev));
+    heads.truncate(VERIFIED_REPOS);
+    let mut behind = Vec::new();
+    for relay in &bridge.crawl.relays {
+        for head in &heads {
+            match relay_behind(bridge, relay, head) {
+                Some(true) if !behind.contains(&head.did) => behind.push(head.did.clone()),
+                Some(_) => {}
+                None => {
+                    recovered.unverified.push(relay.clone());
+                    break;
+                }
+            }
+        }
+    }
+    for did in behind {
+        match bridge.commit(&did, &[]) {
+            Ok(_) => recovered.resynced += 1,
+            Err(e) => eprintln!("Couldn't commit to {did} for relays to resync: {e}"),
+        }
+    }
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crawl::CrawlConfig;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::receipts::{Outcome, Receipt};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::Write;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn what_a_crash_leaves_behind_is_repaired() {
+        let dir = crate::storage::tests::temp_state_dir();
+        assert!(!begin(&dir).unwrap());
+        assert!(begin(&dir).unwrap(), "the mark wasn't cleared");
+        finish(&dir).unwrap();
+        assert!(!begin(&dir).unwrap());
+
+        let mock = Arc::new(MockTransport::new());
+        let relay = "https://relay.example";
+        let bridge = Bridge::new()
+            .with_transport(mock.clone())
+            .with_repo_signer(Arc::new(HashSigner))
+            .with_crawl(CrawlConfig {
+                relays: vec![relay.to_string()],
+                ..CrawlConfig::default()
+            });
+        let did = did!("did:plc:alice");
+        let owner = KeyOwner::Account(did.clone());
+        let generator = FakeGenerator::default();
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let write = Write::Create {
+            path: "app.bsky.feed.post/3k".to_string(),
+            record: Value::object([("text", Value::from("hi"))]),
+        };
+        let head = bridge.commit(&did, &[write]).unwrap();
+        mock.respond_json(
+            &format!("{relay}/xrpc/{GET_LATEST_COMMIT}?did={did}"),
+            r#"{"cid": "bafy", "rev": "2222222222222"}"#,
+        );
+
+        let create = |id: &str, inbox: &str| {
+            let activity = format!(
+                r#"{{"type": "Create", "actor": "https://bridge.example/alice",
+                    "object": {{"id": "{id}"}}}}"#
+            );
+            Job::Deliver(Delivery::new(inbox, activity))
+        };
+        let sent = create("https://bridge.example/sent", "https://a.example/inbox");
+        let gone = create("https://bridge.example/gone", "https://a.example/inbox");
+        let pending = create("https://bridge.example/sent", "https://b.example/inbox");
+        for job in [&sent, &sent, &gone, &pending] {
+            bridge.jobs.push(job.clone()).unwrap();
+        }
+        let Job::Deliver(d) = &sent else {
+            unreachable!()
+        };
+        let receipt = Receipt::new(d, Outcome::Delivered).unwrap();
+        bridge.receipts.append(receipt).unwrap();
+        let tombstone = crate::published::Published {
+            document: Value::object([("type", Value::from("Note"))]),
+            origin: None,
+            deleted: true,
+        };
+        let gone_id = "https://bridge.example/gone";
+        bridge.published.publish(gone_id, tombstone).unwrap();
+
+        let recovered = recover(&bridge).unwrap();
+        assert_eq!(
+            recovered,
+            Recovered {
+                repeated: 1,
+                delivered: 1,
+                deleted: 1,
+                resynced: 1,
+                unverified: Vec::new(),
+            }
+        );
+        let queued = bridge.jobs.queued();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].job, pending);
+        assert_ne!(bridge.repos.head(&did).map(|h| h.rev), Some(head.rev));
+    }
+}
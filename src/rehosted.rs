@@ -0,0 +1,259 @@
+//! Serving re-hosted media
+//!
+//! Files the bridge re-hosts in its [`MediaStore`](crate::retention::MediaStore) are served at
+//! `/media/{did}/{name}` ([`media_url`]). Mastodon's apps, and browsers, play video by asking
+//! for it a range at a time, and go back for files they've cached only to check they haven't
+//! changed, so:
+//!
+//! - a `Range` of bytes is answered with `206 Partial Content` and just those bytes, read
+//!   from disk as they're sent, and `If-Range` falls back to the whole file if it has changed
+//! - `If-None-Match` with a file's `ETag` is answered with `304 Not Modified`
+//! - `HEAD` says how long the file is without sending it
+//!
+//! Files are named for what they hold and aren't rewritten, so the `ETag` is derived from
+//! the name and size rather than read from the file. With a CDN in front of the media
+//! (`FEDIBRIDGE_MEDIA_CDN_URL`), requests are redirected there instead, to
+//! `{cdn}/{did}/{name}`; the CDN fetches what it doesn't have yet from `/media/origin/...`,
+//! which is served the same but never redirected
+
+use crate::bridge::Bridge;
+use crate::crypto::{hex_encode, sha256};
+use crate::http::{percent_encode, FileBody, Handler, Method, Request, Response};
+use crate::normalize;
+use std::sync::Arc;
+
+/// The path re-hosted media is served under
+pub const MEDIA_PATH: &str = "/media";
+/// Where the CDN fetches media from, without being redirected back to itself
+const ORIGIN_SEGMENT: &str = "origin";
+
+/// The URL `owner`'s re-hosted file `name` is served at, on the bridge at `hostname`
+pub fn media_url(hostname: &str, owner: &atproto::DID::Did, name: &str) -> String {
+    format!(
+        "https://{hostname}{MEDIA_PATH}/{}/{}",
+        percent_encode(owner.as_str()),
+        percent_encode(name)
+    )
+}
+
+/// The media type of a file, by its extension
+fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, e)| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mov") => "video/quicktime",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("m4a") => "audio/mp4",
+        Some("ogg" | "oga") => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// The bytes `range` asks for of a file `size` long, as the offset and length to send.
+/// `Ok(None)` is for ranges which can't be served as one, so are ignored, and `Err` for
+/// those none of which is in the file
+fn byte_range(range: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let number = |n: &str| n.trim().parse::<u64>().ok();
+    let (first, last) = match (start.trim().is_empty(), number(start), number(end)) {
+        // The last `n` bytes
+        (true, _, Some(n)) if n > 0 => (size.saturating_sub(n), size.saturating_sub(1)),
+        (true, _, _) => return Err(()),
+        (false, Some(first), None) if end.trim().is_empty() => (first, size.saturating_sub(1)),
+        (false, Some(first), Some(last)) if first <= last => (first, last.min(size - 1)),
+        _ => return Ok(None),
+    };
+    if size == 0 || first >= size {
+        return Err(());
+    }
+    Ok(Some((first, last - first + 1)))
+}
+
+/// Re-hosted media under [`MEDIA_PATH`], in front of `inner`
+pub struct MediaEndpoints<H> {
+    bridge: Arc<Bridge>,
+    /// Where requests are redirected to, if anywhere
+    cdn: Option<String>,
+    inner: H,
+}
+
+impl<H: Handler> MediaEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, inner: H) -> MediaEndpoints<H> {
+        MediaEndpoints {
+            bridge,
+            cdn: None,
+            inner,
+        }
+    }
+
+    /// Redirect requests to the CDN at `cdn`, if there is one
+    pub fn with_cdn(self, cdn: Option<String>) -> MediaEndpoints<H> {
+        MediaEndpoints { cdn, ..self }
+    }
+
+    fn serve(&self, request: &Request, did: &str, name: &str) -> Response {
+        let (Some(media), Ok(owner)) = (&self.bridge.media, normalize::parse_did(did)) else {
+            return Response::error(404, "Not found");
+        };
+        if name.is_empty() || name.starts_with('.') {
+            return Response::error(404, "Not found");
+        }
+        let (path, size) = match media.locate(&owner, name) {
+            Ok(Some(found)) => found,
+            Ok(None) => return Response::error(404, "Not found"),
+            Err(e) => return Response::error(500, e.to_string()),
+        };
+        let hash = sha256(format!("{name}\0{size}").as_bytes());
+        let etag = format!("\"{}\"", hex_encode(&hash[..8]));
+        let response = |status| {
+            Response::new(status)
+                .with_header("etag", &etag)
+                .with_header("accept-ranges", "bytes")
+                .with_header("cache-control", "public, max-age=86400")
+        };
+        let matches = |tags: &str| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag)
+        };
+        if request.header("if-none-match").is_some_and(matches) {
+            return response(304);
+        }
+        // A range of a file since changed would be spliced into the wrong file
+        let unchanged = request
+            .header("if-range")
+            .is_none_or(|tag| tag.trim() == etag);
+        let range = match request.header("range").filter(|_| unchanged) {
+            Some(range) => byte_range(range, size),
+            None => Ok(None),
+        };
+        let (status, offset, length) = match range {
+            Ok(Some((offset, length))) => (206, offset, length),
+            Ok(None) => (200, 0, size),
+            Err(()) => {
+                return response(416).with_header("content-range", &format!("bytes */{size}"));
+            }
+        };
+        let mut response = response(status).with_header("content-type", content_type(name));
+        if status == 206 {
+            let last = offset + length - 1;
+            let content_range = format!("bytes {offset}-{last}/{size}");
+            response = response.with_header("content-range", &content_range);
+        }
+        if request.method == Method::Head {
+            return response.with_header("content-length", &length.to_string());
+        }
+        response.with_file(FileBody {
+            path,
+            offset,
+            length,
+        })
+    }
+}
+
+impl<H: Handler> Handler for MediaEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let segments = request.segments();
+        let (Method::Get | Method::Head, ["media", rest @ ..]) = (request.method, &segments[..])
+        else {
+            return self.inner.handle(request);
+        };
+        match (rest, &self.cdn) {
+            ([ORIGIN_SEGMENT, did, name], _) => self.serve(request, did, name),
+            ([did, name], Some(cdn)) => {
+                let location = format!(
+                    "{}/{}/{}",
+                    cdn.trim_end_matches('/'),
+                    percent_encode(did),
+                    percent_encode(name)
+                );
+                Response::new(302).with_header("location", &location)
+            }
+            ([did, name], None) => self.serve(request, did, name),
+            _ => Response::error(404, "Not found"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retention::{MediaStore, RetentionConfig};
+    use crate::storage::tests::temp_state_dir;
+    use atproto::did;
+
+    #[test]
+    fn serves_ranges_and_revalidations() {
+        let media = MediaStore::open(&temp_state_dir()).unwrap();
+        let alice = did!("did:plc:alice");
+        media.put(&alice, "v.mp4", b"0123456789").unwrap();
+        let bridge = Bridge::new().with_media_store(media, RetentionConfig::default());
+        let bridge = Arc::new(bridge);
+        let endpoints = MediaEndpoints::new(bridge.clone(), |_: &Request| Response::new(404));
+        let url = media_url("bridge.example", &alice, "v.mp4");
+        let path = url.strip_prefix("https://bridge.example").unwrap();
+        let get = |range: Option<&str>| {
+            let request = Request::new(Method::Get, path);
+            let request = match range {
+                Some(range) => request.with_header("range", range),
+                None => request,
+            };
+            endpoints.handle(&request)
+        };
+        let sent = |response: &Response| {
+            let mut written = Vec::new();
+            response.write_to(&mut written).unwrap();
+            let body = written.split(|&b| b == b'\n').next_back().unwrap().to_vec();
+            String::from_utf8(body).unwrap()
+        };
+
+        let whole = get(None);
+        assert_eq!(whole.status, 200);
+        assert_eq!(whole.header("content-type"), Some("video/mp4"));
+        assert_eq!(sent(&whole), "0123456789");
+        let part = get(Some("bytes=2-4"));
+        assert_eq!(part.status, 206);
+        assert_eq!(part.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(sent(&part), "234");
+        assert_eq!(sent(&get(Some("bytes=-3"))), "789");
+        assert_eq!(sent(&get(Some("bytes=8-"))), "89");
+        assert_eq!(get(Some("bytes=10-")).status, 416);
+        // Several ranges at once are served as the whole file
+        assert_eq!(get(Some("bytes=0-1,4-5")).status, 200);
+
+        let etag = whole.header("etag").unwrap();
+        let revalidate = Request::new(Method::Get, path).with_header("if-none-match", etag);
+        assert_eq!(endpoints.handle(&revalidate).status, 304);
+        let stale = Request::new(Method::Get, path)
+            .with_header("range", "bytes=0-1")
+            .with_header("if-range", "\"other\"");
+        assert_eq!(endpoints.handle(&stale).status, 200);
+        let head = endpoints.handle(&Request::new(Method::Head, path));
+        assert!(sent(&head).is_empty());
+        assert_eq!(head.header("content-length"), Some("10"));
+
+        let cdn = Some("https://cdn.example/".to_string());
+        let endpoints = MediaEndpoints::new(bridge, |_: &Request| Response::new(404)).with_cdn(cdn);
+        let redirected = endpoints.handle(&Request::new(Method::Get, path));
+        assert_eq!(redirected.status, 302);
+        let location = "https://cdn.example/did%3Aplc%3Aalice/v.mp4";
+        assert_eq!(redirected.header("location"), Some(location));
+        let origin = path.replacen("/media/", "/media/origin/", 1);
+        let pulled = endpoints.handle(&Request::new(Method::Get, &origin));
+        assert_eq!(pulled.status, 200);
+    }
+}
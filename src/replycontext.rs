@@ -0,0 +1,323 @@
+//! Context for replies to posts which weren't bridged
+//!
+//! A reply to someone who isn't bridged arrives on the other network replying to nothing
+//! there, and on its own can be hard to follow. `FEDIBRIDGE_REPLY_CONTEXT` has what's said of
+//! the post it replies to ([`ReplyContext`]):
+//!
+//! - `off`, the default: nothing
+//! - `link`: who it replies to, linking to their post
+//! - `quote`: that, and the start of their post quoted
+//!
+//! The post replied to has to be public, and is only quoted if its author hasn't asked not to
+//! be seen beyond their own network: on Bluesky with the `!no-unauthenticated` label, on the
+//! fediverse with `#nobridge` or `#nobot` in their profile, or by having their account paused
+//! on the bridge. What can't be checked isn't quoted. Onto the fediverse it's a paragraph
+//! ahead of the content ([`fediverse_header`]), and onto Bluesky a line ahead of the text
+//! ([`bluesky_header`]), the quote cut short to leave the reply its room
+
+use crate::appview::AppView;
+use crate::article::{trim, MAX_POST_LENGTH};
+use crate::audience::{visibility, Visibility};
+use crate::bridge::Bridge;
+use crate::html::escape;
+use crate::json::{id_of, Value};
+use crate::mentions::BLUESKY_PROFILE;
+use crate::parents;
+use crate::richtext::{self, RichText};
+use crate::store::{Mapping, MappingStatus};
+use crate::url::Url;
+
+/// The most characters of a post quoted
+pub const QUOTE_LENGTH: usize = 140;
+/// The class of the paragraph put ahead of fediverse replies
+const CONTEXT_CLASS: &str = "bridged-reply-context";
+/// Hashtags fediverse accounts use to ask not to be bridged
+const OPT_OUT_TAGS: [&str; 2] = ["#nobridge", "#nobot"];
+/// The label Bluesky accounts use to ask not to be shown to logged-out viewers
+const NO_UNAUTHENTICATED: &str = "!no-unauthenticated";
+/// What separates the context from the reply on Bluesky
+const SEPARATOR: &str = "\n\n";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What's said of an unbridged post a bridged reply replies to
+pub enum ReplyContext {
+    #[default]
+    Off,
+    Link,
+    Quote,
+}
+
+impl ReplyContext {
+    pub const ALL: [ReplyContext; 3] = [ReplyContext::Off, ReplyContext::Link, ReplyContext::Quote];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplyContext::Off => "off",
+            ReplyContext::Link => "link",
+            ReplyContext::Quote => "quote",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<ReplyContext> {
+        ReplyContext::ALL
+            .into_iter()
+            .find(|context| context.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The post a reply replies to, as it's shown with it
+pub struct Parent {
+    /// Where it can be seen
+    pub url: String,
+    /// Who wrote it, as they're known on their own network
+    pub author: String,
+    /// The start of it, if it can be quoted
+    pub quote: Option<String>,
+}
+
+/// Whether the author of `mapping`'s account asked for it not to be bridged
+fn paused(mapping: Option<&Mapping>) -> bool {
+    mapping.is_some_and(|m| !matches!(m.status, MappingStatus::Active | MappingStatus::Passive))
+}
+
+/// The start of `text`, if `bridge` quotes posts and their author allows it
+fn quoted(bridge: &Bridge, text: &str, allowed: bool) -> Option<String> {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let quote = bridge.reply_context == ReplyContext::Quote && allowed && !text.is_empty();
+    quote.then(|| trim(&text, QUOTE_LENGTH))
+}
+
+/// The post the Bluesky post `record` replies to, unless its author is bridged
+pub fn bluesky_parent(bridge: &Bridge, record: &Value) -> Option<Parent> {
+    if bridge.reply_context == ReplyContext::Off {
+        return None;
+    }
+    let uri = record.get("reply")?.get("parent")?.get("uri")?.as_str()?;
+    let post = AppView::of(bridge).post(uri).ok()??;
+    let mapping = bridge.identities.get(&post.author.did);
+    if mapping
+        .as_ref()
+        .is_some_and(|m| m.status == MappingStatus::Active)
+    {
+        return None;
+    }
+    let rkey = uri.rsplit('/').next()?;
+    let text = post.record.get("text").and_then(Value::as_str);
+    let allowed = !post.author.is_labeled(NO_UNAUTHENTICATED) && !paused(mapping.as_ref());
+    Some(Parent {
+        url: format!("{BLUESKY_PROFILE}/{}/post/{rkey}", post.author.did),
+        author: format!("@{}", post.author.handle),
+        quote: quoted(bridge, text.unwrap_or_default(), allowed),
+    })
+}
+
+/// The post the fediverse post `object` replies to, unless its author is bridged. It has to
+/// be public
+pub fn fediverse_parent(bridge: &Bridge, object: &Value) -> Option<Parent> {
+    if bridge.reply_context == ReplyContext::Off {
+        return None;
+    }
+    let parent = id_of(object.get("inReplyTo"))?;
+    let fetched = parents::fetch(bridge, parent).ok()?;
+    if visibility(&fetched) != Visibility::Public {
+        return None;
+    }
+    let author = match fetched.get("attributedTo")? {
+        Value::Array(actors) => id_of(actors.first())?,
+        actor => id_of(Some(actor))?,
+    };
+    let mapping = bridge.identities.get_by_actor(author);
+    if mapping
+        .as_ref()
+        .is_some_and(|m| m.status == MappingStatus::Active)
+    {
+        return None;
+    }
+    let actor = parents::fetch(bridge, author).ok();
+    let summary = actor.as_ref().and_then(|a| a.get("summary")?.as_str());
+    let opted_out = summary.is_some_and(|summary| {
+        let summary = summary.to_lowercase();
+        OPT_OUT_TAGS.iter().any(|tag| summary.contains(tag))
+    });
+    let allowed = actor.is_some() && !opted_out && !paused(mapping.as_ref());
+    let username = actor
+        .as_ref()
+        .and_then(|a| a.get("preferredUsername")?.as_str());
+    let host = Url::parse(author).ok().map(|url| url.host);
+    let name = match (username, host) {
+        (Some(username), Some(host)) => format!("@{username}@{host}"),
+        _ => author.to_string(),
+    };
+    let url = fetched.get("url").and_then(Value::as_str).unwrap_or(parent);
+    let content = fetched.get("content").and_then(Value::as_str);
+    let text = richtext::from_html(content.unwrap_or_default()).text;
+    Some(Parent {
+        url: url.to_string(),
+        author: name,
+        quote: quoted(bridge, &text, allowed),
+    })
+}
+
+/// The paragraph put ahead of a fediverse reply to `parent`
+pub fn fediverse_header(parent: &Parent) -> String {
+    let link = format!(
+        "<a href=\"{}\">{}</a>",
+        escape(&parent.url),
+        escape(&parent.author)
+    );
+    let lead = format!("<p class=\"{CONTEXT_CLASS}\">Replying to {link}");
+    match &parent.quote {
+        Some(quote) => {
+            let quote = format!(
+                "<blockquote class=\"{CONTEXT_CLASS}\"><p>{}</p>",
+                escape(quote)
+            );
+            format!("{lead}:</p>{quote}</blockquote>")
+        }
+        None => format!("{lead}</p>"),
+    }
+}
+
+/// `rich` after a line saying it replies to `parent`, within [`MAX_POST_LENGTH`] graphemes.
+/// The quote's cut short, or left off, to make room, and `rich` is returned as it was if not
+/// even the link fits
+pub fn bluesky_header(rich: &RichText, parent: &Parent) -> RichText {
+    let length = |text: &str| text.chars().count();
+    let lead = "Replying to ";
+    let fixed = length(lead) + length(&parent.author) + length(SEPARATOR) + length(&rich.text);
+    let Some(room) = MAX_POST_LENGTH.checked_sub(fixed) else {
+        return rich.clone();
+    };
+    let mut header = RichText::new();
+    header.push_str(lead);
+    header.push_link(&parent.author, &parent.url);
+    // The colon and quotation marks are three more
+    let room = room.saturating_sub(3);
+    if let Some(quote) = parent.quote.as_deref().filter(|_| room >= QUOTE_LENGTH / 4) {
+        header.push_str(&format!(": “{}”", trim(quote, room)));
+    }
+    header.push_str(SEPARATOR);
+    let offset = header.text.len();
+    header.text.push_str(&rich.text);
+    header
+        .facets
+        .extend(rich.facets.iter().cloned().map(|mut facet| {
+            facet.byte_start += offset;
+            facet.byte_end += offset;
+            facet
+        }));
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appview::GET_POST_THREAD;
+    use crate::http::percent_encode;
+    use crate::json;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const PARENT: &str = "https://b.example/notes/1";
+    const BOB: &str = "https://b.example/users/bob";
+    const POST: &str = "at://did:plc:carol/app.bsky.feed.post/3k";
+
+    fn bridge(mock: &Arc<MockTransport>, context: ReplyContext) -> Bridge {
+        Bridge::new()
+            .with_transport(mock.clone())
+            .with_reply_context(context)
+    }
+
+    fn bob(mock: &MockTransport, summary: &str) {
+        mock.respond_json(
+            PARENT,
+            &format!(
+                r#"{{"id": "{PARENT}", "type": "Note", "attributedTo": "{BOB}",
+                    "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                    "content": "<p>Which   way to the station?</p>"}}"#
+            ),
+        );
+        mock.respond_json(
+            BOB,
+            &format!(r#"{{"id": "{BOB}", "preferredUsername": "bob", "summary": "{summary}"}}"#),
+        );
+    }
+
+    #[test]
+    fn replies_to_unbridged_posts_say_what_they_reply_to() {
+        let reply = json::parse(&format!(r#"{{"inReplyTo": "{PARENT}"}}"#)).unwrap();
+        let mock = Arc::new(MockTransport::new());
+        bob(&mock, "<p>Trains</p>");
+        assert_eq!(
+            fediverse_parent(&bridge(&mock, ReplyContext::Off), &reply),
+            None
+        );
+        let parent = fediverse_parent(&bridge(&mock, ReplyContext::Quote), &reply).unwrap();
+        assert_eq!(parent.author, "@bob@b.example");
+        assert_eq!(parent.quote.as_deref(), Some("Which way to the station?"));
+        assert!(fediverse_header(&parent).contains("<blockquote"));
+        let parent_link = fediverse_parent(&bridge(&mock, ReplyContext::Link), &reply).unwrap();
+        assert_eq!(parent_link.quote, None);
+        let link = format!(r#"<a href="{PARENT}">@bob@b.example</a>"#);
+        assert_eq!(
+            fediverse_header(&parent_link),
+            format!(r#"<p class="{CONTEXT_CLASS}">Replying to {link}</p>"#)
+        );
+
+        // Those who'd rather not be bridged are linked to, not quoted
+        let opted_out = Arc::new(MockTransport::new());
+        bob(&opted_out, "<p>Trains #NoBridge</p>");
+        let parent = fediverse_parent(&bridge(&opted_out, ReplyContext::Quote), &reply);
+        assert_eq!(parent.unwrap().quote, None);
+
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            &format!(
+                "https://public.api.bsky.app/xrpc/{GET_POST_THREAD}?uri={}&depth=0&parentHeight=0",
+                percent_encode(POST)
+            ),
+            &format!(
+                r#"{{"thread": {{"$type": "app.bsky.feed.defs#threadViewPost", "post": {{
+                    "uri": "{POST}", "cid": "bafy",
+                    "author": {{"did": "did:plc:carol", "handle": "carol.bsky.social",
+                        "labels": [{{"val": "!no-unauthenticated"}}]}},
+                    "record": {{"text": "Not for the fediverse"}}}}}}}}"#
+            ),
+        );
+        let record = json::parse(&format!(
+            r#"{{"reply": {{"parent": {{"uri": "{POST}"}}}}}}"#
+        ));
+        let parent = bluesky_parent(&bridge(&mock, ReplyContext::Quote), &record.unwrap());
+        let parent = parent.unwrap();
+        assert_eq!(parent.quote, None);
+        assert_eq!(
+            parent.url,
+            format!("{BLUESKY_PROFILE}/{}/post/3k", did!("did:plc:carol"))
+        );
+
+        // On Bluesky the quote gives way to the reply
+        let parent = Parent {
+            url: PARENT.to_string(),
+            author: "@bob@b.example".to_string(),
+            quote: Some("Which way to the station".repeat(5)),
+        };
+        let mut rich = RichText::new();
+        rich.push_link("a map", "https://maps.example/");
+        rich.push_str(&" left".repeat(40));
+        let replied = bluesky_header(&rich, &parent);
+        assert!(replied.text.chars().count() <= MAX_POST_LENGTH);
+        assert!(replied
+            .text
+            .starts_with("Replying to @bob@b.example: “Which"));
+        assert_eq!(replied.facets.len(), 2);
+        let map = &replied.facets[1];
+        assert_eq!(&replied.text[map.byte_start..map.byte_end], "a map");
+        rich.push_str(&" left".repeat(10));
+        let replied = bluesky_header(&rich, &parent);
+        assert!(replied
+            .text
+            .starts_with("Replying to @bob@b.example\n\na map"));
+    }
+}
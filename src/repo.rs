@@ -0,0 +1,844 @@
+//! The atproto repos the bridge hosts for bridged fediverse accounts
+//!
+//! Each account's records are kept in a Merkle Search Tree under a signed commit, so that
+//! relays can crawl them through the [sync endpoints](crate::sync) like any other PDS's.
+//! Every commit is sequenced, and the latest [`BACKLOG_LEN`] are kept as `#commit` event
+//! frames for subscribers to catch up from.
+//!
+//! Repos are persisted as their records with each one's latest revision and signature. The
+//! tree and commit blocks are rebuilt from those when they're loaded, which gives the same
+//! CIDs as the encoding is deterministic. Commits are signed with the account's
+//! [repo signing key](crate::keys::KeyPurpose::RepoSigning), by a [`RepoSigner`]
+//!
+//! Writes are made from what was read of a repo a moment before, and something else may have
+//! written to it in between. So, as `swapRecord` and `swapCommit` do for a PDS, updates and
+//! deletes can name the CID the record has to still have, and commits the one the repo has
+//! to still be at, and are refused as a conflict otherwise. [`Bridge::update_repo`] reads
+//! again and retries
+//!
+//! [`Bridge::update_repo`]: crate::bridge::Bridge::update_repo
+
+use crate::car::{self, Cid};
+use crate::crypto::{hex_decode, hex_encode, sha256};
+use crate::json::{self, Value};
+use crate::keys::KeyPair;
+use crate::quota::Throttled;
+use crate::storage::StateDir;
+use crate::time::format_rfc3339;
+use atproto::DID::Did;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// The repos' file, at the root of the state directory as every shard shares them
+pub const REPOS_FILE: &str = "repos.json";
+/// How many commit events are kept for subscribers to replay
+pub const BACKLOG_LEN: usize = 10_000;
+/// An event's blocks are left out, and it's marked `tooBig`, beyond this many bytes
+const MAX_EVENT_BLOCKS: usize = 1_000_000;
+const COMMIT_VERSION: u64 = 3;
+/// The alphabet TIDs are written in, which sorts the same as the numbers they encode
+const TID_ALPHABET: &[u8; 32] = b"234567abcdefghijklmnopqrstuvwxyz";
+
+/// Signs repo commits
+pub trait RepoSigner: Send + Sync {
+    /// Sign `bytes`, an encoded unsigned commit, with `key`. The signature is the compact
+    /// form, `r` then `s` with a low `s`
+    fn sign(&self, key: &KeyPair, bytes: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+#[derive(Debug, Error)]
+/// Errors writing to a repo
+pub enum RepoError {
+    #[error("Invalid record path {path}")]
+    InvalidPath { path: String },
+    #[error("A record already exists at {path}")]
+    AlreadyExists { path: String },
+    #[error("No record exists at {path}")]
+    NotFound { path: String },
+    #[error("The record at {path} has changed since it was read")]
+    SwapRecord { path: String },
+    #[error("{did}'s repo has changed since it was read")]
+    SwapCommit { did: Did },
+    #[error("{did} has no repo signing key")]
+    NoKey { did: Did },
+    #[error("{did} is suspended, and its repo frozen")]
+    Suspended { did: Did },
+    #[error("{did} hasn't agreed to the current terms")]
+    NotConsented { did: Did },
+    #[error("{path} isn't mirrored, as the bridge only mirrors posts")]
+    MirrorOnly { path: String },
+    #[error(transparent)]
+    Throttled(#[from] Throttled),
+    #[error("No repo signer is configured")]
+    NoSigner,
+    #[error("Couldn't sign the commit: {0:#}")]
+    Signing(anyhow::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One change to a repo
+pub enum Write {
+    Create {
+        path: String,
+        record: Value,
+    },
+    /// `swap` is the CID the record has to have until now, if it matters
+    Update {
+        path: String,
+        record: Value,
+        swap: Option<Cid>,
+    },
+    Delete {
+        path: String,
+        swap: Option<Cid>,
+    },
+}
+
+impl Write {
+    pub fn path(&self) -> &str {
+        match self {
+            Write::Create { path, .. }
+            | Write::Update { path, .. }
+            | Write::Delete { path, .. } => path,
+        }
+    }
+
+    pub fn action(&self) -> &'static str {
+        match self {
+            Write::Create { .. } => "create",
+            Write::Update { .. } => "update",
+            Write::Delete { .. } => "delete",
+        }
+    }
+}
+
+/// The CID of `record`'s block
+pub fn record_cid(record: &Value) -> Cid {
+    Cid::for_dag_cbor(&car::encode_dag_cbor(record))
+}
+
+/// Whether `path` is a `collection/record-key` path
+fn valid_path(path: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || ".-_:~".contains(c);
+    match path.split_once('/') {
+        Some((collection, key)) => {
+            collection.contains('.')
+                && !key.is_empty()
+                && key != "."
+                && key != ".."
+                && collection
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ".-".contains(c))
+                && key.chars().all(allowed)
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A repo's latest commit
+pub struct Head {
+    pub did: Did,
+    pub cid: Cid,
+    pub rev: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A sequenced commit, as the frame subscribers are sent
+pub struct Event {
+    pub seq: i64,
+    pub frame: Arc<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a subscriber resuming after some event is sent
+pub enum Replay {
+    /// Every event since
+    Events(Vec<Event>),
+    /// Some events since have already left the backlog. These are the rest
+    Outdated(Vec<Event>),
+    /// The cursor is ahead of every event so far
+    Future,
+}
+
+#[derive(Debug, Clone)]
+struct Repo {
+    records: BTreeMap<String, Value>,
+    rev: String,
+    sig: Vec<u8>,
+    commit: Cid,
+}
+
+/// The blocks of a repo: its commit, its tree's nodes and its records
+struct Blocks {
+    commit: Cid,
+    commit_block: Vec<u8>,
+    nodes: Vec<Vec<u8>>,
+    records: Vec<Vec<u8>>,
+}
+
+/// A key's layer in the tree: the leading zero bits of its hash, counted in pairs
+fn layer(key: &str) -> u32 {
+    let hash = sha256(key.as_bytes());
+    let zeros = hash
+        .iter()
+        .position(|&b| b != 0)
+        .map_or(256, |i| i as u32 * 8 + hash[i].leading_zeros());
+    zeros / 2
+}
+
+fn encode_text(out: &mut Vec<u8>, text: &str) {
+    car::encode_into(out, &Value::from(text));
+}
+
+fn encode_optional_link(out: &mut Vec<u8>, cid: Option<&Cid>) {
+    match cid {
+        Some(cid) => car::encode_link(out, cid),
+        None => car::encode_into(out, &Value::Null),
+    }
+}
+
+/// Encode the tree node holding `items` at `layer`, and every node below it, returning the
+/// node's CID. `items` are sorted by key
+fn tree_node(items: &[(&str, Cid, u32)], layer: u32, nodes: &mut Vec<Vec<u8>>) -> Option<Cid> {
+    if items.is_empty() {
+        return None;
+    }
+    let below = layer.saturating_sub(1);
+    let here: Vec<usize> = (0..items.len()).filter(|&i| items[i].2 == layer).collect();
+    let left = tree_node(
+        &items[..here.first().copied().unwrap_or(items.len())],
+        below,
+        nodes,
+    );
+    let mut node = Vec::new();
+    car::head(&mut node, 5, 2);
+    encode_text(&mut node, "e");
+    car::head(&mut node, 4, here.len() as u64);
+    let mut previous: &str = "";
+    for (n, &i) in here.iter().enumerate() {
+        let end = here.get(n + 1).copied().unwrap_or(items.len());
+        let right = tree_node(&items[i + 1..end], below, nodes);
+        let (key, value, _) = items[i];
+        // Each key is written as what it shares with the one before, and the rest
+        let shared = key
+            .bytes()
+            .zip(previous.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        car::head(&mut node, 5, 4);
+        encode_text(&mut node, "k");
+        car::head(&mut node, 2, (key.len() - shared) as u64);
+        node.extend_from_slice(&key.as_bytes()[shared..]);
+        encode_text(&mut node, "p");
+        car::encode_into(&mut node, &Value::from(shared));
+        encode_text(&mut node, "t");
+        encode_optional_link(&mut node, right.as_ref());
+        encode_text(&mut node, "v");
+        car::encode_link(&mut node, &value);
+        previous = key;
+    }
+    encode_text(&mut node, "l");
+    encode_optional_link(&mut node, left.as_ref());
+    let cid = Cid::for_dag_cbor(&node);
+    nodes.push(node);
+    Some(cid)
+}
+
+/// Encode a commit, signed if `sig` is given
+fn encode_commit(did: &Did, data: &Cid, rev: &str, sig: Option<&[u8]>) -> Vec<u8> {
+    let mut commit = Vec::new();
+    car::head(&mut commit, 5, 5 + sig.is_some() as u64);
+    encode_text(&mut commit, "did");
+    encode_text(&mut commit, did.as_str());
+    encode_text(&mut commit, "rev");
+    encode_text(&mut commit, rev);
+    if let Some(sig) = sig {
+        encode_text(&mut commit, "sig");
+        car::head(&mut commit, 2, sig.len() as u64);
+        commit.extend_from_slice(sig);
+    }
+    encode_text(&mut commit, "data");
+    car::encode_link(&mut commit, data);
+    encode_text(&mut commit, "prev");
+    car::encode_into(&mut commit, &Value::Null);
+    encode_text(&mut commit, "version");
+    car::encode_into(&mut commit, &Value::from(COMMIT_VERSION));
+    commit
+}
+
+/// The root of the tree of `records`, with its nodes and the records' blocks
+fn tree(records: &BTreeMap<String, Value>) -> (Cid, Vec<Vec<u8>>, Vec<Vec<u8>>) {
+    let blocks: Vec<Vec<u8>> = records.values().map(car::encode_dag_cbor).collect();
+    let items: Vec<(&str, Cid, u32)> = records
+        .keys()
+        .zip(&blocks)
+        .map(|(key, block)| (key.as_str(), Cid::for_dag_cbor(block), layer(key)))
+        .collect();
+    let top = items.iter().map(|(_, _, layer)| *layer).max().unwrap_or(0);
+    let mut nodes = Vec::new();
+    let root = tree_node(&items, top, &mut nodes).unwrap_or_else(|| {
+        // An empty tree is a single node with no entries
+        let mut empty = Vec::new();
+        car::head(&mut empty, 5, 2);
+        encode_text(&mut empty, "e");
+        car::head(&mut empty, 4, 0);
+        encode_text(&mut empty, "l");
+        encode_optional_link(&mut empty, None);
+        let cid = Cid::for_dag_cbor(&empty);
+        nodes.push(empty);
+        cid
+    });
+    (root, nodes, blocks)
+}
+
+/// A TID for `time`, which sorts after `after` if it's given
+fn next_tid(time: SystemTime, after: Option<&str>) -> String {
+    let micros = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let after = after.and_then(parse_tid).map_or(0, |tid| (tid >> 10) + 1);
+    let tid = micros.max(after) << 10 & (u64::MAX >> 1);
+    (0..13)
+        .map(|i| TID_ALPHABET[(tid >> (60 - 5 * i)) as usize & 31] as char)
+        .collect()
+}
+
+fn parse_tid(tid: &str) -> Option<u64> {
+    tid.bytes().try_fold(0u64, |n, c| {
+        let digit = TID_ALPHABET.iter().position(|&a| a == c)?;
+        Some(n << 5 | digit as u64)
+    })
+}
+
+impl Repo {
+    fn blocks(&self, did: &Did) -> Blocks {
+        let (data, nodes, records) = tree(&self.records);
+        let commit_block = encode_commit(did, &data, &self.rev, Some(&self.sig));
+        Blocks {
+            commit: Cid::for_dag_cbor(&commit_block),
+            commit_block,
+            nodes,
+            records,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        Value::object([
+            ("rev", Value::from(self.rev.as_str())),
+            ("sig", Value::from(hex_encode(&self.sig))),
+            ("records", Value::Object(self.records.clone())),
+        ])
+    }
+
+    fn from_json(did: &Did, value: &Value) -> Option<Repo> {
+        let Some(Value::Object(records)) = value.get("records") else {
+            return None;
+        };
+        let mut repo = Repo {
+            records: records.clone(),
+            rev: value.get("rev")?.as_str()?.to_string(),
+            sig: hex_decode(value.get("sig")?.as_str()?)?,
+            commit: Cid::for_dag_cbor(&[]),
+        };
+        repo.commit = repo.blocks(did).commit;
+        Some(repo)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    repos: BTreeMap<Did, Repo>,
+    /// The latest event's sequence number
+    seq: i64,
+    backlog: VecDeque<Event>,
+}
+
+#[derive(Debug, Default)]
+/// Every repo the bridge hosts
+pub struct RepoStore {
+    inner: Mutex<Inner>,
+    /// Signalled as each event is sequenced
+    sequenced: Condvar,
+    dir: Option<StateDir>,
+}
+
+impl RepoStore {
+    /// The repos persisted in `dir`
+    pub fn open(dir: StateDir) -> io::Result<RepoStore> {
+        let mut inner = Inner::default();
+        if let Some(contents) = dir.read(REPOS_FILE)? {
+            let invalid = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+            let value = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| invalid(e.to_string()))?;
+            inner.seq = value.get("seq").and_then(Value::as_i64).unwrap_or_default();
+            if let Some(Value::Object(repos)) = value.get("repos") {
+                for (did, repo) in repos {
+                    let did = Did::try_create(did.clone()).map_err(|e| invalid(e.to_string()))?;
+                    let repo = Repo::from_json(&did, repo)
+                        .ok_or_else(|| invalid(format!("Malformed repo for {did}")))?;
+                    inner.repos.insert(did, repo);
+                }
+            }
+        }
+        Ok(RepoStore {
+            inner: Mutex::new(inner),
+            sequenced: Condvar::new(),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, inner: &Inner) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let repos = inner
+            .repos
+            .iter()
+            .map(|(did, repo)| (did.as_str().to_string(), repo.to_json()))
+            .collect();
+        let state = Value::object([
+            ("seq", Value::from(inner.seq)),
+            ("repos", Value::Object(repos)),
+        ]);
+        dir.write(REPOS_FILE, state.to_string().as_bytes())
+    }
+
+    /// Apply `writes` to `did`'s repo, creating it if need be, in a commit signed with `key`
+    pub fn commit(
+        &self,
+        did: &Did,
+        writes: &[Write],
+        key: &KeyPair,
+        signer: &dyn RepoSigner,
+        now: SystemTime,
+    ) -> Result<Head, RepoError> {
+        self.commit_swapped(did, writes, None, key, signer, now)
+    }
+
+    /// [Commit](RepoStore::commit) `writes`, if `did`'s repo is still at the commit `swap`
+    pub fn commit_swapped(
+        &self,
+        did: &Did,
+        writes: &[Write],
+        swap: Option<&Cid>,
+        key: &KeyPair,
+        signer: &dyn RepoSigner,
+        now: SystemTime,
+    ) -> Result<Head, RepoError> {
+        let mut inner = self.inner.lock().unwrap();
+        let previous = inner.repos.get(did);
+        if swap.is_some_and(|swap| previous.map(|repo| &repo.commit) != Some(swap)) {
+            return Err(RepoError::SwapCommit { did: did.clone() });
+        }
+        let old_nodes: HashSet<Cid> = previous
+            .map(|repo| tree(&repo.records).1)
+            .unwrap_or_default()
+            .iter()
+            .map(|node| Cid::for_dag_cbor(node))
+            .collect();
+        let since = previous.map(|repo| repo.rev.clone());
+        let mut records = previous
+            .map(|repo| repo.records.clone())
+            .unwrap_or_default();
+        for write in writes {
+            let path = write.path().to_string();
+            if !valid_path(&path) {
+                return Err(RepoError::InvalidPath { path });
+            }
+            if let Write::Update {
+                swap: Some(swap), ..
+            }
+            | Write::Delete {
+                swap: Some(swap), ..
+            } = write
+            {
+                if records.get(&path).map(record_cid).as_ref() != Some(swap) {
+                    return Err(RepoError::SwapRecord { path });
+                }
+            }
+            match write {
+                Write::Create { record, .. } if !records.contains_key(&path) => {
+                    records.insert(path, record.clone());
+                }
+                Write::Create { .. } => return Err(RepoError::AlreadyExists { path }),
+                Write::Update { record, .. } if records.contains_key(&path) => {
+                    records.insert(path, record.clone());
+                }
+                Write::Update { .. } | Write::Delete { .. } => {
+                    records.remove(&path).ok_or(RepoError::NotFound { path })?;
+                }
+            }
+        }
+
+        let rev = next_tid(now, since.as_deref());
+        let (data, nodes, _) = tree(&records);
+        let sig = signer
+            .sign(key, &encode_commit(did, &data, &rev, None))
+            .map_err(RepoError::Signing)?;
+        let commit_block = encode_commit(did, &data, &rev, Some(&sig));
+        let commit = Cid::for_dag_cbor(&commit_block);
+
+        // Subscribers are sent the commit, the nodes it added and the records written
+        let mut blocks = vec![commit_block];
+        blocks.extend(
+            nodes
+                .into_iter()
+                .filter(|node| !old_nodes.contains(&Cid::for_dag_cbor(node))),
+        );
+        let mut ops = Vec::new();
+        for write in writes {
+            let cid = match write {
+                Write::Create { record, .. } | Write::Update { record, .. } => {
+                    let block = car::encode_dag_cbor(record);
+                    let cid = Cid::for_dag_cbor(&block);
+                    blocks.push(block);
+                    Some(cid)
+                }
+                Write::Delete { .. } => None,
+            };
+            ops.push((write.action(), write.path(), cid));
+        }
+        let seq = inner.seq + 1;
+        let frame = commit_frame(CommitFrame {
+            seq,
+            did,
+            commit: &commit,
+            rev: &rev,
+            since: since.as_deref(),
+            car: car::write_car(&commit, &blocks),
+            ops: &ops,
+            time: now,
+        });
+
+        inner.repos.insert(
+            did.clone(),
+            Repo {
+                records,
+                rev: rev.clone(),
+                sig,
+                commit,
+            },
+        );
+        inner.seq = seq;
+        inner.backlog.push_back(Event {
+            seq,
+            frame: Arc::new(frame),
+        });
+        if inner.backlog.len() > BACKLOG_LEN {
+            inner.backlog.pop_front();
+        }
+        let saved = self.save(&inner);
+        drop(inner);
+        self.sequenced.notify_all();
+        saved?;
+        Ok(Head {
+            did: did.clone(),
+            cid: commit,
+            rev,
+        })
+    }
+
+    /// `did`'s latest commit, if the bridge hosts its repo
+    pub fn head(&self, did: &Did) -> Option<Head> {
+        let inner = self.inner.lock().unwrap();
+        let repo = inner.repos.get(did)?;
+        Some(Head {
+            did: did.clone(),
+            cid: repo.commit,
+            rev: repo.rev.clone(),
+        })
+    }
+
+    /// The latest commit of up to `limit` repos, in DID order, starting after `after`
+    pub fn heads(&self, after: Option<&Did>, limit: usize) -> Vec<Head> {
+        let inner = self.inner.lock().unwrap();
+        let repos = inner.repos.iter();
+        repos
+            .filter(|(did, _)| after.is_none_or(|after| *did > after))
+            .take(limit)
+            .map(|(did, repo)| Head {
+                did: did.clone(),
+                cid: repo.commit,
+                rev: repo.rev.clone(),
+            })
+            .collect()
+    }
+
+    /// The records in `collection` of `did`'s repo, as their record keys and values
+    pub fn records(&self, did: &Did, collection: &str) -> Vec<(String, Value)> {
+        let inner = self.inner.lock().unwrap();
+        let Some(repo) = inner.repos.get(did) else {
+            return Vec::new();
+        };
+        let prefix = format!("{collection}/");
+        let records = repo.records.range(prefix.clone()..);
+        records
+            .map_while(|(path, record)| Some((path.strip_prefix(&prefix)?, record)))
+            .map(|(rkey, record)| (rkey.to_string(), record.clone()))
+            .collect()
+    }
+
+    /// The whole of `did`'s repo as a CAR, rooted at its latest commit
+    pub fn export(&self, did: &Did) -> Option<Vec<u8>> {
+        let repo = self.inner.lock().unwrap().repos.get(did)?.clone();
+        let blocks = repo.blocks(did);
+        let mut all = vec![blocks.commit_block];
+        all.extend(blocks.nodes);
+        all.extend(blocks.records);
+        Some(car::write_car(&blocks.commit, &all))
+    }
+
+    /// The latest event's sequence number
+    pub fn seq(&self) -> i64 {
+        self.inner.lock().unwrap().seq
+    }
+
+    /// The events after `cursor`, for a subscriber resuming from it
+    pub fn replay(&self, cursor: i64) -> Replay {
+        let inner = self.inner.lock().unwrap();
+        if cursor > inner.seq {
+            return Replay::Future;
+        }
+        let events = inner.backlog.iter().filter(|event| event.seq > cursor);
+        let events: Vec<Event> = events.cloned().collect();
+        let oldest = inner
+            .backlog
+            .front()
+            .map_or(inner.seq + 1, |event| event.seq);
+        match cursor + 1 < oldest {
+            true => Replay::Outdated(events),
+            false => Replay::Events(events),
+        }
+    }
+
+    /// Events after `after`, waiting up to `timeout` for one if there aren't any yet
+    pub fn wait(&self, after: i64, timeout: Duration) -> Vec<Event> {
+        let inner = self.inner.lock().unwrap();
+        let (inner, _) = self
+            .sequenced
+            .wait_timeout_while(inner, timeout, |inner| inner.seq <= after)
+            .unwrap();
+        let events = inner.backlog.iter().filter(|event| event.seq > after);
+        events.cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().repos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+struct CommitFrame<'a> {
+    seq: i64,
+    did: &'a Did,
+    commit: &'a Cid,
+    rev: &'a str,
+    since: Option<&'a str>,
+    car: Vec<u8>,
+    ops: &'a [(&'static str, &'a str, Option<Cid>)],
+    time: SystemTime,
+}
+
+/// Encode a `#commit` event frame: its header, then the commit's details
+fn commit_frame(commit: CommitFrame) -> Vec<u8> {
+    let too_big = commit.car.len() > MAX_EVENT_BLOCKS;
+    let mut frame = car::encode_dag_cbor(&Value::object([
+        ("op", Value::from(1i64)),
+        ("t", Value::from("#commit")),
+    ]));
+    let out = &mut frame;
+    // Keys in DAG-CBOR order: shortest first, then bytewise
+    car::head(out, 5, 11);
+    encode_text(out, "ops");
+    car::head(out, 4, commit.ops.len() as u64);
+    for (action, path, cid) in commit.ops {
+        car::head(out, 5, 3);
+        encode_text(out, "cid");
+        encode_optional_link(out, cid.as_ref());
+        encode_text(out, "path");
+        encode_text(out, path);
+        encode_text(out, "action");
+        encode_text(out, action);
+    }
+    encode_text(out, "rev");
+    encode_text(out, commit.rev);
+    encode_text(out, "seq");
+    car::encode_into(out, &Value::from(commit.seq));
+    encode_text(out, "repo");
+    encode_text(out, commit.did.as_str());
+    encode_text(out, "time");
+    encode_text(out, &format_rfc3339(commit.time));
+    encode_text(out, "blobs");
+    car::head(out, 4, 0);
+    encode_text(out, "since");
+    car::encode_into(out, &Value::from(commit.since));
+    encode_text(out, "blocks");
+    let car: &[u8] = if too_big { &[] } else { &commit.car };
+    car::head(out, 2, car.len() as u64);
+    out.extend_from_slice(car);
+    encode_text(out, "commit");
+    car::encode_link(out, commit.commit);
+    encode_text(out, "rebase");
+    car::encode_into(out, &Value::Bool(false));
+    encode_text(out, "tooBig");
+    car::encode_into(out, &Value::Bool(too_big));
+    frame
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cbor::{self, Cbor};
+    use crate::keys::KeyAlgorithm;
+    use crate::storage::tests::temp_state_dir;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    /// "Signs" with a hash of the key and commit, which is enough to tell signatures apart
+    pub struct HashSigner;
+
+    impl RepoSigner for HashSigner {
+        fn sign(&self, key: &KeyPair, bytes: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(sha256(&[key.private_key.as_slice(), bytes].concat()).to_vec())
+        }
+    }
+
+    pub fn key() -> KeyPair {
+        KeyPair {
+            algorithm: KeyAlgorithm::Secp256k1,
+            private_key: vec![1; 32],
+            public_key: "zQ3sh".to_string(),
+        }
+    }
+
+    fn post(text: &str) -> Value {
+        Value::object([
+            ("$type", Value::from("app.bsky.feed.post")),
+            ("text", Value::from(text)),
+        ])
+    }
+
+    fn create(path: &str, text: &str) -> Write {
+        Write::Create {
+            path: path.to_string(),
+            record: post(text),
+        }
+    }
+
+    #[test]
+    fn tree_matches_the_reference_layering() {
+        // Examples from the atproto repo spec's MST test vectors
+        assert_eq!(layer("2653ae71"), 0);
+        assert_eq!(layer("blue"), 1);
+        assert_eq!(layer("app.bsky.feed.post/454397e440ec"), 4);
+        assert_eq!(layer("app.bsky.feed.post/9adeb165882c"), 8);
+
+        let empty = tree(&BTreeMap::new()).0;
+        assert_eq!(
+            empty.to_string(),
+            "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm"
+        );
+    }
+
+    #[test]
+    fn commits_are_sequenced_and_survive_reopening() {
+        let dir = temp_state_dir();
+        let store = RepoStore::open(dir.clone()).unwrap();
+        let now = SystemTime::now();
+        let first = store
+            .commit(
+                &ALICE,
+                &[create("app.bsky.feed.post/1", "hi")],
+                &key(),
+                &HashSigner,
+                now,
+            )
+            .unwrap();
+        let writes = [
+            create("app.bsky.feed.post/2", "again"),
+            Write::Delete {
+                path: "app.bsky.feed.post/1".to_string(),
+                swap: Some(record_cid(&post("hi"))),
+            },
+        ];
+        let second = store
+            .commit(&ALICE, &writes, &key(), &HashSigner, now)
+            .unwrap();
+        assert!(second.rev > first.rev);
+        assert!(matches!(
+            store.commit(
+                &ALICE,
+                &[create("app.bsky.feed.post/2", "dup")],
+                &key(),
+                &HashSigner,
+                now
+            ),
+            Err(RepoError::AlreadyExists { .. })
+        ));
+        assert!(matches!(
+            store.commit(&ALICE, &[create("post", "bad")], &key(), &HashSigner, now),
+            Err(RepoError::InvalidPath { .. })
+        ));
+        // Nor is anything written over what changed since it was read
+        let stale = Write::Update {
+            path: "app.bsky.feed.post/2".to_string(),
+            record: post("edited"),
+            swap: Some(record_cid(&post("hi"))),
+        };
+        assert!(matches!(
+            store.commit(&ALICE, &[stale], &key(), &HashSigner, now),
+            Err(RepoError::SwapRecord { .. })
+        ));
+        let behind = Some(&first.cid);
+        assert!(matches!(
+            store.commit_swapped(&ALICE, &[], behind, &key(), &HashSigner, now),
+            Err(RepoError::SwapCommit { .. })
+        ));
+
+        assert_eq!(store.seq(), 2);
+        let Replay::Events(events) = store.replay(1) else {
+            panic!("the backlog holds every event");
+        };
+        let (header, body) = cbor::decode(&events[0].frame).unwrap();
+        assert_eq!(header.get("t").and_then(|t| t.as_str()), Some("#commit"));
+        let (body, _) = cbor::decode(body).unwrap();
+        assert_eq!(
+            body.get("since").and_then(|s| s.as_str()),
+            Some(first.rev.as_str())
+        );
+        assert_eq!(
+            body.get("commit").and_then(|c| c.as_link()),
+            Some(second.cid)
+        );
+        let ops = body.get("ops").and_then(|ops| ops.as_array()).unwrap();
+        let actions: Vec<_> = ops
+            .iter()
+            .filter_map(|op| op.get("action")?.as_str())
+            .collect();
+        assert_eq!(actions, ["create", "delete"]);
+        let blocks = body.get("blocks").and_then(|b| b.as_bytes()).unwrap();
+        assert!(car::read_car(blocks).unwrap().find(&second.cid).is_some());
+        assert_eq!(store.replay(5), Replay::Future);
+
+        let reopened = RepoStore::open(dir).unwrap();
+        assert_eq!(reopened.head(&ALICE), Some(second.clone()));
+        assert_eq!(reopened.seq(), 2);
+        let exported = reopened.export(&ALICE).unwrap();
+        let blocks = car::read_car(&exported).unwrap();
+        // The commit, the tree's single node and the one record left
+        assert_eq!(blocks.clone().count(), 3);
+        let (commit, _) = cbor::decode(blocks.find(&second.cid).unwrap()).unwrap();
+        assert_eq!(commit.get("did"), Some(Cbor::Text("did:plc:alice")));
+    }
+}
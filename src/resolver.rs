@@ -0,0 +1,286 @@
+//! DID resolution
+//!
+//! DID documents are fetched from the PLC directory for `did:plc` and from the host's
+//! `did.json` for `did:web`, and go through the shared [`FetchCache`]. [`Resolver::resolve_many`]
+//! resolves a batch at once (e.g. every author on a page of the firehose) with a bounded number
+//! of requests in flight
+
+use crate::cache::{FetchCache, Fetched, ResourceKind, Validators};
+use crate::json::{self, Value};
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use atproto::DID::{Did, DidMethod};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use thiserror::Error;
+
+/// Where `did:plc` documents are fetched from by default
+pub const DEFAULT_PLC_DIRECTORY: &str = "https://plc.directory";
+/// Default number of fetches [`Resolver::resolve_many`] runs at once
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors resolving a DID
+pub enum ResolveError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("No DID document found for {did}")]
+    NotFound { did: String },
+    #[error("Fetching the DID document for {did} failed with status {status}")]
+    Rejected { did: String, status: u16 },
+    #[error("Invalid DID document for {did} - {reason}")]
+    InvalidDocument { did: String, reason: String },
+}
+
+#[derive(Clone)]
+/// Resolves DIDs to their documents
+pub struct Resolver {
+    transport: Arc<dyn HttpTransport>,
+    documents: Arc<FetchCache<Value>>,
+    plc_directory: String,
+    concurrency: usize,
+}
+
+impl Resolver {
+    pub fn new(transport: Arc<dyn HttpTransport>, documents: Arc<FetchCache<Value>>) -> Resolver {
+        Resolver {
+            transport,
+            documents,
+            plc_directory: DEFAULT_PLC_DIRECTORY.to_string(),
+            concurrency: DEFAULT_CONCURRENCY,
+        }
+    }
+
+    pub fn with_plc_directory(self, plc_directory: impl Into<String>) -> Resolver {
+        Resolver {
+            plc_directory: plc_directory.into(),
+            ..self
+        }
+    }
+
+    /// Where `did:plc` documents are resolved
+    pub fn plc_directory(&self) -> &str {
+        &self.plc_directory
+    }
+
+    /// Set how many fetches [`Resolver::resolve_many`] runs at once (at least one)
+    pub fn with_concurrency(self, concurrency: usize) -> Resolver {
+        Resolver {
+            concurrency: concurrency.max(1),
+            ..self
+        }
+    }
+
+    /// Where the document for `did` is published
+    fn document_url(&self, did: &Did) -> Option<String> {
+        match did.method() {
+            DidMethod::Plc => Some(format!(
+                "{}/{}",
+                self.plc_directory.trim_end_matches('/'),
+                did
+            )),
+            DidMethod::Web => did.to_did_web_url(),
+        }
+    }
+
+    /// Get the document for `did`, from the cache if possible
+    pub fn resolve(&self, did: &Did) -> Result<Arc<Value>, ResolveError> {
+        let url = self
+            .document_url(did)
+            .ok_or_else(|| ResolveError::NotFound {
+                did: did.to_string(),
+            })?;
+        let transport = self.transport.clone();
+        let did_string = did.to_string();
+        self.documents.get_or_revalidate(
+            ResourceKind::DidDocument,
+            did.as_str(),
+            move |validators| fetch_document(transport.as_ref(), &did_string, &url, validators),
+        )
+    }
+
+    /// Resolve a batch of DIDs, returning a result for each distinct one
+    ///
+    /// Duplicates are resolved once, cached documents are returned without a request, and at
+    /// most the configured concurrency of fetches run at a time. A failure only affects the
+    /// DID it belongs to
+    pub fn resolve_many(&self, dids: &[Did]) -> HashMap<Did, Result<Arc<Value>, ResolveError>> {
+        let mut seen = HashSet::new();
+        let unique: Vec<&Did> = dids.iter().filter(|did| seen.insert(*did)).collect();
+        let results = Mutex::new(HashMap::with_capacity(unique.len()));
+        let next = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(unique.len()) {
+                scope.spawn(|| {
+                    while let Some(did) = unique.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let result = self.resolve(did);
+                        results.lock().unwrap().insert((*did).clone(), result);
+                    }
+                });
+            }
+        });
+        results.into_inner().unwrap()
+    }
+}
+
+fn fetch_document(
+    transport: &dyn HttpTransport,
+    did: &str,
+    url: &str,
+    validators: Option<&Validators>,
+) -> Result<Fetched<Value>, ResolveError> {
+    use ResolveError::*;
+    let request = OutboundRequest::get(url)
+        .with_header("accept", "application/json")
+        .if_modified(validators);
+    let response = transport.send(&request)?;
+    match response.status {
+        304 if validators.is_some() => return Ok(Fetched::NotModified),
+        404 | 410 => return Err(NotFound { did: did.into() }),
+        _ if !response.is_success() => {
+            return Err(Rejected {
+                did: did.into(),
+                status: response.status,
+            })
+        }
+        _ => {}
+    }
+    let invalid = |reason: String| InvalidDocument {
+        did: did.into(),
+        reason,
+    };
+    let body = std::str::from_utf8(&response.body).map_err(|e| invalid(e.to_string()))?;
+    let document = json::parse(body).map_err(|e| invalid(e.to_string()))?;
+    match document.get("id").and_then(Value::as_str) {
+        Some(id) if id == did => Ok(Fetched::Modified {
+            value: document,
+            size: response.body.len(),
+            validators: response.validators(),
+        }),
+        Some(id) => Err(invalid(format!("document is for {id}"))),
+        None => Err(invalid("missing id".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{CacheConfig, Policy};
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+    use std::time::Duration;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:web:bob.example");
+    const CAROL: Did = did!("did:plc:carol");
+
+    fn resolver(mock: &Arc<MockTransport>) -> Resolver {
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            r#"{"id": "did:plc:alice"}"#,
+        )
+        .respond_json(
+            "https://bob.example/.well-known/did.json",
+            r#"{"id": "did:web:bob.example"}"#,
+        );
+        Resolver::new(mock.clone(), Arc::default()).with_concurrency(2)
+    }
+
+    #[test]
+    fn resolves_each_method() {
+        let mock = Arc::new(MockTransport::new());
+        let resolver = resolver(&mock);
+        let document = resolver.resolve(&BOB).unwrap();
+        assert_eq!(
+            document.get("id").and_then(Value::as_str),
+            Some("did:web:bob.example")
+        );
+        assert!(resolver.resolve(&ALICE).is_ok());
+        assert_eq!(
+            resolver.resolve(&CAROL),
+            Err(ResolveError::NotFound {
+                did: CAROL.to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn resolve_many_dedupes_and_uses_cache() {
+        let mock = Arc::new(MockTransport::new());
+        let resolver = resolver(&mock);
+        resolver.resolve(&BOB).unwrap();
+
+        let results = resolver.resolve_many(&[ALICE, BOB, ALICE, CAROL, ALICE]);
+        assert_eq!(results.len(), 3);
+        assert!(results[&ALICE].is_ok());
+        assert!(results[&BOB].is_ok());
+        assert!(matches!(
+            results[&CAROL],
+            Err(ResolveError::NotFound { .. })
+        ));
+        assert_eq!(
+            mock.requests_to("https://plc.directory/did:plc:alice")
+                .len(),
+            1
+        );
+        assert_eq!(
+            mock.requests_to("https://bob.example/.well-known/did.json")
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_documents() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://plc.directory/did:plc:carol",
+            r#"{"id": "did:plc:alice"}"#,
+        )
+        .respond(
+            crate::http::Method::Get,
+            "https://plc.directory/did:plc:alice",
+            OutboundResponse::new(500),
+        );
+        let resolver = Resolver::new(mock.clone(), Arc::default());
+        let results = resolver.resolve_many(&[ALICE, CAROL]);
+        assert!(matches!(
+            results[&CAROL],
+            Err(ResolveError::InvalidDocument { .. })
+        ));
+        assert!(matches!(
+            results[&ALICE],
+            Err(ResolveError::Rejected { status: 500, .. })
+        ));
+    }
+
+    #[test]
+    fn refetches_conditionally() {
+        let mock = Arc::new(MockTransport::new());
+        let url = "https://plc.directory/did:plc:alice";
+        let document = OutboundResponse::new(200)
+            .with_header("etag", "\"v1\"")
+            .with_body(r#"{"id": "did:plc:alice"}"#);
+        mock.respond(crate::http::Method::Get, url, document)
+            .respond(crate::http::Method::Get, url, OutboundResponse::new(304));
+        let mut config = CacheConfig::default();
+        config.policies.insert(
+            ResourceKind::DidDocument,
+            Policy {
+                ttl: Duration::ZERO,
+                stale_for: Duration::ZERO,
+            },
+        );
+        let documents = Arc::new(FetchCache::new(config));
+        let resolver = Resolver::new(mock.clone(), documents.clone());
+        let first = resolver.resolve(&ALICE).unwrap();
+        // Expired straight away, but still current according to its ETag
+        let second = resolver.resolve(&ALICE).unwrap();
+        assert_eq!(first, second);
+        let requests = mock.requests_to(url);
+        assert_eq!(requests[0].header("if-none-match"), None);
+        assert_eq!(requests[1].header("if-none-match"), Some("\"v1\""));
+        assert_eq!(documents.stats().not_modified, 1);
+    }
+}
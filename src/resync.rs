@@ -0,0 +1,511 @@
+//! Recovering from firehose events which were lost
+//!
+//! Events can go missing between the relay and the bridge: the connection drops and the relay
+//! has moved on by the time the cursor is resumed, or a commit is too big for the relay to
+//! include its records. Either is [noticed](observe) as the firehose is read, and logged:
+//!
+//! - a jump in sequence numbers ([`Gap::Sequence`]), where nothing says whose events went
+//!   missing, so every tracked repo of the shard is [affected]
+//! - a commit to a tracked repo following a revision other than the last one seen of it
+//!   ([`Gap::Rev`]), so its repo missed a commit in between
+//! - a commit marked `tooBig` ([`Gap::TooBig`]), whose operations are known but not their
+//!   records
+//!
+//! A `tooBig` commit's records are fetched one by one with `getRecord` ([`too_big`]). Anything
+//! else is recovered by fetching the whole repo with `getRepo` from its PDS and comparing it
+//! with what the caller knows of it ([`resync`]), giving the operations to handle as if the
+//! missing commits had arrived
+
+use crate::bridge::Bridge;
+use crate::car::{self, Cid};
+use crate::cbor::{self, CborError};
+use crate::firehose::{Action, CommitFrame, Frame, Operation};
+use crate::http::percent_encode;
+use crate::json::{self, Value};
+use crate::resolver::ResolveError;
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Mutex;
+use thiserror::Error;
+
+pub const GET_REPO: &str = "com.atproto.sync.getRepo";
+pub const GET_RECORD: &str = "com.atproto.repo.getRecord";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Events the bridge should have had, but didn't
+pub enum Gap {
+    /// The events between `after` and `next` never arrived
+    Sequence { after: i64, next: i64 },
+    /// A commit to `did` followed revision `since`, but the last one seen was `last`
+    Rev {
+        did: Did,
+        last: String,
+        since: Option<String>,
+    },
+    /// The relay left the records out of commit `seq` to `did`
+    TooBig { did: Did, seq: i64 },
+}
+
+impl fmt::Display for Gap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gap::Sequence { after, next } => {
+                write!(f, "events {} to {} never arrived", after + 1, next - 1)
+            }
+            Gap::Rev { did, last, since } => write!(
+                f,
+                "a commit to {did} followed revision {}, not {last}",
+                since.as_deref().unwrap_or("none")
+            ),
+            Gap::TooBig { did, seq } => write!(f, "commit {seq} to {did} was too big to include"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Where the firehose has been, to tell when it skips something
+pub struct GapDetector {
+    last_seq: Mutex<Option<i64>>,
+    /// The latest revision seen of each tracked repo
+    revs: Mutex<HashMap<Did, String>>,
+}
+
+impl GapDetector {
+    /// Note an event at `seq`, returning the gap before it if there was one
+    ///
+    /// A sequence number going backwards is a relay which has been reset, not a gap
+    fn sequence(&self, seq: i64) -> Option<Gap> {
+        let mut last = self.last_seq.lock().unwrap();
+        let after = last.replace(seq)?;
+        (seq > after + 1).then_some(Gap::Sequence { after, next: seq })
+    }
+
+    /// Note a commit to a tracked repo, returning what's missing from it
+    fn commit(&self, commit: &CommitFrame) -> Vec<Gap> {
+        let mut gaps = Vec::new();
+        if let Some(rev) = commit.rev {
+            let mut revs = self.revs.lock().unwrap();
+            let last = revs.insert(commit.repo.clone(), rev.to_string());
+            if let Some(last) = last.filter(|last| commit.since != Some(last.as_str())) {
+                gaps.push(Gap::Rev {
+                    did: commit.repo.clone(),
+                    last,
+                    since: commit.since.map(str::to_string),
+                });
+            }
+        }
+        if commit.too_big {
+            gaps.push(Gap::TooBig {
+                did: commit.repo.clone(),
+                seq: commit.seq,
+            });
+        }
+        gaps
+    }
+
+    /// Note that `did`'s repo has been fetched as of `rev`
+    fn resynced(&self, did: &Did, rev: &str) {
+        let mut revs = self.revs.lock().unwrap();
+        revs.insert(did.clone(), rev.to_string());
+    }
+}
+
+/// Note a message read from the firehose, logging and returning any gap it shows
+///
+/// Only repos this shard tracks are followed from commit to commit
+pub fn observe(bridge: &Bridge, frame: &Frame) -> Vec<Gap> {
+    let (seq, commit) = match frame {
+        Frame::Commit(commit) => (commit.seq, Some(commit)),
+        Frame::Account(event) => (event.seq, None),
+        Frame::Identity(event) => (event.seq, None),
+        Frame::Other(_) => return Vec::new(),
+    };
+    let mut gaps: Vec<Gap> = bridge.gaps.sequence(seq).into_iter().collect();
+    if let Some(commit) = commit.filter(|commit| {
+        bridge.shard.owns(&commit.repo) && bridge.identities.is_tracked(&commit.repo)
+    }) {
+        gaps.extend(bridge.gaps.commit(commit));
+    }
+    for gap in &gaps {
+        eprintln!("Firehose gap: {gap}");
+    }
+    gaps
+}
+
+/// The repos which may have missed events in `gap`
+pub fn affected(bridge: &Bridge, gap: &Gap) -> Vec<Did> {
+    match gap {
+        Gap::Sequence { .. } => {
+            let tracked = bridge.identities.all().into_iter().map(|m| m.did);
+            let tracked = tracked.filter(|did| bridge.identities.is_tracked(did));
+            tracked.filter(|did| bridge.shard.owns(did)).collect()
+        }
+        Gap::Rev { did, .. } | Gap::TooBig { did, .. } => vec![did.clone()],
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ResyncError {
+    #[error(transparent)]
+    Resolve(#[from] ResolveError),
+    #[error("{did} has no PDS in its DID document")]
+    NoPds { did: Did },
+    #[error("{did}'s PDS responded to {method} with {status}")]
+    Rejected {
+        did: Did,
+        method: &'static str,
+        status: u16,
+    },
+    #[error("Malformed repo - {0}")]
+    Malformed(#[from] CborError),
+    #[error("Malformed repo - {reason}")]
+    Incomplete { reason: &'static str },
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+}
+
+/// The PDS hosting `did`'s repo, from its DID document
+fn pds(bridge: &Bridge, did: &Did) -> Result<String, ResyncError> {
+    let document = bridge.resolver().resolve(did)?;
+    let services = document.get("service").and_then(Value::as_array);
+    let pds = services.unwrap_or_default().iter().find(|service| {
+        let id = service.get("id").and_then(Value::as_str);
+        id.is_some_and(|id| id.ends_with("#atproto_pds"))
+    });
+    let endpoint = pds.and_then(|pds| pds.get("serviceEndpoint")?.as_str());
+    let endpoint = endpoint.ok_or_else(|| ResyncError::NoPds { did: did.clone() })?;
+    Ok(endpoint.trim_end_matches('/').to_string())
+}
+
+/// Fetch an XRPC query from `did`'s PDS, `None` if it answers 400 (as it does for anything
+/// not found)
+pub(crate) fn query(
+    bridge: &Bridge,
+    did: &Did,
+    method: &'static str,
+    params: &str,
+) -> Result<Option<Vec<u8>>, ResyncError> {
+    let url = format!("{}/xrpc/{method}?{params}", pds(bridge, did)?);
+    let response = bridge.transport.send(&OutboundRequest::get(url))?;
+    if response.status == 400 {
+        return Ok(None);
+    }
+    if !response.is_success() {
+        return Err(ResyncError::Rejected {
+            did: did.clone(),
+            method,
+            status: response.status,
+        });
+    }
+    Ok(Some(response.body))
+}
+
+/// The record at `path` in `did`'s repo, with its CID, or `None` if there isn't one
+pub fn fetch_record(
+    bridge: &Bridge,
+    did: &Did,
+    path: &str,
+) -> Result<Option<(Cid, Value)>, ResyncError> {
+    let Some((collection, rkey)) = path.split_once('/') else {
+        return Ok(None);
+    };
+    let params = format!(
+        "repo={}&collection={}&rkey={}",
+        percent_encode(did.as_str()),
+        percent_encode(collection),
+        percent_encode(rkey)
+    );
+    let Some(body) = query(bridge, did, GET_RECORD, &params)? else {
+        return Ok(None);
+    };
+    let response = json::parse(&String::from_utf8_lossy(&body)).unwrap_or(Value::Null);
+    let cid = response.get("cid").and_then(Value::as_str);
+    let (Some(Ok(cid)), Some(record)) = (cid.map(str::parse), response.get("value")) else {
+        return Err(ResyncError::Incomplete {
+            reason: "record without a CID or value",
+        });
+    };
+    Ok(Some((cid, record.clone())))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A repo's records as of a revision
+pub struct Snapshot {
+    pub rev: String,
+    /// By `<collection>/<rkey>`
+    pub records: BTreeMap<String, (Cid, Value)>,
+}
+
+/// Collect the `(key, record CID)` entries of the tree node `cid` and every node below it,
+/// in key order
+fn walk(
+    blocks: &HashMap<Cid, &[u8]>,
+    cid: &Cid,
+    entries: &mut Vec<(String, Cid)>,
+) -> Result<(), ResyncError> {
+    let missing = ResyncError::Incomplete {
+        reason: "missing tree node",
+    };
+    let node = cbor::decode(blocks.get(cid).ok_or(missing)?)?.0;
+    if let Some(left) = node.get("l").and_then(|l| l.as_link()) {
+        walk(blocks, &left, entries)?;
+    }
+    let mut key: Vec<u8> = Vec::new();
+    for entry in node
+        .get("e")
+        .and_then(|e| e.as_array())
+        .iter()
+        .flat_map(|entries| entries.iter())
+    {
+        let malformed = ResyncError::Incomplete {
+            reason: "malformed tree entry",
+        };
+        let shared = entry.get("p").and_then(|p| p.as_i64());
+        let rest = entry.get("k").and_then(|k| k.as_bytes());
+        let value = entry.get("v").and_then(|v| v.as_link());
+        let (Some(shared), Some(rest), Some(value)) = (shared, rest, value) else {
+            return Err(malformed);
+        };
+        // Each key is given as how much it shares with the one before, and the rest
+        key.truncate(usize::try_from(shared).unwrap_or_default());
+        key.extend_from_slice(rest);
+        entries.push((String::from_utf8_lossy(&key).into_owned(), value));
+        if let Some(right) = entry.get("t").and_then(|t| t.as_link()) {
+            walk(blocks, &right, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read a repo exported as a CAR
+pub fn read_repo(repo: &[u8]) -> Result<Snapshot, ResyncError> {
+    let incomplete = |reason| ResyncError::Incomplete { reason };
+    let root = car::car_root(repo)?.ok_or(incomplete("no root"))?;
+    let blocks = car::read_car(repo)?.collect::<Result<HashMap<_, _>, _>>()?;
+    let commit = cbor::decode(blocks.get(&root).ok_or(incomplete("missing commit"))?)?.0;
+    let rev = commit.get("rev").and_then(|rev| rev.as_str());
+    let data = commit.get("data").and_then(|data| data.as_link());
+    let (Some(rev), Some(data)) = (rev, data) else {
+        return Err(incomplete("malformed commit"));
+    };
+    let mut entries = Vec::new();
+    walk(&blocks, &data, &mut entries)?;
+    let records = entries
+        .into_iter()
+        .map(|(path, cid)| {
+            let block = blocks.get(&cid).ok_or(incomplete("missing record"))?;
+            Ok((path, (cid, cbor::decode(block)?.0.to_json())))
+        })
+        .collect::<Result<_, ResyncError>>()?;
+    Ok(Snapshot {
+        rev: rev.to_string(),
+        records,
+    })
+}
+
+/// Fetch the whole of `did`'s repo from its PDS
+pub fn fetch_repo(bridge: &Bridge, did: &Did) -> Result<Snapshot, ResyncError> {
+    let params = format!("did={}", percent_encode(did.as_str()));
+    let repo = query(bridge, did, GET_REPO, &params)?;
+    read_repo(&repo.ok_or_else(|| ResyncError::Rejected {
+        did: did.clone(),
+        method: GET_REPO,
+        status: 400,
+    })?)
+}
+
+/// The operations which take a repo from `known`, its records' CIDs by path, to `snapshot`
+pub fn reconcile(
+    known: &BTreeMap<String, Cid>,
+    snapshot: &Snapshot,
+) -> Vec<(Operation, Option<Value>)> {
+    let op = |action, path: &str| Operation {
+        action,
+        path: path.to_string(),
+    };
+    let written = snapshot.records.iter().filter_map(|(path, (cid, record))| {
+        let action = match known.get(path) {
+            None => Action::Create,
+            Some(known) if known != cid => Action::Update,
+            Some(_) => return None,
+        };
+        Some((op(action, path), Some(record.clone())))
+    });
+    let deleted = known
+        .keys()
+        .filter(|path| !snapshot.records.contains_key(*path));
+    written
+        .chain(deleted.map(|path| (op(Action::Delete, path), None)))
+        .collect()
+}
+
+/// Fetch `did`'s repo and compare it with what's `known` of it, as [`reconcile`] does
+pub fn resync(
+    bridge: &Bridge,
+    did: &Did,
+    known: &BTreeMap<String, Cid>,
+) -> Result<Vec<(Operation, Option<Value>)>, ResyncError> {
+    let snapshot = fetch_repo(bridge, did)?;
+    bridge.gaps.resynced(did, &snapshot.rev);
+    Ok(reconcile(known, &snapshot))
+}
+
+/// The operations of a `tooBig` commit, each with the record it wrote fetched from the repo
+///
+/// A record which has gone since is deleted instead, as a later commit will have
+pub fn too_big(
+    bridge: &Bridge,
+    commit: &CommitFrame,
+) -> Result<Vec<(Operation, Option<Value>)>, ResyncError> {
+    let mut operations = Vec::new();
+    for mut op in commit.header().ops {
+        if op.action == Action::Delete {
+            operations.push((op, None));
+            continue;
+        }
+        let record = fetch_record(bridge, &commit.repo, &op.path)?;
+        if record.is_none() {
+            op.action = Action::Delete;
+        }
+        operations.push((op, record.map(|(_, record)| record)));
+    }
+    Ok(operations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firehose::encode_commit;
+    use crate::http::Method;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::Write;
+    use crate::store::Mapping;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+
+    fn bridge(mock: &Arc<MockTransport>) -> Bridge {
+        let bridge = Bridge::new().with_transport(mock.clone());
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://a.example/users/alice"));
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            r##"{"id": "did:plc:alice", "service": [{"id": "#atproto_pds",
+                "type": "AtprotoPersonalDataServer", "serviceEndpoint": "https://pds.example"}]}"##,
+        );
+        bridge
+    }
+
+    fn op(action: Action, path: &str) -> Operation {
+        Operation {
+            action,
+            path: path.to_string(),
+        }
+    }
+
+    #[test]
+    fn notices_skipped_events_and_revisions() {
+        let bridge = Bridge::new();
+        bridge
+            .identities
+            .insert(Mapping::new(ALICE, "https://a.example/users/alice"));
+        let post = Some(Value::object([("text", Value::from("hi"))]));
+        let message =
+            |seq| encode_commit(seq, &ALICE, &[(op(Action::Create, "p/1"), post.clone())]);
+        let (first, second, third) = (message(1), message(2), message(5));
+        let frame = |message, rev, since, too_big| {
+            let Ok(Frame::Commit(mut commit)) = Frame::parse(message) else {
+                panic!("not a commit");
+            };
+            commit.rev = Some(rev);
+            commit.since = since;
+            commit.too_big = too_big;
+            Frame::Commit(commit)
+        };
+        assert_eq!(observe(&bridge, &frame(&first, "3a", None, false)), []);
+        assert_eq!(
+            observe(&bridge, &frame(&second, "3b", Some("3a"), true)),
+            [Gap::TooBig { did: ALICE, seq: 2 }]
+        );
+        let gaps = observe(&bridge, &frame(&third, "3d", Some("3c"), false));
+        assert_eq!(
+            gaps,
+            [
+                Gap::Sequence { after: 2, next: 5 },
+                Gap::Rev {
+                    did: ALICE,
+                    last: "3b".to_string(),
+                    since: Some("3c".to_string())
+                }
+            ]
+        );
+        assert_eq!(gaps[0].to_string(), "events 3 to 4 never arrived");
+        assert_eq!(affected(&bridge, &gaps[0]), [ALICE]);
+    }
+
+    #[test]
+    fn resyncs_from_the_repo() {
+        // A repo the bridge hosts stands in for Alice's PDS
+        let host = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let owner = KeyOwner::Account(ALICE);
+        let generator = FakeGenerator::default();
+        host.keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let record = |text| Value::object([("text", Value::from(text))]);
+        let writes = ["1", "2", "3"].map(|rkey| Write::Create {
+            path: format!("app.bsky.feed.post/{rkey}"),
+            record: record(rkey),
+        });
+        host.commit(&ALICE, &writes).unwrap();
+        let update = Write::Update {
+            path: "app.bsky.feed.post/2".to_string(),
+            record: record("edited"),
+            swap: None,
+        };
+        host.commit(&ALICE, &[update]).unwrap();
+
+        let mock = Arc::new(MockTransport::new());
+        let url = "https://pds.example/xrpc/com.atproto.sync.getRepo?did=did%3Aplc%3Aalice";
+        let car = host.repos.export(&ALICE).unwrap();
+        mock.respond(Method::Get, url, OutboundResponse::new(200).with_body(car));
+        let bridge = bridge(&mock);
+        let snapshot = read_repo(&host.repos.export(&ALICE).unwrap()).unwrap();
+        assert_eq!(snapshot.rev, host.repos.head(&ALICE).unwrap().rev);
+        assert_eq!(snapshot.records.len(), 3);
+
+        // Known before the edit, with a post since deleted
+        let cid = |path: &str| snapshot.records[path].0;
+        let known = BTreeMap::from([
+            (
+                "app.bsky.feed.post/1".to_string(),
+                cid("app.bsky.feed.post/1"),
+            ),
+            ("app.bsky.feed.post/2".to_string(), Cid::for_raw(b"old")),
+            ("app.bsky.feed.post/0".to_string(), Cid::for_raw(b"gone")),
+        ]);
+        let ops = resync(&bridge, &ALICE, &known).unwrap();
+        assert_eq!(
+            ops,
+            [
+                (
+                    op(Action::Update, "app.bsky.feed.post/2"),
+                    Some(record("edited"))
+                ),
+                (
+                    op(Action::Create, "app.bsky.feed.post/3"),
+                    Some(record("3"))
+                ),
+                (op(Action::Delete, "app.bsky.feed.post/0"), None),
+            ]
+        );
+        assert_eq!(mock.requests_to(url).len(), 1);
+    }
+}
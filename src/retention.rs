@@ -0,0 +1,319 @@
+//! Retention of cached and re-hosted content
+//!
+//! Without limits, a long-running bridge keeps every remote document it has looked up and
+//! every file it has re-hosted. Documents are already bounded in size by the
+//! [`FetchCache`](crate::cache::FetchCache), and expire by age; re-hosted media lives in the
+//! state directory's [`MediaStore`], bounded by a [`RetentionConfig`].
+//!
+//! A background thread ([`spawn`]) runs [`cleanup`] every
+//! [`cleanup_interval`](RetentionConfig::cleanup_interval), dropping expired documents and
+//! then media which is too old or, least recently used first, over the size limit, and
+//! [audit records](crate::audit), [handled activities](crate::dedup),
+//! [quarantined records](crate::lexicon), [traced decisions](crate::trace),
+//! [delivery receipts](crate::receipts) and [archived events](crate::archive) past their
+//! retention periods
+
+use crate::bridge::Bridge;
+use crate::http::percent_encode;
+use crate::shutdown::Shutdown;
+use crate::storage::StateDir;
+use atproto::DID::Did;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Where re-hosted media is kept in the state directory
+pub const MEDIA_DIR: &str = "media";
+
+#[derive(Debug, Clone, PartialEq)]
+/// How long re-hosted media is kept, and how often retention is enforced
+pub struct RetentionConfig {
+    /// Media unused for longer than this is removed
+    pub media_ttl: Option<Duration>,
+    /// Beyond this many bytes of media, the least recently used is removed
+    pub media_max_bytes: Option<u64>,
+    /// Audit records older than this are removed
+    pub audit_ttl: Option<Duration>,
+    /// How long handled activities are remembered, so their redeliveries can be dropped
+    pub seen_ttl: Duration,
+    /// Quarantined records older than this are removed
+    pub quarantine_ttl: Option<Duration>,
+    /// Traced decisions older than this are removed
+    pub decision_ttl: Option<Duration>,
+    /// Delivery receipts older than this are removed
+    pub receipt_ttl: Option<Duration>,
+    /// Archived events older than this are removed. Replays rely on the decision trace to
+    /// know what was bridged, so this shouldn't be longer than `decision_ttl`
+    pub archive_ttl: Option<Duration>,
+    pub cleanup_interval: Duration,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            media_ttl: Some(Duration::from_secs(30 * 86400)),
+            media_max_bytes: None,
+            audit_ttl: Some(Duration::from_secs(90 * 86400)),
+            seen_ttl: Duration::from_secs(7 * 86400),
+            quarantine_ttl: Some(Duration::from_secs(30 * 86400)),
+            decision_ttl: Some(Duration::from_secs(14 * 86400)),
+            receipt_ttl: Some(Duration::from_secs(14 * 86400)),
+            archive_ttl: Some(Duration::from_secs(7 * 86400)),
+            cleanup_interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Files the bridge has re-hosted, by the account they belong to and name
+///
+/// Each file's modification time records when it was last used, so that retention can be
+/// enforced by age and by recency
+pub struct MediaStore {
+    dir: StateDir,
+}
+
+/// A stored file, for deciding what to remove
+struct Stored {
+    name: String,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl MediaStore {
+    /// Open (creating if needed) the media store in a state directory
+    pub fn open(root: &StateDir) -> io::Result<MediaStore> {
+        Ok(MediaStore {
+            dir: root.subdir(MEDIA_DIR)?,
+        })
+    }
+
+    /// Where `owner`'s files are stored. `+` never appears in an encoded DID, so one
+    /// account's prefix can't be the start of another's
+    fn prefix(owner: &Did) -> String {
+        format!("{}+", percent_encode(owner.as_str()))
+    }
+
+    pub fn put(&self, owner: &Did, name: &str, data: &[u8]) -> io::Result<()> {
+        self.dir.write(&(Self::prefix(owner) + name), data)
+    }
+
+    /// Read a file, marking it as used
+    pub fn get(&self, owner: &Did, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let name = Self::prefix(owner) + name;
+        let data = self.dir.read(&name)?;
+        if data.is_some() {
+            let file = fs::File::options()
+                .write(true)
+                .open(self.dir.path().join(name))?;
+            file.set_modified(SystemTime::now())?;
+        }
+        Ok(data)
+    }
+
+    /// Where a file is and how long it is, marking it as used, so it can be sent without
+    /// reading it all
+    pub fn locate(&self, owner: &Did, name: &str) -> io::Result<Option<(PathBuf, u64)>> {
+        let path = self.dir.path().join(Self::prefix(owner) + name);
+        let file = match fs::File::options().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.set_modified(SystemTime::now())?;
+        Ok(Some((path, file.metadata()?.len())))
+    }
+
+    /// Remove all of `owner`'s files, returning how many there were
+    pub fn purge(&self, owner: &Did) -> io::Result<usize> {
+        let prefix = Self::prefix(owner);
+        let mut removed = 0;
+        for file in self.stored()? {
+            if file.name.starts_with(&prefix) {
+                self.dir.remove(&file.name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Stored files, least recently used first. Files mid-write are skipped
+    fn stored(&self) -> io::Result<Vec<Stored>> {
+        let mut stored = Vec::new();
+        for entry in fs::read_dir(self.dir.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if name.starts_with('.') || !metadata.is_file() {
+                continue;
+            }
+            stored.push(Stored {
+                name,
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+        stored.sort_by_key(|file| file.last_used);
+        Ok(stored)
+    }
+
+    /// Summed size of all stored files
+    pub fn bytes(&self) -> io::Result<u64> {
+        Ok(self.stored()?.iter().map(|file| file.size).sum())
+    }
+
+    /// Remove files `config` says are no longer kept as of `now`, returning how many
+    pub fn enforce(&self, config: &RetentionConfig, now: SystemTime) -> io::Result<usize> {
+        let stored = self.stored()?;
+        let mut bytes: u64 = stored.iter().map(|file| file.size).sum();
+        let mut removed = 0;
+        for file in stored {
+            let age = now.duration_since(file.last_used).unwrap_or_default();
+            let expired = config.media_ttl.is_some_and(|ttl| age >= ttl);
+            let over = config.media_max_bytes.is_some_and(|max| bytes > max);
+            if !expired && !over {
+                continue;
+            }
+            self.dir.remove(&file.name)?;
+            bytes -= file.size;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// What one run of [`cleanup`] removed
+pub struct Cleanup {
+    pub documents: usize,
+    pub media: usize,
+    pub audit_records: usize,
+    pub seen_activities: usize,
+    pub quarantined: usize,
+    pub decisions: usize,
+    pub receipts: usize,
+    pub archived: usize,
+}
+
+/// Drop expired documents, audit records, handled activities, quarantined records,
+/// decisions, receipts and archived events, and enforce media retention
+pub fn cleanup(bridge: &Bridge, now: Instant) -> io::Result<Cleanup> {
+    let documents = bridge.documents.purge_expired(now);
+    let media = match &bridge.media {
+        Some(media) => media.enforce(&bridge.retention, SystemTime::now())?,
+        None => 0,
+    };
+    let audit_records = match bridge.retention.audit_ttl {
+        Some(ttl) => bridge.audit.prune(ttl, SystemTime::now())?,
+        None => 0,
+    };
+    let seen_activities = bridge
+        .seen_activities
+        .prune(bridge.retention.seen_ttl, SystemTime::now())?;
+    let quarantined = match bridge.retention.quarantine_ttl {
+        Some(ttl) => bridge.quarantine.prune(ttl, SystemTime::now())?,
+        None => 0,
+    };
+    let decisions = match bridge.retention.decision_ttl {
+        Some(ttl) => bridge.decisions.prune(ttl, SystemTime::now())?,
+        None => 0,
+    };
+    let receipts = match bridge.retention.receipt_ttl {
+        Some(ttl) => bridge.receipts.prune(ttl, SystemTime::now())?,
+        None => 0,
+    };
+    let archived = match (&bridge.archive, bridge.retention.archive_ttl) {
+        (Some(archive), Some(ttl)) => archive.prune(ttl, SystemTime::now())?,
+        _ => 0,
+    };
+    Ok(Cleanup {
+        documents,
+        media,
+        audit_records,
+        seen_activities,
+        quarantined,
+        decisions,
+        receipts,
+        archived,
+    })
+}
+
+/// Start a thread enforcing retention periodically, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_run = Instant::now();
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_run.elapsed() < bridge.retention.cleanup_interval {
+                continue;
+            }
+            last_run = Instant::now();
+            if let Err(e) = cleanup(&bridge, last_run) {
+                eprintln!("Couldn't enforce retention: {e}");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const DAY: Duration = Duration::from_secs(86400);
+
+    /// A store holding `files`, each last used the given number of days ago
+    fn store(files: &[(&str, usize, u64)]) -> (MediaStore, SystemTime) {
+        let store = MediaStore::open(&temp_state_dir()).unwrap();
+        let now = SystemTime::now();
+        for &(name, size, days) in files {
+            store.put(&ALICE, name, &vec![0; size]).unwrap();
+            let file = fs::File::options()
+                .write(true)
+                .open(store.dir.path().join(MediaStore::prefix(&ALICE) + name))
+                .unwrap();
+            file.set_modified(now - DAY * days as u32).unwrap();
+        }
+        (store, now)
+    }
+
+    #[test]
+    fn expires_unused_media() {
+        let (store, now) = store(&[("old.jpg", 10, 40), ("recent.jpg", 10, 1)]);
+        let config = RetentionConfig::default();
+        assert_eq!(store.enforce(&config, now).unwrap(), 1);
+        assert_eq!(store.get(&ALICE, "old.jpg").unwrap(), None);
+        assert_eq!(store.bytes().unwrap(), 10);
+
+        let forever = RetentionConfig {
+            media_ttl: None,
+            ..config
+        };
+        let (store, now) = self::store(&[("old.jpg", 10, 400)]);
+        assert_eq!(store.enforce(&forever, now).unwrap(), 0);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_media() {
+        let (store, now) = store(&[("a.jpg", 10, 3), ("b.jpg", 10, 2), ("c.jpg", 10, 1)]);
+        // Reading `a` makes `b` the least recently used
+        assert!(store.get(&ALICE, "a.jpg").unwrap().is_some());
+        let config = RetentionConfig {
+            media_ttl: None,
+            media_max_bytes: Some(25),
+            ..RetentionConfig::default()
+        };
+        assert_eq!(store.enforce(&config, now).unwrap(), 1);
+        assert_eq!(store.get(&ALICE, "b.jpg").unwrap(), None);
+        assert_eq!(store.bytes().unwrap(), 20);
+        // Purging is by account
+        store.put(&did!("did:plc:alicex"), "d.jpg", b"d").unwrap();
+        assert_eq!(store.purge(&ALICE).unwrap(), 2);
+        assert_eq!(store.bytes().unwrap(), 1);
+    }
+}
@@ -0,0 +1,151 @@
+//! Not bridging what its author has since deleted
+//!
+//! Events aren't always bridged as they happen. Backfill publishes an account's history, and
+//! [replay](crate::archive::replay) runs archived events through the pipeline again, days
+//! later perhaps. By then the author may have deleted a post, and the delete, having been
+//! handled already or before there was anything to delete, won't come again: publishing the
+//! post would bring it back for good.
+//!
+//! So before publishing an event older than the bridge's retraction window
+//! (`FEDIBRIDGE_RETRACTION_WINDOW_SECS`), its origin is asked whether it's still there:
+//!
+//! - a commit's records, with `com.atproto.repo.getRecord` on the author's PDS
+//!   ([`commit_retracted`])
+//! - an activity's object, with a `HEAD` request to its ID ([`activity_retracted`])
+//!
+//! Events within the window are too recent to be worth asking about. An origin which can't
+//! say is an error, and the event isn't published
+
+use crate::bridge::Bridge;
+use crate::delivery::ACTIVITY_JSON;
+use crate::firehose::{Action, EventHeader};
+use crate::http::Method;
+use crate::json::Value;
+use crate::resync::{fetch_record, ResyncError};
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// How old an event is before its origin is checked, by default
+pub const DEFAULT_RETRACTION_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Error)]
+/// Why it isn't known whether an event's origin still has it
+pub enum RetractionError {
+    #[error(transparent)]
+    Record(#[from] ResyncError),
+    #[error("{url} responded with {status}")]
+    Object { url: String, status: u16 },
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+}
+
+/// Whether an event which arrived `at` is old enough to be checked by now
+pub fn outside_window(bridge: &Bridge, at: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(at).unwrap_or_default() >= bridge.retraction_window
+}
+
+/// Whether the record at `path` in `did`'s repo has been deleted
+pub fn record_deleted(bridge: &Bridge, did: &Did, path: &str) -> Result<bool, RetractionError> {
+    Ok(fetch_record(bridge, did, path)?.is_none())
+}
+
+/// Whether the object `id` has been deleted, as its server answers `404` or `410` for it
+pub fn object_deleted(bridge: &Bridge, id: &str) -> Result<bool, RetractionError> {
+    let request = OutboundRequest::new(Method::Head, id).with_header("accept", ACTIVITY_JSON);
+    let response = bridge.transport.send(&request)?;
+    match response.status {
+        404 | 410 => Ok(true),
+        _ if response.is_success() => Ok(false),
+        status => Err(RetractionError::Object {
+            url: id.to_string(),
+            status,
+        }),
+    }
+}
+
+/// Whether any record the commit with `header` created or updated has been deleted since
+pub fn commit_retracted(bridge: &Bridge, header: &EventHeader) -> Result<bool, RetractionError> {
+    for op in &header.ops {
+        if op.action != Action::Delete && record_deleted(bridge, &header.did, &op.path)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the object `activity` creates or updates has been deleted since. Other activities
+/// are about objects the bridge has, and aren't checked
+pub fn activity_retracted(bridge: &Bridge, activity: &Value) -> Result<bool, RetractionError> {
+    let kind = activity.get("type").and_then(Value::as_str);
+    if !matches!(kind, Some("Create" | "Update")) {
+        return Ok(false);
+    }
+    let object = activity.get("object");
+    let id = object.and_then(|o| o.as_str().or_else(|| o.get("id").and_then(Value::as_str)));
+    match id {
+        Some(id) => object_deleted(bridge, id),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed::POST_COLLECTION;
+    use crate::firehose::Operation;
+    use crate::json;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn asks_origins_whether_they_still_have_things() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new().with_transport(mock.clone());
+        mock.respond_json(
+            "https://plc.directory/did:plc:alice",
+            r##"{"id": "did:plc:alice", "service": [{"id": "#atproto_pds",
+                "type": "AtprotoPersonalDataServer", "serviceEndpoint": "https://pds.example"}]}"##,
+        );
+        let get_record = "https://pds.example/xrpc/com.atproto.repo.getRecord\
+                          ?repo=did%3Aplc%3Aalice&collection=app.bsky.feed.post&rkey=";
+        mock.respond(
+            Method::Get,
+            &format!("{get_record}1"),
+            OutboundResponse::new(400).with_body(r#"{"error": "RecordNotFound"}"#),
+        );
+        let header = |rkey: &str, action| EventHeader {
+            seq: 1,
+            did: did!("did:plc:alice"),
+            ops: vec![Operation {
+                action,
+                path: format!("{POST_COLLECTION}/{rkey}"),
+            }],
+        };
+        assert!(commit_retracted(&bridge, &header("1", Action::Create)).unwrap());
+        // Deletes aren't asked about
+        assert!(!commit_retracted(&bridge, &header("2", Action::Delete)).unwrap());
+
+        let gone = "https://b.example/notes/1";
+        mock.respond(Method::Head, gone, OutboundResponse::new(410))
+            .respond(Method::Head, gone, OutboundResponse::new(503));
+        let create = json::parse(
+            r#"{"type": "Create", "actor": "https://b.example/users/bob",
+                "object": {"id": "https://b.example/notes/1", "type": "Note"}}"#,
+        )
+        .unwrap();
+        assert!(activity_retracted(&bridge, &create).unwrap());
+        // An origin which can't say isn't taken to have kept it
+        assert!(activity_retracted(&bridge, &create).is_err());
+
+        let now = SystemTime::now();
+        assert!(!outside_window(&bridge, now, now));
+        assert!(outside_window(
+            &bridge,
+            now - DEFAULT_RETRACTION_WINDOW,
+            now
+        ));
+    }
+}
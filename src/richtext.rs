@@ -0,0 +1,334 @@
+//! Bluesky rich text: post text plus facets
+//!
+//! Links, mentions and tags in a Bluesky post are facets, annotations on UTF-8 byte ranges of
+//! the text, rather than markup. [`from_html_with`] turns fediverse post content into text
+//! and facets, shortening links whose text is just their URL the way the official client does
+//! ([`shorten_url`]) so they don't eat into the post's length limit. The facet keeps the full
+//! URI either way
+
+use crate::html::{tokenize, Token};
+use crate::json::Value;
+use crate::normalize;
+use atproto::DID::Did;
+
+/// The facet feature type for links
+pub const LINK: &str = "app.bsky.richtext.facet#link";
+/// The facet feature type for mentions
+pub const MENTION: &str = "app.bsky.richtext.facet#mention";
+/// The facet feature type for hashtags
+pub const TAG: &str = "app.bsky.richtext.facet#tag";
+
+/// Paths longer than this are shortened in display text
+const MAX_DISPLAY_PATH: usize = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What a facet annotates its text with
+pub enum Feature {
+    Link {
+        uri: String,
+    },
+    Mention {
+        did: Did,
+    },
+    /// A hashtag, without its `#`
+    Tag {
+        tag: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An annotated byte range of a post's text
+pub struct Facet {
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub feature: Feature,
+}
+
+impl Facet {
+    pub fn to_json(&self) -> Value {
+        let feature = match &self.feature {
+            Feature::Link { uri } => Value::object([
+                ("$type", Value::from(LINK)),
+                ("uri", Value::from(uri.as_str())),
+            ]),
+            Feature::Mention { did } => Value::object([
+                ("$type", Value::from(MENTION)),
+                ("did", Value::from(did.as_str())),
+            ]),
+            Feature::Tag { tag } => Value::object([
+                ("$type", Value::from(TAG)),
+                ("tag", Value::from(tag.as_str())),
+            ]),
+        };
+        Value::object([
+            (
+                "index",
+                Value::object([
+                    ("byteStart", Value::from(self.byte_start)),
+                    ("byteEnd", Value::from(self.byte_end)),
+                ]),
+            ),
+            ("features", Value::Array(vec![feature])),
+        ])
+    }
+
+    /// Parse a facet from a record, keeping the first feature this understands
+    pub fn from_json(value: &Value) -> Option<Facet> {
+        let index = value.get("index")?;
+        let offset = |name| usize::try_from(index.get(name)?.as_i64()?).ok();
+        let feature = value.get("features")?.as_array()?.iter().find_map(|f| {
+            match f.get("$type")?.as_str()? {
+                LINK => Some(Feature::Link {
+                    uri: f.get("uri")?.as_str()?.to_string(),
+                }),
+                MENTION => Some(Feature::Mention {
+                    did: normalize::parse_did(f.get("did")?.as_str()?).ok()?,
+                }),
+                TAG => Some(Feature::Tag {
+                    tag: f.get("tag")?.as_str()?.to_string(),
+                }),
+                _ => None,
+            }
+        })?;
+        Some(Facet {
+            byte_start: offset("byteStart")?,
+            byte_end: offset("byteEnd")?,
+            feature,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Post text along with its facets
+pub struct RichText {
+    pub text: String,
+    pub facets: Vec<Facet>,
+}
+
+impl RichText {
+    pub fn new() -> RichText {
+        RichText::default()
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    /// Append `display` annotated with `feature`
+    pub fn push_facet(&mut self, display: &str, feature: Feature) {
+        let byte_start = self.text.len();
+        self.text.push_str(display);
+        self.facets.push(Facet {
+            byte_start,
+            byte_end: self.text.len(),
+            feature,
+        });
+    }
+
+    /// Append `display` as a link to `uri`
+    pub fn push_link(&mut self, display: &str, uri: &str) {
+        let uri = uri.to_string();
+        self.push_facet(display, Feature::Link { uri });
+    }
+
+    /// The facets as a record's `facets` array
+    pub fn facets_json(&self) -> Value {
+        Value::Array(self.facets.iter().map(Facet::to_json).collect())
+    }
+}
+
+/// A URL as the official client displays it: without its scheme, and with long paths cut
+/// down to `example.com/very/long…`
+pub fn shorten_url(uri: &str) -> String {
+    let Some((scheme, rest)) = uri.split_once("://") else {
+        return uri.to_string();
+    };
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return uri.to_string();
+    }
+    let (host, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+    let path = if path == "/" { "" } else { path };
+    if path.chars().count() <= MAX_DISPLAY_PATH {
+        return format!("{host}{path}");
+    }
+    let kept: String = path.chars().take(MAX_DISPLAY_PATH - 2).collect();
+    format!("{host}{kept}…")
+}
+
+/// Whether a link's text is just its URL, possibly without the scheme or cut short
+pub(crate) fn is_url_text(text: &str, uri: &str) -> bool {
+    let bare = |s: &str| -> String {
+        let s = s.split_once("://").map_or(s, |(_, rest)| rest);
+        let s = s.strip_prefix("www.").unwrap_or(s);
+        s.trim_end_matches('/').to_string()
+    };
+    let (text, uri) = (bare(text.trim()), bare(uri));
+    match text.strip_suffix(['…', '.']) {
+        Some(prefix) => uri.starts_with(prefix.trim_end_matches('.')),
+        None => text == uri,
+    }
+}
+
+/// Collapse runs of whitespace as HTML rendering would, without doubling up spaces or
+/// starting lines with them
+fn push_collapsed(out: &mut String, text: &str) {
+    for (i, word) in text.split(|c: char| c.is_ascii_whitespace()).enumerate() {
+        if i > 0 && !out.is_empty() && !out.ends_with([' ', '\n']) {
+            out.push(' ');
+        }
+        out.push_str(word);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What an `<a>` in post content turns into
+enum Anchor {
+    Plain,
+    Link,
+    Mention,
+}
+
+/// Convert fediverse post content to Bluesky rich text, with mentions as plain text
+pub fn from_html(html: &str) -> RichText {
+    from_html_with(html, |_, _| None)
+}
+
+/// Convert fediverse post content to Bluesky rich text
+///
+/// Paragraphs are separated by blank lines and `<br>`s become newlines. Links to `http(s)`
+/// URLs become link facets and hashtags are kept as plain text. Mentions are given to
+/// `mention` with their href and text, and become whichever facet it returns, or plain text
+/// if none
+pub fn from_html_with(
+    html: &str,
+    mut mention: impl FnMut(&str, &str) -> Option<Feature>,
+) -> RichText {
+    let mut rich = RichText::new();
+    // The link being read, and its text so far
+    let mut link: Option<(Anchor, String, String)> = None;
+    for token in tokenize(html) {
+        match &token {
+            Token::Text(text) => match &mut link {
+                Some((_, _, label)) => push_collapsed(label, text),
+                None => push_collapsed(&mut rich.text, text),
+            },
+            Token::Start { name, .. } if name == "p" && !rich.text.is_empty() => {
+                let trimmed = rich.text.trim_end().len();
+                rich.text.truncate(trimmed);
+                rich.push_str("\n\n");
+            }
+            Token::Start { name, .. } if name == "br" => {
+                let trimmed = rich.text.trim_end_matches(' ').len();
+                rich.text.truncate(trimmed);
+                rich.push_str("\n");
+            }
+            Token::Start { name, .. } if name == "a" => {
+                let href = token.attribute("href").unwrap_or_default();
+                let linkable = href.starts_with("http://") || href.starts_with("https://");
+                let anchor = match linkable {
+                    false => Anchor::Plain,
+                    _ if token.has_class("hashtag") => Anchor::Plain,
+                    _ if token.has_class("mention") => Anchor::Mention,
+                    _ => Anchor::Link,
+                };
+                link = Some((anchor, href.to_string(), String::new()));
+            }
+            Token::End { name } if name == "a" => {
+                let Some((anchor, href, label)) = link.take() else {
+                    continue;
+                };
+                let label = label.trim();
+                match anchor {
+                    Anchor::Plain => rich.push_str(label),
+                    Anchor::Mention => match mention(&href, label) {
+                        Some(feature) => rich.push_facet(label, feature),
+                        None => rich.push_str(label),
+                    },
+                    Anchor::Link if is_url_text(label, &href) || label.is_empty() => {
+                        rich.push_link(&shorten_url(&href), &href)
+                    }
+                    Anchor::Link => rich.push_link(label, &href),
+                }
+            }
+            _ => {}
+        }
+    }
+    let trimmed = rich.text.trim_end().len();
+    rich.text.truncate(trimmed);
+    rich
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shortens_like_the_official_client() {
+        assert_eq!(shorten_url("https://example.com/"), "example.com");
+        assert_eq!(
+            shorten_url("https://example.com/about"),
+            "example.com/about"
+        );
+        assert_eq!(
+            shorten_url("https://example.com/very/long/path/to/a/page?with=query"),
+            "example.com/very/long/pa…"
+        );
+        assert_eq!(
+            shorten_url("http://www.example.com:8080/ok"),
+            "www.example.com:8080/ok"
+        );
+        assert_eq!(shorten_url("mailto:a@example.com"), "mailto:a@example.com");
+    }
+
+    #[test]
+    fn converts_mastodon_html() {
+        let rich = from_html(
+            "<p>Café news: <a href=\"https://news.example/2024/05/01/a-very-long-headline\" \
+             rel=\"nofollow noopener\" target=\"_blank\"><span class=\"invisible\">https://</span>\
+             <span class=\"ellipsis\">news.example/2024/05/01/a-ve</span><span class=\"invisible\">\
+             ry-long-headline</span></a></p><p>via <a href=\"https://a.example/@bob\" \
+             class=\"u-url mention\">@<span>bob</span></a><br>and <a href=\"https://blog.example/\
+             post\">my blog</a></p>",
+        );
+        assert_eq!(
+            rich.text,
+            "Café news: news.example/2024/05/01/a…\n\nvia @bob\nand my blog"
+        );
+        let facets: Vec<_> = rich
+            .facets
+            .iter()
+            .map(|f| (&rich.text[f.byte_start..f.byte_end], &f.feature))
+            .collect();
+        assert_eq!(
+            facets,
+            vec![
+                (
+                    "news.example/2024/05/01/a…",
+                    &Feature::Link {
+                        uri: "https://news.example/2024/05/01/a-very-long-headline".to_string()
+                    }
+                ),
+                (
+                    "my blog",
+                    &Feature::Link {
+                        uri: "https://blog.example/post".to_string()
+                    }
+                ),
+            ]
+        );
+        let json = rich.facets_json();
+        let index = json.as_array().unwrap()[0].get("index").unwrap();
+        // Offsets are in bytes, so the é counts twice
+        assert_eq!(index.get("byteStart"), Some(&Value::from(12u32)));
+        let parsed: Vec<_> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(Facet::from_json)
+            .collect();
+        assert_eq!(
+            parsed,
+            rich.facets.into_iter().map(Some).collect::<Vec<_>>()
+        );
+    }
+}
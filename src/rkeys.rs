@@ -0,0 +1,129 @@
+//! Record keys derived from what a record was bridged from
+//!
+//! A record made from a fediverse object is keyed by the object's id, rather than by the
+//! clock: [`derive()`] hashes the id into a TID, so the same object always gets the same key.
+//! Replaying a delivery, retrying one which failed halfway, or two workers handling it at
+//! once then all land on the one record instead of each making another.
+//!
+//! Two ids can still hash to the same key. [`create`] is told how to recognise the record
+//! already made from its object, and where another has the key, moves on to the id's next
+//! candidate, up to [`MAX_ATTEMPTS`] of them. Derived keys' timestamps are kept before
+//! [`DERIVED_SPAN`], so they never sort among keys made from the clock
+
+use crate::bridge::Bridge;
+use crate::crypto::sha256;
+use crate::json::Value;
+use crate::repo::{RepoError, Write};
+use atproto::tid::Tid;
+use atproto::DID::Did;
+
+/// How many candidate keys an id has
+pub const MAX_ATTEMPTS: u16 = 16;
+/// The microseconds since the UNIX epoch derived keys' timestamps are kept under, which
+/// falls in 2005
+pub const DERIVED_SPAN: u64 = 1 << 50;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The key a record made from an object has
+pub enum Claimed {
+    /// The record was created under it
+    Created(String),
+    /// The record had been created under it already, and was left as it was
+    Existing(String),
+}
+
+impl Claimed {
+    pub fn rkey(&self) -> &str {
+        match self {
+            Claimed::Created(rkey) | Claimed::Existing(rkey) => rkey,
+        }
+    }
+}
+
+/// The `attempt`th candidate key for a record made from the object `origin`
+pub fn derive(origin: &str, attempt: u16) -> Tid {
+    let digest = sha256(format!("{origin}#{attempt}").as_bytes());
+    let bits = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    Tid::from_parts((bits >> 10) % DERIVED_SPAN, (bits & 0x3ff) as u16)
+}
+
+/// The first of `origin`'s keys which isn't `taken`, for records made several at a time
+pub fn free(origin: &str, taken: impl Fn(&str) -> bool) -> Option<Tid> {
+    let mut keys = (0..MAX_ATTEMPTS).map(|attempt| derive(origin, attempt));
+    keys.find(|key| !taken(key.as_str()))
+}
+
+/// Create `record` in `collection` of `did`'s repo, under the first of `origin`'s keys which
+/// is free or has the record already, as `same` says of what's there
+pub fn create(
+    bridge: &Bridge,
+    did: &Did,
+    collection: &str,
+    origin: &str,
+    record: Value,
+    same: impl Fn(&Value) -> bool,
+) -> Result<Claimed, RepoError> {
+    let mut claimed = None;
+    bridge.update_repo(did, || {
+        let records = bridge.repos.records(did, collection);
+        for attempt in 0..MAX_ATTEMPTS {
+            let rkey = derive(origin, attempt).to_string();
+            match records.iter().find(|(key, _)| *key == rkey) {
+                Some((_, existing)) if same(existing) => {
+                    claimed = Some(Claimed::Existing(rkey));
+                    return Ok(Vec::new());
+                }
+                Some(_) => continue,
+                None => {
+                    let path = format!("{collection}/{rkey}");
+                    claimed = Some(Claimed::Created(rkey));
+                    let record = record.clone();
+                    return Ok(vec![Write::Create { path, record }]);
+                }
+            }
+        }
+        let path = format!("{collection}/{}", derive(origin, MAX_ATTEMPTS - 1));
+        Err(RepoError::AlreadyExists { path })
+    })?;
+    Ok(claimed.expect("a key is claimed or an error returned"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use atproto::did;
+    use std::sync::Arc;
+
+    #[test]
+    fn the_same_origin_converges_on_one_record() {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let did = did!("did:plc:alice");
+        let generator = crate::keys::tests::FakeGenerator::default();
+        let owner = KeyOwner::Account(did.clone());
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+            .unwrap();
+        let collection = "app.bsky.feed.like";
+        let like = |subject: &str| Value::object([("subject", Value::from(subject))]);
+        let same = |subject: &'static str| {
+            move |record: &Value| record.get("subject") == Some(&Value::from(subject))
+        };
+
+        let origin = "https://mastodon.example/likes/1";
+        let key = derive(origin, 0);
+        assert_eq!(key, derive(origin, 0));
+        assert_ne!(key, derive(origin, 1));
+        assert!(key.timestamp_micros() < DERIVED_SPAN);
+        let first = create(&bridge, &did, collection, origin, like("a"), same("a")).unwrap();
+        assert_eq!(first, Claimed::Created(key.to_string()));
+        let again = create(&bridge, &did, collection, origin, like("a"), same("a")).unwrap();
+        assert_eq!(again, Claimed::Existing(key.to_string()));
+        // Another record already under the key, it moves on to the next
+        let other = create(&bridge, &did, collection, origin, like("b"), same("b")).unwrap();
+        assert_eq!(other, Claimed::Created(derive(origin, 1).to_string()));
+        assert_eq!(bridge.repos.records(&did, collection).len(), 2);
+    }
+}
@@ -0,0 +1,174 @@
+//! Using the bridge from async code, on whichever runtime the caller has
+//!
+//! The bridge itself is synchronous: it runs on threads, and its clients (the resolver,
+//! fetchers, XRPC calls) make requests through a blocking [`HttpTransport`]. It doesn't pick
+//! an executor, so embedders bring their own, meeting it at two points:
+//!
+//! - An async HTTP client (on tokio, smol or anything else) implements [`AsyncHttpTransport`],
+//!   and [`Blocking`] adapts it for the clients, driving each request with [`block_on`]. That
+//!   parks the calling thread, so the clients are called from a thread the runtime can spare,
+//!   such as one from its `spawn_blocking`
+//! - From async code, [`unblock`] runs a client call on its own thread and awaits the result,
+//!   and [`Unblocked`] does the same for a blocking transport
+//!
+//! Neither needs anything from a runtime beyond a [`Waker`]
+
+use crate::transport::{HttpTransport, OutboundRequest, OutboundResponse, TransportError};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+/// What [`AsyncHttpTransport::send`] returns
+pub type SendFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<OutboundResponse, TransportError>> + Send + 'a>>;
+
+/// A transport sending requests asynchronously
+pub trait AsyncHttpTransport: Send + Sync {
+    fn send<'a>(&'a self, request: &'a OutboundRequest) -> SendFuture<'a>;
+}
+
+/// Wakes a thread parked in [`block_on`]
+struct Unpark(Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` to completion on this thread, parking it while the future waits
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// A blocking transport sending through an async one
+pub struct Blocking<T>(pub T);
+
+impl<T: AsyncHttpTransport> HttpTransport for Blocking<T> {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        block_on(self.0.send(request))
+    }
+}
+
+type Outcome<T> = thread::Result<T>;
+
+struct Shared<T> {
+    outcome: Option<Outcome<T>>,
+    waker: Option<Waker>,
+}
+
+/// The result of a call [`unblock`] is making on another thread
+pub struct Unblock<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+/// Call `f` on a thread of its own, for async code to await without blocking its executor
+///
+/// If `f` panics, so does awaiting it
+pub fn unblock<T, F>(f: F) -> Unblock<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let shared = Arc::new(Mutex::new(Shared {
+        outcome: None,
+        waker: None,
+    }));
+    let theirs = shared.clone();
+    thread::spawn(move || {
+        let outcome = panic::catch_unwind(AssertUnwindSafe(f));
+        let mut shared = theirs.lock().unwrap();
+        shared.outcome = Some(outcome);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    });
+    Unblock { shared }
+}
+
+impl<T> Future for Unblock<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.outcome.take() {
+            Some(Ok(output)) => Poll::Ready(output),
+            Some(Err(panicked)) => panic::resume_unwind(panicked),
+            None => {
+                shared.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// An async transport sending through a blocking one, a thread per request
+pub struct Unblocked(pub Arc<dyn HttpTransport>);
+
+impl AsyncHttpTransport for Unblocked {
+    fn send<'a>(&'a self, request: &'a OutboundRequest) -> SendFuture<'a> {
+        let (transport, request) = (self.0.clone(), request.clone());
+        Box::pin(unblock(move || transport.send(&request)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    /// An async transport answering from a mock after yielding once, as a real one would
+    /// while waiting on its socket
+    struct Yielding(MockTransport);
+
+    impl AsyncHttpTransport for Yielding {
+        fn send<'a>(&'a self, request: &'a OutboundRequest) -> SendFuture<'a> {
+            let mut yielded = false;
+            Box::pin(std::future::poll_fn(move |context| {
+                if !yielded {
+                    yielded = true;
+                    context.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Poll::Ready(self.0.send(request))
+            }))
+        }
+    }
+
+    #[test]
+    fn clients_send_through_async_transports() {
+        let mock = MockTransport::new();
+        mock.respond_json("https://b.example/users/bob", r#"{"type": "Person"}"#);
+        let transport = Blocking(Yielding(mock));
+        let response = transport
+            .send(&OutboundRequest::get("https://b.example/users/bob"))
+            .unwrap();
+        assert_eq!(response.body, br#"{"type": "Person"}"#);
+
+        let unblocked = Unblocked(Arc::new(transport));
+        let request = OutboundRequest::get("https://b.example/users/bob");
+        assert!(block_on(unblocked.send(&request)).unwrap().is_success());
+    }
+
+    #[test]
+    fn unblocked_calls_return_or_panic_when_awaited() {
+        assert_eq!(block_on(unblock(|| 6 * 7)), 42);
+        let panicked = panic::catch_unwind(|| block_on(unblock(|| panic!("in the call"))));
+        assert!(panicked.is_err());
+    }
+}
@@ -0,0 +1,483 @@
+//! Suspending bridged identities and defederating instances
+//!
+//! Pausing an identity only stops bridging it; what it already published stays up. An
+//! operator dealing with abuse wants more, without the deletes of [unbridging](crate::unbridge)
+//! which can't be taken back. So sanctions change what's served rather than what's kept:
+//!
+//! - [suspending](suspend) an identity pauses it, serves its actor and everything attributed
+//!   to it as a `410 Gone` `Tombstone`, and freezes its repo, refusing every write and
+//!   serving it to relays as taken down
+//! - [defederating](defederate) an instance denies it in the [policy](crate::policy), so
+//!   nothing from it is accepted and nothing is delivered to it. Purging it suspends every
+//!   identity bridged from it too
+//!
+//! Each is recorded with what it replaced, and lifted with [`unsuspend`] and [`refederate`],
+//! which put that back: the identity's status, the domain's previous rule, and the
+//! suspensions a purge made. They're managed with the admin API's `/admin/suspensions` and
+//! `/admin/defederations`, or the `suspend`, `unsuspend`, `defederate` and `refederate`
+//! commands, and kept at the root of the state directory, shared by every shard
+
+use crate::bridge::Bridge;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::normalize;
+use crate::policy::{PolicyAction, PolicyError, Rule, Subject};
+use crate::storage::StateDir;
+use crate::store::MappingStatus;
+use crate::time::{format_rfc3339, parse_rfc3339};
+use crate::url::Url;
+use atproto::DID::Did;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// The sanctions' file in the state directory
+pub const SANCTIONS_FILE: &str = "sanctions.json";
+
+#[derive(Debug, Error)]
+/// Why a sanction couldn't be imposed or lifted
+pub enum SanctionError {
+    #[error("{did} isn't bridged")]
+    NotBridged { did: Did },
+    #[error("{did} is already suspended")]
+    AlreadySuspended { did: Did },
+    #[error("Expected a domain - found {found}")]
+    InvalidDomain { found: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A suspended identity
+pub struct Suspension {
+    pub did: Did,
+    /// Its status before, which lifting the suspension restores
+    pub previous: MappingStatus,
+    pub reason: Option<String>,
+    /// The domain whose defederation suspended it, if that's why
+    pub defederated: Option<String>,
+    pub at: SystemTime,
+}
+
+impl Suspension {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("did", Value::from(self.did.as_str())),
+            ("previous", Value::from(self.previous.as_str())),
+            ("reason", Value::from(self.reason.clone())),
+            ("defederated", Value::from(self.defederated.clone())),
+            ("at", Value::from(format_rfc3339(self.at))),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Suspension> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        Some(Suspension {
+            did: normalize::parse_did(field("did")?).ok()?,
+            previous: MappingStatus::parse(field("previous")?)?,
+            reason: field("reason").map(str::to_string),
+            defederated: field("defederated").map(str::to_string),
+            at: parse_rfc3339(field("at")?).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A defederated instance
+pub struct Defederation {
+    /// The lowercased domain, covering its subdomains
+    pub domain: String,
+    /// The domain's rule before, which refederating restores
+    pub previous: Option<Rule>,
+    /// Whether the identities bridged from it were suspended
+    pub purged: bool,
+    pub reason: Option<String>,
+    pub at: SystemTime,
+}
+
+impl Defederation {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("domain", Value::from(self.domain.as_str())),
+            (
+                "previous",
+                self.previous.as_ref().map_or(Value::Null, Rule::to_json),
+            ),
+            ("purged", Value::from(self.purged)),
+            ("reason", Value::from(self.reason.clone())),
+            ("at", Value::from(format_rfc3339(self.at))),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Defederation> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let previous = match value.get("previous") {
+            None | Some(Value::Null) => None,
+            Some(rule) => Some(Rule::from_json(rule).ok()?),
+        };
+        Some(Defederation {
+            domain: field("domain")?.to_string(),
+            previous,
+            purged: value.get("purged").and_then(Value::as_bool)?,
+            reason: field("reason").map(str::to_string),
+            at: parse_rfc3339(field("at")?).ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Imposed {
+    suspensions: BTreeMap<Did, Suspension>,
+    defederations: BTreeMap<String, Defederation>,
+}
+
+impl Imposed {
+    fn to_json(&self) -> Value {
+        let suspensions = self.suspensions.values().map(Suspension::to_json);
+        let defederations = self.defederations.values().map(Defederation::to_json);
+        Value::object([
+            ("suspensions", Value::Array(suspensions.collect())),
+            ("defederations", Value::Array(defederations.collect())),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// The sanctions in force
+pub struct Sanctions {
+    imposed: RwLock<Imposed>,
+    /// Where they're kept, if anywhere
+    dir: Option<StateDir>,
+}
+
+impl Sanctions {
+    /// The sanctions kept in `dir`
+    pub fn open(dir: StateDir) -> io::Result<Sanctions> {
+        let mut imposed = Imposed::default();
+        if let Some(contents) = dir.read(SANCTIONS_FILE)? {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid saved sanction");
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let entries = |name| {
+                saved
+                    .get(name)
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+            };
+            for entry in entries("suspensions") {
+                let suspension = Suspension::from_json(entry).ok_or_else(invalid)?;
+                imposed
+                    .suspensions
+                    .insert(suspension.did.clone(), suspension);
+            }
+            for entry in entries("defederations") {
+                let defederation = Defederation::from_json(entry).ok_or_else(invalid)?;
+                imposed
+                    .defederations
+                    .insert(defederation.domain.clone(), defederation);
+            }
+        }
+        Ok(Sanctions {
+            imposed: RwLock::new(imposed),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, imposed: &Imposed) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        dir.write(SANCTIONS_FILE, imposed.to_json().to_string().as_bytes())
+    }
+
+    pub fn suspension(&self, did: &Did) -> Option<Suspension> {
+        let imposed = self.imposed.read().unwrap();
+        imposed.suspensions.get(&normalize::did(did)).cloned()
+    }
+
+    pub fn is_suspended(&self, did: &Did) -> bool {
+        let imposed = self.imposed.read().unwrap();
+        imposed.suspensions.contains_key(&normalize::did(did))
+    }
+
+    pub fn defederation(&self, domain: &str) -> Option<Defederation> {
+        let imposed = self.imposed.read().unwrap();
+        imposed.defederations.get(domain).cloned()
+    }
+
+    pub fn suspensions(&self) -> Vec<Suspension> {
+        let imposed = self.imposed.read().unwrap();
+        imposed.suspensions.values().cloned().collect()
+    }
+
+    pub fn defederations(&self) -> Vec<Defederation> {
+        let imposed = self.imposed.read().unwrap();
+        imposed.defederations.values().cloned().collect()
+    }
+
+    /// Every suspension and defederation in force
+    pub fn to_json(&self) -> Value {
+        self.imposed.read().unwrap().to_json()
+    }
+
+    fn impose(&self, change: impl FnOnce(&mut Imposed)) -> io::Result<()> {
+        let mut imposed = self.imposed.write().unwrap();
+        change(&mut imposed);
+        self.save(&imposed)
+    }
+}
+
+/// Whether `host` is `domain` or under it
+fn on_domain(host: &str, domain: &str) -> bool {
+    host.strip_suffix(domain)
+        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+}
+
+/// Suspend the bridged identity `did`
+pub fn suspend(
+    bridge: &Bridge,
+    did: &Did,
+    reason: Option<&str>,
+    now: SystemTime,
+) -> Result<Suspension, SanctionError> {
+    suspend_for(bridge, did, reason, None, now)
+}
+
+fn suspend_for(
+    bridge: &Bridge,
+    did: &Did,
+    reason: Option<&str>,
+    defederated: Option<&str>,
+    now: SystemTime,
+) -> Result<Suspension, SanctionError> {
+    let not_bridged = || SanctionError::NotBridged { did: did.clone() };
+    let mapping = bridge.identities.get(did).ok_or_else(not_bridged)?;
+    if mapping.status == MappingStatus::Passive {
+        return Err(not_bridged());
+    }
+    if bridge.sanctions.is_suspended(&mapping.did) {
+        return Err(SanctionError::AlreadySuspended { did: mapping.did });
+    }
+    let suspension = Suspension {
+        did: mapping.did.clone(),
+        previous: mapping.status,
+        reason: reason.map(str::to_string),
+        defederated: defederated.map(str::to_string),
+        at: now,
+    };
+    bridge.sanctions.impose(|imposed| {
+        let suspension = suspension.clone();
+        imposed
+            .suspensions
+            .insert(suspension.did.clone(), suspension);
+    })?;
+    let _ = bridge
+        .identities
+        .set_status(&mapping.did, MappingStatus::Paused);
+    Ok(suspension)
+}
+
+/// Lift `did`'s suspension, returning it if there was one. An identity active before has its
+/// profile published again, replacing the tombstone
+pub fn unsuspend(bridge: &Bridge, did: &Did) -> Result<Option<Suspension>, SanctionError> {
+    let did = normalize::did(did);
+    let Some(suspension) = bridge.sanctions.suspension(&did) else {
+        return Ok(None);
+    };
+    bridge.sanctions.impose(|imposed| {
+        imposed.suspensions.remove(&*did);
+    })?;
+    if bridge
+        .identities
+        .set_status(&did, suspension.previous)
+        .is_ok()
+        && suspension.previous == MappingStatus::Active
+    {
+        bridge.jobs.push(Job::SyncProfile {
+            did: did.into_owned(),
+        })?;
+    }
+    Ok(Some(suspension))
+}
+
+/// Defederate `domain`, suspending the identities bridged from it if `purge`. Defederating
+/// it again keeps what it replaced, and may purge what it didn't before
+pub fn defederate(
+    bridge: &Bridge,
+    domain: &str,
+    purge: bool,
+    reason: Option<&str>,
+    now: SystemTime,
+) -> Result<Defederation, SanctionError> {
+    let invalid = |_: PolicyError| SanctionError::InvalidDomain {
+        found: domain.to_string(),
+    };
+    let subject = domain.parse::<Subject>().map_err(invalid)?;
+    let Subject::Domain(domain) = subject.clone() else {
+        return Err(SanctionError::InvalidDomain {
+            found: domain.to_string(),
+        });
+    };
+    let existing = bridge.sanctions.defederation(&domain);
+    let previous = match &existing {
+        Some(existing) => existing.previous.clone(),
+        None => bridge
+            .policy
+            .rules()
+            .into_iter()
+            .find(|rule| rule.subject == subject),
+    };
+    let mut rule = previous.clone().unwrap_or(Rule {
+        subject,
+        actions: Vec::new(),
+    });
+    rule.add(PolicyAction::Deny);
+    bridge.policy.set(rule)?;
+    let purged = purge || existing.as_ref().is_some_and(|e| e.purged);
+    let defederation = Defederation {
+        domain: domain.clone(),
+        previous,
+        purged,
+        reason: reason.map(str::to_string),
+        at: existing.map_or(now, |e| e.at),
+    };
+    bridge.sanctions.impose(|imposed| {
+        let defederation = defederation.clone();
+        imposed.defederations.insert(domain.clone(), defederation);
+    })?;
+    if purge {
+        for mapping in bridge.identities.all() {
+            let host = Url::parse(&mapping.actor).map(|url| url.host);
+            if !host.is_ok_and(|host| on_domain(&host, &domain)) {
+                continue;
+            }
+            match suspend_for(bridge, &mapping.did, reason, Some(&domain), now) {
+                Ok(_)
+                | Err(SanctionError::NotBridged { .. })
+                | Err(SanctionError::AlreadySuspended { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(defederation)
+}
+
+/// Federate with `domain` again, returning its defederation if it was. Its previous rule is
+/// restored, and the suspensions its purge made are lifted
+pub fn refederate(bridge: &Bridge, domain: &str) -> Result<Option<Defederation>, SanctionError> {
+    let domain = domain.to_ascii_lowercase();
+    let Some(defederation) = bridge.sanctions.defederation(&domain) else {
+        return Ok(None);
+    };
+    match &defederation.previous {
+        Some(rule) => bridge.policy.set(rule.clone())?,
+        None => {
+            bridge.policy.remove(&Subject::Domain(domain.clone()))?;
+        }
+    }
+    bridge.sanctions.impose(|imposed| {
+        imposed.defederations.remove(&domain);
+    })?;
+    let purged = bridge.sanctions.suspensions().into_iter();
+    for suspension in purged.filter(|s| s.defederated.as_deref() == Some(domain.as_str())) {
+        unsuspend(bridge, &suspension.did)?;
+    }
+    Ok(Some(defederation))
+}
+
+/// Whether `document`, served by the bridge, is or is attributed to a suspended identity's
+/// actor, and should be tombstoned
+pub fn tombstoned(bridge: &Bridge, document: &Value) -> bool {
+    ["id", "actor", "attributedTo"].into_iter().any(|field| {
+        let actor = document.get(field).and_then(Value::as_str);
+        let mapping = actor.and_then(|actor| bridge.identities.get_by_actor(actor));
+        mapping.is_some_and(|m| bridge.sanctions.is_suspended(&m.did))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy;
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    #[test]
+    fn sanctions_are_lifted_as_they_were_imposed() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            sanctions: Sanctions::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        let alice = did!("did:web:bridge.example:u:alice");
+        let bob = did!("did:web:bridge.example:u:bob");
+        let carol = did!("did:plc:carol");
+        bridge.identities.insert(Mapping::new(
+            alice.clone(),
+            "https://spam.example/users/alice",
+        ));
+        bridge.identities.insert(Mapping::new(
+            bob.clone(),
+            "https://eu.spam.example/users/bob",
+        ));
+        bridge.identities.insert(Mapping::new(
+            carol.clone(),
+            "https://bridge.example/ap/carol",
+        ));
+        bridge
+            .identities
+            .set_status(&bob, MappingStatus::Paused)
+            .unwrap();
+        let now = SystemTime::now();
+
+        suspend(&bridge, &carol, Some("Harassment"), now).unwrap();
+        assert!(matches!(
+            suspend(&bridge, &carol, None, now),
+            Err(SanctionError::AlreadySuspended { .. })
+        ));
+        let note = json::parse(
+            r#"{"id": "https://bridge.example/ap/carol/1",
+                "attributedTo": "https://bridge.example/ap/carol"}"#,
+        )
+        .unwrap();
+        assert!(tombstoned(&bridge, &note));
+
+        let silenced = policy::parse_rules("spam.example=silence").unwrap();
+        bridge.policy.merge(silenced).unwrap();
+        defederate(&bridge, "Spam.Example", true, None, now).unwrap();
+        assert!(bridge.policy.verdict(Some("eu.spam.example"), None).denied);
+        assert!(bridge.sanctions.is_suspended(&alice));
+        assert!(bridge.sanctions.is_suspended(&bob));
+        assert_eq!(
+            bridge.identities.get(&alice).unwrap().status,
+            MappingStatus::Paused
+        );
+
+        // They outlive a restart
+        let bridge = Bridge {
+            sanctions: Sanctions::open(dir).unwrap(),
+            ..bridge
+        };
+        assert_eq!(bridge.sanctions.suspensions().len(), 3);
+        refederate(&bridge, "spam.example").unwrap().unwrap();
+        let verdict = bridge.policy.verdict(Some("spam.example"), None);
+        assert!(!verdict.denied && verdict.silenced);
+        assert_eq!(
+            bridge.identities.get(&alice).unwrap().status,
+            MappingStatus::Active
+        );
+        // bob was paused before, and stays so
+        assert_eq!(
+            bridge.identities.get(&bob).unwrap().status,
+            MappingStatus::Paused
+        );
+        // Carol's suspension wasn't the purge's to lift
+        assert!(bridge.sanctions.is_suspended(&carol));
+        unsuspend(&bridge, &carol).unwrap().unwrap();
+        assert!(!tombstoned(&bridge, &note));
+        assert!(unsuspend(&bridge, &carol).unwrap().is_none());
+    }
+}
@@ -0,0 +1,351 @@
+//! Bridging an account's posts only at the times it chooses
+//!
+//! People cross-posting to both networks sometimes don't want their posts arriving on the
+//! other one at night, or want a day's posts to go out together. A [`Schedule`] in an
+//! account's preferences says when its posts may be delivered:
+//!
+//! - quiet hours, such as `22:00-07:00`, during which nothing is delivered
+//! - a daily time, such as `18:00`, from which the day's posts are delivered for an hour
+//!
+//! in an offset from UTC of its choosing. Deliveries of its posts (`Create`, `Announce`,
+//! `Update` and `Delete` of anything but the actor itself) which run outside those times
+//! are deferred in the queue until the schedule next opens, while follows, replies to
+//! follows and profile updates go as they always would. As they wait, a held post's
+//! deliveries are coalesced: an `Update` replaces the held `Create` or `Update` of the same
+//! object to the same inbox, and a `Delete` of a post whose `Create` is still held means
+//! neither is sent
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long after a schedule's daily time its held posts may still be delivered
+pub const BATCH_WINDOW: Duration = Duration::from_secs(60 * 60);
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// When an account's posts may be bridged. Times are minutes after local midnight
+pub struct Schedule {
+    /// The local time's offset from UTC, in minutes east
+    pub offset: i32,
+    /// When nothing is delivered, from the first minute until the second
+    pub quiet: Option<(u32, u32)>,
+    /// When the posts held since the last one are delivered
+    pub daily: Option<u32>,
+}
+
+/// A time of day such as `07:30`, as minutes after midnight
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    if minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn format_time(minutes: u32) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+/// An offset such as `+02:00`, `-05:30` or `Z`, as minutes east of UTC
+fn parse_offset(offset: &str) -> Option<i32> {
+    let offset = offset.trim();
+    if offset == "Z" {
+        return Some(0);
+    }
+    let (sign, time) = match offset.split_at_checked(1)? {
+        ("+", time) => (1, time),
+        ("-", time) => (-1, time),
+        _ => return None,
+    };
+    let minutes = parse_time(time)?;
+    Some(sign * minutes as i32)
+}
+
+fn format_offset(offset: i32) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("{sign}{}", format_time(offset.unsigned_abs()))
+}
+
+impl Schedule {
+    /// A schedule from the preferences JSON [`Schedule::to_json`] writes, such as
+    /// `{"quietHours": "22:00-07:00", "dailyAt": "18:00", "utcOffset": "+02:00"}`
+    pub fn from_json(value: &Value) -> Result<Schedule, String> {
+        if !matches!(value, Value::Object(_)) {
+            return Err("schedule must be an object or null".to_string());
+        }
+        let text = |name: &str| match value.get(name).filter(|v| !matches!(v, Value::Null)) {
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| format!("{name} must be a string")),
+            None => Ok(None),
+        };
+        let offset = match text("utcOffset")? {
+            Some(offset) => parse_offset(offset).ok_or("utcOffset must be like +02:00")?,
+            None => 0,
+        };
+        let quiet = match text("quietHours")? {
+            Some(hours) => {
+                let invalid = || "quietHours must be like 22:00-07:00".to_string();
+                let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+                let hours = (parse_time(start), parse_time(end));
+                let (Some(start), Some(end)) = hours else {
+                    return Err(invalid());
+                };
+                Some((start, end)).filter(|_| start != end)
+            }
+            None => None,
+        };
+        let daily = match text("dailyAt")? {
+            Some(time) => Some(parse_time(time).ok_or("dailyAt must be like 18:00")?),
+            None => None,
+        };
+        let schedule = Schedule {
+            offset,
+            quiet,
+            daily,
+        };
+        if let (Some(daily), Some(_)) = (daily, quiet) {
+            if schedule.is_quiet(daily) {
+                return Err("dailyAt can't be during quietHours".to_string());
+            }
+        }
+        Ok(schedule)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let quiet = self
+            .quiet
+            .map(|(start, end)| format!("{}-{}", format_time(start), format_time(end)));
+        Value::object([
+            ("utcOffset", Value::from(format_offset(self.offset))),
+            ("quietHours", Value::from(quiet)),
+            ("dailyAt", Value::from(self.daily.map(format_time))),
+        ])
+    }
+
+    fn is_quiet(&self, minute: u32) -> bool {
+        match self.quiet {
+            Some((start, end)) if start < end => (start..end).contains(&minute),
+            Some((start, end)) => minute >= start || minute < end,
+            None => false,
+        }
+    }
+
+    /// When posts may next be delivered after `now`, or `None` if they may be now
+    pub fn opens(&self, now: SystemTime) -> Option<SystemTime> {
+        let mut at = now;
+        // Whichever of the quiet hours and the daily time keeps it closed longest, until both
+        // agree it's open. A daily time is never in the quiet hours, so that's twice at most
+        for _ in 0..3 {
+            let local = seconds(at) + i64::from(self.offset) * 60;
+            let minute = local.div_euclid(60).rem_euclid(MINUTES_PER_DAY) as u32;
+            let next = |minute: u32| {
+                let midnight = local - local.rem_euclid(86_400);
+                let mut next = midnight + i64::from(minute) * 60;
+                if next <= local {
+                    next += 86_400;
+                }
+                time(next - i64::from(self.offset) * 60)
+            };
+            let quiet = self
+                .quiet
+                .filter(|_| self.is_quiet(minute))
+                .map(|(_, end)| next(end));
+            let batching = self.daily.filter(|&daily| {
+                let since = (i64::from(minute) - i64::from(daily)).rem_euclid(MINUTES_PER_DAY);
+                since >= BATCH_WINDOW.as_secs() as i64 / 60
+            });
+            let closed = quiet.into_iter().chain(batching.map(next)).max();
+            match closed {
+                Some(until) => at = until,
+                None => break,
+            }
+        }
+        (at > now).then_some(at)
+    }
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn time(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What becomes of a delivery, by its account's schedule
+pub enum Hold {
+    /// It's sent now
+    Send,
+    /// It waits in the queue until then
+    Until(SystemTime),
+    /// It's been merged into a delivery still held, or cancels one out, so isn't sent
+    Coalesced,
+}
+
+/// The `type` and object IRI of a delivery's activity
+fn post(activity: &Value) -> Option<(&str, &str)> {
+    let kind = activity.get("type")?.as_str()?;
+    let object = activity.get("object")?;
+    let object = object.as_str().or_else(|| object.get("id")?.as_str())?;
+    Some((kind, object))
+}
+
+/// Whether `delivery` waits for its account's schedule to open, at `now`
+///
+/// Held deliveries of the same object to the same inbox it supersedes are cancelled, and
+/// one it can be merged into replaced by the merge, scheduled for when the schedule opens
+pub fn hold(bridge: &Bridge, delivery: &Delivery, now: SystemTime) -> io::Result<Hold> {
+    let Ok(activity) = json::parse(&delivery.activity) else {
+        return Ok(Hold::Send);
+    };
+    let actor = activity.get("actor").and_then(Value::as_str);
+    let mapping = actor.and_then(|actor| bridge.identities.get_by_actor(actor));
+    let Some(schedule) = mapping.and_then(|m| m.preferences.schedule) else {
+        return Ok(Hold::Send);
+    };
+    let Some((kind, object)) = post(&activity) else {
+        return Ok(Hold::Send);
+    };
+    if !matches!(kind, "Create" | "Announce" | "Update" | "Delete") || Some(object) == actor {
+        return Ok(Hold::Send);
+    }
+    let Some(until) = schedule.opens(now) else {
+        return Ok(Hold::Send);
+    };
+    if !matches!(kind, "Update" | "Delete") {
+        return Ok(Hold::Until(until));
+    }
+    // What's held for this object and inbox, which this delivery itself is among
+    let held = |d: &Delivery, kinds: &[&str]| {
+        let activity = json::parse(&d.activity).ok();
+        let post = activity.as_ref().and_then(post);
+        d.inbox == delivery.inbox
+            && d != delivery
+            && post.is_some_and(|(k, o)| o == object && kinds.contains(&k))
+    };
+    let created = bridge
+        .jobs
+        .queued()
+        .into_iter()
+        .find_map(|queued| match queued.job {
+            Job::Deliver(d) if held(&d, &["Create"]) => Some(d),
+            _ => None,
+        });
+    bridge
+        .jobs
+        .cancel(|job| matches!(job, Job::Deliver(d) if held(d, &["Create", "Update"])))?;
+    let Some(created) = created else {
+        return Ok(Hold::Until(until));
+    };
+    if kind == "Update" {
+        let mut merged = json::parse(&created.activity).unwrap_or(Value::Null);
+        if let (Value::Object(fields), Some(updated)) = (&mut merged, activity.get("object")) {
+            fields.insert("object".to_string(), updated.clone());
+        }
+        let merged = Delivery {
+            activity: merged.to_string(),
+            ..created
+        };
+        let job = Job::Deliver(merged);
+        let priority = job.default_priority();
+        bridge.jobs.schedule(job, priority, until)?;
+    }
+    Ok(Hold::Coalesced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Mapping, Preferences};
+    use atproto::did;
+
+    const ACTOR: &str = "https://bridge.example/ap/did:plc:alice";
+
+    fn activity(kind: &str, id: &str, content: &str) -> Delivery {
+        let activity = Value::object([
+            ("id", Value::from(id)),
+            ("type", Value::from(kind)),
+            ("actor", Value::from(ACTOR)),
+            (
+                "object",
+                Value::object([
+                    ("id", Value::from("https://bridge.example/notes/1")),
+                    ("content", Value::from(content)),
+                ]),
+            ),
+        ]);
+        Delivery::new("https://a.example/inbox", activity.to_string())
+    }
+
+    #[test]
+    fn holds_and_coalesces_posts_outside_the_schedule() {
+        let schedule = Value::object([
+            ("utcOffset", Value::from("+02:00")),
+            ("quietHours", Value::from("22:00-07:00")),
+        ]);
+        let schedule = Schedule::from_json(&schedule).unwrap();
+        assert_eq!(Schedule::from_json(&schedule.to_json()), Ok(schedule));
+        // 23:30 local, the quiet hours ending at 05:00 UTC the next day
+        let night = UNIX_EPOCH + Duration::from_secs(21 * 3600 + 30 * 60);
+        let morning = UNIX_EPOCH + Duration::from_secs(29 * 3600);
+        assert_eq!(schedule.opens(night), Some(morning));
+        assert_eq!(schedule.opens(morning), None);
+        let daily = Schedule {
+            daily: Some(18 * 60),
+            ..schedule
+        };
+        // 07:00 local waits for the day's batch at 18:00, 16:00 UTC
+        let batch = UNIX_EPOCH + Duration::from_secs(40 * 3600);
+        assert_eq!(daily.opens(night), Some(batch));
+        assert_eq!(daily.opens(batch + Duration::from_secs(600)), None);
+        let invalid = Value::object([
+            ("quietHours", Value::from("17:00-19:00")),
+            ("dailyAt", Value::from("18:00")),
+        ]);
+        assert!(Schedule::from_json(&invalid).is_err());
+
+        let bridge = Bridge::new();
+        let mut mapping = Mapping::new(did!("did:plc:alice"), ACTOR);
+        mapping.preferences = Preferences {
+            schedule: Some(schedule),
+            ..Preferences::default()
+        };
+        bridge.identities.insert(mapping);
+        let create = activity("Create", "https://bridge.example/c/1", "first");
+        assert_eq!(hold(&bridge, &create, morning).unwrap(), Hold::Send);
+        assert_eq!(hold(&bridge, &create, night).unwrap(), Hold::Until(morning));
+        bridge.jobs.push(Job::Deliver(create.clone())).unwrap();
+
+        // An edit before the post goes is folded into it
+        let update = activity("Update", "https://bridge.example/u/1", "edited");
+        assert_eq!(hold(&bridge, &update, night).unwrap(), Hold::Coalesced);
+        let queued = bridge.jobs.queued();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].run_at, morning);
+        let Job::Deliver(merged) = &queued[0].job else {
+            panic!("expected a delivery");
+        };
+        let merged = json::parse(&merged.activity).unwrap();
+        assert_eq!(
+            merged.get("id").and_then(Value::as_str),
+            Some("https://bridge.example/c/1")
+        );
+        let content = merged.get("object").and_then(|o| o.get("content"));
+        assert_eq!(content.and_then(Value::as_str), Some("edited"));
+
+        // And deleting it means nothing's sent at all
+        let delete = activity("Delete", "https://bridge.example/d/1", "");
+        assert_eq!(hold(&bridge, &delete, night).unwrap(), Hold::Coalesced);
+        assert!(bridge.jobs.queued().is_empty());
+    }
+}
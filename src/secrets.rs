@@ -0,0 +1,272 @@
+//! Reading secrets from where operators keep them
+//!
+//! A secret in configuration, such as `FEDIBRIDGE_ADMIN_TOKEN` or the keystore passphrase the
+//! signing keys are encrypted with, needn't be in the environment in plain text. Instead of
+//! `VAR` itself, one of these can be set:
+//!
+//! - `VAR_FILE`: a file holding it, such as a mounted Docker or Kubernetes secret
+//! - `VAR_FROM`: a reference `<provider>:<reference>` to a [`SecretProvider`], such as
+//!   `fd:3` for a file descriptor the secret is written to by whatever started the bridge, or
+//!   `vault:secret/data/fedibridge#admin_token` for a Vault KV secret
+//!   (`FEDIBRIDGE_VAULT_ADDR`, `FEDIBRIDGE_VAULT_TOKEN`, which can be read from a file too)
+//!
+//! Other stores, such as a cloud KMS, are plugged in by registering a provider with
+//! [`Secrets::with_provider`] and loading the configuration with
+//! [`Config::from_vars_with_secrets`](crate::config::Config::from_vars_with_secrets). Secrets
+//! are read once, at startup, and trailing newlines are dropped
+
+use crate::json::{self, Value};
+use crate::transport::{self, HttpTransport, OutboundRequest};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors reading a secret
+pub enum SecretError {
+    #[error("{var} is set along with {var}_FILE or {var}_FROM - choose one")]
+    Ambiguous { var: String },
+    #[error("{var}_FROM should be <provider>:<reference> - found {found}")]
+    Malformed { var: String, found: String },
+    #[error("No secret provider {provider} for {var}_FROM")]
+    UnknownProvider { var: String, provider: String },
+    #[error("Couldn't read {var} from {provider} - {reason}")]
+    Unavailable {
+        var: String,
+        provider: String,
+        reason: String,
+    },
+}
+
+/// Somewhere secrets are kept, which can look one up by reference
+pub trait SecretProvider: Send + Sync {
+    /// The secret `reference` names, or why it couldn't be read
+    fn fetch(&self, reference: &str) -> Result<String, String>;
+}
+
+/// Secrets in files, by path
+pub struct FileSecrets;
+
+impl SecretProvider for FileSecrets {
+    fn fetch(&self, path: &str) -> Result<String, String> {
+        fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))
+    }
+}
+
+/// Secrets written to file descriptors the bridge was started with, by number
+pub struct FdSecrets;
+
+impl SecretProvider for FdSecrets {
+    fn fetch(&self, fd: &str) -> Result<String, String> {
+        let fd: u32 = fd
+            .parse()
+            .map_err(|_| format!("{fd} isn't a file descriptor"))?;
+        fs::read_to_string(format!("/dev/fd/{fd}")).map_err(|e| format!("descriptor {fd}: {e}"))
+    }
+}
+
+/// Secrets in a Vault KV engine, referenced as `<path>#<key>`
+pub struct VaultSecrets {
+    transport: Arc<dyn HttpTransport>,
+    addr: String,
+    token: String,
+}
+
+impl VaultSecrets {
+    pub fn new(transport: Arc<dyn HttpTransport>, addr: &str, token: &str) -> VaultSecrets {
+        VaultSecrets {
+            transport,
+            addr: addr.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+        }
+    }
+}
+
+impl SecretProvider for VaultSecrets {
+    fn fetch(&self, reference: &str) -> Result<String, String> {
+        let (path, key) = reference
+            .split_once('#')
+            .ok_or_else(|| format!("{reference} should be <path>#<key>"))?;
+        let url = format!("{}/v1/{}", self.addr, path.trim_start_matches('/'));
+        let request = OutboundRequest::get(&url).with_header("x-vault-token", &self.token);
+        let response = self.transport.send(&request).map_err(|e| e.to_string())?;
+        if !response.is_success() {
+            return Err(format!("{url} responded with {}", response.status));
+        }
+        let body = json::parse(&String::from_utf8_lossy(&response.body))
+            .map_err(|_| format!("{url} didn't respond with JSON"))?;
+        // Version 2 of the KV engine keeps the secret's fields under `data.data`
+        let data = body.get("data");
+        let field = |data: Option<&Value>| data?.get(key)?.as_str().map(str::to_string);
+        field(data.and_then(|data| data.get("data")))
+            .or_else(|| field(data))
+            .ok_or_else(|| format!("{path} has no {key}"))
+    }
+}
+
+/// The secret providers configured values can be read from, by name
+pub struct Secrets {
+    providers: HashMap<String, Arc<dyn SecretProvider>>,
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Secrets::new()
+    }
+}
+
+impl Secrets {
+    /// Reading from files and file descriptors
+    pub fn new() -> Secrets {
+        let providers: [(&str, Arc<dyn SecretProvider>); 2] =
+            [("file", Arc::new(FileSecrets)), ("fd", Arc::new(FdSecrets))];
+        Secrets {
+            providers: providers
+                .into_iter()
+                .map(|(name, provider)| (name.to_string(), provider))
+                .collect(),
+        }
+    }
+
+    /// The providers configured by `lookup`: those of [`Secrets::new`], and Vault if
+    /// `FEDIBRIDGE_VAULT_ADDR` and `FEDIBRIDGE_VAULT_TOKEN` are set
+    pub fn from_vars(lookup: impl Fn(&str) -> Option<String>) -> Result<Secrets, SecretError> {
+        let secrets = Secrets::new();
+        let addr = lookup("FEDIBRIDGE_VAULT_ADDR").filter(|addr| !addr.is_empty());
+        let token = secrets.read(&lookup, "FEDIBRIDGE_VAULT_TOKEN")?;
+        Ok(match (addr, token) {
+            (Some(addr), Some(token)) => {
+                let vault = VaultSecrets::new(transport::platform_default(), &addr, &token);
+                secrets.with_provider("vault", Arc::new(vault))
+            }
+            _ => secrets,
+        })
+    }
+
+    /// These providers, and `provider` as `name`
+    pub fn with_provider(mut self, name: &str, provider: Arc<dyn SecretProvider>) -> Secrets {
+        self.providers.insert(name.to_string(), provider);
+        self
+    }
+
+    /// The value of `var` by `lookup`, or the secret `var_FILE` or `var_FROM` refers to
+    pub fn read(
+        &self,
+        lookup: &impl Fn(&str) -> Option<String>,
+        var: &str,
+    ) -> Result<Option<String>, SecretError> {
+        let nonempty = |var: &str| lookup(var).filter(|value| !value.is_empty());
+        let (provider, reference) = match (
+            nonempty(&format!("{var}_FILE")),
+            nonempty(&format!("{var}_FROM")),
+        ) {
+            (Some(path), None) => ("file".to_string(), path),
+            (None, Some(reference)) => match reference.split_once(':') {
+                Some((provider, reference)) => (provider.to_string(), reference.to_string()),
+                None => {
+                    return Err(SecretError::Malformed {
+                        var: var.to_string(),
+                        found: reference,
+                    })
+                }
+            },
+            (None, None) => return Ok(lookup(var)),
+            (Some(_), Some(_)) => {
+                return Err(SecretError::Ambiguous {
+                    var: var.to_string(),
+                })
+            }
+        };
+        if nonempty(var).is_some() {
+            return Err(SecretError::Ambiguous {
+                var: var.to_string(),
+            });
+        }
+        let Some(source) = self.providers.get(&provider) else {
+            return Err(SecretError::UnknownProvider {
+                var: var.to_string(),
+                provider,
+            });
+        };
+        let secret = source
+            .fetch(&reference)
+            .map_err(|reason| SecretError::Unavailable {
+                var: var.to_string(),
+                provider,
+                reason,
+            })?;
+        Ok(Some(secret.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Read every one of `vars`, for those which are set
+    pub fn read_all(
+        &self,
+        lookup: &impl Fn(&str) -> Option<String>,
+        vars: &[&str],
+    ) -> Result<BTreeMap<String, String>, SecretError> {
+        let mut secrets = BTreeMap::new();
+        for var in vars {
+            if let Some(secret) = self.read(lookup, var)? {
+                secrets.insert(var.to_string(), secret);
+            }
+        }
+        Ok(secrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn secrets_are_read_from_where_they_are_referred_to() {
+        let dir = temp_state_dir();
+        dir.write("admin-token", b"from a file\n").unwrap();
+        let path = dir.path().join("admin-token");
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            "https://vault.example/v1/secret/data/fedibridge",
+            r#"{"data": {"data": {"passphrase": "from vault"}}}"#,
+        );
+        let secrets = Secrets::new().with_provider(
+            "vault",
+            Arc::new(VaultSecrets::new(mock, "https://vault.example/", "t")),
+        );
+        let vars: HashMap<&str, String> = HashMap::from([
+            ("PLAIN", "as it is".to_string()),
+            ("ADMIN_TOKEN_FILE", path.display().to_string()),
+            (
+                "PASSPHRASE_FROM",
+                "vault:secret/data/fedibridge#passphrase".to_string(),
+            ),
+            (
+                "MISSING_FROM",
+                "vault:secret/data/fedibridge#missing".to_string(),
+            ),
+            ("KMS_FROM", "kms:key".to_string()),
+            ("BOTH", "plain".to_string()),
+            ("BOTH_FILE", path.display().to_string()),
+        ]);
+        let lookup = |var: &str| vars.get(var).cloned();
+        let read = |var| secrets.read(&lookup, var);
+
+        assert_eq!(read("PLAIN"), Ok(Some("as it is".to_string())));
+        assert_eq!(read("UNSET"), Ok(None));
+        assert_eq!(read("ADMIN_TOKEN"), Ok(Some("from a file".to_string())));
+        assert_eq!(read("PASSPHRASE"), Ok(Some("from vault".to_string())));
+        assert!(matches!(
+            read("MISSING"),
+            Err(SecretError::Unavailable { .. })
+        ));
+        assert!(matches!(
+            read("KMS"),
+            Err(SecretError::UnknownProvider { .. })
+        ));
+        assert!(matches!(read("BOTH"), Err(SecretError::Ambiguous { .. })));
+        let all = secrets.read_all(&lookup, &["PLAIN", "ADMIN_TOKEN", "UNSET"]);
+        assert_eq!(all.map(|all| all.len()), Ok(2));
+    }
+}
@@ -0,0 +1,352 @@
+//! Remembering the objects and records recently translated
+//!
+//! Relays and shared inboxes hand the bridge the same post many times, each time wrapped in
+//! a different activity, so [`SeenActivities`](crate::dedup::SeenActivities) can't tell
+//! they're the same. What they share is the object: a `Note`'s IRI, or a record's
+//! `at://` URI. Before translating one, [`translate_once`] looks it up in [`SeenObjects`]:
+//!
+//! - the last [`WINDOW`] keys are kept exactly, and anything among them is skipped
+//! - older keys are kept in a Bloom filter, a bit array a few bits per key. One it has never
+//!   had answers [`Sighting::Unseen`], and is translated without looking any further; one it
+//!   has answers [`Sighting::Probably`], since it answers that for a few keys in a thousand
+//!   it never had, so the caller is asked to confirm before it's skipped
+//!
+//! The filter is kept in two generations of up to [`GENERATION`] keys, the older dropped as
+//! the newer fills, so it remembers between one and two generations back in a fixed
+//! [`FILTER_BYTES`] apiece. Keys should change with what they name: an edited post is
+//! translated again, so a record is seen by its URI and CID. The generations are written to
+//! the shard's state directory as they're replaced and at shutdown, and the window is
+//! appended to it as it grows
+
+use crate::bridge::Bridge;
+use crate::crypto::sha256;
+use crate::json::Value;
+use crate::storage::{JsonLog, StateDir};
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::sync::Mutex;
+
+/// The filter generations, both of them, as they were last saved
+pub const FILTER_FILE: &str = "seen-objects.bloom";
+/// The window, one key per line
+pub const WINDOW_FILE: &str = "seen-objects.jsonl";
+/// How many of the most recent keys are kept exactly
+pub const WINDOW: usize = 10_000;
+/// How many keys a filter generation has before it's replaced
+pub const GENERATION: usize = 100_000;
+/// The size of a filter generation, chosen so [`GENERATION`] keys in it answer
+/// [`Sighting::Probably`] for under one key in a hundred never added
+pub const FILTER_BYTES: usize = 128 * 1024;
+/// The bits set for each key
+const HASHES: u64 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What [`SeenObjects`] knows of a key
+pub enum Sighting {
+    /// It has never been seen
+    Unseen,
+    /// It's among the last [`WINDOW`] seen
+    Recent,
+    /// It was seen before those, or it may only look like it was
+    Probably,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    words: Vec<u64>,
+    keys: usize,
+}
+
+impl Filter {
+    fn new() -> Filter {
+        Filter {
+            words: vec![0; FILTER_BYTES / 8],
+            keys: 0,
+        }
+    }
+
+    /// The bits `key` sets, by double hashing
+    fn bits(key: &str) -> impl Iterator<Item = usize> {
+        let hash = sha256(key.as_bytes());
+        let half = |at: usize| u64::from_le_bytes(hash[at..at + 8].try_into().unwrap());
+        let (first, second) = (half(0), half(8) | 1);
+        let size = (FILTER_BYTES * 8) as u64;
+        (0..HASHES).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+    }
+
+    fn insert(&mut self, key: &str) {
+        for bit in Filter::bits(key) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+        self.keys += 1;
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        Filter::bits(key).all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn encode(&self, into: &mut Vec<u8>) {
+        into.extend((self.keys as u64).to_le_bytes());
+        into.extend(self.words.iter().flat_map(|word| word.to_le_bytes()));
+    }
+
+    /// A filter from the start of `bytes`, and what follows it
+    fn decode(bytes: &[u8]) -> Option<(Filter, &[u8])> {
+        let (keys, rest) = bytes.split_first_chunk::<8>()?;
+        let (words, rest) = rest.split_at_checked(FILTER_BYTES)?;
+        let words = words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()));
+        let keys = u64::from_le_bytes(*keys) as usize;
+        Some((
+            Filter {
+                words: words.collect(),
+                keys,
+            },
+            rest,
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct Index {
+    current: Filter,
+    previous: Filter,
+    window: VecDeque<String>,
+    recent: HashSet<String>,
+    /// The lines in the window file, which is rewritten once it's twice the window
+    lines: usize,
+}
+
+#[derive(Debug)]
+/// The objects and records translated recently, by their keys
+pub struct SeenObjects {
+    index: Mutex<Index>,
+    dir: Option<StateDir>,
+    window: Option<JsonLog>,
+}
+
+impl Default for SeenObjects {
+    fn default() -> Self {
+        SeenObjects {
+            index: Mutex::new(Index {
+                current: Filter::new(),
+                previous: Filter::new(),
+                window: VecDeque::new(),
+                recent: HashSet::new(),
+                lines: 0,
+            }),
+            dir: None,
+            window: None,
+        }
+    }
+}
+
+impl SeenObjects {
+    /// An index persisted in `dir`, with what was saved there
+    ///
+    /// A filter file which doesn't decode is started afresh. The window is added to the filter
+    /// again, for what was seen since it was last saved
+    pub fn open(dir: StateDir) -> io::Result<SeenObjects> {
+        let seen = SeenObjects::default();
+        let mut index = seen.index.lock().unwrap();
+        let saved = dir.read(FILTER_FILE)?.unwrap_or_default();
+        let filters = Filter::decode(&saved).and_then(|(current, rest)| {
+            let (previous, rest) = Filter::decode(rest)?;
+            rest.is_empty().then_some((current, previous))
+        });
+        if let Some((current, previous)) = filters {
+            (index.current, index.previous) = (current, previous);
+        }
+        let (window, lines) = JsonLog::open(dir.clone(), WINDOW_FILE)?;
+        for line in &lines {
+            if let Some(key) = line.get("key").and_then(Value::as_str) {
+                index.lines += 1;
+                if !index.current.contains(key) {
+                    index.current.insert(key);
+                }
+                index.remember(key);
+            }
+        }
+        drop(index);
+        Ok(SeenObjects {
+            dir: Some(dir),
+            window: Some(window),
+            ..seen
+        })
+    }
+
+    /// What's known of `key`
+    pub fn check(&self, key: &str) -> Sighting {
+        let index = self.index.lock().unwrap();
+        if index.recent.contains(key) {
+            Sighting::Recent
+        } else if index.current.contains(key) || index.previous.contains(key) {
+            Sighting::Probably
+        } else {
+            Sighting::Unseen
+        }
+    }
+
+    /// Record that `key` has been seen
+    pub fn record(&self, key: &str) -> io::Result<()> {
+        let mut index = self.index.lock().unwrap();
+        if index.recent.contains(key) {
+            return Ok(());
+        }
+        index.current.insert(key);
+        index.remember(key);
+        let rotated = index.current.keys >= GENERATION;
+        if rotated {
+            index.previous = std::mem::replace(&mut index.current, Filter::new());
+        }
+        let (Some(dir), Some(window)) = (&self.dir, &self.window) else {
+            return Ok(());
+        };
+        if rotated {
+            dir.write(FILTER_FILE, &index.encode())?;
+        }
+        if index.lines >= 2 * WINDOW {
+            window.rewrite(index.window.iter().map(|key| line(key)))?;
+            index.lines = index.window.len();
+        } else {
+            window.append([line(key)])?;
+            index.lines += 1;
+        }
+        Ok(())
+    }
+
+    /// Write the filter to the state directory, for it to be there next time
+    pub fn save(&self) -> io::Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        dir.write(FILTER_FILE, &self.index.lock().unwrap().encode())
+    }
+
+    /// How many keys are kept exactly
+    pub fn len(&self) -> usize {
+        self.index.lock().unwrap().window.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Index {
+    /// Add `key` to the window, dropping the oldest once it's full
+    fn remember(&mut self, key: &str) {
+        if !self.recent.insert(key.to_string()) {
+            return;
+        }
+        self.window.push_back(key.to_string());
+        while self.window.len() > WINDOW {
+            if let Some(oldest) = self.window.pop_front() {
+                self.recent.remove(&oldest);
+            }
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 * (FILTER_BYTES + 8));
+        self.current.encode(&mut bytes);
+        self.previous.encode(&mut bytes);
+        bytes
+    }
+}
+
+fn line(key: &str) -> Value {
+    Value::object([("key", Value::from(key))])
+}
+
+/// The key a record is seen by, at the version with `cid`
+pub fn record_key(uri: &str, cid: &str) -> String {
+    format!("{uri}#{cid}")
+}
+
+/// Translate what `key` names with `translate`, unless it has been already
+///
+/// Returns `None` if it's skipped. A key the filter only probably has is skipped if
+/// `confirm` says it was translated, such as by finding what it was translated to, and one
+/// it's never had is translated without asking. It's only recorded as seen if `translate`
+/// succeeds, and failing to persist that is only logged
+pub fn translate_once<T, E>(
+    bridge: &Bridge,
+    key: &str,
+    confirm: impl FnOnce(&str) -> bool,
+    translate: impl FnOnce() -> Result<T, E>,
+) -> Result<Option<T>, E> {
+    let skip = match bridge.seen_objects.check(key) {
+        Sighting::Recent => true,
+        Sighting::Probably => confirm(key),
+        Sighting::Unseen => false,
+    };
+    if skip {
+        return Ok(None);
+    }
+    let translated = translate()?;
+    if let Err(e) = bridge.seen_objects.record(key) {
+        eprintln!("Couldn't record a translated object: {e}");
+    }
+    Ok(Some(translated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+
+    #[test]
+    fn translates_each_object_once() {
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            seen_objects: SeenObjects::open(dir.clone()).unwrap(),
+            ..Bridge::new()
+        };
+        let note = "https://a.example/notes/1";
+        let translate = || Ok::<_, ()>("translated");
+        let unconfirmed = |_: &str| false;
+        assert_eq!(
+            translate_once(&bridge, note, unconfirmed, translate),
+            Ok(Some("translated"))
+        );
+        assert_eq!(
+            translate_once(&bridge, note, unconfirmed, translate),
+            Ok(None)
+        );
+        // A failure isn't recorded
+        let other = "https://a.example/notes/2";
+        assert_eq!(
+            translate_once(&bridge, other, unconfirmed, || Err::<(), _>(())),
+            Err(())
+        );
+        assert_eq!(bridge.seen_objects.check(other), Sighting::Unseen);
+        let v1 = record_key("at://did:plc:alice/app.bsky.feed.post/1", "bafyv1");
+        bridge.seen_objects.record(&v1).unwrap();
+        let v2 = record_key("at://did:plc:alice/app.bsky.feed.post/1", "bafyv2");
+        assert_eq!(bridge.seen_objects.check(&v2), Sighting::Unseen);
+
+        // Past the window, the filter still has them, and the caller confirms
+        for n in 0..WINDOW {
+            bridge
+                .seen_objects
+                .record(&format!("https://b.example/{n}"))
+                .unwrap();
+        }
+        assert_eq!(bridge.seen_objects.len(), WINDOW);
+        assert_eq!(bridge.seen_objects.check(note), Sighting::Probably);
+        assert_eq!(translate_once(&bridge, note, |_| true, translate), Ok(None));
+        assert_eq!(
+            translate_once(&bridge, note, unconfirmed, translate),
+            Ok(Some("translated"))
+        );
+
+        bridge.seen_objects.save().unwrap();
+        let reopened = SeenObjects::open(dir).unwrap();
+        assert_eq!(reopened.len(), WINDOW);
+        assert_eq!(reopened.check(note), Sighting::Recent);
+        assert_eq!(reopened.check(&v1), Sighting::Probably);
+        assert_eq!(reopened.check(other), Sighting::Unseen);
+    }
+}
@@ -0,0 +1,253 @@
+//! Shedding firehose events while the bridge is behind
+//!
+//! A burst on the firehose can leave the bridge further behind than it will ever catch up
+//! on, handling every event as it comes. With thresholds set, events are shed once the
+//! [lag](crate::firehose::FirehoseCursor::lag) passes them, by what they're for:
+//!
+//! - past `FEDIBRIDGE_SHED_UNBRIDGED_LAG`, events only writing to collections the bridge
+//!   doesn't bridge ([`BRIDGED_COLLECTIONS`]) are skipped, without being decoded
+//! - past `FEDIBRIDGE_SHED_DEFER_LAG`, likes and reposts are deferred, and handled once the
+//!   lag is back under it. At most `FEDIBRIDGE_SHED_DEFER_CAPACITY` are held, in memory, and
+//!   beyond that the oldest are shed. Those held when the bridge stops are handled first, but
+//!   are lost if it crashes, and one deleted before it's handled isn't handled at all
+//! - past `FEDIBRIDGE_SHED_ESSENTIAL_LAG`, only posts and deletions are handled, besides
+//!   what's deferred
+//!
+//! Account and identity events are always handled. What's shed and deferred is counted, by
+//! collection, for operators ([`Shedding::to_json`])
+
+use crate::firehose::{Action, EventHeader, Operation};
+use crate::json::Value;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The collections the bridge does something with
+pub const BRIDGED_COLLECTIONS: [&str; 11] = [
+    "app.bsky.feed.post",
+    "app.bsky.feed.like",
+    "app.bsky.feed.repost",
+    "app.bsky.feed.threadgate",
+    "app.bsky.feed.postgate",
+    "app.bsky.actor.profile",
+    "app.bsky.graph.follow",
+    "app.bsky.graph.block",
+    "app.bsky.graph.list",
+    "app.bsky.graph.listitem",
+    "app.bsky.graph.starterpack",
+];
+/// Collections whose records are deferred rather than shed
+const ENGAGEMENT: [&str; 2] = ["app.bsky.feed.like", "app.bsky.feed.repost"];
+const POST_COLLECTION: &str = "app.bsky.feed.post";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShedConfig {
+    /// The lag past which events for collections the bridge doesn't bridge are shed
+    pub unbridged_lag: Option<i64>,
+    /// The lag past which likes and reposts are deferred
+    pub defer_lag: Option<i64>,
+    /// The lag past which only posts and deletions are handled
+    pub essential_lag: Option<i64>,
+    /// The most events deferred at once
+    pub defer_capacity: usize,
+}
+
+impl Default for ShedConfig {
+    fn default() -> Self {
+        ShedConfig {
+            unbridged_lag: None,
+            defer_lag: None,
+            essential_lag: None,
+            defer_capacity: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What's done with an event
+pub enum Triage {
+    Handle,
+    /// Held until the lag is back under the deferral threshold
+    Defer,
+    Shed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+/// What became of events, as counted
+pub enum Outcome {
+    Shed,
+    Deferred,
+    /// Deferred, then shed to make room for others
+    Overflowed,
+    /// Deferred, then handled
+    Replayed,
+}
+
+impl Outcome {
+    pub const ALL: [Outcome; 4] = [
+        Outcome::Shed,
+        Outcome::Deferred,
+        Outcome::Overflowed,
+        Outcome::Replayed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Shed => "shed",
+            Outcome::Deferred => "deferred",
+            Outcome::Overflowed => "overflowed",
+            Outcome::Replayed => "replayed",
+        }
+    }
+}
+
+fn passed(threshold: Option<i64>, lag: i64) -> bool {
+    threshold.is_some_and(|threshold| lag > threshold)
+}
+
+fn essential(op: &Operation) -> bool {
+    op.action == Action::Delete || op.collection() == POST_COLLECTION
+}
+
+fn engagement(op: &Operation) -> bool {
+    op.action != Action::Delete && ENGAGEMENT.contains(&op.collection())
+}
+
+#[derive(Debug, Default)]
+/// The shedding policy, and what it has shed
+pub struct Shedding {
+    pub config: ShedConfig,
+    counts: Mutex<BTreeMap<(Outcome, String), u64>>,
+}
+
+impl Shedding {
+    pub fn new(config: ShedConfig) -> Shedding {
+        Shedding {
+            config,
+            ..Shedding::default()
+        }
+    }
+
+    /// Whether likes and reposts are deferred at `lag`
+    pub fn deferring(&self, lag: i64) -> bool {
+        passed(self.config.defer_lag, lag)
+    }
+
+    /// What to do with `event` when `lag` events behind
+    pub fn triage(&self, event: &EventHeader, lag: i64) -> Triage {
+        let ops = &event.ops;
+        let unbridged = || {
+            let bridged = |op: &Operation| BRIDGED_COLLECTIONS.contains(&op.collection());
+            passed(self.config.unbridged_lag, lag) && !ops.iter().any(bridged)
+        };
+        if ops.is_empty() || ops.iter().any(essential) {
+            Triage::Handle
+        } else if self.deferring(lag) && ops.iter().all(engagement) {
+            Triage::Defer
+        } else if passed(self.config.essential_lag, lag) || unbridged() {
+            Triage::Shed
+        } else {
+            Triage::Handle
+        }
+    }
+
+    /// Count `event` as having met `outcome`, under the collection of its first operation
+    pub fn record(&self, outcome: Outcome, event: &EventHeader) {
+        let collection = event.ops.first().map_or("", Operation::collection);
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry((outcome, collection.to_string())).or_default() += 1;
+    }
+
+    /// How many events have met `outcome`
+    pub fn count(&self, outcome: Outcome) -> u64 {
+        let counts = self.counts.lock().unwrap();
+        counts
+            .iter()
+            .filter(|((counted, _), _)| *counted == outcome)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// The thresholds, which are passed at `lag`, and the counts, by outcome then collection
+    pub fn to_json(&self, lag: i64) -> Value {
+        let threshold = |threshold: Option<i64>| {
+            Value::object([
+                ("lag", threshold.map_or(Value::Null, Value::from)),
+                ("passed", Value::from(passed(threshold, lag))),
+            ])
+        };
+        let counts = self.counts.lock().unwrap();
+        let outcomes = Outcome::ALL.iter().map(|outcome| {
+            let collections = counts
+                .iter()
+                .filter(|((counted, _), _)| counted == outcome)
+                .map(|((_, collection), count)| (collection.clone(), Value::from(*count)));
+            (outcome.as_str(), Value::Object(collections.collect()))
+        });
+        Value::object([
+            ("lag", Value::from(lag)),
+            ("unbridged", threshold(self.config.unbridged_lag)),
+            ("defer", threshold(self.config.defer_lag)),
+            ("essential", threshold(self.config.essential_lag)),
+            ("counts", Value::object(outcomes)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atproto::did;
+
+    fn event(ops: &[(Action, &str)]) -> EventHeader {
+        EventHeader {
+            seq: 1,
+            did: did!("did:plc:alice"),
+            ops: ops
+                .iter()
+                .map(|&(action, collection)| Operation {
+                    action,
+                    path: format!("{collection}/3k"),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn events_are_shed_by_importance_as_the_lag_grows() {
+        let shedding = Shedding::new(ShedConfig {
+            unbridged_lag: Some(100),
+            defer_lag: Some(1_000),
+            essential_lag: Some(10_000),
+            ..ShedConfig::default()
+        });
+        let post = event(&[(Action::Create, POST_COLLECTION)]);
+        let like = event(&[(Action::Create, "app.bsky.feed.like")]);
+        let unlike = event(&[(Action::Delete, "app.bsky.feed.like")]);
+        let follow = event(&[(Action::Create, "app.bsky.graph.follow")]);
+        let other = event(&[(Action::Create, "com.example.thing")]);
+        let account = event(&[]);
+        let at = |lag: i64| {
+            [&post, &like, &unlike, &follow, &other, &account]
+                .map(|event| shedding.triage(event, lag))
+        };
+        use Triage::*;
+        assert_eq!(at(50), [Handle; 6]);
+        assert_eq!(at(500), [Handle, Handle, Handle, Handle, Shed, Handle]);
+        assert_eq!(at(5_000), [Handle, Defer, Handle, Handle, Shed, Handle]);
+        assert_eq!(at(50_000), [Handle, Defer, Handle, Shed, Shed, Handle]);
+        assert_eq!(Shedding::default().triage(&other, i64::MAX), Handle);
+
+        shedding.record(Outcome::Shed, &other);
+        shedding.record(Outcome::Shed, &follow);
+        shedding.record(Outcome::Deferred, &like);
+        assert_eq!(shedding.count(Outcome::Shed), 2);
+        let json = shedding.to_json(5_000);
+        let counts = json.get("counts").unwrap();
+        let deferred = counts
+            .get("deferred")
+            .and_then(|d| d.get("app.bsky.feed.like"));
+        assert_eq!(deferred, Some(&Value::from(1u64)));
+        let defer = json.get("defer").and_then(|d| d.get("passed"));
+        assert_eq!(defer, Some(&Value::from(true)));
+    }
+}
@@ -0,0 +1,191 @@
+//! Coordinated shutdown
+//!
+//! Shutting down happens in three phases:
+//!
+//! 1. Stop accepting new work - servers and consumers poll [`Shutdown::is_requested`] and
+//!    [`Shutdown::begin`] refuses to hand out new [`InFlight`] guards
+//! 2. Drain - wait for outstanding `InFlight` guards (translations, deliveries in progress) to
+//!    be dropped, up to the deadline
+//! 3. Flush - run the registered hooks in order, persisting cursors and queues and closing
+//!    upstream connections
+//!
+//! Hooks still run when the drain deadline passes, since losing the cursor is worse than
+//! replaying a few events
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+type Hook = Box<dyn Fn() -> anyhow::Result<()> + Send + Sync>;
+
+/// Shared shutdown state
+pub struct Shutdown {
+    requested: AtomicBool,
+    in_flight: Mutex<usize>,
+    drained: Condvar,
+    hooks: Mutex<Vec<(String, Hook)>>,
+}
+
+/// Marks a unit of work which shutdown should wait for
+///
+/// Dropping the guard marks the work as finished
+pub struct InFlight {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        let mut in_flight = self.shutdown.in_flight.lock().unwrap();
+        *in_flight -= 1;
+        if *in_flight == 0 {
+            self.shutdown.drained.notify_all();
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// What happened during a shutdown
+pub struct ShutdownReport {
+    /// Whether all in-flight work finished before the deadline
+    pub drained: bool,
+    /// Hooks which failed, with their errors
+    pub failed_hooks: Vec<(String, anyhow::Error)>,
+}
+
+impl ShutdownReport {
+    pub fn is_clean(&self) -> bool {
+        self.drained && self.failed_hooks.is_empty()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Arc<Shutdown> {
+        Arc::new(Shutdown {
+            requested: AtomicBool::new(false),
+            in_flight: Mutex::new(0),
+            drained: Condvar::new(),
+            hooks: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Ask everything to stop accepting new work
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Start a unit of work, or `None` if shutdown has been requested and the work should be
+    /// refused
+    pub fn begin(self: &Arc<Self>) -> Option<InFlight> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        // Checked under the lock so drain can't observe zero and then have work sneak in
+        if self.is_requested() {
+            return None;
+        }
+        *in_flight += 1;
+        Some(InFlight {
+            shutdown: self.clone(),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+
+    /// Register a hook to run during the flush phase, in registration order
+    pub fn on_shutdown<F>(&self, name: impl Into<String>, hook: F)
+    where
+        F: Fn() -> anyhow::Result<()> + Send + Sync + 'static,
+    {
+        self.hooks
+            .lock()
+            .unwrap()
+            .push((name.into(), Box::new(hook)));
+    }
+
+    /// Wait until no work is in flight, returning false if the deadline passed first
+    pub fn wait_drained(&self, deadline: Instant) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            in_flight = self
+                .drained
+                .wait_timeout(in_flight, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Run the full shutdown sequence, bounding the drain phase by `timeout`
+    pub fn run(&self, timeout: Duration) -> ShutdownReport {
+        self.request();
+        let drained = self.wait_drained(Instant::now() + timeout);
+        let hooks = std::mem::take(&mut *self.hooks.lock().unwrap());
+        let failed_hooks = hooks
+            .into_iter()
+            .filter_map(|(name, hook)| hook().err().map(|e| (name, e)))
+            .collect();
+        ShutdownReport {
+            drained,
+            failed_hooks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn refuses_work_after_request() {
+        let shutdown = Shutdown::new();
+        let guard = shutdown.begin();
+        assert!(guard.is_some());
+        shutdown.request();
+        assert!(shutdown.begin().is_none());
+        assert_eq!(shutdown.in_flight(), 1);
+        drop(guard);
+        assert_eq!(shutdown.in_flight(), 0);
+    }
+
+    #[test]
+    fn drains_then_runs_hooks_in_order() {
+        let shutdown = Shutdown::new();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for name in ["cursor", "deliveries"] {
+            let order = order.clone();
+            shutdown.on_shutdown(name, move || {
+                order.lock().unwrap().push(name);
+                Ok(())
+            });
+        }
+        let guard = shutdown.begin().unwrap();
+        let worker = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(guard);
+        });
+        let report = shutdown.run(Duration::from_secs(5));
+        worker.join().unwrap();
+        assert!(report.is_clean());
+        assert_eq!(*order.lock().unwrap(), ["cursor", "deliveries"]);
+    }
+
+    #[test]
+    fn deadline_still_flushes() {
+        let shutdown = Shutdown::new();
+        let _stuck = shutdown.begin().unwrap();
+        shutdown.on_shutdown("broken", || Err(anyhow::anyhow!("disk full")));
+        let report = shutdown.run(Duration::from_millis(10));
+        assert!(!report.drained);
+        assert_eq!(report.failed_hooks.len(), 1);
+        assert_eq!(report.failed_hooks[0].0, "broken");
+    }
+}
@@ -0,0 +1,842 @@
+//! Verifying HTTP signatures on inbound requests
+//!
+//! Fediverse servers sign requests with a key published in the signing actor's document.
+//! Fetching and parsing that document for every inbound activity is expensive on a busy
+//! bridge, so keys which have verified a signature are kept in a [`KeyCache`] for a while and
+//! checked directly next time.
+//!
+//! A cached key is dropped when a signature fails to verify with it, in case the actor
+//! rotated its key, and the actor's document is then refetched once before giving up. Keys
+//! are also dropped when their actor is updated with a different key or deleted.
+//!
+//! The signing key may be either kind an actor [publishes](crate::actorkeys): a legacy RSA
+//! `publicKey` or an FEP-521a Multikey. The signature algorithms themselves are supplied by a
+//! [`SignatureVerifier`].
+//!
+//! Before the signature is checked, its signed `Date` must be close enough to the bridge's
+//! clock and a signed `Digest` must match the body, and after, it mustn't have been seen
+//! before. Servers' clock discipline varies wildly, so how strict each check is comes from a
+//! [`SignaturePolicy`], which operators can set per class of instance with
+//! [`SignaturePolicies`]
+
+use crate::actorkeys::{self, PublicKey};
+use crate::bridge::Bridge;
+use crate::cache::{Fetched, ResourceKind};
+use crate::crypto::{base64_encode, sha256};
+use crate::delivery::ACTIVITY_JSON;
+use crate::egress;
+use crate::http::Request;
+use crate::json::{self, Value};
+use crate::time::parse_http_date;
+use crate::transport::OutboundRequest;
+use crate::url::Url;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// How long a verified key is trusted without looking at its actor again
+pub const DEFAULT_KEY_TTL: Duration = Duration::from_secs(6 * 3600);
+
+/// How far a signed date may be from the bridge's clock by default, as Mastodon allows
+pub const DEFAULT_MAX_SIGNATURE_SKEW: Duration = Duration::from_secs(12 * 3600);
+
+/// Signatures remembered at most, to refuse replays. The oldest are forgotten first
+const MAX_REMEMBERED: usize = 100_000;
+
+/// Checks signatures made with an actor's key
+pub trait SignatureVerifier: Send + Sync {
+    /// Whether `signature`, in base64, is a signature of `signed` by `public_key`
+    ///
+    /// Keys in a form or of an algorithm the verifier doesn't support don't verify anything
+    fn verify(&self, public_key: &PublicKey, signed: &[u8], signature: &str) -> bool;
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors verifying a request's signature
+pub enum SignatureError {
+    #[error("Request isn't signed")]
+    Unsigned,
+    #[error("Malformed Signature header - found {found}")]
+    Malformed { found: String },
+    #[error("Signed header {name} is missing from the request")]
+    MissingHeader { name: String },
+    #[error("No signature verifier is configured")]
+    NoVerifier,
+    #[error("Couldn't fetch the actor for key {key_id}: {reason}")]
+    KeyUnavailable { key_id: String, reason: String },
+    #[error("Signature doesn't verify with key {key_id}")]
+    Invalid { key_id: String },
+    #[error("The request's Date isn't signed")]
+    DateUnsigned,
+    #[error("Signed date {date} is too far from the bridge's clock")]
+    Stale { date: String },
+    #[error("The request's body has no signed Digest")]
+    DigestUnsigned,
+    #[error("The Digest doesn't match the request's body")]
+    DigestMismatch,
+    #[error("Signature by {key_id} was already used")]
+    Replayed { key_id: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How strictly signatures from an instance are checked
+pub struct SignaturePolicy {
+    /// How far the signed `Date` may be from the bridge's clock, either way. Zero doesn't check
+    /// the date, or need it signed
+    pub max_skew: Duration,
+    /// Whether requests with a body must sign a `Digest` of it. Digests are checked anyway
+    pub require_digest: bool,
+    /// How long signatures are remembered, to refuse them being replayed. Zero doesn't
+    /// remember them
+    pub replay_window: Duration,
+}
+
+impl Default for SignaturePolicy {
+    fn default() -> Self {
+        SignaturePolicy {
+            max_skew: DEFAULT_MAX_SIGNATURE_SKEW,
+            require_digest: true,
+            // Anything older is stale anyway
+            replay_window: DEFAULT_MAX_SIGNATURE_SKEW,
+        }
+    }
+}
+
+impl SignaturePolicy {
+    /// This policy with `settings` such as `skew:86400;digest:optional;replay:0` applied,
+    /// times in seconds
+    pub fn with_settings(self, settings: &str) -> Option<SignaturePolicy> {
+        let mut policy = self;
+        for setting in settings.split(';').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, value) = setting.split_once(':')?;
+            let seconds = || value.trim().parse().ok().map(Duration::from_secs);
+            match name.trim() {
+                "skew" => policy.max_skew = seconds()?,
+                "replay" => policy.replay_window = seconds()?,
+                "digest" => {
+                    policy.require_digest = match value.trim() {
+                        "required" => true,
+                        "optional" => false,
+                        _ => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(policy)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Signature policies for classes of instance, by domain
+pub struct SignaturePolicies {
+    /// For instances in no class
+    pub default: SignaturePolicy,
+    pub classes: BTreeMap<String, SignaturePolicy>,
+    /// Domains, covering their subdomains, and the class each is in. The most specific wins
+    pub domains: Vec<(String, String)>,
+}
+
+impl SignaturePolicies {
+    /// Classes from entries like `lenient=skew:86400;digest:optional`, separated by commas,
+    /// and domains from ones like `old.example=lenient`. Fails with the entry that doesn't
+    /// parse
+    pub fn parse(
+        default: SignaturePolicy,
+        classes: &str,
+        domains: &str,
+    ) -> Result<SignaturePolicies, String> {
+        let entries = |list: &str| {
+            let list: Vec<String> = list
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect();
+            list
+        };
+        let mut policies = SignaturePolicies {
+            default,
+            ..SignaturePolicies::default()
+        };
+        for entry in entries(classes) {
+            let (name, settings) = entry.split_once('=').ok_or_else(|| entry.clone())?;
+            let policy = default
+                .with_settings(settings)
+                .ok_or_else(|| entry.clone())?;
+            policies.classes.insert(name.trim().to_string(), policy);
+        }
+        for entry in entries(domains) {
+            match entry.split_once('=') {
+                Some((domain, class)) if policies.classes.contains_key(class.trim()) => {
+                    let domain = domain.trim().trim_matches('.').to_ascii_lowercase();
+                    policies.domains.push((domain, class.trim().to_string()));
+                }
+                _ => return Err(entry),
+            }
+        }
+        Ok(policies)
+    }
+
+    /// The policy for signatures by keys on `host`
+    pub fn for_host(&self, host: &str) -> SignaturePolicy {
+        let host = host.to_ascii_lowercase();
+        let class = self
+            .domains
+            .iter()
+            .filter(|(domain, _)| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+            .max_by_key(|(domain, _)| domain.len());
+        class
+            .and_then(|(_, class)| self.classes.get(class))
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Seen {
+    /// When each is forgotten
+    expiries: HashMap<String, Instant>,
+    /// In the order they were seen, with when they were to be forgotten then
+    order: VecDeque<(String, Instant)>,
+}
+
+#[derive(Debug, Default)]
+/// Signatures which have verified recently, by key ID and signature
+pub struct SeenSignatures {
+    seen: Mutex<Seen>,
+}
+
+impl SeenSignatures {
+    /// Remember a signature for `window`, returning whether it was already remembered
+    pub fn replayed(&self, key_id: &str, signature: &str, window: Duration, now: Instant) -> bool {
+        let key = format!("{key_id} {signature}");
+        let mut seen = self.seen.lock().unwrap();
+        let Seen { expiries, order } = &mut *seen;
+        if expiries.get(&key).is_some_and(|expires| *expires > now) {
+            return true;
+        }
+        while let Some((oldest, expires)) = order.front() {
+            if *expires > now && order.len() < MAX_REMEMBERED {
+                break;
+            }
+            // Unless it's been seen again since
+            if expiries.get(oldest) == Some(expires) {
+                expiries.remove(oldest);
+            }
+            order.pop_front();
+        }
+        expiries.insert(key.clone(), now + window);
+        order.push_back((key, now + window));
+        false
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The parameters of a `Signature` header
+pub struct SignatureParams {
+    pub key_id: String,
+    /// Names of the signed headers, in order
+    pub headers: Vec<String>,
+    /// Base64
+    pub signature: String,
+}
+
+impl SignatureParams {
+    pub fn parse(header: &str) -> Result<SignatureParams, SignatureError> {
+        let mut params = HashMap::new();
+        for param in header.split(',') {
+            let (name, value) =
+                param
+                    .trim()
+                    .split_once('=')
+                    .ok_or_else(|| SignatureError::Malformed {
+                        found: header.to_string(),
+                    })?;
+            params.insert(name.trim(), value.trim().trim_matches('"'));
+        }
+        let (Some(key_id), Some(signature)) = (params.get("keyId"), params.get("signature")) else {
+            return Err(SignatureError::Malformed {
+                found: header.to_string(),
+            });
+        };
+        // Only the date is signed when no headers are listed
+        let headers = params.get("headers").copied().unwrap_or("date");
+        Ok(SignatureParams {
+            key_id: key_id.to_string(),
+            headers: headers
+                .split_whitespace()
+                .map(str::to_ascii_lowercase)
+                .collect(),
+            signature: signature.to_string(),
+        })
+    }
+
+    /// The actor the key belongs to, by its ID without the fragment
+    pub fn actor(&self) -> &str {
+        self.key_id.split('#').next().unwrap_or(&self.key_id)
+    }
+}
+
+/// The string a request's signature covers, built from the headers it lists
+pub fn signing_string(request: &Request, headers: &[String]) -> Result<String, SignatureError> {
+    let lines = headers
+        .iter()
+        .map(|name| {
+            let value = match name.as_str() {
+                "(request-target)" => {
+                    let method = request.method.as_str().to_ascii_lowercase();
+                    format!("{method} {}", request.target)
+                }
+                _ => request
+                    .header(name)
+                    .ok_or_else(|| SignatureError::MissingHeader { name: name.clone() })?
+                    .to_string(),
+            };
+            Ok(format!("{name}: {value}"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(lines.join("\n"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A key which has verified a signature, and the actor it belongs to
+pub struct VerifiedKey {
+    pub actor: String,
+    pub public_key: PublicKey,
+    verified_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Signatures which didn't verify with a cached or freshly fetched key
+    pub failures: u64,
+    /// Keys dropped after failing, or because their actor changed
+    pub invalidations: u64,
+}
+
+impl KeyCacheStats {
+    /// The fraction of lookups answered from the cache, once there have been any
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("hits", Value::from(self.hits)),
+            ("misses", Value::from(self.misses)),
+            ("failures", Value::from(self.failures)),
+            ("invalidations", Value::from(self.invalidations)),
+            ("hitRate", Value::from(self.hit_rate())),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    keys: HashMap<String, VerifiedKey>,
+    stats: KeyCacheStats,
+}
+
+#[derive(Debug)]
+/// Verified keys by key ID
+pub struct KeyCache {
+    ttl: Duration,
+    inner: Mutex<Inner>,
+}
+
+impl Default for KeyCache {
+    fn default() -> Self {
+        KeyCache::new(DEFAULT_KEY_TTL)
+    }
+}
+
+impl KeyCache {
+    pub fn new(ttl: Duration) -> KeyCache {
+        KeyCache {
+            ttl,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// The cached key, if it was verified within the TTL
+    pub fn get(&self, key_id: &str, now: Instant) -> Option<VerifiedKey> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = inner.keys.get(key_id).cloned();
+        match key.filter(|key| now.duration_since(key.verified_at) < self.ttl) {
+            Some(key) => {
+                inner.stats.hits += 1;
+                Some(key)
+            }
+            None => {
+                inner.keys.remove(key_id);
+                inner.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key_id: &str, actor: &str, public_key: &PublicKey, now: Instant) {
+        let key = VerifiedKey {
+            actor: actor.to_string(),
+            public_key: public_key.clone(),
+            verified_at: now,
+        };
+        self.inner
+            .lock()
+            .unwrap()
+            .keys
+            .insert(key_id.to_string(), key);
+    }
+
+    /// Record a signature failing to verify with a key, dropping it if it was cached
+    pub fn failed(&self, key_id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.stats.failures += 1;
+        if inner.keys.remove(key_id).is_some() {
+            inner.stats.invalidations += 1;
+        }
+    }
+
+    /// Drop every key of `actor`, as when it's deleted
+    pub fn invalidate_actor(&self, actor: &str) {
+        self.retain(|_, key| key.actor != actor);
+    }
+
+    /// Drop keys of an actor whose document has changed, unless it still publishes them
+    pub fn actor_updated(&self, document: &Value) {
+        let Some(actor) = document.get("id").and_then(Value::as_str) else {
+            return;
+        };
+        self.retain(|key_id, key| {
+            key.actor != actor
+                || actorkeys::published_key(document, key_id).as_ref() == Some(&key.public_key)
+        });
+    }
+
+    fn retain(&self, keep: impl Fn(&str, &VerifiedKey) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let before = inner.keys.len();
+        inner.keys.retain(|key_id, key| keep(key_id, key));
+        inner.stats.invalidations += (before - inner.keys.len()) as u64;
+    }
+
+    pub fn stats(&self) -> KeyCacheStats {
+        self.inner.lock().unwrap().stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Fetch the actor owning `key_id` through the document cache, bypassing it if `refresh`
+///
+/// Redirects are followed, but only a document of `actor` itself, from its own origin, is
+/// kept, so nobody can publish a key for an actor elsewhere
+fn fetch_key(
+    bridge: &Bridge,
+    key_id: &str,
+    actor: &str,
+    refresh: bool,
+) -> Result<PublicKey, SignatureError> {
+    if refresh {
+        bridge.documents.invalidate(ResourceKind::Actor, actor);
+    }
+    let unavailable = |reason: String| SignatureError::KeyUnavailable {
+        key_id: key_id.to_string(),
+        reason,
+    };
+    let origin = Url::parse(actor)
+        .map_err(|e| unavailable(e.to_string()))?
+        .origin();
+    let transport = bridge.transport.clone();
+    let policy = bridge.egress.clone();
+    let url = actor.to_string();
+    let document = bridge
+        .documents
+        .get_or_revalidate(ResourceKind::Actor, actor, move |validators| {
+            let request = OutboundRequest::get(&url)
+                .with_header("accept", ACTIVITY_JSON)
+                .if_modified(validators);
+            let (fetched, response) =
+                egress::fetch(transport.as_ref(), &policy, &request).map_err(|e| e.to_string())?;
+            if fetched.origin() != origin {
+                return Err(format!(
+                    "it redirected to {fetched}, off the actor's origin"
+                ));
+            }
+            if response.status == 304 && validators.is_some() {
+                return Ok(Fetched::NotModified);
+            }
+            if !response.is_success() {
+                return Err(format!("status {}", response.status));
+            }
+            let body = String::from_utf8_lossy(&response.body);
+            let document = json::parse(&body).map_err(|e| e.to_string())?;
+            let id = document.get("id").and_then(Value::as_str);
+            if id != Some(url.as_str()) {
+                let id = id.unwrap_or("nothing");
+                return Err(format!("the document is of {id}, not the actor"));
+            }
+            Ok(Fetched::Modified {
+                value: document,
+                size: response.body.len(),
+                validators: response.validators(),
+            })
+        })
+        .map_err(unavailable)?;
+    actorkeys::published_key(&document, key_id)
+        .ok_or_else(|| unavailable("it doesn't publish the key".to_string()))
+}
+
+/// Check the date and digest `params` sign are acceptable under `policy`
+fn check_signed_headers(
+    request: &Request,
+    params: &SignatureParams,
+    policy: &SignaturePolicy,
+    now: SystemTime,
+) -> Result<(), SignatureError> {
+    let signs = |name: &str| params.headers.iter().any(|signed| signed == name);
+    if !policy.max_skew.is_zero() {
+        let date = request
+            .header("date")
+            .filter(|_| signs("date"))
+            .ok_or(SignatureError::DateUnsigned)?;
+        let skew = parse_http_date(date).map(|date| match date.duration_since(now) {
+            Ok(ahead) => ahead,
+            Err(behind) => behind.duration(),
+        });
+        if skew.is_none_or(|skew| skew > policy.max_skew) {
+            return Err(SignatureError::Stale {
+                date: date.to_string(),
+            });
+        }
+    }
+    match request.header("digest") {
+        Some(digest) => {
+            let expected = base64_encode(&sha256(&request.body));
+            let matches = digest.split(',').any(|digest| {
+                digest
+                    .trim()
+                    .split_once('=')
+                    .is_some_and(|(algorithm, value)| {
+                        algorithm.eq_ignore_ascii_case("sha-256") && value == expected
+                    })
+            });
+            if !matches {
+                return Err(SignatureError::DigestMismatch);
+            }
+            if policy.require_digest && !signs("digest") {
+                return Err(SignatureError::DigestUnsigned);
+            }
+        }
+        None if policy.require_digest && !request.body.is_empty() => {
+            return Err(SignatureError::DigestUnsigned)
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Verify a request's signature, returning the actor who signed it
+pub fn verify(bridge: &Bridge, request: &Request) -> Result<String, SignatureError> {
+    let header = request
+        .header("signature")
+        .ok_or(SignatureError::Unsigned)?;
+    let params = SignatureParams::parse(header)?;
+    let host = Url::parse(params.actor())
+        .map(|url| url.host)
+        .unwrap_or_default();
+    let policy = bridge.signature_policies.for_host(&host);
+    check_signed_headers(request, &params, &policy, SystemTime::now())?;
+    let signed = signing_string(request, &params.headers)?;
+    let verifier = bridge
+        .signature_verifier
+        .as_ref()
+        .ok_or(SignatureError::NoVerifier)?;
+    let cache = &bridge.verified_keys;
+    let verifies = |key: &PublicKey| verifier.verify(key, signed.as_bytes(), &params.signature);
+    let fresh = |actor: String| {
+        let window = policy.replay_window;
+        let seen = &bridge.seen_signatures;
+        if !window.is_zero()
+            && seen.replayed(&params.key_id, &params.signature, window, Instant::now())
+        {
+            return Err(SignatureError::Replayed {
+                key_id: params.key_id.clone(),
+            });
+        }
+        Ok(actor)
+    };
+
+    let mut refresh = false;
+    if let Some(key) = cache.get(&params.key_id, Instant::now()) {
+        if verifies(&key.public_key) {
+            return fresh(key.actor);
+        }
+        // The actor may have rotated its key since
+        cache.failed(&params.key_id);
+        refresh = true;
+    }
+    let actor = params.actor();
+    loop {
+        let key = fetch_key(bridge, &params.key_id, actor, refresh)?;
+        if verifies(&key) {
+            cache.insert(&params.key_id, actor, &key, Instant::now());
+            return fresh(actor.to_string());
+        }
+        cache.failed(&params.key_id);
+        if refresh {
+            return Err(SignatureError::Invalid {
+                key_id: params.key_id,
+            });
+        }
+        refresh = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hex_encode;
+    use crate::http::Method;
+    use crate::time::format_http_date;
+    use crate::transport::{MockTransport, OutboundResponse};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    const ACTOR: &str = "https://b.example/users/bob";
+    const KEY_ID: &str = "https://b.example/users/bob#main-key";
+
+    /// "Signs" by hashing the key and signed string together
+    struct FakeVerifier;
+
+    fn sign(pem: &str, signed: &str) -> String {
+        hex_encode(&sha256(format!("{pem}{signed}").as_bytes()))
+    }
+
+    impl SignatureVerifier for FakeVerifier {
+        fn verify(&self, public_key: &PublicKey, signed: &[u8], signature: &str) -> bool {
+            let (PublicKey::Pem(key) | PublicKey::Multibase(key)) = public_key;
+            sign(key, &String::from_utf8_lossy(signed)) == signature
+        }
+    }
+
+    fn actor(pem: &str) -> String {
+        format!(
+            r#"{{"id": "{ACTOR}", "publicKey": {{"id": "{KEY_ID}", "owner": "{ACTOR}",
+                "publicKeyPem": "{pem}"}}}}"#
+        )
+    }
+
+    fn sign_request(pem: &str, request: Request, headers: &str) -> Request {
+        let names: Vec<String> = headers.split(' ').map(str::to_string).collect();
+        let signature = sign(pem, &signing_string(&request, &names).unwrap());
+        let header = format!(r#"keyId="{KEY_ID}",headers="{headers}",signature="{signature}""#);
+        request.with_header("Signature", &header)
+    }
+
+    fn signed_request_at(pem: &str, date: &str) -> Request {
+        let request = Request::new(Method::Post, "/users/alice/inbox")
+            .with_header("Host", "bridge.example")
+            .with_header("Date", date);
+        sign_request(pem, request, "(request-target) host date")
+    }
+
+    /// A request signed a moment ago, with a signature of its own
+    fn signed_request(pem: &str) -> Request {
+        static SIGNED: AtomicU64 = AtomicU64::new(0);
+        let ago = Duration::from_secs(SIGNED.fetch_add(1, Ordering::Relaxed));
+        signed_request_at(pem, &format_http_date(SystemTime::now() - ago))
+    }
+
+    #[test]
+    fn signing_string_follows_listed_headers() {
+        let request = signed_request_at("key", "Tue, 07 Jun 2024 20:51:35 GMT");
+        let params = SignatureParams::parse(request.header("signature").unwrap()).unwrap();
+        assert_eq!(params.actor(), ACTOR);
+        assert_eq!(
+            signing_string(&request, &params.headers).unwrap(),
+            "(request-target): post /users/alice/inbox\nhost: bridge.example\n\
+             date: Tue, 07 Jun 2024 20:51:35 GMT"
+        );
+        let digest = ["digest".to_string()];
+        assert_eq!(
+            signing_string(&request, &digest),
+            Err(SignatureError::MissingHeader {
+                name: "digest".to_string()
+            })
+        );
+        assert!(matches!(
+            SignatureParams::parse("keyId"),
+            Err(SignatureError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn keys_are_only_taken_from_the_actors_own_document() {
+        let mut bridge = Bridge::new();
+        bridge.signature_verifier = Some(Arc::new(FakeVerifier));
+
+        // A document claiming to be someone else
+        let mock = Arc::new(MockTransport::new());
+        let mallory = actor("key").replace(
+            r#""id": "https://b.example/users/bob""#,
+            r#""id": "https://b.example/users/mallory""#,
+        );
+        mock.respond_json(ACTOR, &mallory);
+        let bridge = bridge.with_transport(mock);
+        assert!(matches!(
+            verify(&bridge, &signed_request("key")),
+            Err(SignatureError::KeyUnavailable { .. })
+        ));
+        assert!(bridge.verified_keys.is_empty());
+
+        // Or one redirected to from another origin
+        let mock = Arc::new(MockTransport::new());
+        let elsewhere = "https://c.example/bob";
+        let redirect = |to: &str| OutboundResponse::new(302).with_header("location", to);
+        mock.respond(Method::Get, ACTOR, redirect(elsewhere));
+        mock.respond_json(elsewhere, &actor("key"));
+        let bridge = bridge.with_transport(mock);
+        assert!(matches!(
+            verify(&bridge, &signed_request("key")),
+            Err(SignatureError::KeyUnavailable { .. })
+        ));
+
+        // Though a redirect within the actor's origin is followed
+        let mock = Arc::new(MockTransport::new());
+        let moved = "https://b.example/people/bob";
+        mock.respond(Method::Get, ACTOR, redirect(moved));
+        mock.respond_json(moved, &actor("key"));
+        let bridge = bridge.with_transport(mock);
+        assert_eq!(verify(&bridge, &signed_request("key")).unwrap(), ACTOR);
+    }
+
+    #[test]
+    fn verified_keys_are_cached_until_rotated() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(ACTOR, &actor("old"));
+        let mut bridge = Bridge::new().with_transport(mock.clone());
+        bridge.signature_verifier = Some(Arc::new(FakeVerifier));
+
+        assert_eq!(verify(&bridge, &signed_request("old")).unwrap(), ACTOR);
+        assert_eq!(verify(&bridge, &signed_request("old")).unwrap(), ACTOR);
+        assert_eq!(mock.requests_to(ACTOR).len(), 1);
+        let stats = bridge.verified_keys.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        assert_eq!(stats.hit_rate(), Some(0.5));
+
+        // A rotated key fails with the cached one, which is dropped for a fresh fetch
+        let rotated = Arc::new(MockTransport::new());
+        rotated.respond_json(ACTOR, &actor("new"));
+        let bridge = bridge.with_transport(rotated.clone());
+        assert_eq!(verify(&bridge, &signed_request("new")).unwrap(), ACTOR);
+        assert_eq!(rotated.requests_to(ACTOR).len(), 1);
+        let stats = bridge.verified_keys.stats();
+        assert_eq!((stats.failures, stats.invalidations), (1, 1));
+
+        assert!(matches!(
+            verify(&bridge, &signed_request("forged")),
+            Err(SignatureError::Invalid { .. })
+        ));
+        assert!(bridge.verified_keys.is_empty());
+
+        // Updates which change the published key drop it too
+        verify(&bridge, &signed_request("new")).unwrap();
+        bridge
+            .verified_keys
+            .actor_updated(&json::parse(&actor("new")).unwrap());
+        assert_eq!(bridge.verified_keys.len(), 1);
+        bridge
+            .verified_keys
+            .actor_updated(&json::parse(&actor("newer")).unwrap());
+        assert!(bridge.verified_keys.is_empty());
+    }
+
+    #[test]
+    fn verifies_with_multikeys() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(
+            ACTOR,
+            &format!(
+                r#"{{"id": "{ACTOR}", "assertionMethod": [{{"id": "{KEY_ID}", "type": "Multikey",
+                    "controller": "{ACTOR}", "publicKeyMultibase": "z6Mk"}}]}}"#
+            ),
+        );
+        let mut bridge = Bridge::new().with_transport(mock);
+        bridge.signature_verifier = Some(Arc::new(FakeVerifier));
+        assert_eq!(verify(&bridge, &signed_request("z6Mk")).unwrap(), ACTOR);
+        let cached = bridge.verified_keys.get(KEY_ID, Instant::now()).unwrap();
+        assert_eq!(cached.public_key, PublicKey::Multibase("z6Mk".to_string()));
+    }
+
+    #[test]
+    fn policies_check_dates_digests_and_replays() {
+        let mock = Arc::new(MockTransport::new());
+        mock.respond_json(ACTOR, &actor("key"));
+        let lenient = SignaturePolicies::parse(
+            SignaturePolicy::default(),
+            "lenient=skew:0;digest:optional",
+            "example=lenient",
+        )
+        .unwrap();
+        assert_eq!(lenient.for_host("bridge.example").max_skew, Duration::ZERO);
+        assert_eq!(
+            lenient.for_host("elsewhere.test"),
+            SignaturePolicy::default()
+        );
+        assert!(SignaturePolicies::parse(SignaturePolicy::default(), "", "a.example=b").is_err());
+        let mut bridge = Bridge::new().with_transport(mock);
+        bridge.signature_verifier = Some(Arc::new(FakeVerifier));
+
+        let old = || signed_request_at("key", "Tue, 07 Jun 2024 20:51:35 GMT");
+        assert!(matches!(
+            verify(&bridge, &old()),
+            Err(SignatureError::Stale { .. })
+        ));
+        let body = br#"{"type": "Follow"}"#;
+        let unsigned_body = signed_request("key").with_body(body.to_vec());
+        assert_eq!(
+            verify(&bridge, &unsigned_body),
+            Err(SignatureError::DigestUnsigned)
+        );
+        let date = format_http_date(SystemTime::now());
+        let digest = format!("SHA-256={}", base64_encode(&sha256(body)));
+        let with_digest = |body: &[u8]| {
+            let request = Request::new(Method::Post, "/users/alice/inbox")
+                .with_header("Date", &date)
+                .with_header("Digest", &digest)
+                .with_body(body.to_vec());
+            sign_request("key", request, "(request-target) date digest")
+        };
+        assert_eq!(verify(&bridge, &with_digest(body)).unwrap(), ACTOR);
+        assert_eq!(
+            verify(&bridge, &with_digest(b"{}")),
+            Err(SignatureError::DigestMismatch)
+        );
+        // The same signature again is a replay
+        assert!(matches!(
+            verify(&bridge, &with_digest(body)),
+            Err(SignatureError::Replayed { .. })
+        ));
+
+        let bridge = bridge.with_signature_policies(lenient);
+        assert_eq!(verify(&bridge, &old()).unwrap(), ACTOR);
+        assert_eq!(verify(&bridge, &unsigned_body).unwrap(), ACTOR);
+    }
+}
@@ -0,0 +1,460 @@
+//! Snapshots of the whole bridge state, for migrating hosts and disaster recovery
+//!
+//! [`snapshot`] packs everything in a state directory into a tar archive: identity mappings,
+//! firehose cursors for every shard, job queues including spilled batches, keys, federation
+//! policy, the deletion log and re-hosted media. [`restore`] unpacks one into an empty state
+//! directory on another host, which then carries on where the old one left off.
+//!
+//! The archive opens with a manifest of every file's size and SHA-256, and a restore checks
+//! each file against it, so a truncated or tampered copy is refused rather than restored.
+//!
+//! The bridge only flushes its cursors, queue and mappings to disk as it shuts down, so
+//! snapshot a stopped bridge. Keys stay sealed with the keystore passphrase, which the new host
+//! will need as well
+
+use crate::crypto::{hex_decode, hex_encode, sha256};
+use crate::json::{self, Value};
+use crate::storage::StateDir;
+use crate::time::{format_rfc3339, parse_rfc3339};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// The first entry of every snapshot
+pub const MANIFEST_FILE: &str = "fedibridge-snapshot.json";
+const VERSION: i64 = 1;
+const BLOCK: usize = 512;
+/// The longest path a tar header holds. Longer ones go in a pax extended header
+const NAME_LEN: usize = 100;
+
+#[derive(Debug, Error)]
+/// Errors taking or restoring a snapshot
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Not a bridge snapshot: {reason}")]
+    Malformed { reason: String },
+    #[error("Snapshot version {version} isn't supported")]
+    UnsupportedVersion { version: i64 },
+    #[error("{path} doesn't match the snapshot's manifest")]
+    Corrupt { path: String },
+    #[error("{path} changed while it was being archived. Stop the bridge before snapshotting")]
+    Changed { path: String },
+    #[error("Refusing to restore into {}, which already holds bridge state", path.display())]
+    NotEmpty { path: PathBuf },
+}
+
+fn malformed(reason: impl Into<String>) -> SnapshotError {
+    SnapshotError::Malformed {
+        reason: reason.into(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A file in a snapshot, by its path relative to the state directory
+pub struct ArchivedFile {
+    pub path: String,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// What a snapshot holds
+pub struct Manifest {
+    pub created_at: SystemTime,
+    pub files: Vec<ArchivedFile>,
+}
+
+impl Manifest {
+    fn to_json(&self) -> Value {
+        let files = self.files.iter().map(|file| {
+            Value::object([
+                ("path", Value::from(file.path.as_str())),
+                ("size", Value::from(file.size)),
+                ("sha256", Value::from(hex_encode(&file.sha256))),
+            ])
+        });
+        Value::object([
+            ("version", Value::from(VERSION)),
+            ("createdAt", Value::from(format_rfc3339(self.created_at))),
+            ("files", Value::Array(files.collect())),
+        ])
+    }
+
+    fn from_json(manifest: &Value) -> Result<Manifest, SnapshotError> {
+        match manifest.get("version").and_then(Value::as_i64) {
+            Some(VERSION) => {}
+            Some(version) => return Err(SnapshotError::UnsupportedVersion { version }),
+            None => return Err(malformed("the manifest has no version")),
+        }
+        let file = |file: &Value| {
+            let path = file.get("path").and_then(Value::as_str)?;
+            Some(ArchivedFile {
+                path: is_safe(path).then(|| path.to_string())?,
+                size: file.get("size")?.as_i64()?.try_into().ok()?,
+                sha256: hex_decode(file.get("sha256")?.as_str()?)?.try_into().ok()?,
+            })
+        };
+        let created_at = manifest.get("createdAt").and_then(Value::as_str);
+        Ok(Manifest {
+            created_at: created_at
+                .and_then(|at| parse_rfc3339(at).ok())
+                .ok_or_else(|| malformed("the manifest has no creation time"))?,
+            files: manifest
+                .get("files")
+                .and_then(Value::as_array)
+                .unwrap_or_default()
+                .iter()
+                .map(|f| file(f).ok_or_else(|| malformed("invalid file in the manifest")))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Whether a path from an archive stays inside the state directory: relative, with no empty,
+/// `.` or `..` components and no backslashes. Hidden names are refused too, as they're only
+/// used for writes in progress
+fn is_safe(path: &str) -> bool {
+    path.split('/').all(|part| {
+        !part.is_empty()
+            && part != "."
+            && part != ".."
+            && !part.starts_with('.')
+            && !part.contains(['\\', '\0'])
+    })
+}
+
+/// The directory holding `path`, and its file name, if `path` [is safe](is_safe)
+fn locate<'a>(root: &StateDir, path: &'a str) -> io::Result<(StateDir, &'a str)> {
+    if !is_safe(path) {
+        let message = format!("{path} isn't inside the state directory");
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut dir = root.clone();
+    for part in dirs.split('/').filter(|part| !part.is_empty()) {
+        dir = dir.subdir(part)?;
+    }
+    Ok((dir, name))
+}
+
+/// Paths of every file under `dir`, sorted
+fn walk(dir: &StateDir, prefix: &str, paths: &mut Vec<String>) -> io::Result<()> {
+    paths.extend(
+        dir.files()?
+            .into_iter()
+            .map(|name| prefix.to_string() + &name),
+    );
+    for name in dir.subdirs()? {
+        walk(&dir.subdir(&name)?, &format!("{prefix}{name}/"), paths)?;
+    }
+    Ok(())
+}
+
+/// A tar header block
+fn header(name: &str, size: u64, mtime: u64, kind: u8) -> [u8; BLOCK] {
+    let mut header = [0u8; BLOCK];
+    let mut field = |at: usize, value: &[u8]| header[at..at + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, b"0000600\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{size:011o}\0").as_bytes());
+    field(136, format!("{mtime:011o}\0").as_bytes());
+    field(148, b"        ");
+    field(156, &[kind]);
+    field(257, b"ustar\0");
+    field(263, b"00");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+struct TarWriter<W> {
+    output: W,
+    mtime: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    fn entry(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        let name = if path.len() > NAME_LEN {
+            // A pax record is `<length> path=<path>\n`, the length counting its own digits
+            let record = format!(" path={path}\n");
+            let mut len = record.len() + 1;
+            while len.to_string().len() + record.len() != len {
+                len += 1;
+            }
+            self.file("PaxHeader", b'x', format!("{len}{record}").as_bytes())?;
+            let end = (0..=NAME_LEN).rev().find(|&i| path.is_char_boundary(i));
+            &path[..end.unwrap_or_default()]
+        } else {
+            path
+        };
+        self.file(name, b'0', data)
+    }
+
+    fn file(&mut self, name: &str, kind: u8, data: &[u8]) -> io::Result<()> {
+        let size = data.len() as u64;
+        self.output
+            .write_all(&header(name, size, self.mtime, kind))?;
+        self.output.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.output.write_all(&[0; BLOCK][..padding])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.output.write_all(&[0; BLOCK * 2])?;
+        self.output.flush()
+    }
+}
+
+struct TarReader<R> {
+    input: R,
+}
+
+impl<R: Read> TarReader<R> {
+    /// The next file's path and contents
+    fn next(&mut self) -> Result<Option<(String, Vec<u8>)>, SnapshotError> {
+        let mut long_path = None;
+        loop {
+            let mut header = [0u8; BLOCK];
+            self.input
+                .read_exact(&mut header)
+                .map_err(|_| malformed("the archive is truncated"))?;
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let text = |range: std::ops::Range<usize>| {
+                let field = &header[range];
+                let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+                String::from_utf8_lossy(&field[..end]).trim().to_string()
+            };
+            let mut unsummed = header;
+            unsummed[148..156].fill(b' ');
+            let checksum: u32 = unsummed.iter().map(|&b| b as u32).sum();
+            if u32::from_str_radix(&text(148..156), 8) != Ok(checksum) {
+                return Err(malformed("a header's checksum is wrong"));
+            }
+            let size = u64::from_str_radix(&text(124..136), 8)
+                .map_err(|_| malformed("a header's size is invalid"))?;
+            let mut data = Vec::new();
+            (&mut self.input).take(size).read_to_end(&mut data)?;
+            let padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+            io::copy(&mut (&mut self.input).take(padding), &mut io::sink())?;
+            if data.len() as u64 != size {
+                return Err(malformed("the archive is truncated"));
+            }
+            match header[156] {
+                b'0' | 0 => {
+                    let path = long_path.take().unwrap_or_else(|| text(0..NAME_LEN));
+                    return Ok(Some((path, data)));
+                }
+                b'x' => {
+                    let records = String::from_utf8_lossy(&data);
+                    long_path = records
+                        .lines()
+                        .find_map(|record| record.split_once(" path="))
+                        .map(|(_, path)| path.to_string());
+                }
+                b'5' => {}
+                kind => {
+                    let kind = kind as char;
+                    return Err(malformed(format!("unexpected entry of type {kind:?}")));
+                }
+            }
+        }
+    }
+}
+
+/// Archive everything in `root` to `output`
+pub fn snapshot(root: &StateDir, output: impl Write) -> Result<Manifest, SnapshotError> {
+    let mut paths = Vec::new();
+    walk(root, "", &mut paths)?;
+    let mut files = Vec::new();
+    for path in paths {
+        let (dir, name) = locate(root, &path)?;
+        // Files can be removed out from under a snapshot, e.g. by media retention
+        if let Some(data) = dir.read(name)? {
+            files.push(ArchivedFile {
+                size: data.len() as u64,
+                sha256: sha256(&data),
+                path,
+            });
+        }
+    }
+    let manifest = Manifest {
+        created_at: SystemTime::now(),
+        files,
+    };
+    let mtime = manifest
+        .created_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut archive = TarWriter { output, mtime };
+    archive.entry(MANIFEST_FILE, manifest.to_json().to_string().as_bytes())?;
+    // Read again rather than held in memory, as re-hosted media can be large
+    for file in &manifest.files {
+        let (dir, name) = locate(root, &file.path)?;
+        let data = dir.read(name)?.unwrap_or_default();
+        if sha256(&data) != file.sha256 {
+            let path = file.path.clone();
+            return Err(SnapshotError::Changed { path });
+        }
+        archive.entry(&file.path, &data)?;
+    }
+    archive.finish()?;
+    Ok(manifest)
+}
+
+/// Unpack a snapshot from `input` into `root`, which must hold no files yet
+pub fn restore(input: impl Read, root: &StateDir) -> Result<Manifest, SnapshotError> {
+    use SnapshotError::*;
+    let mut existing = Vec::new();
+    walk(root, "", &mut existing)?;
+    if !existing.is_empty() {
+        return Err(NotEmpty {
+            path: root.path().to_path_buf(),
+        });
+    }
+    let mut archive = TarReader { input };
+    let manifest = match archive.next()? {
+        Some((path, data)) if path == MANIFEST_FILE => {
+            let manifest = json::parse(&String::from_utf8_lossy(&data))
+                .map_err(|e| malformed(format!("the manifest isn't JSON: {e}")))?;
+            Manifest::from_json(&manifest)?
+        }
+        _ => return Err(malformed("it doesn't start with a manifest")),
+    };
+    let mut expected: HashMap<&str, &ArchivedFile> = manifest
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file))
+        .collect();
+    while let Some((path, data)) = archive.next()? {
+        match expected.remove(path.as_str()) {
+            Some(file) if file.size == data.len() as u64 && file.sha256 == sha256(&data) => {
+                let (dir, name) = locate(root, &file.path)?;
+                dir.write(name, &data)?;
+            }
+            _ => return Err(Corrupt { path }),
+        }
+    }
+    if let Some(path) = expected.keys().min() {
+        return Err(malformed(format!("{path} is missing")));
+    }
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+
+    fn populated() -> StateDir {
+        let root = temp_state_dir();
+        root.write("identities.json", br#"{"identities": []}"#)
+            .unwrap();
+        let shard = root.subdir("shard-0-of-2").unwrap();
+        shard.write("cursor", b"42").unwrap();
+        let media = root.subdir("media").unwrap();
+        media
+            .write(&format!("{}.jpg", "a".repeat(120)), &[7; 700])
+            .unwrap();
+        root.subdir("empty").unwrap();
+        root.write(".cursor.tmp", b"half-written").unwrap();
+        root
+    }
+
+    #[test]
+    fn restores_what_was_snapshotted() {
+        let root = populated();
+        let mut archive = Vec::new();
+        let manifest = snapshot(&root, &mut archive).unwrap();
+        let paths: Vec<_> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        let long = format!("media/{}.jpg", "a".repeat(120));
+        assert_eq!(paths, ["identities.json", &long, "shard-0-of-2/cursor"]);
+
+        let target = temp_state_dir();
+        let restored = restore(&archive[..], &target).unwrap();
+        assert_eq!(restored.files, manifest.files);
+        let cursor = target.subdir("shard-0-of-2").unwrap().read("cursor");
+        assert_eq!(cursor.unwrap(), Some(b"42".to_vec()));
+        let (dir, name) = locate(&target, &long).unwrap();
+        assert_eq!(dir.read(name).unwrap(), Some(vec![7; 700]));
+        assert!(locate(&target, "../escape").is_err());
+        assert_eq!(target.read(".cursor.tmp").unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_occupied_targets_and_damaged_archives() {
+        let root = populated();
+        let mut archive = Vec::new();
+        snapshot(&root, &mut archive).unwrap();
+        assert!(matches!(
+            restore(&archive[..], &root),
+            Err(SnapshotError::NotEmpty { .. })
+        ));
+
+        // Flip a byte of the cursor's contents, fixing nothing else up
+        let at = archive.windows(2).rposition(|w| w == b"42").unwrap();
+        let mut damaged = archive.clone();
+        damaged[at] = b'7';
+        assert!(matches!(
+            restore(&damaged[..], &temp_state_dir()),
+            Err(SnapshotError::Corrupt { path }) if path == "shard-0-of-2/cursor"
+        ));
+        let truncated = &archive[..archive.len() - BLOCK * 3];
+        assert!(matches!(
+            restore(truncated, &temp_state_dir()),
+            Err(SnapshotError::Malformed { .. })
+        ));
+    }
+
+    #[test]
+    fn refuses_archives_writing_outside_the_state_directory() {
+        let target = temp_state_dir();
+        let outside = target.path().parent().unwrap().join("escaped");
+        for path in [
+            "../escaped",
+            "../../etc/cron.d/x",
+            "/tmp/escaped",
+            "a//b",
+            "a/./b",
+            "a\\..\\b",
+            ".cursor.tmp",
+        ] {
+            let manifest = Manifest {
+                created_at: UNIX_EPOCH,
+                files: vec![ArchivedFile {
+                    path: path.to_string(),
+                    size: 3,
+                    sha256: sha256(b"bad"),
+                }],
+            };
+            let mut archive = Vec::new();
+            let mut writer = TarWriter {
+                output: &mut archive,
+                mtime: 0,
+            };
+            writer
+                .entry(MANIFEST_FILE, manifest.to_json().to_string().as_bytes())
+                .unwrap();
+            writer.entry(path, b"bad").unwrap();
+            writer.finish().unwrap();
+            assert!(
+                matches!(
+                    restore(&archive[..], &target),
+                    Err(SnapshotError::Malformed { .. })
+                ),
+                "{path} was restored"
+            );
+            let mut written = Vec::new();
+            walk(&target, "", &mut written).unwrap();
+            assert!(written.is_empty(), "{path} wrote {written:?}");
+        }
+        assert!(!outside.exists());
+    }
+}
@@ -0,0 +1,479 @@
+//! Aggregate statistics on what the bridge has done
+//!
+//! Counts are kept as they happen rather than worked out from the [audit log](crate::audit),
+//! so reporting on months of traffic doesn't mean reading months of records, and outlives
+//! the log's retention. There are two tables, one row per day in each:
+//!
+//! - volumes, per network bridged onto and per fediverse instance: how many activities or
+//!   records went out, and how many of them were posts
+//! - failures, per job kind and reason, from each failed attempt at a job, so the commonest
+//!   show what's going wrong
+//!
+//! The tables are saved to the shard's state directory at most once a minute, and on
+//! shutdown. The admin API's `GET /admin/stats` and the `stats` command report on them
+
+use crate::audit::AuditRecord;
+use crate::delivery::DeliveryError;
+use crate::digest::Network;
+use crate::json::{self, Value};
+use crate::storage::StateDir;
+use crate::time::format_rfc3339;
+use crate::transport::TransportError;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The tables' file in the state directory
+pub const STATS_FILE: &str = "stats.json";
+/// The most instances and failure reasons reported when a query doesn't say
+pub const DEFAULT_TOP: usize = 10;
+/// The longest a recorded count goes unsaved
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Longer error messages are cut short as failure reasons
+const MAX_REASON_LENGTH: usize = 80;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// How much went out onto a network
+pub struct Volume {
+    pub activities: u64,
+    /// Of the activities, those creating posts
+    pub posts: u64,
+}
+
+impl Volume {
+    fn add(&mut self, other: Volume) {
+        self.activities += other.activities;
+        self.posts += other.posts;
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("activities", Value::from(self.activities)),
+            ("posts", Value::from(self.posts)),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// The volume in each direction
+pub struct Directions {
+    pub fediverse: Volume,
+    pub bluesky: Volume,
+}
+
+impl Directions {
+    fn add(&mut self, network: Network, volume: Volume) {
+        match network {
+            Network::Fediverse => self.fediverse.add(volume),
+            Network::Bluesky => self.bluesky.add(volume),
+        }
+    }
+
+    pub fn activities(&self) -> u64 {
+        self.fediverse.activities + self.bluesky.activities
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("fediverse", self.fediverse.to_json()),
+            ("bluesky", self.bluesky.to_json()),
+        ])
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Which days to report on, inclusive, and how many instances and failure reasons
+pub struct StatsQuery {
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+    pub top: usize,
+}
+
+impl Default for StatsQuery {
+    fn default() -> StatsQuery {
+        StatsQuery {
+            since: None,
+            until: None,
+            top: DEFAULT_TOP,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatsReport {
+    /// Each day with anything recorded, oldest first
+    pub days: Vec<(String, Directions)>,
+    pub totals: Directions,
+    /// The busiest instances first
+    pub instances: Vec<(String, Directions)>,
+    /// The commonest failure reasons first
+    pub failures: Vec<(String, u64)>,
+}
+
+impl StatsReport {
+    pub fn to_json(&self) -> Value {
+        let named = |key: &str, name: &str, directions: &Directions| {
+            let mut row = directions.to_json();
+            if let Value::Object(fields) = &mut row {
+                fields.insert(key.to_string(), Value::from(name));
+            }
+            row
+        };
+        Value::object([
+            (
+                "days",
+                Value::Array(self.days.iter().map(|(d, v)| named("day", d, v)).collect()),
+            ),
+            ("totals", self.totals.to_json()),
+            (
+                "instances",
+                Value::Array(
+                    self.instances
+                        .iter()
+                        .map(|(i, v)| named("instance", i, v))
+                        .collect(),
+                ),
+            ),
+            (
+                "failures",
+                Value::Array(
+                    self.failures
+                        .iter()
+                        .map(|(reason, count)| {
+                            Value::object([
+                                ("reason", Value::from(reason.as_str())),
+                                ("count", Value::from(*count)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ])
+    }
+}
+
+/// A row of the volumes table: the day, the network, and the instance if known
+type VolumeKey = (String, &'static str, Option<String>);
+
+#[derive(Debug, Default)]
+struct Tables {
+    volumes: BTreeMap<VolumeKey, Volume>,
+    /// Counts by day and reason
+    failures: BTreeMap<(String, String), u64>,
+    saved: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+/// The pre-aggregated tables
+pub struct Stats {
+    tables: Mutex<Tables>,
+    dir: Option<StateDir>,
+}
+
+/// The UTC day `at` falls on, as `YYYY-MM-DD`
+fn day(at: SystemTime) -> String {
+    format_rfc3339(at)[..10].to_string()
+}
+
+/// Why a job failed, as a short reason shared by failures alike, such as `deliver: timeout`
+pub fn failure_reason(kind: &str, error: &anyhow::Error) -> String {
+    let transport = |e: &TransportError| match e {
+        TransportError::InvalidUrl(_) => "invalid URL".to_string(),
+        TransportError::UnsupportedScheme { scheme } => format!("unsupported scheme {scheme}"),
+        TransportError::Connect { .. } => "couldn't connect".to_string(),
+        TransportError::Timeout { .. } => "timeout".to_string(),
+        TransportError::MalformedResponse { .. } => "malformed response".to_string(),
+        TransportError::ResponseTooLarge { .. } => "response too large".to_string(),
+        TransportError::Forbidden(_) => "forbidden destination".to_string(),
+        TransportError::CircuitOpen { .. } => "circuit open".to_string(),
+    };
+    let reason = match (
+        error.downcast_ref::<DeliveryError>(),
+        error.downcast_ref::<TransportError>(),
+    ) {
+        (Some(DeliveryError::Rejected { status, .. }), _) => format!("status {status}"),
+        (Some(DeliveryError::Transport(e)), _) | (None, Some(e)) => transport(e),
+        (Some(DeliveryError::NoSigner | DeliveryError::NoKey { .. }), _) => "unsigned".to_string(),
+        (Some(_), _) | (None, None) => error.to_string().chars().take(MAX_REASON_LENGTH).collect(),
+    };
+    format!("{kind}: {reason}")
+}
+
+impl Stats {
+    /// The tables persisted in `dir`, or empty ones
+    pub fn open(dir: StateDir) -> io::Result<Stats> {
+        let mut tables = Tables::default();
+        if let Some(contents) = dir.read(STATS_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let rows = |table| {
+                saved
+                    .get(table)
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+            };
+            let count = |row: &Value, field| {
+                let count = row.get(field).and_then(Value::as_i64);
+                count.and_then(|n| u64::try_from(n).ok()).unwrap_or(0)
+            };
+            for row in rows("volumes") {
+                let day = row.get("day").and_then(Value::as_str);
+                let network = row.get("network").and_then(Value::as_str);
+                let Some((day, network)) = day.zip(network.and_then(Network::parse)) else {
+                    continue;
+                };
+                let instance = row.get("instance").and_then(Value::as_str);
+                let key = (
+                    day.to_string(),
+                    network.as_str(),
+                    instance.map(str::to_string),
+                );
+                let volume = Volume {
+                    activities: count(row, "activities"),
+                    posts: count(row, "posts"),
+                };
+                tables.volumes.insert(key, volume);
+            }
+            for row in rows("failures") {
+                let day = row.get("day").and_then(Value::as_str);
+                let reason = row.get("reason").and_then(Value::as_str);
+                if let Some((day, reason)) = day.zip(reason) {
+                    let key = (day.to_string(), reason.to_string());
+                    tables.failures.insert(key, count(row, "count"));
+                }
+            }
+        }
+        Ok(Stats {
+            tables: Mutex::new(tables),
+            dir: Some(dir),
+        })
+    }
+
+    fn save(&self, tables: &mut Tables) -> io::Result<()> {
+        tables.saved = Some(Instant::now());
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        let volumes = tables.volumes.iter().map(|((day, network, instance), v)| {
+            Value::object([
+                ("day", Value::from(day.as_str())),
+                ("network", Value::from(*network)),
+                ("instance", Value::from(instance.clone())),
+                ("activities", Value::from(v.activities)),
+                ("posts", Value::from(v.posts)),
+            ])
+        });
+        let failures = tables.failures.iter().map(|((day, reason), count)| {
+            Value::object([
+                ("day", Value::from(day.as_str())),
+                ("reason", Value::from(reason.as_str())),
+                ("count", Value::from(*count)),
+            ])
+        });
+        let saved = Value::object([
+            ("volumes", Value::Array(volumes.collect())),
+            ("failures", Value::Array(failures.collect())),
+        ]);
+        dir.write(STATS_FILE, saved.to_string().as_bytes())
+    }
+
+    /// Save the tables if they haven't been for a while. A count is only lost to a failed
+    /// save if the bridge stops before the next one succeeds, so failures are only logged
+    fn changed(&self, tables: &mut Tables) {
+        if tables
+            .saved
+            .is_some_and(|saved| saved.elapsed() < SAVE_INTERVAL)
+        {
+            return;
+        }
+        if let Err(e) = self.save(tables) {
+            eprintln!("Couldn't save the statistics: {e}");
+        }
+    }
+
+    /// Count what `record` did, to or from `instance`
+    pub fn record(&self, record: &AuditRecord, instance: Option<&str>) {
+        let key = (
+            day(record.at),
+            record.network.as_str(),
+            instance.map(str::to_ascii_lowercase),
+        );
+        let mut tables = self.tables.lock().unwrap();
+        let volume = tables.volumes.entry(key).or_default();
+        volume.activities += 1;
+        volume.posts += u64::from(record.is_post());
+        self.changed(&mut tables);
+    }
+
+    /// Count a failed attempt at a job, for `reason` from [`failure_reason`]
+    pub fn failed(&self, at: SystemTime, reason: &str) {
+        let mut tables = self.tables.lock().unwrap();
+        *tables
+            .failures
+            .entry((day(at), reason.to_string()))
+            .or_default() += 1;
+        self.changed(&mut tables);
+    }
+
+    /// Save the tables now, as on shutdown
+    pub fn flush(&self) -> io::Result<()> {
+        self.save(&mut self.tables.lock().unwrap())
+    }
+
+    pub fn report(&self, query: &StatsQuery) -> StatsReport {
+        let since = query.since.map(day);
+        let until = query.until.map(day);
+        let wanted = |day: &String| {
+            since.as_ref().is_none_or(|since| day >= since)
+                && until.as_ref().is_none_or(|until| day <= until)
+        };
+        let tables = self.tables.lock().unwrap();
+        let mut report = StatsReport::default();
+        let mut days: BTreeMap<&str, Directions> = BTreeMap::new();
+        let mut instances: HashMap<&str, Directions> = HashMap::new();
+        for ((day, network, instance), volume) in &tables.volumes {
+            if !wanted(day) {
+                continue;
+            }
+            let network = Network::parse(network).expect("only networks are recorded");
+            days.entry(day).or_default().add(network, *volume);
+            report.totals.add(network, *volume);
+            if let Some(instance) = instance {
+                instances.entry(instance).or_default().add(network, *volume);
+            }
+        }
+        let mut failures: HashMap<&str, u64> = HashMap::new();
+        for ((day, reason), count) in &tables.failures {
+            if wanted(day) {
+                *failures.entry(reason).or_default() += count;
+            }
+        }
+        report.days = days
+            .into_iter()
+            .map(|(day, directions)| (day.to_string(), directions))
+            .collect();
+        let mut instances: Vec<_> = instances.into_iter().collect();
+        instances.sort_by(|a, b| b.1.activities().cmp(&a.1.activities()).then(a.0.cmp(b.0)));
+        report.instances = instances
+            .into_iter()
+            .take(query.top)
+            .map(|(instance, directions)| (instance.to_string(), directions))
+            .collect();
+        let mut failures: Vec<_> = failures.into_iter().collect();
+        failures.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        report.failures = failures
+            .into_iter()
+            .take(query.top)
+            .map(|(reason, count)| (reason.to_string(), count))
+            .collect();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::time::parse_rfc3339;
+
+    fn record(at: &str, network: Network, action: &str, object: &str) -> AuditRecord {
+        AuditRecord {
+            at: parse_rfc3339(at).unwrap(),
+            network,
+            action: action.to_string(),
+            actor: "https://bridge.example/ap/alice".to_string(),
+            object: Some(object.to_string()),
+            cause: None,
+        }
+    }
+
+    #[test]
+    fn reports_volumes_instances_and_failures_by_day() {
+        let dir = temp_state_dir();
+        let stats = Stats::open(dir.clone()).unwrap();
+        let post = "at://did:plc:alice/app.bsky.feed.post/3k";
+        let like = "at://did:plc:alice/app.bsky.feed.like/3l";
+        let create = "com.atproto.repo.createRecord";
+        for (at, network, action, object, instance) in [
+            (
+                "2026-10-01T10:00:00Z",
+                Network::Fediverse,
+                "Create",
+                "x",
+                "b.example",
+            ),
+            (
+                "2026-10-01T23:59:00Z",
+                Network::Fediverse,
+                "Like",
+                "y",
+                "c.example",
+            ),
+            (
+                "2026-10-02T00:01:00Z",
+                Network::Fediverse,
+                "Create",
+                "z",
+                "B.example",
+            ),
+            (
+                "2026-10-02T08:00:00Z",
+                Network::Bluesky,
+                create,
+                post,
+                "b.example",
+            ),
+            (
+                "2026-10-02T09:00:00Z",
+                Network::Bluesky,
+                create,
+                like,
+                "b.example",
+            ),
+        ] {
+            stats.record(&record(at, network, action, object), Some(instance));
+        }
+        let timeout = anyhow::Error::from(DeliveryError::Transport(TransportError::Timeout {
+            url: "https://b.example/inbox".to_string(),
+        }));
+        let reason = failure_reason("deliver", &timeout);
+        assert_eq!(reason, "deliver: timeout");
+        let at = parse_rfc3339("2026-10-02T00:00:00Z").unwrap();
+        stats.failed(at, &reason);
+        stats.failed(at, &reason);
+        stats.failed(at, "notify: status 500");
+        stats.flush().unwrap();
+
+        let stats = Stats::open(dir).unwrap();
+        let report = stats.report(&StatsQuery::default());
+        assert_eq!(report.days.len(), 2);
+        assert_eq!(
+            report.days[0].1.fediverse,
+            Volume {
+                activities: 2,
+                posts: 1
+            }
+        );
+        assert_eq!(
+            report.totals.bluesky,
+            Volume {
+                activities: 2,
+                posts: 1
+            }
+        );
+        assert_eq!(report.instances[0].0, "b.example");
+        assert_eq!(report.instances[0].1.activities(), 4);
+        assert_eq!(report.failures[0], ("deliver: timeout".to_string(), 2));
+
+        let query = StatsQuery {
+            since: Some(at),
+            top: 1,
+            ..StatsQuery::default()
+        };
+        let report = stats.report(&query);
+        assert_eq!(report.days.len(), 1);
+        assert_eq!(report.instances.len(), 1);
+        assert_eq!(report.failures.len(), 1);
+    }
+}
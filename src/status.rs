@@ -0,0 +1,237 @@
+//! Reflecting account status changes across the bridge
+//!
+//! When an account stops being active on its own network its bridged counterpart follows:
+//!
+//! | atproto event                  | Fediverse side                           |
+//! |--------------------------------|------------------------------------------|
+//! | deactivated or suspended       | bridging suspended                       |
+//! | taken down                     | bridging suspended, actor `Delete` sent  |
+//! | deleted                        | [unbridged](crate::unbridge)             |
+//! | reactivated                    | bridging resumed, profile resynced       |
+//! | `#identity` (e.g. new handle)  | handle updated, profile resynced         |
+//!
+//! and in the other direction a fediverse actor which is deleted (which is also how instances
+//! announce suspensions) or answers `410 Gone` has the repo bridging it deactivated.
+//!
+//! Only suspensions made here are lifted on reactivation, so an operator's pause sticks
+
+use crate::bridge::Bridge;
+use crate::cache::ResourceKind;
+use crate::digest::Network;
+use crate::firehose::{AccountEvent, AccountStatus, IdentityEvent};
+use crate::jobs::Job;
+use crate::json::Value;
+use crate::store::MappingStatus;
+use crate::unbridge::{unbridge, DeletionReason, UnbridgeError};
+use std::io;
+
+/// Apply an `#account` event to a bridged account, if it is one
+pub fn account_changed(bridge: &Bridge, event: &AccountEvent) -> io::Result<()> {
+    let Some(mapping) = bridge.identities.get(&event.did) else {
+        return Ok(());
+    };
+    let did = event.did.clone();
+    // Nothing is bridged for a passive mapping, so there's only ever the entry to clean up
+    if mapping.status == MappingStatus::Passive {
+        if event.status == Some(AccountStatus::Deleted) {
+            let _ = bridge.identities.remove(&did);
+        }
+        return Ok(());
+    }
+    let suspend = || match mapping.status {
+        MappingStatus::Active => bridge.identities.set_status(&did, MappingStatus::Suspended),
+        MappingStatus::Paused | MappingStatus::Suspended | MappingStatus::Passive => Ok(()),
+    };
+    if event.active {
+        if mapping.status == MappingStatus::Suspended {
+            let _ = bridge.identities.set_status(&did, MappingStatus::Active);
+            bridge.jobs.push(Job::SyncProfile { did })?;
+        }
+        return Ok(());
+    }
+    match event.status {
+        Some(AccountStatus::Deleted) => {
+            let reason = DeletionReason::DeletedAtOrigin(Network::Bluesky);
+            // It's bridged, so the only way this can fail is writing state
+            if let Err(UnbridgeError::Io(e)) = unbridge(bridge, &did, reason, None) {
+                return Err(e);
+            }
+        }
+        Some(AccountStatus::Takendown) => {
+            let _ = suspend();
+            bridge.jobs.push(Job::DeleteActor { did })?;
+        }
+        Some(AccountStatus::Deactivated | AccountStatus::Suspended) | None => {
+            let _ = suspend();
+        }
+    }
+    Ok(())
+}
+
+/// Apply an `#identity` event to a bridged account, if it is one
+pub fn identity_changed(bridge: &Bridge, event: &IdentityEvent) -> io::Result<()> {
+    let Some(mapping) = bridge.identities.get(&event.did) else {
+        return Ok(());
+    };
+    // The DID document may have changed too, so don't keep serving the old one
+    bridge
+        .documents
+        .invalidate(ResourceKind::DidDocument, event.did.as_str());
+    if mapping.handle != event.handle {
+        let _ = bridge
+            .identities
+            .set_handle(&event.did, event.handle.clone());
+        if mapping.status == MappingStatus::Passive {
+            return Ok(());
+        }
+        bridge.jobs.push(Job::SyncProfile {
+            did: event.did.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+/// The actor an activity announces the deletion of, if that's what it is
+///
+/// Only an actor deleting itself counts, which is what instances send on suspension
+pub fn deleted_actor(activity: &Value) -> Option<&str> {
+    if activity.get("type")?.as_str()? != "Delete" {
+        return None;
+    }
+    let actor = activity.get("actor")?.as_str()?;
+    let object = activity.get("object")?;
+    let object = object
+        .as_str()
+        .or_else(|| object.get("id").and_then(Value::as_str))?;
+    (object == actor).then_some(actor)
+}
+
+/// Deactivate the repo bridging a fediverse actor which no longer exists
+///
+/// Returns whether `actor` was bridged
+pub fn remote_actor_gone(bridge: &Bridge, actor: &str) -> io::Result<bool> {
+    bridge.verified_keys.invalidate_actor(actor);
+    let Some(mapping) = bridge.identities.get_by_actor(actor) else {
+        return Ok(false);
+    };
+    if mapping.status != MappingStatus::Suspended {
+        let _ = bridge
+            .identities
+            .set_status(&mapping.did, MappingStatus::Suspended);
+        bridge
+            .jobs
+            .push(Job::DeactivateAccount { did: mapping.did })?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::store::Mapping;
+    use atproto::did;
+    use atproto::DID::Did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ACTOR: &str = "https://bridge.example/users/alice";
+
+    fn bridge() -> Bridge {
+        let bridge = Bridge::new();
+        bridge.identities.insert(Mapping::new(ALICE, ACTOR));
+        bridge
+    }
+
+    fn account(active: bool, status: Option<AccountStatus>) -> AccountEvent {
+        AccountEvent {
+            seq: 1,
+            did: ALICE,
+            active,
+            status,
+        }
+    }
+
+    fn queued(bridge: &Bridge) -> Vec<Job> {
+        bridge.jobs.queued().into_iter().map(|j| j.job).collect()
+    }
+
+    fn status(bridge: &Bridge) -> Option<MappingStatus> {
+        bridge.identities.get(&ALICE).map(|m| m.status)
+    }
+
+    #[test]
+    fn deactivation_suspends_until_reactivated() {
+        let bridge = bridge();
+        account_changed(&bridge, &account(false, Some(AccountStatus::Deactivated))).unwrap();
+        assert_eq!(status(&bridge), Some(MappingStatus::Suspended));
+        assert!(queued(&bridge).is_empty());
+        account_changed(&bridge, &account(true, None)).unwrap();
+        assert_eq!(status(&bridge), Some(MappingStatus::Active));
+        assert_eq!(queued(&bridge), vec![Job::SyncProfile { did: ALICE }]);
+    }
+
+    #[test]
+    fn operator_pause_survives_reactivation() {
+        let bridge = bridge();
+        bridge
+            .identities
+            .set_status(&ALICE, MappingStatus::Paused)
+            .unwrap();
+        account_changed(&bridge, &account(false, Some(AccountStatus::Suspended))).unwrap();
+        account_changed(&bridge, &account(true, None)).unwrap();
+        assert_eq!(status(&bridge), Some(MappingStatus::Paused));
+    }
+
+    #[test]
+    fn takedown_and_deletion_delete_the_actor() {
+        let bridge = bridge();
+        account_changed(&bridge, &account(false, Some(AccountStatus::Takendown))).unwrap();
+        assert_eq!(status(&bridge), Some(MappingStatus::Suspended));
+        account_changed(&bridge, &account(false, Some(AccountStatus::Deleted))).unwrap();
+        assert_eq!(status(&bridge), None);
+        // The actor is only deleted once, and the deletion is audited
+        assert_eq!(queued(&bridge), vec![Job::DeleteActor { did: ALICE }]);
+        assert_eq!(bridge.deletions.all().len(), 1);
+        // Events for accounts which aren't bridged are ignored
+        account_changed(&bridge, &account(false, Some(AccountStatus::Deleted))).unwrap();
+        assert_eq!(queued(&bridge).len(), 1);
+    }
+
+    #[test]
+    fn handle_changes_resync_profile() {
+        let bridge = bridge();
+        let event = IdentityEvent {
+            seq: 2,
+            did: ALICE,
+            handle: Some("alice.example".to_string()),
+        };
+        identity_changed(&bridge, &event).unwrap();
+        identity_changed(&bridge, &event).unwrap();
+        assert_eq!(
+            bridge.identities.get(&ALICE).unwrap().handle.as_deref(),
+            Some("alice.example")
+        );
+        assert_eq!(queued(&bridge), vec![Job::SyncProfile { did: ALICE }]);
+    }
+
+    #[test]
+    fn remote_deletion_deactivates_repo() {
+        let bridge = bridge();
+        let activity = json::parse(&format!(
+            r#"{{"type": "Delete", "actor": "{ACTOR}", "object": {{"id": "{ACTOR}"}}}}"#
+        ))
+        .unwrap();
+        assert_eq!(deleted_actor(&activity), Some(ACTOR));
+        let note = json::parse(&format!(
+            r#"{{"type": "Delete", "actor": "{ACTOR}", "object": "{ACTOR}/notes/1"}}"#
+        ))
+        .unwrap();
+        assert_eq!(deleted_actor(&note), None);
+
+        assert!(remote_actor_gone(&bridge, ACTOR).unwrap());
+        assert!(remote_actor_gone(&bridge, ACTOR).unwrap());
+        assert!(!remote_actor_gone(&bridge, "https://other.example/users/bob").unwrap());
+        assert_eq!(status(&bridge), Some(MappingStatus::Suspended));
+        assert_eq!(queued(&bridge), vec![Job::DeactivateAccount { did: ALICE }]);
+    }
+}
@@ -0,0 +1,249 @@
+//! On-disk state directory
+//!
+//! Bridge state that has to survive restarts is written as named files in a single directory.
+//! Writes go to a temporary file which is then renamed over the old one, so a crash mid-write
+//! never leaves a half-written file behind. The layout of the files is versioned, by
+//! [`FORMAT_VERSION`] in [`FORMAT_FILE`], so that a bridge refuses state written by a newer one
+//!
+//! State which grows an entry at a time is kept in a [`JsonLog`] instead, appended to rather
+//! than rewritten each time
+
+use crate::json::{self, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The version of the state directory's layout this build reads and writes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The file recording the layout version of a state directory
+pub const FORMAT_FILE: &str = "format-version";
+
+#[derive(Debug, Clone)]
+/// A directory holding the bridge's persisted state
+pub struct StateDir {
+    root: PathBuf,
+}
+
+impl StateDir {
+    /// Open (creating if needed) a state directory
+    pub fn open(root: impl Into<PathBuf>) -> io::Result<StateDir> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(StateDir { root })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Atomically replace the contents of `name`
+    pub fn write(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let tmp = self.root.join(format!(".{name}.tmp"));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(tmp, self.root.join(name))
+    }
+
+    /// Read the contents of `name`, or `None` if it has never been written
+    pub fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.root.join(name)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Open (creating if needed) a nested state directory
+    pub fn subdir(&self, name: &str) -> io::Result<StateDir> {
+        StateDir::open(self.root.join(name))
+    }
+
+    /// Names of the nested state directories
+    pub fn subdirs(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Add to the end of `name`, creating it if needed
+    ///
+    /// Unlike [`write`](StateDir::write) this isn't atomic: a crash can leave part of
+    /// `contents` behind
+    pub fn append(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::options()
+            .create(true)
+            .append(true)
+            .open(self.root.join(name))?;
+        file.write_all(contents)?;
+        file.sync_data()
+    }
+
+    /// Names of the files directly in this directory, leaving out any mid-write
+    pub fn files(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_tmp = name.starts_with('.') && name.ends_with(".tmp");
+            if entry.file_type()?.is_file() && !is_tmp {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// The layout version this directory was written with, or `None` if it predates versioning
+    /// or is new
+    pub fn format_version(&self) -> io::Result<Option<u32>> {
+        let Some(contents) = self.read(FORMAT_FILE)? else {
+            return Ok(None);
+        };
+        let version = String::from_utf8_lossy(&contents)
+            .trim()
+            .parse()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed {FORMAT_FILE}"),
+                )
+            })?;
+        Ok(Some(version))
+    }
+
+    /// Record that this directory is laid out as [`FORMAT_VERSION`], refusing one laid out by a
+    /// newer version
+    pub fn stamp_format(&self) -> io::Result<()> {
+        match self.format_version()? {
+            Some(FORMAT_VERSION) => Ok(()),
+            Some(version) if version > FORMAT_VERSION => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Laid out by a newer fedibridge, as version {version}"),
+            )),
+            _ => self.write(FORMAT_FILE, FORMAT_VERSION.to_string().as_bytes()),
+        }
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A file in a state directory of JSON values, one per line, which grows by appending
+///
+/// An append isn't atomic, so a crash can leave the last line torn. Opening the log ends that
+/// line, so the next value appended starts one of its own, and skips any line which doesn't
+/// parse
+pub struct JsonLog {
+    dir: StateDir,
+    name: String,
+}
+
+impl JsonLog {
+    /// The log `name` in `dir`, and the values already in it, oldest first
+    pub fn open(dir: StateDir, name: &str) -> io::Result<(JsonLog, Vec<Value>)> {
+        let contents = dir.read(name)?.unwrap_or_default();
+        if contents.last().is_some_and(|&b| b != b'\n') {
+            dir.append(name, b"\n")?;
+        }
+        let values = String::from_utf8_lossy(&contents)
+            .lines()
+            .filter_map(|line| json::parse(line).ok())
+            .collect();
+        let log = JsonLog {
+            dir,
+            name: name.to_string(),
+        };
+        Ok((log, values))
+    }
+
+    /// Add `values` to the end of the log, in a single write
+    pub fn append(&self, values: impl IntoIterator<Item = Value>) -> io::Result<()> {
+        self.dir.append(&self.name, lines(values).as_bytes())
+    }
+
+    /// Atomically replace everything in the log with `values`, as once some have expired
+    pub fn rewrite(&self, values: impl IntoIterator<Item = Value>) -> io::Result<()> {
+        self.dir.write(&self.name, lines(values).as_bytes())
+    }
+}
+
+fn lines(values: impl IntoIterator<Item = Value>) -> String {
+    values
+        .into_iter()
+        .map(|value| format!("{value}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh state directory under the system temp dir
+    pub(crate) fn temp_state_dir() -> StateDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("fedibridge-test-{}-{n}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        StateDir::open(path).unwrap()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let dir = temp_state_dir();
+        assert_eq!(dir.read("cursor").unwrap(), None);
+        dir.write("cursor", b"42").unwrap();
+        dir.write("cursor", b"43").unwrap();
+        assert_eq!(dir.read("cursor").unwrap(), Some(b"43".to_vec()));
+        dir.remove("cursor").unwrap();
+        dir.remove("cursor").unwrap();
+        assert_eq!(dir.read("cursor").unwrap(), None);
+    }
+
+    #[test]
+    fn nested_directories() {
+        let dir = temp_state_dir();
+        dir.write("file", b"").unwrap();
+        dir.subdir("b").unwrap().write("cursor", b"1").unwrap();
+        dir.subdir("a").unwrap();
+        assert_eq!(dir.subdirs().unwrap(), ["a", "b"]);
+        assert_eq!(dir.files().unwrap(), ["file"]);
+        assert_eq!(
+            dir.subdir("b").unwrap().read("cursor").unwrap(),
+            Some(b"1".to_vec())
+        );
+    }
+
+    #[test]
+    fn json_logs_survive_torn_appends() {
+        let dir = temp_state_dir();
+        let (log, values) = JsonLog::open(dir.clone(), "log.jsonl").unwrap();
+        assert!(values.is_empty());
+        log.append([Value::from(1_i64), Value::from(2_i64)])
+            .unwrap();
+        dir.append("log.jsonl", b"{\"torn").unwrap();
+        let (log, values) = JsonLog::open(dir.clone(), "log.jsonl").unwrap();
+        assert_eq!(values, [Value::from(1_i64), Value::from(2_i64)]);
+        log.append([Value::from(3_i64)]).unwrap();
+        let (log, values) = JsonLog::open(dir.clone(), "log.jsonl").unwrap();
+        assert_eq!(values.last(), Some(&Value::from(3_i64)));
+        log.rewrite([Value::from(4_i64)]).unwrap();
+        assert_eq!(
+            JsonLog::open(dir, "log.jsonl").unwrap().1,
+            [Value::from(4_i64)]
+        );
+    }
+}
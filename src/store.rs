@@ -0,0 +1,630 @@
+//! The identity store, mapping atproto identities to their bridged ActivityPub actors
+
+use crate::audience::UnlistedPolicy;
+use crate::engagement::EngagementMirror;
+use crate::json::{self, Value};
+use crate::language;
+use crate::lookalike;
+use crate::normalize;
+use crate::schedule::Schedule;
+use crate::storage::StateDir;
+use atproto::DID::Did;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::sync::RwLock;
+use thiserror::Error;
+
+/// Where the mappings are kept, at the root of the state directory as every shard shares them
+pub const IDENTITIES_FILE: &str = "identities.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Whether a mapping is currently being bridged
+pub enum MappingStatus {
+    Active,
+    /// Paused by an operator
+    Paused,
+    /// Paused because the account isn't active on its own network, until it's reactivated
+    Suspended,
+    /// Not bridged, only known so that mentions of it can be linked. The `actor` is the
+    /// account's native profile rather than a bridged actor
+    Passive,
+}
+
+impl MappingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MappingStatus::Active => "active",
+            MappingStatus::Paused => "paused",
+            MappingStatus::Suspended => "suspended",
+            MappingStatus::Passive => "passive",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<MappingStatus> {
+        [
+            MappingStatus::Active,
+            MappingStatus::Paused,
+            MappingStatus::Suspended,
+            MappingStatus::Passive,
+        ]
+        .into_iter()
+        .find(|status| status.as_str() == s)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Per-account bridging preferences
+pub struct Preferences {
+    /// Only bridge images and video which have alt text
+    pub require_alt_text: bool,
+    /// Bridge direct messages with people on the other network who have opted in too
+    pub bridge_dms: bool,
+    /// Send periodic digests of interactions on the other network
+    pub digests: bool,
+    /// Present the account as automated on the other network
+    pub bot: bool,
+    /// What becomes of unlisted posts, rather than what their instance's policy says
+    pub unlisted: Option<UnlistedPolicy>,
+    /// Only bridge posts in these languages, as normalized tags. Empty for all of them
+    pub languages: Vec<String>,
+    /// When posts are bridged, if not as soon as they're made
+    pub schedule: Option<Schedule>,
+    /// How likes and reposts on Bluesky are shown on the fediverse, if they are
+    pub engagement: Option<EngagementMirror>,
+}
+
+impl Preferences {
+    /// These preferences with those set in `changes`, a JSON object of the same fields as
+    /// [`Mapping::to_json`] writes
+    pub fn with_changes(&self, changes: &Value) -> Result<Preferences, String> {
+        let flag = |name, current| match changes.get(name) {
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| format!("{name} must be a boolean")),
+            None => Ok(current),
+        };
+        Ok(Preferences {
+            require_alt_text: flag("requireAltText", self.require_alt_text)?,
+            bridge_dms: flag("bridgeDms", self.bridge_dms)?,
+            digests: flag("digests", self.digests)?,
+            bot: flag("bot", self.bot)?,
+            unlisted: match changes.get("unlisted") {
+                Some(Value::Null) => None,
+                Some(value) => Some(
+                    value
+                        .as_str()
+                        .and_then(UnlistedPolicy::parse)
+                        .ok_or("unlisted must be skip, quiet, bridge or null")?,
+                ),
+                None => self.unlisted,
+            },
+            languages: match changes.get("languages") {
+                Some(value) => {
+                    let invalid = || "languages must be a list of language tags".to_string();
+                    let tags = value.as_array().ok_or_else(invalid)?;
+                    let mut languages = Vec::new();
+                    for tag in tags {
+                        let tag = tag.as_str().and_then(language::normalize);
+                        let tag = tag.ok_or_else(invalid)?;
+                        if !languages.contains(&tag) {
+                            languages.push(tag);
+                        }
+                    }
+                    languages
+                }
+                None => self.languages.clone(),
+            },
+            schedule: match changes.get("schedule") {
+                Some(Value::Null) => None,
+                Some(value) => Some(Schedule::from_json(value)?),
+                None => self.schedule,
+            },
+            engagement: match changes.get("engagement") {
+                Some(Value::Null) => None,
+                Some(value) => Some(
+                    value
+                        .as_str()
+                        .and_then(EngagementMirror::parse)
+                        .ok_or("engagement must be collections, footer or null")?,
+                ),
+                None => self.engagement,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A single bridged identity
+pub struct Mapping {
+    pub did: Did,
+    /// The IRI of the ActivityPub actor
+    pub actor: String,
+    /// The most recently seen atproto handle, if any
+    pub handle: Option<String>,
+    pub status: MappingStatus,
+    pub preferences: Preferences,
+}
+
+impl Mapping {
+    pub fn new(did: Did, actor: impl Into<String>) -> Mapping {
+        Mapping {
+            did,
+            actor: actor.into(),
+            handle: None,
+            status: MappingStatus::Active,
+            preferences: Preferences::default(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("did", Value::from(self.did.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            ("handle", Value::from(self.handle.clone())),
+            ("status", Value::from(self.status.as_str())),
+            (
+                "preferences",
+                Value::object([
+                    (
+                        "requireAltText",
+                        Value::from(self.preferences.require_alt_text),
+                    ),
+                    ("bridgeDms", Value::from(self.preferences.bridge_dms)),
+                    ("digests", Value::from(self.preferences.digests)),
+                    ("bot", Value::from(self.preferences.bot)),
+                    (
+                        "unlisted",
+                        Value::from(self.preferences.unlisted.map(|u| u.as_str())),
+                    ),
+                    ("languages", Value::from(self.preferences.languages.clone())),
+                    (
+                        "schedule",
+                        self.preferences
+                            .schedule
+                            .map_or(Value::Null, |s| s.to_json()),
+                    ),
+                    (
+                        "engagement",
+                        Value::from(self.preferences.engagement.map(|e| e.as_str())),
+                    ),
+                ]),
+            ),
+        ])
+    }
+
+    pub fn from_json(value: &Value) -> Option<Mapping> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let preferences = value.get("preferences");
+        let preference = |name| {
+            preferences
+                .and_then(|p| p.get(name))
+                .and_then(Value::as_bool)
+                .unwrap_or_default()
+        };
+        Some(Mapping {
+            did: Did::try_create(field("did")?.to_string()).ok()?,
+            actor: field("actor")?.to_string(),
+            handle: field("handle").map(str::to_string),
+            status: MappingStatus::parse(field("status")?)?,
+            preferences: Preferences {
+                require_alt_text: preference("requireAltText"),
+                bridge_dms: preference("bridgeDms"),
+                digests: preference("digests"),
+                bot: preference("bot"),
+                unlisted: preferences
+                    .and_then(|p| p.get("unlisted"))
+                    .and_then(Value::as_str)
+                    .and_then(UnlistedPolicy::parse),
+                languages: preferences
+                    .and_then(|p| p.get("languages"))
+                    .and_then(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|tag| tag.as_str().and_then(language::normalize))
+                    .collect(),
+                schedule: preferences
+                    .and_then(|p| p.get("schedule"))
+                    .and_then(|s| Schedule::from_json(s).ok()),
+                engagement: preferences
+                    .and_then(|p| p.get("engagement"))
+                    .and_then(Value::as_str)
+                    .and_then(EngagementMirror::parse),
+            },
+        })
+    }
+
+    /// Whether `query` is a (case-insensitive) substring of any identifying field
+    fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.did.as_str().to_lowercase().contains(&query)
+            || self.actor.to_lowercase().contains(&query)
+            || self
+                .handle
+                .as_ref()
+                .is_some_and(|h| h.to_lowercase().contains(&query))
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors from identity store operations
+pub enum StoreError {
+    #[error("No mapping exists for {did}")]
+    NotFound { did: Did },
+    #[error("{handle} is already another account's handle")]
+    HandleTaken { handle: String },
+    #[error("{handle} looks like {of}, another account's handle")]
+    Lookalike { handle: String, of: String },
+}
+
+/// The DIDs of the accounts with handles, by their handles' [skeletons](lookalike::skeleton)
+type Skeletons = HashMap<String, BTreeSet<Did>>;
+
+/// The handle among `mappings` of an account other than `did` which `handle` looks like
+fn lookalike(
+    mappings: &HashMap<Did, Mapping>,
+    skeletons: &Skeletons,
+    did: &Did,
+    handle: &str,
+) -> Option<String> {
+    let alike = skeletons.get(&lookalike::skeleton(handle))?;
+    let others = alike.iter().filter(|other| *other != did);
+    let mut handles = others.filter_map(|other| mappings.get(other)?.handle.as_deref());
+    handles.find(|other| *other != handle).map(str::to_string)
+}
+
+/// Refuse `handle` for `did` if it's a [lookalike] of another account's among `mappings`
+/// made with characters beyond ASCII. One which is all ASCII, such as `dare` of `clare`, is
+/// as likely to be a coincidence, so is only [flagged](lookalike::flagged)
+fn unlike(
+    mappings: &HashMap<Did, Mapping>,
+    skeletons: &Skeletons,
+    did: &Did,
+    handle: Option<&String>,
+) -> Result<(), StoreError> {
+    let Some(handle) = handle.filter(|handle| lookalike::deceptive(handle)) else {
+        return Ok(());
+    };
+    match lookalike(mappings, skeletons, did, handle) {
+        Some(of) => Err(StoreError::Lookalike {
+            handle: handle.clone(),
+            of,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Move `did` in `skeletons` from the skeleton of its handle `old` to that of `new`
+fn reindex(skeletons: &mut Skeletons, did: &Did, old: Option<&str>, new: Option<&str>) {
+    if let Some(old) = old {
+        let skeleton = lookalike::skeleton(old);
+        if let Some(dids) = skeletons.get_mut(&skeleton) {
+            dids.remove(did);
+            if dids.is_empty() {
+                skeletons.remove(&skeleton);
+            }
+        }
+    }
+    if let Some(new) = new {
+        let dids = skeletons.entry(lookalike::skeleton(new)).or_default();
+        dids.insert(did.clone());
+    }
+}
+
+/// `mapping` with its DID and handle canonicalized
+fn canonical(mut mapping: Mapping) -> Mapping {
+    mapping.did = normalize::did(&mapping.did).into_owned();
+    mapping.handle = mapping.handle.as_deref().map(normalize::handle);
+    mapping
+}
+
+#[derive(Debug, Default)]
+/// In-memory store of identity mappings
+pub struct IdentityStore {
+    mappings: RwLock<HashMap<Did, Mapping>>,
+    /// Only locked while `mappings` is, so lookalikes are found without going through them all
+    skeletons: RwLock<Skeletons>,
+}
+
+impl IdentityStore {
+    pub fn new() -> IdentityStore {
+        IdentityStore::default()
+    }
+
+    /// The mappings persisted in `dir`, or none if it has never been saved to
+    pub fn load(dir: &StateDir) -> io::Result<IdentityStore> {
+        let store = IdentityStore::new();
+        if let Some(contents) = dir.read(IDENTITIES_FILE)? {
+            let saved = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mappings = saved.get("identities").and_then(Value::as_array);
+            let mut loaded = store.mappings.write().unwrap();
+            let mut skeletons = store.skeletons.write().unwrap();
+            for mapping in mappings.unwrap_or_default() {
+                let mapping = canonical(Mapping::from_json(mapping).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Invalid saved mapping")
+                })?);
+                reindex(
+                    &mut skeletons,
+                    &mapping.did,
+                    None,
+                    mapping.handle.as_deref(),
+                );
+                loaded.insert(mapping.did.clone(), mapping);
+            }
+            drop(skeletons);
+            drop(loaded);
+        }
+        Ok(store)
+    }
+
+    /// Persist every mapping to `dir`
+    pub fn save(&self, dir: &StateDir) -> io::Result<()> {
+        let all = self.all().iter().map(Mapping::to_json).collect();
+        let saved = Value::object([("identities", Value::Array(all))]);
+        dir.write(IDENTITIES_FILE, saved.to_string().as_bytes())
+    }
+
+    /// Insert a mapping, with its DID and handle canonicalized, returning the one it replaced
+    ///
+    /// Unlike [`try_insert`](IdentityStore::try_insert), a [lookalike] of another account's
+    /// handle is let in, as the handle an account has on atproto is its own to choose. It's
+    /// logged, though, and [flagged](lookalike::flagged) for operators
+    pub fn insert(&self, mapping: Mapping) -> Option<Mapping> {
+        let mapping = canonical(mapping);
+        let mut mappings = self.mappings.write().unwrap();
+        let mut skeletons = self.skeletons.write().unwrap();
+        let handle = mapping.handle.as_ref();
+        if let Err(e) = unlike(&mappings, &skeletons, &mapping.did, handle) {
+            eprintln!("{} was let in, though: {e}", mapping.did);
+        }
+        Self::put(&mut mappings, &mut skeletons, mapping)
+    }
+
+    /// [Insert](IdentityStore::insert) a mapping unless its handle is a [lookalike] of another
+    /// account's
+    pub fn try_insert(&self, mapping: Mapping) -> Result<Option<Mapping>, StoreError> {
+        let mapping = canonical(mapping);
+        let mut mappings = self.mappings.write().unwrap();
+        let mut skeletons = self.skeletons.write().unwrap();
+        unlike(&mappings, &skeletons, &mapping.did, mapping.handle.as_ref())?;
+        Ok(Self::put(&mut mappings, &mut skeletons, mapping))
+    }
+
+    fn put(
+        mappings: &mut HashMap<Did, Mapping>,
+        skeletons: &mut Skeletons,
+        mapping: Mapping,
+    ) -> Option<Mapping> {
+        let old = mappings.get(&mapping.did).and_then(|m| m.handle.as_deref());
+        reindex(skeletons, &mapping.did, old, mapping.handle.as_deref());
+        mappings.insert(mapping.did.clone(), mapping)
+    }
+
+    pub fn get(&self, did: &Did) -> Option<Mapping> {
+        self.mappings
+            .read()
+            .unwrap()
+            .get(&normalize::did(did))
+            .cloned()
+    }
+
+    /// Whether `did` has a mapping, whatever its status
+    pub fn contains(&self, did: &Did) -> bool {
+        self.mappings
+            .read()
+            .unwrap()
+            .contains_key(&normalize::did(did))
+    }
+
+    /// Whether `did` is actually bridged, rather than unknown or passive
+    pub fn is_tracked(&self, did: &Did) -> bool {
+        self.mappings
+            .read()
+            .unwrap()
+            .get(&normalize::did(did))
+            .is_some_and(|m| m.status != MappingStatus::Passive)
+    }
+
+    /// Find the mapping for an ActivityPub actor IRI
+    pub fn get_by_actor(&self, actor: &str) -> Option<Mapping> {
+        self.mappings
+            .read()
+            .unwrap()
+            .values()
+            .find(|m| m.actor == actor)
+            .cloned()
+    }
+
+    /// Find the mapping with `handle`, however it's written
+    pub fn get_by_handle(&self, handle: &str) -> Option<Mapping> {
+        let handle = normalize::handle(handle);
+        self.mappings
+            .read()
+            .unwrap()
+            .values()
+            .find(|m| m.handle.as_deref() == Some(handle.as_str()))
+            .cloned()
+    }
+
+    /// Find the mapping an ActivityPub object belongs to: the one whose actor it is, or whose
+    /// actor IRI it's under
+    pub fn get_by_object(&self, object: &str) -> Option<Mapping> {
+        self.get_by_actor(object).or_else(|| {
+            self.all().into_iter().find(|m| {
+                object
+                    .strip_prefix(m.actor.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+            })
+        })
+    }
+
+    /// All mappings, sorted by DID
+    pub fn all(&self) -> Vec<Mapping> {
+        let mut all: Vec<_> = self.mappings.read().unwrap().values().cloned().collect();
+        all.sort_by(|a, b| a.did.cmp(&b.did));
+        all
+    }
+
+    /// Mappings where the DID, actor or handle contains `query`, sorted by DID
+    pub fn search(&self, query: &str) -> Vec<Mapping> {
+        let mut found: Vec<_> = self
+            .mappings
+            .read()
+            .unwrap()
+            .values()
+            .filter(|m| m.matches(query))
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| a.did.cmp(&b.did));
+        found
+    }
+
+    pub fn set_status(&self, did: &Did, status: MappingStatus) -> Result<(), StoreError> {
+        match self.mappings.write().unwrap().get_mut(&normalize::did(did)) {
+            Some(mapping) => {
+                mapping.status = status;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound { did: did.clone() }),
+        }
+    }
+
+    pub fn set_handle(&self, did: &Did, handle: Option<String>) -> Result<(), StoreError> {
+        match self.mappings.write().unwrap().get_mut(&normalize::did(did)) {
+            Some(mapping) => {
+                let handle = handle.as_deref().map(normalize::handle);
+                let mut skeletons = self.skeletons.write().unwrap();
+                let (old, new) = (mapping.handle.as_deref(), handle.as_deref());
+                reindex(&mut skeletons, &mapping.did, old, new);
+                mapping.handle = handle;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound { did: did.clone() }),
+        }
+    }
+
+    /// The mapping of another account than `did` whose handle `handle` is a
+    /// [lookalike] of
+    pub fn lookalike_of(&self, did: &Did, handle: &str) -> Option<Mapping> {
+        let mappings = self.mappings.read().unwrap();
+        let skeletons = self.skeletons.read().unwrap();
+        let of = lookalike(
+            &mappings,
+            &skeletons,
+            &normalize::did(did),
+            &normalize::handle(handle),
+        )?;
+        let mapping = mappings
+            .values()
+            .find(|m| m.handle.as_deref() == Some(of.as_str()));
+        mapping.cloned()
+    }
+
+    /// Give `did` the handle `handle` unless another mapping has it or one like it, returning
+    /// the one it had
+    pub fn claim_handle(&self, did: &Did, handle: &str) -> Result<Option<String>, StoreError> {
+        let did = normalize::did(did);
+        let handle = normalize::handle(handle);
+        let mut mappings = self.mappings.write().unwrap();
+        let taken = mappings
+            .values()
+            .any(|m| m.did != *did && m.handle.as_deref() == Some(handle.as_str()));
+        if taken {
+            return Err(StoreError::HandleTaken { handle });
+        }
+        let mut skeletons = self.skeletons.write().unwrap();
+        unlike(&mappings, &skeletons, &did, Some(&handle))?;
+        match mappings.get_mut(&did) {
+            Some(mapping) => {
+                let old = mapping.handle.as_deref();
+                reindex(&mut skeletons, &did, old, Some(&handle));
+                Ok(mapping.handle.replace(handle))
+            }
+            None => Err(StoreError::NotFound {
+                did: did.into_owned(),
+            }),
+        }
+    }
+
+    pub fn set_preferences(&self, did: &Did, preferences: Preferences) -> Result<(), StoreError> {
+        match self.mappings.write().unwrap().get_mut(&normalize::did(did)) {
+            Some(mapping) => {
+                mapping.preferences = preferences;
+                Ok(())
+            }
+            None => Err(StoreError::NotFound { did: did.clone() }),
+        }
+    }
+
+    pub fn remove(&self, did: &Did) -> Result<Mapping, StoreError> {
+        let mut mappings = self.mappings.write().unwrap();
+        let removed = mappings.remove(&normalize::did(did));
+        let removed = removed.ok_or_else(|| StoreError::NotFound { did: did.clone() })?;
+        let mut skeletons = self.skeletons.write().unwrap();
+        reindex(
+            &mut skeletons,
+            &removed.did,
+            removed.handle.as_deref(),
+            None,
+        );
+        Ok(removed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.mappings.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn did(s: &str) -> Did {
+        Did::try_create(s.to_string()).unwrap()
+    }
+
+    #[test]
+    fn search_matches_handle_case_insensitively() {
+        let store = IdentityStore::new();
+        let mut mapping = Mapping::new(did("did:plc:aaaa"), "https://bridge.example/users/a");
+        mapping.handle = Some("Alice.bsky.social".to_string());
+        store.insert(mapping);
+        store.insert(Mapping::new(
+            did("did:plc:bbbb"),
+            "https://bridge.example/users/b",
+        ));
+
+        let found = store.search("alice");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].did, did("did:plc:aaaa"));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = crate::storage::tests::temp_state_dir();
+        assert!(IdentityStore::load(&dir).unwrap().is_empty());
+        let store = IdentityStore::new();
+        let mut mapping = Mapping::new(did("did:plc:aaaa"), "https://bridge.example/users/a");
+        mapping.handle = Some("alice.bsky.social".to_string());
+        mapping.status = MappingStatus::Paused;
+        mapping.preferences.digests = true;
+        store.insert(mapping.clone());
+        store.save(&dir).unwrap();
+        assert_eq!(IdentityStore::load(&dir).unwrap().all(), [mapping]);
+    }
+
+    #[test]
+    fn missing_mapping_errors() {
+        let store = IdentityStore::new();
+        assert_eq!(
+            store.set_status(&did("did:plc:aaaa"), MappingStatus::Paused),
+            Err(StoreError::NotFound {
+                did: did("did:plc:aaaa")
+            })
+        );
+    }
+}
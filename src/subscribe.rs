@@ -0,0 +1,166 @@
+//! Consuming a relay's firehose
+//!
+//! [`spawn`] subscribes to the active [relay](crate::upstream)'s `subscribeRepos` from the
+//! bridge's cursor, and [offers](Lanes::offer) each event to the [lanes](crate::ingest), which
+//! hand it on to [`receive_frame`] to be archived and handled once. Whenever the connection
+//! drops, or another relay becomes the active one, it subscribes again from the cursor: the
+//! events sent again were handled already, and are recognised as such
+
+use crate::bridge::Bridge;
+use crate::firehose::Frame;
+use crate::inbound::receive_frame;
+use crate::ingest::{LaneConfig, Lanes};
+use crate::shutdown::Shutdown;
+use crate::upstream::{subscription, UpstreamKind};
+use crate::websocket;
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long to wait before subscribing again after losing the firehose
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+/// How long a read waits, so shutdown and relay switches are noticed that quickly
+const POLL: Duration = Duration::from_millis(250);
+
+/// Start a thread consuming the firehose into lanes configured by `config`, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, config: LaneConfig, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let lanes = Lanes::new(bridge.clone(), config, |bridge, message: Vec<u8>| {
+            if let Err(e) = receive_frame(bridge, &message) {
+                eprintln!("Couldn't handle a firehose event: {e:#}");
+            }
+        });
+        while !shutdown.is_requested() {
+            let Some(relay) = bridge.upstreams.active(UpstreamKind::Relay) else {
+                wait(&shutdown, RETRY_AFTER);
+                continue;
+            };
+            let relay = relay.to_string();
+            if let Err(e) = consume(&bridge, &lanes, &relay, &shutdown) {
+                eprintln!("Lost the firehose from {relay}: {e}");
+                let failed = Err(format!("Lost its firehose: {e}"));
+                bridge.upstreams.record(&relay, SystemTime::now(), failed);
+                wait(&shutdown, RETRY_AFTER);
+            }
+        }
+        lanes.finish();
+    })
+}
+
+fn wait(shutdown: &Shutdown, duration: Duration) {
+    let started = Instant::now();
+    while !shutdown.is_requested() && started.elapsed() < duration {
+        thread::sleep(POLL);
+    }
+}
+
+/// Read `relay`'s firehose into `lanes`, until shutdown or another relay becomes the active one
+fn consume(
+    bridge: &Bridge,
+    lanes: &Lanes<Vec<u8>>,
+    relay: &str,
+    shutdown: &Shutdown,
+) -> io::Result<()> {
+    let url = subscription(relay, bridge.firehose.position());
+    let mut client = websocket::connect(&*bridge.transport, &url, POLL)?;
+    while !shutdown.is_requested() && bridge.upstreams.active(UpstreamKind::Relay) == Some(relay) {
+        if let Some(message) = client.receive()? {
+            offer(lanes, message);
+        }
+    }
+    Ok(())
+}
+
+/// Offer the firehose message `message` to `lanes`, as the event its header describes
+fn offer(lanes: &Lanes<Vec<u8>>, message: Vec<u8>) {
+    let header = match Frame::parse(&message) {
+        Ok(Frame::Commit(commit)) => commit.header(),
+        Ok(Frame::Account(account)) => account.header(),
+        Ok(Frame::Identity(identity)) => identity.header(),
+        // Info and error frames have no place in the sequence
+        Ok(Frame::Other(_)) => return,
+        Err(e) => {
+            eprintln!("Skipping a malformed firehose message: {e}");
+            return;
+        }
+    };
+    // Each commit is claimed by `receive_frame`, so the lanes aren't told of it
+    lanes.offer(&header, None, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::firehose::{encode_commit, Action, Operation};
+    use crate::json::Value;
+    use crate::store::Mapping;
+    use crate::transport::StdTransport;
+    use crate::upstream::UpstreamConfig;
+    use crate::websocket::accept_key;
+    use atproto::did;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn firehose_events_are_consumed_from_the_cursor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = format!("http://{}", listener.local_addr().unwrap());
+        let alice = did!("did:plc:alice");
+        let op = Operation {
+            action: Action::Create,
+            path: "app.bsky.feed.post/1".to_string(),
+        };
+        let record = Value::object([("text", Value::from("hi"))]);
+        let message = encode_commit(8, &alice, &[(op, Some(record))]);
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket.try_clone().unwrap());
+            let mut request = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                request.push(std::mem::take(&mut line));
+            }
+            let key = request
+                .iter()
+                .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "));
+            let accept = accept_key(key.unwrap().trim());
+            let mut socket = socket;
+            write!(
+                socket,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+            )
+            .unwrap();
+            let mut frame = vec![0x82, 126];
+            frame.extend((message.len() as u16).to_be_bytes());
+            frame.extend(&message);
+            socket.write_all(&frame).unwrap();
+            request.remove(0)
+        });
+        let bridge = Bridge::new()
+            .with_transport(Arc::new(StdTransport::default()))
+            .with_upstreams(UpstreamConfig {
+                relays: vec![relay],
+                ..UpstreamConfig::default()
+            });
+        bridge.firehose.processed(7);
+        bridge
+            .identities
+            .insert(Mapping::new(alice, "https://b.example/alice"));
+        let bridge = Arc::new(bridge);
+        let shutdown = Shutdown::new();
+        let consumer = spawn(bridge.clone(), LaneConfig::default(), shutdown.clone());
+        let requested = server.join().unwrap();
+        assert!(
+            requested.contains("subscribeRepos?cursor=7 "),
+            "{requested}"
+        );
+        while bridge.firehose.position() < 8 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(bridge.seen_activities.len(), 1);
+        shutdown.request();
+        consumer.join().unwrap();
+    }
+}
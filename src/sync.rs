@@ -0,0 +1,401 @@
+//! The `com.atproto.sync` endpoints, for relays to crawl the repos the bridge hosts
+//!
+//! This is the part of a PDS's surface relays need to treat the bridge as one:
+//!
+//! - `getRepo`: a repo as a CAR. It's always the whole repo, `since` is ignored
+//! - `getLatestCommit`: a repo's latest commit CID and revision
+//! - `listRepos`: every hosted repo's head, paginated by DID
+//! - `subscribeRepos`: a WebSocket stream of `#commit` events, from the bridge's
+//!   [backlog](crate::repo::BACKLOG_LEN) when resuming from a cursor
+//!
+//! Repos of paused or suspended accounts are listed as inactive and not served. Anything
+//! else is passed on to the handler these endpoints wrap
+
+use crate::bridge::Bridge;
+use crate::car;
+use crate::http::{Handler, Method, Request, Response};
+use crate::json::Value;
+use crate::normalize;
+use crate::repo::{Event, Replay};
+use crate::store::MappingStatus;
+use crate::websocket::{self, WebSocket, GOING_AWAY};
+use atproto::DID::Did;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const GET_REPO: &str = "com.atproto.sync.getRepo";
+pub const GET_LATEST_COMMIT: &str = "com.atproto.sync.getLatestCommit";
+pub const LIST_REPOS: &str = "com.atproto.sync.listRepos";
+pub const SUBSCRIBE_REPOS: &str = "com.atproto.sync.subscribeRepos";
+pub const CAR_CONTENT_TYPE: &str = "application/vnd.ipld.car";
+
+const DEFAULT_LIST_LIMIT: usize = 500;
+const MAX_LIST_LIMIT: usize = 1000;
+/// How long a subscription waits for events before checking on its client
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The sync endpoints, in front of `inner`
+pub struct SyncEndpoints<H> {
+    bridge: Arc<Bridge>,
+    inner: H,
+}
+
+impl<H: Handler> SyncEndpoints<H> {
+    pub fn new(bridge: Arc<Bridge>, inner: H) -> SyncEndpoints<H> {
+        SyncEndpoints { bridge, inner }
+    }
+
+    /// Why `did`'s repo isn't active, if it isn't
+    fn inactive(&self, did: &Did) -> Option<&'static str> {
+        if self.bridge.sanctions.is_suspended(did) {
+            return Some("suspended");
+        }
+        match self.bridge.identities.get(did).map(|m| m.status) {
+            Some(MappingStatus::Active) => None,
+            Some(MappingStatus::Paused) => Some("deactivated"),
+            Some(MappingStatus::Suspended) => Some("suspended"),
+            Some(MappingStatus::Passive) | None => Some("deleted"),
+        }
+    }
+
+    /// The repo a request is for, if the bridge serves it
+    fn requested_repo(&self, request: &Request) -> Result<Did, Response> {
+        let did = request
+            .query_param("did")
+            .ok_or_else(|| Response::xrpc_error(400, "InvalidRequest", "Missing did"))?;
+        let did = normalize::parse_did(did)
+            .map_err(|e| Response::xrpc_error(400, "InvalidRequest", e.to_string()))?;
+        if self.bridge.repos.head(&did).is_none() {
+            return Err(Response::xrpc_error(
+                404,
+                "RepoNotFound",
+                format!("No repo for {did}"),
+            ));
+        }
+        match self.inactive(&did) {
+            Some("deactivated") => Err(Response::xrpc_error(
+                400,
+                "RepoDeactivated",
+                "Repo is deactivated",
+            )),
+            Some("suspended") => Err(Response::xrpc_error(
+                400,
+                "RepoTakendown",
+                "Repo is suspended",
+            )),
+            Some(_) => Err(Response::xrpc_error(
+                404,
+                "RepoNotFound",
+                format!("No repo for {did}"),
+            )),
+            None => Ok(did),
+        }
+    }
+
+    fn get_repo(&self, request: &Request) -> Result<Response, Response> {
+        let did = self.requested_repo(request)?;
+        let car = self.bridge.repos.export(&did).unwrap_or_default();
+        Ok(Response::new(200)
+            .with_header("content-type", CAR_CONTENT_TYPE)
+            .with_body(car))
+    }
+
+    fn get_latest_commit(&self, request: &Request) -> Result<Response, Response> {
+        let did = self.requested_repo(request)?;
+        let head = self.bridge.repos.head(&did).ok_or_else(|| {
+            Response::xrpc_error(404, "RepoNotFound", format!("No repo for {did}"))
+        })?;
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("cid", Value::from(head.cid.to_string())),
+                ("rev", Value::from(head.rev)),
+            ]),
+        ))
+    }
+
+    fn list_repos(&self, request: &Request) -> Result<Response, Response> {
+        let limit = match request.query_param("limit") {
+            Some(limit) => limit
+                .parse::<usize>()
+                .ok()
+                .filter(|limit| (1..=MAX_LIST_LIMIT).contains(limit))
+                .ok_or_else(|| Response::xrpc_error(400, "InvalidRequest", "Invalid limit"))?,
+            None => DEFAULT_LIST_LIMIT,
+        };
+        let after = match request.query_param("cursor") {
+            Some(cursor) => Some(
+                normalize::parse_did(cursor)
+                    .map_err(|_| Response::xrpc_error(400, "InvalidRequest", "Invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let heads = self.bridge.repos.heads(after.as_ref(), limit);
+        let cursor = (heads.len() == limit)
+            .then(|| heads.last().map(|head| head.did.as_str().to_string()))
+            .flatten();
+        let repos = heads
+            .into_iter()
+            .map(|head| {
+                let status = self.inactive(&head.did);
+                Value::object([
+                    ("did", Value::from(head.did.as_str())),
+                    ("head", Value::from(head.cid.to_string())),
+                    ("rev", Value::from(head.rev)),
+                    ("active", Value::from(status.is_none())),
+                    ("status", Value::from(status)),
+                ])
+            })
+            .collect();
+        Ok(Response::json(
+            200,
+            &Value::object([
+                ("cursor", Value::from(cursor)),
+                ("repos", Value::Array(repos)),
+            ]),
+        ))
+    }
+
+    fn subscribe_repos(&self, request: &Request) -> Result<Response, Response> {
+        let cursor = match request.query_param("cursor") {
+            Some(cursor) => Some(
+                cursor
+                    .parse::<i64>()
+                    .map_err(|_| Response::xrpc_error(400, "InvalidRequest", "Invalid cursor"))?,
+            ),
+            None => None,
+        };
+        let bridge = self.bridge.clone();
+        Ok(websocket::accept(request, move |mut socket, shutdown| {
+            let mut seq = match cursor {
+                Some(cursor) => match bridge.repos.replay(cursor) {
+                    Replay::Future => {
+                        let message = "Cursor is ahead of the stream";
+                        socket.send(&frame(
+                            -1,
+                            None,
+                            [("error", "FutureCursor"), ("message", message)],
+                        ))?;
+                        return socket.close(GOING_AWAY);
+                    }
+                    Replay::Outdated(events) => {
+                        let message = "Cursor is older than the backlog, some events were missed";
+                        let info = frame(
+                            1,
+                            Some("#info"),
+                            [("name", "OutdatedCursor"), ("message", message)],
+                        );
+                        socket.send(&info)?;
+                        send(&mut socket, &events, cursor)?
+                    }
+                    Replay::Events(events) => send(&mut socket, &events, cursor)?,
+                },
+                None => bridge.repos.seq(),
+            };
+            while !shutdown.is_requested() {
+                let events = bridge.repos.wait(seq, POLL_INTERVAL);
+                seq = send(&mut socket, &events, seq)?;
+                if !socket.poll()? {
+                    return Ok(());
+                }
+            }
+            socket.close(GOING_AWAY)
+        }))
+    }
+}
+
+/// Send `events`, returning the sequence number of the last, or `seq` if there were none
+fn send(socket: &mut WebSocket, events: &[Event], seq: i64) -> io::Result<i64> {
+    for event in events {
+        socket.send(&event.frame)?;
+    }
+    Ok(events.last().map_or(seq, |event| event.seq))
+}
+
+/// A frame with header `op` and `t`, and a body of strings
+fn frame<const N: usize>(op: i64, kind: Option<&str>, body: [(&str, &str); N]) -> Vec<u8> {
+    let mut header = vec![("op", Value::from(op))];
+    header.extend(kind.map(|kind| ("t", Value::from(kind))));
+    let mut frame = car::encode_dag_cbor(&Value::object(header));
+    let body = body.map(|(key, value)| (key, Value::from(value)));
+    frame.extend(car::encode_dag_cbor(&Value::object(body)));
+    frame
+}
+
+impl<H: Handler> Handler for SyncEndpoints<H> {
+    fn handle(&self, request: &Request) -> Response {
+        let result = match (request.method, request.segments().as_slice()) {
+            (Method::Get, ["xrpc", GET_REPO]) => self.get_repo(request),
+            (Method::Get, ["xrpc", GET_LATEST_COMMIT]) => self.get_latest_commit(request),
+            (Method::Get, ["xrpc", LIST_REPOS]) => self.list_repos(request),
+            (Method::Get, ["xrpc", SUBSCRIBE_REPOS]) => self.subscribe_repos(request),
+            _ => return self.inner.handle(request),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::keys::tests::FakeGenerator;
+    use crate::keys::{KeyOwner, KeyPurpose};
+    use crate::repo::tests::HashSigner;
+    use crate::repo::{Head, Write};
+    use crate::store::Mapping;
+    use atproto::did;
+    #[cfg(feature = "net")]
+    use {
+        crate::cbor,
+        crate::http,
+        crate::shutdown::Shutdown,
+        std::io::{Read, Write as _},
+        std::net::{TcpListener, TcpStream},
+        std::thread,
+    };
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:plc:bob");
+
+    fn bridge() -> Arc<Bridge> {
+        let bridge = Bridge::new().with_repo_signer(Arc::new(HashSigner));
+        let generator = FakeGenerator::default();
+        for (did, name) in [(ALICE, "alice"), (BOB, "bob")] {
+            let actor = format!("https://remote.example/users/{name}");
+            bridge.identities.insert(Mapping::new(did.clone(), actor));
+            let owner = KeyOwner::Account(did);
+            bridge
+                .keys
+                .ensure(&owner, KeyPurpose::RepoSigning, &generator)
+                .unwrap();
+        }
+        Arc::new(bridge)
+    }
+
+    fn post(bridge: &Bridge, did: &Did, rkey: &str) -> Head {
+        let record = Value::object([("text", Value::from(rkey))]);
+        let path = format!("app.bsky.feed.post/{rkey}");
+        bridge
+            .commit(did, &[Write::Create { path, record }])
+            .unwrap()
+    }
+
+    fn endpoints(bridge: &Arc<Bridge>) -> SyncEndpoints<impl Handler> {
+        SyncEndpoints::new(bridge.clone(), |_: &Request| Response::new(418))
+    }
+
+    fn get(endpoints: &impl Handler, target: &str) -> Response {
+        endpoints.handle(&Request::new(Method::Get, target))
+    }
+
+    fn get_json(endpoints: &impl Handler, target: &str) -> Value {
+        let response = get(endpoints, target);
+        json::parse(std::str::from_utf8(&response.body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serves_repos_to_crawlers() {
+        let bridge = bridge();
+        post(&bridge, &ALICE, "1");
+        let head = post(&bridge, &ALICE, "2");
+        post(&bridge, &BOB, "1");
+        let endpoints = endpoints(&bridge);
+
+        let repo = get(
+            &endpoints,
+            "/xrpc/com.atproto.sync.getRepo?did=did:plc:alice",
+        );
+        assert_eq!(repo.header("content-type"), Some(CAR_CONTENT_TYPE));
+        assert!(car::read_car(&repo.body).unwrap().find(&head.cid).is_some());
+        let latest = get_json(
+            &endpoints,
+            "/xrpc/com.atproto.sync.getLatestCommit?did=did:plc:alice",
+        );
+        assert_eq!(latest.get("cid"), Some(&Value::from(head.cid.to_string())));
+        let unknown = get(
+            &endpoints,
+            "/xrpc/com.atproto.sync.getRepo?did=did:plc:carol",
+        );
+        assert_eq!(unknown.status, 404);
+        assert_eq!(get(&endpoints, "/xrpc/other").status, 418);
+
+        bridge
+            .identities
+            .set_status(&BOB, MappingStatus::Paused)
+            .unwrap();
+        let paused = get(&endpoints, "/xrpc/com.atproto.sync.getRepo?did=did:plc:bob");
+        assert_eq!(paused.status, 400);
+        let page = get_json(&endpoints, "/xrpc/com.atproto.sync.listRepos?limit=1");
+        assert_eq!(page.get("cursor"), Some(&Value::from("did:plc:alice")));
+        let page = get_json(
+            &endpoints,
+            "/xrpc/com.atproto.sync.listRepos?cursor=did:plc:alice",
+        );
+        let repos = page.get("repos").and_then(Value::as_array).unwrap();
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].get("active"), Some(&Value::from(false)));
+        assert_eq!(repos[0].get("status"), Some(&Value::from("deactivated")));
+        assert_eq!(page.get("cursor"), Some(&Value::Null));
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn subscribers_replay_then_follow_commits() {
+        let bridge = bridge();
+        post(&bridge, &ALICE, "1");
+        post(&bridge, &ALICE, "2");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let server = {
+            let handler = Arc::new(endpoints(&bridge));
+            let shutdown = shutdown.clone();
+            thread::spawn(move || http::serve(listener, handler, shutdown))
+        };
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(
+            client,
+            "GET /xrpc/com.atproto.sync.subscribeRepos?cursor=1 HTTP/1.1\r\n\
+             upgrade: websocket\r\nconnection: upgrade\r\n\
+             sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        )
+        .unwrap();
+        let mut received = Vec::new();
+        let mut byte = [0];
+        while !received.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            received.push(byte[0]);
+        }
+        assert!(received.starts_with(b"HTTP/1.1 101"));
+
+        let mut next_seq = || {
+            let mut header = [0; 2];
+            client.read_exact(&mut header).unwrap();
+            let len = match header[1] {
+                126 => {
+                    let mut len = [0; 2];
+                    client.read_exact(&mut len).unwrap();
+                    u16::from_be_bytes(len) as usize
+                }
+                len => len as usize,
+            };
+            let mut frame = vec![0; len];
+            client.read_exact(&mut frame).unwrap();
+            let (_, body) = cbor::decode(&frame).unwrap();
+            cbor::decode(body)
+                .unwrap()
+                .0
+                .get("seq")
+                .and_then(|s| s.as_i64())
+        };
+        // What came after the cursor from the backlog, then commits as they happen
+        assert_eq!(next_seq(), Some(2));
+        post(&bridge, &ALICE, "3");
+        assert_eq!(next_seq(), Some(3));
+
+        shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+}
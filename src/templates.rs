@@ -0,0 +1,217 @@
+//! Wording for content the bridge can't carry across as it is
+//!
+//! Some things only make it to the other network in a degraded form, explained in words:
+//!
+//! | Message       | When                                               | Fields                             |
+//! |---------------|----------------------------------------------------|------------------------------------|
+//! | `poll`        | A fediverse poll, bridged as a text post           | `{question}`, `{options}`, `{url}` |
+//! | `truncated`   | Ends a post cut short to fit Bluesky's limit       | `{url}`                            |
+//! | `dmBounce`    | Answers a direct message which can't be bridged    | none                               |
+//! | `replyBounce` | Answers a reply a Bluesky thread's gate keeps out  | none                               |
+//!
+//! Each has built-in English wording, which operators can replace, in as many languages as
+//! they like, with a JSON file named by `FEDIBRIDGE_TEMPLATES_FILE`, of templates by message
+//! and then language tag, `default` being for any other language:
+//!
+//! ```json
+//! {"dmBounce": {"default": "Sorry, I can't read DMs", "de": "Ich kann keine DMs lesen"}}
+//! ```
+//!
+//! Messages are written in the first language of the person reading them which has a
+//! template, a tag such as `pt-BR` falling back to `pt`, or else the default.
+//! `FEDIBRIDGE_DM_BOUNCE_MESSAGE` is the default `dmBounce`, if the file doesn't set one
+
+use crate::alerts;
+use crate::article::{trim, MAX_POST_LENGTH};
+use crate::dm::DEFAULT_BOUNCE_MESSAGE;
+use crate::json::{self, Value};
+use crate::language;
+use crate::threadgate::DEFAULT_REPLY_BOUNCE_MESSAGE;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// The language key for templates used when no other language has one
+pub const DEFAULT_LANGUAGE: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Something the bridge says about degraded content
+pub enum Message {
+    Poll,
+    Truncated,
+    DmBounce,
+    ReplyBounce,
+}
+
+impl Message {
+    pub const ALL: [Message; 4] = [
+        Message::Poll,
+        Message::Truncated,
+        Message::DmBounce,
+        Message::ReplyBounce,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Message::Poll => "poll",
+            Message::Truncated => "truncated",
+            Message::DmBounce => "dmBounce",
+            Message::ReplyBounce => "replyBounce",
+        }
+    }
+
+    pub fn parse(message: &str) -> Option<Message> {
+        Message::ALL.into_iter().find(|m| m.as_str() == message)
+    }
+
+    /// The built-in English wording
+    pub fn default_template(&self) -> &'static str {
+        match self {
+            Message::Poll => "{question}\n\n{options}\n\nVote on the original post: {url}",
+            Message::Truncated => "Read the whole post: {url}",
+            Message::DmBounce => DEFAULT_BOUNCE_MESSAGE,
+            Message::ReplyBounce => DEFAULT_REPLY_BOUNCE_MESSAGE,
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+/// Errors reading a templates file
+pub enum TemplateError {
+    #[error("Templates should be a JSON object of messages")]
+    Malformed,
+    #[error("Unknown message {0:?}")]
+    UnknownMessage(String),
+    #[error("The {message} template for {language:?} should be a string")]
+    NotText { message: String, language: String },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The wording of each message, as far as operators have changed it
+pub struct Templates {
+    /// By message, and then normalized language tag or [`DEFAULT_LANGUAGE`]
+    templates: BTreeMap<(Message, String), String>,
+}
+
+impl Templates {
+    pub fn new() -> Templates {
+        Templates::default()
+    }
+
+    /// These templates with `message` worded as `template` in `language`, or by default
+    /// without one
+    pub fn with(mut self, message: Message, language: Option<&str>, template: &str) -> Templates {
+        let language = language.and_then(language::normalize);
+        let language = language.unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+        self.templates
+            .insert((message, language), template.to_string());
+        self
+    }
+
+    /// These templates with those in `contents`, a templates file, in place of any they
+    /// both have
+    pub fn merge_json(self, contents: &str) -> Result<Templates, TemplateError> {
+        let Ok(Value::Object(messages)) = json::parse(contents) else {
+            return Err(TemplateError::Malformed);
+        };
+        let mut templates = self;
+        for (name, languages) in &messages {
+            let message = Message::parse(name)
+                .ok_or_else(|| TemplateError::UnknownMessage(name.to_string()))?;
+            let Value::Object(languages) = languages else {
+                return Err(TemplateError::Malformed);
+            };
+            for (language, template) in languages {
+                let template = template.as_str().ok_or_else(|| TemplateError::NotText {
+                    message: name.to_string(),
+                    language: language.to_string(),
+                })?;
+                let language = Some(language.as_str()).filter(|l| *l != DEFAULT_LANGUAGE);
+                templates = templates.with(message, language, template);
+            }
+        }
+        Ok(templates)
+    }
+
+    /// The template for `message` in the first of `languages` with one, or the default
+    pub fn get(&self, message: Message, languages: &[String]) -> &str {
+        let find = |language: &str| self.templates.get(&(message, language.to_string()));
+        let chosen = languages
+            .iter()
+            .filter_map(|l| language::normalize(l))
+            .find_map(|tag| {
+                let primary = tag.split('-').next().unwrap_or_default().to_string();
+                find(&tag).or_else(|| find(&primary))
+            });
+        chosen
+            .or_else(|| find(DEFAULT_LANGUAGE))
+            .map_or(message.default_template(), String::as_str)
+    }
+
+    /// `message` in the first of `languages` it's worded in, with `fields` filled in
+    pub fn render(
+        &self,
+        message: Message,
+        languages: &[String],
+        fields: &[(&str, String)],
+    ) -> String {
+        alerts::render(self.get(message, languages), fields)
+    }
+
+    /// `text` as it fits a Bluesky post: whole if it can be, or else cut short and ended with
+    /// the [truncated](Message::Truncated) notice linking to `url`
+    pub fn fit(&self, text: &str, languages: &[String], url: &str) -> String {
+        if text.chars().count() <= MAX_POST_LENGTH {
+            return text.to_string();
+        }
+        let notice = self.render(Message::Truncated, languages, &[("url", url.to_string())]);
+        let room = MAX_POST_LENGTH.saturating_sub(notice.chars().count() + 2);
+        match room {
+            0 => trim(text, MAX_POST_LENGTH),
+            room => format!("{}\n\n{notice}", trim(text, room)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_worded_in_the_readers_language() {
+        let templates = Templates::new()
+            .with(Message::DmBounce, None, "No DMs here")
+            .merge_json(
+                r#"{"dmBounce": {"de": "Keine DMs", "pt": "Sem DMs"},
+                    "truncated": {"default": "More at {url}"}}"#,
+            )
+            .unwrap();
+        let langs = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            templates.get(Message::DmBounce, &langs(&["de"])),
+            "Keine DMs"
+        );
+        assert_eq!(
+            templates.get(Message::DmBounce, &langs(&["fr", "pt_BR"])),
+            "Sem DMs"
+        );
+        assert_eq!(
+            templates.get(Message::DmBounce, &langs(&["fr"])),
+            "No DMs here"
+        );
+        assert_eq!(
+            templates.get(Message::Poll, &[]),
+            Message::Poll.default_template()
+        );
+
+        let long = "word ".repeat(100);
+        let fitted = templates.fit(&long, &[], "https://a.example/1");
+        assert!(fitted.chars().count() <= MAX_POST_LENGTH);
+        assert!(fitted.ends_with("…\n\nMore at https://a.example/1"));
+        assert_eq!(templates.fit("short", &[], "https://a.example/1"), "short");
+
+        assert_eq!(
+            Templates::new().merge_json(r#"{"bounce": {}}"#),
+            Err(TemplateError::UnknownMessage("bounce".into()))
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Hosting several bridges from one process
+//!
+//! A hosting provider bridging several communities can run a bridge for each from one process,
+//! by listing their domains in `FEDIBRIDGE_TENANTS`. Each tenant is a bridge of its own: its
+//! domain is its `FEDIBRIDGE_HOSTNAME`, and it has its own policies, keys and accounts. They're
+//! configured by the usual variables, which a tenant can set over for itself by putting
+//! `TENANT_<DOMAIN>_` after their `FEDIBRIDGE_`, with the domain in capitals and its dots and
+//! dashes as underscores. `FEDIBRIDGE_TENANT_BRIDGE_EXAMPLE_ADMIN_TOKEN` is the admin token of
+//! `bridge.example`, say.
+//!
+//! Each tenant's state is kept in `tenants/<domain>` under the state directory, unless it sets a
+//! state directory of its own, and no two may share one, or keep theirs in another's. Tenants
+//! listening on the same address are told apart by the `Host` of each request, which is either
+//! a tenant's domain or a name under it, such as a bridged account's handle ([`TenantRouter`]).
+//!
+//! For commands run against one tenant, such as `doctor` or `approve`, `FEDIBRIDGE_TENANT` names
+//! the tenant, and its configuration is used as a single bridge's would be
+
+use crate::config::{Config, ConfigError};
+use crate::http::{Handler, Request, Response};
+use crate::normalize;
+use crate::secrets::Secrets;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The variable listing the tenants' domains
+pub const TENANTS_VAR: &str = "FEDIBRIDGE_TENANTS";
+/// The variable naming the tenant a command is run against
+pub const TENANT_VAR: &str = "FEDIBRIDGE_TENANT";
+/// Where tenants' state is kept under the state directory, by domain
+pub const TENANTS_DIR: &str = "tenants";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TenantError {
+    #[error("{domain}: {source}")]
+    Config {
+        domain: String,
+        #[source]
+        source: ConfigError,
+    },
+    #[error("{0} isn't a domain a tenant can have")]
+    Domain(String),
+    #[error("{0} is listed as a tenant more than once, or as another spelled differently")]
+    Duplicate(String),
+    #[error("{0} and {1} would keep their state in the same place")]
+    SharedState(String, String),
+    #[error("{0} isn't one of FEDIBRIDGE_TENANTS")]
+    Unknown(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// One of the bridges hosted
+pub struct Tenant {
+    pub domain: String,
+    pub config: Config,
+}
+
+/// The name `var` is set by for the tenant `domain` alone
+pub fn tenant_var(domain: &str, var: &str) -> String {
+    let tenant: String = domain
+        .chars()
+        .map(|c| match c {
+            '.' | '-' => '_',
+            c => c.to_ascii_uppercase(),
+        })
+        .collect();
+    match var.strip_prefix("FEDIBRIDGE_") {
+        Some(rest) => format!("FEDIBRIDGE_TENANT_{tenant}_{rest}"),
+        None => var.to_string(),
+    }
+}
+
+/// The tenants listed, configured using `lookup` to read variables and `secrets` to read those
+/// set as references to them. Without any, the bridge is a single one
+pub fn from_vars_with_secrets(
+    lookup: impl Fn(&str) -> Option<String>,
+    secrets: &Secrets,
+) -> Result<Vec<Tenant>, TenantError> {
+    let Some(listed) = lookup(TENANTS_VAR) else {
+        return Ok(Vec::new());
+    };
+    let shared = lookup("FEDIBRIDGE_STATE_DIR").map_or(Config::default().state_dir, PathBuf::from);
+    let mut tenants: Vec<Tenant> = Vec::new();
+    let mut vars: Vec<String> = Vec::new();
+    for listed in listed.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let domain = normalize::handle(listed);
+        let labels = domain.split('.').collect::<Vec<_>>();
+        if labels.len() < 2 || labels.iter().any(|label| label.is_empty()) {
+            return Err(TenantError::Domain(listed.to_string()));
+        }
+        // Distinct domains can still be set by the same variables
+        let prefix = tenant_var(&domain, "FEDIBRIDGE_");
+        if vars.contains(&prefix) {
+            return Err(TenantError::Duplicate(listed.to_string()));
+        }
+        vars.push(prefix);
+        let tenant = |var: &str| lookup(&tenant_var(&domain, var)).or_else(|| lookup(var));
+        let mut config = Config::from_vars_with_secrets(tenant, secrets).map_err(|source| {
+            TenantError::Config {
+                domain: domain.clone(),
+                source,
+            }
+        })?;
+        if lookup(&tenant_var(&domain, "FEDIBRIDGE_STATE_DIR")).is_none() {
+            config.state_dir = shared.join(TENANTS_DIR).join(&domain);
+        }
+        config.hostname = Some(domain.clone());
+        tenants.push(Tenant { domain, config });
+    }
+    for (i, tenant) in tenants.iter().enumerate() {
+        for other in &tenants[i + 1..] {
+            let (a, b) = (&tenant.config.state_dir, &other.config.state_dir);
+            if a.starts_with(b) || b.starts_with(a) {
+                let domains = (tenant.domain.clone(), other.domain.clone());
+                return Err(TenantError::SharedState(domains.0, domains.1));
+            }
+        }
+    }
+    Ok(tenants)
+}
+
+/// The tenant named by `FEDIBRIDGE_TENANT`, if any is
+pub fn selected(
+    tenants: &[Tenant],
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<Option<&Tenant>, TenantError> {
+    let Some(name) = lookup(TENANT_VAR).filter(|name| !name.is_empty()) else {
+        return Ok(None);
+    };
+    let domain = normalize::handle(&name);
+    let tenant = tenants.iter().find(|tenant| tenant.domain == domain);
+    tenant.map(Some).ok_or(TenantError::Unknown(name))
+}
+
+#[derive(Default)]
+/// Requests sent to the tenant whose domain their `Host` is, or is under
+pub struct TenantRouter {
+    tenants: Vec<(String, Arc<dyn Handler>)>,
+}
+
+impl TenantRouter {
+    pub fn new() -> TenantRouter {
+        TenantRouter::default()
+    }
+
+    pub fn with_tenant(mut self, domain: &str, handler: Arc<dyn Handler>) -> TenantRouter {
+        self.tenants.push((domain.to_string(), handler));
+        // A tenant under another's domain is matched before it
+        self.tenants
+            .sort_by_key(|(domain, _)| usize::MAX - domain.len());
+        self
+    }
+
+    /// The tenant `host` is served by
+    pub fn tenant(&self, host: &str) -> Option<&str> {
+        self.route(host).map(|(domain, _)| domain.as_str())
+    }
+
+    fn route(&self, host: &str) -> Option<&(String, Arc<dyn Handler>)> {
+        // Without its port, which isn't part of a bracketed IPv6 address's last segment
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        let host = normalize::handle(host);
+        self.tenants.iter().find(|(domain, _)| {
+            host == *domain
+                || host
+                    .strip_suffix(domain.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+impl Handler for TenantRouter {
+    fn handle(&self, request: &Request) -> Response {
+        let host = request.header("host").unwrap_or_default();
+        match self.route(host) {
+            Some((_, handler)) => handler.handle(request),
+            None => Response::error(421, format!("No bridge is hosted at {host}")),
+        }
+    }
+
+    fn body_limit(&self, request: &Request) -> usize {
+        let host = request.header("host").unwrap_or_default();
+        match self.route(host) {
+            Some((_, handler)) => handler.body_limit(request),
+            // Refused whatever it sends
+            None => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tenants_are_configured_apart_and_routed_by_host() {
+        let vars: HashMap<&str, &str> = HashMap::from([
+            (TENANTS_VAR, "Bridge.Example, other-bridge.example"),
+            ("FEDIBRIDGE_STATE_DIR", "/var/lib/fedibridge"),
+            ("FEDIBRIDGE_REQUIRE_APPROVAL", "true"),
+            (
+                "FEDIBRIDGE_TENANT_OTHER_BRIDGE_EXAMPLE_REQUIRE_APPROVAL",
+                "false",
+            ),
+            ("FEDIBRIDGE_TENANT_BRIDGE_EXAMPLE_ADMIN_TOKEN", "bridge"),
+        ]);
+        let lookup = |var: &str| vars.get(var).map(|value| value.to_string());
+        let tenants = from_vars_with_secrets(lookup, &Secrets::default()).unwrap();
+        assert_eq!(tenants.len(), 2);
+        let (bridge, other) = (&tenants[0].config, &tenants[1].config);
+        assert_eq!(bridge.hostname.as_deref(), Some("bridge.example"));
+        assert_eq!(
+            bridge.state_dir,
+            PathBuf::from("/var/lib/fedibridge/tenants/bridge.example")
+        );
+        assert!(bridge.require_approval);
+        assert!(!other.require_approval);
+        assert_eq!(
+            bridge.admin.as_ref().map(|a| a.token.as_str()),
+            Some("bridge")
+        );
+        assert!(other.admin.is_none());
+
+        let mut shared = vars.clone();
+        shared.insert(
+            "FEDIBRIDGE_TENANT_BRIDGE_EXAMPLE_STATE_DIR",
+            "/var/lib/fedibridge",
+        );
+        let lookup = |var: &str| shared.get(var).map(|value| value.to_string());
+        assert!(matches!(
+            from_vars_with_secrets(lookup, &Secrets::default()),
+            Err(TenantError::SharedState(..))
+        ));
+        let single = |var: &str| (var == TENANT_VAR).then(|| "other-bridge.example".to_string());
+        let tenant = selected(&tenants, single).unwrap();
+        assert_eq!(tenant.map(|t| t.config.require_approval), Some(false));
+        assert_eq!(selected(&tenants, |_| None), Ok(None));
+
+        let answer = |status: u16| -> Arc<dyn Handler> {
+            Arc::new(move |_: &Request| Response::new(status))
+        };
+        let router = TenantRouter::new()
+            .with_tenant("bridge.example", answer(200))
+            .with_tenant("ap.bridge.example", answer(201));
+        let status = |host: &str| {
+            let request = Request::new(Method::Get, "/").with_header("Host", host);
+            router.handle(&request).status
+        };
+        assert_eq!(status("bridge.example:8080"), 200);
+        assert_eq!(status("alice.Bridge.Example"), 200);
+        assert_eq!(status("ap.bridge.example"), 201);
+        assert_eq!(status("notbridge.example"), 421);
+    }
+}
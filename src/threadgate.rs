@@ -0,0 +1,505 @@
+//! Who may reply to a post, as its author said on their own network
+//!
+//! A Bluesky post's author can limit who replies to their thread with a threadgate: only
+//! those they mention, follow or are followed by, or who are on a list, or nobody. The
+//! fediverse has no such thing, so a reply to a bridged post from someone the gate doesn't
+//! let in would be bridged to a thread its author closed. Fediverse replies are checked
+//! against the gate of the thread they're in ([`reply_received`]), as the repliers' bridged
+//! accounts, and refused (not bridged) unless it lets them in. Unless [bouncing is turned
+//! off](ReplyGatePolicy::bounce), the replier is told why, in a direct reply from the post's
+//! author worded by the `replyBounce` [template](crate::templates), at most once every
+//! [`BOUNCE_COOLDOWN`](crate::dm::BOUNCE_COOLDOWN).
+//!
+//! The other way, fediverse posts can say who may reply as GoToSocial's
+//! `interactionPolicy.canReply` does, and [`threadgate`] is the gate to write alongside the
+//! post bridged from one: followers, accounts followed and those mentioned, as what Bluesky
+//! calls them. A policy letting in anyone, or who approves replies, has no gate
+
+use crate::appview::{AppView, AppViewError};
+use crate::audit::Cause;
+use crate::bridge::Bridge;
+use crate::delivery::{self, Delivery};
+use crate::dm::{self, PUBLIC};
+use crate::html;
+use crate::http::percent_encode;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::language;
+use crate::preview::bluesky_post;
+use crate::richtext::{Facet, Feature};
+use crate::store::MappingStatus;
+use crate::templates::Message;
+use crate::time::unique_millis;
+use crate::transport::{OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::io;
+use std::time::Instant;
+use thiserror::Error;
+
+pub const THREADGATE_COLLECTION: &str = "app.bsky.feed.threadgate";
+pub const GET_RELATIONSHIPS: &str = "app.bsky.graph.getRelationships";
+pub const GET_LIST: &str = "app.bsky.graph.getList";
+/// How many pages of a list are read looking for a replier
+const MAX_LIST_PAGES: usize = 10;
+
+pub const DEFAULT_REPLY_BOUNCE_MESSAGE: &str = "The author of this post only lets some people \
+     reply to it on Bluesky, so your reply won't be seen there.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How fediverse replies to gated threads are handled
+pub struct ReplyGatePolicy {
+    /// Whether replies the gate doesn't let in are refused, rather than bridged anyway
+    pub enforce: bool,
+    /// Whether refused repliers are told, rather than their replies dropped without a word
+    pub bounce: bool,
+}
+
+impl Default for ReplyGatePolicy {
+    fn default() -> Self {
+        ReplyGatePolicy {
+            enforce: true,
+            bounce: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Who a gate lets reply, besides the thread's author
+pub enum Allow {
+    /// Those mentioned in the thread's first post
+    Mentioned,
+    /// Those its author follows
+    Following,
+    /// Those following its author
+    Followers,
+    /// Those on the list with this `at://` URI
+    List(String),
+}
+
+impl Allow {
+    /// The rule's `$type` in a threadgate record
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Allow::Mentioned => "app.bsky.feed.threadgate#mentionRule",
+            Allow::Following => "app.bsky.feed.threadgate#followingRule",
+            Allow::Followers => "app.bsky.feed.threadgate#followerRule",
+            Allow::List(_) => "app.bsky.feed.threadgate#listRule",
+        }
+    }
+
+    fn from_json(rule: &Value) -> Option<Allow> {
+        let kind = rule.get("$type").and_then(Value::as_str)?;
+        [Allow::Mentioned, Allow::Following, Allow::Followers]
+            .into_iter()
+            .find(|allow| allow.kind() == kind)
+            .or_else(|| {
+                let list = rule.get("list").and_then(Value::as_str)?;
+                (kind == Allow::List(String::new()).kind()).then(|| Allow::List(list.to_string()))
+            })
+    }
+
+    fn to_json(&self) -> Value {
+        let mut rule = vec![("$type", Value::from(self.kind()))];
+        if let Allow::List(list) = self {
+            rule.push(("list", Value::from(list.as_str())));
+        }
+        Value::object(rule)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The gate on a Bluesky thread
+pub struct Gate {
+    /// The `at://` URI of the thread's first post
+    pub root: String,
+    pub author: Did,
+    /// Who it lets in. Nobody, if it's empty
+    pub allow: Vec<Allow>,
+    /// Who the first post mentions
+    pub mentioned: Vec<Did>,
+}
+
+#[derive(Debug, Error)]
+pub enum GateError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("The AppView responded to {method} with {status}")]
+    Rejected { method: &'static str, status: u16 },
+    #[error(transparent)]
+    AppView(#[from] AppViewError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What became of a reply
+pub enum Verdict {
+    /// It isn't a reply to a gated Bluesky thread
+    Ungated,
+    /// The gate lets its author in
+    Permitted,
+    /// The gate keeps its author out, so it's not to be bridged
+    Refused {
+        /// Whether they were told
+        bounced: bool,
+    },
+}
+
+/// Call the AppView's `method` with `params`, or `None` for a 400, as for a post deleted
+fn appview(
+    bridge: &Bridge,
+    method: &'static str,
+    params: &str,
+) -> Result<Option<Value>, GateError> {
+    let url = format!(
+        "{}/xrpc/{method}?{params}",
+        bridge.feeds.appview.trim_end_matches('/')
+    );
+    let response = bridge.transport.send(&OutboundRequest::get(url))?;
+    if response.status == 400 {
+        return Ok(None);
+    }
+    if !response.is_success() {
+        let status = response.status;
+        return Err(GateError::Rejected { method, status });
+    }
+    Ok(json::parse(&String::from_utf8_lossy(&response.body)).ok())
+}
+
+/// The gate on the thread the Bluesky post at `uri` is in, if it has one
+pub fn gate(bridge: &Bridge, uri: &str) -> Result<Option<Gate>, GateError> {
+    let appview = AppView::of(bridge);
+    let Some(mut post) = appview.post(uri)? else {
+        return Ok(None);
+    };
+    let root = post.root().filter(|root| *root != uri).map(str::to_string);
+    if let Some(root) = root {
+        let Some(root) = appview.post(&root)? else {
+            return Ok(None);
+        };
+        post = root;
+    }
+    let Some(record) = &post.threadgate else {
+        return Ok(None);
+    };
+    let author = post.author.did.clone();
+    // Without rules, anyone may reply
+    let Some(allow) = record.get("allow").and_then(Value::as_array) else {
+        return Ok(None);
+    };
+    let facets = post.record.get("facets").and_then(Value::as_array);
+    let mentioned = facets
+        .unwrap_or_default()
+        .iter()
+        .filter_map(Facet::from_json)
+        .filter_map(|facet| match facet.feature {
+            Feature::Mention { did } => Some(did),
+            _ => None,
+        });
+    Ok(Some(Gate {
+        root: post.uri.clone(),
+        author,
+        allow: allow.iter().filter_map(Allow::from_json).collect(),
+        mentioned: mentioned.collect(),
+    }))
+}
+
+/// Whether `gate` lets `replier` reply. Anyone not bridged has no account to be let in as
+pub fn permits(bridge: &Bridge, gate: &Gate, replier: Option<&Did>) -> Result<bool, GateError> {
+    let Some(replier) = replier else {
+        return Ok(false);
+    };
+    if *replier == gate.author {
+        return Ok(true);
+    }
+    let mut relationship = None;
+    for allow in &gate.allow {
+        let permitted = match allow {
+            Allow::Mentioned => gate.mentioned.contains(replier),
+            Allow::Following | Allow::Followers => {
+                if relationship.is_none() {
+                    let params = format!(
+                        "actor={}&others={}",
+                        percent_encode(gate.author.as_str()),
+                        percent_encode(replier.as_str())
+                    );
+                    let found = appview(bridge, GET_RELATIONSHIPS, &params)?;
+                    let found =
+                        found.and_then(|r| r.get("relationships")?.as_array()?.first().cloned());
+                    relationship = Some(found.unwrap_or(Value::Null));
+                }
+                let field = match allow {
+                    Allow::Following => "following",
+                    _ => "followedBy",
+                };
+                relationship.as_ref().and_then(|r| r.get(field)).is_some()
+            }
+            Allow::List(list) => on_list(bridge, list, replier)?,
+        };
+        if permitted {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `did` is on the list `list`, as far as the first [`MAX_LIST_PAGES`] pages say
+fn on_list(bridge: &Bridge, list: &str, did: &Did) -> Result<bool, GateError> {
+    let mut cursor: Option<String> = None;
+    for _ in 0..MAX_LIST_PAGES {
+        let mut params = format!("list={}&limit=100", percent_encode(list));
+        if let Some(cursor) = &cursor {
+            params.push_str(&format!("&cursor={}", percent_encode(cursor)));
+        }
+        let Some(page) = appview(bridge, GET_LIST, &params)? else {
+            return Ok(false);
+        };
+        let items = page
+            .get("items")
+            .and_then(Value::as_array)
+            .unwrap_or_default();
+        let member = |item: &Value| {
+            item.get("subject").and_then(|subject| subject.get("did"))
+                == Some(&Value::from(did.as_str()))
+        };
+        if items.iter().any(member) {
+            return Ok(true);
+        }
+        cursor = page
+            .get("cursor")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        if cursor.is_none() || items.is_empty() {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+/// Check a fediverse `Create` replying to a post bridged from Bluesky against its thread's
+/// gate, bouncing it if it's refused
+pub fn reply_received(bridge: &Bridge, activity: &Value) -> Result<Verdict, GateError> {
+    if !bridge.reply_gates.enforce || activity.get("type").and_then(Value::as_str) != Some("Create")
+    {
+        return Ok(Verdict::Ungated);
+    }
+    let (Some(object), Some(sender)) = (
+        activity.get("object"),
+        activity.get("actor").and_then(Value::as_str),
+    ) else {
+        return Ok(Verdict::Ungated);
+    };
+    let parent = object.get("inReplyTo");
+    let parent = parent.and_then(|p| p.as_str().or_else(|| p.get("id")?.as_str()));
+    let Some(published) = parent.and_then(|parent| bridge.published.get(parent)) else {
+        return Ok(Verdict::Ungated);
+    };
+    let Some(uri) = published.origin.as_deref().and_then(bluesky_post) else {
+        return Ok(Verdict::Ungated);
+    };
+    let Some(gate) = gate(bridge, &uri)? else {
+        return Ok(Verdict::Ungated);
+    };
+    let replier = bridge
+        .identities
+        .get_by_actor(sender)
+        .filter(|mapping| mapping.status == MappingStatus::Active);
+    if permits(bridge, &gate, replier.as_ref().map(|mapping| &mapping.did))? {
+        return Ok(Verdict::Permitted);
+    }
+    let author = bridge.identities.get(&gate.author);
+    let inbox = delivery::shared_inbox(&bridge.documents, sender);
+    let (Some(author), Some(inbox), true) = (author, inbox, bridge.reply_gates.bounce) else {
+        return Ok(Verdict::Refused { bounced: false });
+    };
+    if !bridge.bounces.allow(sender, &author.actor, Instant::now()) {
+        return Ok(Verdict::Refused { bounced: false });
+    }
+    let languages = [
+        language::languages_of(object),
+        author.preferences.languages.clone(),
+    ];
+    let message = bridge
+        .templates
+        .render(Message::ReplyBounce, &languages.concat(), &[]);
+    let reply = object.get("id").and_then(Value::as_str);
+    let id = format!("{}/bounces/{}", author.actor, unique_millis());
+    let content = format!("<p>{}</p>", html::escape(&message));
+    let bounce = dm::direct_note(&id, &author.actor, sender, &content, reply);
+    let delivery = Delivery::new(inbox, bounce.to_string());
+    bridge.jobs.push(Job::Deliver(
+        delivery.because(Cause::new("reply bounce", reply)),
+    ))?;
+    Ok(Verdict::Refused { bounced: true })
+}
+
+/// The threadgate for the post at `uri` bridged from the fediverse `object`, with who its
+/// `interactionPolicy` lets reply, if it doesn't let in everyone
+pub fn threadgate(object: &Value, uri: &str, created_at: &str) -> Option<Value> {
+    let can_reply = object.get("interactionPolicy")?.get("canReply")?;
+    let always = match can_reply.get("always") {
+        Some(Value::Array(always)) => always.iter().filter_map(Value::as_str).collect(),
+        Some(Value::String(always)) => vec![always.as_str()],
+        _ => Vec::new(),
+    };
+    let approval = can_reply.get("approvalRequired").and_then(Value::as_array);
+    // Bluesky can't hold replies for approval, so those who'd need it are let in
+    let approval = approval
+        .unwrap_or_default()
+        .iter()
+        .filter_map(Value::as_str);
+    let allowed: Vec<&str> = always.into_iter().chain(approval).collect();
+    if allowed
+        .iter()
+        .any(|address| matches!(*address, PUBLIC | "as:Public" | "Public"))
+    {
+        return None;
+    }
+    let mentioned: Vec<&str> = match object.get("tag") {
+        Some(Value::Array(tags)) => tags
+            .iter()
+            .filter(|tag| tag.get("type").and_then(Value::as_str) == Some("Mention"))
+            .filter_map(|tag| tag.get("href")?.as_str())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut allow = Vec::new();
+    for address in allowed {
+        let rule = if address.ends_with("/followers") {
+            Allow::Followers
+        } else if address.ends_with("/following") {
+            Allow::Following
+        } else if mentioned.contains(&address) {
+            Allow::Mentioned
+        } else {
+            continue;
+        };
+        if !allow.contains(&rule) {
+            allow.push(rule);
+        }
+    }
+    let rules = allow.iter().map(Allow::to_json).collect();
+    Some(Value::object([
+        ("$type", Value::from(THREADGATE_COLLECTION)),
+        ("post", Value::from(uri)),
+        ("allow", Value::Array(rules)),
+        ("createdAt", Value::from(created_at)),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appview::{GET_POST_THREAD, THREAD_VIEW};
+    use crate::published::Published;
+    use crate::store::Mapping;
+    use crate::transport::MockTransport;
+    use atproto::did;
+    use std::sync::Arc;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+    const NOTE: &str = "https://bridge.example/notes/1";
+    const ROOT: &str = "at://did:plc:alice/app.bsky.feed.post/root";
+
+    fn reply(actor: &str) -> Value {
+        json::parse(&format!(
+            r#"{{"type": "Create", "actor": "{actor}",
+                "object": {{"id": "{actor}/notes/9", "type": "Note", "inReplyTo": "{NOTE}"}}}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn replies_outside_a_threadgate_are_refused_and_bounced() {
+        let mock = Arc::new(MockTransport::new());
+        let bridge = Bridge::new().with_transport(mock.clone());
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        let bob = "https://a.example/users/bob";
+        let carol = "https://a.example/users/carol";
+        bridge
+            .identities
+            .insert(Mapping::new(did!("did:plc:carol"), carol));
+        let published = Published {
+            document: Value::object([("id", Value::from(NOTE))]),
+            origin: Some("https://bsky.app/profile/did:plc:alice/post/root".to_string()),
+            deleted: false,
+        };
+        bridge.published.publish(NOTE, published).unwrap();
+        let appview = "https://public.api.bsky.app/xrpc";
+        mock.respond_json(
+            &format!(
+                "{appview}/{GET_POST_THREAD}?uri={}&depth=0&parentHeight=0",
+                percent_encode(ROOT)
+            ),
+            &format!(
+                r#"{{"thread": {{"$type": "{THREAD_VIEW}", "post": {{"uri": "{ROOT}", "cid": "bafy",
+                    "author": {{"did": "did:plc:alice", "handle": "alice.test"}},
+                    "record": {{"text": "hi"}},
+                    "threadgate": {{"record": {{"allow": [
+                        {{"$type": "app.bsky.feed.threadgate#followingRule"}}]}}}}}}}}}}"#
+            ),
+        );
+        mock.respond_json(
+            &format!("{appview}/{GET_RELATIONSHIPS}?actor=did%3Aplc%3Aalice&others=did%3Aplc%3Acarol"),
+            r#"{"relationships": [{"did": "did:plc:carol", "following": "at://did:plc:alice/app.bsky.graph.follow/1"}]}"#,
+        );
+        // Bob isn't bridged, so isn't followed by anyone on Bluesky
+        let refused = reply_received(&bridge, &reply(bob)).unwrap();
+        assert_eq!(refused, Verdict::Refused { bounced: true });
+        let jobs = bridge.jobs.queued();
+        let Job::Deliver(delivery) = &jobs[0].job else {
+            panic!("expected a delivery");
+        };
+        assert_eq!(delivery.inbox, "https://a.example/inbox");
+        let bounce = json::parse(&delivery.activity).unwrap();
+        assert_eq!(bounce.get("actor"), Some(&Value::from(ALICE_ACTOR)));
+        let in_reply_to = bounce.get("object").and_then(|note| note.get("inReplyTo"));
+        assert_eq!(in_reply_to, Some(&Value::from(format!("{bob}/notes/9"))));
+        // Once per conversation
+        let again = reply_received(&bridge, &reply(bob)).unwrap();
+        assert_eq!(again, Verdict::Refused { bounced: false });
+        assert_eq!(
+            reply_received(&bridge, &reply(carol)).unwrap(),
+            Verdict::Permitted
+        );
+
+        let ungated = json::parse(
+            r#"{"type": "Create", "actor": "https://a.example/users/bob",
+            "object": {"type": "Note", "inReplyTo": "https://a.example/notes/2"}}"#,
+        )
+        .unwrap();
+        assert_eq!(reply_received(&bridge, &ungated).unwrap(), Verdict::Ungated);
+    }
+
+    #[test]
+    fn fediverse_reply_policies_become_threadgates() {
+        let object = json::parse(
+            r#"{"type": "Note", "attributedTo": "https://a.example/users/bob",
+                "tag": [{"type": "Mention", "href": "https://b.example/users/dan"}],
+                "interactionPolicy": {"canReply": {"always": [
+                    "https://a.example/users/bob/followers", "https://b.example/users/dan"]}}}"#,
+        )
+        .unwrap();
+        let uri = "at://did:plc:bob/app.bsky.feed.post/1";
+        let gate = threadgate(&object, uri, "2026-01-01T00:00:00Z").unwrap();
+        let rules: Vec<_> = gate
+            .get("allow")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .filter_map(Allow::from_json)
+            .collect();
+        assert_eq!(rules, [Allow::Followers, Allow::Mentioned]);
+        assert_eq!(
+            crate::lexicon::validate(THREADGATE_COLLECTION, &gate).map_err(|e| e.to_string()),
+            Ok(())
+        );
+
+        let public = json::parse(
+            r#"{"interactionPolicy": {"canReply": {"always": ["https://www.w3.org/ns/activitystreams#Public"]}}}"#,
+        )
+        .unwrap();
+        assert_eq!(threadgate(&public, uri, "2026-01-01T00:00:00Z"), None);
+    }
+}
@@ -0,0 +1,400 @@
+//! Time helpers shared by persisted state and translated content
+//!
+//! Both networks timestamp content with RFC 3339, but with different habits: offsets rather
+//! than UTC, no fractional seconds or nanosecond ones, and the occasional clock running well
+//! ahead. [`normalize_timestamp`] puts everything the bridge emits into one UTC,
+//! millisecond-precision form, tolerating a configurable amount of clock skew
+
+use crate::parsing::ParseMode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// How far in the future a timestamp may be, by default, before it's refused
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// Milliseconds since the unix epoch, clamping pre-epoch times to 0
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// The inverse of [`unix_millis`]
+pub fn from_unix_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+/// The current time in milliseconds, bumped to stay unique, for minting ids
+pub fn unique_millis() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = unix_millis(SystemTime::now()) as u64;
+    let previous = LAST
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            Some(now.max(last + 1))
+        })
+        .expect("update always succeeds");
+    now.max(previous + 1)
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors reading a timestamp
+pub enum TimestampError {
+    #[error("Expected an RFC 3339 timestamp - found {found}")]
+    Malformed { found: String },
+    #[error("Timestamp is before 1970 - found {found}")]
+    BeforeEpoch { found: String },
+    #[error("Timestamp is too far in the future - found {found}")]
+    TooFarInFuture { found: String },
+}
+
+/// Days since the unix epoch of a proleptic Gregorian date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The inverse of [`days_from_civil`]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Parse an RFC 3339 date-time such as `2024-05-01T12:30:00.123+02:00`, leniently
+pub fn parse_rfc3339(timestamp: &str) -> Result<SystemTime, TimestampError> {
+    parse_rfc3339_with(timestamp, ParseMode::Lenient)
+}
+
+/// Parse an RFC 3339 date-time in `mode`
+///
+/// Strictly, the date and time have to be separated by `T` and a UTC offset given as `Z`, as
+/// atproto requires. Leniently, `t` or a space and `z` are accepted too
+pub fn parse_rfc3339_with(timestamp: &str, mode: ParseMode) -> Result<SystemTime, TimestampError> {
+    let malformed = || TimestampError::Malformed {
+        found: timestamp.to_string(),
+    };
+    let b = timestamp.as_bytes();
+    let number = |range: std::ops::Range<usize>| -> Result<i64, TimestampError> {
+        let digits = b.get(range).ok_or_else(malformed)?;
+        if !digits.iter().all(u8::is_ascii_digit) {
+            return Err(malformed());
+        }
+        Ok(digits.iter().fold(0, |n, d| n * 10 + i64::from(d - b'0')))
+    };
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if b.len() < 20
+        || separators.iter().any(|&(i, c)| b[i] != c)
+        || !(b[10] == b'T' || !mode.is_strict() && matches!(b[10], b't' | b' '))
+    {
+        return Err(malformed());
+    }
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    // Leap seconds are accepted, and folded into the following second
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(malformed());
+    }
+
+    let mut rest = 19;
+    let mut nanos = 0i64;
+    if b[rest] == b'.' {
+        let digits = b[rest + 1..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if digits == 0 {
+            return Err(malformed());
+        }
+        // Anything beyond nanoseconds is truncated
+        let fraction = number(rest + 1..rest + 1 + digits.min(9))?;
+        nanos = fraction * 10i64.pow(9 - digits.min(9) as u32);
+        rest += 1 + digits;
+    }
+    let offset_minutes = match &b[rest..] {
+        [b'Z'] => 0,
+        [b'z'] if !mode.is_strict() => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let (hours, minutes) = (number(rest + 1..rest + 3)?, number(rest + 4..rest + 6)?);
+            if hours > 23 || minutes > 59 {
+                return Err(malformed());
+            }
+            let offset = hours * 60 + minutes;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return Err(malformed()),
+    };
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+        - offset_minutes * 60;
+    if seconds < 0 {
+        return Err(TimestampError::BeforeEpoch {
+            found: timestamp.to_string(),
+        });
+    }
+    Ok(UNIX_EPOCH + Duration::new(seconds as u64, nanos as u32))
+}
+
+/// Format as RFC 3339 in UTC with millisecond precision, e.g. `2024-05-01T10:30:00.123Z`
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let millis = unix_millis(time);
+    let (days, millis_of_day) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    let (year, month, day) = civil_from_days(days);
+    let seconds = millis_of_day / 1000;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis_of_day % 1000
+    )
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format as an HTTP date in its IMF-fixdate form, e.g. `Tue, 07 Jun 2024 20:51:35 GMT`
+pub fn format_http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let seconds = unix_millis(time) / 1000;
+    let (days, seconds) = (seconds.div_euclid(86400), seconds.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {day:02} {} {year:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        MONTHS[month as usize - 1],
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Parse an HTTP date such as `Tue, 07 Jun 2024 20:51:35 GMT`, as `Date` headers give
+///
+/// Only the IMF-fixdate form is accepted, the obsolete ones having long fallen out of use
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let (_, rest) = date.trim().split_once(", ")?;
+    let [day, month, year, time, "GMT"] = rest.split(' ').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let number = |n: &str| n.parse::<u32>().ok().map(i64::from);
+    let month = MONTHS.iter().position(|&m| m == month)? as i64 + 1;
+    let (day, year) = (number(day)?, number(year)?);
+    let [hour, minute, second] = time.split(':').map(number).collect::<Option<Vec<_>>>()?[..]
+    else {
+        return None;
+    };
+    if year < 1970
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds as u64))
+}
+
+/// Parse an ISO 8601 duration such as `PT1M30S`, as ActivityPub gives for video and audio
+///
+/// Only days and smaller units are accepted, as longer ones don't have a fixed length
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let rest = duration.trim().strip_prefix('P')?;
+    let (days, time) = rest.split_once('T').unwrap_or((rest, ""));
+    if days.is_empty() && time.is_empty() {
+        return None;
+    }
+    let mut seconds = 0.0;
+    for (part, units) in [
+        (days, &[('D', 86_400.0)][..]),
+        (time, &[('H', 3600.0), ('M', 60.0), ('S', 1.0)]),
+    ] {
+        let mut part = part;
+        for &(unit, scale) in units {
+            if let Some((value, rest)) = part.split_once(unit) {
+                let value: f64 = value.parse().ok().filter(|v: &f64| *v >= 0.0)?;
+                seconds += value * scale;
+                part = rest;
+            }
+        }
+        if !part.is_empty() {
+            return None;
+        }
+    }
+    Some(Duration::from_secs_f64(seconds))
+}
+
+/// Bring a timestamp from either network into the bridge's canonical form
+///
+/// Times up to `max_skew` ahead of `now` are assumed to be clock drift and clamped to `now`,
+/// anything further ahead is refused
+pub fn normalize_timestamp(
+    timestamp: &str,
+    now: SystemTime,
+    max_skew: Duration,
+) -> Result<String, TimestampError> {
+    let time = parse_rfc3339(timestamp.trim())?;
+    if time > now + max_skew {
+        return Err(TimestampError::TooFarInFuture {
+            found: timestamp.to_string(),
+        });
+    }
+    Ok(format_rfc3339(time.min(now)))
+}
+
+/// The publish time to give translated content
+///
+/// The original's own time is kept where it's usable, so that backfilled posts keep their
+/// dates, and the bridge's clock is only used when there isn't one
+pub fn publish_time(original: Option<&str>, now: SystemTime, max_skew: Duration) -> String {
+    original
+        .and_then(|t| normalize_timestamp(t, now, max_skew).ok())
+        .unwrap_or_else(|| format_rfc3339(now))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(timestamp: &str) -> SystemTime {
+        parse_rfc3339(timestamp).unwrap()
+    }
+
+    #[test]
+    fn parse_and_format() {
+        assert_eq!(at("1970-01-01T00:00:00Z"), UNIX_EPOCH);
+        assert_eq!(
+            format_rfc3339(at("2024-02-29T23:30:00.5-01:00")),
+            "2024-03-01T00:30:00.500Z"
+        );
+        assert_eq!(
+            format_rfc3339(at("2023-11-14t22:13:20.123456789z")),
+            "2023-11-14T22:13:20.123Z"
+        );
+        assert_eq!(unix_millis(at("2023-11-14T22:13:20Z")), 1_700_000_000_000);
+        for malformed in [
+            "2024-02-30T00:00:00Z",
+            "2024-01-01T00:00:00",
+            "2024-01-01T24:00:00Z",
+            "2024-01-01T00:00:00.Z",
+            "2024-01-01T00:00:00+0100",
+            "2024-1-01T00:00:00Z",
+            "yesterday",
+        ] {
+            assert!(
+                matches!(
+                    parse_rfc3339(malformed),
+                    Err(TimestampError::Malformed { .. })
+                ),
+                "{malformed}"
+            );
+        }
+        assert!(matches!(
+            parse_rfc3339("1969-12-31T23:59:59Z"),
+            Err(TimestampError::BeforeEpoch { .. })
+        ));
+        for lenient in ["2023-11-14t22:13:20z", "2023-11-14 22:13:20Z"] {
+            assert!(parse_rfc3339_with(lenient, ParseMode::Strict).is_err());
+            assert_eq!(unix_millis(at(lenient)), 1_700_000_000_000);
+        }
+    }
+
+    #[test]
+    fn skew_tolerance() {
+        let now = at("2024-05-01T12:00:00Z");
+        let skew = Duration::from_secs(60);
+        let normalize = |t: &str| normalize_timestamp(t, now, skew);
+        assert_eq!(
+            normalize("2024-05-01T14:00:30+02:00").unwrap(),
+            "2024-05-01T12:00:00.000Z"
+        );
+        assert_eq!(
+            normalize("2024-05-01T12:01:01Z"),
+            Err(TimestampError::TooFarInFuture {
+                found: "2024-05-01T12:01:01Z".to_string()
+            })
+        );
+        assert!(normalize("3024-05-01T12:00:00Z").is_err());
+    }
+
+    #[test]
+    fn http_dates() {
+        assert_eq!(
+            parse_http_date("Tue, 14 Nov 2023 22:13:20 GMT"),
+            Some(at("2023-11-14T22:13:20Z"))
+        );
+        assert_eq!(
+            format_http_date(at("2023-11-14T22:13:20.5Z")),
+            "Tue, 14 Nov 2023 22:13:20 GMT"
+        );
+        for invalid in [
+            "Tue, 14 Nov 2023 22:13:20 UTC",
+            "Tue, 31 Nov 2023 22:13:20 GMT",
+            "Tuesday, 14-Nov-23 22:13:20 GMT",
+            "2023-11-14T22:13:20Z",
+        ] {
+            assert_eq!(parse_http_date(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn durations() {
+        let secs = |s: u64| Some(Duration::from_secs(s));
+        assert_eq!(parse_duration("PT1M30S"), secs(90));
+        assert_eq!(parse_duration("PT93S"), secs(93));
+        assert_eq!(parse_duration("P1DT1H"), secs(90_000));
+        assert_eq!(parse_duration("PT0.5S"), Some(Duration::from_millis(500)));
+        for invalid in ["", "P", "PT", "PT1X", "PT1S1M", "P1Y", "PT-1S", "1M"] {
+            assert_eq!(parse_duration(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn backfills_keep_original_times() {
+        let now = at("2024-05-01T12:00:00Z");
+        let skew = DEFAULT_MAX_CLOCK_SKEW;
+        assert_eq!(
+            publish_time(Some("2019-03-04T05:06:07Z"), now, skew),
+            "2019-03-04T05:06:07.000Z"
+        );
+        assert_eq!(publish_time(None, now, skew), "2024-05-01T12:00:00.000Z");
+        assert_eq!(
+            publish_time(Some("2999-01-01T00:00:00Z"), now, skew),
+            "2024-05-01T12:00:00.000Z"
+        );
+    }
+}
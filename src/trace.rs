@@ -0,0 +1,336 @@
+//! A trace of what the bridge decided about each event
+//!
+//! The [audit log](crate::audit) records what the bridge did; this records what it chose not
+//! to do, and why, so "my post didn't bridge" can be answered with the exact rule which kept
+//! it back. Each event considered is traced as a [`Decision`]: bridged, or skipped for a
+//! [`Reason`] such as the account not having opted in or the federation policy denying it.
+//!
+//! Decisions are taken wherever the bridge makes them:
+//!
+//! - firehose events of known accounts, as they're [considered](crate::bridge::Bridge::wants);
+//!   the rest of the firehose isn't traced, as there's far too much of it
+//! - firehose records failing [validation](crate::lexicon::admit)
+//! - each delivery to the fediverse, as it goes out or is refused
+//! - the parents of replies, as they're [fetched](crate::parents)
+//! - inbound requests refused by the [rate limits](crate::ratelimit)
+//!
+//! Like the audit log, decisions are appended to the shard's state directory and dropped
+//! once older than [`RetentionConfig::decision_ttl`](crate::retention::RetentionConfig). The
+//! admin API's `GET /admin/decisions` queries them
+
+use crate::bridge::Bridge;
+use crate::delivery::Delivery;
+use crate::digest::Network;
+use crate::json::Value;
+use crate::storage::{JsonLog, StateDir};
+use crate::time::{format_rfc3339, parse_rfc3339};
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The decision trace's file in the state directory, one JSON decision per line
+pub const DECISIONS_FILE: &str = "decisions.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Why an event wasn't bridged
+pub enum Reason {
+    /// Its account isn't bridged, or only known to link mentions of it
+    NotOptedIn,
+    /// Its account hasn't agreed to the bridge's current terms
+    AwaitingConsent,
+    /// It isn't addressed to the public
+    NotPublic,
+    /// A firehose filter, content filter or language preference left it out
+    Filtered,
+    /// Its sender was over their rate limit
+    OverLimit,
+    /// The federation policy denies its account or instance
+    PolicyDenied,
+    /// It'd go to another bridge, which would bridge it back
+    OtherBridge,
+    /// It failed validation against its lexicon
+    Invalid,
+    /// The bridge only mirrors posts, and it's something else
+    MirrorOnly,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 9] = [
+        Reason::NotOptedIn,
+        Reason::AwaitingConsent,
+        Reason::NotPublic,
+        Reason::Filtered,
+        Reason::OverLimit,
+        Reason::PolicyDenied,
+        Reason::OtherBridge,
+        Reason::Invalid,
+        Reason::MirrorOnly,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::NotOptedIn => "not-opted-in",
+            Reason::AwaitingConsent => "awaiting-consent",
+            Reason::NotPublic => "not-public",
+            Reason::Filtered => "filtered",
+            Reason::OverLimit => "over-limit",
+            Reason::PolicyDenied => "policy-denied",
+            Reason::OtherBridge => "other-bridge",
+            Reason::Invalid => "invalid",
+            Reason::MirrorOnly => "mirror-only",
+        }
+    }
+
+    pub fn parse(rule: &str) -> Option<Reason> {
+        Reason::ALL.into_iter().find(|known| known.as_str() == rule)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What the bridge decided about one event
+pub struct Decision {
+    pub at: SystemTime,
+    /// The network the event came from
+    pub network: Network,
+    /// The ID or URI of the event, or of the object it's about
+    pub event: String,
+    /// Whose it is
+    pub actor: String,
+    /// `None` if it was bridged, or why it was skipped
+    pub skipped: Option<Reason>,
+    /// Anything more specific, such as which filter, or where it was bridged to
+    pub detail: Option<String>,
+}
+
+impl Decision {
+    pub fn bridged(network: Network, event: &str, actor: &str) -> Decision {
+        Decision {
+            at: SystemTime::now(),
+            network,
+            event: event.to_string(),
+            actor: actor.to_string(),
+            skipped: None,
+            detail: None,
+        }
+    }
+
+    pub fn skipped(network: Network, event: &str, actor: &str, reason: Reason) -> Decision {
+        Decision::bridged(network, event, actor).skipped_by(reason)
+    }
+
+    /// A decision to bridge the post `delivery` is of, by the event it was caused by if it
+    /// says, to the inbox it's for
+    pub fn delivered(delivery: &Delivery) -> Option<Decision> {
+        let decision = Decision::bridged(Network::Bluesky, &delivery.post()?, &delivery.actor()?);
+        Some(decision.with_detail(format!("Delivered to {}", delivery.inbox)))
+    }
+
+    /// This decision, but skipped for `reason`
+    pub fn skipped_by(self, reason: Reason) -> Decision {
+        Decision {
+            skipped: Some(reason),
+            ..self
+        }
+    }
+
+    pub fn with_detail(self, detail: impl Into<String>) -> Decision {
+        Decision {
+            detail: Some(detail.into()),
+            ..self
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("at", Value::from(format_rfc3339(self.at))),
+            ("network", Value::from(self.network.as_str())),
+            ("event", Value::from(self.event.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            (
+                "decision",
+                Value::from(if self.skipped.is_some() {
+                    "skipped"
+                } else {
+                    "bridged"
+                }),
+            ),
+            ("rule", Value::from(self.skipped.map(|rule| rule.as_str()))),
+            ("detail", Value::from(self.detail.clone())),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<Decision> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let skipped = match field("rule") {
+            Some(rule) => Some(Reason::parse(rule)?),
+            None => None,
+        };
+        Some(Decision {
+            at: parse_rfc3339(field("at")?).ok()?,
+            network: Network::parse(field("network")?)?,
+            event: field("event")?.to_string(),
+            actor: field("actor")?.to_string(),
+            skipped,
+            detail: field("detail").map(str::to_string),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// Which decisions to return. Unset fields match everything
+pub struct DecisionQuery {
+    pub network: Option<Network>,
+    pub event: Option<String>,
+    pub actor: Option<String>,
+    /// Only decisions skipped for this reason
+    pub rule: Option<Reason>,
+    pub since: Option<SystemTime>,
+    /// At most this many decisions, newest first. Zero means no limit
+    pub limit: usize,
+}
+
+impl DecisionQuery {
+    fn matches(&self, decision: &Decision) -> bool {
+        self.network
+            .is_none_or(|network| network == decision.network)
+            && self
+                .event
+                .as_ref()
+                .is_none_or(|event| *event == decision.event)
+            && self
+                .actor
+                .as_ref()
+                .is_none_or(|actor| *actor == decision.actor)
+            && (self.rule.is_none() || self.rule == decision.skipped)
+            && self.since.is_none_or(|since| decision.at >= since)
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every decision within the retention period, oldest first
+pub struct DecisionLog {
+    decisions: Mutex<Vec<Decision>>,
+    log: Option<JsonLog>,
+}
+
+impl DecisionLog {
+    /// A log persisted in `dir`, with the decisions already there
+    pub fn open(dir: StateDir) -> io::Result<DecisionLog> {
+        let (log, decisions) = JsonLog::open(dir, DECISIONS_FILE)?;
+        let decisions = decisions.iter().filter_map(Decision::from_json).collect();
+        Ok(DecisionLog {
+            decisions: Mutex::new(decisions),
+            log: Some(log),
+        })
+    }
+
+    pub fn append(&self, decision: Decision) -> io::Result<()> {
+        let mut decisions = self.decisions.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([decision.to_json()])?;
+        }
+        decisions.push(decision);
+        Ok(())
+    }
+
+    /// Decisions matching `query`, newest first
+    pub fn query(&self, query: &DecisionQuery) -> Vec<Decision> {
+        let decisions = self.decisions.lock().unwrap();
+        let limit = match query.limit {
+            0 => usize::MAX,
+            limit => limit,
+        };
+        let found = decisions.iter().rev().filter(|d| query.matches(d));
+        found.take(limit).cloned().collect()
+    }
+
+    /// Drop decisions older than `ttl` as of `now`, returning how many there were
+    pub fn prune(&self, ttl: Duration, now: SystemTime) -> io::Result<usize> {
+        let mut decisions = self.decisions.lock().unwrap();
+        let before = decisions.len();
+        decisions.retain(|decision| now.duration_since(decision.at).unwrap_or_default() < ttl);
+        let pruned = before - decisions.len();
+        if let (Some(log), true) = (&self.log, pruned > 0) {
+            log.rewrite(decisions.iter().map(Decision::to_json))?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn len(&self) -> usize {
+        self.decisions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Trace `decision`. Whatever was decided has been either way, so failing to record it is
+/// only logged
+pub fn record(bridge: &Bridge, decision: Decision) {
+    if let Err(e) = bridge.decisions.append(decision) {
+        eprintln!("Couldn't write to the decision trace: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_state_dir;
+    use crate::time::{from_unix_millis, unix_millis};
+
+    const DAY: Duration = Duration::from_secs(86400);
+    const POST: &str = "at://did:plc:alice/app.bsky.feed.post/1";
+
+    #[test]
+    fn decisions_are_queryable_until_they_expire() {
+        let dir = temp_state_dir();
+        let log = DecisionLog::open(dir.clone()).unwrap();
+        let now = from_unix_millis(unix_millis(SystemTime::now()));
+        let old = Decision {
+            at: now - DAY * 40,
+            ..Decision::bridged(Network::Bluesky, POST, "did:plc:alice")
+        };
+        log.append(old).unwrap();
+        let denied = Decision {
+            at: now,
+            ..Decision::skipped(
+                Network::Bluesky,
+                POST,
+                "did:plc:alice",
+                Reason::PolicyDenied,
+            )
+            .with_detail("b.example is denied")
+        };
+        log.append(denied.clone()).unwrap();
+        let other = Decision {
+            at: now,
+            ..Decision::skipped(
+                Network::Fediverse,
+                "https://b.example/notes/1",
+                "https://b.example/users/bob",
+                Reason::NotPublic,
+            )
+        };
+        log.append(other).unwrap();
+        dir.append(DECISIONS_FILE, b"{\"at\": \"20").unwrap();
+
+        let log = DecisionLog::open(dir.clone()).unwrap();
+        assert_eq!(log.len(), 3);
+        let query = DecisionQuery {
+            event: Some(POST.to_string()),
+            ..DecisionQuery::default()
+        };
+        let traced = log.query(&query);
+        assert_eq!(traced.len(), 2);
+        assert_eq!(traced[0], denied);
+        let query = DecisionQuery {
+            rule: Some(Reason::NotPublic),
+            ..DecisionQuery::default()
+        };
+        assert_eq!(log.query(&query)[0].actor, "https://b.example/users/bob");
+
+        assert_eq!(log.prune(DAY * 30, now).unwrap(), 1);
+        assert_eq!(DecisionLog::open(dir).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,156 @@
+//! Transformers: operator code customizing bridged posts
+//!
+//! Translating a post happens in [`Stage`]s: its text, its media, then who it's addressed
+//! to. A [`Transformer`] is registered to run [before or after](Hook) one of them, and is
+//! handed the activity being sent with its object, to change however it likes - appending
+//! an attribution line, rewriting links, adding a hashtag. Transformers at the same point run
+//! in the order they were added, and see what the ones before them did.
+//!
+//! Deployments compile theirs in and add them with [`Bridge::with_transformer`]. Deliveries
+//! from bridged accounts pass through them as they're sent, around the
+//! [federation policy](crate::policy)'s rewrites for each stage: its content warnings for
+//! the text, stripping attachments for the media and unlisting for the audience. Content
+//! filters see the result, so a transformer can't sneak a post past them
+//!
+//! [`Bridge::with_transformer`]: crate::bridge::Bridge::with_transformer
+
+use crate::json::Value;
+use crate::store::Mapping;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A step in translating a post, in the order they run
+pub enum Stage {
+    /// The content, summary and tags
+    Text,
+    /// Attachments
+    Media,
+    /// `to` and `cc`, on the activity and its object
+    Audience,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Text, Stage::Media, Stage::Audience];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Whether a transformer runs before or after the bridge's own work in a stage
+pub enum Hook {
+    Before,
+    After,
+}
+
+#[derive(Debug, Clone, Copy)]
+/// What's being translated
+pub struct Context<'a> {
+    /// The bridged account the post is from
+    pub mapping: &'a Mapping,
+    /// Where it's being sent
+    pub inbox: &'a str,
+}
+
+/// Something customizing bridged posts
+pub trait Transformer: Send + Sync {
+    /// Identifies the transformer in logs
+    fn name(&self) -> &str;
+
+    /// Change `activity`, the ActivityPub activity being sent with its object
+    fn transform(&self, context: &Context, activity: &mut Value);
+}
+
+#[derive(Clone, Default)]
+/// The transformers registered at each point, in the order they run
+pub struct Transformers {
+    hooks: Vec<(Stage, Hook, Arc<dyn Transformer>)>,
+}
+
+impl Transformers {
+    /// Run `transformer` at `hook` of `stage`, after any already registered there
+    pub fn register(&mut self, stage: Stage, hook: Hook, transformer: Arc<dyn Transformer>) {
+        self.hooks.push((stage, hook, transformer));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    fn run_at(&self, stage: Stage, hook: Hook, context: &Context, activity: &mut Value) {
+        let registered = self
+            .hooks
+            .iter()
+            .filter(|(s, h, _)| (*s, *h) == (stage, hook));
+        for (_, _, transformer) in registered {
+            transformer.transform(context, activity);
+        }
+    }
+
+    /// Run `stage`: the transformers before it, the bridge's own `step`, then those after it
+    pub fn run(
+        &self,
+        stage: Stage,
+        context: &Context,
+        activity: &mut Value,
+        step: impl FnOnce(&mut Value),
+    ) {
+        self.run_at(stage, Hook::Before, context, activity);
+        step(activity);
+        self.run_at(stage, Hook::After, context, activity);
+    }
+}
+
+impl std::fmt::Debug for Transformers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.hooks.iter().map(|(stage, hook, transformer)| {
+            format!("{hook:?} {stage:?}: {}", transformer.name())
+        });
+        f.debug_list().entries(names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use atproto::did;
+
+    /// Appends its name to the object's content
+    struct Append(&'static str);
+
+    impl Transformer for Append {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn transform(&self, _: &Context, activity: &mut Value) {
+            let Value::Object(fields) = activity else {
+                return;
+            };
+            let Some(Value::Object(object)) = fields.get_mut("object") else {
+                return;
+            };
+            let content = object.get("content").and_then(Value::as_str);
+            let content = format!("{}{}", content.unwrap_or_default(), self.0);
+            object.insert("content".into(), Value::from(content));
+        }
+    }
+
+    #[test]
+    fn transformers_run_in_order_around_each_stage() {
+        let mut transformers = Transformers::default();
+        transformers.register(Stage::Text, Hook::After, Arc::new(Append("c")));
+        transformers.register(Stage::Text, Hook::Before, Arc::new(Append("a")));
+        transformers.register(Stage::Media, Hook::Before, Arc::new(Append("x")));
+        transformers.register(Stage::Text, Hook::After, Arc::new(Append("d")));
+        let mapping = Mapping::new(did!("did:plc:alice"), "https://bridge.example/users/alice");
+        let context = Context {
+            mapping: &mapping,
+            inbox: "https://b.example/inbox",
+        };
+        let mut activity = json::parse(r#"{"object": {"content": ""}}"#).unwrap();
+        transformers.run(Stage::Text, &context, &mut activity, |activity| {
+            Append("b").transform(&context, activity)
+        });
+        let content = activity.get("object").and_then(|o| o.get("content"));
+        assert_eq!(content, Some(&Value::from("abcd")));
+    }
+}
@@ -0,0 +1,1087 @@
+//! Outbound HTTP
+//!
+//! Everything the bridge fetches or sends goes through the [`HttpTransport`] trait, so that
+//! tests can swap the network for a [`MockTransport`] with canned responses and failures.
+//!
+//! [`StdTransport`] is a plain HTTP/1.1 client built on `std::net`. It only speaks TLS through
+//! a backend's [`TlsConnector`], such as [`RustlsConnector`](crate::tls::RustlsConnector);
+//! without one, `https` is left to a local TLS-terminating proxy.
+//! It keeps connections alive and pools them per host, so that deliveries to a busy instance
+//! don't each pay for a new connection; see [`PoolConfig`]. Given an
+//! [egress policy](crate::egress::EgressPolicy), it refuses requests to the operator's own
+//! network. HTTP/2 is negotiated during the
+//! TLS handshake, so it's left to TLS-capable transports too. It needs the `net` feature; where
+//! there are no sockets, as on `wasm32-unknown-unknown`, the embedder fetches instead through a
+//! [`FetchTransport`](crate::fetch::FetchTransport)
+
+use crate::cache::Validators;
+use crate::egress::EgressError;
+use crate::http::Method;
+use crate::time::format_rfc3339;
+use crate::url::UrlError;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+#[cfg(feature = "net")]
+use {
+    crate::dns::DnsResolver,
+    crate::egress::EgressPolicy,
+    crate::proxy::{ProxyRules, Route},
+    crate::tls::{Stream, TlsConnector},
+    crate::url::Url,
+    std::io::{self, BufRead, BufReader, Read, Write},
+    std::net::{SocketAddr, TcpStream},
+    std::sync::{mpsc, Condvar},
+    std::thread,
+    std::time::Instant,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A request to a remote server
+pub struct OutboundRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Overrides the transport's own timeout, e.g. for fetches which should give up sooner
+    pub timeout: Option<Duration>,
+}
+
+impl OutboundRequest {
+    pub fn new(method: Method, url: impl Into<String>) -> OutboundRequest {
+        OutboundRequest {
+            method,
+            url: url.into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn get(url: impl Into<String>) -> OutboundRequest {
+        OutboundRequest::new(Method::Get, url)
+    }
+
+    pub fn post(url: impl Into<String>, body: impl Into<Vec<u8>>) -> OutboundRequest {
+        OutboundRequest::new(Method::Post, url).with_body(body)
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> OutboundRequest {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> OutboundRequest {
+        self.body = body.into();
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> OutboundRequest {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Ask for the resource only if it has changed since it was sent with `validators`
+    pub fn if_modified(mut self, validators: Option<&Validators>) -> OutboundRequest {
+        let Some(validators) = validators else {
+            return self;
+        };
+        if let Some(etag) = &validators.etag {
+            self = self.with_header("if-none-match", etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            self = self.with_header("if-modified-since", last_modified);
+        }
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A remote server's response
+pub struct OutboundResponse {
+    pub status: u16,
+    /// Headers with lowercased names
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl OutboundResponse {
+    pub fn new(status: u16) -> OutboundResponse {
+        OutboundResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> OutboundResponse {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> OutboundResponse {
+        self.body = body.into();
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The validators to make later requests for this resource conditional with
+    pub fn validators(&self) -> Validators {
+        Validators {
+            etag: self.header("etag").map(str::to_string),
+            last_modified: self.header("last-modified").map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors sending a request
+///
+/// HTTP error statuses are not errors at this level, they're returned as responses
+pub enum TransportError {
+    #[error(transparent)]
+    InvalidUrl(#[from] UrlError),
+    #[error("This transport can't make {scheme} requests")]
+    UnsupportedScheme { scheme: String },
+    #[error("Couldn't connect to {url} - {reason}")]
+    Connect { url: String, reason: String },
+    #[error("Request to {url} timed out")]
+    Timeout { url: String },
+    #[error("Malformed response from {url} - {reason}")]
+    MalformedResponse { url: String, reason: String },
+    #[error("Response from {url} exceeded {limit} bytes")]
+    ResponseTooLarge { url: String, limit: usize },
+    #[error(transparent)]
+    Forbidden(#[from] EgressError),
+    #[error("Not contacting {host} until {}, after repeated failures", format_rfc3339(*.until))]
+    CircuitOpen { host: String, until: SystemTime },
+}
+
+/// Something which can send HTTP requests
+pub trait HttpTransport: Send + Sync {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError>;
+
+    #[cfg(feature = "net")]
+    /// Connect to the server of the `http` or `https` `url` as a request to it would, for a
+    /// protocol which takes the connection over, such as a [WebSocket](crate::websocket)
+    fn open(&self, url: &str) -> Result<Box<dyn Stream>, TransportError> {
+        let scheme = url.split("://").next().unwrap_or_default();
+        Err(TransportError::UnsupportedScheme {
+            scheme: scheme.to_string(),
+        })
+    }
+}
+
+/// The transport the platform has: a [`StdTransport`] with the `net` feature, or else on
+/// WebAssembly a [`FetchTransport`](crate::fetch::FetchTransport)
+///
+/// Anywhere else, every request fails until another transport is set
+pub fn platform_default() -> Arc<dyn HttpTransport> {
+    #[cfg(feature = "net")]
+    return Arc::new(StdTransport::default());
+    #[cfg(all(not(feature = "net"), target_arch = "wasm32"))]
+    return Arc::new(crate::fetch::FetchTransport::new());
+    #[cfg(all(not(feature = "net"), not(target_arch = "wasm32")))]
+    return Arc::new(Disconnected);
+}
+
+#[cfg(all(not(feature = "net"), not(target_arch = "wasm32")))]
+/// Fails every request, for builds with nothing to send them over
+struct Disconnected;
+
+#[cfg(all(not(feature = "net"), not(target_arch = "wasm32")))]
+impl HttpTransport for Disconnected {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        Err(TransportError::Connect {
+            url: request.url.clone(),
+            reason: "this build has no transport".to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How [`StdTransport`] reuses connections
+pub struct PoolConfig {
+    /// Connections open to one host at once, busy or idle. Requests beyond this wait for one
+    /// to be free, for as long as they'd wait for a response
+    pub max_per_host: usize,
+    /// How long an idle connection is kept for reuse. Servers close idle connections
+    /// themselves eventually, so this should be shorter than they tend to wait
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_per_host: 8,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+type Connection = BufReader<Box<dyn Stream>>;
+
+#[cfg(feature = "net")]
+#[derive(Debug, Default)]
+struct HostConnections {
+    /// Connections waiting for reuse, most recently used last
+    idle: Vec<(Connection, Instant)>,
+    /// Every connection to the host, including idle ones
+    open: usize,
+}
+
+#[cfg(feature = "net")]
+#[derive(Debug, Default)]
+struct ConnectionPool {
+    config: PoolConfig,
+    hosts: Mutex<HashMap<String, HostConnections>>,
+    freed: Condvar,
+}
+
+#[cfg(feature = "net")]
+impl ConnectionPool {
+    /// An idle connection to `host`, or `None` with a slot reserved to open one, waiting up to
+    /// `timeout` for either. Gives up with `Err` if the wait runs out
+    fn acquire(&self, host: &str, timeout: Duration) -> Result<Option<Connection>, ()> {
+        let deadline = Instant::now() + timeout;
+        let mut hosts = self.hosts.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let connections = hosts.entry(host.to_string()).or_default();
+            while let Some((connection, since)) = connections.idle.pop() {
+                if now.saturating_duration_since(since) < self.config.idle_timeout
+                    && is_open(&connection)
+                {
+                    return Ok(Some(connection));
+                }
+                connections.open -= 1;
+            }
+            if connections.open < self.config.max_per_host.max(1) {
+                connections.open += 1;
+                return Ok(None);
+            }
+            let wait = deadline.saturating_duration_since(now);
+            if wait.is_zero() {
+                return Err(());
+            }
+            hosts = self.freed.wait_timeout(hosts, wait).unwrap().0;
+        }
+    }
+
+    /// Give back a connection for reuse, or with `None` the slot of one which was closed
+    fn release(&self, host: &str, connection: Option<Connection>) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(connections) = hosts.get_mut(host) {
+            match connection {
+                Some(connection) => connections.idle.push((connection, Instant::now())),
+                None => connections.open -= 1,
+            }
+            if connections.open == 0 {
+                hosts.remove(host);
+            }
+        }
+        drop(hosts);
+        self.freed.notify_all();
+    }
+
+    fn idle(&self) -> usize {
+        let hosts = self.hosts.lock().unwrap();
+        hosts
+            .values()
+            .map(|connections| connections.idle.len())
+            .sum()
+    }
+}
+
+#[cfg(feature = "net")]
+/// Connect to the first of `addresses` to answer, starting on each after the last has had
+/// `delay` to, or as soon as it fails
+fn race(addresses: &[SocketAddr], delay: Duration, timeout: Duration) -> io::Result<TcpStream> {
+    let deadline = Instant::now() + timeout;
+    let (sender, attempts) = mpsc::channel();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+    let mut pending = 0;
+    let mut untried = addresses.iter();
+    loop {
+        let next = match untried.next() {
+            Some(&addr) => {
+                let sender = sender.clone();
+                // Losing attempts' streams are dropped, as nothing receives them
+                thread::spawn(move || sender.send(TcpStream::connect_timeout(&addr, timeout)));
+                pending += 1;
+                (Instant::now() + delay).min(deadline)
+            }
+            None if pending == 0 => return Err(last_error),
+            None => deadline,
+        };
+        // Wait for the next attempt's turn, or for one to finish
+        loop {
+            let wait = next.saturating_duration_since(Instant::now());
+            match attempts.recv_timeout(wait) {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    last_error = e;
+                    pending -= 1;
+                    if pending == 0 {
+                        break;
+                    }
+                }
+                Err(_) if next == deadline => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connecting timed out",
+                    ))
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+/// Whether an idle connection is still usable, rather than closed by the server or sent
+/// something unasked for
+fn is_open(connection: &Connection) -> bool {
+    if !connection.buffer().is_empty() {
+        return false;
+    }
+    let stream = connection.get_ref().socket();
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(
+        stream.peek(&mut [0]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock
+    );
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+#[cfg(feature = "net")]
+/// A pool slot, given back when dropped: with its connection if one was kept
+struct Checkout<'a> {
+    pool: &'a ConnectionPool,
+    host: &'a str,
+    connection: Option<Connection>,
+}
+
+#[cfg(feature = "net")]
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        self.pool.release(self.host, self.connection.take());
+    }
+}
+
+#[cfg(feature = "net")]
+#[derive(Debug, Clone)]
+/// Plain HTTP/1.1 over `std::net`, with pooled keep-alive connections
+///
+/// Clones share their pool
+pub struct StdTransport {
+    pub timeout: Duration,
+    /// Responses larger than this are abandoned
+    pub max_response_size: usize,
+    connections: Arc<ConnectionPool>,
+    /// How `https` requests are made, if they can be
+    tls: Option<Arc<dyn TlsConnector>>,
+    /// Which connections are tunnelled through proxies
+    proxies: ProxyRules,
+    /// What hosts are resolved with, and how their addresses are tried
+    dns: Arc<DnsResolver>,
+    /// Where requests may go, if anywhere isn't fine
+    egress: Option<EgressPolicy>,
+}
+
+#[cfg(feature = "net")]
+impl Default for StdTransport {
+    fn default() -> Self {
+        StdTransport {
+            timeout: Duration::from_secs(10),
+            max_response_size: 10 * 1024 * 1024,
+            connections: Arc::default(),
+            tls: None,
+            proxies: ProxyRules::default(),
+            dns: Arc::default(),
+            egress: None,
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Delete => "DELETE",
+    }
+}
+
+#[cfg(feature = "net")]
+impl StdTransport {
+    /// Replace the connection pool with an empty one configured by `config`
+    pub fn with_pool(self, config: PoolConfig) -> StdTransport {
+        StdTransport {
+            connections: Arc::new(ConnectionPool {
+                config,
+                ..ConnectionPool::default()
+            }),
+            ..self
+        }
+    }
+
+    /// Make `https` requests through `connector`
+    pub fn with_tls(self, connector: Arc<dyn TlsConnector>) -> StdTransport {
+        StdTransport {
+            tls: Some(connector),
+            ..self
+        }
+    }
+
+    /// Tunnel connections through proxies as `rules` say
+    pub fn with_proxies(self, rules: ProxyRules) -> StdTransport {
+        StdTransport {
+            proxies: rules,
+            ..self
+        }
+    }
+
+    /// Resolve hosts with `resolver`
+    pub fn with_dns(self, resolver: Arc<DnsResolver>) -> StdTransport {
+        StdTransport {
+            dns: resolver,
+            ..self
+        }
+    }
+
+    /// Refuse requests `policy` doesn't allow, before connecting and again once their host
+    /// is resolved
+    pub fn with_egress(self, policy: EgressPolicy) -> StdTransport {
+        StdTransport {
+            egress: Some(policy),
+            ..self
+        }
+    }
+
+    /// How many connections are waiting for reuse
+    pub fn idle_connections(&self) -> usize {
+        self.connections.idle()
+    }
+
+    fn io_error(&self, url: &str, e: io::Error) -> TransportError {
+        let refused = e.get_ref().and_then(|e| e.downcast_ref::<EgressError>());
+        if let Some(refused) = refused {
+            return TransportError::Forbidden(refused.clone());
+        }
+        match e.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => TransportError::Timeout {
+                url: url.to_string(),
+            },
+            _ => TransportError::Connect {
+                url: url.to_string(),
+                reason: e.to_string(),
+            },
+        }
+    }
+
+    /// Connect to `host`, as long as each of its addresses is one `egress` allows
+    fn dial(
+        &self,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+        egress: Option<&EgressPolicy>,
+    ) -> io::Result<TcpStream> {
+        let addresses = self.dns.addresses(host)?;
+        if let Some(policy) = egress {
+            for ip in &addresses {
+                policy.check_address(host, *ip).map_err(io::Error::other)?;
+            }
+        }
+        let addresses: Vec<SocketAddr> = addresses
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        let stream = match self.dns.config.attempt_delay {
+            Some(delay) if addresses.len() > 1 => race(&addresses, delay, timeout),
+            _ => {
+                let mut last_error =
+                    io::Error::new(io::ErrorKind::NotFound, "host has no addresses");
+                let mut connected = None;
+                for addr in &addresses {
+                    match TcpStream::connect_timeout(addr, timeout) {
+                        Ok(stream) => {
+                            connected = Some(stream);
+                            break;
+                        }
+                        Err(e) => last_error = e,
+                    }
+                }
+                connected.ok_or(last_error)
+            }
+        }?;
+        // Handshakes with proxies and TLS shouldn't hang any longer than requests
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(stream)
+    }
+
+    fn connect(&self, url: &Url, timeout: Duration) -> io::Result<Box<dyn Stream>> {
+        let host = url.host.trim_start_matches('[').trim_end_matches(']');
+        let port = url.port_or_default();
+        let stream = match self.proxies.route(host) {
+            Route::Direct => self.dial(host, port, timeout, self.egress.as_ref())?,
+            // The proxy resolves the host, so only its name could be checked
+            Route::Through(proxy) => {
+                let mut stream = self.dial(&proxy.host, proxy.port, timeout, None)?;
+                proxy.tunnel(&mut stream, host, port)?;
+                stream
+            }
+            Route::Unreachable => {
+                let reason = format!("{host} is only reachable through a proxy");
+                return Err(io::Error::new(io::ErrorKind::NotFound, reason));
+            }
+        };
+        match &self.tls {
+            Some(tls) if url.scheme == "https" => tls.connect(host, stream),
+            _ => Ok(Box::new(stream)),
+        }
+    }
+
+    /// Read a response to a `method` request, and whether the connection can be reused after
+    fn read_response<R: BufRead>(
+        &self,
+        url: &str,
+        method: Method,
+        reader: &mut R,
+    ) -> Result<(OutboundResponse, bool), TransportError> {
+        let malformed = |reason: &str| TransportError::MalformedResponse {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let mut line = String::new();
+        reader
+            .read_line(&mut line)
+            .map_err(|e| self.io_error(url, e))?;
+        let status: u16 = line
+            .split(' ')
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| malformed("bad status line"))?;
+        // HTTP/1.0 servers close the connection unless they say otherwise
+        let http_1_1 = line.starts_with("HTTP/1.1");
+        let mut response = OutboundResponse::new(status);
+        loop {
+            line.clear();
+            reader
+                .read_line(&mut line)
+                .map_err(|e| self.io_error(url, e))?;
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| malformed("bad header"))?;
+            response = response.with_header(name.trim(), value.trim());
+        }
+
+        let too_large = || TransportError::ResponseTooLarge {
+            url: url.to_string(),
+            limit: self.max_response_size,
+        };
+        let chunked = response
+            .header("transfer-encoding")
+            .is_some_and(|te| te.eq_ignore_ascii_case("chunked"));
+        let length: Option<usize> = response
+            .header("content-length")
+            .and_then(|l| l.parse().ok());
+        let connection = response.header("connection").unwrap_or_default();
+        let keep_alive = if http_1_1 {
+            !connection.eq_ignore_ascii_case("close")
+        } else {
+            connection.eq_ignore_ascii_case("keep-alive")
+        };
+        let mut body = Vec::new();
+        if method == Method::Head || matches!(status, 100..=199 | 204 | 304) {
+            return Ok((response, keep_alive));
+        }
+        if chunked {
+            loop {
+                line.clear();
+                reader
+                    .read_line(&mut line)
+                    .map_err(|e| self.io_error(url, e))?;
+                let size = line.trim().split(';').next().unwrap_or_default();
+                let size =
+                    usize::from_str_radix(size, 16).map_err(|_| malformed("bad chunk size"))?;
+                if size == 0 {
+                    // Skip any trailers, up to the blank line ending the response
+                    loop {
+                        line.clear();
+                        reader
+                            .read_line(&mut line)
+                            .map_err(|e| self.io_error(url, e))?;
+                        if line.trim_end().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                if body.len() + size > self.max_response_size {
+                    return Err(too_large());
+                }
+                let start = body.len();
+                body.resize(start + size, 0);
+                reader
+                    .read_exact(&mut body[start..])
+                    .map_err(|e| self.io_error(url, e))?;
+                // Each chunk is followed by a CRLF
+                line.clear();
+                reader
+                    .read_line(&mut line)
+                    .map_err(|e| self.io_error(url, e))?;
+            }
+        } else if let Some(length) = length {
+            if length > self.max_response_size {
+                return Err(too_large());
+            }
+            body.resize(length, 0);
+            reader
+                .read_exact(&mut body)
+                .map_err(|e| self.io_error(url, e))?;
+        } else {
+            // Without a length the body runs until the connection closes
+            let limit = self.max_response_size as u64 + 1;
+            reader
+                .take(limit)
+                .read_to_end(&mut body)
+                .map_err(|e| self.io_error(url, e))?;
+            if body.len() > self.max_response_size {
+                return Err(too_large());
+            }
+            return Ok((response.with_body(body), false));
+        }
+        Ok((response.with_body(body), keep_alive))
+    }
+
+    /// Send a request over `connection`, returning the response and the connection if it can
+    /// be reused
+    fn exchange(
+        &self,
+        mut connection: Connection,
+        url: &Url,
+        request: &OutboundRequest,
+        timeout: Duration,
+    ) -> Result<(OutboundResponse, Option<Connection>), TransportError> {
+        let io_error = |e| self.io_error(&request.url, e);
+        let stream = connection.get_mut();
+        let socket = stream.socket();
+        socket.set_read_timeout(Some(timeout)).map_err(io_error)?;
+        socket.set_write_timeout(Some(timeout)).map_err(io_error)?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nhost: {}\r\ncontent-length: {}\r\n",
+            method_name(request.method),
+            url.path,
+            url.authority(),
+            request.body.len()
+        );
+        for (name, value) in &request.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).map_err(io_error)?;
+        stream.write_all(&request.body).map_err(io_error)?;
+        stream.flush().map_err(io_error)?;
+
+        let (response, reusable) =
+            self.read_response(&request.url, request.method, &mut connection)?;
+        Ok((response, reusable.then_some(connection)))
+    }
+}
+
+#[cfg(feature = "net")]
+impl StdTransport {
+    /// `url`, if requests may be made to it
+    fn admit(&self, url: &str) -> Result<Url, TransportError> {
+        let url = Url::parse(url)?;
+        match url.scheme.as_str() {
+            "http" => {}
+            "https" if self.tls.is_some() => {}
+            _ => return Err(TransportError::UnsupportedScheme { scheme: url.scheme }),
+        }
+        if let Some(policy) = &self.egress {
+            policy.check(&url)?;
+        }
+        Ok(url)
+    }
+}
+
+#[cfg(feature = "net")]
+impl HttpTransport for StdTransport {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        let url = self.admit(&request.url)?;
+        let timeout = request.timeout.unwrap_or(self.timeout);
+        let host = format!("{}://{}", url.scheme, url.authority());
+        let idle =
+            self.connections
+                .acquire(&host, timeout)
+                .map_err(|()| TransportError::Timeout {
+                    url: request.url.clone(),
+                })?;
+        let mut checkout = Checkout {
+            pool: &self.connections,
+            host: &host,
+            connection: None,
+        };
+        if let Some(connection) = idle {
+            match self.exchange(connection, &url, request, timeout) {
+                Ok((response, connection)) => {
+                    checkout.connection = connection;
+                    return Ok(response);
+                }
+                // The server may have closed the connection just as it was reused. Retrying
+                // on a new one could repeat a request which was handled, so only do that
+                // when repeating it is harmless
+                Err(e) if request.method == Method::Post => return Err(e),
+                Err(_) => {}
+            }
+        }
+        let stream = self
+            .connect(&url, timeout)
+            .map_err(|e| self.io_error(&request.url, e))?;
+        let (response, connection) =
+            self.exchange(BufReader::new(stream), &url, request, timeout)?;
+        checkout.connection = connection;
+        Ok(response)
+    }
+
+    fn open(&self, url: &str) -> Result<Box<dyn Stream>, TransportError> {
+        let parsed = self.admit(url)?;
+        self.connect(&parsed, self.timeout)
+            .map_err(|e| self.io_error(url, e))
+    }
+}
+
+type Reply = Result<OutboundResponse, TransportError>;
+
+#[derive(Debug, Default)]
+/// A transport which answers from canned replies and records every request
+///
+/// Each route holds a sequence of replies which are used in order, with the last one
+/// repeating forever. Requests to unknown routes get a 404
+pub struct MockTransport {
+    routes: Mutex<HashMap<(Method, String), VecDeque<Reply>>>,
+    requests: Mutex<Vec<OutboundRequest>>,
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    fn push(&self, method: Method, url: &str, reply: Reply) {
+        self.routes
+            .lock()
+            .unwrap()
+            .entry((method, url.to_string()))
+            .or_default()
+            .push_back(reply);
+    }
+
+    /// Queue a response for a route
+    pub fn respond(&self, method: Method, url: &str, response: OutboundResponse) -> &MockTransport {
+        self.push(method, url, Ok(response));
+        self
+    }
+
+    /// Queue a JSON response for GET requests to a route
+    pub fn respond_json(&self, url: &str, body: &str) -> &MockTransport {
+        let response = OutboundResponse::new(200)
+            .with_header("content-type", "application/json")
+            .with_body(body);
+        self.respond(Method::Get, url, response)
+    }
+
+    /// Queue a transport-level failure for a route
+    pub fn fail(&self, method: Method, url: &str, error: TransportError) -> &MockTransport {
+        self.push(method, url, Err(error));
+        self
+    }
+
+    /// Every request sent so far, in order
+    pub fn requests(&self) -> Vec<OutboundRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests sent to a particular URL
+    pub fn requests_to(&self, url: &str) -> Vec<OutboundRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.url == url)
+            .cloned()
+            .collect()
+    }
+}
+
+impl HttpTransport for MockTransport {
+    fn send(&self, request: &OutboundRequest) -> Result<OutboundResponse, TransportError> {
+        self.requests.lock().unwrap().push(request.clone());
+        let mut routes = self.routes.lock().unwrap();
+        let Some(replies) = routes.get_mut(&(request.method, request.url.clone())) else {
+            return Ok(OutboundResponse::new(404));
+        };
+        match replies.len() {
+            0 => Ok(OutboundResponse::new(404)),
+            1 => replies[0].clone(),
+            _ => replies.pop_front().expect("checked length"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "net")]
+    use {
+        crate::http::{self, Request, Response},
+        crate::shutdown::Shutdown,
+        std::net::TcpListener,
+        std::sync::atomic::{AtomicUsize, Ordering},
+        std::thread,
+    };
+
+    #[test]
+    fn mock_replays_in_order_then_repeats() {
+        let mock = MockTransport::new();
+        let url = "https://remote.example/inbox";
+        mock.fail(
+            Method::Post,
+            url,
+            TransportError::Timeout {
+                url: url.to_string(),
+            },
+        )
+        .respond(Method::Post, url, OutboundResponse::new(202));
+        let request = OutboundRequest::post(url, "{}");
+        assert!(mock.send(&request).is_err());
+        assert_eq!(mock.send(&request).unwrap().status, 202);
+        assert_eq!(mock.send(&request).unwrap().status, 202);
+        assert_eq!(mock.send(&OutboundRequest::get(url)).unwrap().status, 404);
+        assert_eq!(mock.requests_to(url).len(), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn std_transport_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let handler: Arc<dyn http::Handler> = Arc::new(|request: &Request| {
+            Response::new(200).with_body(format!(
+                "{} {}",
+                request.header("x-test").unwrap_or_default(),
+                String::from_utf8_lossy(&request.body)
+            ))
+        });
+        let server = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || http::serve(listener, handler, shutdown))
+        };
+
+        let request = OutboundRequest::post(format!("http://{addr}/inbox"), "hello")
+            .with_header("X-Test", "yes");
+        let response = StdTransport::default().send(&request).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"yes hello");
+
+        // The bridge's own network is off limits, unless the operator allows it
+        let guarded = StdTransport::default().with_egress(EgressPolicy::default());
+        assert!(matches!(
+            guarded.send(&request),
+            Err(TransportError::Forbidden(EgressError::Port { .. }))
+        ));
+        let allowed = EgressPolicy {
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..EgressPolicy::default()
+        };
+        let allowed = StdTransport::default().with_egress(allowed);
+        assert_eq!(allowed.send(&request).unwrap().status, 200);
+
+        shutdown.request();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn chunked_and_oversized_bodies() {
+        let transport = StdTransport {
+            max_response_size: 8,
+            ..StdTransport::default()
+        };
+        let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let response = transport
+            .read_response("http://a.example", Method::Get, &mut &raw[..])
+            .unwrap()
+            .0;
+        assert_eq!(response.body, b"abcde");
+
+        let raw = b"HTTP/1.1 200 OK\r\n\r\n0123456789";
+        assert!(matches!(
+            transport.read_response("http://a.example", Method::Get, &mut &raw[..]),
+            Err(TransportError::ResponseTooLarge { .. })
+        ));
+        assert!(matches!(
+            transport.send(&OutboundRequest::get("https://a.example")),
+            Err(TransportError::UnsupportedScheme { .. })
+        ));
+    }
+
+    /// Answers every request on a connection with "ok" until the client closes it, closing it
+    /// itself after requests to `/close`
+    #[cfg(feature = "net")]
+    fn keep_alive_server(listener: TcpListener, accepted: Arc<AtomicUsize>) {
+        for stream in listener.incoming() {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let mut reader = BufReader::new(stream.unwrap());
+            loop {
+                let mut head = Vec::new();
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 0 && line != "\r\n" {
+                    head.push(std::mem::take(&mut line));
+                }
+                if head.is_empty() {
+                    break;
+                }
+                let close = head[0].starts_with("GET /close ");
+                let connection = if close { "close" } else { "keep-alive" };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nconnection: {connection}\r\ncontent-length: 2\r\n\r\nok"
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                if close {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn reuses_kept_alive_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        {
+            let accepted = accepted.clone();
+            thread::spawn(move || keep_alive_server(listener, accepted));
+        }
+        let transport = StdTransport::default();
+        for _ in 0..3 {
+            let response = transport
+                .send(&OutboundRequest::get(format!("http://{addr}/inbox")))
+                .unwrap();
+            assert_eq!(response.body, b"ok");
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(transport.idle_connections(), 1);
+
+        // Connections the server closes aren't kept
+        let close = OutboundRequest::get(format!("http://{addr}/close"));
+        assert_eq!(transport.send(&close).unwrap().body, b"ok");
+        assert_eq!(transport.idle_connections(), 0);
+        transport
+            .send(&OutboundRequest::get(format!("http://{addr}/inbox")))
+            .unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "net")]
+    #[derive(Debug, Default)]
+    /// A "backend" which doesn't encrypt anything, but records who it shook hands with
+    struct Plaintext {
+        hosts: Mutex<Vec<String>>,
+    }
+
+    #[cfg(feature = "net")]
+    impl TlsConnector for Plaintext {
+        fn connect(&self, host: &str, socket: TcpStream) -> io::Result<Box<dyn Stream>> {
+            self.hosts.lock().unwrap().push(host.to_string());
+            Ok(Box::new(socket))
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn https_goes_through_the_tls_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || keep_alive_server(listener, Arc::default()));
+        let tls = Arc::new(Plaintext::default());
+        let transport = StdTransport::default().with_tls(tls.clone());
+        for _ in 0..2 {
+            let request = OutboundRequest::get(format!("https://127.0.0.1:{port}/inbox"));
+            assert_eq!(transport.send(&request).unwrap().body, b"ok");
+        }
+        // The connection was kept, so there was only the one handshake
+        assert_eq!(*tls.hosts.lock().unwrap(), ["127.0.0.1"]);
+        assert_eq!(transport.idle_connections(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn stalled_addresses_are_raced() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // A documentation address, which nothing answers from
+        let stalled = SocketAddr::from(([192, 0, 2, 1], port));
+        let started = Instant::now();
+        let addresses = [stalled, listener.local_addr().unwrap()];
+        let timeout = Duration::from_secs(5);
+        race(&addresses, Duration::from_millis(50), timeout).unwrap();
+        assert!(started.elapsed() < timeout);
+    }
+
+    #[test]
+    #[cfg(feature = "net")]
+    fn caps_connections_per_host() {
+        let pool = ConnectionPool {
+            config: PoolConfig {
+                max_per_host: 1,
+                ..PoolConfig::default()
+            },
+            ..ConnectionPool::default()
+        };
+        let wait = Duration::from_millis(10);
+        assert!(matches!(pool.acquire("a.example", wait), Ok(None)));
+        assert!(pool.acquire("a.example", wait).is_err());
+        // Other hosts have their own limit
+        assert!(matches!(pool.acquire("b.example", wait), Ok(None)));
+        pool.release("a.example", None);
+        assert!(matches!(pool.acquire("a.example", wait), Ok(None)));
+    }
+}
@@ -0,0 +1,320 @@
+//! Unbridging accounts
+//!
+//! Unbridging undoes everything bridging did for an account, either on request or because
+//! the account was deleted on its own network:
+//!
+//! - its counterpart is removed from the other network. A Bluesky account's actor is sent
+//!   an actor `Delete`, which fediverse servers take as deleting everything it posted, and
+//!   a fediverse account's bridged repo is deactivated along with its records
+//! - jobs still waiting to bridge anything for it are cancelled
+//! - media re-hosted for it and interactions waiting for its digest are purged
+//! - its mapping is removed
+//!
+//! Every deletion is kept in the [`DeletionLog`], which holds identifiers and counts but
+//! nothing posted. Keys are left in the keystore, as the removal jobs still need to sign
+//! with them
+
+use crate::bridge::Bridge;
+use crate::digest::{self, Network};
+use crate::export;
+use crate::jobs::Job;
+use crate::json::{self, Value};
+use crate::storage::{JsonLog, StateDir};
+use crate::store::MappingStatus;
+use crate::time::{format_rfc3339, parse_rfc3339};
+use atproto::DID::Did;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use thiserror::Error;
+
+/// The deletion log's file in the state directory, one JSON record per line
+const DELETIONS_FILE: &str = "deletions.jsonl";
+/// Where every record was saved as a single JSON object, before [`DELETIONS_FILE`]
+const OLD_DELETIONS_FILE: &str = "deletions.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why an account was unbridged
+pub enum DeletionReason {
+    /// By the account or an operator
+    Requested,
+    /// The account was deleted on its home network
+    DeletedAtOrigin(Network),
+}
+
+impl DeletionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletionReason::Requested => "requested",
+            DeletionReason::DeletedAtOrigin(Network::Bluesky) => "deletedOnBluesky",
+            DeletionReason::DeletedAtOrigin(Network::Fediverse) => "deletedOnFediverse",
+        }
+    }
+
+    pub fn parse(reason: &str) -> Option<DeletionReason> {
+        match reason {
+            "requested" => Some(DeletionReason::Requested),
+            "deletedOnBluesky" => Some(DeletionReason::DeletedAtOrigin(Network::Bluesky)),
+            "deletedOnFediverse" => Some(DeletionReason::DeletedAtOrigin(Network::Fediverse)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An audit record of one account being unbridged
+pub struct DeletionRecord {
+    pub did: Did,
+    pub actor: String,
+    pub handle: Option<String>,
+    pub reason: DeletionReason,
+    /// Who asked for it, if anyone did
+    pub requested_by: Option<String>,
+    pub deleted_at: SystemTime,
+    /// The job queued to remove the account's counterpart, if it had one
+    pub removal: Option<&'static str>,
+    pub cancelled_jobs: usize,
+    pub purged_media: usize,
+}
+
+impl DeletionRecord {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("did", Value::from(self.did.as_str())),
+            ("actor", Value::from(self.actor.as_str())),
+            ("handle", Value::from(self.handle.clone())),
+            ("reason", Value::from(self.reason.as_str())),
+            ("requestedBy", Value::from(self.requested_by.clone())),
+            ("deletedAt", Value::from(format_rfc3339(self.deleted_at))),
+            ("removal", Value::from(self.removal)),
+            ("cancelledJobs", Value::from(self.cancelled_jobs)),
+            ("purgedMedia", Value::from(self.purged_media)),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<DeletionRecord> {
+        let field = |name| value.get(name).and_then(Value::as_str);
+        let count = |name| usize::try_from(value.get(name)?.as_i64()?).ok();
+        let removal = match field("removal") {
+            Some("deleteActor") => Some("deleteActor"),
+            Some("deactivateAccount") => Some("deactivateAccount"),
+            _ => None,
+        };
+        Some(DeletionRecord {
+            did: Did::try_create(field("did")?.to_string()).ok()?,
+            actor: field("actor")?.to_string(),
+            handle: field("handle").map(str::to_string),
+            reason: DeletionReason::parse(field("reason")?)?,
+            requested_by: field("requestedBy").map(str::to_string),
+            deleted_at: parse_rfc3339(field("deletedAt")?).ok()?,
+            removal,
+            cancelled_jobs: count("cancelledJobs")?,
+            purged_media: count("purgedMedia")?,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+/// Every account which has been unbridged, oldest first
+pub struct DeletionLog {
+    records: Mutex<Vec<DeletionRecord>>,
+    log: Option<JsonLog>,
+}
+
+impl DeletionLog {
+    /// A log persisted in `dir`, with the records already there
+    ///
+    /// Records saved in the old single `deletions.json` are moved into the log
+    pub fn open(dir: StateDir) -> io::Result<DeletionLog> {
+        let (log, lines) = JsonLog::open(dir.clone(), DELETIONS_FILE)?;
+        let mut records: Vec<DeletionRecord> =
+            lines.iter().filter_map(DeletionRecord::from_json).collect();
+        if let Some(contents) = dir.read(OLD_DELETIONS_FILE)? {
+            let old = json::parse(&String::from_utf8_lossy(&contents))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let moved = old.get("deletions").and_then(Value::as_array);
+            let moved = moved.unwrap_or_default().iter();
+            let moved: Vec<DeletionRecord> = moved.filter_map(DeletionRecord::from_json).collect();
+            records.splice(0..0, moved);
+            log.rewrite(records.iter().map(DeletionRecord::to_json))?;
+            dir.remove(OLD_DELETIONS_FILE)?;
+        }
+        Ok(DeletionLog {
+            records: Mutex::new(records),
+            log: Some(log),
+        })
+    }
+
+    pub fn record(&self, record: DeletionRecord) -> io::Result<()> {
+        let mut records = self.records.lock().unwrap();
+        if let Some(log) = &self.log {
+            log.append([record.to_json()])?;
+        }
+        records.push(record);
+        Ok(())
+    }
+
+    pub fn all(&self) -> Vec<DeletionRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Error)]
+/// Errors unbridging an account
+pub enum UnbridgeError {
+    #[error("{did} isn't bridged")]
+    NotBridged { did: Did },
+    #[error("Can't tell which network {did} is from without an instance actor configured")]
+    UnknownHome { did: Did },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Unbridge `did` entirely, returning the audit record kept of it
+pub fn unbridge(
+    bridge: &Bridge,
+    did: &Did,
+    reason: DeletionReason,
+    requested_by: Option<&str>,
+) -> Result<DeletionRecord, UnbridgeError> {
+    use UnbridgeError::*;
+    let mapping = bridge
+        .identities
+        .get(did)
+        .ok_or_else(|| NotBridged { did: did.clone() })?;
+    let home = match reason {
+        DeletionReason::DeletedAtOrigin(home) => Some(home),
+        DeletionReason::Requested => digest::home_network(bridge, &mapping),
+    };
+    // Passive mappings were never bridged, so there's nothing on the other side to remove
+    let removal = match (mapping.status, home) {
+        (MappingStatus::Passive, _) => None,
+        (_, Some(Network::Bluesky)) => Some(Job::DeleteActor { did: did.clone() }),
+        (_, Some(Network::Fediverse)) => Some(Job::DeactivateAccount { did: did.clone() }),
+        (_, None) => return Err(UnknownHome { did: did.clone() }),
+    };
+    let is_removal =
+        |job: &Job| matches!(job, Job::DeleteActor { .. } | Job::DeactivateAccount { .. });
+    let cancelled_jobs = bridge
+        .jobs
+        .cancel(|job| !is_removal(job) && export::involves(job, &mapping))?;
+    if let Some(job) = &removal {
+        if !bridge.jobs.contains(|queued| queued == job) {
+            bridge.jobs.push(job.clone())?;
+        }
+    }
+    let purged_media = match &bridge.media {
+        Some(media) => media.purge(did)?,
+        None => 0,
+    };
+    bridge.digest_collector.discard(did);
+    let _ = bridge.identities.remove(did);
+    let record = DeletionRecord {
+        did: mapping.did,
+        actor: mapping.actor,
+        handle: mapping.handle,
+        reason,
+        requested_by: requested_by.map(str::to_string),
+        deleted_at: SystemTime::now(),
+        removal: removal.as_ref().map(Job::kind),
+        cancelled_jobs,
+        purged_media,
+    };
+    bridge.deletions.record(record.clone())?;
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delivery::Delivery;
+    use crate::moderation::ModerationConfig;
+    use crate::retention::{MediaStore, RetentionConfig};
+    use crate::storage::tests::temp_state_dir;
+    use crate::store::Mapping;
+    use atproto::did;
+
+    const ALICE: Did = did!("did:plc:alice");
+    const BOB: Did = did!("did:plc:bob");
+    const ALICE_ACTOR: &str = "https://bridge.example/users/alice";
+
+    fn bridge() -> Bridge {
+        let media = MediaStore::open(&temp_state_dir()).unwrap();
+        let bridge = Bridge::new()
+            .with_moderation(ModerationConfig {
+                instance_actor: Some("https://bridge.example/actor".to_string()),
+                ..ModerationConfig::default()
+            })
+            .with_media_store(media, RetentionConfig::default());
+        bridge.identities.insert(Mapping::new(ALICE, ALICE_ACTOR));
+        bridge
+            .identities
+            .insert(Mapping::new(BOB, "https://b.example/users/bob"));
+        bridge
+    }
+
+    #[test]
+    fn unbridging_removes_everything() {
+        let bridge = bridge();
+        let activity = format!(r#"{{"type": "Create", "actor": "{ALICE_ACTOR}"}}"#);
+        let delivery = Delivery::new("https://b.example/inbox", activity);
+        bridge.jobs.push(Job::Deliver(delivery)).unwrap();
+        bridge.jobs.push(Job::SyncProfile { did: BOB }).unwrap();
+        let media = bridge.media.as_ref().unwrap();
+        media.put(&ALICE, "1.jpg", b"jpeg").unwrap();
+
+        let record = unbridge(&bridge, &ALICE, DeletionReason::Requested, Some("admin")).unwrap();
+        assert_eq!(record.removal, Some("deleteActor"));
+        assert_eq!((record.cancelled_jobs, record.purged_media), (1, 1));
+        assert_eq!(bridge.identities.get(&ALICE), None);
+        let queued: Vec<_> = bridge.jobs.queued().into_iter().map(|j| j.job).collect();
+        assert_eq!(
+            queued,
+            [
+                Job::SyncProfile { did: BOB },
+                Job::DeleteActor { did: ALICE }
+            ]
+        );
+        assert_eq!(bridge.deletions.all(), [record]);
+        assert!(matches!(
+            unbridge(&bridge, &ALICE, DeletionReason::Requested, None),
+            Err(UnbridgeError::NotBridged { .. })
+        ));
+
+        // Fediverse accounts have their bridged repo deactivated instead
+        let record = unbridge(&bridge, &BOB, DeletionReason::Requested, None).unwrap();
+        assert_eq!(record.removal, Some("deactivateAccount"));
+    }
+
+    #[test]
+    fn deletion_log_persists() {
+        let dir = temp_state_dir();
+        let log = DeletionLog::open(dir.clone()).unwrap();
+        let record = DeletionRecord {
+            did: ALICE,
+            actor: ALICE_ACTOR.to_string(),
+            handle: Some("alice.example".to_string()),
+            reason: DeletionReason::DeletedAtOrigin(Network::Bluesky),
+            requested_by: None,
+            deleted_at: parse_rfc3339("2024-05-01T12:00:00Z").unwrap(),
+            removal: Some("deleteActor"),
+            cancelled_jobs: 2,
+            purged_media: 0,
+        };
+        log.record(record.clone()).unwrap();
+        let reopened = DeletionLog::open(dir.clone()).unwrap();
+        assert_eq!(reopened.all(), std::slice::from_ref(&record));
+
+        // Records in the old single file are moved into the log, ahead of the newer ones
+        let older = DeletionRecord {
+            did: BOB,
+            ..record.clone()
+        };
+        let old = Value::object([("deletions", Value::Array(vec![older.to_json()]))]);
+        dir.write(OLD_DELETIONS_FILE, old.to_string().as_bytes())
+            .unwrap();
+        DeletionLog::open(dir.clone()).unwrap();
+        assert_eq!(dir.read(OLD_DELETIONS_FILE).unwrap(), None);
+        assert_eq!(DeletionLog::open(dir).unwrap().all(), [older, record]);
+    }
+}
@@ -0,0 +1,353 @@
+//! Failing over between firehose relays and PLC directories
+//!
+//! One relay or directory going down shouldn't stop the bridge, so several of each can be
+//! configured in order of preference (`FEDIBRIDGE_FIREHOSE_RELAYS`,
+//! `FEDIBRIDGE_PLC_DIRECTORIES`). Each is health checked every
+//! [`UpstreamConfig::check_interval`], and the first which answered is used, so the bridge goes
+//! back to a preferred one once it recovers. A failure seen in use ([`Upstreams::record`])
+//! counts straight away rather than at the next check.
+//!
+//! Relays number their events independently, so the firehose cursor only means something to
+//! the relay it came from. The position reached on each is kept in the shard's state
+//! directory, and on switching [`reconcile`] keeps the old relay's and resumes the new one from
+//! where the bridge left it, or from the start of what it keeps if it was never consumed. What
+//! it replays that was handled from the old relay is recognised by commit CID
+//! ([`ProcessedEvents::carry_over`](crate::firehose::ProcessedEvents::carry_over))
+
+use crate::bridge::Bridge;
+use crate::json::{self, Value};
+use crate::resolver::DEFAULT_PLC_DIRECTORY;
+use crate::shutdown::Shutdown;
+use crate::storage::StateDir;
+use crate::time::format_rfc3339;
+use crate::transport::OutboundRequest;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// The positions reached on each relay, and which the cursor is of, in the shard's state dir
+const POSITIONS_FILE: &str = "firehose-relays.json";
+pub const SUBSCRIBE_REPOS: &str = "com.atproto.sync.subscribeRepos";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What an upstream is
+pub enum UpstreamKind {
+    /// A relay the firehose is consumed from
+    Relay,
+    /// A directory `did:plc` documents are resolved from
+    PlcDirectory,
+}
+
+impl UpstreamKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamKind::Relay => "relay",
+            UpstreamKind::PlcDirectory => "plcDirectory",
+        }
+    }
+
+    /// Where an upstream of this kind at `base` answers health checks
+    pub fn health_url(&self, base: &str) -> String {
+        let base = base.trim_end_matches('/');
+        match self {
+            UpstreamKind::Relay => format!("{base}/xrpc/_health"),
+            UpstreamKind::PlcDirectory => format!("{base}/_health"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The relays and directories to choose between
+pub struct UpstreamConfig {
+    /// Base URLs of the relays to consume, most preferred first, e.g. `https://bsky.network`
+    pub relays: Vec<String>,
+    /// Base URLs of the PLC directories, most preferred first
+    pub plc_directories: Vec<String>,
+    /// How often each is health checked
+    pub check_interval: Duration,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            relays: Vec::new(),
+            plc_directories: vec![DEFAULT_PLC_DIRECTORY.to_string()],
+            check_interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    fn of(&self, kind: UpstreamKind) -> &[String] {
+        match kind {
+            UpstreamKind::Relay => &self.relays,
+            UpstreamKind::PlcDirectory => &self.plc_directories,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// How an upstream last answered
+pub struct Health {
+    /// When it was last checked or used
+    pub checked_at: Option<SystemTime>,
+    /// Why it last failed, if it did
+    pub last_error: Option<String>,
+}
+
+impl Health {
+    /// Whether it's worth using: it answered last time, or hasn't been asked yet
+    pub fn healthy(&self) -> bool {
+        self.last_error.is_none()
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("healthy", Value::from(self.healthy())),
+            (
+                "checkedAt",
+                Value::from(self.checked_at.map(format_rfc3339)),
+            ),
+            ("lastError", Value::from(self.last_error.clone())),
+        ])
+    }
+}
+
+#[derive(Debug, Default)]
+/// The configured upstreams and how each is doing
+pub struct Upstreams {
+    config: UpstreamConfig,
+    health: Mutex<HashMap<String, Health>>,
+}
+
+impl Upstreams {
+    pub fn new(config: UpstreamConfig) -> Upstreams {
+        Upstreams {
+            config,
+            health: Mutex::default(),
+        }
+    }
+
+    pub fn config(&self) -> &UpstreamConfig {
+        &self.config
+    }
+
+    pub fn health(&self, upstream: &str) -> Health {
+        let health = self.health.lock().unwrap();
+        health.get(upstream).cloned().unwrap_or_default()
+    }
+
+    /// The most preferred healthy upstream of `kind`, or the most preferred if none are
+    pub fn active(&self, kind: UpstreamKind) -> Option<&str> {
+        let upstreams = self.config.of(kind);
+        let healthy = upstreams.iter().find(|u| self.health(u).healthy());
+        healthy.or(upstreams.first()).map(String::as_str)
+    }
+
+    /// Note how `upstream` answered at `now`
+    pub fn record(&self, upstream: &str, at: SystemTime, result: Result<(), String>) {
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry(upstream.to_string()).or_default();
+        health.checked_at = Some(at);
+        health.last_error = result.err();
+    }
+
+    pub fn to_json(&self) -> Value {
+        let kind = |kind: UpstreamKind| {
+            let upstreams = self.config.of(kind).iter().map(|upstream| {
+                let mut health = self.health(upstream).to_json();
+                if let Value::Object(fields) = &mut health {
+                    fields.insert("url".into(), Value::from(upstream.as_str()));
+                }
+                health
+            });
+            Value::object([
+                ("active", Value::from(self.active(kind))),
+                ("upstreams", Value::Array(upstreams.collect())),
+            ])
+        };
+        Value::object([
+            ("relays", kind(UpstreamKind::Relay)),
+            ("plcDirectories", kind(UpstreamKind::PlcDirectory)),
+        ])
+    }
+}
+
+/// Health check every configured upstream of `bridge` at `now`
+pub fn check(bridge: &Bridge, now: SystemTime) {
+    let config = bridge.upstreams.config();
+    for kind in [UpstreamKind::Relay, UpstreamKind::PlcDirectory] {
+        for upstream in config.of(kind) {
+            let url = kind.health_url(upstream);
+            let request = OutboundRequest::get(&url).with_header("accept", "application/json");
+            let result = match bridge.transport.send(&request) {
+                Ok(response) if response.is_success() => Ok(()),
+                Ok(response) => Err(format!("{url} responded with {}", response.status)),
+                Err(e) => Err(format!("Couldn't reach {url}: {e}")),
+            };
+            let was_healthy = bridge.upstreams.health(upstream).healthy();
+            if let (true, Err(e)) = (was_healthy, &result) {
+                eprintln!("The {} {upstream} is unhealthy: {e}", kind.as_str());
+            }
+            bridge.upstreams.record(upstream, now, result);
+        }
+    }
+}
+
+/// Where to subscribe to the firehose of `relay` from `cursor`
+pub fn subscription(relay: &str, cursor: i64) -> String {
+    let relay = relay.trim_end_matches('/');
+    let relay = match relay.split_once("://") {
+        Some(("http", rest)) => format!("ws://{rest}"),
+        Some(("https", rest)) => format!("wss://{rest}"),
+        _ => relay.to_string(),
+    };
+    format!("{relay}/xrpc/{SUBSCRIBE_REPOS}?cursor={cursor}")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A change of the relay the firehose is consumed from
+pub struct Switch {
+    pub from: String,
+    pub to: String,
+    /// The position on the new relay the cursor was set to
+    pub resume: i64,
+}
+
+/// Point the firehose cursor at the active relay, keeping the position reached on the one
+/// before in the shard's state directory `dir`. Returns the switch, if there was one
+pub fn reconcile(bridge: &Bridge, dir: &StateDir) -> io::Result<Option<Switch>> {
+    let Some(active) = bridge.upstreams.active(UpstreamKind::Relay) else {
+        return Ok(None);
+    };
+    let saved = dir
+        .read(POSITIONS_FILE)?
+        .and_then(|contents| json::parse(&String::from_utf8_lossy(&contents)).ok());
+    let current = saved
+        .as_ref()
+        .and_then(|saved| saved.get("current")?.as_str());
+    let mut positions: BTreeMap<String, Value> =
+        match saved.as_ref().and_then(|s| s.get("positions")) {
+            Some(Value::Object(positions)) => positions.clone(),
+            _ => BTreeMap::new(),
+        };
+    let switch = match current {
+        Some(current) if current == active => return Ok(None),
+        Some(current) => {
+            let position = bridge.firehose.position();
+            positions.insert(current.to_string(), Value::from(position));
+            let resume = positions.get(active).and_then(Value::as_i64);
+            let resume = resume.unwrap_or_default();
+            bridge.processed_events.carry_over()?;
+            bridge.firehose.reset(resume);
+            bridge.firehose.save(dir)?;
+            Some(Switch {
+                from: current.to_string(),
+                to: active.to_string(),
+                resume,
+            })
+        }
+        // The cursor so far is of the relay in use now
+        None => None,
+    };
+    let saved = Value::object([
+        ("current", Value::from(active)),
+        ("positions", Value::Object(positions)),
+    ]);
+    dir.write(POSITIONS_FILE, saved.to_string().as_bytes())?;
+    Ok(switch)
+}
+
+/// Start a thread health checking the upstreams and reconciling the cursor of the shard's
+/// state directory `dir` with the active relay, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, dir: StateDir, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_check = None;
+        while !shutdown.is_requested() {
+            let interval = bridge.upstreams.config().check_interval;
+            if last_check.is_some_and(|at: Instant| at.elapsed() < interval) {
+                thread::sleep(Duration::from_millis(250));
+                continue;
+            }
+            last_check = Some(Instant::now());
+            check(&bridge, SystemTime::now());
+            match reconcile(&bridge, &dir) {
+                Ok(Some(switch)) => eprintln!(
+                    "Switched the firehose from {} to {}, resuming from {}",
+                    switch.from, switch.to, switch.resume
+                ),
+                Ok(None) => {}
+                Err(e) => eprintln!("Couldn't reconcile the firehose cursor: {e}"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::car::Cid;
+    use crate::firehose::{FirehoseCursor, ProcessedEvents};
+    use crate::http::Method;
+    use crate::storage::tests::temp_state_dir;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    const PRIMARY: &str = "https://relay.example";
+    const FALLBACK: &str = "https://fallback.example";
+
+    #[test]
+    fn fails_over_and_back_keeping_each_relays_position() {
+        let mock = Arc::new(MockTransport::new());
+        let healthy = |url: &str| mock.respond_json(url, r#"{"version": "1"}"#);
+        healthy("https://fallback.example/xrpc/_health");
+        healthy("https://plc.example/_health");
+        let dir = temp_state_dir();
+        let bridge = Bridge {
+            processed_events: ProcessedEvents::open(dir.clone()).unwrap(),
+            ..Bridge::new().with_transport(mock.clone())
+        }
+        .with_upstreams(UpstreamConfig {
+            relays: vec![PRIMARY.to_string(), FALLBACK.to_string()],
+            plc_directories: vec![
+                "https://down.example".to_string(),
+                "https://plc.example".to_string(),
+            ],
+            ..UpstreamConfig::default()
+        });
+        let relay = || bridge.upstreams.active(UpstreamKind::Relay);
+        assert_eq!(relay(), Some(PRIMARY), "unchecked relays are worth a try");
+        assert_eq!(reconcile(&bridge, &dir).unwrap(), None);
+        bridge.firehose.processed(100);
+        let commit = Cid::for_raw(b"commit");
+        assert!(bridge.processed_events.claim(100, &commit));
+        bridge.processed_events.handled(100, &commit).unwrap();
+
+        check(&bridge, SystemTime::now());
+        assert_eq!(relay(), Some(FALLBACK));
+        assert_eq!(bridge.resolver().plc_directory(), "https://plc.example");
+        let switch = reconcile(&bridge, &dir).unwrap().unwrap();
+        assert_eq!((switch.to.as_str(), switch.resume), (FALLBACK, 0));
+        assert_eq!(FirehoseCursor::load(&dir).unwrap().position(), 0);
+        // The fallback replays the commit under its own sequence number
+        assert!(!bridge.processed_events.claim(7, &commit));
+        let reopened = ProcessedEvents::open(dir.clone()).unwrap();
+        assert!(!reopened.claim(7, &commit));
+        bridge.firehose.processed(40);
+
+        mock.respond(
+            Method::Get,
+            "https://relay.example/xrpc/_health",
+            OutboundResponse::new(200),
+        );
+        check(&bridge, SystemTime::now());
+        let switch = reconcile(&bridge, &dir).unwrap().unwrap();
+        assert_eq!((switch.to.as_str(), switch.resume), (PRIMARY, 100));
+        assert_eq!(bridge.firehose.position(), 100);
+        assert_eq!(
+            subscription(PRIMARY, 100),
+            "wss://relay.example/xrpc/com.atproto.sync.subscribeRepos?cursor=100"
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Just enough URL parsing for outbound requests
+//!
+//! Only absolute `http`/`https` URLs with a host are accepted; userinfo is rejected outright
+//! since nothing the bridge talks to should need it and it's a common way to disguise a host
+
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Error, PartialEq)]
+/// Errors parsing a URL
+pub enum UrlError {
+    #[error("Expected an http or https URL - found {found}")]
+    UnsupportedScheme { found: String },
+    #[error("URL has no host")]
+    MissingHost,
+    #[error("URLs containing credentials are not allowed")]
+    Credentials,
+    #[error("Invalid port - found {found}")]
+    InvalidPort { found: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// A parsed absolute URL
+pub struct Url {
+    /// Either `http` or `https`
+    pub scheme: String,
+    /// Lowercased host, with IPv6 literals kept in their brackets
+    pub host: String,
+    pub port: Option<u16>,
+    /// The path and query, always starting with `/`
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url, UrlError> {
+        use UrlError::*;
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| UnsupportedScheme {
+            found: url.to_string(),
+        })?;
+        let scheme = scheme.to_ascii_lowercase();
+        if scheme != "http" && scheme != "https" {
+            return Err(UnsupportedScheme { found: scheme });
+        }
+        // Fragments are never sent to the server
+        let rest = rest.split('#').next().unwrap_or_default();
+        let split = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, path) = rest.split_at(split);
+        if authority.contains('@') {
+            return Err(Credentials);
+        }
+        let (host, port) = match authority.rfind(':') {
+            // A colon inside an IPv6 literal isn't a port separator
+            Some(i) if !authority[i..].contains(']') => {
+                let port = &authority[i + 1..];
+                let port = port.parse().map_err(|_| InvalidPort {
+                    found: port.to_string(),
+                })?;
+                (&authority[..i], Some(port))
+            }
+            _ => (authority, None),
+        };
+        if host.is_empty() {
+            return Err(MissingHost);
+        }
+        let path = match path {
+            "" => "/".to_string(),
+            p if p.starts_with('?') => format!("/{p}"),
+            p => p.to_string(),
+        };
+        Ok(Url {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+
+    fn default_port(&self) -> u16 {
+        if self.scheme == "https" {
+            443
+        } else {
+            80
+        }
+    }
+
+    /// The port to connect to, using the scheme's default if none was given
+    pub fn port_or_default(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.default_port())
+    }
+
+    /// The host with the port if it isn't the scheme default, as used in a `Host` header
+    pub fn authority(&self) -> String {
+        match self.port {
+            Some(port) if port != self.default_port() => format!("{}:{port}", self.host),
+            _ => self.host.clone(),
+        }
+    }
+
+    /// The `scheme://authority` prefix, useful as a per-origin key
+    pub fn origin(&self) -> String {
+        format!("{}://{}", self.scheme, self.authority())
+    }
+
+    /// Resolve a possibly relative reference, such as a `Location` header, against this URL
+    ///
+    /// Dot segments are left as they are, which servers resolve for themselves
+    pub fn join(&self, reference: &str) -> Result<Url, UrlError> {
+        let reference = reference.trim();
+        if reference.contains("://") {
+            return Url::parse(reference);
+        }
+        if reference.starts_with("//") {
+            return Url::parse(&format!("{}:{reference}", self.scheme));
+        }
+        let path = self.path.split('?').next().unwrap_or_default();
+        let joined = match reference {
+            "" => self.path.clone(),
+            r if r.starts_with('/') => r.to_string(),
+            r if r.starts_with('?') => format!("{path}{r}"),
+            r => format!("{}{r}", &path[..path.rfind('/').map_or(0, |i| i + 1)]),
+        };
+        Url::parse(&format!("{}{joined}", self.origin()))
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.origin(), self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_full() {
+        let url = Url::parse("HTTPS://Mastodon.Social:8443/users/alice?x=1#frag").unwrap();
+        assert_eq!(url.scheme, "https");
+        assert_eq!(url.host, "mastodon.social");
+        assert_eq!(url.port, Some(8443));
+        assert_eq!(url.path, "/users/alice?x=1");
+        assert_eq!(
+            url.to_string(),
+            "https://mastodon.social:8443/users/alice?x=1"
+        );
+    }
+
+    #[test]
+    fn defaults_and_ipv6() {
+        let url = Url::parse("http://[::1]").unwrap();
+        assert_eq!(url.host, "[::1]");
+        assert_eq!(url.port_or_default(), 80);
+        assert_eq!(url.path, "/");
+        assert_eq!(
+            Url::parse("https://a.example:443/").unwrap().authority(),
+            "a.example"
+        );
+    }
+
+    #[test]
+    fn join() {
+        let base = Url::parse("https://a.example/posts/1?page=2").unwrap();
+        let join = |reference| base.join(reference).unwrap().to_string();
+        assert_eq!(join("/about"), "https://a.example/about");
+        assert_eq!(join("2"), "https://a.example/posts/2");
+        assert_eq!(join("?page=3"), "https://a.example/posts/1?page=3");
+        assert_eq!(join("//b.example/x"), "https://b.example/x");
+        assert_eq!(join("http://c.example"), "http://c.example/");
+        assert_eq!(
+            base.join("ftp://c.example"),
+            Err(UrlError::UnsupportedScheme {
+                found: "ftp".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_bad_urls() {
+        assert!(matches!(
+            Url::parse("ftp://a.example"),
+            Err(UrlError::UnsupportedScheme { .. })
+        ));
+        assert_eq!(
+            Url::parse("https://user@a.example"),
+            Err(UrlError::Credentials)
+        );
+        assert_eq!(Url::parse("https:///path"), Err(UrlError::MissingHost));
+        assert!(matches!(
+            Url::parse("https://a.example:99999"),
+            Err(UrlError::InvalidPort { .. })
+        ));
+    }
+}
@@ -0,0 +1,356 @@
+//! Webhooks notifying operators' tooling of bridge events
+//!
+//! Each configured URL is POSTed a JSON object with the event's `type`, when it
+//! happened (`at`) and its details, for:
+//!
+//! - `optIn`: an account started being bridged
+//! - `approvalRequested`: an account asked to be bridged, and is waiting for
+//!   [approval](crate::approval)
+//! - `deliveryFailed`: a delivery ran out of retries and is dead
+//! - `firehoseLag`: the firehose fell further behind than the threshold. It fires once per
+//!   excursion, when the lag first crosses the threshold
+//! - `policyViolation`: a content filter refused a post
+//!
+//! Notifications are queued as [`Job::Notify`] jobs, so a webhook which is down gets them
+//! later. With a secret configured, each body is signed with HMAC-SHA256, sent hex-encoded
+//! as `X-Fedibridge-Signature: sha256=<signature>`
+
+use crate::bridge::Bridge;
+use crate::crypto::{hex_encode, hmac_sha256};
+use crate::jobs::Job;
+use crate::json::Value;
+use crate::shutdown::Shutdown;
+use crate::time::format_rfc3339;
+use crate::transport::{HttpTransport, OutboundRequest, TransportError};
+use atproto::DID::Did;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+
+/// Carries the body's signature, when a secret is configured
+pub const SIGNATURE_HEADER: &str = "x-fedibridge-signature";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The kinds of event webhooks can be sent
+pub enum EventKind {
+    OptIn,
+    ApprovalRequested,
+    DeliveryFailed,
+    FirehoseLag,
+    PolicyViolation,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 5] = [
+        EventKind::OptIn,
+        EventKind::ApprovalRequested,
+        EventKind::DeliveryFailed,
+        EventKind::FirehoseLag,
+        EventKind::PolicyViolation,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::OptIn => "optIn",
+            EventKind::ApprovalRequested => "approvalRequested",
+            EventKind::DeliveryFailed => "deliveryFailed",
+            EventKind::FirehoseLag => "firehoseLag",
+            EventKind::PolicyViolation => "policyViolation",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EventKind> {
+        EventKind::ALL.into_iter().find(|k| k.as_str() == kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Something which happened that operators may want to know about
+pub enum WebhookEvent {
+    OptIn {
+        did: Did,
+        actor: String,
+    },
+    ApprovalRequested {
+        did: Did,
+        actor: String,
+    },
+    DeliveryFailed {
+        job: u64,
+        inbox: String,
+        attempts: u32,
+        error: Option<String>,
+    },
+    FirehoseLag {
+        lag: i64,
+        threshold: i64,
+    },
+    PolicyViolation {
+        /// The actor or DID whose post was refused
+        author: String,
+        object: Option<String>,
+        filter: String,
+        reason: String,
+    },
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            WebhookEvent::OptIn { .. } => EventKind::OptIn,
+            WebhookEvent::ApprovalRequested { .. } => EventKind::ApprovalRequested,
+            WebhookEvent::DeliveryFailed { .. } => EventKind::DeliveryFailed,
+            WebhookEvent::FirehoseLag { .. } => EventKind::FirehoseLag,
+            WebhookEvent::PolicyViolation { .. } => EventKind::PolicyViolation,
+        }
+    }
+
+    pub fn to_json(&self, at: SystemTime) -> Value {
+        let mut fields = vec![
+            ("type", Value::from(self.kind().as_str())),
+            ("at", Value::from(format_rfc3339(at))),
+        ];
+        match self {
+            WebhookEvent::OptIn { did, actor } | WebhookEvent::ApprovalRequested { did, actor } => {
+                fields.push(("did", Value::from(did.as_str())));
+                fields.push(("actor", Value::from(actor.as_str())));
+            }
+            WebhookEvent::DeliveryFailed {
+                job,
+                inbox,
+                attempts,
+                error,
+            } => {
+                fields.push(("job", Value::from(*job)));
+                fields.push(("inbox", Value::from(inbox.as_str())));
+                fields.push(("attempts", Value::from(*attempts)));
+                fields.push(("error", Value::from(error.clone())));
+            }
+            WebhookEvent::FirehoseLag { lag, threshold } => {
+                fields.push(("lag", Value::from(*lag)));
+                fields.push(("threshold", Value::from(*threshold)));
+            }
+            WebhookEvent::PolicyViolation {
+                author,
+                object,
+                filter,
+                reason,
+            } => {
+                fields.push(("author", Value::from(author.as_str())));
+                fields.push(("object", Value::from(object.clone())));
+                fields.push(("filter", Value::from(filter.as_str())));
+                fields.push(("reason", Value::from(reason.as_str())));
+            }
+        }
+        Value::object(fields)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Where webhooks are sent, and for what
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    /// The events sent. Empty sends every kind
+    pub events: Vec<EventKind>,
+    /// Bodies are signed with this, when set
+    pub secret: Option<String>,
+    /// How many events behind the firehose may fall before `firehoseLag` fires
+    pub lag_threshold: Option<i64>,
+    /// How often the firehose lag is checked
+    pub check_interval: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            urls: Vec::new(),
+            events: Vec::new(),
+            secret: None,
+            lag_threshold: Some(10_000),
+            check_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl WebhookConfig {
+    pub fn wants(&self, kind: EventKind) -> bool {
+        !self.urls.is_empty() && (self.events.is_empty() || self.events.contains(&kind))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A webhook body waiting to be sent
+pub struct Notification {
+    pub url: String,
+    pub body: String,
+}
+
+#[derive(Debug, Error)]
+/// Errors sending a webhook
+pub enum WebhookError {
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error("Webhook responded with {status}")]
+    Rejected { status: u16 },
+}
+
+/// Queue `event` for every webhook which wants it
+///
+/// The event has already happened, so failing to queue it is only logged
+pub fn notify(bridge: &Bridge, event: WebhookEvent) {
+    if !bridge.webhooks.wants(event.kind()) {
+        return;
+    }
+    let body = event.to_json(SystemTime::now()).to_string();
+    for url in &bridge.webhooks.urls {
+        let notification = Notification {
+            url: url.clone(),
+            body: body.clone(),
+        };
+        if let Err(e) = bridge.jobs.push(Job::Notify(notification)) {
+            eprintln!("Couldn't queue {} webhook: {e}", event.kind().as_str());
+        }
+    }
+}
+
+/// POST a notification, signed if `config` has a secret
+pub fn send(
+    transport: &dyn HttpTransport,
+    config: &WebhookConfig,
+    notification: &Notification,
+) -> Result<(), WebhookError> {
+    let mut request = OutboundRequest::post(&notification.url, notification.body.clone())
+        .with_header("content-type", "application/json");
+    if let Some(secret) = &config.secret {
+        let signature = hmac_sha256(secret.as_bytes(), notification.body.as_bytes());
+        let signature = format!("sha256={}", hex_encode(&signature));
+        request = request.with_header(SIGNATURE_HEADER, &signature);
+    }
+    let response = transport.send(&request)?;
+    if !response.is_success() {
+        return Err(WebhookError::Rejected {
+            status: response.status,
+        });
+    }
+    Ok(())
+}
+
+/// The lag event to send, if the firehose has just fallen behind. `lagging` tracks whether
+/// it already had, so each excursion is only reported once
+pub fn check_lag(bridge: &Bridge, lagging: &mut bool) -> Option<WebhookEvent> {
+    let threshold = bridge.webhooks.lag_threshold?;
+    let lag = bridge.firehose.lag();
+    let was_lagging = std::mem::replace(lagging, lag > threshold);
+    (*lagging && !was_lagging).then_some(WebhookEvent::FirehoseLag { lag, threshold })
+}
+
+/// Start a thread watching the firehose lag, until shutdown
+pub fn spawn(bridge: Arc<Bridge>, shutdown: Arc<Shutdown>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut last_check = Instant::now();
+        let mut lagging = false;
+        while !shutdown.is_requested() {
+            thread::sleep(Duration::from_millis(250));
+            if last_check.elapsed() < bridge.webhooks.check_interval {
+                continue;
+            }
+            last_check = Instant::now();
+            if let Some(event) = check_lag(&bridge, &mut lagging) {
+                notify(&bridge, event);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use crate::json;
+    use crate::transport::{MockTransport, OutboundResponse};
+
+    const HOOK: &str = "https://alerts.example/hook";
+
+    fn bridge(events: Vec<EventKind>) -> Bridge {
+        Bridge::new().with_webhooks(WebhookConfig {
+            urls: vec![HOOK.to_string()],
+            events,
+            secret: Some("s3cret".to_string()),
+            lag_threshold: Some(100),
+            ..WebhookConfig::default()
+        })
+    }
+
+    fn queued(bridge: &Bridge) -> Vec<Notification> {
+        let jobs = bridge.jobs.queued().into_iter();
+        jobs.filter_map(|queued| match queued.job {
+            Job::Notify(notification) => Some(notification),
+            _ => None,
+        })
+        .collect()
+    }
+
+    #[test]
+    fn sends_wanted_events_signed() {
+        let bridge = bridge(vec![EventKind::OptIn]);
+        let did = atproto::did!("did:plc:alice");
+        notify(
+            &bridge,
+            WebhookEvent::OptIn {
+                did,
+                actor: "https://bridge.example/users/alice".to_string(),
+            },
+        );
+        notify(
+            &bridge,
+            WebhookEvent::FirehoseLag {
+                lag: 1,
+                threshold: 0,
+            },
+        );
+        let notifications = queued(&bridge);
+        assert_eq!(notifications.len(), 1);
+        let body = json::parse(&notifications[0].body).unwrap();
+        assert_eq!(body.get("type"), Some(&Value::from("optIn")));
+        assert_eq!(body.get("did"), Some(&Value::from("did:plc:alice")));
+
+        let mock = MockTransport::new();
+        mock.respond(Method::Post, HOOK, OutboundResponse::new(204))
+            .respond(Method::Post, HOOK, OutboundResponse::new(500));
+        send(&mock, &bridge.webhooks, &notifications[0]).unwrap();
+        let signature = hmac_sha256(b"s3cret", notifications[0].body.as_bytes());
+        let sent = mock.requests_to(HOOK);
+        assert_eq!(
+            sent[0].header(SIGNATURE_HEADER),
+            Some(format!("sha256={}", hex_encode(&signature)).as_str())
+        );
+        assert!(matches!(
+            send(&mock, &bridge.webhooks, &notifications[0]),
+            Err(WebhookError::Rejected { status: 500 })
+        ));
+    }
+
+    #[test]
+    fn lag_fires_once_per_excursion() {
+        let bridge = bridge(Vec::new());
+        let mut lagging = false;
+        bridge.firehose.saw(50);
+        assert_eq!(check_lag(&bridge, &mut lagging), None);
+        bridge.firehose.saw(150);
+        assert_eq!(
+            check_lag(&bridge, &mut lagging),
+            Some(WebhookEvent::FirehoseLag {
+                lag: 150,
+                threshold: 100
+            })
+        );
+        bridge.firehose.saw(300);
+        assert_eq!(check_lag(&bridge, &mut lagging), None);
+        bridge.firehose.processed(290);
+        assert_eq!(check_lag(&bridge, &mut lagging), None);
+        assert!(!lagging);
+        bridge.firehose.saw(500);
+        assert!(check_lag(&bridge, &mut lagging).is_some());
+    }
+}
@@ -0,0 +1,394 @@
+//! WebSocket connections
+//!
+//! Just enough of RFC 6455 for the bridge to push messages to subscribers: the opening
+//! handshake, unmasked binary and text frames out, and reading what the client sends only to
+//! answer pings and notice when it closes. With the `net` feature, [`connect`] opens one the
+//! other way, as to a relay's firehose, reading the server's messages through a [`Client`]
+
+use crate::crypto::{base64_encode, sha1};
+use crate::http::{Request, Response, Upgrade};
+use crate::shutdown::Shutdown;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+#[cfg(feature = "net")]
+use {
+    crate::crypto::random_bytes, crate::tls::Stream, crate::transport::HttpTransport,
+    crate::url::Url,
+};
+
+/// Appended to the client's key before hashing it into the accept header
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Client frames larger than this are refused rather than buffered
+const MAX_CLIENT_FRAME: usize = 64 * 1024;
+/// Messages from a server larger than this are refused, though firehose commits are far
+/// smaller
+const MAX_SERVER_MESSAGE: usize = 16 * 1024 * 1024;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Status codes for closing a connection
+pub const GOING_AWAY: u16 = 1001;
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// The value of `Sec-WebSocket-Accept` answering `key`
+pub fn accept_key(key: &str) -> String {
+    base64_encode(&sha1(format!("{key}{HANDSHAKE_GUID}").as_bytes()))
+}
+
+/// Answer a WebSocket handshake, handing the connection to `run` once it's open
+pub fn accept(
+    request: &Request,
+    run: impl Fn(WebSocket, &Shutdown) -> io::Result<()> + Send + Sync + 'static,
+) -> Response {
+    let upgrading = request
+        .header("upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let Some(key) = request.header("sec-websocket-key").filter(|_| upgrading) else {
+        return Response::error(426, "Expected a WebSocket upgrade")
+            .with_header("upgrade", "websocket");
+    };
+    let upgrade = Upgrade::new(move |stream, shutdown| run(WebSocket::new(stream), shutdown));
+    Response::switching_protocols(upgrade)
+        .with_header("upgrade", "websocket")
+        .with_header("sec-websocket-accept", &accept_key(key))
+}
+
+/// An open WebSocket connection
+pub struct WebSocket {
+    stream: TcpStream,
+    /// Bytes read from the client that don't make up a whole frame yet
+    incoming: Vec<u8>,
+}
+
+impl WebSocket {
+    fn new(stream: TcpStream) -> WebSocket {
+        WebSocket {
+            stream,
+            incoming: Vec::new(),
+        }
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&frame(opcode, payload, None))?;
+        self.stream.flush()
+    }
+
+    /// Send a binary message
+    pub fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.write_frame(BINARY, message)
+    }
+
+    /// Send a text message
+    pub fn send_text(&mut self, message: &str) -> io::Result<()> {
+        self.write_frame(TEXT, message.as_bytes())
+    }
+
+    /// Tell the client the connection is closing, with `code` saying why
+    pub fn close(mut self, code: u16) -> io::Result<()> {
+        self.write_frame(CLOSE, &code.to_be_bytes())
+    }
+
+    /// Handle whatever the client has sent, without waiting for more. Returns false once the
+    /// client has closed the connection
+    pub fn poll(&mut self) -> io::Result<bool> {
+        self.stream
+            .set_read_timeout(Some(Duration::from_millis(1)))?;
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.incoming.extend_from_slice(&buffer[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(e),
+            }
+            if self.incoming.len() > MAX_CLIENT_FRAME + 14 {
+                break;
+            }
+        }
+        while let Some((opcode, payload)) = self.next_frame()? {
+            match opcode {
+                CLOSE => return Ok(false),
+                PING => self.write_frame(PONG, &payload)?,
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+
+    /// Take the next whole frame from what's been read, unmasking it
+    fn next_frame(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        match take_frame(&mut self.incoming, MAX_CLIENT_FRAME) {
+            Ok(frame) => Ok(frame.map(|(_, opcode, payload)| (opcode, payload))),
+            Err(e) => {
+                let _ = self.write_frame(CLOSE, &MESSAGE_TOO_BIG.to_be_bytes());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A frame of `payload`, masked with `mask` if it's given, as a client's must be
+fn frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(masked | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(masked | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend(mask);
+            frame.extend(
+                payload
+                    .iter()
+                    .enumerate()
+                    .map(|(i, byte)| byte ^ mask[i % 4]),
+            );
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    frame
+}
+
+/// Take the next whole frame from `incoming`, unmasking it, as whether it's the last of its
+/// message, its opcode and its payload. A frame longer than `max` is an error
+fn take_frame(incoming: &mut Vec<u8>, max: usize) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+    let bytes = &*incoming;
+    if bytes.len() < 2 {
+        return Ok(None);
+    }
+    let (len, mut offset) = match bytes[1] & 0x7f {
+        126 if bytes.len() >= 4 => (u16::from_be_bytes([bytes[2], bytes[3]]) as u64, 4),
+        127 if bytes.len() >= 10 => (u64::from_be_bytes(bytes[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if len > max as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "WebSocket frame too large",
+        ));
+    }
+    let masked = bytes[1] & 0x80 != 0;
+    let mask_len = if masked { 4 } else { 0 };
+    if bytes.len() < offset + mask_len + len as usize {
+        return Ok(None);
+    }
+    let mask: Vec<u8> = bytes[offset..offset + mask_len].to_vec();
+    offset += mask_len;
+    let fin = bytes[0] & 0x80 != 0;
+    let opcode = bytes[0] & 0x0f;
+    let mut payload = bytes[offset..offset + len as usize].to_vec();
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+    incoming.drain(..offset + len as usize);
+    Ok(Some((fin, opcode, payload)))
+}
+
+#[cfg(feature = "net")]
+/// Open a WebSocket to the `ws` or `wss` `url` through `transport`
+///
+/// Reads wait at most `wait`, so the caller can look up from [`Client::receive`] that often
+pub fn connect(transport: &dyn HttpTransport, url: &str, wait: Duration) -> io::Result<Client> {
+    let url = match url.split_once("://") {
+        Some(("ws", rest)) => format!("http://{rest}"),
+        Some(("wss", rest)) => format!("https://{rest}"),
+        _ => url.to_string(),
+    };
+    let parsed = Url::parse(&url).map_err(io::Error::other)?;
+    let mut stream = transport.open(&url).map_err(io::Error::other)?;
+    let mut nonce = [0; 16];
+    random_bytes(&mut nonce)?;
+    let key = base64_encode(&nonce);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        parsed.path,
+        parsed.authority()
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+    // Read a byte at a time, so nothing the server sends after its response is read with it
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 || head.len() > MAX_CLIENT_FRAME {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The WebSocket handshake wasn't answered",
+            ));
+        }
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let status = lines.next().and_then(|line| line.split(' ').nth(1));
+    let accepted = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+        .map(|(_, value)| value.trim());
+    if status != Some("101") || accepted != Some(accept_key(&key).as_str()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{url} refused the WebSocket handshake"),
+        ));
+    }
+    stream.socket().set_read_timeout(Some(wait))?;
+    Ok(Client {
+        stream,
+        incoming: Vec::new(),
+        message: None,
+    })
+}
+
+#[cfg(feature = "net")]
+/// A WebSocket connection the bridge opened
+pub struct Client {
+    stream: Box<dyn Stream>,
+    /// Bytes read from the server that don't make up a whole frame yet
+    incoming: Vec<u8>,
+    /// The opcode and payload so far of a message whose last frame hasn't arrived yet
+    message: Option<(u8, Vec<u8>)>,
+}
+
+#[cfg(feature = "net")]
+impl Client {
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut mask = [0; 4];
+        random_bytes(&mut mask)?;
+        self.stream.write_all(&frame(opcode, payload, Some(mask)))?;
+        self.stream.flush()
+    }
+
+    /// The next message the server sends, binary or text, or `None` if none arrived within
+    /// the wait it was connected with. Pings are answered along the way, and the server
+    /// closing the connection is an error
+    pub fn receive(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            while let Some((fin, opcode, payload)) =
+                take_frame(&mut self.incoming, MAX_SERVER_MESSAGE)?
+            {
+                let message = match (opcode, self.message.take()) {
+                    (PING, message) => {
+                        self.message = message;
+                        self.write_frame(PONG, &payload)?;
+                        continue;
+                    }
+                    (CLOSE, _) => {
+                        let _ = self.write_frame(CLOSE, &payload);
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionAborted,
+                            "The server closed the WebSocket",
+                        ));
+                    }
+                    (CONTINUATION, Some((opcode, mut message))) => {
+                        message.extend(payload);
+                        (opcode, message)
+                    }
+                    (TEXT | BINARY, None) => (opcode, payload),
+                    (PONG, message) => {
+                        self.message = message;
+                        continue;
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Unexpected WebSocket frame {opcode:#x}"),
+                        ))
+                    }
+                };
+                if message.1.len() > MAX_SERVER_MESSAGE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "WebSocket message too large",
+                    ));
+                }
+                if fin {
+                    return Ok(Some(message.1));
+                }
+                self.message = Some(message);
+            }
+            let mut buffer = [0; 16 * 1024];
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.incoming.extend_from_slice(&buffer[..n]),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Method;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn handshake_and_frames() {
+        // The example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(
+            accept(&Request::new(Method::Get, "/"), |_, _| Ok(())).status,
+            426
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let server = thread::spawn(move || {
+            let mut socket = WebSocket::new(listener.accept().unwrap().0);
+            socket.send(&[7; 300]).unwrap();
+            while socket.poll().unwrap() {}
+        });
+        let mut header = [0; 4];
+        client.read_exact(&mut header).unwrap();
+        assert_eq!(header, [0x82, 126, 0x01, 0x2c]);
+        client.read_exact(&mut [0; 300]).unwrap();
+        // A masked ping, answered with a pong, then a close
+        let mask = [1, 2, 3, 4];
+        let ping: Vec<u8> = b"hi".iter().zip(mask).map(|(b, m)| b ^ m).collect();
+        client
+            .write_all(&[[0x89, 0x82].as_slice(), &mask, &ping].concat())
+            .unwrap();
+        let mut pong = [0; 4];
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8a, 2, b'h', b'i']);
+        client.write_all(&[0x88, 0x80, 0, 0, 0, 0]).unwrap();
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+//! A mock ActivityPub server
+//!
+//! Serves actors at `/users/{name}`, resolves them over WebFinger, and records everything
+//! POSTed to their inboxes (or the shared `/inbox`) for tests to assert on
+
+use crate::server::MockServer;
+use fedibridge::delivery::ACTIVITY_JSON;
+use fedibridge::http::{Method, Request, Response};
+use fedibridge::json::{self, Value};
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+/// An activity POSTed to one of the server's inboxes
+pub struct ReceivedActivity {
+    /// The inbox's path, e.g. `/users/alice/inbox` or `/inbox`
+    pub inbox: String,
+    pub headers: Vec<(String, String)>,
+    pub activity: Value,
+}
+
+impl ReceivedActivity {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Like [`Response::json`] but with a more specific content type
+fn json_as(status: u16, value: &Value, content_type: &str) -> Response {
+    Response::new(status)
+        .with_header("content-type", content_type)
+        .with_body(value.to_string())
+}
+
+#[derive(Default)]
+struct Shared {
+    base: OnceLock<String>,
+    actors: Mutex<BTreeMap<String, Value>>,
+    received: Mutex<Vec<ReceivedActivity>>,
+    arrived: Condvar,
+}
+
+impl Shared {
+    fn base(&self) -> &str {
+        self.base.get().map_or("", String::as_str)
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        match (request.method, request.segments().as_slice()) {
+            (Method::Get, [".well-known", "webfinger"]) => self.webfinger(request),
+            (Method::Get, ["users", name]) => match self.actors.lock().unwrap().get(*name) {
+                Some(actor) => json_as(200, actor, ACTIVITY_JSON),
+                None => Response::error(404, "No such actor"),
+            },
+            (Method::Post, ["users", name, "inbox"])
+                if self.actors.lock().unwrap().contains_key(*name) =>
+            {
+                self.receive(request)
+            }
+            (Method::Post, ["inbox"]) => self.receive(request),
+            _ => Response::error(404, "Not found"),
+        }
+    }
+
+    fn webfinger(&self, request: &Request) -> Response {
+        let Some(resource) = request.query_param("resource") else {
+            return Response::error(400, "Missing resource");
+        };
+        let host = self.base().trim_start_matches("http://");
+        let name = resource
+            .strip_prefix("acct:")
+            .and_then(|acct| acct.strip_suffix(&format!("@{host}")));
+        match name {
+            Some(name) if self.actors.lock().unwrap().contains_key(name) => json_as(
+                200,
+                &Value::object([
+                    ("subject", Value::from(resource)),
+                    (
+                        "links",
+                        Value::Array(vec![Value::object([
+                            ("rel", Value::from("self")),
+                            ("type", Value::from(ACTIVITY_JSON)),
+                            ("href", Value::from(format!("{}/users/{name}", self.base()))),
+                        ])]),
+                    ),
+                ]),
+                "application/jrd+json",
+            ),
+            _ => Response::error(404, "No such account"),
+        }
+    }
+
+    fn receive(&self, request: &Request) -> Response {
+        let Ok(activity) = json::parse(&String::from_utf8_lossy(&request.body)) else {
+            return Response::error(400, "Activity isn't valid JSON");
+        };
+        self.received.lock().unwrap().push(ReceivedActivity {
+            inbox: request.path.clone(),
+            headers: request.headers.clone(),
+            activity,
+        });
+        self.arrived.notify_all();
+        Response::new(202)
+    }
+}
+
+/// An in-process ActivityPub server
+pub struct MockApServer {
+    shared: Arc<Shared>,
+    server: MockServer,
+}
+
+impl MockApServer {
+    pub fn start() -> io::Result<MockApServer> {
+        let shared = Arc::new(Shared::default());
+        let handler = shared.clone();
+        let server = MockServer::start(Arc::new(move |r: &Request| handler.handle(r)))?;
+        let _ = shared.base.set(server.url());
+        Ok(MockApServer { shared, server })
+    }
+
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// The host (with port) used in WebFinger addresses
+    pub fn host(&self) -> String {
+        self.server.addr().to_string()
+    }
+
+    pub fn actor_url(&self, name: &str) -> String {
+        format!("{}/users/{name}", self.url())
+    }
+
+    pub fn inbox_url(&self, name: &str) -> String {
+        format!("{}/inbox", self.actor_url(name))
+    }
+
+    pub fn shared_inbox_url(&self) -> String {
+        format!("{}/inbox", self.url())
+    }
+
+    /// Add a `Person` actor, returning its id
+    pub fn add_actor(&self, name: &str) -> String {
+        let id = self.actor_url(name);
+        let actor = Value::object([
+            (
+                "@context",
+                Value::Array(vec![
+                    Value::from("https://www.w3.org/ns/activitystreams"),
+                    Value::from("https://w3id.org/security/v1"),
+                ]),
+            ),
+            ("id", Value::from(id.as_str())),
+            ("type", Value::from("Person")),
+            ("preferredUsername", Value::from(name)),
+            ("inbox", Value::from(self.inbox_url(name))),
+            ("outbox", Value::from(format!("{id}/outbox"))),
+            (
+                "endpoints",
+                Value::object([("sharedInbox", Value::from(self.shared_inbox_url()))]),
+            ),
+        ]);
+        self.set_actor(name, actor);
+        id
+    }
+
+    /// Serve `actor` as the document for `name`, e.g. to test unusual actors
+    pub fn set_actor(&self, name: &str, actor: Value) {
+        self.shared
+            .actors
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), actor);
+    }
+
+    /// Everything received so far, oldest first
+    pub fn received(&self) -> Vec<ReceivedActivity> {
+        self.shared.received.lock().unwrap().clone()
+    }
+
+    /// Activities received by `name`'s own inbox
+    pub fn received_by(&self, name: &str) -> Vec<ReceivedActivity> {
+        let inbox = format!("/users/{name}/inbox");
+        let received = self.shared.received.lock().unwrap();
+        received
+            .iter()
+            .filter(|a| a.inbox == inbox)
+            .cloned()
+            .collect()
+    }
+
+    /// Wait until at least `count` activities have arrived, returning everything received
+    ///
+    /// Deliveries are asynchronous, so tests should wait rather than check immediately
+    pub fn wait_for(&self, count: usize, timeout: Duration) -> Vec<ReceivedActivity> {
+        let deadline = Instant::now() + timeout;
+        let mut received = self.shared.received.lock().unwrap();
+        while received.len() < count {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            received = self
+                .shared
+                .arrived
+                .wait_timeout(received, deadline - now)
+                .unwrap()
+                .0;
+        }
+        received.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fedibridge::transport::{HttpTransport, OutboundRequest, StdTransport};
+
+    #[test]
+    fn webfinger_and_actor() {
+        let server = MockApServer::start().unwrap();
+        let id = server.add_actor("alice");
+        let transport = StdTransport::default();
+
+        let url = format!(
+            "{}/.well-known/webfinger?resource=acct:alice@{}",
+            server.url(),
+            server.host()
+        );
+        let response = transport.send(&OutboundRequest::get(url)).unwrap();
+        let jrd = json::parse(&String::from_utf8_lossy(&response.body)).unwrap();
+        let link = &jrd.get("links").unwrap().as_array().unwrap()[0];
+        assert_eq!(link.get("href").and_then(Value::as_str), Some(id.as_str()));
+
+        let response = transport.send(&OutboundRequest::get(id.as_str())).unwrap();
+        assert_eq!(response.header("content-type"), Some(ACTIVITY_JSON));
+        let missing = transport
+            .send(&OutboundRequest::get(server.actor_url("bob")))
+            .unwrap();
+        assert_eq!(missing.status, 404);
+    }
+
+    #[test]
+    fn captures_inbox_deliveries() {
+        let server = MockApServer::start().unwrap();
+        server.add_actor("alice");
+        let transport = StdTransport::default();
+        let post = |url: String, body: &str| {
+            transport
+                .send(&OutboundRequest::post(url, body))
+                .unwrap()
+                .status
+        };
+        assert_eq!(post(server.inbox_url("alice"), r#"{"type":"Follow"}"#), 202);
+        assert_eq!(post(server.shared_inbox_url(), r#"{"type":"Like"}"#), 202);
+        assert_eq!(post(server.inbox_url("bob"), "{}"), 404);
+        assert_eq!(post(server.shared_inbox_url(), "not json"), 400);
+
+        let received = server.wait_for(2, Duration::from_secs(1));
+        assert_eq!(received.len(), 2);
+        let alice = server.received_by("alice");
+        assert_eq!(
+            alice[0].activity.get("type").and_then(Value::as_str),
+            Some("Follow")
+        );
+    }
+}
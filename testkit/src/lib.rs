@@ -0,0 +1,88 @@
+//! Fixtures for end-to-end bridging tests
+//!
+//! Each mock is a real HTTP server bound to an ephemeral port on localhost, running in-process
+//! on the bridge's own [`http::serve`](fedibridge::http::serve). Tests point the bridge (with
+//! its normal [`StdTransport`](fedibridge::transport::StdTransport)) at the mock's URL, then
+//! inspect what the mock received, without needing any external services
+//!
+//! - [`MockPds`] - an atproto PDS with accounts, records and a firehose
+//! - [`MockApServer`] - an ActivityPub server with actors, WebFinger and inbox capture
+
+pub mod activitypub;
+pub mod pds;
+pub mod server;
+
+pub use activitypub::{MockApServer, ReceivedActivity};
+pub use pds::{FirehoseEvent, MockPds};
+pub use server::MockServer;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fedibridge::bridge::Bridge;
+    use fedibridge::delivery::{Delivery, RequestSigner};
+    use fedibridge::jobs::{spawn_workers, Job};
+    use fedibridge::keys::{KeyAlgorithm, KeyGenerator, KeyOwner, KeyPair, KeyPurpose};
+    use fedibridge::shutdown::Shutdown;
+    use fedibridge::store::Mapping;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Keys and signatures which are only placeholders, as the mock doesn't verify them
+    struct Placeholder;
+
+    impl KeyGenerator for Placeholder {
+        fn generate(&self, algorithm: KeyAlgorithm) -> anyhow::Result<KeyPair> {
+            Ok(KeyPair {
+                algorithm,
+                private_key: vec![0],
+                public_key: "public".to_string(),
+            })
+        }
+    }
+
+    impl RequestSigner for Placeholder {
+        fn sign(&self, _: &KeyPair, signed: &[u8]) -> anyhow::Result<Vec<u8>> {
+            Ok(signed.to_vec())
+        }
+    }
+
+    #[test]
+    fn bridge_delivers_to_mock_inbox() {
+        let server = MockApServer::start().unwrap();
+        server.add_actor("alice");
+        let bridge = Arc::new(Bridge::new().with_request_signer(Arc::new(Placeholder)));
+        let actor = "https://bridge.example/users/bob";
+        let did = atproto::did!("did:plc:bob");
+        bridge.identities.insert(Mapping::new(did.clone(), actor));
+        let owner = KeyOwner::Account(did);
+        bridge
+            .keys
+            .ensure(&owner, KeyPurpose::HttpSignature, &Placeholder)
+            .unwrap();
+        let activity = r#"{"type":"Create","actor":"https://bridge.example/users/bob"}"#;
+        bridge
+            .jobs
+            .push(Job::Deliver(Delivery::new(
+                server.inbox_url("alice"),
+                activity,
+            )))
+            .unwrap();
+
+        let shutdown = Shutdown::new();
+        let workers = spawn_workers(bridge.jobs.clone(), bridge.clone(), 1, shutdown.clone());
+        let received = server.wait_for(1, Duration::from_secs(5));
+        shutdown.run(Duration::from_secs(1));
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].inbox, "/users/alice/inbox");
+        assert_eq!(
+            received[0].header("content-type"),
+            Some(fedibridge::delivery::ACTIVITY_JSON)
+        );
+        let signature = received[0].header("signature").unwrap();
+        assert!(signature.contains(r#"keyId="https://bridge.example/users/bob#main-key""#));
+    }
+}
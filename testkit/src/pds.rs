@@ -0,0 +1,308 @@
+//! A mock atproto PDS
+//!
+//! Implements the handful of XRPC methods the bridge reads from a PDS, backed by records the
+//! test puts in directly. Every write is also appended to a firehose.
+//!
+//! The real firehose (`com.atproto.sync.subscribeRepos`) is a WebSocket stream of CBOR
+//! frames. The mock serves the same events as a JSON query instead
+//! (`{"events": [...]}` for everything after `?cursor=`), and tests can read them in-process
+//! with [`MockPds::events_since`]
+
+use crate::server::MockServer;
+use atproto::DID::Did;
+use fedibridge::http::{Request, Response};
+use fedibridge::json::Value;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+/// A repo write as seen on the firehose
+pub struct FirehoseEvent {
+    pub seq: i64,
+    pub did: Did,
+    /// `create`, `update` or `delete`
+    pub action: &'static str,
+    /// `{collection}/{rkey}`
+    pub path: String,
+    /// The new record, absent for deletes
+    pub record: Option<Value>,
+}
+
+impl FirehoseEvent {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("seq", Value::from(self.seq)),
+            ("repo", Value::from(self.did.as_str())),
+            ("action", Value::from(self.action)),
+            ("path", Value::from(self.path.as_str())),
+            ("record", self.record.clone().unwrap_or(Value::Null)),
+        ])
+    }
+}
+
+type RecordKey = (Did, String, String);
+
+#[derive(Default)]
+struct State {
+    /// Handle of each account
+    accounts: BTreeMap<Did, String>,
+    records: BTreeMap<RecordKey, Value>,
+    events: Vec<FirehoseEvent>,
+}
+
+impl State {
+    /// Look up a repo by DID or handle
+    fn repo(&self, repo: &str) -> Option<&Did> {
+        self.accounts
+            .iter()
+            .find(|(did, handle)| did.as_str() == repo || handle.as_str() == repo)
+            .map(|(did, _)| did)
+    }
+
+    fn emit(&mut self, did: &Did, action: &'static str, path: String, record: Option<Value>) {
+        let seq = self.events.len() as i64 + 1;
+        self.events.push(FirehoseEvent {
+            seq,
+            did: did.clone(),
+            action,
+            path,
+            record,
+        });
+    }
+}
+
+fn at_uri(did: &Did, collection: &str, rkey: &str) -> String {
+    format!("at://{did}/{collection}/{rkey}")
+}
+
+/// An XRPC error in the shape atproto services use
+fn xrpc_error(status: u16, error: &str, message: &str) -> Response {
+    Response::json(
+        status,
+        &Value::object([
+            ("error", Value::from(error)),
+            ("message", Value::from(message)),
+        ]),
+    )
+}
+
+fn handle(state: &Mutex<State>, request: &Request) -> Response {
+    let state = state.lock().unwrap();
+    let Some(method) = request.path.strip_prefix("/xrpc/") else {
+        return Response::error(404, "Not found");
+    };
+    let param = |name| request.query_param(name).unwrap_or_default();
+    let repo = || {
+        state
+            .repo(param("repo"))
+            .ok_or_else(|| xrpc_error(400, "RepoNotFound", "Could not find repo"))
+    };
+    let result = match method {
+        "com.atproto.identity.resolveHandle" => state
+            .repo(param("handle"))
+            .map(|did| Value::object([("did", Value::from(did.as_str()))]))
+            .ok_or_else(|| xrpc_error(400, "InvalidRequest", "Unable to resolve handle")),
+        "com.atproto.repo.describeRepo" => repo().map(|did| {
+            let mut collections: Vec<_> = state
+                .records
+                .keys()
+                .filter(|(d, _, _)| d == did)
+                .map(|(_, c, _)| Value::from(c.as_str()))
+                .collect();
+            collections.dedup();
+            Value::object([
+                ("did", Value::from(did.as_str())),
+                ("handle", Value::from(state.accounts[did].as_str())),
+                ("collections", Value::Array(collections)),
+            ])
+        }),
+        "com.atproto.repo.getRecord" => repo().and_then(|did| {
+            let (collection, rkey) = (param("collection"), param("rkey"));
+            let key = (did.clone(), collection.to_string(), rkey.to_string());
+            state
+                .records
+                .get(&key)
+                .map(|value| {
+                    Value::object([
+                        ("uri", Value::from(at_uri(did, collection, rkey))),
+                        ("value", value.clone()),
+                    ])
+                })
+                .ok_or_else(|| xrpc_error(400, "RecordNotFound", "Could not locate record"))
+        }),
+        "com.atproto.repo.listRecords" => repo().map(|did| {
+            let records = state
+                .records
+                .iter()
+                .filter(|((d, c, _), _)| d == did && c == param("collection"))
+                .map(|((d, c, rkey), value)| {
+                    Value::object([
+                        ("uri", Value::from(at_uri(d, c, rkey))),
+                        ("value", value.clone()),
+                    ])
+                })
+                .collect();
+            Value::object([("records", Value::Array(records))])
+        }),
+        "com.atproto.sync.subscribeRepos" => {
+            let cursor = param("cursor").parse().unwrap_or(0);
+            let events = state
+                .events
+                .iter()
+                .filter(|e| e.seq > cursor)
+                .map(FirehoseEvent::to_json)
+                .collect();
+            Ok(Value::object([("events", Value::Array(events))]))
+        }
+        _ => Err(xrpc_error(
+            501,
+            "MethodNotImplemented",
+            "Method not implemented",
+        )),
+    };
+    match result {
+        Ok(body) => Response::json(200, &body),
+        Err(response) => response,
+    }
+}
+
+/// An in-process PDS
+pub struct MockPds {
+    state: Arc<Mutex<State>>,
+    server: MockServer,
+}
+
+impl MockPds {
+    pub fn start() -> io::Result<MockPds> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let handler = state.clone();
+        let server = MockServer::start(Arc::new(move |r: &Request| handle(&handler, r)))?;
+        Ok(MockPds { state, server })
+    }
+
+    /// The PDS's base URL, as found in a DID document's service endpoint
+    pub fn url(&self) -> String {
+        self.server.url()
+    }
+
+    /// Host an account
+    ///
+    /// # Panics
+    /// If `did` isn't a valid DID
+    pub fn create_account(&self, did: &str, handle: &str) -> Did {
+        let did = Did::try_create(did.to_string()).expect("create_account needs a valid DID");
+        let mut state = self.state.lock().unwrap();
+        state.accounts.insert(did.clone(), handle.to_string());
+        did
+    }
+
+    /// Create or replace a record, returning its `at://` URI
+    pub fn put_record(&self, did: &Did, collection: &str, rkey: &str, record: Value) -> String {
+        let mut state = self.state.lock().unwrap();
+        let key = (did.clone(), collection.to_string(), rkey.to_string());
+        let action = match state.records.insert(key, record.clone()) {
+            Some(_) => "update",
+            None => "create",
+        };
+        state.emit(did, action, format!("{collection}/{rkey}"), Some(record));
+        at_uri(did, collection, rkey)
+    }
+
+    /// Delete a record, returning whether it existed
+    pub fn delete_record(&self, did: &Did, collection: &str, rkey: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let key = (did.clone(), collection.to_string(), rkey.to_string());
+        let existed = state.records.remove(&key).is_some();
+        if existed {
+            state.emit(did, "delete", format!("{collection}/{rkey}"), None);
+        }
+        existed
+    }
+
+    /// Firehose events after `cursor`, oldest first
+    pub fn events_since(&self, cursor: i64) -> Vec<FirehoseEvent> {
+        let state = self.state.lock().unwrap();
+        state
+            .events
+            .iter()
+            .filter(|e| e.seq > cursor)
+            .cloned()
+            .collect()
+    }
+
+    /// Sequence number of the latest firehose event
+    pub fn head(&self) -> i64 {
+        self.state.lock().unwrap().events.len() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fedibridge::json;
+    use fedibridge::transport::{HttpTransport, OutboundRequest, StdTransport};
+
+    fn xrpc(pds: &MockPds, query: &str) -> (u16, Value) {
+        let url = format!("{}/xrpc/{query}", pds.url());
+        let response = StdTransport::default()
+            .send(&OutboundRequest::get(url))
+            .unwrap();
+        let body = json::parse(&String::from_utf8_lossy(&response.body)).unwrap();
+        (response.status, body)
+    }
+
+    #[test]
+    fn serves_records() {
+        let pds = MockPds::start().unwrap();
+        let did = pds.create_account("did:plc:alice", "alice.test");
+        let post = Value::object([("text", Value::from("hello"))]);
+        let uri = pds.put_record(&did, "app.bsky.feed.post", "3k", post.clone());
+        assert_eq!(uri, "at://did:plc:alice/app.bsky.feed.post/3k");
+
+        let (status, body) = xrpc(&pds, "com.atproto.identity.resolveHandle?handle=alice.test");
+        assert_eq!(status, 200);
+        assert_eq!(
+            body.get("did").and_then(Value::as_str),
+            Some("did:plc:alice")
+        );
+
+        let (_, body) = xrpc(
+            &pds,
+            "com.atproto.repo.getRecord?repo=alice.test&collection=app.bsky.feed.post&rkey=3k",
+        );
+        assert_eq!(body.get("value"), Some(&post));
+
+        let (status, body) = xrpc(
+            &pds,
+            "com.atproto.repo.getRecord?repo=did:plc:bob&collection=app.bsky.feed.post&rkey=3k",
+        );
+        assert_eq!(status, 400);
+        assert_eq!(
+            body.get("error").and_then(Value::as_str),
+            Some("RepoNotFound")
+        );
+    }
+
+    #[test]
+    fn writes_appear_on_firehose() {
+        let pds = MockPds::start().unwrap();
+        let did = pds.create_account("did:plc:alice", "alice.test");
+        pds.put_record(&did, "app.bsky.feed.post", "1", Value::Null);
+        pds.put_record(&did, "app.bsky.feed.post", "1", Value::Null);
+        assert!(pds.delete_record(&did, "app.bsky.feed.post", "1"));
+        assert!(!pds.delete_record(&did, "app.bsky.feed.post", "1"));
+
+        let actions: Vec<_> = pds.events_since(0).iter().map(|e| e.action).collect();
+        assert_eq!(actions, ["create", "update", "delete"]);
+        assert_eq!(pds.head(), 3);
+
+        let (_, body) = xrpc(&pds, "com.atproto.sync.subscribeRepos?cursor=2");
+        let events = body.get("events").and_then(Value::as_array).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].get("action").and_then(Value::as_str),
+            Some("delete")
+        );
+    }
+}
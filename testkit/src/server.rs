@@ -0,0 +1,50 @@
+//! A handler served on localhost for the lifetime of a test
+
+use fedibridge::http::{self, Handler};
+use fedibridge::shutdown::Shutdown;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// An HTTP server on an ephemeral localhost port, stopped when dropped
+pub struct MockServer {
+    addr: SocketAddr,
+    shutdown: Arc<Shutdown>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl MockServer {
+    pub fn start(handler: Arc<dyn Handler>) -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let shutdown = Shutdown::new();
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || http::serve(listener, handler, shutdown))
+        };
+        Ok(MockServer {
+            addr,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The server's base URL, without a trailing slash
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown.request();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}